@@ -0,0 +1,228 @@
+//! # Linux Guest Agent
+//!
+//! An example agent for Linux guests: it registers the offsets of the running kernel with the hypervisor, lists the
+//! tasks of the guest and optionally hooks a syscall, over the same CPUID hypercall ABI as the Windows client.
+//!
+//! The offsets of the symbols are read from `/proc/kallsyms`, which requires root (or `kernel.kptr_restrict=0`), and
+//! the offsets of the structures from a file of `name=value` lines, e.g., from `pahole -C task_struct vmlinux`:
+//!
+//! ```text
+//! task_tasks=0x8f8
+//! task_pid=0x9f0
+//! task_tgid=0x9f4
+//! task_comm=0xbc0
+//! task_mm=0x948
+//! mm_pgd=0x68
+//! ```
+//!
+//! Usage: `sudo ./linux_agent <structure-offsets-file> [syscall-number-to-hook]`
+
+use {
+    shared::{
+        ClientCommand, ClientDataPayload, Command, DetourType, HookData, LinuxKernelOffsets, LinuxKernelOperation, LinuxTask, LinuxTaskHeader,
+        LinuxTasksOperation, LINUX_RELEASE_SIZE, PASSWORD,
+    },
+    std::{arch::asm, collections::HashMap, fs, mem::size_of},
+};
+
+/// The maximum number of tasks read from the hypervisor.
+const MAX_TASKS: usize = 0x2000;
+
+/// The size of a page, touched before the hypervisor writes to a buffer.
+const PAGE_SIZE: usize = 0x1000;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let Some(structure_offsets_path) = args.get(1) else {
+        eprintln!("Usage: {} <structure-offsets-file> [syscall-number-to-hook]", args[0]);
+        return;
+    };
+
+    let Some(release) = read_release() else {
+        eprintln!("Failed to read the kernel release");
+        return;
+    };
+
+    let Some(offsets) = read_kernel_offsets(structure_offsets_path) else {
+        eprintln!("Failed to read the kernel offsets, is the agent running as root?");
+        return;
+    };
+
+    println!("Kernel {}: {:#x?}", release, offsets);
+
+    if configure_linux_kernel(&release, offsets).is_none() {
+        eprintln!("Failed to register the kernel offsets");
+        return;
+    }
+
+    match read_linux_tasks(MAX_TASKS) {
+        Some((tasks, total_tasks)) => {
+            println!("{} of {} tasks:", tasks.len(), total_tasks);
+
+            for task in &tasks {
+                let comm_size = task.comm.iter().position(|&b| b == 0).unwrap_or(task.comm.len());
+                println!(
+                    "{:>7} {:>7} {:#018x} {:#014x} {}",
+                    task.pid,
+                    task.tgid,
+                    task.task_va,
+                    task.guest_cr3,
+                    String::from_utf8_lossy(&task.comm[..comm_size])
+                );
+            }
+        }
+        None => eprintln!("Failed to read the tasks"),
+    }
+
+    if let Some(syscall_number) = args.get(2).and_then(|number| number.parse::<u16>().ok()) {
+        match hook_syscall(syscall_number, Command::EnableKernelEptHook) {
+            Some(_) => println!("Hooked syscall {}", syscall_number),
+            None => eprintln!("Failed to hook syscall {}", syscall_number),
+        }
+    }
+}
+
+/// Reads the release of the running kernel, as reported by `uname -r`.
+fn read_release() -> Option<String> {
+    Some(fs::read_to_string("/proc/sys/kernel/osrelease").ok()?.trim().to_string())
+}
+
+/// Reads the offsets of the running kernel: the symbols relative to `entry_SYSCALL_64` from `/proc/kallsyms`, and the
+/// structure members from a file of `name=value` lines.
+fn read_kernel_offsets(structure_offsets_path: &str) -> Option<LinuxKernelOffsets> {
+    let kallsyms = fs::read_to_string("/proc/kallsyms").ok()?;
+
+    let mut symbols: Vec<(u64, &str)> = kallsyms
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let name = fields.nth(1)?;
+            Some((address, name))
+        })
+        .collect();
+    symbols.sort_unstable();
+
+    let symbol = |name: &str| symbols.iter().find(|(_, symbol)| *symbol == name).map(|(address, _)| *address);
+
+    let syscall_entry = symbol("entry_SYSCALL_64").filter(|&address| address != 0)?;
+    let sys_call_table = symbol("sys_call_table")?;
+
+    // The table ends at the next symbol.
+    let sys_call_table_end = symbols.iter().map(|(address, _)| *address).find(|&address| address > sys_call_table)?;
+
+    let structure_offsets_file = fs::read_to_string(structure_offsets_path).ok()?;
+    let structure_offsets: HashMap<&str, u64> = structure_offsets_file
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            let value = value.trim();
+            let value = match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                None => value.parse().ok()?,
+            };
+            Some((name.trim(), value))
+        })
+        .collect();
+
+    let offset = |name: &str| symbol(name).map(|address| address.wrapping_sub(syscall_entry) as i64);
+    let member = |name: &str| structure_offsets.get(name).copied();
+
+    Some(LinuxKernelOffsets {
+        linux_banner: offset("linux_banner")?,
+        sys_call_table: offset("sys_call_table")?,
+        syscall_count: (sys_call_table_end - sys_call_table) / size_of::<u64>() as u64,
+        init_task: offset("init_task")?,
+        task_tasks: member("task_tasks")?,
+        task_pid: member("task_pid")?,
+        task_tgid: member("task_tgid")?,
+        task_comm: member("task_comm")?,
+        task_mm: member("task_mm")?,
+        mm_pgd: member("mm_pgd")?,
+    })
+}
+
+/// Registers the offsets of a kernel release with the hypervisor.
+fn configure_linux_kernel(release: &str, offsets: LinuxKernelOffsets) -> Option<()> {
+    let mut release_bytes = [0u8; LINUX_RELEASE_SIZE];
+    release_bytes.get_mut(..release.len())?.copy_from_slice(release.as_bytes());
+
+    let client_command = ClientCommand {
+        command: Command::ConfigureLinuxKernel,
+        payload: ClientDataPayload::LinuxKernel(LinuxKernelOperation {
+            release: release_bytes,
+            offsets,
+            remove: false,
+        }),
+    };
+
+    (call_hypervisor(client_command.as_ptr()) == 1).then_some(())
+}
+
+/// Reads the tasks of the guest, and the number of tasks including those that didn't fit in the buffer.
+fn read_linux_tasks(max_tasks: usize) -> Option<(Vec<LinuxTask>, u64)> {
+    let header_size = size_of::<LinuxTaskHeader>();
+    let mut buffer = vec![0u8; header_size + max_tasks * size_of::<LinuxTask>()];
+
+    // The pages of a zeroed allocation may not be mapped until written, while the hypervisor writes the buffer through
+    // the page tables of the process.
+    for offset in (0..buffer.len()).step_by(PAGE_SIZE) {
+        unsafe { core::ptr::write_volatile(buffer.as_mut_ptr().add(offset), 0) };
+    }
+
+    let client_command = ClientCommand {
+        command: Command::ReadLinuxTasks,
+        payload: ClientDataPayload::LinuxTasks(LinuxTasksOperation {
+            buffer: buffer.as_mut_ptr() as u64,
+            buffer_size: buffer.len() as u64,
+        }),
+    };
+
+    if call_hypervisor(client_command.as_ptr()) != 1 {
+        return None;
+    }
+
+    let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const LinuxTaskHeader) };
+    let tasks = (0..header.task_count.min(max_tasks as u64) as usize)
+        .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<LinuxTask>().add(index)) })
+        .collect();
+
+    Some((tasks, header.total_tasks))
+}
+
+/// Enables or disables the kernel EPT hook of the function of a syscall, resolved by the hypervisor through
+/// `sys_call_table`.
+fn hook_syscall(syscall_number: u16, command: Command) -> Option<()> {
+    let client_command = ClientCommand {
+        command,
+        payload: ClientDataPayload::Hook(HookData {
+            function_hash: 0,
+            syscall_number,
+            detour_type: DetourType::Vmcall,
+        }),
+    };
+
+    (call_hypervisor(client_command.as_ptr()) == 1).then_some(())
+}
+
+/// Issues a hypercall: CPUID with the password in RAX and the address of the `ClientCommand` in RCX, the hypervisor
+/// returning 1 in RAX on success.
+fn call_hypervisor(command_rcx: u64) -> u64 {
+    let mut rax = PASSWORD;
+
+    unsafe {
+        asm!(
+        "mov {0:r}, rbx",
+        "cpuid",
+        "xchg {0:r}, rbx",
+        out(reg) _,
+        inout("rax") rax,
+        inout("rcx") command_rcx => _,
+        lateout("rdx") _,
+        options(nostack, preserves_flags),
+        );
+    }
+
+    rax
+}
@@ -7,8 +7,9 @@
 
 use {
     crate::{pemem::djb2_hash, ssn::Syscall},
-    shared::{ClientCommand, ClientDataPayload, Command, HookData, ProcessMemoryOperation, PASSWORD},
+    shared::{AllocationAlert, AllocationAlertHeader, AllocationMapHeader, AllocationMonitorOperation, AllocationRegion, BackpressurePolicy, BenchmarkExitReason, BenchmarkHeader, BenchmarkOperation, BootHookOperation, BroadcastCommand, ClientCommand, ClientDataPayload, CodeIntegrityOperation, CodeSnapshot, CodeSnapshotHeader, CodeSnapshotOperation, Command, CpuidOverrideAction, CpuidOverrideOperation, DetectionCheckResult, DetectionCorpusHeader, DetectionCorpusOperation, DetectionProbes, DetectionTechnique, DetectionVerdict, DeterminismOperation, DetourType, DevirtualizeOperation, EptViewOperation, EventRingId, EventRingOperation, EventRingStats, EventRingStatsOperation, EventStreamHeader, EventStreamRecord, ExceptionEvent, ExceptionTelemetryHeader, ExceptionTelemetryOperation, ExecutionTraceHeader, ExecutionTraceOperation, ExecutionTraceRecord, ExitStatisticsHeader, ExitStatisticsOperation, ExitStatisticsRecord, ExfilOperation, HardwareBreakpointOperation, HookData, HookViewOperation, Hypercall, HypercallStatus, HypervisorPresence, KernelCallback, KernelCallbackHeader, MemorySearchHeader, MemorySearchMatch, MemorySearchOperation, MsrBitmapOperation, MsrContextRuleOperation, OsEventsOperation, ProcessDumpChunk, ProcessDumpHeader, ProcessDumpOperation, ProcessMemoryOperation, ProcessTrackingOperation, ProfileHeader, ProfileSample, ProfilerOperation, ProtectedMemoryAction, ProtectedMemoryOperation, ResetPolicy, ResolvedSymbol, RtcOffsetOperation, SaveConfigurationOperation, SearchEncoding, SharedPage, SharedPageOperation, SignatureScanHeader, SignatureScanOperation, SymbolHeader, SymbolOperation, SyscallTraceFilterMode, SyscallTraceHeader, SyscallTraceOperation, SyscallTraceRecord, TransferProgress, TscCompensationOperation, UnpackedPage, UnpackerDumpHeader, UnpackerOperation, WatchdogOperation, WindowsIntrospectionOperation, WindowsProcess, WindowsProcessHeader, WindowsThread, WindowsThreadHeader, WindowsVad, WindowsVadHeader, XsavePolicyOperation, DETECTION_TECHNIQUE_COUNT, HYPERCALL_ABI_VERSION, HYPERCALL_MAGIC, HYPERCALL_SECRET, CODE_INTEGRITY_DRIVER_NAME_SIZE, MAX_BENCHMARK_EXIT_REASONS, MAX_CODE_INTEGRITY_DRIVERS, MAX_EXIT_STATISTICS_RECORDS, MAX_PROCESS_DUMP_SIZE, MAX_SEARCH_PATTERN_SIZE, MAX_SIGNATURE_SIZE, MAX_SYMBOL_ADDRESSES, MAX_SYSCALL_TRACE_FILTER, PASSWORD},
     std::arch::asm,
+    x86::{cpuid::cpuid, time::rdtsc},
 };
 
 /// Struct to encapsulate the result of a CPUID instruction.
@@ -28,16 +29,21 @@ pub struct HypervisorCommunicator {
 impl HypervisorCommunicator {
     /// Enables a kernel EPT hook by specifying the function name.
     pub fn enable_ept_kernel_hook(&self, function_name: &str) -> Option<()> {
-        self.manage_ept_kernel_hook(function_name, Command::EnableKernelEptHook)
+        self.manage_ept_kernel_hook(function_name, Command::EnableKernelEptHook, DetourType::Vmcall)
+    }
+
+    /// Enables a kernel EPT hook by specifying the function name and the detour written over the function.
+    pub fn enable_ept_kernel_hook_with_detour(&self, function_name: &str, detour_type: DetourType) -> Option<()> {
+        self.manage_ept_kernel_hook(function_name, Command::EnableKernelEptHook, detour_type)
     }
 
     /// Disables a kernel EPT hook by specifying the function name.
     pub fn disable_ept_kernel_hook(&self, function_name: &str) -> Option<()> {
-        self.manage_ept_kernel_hook(function_name, Command::DisableKernelEptHook)
+        self.manage_ept_kernel_hook(function_name, Command::DisableKernelEptHook, DetourType::Vmcall)
     }
 
     /// Internal function to manage (enable/disable) kernel EPT hooks.
-    fn manage_ept_kernel_hook(&self, function_name: &str, command: Command) -> Option<()> {
+    fn manage_ept_kernel_hook(&self, function_name: &str, command: Command, detour_type: DetourType) -> Option<()> {
         // Lookup the syscall number using the function hash
         let mut syscall = Syscall::new();
         let function_hash = djb2_hash(function_name.as_bytes());
@@ -48,6 +54,7 @@ impl HypervisorCommunicator {
         let hook_data = HookData {
             function_hash,
             syscall_number,
+            detour_type,
         };
 
         let client_command = ClientCommand {
@@ -96,6 +103,97 @@ impl HypervisorCommunicator {
         }
     }
 
+    /// Maps the hypervisor's shared communication page over the provided guest page and returns its guest physical address.
+    ///
+    /// The page must be page-aligned and locked in memory (e.g., allocated with `VirtualAlloc` and locked with `VirtualLock`)
+    /// until `unmap_shared_page` is called. The thread should stay on the same logical processor while using the page.
+    pub fn map_shared_page(guest_page: *mut SharedPage) -> Option<u64> {
+        log::debug!("Mapping shared page over: {:p}", guest_page);
+
+        let mut shared_page_gpa = 0u64;
+
+        let client_command = ClientCommand {
+            command: Command::MapSharedPage,
+            payload: ClientDataPayload::SharedPage(SharedPageOperation {
+                guest_page_va: guest_page as u64,
+                buffer: &mut shared_page_gpa as *mut u64 as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 && unsafe { (*guest_page).is_valid() } {
+            log::debug!("Mapped shared page at GPA: {:#x}", shared_page_gpa);
+            Some(shared_page_gpa)
+        } else {
+            log::error!("Failed to map shared page");
+            None
+        }
+    }
+
+    /// Restores the original guest page that the shared communication page was mapped over.
+    pub fn unmap_shared_page() -> Option<()> {
+        log::debug!("Unmapping shared page");
+
+        let client_command = ClientCommand {
+            command: Command::UnmapSharedPage,
+            payload: ClientDataPayload::SharedPage(SharedPageOperation { guest_page_va: 0, buffer: 0 }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Shared page unmapped successfully");
+            Some(())
+        } else {
+            log::error!("Failed to unmap shared page");
+            None
+        }
+    }
+
+    /// Shifts the RTC time observed by the guest by an offset in seconds, or restores the hardware time with an offset of 0.
+    pub fn set_rtc_offset(offset_seconds: i64) -> Option<()> {
+        log::debug!("Setting RTC offset to {} seconds", offset_seconds);
+
+        let client_command = ClientCommand {
+            command: Command::SetRtcOffset,
+            payload: ClientDataPayload::RtcOffset(RtcOffsetOperation { offset_seconds }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("RTC offset set successfully");
+            Some(())
+        } else {
+            log::error!("Failed to set RTC offset");
+            None
+        }
+    }
+
+    /// Appends a buffer to the exfiltration file on the EFI System Partition, written by the hypervisor to the disk.
+    pub fn append_exfil_data(data: &[u8]) -> Option<()> {
+        log::debug!("Appending {} bytes to the exfiltration file", data.len());
+
+        let client_command = ClientCommand {
+            command: Command::AppendExfilData,
+            payload: ClientDataPayload::Exfil(ExfilOperation {
+                buffer: data.as_ptr() as u64,
+                buffer_size: data.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Data appended successfully");
+            Some(())
+        } else {
+            log::error!("Failed to append data to the exfiltration file");
+            None
+        }
+    }
+
     /// Sends a command to the hypervisor using CPUID.
     fn call_hypervisor(command_rcx: u64) -> CpuidResult {
         let mut rax = PASSWORD;
@@ -124,34 +222,2099 @@ impl HypervisorCommunicator {
         }
     }
 
-    /// Reads memory from the opened process using the stored CR3.
-    pub fn read_process_memory(&self, address: u64, buffer: &mut [u8]) -> Option<()> {
-        log::debug!("Reading memory from address: {:#x}", address);
+    /// Makes a hypercall using VMCALL with the arguments in RDX, R8 and R9 and the session key in R10, returning its
+    /// status and its results. A hypercall that isn't authenticated raises #UD instead of returning.
+    pub fn hypercall(hypercall: Hypercall, session_key: u64, arguments: [u64; 3]) -> (Option<HypercallStatus>, [u64; 3]) {
+        let mut rax = HYPERCALL_MAGIC;
+        let mut rdx = arguments[0];
+        let mut r8 = arguments[1];
+        let mut r9 = arguments[2];
 
-        let memory_operation = ProcessMemoryOperation {
-            process_id: None,
-            guest_cr3: Some(self.process_cr3),
-            address: Some(address),
-            buffer: buffer.as_ptr() as u64,
-            buffer_size: buffer.len() as u64,
+        unsafe {
+            asm!(
+            "vmcall",
+            inout("rax") rax,
+            in("rcx") ((HYPERCALL_ABI_VERSION as u64) << 32) | hypercall as u64,
+            inout("rdx") rdx,
+            inout("r8") r8,
+            inout("r9") r9,
+            in("r10") session_key,
+            options(nostack, preserves_flags),
+            );
+        }
+
+        (HypercallStatus::from_u64(rax), [rdx, r8, r9])
+    }
+
+    /// Makes a hypercall using CPUID, for guests where VMCALL from user mode is undesirable, with the same registers as
+    /// `hypercall`. A hypercall that isn't authenticated returns the results of a CPUID leaf instead, reported as a
+    /// `None` status.
+    pub fn cpuid_hypercall(hypercall: Hypercall, session_key: u64, arguments: [u64; 3]) -> (Option<HypercallStatus>, [u64; 3]) {
+        let mut rax = HYPERCALL_MAGIC;
+        let mut rdx = arguments[0];
+        let mut r8 = arguments[1];
+        let mut r9 = arguments[2];
+        let rbx: u64;
+
+        unsafe {
+            asm!(
+            "mov {0:r}, rbx",
+            "cpuid",
+            "xchg {0:r}, rbx",
+            out(reg) rbx,
+            inout("rax") rax,
+            inout("rcx") ((HYPERCALL_ABI_VERSION as u64) << 32) | hypercall as u64 => _,
+            inout("rdx") rdx,
+            inout("r8") r8,
+            inout("r9") r9,
+            in("r10") session_key,
+            options(nostack, preserves_flags),
+            );
+        }
+
+        match rbx == HYPERCALL_MAGIC {
+            true => (HypercallStatus::from_u64(rax), [rdx, r8, r9]),
+            false => (None, [0; 3]),
+        }
+    }
+
+    /// Opens the hypercall session with the shared secret, returning the session key passed to the other hypercalls.
+    ///
+    /// The hypercall raises #UD if the secret is wrong.
+    pub fn open_hypercall_session() -> Option<u64> {
+        let (status, results) = Self::hypercall(Hypercall::OpenSession, 0, [HYPERCALL_SECRET, 0, 0]);
+
+        match status {
+            Some(HypercallStatus::Success) => {
+                log::debug!("Hypercall session opened");
+                Some(results[0])
+            }
+            status => {
+                log::error!("Failed to open hypercall session: {:?}", status);
+                None
+            }
+        }
+    }
+
+    /// Queries the hypercall ABI version, the `HypercallFeature` bits of the enabled features and the number of
+    /// hypercalls of the hypervisor.
+    pub fn query_hypervisor_status(session_key: u64) -> Option<(u32, u64, u64)> {
+        let (status, results) = Self::hypercall(Hypercall::QueryStatus, session_key, [0; 3]);
+
+        match status {
+            Some(HypercallStatus::Success) => {
+                log::debug!("Hypervisor ABI version {}, features: {:#x}, {} hypercalls", results[0], results[1], results[2]);
+                Some((results[0] as u32, results[1], results[2]))
+            }
+            status => {
+                log::error!("Failed to query hypervisor status: {:?}", status);
+                None
+            }
+        }
+    }
+
+    /// Reads a `u64` from the memory of a process by its process ID, without opening it.
+    pub fn read_process_u64(session_key: u64, process_id: u64, address: u64) -> Option<u64> {
+        let (status, results) = Self::hypercall(Hypercall::ReadProcessMemory, session_key, [process_id, address, 0]);
+
+        match status {
+            Some(HypercallStatus::Success) => Some(results[0]),
+            status => {
+                log::error!("Failed to read memory of process {} at {:#x}: {:?}", process_id, address, status);
+                None
+            }
+        }
+    }
+
+    /// Writes a `u64` to the memory of a process by its process ID, without opening it.
+    pub fn write_process_u64(session_key: u64, process_id: u64, address: u64, value: u64) -> Option<()> {
+        let (status, _) = Self::hypercall(Hypercall::WriteProcessMemory, session_key, [process_id, address, value]);
+
+        match status {
+            Some(HypercallStatus::Success) => Some(()),
+            status => {
+                log::error!("Failed to write memory of process {} at {:#x}: {:?}", process_id, address, status);
+                None
+            }
+        }
+    }
+
+    /// Registers the event stream buffer at a page-aligned, physically contiguous guest physical address, streaming the
+    /// events whose `EventStreamKind` bit is set in `kinds`, and returns the capacity of its ring.
+    ///
+    /// The buffer must stay allocated and resident until `unregister_event_stream`, as the hypervisor writes it by its
+    /// physical address.
+    pub fn register_event_stream(session_key: u64, buffer_pa: u64, size: u64, kinds: u64) -> Option<u64> {
+        let (status, results) = Self::hypercall(Hypercall::RegisterEventStream, session_key, [buffer_pa, size, kinds]);
+
+        match status {
+            Some(HypercallStatus::Success) => {
+                log::debug!("Event stream registered with a capacity of {} records", results[0]);
+                Some(results[0])
+            }
+            status => {
+                log::error!("Failed to register event stream: {:?}", status);
+                None
+            }
+        }
+    }
+
+    /// Unregisters the event stream buffer, which the hypervisor no longer writes once this returns.
+    pub fn unregister_event_stream(session_key: u64) -> Option<()> {
+        let (status, _) = Self::hypercall(Hypercall::UnregisterEventStream, session_key, [0; 3]);
+
+        match status {
+            Some(HypercallStatus::Success) => Some(()),
+            status => {
+                log::error!("Failed to unregister event stream: {:?}", status);
+                None
+            }
+        }
+    }
+
+    /// Consumes the published records of a registered event stream buffer, mapped at `buffer`, up to `max_records`,
+    /// returning them oldest first with the number of records dropped since the registration.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must map the whole registered buffer, and a single consumer may drain it at a time.
+    pub unsafe fn drain_event_stream(buffer: *mut EventStreamHeader, max_records: usize) -> (Vec<EventStreamRecord>, u64) {
+        use core::sync::atomic::{AtomicU64, Ordering};
+
+        let atomic = |field: *mut u64| unsafe { &*(field as *const AtomicU64) };
+
+        let read_index = atomic(core::ptr::addr_of_mut!((*buffer).read_index));
+        let capacity = core::ptr::read_volatile(core::ptr::addr_of!((*buffer).capacity));
+        let records = buffer.add(1).cast::<EventStreamRecord>();
+
+        let mut drained = Vec::new();
+        let mut index = read_index.load(Ordering::Acquire);
+
+        while capacity != 0 && drained.len() < max_records {
+            let record = records.add((index % capacity) as usize);
+
+            // The record is complete once its sequence is published.
+            if atomic(core::ptr::addr_of_mut!((*record).sequence)).load(Ordering::Acquire) != index + 1 {
+                break;
+            }
+
+            drained.push(core::ptr::read_volatile(record));
+            index += 1;
+            read_index.store(index, Ordering::Release);
+        }
+
+        let dropped_records = atomic(core::ptr::addr_of_mut!((*buffer).dropped_records)).load(Ordering::Acquire);
+
+        (drained, dropped_records)
+    }
+
+    /// Reports the logical processors whose guest makes no progress for `period_ms` milliseconds, optionally injecting an NMI
+    /// to trigger a crash dump, or disables the watchdog with a period of 0.
+    pub fn configure_watchdog(period_ms: u64, inject_nmi: bool) -> Option<()> {
+        log::debug!("Configuring watchdog with a period of {} ms, inject NMI: {}", period_ms, inject_nmi);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureWatchdog,
+            payload: ClientDataPayload::Watchdog(WatchdogOperation { period_ms, inject_nmi }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Watchdog configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure watchdog");
+            None
+        }
+    }
+
+    /// Sets how the guest's attempts to reset or shut down the system are handled: allowed, delayed or vetoed.
+    pub fn set_reset_policy(policy: ResetPolicy) -> Option<()> {
+        log::debug!("Setting reset policy to: {:?}", policy);
+
+        let client_command = ClientCommand {
+            command: Command::SetResetPolicy,
+            payload: ClientDataPayload::ResetPolicy(policy),
         };
 
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Reset policy set successfully");
+            Some(())
+        } else {
+            log::error!("Failed to set reset policy");
+            None
+        }
+    }
+
+    /// Enables the compensation of the guest TSC for the time spent in the hypervisor, optionally intercepting `RDTSC`
+    /// and scaling the guest TSC with `tsc_multiplier` (16.48 fixed point, `1 << 48` unscaled). The exit latency hidden
+    /// on each VM exit is `exit_latency_ticks`, or the latency calibrated at startup if `None`. Disables it if `enabled`
+    /// is false.
+    pub fn configure_tsc_compensation(enabled: bool, rdtsc_exiting: bool, tsc_multiplier: u64, exit_latency_ticks: Option<u64>) -> Option<()> {
+        log::debug!(
+            "Configuring TSC compensation, enabled: {}, RDTSC exiting: {}, multiplier: {:#x}, exit latency: {:?}",
+            enabled,
+            rdtsc_exiting,
+            tsc_multiplier,
+            exit_latency_ticks
+        );
+
         let client_command = ClientCommand {
-            command: Command::ReadProcessMemory,
-            payload: ClientDataPayload::Memory(memory_operation),
+            command: Command::ConfigureTscCompensation,
+            payload: ClientDataPayload::TscCompensation(TscCompensationOperation {
+                enabled,
+                rdtsc_exiting,
+                tsc_multiplier,
+                exit_latency_ticks,
+            }),
         };
 
         let result = Self::call_hypervisor(client_command.as_ptr());
 
         if result.eax == 1 {
-            log::debug!("Memory read successfully");
+            log::debug!("TSC compensation configured successfully");
             Some(())
         } else {
-            log::error!("Failed to read memory");
+            log::error!("Failed to configure TSC compensation");
+            None
+        }
+    }
+
+    /// Enables or disables the tracking of the process owning the current address space of each logical processor.
+    pub fn configure_process_tracking(enabled: bool) -> Option<()> {
+        log::debug!("Configuring process tracking, enabled: {}", enabled);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureProcessTracking,
+            payload: ClientDataPayload::ProcessTracking(ProcessTrackingOperation { enabled }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Process tracking configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure process tracking");
+            None
+        }
+    }
+
+    /// Sets the XSAVE state components the guest may not enable in XCR0 (e.g., `0x60000` for AMX), or allows all the
+    /// supported ones with 0. The policy applies to the next `XSETBV` of each logical processor.
+    pub fn configure_xsave_policy(denied_components: u64) -> Option<()> {
+        log::debug!("Configuring XSAVE policy, denied components: {:#x}", denied_components);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureXsavePolicy,
+            payload: ClientDataPayload::XsavePolicy(XsavePolicyOperation { denied_components }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("XSAVE policy configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure the XSAVE policy");
+            None
+        }
+    }
+
+    /// Sets a hardware breakpoint of the hypervisor in a debug register (0 to 3), hidden from the guest, or removes it
+    /// with address 0. The condition is 0 for execution, 1 for writes and 3 for reads and writes.
+    pub fn set_hardware_breakpoint(slot: u64, address: u64, condition: u64, length: u64) -> Option<()> {
+        log::debug!("Setting hardware breakpoint {}: {:#x}, condition: {}, length: {}", slot, address, condition, length);
+
+        let client_command = ClientCommand {
+            command: Command::SetHardwareBreakpoint,
+            payload: ClientDataPayload::HardwareBreakpoint(HardwareBreakpointOperation { slot, address, condition, length }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Hardware breakpoint set successfully");
+            Some(())
+        } else {
+            log::error!("Failed to set the hardware breakpoint");
+            None
+        }
+    }
+
+    /// Sets what an event ring of the hypervisor does with new events while it's full: drop them (the default), drop the
+    /// oldest events, or stall the logical processor recording them until the ring is drained, up to a timeout.
+    pub fn configure_event_ring(ring: EventRingId, policy: BackpressurePolicy) -> Option<()> {
+        log::debug!("Setting event ring {:?} backpressure policy to: {:?}", ring, policy);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureEventRing,
+            payload: ClientDataPayload::EventRing(EventRingOperation { ring, policy }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Event ring configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure the event ring");
+            None
+        }
+    }
+
+    /// Reads the statistics of an event ring since the hypervisor started: its fill level and high watermark, and the
+    /// events it recorded, dropped by policy and stalled for, with the stalls that timed out.
+    pub fn read_event_ring_stats(ring: EventRingId) -> Option<EventRingStats> {
+        log::debug!("Reading event ring {:?} statistics", ring);
+
+        let mut buffer = vec![0u8; core::mem::size_of::<EventRingStats>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadEventRingStats,
+            payload: ClientDataPayload::EventRingStats(EventRingStatsOperation {
+                ring,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read the event ring statistics");
+            return None;
+        }
+
+        let stats = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const EventRingStats) };
+
+        log::debug!("Event ring {:?} statistics: {:?}", ring, stats);
+        Some(stats)
+    }
+
+    /// Saves the hypervisor presence, the TSC compensation, the CPUID overrides and the boot-time hook manifest to the
+    /// configuration file on the ESP, re-applied at the next boot, or discards the saved configuration if `discard` is
+    /// set. The configuration file must have been set up at boot.
+    pub fn save_configuration(discard: bool) -> Option<()> {
+        log::debug!("Saving the configuration, discarded: {}", discard);
+
+        let client_command = ClientCommand {
+            command: Command::SaveConfiguration,
+            payload: ClientDataPayload::SaveConfiguration(SaveConfigurationOperation { discard }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Configuration saved successfully");
+            Some(())
+        } else {
+            log::error!("Failed to save the configuration");
+            None
+        }
+    }
+
+    /// Installs the hooks of the boot-time hook manifest, if it selects the agent hypercall trigger or if `force` is set.
+    pub fn trigger_boot_hooks(force: bool) -> Option<()> {
+        log::debug!("Triggering boot hooks, forced: {}", force);
+
+        let client_command = ClientCommand {
+            command: Command::TriggerBootHooks,
+            payload: ClientDataPayload::BootHook(BootHookOperation { force }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Boot hooks triggered successfully");
+            Some(())
+        } else {
+            log::error!("Failed to trigger boot hooks");
             None
         }
     }
 
+    /// Searches `start_address..end_address` of a process (4 for the kernel address space) for a string, or a regex-lite
+    /// pattern if `is_regex` is set, encoded as `encoding`, returning up to `max_matches` matches in address order.
+    ///
+    /// The hypervisor scans a bounded part of the range per call, so the search is resumed until the range is exhausted.
+    pub fn search_memory(process_id: u64, start_address: u64, end_address: u64, pattern: &str, is_regex: bool, case_insensitive: bool, encoding: SearchEncoding, max_matches: usize) -> Option<Vec<MemorySearchMatch>> {
+        log::debug!("Searching {:#x}-{:#x} of process {} for {:?}", start_address, end_address, process_id, pattern);
+
+        if pattern.len() > MAX_SEARCH_PATTERN_SIZE {
+            log::error!("The search pattern is longer than {} bytes", MAX_SEARCH_PATTERN_SIZE);
+            return None;
+        }
+
+        let mut search_pattern = [0; MAX_SEARCH_PATTERN_SIZE];
+        search_pattern[..pattern.len()].copy_from_slice(pattern.as_bytes());
+
+        let header_size = core::mem::size_of::<MemorySearchHeader>();
+        let mut matches = Vec::new();
+        let mut address = start_address;
+
+        while address < end_address && matches.len() < max_matches {
+            let mut buffer = vec![0u8; header_size + (max_matches - matches.len()) * core::mem::size_of::<MemorySearchMatch>()];
+
+            let client_command = ClientCommand {
+                command: Command::SearchMemory,
+                payload: ClientDataPayload::MemorySearch(MemorySearchOperation {
+                    process_id,
+                    start_address: address,
+                    end_address,
+                    pattern: search_pattern,
+                    pattern_size: pattern.len() as u64,
+                    is_regex,
+                    case_insensitive,
+                    encoding,
+                    buffer: buffer.as_mut_ptr() as u64,
+                    buffer_size: buffer.len() as u64,
+                }),
+            };
+
+            let result = Self::call_hypervisor(client_command.as_ptr());
+
+            if result.eax != 1 {
+                log::error!("Failed to search memory at {:#x}", address);
+                return None;
+            }
+
+            let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const MemorySearchHeader) };
+
+            for index in 0..header.match_count.min((max_matches - matches.len()) as u64) as usize {
+                matches.push(unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<MemorySearchMatch>().add(index)) });
+            }
+
+            if header.next_address <= address {
+                break;
+            }
+
+            address = header.next_address;
+        }
+
+        log::debug!("Found {} matches", matches.len());
+
+        Some(matches)
+    }
+
+    /// Scans `start_address..end_address` of the guest physical memory if `physical` is set, or of a process (4 for
+    /// the kernel address space) otherwise, for a byte pattern, `None` bytes matching any byte, returning the addresses
+    /// of up to `max_matches` matches in address order.
+    ///
+    /// The hypervisor scans a bounded part of the range per call, so the scan is resumed until the range is exhausted.
+    pub fn scan_signature(process_id: u64, physical: bool, start_address: u64, end_address: u64, signature: &[Option<u8>], max_matches: usize) -> Option<Vec<u64>> {
+        log::debug!("Scanning {:#x}-{:#x} for a signature of {} bytes", start_address, end_address, signature.len());
+
+        if signature.len() > MAX_SIGNATURE_SIZE {
+            log::error!("The signature is longer than {} bytes", MAX_SIGNATURE_SIZE);
+            return None;
+        }
+
+        let mut pattern = [0; MAX_SIGNATURE_SIZE];
+        let mut mask = [0; MAX_SIGNATURE_SIZE];
+
+        for (index, byte) in signature.iter().enumerate() {
+            if let Some(byte) = byte {
+                pattern[index] = *byte;
+                mask[index] = 0xFF;
+            }
+        }
+
+        let header_size = core::mem::size_of::<SignatureScanHeader>();
+        let mut matches = Vec::new();
+        let mut address = start_address;
+
+        while address < end_address && matches.len() < max_matches {
+            let mut buffer = vec![0u8; header_size + (max_matches - matches.len()) * core::mem::size_of::<u64>()];
+
+            let client_command = ClientCommand {
+                command: Command::ScanSignature,
+                payload: ClientDataPayload::SignatureScan(SignatureScanOperation {
+                    process_id,
+                    physical,
+                    start_address: address,
+                    end_address,
+                    pattern,
+                    mask,
+                    pattern_size: signature.len() as u64,
+                    buffer: buffer.as_mut_ptr() as u64,
+                    buffer_size: buffer.len() as u64,
+                }),
+            };
+
+            let result = Self::call_hypervisor(client_command.as_ptr());
+
+            if result.eax != 1 {
+                log::error!("Failed to scan memory at {:#x}", address);
+                return None;
+            }
+
+            let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const SignatureScanHeader) };
+
+            for index in 0..header.match_count.min((max_matches - matches.len()) as u64) as usize {
+                matches.push(unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<u64>().add(index)) });
+            }
+
+            if header.next_address <= address {
+                break;
+            }
+
+            address = header.next_address;
+        }
+
+        log::debug!("Found {} matches", matches.len());
+
+        Some(matches)
+    }
+
+    /// Reads up to `max_processes` processes of the guest, in the order of the list of processes, with the number of
+    /// processes in the list.
+    pub fn enumerate_processes(max_processes: usize) -> Option<(Vec<WindowsProcess>, u64)> {
+        log::debug!("Reading up to {} processes", max_processes);
+
+        let header_size = core::mem::size_of::<WindowsProcessHeader>();
+        let mut buffer = vec![0u8; header_size + max_processes * core::mem::size_of::<WindowsProcess>()];
+
+        let client_command = ClientCommand {
+            command: Command::EnumerateProcesses,
+            payload: ClientDataPayload::WindowsIntrospection(WindowsIntrospectionOperation {
+                process_id: 0,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to enumerate processes");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const WindowsProcessHeader) };
+        let processes = (0..header.process_count.min(max_processes as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<WindowsProcess>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} of {} processes", header.process_count, header.total_processes);
+        Some((processes, header.total_processes))
+    }
+
+    /// Reads up to `max_threads` threads of a process of the guest, in the order of the list of threads of the process,
+    /// with the number of threads of the process.
+    pub fn enumerate_threads(process_id: u64, max_threads: usize) -> Option<(Vec<WindowsThread>, u64)> {
+        log::debug!("Reading up to {} threads of process: {}", max_threads, process_id);
+
+        let header_size = core::mem::size_of::<WindowsThreadHeader>();
+        let mut buffer = vec![0u8; header_size + max_threads * core::mem::size_of::<WindowsThread>()];
+
+        let client_command = ClientCommand {
+            command: Command::EnumerateThreads,
+            payload: ClientDataPayload::WindowsIntrospection(WindowsIntrospectionOperation {
+                process_id,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to enumerate threads of process: {}", process_id);
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const WindowsThreadHeader) };
+        let threads = (0..header.thread_count.min(max_threads as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<WindowsThread>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} of {} threads", header.thread_count, header.total_threads);
+        Some((threads, header.total_threads))
+    }
+
+    /// Reads up to `max_vads` VADs of a process of the guest, the ranges of its virtual address space, in address order,
+    /// with the number of VADs of the process.
+    pub fn enumerate_vads(process_id: u64, max_vads: usize) -> Option<(Vec<WindowsVad>, u64)> {
+        log::debug!("Reading up to {} VADs of process: {}", max_vads, process_id);
+
+        let header_size = core::mem::size_of::<WindowsVadHeader>();
+        let mut buffer = vec![0u8; header_size + max_vads * core::mem::size_of::<WindowsVad>()];
+
+        let client_command = ClientCommand {
+            command: Command::EnumerateVads,
+            payload: ClientDataPayload::WindowsIntrospection(WindowsIntrospectionOperation {
+                process_id,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to enumerate VADs of process: {}", process_id);
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const WindowsVadHeader) };
+        let vads = (0..header.vad_count.min(max_vads as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<WindowsVad>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} of {} VADs", header.vad_count, header.total_vads);
+        Some((vads, header.total_vads))
+    }
+
+    /// Dumps the private executable memory of the opened process, e.g., shellcode or unpacked code, as the VADs of the
+    /// process, up to `max_vads` of them, with their content, the pages that can't be read being skipped.
+    pub fn dump_private_executable_memory(&self, process_id: u64, max_vads: usize) -> Option<Vec<(WindowsVad, Vec<u8>)>> {
+        let (vads, _) = Self::enumerate_vads(process_id, max_vads)?;
+
+        let dumps: Vec<(WindowsVad, Vec<u8>)> = vads
+            .into_iter()
+            .filter(|vad| vad.private_memory != 0 && vad.is_executable())
+            .map(|vad| {
+                let mut content = vec![0u8; vad.size() as usize];
+
+                for (index, page) in content.chunks_mut(0x1000).enumerate() {
+                    if self.read_process_memory(vad.start_address + (index * 0x1000) as u64, page).is_none() {
+                        log::warn!("Failed to read page {:#x}", vad.start_address + (index * 0x1000) as u64);
+                    }
+                }
+
+                (vad, content)
+            })
+            .collect();
+
+        log::debug!("Dumped {} private executable VADs", dumps.len());
+        Some(dumps)
+    }
+
+    /// Dumps `start_address..end_address` of a process by the hypervisor, optionally only its private or executable
+    /// VADs, returning the chunks of mapped memory in address order, or writing them to the serial port of the machine
+    /// if `serial` is set.
+    ///
+    /// The hypervisor dumps a bounded part of the range per call, so the dump is resumed until the range is exhausted.
+    pub fn dump_process(process_id: u64, start_address: u64, end_address: u64, private_only: bool, executable_only: bool, serial: bool) -> Option<Vec<(u64, Vec<u8>)>> {
+        log::debug!("Dumping {:#x}-{:#x} of process {}", start_address, end_address, process_id);
+
+        let header_size = core::mem::size_of::<ProcessDumpHeader>();
+        let chunk_size = core::mem::size_of::<ProcessDumpChunk>();
+
+        // A chunk header per page at most.
+        let buffer_size = header_size + MAX_PROCESS_DUMP_SIZE as usize + (MAX_PROCESS_DUMP_SIZE as usize / 0x1000) * chunk_size;
+
+        let mut chunks = Vec::new();
+        let mut buffer = vec![0u8; buffer_size];
+        let mut address = start_address;
+
+        while address < end_address {
+            let client_command = ClientCommand {
+                command: Command::DumpProcessMemory,
+                payload: ClientDataPayload::ProcessDump(ProcessDumpOperation {
+                    process_id,
+                    start_address: address,
+                    end_address,
+                    private_only,
+                    executable_only,
+                    serial,
+                    buffer: buffer.as_mut_ptr() as u64,
+                    buffer_size: buffer.len() as u64,
+                }),
+            };
+
+            let result = Self::call_hypervisor(client_command.as_ptr());
+
+            if result.eax != 1 {
+                log::error!("Failed to dump process memory at {:#x}", address);
+                return None;
+            }
+
+            let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const ProcessDumpHeader) };
+            let data = &buffer[header_size..header_size + (header.data_size as usize).min(buffer_size - header_size)];
+            let mut offset = 0;
+
+            while offset + chunk_size <= data.len() {
+                let chunk = unsafe { core::ptr::read_unaligned(data[offset..].as_ptr() as *const ProcessDumpChunk) };
+                let bytes = data.get(offset + chunk_size..offset + chunk_size + chunk.size as usize)?;
+
+                chunks.push((chunk.address, bytes.to_vec()));
+                offset += chunk_size + chunk.size as usize;
+            }
+
+            if header.next_address <= address {
+                break;
+            }
+
+            address = header.next_address;
+        }
+
+        log::debug!("Dumped {} chunks", chunks.len());
+
+        Some(chunks)
+    }
+
+    /// Reads up to `max_callbacks` notification callbacks registered in the kernel of the guest, the notify routines
+    /// first, then the registry and the handle callbacks, with the number of callbacks registered.
+    pub fn enumerate_kernel_callbacks(max_callbacks: usize) -> Option<(Vec<KernelCallback>, u64)> {
+        log::debug!("Reading up to {} kernel callbacks", max_callbacks);
+
+        let header_size = core::mem::size_of::<KernelCallbackHeader>();
+        let mut buffer = vec![0u8; header_size + max_callbacks * core::mem::size_of::<KernelCallback>()];
+
+        let client_command = ClientCommand {
+            command: Command::EnumerateKernelCallbacks,
+            payload: ClientDataPayload::WindowsIntrospection(WindowsIntrospectionOperation {
+                process_id: 0,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to enumerate kernel callbacks");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const KernelCallbackHeader) };
+        let callbacks = (0..header.callback_count.min(max_callbacks as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<KernelCallback>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} of {} kernel callbacks", header.callback_count, header.total_callbacks);
+        Some((callbacks, header.total_callbacks))
+    }
+
+    /// Hooks `PspInsertProcess` and `PsCallImageNotifyRoutines` at their RVAs in ntoskrnl.exe, e.g., resolved from its
+    /// symbols, to push the `ProcessCreated` and `ImageLoaded` events to the event stream, an RVA of 0 not hooking the
+    /// function and removing its previous hook.
+    pub fn configure_os_events(process_insert_rva: u64, image_notify_rva: u64) -> Option<()> {
+        log::debug!("Configuring OS events, PspInsertProcess RVA: {:#x}, PsCallImageNotifyRoutines RVA: {:#x}", process_insert_rva, image_notify_rva);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureOsEvents,
+            payload: ClientDataPayload::OsEvents(OsEventsOperation {
+                process_insert_rva,
+                image_notify_rva,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("OS events configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure OS events");
+            None
+        }
+    }
+
+    /// Hashes the code pages of ntoskrnl.exe and of up to `MAX_CODE_INTEGRITY_DRIVERS` drivers, e.g., "ci.dll", and
+    /// verifies them every `period_ms` milliseconds, pushing a `CodeIntegrityViolation` event to the event stream for
+    /// each modified page, a period of 0 stopping the monitor.
+    pub fn configure_code_integrity(period_ms: u64, drivers: &[&str]) -> Option<()> {
+        log::debug!("Configuring code integrity, every {} ms, drivers: {:?}", period_ms, drivers);
+
+        if drivers.len() > MAX_CODE_INTEGRITY_DRIVERS {
+            log::error!("More than {} drivers", MAX_CODE_INTEGRITY_DRIVERS);
+            return None;
+        }
+
+        let mut driver_names = [[0; CODE_INTEGRITY_DRIVER_NAME_SIZE]; MAX_CODE_INTEGRITY_DRIVERS];
+
+        for (driver_name, driver) in driver_names.iter_mut().zip(drivers) {
+            if driver.len() >= CODE_INTEGRITY_DRIVER_NAME_SIZE {
+                log::error!("The driver name {} is longer than {} bytes", driver, CODE_INTEGRITY_DRIVER_NAME_SIZE - 1);
+                return None;
+            }
+
+            driver_name[..driver.len()].copy_from_slice(driver.as_bytes());
+        }
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureCodeIntegrity,
+            payload: ClientDataPayload::CodeIntegrity(CodeIntegrityOperation { period_ms, drivers: driver_names }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Code integrity configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure code integrity");
+            None
+        }
+    }
+
+    /// Protects `address..address + size` of a process from the reads and/or writes of the other processes, the denied
+    /// accesses getting a page fault or a decoy page as `action` says and being pushed to the event stream as
+    /// `ProtectedMemoryAccess` events. `allow_kernel_access` lets the kernel access the range in the address space of
+    /// the process, e.g., in its system calls.
+    pub fn protect_memory(process_id: u64, address: u64, size: u64, deny_reads: bool, deny_writes: bool, allow_kernel_access: bool, action: ProtectedMemoryAction) -> Option<()> {
+        log::debug!("Protecting {:#x} ({:#x} bytes) of process {}, reads denied: {}, writes denied: {}, {:?}", address, size, process_id, deny_reads, deny_writes, action);
+
+        let client_command = ClientCommand {
+            command: Command::ProtectMemory,
+            payload: ClientDataPayload::ProtectedMemory(ProtectedMemoryOperation { process_id, address, size, deny_reads, deny_writes, allow_kernel_access, action }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Memory protected successfully");
+            Some(())
+        } else {
+            log::error!("Failed to protect memory");
+            None
+        }
+    }
+
+    /// Removes the protected range of a process at `address`, or all of them if `address` is 0.
+    pub fn unprotect_memory(process_id: u64, address: u64) -> Option<()> {
+        log::debug!("Unprotecting {:#x} of process {}", address, process_id);
+
+        let client_command = ClientCommand {
+            command: Command::UnprotectMemory,
+            payload: ClientDataPayload::ProtectedMemory(ProtectedMemoryOperation {
+                process_id,
+                address,
+                size: 0,
+                deny_reads: false,
+                deny_writes: false,
+                allow_kernel_access: false,
+                action: ProtectedMemoryAction::PageFault,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Memory unprotected successfully");
+            Some(())
+        } else {
+            log::error!("Failed to unprotect memory");
+            None
+        }
+    }
+
+    /// Duplicates, collapses or switches an EPT view, or sets or resets a divergent page of an alternate view.
+    /// View 0 is the primary EPT, and a view duplicated from it is the clean identity map.
+    pub fn configure_ept_view(operation: EptViewOperation) -> Option<()> {
+        log::debug!("Configuring EPT view: {:?}", operation);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureEptView,
+            payload: ClientDataPayload::EptView(operation),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("EPT view configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure EPT view");
+            None
+        }
+    }
+
+    /// Unloads the hypervisor, or cancels the unloading with `DevirtualizeOperation::Cancel`.
+    /// Each logical processor leaves VMX operation on a later VM exit, once the guest can be resumed natively.
+    pub fn devirtualize(operation: DevirtualizeOperation) -> Option<()> {
+        log::debug!("Devirtualizing: {:?}", operation);
+
+        let client_command = ClientCommand {
+            command: Command::Devirtualize,
+            payload: ClientDataPayload::Devirtualize(operation),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Devirtualization requested successfully");
+            Some(())
+        } else {
+            log::error!("Failed to request devirtualization");
+            None
+        }
+    }
+
+    /// Broadcasts a command to all the logical processors, e.g., to flush the EPT or to pause the other ones.
+    /// Fails if a logical processor didn't execute it in time, though it still does on a later VM exit.
+    pub fn broadcast(command: BroadcastCommand) -> Option<()> {
+        log::debug!("Broadcasting: {:?}", command);
+
+        let client_command = ClientCommand {
+            command: Command::Broadcast,
+            payload: ClientDataPayload::Broadcast(command),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Command broadcast successfully");
+            Some(())
+        } else {
+            log::error!("Failed to broadcast the command");
+            None
+        }
+    }
+
+    /// Reads the VM exits of each logical processor by basic exit reason, with the shortest, total and longest latency
+    /// of their handler in TSC ticks. With `reset`, the next read covers the VM exits since this one, and with `log`,
+    /// the hypervisor also logs the statistics of all the logical processors.
+    pub fn read_exit_statistics(reset: bool, log: bool) -> Option<(ExitStatisticsHeader, Vec<ExitStatisticsRecord>)> {
+        log::debug!("Reading VM exit statistics");
+
+        let header_size = core::mem::size_of::<ExitStatisticsHeader>();
+        let mut buffer = vec![0u8; header_size + MAX_EXIT_STATISTICS_RECORDS * core::mem::size_of::<ExitStatisticsRecord>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadExitStatistics,
+            payload: ClientDataPayload::ExitStatistics(ExitStatisticsOperation {
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+                reset,
+                log,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read VM exit statistics");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const ExitStatisticsHeader) };
+        let records = (0..header.record_count.min(MAX_EXIT_STATISTICS_RECORDS as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<ExitStatisticsRecord>().add(index)) })
+            .collect();
+
+        log::debug!("Read VM exit statistics of {} processors", header.reported_processors);
+        Some((header, records))
+    }
+
+    /// Loads the exports of a module mapped in the guest, so the addresses of the module resolve to them.
+    ///
+    /// `process_id` is the ID of the process of a user-mode module, or 0 for a kernel module.
+    pub fn load_module_symbols(process_id: u64, base_address: u64) -> Option<()> {
+        log::debug!("Loading the symbols of {:#x} for process {}", base_address, process_id);
+
+        let client_command = ClientCommand {
+            command: Command::LoadModuleSymbols,
+            payload: ClientDataPayload::Symbol(SymbolOperation {
+                process_id,
+                base_address,
+                addresses: [0; MAX_SYMBOL_ADDRESSES],
+                address_count: 0,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Module symbols loaded successfully");
+            Some(())
+        } else {
+            log::error!("Failed to load module symbols");
+            None
+        }
+    }
+
+    /// Resolves guest addresses seen in a process to `module!export+0x23`-style strings, `module+0x1234` before the
+    /// first export of a module, or the raw address outside the loaded modules.
+    pub fn resolve_symbols(process_id: u64, addresses: &[u64]) -> Option<Vec<String>> {
+        log::debug!("Resolving {} addresses for process {}", addresses.len(), process_id);
+
+        let header_size = core::mem::size_of::<SymbolHeader>();
+        let mut symbols = Vec::with_capacity(addresses.len());
+
+        for chunk in addresses.chunks(MAX_SYMBOL_ADDRESSES) {
+            let mut buffer = vec![0u8; header_size + chunk.len() * core::mem::size_of::<ResolvedSymbol>()];
+            let mut chunk_addresses = [0; MAX_SYMBOL_ADDRESSES];
+            chunk_addresses[..chunk.len()].copy_from_slice(chunk);
+
+            let client_command = ClientCommand {
+                command: Command::ResolveSymbols,
+                payload: ClientDataPayload::Symbol(SymbolOperation {
+                    process_id,
+                    base_address: 0,
+                    addresses: chunk_addresses,
+                    address_count: chunk.len() as u64,
+                    buffer: buffer.as_mut_ptr() as u64,
+                    buffer_size: buffer.len() as u64,
+                }),
+            };
+
+            let result = Self::call_hypervisor(client_command.as_ptr());
+
+            if result.eax != 1 {
+                log::error!("Failed to resolve symbols");
+                return None;
+            }
+
+            let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const SymbolHeader) };
+
+            for index in 0..header.symbol_count.min(chunk.len() as u64) as usize {
+                let symbol = unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<ResolvedSymbol>().add(index)) };
+                symbols.push(Self::format_symbol(&symbol));
+            }
+        }
+
+        Some(symbols)
+    }
+
+    /// Formats a resolved symbol as `module!export+0x23`, `module+0x1234` or the raw address.
+    fn format_symbol(symbol: &ResolvedSymbol) -> String {
+        let name = |bytes: &[u8]| {
+            let length = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..length]).into_owned()
+        };
+
+        match (symbol.module_base, name(&symbol.export_name)) {
+            (0, _) => format!("{:#x}", symbol.address),
+            (_, export_name) if export_name.is_empty() => format!("{}+{:#x}", name(&symbol.module_name), symbol.offset),
+            (_, export_name) => format!("{}!{}+{:#x}", name(&symbol.module_name), export_name, symbol.offset),
+        }
+    }
+
+    /// Enables the deterministic mode, in which the TSC observed by the guest starts at `start_tsc` (or the current TSC
+    /// if 0) and advances by `tsc_ticks_per_read` on each read, and `RDRAND`/`RDSEED` return values derived from `seed`.
+    /// Disables it if `enabled` is false.
+    pub fn configure_deterministic_mode(enabled: bool, seed: u64, start_tsc: u64, tsc_ticks_per_read: u64) -> Option<()> {
+        log::debug!("Configuring deterministic mode, enabled: {}, seed: {:#x}", enabled, seed);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureDeterministicMode,
+            payload: ClientDataPayload::Determinism(DeterminismOperation {
+                enabled,
+                seed,
+                start_tsc,
+                tsc_ticks_per_read,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Deterministic mode configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure deterministic mode");
+            None
+        }
+    }
+
+    /// Enables or disables a hooked function in an alternate hook view, binds a hooked function to a process, or assigns a view to a process or a logical processor.
+    /// Functions are identified by the hash of their name, or their syscall number if they aren't exported.
+    pub fn configure_hook_view(operation: HookViewOperation) -> Option<()> {
+        log::debug!("Configuring hook view: {:?}", operation);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureHookView,
+            payload: ClientDataPayload::HookView(operation),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Hook view configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure hook view");
+            None
+        }
+    }
+
+    /// Reads memory from the opened process using the stored CR3.
+    pub fn read_process_memory(&self, address: u64, buffer: &mut [u8]) -> Option<()> {
+        log::debug!("Reading memory from address: {:#x}", address);
+
+        let memory_operation = ProcessMemoryOperation {
+            process_id: None,
+            guest_cr3: Some(self.process_cr3),
+            address: Some(address),
+            buffer: buffer.as_ptr() as u64,
+            buffer_size: buffer.len() as u64,
+        };
+
+        let client_command = ClientCommand {
+            command: Command::ReadProcessMemory,
+            payload: ClientDataPayload::Memory(memory_operation),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Memory read successfully");
+            Some(())
+        } else {
+            log::error!("Failed to read memory");
+            None
+        }
+    }
+
+    /// Starts reading memory from the opened process in the background, without stalling the processor for large buffers.
+    ///
+    /// The shared page must be mapped with `map_shared_page` on the same logical processor, and the buffer must be locked
+    /// in memory until `transfer_progress` reports that the transfer is no longer in progress or `cancel_async_read` is called.
+    pub fn read_process_memory_async(&self, address: u64, buffer: &mut [u8]) -> Option<()> {
+        log::debug!("Starting asynchronous read from address: {:#x}", address);
+
+        let memory_operation = ProcessMemoryOperation {
+            process_id: None,
+            guest_cr3: Some(self.process_cr3),
+            address: Some(address),
+            buffer: buffer.as_ptr() as u64,
+            buffer_size: buffer.len() as u64,
+        };
+
+        let client_command = ClientCommand {
+            command: Command::StartAsyncRead,
+            payload: ClientDataPayload::Memory(memory_operation),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Asynchronous read started successfully");
+            Some(())
+        } else {
+            log::error!("Failed to start asynchronous read");
+            None
+        }
+    }
+
+    /// Cancels the asynchronous read started by `read_process_memory_async` on the current logical processor.
+    pub fn cancel_async_read() -> Option<()> {
+        log::debug!("Cancelling asynchronous read");
+
+        let client_command = ClientCommand {
+            command: Command::CancelAsyncRead,
+            payload: ClientDataPayload::Memory(ProcessMemoryOperation {
+                process_id: None,
+                guest_cr3: None,
+                address: None,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Asynchronous read cancelled successfully");
+            Some(())
+        } else {
+            log::error!("Failed to cancel asynchronous read");
+            None
+        }
+    }
+
+    /// Returns the latest progress of the asynchronous read published by the hypervisor to the shared page, if any.
+    pub fn transfer_progress(shared_page: *const SharedPage) -> Option<TransferProgress> {
+        let shared_page = unsafe { &*shared_page };
+
+        if unsafe { core::ptr::read_volatile(&shared_page.host_to_guest_sequence) } == 0
+            || shared_page.host_to_guest_length != core::mem::size_of::<TransferProgress>() as u64
+        {
+            return None;
+        }
+
+        Some(unsafe { core::ptr::read_unaligned(shared_page.host_to_guest_buffer.as_ptr() as *const TransferProgress) })
+    }
+
+    /// Starts sampling the guest RIP and the first `stack_depth` slots of the stack on all logical processors, `frequency_hz` times per second.
+    ///
+    /// The samples of a previous run that haven't been read with `read_profile` are discarded.
+    pub fn start_profiling(frequency_hz: u64, stack_depth: usize) -> Option<()> {
+        log::debug!("Starting profiler at {} Hz with a stack depth of {}", frequency_hz, stack_depth);
+
+        let client_command = ClientCommand {
+            command: Command::StartProfiling,
+            payload: ClientDataPayload::Profiler(ProfilerOperation {
+                frequency_hz,
+                stack_depth: stack_depth as u64,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Profiler started successfully");
+            Some(())
+        } else {
+            log::error!("Failed to start profiler");
+            None
+        }
+    }
+
+    /// Stops the sampling started by `start_profiling`, keeping the samples that haven't been read yet.
+    pub fn stop_profiling() -> Option<()> {
+        log::debug!("Stopping profiler");
+
+        let client_command = ClientCommand {
+            command: Command::StopProfiling,
+            payload: ClientDataPayload::Profiler(ProfilerOperation {
+                frequency_hz: 0,
+                stack_depth: 0,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Profiler stopped successfully");
+            Some(())
+        } else {
+            log::error!("Failed to stop profiler");
+            None
+        }
+    }
+
+    /// Moves up to `max_samples` of the oldest samples out of the hypervisor, returned with the number of samples dropped since the last read.
+    pub fn read_profile(max_samples: usize) -> Option<(Vec<ProfileSample>, u64)> {
+        log::debug!("Reading up to {} profile samples", max_samples);
+
+        let header_size = core::mem::size_of::<ProfileHeader>();
+        let mut buffer = vec![0u8; header_size + max_samples * core::mem::size_of::<ProfileSample>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadProfile,
+            payload: ClientDataPayload::Profiler(ProfilerOperation {
+                frequency_hz: 0,
+                stack_depth: 0,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read profile");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const ProfileHeader) };
+        let samples = (0..header.sample_count.min(max_samples as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<ProfileSample>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} profile samples, {} dropped", header.sample_count, header.dropped_samples);
+        Some((samples, header.dropped_samples))
+    }
+
+    /// Reads the summary of the VM exits of the benchmark run: the run and its measurement window, and the VM exits of
+    /// each basic exit reason with their handling time in TSC ticks. The summary is complete once `is_complete` is set.
+    pub fn read_benchmark() -> Option<(BenchmarkHeader, Vec<BenchmarkExitReason>)> {
+        log::debug!("Reading benchmark summary");
+
+        let header_size = core::mem::size_of::<BenchmarkHeader>();
+        let mut buffer = vec![0u8; header_size + MAX_BENCHMARK_EXIT_REASONS * core::mem::size_of::<BenchmarkExitReason>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadBenchmark,
+            payload: ClientDataPayload::Benchmark(BenchmarkOperation {
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read benchmark summary, no benchmark run may be armed");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const BenchmarkHeader) };
+        let exit_reasons = (0..header.exit_reason_count.min(MAX_BENCHMARK_EXIT_REASONS as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<BenchmarkExitReason>().add(index)) })
+            .collect();
+
+        log::debug!("Read benchmark summary of run {}/{}", header.run_index + 1, header.run_count);
+        Some((header, exit_reasons))
+    }
+
+    /// Starts recording the system calls selected by `filter_mode` and the names of up to `MAX_SYSCALL_TRACE_FILTER`
+    /// functions (e.g., "NtOpenProcess"), with their arguments and return values. The records of a previous run are discarded.
+    pub fn start_syscall_trace(filter_mode: SyscallTraceFilterMode, function_names: &[&str]) -> Option<()> {
+        if function_names.len() > MAX_SYSCALL_TRACE_FILTER {
+            log::error!("Too many functions in the syscall trace filter: {}", function_names.len());
+            return None;
+        }
+
+        let mut syscall = Syscall::new();
+        let mut syscall_numbers = [0u32; MAX_SYSCALL_TRACE_FILTER];
+        for (syscall_number, function_name) in syscall_numbers.iter_mut().zip(function_names) {
+            *syscall_number = syscall.get_ssn_by_hash(djb2_hash(function_name.as_bytes()))? as u32;
+            log::debug!("Function: {} Syscall number: {}", function_name, syscall_number);
+        }
+
+        let client_command = ClientCommand {
+            command: Command::StartSyscallTrace,
+            payload: ClientDataPayload::SyscallTrace(SyscallTraceOperation {
+                filter_mode,
+                syscall_count: function_names.len() as u64,
+                syscall_numbers,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Syscall trace started successfully");
+            Some(())
+        } else {
+            log::error!("Failed to start syscall trace");
+            None
+        }
+    }
+
+    /// Stops recording the system calls, keeping the records so far.
+    pub fn stop_syscall_trace() -> Option<()> {
+        log::debug!("Stopping syscall trace");
+
+        let client_command = ClientCommand {
+            command: Command::StopSyscallTrace,
+            payload: ClientDataPayload::SyscallTrace(SyscallTraceOperation {
+                filter_mode: SyscallTraceFilterMode::All,
+                syscall_count: 0,
+                syscall_numbers: [0; MAX_SYSCALL_TRACE_FILTER],
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Syscall trace stopped successfully");
+            Some(())
+        } else {
+            log::error!("Failed to stop syscall trace");
+            None
+        }
+    }
+
+    /// Moves up to `max_records` of the oldest system call records out of the hypervisor, returned with the number of records dropped since the last read.
+    pub fn read_syscall_trace(max_records: usize) -> Option<(Vec<SyscallTraceRecord>, u64)> {
+        log::debug!("Reading up to {} syscall trace records", max_records);
+
+        let header_size = core::mem::size_of::<SyscallTraceHeader>();
+        let mut buffer = vec![0u8; header_size + max_records * core::mem::size_of::<SyscallTraceRecord>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadSyscallTrace,
+            payload: ClientDataPayload::SyscallTrace(SyscallTraceOperation {
+                filter_mode: SyscallTraceFilterMode::All,
+                syscall_count: 0,
+                syscall_numbers: [0; MAX_SYSCALL_TRACE_FILTER],
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read syscall trace");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const SyscallTraceHeader) };
+        let records = (0..header.record_count.min(max_records as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<SyscallTraceRecord>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} syscall trace records, {} dropped", header.record_count, header.dropped_records);
+        Some((records, header.dropped_records))
+    }
+
+    /// Enables the recording of the selected exceptions of the whole guest, at most `max_events_per_second` per second,
+    /// discarding the events of a previous run. Disables it if no exception is selected.
+    pub fn configure_exception_telemetry(
+        page_faults: bool,
+        user_mode_page_faults_only: bool,
+        general_protection_faults: bool,
+        invalid_opcodes: bool,
+        max_events_per_second: u64,
+    ) -> Option<()> {
+        log::debug!(
+            "Configuring exception telemetry, #PF: {} (user mode only: {}), #GP: {}, #UD: {}",
+            page_faults,
+            user_mode_page_faults_only,
+            general_protection_faults,
+            invalid_opcodes
+        );
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureExceptionTelemetry,
+            payload: ClientDataPayload::ExceptionTelemetry(ExceptionTelemetryOperation {
+                page_faults,
+                user_mode_page_faults_only,
+                general_protection_faults,
+                invalid_opcodes,
+                max_events_per_second,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Exception telemetry configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure exception telemetry");
+            None
+        }
+    }
+
+    /// Moves up to `max_events` of the oldest exception events out of the hypervisor, returned with the numbers of
+    /// events dropped and suppressed by the rate limit since the last read.
+    pub fn read_exception_telemetry(max_events: usize) -> Option<(Vec<ExceptionEvent>, u64, u64)> {
+        log::debug!("Reading up to {} exception events", max_events);
+
+        let header_size = core::mem::size_of::<ExceptionTelemetryHeader>();
+        let mut buffer = vec![0u8; header_size + max_events * core::mem::size_of::<ExceptionEvent>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadExceptionTelemetry,
+            payload: ClientDataPayload::ExceptionTelemetry(ExceptionTelemetryOperation {
+                page_faults: false,
+                user_mode_page_faults_only: false,
+                general_protection_faults: false,
+                invalid_opcodes: false,
+                max_events_per_second: 0,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read exception telemetry");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const ExceptionTelemetryHeader) };
+        let events = (0..header.event_count.min(max_events as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<ExceptionEvent>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} exception events, {} dropped, {} suppressed", header.event_count, header.dropped_events, header.suppressed_events);
+        Some((events, header.dropped_events, header.suppressed_events))
+    }
+
+    /// Swaps the MSR bitmap profile defining which MSR accesses that aren't hooked cause VM exits on all the logical
+    /// processors, e.g., to switch between the quiet and the intercept-everything profiles.
+    pub fn configure_msr_bitmap(operation: MsrBitmapOperation) -> Option<()> {
+        log::debug!("Configuring MSR bitmap: {:x?}", operation);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureMsrBitmap,
+            payload: ClientDataPayload::MsrBitmap(operation),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("MSR bitmap configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure MSR bitmap");
+            None
+        }
+    }
+
+    /// Adds a rule deciding how the reads or the writes of an MSR made from a guest context (CPL, address space, range
+    /// of the accessing code) are handled: passed through to the hardware, handled by the hook of the MSR or rejected
+    /// with a #GP, e.g., to pass the reads of IA32_LSTAR by the kernel's early-boot code through, or clears the rules.
+    pub fn configure_msr_context_rule(operation: MsrContextRuleOperation) -> Option<()> {
+        log::debug!("Configuring MSR context rule: {:x?}", operation);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureMsrContextRule,
+            payload: ClientDataPayload::MsrContextRule(operation),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("MSR context rules configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure the MSR context rules");
+            None
+        }
+    }
+
+    /// Runs the corpus of public hypervisor-detection techniques against the hypervisor: the CPUID results and timing
+    /// are probed from this process, as a user-mode detector does, and the hypervisor evaluates every technique from
+    /// them and from what the kernel reads, returning the pass/fail matrix in `DetectionTechnique` order.
+    pub fn run_detection_corpus() -> Option<(DetectionCorpusHeader, Vec<DetectionCheckResult>)> {
+        let probes = Self::probe_detection_techniques();
+        log::debug!("Running detection corpus with probes: {:x?}", probes);
+
+        let header_size = core::mem::size_of::<DetectionCorpusHeader>();
+        let mut buffer = vec![0u8; header_size + DETECTION_TECHNIQUE_COUNT * core::mem::size_of::<DetectionCheckResult>()];
+
+        let client_command = ClientCommand {
+            command: Command::RunDetectionCorpus,
+            payload: ClientDataPayload::DetectionCorpus(DetectionCorpusOperation {
+                probes,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to run the detection corpus");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const DetectionCorpusHeader) };
+        let results: Vec<DetectionCheckResult> = (0..header.check_count.min(DETECTION_TECHNIQUE_COUNT as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<DetectionCheckResult>().add(index)) })
+            .collect();
+
+        for check in &results {
+            log::info!(
+                "{:?}: {:?} (observed {:#x}, expected {:#x})",
+                DetectionTechnique::from_u64(check.technique),
+                DetectionVerdict::from_u64(check.verdict),
+                check.observed,
+                check.expected
+            );
+        }
+
+        log::info!("Detection corpus: {} passed, {} failed, {} skipped", header.passed, header.failed, header.skipped);
+        Some((header, results))
+    }
+
+    /// Probes the CPUID leaves read by the detection techniques, and the shortest duration of CPUID between two RDTSC.
+    fn probe_detection_techniques() -> DetectionProbes {
+        const TIMING_ITERATIONS: usize = 100;
+
+        let cpuid_leaf = |leaf: u32| {
+            let result = cpuid!(leaf, 0);
+            [result.eax, result.ebx, result.ecx, result.edx]
+        };
+
+        let max_basic_leaf = cpuid_leaf(0)[0];
+
+        let cpuid_tsc_ticks = (0..TIMING_ITERATIONS)
+            .map(|_| unsafe {
+                let start_tsc = rdtsc();
+                cpuid!(0);
+                rdtsc().wrapping_sub(start_tsc)
+            })
+            .min()
+            .unwrap_or(0);
+
+        DetectionProbes {
+            cpuid_feature_information: cpuid_leaf(1),
+            cpuid_hypervisor_leaf: cpuid_leaf(0x40000000),
+            cpuid_max_basic_leaf: cpuid_leaf(max_basic_leaf),
+            cpuid_tsc_ticks,
+        }
+    }
+
+    /// Write-protects the pages backing `size` bytes at `base_address` in a process, e.g., its main image, so the pages
+    /// it writes and then executes are dumped by the hypervisor.
+    pub fn start_unpacker(process_id: u64, base_address: u64, size: u64) -> Option<()> {
+        log::debug!("Starting unpacker for process {}: {:#x} ({:#x} bytes)", process_id, base_address, size);
+
+        let client_command = ClientCommand {
+            command: Command::StartUnpacker,
+            payload: ClientDataPayload::Unpacker(UnpackerOperation {
+                process_id,
+                base_address,
+                size,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Unpacker started successfully");
+            Some(())
+        } else {
+            log::error!("Failed to start unpacker");
+            None
+        }
+    }
+
+    /// Stops watching the range, keeping the pages dumped so far.
+    pub fn stop_unpacker() -> Option<()> {
+        log::debug!("Stopping unpacker");
+
+        let client_command = ClientCommand {
+            command: Command::StopUnpacker,
+            payload: ClientDataPayload::Unpacker(UnpackerOperation {
+                process_id: 0,
+                base_address: 0,
+                size: 0,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Unpacker stopped successfully");
+            Some(())
+        } else {
+            log::error!("Failed to stop unpacker");
+            None
+        }
+    }
+
+    /// Moves up to `max_dumps` of the pages dumped so far, oldest first, returning them with the number of pages dropped
+    /// because the buffer of the hypervisor was full and the number of pages still watched.
+    pub fn read_unpacker_dumps(max_dumps: usize) -> Option<(Vec<UnpackedPage>, u64, u64)> {
+        log::debug!("Reading up to {} unpacked pages", max_dumps);
+
+        let header_size = core::mem::size_of::<UnpackerDumpHeader>();
+        let mut buffer = vec![0u8; header_size + max_dumps * core::mem::size_of::<UnpackedPage>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadUnpackerDumps,
+            payload: ClientDataPayload::Unpacker(UnpackerOperation {
+                process_id: 0,
+                base_address: 0,
+                size: 0,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read unpacked pages");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const UnpackerDumpHeader) };
+        let dumps = (0..header.dump_count.min(max_dumps as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<UnpackedPage>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} unpacked pages, {} dropped, {} pages watched", header.dump_count, header.dropped_dumps, header.watched_pages);
+        Some((dumps, header.dropped_dumps, header.watched_pages))
+    }
+
+    /// Traces the instructions executed in `size` bytes at `base_address` in a process, single-stepping them while RIP
+    /// is in the range, with the general-purpose registers if `capture_registers` is set.
+    pub fn start_execution_trace(process_id: u64, base_address: u64, size: u64, capture_registers: bool) -> Option<()> {
+        log::debug!("Starting execution trace for process {}: {:#x} ({:#x} bytes)", process_id, base_address, size);
+
+        let client_command = ClientCommand {
+            command: Command::StartExecutionTrace,
+            payload: ClientDataPayload::ExecutionTrace(ExecutionTraceOperation {
+                process_id,
+                base_address,
+                size,
+                capture_registers,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Execution trace started successfully");
+            Some(())
+        } else {
+            log::error!("Failed to start execution trace");
+            None
+        }
+    }
+
+    /// Stops tracing the range, keeping the instructions recorded so far.
+    pub fn stop_execution_trace() -> Option<()> {
+        log::debug!("Stopping execution trace");
+
+        let client_command = ClientCommand {
+            command: Command::StopExecutionTrace,
+            payload: ClientDataPayload::ExecutionTrace(ExecutionTraceOperation {
+                process_id: 0,
+                base_address: 0,
+                size: 0,
+                capture_registers: false,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Execution trace stopped successfully");
+            Some(())
+        } else {
+            log::error!("Failed to stop execution trace");
+            None
+        }
+    }
+
+    /// Moves up to `max_records` of the instructions recorded so far, oldest first, returning them with the number of
+    /// records dropped because the buffer of the hypervisor was full and the number of pages still traced.
+    pub fn read_execution_trace(max_records: usize) -> Option<(Vec<ExecutionTraceRecord>, u64, u64)> {
+        log::debug!("Reading up to {} execution trace records", max_records);
+
+        let header_size = core::mem::size_of::<ExecutionTraceHeader>();
+        let mut buffer = vec![0u8; header_size + max_records * core::mem::size_of::<ExecutionTraceRecord>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadExecutionTrace,
+            payload: ClientDataPayload::ExecutionTrace(ExecutionTraceOperation {
+                process_id: 0,
+                base_address: 0,
+                size: 0,
+                capture_registers: false,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read execution trace");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const ExecutionTraceHeader) };
+        let records = (0..header.record_count.min(max_records as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<ExecutionTraceRecord>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} execution trace records, {} dropped, {} pages traced", header.record_count, header.dropped_records, header.traced_pages);
+        Some((records, header.dropped_records, header.traced_pages))
+    }
+
+    /// Overrides the results of a CPUID leaf, for a subleaf or every subleaf if `None`, on all the logical processors.
+    pub fn configure_cpuid_override(leaf: u32, sub_leaf: Option<u32>, action: CpuidOverrideAction) -> Option<()> {
+        log::debug!("Configuring CPUID override: {:#x} {:x?}: {:x?}", leaf, sub_leaf, action);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureCpuidOverride,
+            payload: ClientDataPayload::CpuidOverride(CpuidOverrideOperation { leaf, sub_leaf, action }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("CPUID override configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure CPUID override");
+            None
+        }
+    }
+
+    /// Hides or reveals VMX support (CPUID.1:ECX bit 5), keeping the other overrides of leaf 1.
+    pub fn hide_vmx_support(hide: bool) -> Option<()> {
+        const VMX_SUPPORT_BIT: u32 = 1 << 5;
+
+        let action = match hide {
+            true => CpuidOverrideAction::Modify {
+                and_mask: [u32::MAX, u32::MAX, !VMX_SUPPORT_BIT, u32::MAX],
+                or_mask: [0; 4],
+            },
+            false => CpuidOverrideAction::Modify {
+                and_mask: [u32::MAX; 4],
+                or_mask: [0, 0, VMX_SUPPORT_BIT, 0],
+            },
+        };
+
+        Self::configure_cpuid_override(1, None, action)
+    }
+
+    /// Replaces the processor vendor string of leaf 0, e.g., "GenuineIntel", with a string of up to 12 bytes.
+    pub fn set_cpuid_vendor_string(vendor: &str) -> Option<()> {
+        let registers = Self::cpuid_string_registers(vendor)?;
+
+        // The vendor string is returned in EBX, EDX, ECX, and EAX keeps the maximum basic leaf.
+        Self::configure_cpuid_override(
+            0,
+            None,
+            CpuidOverrideAction::Modify {
+                and_mask: [u32::MAX, 0, 0, 0],
+                or_mask: [0, registers[0], registers[2], registers[1]],
+            },
+        )
+    }
+
+    /// Emulates the hypervisor vendor leaf 0x40000000, returning the maximum hypervisor leaf and a vendor signature of
+    /// up to 12 bytes, e.g., "Microsoft Hv".
+    pub fn set_cpuid_hypervisor_vendor(max_leaf: u32, signature: &str) -> Option<()> {
+        let registers = Self::cpuid_string_registers(signature)?;
+
+        Self::configure_cpuid_override(0x4000_0000, None, CpuidOverrideAction::Replace([max_leaf, registers[0], registers[1], registers[2]]))
+    }
+
+    /// Hides the hypervisor leaves 0x40000000+ and the hypervisor-present bit, or exposes the "Illusion" vendor signature,
+    /// version and hypervisor-present bit to cooperative guests and tooling. The CPUID overrides still apply on top.
+    pub fn set_hypervisor_presence(presence: HypervisorPresence) -> Option<()> {
+        log::debug!("Setting hypervisor presence to: {:?}", presence);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureHypervisorPresence,
+            payload: ClientDataPayload::HypervisorPresence(presence),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Hypervisor presence set successfully");
+            Some(())
+        } else {
+            log::error!("Failed to set hypervisor presence");
+            None
+        }
+    }
+
+    /// Encodes a string of up to 12 bytes in three little-endian CPUID registers.
+    fn cpuid_string_registers(string: &str) -> Option<[u32; 3]> {
+        if string.len() > 12 {
+            log::error!("CPUID string too long: {}", string);
+            return None;
+        }
+
+        let mut bytes = [0u8; 12];
+        bytes[..string.len()].copy_from_slice(string.as_bytes());
+
+        Some(core::array::from_fn(|index| u32::from_le_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap())))
+    }
+
+    /// Starts maintaining the map of the virtual memory allocations of a process and alerting its RWX memory.
+    ///
+    /// The system call numbers of `NtAllocateVirtualMemory`, `NtProtectVirtualMemory` and `NtFreeVirtualMemory` are
+    /// resolved from ntdll.dll.
+    pub fn start_allocation_monitor(process_id: u64) -> Option<()> {
+        log::debug!("Starting allocation monitor for process: {}", process_id);
+
+        let mut syscall = Syscall::new();
+        let mut resolve = |function_name: &str| -> Option<u32> {
+            let syscall_number = syscall.get_ssn_by_hash(djb2_hash(function_name.as_bytes()))? as u32;
+            log::debug!("Function: {} Syscall number: {}", function_name, syscall_number);
+            Some(syscall_number)
+        };
+
+        let client_command = ClientCommand {
+            command: Command::StartAllocationMonitor,
+            payload: ClientDataPayload::AllocationMonitor(AllocationMonitorOperation {
+                process_id,
+                allocate_syscall_number: resolve("NtAllocateVirtualMemory")?,
+                protect_syscall_number: resolve("NtProtectVirtualMemory")?,
+                free_syscall_number: Some(resolve("NtFreeVirtualMemory")?),
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Allocation monitor started successfully");
+            Some(())
+        } else {
+            log::error!("Failed to start allocation monitor");
+            None
+        }
+    }
+
+    /// Stops monitoring the allocations of a process, or of every process with 0.
+    pub fn stop_allocation_monitor(process_id: u64) -> Option<()> {
+        log::debug!("Stopping allocation monitor for process: {}", process_id);
+
+        let client_command = ClientCommand {
+            command: Command::StopAllocationMonitor,
+            payload: ClientDataPayload::AllocationMonitor(AllocationMonitorOperation {
+                process_id,
+                allocate_syscall_number: 0,
+                protect_syscall_number: 0,
+                free_syscall_number: None,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Allocation monitor stopped successfully");
+            Some(())
+        } else {
+            log::error!("Failed to stop allocation monitor");
+            None
+        }
+    }
+
+    /// Reads up to `max_regions` regions of the allocation map of a monitored process, with the number of regions in
+    /// the map and the number of regions dropped because the map was full.
+    pub fn read_allocation_map(process_id: u64, max_regions: usize) -> Option<(Vec<AllocationRegion>, u64, u64)> {
+        log::debug!("Reading up to {} allocation regions of process: {}", max_regions, process_id);
+
+        let header_size = core::mem::size_of::<AllocationMapHeader>();
+        let mut buffer = vec![0u8; header_size + max_regions * core::mem::size_of::<AllocationRegion>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadAllocationMap,
+            payload: ClientDataPayload::AllocationMonitor(AllocationMonitorOperation {
+                process_id,
+                allocate_syscall_number: 0,
+                protect_syscall_number: 0,
+                free_syscall_number: None,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read allocation map");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const AllocationMapHeader) };
+        let regions = (0..header.region_count.min(max_regions as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<AllocationRegion>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} of {} allocation regions, {} dropped", header.region_count, header.total_regions, header.dropped_regions);
+        Some((regions, header.total_regions, header.dropped_regions))
+    }
+
+    /// Reads up to `max_alerts` of the oldest allocation alerts, with the number of alerts dropped since the last read.
+    pub fn read_allocation_alerts(max_alerts: usize) -> Option<(Vec<AllocationAlert>, u64)> {
+        log::debug!("Reading up to {} allocation alerts", max_alerts);
+
+        let header_size = core::mem::size_of::<AllocationAlertHeader>();
+        let mut buffer = vec![0u8; header_size + max_alerts * core::mem::size_of::<AllocationAlert>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadAllocationAlerts,
+            payload: ClientDataPayload::AllocationMonitor(AllocationMonitorOperation {
+                process_id: 0,
+                allocate_syscall_number: 0,
+                protect_syscall_number: 0,
+                free_syscall_number: None,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read allocation alerts");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const AllocationAlertHeader) };
+        let alerts = (0..header.alert_count.min(max_alerts as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<AllocationAlert>().add(index)) })
+            .collect();
+
+        log::debug!("Read {} allocation alerts, {} dropped", header.alert_count, header.dropped_alerts);
+        Some((alerts, header.dropped_alerts))
+    }
+
+    /// Enables the automatic copies of the code pages seen to become executable after being written, at most
+    /// `max_snapshots_per_second` per second, or disables them if `enabled` is false. The copies held are kept.
+    pub fn configure_code_snapshots(enabled: bool, max_snapshots_per_second: u64) -> Option<()> {
+        log::debug!("Configuring code snapshots, enabled: {}, {} per second", enabled, max_snapshots_per_second);
+
+        let client_command = ClientCommand {
+            command: Command::ConfigureCodeSnapshots,
+            payload: ClientDataPayload::CodeSnapshot(CodeSnapshotOperation {
+                enabled,
+                max_snapshots_per_second,
+                buffer: 0,
+                buffer_size: 0,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax == 1 {
+            log::debug!("Code snapshots configured successfully");
+            Some(())
+        } else {
+            log::error!("Failed to configure code snapshots");
+            None
+        }
+    }
+
+    /// Moves up to `max_snapshots` of the copies of the transient code pages, oldest first, returning them with the
+    /// numbers of pages dropped because the store of the hypervisor was full and suppressed by the rate limit.
+    pub fn read_code_snapshots(max_snapshots: usize) -> Option<(Vec<CodeSnapshot>, u64, u64)> {
+        log::debug!("Reading up to {} code snapshots", max_snapshots);
+
+        let header_size = core::mem::size_of::<CodeSnapshotHeader>();
+        let mut buffer = vec![0u8; header_size + max_snapshots * core::mem::size_of::<CodeSnapshot>()];
+
+        let client_command = ClientCommand {
+            command: Command::ReadCodeSnapshots,
+            payload: ClientDataPayload::CodeSnapshot(CodeSnapshotOperation {
+                enabled: false,
+                max_snapshots_per_second: 0,
+                buffer: buffer.as_mut_ptr() as u64,
+                buffer_size: buffer.len() as u64,
+            }),
+        };
+
+        let result = Self::call_hypervisor(client_command.as_ptr());
+
+        if result.eax != 1 {
+            log::error!("Failed to read code snapshots");
+            return None;
+        }
+
+        let header = unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const CodeSnapshotHeader) };
+        let snapshots = (0..header.snapshot_count.min(max_snapshots as u64) as usize)
+            .map(|index| unsafe { core::ptr::read_unaligned(buffer[header_size..].as_ptr().cast::<CodeSnapshot>().add(index)) })
+            .collect();
+
+        log::debug!(
+            "Read {} code snapshots, {} dropped, {} suppressed, {} still held",
+            header.snapshot_count, header.dropped_snapshots, header.suppressed_snapshots, header.held_snapshots
+        );
+        Some((snapshots, header.dropped_snapshots, header.suppressed_snapshots))
+    }
+
     /// Writes memory to the opened process using the stored CR3.
     pub fn write_process_memory(&self, address: u64, buffer: &[u8]) -> Option<()> {
         log::debug!("Writing memory to address: {:#x}", address);
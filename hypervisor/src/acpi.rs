@@ -0,0 +1,829 @@
+//! Provides parsing of the Advanced Configuration and Power Interface (ACPI) tables at boot time.
+//!
+//! The tables are discovered through the Root System Description Pointer (RSDP) and the Extended System
+//! Description Table (XSDT) and exposed to later subsystems, such as the DMA remapping hardware units (DMAR),
+//! the High Precision Event Timer (HPET) and the ACPI Power Management (PM) timer. Selected tables can also be
+//! patched or hidden before they are presented to the operating system.
+//!
+//! Reference: Advanced Configuration and Power Interface (ACPI) Specification: 5.2 ACPI System Description Tables
+
+use {
+    crate::error::HypervisorError,
+    alloc::vec::Vec,
+    core::{
+        mem::size_of,
+        ptr::{read_unaligned, write_unaligned},
+        slice,
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::RwLock,
+};
+
+/// The signature of the RSDP.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The signature of the Fixed ACPI Description Table (FADT).
+pub const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+/// The signature of the Differentiated System Description Table (DSDT).
+pub const DSDT_SIGNATURE: &[u8; 4] = b"DSDT";
+
+/// The signature of the High Precision Event Timer Table (HPET).
+pub const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+
+/// The signature of the DMA Remapping Reporting Table (DMAR).
+pub const DMAR_SIGNATURE: &[u8; 4] = b"DMAR";
+
+/// The signature of the PCI Express Memory-mapped Configuration Space Base Address Description Table (MCFG).
+pub const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+
+/// The signature of the System Resource Affinity Table (SRAT).
+pub const SRAT_SIGNATURE: &[u8; 4] = b"SRAT";
+
+/// The type of the Processor Local APIC/SAPIC Affinity Structure of the SRAT.
+const SRAT_PROCESSOR_APIC_AFFINITY: u8 = 0;
+
+/// The type of the Memory Affinity Structure of the SRAT.
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+
+/// The type of the Processor Local x2APIC Affinity Structure of the SRAT.
+const SRAT_PROCESSOR_X2APIC_AFFINITY: u8 = 2;
+
+/// The Enabled flag of the affinity structures of the SRAT, clear for the structures to ignore.
+const SRAT_AFFINITY_ENABLED: u32 = 1 << 0;
+
+/// The AML encoding of a `Device` definition (DeviceOp).
+const AML_DEVICE_OP: [u8; 2] = [0x5B, 0x82];
+
+/// The names identifying a device in the ACPI namespace, renamed to hide the device from the operating system.
+const AML_DEVICE_ID_NAMES: [&[u8; 4]; 3] = [b"_HID", b"_CID", b"_ADR"];
+
+/// The AML encoding of a `Name` definition (NameOp).
+const AML_NAME_OP: u8 = 0x08;
+
+/// The AML encoding of the root prefix of a name (`\`).
+const AML_ROOT_PREFIX: u8 = 0x5C;
+
+/// The AML encoding of a `Package` definition (PackageOp).
+const AML_PACKAGE_OP: u8 = 0x12;
+
+/// The offset of the checksum in the System Description Table header.
+const SDT_CHECKSUM_OFFSET: usize = 9;
+
+lazy_static! {
+    /// A globally shared instance of `AcpiTables`, protected by a read-write lock.
+    ///
+    /// The tables are parsed once at boot by `AcpiTables::initialize_shared_acpi_tables` and are only read afterwards.
+    pub static ref SHARED_ACPI_TABLES: RwLock<AcpiTables> = RwLock::new(AcpiTables::default());
+}
+
+/// The Root System Description Pointer (RSDP) structure for ACPI 2.0 and later.
+///
+/// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 5.2.5.3 Root System Description Pointer (RSDP) Structure
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    pub reserved: [u8; 3],
+}
+
+/// The header shared by all System Description Tables.
+///
+/// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 5.2.6 System Description Table Header
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Describes an ACPI table found at boot.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiTable {
+    /// The signature of the table.
+    pub signature: [u8; 4],
+
+    /// The physical address of the table.
+    pub pa: u64,
+
+    /// The length of the table in bytes, including the header.
+    pub length: u32,
+}
+
+/// Describes a PCI Express Enhanced Configuration Access Mechanism (ECAM) region from the MCFG.
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    /// The physical address of the configuration space of the start bus.
+    pub base_pa: u64,
+
+    /// The PCI segment group number.
+    pub segment: u16,
+
+    /// The first bus number decoded by the region.
+    pub start_bus: u8,
+
+    /// The last bus number decoded by the region.
+    pub end_bus: u8,
+}
+
+/// Associates a logical processor with its proximity domain, from the SRAT.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorAffinity {
+    /// The APIC ID of the logical processor, its x2APIC ID if it's above 254.
+    pub apic_id: u32,
+
+    /// The proximity domain of the logical processor.
+    pub proximity_domain: u32,
+}
+
+/// Associates a range of physical memory with its proximity domain, from the SRAT.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAffinity {
+    /// The physical address of the range.
+    pub base_pa: u64,
+
+    /// The length of the range in bytes.
+    pub length: u64,
+
+    /// The proximity domain of the range.
+    pub proximity_domain: u32,
+}
+
+/// The ACPI tables discovered through the RSDP.
+#[derive(Debug, Clone, Default)]
+pub struct AcpiTables {
+    /// The physical address of the RSDP.
+    pub rsdp_pa: u64,
+
+    /// The physical address of the XSDT.
+    pub xsdt_pa: u64,
+
+    /// The tables referenced by the XSDT, plus the DSDT referenced by the FADT.
+    pub tables: Vec<AcpiTable>,
+}
+
+impl AcpiTables {
+    /// Parses the ACPI tables and stores them in `SHARED_ACPI_TABLES`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rsdp_pa` - The physical address of the RSDP.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the tables have been parsed, otherwise `Err(HypervisorError)`.
+    pub fn initialize_shared_acpi_tables(rsdp_pa: u64) -> Result<(), HypervisorError> {
+        let acpi_tables = unsafe { Self::parse(rsdp_pa)? };
+        *SHARED_ACPI_TABLES.write() = acpi_tables;
+        Ok(())
+    }
+
+    /// Parses the RSDP, the XSDT and all tables it references.
+    ///
+    /// # Arguments
+    ///
+    /// * `rsdp_pa` - The physical address of the RSDP.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<AcpiTables, HypervisorError>` containing the discovered tables.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `rsdp_pa` points to a valid RSDP and that the ACPI tables are identity mapped.
+    pub unsafe fn parse(rsdp_pa: u64) -> Result<Self, HypervisorError> {
+        let rsdp = read_unaligned(rsdp_pa as *const Rsdp);
+
+        // Only ACPI 2.0+ (revision 2) provides the XSDT.
+        if rsdp.signature != *RSDP_SIGNATURE || rsdp.revision < 2 {
+            error!("Invalid or unsupported RSDP at {:#x}", rsdp_pa);
+            return Err(HypervisorError::InvalidAcpiTable);
+        }
+
+        let xsdt_pa = rsdp.xsdt_address;
+        let xsdt = read_unaligned(xsdt_pa as *const SdtHeader);
+
+        if xsdt.signature != *b"XSDT" || !is_checksum_valid(xsdt_pa, xsdt.length) {
+            error!("Invalid XSDT at {:#x}", xsdt_pa);
+            return Err(HypervisorError::InvalidAcpiTable);
+        }
+
+        let mut tables = Vec::new();
+
+        for table_pa in xsdt_entries(xsdt_pa) {
+            let header = read_unaligned(table_pa as *const SdtHeader);
+            trace!("ACPI table {} at {:#x} ({:#x} bytes)", signature_str(&header.signature), table_pa, { header.length });
+
+            tables.push(AcpiTable {
+                signature: header.signature,
+                pa: table_pa,
+                length: header.length,
+            });
+
+            // The DSDT isn't referenced by the XSDT, but by the FADT (X_DSDT at offset 140, DSDT at offset 40).
+            if header.signature == *FADT_SIGNATURE {
+                let x_dsdt = match header.length >= 148 {
+                    true => read_unaligned((table_pa + 140) as *const u64),
+                    false => 0,
+                };
+                let dsdt_pa = match x_dsdt {
+                    0 => read_unaligned((table_pa + 40) as *const u32) as u64,
+                    x_dsdt => x_dsdt,
+                };
+
+                if dsdt_pa != 0 {
+                    let dsdt = read_unaligned(dsdt_pa as *const SdtHeader);
+                    tables.push(AcpiTable {
+                        signature: dsdt.signature,
+                        pa: dsdt_pa,
+                        length: dsdt.length,
+                    });
+                }
+            }
+        }
+
+        debug!("Found {} ACPI tables", tables.len());
+
+        Ok(Self { rsdp_pa, xsdt_pa, tables })
+    }
+
+    /// Finds a table by its signature.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The signature of the table, e.g., `DMAR_SIGNATURE`.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the table if found.
+    pub fn find_table(&self, signature: &[u8; 4]) -> Option<AcpiTable> {
+        self.tables.iter().find(|table| table.signature == *signature).copied()
+    }
+
+    /// Returns the base address of the HPET registers from the HPET table.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the physical address of the HPET registers if found.
+    ///
+    /// Reference: IA-PC HPET (High Precision Event Timers) Specification: 3.2.4 The ACPI 2.0 HPET Description Table (HPET)
+    pub fn hpet_base_address(&self) -> Option<u64> {
+        let hpet = self.find_table(HPET_SIGNATURE)?;
+
+        // The base address is a Generic Address Structure (GAS) at offset 40, the address is at offset 4 of the GAS.
+        match unsafe { read_unaligned((hpet.pa + 44) as *const u64) } {
+            0 => None,
+            address => Some(address),
+        }
+    }
+
+    /// Returns the I/O port of the ACPI PM timer from the FADT.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the I/O port of the PM timer if found.
+    ///
+    /// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 5.2.9 Fixed ACPI Description Table (FADT)
+    pub fn pm_timer_port(&self) -> Option<u16> {
+        // X_PM_TMR_BLK is a GAS at offset 208, PM_TMR_BLK is at offset 76.
+        self.fadt_io_port(208, 76)
+    }
+
+    /// Returns the I/O ports of the ACPI PM1a and PM1b control registers from the FADT.
+    ///
+    /// # Returns
+    ///
+    /// The I/O ports of the PM1a and PM1b control registers, `None` for the registers that aren't present.
+    ///
+    /// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 4.8.3.2 Power Management 1 Control Registers
+    pub fn pm1_control_ports(&self) -> [Option<u16>; 2] {
+        // X_PM1a_CNT_BLK and X_PM1b_CNT_BLK are GASes at offsets 172 and 184, PM1a_CNT_BLK and PM1b_CNT_BLK are at offsets 64 and 68.
+        [self.fadt_io_port(172, 64), self.fadt_io_port(184, 68)]
+    }
+
+    /// Returns the I/O port of the ACPI reset register and the value to write to it to reset the system, from the FADT.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the I/O port and the reset value, if the reset register is supported and in system I/O space.
+    ///
+    /// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 4.8.3.6 Reset Register
+    pub fn reset_register(&self) -> Option<(u16, u8)> {
+        let fadt = self.find_table(FADT_SIGNATURE)?;
+
+        // The RESET_REG_SUP flag is bit 10 of the flags at offset 112, RESET_REG is a GAS at offset 116 and RESET_VALUE is at offset 128.
+        if fadt.length < 129 || unsafe { read_unaligned((fadt.pa + 112) as *const u32) } & (1 << 10) == 0 {
+            return None;
+        }
+
+        let address_space_id = unsafe { read_unaligned((fadt.pa + 116) as *const u8) };
+        let address = unsafe { read_unaligned((fadt.pa + 120) as *const u64) };
+        let value = unsafe { read_unaligned((fadt.pa + 128) as *const u8) };
+
+        match address_space_id == 1 && address != 0 {
+            true => Some((address as u16, value)),
+            false => None,
+        }
+    }
+
+    /// Returns an I/O port of a fixed hardware register block from the FADT.
+    ///
+    /// The extended address, a Generic Address Structure (GAS), takes precedence over the legacy 32-bit port if it's
+    /// present and in system I/O space (address space ID 1).
+    ///
+    /// # Arguments
+    ///
+    /// * `gas_offset` - The offset of the extended address in the FADT.
+    /// * `legacy_offset` - The offset of the legacy port in the FADT.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the I/O port if found.
+    fn fadt_io_port(&self, gas_offset: u64, legacy_offset: u64) -> Option<u16> {
+        let fadt = self.find_table(FADT_SIGNATURE)?;
+
+        if fadt.length as u64 >= gas_offset + 12 {
+            let address_space_id = unsafe { read_unaligned((fadt.pa + gas_offset) as *const u8) };
+            let address = unsafe { read_unaligned((fadt.pa + gas_offset + 4) as *const u64) };
+
+            if address_space_id == 1 && address != 0 {
+                return Some(address as u16);
+            }
+        }
+
+        match unsafe { read_unaligned((fadt.pa + legacy_offset) as *const u32) } {
+            0 => None,
+            port => Some(port as u16),
+        }
+    }
+
+    /// Returns the physical address of the Firmware ACPI Control Structure (FACS) from the FADT.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the physical address of the FACS if found.
+    ///
+    /// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 5.2.10 Firmware ACPI Control Structure (FACS)
+    pub fn facs_pa(&self) -> Option<u64> {
+        let fadt = self.find_table(FADT_SIGNATURE)?;
+
+        // X_FIRMWARE_CTRL is at offset 132, FIRMWARE_CTRL is at offset 36.
+        let x_firmware_ctrl = match fadt.length >= 140 {
+            true => unsafe { read_unaligned((fadt.pa + 132) as *const u64) },
+            false => 0,
+        };
+
+        match x_firmware_ctrl {
+            0 => match unsafe { read_unaligned((fadt.pa + 36) as *const u32) } {
+                0 => None,
+                firmware_ctrl => Some(firmware_ctrl as u64),
+            },
+            x_firmware_ctrl => Some(x_firmware_ctrl),
+        }
+    }
+
+    /// Returns the values of the sleep type fields of the PM1a and PM1b control registers entering a sleep state, from
+    /// the `\_Sx` object of the DSDT.
+    ///
+    /// Only objects defined by a `Name` of a `Package` whose first elements are constants are supported, as generated for
+    /// the sleep states by the usual ASL compilers.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The sleep state, e.g., 3 for S3.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the SLP_TYPa and SLP_TYPb values, if the sleep state is supported.
+    ///
+    /// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 7.4.2 \_Sx (System States)
+    pub fn sleep_types(&self, state: u8) -> Option<(u8, u8)> {
+        let dsdt = self.find_table(DSDT_SIGNATURE)?;
+        let table_bytes = unsafe { slice::from_raw_parts(dsdt.pa as *const u8, dsdt.length as usize) };
+        let name = [b'_', b'S', b'0' + state, b'_'];
+
+        let mut offset = size_of::<SdtHeader>();
+
+        while let Some(position) = table_bytes[offset..].windows(name.len()).position(|window| window == name) {
+            let name_offset = offset + position;
+            offset = name_offset + name.len();
+
+            let is_named = matches!(table_bytes[..name_offset], [.., AML_NAME_OP] | [.., AML_NAME_OP, AML_ROOT_PREFIX]);
+            if !is_named || table_bytes.get(offset) != Some(&AML_PACKAGE_OP) {
+                continue;
+            }
+
+            // The PkgLength and the number of elements precede the elements.
+            let (_, length_bytes) = decode_package_length(&table_bytes[offset + 1..])?;
+            let elements = table_bytes.get(offset + 1 + length_bytes + 1..)?;
+
+            let (sleep_type_a, sleep_type_a_bytes) = decode_byte_constant(elements)?;
+            let (sleep_type_b, _) = decode_byte_constant(&elements[sleep_type_a_bytes..]).unwrap_or((0, 0));
+
+            debug!("ACPI sleep state S{}: SLP_TYPa: {:#x}, SLP_TYPb: {:#x}", state, sleep_type_a, sleep_type_b);
+
+            return Some((sleep_type_a, sleep_type_b));
+        }
+
+        None
+    }
+
+    /// Returns whether the ACPI PM timer is 32 bits wide, from the TMR_VAL_EXT flag (bit 8) of the FADT flags.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the PM timer is 32 bits wide, `false` if it is 24 bits wide or the FADT is missing.
+    pub fn is_pm_timer_32bit(&self) -> bool {
+        match self.find_table(FADT_SIGNATURE) {
+            // The flags are located at offset 112 of the FADT.
+            Some(fadt) if fadt.length >= 116 => (unsafe { read_unaligned((fadt.pa + 112) as *const u32) } & (1 << 8)) != 0,
+            _ => false,
+        }
+    }
+
+    /// Returns the ECAM regions of the PCI segment groups from the MCFG.
+    ///
+    /// # Returns
+    ///
+    /// A `Vec` containing the ECAM regions, empty if the MCFG is missing.
+    ///
+    /// Reference: PCI Firmware Specification: 4.1.2 MCFG Table Description
+    pub fn ecam_regions(&self) -> Vec<EcamRegion> {
+        let Some(mcfg) = self.find_table(MCFG_SIGNATURE) else {
+            return Vec::new();
+        };
+
+        // The allocation structures start after the header and 8 reserved bytes, each one is 16 bytes long.
+        let entries_pa = mcfg.pa + size_of::<SdtHeader>() as u64 + 8;
+        let entry_count = (mcfg.length as u64).saturating_sub(size_of::<SdtHeader>() as u64 + 8) / 16;
+
+        (0..entry_count)
+            .map(|i| unsafe {
+                let entry_pa = entries_pa + i * 16;
+                EcamRegion {
+                    base_pa: read_unaligned(entry_pa as *const u64),
+                    segment: read_unaligned((entry_pa + 8) as *const u16),
+                    start_bus: read_unaligned((entry_pa + 10) as *const u8),
+                    end_bus: read_unaligned((entry_pa + 11) as *const u8),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the proximity domains of the logical processors from the SRAT.
+    ///
+    /// # Returns
+    ///
+    /// A `Vec` containing the affinity of each enabled logical processor, empty if the SRAT is missing.
+    ///
+    /// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 5.2.16.1 Processor Local APIC/SAPIC
+    /// Affinity Structure and 5.2.16.3 Processor Local x2APIC Affinity Structure
+    pub fn processor_affinities(&self) -> Vec<ProcessorAffinity> {
+        self.srat_structures()
+            .into_iter()
+            .filter_map(|(structure_type, length, structure_pa)| unsafe {
+                match structure_type {
+                    // The proximity domain is split between bits 7:0 at offset 2 and bits 31:8 at offset 9.
+                    SRAT_PROCESSOR_APIC_AFFINITY if length >= 16 => {
+                        let domain_high = read_unaligned((structure_pa + 9) as *const [u8; 3]);
+                        let proximity_domain = u32::from_le_bytes([
+                            read_unaligned((structure_pa + 2) as *const u8),
+                            domain_high[0],
+                            domain_high[1],
+                            domain_high[2],
+                        ]);
+
+                        let flags = read_unaligned((structure_pa + 4) as *const u32);
+                        let apic_id = read_unaligned((structure_pa + 3) as *const u8) as u32;
+                        (flags & SRAT_AFFINITY_ENABLED != 0).then_some(ProcessorAffinity { apic_id, proximity_domain })
+                    }
+                    SRAT_PROCESSOR_X2APIC_AFFINITY if length >= 24 => {
+                        let proximity_domain = read_unaligned((structure_pa + 4) as *const u32);
+                        let apic_id = read_unaligned((structure_pa + 8) as *const u32);
+                        let flags = read_unaligned((structure_pa + 12) as *const u32);
+                        (flags & SRAT_AFFINITY_ENABLED != 0).then_some(ProcessorAffinity { apic_id, proximity_domain })
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the proximity domains of the ranges of physical memory from the SRAT.
+    ///
+    /// # Returns
+    ///
+    /// A `Vec` containing the affinity of each enabled range of memory, empty if the SRAT is missing.
+    ///
+    /// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 5.2.16.2 Memory Affinity Structure
+    pub fn memory_affinities(&self) -> Vec<MemoryAffinity> {
+        self.srat_structures()
+            .into_iter()
+            .filter(|&(structure_type, length, _)| structure_type == SRAT_MEMORY_AFFINITY && length >= 40)
+            .filter_map(|(_, _, structure_pa)| unsafe {
+                let flags = read_unaligned((structure_pa + 28) as *const u32);
+                let affinity = MemoryAffinity {
+                    base_pa: read_unaligned((structure_pa + 8) as *const u64),
+                    length: read_unaligned((structure_pa + 16) as *const u64),
+                    proximity_domain: read_unaligned((structure_pa + 2) as *const u32),
+                };
+
+                (flags & SRAT_AFFINITY_ENABLED != 0 && affinity.length != 0).then_some(affinity)
+            })
+            .collect()
+    }
+
+    /// Returns the type, the length and the physical address of each structure of the SRAT.
+    ///
+    /// The structures start after the header and 12 reserved bytes, each one starting with its type and its length.
+    fn srat_structures(&self) -> Vec<(u8, u8, u64)> {
+        let Some(srat) = self.find_table(SRAT_SIGNATURE) else {
+            return Vec::new();
+        };
+
+        let end_pa = srat.pa + srat.length as u64;
+        let mut structure_pa = srat.pa + size_of::<SdtHeader>() as u64 + 12;
+        let mut structures = Vec::new();
+
+        while structure_pa + 2 <= end_pa {
+            let (structure_type, length) = unsafe { (read_unaligned(structure_pa as *const u8), read_unaligned((structure_pa + 1) as *const u8)) };
+
+            // A structure of length 0 would never end the walk, and a truncated one can't be read.
+            if length < 2 || structure_pa + length as u64 > end_pa {
+                warn!("Malformed SRAT structure at {:#x}", structure_pa);
+                break;
+            }
+
+            structures.push((structure_type, length, structure_pa));
+            structure_pa += length as u64;
+        }
+
+        structures
+    }
+
+    /// Hides a device from the operating system by renaming its identification objects in the DSDT.
+    ///
+    /// The `_HID`, `_CID` and `_ADR` objects inside the `Device` definition, including those of its child devices,
+    /// are renamed with an `X` prefix so the device is no longer matched to a driver or to a PCI function. Only devices defined by a single name
+    /// segment (e.g., `Device (TPM0)`) in the DSDT are supported.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name segment of the device, e.g., `b"TPM0"`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(count)` with the number of renamed objects, otherwise `Err(HypervisorError)`.
+    ///
+    /// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 20.2.5.2 Named Objects Encoding
+    pub fn hide_device_object(&self, name: &[u8; 4]) -> Result<usize, HypervisorError> {
+        let dsdt = self.find_table(DSDT_SIGNATURE).ok_or(HypervisorError::AcpiTableNotFound)?;
+        let table_bytes = unsafe { slice::from_raw_parts(dsdt.pa as *const u8, dsdt.length as usize) };
+
+        let mut renamed = Vec::new();
+        let mut offset = size_of::<SdtHeader>();
+
+        while let Some(position) = table_bytes[offset..]
+            .windows(AML_DEVICE_OP.len())
+            .position(|window| window == AML_DEVICE_OP)
+        {
+            let device_offset = offset + position;
+            offset = device_offset + AML_DEVICE_OP.len();
+
+            let Some((package_length, length_bytes)) = decode_package_length(&table_bytes[offset..]) else {
+                continue;
+            };
+
+            let name_offset = offset + length_bytes;
+            let package_end = offset + package_length;
+
+            if package_end > table_bytes.len() || table_bytes.get(name_offset..name_offset + 4) != Some(name.as_slice()) {
+                continue;
+            }
+
+            // NameOp (0x08) followed by one of the identification names, within the body of the device.
+            for body_offset in name_offset + 4..package_end.saturating_sub(4) {
+                let candidate = &table_bytes[body_offset + 1..body_offset + 5];
+
+                if table_bytes[body_offset] == 0x08 && AML_DEVICE_ID_NAMES.iter().any(|id_name| candidate == id_name.as_slice()) {
+                    renamed.push(body_offset + 1);
+                }
+            }
+
+            break;
+        }
+
+        if renamed.is_empty() {
+            return Err(HypervisorError::AcpiTableNotFound);
+        }
+
+        for &name_offset in &renamed {
+            self.patch_table(DSDT_SIGNATURE, name_offset, b"X")?;
+        }
+
+        debug!("Hid ACPI device {} ({} objects renamed)", signature_str(name), renamed.len());
+
+        Ok(renamed.len())
+    }
+
+    /// Overwrites bytes of a table and updates its checksum.
+    ///
+    /// This must be done before the operating system reads the tables, i.e., before `ExitBootServices`.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The signature of the table.
+    /// * `offset` - The offset in the table, from the start of the header.
+    /// * `bytes` - The bytes to write.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the table has been patched, otherwise `Err(HypervisorError)`.
+    pub fn patch_table(&self, signature: &[u8; 4], offset: usize, bytes: &[u8]) -> Result<(), HypervisorError> {
+        let table = self.find_table(signature).ok_or(HypervisorError::AcpiTableNotFound)?;
+
+        // Don't allow patching the header, the length and checksum have to stay consistent.
+        if offset < size_of::<SdtHeader>() || offset + bytes.len() > table.length as usize {
+            return Err(HypervisorError::InvalidAcpiTable);
+        }
+
+        unsafe {
+            let table_bytes = slice::from_raw_parts_mut(table.pa as *mut u8, table.length as usize);
+            table_bytes[offset..offset + bytes.len()].copy_from_slice(bytes);
+            update_checksum(table.pa, table.length);
+        }
+
+        debug!("Patched ACPI table {} at offset {:#x}", signature_str(signature), offset);
+
+        Ok(())
+    }
+
+    /// Replaces the first occurrence of a byte pattern in a table and updates its checksum.
+    ///
+    /// This can be used to alter the Definition Blocks of the DSDT, e.g., renaming the `_STA` method of a device
+    /// so the device is not reported to the operating system. The replacement must be the same length as the pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The signature of the table.
+    /// * `pattern` - The bytes to search for.
+    /// * `replacement` - The bytes to write instead.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(offset)` with the offset of the replaced bytes, otherwise `Err(HypervisorError)`.
+    pub fn replace_in_table(&self, signature: &[u8; 4], pattern: &[u8], replacement: &[u8]) -> Result<usize, HypervisorError> {
+        if pattern.is_empty() || pattern.len() != replacement.len() {
+            return Err(HypervisorError::InvalidAcpiTable);
+        }
+
+        let table = self.find_table(signature).ok_or(HypervisorError::AcpiTableNotFound)?;
+        let table_bytes = unsafe { slice::from_raw_parts(table.pa as *const u8, table.length as usize) };
+
+        let offset = table_bytes[size_of::<SdtHeader>()..]
+            .windows(pattern.len())
+            .position(|window| window == pattern)
+            .ok_or(HypervisorError::AcpiTableNotFound)?
+            + size_of::<SdtHeader>();
+
+        self.patch_table(signature, offset, replacement)?;
+
+        Ok(offset)
+    }
+
+    /// Removes a table from the XSDT so it's not presented to the operating system.
+    ///
+    /// The table itself is left intact and stays available through `find_table`.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The signature of the table to hide.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the table has been hidden, otherwise `Err(HypervisorError)`.
+    pub fn hide_table(&self, signature: &[u8; 4]) -> Result<(), HypervisorError> {
+        let table = self.find_table(signature).ok_or(HypervisorError::AcpiTableNotFound)?;
+
+        unsafe {
+            let entries = xsdt_entries(self.xsdt_pa);
+            let index = entries
+                .iter()
+                .position(|&table_pa| table_pa == table.pa)
+                .ok_or(HypervisorError::AcpiTableNotFound)?;
+
+            // Shift the remaining entries down and shrink the XSDT by one entry.
+            let entries_pa = self.xsdt_pa + size_of::<SdtHeader>() as u64;
+            for (i, &table_pa) in entries.iter().enumerate().skip(index + 1) {
+                write_unaligned((entries_pa + (i as u64 - 1) * 8) as *mut u64, table_pa);
+            }
+
+            let length = read_unaligned((self.xsdt_pa + 4) as *const u32) - 8;
+            write_unaligned((self.xsdt_pa + 4) as *mut u32, length);
+            update_checksum(self.xsdt_pa, length);
+        }
+
+        debug!("Hid ACPI table {} from the XSDT", signature_str(signature));
+
+        Ok(())
+    }
+}
+
+/// Decodes an AML PkgLength, the length of a package including the PkgLength itself.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes starting at the PkgLength.
+///
+/// # Returns
+///
+/// An `Option` containing the package length and the number of bytes of the PkgLength.
+///
+/// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 20.2.4 Package Length Encoding
+fn decode_package_length(bytes: &[u8]) -> Option<(usize, usize)> {
+    let lead_byte = *bytes.first()?;
+
+    // Bits 7:6 are the number of following bytes, with only bits 3:0 of the lead byte used if there are any.
+    let following_bytes = (lead_byte >> 6) as usize;
+    if following_bytes == 0 {
+        return Some(((lead_byte & 0x3F) as usize, 1));
+    }
+
+    let mut length = (lead_byte & 0x0F) as usize;
+    for (i, &byte) in bytes.get(1..=following_bytes)?.iter().enumerate() {
+        length |= (byte as usize) << (4 + i * 8);
+    }
+
+    Some((length, following_bytes + 1))
+}
+
+/// Decodes an AML constant fitting in a byte: `Zero`, `One` or a `ByteConst`.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes starting at the constant.
+///
+/// # Returns
+///
+/// An `Option` containing the value and the number of bytes of the constant.
+///
+/// Reference: Advanced Configuration and Power Interface (ACPI) Specification: 20.2.3 Data Objects Encoding
+fn decode_byte_constant(bytes: &[u8]) -> Option<(u8, usize)> {
+    match *bytes.first()? {
+        // ZeroOp and OneOp.
+        0x00 => Some((0, 1)),
+        0x01 => Some((1, 1)),
+        // BytePrefix followed by the value.
+        0x0A => Some((*bytes.get(1)?, 2)),
+        _ => None,
+    }
+}
+
+/// Reads the table addresses referenced by the XSDT.
+///
+/// # Arguments
+///
+/// * `xsdt_pa` - The physical address of the XSDT.
+unsafe fn xsdt_entries(xsdt_pa: u64) -> Vec<u64> {
+    let length = read_unaligned((xsdt_pa + 4) as *const u32) as usize;
+    let entries_pa = xsdt_pa + size_of::<SdtHeader>() as u64;
+    let entry_count = length.saturating_sub(size_of::<SdtHeader>()) / 8;
+
+    (0..entry_count as u64)
+        .map(|i| read_unaligned((entries_pa + i * 8) as *const u64))
+        .collect()
+}
+
+/// Checks whether all bytes of a table sum to zero.
+unsafe fn is_checksum_valid(table_pa: u64, length: u32) -> bool {
+    let table_bytes = slice::from_raw_parts(table_pa as *const u8, length as usize);
+    table_bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Recalculates the checksum of a table after it has been modified.
+unsafe fn update_checksum(table_pa: u64, length: u32) {
+    let table_bytes = slice::from_raw_parts_mut(table_pa as *mut u8, length as usize);
+    table_bytes[SDT_CHECKSUM_OFFSET] = 0;
+    let sum = table_bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    table_bytes[SDT_CHECKSUM_OFFSET] = 0u8.wrapping_sub(sum);
+}
+
+/// Converts a table signature to a string for logging.
+fn signature_str(signature: &[u8; 4]) -> &str {
+    core::str::from_utf8(signature).unwrap_or("????")
+}
@@ -0,0 +1,402 @@
+//! Provides a minimal Advanced Host Controller Interface (AHCI) sector writer, used by the hypervisor to write to a
+//! SATA disk after `ExitBootServices` without going through the guest's storage stack.
+//!
+//! The port stays owned by the guest's driver: a command is only issued while the port is idle, using a free command
+//! slot of the command list set up by the driver, and the slot's command header is restored once the command completes.
+//! This is best-effort, as the guest's driver isn't synchronized with beyond checking that the port is idle.
+//!
+//! The command doesn't raise an interrupt the driver didn't ask for: the port's interrupts are disabled while it runs,
+//! and the interrupt status it caused is cleared. A command that fails or times out is stopped by the port recovery
+//! before its slot is handed back, as the port stops processing the command list after a task file error.
+//!
+//! The port of the disk holding the EFI System Partition is located once at boot and shared by the files the
+//! hypervisor writes to, so their commands are never issued concurrently.
+//!
+//! The controller reads the command table and the written sectors by DMA, so they are in pages allocated by the loader
+//! outside the hypervisor memory excluded from device DMA (see `vtd`), which is checked when the port is located.
+//!
+//! Reference: Serial ATA Advanced Host Controller Interface (AHCI) 1.3.1
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            device_hiding::{read_config_dword, Bdf},
+            page::Page,
+            vtd::is_dma_accessible,
+        },
+    },
+    core::ptr::{read_volatile, write_volatile},
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The size of a sector in bytes.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The number of pages the controller reads by DMA: the command table and the buffer of the written sectors.
+pub const AHCI_DMA_PAGES: usize = 2;
+
+/// The offset of the Host Capabilities register (CAP) in the HBA memory registers.
+const HBA_CAP: u64 = 0x00;
+
+/// The offset of the registers of port 0 in the HBA memory registers, each port takes 0x80 bytes.
+const HBA_PORTS: u64 = 0x100;
+
+/// The offset of the Command List Base Address register (PxCLB) of a port.
+const PORT_CLB: u64 = 0x00;
+
+/// The offset of the Interrupt Status register (PxIS) of a port.
+const PORT_IS: u64 = 0x10;
+
+/// The offset of the Interrupt Enable register (PxIE) of a port.
+const PORT_IE: u64 = 0x14;
+
+/// The offset of the Command and Status register (PxCMD) of a port.
+const PORT_CMD: u64 = 0x18;
+
+/// The offset of the Task File Data register (PxTFD) of a port.
+const PORT_TFD: u64 = 0x20;
+
+/// The offset of the SATA Error register (PxSERR) of a port.
+const PORT_SERR: u64 = 0x30;
+
+/// The offset of the SATA Active register (PxSACT) of a port.
+const PORT_SACT: u64 = 0x34;
+
+/// The offset of the Command Issue register (PxCI) of a port.
+const PORT_CI: u64 = 0x38;
+
+/// The Start bit of PxCMD, set while the port processes the command list.
+const PORT_CMD_ST: u32 = 1 << 0;
+
+/// The Command List Running bit of PxCMD, cleared once the port has stopped processing the command list.
+const PORT_CMD_CR: u32 = 1 << 15;
+
+/// The Task File Error Status bit of PxIS.
+const PORT_IS_TFES: u32 = 1 << 30;
+
+/// The Error and Busy bits of PxTFD.
+const PORT_TFD_ERR_BSY: u32 = (1 << 0) | (1 << 7);
+
+/// The ATA WRITE DMA EXT command.
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+
+/// The type of a Register - Host to Device FIS.
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+/// The Interrupt on Completion bit of a Physical Region Descriptor.
+const PRD_INTERRUPT_ON_COMPLETION: u32 = 1 << 31;
+
+/// The offset of the Physical Region Descriptor Table in the command table.
+const COMMAND_TABLE_PRDT: usize = 0x80;
+
+/// The size of a command header in the command list.
+const COMMAND_HEADER_SIZE: u64 = 0x20;
+
+/// The number of times the registers are polled before giving up on a busy port or a command.
+const POLL_ITERATIONS: usize = 10_000_000;
+
+/// The Class Code of an AHCI controller: mass storage (01h), SATA (06h), AHCI 1.0 (01h).
+const AHCI_CLASS_CODE: u32 = 0x01_06_01;
+
+/// The offset of the AHCI Base Address register (ABAR, BAR5) in the configuration space.
+const PCI_ABAR: u8 = 0x24;
+
+/// The offset of the bus numbers of a PCI-to-PCI bridge in the configuration space.
+const PCI_BRIDGE_BUS_NUMBERS: u8 = 0x18;
+
+lazy_static! {
+    /// A globally shared instance of `AhciPort`, the port of the disk holding the ESP, protected by a mutex.
+    ///
+    /// The port is set up once at boot by `AhciPort::initialize_shared_ahci_port`, and is `None` otherwise.
+    pub static ref SHARED_AHCI_PORT: Mutex<Option<AhciPort>> = Mutex::new(None);
+}
+
+/// A port of an AHCI controller that sectors can be written to.
+#[derive(Debug)]
+pub struct AhciPort {
+    /// The physical address of the registers of the port.
+    port_pa: u64,
+
+    /// The number of command slots supported by the controller.
+    command_slots: u32,
+
+    /// The physical address of the command table of the commands issued by the hypervisor, the first DMA page.
+    command_table_pa: u64,
+
+    /// The physical address of the buffer the controller reads the written sectors from, the second DMA page.
+    buffer_pa: u64,
+}
+
+impl AhciPort {
+    /// Locates a port of the AHCI controller found at the end of a path of PCI devices, as found in a UEFI device path.
+    ///
+    /// Each element but the last is a PCI-to-PCI bridge starting from bus 0, whose secondary bus holds the next element.
+    ///
+    /// # Arguments
+    ///
+    /// * `pci_path` - The device and function numbers of each PCI device on the path to the controller.
+    /// * `port` - The number of the port on the controller.
+    /// * `dma_pages_pa` - The physical address of `AHCI_DMA_PAGES` zeroed pages the controller may read by DMA.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(AhciPort)` if the controller has been found, otherwise `Err(HypervisorError)`.
+    pub fn from_pci_path(pci_path: &[(u8, u8)], port: u16, dma_pages_pa: u64) -> Result<Self, HypervisorError> {
+        if !is_dma_accessible(dma_pages_pa, (AHCI_DMA_PAGES * BASE_PAGE_SIZE) as u64) {
+            error!("AHCI DMA pages at {:#x} are excluded from device DMA", dma_pages_pa);
+            return Err(HypervisorError::AhciDmaPagesProtected);
+        }
+
+        let mut bus = 0u8;
+        let mut bdf = None;
+
+        for (index, &(device, function)) in pci_path.iter().enumerate() {
+            let current = Bdf::new(0, bus, device, function);
+
+            if index + 1 < pci_path.len() {
+                // The secondary bus number is bits 15:8 of the bus numbers register.
+                let bus_numbers = read_config_dword(current, PCI_BRIDGE_BUS_NUMBERS, &[]).ok_or(HypervisorError::PciDeviceNotFound)?;
+                bus = (bus_numbers >> 8) as u8;
+            }
+
+            bdf = Some(current);
+        }
+
+        let bdf = bdf.ok_or(HypervisorError::PciDeviceNotFound)?;
+
+        // The Class Code is bits 31:8 of the Revision ID and Class Code register at offset 0x08.
+        let class_code = read_config_dword(bdf, 0x08, &[]).ok_or(HypervisorError::PciDeviceNotFound)? >> 8;
+        if class_code != AHCI_CLASS_CODE || port >= 32 {
+            error!("PCI function {:x?} is not an AHCI controller: class {:#x}", bdf, class_code);
+            return Err(HypervisorError::PciDeviceNotFound);
+        }
+
+        let abar = (read_config_dword(bdf, PCI_ABAR, &[]).ok_or(HypervisorError::PciDeviceNotFound)? & !0xF) as u64;
+        let command_slots = ((unsafe { read_volatile((abar + HBA_CAP) as *const u32) } >> 8) & 0x1F) + 1;
+
+        debug!("AHCI controller {:x?}: ABAR {:#x}, port {}, {} command slots", bdf, abar, port, command_slots);
+
+        Ok(Self {
+            port_pa: abar + HBA_PORTS + port as u64 * 0x80,
+            command_slots,
+            command_table_pa: dma_pages_pa,
+            buffer_pa: dma_pages_pa + BASE_PAGE_SIZE as u64,
+        })
+    }
+
+    /// Stores the port in `SHARED_AHCI_PORT`, unless a port is already stored.
+    ///
+    /// This must be called at boot, before the files on the ESP are handed to the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `pci_path` - The device and function numbers of each PCI device on the path to the controller.
+    /// * `port` - The number of the port on the controller.
+    /// * `dma_pages_pa` - The physical address of `AHCI_DMA_PAGES` zeroed pages the controller may read by DMA.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the port is stored, otherwise `Err(HypervisorError)`.
+    pub fn initialize_shared_ahci_port(pci_path: &[(u8, u8)], port: u16, dma_pages_pa: u64) -> Result<(), HypervisorError> {
+        let mut shared_port = SHARED_AHCI_PORT.lock();
+
+        if shared_port.is_none() {
+            *shared_port = Some(Self::from_pci_path(pci_path, port, dma_pages_pa)?);
+        }
+
+        Ok(())
+    }
+
+    /// Writes sectors to the disk attached to the port.
+    ///
+    /// # Arguments
+    ///
+    /// * `lba` - The logical block address of the first sector on the disk.
+    /// * `data` - The data to write, a multiple of `SECTOR_SIZE` of at most a page.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the sectors have been written, otherwise `Err(HypervisorError)`.
+    pub fn write_sectors(&mut self, lba: u64, data: &[u8]) -> Result<(), HypervisorError> {
+        if data.is_empty() || !data.len().is_multiple_of(SECTOR_SIZE) || data.len() > BASE_PAGE_SIZE {
+            return Err(HypervisorError::InvalidBytes);
+        }
+
+        if self.read_port(PORT_CMD) & PORT_CMD_ST == 0 {
+            error!("AHCI port is not running");
+            return Err(HypervisorError::AhciCommandFailed);
+        }
+
+        // Wait for the guest's commands to complete, NCQ and non-NCQ commands can't be mixed.
+        if !self.poll(|port| port.read_port(PORT_CI) == 0 && port.read_port(PORT_SACT) == 0) {
+            error!("AHCI port is busy");
+            return Err(HypervisorError::AhciCommandTimeout);
+        }
+
+        let slot = self.command_slots - 1;
+        let command_list_pa = self.read_port(PORT_CLB) as u64 | (self.read_port(PORT_CLB + 4) as u64) << 32;
+        let header_pa = command_list_pa + slot as u64 * COMMAND_HEADER_SIZE;
+
+        self.buffer().0[..data.len()].copy_from_slice(data);
+        self.build_command_table(lba, data.len());
+
+        let original_header = unsafe { read_volatile(header_pa as *const [u32; 8]) };
+
+        // The interrupt status set by the driver's commands is left for the driver.
+        let original_interrupt_status = self.read_port(PORT_IS);
+        let interrupt_enable = self.read_port(PORT_IE);
+        self.write_port(PORT_IE, 0);
+
+        // CFL (bits 4:0) is the length of the FIS in dwords, W (bit 6) is a write, PRDTL (bits 31:16) is 1 entry.
+        let command_table_pa = self.command_table_pa;
+        let header = [
+            (1 << 16) | (1 << 6) | 5,
+            0,
+            command_table_pa as u32,
+            (command_table_pa >> 32) as u32,
+            0,
+            0,
+            0,
+            0,
+        ];
+        unsafe { write_volatile(header_pa as *mut [u32; 8], header) };
+
+        self.write_port(PORT_CI, 1 << slot);
+
+        let completed = self.poll(|port| port.read_port(PORT_CI) & (1 << slot) == 0 || port.read_port(PORT_IS) & PORT_IS_TFES != 0);
+        let failed = self.read_port(PORT_IS) & PORT_IS_TFES != 0 || self.read_port(PORT_TFD) & PORT_TFD_ERR_BSY != 0;
+
+        // The command is stopped before its slot is handed back to the driver.
+        if !completed || failed {
+            self.recover();
+        }
+
+        unsafe { write_volatile(header_pa as *mut [u32; 8], original_header) };
+
+        self.write_port(PORT_IS, self.read_port(PORT_IS) & !original_interrupt_status);
+        self.write_port(PORT_IE, interrupt_enable);
+
+        match (completed, failed) {
+            (true, false) => {
+                trace!("Wrote {} sectors at LBA {:#x}", data.len() / SECTOR_SIZE, lba);
+                Ok(())
+            }
+            (false, _) => Err(HypervisorError::AhciCommandTimeout),
+            (true, true) => Err(HypervisorError::AhciCommandFailed),
+        }
+    }
+
+    /// Builds the command table of a WRITE DMA EXT command reading all the sectors from the buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `lba` - The logical block address of the first sector on the disk.
+    /// * `length` - The length of the data in bytes.
+    fn build_command_table(&mut self, lba: u64, length: usize) {
+        let sector_count = (length / SECTOR_SIZE) as u16;
+        let buffer_pa = self.buffer_pa;
+        let table = &mut self.command_table().0;
+        table.fill(0);
+
+        // Register - Host to Device FIS, with the C bit set as it's a command.
+        let fis = &mut table[..20];
+        fis[0] = FIS_TYPE_REG_H2D;
+        fis[1] = 0x80;
+        fis[2] = ATA_CMD_WRITE_DMA_EXT;
+        fis[4..7].copy_from_slice(&lba.to_le_bytes()[..3]);
+        // LBA mode.
+        fis[7] = 1 << 6;
+        fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+        fis[12..14].copy_from_slice(&sector_count.to_le_bytes());
+
+        // A single Physical Region Descriptor: data base address and byte count minus one.
+        let prd = &mut table[COMMAND_TABLE_PRDT..COMMAND_TABLE_PRDT + 16];
+        prd[..8].copy_from_slice(&buffer_pa.to_le_bytes());
+        prd[12..16].copy_from_slice(&((length as u32 - 1) & !PRD_INTERRUPT_ON_COMPLETION).to_le_bytes());
+    }
+
+    /// Recovers the port after a command failed or timed out, stopping and restarting the processing of the command
+    /// list, which clears the commands issued.
+    ///
+    /// Reference: Serial ATA Advanced Host Controller Interface (AHCI) 1.3.1: 6.2.2.1 Non-Queued Error Recovery
+    fn recover(&self) {
+        warn!("Recovering AHCI port {:#x}", self.port_pa);
+
+        self.write_port(PORT_CMD, self.read_port(PORT_CMD) & !PORT_CMD_ST);
+
+        if !self.poll(|port| port.read_port(PORT_CMD) & PORT_CMD_CR == 0) {
+            error!("AHCI port {:#x} did not stop", self.port_pa);
+        }
+
+        // The error bits are cleared before the port is started again.
+        self.write_port(PORT_SERR, self.read_port(PORT_SERR));
+        self.write_port(PORT_IS, PORT_IS_TFES);
+
+        self.write_port(PORT_CMD, self.read_port(PORT_CMD) | PORT_CMD_ST);
+    }
+
+    /// Returns the command table, in the first DMA page.
+    fn command_table(&mut self) -> &mut Page {
+        // The page is owned by the port and the host identity maps physical memory.
+        unsafe { &mut *(self.command_table_pa as *mut Page) }
+    }
+
+    /// Returns the buffer of the written sectors, in the second DMA page.
+    fn buffer(&mut self) -> &mut Page {
+        // The page is owned by the port and the host identity maps physical memory.
+        unsafe { &mut *(self.buffer_pa as *mut Page) }
+    }
+
+    /// Polls the port until a condition is met.
+    ///
+    /// # Arguments
+    ///
+    /// * `condition` - The condition to wait for.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the condition has been met, `false` if the polling timed out.
+    fn poll(&self, condition: impl Fn(&Self) -> bool) -> bool {
+        for _ in 0..POLL_ITERATIONS {
+            if condition(self) {
+                return true;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        false
+    }
+
+    /// Reads a register of the port.
+    fn read_port(&self, offset: u64) -> u32 {
+        unsafe { read_volatile((self.port_pa + offset) as *const u32) }
+    }
+
+    /// Writes a register of the port.
+    fn write_port(&self, offset: u64, value: u32) {
+        unsafe { write_volatile((self.port_pa + offset) as *mut u32, value) }
+    }
+}
+
+/// Writes sectors to the disk holding the ESP, if its port has been set up at boot.
+///
+/// # Arguments
+///
+/// * `lba` - The logical block address of the first sector on the disk.
+/// * `data` - The data to write, a multiple of `SECTOR_SIZE` of at most a page.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the sectors have been written, otherwise `Err(HypervisorError)`.
+pub fn write_esp_sectors(lba: u64, data: &[u8]) -> Result<(), HypervisorError> {
+    SHARED_AHCI_PORT
+        .lock()
+        .as_mut()
+        .ok_or(HypervisorError::AhciPortNotInitialized)?
+        .write_sectors(lba, data)
+}
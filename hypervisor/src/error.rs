@@ -244,4 +244,280 @@ pub enum HypervisorError {
 
     #[error("Guest page table unmapping error")]
     GuestPageUnmapError,
+
+    #[error("Unrestricted guest is not supported")]
+    UnrestrictedGuestUnsupported,
+
+    #[error("No Virtual Processor Identifier (VPID) left to allocate")]
+    VpidExhausted,
+
+    #[error("ACPI table not found")]
+    AcpiTableNotFound,
+
+    #[error("DMA remapping is not supported")]
+    DmaRemappingUnsupported,
+
+    #[error("DMA remapping command timed out")]
+    DmaRemappingTimeout,
+
+    #[error("Invalid ACPI table")]
+    InvalidAcpiTable,
+
+    #[error("Hook overlaps an existing hook on the same page")]
+    OverlappingHooks,
+
+    #[error("Unsupported I/O instruction")]
+    UnsupportedIoInstruction,
+
+    #[error("PCI device not found")]
+    PciDeviceNotFound,
+
+    #[error("AHCI command timed out")]
+    AhciCommandTimeout,
+
+    #[error("AHCI command failed")]
+    AhciCommandFailed,
+
+    #[error("Exfiltration file is not initialized")]
+    ExfilFileNotInitialized,
+
+    #[error("Exfiltration file is full")]
+    ExfilFileFull,
+
+    #[error("An asynchronous transfer is already in progress")]
+    TransferInProgress,
+
+    #[error("Shared page is not mapped")]
+    SharedPageNotMapped,
+
+    #[error("VMX-preemption timer is not supported")]
+    PreemptionTimerUnsupported,
+
+    #[error("TPM not found")]
+    TpmNotFound,
+
+    #[error("TPM command timed out")]
+    TpmCommandTimeout,
+
+    #[error("TPM command failed")]
+    TpmCommandFailed,
+
+    #[error("Invalid profiler configuration")]
+    InvalidProfilerConfig,
+
+    #[error("Invalid deterministic mode configuration")]
+    InvalidDeterministicConfig,
+
+    #[error("Invalid hook view")]
+    InvalidHookView,
+
+    #[error("Too many processes with a hook view")]
+    TooManyProcessHookViews,
+
+    #[error("Invalid exception telemetry configuration")]
+    InvalidExceptionTelemetryConfig,
+
+    #[error("Process not found")]
+    ProcessNotFound,
+
+    #[error("Invalid unpacker range")]
+    InvalidUnpackerRange,
+
+    #[error("Too many monitored processes")]
+    TooManyMonitoredProcesses,
+
+    #[error("Invalid code snapshot configuration")]
+    InvalidCodeSnapshotConfig,
+
+    #[error("Invalid TSC compensation configuration")]
+    InvalidTscCompensationConfig,
+
+    #[error("Invalid module image")]
+    InvalidModuleImage,
+
+    #[error("Too many modules with symbols")]
+    TooManySymbolModules,
+
+    #[error("Invalid hook manifest")]
+    InvalidHookManifest,
+
+    #[error("Invalid periodic task")]
+    InvalidPeriodicTask,
+
+    #[error("Too many periodic tasks")]
+    TooManyPeriodicTasks,
+
+    #[error("Invalid search pattern")]
+    InvalidSearchPattern,
+
+    #[error("Invalid XSAVE policy")]
+    InvalidXsavePolicy,
+
+    #[error("Too many processes with bound hooks")]
+    TooManyHookProcesses,
+
+    #[error("Invalid benchmark plan")]
+    InvalidBenchmarkPlan,
+
+    #[error("Invalid hardware breakpoint")]
+    InvalidHardwareBreakpoint,
+
+    #[error("No free hardware breakpoint")]
+    NoFreeHardwareBreakpoint,
+
+    #[error("Invalid exception vector")]
+    InvalidExceptionVector,
+
+    #[error("AHCI port is not initialized")]
+    AhciPortNotInitialized,
+
+    #[error("Configuration file is not initialized")]
+    ConfigFileNotInitialized,
+
+    #[error("Configuration file is full")]
+    ConfigFileFull,
+
+    #[error("Invalid saved configuration")]
+    InvalidSavedConfiguration,
+
+    #[error("Too many MSR context rules")]
+    TooManyMsrContextRules,
+
+    #[error("Linux kernel syscall entry is not captured")]
+    LinuxKernelNotCaptured,
+
+    #[error("No Linux kernel offsets match the running kernel")]
+    LinuxKernelOffsetsNotFound,
+
+    #[error("Too many Linux kernel offsets")]
+    TooManyLinuxKernelOffsets,
+
+    #[error("Invalid Linux kernel release")]
+    InvalidLinuxKernelRelease,
+
+    #[error("Invalid Linux syscall number")]
+    InvalidLinuxSyscallNumber,
+
+    #[error("Invalid Linux task list")]
+    InvalidLinuxTaskList,
+
+    #[error("Single-step requests nested too deep")]
+    SingleStepTooDeep,
+
+    #[error("Invalid execution trace range")]
+    InvalidExecutionTraceRange,
+
+    #[error("Hypercall session already open")]
+    HypercallSessionAlreadyOpen,
+
+    #[error("Hypercall session key unavailable")]
+    HypercallSessionKeyUnavailable,
+
+    #[error("Invalid event stream buffer")]
+    InvalidEventStreamBuffer,
+
+    #[error("Invalid PML5 entry")]
+    InvalidPml5Entry,
+
+    #[error("Paging structure out of range")]
+    PagingStructureOutOfRange,
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+
+    #[error("Kernel module not found")]
+    KernelModuleNotFound,
+
+    #[error("Invalid syscall number")]
+    InvalidSyscallNumber,
+
+    #[error("SSDT not initialized")]
+    SsdtNotInitialized,
+
+    #[error("Unsupported Windows build")]
+    UnsupportedWindowsBuild,
+
+    #[error("Invalid process list")]
+    InvalidProcessList,
+
+    #[error("Invalid VAD tree")]
+    InvalidVadTree,
+
+    #[error("Kernel callbacks not found")]
+    KernelCallbacksNotFound,
+
+    #[error("Invalid OS events configuration")]
+    InvalidOsEventsConfig,
+
+    #[error("Too many code integrity pages")]
+    TooManyCodeIntegrityPages,
+
+    #[error("Invalid protected memory range")]
+    InvalidProtectedMemoryRange,
+
+    #[error("Too many protected memory regions")]
+    TooManyProtectedRegions,
+
+    #[error("Protected memory region not found")]
+    ProtectedRegionNotFound,
+
+    #[error("Invalid EPT view")]
+    InvalidEptView,
+
+    #[error("EPT view not found")]
+    EptViewNotFound,
+
+    #[error("Invalid EPT view page")]
+    InvalidEptViewPage,
+
+    #[error("Too many EPT view pages")]
+    TooManyEptViewPages,
+
+    #[error("Devirtualization gadget not found")]
+    DevirtualizationGadgetNotFound,
+
+    #[error("Devirtualization unsupported")]
+    DevirtualizationUnsupported,
+
+    #[error("Invalid broadcast command")]
+    InvalidBroadcastCommand,
+
+    #[error("Broadcast already in progress")]
+    BroadcastInProgress,
+
+    #[error("Broadcast not acknowledged by every logical processor")]
+    BroadcastTimeout,
+
+    #[error("Resume from sleep unsupported")]
+    SleepResumeUnsupported,
+
+    #[error("Invalid host stack")]
+    InvalidHostStack,
+
+    #[error("Host stack not found")]
+    HostStackNotFound,
+
+    #[error("Guard page unavailable")]
+    GuardPageUnavailable,
+
+    #[error("Invalid VMCS pointer")]
+    InvalidVmcsPointer,
+
+    #[error("Value too wide for the VMCS field")]
+    VmcsFieldValueTooWide,
+
+    #[error("Guest physical address out of range")]
+    GuestPhysicalAddressOutOfRange,
+
+    #[error("Shared page not allocated")]
+    SharedPageNotAllocated,
+
+    #[error("Too many CPUID overrides")]
+    TooManyCpuidHooks,
+
+    #[error("AHCI DMA pages excluded from device DMA")]
+    AhciDmaPagesProtected,
+
+    #[error("Broadcast commands not acknowledged by every logical processor")]
+    BroadcastBacklogFull,
 }
@@ -0,0 +1,184 @@
+//! Provides a guest-to-host exfiltration channel, appending client-supplied buffers to a file on the EFI System
+//! Partition (ESP) after `ExitBootServices`.
+//!
+//! The file is created with a fixed size at boot by the UEFI application, and the disk sectors backing it are
+//! resolved from the FAT32 cluster chain while the firmware's file system driver is still available. The hypervisor
+//! then only writes to these sectors through the `ahci` module, so the file system metadata never changes at runtime
+//! and the data doesn't go through the guest's storage stack.
+//!
+//! The first sector of the file is a header holding `EXFIL_MAGIC` and the number of bytes appended so far, which
+//! is kept across boots so new data is appended after the existing data.
+
+use {
+    crate::{
+        ahci::{write_esp_sectors, SECTOR_SIZE},
+        error::HypervisorError,
+    },
+    alloc::vec::Vec,
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+};
+
+/// The magic value at the start of the header sector of the file ("ILLUEXFL").
+pub const EXFIL_MAGIC: [u8; 8] = *b"ILLUEXFL";
+
+lazy_static! {
+    /// A globally shared instance of `ExfilFile`, protected by a mutex.
+    ///
+    /// The file is set up once at boot by `ExfilFile::initialize_shared_exfil_file`, and is `None` otherwise.
+    pub static ref SHARED_EXFIL_FILE: Mutex<Option<ExfilFile>> = Mutex::new(None);
+}
+
+/// A contiguous run of sectors on the disk backing part of the file.
+#[derive(Debug, Clone, Copy)]
+pub struct SectorExtent {
+    /// The logical block address of the first sector on the disk.
+    pub lba: u64,
+
+    /// The number of sectors.
+    pub count: u64,
+}
+
+impl SectorExtent {
+    /// Returns the logical block address on the disk of a sector of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `extents` - The sectors backing the file, in file order.
+    /// * `sector` - The index of the sector in the file.
+    ///
+    /// # Returns
+    ///
+    /// The logical block address, or `None` if the file has fewer sectors.
+    pub fn lba_of(extents: &[SectorExtent], sector: u64) -> Option<u64> {
+        let mut first_sector = 0;
+
+        for extent in extents {
+            if sector < first_sector + extent.count {
+                return Some(extent.lba + sector - first_sector);
+            }
+
+            first_sector += extent.count;
+        }
+
+        None
+    }
+}
+
+/// The file on the ESP that exfiltrated data is appended to.
+#[derive(Debug)]
+pub struct ExfilFile {
+    /// The sectors backing the file, in file order.
+    extents: Vec<SectorExtent>,
+
+    /// The number of bytes appended to the file, excluding the header sector.
+    length: u64,
+
+    /// The contents of the last, partially written sector.
+    tail_sector: [u8; SECTOR_SIZE],
+}
+
+impl ExfilFile {
+    /// Stores the file in `SHARED_EXFIL_FILE`.
+    ///
+    /// This must be called at boot, after the file has been created and its sectors resolved, and the port of the disk
+    /// holding the ESP has been stored in `SHARED_AHCI_PORT`.
+    ///
+    /// # Arguments
+    ///
+    /// * `extents` - The sectors backing the file, in file order, starting with the header sector.
+    /// * `length` - The number of bytes already appended to the file, from its header.
+    /// * `tail_sector` - The contents of the sector holding the end of the data, if it's partially written.
+    pub fn initialize_shared_exfil_file(extents: Vec<SectorExtent>, length: u64, tail_sector: [u8; SECTOR_SIZE]) {
+        let exfil_file = ExfilFile {
+            extents,
+            length,
+            tail_sector,
+        };
+
+        debug!("Exfiltration file: {:#x} of {:#x} bytes used", length, exfil_file.capacity());
+
+        *SHARED_EXFIL_FILE.lock() = Some(exfil_file);
+    }
+
+    /// Returns the number of bytes that can be appended to the file in total, excluding the header sector.
+    pub fn capacity(&self) -> u64 {
+        let sectors: u64 = self.extents.iter().map(|extent| extent.count).sum();
+        sectors.saturating_sub(1) * SECTOR_SIZE as u64
+    }
+
+    /// Appends data to the file and updates its header.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data to append.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the data has been written to the disk, otherwise `Err(HypervisorError)`.
+    pub fn append(&mut self, data: &[u8]) -> Result<(), HypervisorError> {
+        if self.length + data.len() as u64 > self.capacity() {
+            error!("Exfiltration file is full: {:#x} + {:#x} bytes", self.length, data.len());
+            return Err(HypervisorError::ExfilFileFull);
+        }
+
+        let mut remaining = data;
+
+        while !remaining.is_empty() {
+            let offset = (self.length % SECTOR_SIZE as u64) as usize;
+            let chunk_length = remaining.len().min(SECTOR_SIZE - offset);
+
+            self.tail_sector[offset..offset + chunk_length].copy_from_slice(&remaining[..chunk_length]);
+
+            // The data starts at the sector following the header.
+            let lba = self.lba_of(1 + self.length / SECTOR_SIZE as u64)?;
+            write_esp_sectors(lba, &self.tail_sector)?;
+
+            self.length += chunk_length as u64;
+            remaining = &remaining[chunk_length..];
+
+            if self.length.is_multiple_of(SECTOR_SIZE as u64) {
+                self.tail_sector.fill(0);
+            }
+        }
+
+        self.write_header()
+    }
+
+    /// Writes the header sector with the current length of the data.
+    fn write_header(&mut self) -> Result<(), HypervisorError> {
+        let mut header = [0u8; SECTOR_SIZE];
+        header[..8].copy_from_slice(&EXFIL_MAGIC);
+        header[8..16].copy_from_slice(&self.length.to_le_bytes());
+
+        let lba = self.lba_of(0)?;
+        write_esp_sectors(lba, &header)
+    }
+
+    /// Returns the logical block address on the disk of a sector of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `sector` - The index of the sector in the file.
+    fn lba_of(&self, sector: u64) -> Result<u64, HypervisorError> {
+        SectorExtent::lba_of(&self.extents, sector).ok_or(HypervisorError::ExfilFileFull)
+    }
+}
+
+/// Appends data to the exfiltration file, if it has been set up at boot.
+///
+/// # Arguments
+///
+/// * `data` - The data to append.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the data has been written to the disk, otherwise `Err(HypervisorError)`.
+pub fn append_to_exfil_file(data: &[u8]) -> Result<(), HypervisorError> {
+    SHARED_EXFIL_FILE
+        .lock()
+        .as_mut()
+        .ok_or(HypervisorError::ExfilFileNotInitialized)?
+        .append(data)
+}
@@ -8,6 +8,10 @@ use {crate::intel::vm::Vm, core::mem::size_of};
 /// - Total size in bytes: 5123 * 4096 = 20,971,520 bytes (20 MB).
 pub const STACK_PAGES_PER_PROCESSOR: usize = (size_of::<Vm>() / 0x1000) + 0x1000;
 
+/// Number of guard pages below the stack of each logical processor, unmapped from its host paging.
+/// - Total size in bytes: 4096 bytes (0x1000).
+pub const HOST_STACK_GUARD_PAGES: usize = 1;
+
 /// Total heap size (64 MB) shared across all logical processors.
 /// - Total size in bytes: 64 * 1024 * 1024 = 67,108,864 bytes (64 MB).
 /// - Total size in hexadecimal: 0x4000000 bytes.
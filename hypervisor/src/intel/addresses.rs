@@ -3,19 +3,46 @@
 //! This module introduces the `PhysicalAddress` structure that simplifies operations around
 //! physical addresses. It provides conversions between virtual addresses (VAs) and physical addresses (PAs),
 //! as well as methods for extracting page frame numbers (PFNs) and other address-related information.
+//!
+//! The guest virtual addresses are translated by walking the 4-level or 5-level guest page tables, as selected by the
+//! guest CR4.LA57, then the EPT. The byte ranges of the guest virtual memory are read and written page by page (see
+//! `read_guest_virt_bytes_with_explicit_cr3`), as consecutive guest pages aren't physically contiguous, stopping at
+//! the first page that isn't present, e.g., paged out, instead of failing the whole range.
+//!
+//! With kernel virtual address shadowing (KVA shadow, KPTI), the user address space of a process doesn't map the
+//! kernel, so the kernel virtual addresses, e.g., of a hooked function or of the process structures read on a command
+//! of a user-mode client, are translated with the kernel directory table base when the current CR3 doesn't map them
+//! (see `pa_from_kernel_va`). The kernel directory table base is the guest CR3 the kernel writes IA32_LSTAR with at
+//! boot, the one of the System process (`_KPROCESS.DirectoryTableBase`), as the kernel half of the address spaces is
+//! shared by every process.
 
 use {
     crate::{
         error::HypervisorError,
-        intel::{ept::Ept, paging::PageTables, support::vmread},
+        intel::{
+            ept::Ept,
+            paging::{PageTables, CR3_ADDRESS_MASK},
+            support::vmread,
+        },
+        linux::kernel::SHARED_LINUX_KERNEL,
+        personality::is_linux_guest,
     },
+    core::sync::atomic::{AtomicU64, Ordering},
     log::trace,
     x86::{
-        bits64::paging::{PAddr, BASE_PAGE_SHIFT},
+        bits64::paging::{PAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE},
+        controlregs::Cr4,
         vmx::vmcs,
     },
 };
 
+/// The bit of the virtual addresses of the upper half of the address space, the kernel one, with 4-level and 5-level
+/// paging.
+const KERNEL_VA_BIT: u64 = 1 << 63;
+
+/// The kernel directory table base of a Windows guest, captured on the IA32_LSTAR write of the kernel, or 0.
+static KERNEL_DIRECTORY_TABLE_BASE: AtomicU64 = AtomicU64::new(0);
+
 /// A representation of physical addresses.
 ///
 /// Provides utility methods to work with physical addresses,
@@ -60,8 +87,9 @@ impl PhysicalAddress {
     fn pa_from_va(va: u64, guest_cr3: u64) -> Result<u64, HypervisorError> {
         trace!("Guest CR3: {:#x}", guest_cr3);
 
-        // Translate the guest virtual address (VA) to a guest physical address (PA).
-        let guest_pa = unsafe { PageTables::translate_guest_virtual_to_guest_physical(guest_cr3, va)? };
+        // Translate the guest virtual address (VA) to a guest physical address (PA), with the paging mode of the guest.
+        let five_level = Cr4::from_bits_truncate(vmread(vmcs::guest::CR4) as usize).contains(Cr4::CR4_ENABLE_LA57);
+        let guest_pa = unsafe { PageTables::translate_guest_virtual_to_guest_physical(guest_cr3, va, five_level)? };
         trace!("Guest VA: {:#x} -> Guest PA: {:#x}", va, guest_pa);
 
         // Translate the guest physical address (GPA) to a host physical address (HPA) using the Extended Page Table (EPT).
@@ -113,6 +141,88 @@ impl PhysicalAddress {
         Self::pa_from_va(va, guest_cr3)
     }
 
+    /// Records the kernel directory table base of a Windows guest, used to translate the kernel virtual addresses from
+    /// the user address spaces with KVA shadow.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_cr3` - The guest CR3 the kernel writes IA32_LSTAR with.
+    pub fn set_kernel_directory_table_base(guest_cr3: u64) {
+        trace!("Kernel directory table base: {:#x}", guest_cr3);
+        KERNEL_DIRECTORY_TABLE_BASE.store(guest_cr3, Ordering::Release);
+    }
+
+    /// Returns the directory table base mapping the whole kernel: the kernel CR3 of a Linux guest, or the kernel
+    /// directory table base of a Windows guest, if captured.
+    pub fn kernel_directory_table_base() -> Option<u64> {
+        let kernel_cr3 = match is_linux_guest() {
+            true => SHARED_LINUX_KERNEL.lock().kernel_cr3(),
+            false => KERNEL_DIRECTORY_TABLE_BASE.load(Ordering::Acquire),
+        };
+
+        (kernel_cr3 != 0).then_some(kernel_cr3)
+    }
+
+    /// Converts a kernel virtual address to a host physical address, using the current guest CR3, or the kernel
+    /// directory table base if the current CR3 doesn't map the address, e.g., the user address space of a process
+    /// with KVA shadow.
+    ///
+    /// The current CR3 is tried first, as the session space of Windows is only mapped by the address spaces of the
+    /// processes of the session, not by the one of the System process.
+    ///
+    /// # Arguments
+    ///
+    /// * `va` - The kernel virtual address to translate.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<u64, HypervisorError>` containing the physical address on success, or an error if the translation fails.
+    pub fn pa_from_kernel_va(va: u64) -> Result<u64, HypervisorError> {
+        let guest_cr3 = vmread(vmcs::guest::CR3);
+
+        let error = match Self::pa_from_va(va, guest_cr3) {
+            Ok(host_pa) => return Ok(host_pa),
+            Err(error) => error,
+        };
+
+        match Self::kernel_directory_table_base() {
+            Some(kernel_cr3) if va & KERNEL_VA_BIT != 0 && kernel_cr3 & CR3_ADDRESS_MASK != guest_cr3 & CR3_ADDRESS_MASK => {
+                trace!("Guest VA: {:#x} not mapped by CR3: {:#x}, translating with kernel CR3: {:#x}", va, guest_cr3, kernel_cr3);
+                Self::pa_from_va(va, kernel_cr3)
+            }
+            _ => Err(error),
+        }
+    }
+
+    /// Reads a value from a kernel virtual address, translated as by `pa_from_kernel_va`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ptr` - The kernel virtual address to read from.
+    ///
+    /// # Returns
+    ///
+    /// The value read, or `None` if the address isn't mapped.
+    pub fn read_guest_kernel_virt<T: Sized>(ptr: *const T) -> Option<T> {
+        let phys_addr = PhysicalAddress::pa_from_kernel_va(ptr as u64).ok()? as *const T;
+        Some(unsafe { phys_addr.read() })
+    }
+
+    /// Reads a slice of kernel memory, translated as by `pa_from_kernel_va`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ptr` - The kernel virtual address to start reading from.
+    /// * `len` - The number of elements to read.
+    ///
+    /// # Returns
+    ///
+    /// The borrowed slice, or `None` if the address isn't mapped.
+    pub fn read_guest_kernel_virt_slice<'a, T: Sized>(ptr: *const T, len: usize) -> Option<&'a [T]> {
+        let phys_addr = PhysicalAddress::pa_from_kernel_va(ptr as u64).ok()? as *const T;
+        Some(unsafe { core::slice::from_raw_parts(phys_addr, len) })
+    }
+
     /// Reads a value from a guest virtual address using the current guest CR3.
     ///
     /// This function reads from the guest virtual address using the current CR3 value from the VMCS.
@@ -294,4 +404,76 @@ impl PhysicalAddress {
         }
         Some(())
     }
+
+    /// Reads a range of guest virtual memory into a buffer using a specified guest CR3.
+    ///
+    /// Each page of the range is translated separately, so the range can cross pages that aren't physically contiguous
+    /// or are mapped by pages of different sizes.
+    ///
+    /// # Arguments
+    ///
+    /// * `va` - The guest virtual address to start reading from.
+    /// * `buffer` - The buffer receiving the bytes read.
+    /// * `guest_cr3` - The CR3 value to use for translation.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read, less than the size of the buffer if a page of the range isn't present.
+    pub fn read_guest_virt_bytes_with_explicit_cr3(va: u64, buffer: &mut [u8], guest_cr3: u64) -> usize {
+        Self::for_each_guest_virt_page(va, buffer.len(), guest_cr3, |host_pa, offset, length| unsafe {
+            core::ptr::copy_nonoverlapping(host_pa as *const u8, buffer[offset..].as_mut_ptr(), length);
+        })
+    }
+
+    /// Writes a buffer to a range of guest virtual memory using a specified guest CR3.
+    ///
+    /// Each page of the range is translated separately, so the range can cross pages that aren't physically contiguous
+    /// or are mapped by pages of different sizes. The pages are written regardless of their guest protection.
+    ///
+    /// # Arguments
+    ///
+    /// * `va` - The guest virtual address to start writing to.
+    /// * `data` - The bytes to write.
+    /// * `guest_cr3` - The CR3 value to use for translation.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes written, less than the size of the data if a page of the range isn't present.
+    pub fn write_guest_virt_bytes_with_explicit_cr3(va: u64, data: &[u8], guest_cr3: u64) -> usize {
+        Self::for_each_guest_virt_page(va, data.len(), guest_cr3, |host_pa, offset, length| unsafe {
+            core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), host_pa as *mut u8, length);
+        })
+    }
+
+    /// Calls a function on the part of each page of a range of guest virtual memory, until a page isn't present.
+    ///
+    /// # Arguments
+    ///
+    /// * `va` - The guest virtual address of the range.
+    /// * `size` - The size of the range.
+    /// * `guest_cr3` - The CR3 value to use for translation.
+    /// * `access` - The function called with the host physical address of each part, its offset in the range and its
+    ///   size.
+    ///
+    /// # Returns
+    ///
+    /// The size of the parts accessed.
+    fn for_each_guest_virt_page(va: u64, size: usize, guest_cr3: u64, mut access: impl FnMut(u64, usize, usize)) -> usize {
+        let mut offset = 0;
+
+        while offset < size {
+            let page_va = va.wrapping_add(offset as u64);
+            let length = (BASE_PAGE_SIZE - (page_va as usize % BASE_PAGE_SIZE)).min(size - offset);
+
+            let Ok(host_pa) = Self::pa_from_va(page_va, guest_cr3) else {
+                trace!("Guest VA: {:#x} isn't present, {} of {} bytes accessed", page_va, offset, size);
+                break;
+            };
+
+            access(host_pa, offset, length);
+            offset += length;
+        }
+
+        offset
+    }
 }
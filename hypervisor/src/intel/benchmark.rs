@@ -0,0 +1,515 @@
+//! Provides a benchmarking mode measuring the cost of the interceptions on the hardware it runs on, with the same
+//! methodology for each configuration.
+//!
+//! A benchmark plan lists runs, each with a set of interceptions, and is read by the loader from a UEFI variable or
+//! the ESP. Each boot executes the next run, whose index the loader keeps in another UEFI variable, so the runs are
+//! compared across reboots: the interceptions of the run are enabled before the processors are virtualized, and the
+//! VM exits are counted during a measurement window starting after a warmup, e.g., once the guest has booted and is
+//! idle or running the workload.
+//!
+//! The plan is a text with one directive per line, empty lines and lines starting with `#` being ignored:
+//! - `warmup <seconds>` sets the time between the virtualization and the measurement window, 60 if omitted,
+//! - `duration <seconds>` sets the length of the measurement window, 60 if omitted,
+//! - `run <interception>...` adds a run with the listed interceptions, or with none with `run baseline`.
+//!
+//! The interceptions are:
+//! - `boot_hooks` installs the hooks of the boot-time hook manifest, which are otherwise not installed,
+//! - `cr3_load_exiting` makes MOV to CR3 cause VM exits, as while the processes are tracked,
+//! - `msr_interception` intercepts the accesses to every MSR,
+//! - `tsc_compensation` hides the time spent in VMX root operation from the guest TSC, which is otherwise disabled,
+//! - `rdtsc_exiting` makes `RDTSC` and `RDTSCP` cause VM exits, with the TSC compensation.
+//!
+//! Each logical processor counts its VM exits and their handling time in VMX root operation per basic exit reason
+//! during the window, and merges them into the summary at its first VM exit after the window. The summary is logged
+//! and appended to the exfiltration file, if it is set up, one second after the window, so the summaries of all the
+//! runs accumulate on the ESP. The client reads it with the `ReadBenchmark` command.
+
+use {
+    crate::{
+        error::HypervisorError,
+        exfil::append_to_exfil_file,
+        intel::{
+            bitmap::MsrBitmap,
+            hooks::{
+                boot_manifest::{BootHookManifest, SHARED_BOOT_HOOK_MANIFEST},
+                msr_hook::SHARED_MSR_HOOK_MANAGER,
+            },
+            support::rdtsc,
+            timing::tsc_frequency_hz,
+            tsc_compensation::{TscCompensationConfig, SHARED_TSC_COMPENSATION},
+            vm::Vm,
+            vmerror::{VmxBasicExitReason, EXIT_REASON_COUNT},
+            vmexit::cr::update_cr3_load_exiting,
+        },
+    },
+    alloc::{format, string::String, vec::Vec},
+    bitflags::bitflags,
+    core::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    shared::{BenchmarkExitReason, BenchmarkHeader},
+    spin::Mutex,
+};
+
+/// The maximum number of runs of a plan.
+pub const MAX_BENCHMARK_RUNS: usize = 0x10;
+
+/// The default warmup and measurement window, in seconds.
+const DEFAULT_BENCHMARK_SECONDS: u64 = 60;
+
+/// The TSC at the start of the measurement window, 0 while no run is armed.
+static BENCHMARK_START_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// The TSC at the end of the measurement window.
+static BENCHMARK_END_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// The TSC at which the summary is complete, one second after the measurement window.
+static BENCHMARK_COMPLETE_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the summary is complete and has been reported.
+static BENCHMARK_COMPLETE: AtomicBool = AtomicBool::new(false);
+
+/// Whether MOV to CR3 causes VM exits for the current run, checked without locking by `update_cr3_load_exiting`.
+static CR3_LOAD_EXITING_FORCED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// A globally shared instance of `Benchmark`, protected by a mutex.
+    pub static ref SHARED_BENCHMARK: Mutex<Benchmark> = Mutex::new(Benchmark::new());
+}
+
+bitflags! {
+    /// The interceptions enabled for a run.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct BenchmarkInterceptions: u64 {
+        /// The hooks of the boot-time hook manifest are installed.
+        const BOOT_HOOKS = 1 << 0;
+
+        /// MOV to CR3 causes VM exits.
+        const CR3_LOAD_EXITING = 1 << 1;
+
+        /// The accesses to every MSR cause VM exits.
+        const MSR_INTERCEPTION = 1 << 2;
+
+        /// The time spent in VMX root operation is hidden from the guest TSC.
+        const TSC_COMPENSATION = 1 << 3;
+
+        /// `RDTSC` and `RDTSCP` cause VM exits, with the TSC compensation.
+        const RDTSC_EXITING = 1 << 4;
+    }
+}
+
+/// The runs of a benchmark and their measurement window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchmarkPlan {
+    /// The time between the virtualization and the measurement window, in seconds.
+    warmup_seconds: u64,
+
+    /// The length of the measurement window, in seconds.
+    duration_seconds: u64,
+
+    /// The interceptions of each run, in the order of the plan.
+    runs: Vec<BenchmarkInterceptions>,
+}
+
+impl BenchmarkPlan {
+    /// Parses the text of a plan.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the plan.
+    ///
+    /// # Returns
+    ///
+    /// The plan, or `Err(HypervisorError::InvalidBenchmarkPlan)` if a line is malformed or the plan has no run.
+    pub fn parse(text: &str) -> Result<Self, HypervisorError> {
+        let mut plan = Self {
+            warmup_seconds: DEFAULT_BENCHMARK_SECONDS,
+            duration_seconds: DEFAULT_BENCHMARK_SECONDS,
+            runs: Vec::new(),
+        };
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if !plan.parse_line(line) {
+                error!("Invalid benchmark plan line {}: {}", index + 1, line);
+                return Err(HypervisorError::InvalidBenchmarkPlan);
+            }
+        }
+
+        if plan.runs.is_empty() {
+            error!("The benchmark plan has no run");
+            return Err(HypervisorError::InvalidBenchmarkPlan);
+        }
+
+        Ok(plan)
+    }
+
+    /// Parses a directive of a plan.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The directive, without the leading and trailing whitespace.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the directive is valid.
+    fn parse_line(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            Some("warmup") => match (words.next().and_then(|seconds| seconds.parse().ok()), words.next()) {
+                (Some(seconds), None) => self.warmup_seconds = seconds,
+                _ => return false,
+            },
+            Some("duration") => match (words.next().and_then(|seconds| seconds.parse().ok()), words.next()) {
+                (Some(seconds), None) if seconds != 0 => self.duration_seconds = seconds,
+                _ => return false,
+            },
+            Some("run") if self.runs.len() < MAX_BENCHMARK_RUNS => {
+                let mut interceptions = BenchmarkInterceptions::empty();
+                let mut is_baseline = false;
+
+                for word in words {
+                    match word {
+                        "baseline" => is_baseline = true,
+                        "boot_hooks" => interceptions |= BenchmarkInterceptions::BOOT_HOOKS,
+                        "cr3_load_exiting" => interceptions |= BenchmarkInterceptions::CR3_LOAD_EXITING,
+                        "msr_interception" => interceptions |= BenchmarkInterceptions::MSR_INTERCEPTION,
+                        "tsc_compensation" => interceptions |= BenchmarkInterceptions::TSC_COMPENSATION,
+                        "rdtsc_exiting" => interceptions |= BenchmarkInterceptions::RDTSC_EXITING,
+                        _ => return false,
+                    }
+                }
+
+                // A run lists its interceptions, or is explicitly the baseline.
+                if is_baseline == !interceptions.is_empty() {
+                    return false;
+                }
+
+                self.runs.push(interceptions);
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Returns the number of runs of the plan.
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Enables the interceptions of a run and starts its warmup.
+    ///
+    /// This must be called by the loader before the processors are virtualized, after the boot-time hook manifest is
+    /// loaded and the TSC is calibrated.
+    ///
+    /// # Arguments
+    ///
+    /// * `run_index` - The index of the run in the plan.
+    ///
+    /// # Returns
+    ///
+    /// The interceptions of the run, `Err(HypervisorError::InvalidBenchmarkPlan)` if the plan has no such run, or
+    /// `Err(HypervisorError::InvalidTscCompensationConfig)` if the TSC compensation can't be configured.
+    pub fn arm(&self, run_index: usize) -> Result<BenchmarkInterceptions, HypervisorError> {
+        let interceptions = *self.runs.get(run_index).ok_or(HypervisorError::InvalidBenchmarkPlan)?;
+
+        if !interceptions.contains(BenchmarkInterceptions::BOOT_HOOKS) {
+            *SHARED_BOOT_HOOK_MANIFEST.lock() = BootHookManifest::default();
+        }
+
+        CR3_LOAD_EXITING_FORCED.store(interceptions.contains(BenchmarkInterceptions::CR3_LOAD_EXITING), Ordering::Release);
+
+        if interceptions.contains(BenchmarkInterceptions::MSR_INTERCEPTION) {
+            SHARED_MSR_HOOK_MANAGER.lock().set_bitmap_profile(MsrBitmap::intercept_all());
+        }
+
+        let tsc_compensation = TscCompensationConfig {
+            enabled: interceptions.intersects(BenchmarkInterceptions::TSC_COMPENSATION | BenchmarkInterceptions::RDTSC_EXITING),
+            rdtsc_exiting: interceptions.contains(BenchmarkInterceptions::RDTSC_EXITING),
+            ..TscCompensationConfig::default()
+        };
+        SHARED_TSC_COMPENSATION.lock().configure(tsc_compensation)?;
+
+        let tsc_frequency = tsc_frequency_hz();
+        let start_tsc = rdtsc() + self.warmup_seconds * tsc_frequency;
+        let end_tsc = start_tsc + self.duration_seconds * tsc_frequency;
+
+        *SHARED_BENCHMARK.lock() = Benchmark {
+            run_index: run_index as u64,
+            run_count: self.runs.len() as u64,
+            interceptions,
+            duration_tsc_ticks: end_tsc - start_tsc,
+            ..Benchmark::new()
+        };
+
+        BENCHMARK_END_TSC.store(end_tsc, Ordering::Release);
+        BENCHMARK_COMPLETE_TSC.store(end_tsc + tsc_frequency, Ordering::Release);
+        BENCHMARK_START_TSC.store(start_tsc, Ordering::Release);
+
+        info!(
+            "Benchmark run {}/{} armed: {:?}, {} s warmup, {} s window",
+            run_index + 1,
+            self.runs.len(),
+            interceptions,
+            self.warmup_seconds,
+            self.duration_seconds
+        );
+
+        Ok(interceptions)
+    }
+}
+
+/// The VM exits of a basic exit reason during the measurement window.
+#[derive(Debug, Clone, Copy, Default)]
+struct ExitReasonStatistics {
+    /// The number of VM exits.
+    count: u64,
+
+    /// The total handling time in VMX root operation, in TSC ticks.
+    total_ticks: u64,
+
+    /// The longest handling time in VMX root operation, in TSC ticks.
+    max_ticks: u64,
+}
+
+impl ExitReasonStatistics {
+    /// Adds the VM exits of another logical processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The VM exits of the same reason on the other logical processor.
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.total_ticks += other.total_ticks;
+        self.max_ticks = self.max_ticks.max(other.max_ticks);
+    }
+}
+
+/// The run in progress and the summary of the VM exits of all the logical processors.
+#[derive(Debug)]
+pub struct Benchmark {
+    /// The index of the run in the plan.
+    run_index: u64,
+
+    /// The number of runs of the plan, 0 while no run is armed.
+    run_count: u64,
+
+    /// The interceptions of the run.
+    interceptions: BenchmarkInterceptions,
+
+    /// The length of the measurement window, in TSC ticks.
+    duration_tsc_ticks: u64,
+
+    /// The number of logical processors whose VM exits have been merged.
+    reported_processors: u64,
+
+    /// The VM exits of the reported logical processors, by basic exit reason.
+    exit_statistics: [ExitReasonStatistics; EXIT_REASON_COUNT],
+}
+
+impl Benchmark {
+    /// Creates a new summary without run.
+    fn new() -> Self {
+        Self {
+            run_index: 0,
+            run_count: 0,
+            interceptions: BenchmarkInterceptions::empty(),
+            duration_tsc_ticks: 0,
+            reported_processors: 0,
+            exit_statistics: [ExitReasonStatistics::default(); EXIT_REASON_COUNT],
+        }
+    }
+
+    /// Returns the summary of the run, as read by the client, or `None` while no run is armed.
+    ///
+    /// # Returns
+    ///
+    /// The header of the summary and the VM exits of each basic exit reason with at least one VM exit.
+    pub fn summary(&self) -> Option<(BenchmarkHeader, Vec<BenchmarkExitReason>)> {
+        if self.run_count == 0 {
+            return None;
+        }
+
+        let exit_reasons: Vec<BenchmarkExitReason> = self
+            .exit_statistics
+            .iter()
+            .enumerate()
+            .filter(|(_, statistics)| statistics.count != 0)
+            .map(|(reason, statistics)| BenchmarkExitReason {
+                reason: reason as u64,
+                count: statistics.count,
+                total_ticks: statistics.total_ticks,
+                max_ticks: statistics.max_ticks,
+            })
+            .collect();
+
+        let header = BenchmarkHeader {
+            run_index: self.run_index,
+            run_count: self.run_count,
+            interceptions: self.interceptions.bits(),
+            tsc_frequency_hz: tsc_frequency_hz(),
+            duration_tsc_ticks: self.duration_tsc_ticks,
+            reported_processors: self.reported_processors,
+            is_complete: BENCHMARK_COMPLETE.load(Ordering::Acquire) as u64,
+            exit_reason_count: exit_reasons.len() as u64,
+        };
+
+        Some((header, exit_reasons))
+    }
+
+    /// Formats the summary as text, with the exit rates and the average and longest handling times.
+    fn report(&self) -> String {
+        let tsc_frequency = tsc_frequency_hz().max(1) as u128;
+        let duration_seconds = (self.duration_tsc_ticks as u128 / tsc_frequency).max(1);
+        let nanoseconds = |ticks: u128| ticks * 1_000_000_000 / tsc_frequency;
+
+        let total_exits: u64 = self.exit_statistics.iter().map(|statistics| statistics.count).sum();
+
+        let mut report = format!(
+            "Benchmark run {}/{}: {:?}, {} processors, {} VM exits in {} s ({} per second)\n",
+            self.run_index + 1,
+            self.run_count,
+            self.interceptions,
+            self.reported_processors,
+            total_exits,
+            duration_seconds,
+            total_exits as u128 / duration_seconds
+        );
+
+        for (reason, statistics) in self.exit_statistics.iter().enumerate().filter(|(_, statistics)| statistics.count != 0) {
+            let name = match VmxBasicExitReason::from_u32(reason as u32) {
+                Some(basic_exit_reason) => format!("{:?}", basic_exit_reason),
+                None => format!("{}", reason),
+            };
+
+            report += &format!(
+                "  {}: {} VM exits ({} per second), {} ns average, {} ns max\n",
+                name,
+                statistics.count,
+                statistics.count as u128 / duration_seconds,
+                nanoseconds(statistics.total_ticks as u128 / statistics.count as u128),
+                nanoseconds(statistics.max_ticks as u128)
+            );
+        }
+
+        report
+    }
+}
+
+/// The benchmark state of a logical processor.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorBenchmark {
+    /// Whether the interceptions of the run have been applied to this logical processor.
+    is_initialized: bool,
+
+    /// Whether the VM exits of this logical processor have been merged into the summary.
+    is_reported: bool,
+
+    /// The VM exits of this logical processor during the measurement window, by basic exit reason.
+    exit_statistics: [ExitReasonStatistics; EXIT_REASON_COUNT],
+}
+
+impl ProcessorBenchmark {
+    /// Creates a new state without VM exits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for ProcessorBenchmark {
+    /// Returns a state without VM exits, the arrays of more than 32 elements not implementing `Default`.
+    fn default() -> Self {
+        Self {
+            is_initialized: false,
+            is_reported: false,
+            exit_statistics: [ExitReasonStatistics::default(); EXIT_REASON_COUNT],
+        }
+    }
+}
+
+/// Returns `true` if MOV to CR3 causes VM exits for the current run.
+pub fn is_cr3_load_exiting_forced() -> bool {
+    CR3_LOAD_EXITING_FORCED.load(Ordering::Acquire)
+}
+
+/// Counts a handled VM exit on the current logical processor during the measurement window, and merges the VM exits
+/// of the logical processor into the summary after it.
+///
+/// This must be called last before the time spent in VMX root operation is hidden from the guest TSC, so the handling
+/// time covers the VM exit handler and the synchronizations.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+/// * `exit_tsc` - The TSC at the VM exit.
+pub fn record_benchmark_exit(vm: &mut Vm, basic_exit_reason: VmxBasicExitReason, exit_tsc: u64) {
+    let start_tsc = BENCHMARK_START_TSC.load(Ordering::Acquire);
+
+    if start_tsc == 0 {
+        return;
+    }
+
+    if !vm.benchmark.is_initialized {
+        vm.benchmark.is_initialized = true;
+        update_cr3_load_exiting(vm);
+    }
+
+    if vm.benchmark.is_reported {
+        if !BENCHMARK_COMPLETE.load(Ordering::Acquire) && exit_tsc >= BENCHMARK_COMPLETE_TSC.load(Ordering::Acquire) {
+            complete_benchmark();
+        }
+        return;
+    }
+
+    if exit_tsc < start_tsc {
+        return;
+    }
+
+    if exit_tsc >= BENCHMARK_END_TSC.load(Ordering::Acquire) {
+        let mut benchmark = SHARED_BENCHMARK.lock();
+
+        for (total, statistics) in benchmark.exit_statistics.iter_mut().zip(vm.benchmark.exit_statistics.iter()) {
+            total.merge(statistics);
+        }
+        benchmark.reported_processors += 1;
+
+        vm.benchmark.is_reported = true;
+        return;
+    }
+
+    let handling_ticks = rdtsc().saturating_sub(exit_tsc);
+
+    if let Some(statistics) = vm.benchmark.exit_statistics.get_mut(basic_exit_reason as usize) {
+        statistics.count += 1;
+        statistics.total_ticks += handling_ticks;
+        statistics.max_ticks = statistics.max_ticks.max(handling_ticks);
+    }
+}
+
+/// Reports the summary once, logging it and appending it to the exfiltration file if it is set up.
+fn complete_benchmark() {
+    if BENCHMARK_COMPLETE
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+
+    let report = SHARED_BENCHMARK.lock().report();
+
+    for line in report.lines() {
+        info!("{}", line);
+    }
+
+    if let Err(e) = append_to_exfil_file(report.as_bytes()) {
+        debug!("Benchmark summary not appended to the exfiltration file: {:?}", e);
+    }
+}
@@ -1,8 +1,24 @@
-use bitfield::BitMut;
+use {bitfield::BitMut, core::ops::RangeInclusive};
+
+/// The range of the low MSRs covered by the MSR bitmap.
+pub const MSR_BITMAP_LOW_RANGE: RangeInclusive<u32> = 0x0000_0000..=0x0000_1FFF;
+
+/// The range of the high MSRs covered by the MSR bitmap.
+pub const MSR_BITMAP_HIGH_RANGE: RangeInclusive<u32> = 0xC000_0000..=0xC000_1FFF;
+
+/// Returns `true` if an MSR is covered by the MSR bitmap. The accesses to the other MSRs always cause VM exits.
+///
+/// # Arguments
+///
+/// * `msr` - The MSR.
+pub fn is_msr_in_bitmap(msr: u32) -> bool {
+    MSR_BITMAP_LOW_RANGE.contains(&msr) || MSR_BITMAP_HIGH_RANGE.contains(&msr)
+}
 
 /// Enum representing the type of MSR access.
 ///
 /// There are two types of MSR access: reading from an MSR and writing to an MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum MsrAccessType {
     /// Read access to an MSR.
     Read,
@@ -12,6 +28,7 @@ pub enum MsrAccessType {
 }
 
 /// Specifies the type of MSR operation: either to hook (mask) or Unhook (unmask).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MsrOperation {
     /// Mask the MSR to intercept the operation.
     Hook,
@@ -58,17 +75,107 @@ impl MsrBitmap {
         }
     }
 
+    /// Creates a new MSR bitmap intercepting every access to every MSR.
+    pub fn intercept_all() -> Self {
+        Self {
+            read_low_msrs: [0xFF; 0x400],
+            read_high_msrs: [0xFF; 0x400],
+            write_low_msrs: [0xFF; 0x400],
+            write_high_msrs: [0xFF; 0x400],
+        }
+    }
+
     /// Modifies the interception for a specific MSR based on the specified operation and access type.
     ///
+    /// MSRs outside of the ranges covered by the bitmap are ignored, their accesses always cause VM exits.
+    ///
     /// # Arguments
     ///
     /// * `msr` - The MSR to modify.
     /// * `access` - Specifies the access type read or write for the MSR operation.
     /// * `operation` - Specifies the operation hook (mask) or unhook (unmask) to perform on the MSR.
     pub fn modify_msr_interception(&mut self, msr: u32, access: MsrAccessType, operation: MsrOperation) {
+        if !is_msr_in_bitmap(msr) {
+            return;
+        }
+
+        let (bitmap_section, msr_index, msr_bit) = self.locate_mut(msr, access);
+
+        match operation {
+            MsrOperation::Hook => bitmap_section[msr_index].set_bit(msr_bit, true),
+            MsrOperation::Unhook => bitmap_section[msr_index].set_bit(msr_bit, false),
+        }
+    }
+
+    /// Modifies the interception for a range of MSRs based on the specified operation and access type.
+    ///
+    /// The MSRs of the range outside of the ranges covered by the bitmap are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `msrs` - The range of MSRs to modify.
+    /// * `access` - Specifies the access type read or write for the MSR operation.
+    /// * `operation` - Specifies the operation hook (mask) or unhook (unmask) to perform on the MSRs.
+    pub fn modify_msr_range_interception(&mut self, msrs: RangeInclusive<u32>, access: MsrAccessType, operation: MsrOperation) {
+        for covered_range in [MSR_BITMAP_LOW_RANGE, MSR_BITMAP_HIGH_RANGE] {
+            let start = *msrs.start().max(covered_range.start());
+            let end = *msrs.end().min(covered_range.end());
+
+            for msr in start..=end {
+                let (bitmap_section, msr_index, msr_bit) = self.locate_mut(msr, access);
+                bitmap_section[msr_index].set_bit(msr_bit, matches!(operation, MsrOperation::Hook));
+            }
+        }
+    }
+
+    /// Returns `true` if an access to an MSR causes a VM exit, which is always the case outside of the ranges covered by the bitmap.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `access` - The access type.
+    pub fn is_msr_intercepted(&self, msr: u32, access: MsrAccessType) -> bool {
+        if !is_msr_in_bitmap(msr) {
+            return true;
+        }
+
+        let msr_low = msr & 0x1FFF;
+        let bitmap_section = match (msr >= 0xC000_0000, access) {
+            (true, MsrAccessType::Write) => &self.write_high_msrs,
+            (true, MsrAccessType::Read) => &self.read_high_msrs,
+            (false, MsrAccessType::Write) => &self.write_low_msrs,
+            (false, MsrAccessType::Read) => &self.read_low_msrs,
+        };
+
+        bitmap_section[(msr_low >> 3) as usize] & (1 << (msr_low & 7)) != 0
+    }
+
+    /// Sets the interception of an MSR access to its interception in another bitmap, e.g., to restore it once a hook is removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `access` - The access type.
+    /// * `other` - The bitmap to copy the interception from.
+    pub fn copy_msr_interception(&mut self, msr: u32, access: MsrAccessType, other: &MsrBitmap) {
+        let operation = match other.is_msr_intercepted(msr, access) {
+            true => MsrOperation::Hook,
+            false => MsrOperation::Unhook,
+        };
+
+        self.modify_msr_interception(msr, access, operation);
+    }
+
+    /// Returns the bitmap section, the byte index and the bit of an MSR access, which must be covered by the bitmap.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `access` - The access type.
+    fn locate_mut(&mut self, msr: u32, access: MsrAccessType) -> (&mut [u8; 0x400], usize, usize) {
         let msr_low = msr & 0x1FFF;
         let msr_index = (msr_low >> 3) as usize;
-        let msr_bit = (msr_low & 7) as u8;
+        let msr_bit = (msr_low & 7) as usize;
 
         let bitmap_section = match (msr >= 0xC000_0000, access) {
             (true, MsrAccessType::Write) => &mut self.write_high_msrs,
@@ -77,9 +184,70 @@ impl MsrBitmap {
             (false, MsrAccessType::Read) => &mut self.read_low_msrs,
         };
 
+        (bitmap_section, msr_index, msr_bit)
+    }
+}
+
+/// Specifies the type of I/O port operation: either to hook (mask) or Unhook (unmask).
+pub enum IoOperation {
+    /// Mask the I/O port to intercept IN and OUT instructions.
+    Hook,
+
+    /// Unmask the I/O port to allow IN and OUT instructions.
+    Unhook,
+}
+
+/// Represents the I/O bitmaps used in VMX.
+///
+/// In processors that support the 1-setting of the “use I/O bitmaps” VM-execution control,
+/// the VM-execution control fields include the 64-bit physical addresses of I/O bitmaps A and B,
+/// which are each 4-KByte in size.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.4 I/O-Bitmap Addresses
+#[repr(C, align(4096))]
+#[derive(Debug, Clone, Copy)]
+pub struct IoBitmap {
+    /// I/O bitmap A. Contains one bit for each I/O port in the range 0000H through 7FFFH.
+    pub bitmap_a: [u8; 0x1000],
+
+    /// I/O bitmap B. Contains one bit for each I/O port in the range 8000H through FFFFH.
+    pub bitmap_b: [u8; 0x1000],
+}
+
+impl IoBitmap {
+    /// Creates a new I/O bitmap, initializing all bitmaps to zero.
+    pub fn new() -> Self {
+        Self {
+            bitmap_a: [0; 0x1000],
+            bitmap_b: [0; 0x1000],
+        }
+    }
+
+    /// Modifies the interception for a specific I/O port based on the specified operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port to modify.
+    /// * `operation` - Specifies the operation hook (mask) or unhook (unmask) to perform on the I/O port.
+    pub fn modify_io_interception(&mut self, port: u16, operation: IoOperation) {
+        let port_low = port & 0x7FFF;
+        let port_index = (port_low >> 3) as usize;
+        let port_bit = (port_low & 7) as usize;
+
+        let bitmap_section = match port >= 0x8000 {
+            true => &mut self.bitmap_b,
+            false => &mut self.bitmap_a,
+        };
+
         match operation {
-            MsrOperation::Hook => bitmap_section[msr_index].set_bit(msr_bit as usize, true),
-            MsrOperation::Unhook => bitmap_section[msr_index].set_bit(msr_bit as usize, false),
+            IoOperation::Hook => bitmap_section[port_index].set_bit(port_bit, true),
+            IoOperation::Unhook => bitmap_section[port_index].set_bit(port_bit, false),
         }
     }
 }
+
+impl Default for IoBitmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
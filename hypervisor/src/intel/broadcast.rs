@@ -0,0 +1,390 @@
+//! Provides the commands broadcast by the hypervisor to all the logical processors, so an operation requested through
+//! the hypercall of one of them, e.g., flushing the EPT-derived mappings, pausing the guest or unloading the hypervisor,
+//! applies to each of them. The hypervisor also broadcasts its own commands, e.g., to map the shared page.
+//!
+//! A command is published in a command block, which each logical processor picks up at the end of its VM exits,
+//! executing the command and acknowledging it. The host runs with interrupts disabled, so the other logical processors are made to exit by an NMI of the hypervisor (see
+//! the `nmi` module). A logical processor missing it, e.g., one that just joined, still exits by its VMX-preemption timer
+//! every `POLL_INTERVAL_MS`, if supported. The logical processor broadcasting the command executes it first, then waits
+//! for the acknowledgement of each virtualized logical processor for up to `ACKNOWLEDGE_TIMEOUT_MS`.
+//!
+//! The block keeps the last `COMMAND_QUEUE_LENGTH` commands, so a logical processor acknowledging late, e.g., one idle
+//! without a VMX-preemption timer, still executes each of them in order. A command is refused while the one it would
+//! replace hasn't been acknowledged by every virtualized logical processor.
+//!
+//! A paused logical processor spins in VMX root operation, still executing the later commands, until the pause is
+//! released, replaced by an unload or times out. The guests of the other logical processors keep running, but hang as
+//! soon as they wait for a paused one, e.g., for a TLB shootdown, and Windows bugchecks with CLOCK_WATCHDOG_TIMEOUT
+//! when a processor misses its clock interrupts for too long, hence the limit of `MAX_PAUSE_TIMEOUT_MS`.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            devirtualize::request_devirtualization,
+            exit_statistics::publish_exit_statistics,
+            invept::invept_all_contexts,
+            nmi::{accept_host_nmis, refuse_host_nmis, reset_host_nmis, send_host_nmis_to_others},
+            seqlock::{Generation, Published},
+            shared_page::{apply_map_shared_page, apply_unmap_shared_page, PageMapping},
+            support::rdtsc,
+            timing::tsc_frequency_hz,
+            vm::Vm,
+            vmexit::preemption_timer::{is_preemption_timer_supported, update_preemption_timer},
+        },
+    },
+    core::{
+        hint::spin_loop,
+        sync::atomic::{AtomicU64, Ordering},
+    },
+    lazy_static::lazy_static,
+    log::*,
+    shared::BroadcastCommand,
+    spin::Mutex,
+};
+
+/// The interval in milliseconds at which each logical processor checks the command block without other VM exits.
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// The time in milliseconds the broadcasting logical processor waits for the acknowledgements.
+const ACKNOWLEDGE_TIMEOUT_MS: u64 = 10 * POLL_INTERVAL_MS;
+
+/// The maximum time in milliseconds the logical processors can be paused, well below the clock watchdog of Windows.
+const MAX_PAUSE_TIMEOUT_MS: u64 = 1000;
+
+/// The number of commands kept in the command block for the logical processors acknowledging them late.
+const COMMAND_QUEUE_LENGTH: usize = 8;
+
+/// The last commands broadcast.
+static COMMAND_BLOCK: Published<CommandBlock> = Published::new(CommandBlock {
+    commands: [None; COMMAND_QUEUE_LENGTH],
+    generation: 0,
+    pause: None,
+});
+
+/// The number of logical processors in VMX operation, each of them acknowledging the commands.
+static VIRTUALIZED_PROCESSOR_COUNT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// The acknowledgements of the commands kept in the command block, by generation modulo `COMMAND_QUEUE_LENGTH`,
+    /// protected by a mutex.
+    static ref SHARED_ACKNOWLEDGEMENTS: Mutex<[Acknowledgements; COMMAND_QUEUE_LENGTH]> =
+        Mutex::new([Acknowledgements { generation: 0, count: 0 }; COMMAND_QUEUE_LENGTH]);
+
+    /// Held while a command is broadcast, so a second one is rejected instead of waiting in VMX root operation.
+    static ref BROADCAST_IN_PROGRESS: Mutex<()> = Mutex::new(());
+}
+
+/// A command broadcast to all the logical processors.
+#[derive(Debug, Clone, Copy)]
+pub enum HostCommand {
+    /// A command requested by the user mode client.
+    Client(BroadcastCommand),
+
+    /// Maps the shared page over a guest page, saving its mapping (see `apply_map_shared_page`).
+    MapSharedPage(PageMapping),
+
+    /// Restores the guest page the shared page is mapped over (see `apply_unmap_shared_page`).
+    UnmapSharedPage,
+}
+
+/// The pause of the logical processors, except the one that broadcast it.
+#[derive(Debug, Clone, Copy)]
+struct Pause {
+    /// The initial APIC ID of the logical processor that broadcast the pause.
+    requester_id: u32,
+
+    /// The TSC at which the pause times out.
+    deadline_tsc: u64,
+}
+
+/// The last commands broadcast.
+#[derive(Debug, Clone, Copy)]
+struct CommandBlock {
+    /// The last commands broadcast, by generation modulo `COMMAND_QUEUE_LENGTH`.
+    commands: [Option<HostCommand>; COMMAND_QUEUE_LENGTH],
+
+    /// The number of commands broadcast so far, the generation of the last one.
+    generation: u64,
+
+    /// The pause in effect, if any.
+    pause: Option<Pause>,
+}
+
+/// The acknowledgements of a command.
+#[derive(Debug, Clone, Copy)]
+struct Acknowledgements {
+    /// The generation of the command acknowledged, 0 if none.
+    generation: u64,
+
+    /// The number of logical processors that executed the command.
+    count: u64,
+}
+
+/// The broadcast state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorBroadcast {
+    /// The generation of the last command block picked up on this logical processor.
+    generation: Generation,
+
+    /// The generation of the last command executed on this logical processor.
+    executed_generation: u64,
+
+    /// The number of TSC ticks between two polls of the command block, 0 if not polled.
+    poll_interval_tsc_ticks: u64,
+
+    /// The TSC at which the next poll is due.
+    next_poll_tsc: u64,
+}
+
+impl ProcessorBroadcast {
+    /// Creates a new broadcast state not polling the command block.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if the command block is polled and a poll is due.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The current TSC.
+    pub fn is_poll_due(&self, tsc: u64) -> bool {
+        self.poll_interval_tsc_ticks != 0 && tsc >= self.next_poll_tsc
+    }
+
+    /// Returns the number of TSC ticks until the next poll is due, or `None` if the command block isn't polled.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The current TSC.
+    pub fn ticks_until_next_poll(&self, tsc: u64) -> Option<u64> {
+        match self.poll_interval_tsc_ticks {
+            0 => None,
+            _ => Some(self.next_poll_tsc.saturating_sub(tsc).max(1)),
+        }
+    }
+
+    /// Schedules the next poll one interval from now.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The current TSC.
+    pub fn schedule_next_poll(&mut self, tsc: u64) {
+        self.next_poll_tsc = tsc.wrapping_add(self.poll_interval_tsc_ticks);
+    }
+}
+
+/// Counts the current logical processor among the ones acknowledging the commands, accepts the NMIs of the hypervisor,
+/// and arms its VMX-preemption timer to poll the command block if supported.
+///
+/// This is called once the VMCS is active, before the first VM entry, and on the SIPI starting the logical processor
+/// after an INIT. The commands broadcast before are ignored, and counted as acknowledged.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn join_broadcasts(vm: &mut Vm) {
+    // The acknowledgements are locked so no command is published between its generation and the count.
+    let mut acknowledgements = SHARED_ACKNOWLEDGEMENTS.lock();
+
+    vm.broadcast = ProcessorBroadcast::new();
+    vm.broadcast.generation = Generation::STALE;
+    vm.broadcast.executed_generation = COMMAND_BLOCK.sync(&mut vm.broadcast.generation).map_or(0, |block| block.generation);
+
+    acknowledgements.iter_mut().for_each(|acknowledgements| acknowledgements.count += 1);
+    let count = VIRTUALIZED_PROCESSOR_COUNT.fetch_add(1, Ordering::AcqRel) + 1;
+    drop(acknowledgements);
+
+    if is_preemption_timer_supported() {
+        vm.broadcast.poll_interval_tsc_ticks = (tsc_frequency_hz() / 1000 * POLL_INTERVAL_MS).max(1);
+        vm.broadcast.schedule_next_poll(rdtsc());
+        update_preemption_timer(vm);
+    } else {
+        warn!("VMX-preemption timer unsupported, idle logical processors acknowledge the broadcast commands late");
+    }
+
+    accept_host_nmis(vm);
+
+    debug!("{} logical processors acknowledging the broadcast commands", count);
+}
+
+/// Stops counting the current logical processor among the ones acknowledging the commands, and refuses the NMIs of the
+/// hypervisor unless already refused, once it left VMX operation or while it waits for a SIPI.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn leave_broadcasts(vm: &Vm) {
+    refuse_host_nmis(vm);
+    VIRTUALIZED_PROCESSOR_COUNT.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Stops counting all the logical processors among the ones acknowledging the commands, on the resume from S3, as only
+/// the logical processor that entered the sleep state is virtualized again.
+pub fn reset_broadcasts() {
+    reset_host_nmis();
+    VIRTUALIZED_PROCESSOR_COUNT.store(0, Ordering::Release);
+}
+
+/// Broadcasts a command of the user mode client to all the logical processors, as `broadcast_host_command`.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `command` - The command to broadcast.
+///
+/// # Returns
+///
+/// The result of `broadcast_host_command`.
+pub fn broadcast(vm: &mut Vm, command: BroadcastCommand) -> Result<(), HypervisorError> {
+    broadcast_host_command(vm, HostCommand::Client(command))
+}
+
+/// Broadcasts a command to all the logical processors, executing it on the current one first, then waits for each of
+/// them to acknowledge it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `command` - The command to broadcast.
+///
+/// # Returns
+///
+/// * `Ok(())` - If each virtualized logical processor executed the command.
+/// * `Err(HypervisorError::BroadcastInProgress)` - If another command is being broadcast.
+/// * `Err(HypervisorError::BroadcastBacklogFull)` - If the command kept in the block that this one would replace
+///   hasn't been acknowledged by every virtualized logical processor yet.
+/// * `Err(HypervisorError::InvalidBroadcastCommand)` - If the timeout of a pause is 0 or above `MAX_PAUSE_TIMEOUT_MS`.
+/// * `Err(HypervisorError::BroadcastTimeout)` - If some logical processors didn't acknowledge the command in time,
+///   e.g., because they're busy in a long VM exit. They still execute it on a later VM exit.
+/// * `Err(HypervisorError)` - If the unloading can't be requested.
+pub fn broadcast_host_command(vm: &mut Vm, command: HostCommand) -> Result<(), HypervisorError> {
+    let Some(_in_progress) = BROADCAST_IN_PROGRESS.try_lock() else {
+        return Err(HypervisorError::BroadcastInProgress);
+    };
+
+    if let HostCommand::Client(BroadcastCommand::Pause { timeout_ms }) = command {
+        if timeout_ms == 0 || timeout_ms > MAX_PAUSE_TIMEOUT_MS {
+            return Err(HypervisorError::InvalidBroadcastCommand);
+        }
+    }
+
+    // The commands are broadcast one at a time, so the command block only changes here.
+    let previous = COMMAND_BLOCK.read();
+    let generation = previous.generation + 1;
+    let slot = generation as usize % COMMAND_QUEUE_LENGTH;
+
+    {
+        let replaced = SHARED_ACKNOWLEDGEMENTS.lock()[slot];
+        let processor_count = VIRTUALIZED_PROCESSOR_COUNT.load(Ordering::Acquire);
+
+        if replaced.generation != 0 && replaced.count < processor_count {
+            warn!(
+                "Generation {} acknowledged by {} of {} logical processors, refusing {:?}",
+                replaced.generation, replaced.count, processor_count, command
+            );
+            return Err(HypervisorError::BroadcastBacklogFull);
+        }
+    }
+
+    if let HostCommand::Client(BroadcastCommand::Unload) = command {
+        request_devirtualization()?;
+    }
+
+    let mut commands = previous.commands;
+    commands[slot] = Some(command);
+
+    let block = CommandBlock {
+        commands,
+        generation,
+        pause: match command {
+            HostCommand::Client(BroadcastCommand::Pause { timeout_ms }) => Some(Pause {
+                requester_id: vm.cpuid_feature_info.initial_local_apic_id() as u32,
+                deadline_tsc: rdtsc().wrapping_add(tsc_frequency_hz() / 1000 * timeout_ms),
+            }),
+            HostCommand::Client(BroadcastCommand::Resume | BroadcastCommand::Unload) => None,
+            HostCommand::Client(BroadcastCommand::FlushEpt | BroadcastCommand::PublishExitStatistics { .. })
+            | HostCommand::MapSharedPage(_)
+            | HostCommand::UnmapSharedPage => previous.pause,
+        },
+    };
+
+    {
+        let mut acknowledgements = SHARED_ACKNOWLEDGEMENTS.lock();
+        acknowledgements[slot] = Acknowledgements { generation, count: 0 };
+        COMMAND_BLOCK.publish(block);
+    }
+
+    debug!("Broadcasting {:?}, generation {}", command, block.generation);
+
+    sync_broadcast(vm);
+
+    let nmi_count = send_host_nmis_to_others(vm);
+    trace!("{:?} signaled to {} logical processors", command, nmi_count);
+
+    let deadline_tsc = rdtsc().wrapping_add(tsc_frequency_hz() / 1000 * ACKNOWLEDGE_TIMEOUT_MS);
+
+    loop {
+        let acknowledged = SHARED_ACKNOWLEDGEMENTS.lock()[slot].count;
+        let processor_count = VIRTUALIZED_PROCESSOR_COUNT.load(Ordering::Acquire);
+
+        if acknowledged >= processor_count {
+            debug!("{:?} acknowledged by {} logical processors", command, acknowledged);
+            return Ok(());
+        }
+
+        if rdtsc() >= deadline_tsc {
+            warn!("{:?} acknowledged by {} of {} logical processors", command, acknowledged, processor_count);
+            return Err(HypervisorError::BroadcastTimeout);
+        }
+
+        spin_loop();
+    }
+}
+
+/// Executes the commands broadcast since the last one executed on the current logical processor and acknowledges them,
+/// then spins while the logical processor is paused.
+///
+/// This is called at the end of every VM exit, and only locks the acknowledgements when a command was broadcast.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_broadcast(vm: &mut Vm) {
+    let processor_id = vm.cpuid_feature_info.initial_local_apic_id() as u32;
+
+    while let Some(block) = COMMAND_BLOCK.sync(&mut vm.broadcast.generation) {
+        // A command isn't replaced before each logical processor executed it, so none is missed.
+        let first_generation = (vm.broadcast.executed_generation + 1).max(block.generation.saturating_sub(COMMAND_QUEUE_LENGTH as u64 - 1));
+
+        for generation in first_generation..=block.generation {
+            let command = block.commands[generation as usize % COMMAND_QUEUE_LENGTH];
+            trace!("Executing broadcast {:?}, generation {}", command, generation);
+
+            // Unloading needs nothing else here, each logical processor leaving VMX operation at the end of this VM
+            // exit once the guest can be resumed natively.
+            match command {
+                Some(HostCommand::Client(BroadcastCommand::FlushEpt)) => invept_all_contexts(),
+                Some(HostCommand::Client(BroadcastCommand::PublishExitStatistics { reset })) => publish_exit_statistics(vm, generation, reset),
+                Some(HostCommand::MapSharedPage(saved_mapping)) => apply_map_shared_page(vm, saved_mapping),
+                Some(HostCommand::UnmapSharedPage) => apply_unmap_shared_page(vm),
+                _ => {}
+            }
+
+            let acknowledgements = &mut SHARED_ACKNOWLEDGEMENTS.lock()[generation as usize % COMMAND_QUEUE_LENGTH];
+            if acknowledgements.generation == generation {
+                acknowledgements.count += 1;
+            }
+        }
+
+        vm.broadcast.executed_generation = block.generation;
+
+        let Some(pause) = block.pause.filter(|pause| pause.requester_id != processor_id) else {
+            continue;
+        };
+
+        while !COMMAND_BLOCK.is_changed(vm.broadcast.generation) && rdtsc() < pause.deadline_tsc {
+            spin_loop();
+        }
+    }
+}
@@ -74,6 +74,10 @@ pub struct GuestRegisters {
     pub xmm15: M128A,
     pub original_lstar: u64,
     pub hook_lstar: u64,
+    pub original_sysenter_eip: u64,
+    pub hook_sysenter_eip: u64,
+    pub original_sysenter_esp: u64,
+    pub hook_sysenter_esp: u64,
 }
 
 #[repr(C)]
@@ -84,6 +88,34 @@ pub struct M128A {
     pub high: i64,
 }
 
+impl GuestRegisters {
+    /// Returns a mutable reference to a general-purpose register by its index in instruction encodings.
+    ///
+    /// # Arguments
+    ///
+    /// * `register` - The index of the register (0 = RAX ... 15 = R15).
+    pub fn register_mut(&mut self, register: usize) -> &mut u64 {
+        match register {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsp,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            _ => &mut self.r15,
+        }
+    }
+}
+
 impl fmt::Debug for GuestRegisters {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("GuestRegisters {\n")?;
@@ -0,0 +1,300 @@
+//! Provides automatic shadow copies of transient code pages, so short-lived code (e.g., shellcode) is preserved for
+//! later extraction even if the guest frees or overwrites it.
+//!
+//! While enabled, the pages seen to become executable after being written are copied into host memory, tagged with
+//! the process, the TSC and what observed them:
+//! - the pages dumped by the `unpacker` module on a write→execute transition,
+//! - the pages of a range that the `allocation_monitor` module alerts as made executable while writable, or as given
+//!   the RWX protection, when `NtProtectVirtualMemory` returns. The pages not mapped yet are skipped, and the RWX
+//!   allocations aren't copied, as they contain no code yet.
+//!
+//! The copies are kept until the client drains them with the `ReadCodeSnapshots` command, in a store of bounded total
+//! size allocated when the copies are enabled. A page identical to a copy of the same page of the same process still
+//! held is skipped. The copies are rate limited for the whole system: beyond the configured number of copies per
+//! second, the pages are only counted as suppressed, and when the store is full, they are counted as dropped until it
+//! is drained.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            support::{rdtsc, vmread},
+            timing::tsc_frequency_hz,
+        },
+        windows::eprocess::ProcessInformation,
+    },
+    alloc::{boxed::Box, collections::VecDeque, vec::Vec},
+    core::sync::atomic::{AtomicBool, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    shared::{CodeSnapshot, CodeSnapshotSource, CODE_SNAPSHOT_PAGE_SIZE},
+    spin::Mutex,
+    x86::{bits64::paging::BASE_PAGE_SIZE, vmx::vmcs},
+};
+
+/// The maximum number of copies held until they are drained, bounding the store to 1 MiB.
+pub const CODE_SNAPSHOT_CAPACITY: usize = 0x100;
+
+/// Whether the copies are enabled, checked without locking by the observers.
+static CODE_SNAPSHOTS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// A globally shared instance of `CodeSnapshotStore`, protected by a mutex.
+    pub static ref SHARED_CODE_SNAPSHOTS: Mutex<CodeSnapshotStore> = Mutex::new(CodeSnapshotStore::new());
+}
+
+/// A copy held by the store, with the hash of its content.
+#[derive(Debug)]
+struct HeldSnapshot {
+    /// The FNV-1a hash of the content, to skip the identical copies.
+    hash: u64,
+
+    /// The copy, boxed so the store only moves pointers.
+    snapshot: Box<CodeSnapshot>,
+}
+
+/// The copies of the transient code pages and the rate limit.
+#[derive(Debug)]
+pub struct CodeSnapshotStore {
+    /// The maximum number of copies per second for the whole system.
+    max_snapshots_per_second: u64,
+
+    /// The copies held and not drained yet, oldest first.
+    snapshots: VecDeque<HeldSnapshot>,
+
+    /// The number of pages dropped since the last drain because the store was full.
+    dropped_snapshots: u64,
+
+    /// The number of pages suppressed by the rate limit since the last drain.
+    suppressed_snapshots: u64,
+
+    /// The TSC at which the current one-second window of the rate limit started.
+    window_start_tsc: u64,
+
+    /// The number of copies made in the current window.
+    window_snapshot_count: u64,
+}
+
+impl CodeSnapshotStore {
+    /// Creates a new disabled store, without allocating it.
+    fn new() -> Self {
+        Self {
+            max_snapshots_per_second: 0,
+            snapshots: VecDeque::new(),
+            dropped_snapshots: 0,
+            suppressed_snapshots: 0,
+            window_start_tsc: 0,
+            window_snapshot_count: 0,
+        }
+    }
+
+    /// Enables the copies with a rate limit, or disables them, keeping the copies held so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether the pages are copied.
+    /// * `max_snapshots_per_second` - The maximum number of copies per second for the whole system.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the store has been configured, or `Err(HypervisorError::InvalidCodeSnapshotConfig)` if the rate
+    /// limit would suppress every copy.
+    pub fn configure(&mut self, enabled: bool, max_snapshots_per_second: u64) -> Result<(), HypervisorError> {
+        if enabled && max_snapshots_per_second == 0 {
+            return Err(HypervisorError::InvalidCodeSnapshotConfig);
+        }
+
+        debug!("Code snapshots configured: enabled: {}, {} per second", enabled, max_snapshots_per_second);
+
+        if enabled {
+            self.snapshots.reserve_exact(CODE_SNAPSHOT_CAPACITY.saturating_sub(self.snapshots.len()));
+        }
+
+        self.max_snapshots_per_second = max_snapshots_per_second;
+        CODE_SNAPSHOTS_ENABLED.store(enabled, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Returns the number of copies held.
+    pub fn held_snapshot_count(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Removes the oldest copies from the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_snapshots` - The maximum number of copies to remove.
+    ///
+    /// # Returns
+    ///
+    /// The copies removed, oldest first, and the numbers of pages dropped and suppressed since the last drain.
+    pub fn drain(&mut self, max_snapshots: usize) -> (Vec<CodeSnapshot>, u64, u64) {
+        let count = self.snapshots.len().min(max_snapshots);
+        let snapshots = self.snapshots.drain(..count).map(|held| *held.snapshot).collect();
+
+        (snapshots, core::mem::take(&mut self.dropped_snapshots), core::mem::take(&mut self.suppressed_snapshots))
+    }
+
+    /// Returns `true` if a page can be copied now, counting it as suppressed or dropped otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The TSC of the copy.
+    /// * `page_count` - The number of pages counted if the copy can't be made, e.g., the rest of a range.
+    fn admit(&mut self, tsc: u64, page_count: u64) -> bool {
+        if tsc.wrapping_sub(self.window_start_tsc) >= tsc_frequency_hz() {
+            self.window_start_tsc = tsc;
+            self.window_snapshot_count = 0;
+        }
+
+        if self.window_snapshot_count >= self.max_snapshots_per_second {
+            self.suppressed_snapshots += page_count;
+            return false;
+        }
+
+        if self.snapshots.len() >= CODE_SNAPSHOT_CAPACITY {
+            self.dropped_snapshots += page_count;
+            return false;
+        }
+
+        true
+    }
+
+    /// Adds a copy to the store, unless an identical copy of the same page of the same process is held.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - The copy, admitted by `admit`.
+    fn push(&mut self, snapshot: Box<CodeSnapshot>) {
+        let hash = fnv1a_hash(&snapshot.data);
+
+        let duplicate = self.snapshots.iter().any(|held| {
+            held.hash == hash
+                && held.snapshot.process_id == snapshot.process_id
+                && held.snapshot.guest_va == snapshot.guest_va
+                && held.snapshot.data == snapshot.data
+        });
+
+        if duplicate {
+            trace!("Skipping identical code snapshot of {:#x} in process {}", snapshot.guest_va, snapshot.process_id);
+            return;
+        }
+
+        debug!(
+            "Code snapshot of {:#x} (PA: {:#x}) in process {} from source {}",
+            snapshot.guest_va, snapshot.guest_pa, snapshot.process_id, snapshot.source
+        );
+
+        self.window_snapshot_count += 1;
+        self.snapshots.push_back(HeldSnapshot { hash, snapshot });
+    }
+}
+
+/// Returns `true` if the pages seen to become executable after being written are copied.
+pub fn code_snapshots_enabled() -> bool {
+    CODE_SNAPSHOTS_ENABLED.load(Ordering::Acquire)
+}
+
+/// Copies a guest page that became executable after being written, if the copies are enabled.
+///
+/// # Arguments
+///
+/// * `source` - What observed the page.
+/// * `process_id` - The ID of the process the page belongs to.
+/// * `cr3` - The guest CR3 of the process.
+/// * `guest_va` - The virtual address of the page in the process.
+/// * `guest_page_pa` - The guest physical address of the page.
+pub fn snapshot_code_page(source: CodeSnapshotSource, process_id: u64, cr3: u64, guest_va: u64, guest_page_pa: u64) {
+    if !code_snapshots_enabled() {
+        return;
+    }
+
+    let tsc = rdtsc();
+    let mut store = SHARED_CODE_SNAPSHOTS.lock();
+
+    if !store.admit(tsc, 1) {
+        return;
+    }
+
+    store.push(copy_page(source, tsc, process_id, cr3, guest_va, guest_page_pa));
+}
+
+/// Copies the mapped pages of a range of the current process that became executable after being written, if the
+/// copies are enabled.
+///
+/// # Arguments
+///
+/// * `source` - What observed the range.
+/// * `base_address` - The base address of the range in the current process.
+/// * `size` - The size of the range in bytes.
+pub fn snapshot_code_range(source: CodeSnapshotSource, base_address: u64, size: u64) {
+    if !code_snapshots_enabled() {
+        return;
+    }
+
+    let Some(process_id) = ProcessInformation::get_current_process_id() else {
+        return;
+    };
+
+    let cr3 = vmread(vmcs::guest::CR3);
+    let base_va = base_address & !(BASE_PAGE_SIZE as u64 - 1);
+    let page_count = base_address.saturating_add(size).saturating_sub(base_va).div_ceil(BASE_PAGE_SIZE as u64);
+
+    for index in 0..page_count {
+        let guest_va = base_va + index * BASE_PAGE_SIZE as u64;
+
+        // The pages never accessed since they were committed aren't mapped, and contain no code.
+        let Ok(guest_pa) = PhysicalAddress::pa_from_va_with_current_cr3(guest_va) else {
+            continue;
+        };
+
+        let tsc = rdtsc();
+        let mut store = SHARED_CODE_SNAPSHOTS.lock();
+
+        if !store.admit(tsc, page_count - index) {
+            return;
+        }
+
+        store.push(copy_page(source, tsc, process_id, cr3, guest_va, guest_pa & !(BASE_PAGE_SIZE as u64 - 1)));
+    }
+}
+
+/// Copies a guest page into a new copy.
+///
+/// # Arguments
+///
+/// * `source` - What observed the page.
+/// * `tsc` - The TSC of the copy.
+/// * `process_id` - The ID of the process the page belongs to.
+/// * `cr3` - The guest CR3 of the process.
+/// * `guest_va` - The virtual address of the page in the process.
+/// * `guest_page_pa` - The guest physical address of the page.
+fn copy_page(source: CodeSnapshotSource, tsc: u64, process_id: u64, cr3: u64, guest_va: u64, guest_page_pa: u64) -> Box<CodeSnapshot> {
+    let mut snapshot = Box::new(CodeSnapshot {
+        tsc,
+        process_id,
+        cr3,
+        guest_va,
+        guest_pa: guest_page_pa,
+        source: source as u64,
+        data: [0; CODE_SNAPSHOT_PAGE_SIZE],
+    });
+
+    // The guest physical memory is identity mapped in the host.
+    unsafe { core::ptr::copy_nonoverlapping(guest_page_pa as *const u8, snapshot.data.as_mut_ptr(), CODE_SNAPSHOT_PAGE_SIZE) };
+
+    snapshot
+}
+
+/// Computes the FNV-1a hash of the content of a page.
+///
+/// # Arguments
+///
+/// * `data` - The content of the page.
+fn fnv1a_hash(data: &[u8]) -> u64 {
+    data.iter()
+        .fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
@@ -0,0 +1,525 @@
+//! Provides the virtualization of the debug registers, so the hypervisor places hardware breakpoints that the guest
+//! can neither discover nor clobber.
+//!
+//! While a hypervisor breakpoint is set, `MOV DR` causes VM exits on every logical processor, and the guest reads and
+//! writes a per-processor shadow of DR0-DR3, DR6 and DR7 instead of the registers. The registers hold the guest values,
+//! except for the slots of the hypervisor breakpoints, whose address and DR7 enable, condition and length bits are
+//! those of the hypervisor. A breakpoint the guest sets in such a slot is kept in the shadow but never triggers.
+//!
+//! Debug exceptions are intercepted meanwhile: the conditions of the hypervisor breakpoints are passed to the hooks
+//! implemented with them (see the `hardware_breakpoint_hook` module) or the registered handler and removed, then the remaining ones are reflected to the guest, which observes them in its
+//! shadow of DR6. Hitting an instruction breakpoint resumes the guest with RFLAGS.RF set, so the instruction executes
+//! instead of faulting again.
+//!
+//! DR7.GD would raise the general-detect debug exception before the VM exit, so it's only set in the shadow and
+//! emulated by the `MOV DR` handler.
+//!
+//! The breakpoints are linear addresses, so they apply to every address space, and each logical processor programs them
+//! at its first VM exit after they changed. The shadow is captured from the registers when the first breakpoint is set, and written back to them when
+//! the last one is removed.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            events::EventInjection,
+            hooks::hardware_breakpoint_hook::dispatch_hardware_breakpoint_hook,
+            seqlock::{Generation, Published},
+            support::{dr0_read, dr0_write, dr1_read, dr1_write, dr2_read, dr2_write, dr3_read, dr3_write, dr6_read, dr6_write, vmread, vmwrite},
+            vm::Vm,
+            vmerror::ExceptionInterrupt,
+        },
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::vmx::vmcs::{self, control::PrimaryControls},
+};
+
+/// The number of hardware breakpoints, DR0-DR3.
+pub const HARDWARE_BREAKPOINT_COUNT: usize = 4;
+
+/// The B0-B3 bits of DR6, and of the exit qualification of debug exceptions, reporting the breakpoints hit.
+const DR6_BREAKPOINT_CONDITIONS: u64 = 0xF;
+
+/// The BD bit of DR6: the next instruction accesses a debug register while DR7.GD is set.
+const DR6_DEBUG_REGISTER_ACCESS: u64 = 1 << 13;
+
+/// The BS bit of DR6: single-step.
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+/// The conditions of DR6 set by debug exceptions.
+const DR6_GUEST_CONDITIONS: u64 = DR6_BREAKPOINT_CONDITIONS | DR6_DEBUG_REGISTER_ACCESS | DR6_SINGLE_STEP;
+
+/// The RTM bit of DR6, cleared rather than set when the debug exception occurred in a transaction.
+const DR6_RTM: u64 = 1 << 16;
+
+/// The GD bit of DR7, enabling the general-detect condition.
+const DR7_GENERAL_DETECT: u64 = 1 << 13;
+
+/// The hardware breakpoints programmed on the logical processors.
+static PUBLISHED_BREAKPOINTS: Published<[Option<HardwareBreakpoint>; HARDWARE_BREAKPOINT_COUNT]> = Published::new([None; HARDWARE_BREAKPOINT_COUNT]);
+
+lazy_static! {
+    /// A globally shared instance of `HardwareBreakpoints`, protected by a mutex.
+    pub static ref SHARED_HARDWARE_BREAKPOINTS: Mutex<HardwareBreakpoints> = Mutex::new(HardwareBreakpoints::new());
+}
+
+/// A handler called in VMX root operation when a hypervisor breakpoint is hit, which may change the guest registers,
+/// e.g., to redirect the execution.
+pub type HardwareBreakpointHandler = fn(vm: &mut Vm, event: &HardwareBreakpointEvent);
+
+/// The accesses triggering a hardware breakpoint, as encoded in the R/W bits of DR7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BreakpointCondition {
+    /// The execution of the instruction at the address, before it executes.
+    Execute = 0,
+
+    /// A write to the address, after the instruction executed.
+    Write = 1,
+
+    /// A read or a write of the address, after the instruction executed.
+    ReadWrite = 3,
+}
+
+/// A breakpoint in a debug register, owned by the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HardwareBreakpoint {
+    /// The linear address of the breakpoint.
+    address: u64,
+
+    /// The accesses triggering the breakpoint.
+    condition: BreakpointCondition,
+
+    /// The size of the monitored range in bytes: 1, 2, 4 or 8.
+    length: u8,
+}
+
+impl HardwareBreakpoint {
+    /// Creates a breakpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The linear address of the breakpoint.
+    /// * `condition` - The accesses triggering the breakpoint.
+    /// * `length` - The size of the monitored range in bytes: 1, 2, 4 or 8, and 1 for instruction breakpoints.
+    ///
+    /// # Returns
+    ///
+    /// The breakpoint, or `Err(HypervisorError::InvalidHardwareBreakpoint)` if the length isn't supported or the
+    /// address isn't aligned to it.
+    pub fn new(address: u64, condition: BreakpointCondition, length: u8) -> Result<Self, HypervisorError> {
+        if !matches!(length, 1 | 2 | 4 | 8) || (condition == BreakpointCondition::Execute && length != 1) || !address.is_multiple_of(length as u64) {
+            return Err(HypervisorError::InvalidHardwareBreakpoint);
+        }
+
+        Ok(Self { address, condition, length })
+    }
+
+    /// Returns the linear address of the breakpoint.
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// Returns the accesses triggering the breakpoint.
+    pub fn condition(&self) -> BreakpointCondition {
+        self.condition
+    }
+
+    /// Returns the DR7 bits enabling the breakpoint in a slot: the global enable bit, and the R/W and LEN fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The index of the debug register holding the address.
+    fn dr7_bits(&self, slot: usize) -> u64 {
+        let length = match self.length {
+            1 => 0b00,
+            2 => 0b01,
+            8 => 0b10,
+            _ => 0b11,
+        };
+
+        (1 << (slot * 2 + 1)) | ((self.condition as u64 | length << 2) << (16 + slot * 4))
+    }
+}
+
+/// Returns the DR7 bits controlling a slot: its local and global enable bits, and its R/W and LEN fields.
+///
+/// # Arguments
+///
+/// * `slot` - The index of the debug register.
+fn dr7_slot_mask(slot: usize) -> u64 {
+    (0b11 << (slot * 2)) | (0xF << (16 + slot * 4))
+}
+
+/// A hit of a hypervisor breakpoint on a logical processor.
+#[derive(Debug, Clone, Copy)]
+pub struct HardwareBreakpointEvent {
+    /// The initial APIC ID of the logical processor.
+    pub processor_id: u32,
+
+    /// The index of the debug register holding the breakpoint.
+    pub slot: usize,
+
+    /// The breakpoint hit.
+    pub breakpoint: HardwareBreakpoint,
+
+    /// The guest RIP: the instruction for instruction breakpoints, the one following the access for data breakpoints.
+    pub guest_rip: u64,
+}
+
+/// The hardware breakpoints of the hypervisor, shared by all the logical processors.
+#[derive(Debug)]
+pub struct HardwareBreakpoints {
+    /// The breakpoint in each slot, or `None` for the slots left to the guest.
+    breakpoints: [Option<HardwareBreakpoint>; HARDWARE_BREAKPOINT_COUNT],
+
+    /// The number of hits of each slot since its breakpoint was set.
+    hit_counts: [u64; HARDWARE_BREAKPOINT_COUNT],
+
+    /// The handler called on each hit.
+    handler: Option<HardwareBreakpointHandler>,
+}
+
+impl HardwareBreakpoints {
+    /// Creates the registry, without breakpoints.
+    fn new() -> Self {
+        Self {
+            breakpoints: [None; HARDWARE_BREAKPOINT_COUNT],
+            hit_counts: [0; HARDWARE_BREAKPOINT_COUNT],
+            handler: None,
+        }
+    }
+
+    /// Sets or removes the breakpoint of a slot, which the logical processors pick up on their next VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The index of the debug register, 0 to 3.
+    /// * `breakpoint` - The breakpoint, or `None` to leave the slot to the guest.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the slot has been changed, or `Err(HypervisorError::InvalidHardwareBreakpoint)` if it doesn't exist.
+    pub fn set_breakpoint(&mut self, slot: usize, breakpoint: Option<HardwareBreakpoint>) -> Result<(), HypervisorError> {
+        if slot >= HARDWARE_BREAKPOINT_COUNT {
+            return Err(HypervisorError::InvalidHardwareBreakpoint);
+        }
+
+        debug!("Hardware breakpoint {} set: {:x?}", slot, breakpoint);
+
+        self.breakpoints[slot] = breakpoint;
+        self.hit_counts[slot] = 0;
+        PUBLISHED_BREAKPOINTS.publish(self.breakpoints);
+
+        Ok(())
+    }
+
+    /// Returns the breakpoint of a slot, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The index of the debug register, 0 to 3.
+    pub fn breakpoint(&self, slot: usize) -> Option<HardwareBreakpoint> {
+        self.breakpoints.get(slot).copied().flatten()
+    }
+
+    /// Returns the number of hits of a slot since its breakpoint was set.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The index of the debug register, 0 to 3.
+    pub fn hit_count(&self, slot: usize) -> u64 {
+        self.hit_counts.get(slot).copied().unwrap_or(0)
+    }
+
+    /// Registers the handler called on each hit, replacing the previous one.
+    ///
+    /// The handler is called without the registry locked, so it may set or remove breakpoints.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The handler, or `None` to only log the hits.
+    pub fn set_handler(&mut self, handler: Option<HardwareBreakpointHandler>) {
+        self.handler = handler;
+    }
+}
+
+/// The debug registers of a logical processor, as observed by the guest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorDebugRegisters {
+    /// The generation of the breakpoints programmed in the debug registers of this logical processor.
+    generation: Generation,
+
+    /// Whether `MOV DR` causes VM exits, the guest observing the shadow instead of the debug registers.
+    virtualized: bool,
+
+    /// The hypervisor breakpoints programmed in the debug registers.
+    breakpoints: [Option<HardwareBreakpoint>; HARDWARE_BREAKPOINT_COUNT],
+
+    /// The shadow of DR0-DR3.
+    guest_dr: [u64; HARDWARE_BREAKPOINT_COUNT],
+
+    /// The shadow of DR6.
+    guest_dr6: u64,
+
+    /// The shadow of DR7.
+    guest_dr7: u64,
+}
+
+impl ProcessorDebugRegisters {
+    /// Creates a new processor state, without virtualization.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if the guest observes the shadow instead of the debug registers.
+    pub fn is_virtualized(&self) -> bool {
+        self.virtualized
+    }
+
+    /// Returns the DR7 value loaded for the guest: the shadow, with the slots of the hypervisor breakpoints replaced
+    /// and without the general-detect condition, which is emulated.
+    fn effective_dr7(&self) -> u64 {
+        if !self.virtualized {
+            return self.guest_dr7;
+        }
+
+        self.breakpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, breakpoint)| breakpoint.map(|breakpoint| (slot, breakpoint)))
+            .fold(self.guest_dr7 & !DR7_GENERAL_DETECT, |dr7, (slot, breakpoint)| (dr7 & !dr7_slot_mask(slot)) | breakpoint.dr7_bits(slot))
+    }
+
+    /// Writes the debug registers of the current logical processor: the hypervisor breakpoints in their slots, and the
+    /// shadow of the guest elsewhere.
+    fn write_debug_registers(&self) {
+        let addresses: [u64; HARDWARE_BREAKPOINT_COUNT] =
+            core::array::from_fn(|slot| self.breakpoints[slot].map_or(self.guest_dr[slot], |breakpoint| breakpoint.address));
+
+        dr0_write(addresses[0]);
+        dr1_write(addresses[1]);
+        dr2_write(addresses[2]);
+        dr3_write(addresses[3]);
+        dr6_write(self.guest_dr6);
+        vmwrite(vmcs::guest::DR7, self.effective_dr7());
+    }
+}
+
+/// Programs the hypervisor breakpoints in the debug registers of the current logical processor, and enables or
+/// disables the `MOV DR` exiting.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_debug_registers(vm: &mut Vm) {
+    let mut generation = vm.debug_registers.generation;
+    let Some(breakpoints) = PUBLISHED_BREAKPOINTS.sync(&mut generation) else {
+        return;
+    };
+
+    let debug_registers = &mut vm.debug_registers;
+    let was_virtualized = debug_registers.virtualized;
+    let virtualized = breakpoints.iter().any(Option::is_some);
+
+    if virtualized && !was_virtualized {
+        debug_registers.guest_dr = [dr0_read(), dr1_read(), dr2_read(), dr3_read()];
+        debug_registers.guest_dr6 = dr6_read();
+        debug_registers.guest_dr7 = vmread(vmcs::guest::DR7);
+    }
+
+    debug_registers.generation = generation;
+    debug_registers.virtualized = virtualized;
+    debug_registers.breakpoints = breakpoints;
+
+    if virtualized || was_virtualized {
+        debug_registers.write_debug_registers();
+    }
+
+    let mut primary_controls = PrimaryControls::from_bits_truncate(vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+    primary_controls.set(PrimaryControls::MOV_DR_EXITING, virtualized);
+    vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
+
+    // The debug exceptions stay intercepted once the breakpoints are removed, as the syscall hooks may require them,
+    // and those that aren't handled are reflected to the guest.
+    if virtualized {
+        let exception_bitmap = vmread(vmcs::control::EXCEPTION_BITMAP) | (1u64 << (ExceptionInterrupt::Debug as u32));
+        vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+    }
+
+    trace!("Debug registers on this processor: {:x?}", vm.debug_registers);
+}
+
+/// Emulates the general-detect condition before a `MOV DR` of the guest, by injecting a debug exception if the shadow
+/// of DR7 has GD set. As the processor does, GD is cleared so the debug exception handler can access the registers.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// `true` if the debug exception has been injected, in which case the instruction must not be emulated.
+pub fn emulate_general_detect(vm: &mut Vm) -> bool {
+    let debug_registers = &mut vm.debug_registers;
+
+    if debug_registers.guest_dr7 & DR7_GENERAL_DETECT == 0 {
+        return false;
+    }
+
+    debug!("General-detect debug exception at RIP: {:#x}", vm.guest_registers.rip);
+
+    debug_registers.guest_dr7 &= !DR7_GENERAL_DETECT;
+    debug_registers.guest_dr6 |= DR6_DEBUG_REGISTER_ACCESS;
+    EventInjection::vmentry_inject_db(DR6_DEBUG_REGISTER_ACCESS);
+
+    true
+}
+
+/// Returns the value of a debug register observed by the guest.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `debug_register` - The number of the debug register: 0 to 3, 6 or 7.
+pub fn read_guest_debug_register(vm: &Vm, debug_register: usize) -> u64 {
+    let debug_registers = &vm.debug_registers;
+
+    match debug_register {
+        0..=3 => debug_registers.guest_dr[debug_register],
+        6 => debug_registers.guest_dr6,
+        _ => debug_registers.guest_dr7,
+    }
+}
+
+/// Writes a debug register for the guest: the shadow, and the register unless the hypervisor owns its slot.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `debug_register` - The number of the debug register: 0 to 3, 6 or 7.
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// `true` if the value has been written, `false` if it sets the upper 32 bits of DR6 or DR7, which raises #GP.
+pub fn write_guest_debug_register(vm: &mut Vm, debug_register: usize, value: u64) -> bool {
+    let debug_registers = &mut vm.debug_registers;
+
+    match debug_register {
+        0..=3 => {
+            debug_registers.guest_dr[debug_register] = value;
+
+            if debug_registers.breakpoints[debug_register].is_none() {
+                match debug_register {
+                    0 => dr0_write(value),
+                    1 => dr1_write(value),
+                    2 => dr2_write(value),
+                    _ => dr3_write(value),
+                }
+            }
+        }
+        _ if value >> 32 != 0 => return false,
+        6 => debug_registers.guest_dr6 = value,
+        _ => {
+            debug_registers.guest_dr7 = value;
+            vmwrite(vmcs::guest::DR7, debug_registers.effective_dr7());
+        }
+    }
+
+    true
+}
+
+/// Dispatches the hits of the hypervisor breakpoints of an intercepted debug exception to the hooked function or the
+/// registered handler.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `exit_qualification` - The exit qualification of the debug exception, in the format of DR6.
+///
+/// # Returns
+///
+/// The exit qualification without the conditions of the hypervisor breakpoints, which must be reflected to the guest
+/// unless it reports no condition.
+pub fn dispatch_hardware_breakpoints(vm: &mut Vm, exit_qualification: u64) -> u64 {
+    if !vm.debug_registers.virtualized {
+        return exit_qualification;
+    }
+
+    let breakpoints = vm.debug_registers.breakpoints;
+    let mut exit_qualification = exit_qualification;
+    let mut instruction_breakpoint_hit = false;
+
+    for (slot, breakpoint) in breakpoints.iter().enumerate() {
+        let Some(breakpoint) = *breakpoint else {
+            continue;
+        };
+
+        if exit_qualification & (1 << slot) == 0 {
+            continue;
+        }
+
+        exit_qualification &= !(1 << slot);
+        instruction_breakpoint_hit |= breakpoint.condition == BreakpointCondition::Execute;
+
+        let event = HardwareBreakpointEvent {
+            processor_id: vm.cpuid_feature_info.initial_local_apic_id() as u32,
+            slot,
+            breakpoint,
+            guest_rip: vm.guest_registers.rip,
+        };
+
+        debug!("Hardware breakpoint {} hit: {:x?}", slot, event);
+
+        let handler = {
+            let mut hardware_breakpoints = SHARED_HARDWARE_BREAKPOINTS.lock();
+            hardware_breakpoints.hit_counts[slot] += 1;
+            hardware_breakpoints.handler
+        };
+
+        if dispatch_hardware_breakpoint_hook(vm, &event) {
+            continue;
+        }
+
+        if let Some(handler) = handler {
+            handler(vm, &event);
+        }
+    }
+
+    // The instruction executes on the way back to the guest, instead of hitting the breakpoint again.
+    if instruction_breakpoint_hit {
+        const RFLAGS_RESUME_FLAG: u64 = 1 << 16;
+        vm.guest_registers.rflags |= RFLAGS_RESUME_FLAG;
+        vmwrite(vmcs::guest::RFLAGS, vm.guest_registers.rflags);
+    }
+
+    exit_qualification
+}
+
+/// Reflects an intercepted debug exception to the guest, setting its conditions in the shadow of DR6 while the debug
+/// registers are virtualized.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `exit_qualification` - The exit qualification of the debug exception, in the format of DR6.
+///
+/// # Returns
+///
+/// `true` if the debug exception has been injected, `false` if only hypervisor breakpoints were hit.
+pub fn reflect_debug_exception(vm: &mut Vm, exit_qualification: u64) -> bool {
+    if vm.debug_registers.virtualized {
+        if exit_qualification & (DR6_GUEST_CONDITIONS | DR6_RTM) == 0 {
+            return false;
+        }
+
+        vm.debug_registers.guest_dr6 |= exit_qualification & DR6_GUEST_CONDITIONS;
+    }
+
+    EventInjection::vmentry_inject_db(exit_qualification);
+
+    true
+}
@@ -6,7 +6,10 @@
 //! Credits to Satoshi Tanda: https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/intel_vt/descriptors.rs
 
 use {
-    crate::intel::support::{sgdt, sidt},
+    crate::intel::{
+        host_exception::build_host_idt,
+        support::{sgdt, sidt},
+    },
     alloc::vec::Vec,
     x86::{
         dtables::DescriptorTablePointer,
@@ -118,8 +121,8 @@ impl Descriptors {
         descriptors.cs = SegmentSelector::new(1, x86::Ring::Ring0);
         descriptors.tr = SegmentSelector::new(2, x86::Ring::Ring0);
 
-        // Initialize the IDT with empty descriptors for the host
-        descriptors.idt = Self::copy_current_idt();
+        // Route the exceptions of the host to its handlers, the TSS of each logical processor holding the IST stacks.
+        descriptors.idt = build_host_idt(descriptors.cs);
         descriptors.idtr = DescriptorTablePointer::new_from_slice(&descriptors.idt);
 
         log::debug!("New GDT with TSS and IDT created for host successfully!");
@@ -162,24 +165,6 @@ impl Descriptors {
             .l()
             .finish()
     }
-
-    /// Copies the current IDT for the guest.
-    fn copy_current_idt() -> Vec<u64> {
-        log::trace!("Copying current IDT");
-
-        // Get the current IDTR
-        let current_idtr = sidt();
-
-        // Create a slice from the current IDT entries.
-        let current_idt = unsafe { core::slice::from_raw_parts(current_idtr.base.cast::<u64>(), usize::from(current_idtr.limit + 1) / 8) };
-
-        // Create a new IDT from the slice.
-        let new_idt = current_idt.to_vec();
-
-        log::trace!("Copied current IDT");
-
-        new_idt
-    }
 }
 
 /// Represents the Task State Segment (TSS).
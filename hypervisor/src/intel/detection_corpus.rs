@@ -0,0 +1,261 @@
+//! Provides a regression corpus of public hypervisor-detection techniques the hypervisor evaluates itself against on
+//! demand, so a feature making it visible to the guest, e.g., a new VM exit or MSR hook, is caught by a failing check.
+//!
+//! The corpus is a table of checks, one per `DetectionTechnique`, evaluated on the logical processor running the
+//! client from what the guest observes: the CPUID results and timing measured by the client from user mode, as a
+//! detector does, and, for the instructions only the kernel executes, the MSRs and the debug registers as the guest
+//! reads them through the handlers of the hypervisor. A check passes if the guest observes what it does on bare metal,
+//! and is skipped if the technique doesn't apply to the configuration, e.g., while the hypervisor presence is exposed
+//! on purpose.
+
+use {
+    crate::intel::{
+        bitmap::MsrAccessType,
+        debug_registers::{read_guest_debug_register, HARDWARE_BREAKPOINT_COUNT, SHARED_HARDWARE_BREAKPOINTS},
+        hooks::{
+            cpuid_hook::SHARED_CPUID_HOOK_MANAGER,
+            msr_hook::{MsrHook, MsrHookResult, SHARED_MSR_HOOK_MANAGER},
+        },
+        host_config::SHARED_HOST_CONFIG,
+        support::{rdmsr, vmread},
+        vm::Vm,
+        vmexit::{cpuid::FeatureBits, msr::is_invalid_msr},
+    },
+    alloc::vec::Vec,
+    bit_field::BitField,
+    log::*,
+    shared::{
+        DetectionCheckResult, DetectionCorpusHeader, DetectionProbes, DetectionTechnique, DetectionVerdict, HypervisorPresence,
+        DETECTION_TECHNIQUE_COUNT,
+    },
+    x86::{
+        msr,
+        vmx::vmcs::{self, control::PrimaryControls},
+    },
+};
+
+/// The duration of CPUID above which the public timing checks report a hypervisor, in TSC ticks.
+pub const CPUID_TIMING_THRESHOLD_TICKS: u64 = 1000;
+
+/// The first synthetic Hyper-V MSR, the guest OS ID, read by the invalid MSR checks.
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x40000000;
+
+/// The outcome of a check: the verdict, the value observed by the guest, and the value it observes on bare metal or
+/// the threshold of the technique.
+type CheckOutcome = (DetectionVerdict, u64, u64);
+
+/// A check of the corpus, evaluating a technique from the observations of the guest made by the client.
+type DetectionCheck = fn(vm: &mut Vm, probes: &DetectionProbes) -> CheckOutcome;
+
+/// The corpus, in `DetectionTechnique` order.
+const DETECTION_CORPUS: [(DetectionTechnique, DetectionCheck); DETECTION_TECHNIQUE_COUNT] = [
+    (DetectionTechnique::CpuidHypervisorBit, check_cpuid_hypervisor_bit),
+    (DetectionTechnique::CpuidHypervisorLeaf, check_cpuid_hypervisor_leaf),
+    (DetectionTechnique::CpuidTiming, check_cpuid_timing),
+    (DetectionTechnique::SyntheticMsr, check_synthetic_msr),
+    (DetectionTechnique::FeatureControl, check_feature_control),
+    (DetectionTechnique::LstarHook, check_lstar_hook),
+    (DetectionTechnique::DebugRegisters, check_debug_registers),
+    (DetectionTechnique::HypervisorMemory, check_hypervisor_memory),
+];
+
+/// Evaluates every technique of the corpus on the current logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor, running the client.
+/// * `probes` - The observations of the guest made by the client.
+///
+/// # Returns
+///
+/// The pass/fail summary, and the result of each technique in `DetectionTechnique` order.
+pub fn run_detection_corpus(vm: &mut Vm, probes: &DetectionProbes) -> (DetectionCorpusHeader, Vec<DetectionCheckResult>) {
+    let mut header = DetectionCorpusHeader {
+        check_count: DETECTION_CORPUS.len() as u64,
+        passed: 0,
+        failed: 0,
+        skipped: 0,
+    };
+
+    let results = DETECTION_CORPUS
+        .iter()
+        .map(|&(technique, check)| {
+            let (verdict, observed, expected) = check(vm, probes);
+
+            match verdict {
+                DetectionVerdict::Pass => header.passed += 1,
+                DetectionVerdict::Fail => {
+                    warn!("Detection technique {:?} detects the hypervisor: observed {:#x}, expected {:#x}", technique, observed, expected);
+                    header.failed += 1;
+                }
+                DetectionVerdict::Skipped => header.skipped += 1,
+            }
+
+            DetectionCheckResult {
+                technique: technique as u64,
+                verdict: verdict as u64,
+                observed,
+                expected,
+            }
+        })
+        .collect();
+
+    debug!("Detection corpus: {:?}", header);
+
+    (header, results)
+}
+
+/// Returns the verdict of a check that doesn't skip.
+///
+/// # Arguments
+///
+/// * `passed` - Whether the guest observes what it does on bare metal.
+fn verdict(passed: bool) -> DetectionVerdict {
+    match passed {
+        true => DetectionVerdict::Pass,
+        false => DetectionVerdict::Fail,
+    }
+}
+
+/// Returns whether the hypervisor presence is exposed to the guest on purpose, in which case the CPUID techniques
+/// are skipped.
+fn is_presence_exposed() -> bool {
+    SHARED_CPUID_HOOK_MANAGER.lock().presence() == HypervisorPresence::Exposed
+}
+
+/// Returns the value the guest reads from an MSR, through its hook in the MSR hook registry if any, without the
+/// context rules, which depend on the context of the kernel reading it instead of the client.
+///
+/// The callback of the hook is called as for a read by the guest.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `msr` - The MSR.
+///
+/// # Returns
+///
+/// The value read, or `None` if the read raises #GP.
+fn read_guest_msr(vm: &mut Vm, msr: u32) -> Option<u64> {
+    let hook = SHARED_MSR_HOOK_MANAGER.lock().get(msr, MsrAccessType::Read);
+    let mut value = 0;
+
+    match hook {
+        Some(MsrHook::Shadow(shadow_value)) => return Some(shadow_value),
+        Some(MsrHook::Callback(callback)) => match callback(vm, msr, &mut value).ok()? {
+            MsrHookResult::Emulated => return Some(value),
+            MsrHookResult::InjectGp => return None,
+            MsrHookResult::Passthrough => {}
+        },
+        None => {}
+    }
+
+    match is_invalid_msr(msr) {
+        true => None,
+        false => Some(rdmsr(msr)),
+    }
+}
+
+/// Checks the hypervisor-present bit of CPUID leaf 1, clear on bare metal.
+fn check_cpuid_hypervisor_bit(_vm: &mut Vm, probes: &DetectionProbes) -> CheckOutcome {
+    let present = probes.cpuid_feature_information[2].get_bit(FeatureBits::HypervisorPresentBit as usize) as u64;
+
+    match is_presence_exposed() {
+        true => (DetectionVerdict::Skipped, present, 1),
+        false => (verdict(present == 0), present, 0),
+    }
+}
+
+/// Checks CPUID leaf 0x40000000, returning the results of the highest basic leaf on bare metal. The EBX of both
+/// results is reported, the first characters of the vendor signature.
+fn check_cpuid_hypervisor_leaf(_vm: &mut Vm, probes: &DetectionProbes) -> CheckOutcome {
+    let observed = probes.cpuid_hypervisor_leaf[1] as u64;
+    let expected = probes.cpuid_max_basic_leaf[1] as u64;
+
+    match is_presence_exposed() {
+        true => (DetectionVerdict::Skipped, observed, expected),
+        false => (verdict(probes.cpuid_hypervisor_leaf == probes.cpuid_max_basic_leaf), observed, expected),
+    }
+}
+
+/// Checks the duration of CPUID between two RDTSC against the threshold of the public timing checks.
+fn check_cpuid_timing(_vm: &mut Vm, probes: &DetectionProbes) -> CheckOutcome {
+    match probes.cpuid_tsc_ticks {
+        0 => (DetectionVerdict::Skipped, 0, CPUID_TIMING_THRESHOLD_TICKS),
+        ticks => (verdict(ticks <= CPUID_TIMING_THRESHOLD_TICKS), ticks, CPUID_TIMING_THRESHOLD_TICKS),
+    }
+}
+
+/// Checks that reading a synthetic Hyper-V MSR raises #GP, reporting 1 if the read succeeds.
+fn check_synthetic_msr(vm: &mut Vm, _probes: &DetectionProbes) -> CheckOutcome {
+    let readable = read_guest_msr(vm, HV_X64_MSR_GUEST_OS_ID).is_some() as u64;
+
+    (verdict(readable == 0), readable, 0)
+}
+
+/// Checks that IA32_FEATURE_CONTROL is locked and disables VMXON outside SMX, as firmware leaves it without a
+/// hypervisor.
+fn check_feature_control(vm: &mut Vm, _probes: &DetectionProbes) -> CheckOutcome {
+    const VMX_LOCK_BIT: usize = 0;
+    const VMXON_OUTSIDE_SMX: usize = 2;
+
+    let Some(value) = read_guest_msr(vm, msr::IA32_FEATURE_CONTROL) else {
+        return (DetectionVerdict::Fail, 0, 1 << VMX_LOCK_BIT);
+    };
+
+    let mut expected = value;
+    expected.set_bit(VMX_LOCK_BIT, true);
+    expected.set_bit(VMXON_OUTSIDE_SMX, false);
+
+    (verdict(value == expected), value, expected)
+}
+
+/// Checks that IA32_LSTAR returns the syscall entry of the kernel, skipped until it has been captured.
+fn check_lstar_hook(vm: &mut Vm, _probes: &DetectionProbes) -> CheckOutcome {
+    let original_lstar = vm.guest_registers.original_lstar;
+
+    if original_lstar == 0 {
+        return (DetectionVerdict::Skipped, 0, 0);
+    }
+
+    let value = read_guest_msr(vm, msr::IA32_LSTAR).unwrap_or(0);
+
+    (verdict(value == original_lstar), value, original_lstar)
+}
+
+/// Checks that the debug registers don't expose the hardware breakpoints of the hypervisor, reporting the number of
+/// breakpoints the guest reads, and skipped without breakpoints.
+fn check_debug_registers(vm: &mut Vm, _probes: &DetectionProbes) -> CheckOutcome {
+    let breakpoints: Vec<_> = {
+        let hardware_breakpoints = SHARED_HARDWARE_BREAKPOINTS.lock();
+        (0..HARDWARE_BREAKPOINT_COUNT)
+            .filter_map(|slot| hardware_breakpoints.breakpoint(slot).map(|breakpoint| (slot, breakpoint)))
+            .collect()
+    };
+
+    if breakpoints.is_empty() {
+        return (DetectionVerdict::Skipped, 0, 0);
+    }
+
+    // Without the `MOV DR` exiting, the guest reads the registers holding the breakpoints instead of the shadow.
+    let primary_controls = PrimaryControls::from_bits_truncate(vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+    let virtualized = primary_controls.contains(PrimaryControls::MOV_DR_EXITING);
+
+    let visible = breakpoints
+        .iter()
+        .filter(|(slot, breakpoint)| !virtualized || read_guest_debug_register(vm, *slot) == breakpoint.address())
+        .count() as u64;
+
+    (verdict(visible == 0), visible, 0)
+}
+
+/// Checks that the memory ranges allocated by the hypervisor are hidden from the guest in the EPT of the current
+/// logical processor, reporting the number of hidden ranges.
+fn check_hypervisor_memory(vm: &mut Vm, _probes: &DetectionProbes) -> CheckOutcome {
+    let range_count = SHARED_HOST_CONFIG.read().allocated_memory_ranges.len() as u64;
+
+    match cfg!(feature = "hide_hv_with_ept") {
+        true => (verdict(vm.hidden_memory_range_count as u64 == range_count), vm.hidden_memory_range_count as u64, range_count),
+        false => (DetectionVerdict::Fail, 0, range_count),
+    }
+}
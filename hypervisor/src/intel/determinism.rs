@@ -0,0 +1,247 @@
+//! Provides a deterministic execution mode, in which the time and the entropy observed by the guest only advance by
+//! policy, to make analysis runs such as malware detonations or snapshot fuzzing reproducible.
+//!
+//! While enabled:
+//! - `RDTSC`, `RDTSCP` and reads of IA32_TIME_STAMP_COUNTER return a virtual TSC shared by all the logical processors,
+//!   which starts at a configured value and advances by a fixed number of ticks on each read.
+//! - The PM timer and the HPET main counter are derived from the virtual TSC, if they are intercepted (see the `timing` module).
+//! - `RDRAND` and `RDSEED` return values of a pseudo-random generator seeded with a configured seed.
+//!
+//! The guest-visible state only depends on the order of the reads, so runs are reproducible as long as the guest
+//! executes the same instructions in the same order. The delivery of interrupts, e.g., the local APIC timer, isn't
+//! controlled and remains a source of nondeterminism.
+//!
+//! Each logical processor enables or disables the interception of the instructions at its first VM exit after the
+//! configuration is published.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            bitmap::MsrAccessType,
+            controls::{adjust_vmx_controls, VmxControl},
+            hooks::msr_hook::{MsrHook, MsrHookResult, SHARED_MSR_HOOK_MANAGER},
+            seqlock::Published,
+            support::{rdtsc, vmread, vmwrite},
+            timing::tsc_frequency_hz,
+            tsc_compensation::is_rdtsc_exiting_enabled,
+            vm::Vm,
+        },
+    },
+    core::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::{
+        msr,
+        vmx::vmcs::{
+            self,
+            control::{PrimaryControls, SecondaryControls},
+        },
+    },
+};
+
+/// The increment of the state of the pseudo-random generator (SplitMix64), the golden ratio in 64-bit fixed point.
+const ENTROPY_STATE_INCREMENT: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// The configuration of the deterministic mode, disabled until it's configured.
+static DETERMINISM_CONFIG: Published<DeterministicConfig> = Published::new(DeterministicConfig {
+    enabled: false,
+    seed: 0,
+    start_tsc: 0,
+    tsc_ticks_per_read: 0,
+});
+
+/// Whether the deterministic mode is enabled, checked without locking by the VM exit handlers.
+static DETERMINISM_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The virtual TSC returned by the next read.
+static VIRTUAL_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// The number of ticks the virtual TSC advances by on each read.
+static TSC_TICKS_PER_READ: AtomicU64 = AtomicU64::new(0);
+
+/// The state of the pseudo-random generator returned by `RDRAND` and `RDSEED`.
+static ENTROPY_STATE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// A globally shared instance of `Determinism`, protected by a mutex.
+    pub static ref SHARED_DETERMINISM: Mutex<Determinism> = Mutex::new(Determinism::new());
+}
+
+/// The configuration of the deterministic mode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeterministicConfig {
+    /// Whether the deterministic mode is enabled.
+    pub enabled: bool,
+
+    /// The seed of the pseudo-random generator returned by `RDRAND` and `RDSEED`.
+    pub seed: u64,
+
+    /// The value of the virtual TSC returned by the first read, or 0 to start at the current TSC.
+    pub start_tsc: u64,
+
+    /// The number of ticks the virtual TSC advances by on each read.
+    pub tsc_ticks_per_read: u64,
+}
+
+/// The deterministic mode, locked to serialize its configuration changes.
+#[derive(Debug)]
+pub struct Determinism;
+
+impl Determinism {
+    /// Creates a new disabled deterministic mode.
+    fn new() -> Self {
+        Self
+    }
+
+    /// Publishes a new configuration, which the logical processors pick up on their next VM exit.
+    ///
+    /// Enabling the mode resets the virtual TSC and the pseudo-random generator, so each run starts from the same state.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new configuration.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the configuration was published, or `Err(HypervisorError::InvalidDeterministicConfig)` if the virtual
+    /// TSC wouldn't advance, which would hang the guest waiting for time to pass.
+    pub fn configure(&mut self, config: DeterministicConfig) -> Result<(), HypervisorError> {
+        if config.enabled && config.tsc_ticks_per_read == 0 {
+            return Err(HypervisorError::InvalidDeterministicConfig);
+        }
+
+        debug!("Deterministic mode configured: {:?}", config);
+
+        if config.enabled {
+            let start_tsc = match config.start_tsc {
+                0 => rdtsc(),
+                start_tsc => start_tsc,
+            };
+
+            VIRTUAL_TSC.store(start_tsc, Ordering::Release);
+            TSC_TICKS_PER_READ.store(config.tsc_ticks_per_read, Ordering::Release);
+            ENTROPY_STATE.store(config.seed, Ordering::Release);
+        }
+
+        // Reads of IA32_TIME_STAMP_COUNTER are hooked only while the mode is enabled.
+        let mut msr_hook_manager = SHARED_MSR_HOOK_MANAGER.lock();
+        match config.enabled {
+            true => msr_hook_manager.register(msr::IA32_TIME_STAMP_COUNTER, MsrAccessType::Read, MsrHook::Callback(handle_tsc_read)),
+            false => {
+                msr_hook_manager.unregister(msr::IA32_TIME_STAMP_COUNTER, MsrAccessType::Read);
+            }
+        }
+        drop(msr_hook_manager);
+
+        DETERMINISM_ENABLED.store(config.enabled, Ordering::Release);
+
+        DETERMINISM_CONFIG.publish(config);
+
+        Ok(())
+    }
+}
+
+/// Enables or disables the interception of `RDTSC`, `RDTSCP`, `RDRAND` and `RDSEED` on the current logical processor
+/// for a new deterministic mode configuration.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_deterministic_mode(vm: &mut Vm) {
+    let Some(config) = DETERMINISM_CONFIG.sync(&mut vm.deterministic_mode_generation) else {
+        return;
+    };
+    let enabled = config.enabled;
+
+    // RDTSCP causes VM exits with RDTSC exiting, as it's enabled in the secondary controls. The TSC compensation may
+    // keep RDTSC exiting enabled (see the `tsc_compensation` module).
+    let mut primary_controls = PrimaryControls::from_bits_truncate(vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+    primary_controls.set(PrimaryControls::RDTSC_EXITING, enabled || is_rdtsc_exiting_enabled());
+    vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
+
+    // RDRAND and RDSEED exiting are not supported by all the processors.
+    let entropy_exiting = SecondaryControls::RDRAND_EXITING | SecondaryControls::RDSEED_EXITING;
+    let supported_exiting =
+        SecondaryControls::from_bits_truncate(adjust_vmx_controls(VmxControl::ProcessorBased2, entropy_exiting.bits() as u64) as u32)
+            & entropy_exiting;
+
+    let mut secondary_controls = SecondaryControls::from_bits_truncate(vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) as u32);
+    secondary_controls.set(supported_exiting, enabled);
+    vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, secondary_controls.bits());
+
+    trace!("Deterministic mode {} on this processor, entropy exiting: {:?}", if enabled { "enabled" } else { "disabled" }, supported_exiting);
+}
+
+/// Returns `true` if the deterministic mode is enabled.
+pub fn is_deterministic_mode_enabled() -> bool {
+    DETERMINISM_ENABLED.load(Ordering::Acquire)
+}
+
+/// Reads the virtual TSC and advances it.
+///
+/// # Returns
+///
+/// The value of the virtual TSC, or `None` if the deterministic mode is disabled.
+pub fn read_virtual_tsc() -> Option<u64> {
+    if !DETERMINISM_ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    Some(VIRTUAL_TSC.fetch_add(TSC_TICKS_PER_READ.load(Ordering::Acquire), Ordering::AcqRel))
+}
+
+/// Reads the virtual TSC, converted to ticks of a clock with the given frequency.
+///
+/// # Arguments
+///
+/// * `clock_frequency_hz` - The frequency of the clock, in Hz.
+///
+/// # Returns
+///
+/// The value of the clock, or `None` if the deterministic mode is disabled.
+pub fn read_virtual_clock(clock_frequency_hz: u64) -> Option<u64> {
+    let virtual_tsc = read_virtual_tsc()?;
+
+    Some((virtual_tsc as u128 * clock_frequency_hz as u128 / tsc_frequency_hz() as u128) as u64)
+}
+
+/// Returns the next value of the pseudo-random generator (SplitMix64).
+///
+/// # Returns
+///
+/// The next pseudo-random value, or `None` if the deterministic mode is disabled.
+pub fn next_entropy() -> Option<u64> {
+    if !DETERMINISM_ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let mut z = ENTROPY_STATE
+        .fetch_add(ENTROPY_STATE_INCREMENT, Ordering::AcqRel)
+        .wrapping_add(ENTROPY_STATE_INCREMENT);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    Some(z ^ (z >> 31))
+}
+
+/// Handles a read of IA32_TIME_STAMP_COUNTER by returning the virtual TSC.
+///
+/// # Arguments
+///
+/// * `_vm` - The virtual machine instance of the current logical processor.
+/// * `_msr` - The accessed MSR.
+/// * `value` - The value returned to the guest.
+///
+/// # Returns
+///
+/// `MsrHookResult::Emulated`, or `MsrHookResult::Passthrough` if the mode was disabled meanwhile.
+fn handle_tsc_read(_vm: &mut Vm, _msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError> {
+    match read_virtual_tsc() {
+        Some(virtual_tsc) => {
+            *value = virtual_tsc;
+            Ok(MsrHookResult::Emulated)
+        }
+        None => Ok(MsrHookResult::Passthrough),
+    }
+}
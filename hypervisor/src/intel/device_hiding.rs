@@ -0,0 +1,408 @@
+//! Provides hiding of devices from the guest, specified by their PCI address (BDF) or by an MMIO range.
+//!
+//! A hidden device is removed from all the enumeration paths visible to the guest:
+//! - The legacy PCI configuration ports (`0xCF8`/`0xCFC`) are intercepted through the I/O bitmap and read as all ones.
+//! - The ECAM configuration page of the function, from the MCFG, is mapped to the dummy page (all ones) through EPT.
+//! - The memory BARs of the function, or the given MMIO range, are mapped to the dummy page as well.
+//! - The ACPI `Device` describing it, if any, has its identification objects renamed in the DSDT.
+//!
+//! Reads of the hidden pages return all ones like an absent device, and writes are discarded.
+
+use {
+    crate::{
+        acpi::{EcamRegion, SHARED_ACPI_TABLES},
+        error::HypervisorError,
+        intel::{bitmap::IoOperation, ept::AccessType, hooks::hook_manager::SHARED_HOOK_MANAGER, host_config::SHARED_HOST_CONFIG, vm::Vm},
+    },
+    alloc::{collections::BTreeSet, vec::Vec},
+    core::ptr::{read_volatile, write_volatile},
+    lazy_static::lazy_static,
+    log::*,
+    spin::RwLock,
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        io::{inl, outl},
+    },
+};
+
+/// The legacy PCI configuration address port.
+const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+
+/// The legacy PCI configuration data port, accessed as 4 ports for byte and word accesses.
+const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
+
+/// The enable bit of the legacy PCI configuration address.
+const PCI_CONFIG_ENABLE: u32 = 1 << 31;
+
+/// The offset of the Command register in the configuration space.
+const PCI_COMMAND: u8 = 0x04;
+
+/// The Memory Space Enable bit of the Command register.
+const PCI_COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+
+/// The offset of the Header Type register in the configuration space.
+const PCI_HEADER_TYPE: u8 = 0x0E;
+
+/// The offset of the first Base Address Register in the configuration space.
+const PCI_BAR0: u8 = 0x10;
+
+lazy_static! {
+    /// A globally shared instance of `DeviceHiding`, protected by a read-write lock.
+    ///
+    /// The devices are hidden at boot by `DeviceHiding::hide_device`, before the processors are virtualized.
+    pub static ref SHARED_DEVICE_HIDING: RwLock<DeviceHiding> = RwLock::new(DeviceHiding::default());
+}
+
+/// The address of a PCI function: segment, bus, device and function (BDF).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bdf {
+    /// The PCI segment group number.
+    pub segment: u16,
+
+    /// The bus number.
+    pub bus: u8,
+
+    /// The device number (0-31).
+    pub device: u8,
+
+    /// The function number (0-7).
+    pub function: u8,
+}
+
+impl Bdf {
+    /// Creates a new PCI function address.
+    ///
+    /// # Arguments
+    ///
+    /// * `segment` - The PCI segment group number.
+    /// * `bus` - The bus number.
+    /// * `device` - The device number (0-31).
+    /// * `function` - The function number (0-7).
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Decodes the function addressed by a legacy PCI configuration address, if it's enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The value of the configuration address port.
+    fn from_config_address(address: u32) -> Option<Self> {
+        if address & PCI_CONFIG_ENABLE == 0 {
+            return None;
+        }
+
+        Some(Self::new(0, (address >> 16) as u8, ((address >> 11) & 0x1F) as u8, ((address >> 8) & 0x7) as u8))
+    }
+
+    /// Returns the physical address of the configuration space of the function in the ECAM regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `ecam_regions` - The ECAM regions from the MCFG.
+    fn ecam_address(&self, ecam_regions: &[EcamRegion]) -> Option<u64> {
+        let region = ecam_regions
+            .iter()
+            .find(|region| region.segment == self.segment && (region.start_bus..=region.end_bus).contains(&self.bus))?;
+
+        Some(region.base_pa + (((self.bus - region.start_bus) as u64) << 20 | (self.device as u64) << 15 | (self.function as u64) << 12))
+    }
+}
+
+/// A device to hide from the guest.
+#[derive(Debug, Clone, Copy)]
+pub enum HiddenDevice {
+    /// A PCI function, hidden from the configuration space along with its memory BARs.
+    Pci {
+        /// The address of the function.
+        bdf: Bdf,
+        /// The name segment of the ACPI `Device` describing the function, if any.
+        acpi_name: Option<[u8; 4]>,
+    },
+
+    /// A memory-mapped device that isn't enumerated through PCI, e.g., a platform device.
+    Mmio {
+        /// The physical address of the MMIO range.
+        base_pa: u64,
+        /// The size of the MMIO range in bytes.
+        size: u64,
+        /// The name segment of the ACPI `Device` describing the device, if any.
+        acpi_name: Option<[u8; 4]>,
+    },
+}
+
+/// The devices hidden from the guest.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceHiding {
+    /// The hidden PCI functions.
+    pub hidden_pci_functions: Vec<Bdf>,
+
+    /// The guest physical addresses of the hidden pages: ECAM configuration pages and MMIO ranges.
+    pub hidden_pages: BTreeSet<u64>,
+}
+
+impl DeviceHiding {
+    /// Hides a device from the guest and adds it to `SHARED_DEVICE_HIDING`.
+    ///
+    /// The ACPI tables are patched immediately, the configuration space and MMIO pages are hidden once the processors
+    /// are virtualized by `intercept_hidden_devices`. This must be called after the ACPI tables have been parsed and
+    /// before `ExitBootServices`.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device to hide.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the device has been hidden, otherwise `Err(HypervisorError)`.
+    pub fn hide_device(device: &HiddenDevice) -> Result<(), HypervisorError> {
+        let acpi_tables = SHARED_ACPI_TABLES.read();
+        let mut device_hiding = SHARED_DEVICE_HIDING.write();
+
+        let acpi_name = match *device {
+            HiddenDevice::Pci { bdf, acpi_name } => {
+                let ecam_regions = acpi_tables.ecam_regions();
+
+                // An absent function reads as all ones, which is also what the guest will see once it's hidden.
+                if read_config_dword(bdf, 0, &ecam_regions).is_none_or(|id| id as u16 == u16::MAX) {
+                    return Err(HypervisorError::PciDeviceNotFound);
+                }
+
+                for (base_pa, size) in memory_bars(bdf, &ecam_regions) {
+                    debug!("Hiding BAR of PCI function {:x?}: {:#x} ({:#x} bytes)", bdf, base_pa, size);
+                    device_hiding.add_hidden_range(base_pa, size);
+                }
+
+                if let Some(ecam_address) = bdf.ecam_address(&ecam_regions) {
+                    device_hiding.hidden_pages.insert(ecam_address);
+                }
+
+                device_hiding.hidden_pci_functions.push(bdf);
+                acpi_name
+            }
+            HiddenDevice::Mmio { base_pa, size, acpi_name } => {
+                device_hiding.add_hidden_range(base_pa, size);
+                acpi_name
+            }
+        };
+
+        if let Some(acpi_name) = acpi_name {
+            acpi_tables.hide_device_object(&acpi_name)?;
+        }
+
+        debug!("Hid device: {:x?}", device);
+
+        Ok(())
+    }
+
+    /// Adds the pages of a physical memory range to the hidden pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_pa` - The physical address of the range.
+    /// * `size` - The size of the range in bytes.
+    fn add_hidden_range(&mut self, base_pa: u64, size: u64) {
+        let start = PAddr::from(base_pa).align_down_to_base_page().as_u64();
+        let end = PAddr::from(base_pa + size).align_up_to_base_page().as_u64();
+
+        self.hidden_pages.extend((start..end).step_by(BASE_PAGE_SIZE));
+    }
+}
+
+/// Intercepts the guest's accesses to the hidden devices on the current logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the devices are hidden, otherwise `Err(HypervisorError)`.
+pub fn intercept_hidden_devices(vm: &mut Vm) -> Result<(), HypervisorError> {
+    let device_hiding = SHARED_DEVICE_HIDING.read();
+
+    if !device_hiding.hidden_pci_functions.is_empty() {
+        debug!("Intercepting PCI configuration data ports");
+        for port in PCI_CONFIG_DATA_PORT..PCI_CONFIG_DATA_PORT + 4 {
+            vm.io_bitmap.modify_io_interception(port, IoOperation::Hook);
+        }
+    }
+
+    let dummy_page_pa = SHARED_HOST_CONFIG.read().dummy_page_pa;
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    // Flush only once after all the pages have been hidden.
+    hook_manager.begin_deferred_flush();
+
+    for &guest_page_pa in &device_hiding.hidden_pages {
+        // Read-only, so the dummy page keeps reading as all ones and writes cause an EPT violation to discard them.
+        if let Err(e) = hook_manager.ept_map_host_page(vm, guest_page_pa, dummy_page_pa, AccessType::READ) {
+            hook_manager.end_deferred_flush(vm);
+            return Err(e);
+        }
+    }
+
+    hook_manager.end_deferred_flush(vm);
+
+    Ok(())
+}
+
+/// Checks whether an access to a legacy PCI configuration data port targets a hidden PCI function.
+///
+/// The configuration address port isn't intercepted, it's read to find the function currently addressed.
+///
+/// # Arguments
+///
+/// * `port` - The accessed I/O port.
+pub fn is_hidden_pci_config_access(port: u16) -> bool {
+    if !(PCI_CONFIG_DATA_PORT..PCI_CONFIG_DATA_PORT + 4).contains(&port) {
+        return false;
+    }
+
+    Bdf::from_config_address(unsafe { inl(PCI_CONFIG_ADDRESS_PORT) })
+        .is_some_and(|bdf| SHARED_DEVICE_HIDING.read().hidden_pci_functions.contains(&bdf))
+}
+
+/// Checks whether a guest page belongs to a hidden device.
+///
+/// # Arguments
+///
+/// * `guest_page_pa` - The physical address of the guest page.
+pub fn is_hidden_device_page(guest_page_pa: u64) -> bool {
+    SHARED_DEVICE_HIDING.read().hidden_pages.contains(&guest_page_pa)
+}
+
+/// Reads a dword of the configuration space of a PCI function, through ECAM if available or the legacy ports otherwise.
+///
+/// # Arguments
+///
+/// * `bdf` - The address of the function.
+/// * `offset` - The dword-aligned offset in the configuration space.
+/// * `ecam_regions` - The ECAM regions from the MCFG.
+///
+/// # Returns
+///
+/// An `Option` containing the value, or `None` if the configuration space isn't accessible.
+pub(crate) fn read_config_dword(bdf: Bdf, offset: u8, ecam_regions: &[EcamRegion]) -> Option<u32> {
+    if let Some(ecam_address) = bdf.ecam_address(ecam_regions) {
+        return Some(unsafe { read_volatile((ecam_address + offset as u64) as *const u32) });
+    }
+
+    let address = legacy_config_address(bdf, offset)?;
+
+    Some(unsafe {
+        outl(PCI_CONFIG_ADDRESS_PORT, address);
+        inl(PCI_CONFIG_DATA_PORT)
+    })
+}
+
+/// Writes a dword of the configuration space of a PCI function, through ECAM if available or the legacy ports otherwise.
+///
+/// # Arguments
+///
+/// * `bdf` - The address of the function.
+/// * `offset` - The dword-aligned offset in the configuration space.
+/// * `value` - The value to write.
+/// * `ecam_regions` - The ECAM regions from the MCFG.
+fn write_config_dword(bdf: Bdf, offset: u8, value: u32, ecam_regions: &[EcamRegion]) {
+    if let Some(ecam_address) = bdf.ecam_address(ecam_regions) {
+        unsafe { write_volatile((ecam_address + offset as u64) as *mut u32, value) };
+    } else if let Some(address) = legacy_config_address(bdf, offset) {
+        unsafe {
+            outl(PCI_CONFIG_ADDRESS_PORT, address);
+            outl(PCI_CONFIG_DATA_PORT, value);
+        }
+    }
+}
+
+/// Encodes the legacy PCI configuration address of a function, which can only address segment 0.
+///
+/// # Arguments
+///
+/// * `bdf` - The address of the function.
+/// * `offset` - The dword-aligned offset in the configuration space.
+fn legacy_config_address(bdf: Bdf, offset: u8) -> Option<u32> {
+    if bdf.segment != 0 {
+        return None;
+    }
+
+    Some(PCI_CONFIG_ENABLE | (bdf.bus as u32) << 16 | (bdf.device as u32) << 11 | (bdf.function as u32) << 8 | (offset & 0xFC) as u32)
+}
+
+/// Returns the memory ranges decoded by the memory BARs of a PCI function.
+///
+/// The sizes are probed by writing all ones to each BAR, with memory decoding disabled while probing.
+///
+/// # Arguments
+///
+/// * `bdf` - The address of the function.
+/// * `ecam_regions` - The ECAM regions from the MCFG.
+///
+/// # Returns
+///
+/// A `Vec` containing the physical address and size of each assigned memory BAR.
+///
+/// Reference: PCI Local Bus Specification: 6.2.5.1 Address Maps
+fn memory_bars(bdf: Bdf, ecam_regions: &[EcamRegion]) -> Vec<(u64, u64)> {
+    let read = |offset| read_config_dword(bdf, offset, ecam_regions).unwrap_or(0);
+    let write = |offset, value| write_config_dword(bdf, offset, value, ecam_regions);
+
+    // Type 0 headers (endpoints) have 6 BARs, type 1 headers (bridges) have 2.
+    let bar_count = match (read(PCI_HEADER_TYPE & 0xFC) >> 16) & 0x7F {
+        0 => 6,
+        1 => 2,
+        _ => return Vec::new(),
+    };
+
+    // Only keep the Command register, the Status register bits are cleared by writing ones.
+    let command = read(PCI_COMMAND) & 0xFFFF;
+    write(PCI_COMMAND, command & !PCI_COMMAND_MEMORY_SPACE);
+
+    let mut bars = Vec::new();
+    let mut index = 0;
+
+    while index < bar_count {
+        let offset = PCI_BAR0 + index * 4;
+        let bar = read(offset);
+        index += 1;
+
+        // Bit 0 is set for I/O space BARs.
+        if bar & 0x1 != 0 {
+            continue;
+        }
+
+        write(offset, u32::MAX);
+        let mut size_mask = (read(offset) & !0xF) as u64 | 0xFFFF_FFFF_0000_0000;
+        write(offset, bar);
+
+        let mut base_pa = (bar & !0xF) as u64;
+
+        // Bits 2:1 of 10b mean the BAR is 64 bits wide and the next BAR holds the upper half.
+        if (bar >> 1) & 0x3 == 0x2 && index < bar_count {
+            let upper_offset = PCI_BAR0 + index * 4;
+            let upper_bar = read(upper_offset);
+            index += 1;
+
+            write(upper_offset, u32::MAX);
+            size_mask = (size_mask & 0xFFFF_FFFF) | (read(upper_offset) as u64) << 32;
+            write(upper_offset, upper_bar);
+
+            base_pa |= (upper_bar as u64) << 32;
+        }
+
+        let size = (!size_mask).wrapping_add(1);
+
+        if base_pa != 0 && size != 0 {
+            bars.push((base_pa, size));
+        }
+    }
+
+    write(PCI_COMMAND, command);
+
+    bars
+}
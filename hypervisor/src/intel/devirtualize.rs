@@ -0,0 +1,728 @@
+//! Provides the devirtualization of the logical processors, so the hypervisor can be unloaded without rebooting, e.g.,
+//! for debugging or to compare the behavior of a Windows guest with and without it.
+//!
+//! Once requested, each logical processor leaves VMX operation at the end of its next VM exit after which the guest can
+//! be resumed natively: in kernel mode and 64-bit mode, without a pending, interrupted or pending debug event, outside of
+//! an interrupt shadow and of a single-step. Until then, it retries on each VM exit, the periodic task of the `scheduler`
+//! registered with the request making the logical processors exit every `RETRY_PERIOD_MS` once they picked it up. A
+//! logical processor that causes no VM exit at all, e.g., idle without another periodic task, leaves on its first one,
+//! at the latest on the next poll of the broadcast commands.
+//!
+//! The state the VM entry would have loaded is restored by hand after VMXOFF: the original IA32_LSTAR and SYSENTER MSRs
+//! of the guest, shadowed by the MSR and syscall hooks, the debug registers observed by the guest, IA32_DEBUGCTL and
+//! IA32_EFER, the descriptor tables and segments, the control registers, with CR4.VMXE clear as the guest observed it, and the
+//! general-purpose and XMM registers. The TSC is moved to the guest TSC, though it runs at the native rate from then if
+//! the guest TSC was scaled. The EPT hooks, the EPT views and the hidden memory are undone with the EPT itself.
+//!
+//! The host runs with its own page tables, which don't map the guest kernel, while the guest page tables don't map the
+//! hypervisor, so no instruction of the hypervisor can both load the guest CR3 and be followed by another one. The return
+//! goes through two gadgets found in the code of ntoskrnl.exe on request, `pop rax; ret` and `iretq`: the hypervisor
+//! switches to page tables mapping its own memory and, at the virtual address of the page of the `pop rax; ret`, a copy
+//! of the trampoline ending right before the gadget. The trampoline loads the guest registers, CR0 and the stack pointer,
+//! then the guest CR3, after which the next instruction fetched is the gadget of the guest, which pops the guest RAX and
+//! returns to the `iretq`, which pops the guest RIP, CS, RFLAGS, RSP and SS from a frame written below the guest stack.
+//! The TLB is flushed once the page tables of the trampoline are loaded with the PCID of the guest, so loading the guest
+//! CR3 discards every mapping of the hypervisor.
+//!
+//! An NMI received between the load of the guest IDT and the return to the guest can't be delivered and shuts the
+//! logical processor down. The supervisor shadow stacks (CR4.CET) and 5-level paging aren't supported. The memory of the
+//! hypervisor isn't freed: it is UEFI runtime memory, which stays reserved for the lifetime of the operating system.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: VMXOFF—Leave VMX Operation, and 4.10.4
+//! Invalidation of TLBs and Paging-Structure Caches
+
+use {
+    crate::{
+        allocator::box_zeroed,
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            broadcast::leave_broadcasts,
+            capture::GuestRegisters,
+            debug_registers::read_guest_debug_register,
+            hooks::hook_manager::{kernel_image, KernelImage},
+            nmi::{accept_host_nmis, refuse_host_nmis},
+            page::Page,
+            paging::CR3_ADDRESS_MASK,
+            scheduler::SHARED_SCHEDULER,
+            segmentation::VmxSegmentAccessRights,
+            support::{
+                cr3, cr3_write, cr4_write, dr0_read, dr0_write, dr1_read, dr1_write, dr2_read, dr2_write, dr3_read, dr3_write, dr6_read, dr6_write,
+                dr7_write, rdmsr, rdtsc, read_effective_guest_cr0, read_effective_guest_cr4, vmread, vmxoff, wrmsr,
+            },
+            tsc_compensation::guest_tsc,
+            vm::Vm,
+        },
+        windows::{
+            kernel::{read_guest_bytes, read_section_headers},
+            nt::types::{IMAGE_SCN_MEM_DISCARDABLE, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_WRITE},
+        },
+    },
+    alloc::boxed::Box,
+    core::{
+        arch::{asm, global_asm},
+        mem,
+        sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::{
+        bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SIZE},
+        dtables::{lgdt, lidt, DescriptorTablePointer},
+        msr,
+        segmentation::{load_ds, load_es, load_fs, load_gs, SegmentSelector},
+        task::load_tr,
+        vmx::vmcs,
+    },
+    x86_64::registers::control::Cr4Flags,
+};
+
+/// The `pop rax; ret` gadget the trampoline ends before.
+const POP_RAX_RET: [u8; 2] = [0x58, 0xC3];
+
+/// The `iretq` gadget returning to the guest.
+const IRETQ: [u8; 2] = [0x48, 0xCF];
+
+/// The maximum size of the trampoline, the minimum offset of the `pop rax; ret` gadget in its page.
+const MAX_TRAMPOLINE_SIZE: usize = 0x100;
+
+/// The period of the task making the logical processors exit until they left VMX operation.
+const RETRY_PERIOD_MS: u64 = 1;
+
+/// The present bit of a paging-structure entry.
+const PAGE_PRESENT: u64 = 1 << 0;
+
+/// The writable bit of a paging-structure entry.
+const PAGE_WRITABLE: u64 = 1 << 1;
+
+/// The busy bit of the type of a TSS descriptor, in its access byte.
+const TSS_DESCRIPTOR_BUSY: u8 = 1 << 1;
+
+/// The offset of the access byte in a segment descriptor.
+const DESCRIPTOR_ACCESS_BYTE_OFFSET: usize = 5;
+
+/// Whether the logical processors are requested to leave VMX operation.
+static DEVIRTUALIZATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The number of logical processors that left VMX operation.
+static DEVIRTUALIZED_PROCESSOR_COUNT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// A globally shared instance of `Devirtualization`, protected by a mutex.
+    pub static ref SHARED_DEVIRTUALIZATION: Mutex<Devirtualization> = Mutex::new(Devirtualization::new());
+}
+
+extern "efiapi" {
+    /// The trampoline loading the guest registers and CR3, copied right before the `pop rax; ret` gadget. It's never
+    /// called in place.
+    fn devirtualize_trampoline();
+
+    /// The end of the trampoline.
+    fn devirtualize_trampoline_end();
+}
+
+/// A paging structure of the page tables of the trampoline.
+#[repr(C, align(4096))]
+struct PagingStructure([u64; 512]);
+
+/// The path of the return to the guest, prepared on the first request and shared by all the logical processors.
+#[derive(Debug, Clone, Copy)]
+struct ReturnPath {
+    /// The virtual address of the `pop rax; ret` gadget.
+    pop_rax_ret_va: u64,
+
+    /// The virtual address of the `iretq` gadget.
+    iretq_va: u64,
+
+    /// The virtual address of the copy of the trampoline, ending at the `pop rax; ret` gadget.
+    trampoline_va: u64,
+
+    /// The physical address of the PML4 mapping the memory of the hypervisor and the copy of the trampoline.
+    pml4_pa: u64,
+}
+
+/// The devirtualization request, shared by all the logical processors.
+#[derive(Debug)]
+pub struct Devirtualization {
+    /// The path of the return to the guest, or `None` until it is first requested.
+    return_path: Option<ReturnPath>,
+
+    /// The identifier of the periodic task, or `None` while not requested.
+    task_id: Option<u64>,
+}
+
+impl Devirtualization {
+    /// Creates a new devirtualization state, not requested.
+    fn new() -> Self {
+        Self {
+            return_path: None,
+            task_id: None,
+        }
+    }
+}
+
+/// The registers loaded by the trampoline, on the stack of the host.
+#[repr(C)]
+struct TrampolineContext {
+    /// The general-purpose and XMM registers of the guest, RAX, RSP, RIP and RFLAGS being restored from the frame.
+    registers: GuestRegisters,
+
+    /// The CR0 of the guest, loaded after the XMM registers, as CR0.TS may be set.
+    cr0: u64,
+
+    /// The address of the frame popped by the gadgets, below the guest stack pointer.
+    frame_va: u64,
+
+    /// The CR3 of the guest.
+    cr3: u64,
+}
+
+/// The state loaded by the VM entry that isn't restored by the trampoline and the frame, captured before VMXOFF.
+struct NativeState {
+    /// The effective CR4 of the guest, without CR4.VMXE.
+    cr4: u64,
+
+    /// The base of the GDT of the guest.
+    gdtr_base: u64,
+
+    /// The limit of the GDT of the guest.
+    gdtr_limit: u16,
+
+    /// The base of the IDT of the guest.
+    idtr_base: u64,
+
+    /// The limit of the IDT of the guest.
+    idtr_limit: u16,
+
+    /// The DS, ES, FS and GS selectors of the guest.
+    data_segments: [u16; 4],
+
+    /// The LDTR selector of the guest.
+    ldtr: u16,
+
+    /// The TR selector of the guest.
+    tr: u16,
+
+    /// The IA32_FS_BASE and IA32_GS_BASE of the guest.
+    segment_bases: [u64; 2],
+
+    /// The original IA32_LSTAR of the guest.
+    lstar: u64,
+
+    /// The IA32_SYSENTER_CS, IA32_SYSENTER_ESP and IA32_SYSENTER_EIP of the guest.
+    sysenter: [u64; 3],
+
+    /// The IA32_DEBUGCTL of the guest.
+    debugctl: u64,
+
+    /// The IA32_EFER of the guest, switched with that of the host on VM exits.
+    efer: u64,
+
+    /// DR0-DR3, DR6 and DR7 as observed by the guest.
+    debug_registers: [u64; 6],
+
+    /// The difference between the guest TSC and the TSC.
+    tsc_offset: u64,
+}
+
+impl NativeState {
+    /// Captures the state of the guest from the VMCS and the shadows of the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    fn capture(vm: &Vm) -> Self {
+        let debug_registers = match vm.debug_registers.is_virtualized() {
+            true => [0, 1, 2, 3, 6, 7].map(|debug_register| read_guest_debug_register(vm, debug_register)),
+            false => [dr0_read(), dr1_read(), dr2_read(), dr3_read(), dr6_read(), vmread(vmcs::guest::DR7)],
+        };
+
+        let original_or = |original: u64, current: u64| if original != 0 { original } else { current };
+        let tsc = rdtsc();
+
+        Self {
+            cr4: read_effective_guest_cr4() & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits(),
+            gdtr_base: vmread(vmcs::guest::GDTR_BASE),
+            gdtr_limit: vmread(vmcs::guest::GDTR_LIMIT) as u16,
+            idtr_base: vmread(vmcs::guest::IDTR_BASE),
+            idtr_limit: vmread(vmcs::guest::IDTR_LIMIT) as u16,
+            data_segments: [
+                vmcs::guest::DS_SELECTOR,
+                vmcs::guest::ES_SELECTOR,
+                vmcs::guest::FS_SELECTOR,
+                vmcs::guest::GS_SELECTOR,
+            ]
+            .map(|field| vmread(field) as u16),
+            ldtr: vmread(vmcs::guest::LDTR_SELECTOR) as u16,
+            tr: vmread(vmcs::guest::TR_SELECTOR) as u16,
+            segment_bases: [vmread(vmcs::guest::FS_BASE), vmread(vmcs::guest::GS_BASE)],
+            lstar: original_or(vm.guest_registers.original_lstar, rdmsr(msr::IA32_LSTAR)),
+            sysenter: [
+                vmread(vmcs::guest::IA32_SYSENTER_CS),
+                original_or(vm.guest_registers.original_sysenter_esp, vmread(vmcs::guest::IA32_SYSENTER_ESP)),
+                original_or(vm.guest_registers.original_sysenter_eip, vmread(vmcs::guest::IA32_SYSENTER_EIP)),
+            ],
+            debugctl: vmread(vmcs::guest::IA32_DEBUGCTL_FULL),
+            efer: vmread(vmcs::guest::IA32_EFER_FULL),
+            debug_registers,
+            tsc_offset: guest_tsc(tsc).wrapping_sub(tsc),
+        }
+    }
+
+    /// Restores the MSRs, the debug registers, the segments and CR4 of the guest, after VMXOFF.
+    ///
+    /// The GDT of the guest marks its TSS busy, which `ltr` refuses, so the segments are loaded from a copy of the GDT
+    /// with the TSS available, the GDTR of the guest being loaded afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `gdt` - The copy of the GDT of the guest.
+    fn restore(&self, gdt: &mut [u8]) {
+        if self.tsc_offset != 0 {
+            wrmsr(msr::IA32_TIME_STAMP_COUNTER, rdtsc().wrapping_add(self.tsc_offset));
+        }
+
+        cr4_write(self.cr4);
+
+        wrmsr(msr::IA32_LSTAR, self.lstar);
+        wrmsr(msr::IA32_SYSENTER_CS, self.sysenter[0]);
+        wrmsr(msr::IA32_SYSENTER_ESP, self.sysenter[1]);
+        wrmsr(msr::IA32_SYSENTER_EIP, self.sysenter[2]);
+        wrmsr(msr::IA32_DEBUGCTL, self.debugctl);
+        wrmsr(msr::IA32_EFER, self.efer);
+
+        dr0_write(self.debug_registers[0]);
+        dr1_write(self.debug_registers[1]);
+        dr2_write(self.debug_registers[2]);
+        dr3_write(self.debug_registers[3]);
+        dr6_write(self.debug_registers[4]);
+        dr7_write(self.debug_registers[5]);
+
+        if let Some(access_byte) = gdt.get_mut((self.tr & !0x7) as usize + DESCRIPTOR_ACCESS_BYTE_OFFSET) {
+            *access_byte &= !TSS_DESCRIPTOR_BUSY;
+        }
+
+        let gdt_copy = DescriptorTablePointer::<u64> {
+            limit: self.gdtr_limit,
+            base: gdt.as_ptr() as *const u64,
+        };
+
+        let guest_gdt = DescriptorTablePointer::<u64> {
+            limit: self.gdtr_limit,
+            base: self.gdtr_base as *const u64,
+        };
+
+        let [ds, es, fs, gs] = self.data_segments.map(SegmentSelector::from_raw);
+
+        unsafe {
+            lgdt(&gdt_copy);
+            load_ds(ds);
+            load_es(es);
+            load_fs(fs);
+            load_gs(gs);
+            asm!("lldt {0:x}", in(reg) self.ldtr);
+            load_tr(SegmentSelector::from_raw(self.tr));
+            lgdt(&guest_gdt);
+        }
+
+        // Loading FS and GS reloads their bases from the descriptors.
+        wrmsr(msr::IA32_FS_BASE, self.segment_bases[0]);
+        wrmsr(msr::IA32_GS_BASE, self.segment_bases[1]);
+    }
+}
+
+/// Returns `true` while the logical processors are requested to leave VMX operation.
+pub fn is_devirtualization_requested() -> bool {
+    DEVIRTUALIZATION_REQUESTED.load(Ordering::Acquire)
+}
+
+/// Requests the logical processors to leave VMX operation, preparing the return path to the guest on the first request.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the request is published, each logical processor leaving on a later VM exit.
+/// * `Err(HypervisorError::GetKernelBaseFailed)` - If the base of ntoskrnl.exe isn't captured yet.
+/// * `Err(HypervisorError::DevirtualizationGadgetNotFound)` - If the code of ntoskrnl.exe has no suitable gadget.
+/// * `Err(HypervisorError)` - If the headers of ntoskrnl.exe can't be read, or the periodic task can't be registered.
+pub fn request_devirtualization() -> Result<(), HypervisorError> {
+    let mut devirtualization = SHARED_DEVIRTUALIZATION.lock();
+
+    if devirtualization.return_path.is_none() {
+        devirtualization.return_path = Some(prepare_return_path()?);
+    }
+
+    if devirtualization.task_id.is_none() {
+        devirtualization.task_id = Some(SHARED_SCHEDULER.lock().register("devirtualization", keep_exiting, RETRY_PERIOD_MS)?);
+    }
+
+    DEVIRTUALIZATION_REQUESTED.store(true, Ordering::Release);
+
+    info!("Devirtualization requested: {:x?}", devirtualization.return_path);
+
+    Ok(())
+}
+
+/// Cancels the request, the logical processors that already left VMX operation staying devirtualized.
+pub fn cancel_devirtualization() {
+    let mut devirtualization = SHARED_DEVIRTUALIZATION.lock();
+
+    DEVIRTUALIZATION_REQUESTED.store(false, Ordering::Release);
+
+    if let Some(task_id) = devirtualization.task_id.take() {
+        SHARED_SCHEDULER.lock().unregister(task_id);
+    }
+
+    info!("Devirtualization cancelled, {} logical processors devirtualized", DEVIRTUALIZED_PROCESSOR_COUNT.load(Ordering::Relaxed));
+}
+
+/// The periodic task of a request, run only for the VM exits it causes: each VM exit retries the devirtualization.
+///
+/// # Arguments
+///
+/// * `_vm` - The virtual machine instance of the current logical processor.
+fn keep_exiting(_vm: &mut Vm) {}
+
+/// Finds the gadgets in the code of ntoskrnl.exe and builds the page tables of the trampoline.
+///
+/// # Returns
+///
+/// The return path, or an error if the base of ntoskrnl.exe isn't captured, its headers can't be read, or it has no
+/// suitable gadget.
+fn prepare_return_path() -> Result<ReturnPath, HypervisorError> {
+    let KernelImage {
+        base_va: ntoskrnl_base_va,
+        size: ntoskrnl_size,
+        ..
+    } = kernel_image();
+
+    if ntoskrnl_base_va == 0 {
+        return Err(HypervisorError::GetKernelBaseFailed);
+    }
+
+    let trampoline = trampoline_code();
+
+    if trampoline.len() > MAX_TRAMPOLINE_SIZE {
+        return Err(HypervisorError::DevirtualizationUnsupported);
+    }
+
+    let directory_table_base = PhysicalAddress::kernel_directory_table_base().unwrap_or_else(|| vmread(vmcs::guest::CR3));
+    let sections = read_section_headers(ntoskrnl_base_va, directory_table_base)?;
+
+    let mut pop_rax_ret_va = None;
+    let mut iretq_va = None;
+
+    for section in &sections {
+        if section.Characteristics & IMAGE_SCN_MEM_EXECUTE == 0 || section.Characteristics & (IMAGE_SCN_MEM_WRITE | IMAGE_SCN_MEM_DISCARDABLE) != 0 {
+            continue;
+        }
+
+        let start = ntoskrnl_base_va + section.VirtualAddress as u64;
+        let end = start + (section.VirtualSize as u64).min(ntoskrnl_size.saturating_sub(section.VirtualAddress as u64));
+        let mut page_va = start & !(BASE_PAGE_SIZE as u64 - 1);
+
+        while page_va < end && (pop_rax_ret_va.is_none() || iretq_va.is_none()) {
+            if let Some(page) = PhysicalAddress::read_guest_kernel_virt_slice(page_va as *const u8, BASE_PAGE_SIZE) {
+                let first = start.saturating_sub(page_va) as usize;
+                let last = (end - page_va).min(BASE_PAGE_SIZE as u64) as usize;
+
+                // Both bytes of a gadget are in the page, and the trampoline fits before the `pop rax; ret` in its page.
+                let find = |gadget: [u8; 2], min_offset: usize| {
+                    page[first..last]
+                        .windows(gadget.len())
+                        .enumerate()
+                        .find(|&(index, bytes)| bytes == gadget && first + index >= min_offset)
+                        .map(|(index, _)| page_va + (first + index) as u64)
+                };
+
+                pop_rax_ret_va = pop_rax_ret_va.or_else(|| find(POP_RAX_RET, MAX_TRAMPOLINE_SIZE));
+                iretq_va = iretq_va.or_else(|| find(IRETQ, 0));
+            }
+
+            page_va += BASE_PAGE_SIZE as u64;
+        }
+    }
+
+    let (Some(pop_rax_ret_va), Some(iretq_va)) = (pop_rax_ret_va, iretq_va) else {
+        return Err(HypervisorError::DevirtualizationGadgetNotFound);
+    };
+
+    let trampoline_va = pop_rax_ret_va - trampoline.len() as u64;
+    let pml4_pa = build_trampoline_page_tables(pop_rax_ret_va, trampoline);
+
+    Ok(ReturnPath {
+        pop_rax_ret_va,
+        iretq_va,
+        trampoline_va,
+        pml4_pa,
+    })
+}
+
+/// Builds the page tables of the trampoline: the identity map of the host, and the page of the `pop rax; ret` gadget
+/// mapped to a copy of the trampoline ending right before the gadget.
+///
+/// The tables are never freed, as a logical processor may use them until it leaves VMX operation.
+///
+/// # Arguments
+///
+/// * `pop_rax_ret_va` - The virtual address of the `pop rax; ret` gadget, in the upper half of the address space.
+/// * `trampoline` - The code of the trampoline.
+///
+/// # Returns
+///
+/// The physical address of the PML4.
+fn build_trampoline_page_tables(pop_rax_ret_va: u64, trampoline: &[u8]) -> u64 {
+    let pml4: &mut PagingStructure = Box::leak(unsafe { box_zeroed() });
+    let pdpt: &mut PagingStructure = Box::leak(unsafe { box_zeroed() });
+    let pd: &mut PagingStructure = Box::leak(unsafe { box_zeroed() });
+    let pt: &mut PagingStructure = Box::leak(unsafe { box_zeroed() });
+    let code_page: &mut Page = Box::leak(unsafe { box_zeroed() });
+
+    let offset = pop_rax_ret_va as usize & (BASE_PAGE_SIZE - 1);
+    code_page.0[offset - trampoline.len()..offset].copy_from_slice(trampoline);
+
+    // The host identity map is the first entry of the PML4 of the host, and the gadget is in the upper half, in
+    // another entry. The memory of the host is identity mapped, so the addresses of the tables are physical.
+    let host_pml4 = (cr3() & CR3_ADDRESS_MASK) as *const u64;
+    pml4.0[0] = unsafe { host_pml4.read() };
+
+    let va = VAddr::from(pop_rax_ret_va);
+    pml4.0[pml4_index(va)] = pdpt as *const _ as u64 | PAGE_PRESENT | PAGE_WRITABLE;
+    pdpt.0[pdpt_index(va)] = pd as *const _ as u64 | PAGE_PRESENT | PAGE_WRITABLE;
+    pd.0[pd_index(va)] = pt as *const _ as u64 | PAGE_PRESENT | PAGE_WRITABLE;
+    pt.0[pt_index(va)] = code_page as *const _ as u64 | PAGE_PRESENT;
+
+    pml4 as *const _ as u64
+}
+
+/// Returns the code of the trampoline.
+fn trampoline_code() -> &'static [u8] {
+    let start = devirtualize_trampoline as *const () as usize;
+    let end = devirtualize_trampoline_end as *const () as usize;
+
+    unsafe { core::slice::from_raw_parts(start as *const u8, end - start) }
+}
+
+/// Leaves VMX operation on the current logical processor and resumes the guest natively, if the guest can be.
+///
+/// This is called at the end of the VM exits while devirtualization is requested, and returns if the guest can't be
+/// resumed natively yet, the VM entry resuming it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn devirtualize_processor(vm: &Vm) {
+    let Some(return_path) = SHARED_DEVIRTUALIZATION.lock().return_path else {
+        return;
+    };
+
+    if !is_resumable_natively(vm) {
+        return;
+    }
+
+    let guest_cr3 = vmread(vmcs::guest::CR3);
+
+    // The gadgets aren't mapped in every address space, e.g., the user address spaces of KVA shadow.
+    if PhysicalAddress::pa_from_va_with_current_cr3(return_path.pop_rax_ret_va).is_err()
+        || PhysicalAddress::pa_from_va_with_current_cr3(return_path.iretq_va).is_err()
+    {
+        trace!("Devirtualization gadgets not mapped by CR3 {:#x}", guest_cr3);
+        return;
+    }
+
+    let Some(mut gdt) = read_guest_bytes(vmread(vmcs::guest::GDTR_BASE), vmread(vmcs::guest::GDTR_LIMIT) as usize + 1, guest_cr3) else {
+        trace!("Failed to read the GDT of the guest");
+        return;
+    };
+
+    let Some(frame_va) = write_return_frame(vm, &return_path) else {
+        trace!("Failed to write the return frame below the guest stack");
+        return;
+    };
+
+    let native_state = NativeState::capture(vm);
+
+    let context = TrampolineContext {
+        registers: vm.guest_registers,
+        cr0: read_effective_guest_cr0(),
+        frame_va,
+        cr3: guest_cr3,
+    };
+
+    let count = DEVIRTUALIZED_PROCESSOR_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    info!("Leaving VMX operation, {} logical processors devirtualized, guest RIP: {:#x}", count, vmread(vmcs::guest::RIP));
+
+    // An NMI of the hypervisor received natively would be delivered to the guest.
+    refuse_host_nmis(vm);
+
+    if let Err(e) = vmxoff() {
+        error!("Failed to leave VMX operation: {:?}", e);
+        DEVIRTUALIZED_PROCESSOR_COUNT.fetch_sub(1, Ordering::Relaxed);
+        accept_host_nmis(vm);
+        return;
+    }
+
+    leave_broadcasts(vm);
+
+    native_state.restore(&mut gdt);
+
+    // Load the page tables of the trampoline with the PCID of the guest, then flush the TLB, including the global
+    // mappings and the other PCIDs, by toggling CR4.PGE, so the mappings created from here on are the only ones left
+    // and all of them are discarded by loading the guest CR3.
+    cr3_write(return_path.pml4_pa | (guest_cr3 & !CR3_ADDRESS_MASK));
+    cr4_write(native_state.cr4 ^ Cr4Flags::PAGE_GLOBAL.bits());
+    cr4_write(native_state.cr4);
+
+    let guest_idt = DescriptorTablePointer::<u64> {
+        limit: native_state.idtr_limit,
+        base: native_state.idtr_base as *const u64,
+    };
+
+    unsafe {
+        lidt(&guest_idt);
+        asm!("jmp {0}", in(reg) return_path.trampoline_va, in("rax") &context, options(noreturn));
+    }
+}
+
+/// Returns `true` if the guest can be resumed natively through the gadgets, without the VM entry injecting, blocking
+/// or single-stepping anything.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+fn is_resumable_natively(vm: &Vm) -> bool {
+    const VALID: u64 = 1 << 31;
+
+    // The CPL is the DPL of SS, bits 6:5 of its access rights.
+    let cpl = (vmread(vmcs::guest::SS_ACCESS_RIGHTS) >> 5) & 0x3;
+    let cs_access_rights = VmxSegmentAccessRights(vmread(vmcs::guest::CS_ACCESS_RIGHTS) as u32);
+    let unsupported_cr4 = Cr4Flags::L5_PAGING | Cr4Flags::CONTROL_FLOW_ENFORCEMENT;
+
+    cpl == 0
+        && cs_access_rights.long_mode()
+        && read_effective_guest_cr4() & unsupported_cr4.bits() == 0
+        && vmread(vmcs::guest::ACTIVITY_STATE) == 0
+        && vmread(vmcs::guest::INTERRUPTIBILITY_STATE) == 0
+        && vmread(vmcs::guest::PENDING_DBG_EXCEPTIONS) == 0
+        && vmread(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD) & VALID == 0
+        && vm.pending_events.is_empty()
+        && !vm.single_step.is_active()
+        && vm.mtf_resync_page.is_none()
+        && vm.mtf_hook_write.is_none()
+}
+
+/// Writes the frame popped by the gadgets below the guest stack pointer: the guest RAX, the address of the `iretq`
+/// gadget, then the `iretq` frame of the guest RIP, CS, RFLAGS, RSP and SS.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `return_path` - The return path.
+///
+/// # Returns
+///
+/// The virtual address of the frame, or `None` if the stack isn't mapped.
+fn write_return_frame(vm: &Vm, return_path: &ReturnPath) -> Option<u64> {
+    let rsp = vmread(vmcs::guest::RSP);
+
+    let frame = [
+        vm.guest_registers.rax,
+        return_path.iretq_va,
+        vmread(vmcs::guest::RIP),
+        vmread(vmcs::guest::CS_SELECTOR),
+        vmread(vmcs::guest::RFLAGS),
+        rsp,
+        vmread(vmcs::guest::SS_SELECTOR),
+    ];
+
+    let frame_va = rsp.wrapping_sub(mem::size_of_val(&frame) as u64) & !0xF;
+
+    for (index, value) in frame.iter().enumerate() {
+        PhysicalAddress::write_guest_virt_with_current_cr3((frame_va + (index * mem::size_of::<u64>()) as u64) as *mut u64, *value)?;
+    }
+
+    Some(frame_va)
+}
+
+// The trampoline runs from its copy, with RAX pointing to the `TrampolineContext`. The instruction fetched after the
+// load of CR3 is the `pop rax; ret` gadget of the guest.
+global_asm!(
+    r#"
+.global devirtualize_trampoline
+devirtualize_trampoline:
+    movaps  xmm0, [rax + {registers_xmm0}]
+    movaps  xmm1, [rax + {registers_xmm1}]
+    movaps  xmm2, [rax + {registers_xmm2}]
+    movaps  xmm3, [rax + {registers_xmm3}]
+    movaps  xmm4, [rax + {registers_xmm4}]
+    movaps  xmm5, [rax + {registers_xmm5}]
+    movaps  xmm6, [rax + {registers_xmm6}]
+    movaps  xmm7, [rax + {registers_xmm7}]
+    movaps  xmm8, [rax + {registers_xmm8}]
+    movaps  xmm9, [rax + {registers_xmm9}]
+    movaps  xmm10, [rax + {registers_xmm10}]
+    movaps  xmm11, [rax + {registers_xmm11}]
+    movaps  xmm12, [rax + {registers_xmm12}]
+    movaps  xmm13, [rax + {registers_xmm13}]
+    movaps  xmm14, [rax + {registers_xmm14}]
+    movaps  xmm15, [rax + {registers_xmm15}]
+
+    // CR0 is loaded once the XMM registers are, as the guest may have set CR0.TS.
+    mov     rbx, [rax + {context_cr0}]
+    mov     cr0, rbx
+
+    mov     rbx, [rax + {registers_rbx}]
+    mov     rcx, [rax + {registers_rcx}]
+    mov     rdx, [rax + {registers_rdx}]
+    mov     rsi, [rax + {registers_rsi}]
+    mov     rdi, [rax + {registers_rdi}]
+    mov     rbp, [rax + {registers_rbp}]
+    mov     r8,  [rax + {registers_r8}]
+    mov     r9,  [rax + {registers_r9}]
+    mov     r10, [rax + {registers_r10}]
+    mov     r11, [rax + {registers_r11}]
+    mov     r12, [rax + {registers_r12}]
+    mov     r13, [rax + {registers_r13}]
+    mov     r14, [rax + {registers_r14}]
+    mov     r15, [rax + {registers_r15}]
+    mov     rsp, [rax + {context_frame_va}]
+    mov     rax, [rax + {context_cr3}]
+    mov     cr3, rax
+.global devirtualize_trampoline_end
+devirtualize_trampoline_end:
+    ud2
+"#,
+    registers_rbx = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, rbx),
+    registers_rcx = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, rcx),
+    registers_rdx = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, rdx),
+    registers_rsi = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, rsi),
+    registers_rdi = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, rdi),
+    registers_rbp = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, rbp),
+    registers_r8  = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, r8),
+    registers_r9  = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, r9),
+    registers_r10 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, r10),
+    registers_r11 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, r11),
+    registers_r12 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, r12),
+    registers_r13 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, r13),
+    registers_r14 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, r14),
+    registers_r15 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, r15),
+    registers_xmm0 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm0),
+    registers_xmm1 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm1),
+    registers_xmm2 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm2),
+    registers_xmm3 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm3),
+    registers_xmm4 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm4),
+    registers_xmm5 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm5),
+    registers_xmm6 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm6),
+    registers_xmm7 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm7),
+    registers_xmm8 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm8),
+    registers_xmm9 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm9),
+    registers_xmm10 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm10),
+    registers_xmm11 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm11),
+    registers_xmm12 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm12),
+    registers_xmm13 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm13),
+    registers_xmm14 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm14),
+    registers_xmm15 = const mem::offset_of!(TrampolineContext, registers) + mem::offset_of!(GuestRegisters, xmm15),
+    context_cr0 = const mem::offset_of!(TrampolineContext, cr0),
+    context_frame_va = const mem::offset_of!(TrampolineContext, frame_va),
+    context_cr3 = const mem::offset_of!(TrampolineContext, cr3),
+);
@@ -9,15 +9,19 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            invept::invept_all_contexts,
-            invvpid::invvpid_all_contexts,
+            invept::invept_eptp,
             mtrr::{MemoryType, Mtrr},
+            support::rdmsr,
         },
     },
+    alloc::vec::Vec,
     bitfield::bitfield,
-    core::ptr::addr_of,
+    core::{ops::Range, ptr::addr_of},
     log::*,
-    x86::bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+    x86::{
+        bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+        msr::IA32_VMX_EPT_VPID_CAP,
+    },
 };
 
 /// Represents the entire Extended Page Table structure.
@@ -406,6 +410,36 @@ impl Ept {
         Ok(old_hpa)
     }
 
+    /// Retrieves the host physical address and the access permissions a guest page is currently mapped with.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address of the page.
+    /// * `pt` - The page table of the large page containing the guest page. This is only read for 4KB pages.
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the host physical address of the page and its access permissions.
+    pub fn page_mapping(&self, guest_pa: u64, pt: &Pt) -> (u64, AccessType) {
+        let guest_pa = VAddr::from(guest_pa).align_down_to_base_page();
+        let pde = &self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+
+        let (entry, host_pa) = match pde.large() {
+            true => (pde, (pde.pfn() << BASE_PAGE_SHIFT) + (guest_pa.as_u64() & (LARGE_PAGE_SIZE as u64 - 1))),
+            false => {
+                let pte = &pt.0.entries[pt_index(guest_pa)];
+                (pte, pte.pfn() << BASE_PAGE_SHIFT)
+            }
+        };
+
+        let mut access_type = AccessType::empty();
+        access_type.set(AccessType::READ, entry.readable());
+        access_type.set(AccessType::WRITE, entry.writable());
+        access_type.set(AccessType::EXECUTE, entry.executable());
+
+        (host_pa, access_type)
+    }
+
     pub fn dump_ept_entries(&self, guest_pa: u64, pt: &Pt) {
         let guest_pa = VAddr::from(guest_pa);
         let pdpt_index = pdpt_index(guest_pa);
@@ -451,6 +485,28 @@ impl Ept {
     ///
     /// * `Result<(), HypervisorError>` - The result of the operation, `Ok` if successful, otherwise a `HypervisorError`.
     pub fn swap_page(&mut self, guest_pa: u64, host_pa: u64, access_type: AccessType, pt: &mut Pt) -> Result<(), HypervisorError> {
+        self.swap_page_deferred(guest_pa, host_pa, access_type, pt)?;
+
+        // Invalidate the EPT cache for this EPTP only.
+        self.invalidate_ept_cache()
+    }
+
+    /// Same as `swap_page`, but without invalidating the EPT cache.
+    ///
+    /// This allows the caller to batch many page swaps and invalidate the EPT cache only once afterwards
+    /// using `invalidate_ept_cache`. The changes may not take effect until the cache has been invalidated.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to remap.
+    /// * `host_pa` - The new host physical address to map to the guest physical address.
+    /// * `access_type` - The access permissions to set for the mapped page.
+    /// * `pt` - The page table to use for the remap operation.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - The result of the operation, `Ok` if successful, otherwise a `HypervisorError`.
+    pub fn swap_page_deferred(&mut self, guest_pa: u64, host_pa: u64, access_type: AccessType, pt: &mut Pt) -> Result<(), HypervisorError> {
         let guest_pa = VAddr::from(guest_pa);
         let host_pa = VAddr::from(host_pa);
 
@@ -468,15 +524,114 @@ impl Ept {
         trace!("Remapping GPA {:#x} to HPA {:#x} in the primary EPT", guest_pa, host_pa);
         self.remap_gpa_to_hpa(guest_pa.as_u64(), host_pa.as_u64(), pt)?;
 
-        // Invalidate the EPT cache for all contexts.
-        invept_all_contexts();
-
-        // Invalidate the VPID cache for all contexts.
-        invvpid_all_contexts();
+        Ok(())
+    }
 
+    /// Invalidates the guest-physical and combined mappings derived from this EPT.
+    ///
+    /// A single-context INVEPT keyed on the EPTP of this EPT is used instead of an all-context INVEPT, so
+    /// translations cached for other EPTPs are preserved, unless the processor only supports the all-context type.
+    /// Combined mappings are invalidated for all VPIDs and PCIDs, therefore no additional INVVPID is required after
+    /// modifying EPT entries.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - `Ok` if successful, otherwise a `HypervisorError` if the EPTP could not be created.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.4.3.1 Operations that Invalidate Cached Mappings
+    pub fn invalidate_ept_cache(&self) -> Result<(), HypervisorError> {
+        invept_eptp(self.create_eptp_with_wb_and_4lvl_walk()?);
         Ok(())
     }
 
+    /// Scans a guest physical address range for pages the guest has written to and clears their dirty flags.
+    ///
+    /// This requires the accessed and dirty flags to be enabled in the EPTP (see `create_eptp_with_wb_and_4lvl_walk`).
+    /// The processor sets the dirty flag of the leaf EPT entry on every guest write, so this can be used to detect
+    /// changes to guest memory (e.g., patching of kernel text) without write-protecting every page.
+    ///
+    /// Large pages that have not been split are reported with a 2MB granularity, split pages with a 4KB granularity.
+    /// The page tables of split pages are located by following the PDE, which relies on the host identity map.
+    ///
+    /// Only the first PML4 entry is populated, so ranges ending above the first 512GB are rejected rather than
+    /// wrapping around to the low pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa_range` - The guest physical address range to scan.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<Vec<(u64, usize)>, HypervisorError>` containing the base guest physical address and the size of each dirty page,
+    /// or `HypervisorError::GuestPhysicalAddressOutOfRange` if the range is not covered by the identity map.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.5 Accessed and Dirty Flags for EPT
+    pub fn scan_and_clear_dirty_pages(&mut self, guest_pa_range: Range<u64>) -> Result<Vec<(u64, usize)>, HypervisorError> {
+        trace!("Scanning dirty pages in GPA range {:#x?}", guest_pa_range);
+
+        /// The guest physical addresses covered by the first PML4 entry (512 PDPT entries of 1GB each).
+        const IDENTITY_MAP_LIMIT: u64 = HUGE_PAGE_SIZE as u64 * 512;
+
+        if guest_pa_range.end > IDENTITY_MAP_LIMIT {
+            error!("GPA range {:#x?} exceeds the identity map", guest_pa_range);
+            return Err(HypervisorError::GuestPhysicalAddressOutOfRange);
+        }
+
+        let mut dirty_pages = Vec::new();
+        let mut guest_pa = VAddr::from(guest_pa_range.start).align_down_to_base_page().as_u64();
+
+        while guest_pa < guest_pa_range.end {
+            let gpa = VAddr::from(guest_pa);
+            let pdpt_index = pdpt_index(gpa);
+            let pd_index = pd_index(gpa);
+
+            let pde = &mut self.pd[pdpt_index].0.entries[pd_index];
+
+            if pde.large() {
+                let large_page_base = gpa.align_down_to_large_page().as_u64();
+
+                if pde.dirty() {
+                    pde.set_dirty(false);
+                    dirty_pages.push((large_page_base, LARGE_PAGE_SIZE));
+                }
+
+                guest_pa = large_page_base + LARGE_PAGE_SIZE as u64;
+            } else {
+                // The page table of a split page is identity mapped in the host.
+                let pt = unsafe { &mut *((pde.pfn() << BASE_PAGE_SHIFT) as *mut Pt) };
+                let pte = &mut pt.0.entries[pt_index(gpa)];
+
+                if pte.dirty() {
+                    pte.set_dirty(false);
+                    dirty_pages.push((guest_pa, BASE_PAGE_SIZE));
+                }
+
+                guest_pa += BASE_PAGE_SIZE as u64;
+            }
+        }
+
+        // Cached translations may still have the dirty flag set, which would prevent the processor from setting it again.
+        if !dirty_pages.is_empty() {
+            self.invalidate_ept_cache()?;
+        }
+
+        Ok(dirty_pages)
+    }
+
+    /// Checks whether the processor supports accessed and dirty flags for EPT.
+    ///
+    /// # Returns
+    ///
+    /// `true` if bit 21 of the IA32_VMX_EPT_VPID_CAP MSR is set, otherwise `false`.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.10 VPID AND EPT CAPABILITIES
+    pub fn is_accessed_dirty_supported() -> bool {
+        /// [Bit 21] When set to 1, accessed and dirty flags for EPT are supported.
+        const ACCESSED_DIRTY_FLAGS: u64 = 1 << 21;
+
+        rdmsr(IA32_VMX_EPT_VPID_CAP) & ACCESSED_DIRTY_FLAGS != 0
+    }
+
     /// Decodes an EPTP value to extract the physical base address, memory type, and page walk length.
     ///
     /// This function reverses the encoding done in `create_eptp_with_wb_and_4lvl_walk`.
@@ -520,7 +675,8 @@ impl Ept {
     ///
     /// This function is used in the setup of Intel VT-x virtualization, specifically for configuring the EPT.
     /// It encodes the provided physical base address of the EPT PML4 table into the EPTP format, setting
-    /// the memory type to Write-Back and indicating a 4-level page walk.
+    /// the memory type to Write-Back and indicating a 4-level page walk. Accessed and dirty flags are enabled
+    /// when the processor supports them, which is required for `scan_and_clear_dirty_pages`.
     ///
     /// # Returns
     /// A `Result<u64, HypervisorError>` containing the configured EPTP value. Returns an error if
@@ -542,10 +698,19 @@ impl Ept {
         // Represents the memory type setting for Write-Back (WB) in the EPTP.
         const EPT_MEMORY_TYPE_WB: u64 = MemoryType::WriteBack as u64;
 
+        // [Bit 6] Enables accessed and dirty flags for EPT.
+        const EPT_ENABLE_ACCESSED_DIRTY: u64 = 1 << 6;
+
+        let accessed_dirty = if Self::is_accessed_dirty_supported() {
+            EPT_ENABLE_ACCESSED_DIRTY
+        } else {
+            0
+        };
+
         // Check if the base address is 4KB aligned (the lower 12 bits should be zero).
         if ept_pml4_base_addr.trailing_zeros() >= 12 {
-            // Construct the EPTP with the page walk length and memory type for WB.
-            Ok(ept_pml4_base_addr | EPT_PAGE_WALK_LENGTH_4 | EPT_MEMORY_TYPE_WB)
+            // Construct the EPTP with the page walk length, memory type for WB and the accessed and dirty flags.
+            Ok(ept_pml4_base_addr | EPT_PAGE_WALK_LENGTH_4 | EPT_MEMORY_TYPE_WB | accessed_dirty)
         } else {
             Err(HypervisorError::InvalidEptPml4BaseAddress)
         }
@@ -608,6 +773,8 @@ bitfield! {
     /// * `executable` - If set, code can be executed from the memory region.
     /// * `memory_type` - The memory type (e.g., WriteBack, Uncacheable).
     /// * `large` - If set, this entry maps a large page.
+    /// * `accessed` - Set by the processor when the entry is used for translation (if enabled in the EPTP).
+    /// * `dirty` - Set by the processor when the page mapped by this entry is written to (if enabled in the EPTP).
     /// * `pfn` - The Page Frame Number, indicating the physical address.
     /// * `verify_guest_paging` - Additional flag for guest paging verification.
     /// * `paging_write_access` - Additional flag for paging write access.
@@ -623,6 +790,8 @@ bitfield! {
     pub executable, set_executable: 2;
     pub memory_type, set_memory_type: 5, 3;
     pub large, set_large: 7;
+    pub accessed, set_accessed: 8;
+    pub dirty, set_dirty: 9;
     pub pfn, set_pfn: 51, 12;
     pub verify_guest_paging, set_verify_guest_paging: 57;
     pub paging_write_access, set_paging_write_access: 58;
@@ -0,0 +1,683 @@
+//! Provides a small pool of EPT views, e.g., "clean", "hooked" or "isolated-process", beyond the primary EPT of each
+//! logical processor, each mapping some guest pages differently, so features can build on more than the usual two
+//! views of a hooked page without each managing its own extended page tables.
+//!
+//! View 0 is the primary EPT, modified by the hook manager and the other features. An alternate view is duplicated
+//! from the primary EPT, in which case it is the clean identity map, without the hooks and the other modifications of
+//! the primary EPT except the hidden hypervisor memory, or from another alternate view, whose divergent pages it
+//! copies. The divergent pages of a view are the guest pages it maps to another host page and/or with other
+//! permissions, set and reset individually. The extended page tables of the alternate views are shared by all the
+//! logical processors, so a view is modified once rather than on each of them, and a collapsed view discards its
+//! divergent pages and sends its logical processors back to the primary EPT.
+//!
+//! Each logical processor runs in the view assigned to it, or in the view of the logical processors without an
+//! assigned view. An access denied by a divergent page of an alternate view switches the logical processor to the
+//! view the page names, e.g., an execute-only page switching to a view in which the page is readable and writable,
+//! which switches back on the next instruction fetch, if the page allows the access there. Otherwise the accessing
+//! instruction is single-stepped in the primary EPT before the logical processor returns to its view. The switches
+//! only reach the primary EPT through the assignments, so the EPT violations of an alternate view never reach the
+//! handlers of the primary EPT, e.g., of the hooks, which therefore don't apply in the alternate views.
+//!
+//! At its first VM exit after the views or their assignments changed, each logical processor invalidates the cached
+//! translations of the alternate views and follows its assignment.
+
+use {
+    crate::{
+        allocator::box_zeroed,
+        error::HypervisorError,
+        intel::{
+            ept::{AccessType, Ept, Pt},
+            host_config::SHARED_HOST_CONFIG,
+            invept::invept_eptp,
+            seqlock::{Generation, Published},
+            single_step::{single_step, SingleStepContext, SingleStepOwner},
+            support::{vmread, vmwrite},
+            vm::Vm,
+            vmerror::EptViolationExitQualification,
+            vmexit::ExitType,
+        },
+    },
+    alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec},
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE},
+        vmx::vmcs,
+    },
+};
+
+/// The identifier of an EPT view.
+pub type EptViewId = u8;
+
+/// The primary EPT of each logical processor.
+pub const PRIMARY_EPT_VIEW: EptViewId = 0;
+
+/// The maximum number of EPT views, including the primary EPT, each alternate view taking about 2 MiB of heap.
+pub const MAX_EPT_VIEWS: usize = 4;
+
+/// The maximum number of divergent pages of an alternate view.
+pub const MAX_EPT_VIEW_PAGES: usize = 0x400;
+
+/// The changes of the views and their assignments, published while the manager is locked.
+static EPT_VIEW_CHANGES: Published<()> = Published::new(());
+
+lazy_static! {
+    /// A globally shared instance of `EptViewManager`, protected by a mutex.
+    pub static ref SHARED_EPT_VIEWS: Mutex<EptViewManager> = Mutex::new(EptViewManager::new());
+}
+
+/// A guest page an alternate view maps differently from the identity map.
+#[derive(Debug, Clone, Copy)]
+pub struct DivergentPage {
+    /// The host physical address of the page mapped at the guest page.
+    pub host_page_pa: u64,
+
+    /// The permissions of the page.
+    pub access_type: AccessType,
+
+    /// The view the logical processors switch to on an access the page denies, or `None` to single-step the access in
+    /// the primary EPT.
+    pub switch_view: Option<EptViewId>,
+}
+
+impl DivergentPage {
+    /// Returns `true` if the page allows the access of an EPT violation.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_qualification` - The exit qualification of the EPT violation.
+    fn allows(&self, exit_qualification: &EptViolationExitQualification) -> bool {
+        (!exit_qualification.data_read || self.access_type.contains(AccessType::READ))
+            && (!exit_qualification.data_write || self.access_type.contains(AccessType::WRITE))
+            && (!exit_qualification.instruction_fetch || self.access_type.contains(AccessType::EXECUTE))
+    }
+}
+
+/// An alternate view.
+#[derive(Debug)]
+struct EptView {
+    /// The name of the view, for the logs.
+    name: String,
+
+    /// The divergent pages of the view, by guest page physical address.
+    pages: BTreeMap<u64, DivergentPage>,
+
+    /// The number of allocated memory ranges of `HostConfig` already hidden in the view.
+    hidden_memory_range_count: usize,
+}
+
+/// The extended page tables of an alternate view, kept once the view is collapsed so the logical processors still
+/// translating through them until their next VM exit don't use freed memory, and reused by the next view with its
+/// identifier.
+struct EptViewTables {
+    /// The extended page tables, mapping the first 2 MiB with their own page table.
+    ept: Box<Ept>,
+
+    /// The EPTP of the tables.
+    eptp: u64,
+
+    /// The page tables of the split large pages, by guest large page physical address.
+    page_tables: BTreeMap<u64, Box<Pt>>,
+}
+
+impl EptViewTables {
+    /// Allocates the extended page tables of a view.
+    fn new() -> Result<Self, HypervisorError> {
+        let ept = unsafe { box_zeroed::<Ept>() };
+        let eptp = ept.create_eptp_with_wb_and_4lvl_walk()?;
+
+        Ok(Self {
+            ept,
+            eptp,
+            page_tables: BTreeMap::new(),
+        })
+    }
+
+    /// Resets the tables to the identity map of the guest physical memory.
+    fn reset(&mut self) -> Result<(), HypervisorError> {
+        self.ept.build_identity()?;
+        self.page_tables.clear();
+
+        Ok(())
+    }
+
+    /// Maps a guest page to a host page with permissions, splitting its large page if needed. The caller invalidates
+    /// the cached translations.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_page_pa` - The guest physical address of the page, outside the first 2 MiB.
+    /// * `host_page_pa` - The host physical address of the page.
+    /// * `access_type` - The permissions of the page.
+    fn map_page(&mut self, guest_page_pa: u64, host_page_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
+        let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page().as_u64();
+
+        if !self.page_tables.contains_key(&guest_large_page_pa) {
+            let mut pt = unsafe { box_zeroed::<Pt>() };
+            self.ept.split_2mb_to_4kb(guest_large_page_pa, &mut pt)?;
+            self.page_tables.insert(guest_large_page_pa, pt);
+        }
+
+        let pt = self.page_tables.get_mut(&guest_large_page_pa).ok_or(HypervisorError::PageTableNotFound)?;
+
+        self.ept.swap_page_deferred(guest_page_pa, host_page_pa, access_type, pt)
+    }
+}
+
+/// The EPT view state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorEptView {
+    /// The view the logical processor runs in.
+    pub active_view: EptViewId,
+
+    /// The view assigned to the logical processor, which it runs in after the switches of its accesses.
+    pub assigned_view: EptViewId,
+
+    /// The view the logical processor returns to once the access single-stepped in the primary EPT has completed.
+    pub stepping_view: EptViewId,
+
+    /// The generation of the views applied to the logical processor.
+    pub generation: Generation,
+}
+
+impl ProcessorEptView {
+    /// Creates the state of a logical processor running in the primary EPT.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// The alternate views, their extended page tables and their assignments, shared by all the logical processors.
+pub struct EptViewManager {
+    /// The alternate views, by identifier.
+    views: BTreeMap<EptViewId, EptView>,
+
+    /// The extended page tables of the alternate views, by identifier, allocated with the first view of each
+    /// identifier.
+    tables: [Option<EptViewTables>; MAX_EPT_VIEWS],
+
+    /// The views assigned to logical processors, by initial APIC ID.
+    processor_views: BTreeMap<u32, EptViewId>,
+
+    /// The view of the logical processors without an assigned view.
+    default_processor_view: EptViewId,
+}
+
+impl EptViewManager {
+    /// Creates the manager, without alternate views.
+    fn new() -> Self {
+        Self {
+            views: BTreeMap::new(),
+            tables: Default::default(),
+            processor_views: BTreeMap::new(),
+            default_processor_view: PRIMARY_EPT_VIEW,
+        }
+    }
+
+    /// Returns a view and its EPTP, or the primary EPT if the view doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    /// * `view` - The view.
+    fn view_or_primary(&self, vm: &Vm, view: EptViewId) -> (EptViewId, u64) {
+        match self.eptp(view) {
+            Some(eptp) => (view, eptp),
+            None => (PRIMARY_EPT_VIEW, vm.primary_eptp),
+        }
+    }
+
+    /// Returns `true` if a view exists, the primary EPT always existing.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The view.
+    pub fn is_view(&self, view: EptViewId) -> bool {
+        view == PRIMARY_EPT_VIEW || self.views.contains_key(&view)
+    }
+
+    /// Returns the EPTP of an alternate view.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The alternate view.
+    ///
+    /// # Returns
+    ///
+    /// The EPTP, or `None` if the view doesn't exist.
+    pub fn eptp(&self, view: EptViewId) -> Option<u64> {
+        match self.views.contains_key(&view) {
+            true => self.tables[view as usize].as_ref().map(|tables| tables.eptp),
+            false => None,
+        }
+    }
+
+    /// Creates an alternate view duplicated from another view.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The identifier of the new view, between 1 and `MAX_EPT_VIEWS - 1`.
+    /// * `source` - The duplicated view, the primary EPT giving the clean identity map with the hidden hypervisor
+    ///   memory.
+    /// * `name` - The name of the view, for the logs.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The view has been created.
+    /// * `Err(HypervisorError::InvalidEptView)` - If the identifier is out of range or the view already exists.
+    /// * `Err(HypervisorError::EptViewNotFound)` - If the source view doesn't exist.
+    /// * `Err(HypervisorError)` - If the extended page tables can't be built.
+    pub fn duplicate(&mut self, view: EptViewId, source: EptViewId, name: &str) -> Result<(), HypervisorError> {
+        if view == PRIMARY_EPT_VIEW || view as usize >= MAX_EPT_VIEWS || self.views.contains_key(&view) {
+            return Err(HypervisorError::InvalidEptView);
+        }
+
+        if !self.is_view(source) {
+            return Err(HypervisorError::EptViewNotFound);
+        }
+
+        if self.tables[view as usize].is_none() {
+            self.tables[view as usize] = Some(EptViewTables::new()?);
+        }
+
+        let tables = self.tables[view as usize].as_mut().ok_or(HypervisorError::EptViewNotFound)?;
+        tables.reset()?;
+
+        let pages = match self.views.get(&source) {
+            Some(source) => source.pages.clone(),
+            None => BTreeMap::new(),
+        };
+
+        for (&guest_page_pa, page) in &pages {
+            tables.map_page(guest_page_pa, page.host_page_pa, page.access_type)?;
+        }
+
+        self.views.insert(
+            view,
+            EptView {
+                name: String::from(name),
+                pages,
+                hidden_memory_range_count: 0,
+            },
+        );
+
+        self.hide_hypervisor_memory(view)?;
+        publish_ept_views();
+
+        debug!("EPT view {} ({}) duplicated from view {}", view, name, source);
+
+        Ok(())
+    }
+
+    /// Discards an alternate view, the logical processors running in it returning to their assigned view, or to the
+    /// primary EPT if it was this view.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The alternate view.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The view has been discarded.
+    /// * `Err(HypervisorError::EptViewNotFound)` - If the view doesn't exist.
+    pub fn collapse(&mut self, view: EptViewId) -> Result<(), HypervisorError> {
+        let collapsed_view = self.views.remove(&view).ok_or(HypervisorError::EptViewNotFound)?;
+
+        self.processor_views.retain(|_, assigned_view| *assigned_view != view);
+        if self.default_processor_view == view {
+            self.default_processor_view = PRIMARY_EPT_VIEW;
+        }
+
+        for other_view in self.views.values_mut() {
+            for page in other_view.pages.values_mut().filter(|page| page.switch_view == Some(view)) {
+                page.switch_view = None;
+            }
+        }
+
+        publish_ept_views();
+
+        debug!("EPT view {} ({}) collapsed, {} divergent pages discarded", view, collapsed_view.name, collapsed_view.pages.len());
+
+        Ok(())
+    }
+
+    /// Maps a guest page of an alternate view to a host page with permissions, replacing its previous mapping.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The alternate view.
+    /// * `guest_page_pa` - The guest physical address of the page, outside the first 2 MiB and the hypervisor memory.
+    /// * `page` - The mapping of the page, whose host page isn't in the hypervisor memory.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The page has been mapped.
+    /// * `Err(HypervisorError::EptViewNotFound)` - If the view, or the view the page switches to, doesn't exist.
+    /// * `Err(HypervisorError::InvalidEptViewPage)` - If a page is unaligned, in the first 2 MiB or in the hypervisor
+    ///   memory, or the page switches to the primary EPT or to its own view.
+    /// * `Err(HypervisorError::TooManyEptViewPages)` - If the view has `MAX_EPT_VIEW_PAGES` divergent pages.
+    /// * `Err(HypervisorError)` - If the extended page tables can't be modified.
+    pub fn set_page(&mut self, view: EptViewId, guest_page_pa: u64, page: DivergentPage) -> Result<(), HypervisorError> {
+        if let Some(switch_view) = page.switch_view {
+            if switch_view == PRIMARY_EPT_VIEW || switch_view == view {
+                return Err(HypervisorError::InvalidEptViewPage);
+            }
+
+            if !self.views.contains_key(&switch_view) {
+                return Err(HypervisorError::EptViewNotFound);
+            }
+        }
+
+        let ept_view = self.views.get_mut(&view).ok_or(HypervisorError::EptViewNotFound)?;
+
+        if !is_mappable_page(guest_page_pa) || !is_mappable_page(page.host_page_pa) {
+            return Err(HypervisorError::InvalidEptViewPage);
+        }
+
+        if !ept_view.pages.contains_key(&guest_page_pa) && ept_view.pages.len() >= MAX_EPT_VIEW_PAGES {
+            return Err(HypervisorError::TooManyEptViewPages);
+        }
+
+        let tables = self.tables[view as usize].as_mut().ok_or(HypervisorError::EptViewNotFound)?;
+        tables.map_page(guest_page_pa, page.host_page_pa, page.access_type)?;
+
+        ept_view.pages.insert(guest_page_pa, page);
+        publish_ept_views();
+
+        trace!("EPT view {}: GPA {:#x} -> HPA {:#x} {:?}", view, guest_page_pa, page.host_page_pa, page.access_type);
+
+        Ok(())
+    }
+
+    /// Maps a divergent guest page of an alternate view back to itself with every permission.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The alternate view.
+    /// * `guest_page_pa` - The guest physical address of the page.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The page has been reset.
+    /// * `Err(HypervisorError::EptViewNotFound)` - If the view doesn't exist.
+    /// * `Err(HypervisorError::InvalidEptViewPage)` - If the page isn't a divergent page of the view.
+    /// * `Err(HypervisorError)` - If the extended page tables can't be modified.
+    pub fn reset_page(&mut self, view: EptViewId, guest_page_pa: u64) -> Result<(), HypervisorError> {
+        let ept_view = self.views.get_mut(&view).ok_or(HypervisorError::EptViewNotFound)?;
+
+        if ept_view.pages.remove(&guest_page_pa).is_none() {
+            return Err(HypervisorError::InvalidEptViewPage);
+        }
+
+        let tables = self.tables[view as usize].as_mut().ok_or(HypervisorError::EptViewNotFound)?;
+        tables.map_page(guest_page_pa, guest_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+
+        publish_ept_views();
+
+        trace!("EPT view {}: GPA {:#x} reset", view, guest_page_pa);
+
+        Ok(())
+    }
+
+    /// Assigns a view to a logical processor, or to the logical processors without an assigned view.
+    ///
+    /// # Arguments
+    ///
+    /// * `processor_id` - The initial APIC ID of the logical processor, or `None` for the logical processors without
+    ///   an assigned view.
+    /// * `view` - The view, `PRIMARY_EPT_VIEW` removing the assignment of a logical processor.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The view has been assigned.
+    /// * `Err(HypervisorError::EptViewNotFound)` - If the view doesn't exist.
+    pub fn switch(&mut self, processor_id: Option<u32>, view: EptViewId) -> Result<(), HypervisorError> {
+        if !self.is_view(view) {
+            return Err(HypervisorError::EptViewNotFound);
+        }
+
+        match processor_id {
+            Some(processor_id) if view == PRIMARY_EPT_VIEW => {
+                self.processor_views.remove(&processor_id);
+            }
+            Some(processor_id) => {
+                self.processor_views.insert(processor_id, view);
+            }
+            None => self.default_processor_view = view,
+        }
+
+        publish_ept_views();
+
+        debug!("EPT view {} assigned to processor {:?}", view, processor_id);
+
+        Ok(())
+    }
+
+    /// Hides the hypervisor memory in all the alternate views, mapping it to the dummy page as in the primary EPT.
+    ///
+    /// Only the ranges recorded since the last call are hidden, so this can be called again to hide the ranges
+    /// recorded later, such as the stacks of the processors virtualized afterwards.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the memory has been hidden, or `Err(HypervisorError)` if the extended page tables can't be modified.
+    pub fn hide_hypervisor_memory_in_views(&mut self) -> Result<(), HypervisorError> {
+        let views = self.views.keys().copied().collect::<Vec<_>>();
+
+        for view in views {
+            self.hide_hypervisor_memory(view)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hides the hypervisor memory recorded since the last call in an alternate view.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The alternate view.
+    fn hide_hypervisor_memory(&mut self, view: EptViewId) -> Result<(), HypervisorError> {
+        if !cfg!(feature = "hide_hv_with_ept") {
+            return Ok(());
+        }
+
+        let ept_view = self.views.get_mut(&view).ok_or(HypervisorError::EptViewNotFound)?;
+
+        let (pages, range_count, dummy_page_pa) = {
+            let host_config = SHARED_HOST_CONFIG.read();
+            (host_config.allocated_pages(ept_view.hidden_memory_range_count), host_config.allocated_memory_ranges.len(), host_config.dummy_page_pa)
+        };
+
+        if range_count == ept_view.hidden_memory_range_count {
+            return Ok(());
+        }
+
+        let tables = self.tables[view as usize].as_mut().ok_or(HypervisorError::EptViewNotFound)?;
+
+        for guest_page_pa in pages.into_iter().filter(|&page_pa| page_pa >= LARGE_PAGE_SIZE as u64) {
+            tables.map_page(guest_page_pa, dummy_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+        }
+
+        ept_view.hidden_memory_range_count = range_count;
+        publish_ept_views();
+
+        trace!("Hypervisor memory hidden in EPT view {}: {} ranges", view, range_count);
+
+        Ok(())
+    }
+}
+
+/// Returns `true` if a page can be a divergent guest page or the host page of one: page-aligned, outside the first
+/// 2 MiB, which the extended page tables map with their own page table, and outside the hypervisor memory.
+///
+/// # Arguments
+///
+/// * `page_pa` - The physical address of the page.
+fn is_mappable_page(page_pa: u64) -> bool {
+    if page_pa & (BASE_PAGE_SIZE as u64 - 1) != 0 || page_pa < LARGE_PAGE_SIZE as u64 {
+        return false;
+    }
+
+    !SHARED_HOST_CONFIG
+        .read()
+        .allocated_memory_ranges
+        .iter()
+        .any(|&(start, size)| (start as u64..(start + size) as u64).contains(&page_pa))
+}
+
+/// Publishes a change of the views or their assignments to the logical processors.
+fn publish_ept_views() {
+    EPT_VIEW_CHANGES.publish(());
+}
+
+/// Applies the changes of the views to the current logical processor, invalidating the cached translations of the
+/// alternate views and switching to its assigned view if its assignment has changed or its view has been collapsed.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_ept_views(vm: &mut Vm) {
+    // The logical processor returns to its view once the access being single-stepped in the primary EPT completes,
+    // picking up the changes at a later VM exit.
+    if vm.single_step.is_stepping_for(SingleStepOwner::EptViewAccess) {
+        vm.published_generation = Generation::STALE;
+        return;
+    }
+
+    if EPT_VIEW_CHANGES.sync(&mut vm.ept_view.generation).is_none() {
+        return;
+    }
+
+    let manager = SHARED_EPT_VIEWS.lock();
+    let processor_id = vm.cpuid_feature_info.initial_local_apic_id() as u32;
+
+    let assigned_view = *manager.processor_views.get(&processor_id).unwrap_or(&manager.default_processor_view);
+
+    for view in manager.views.keys() {
+        if let Some(eptp) = manager.eptp(*view) {
+            invept_eptp(eptp);
+        }
+    }
+
+    let view = match assigned_view != vm.ept_view.assigned_view || !manager.is_view(vm.ept_view.active_view) {
+        true => assigned_view,
+        false => vm.ept_view.active_view,
+    };
+
+    let (view, eptp) = manager.view_or_primary(vm, view);
+
+    vm.ept_view.assigned_view = assigned_view;
+    drop(manager);
+
+    if view != vm.ept_view.active_view {
+        activate_view(vm, view, eptp);
+    }
+}
+
+/// Handles an EPT violation of a logical processor running in an alternate view, switching to the view the accessed
+/// page names if it allows the access, or single-stepping the access in the primary EPT otherwise.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `guest_page_pa` - The guest physical address of the accessed page.
+/// * `exit_qualification` - The exit qualification of the EPT violation.
+///
+/// # Returns
+///
+/// `Ok(ExitType::Continue)` if the access has been handled, or `Err(HypervisorError)` if it can't be single-stepped.
+pub fn handle_ept_view_violation(
+    vm: &mut Vm,
+    guest_page_pa: u64,
+    exit_qualification: &EptViolationExitQualification,
+) -> Result<ExitType, HypervisorError> {
+    let view = vm.ept_view.active_view;
+    let manager = SHARED_EPT_VIEWS.lock();
+
+    let Some(page) = manager.views.get(&view).and_then(|ept_view| ept_view.pages.get(&guest_page_pa)) else {
+        // The page has been reset meanwhile, and the translation cached before is discarded.
+        if let Some(eptp) = manager.eptp(view) {
+            invept_eptp(eptp);
+        }
+        return Ok(ExitType::Continue);
+    };
+
+    // The access is retried in the view the page names if that view allows it, so the views don't switch forever.
+    let switch_view = page.switch_view.filter(|&switch_view| {
+        manager
+            .views
+            .get(&switch_view)
+            .map(|ept_view| ept_view.pages.get(&guest_page_pa).is_none_or(|page| page.allows(exit_qualification)))
+            .unwrap_or(false)
+    });
+
+    if let Some(switch_view) = switch_view {
+        let eptp = manager.eptp(switch_view).ok_or(HypervisorError::EptViewNotFound)?;
+        drop(manager);
+
+        trace!("EPT view switch {} -> {} on GPA {:#x}", view, switch_view, guest_page_pa);
+        activate_view(vm, switch_view, eptp);
+
+        return Ok(ExitType::Continue);
+    }
+
+    drop(manager);
+
+    trace!("Stepping the access to GPA {:#x} of EPT view {} in the primary EPT", guest_page_pa, view);
+
+    vm.ept_view.stepping_view = view;
+    let eptp = vm.primary_eptp;
+    activate_view(vm, PRIMARY_EPT_VIEW, eptp);
+
+    let context = SingleStepContext {
+        guest_page_pa,
+        guest_next_page_pa: None,
+    };
+
+    single_step(vm, SingleStepOwner::EptViewAccess, 1, context, return_to_ept_view)?;
+
+    Ok(ExitType::Continue)
+}
+
+/// Returns to the alternate view an access has been single-stepped from, or to the assigned view if it has been
+/// collapsed meanwhile, the changes published during the access being applied by the next synchronization.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `_context` - The guest physical address of the accessed page.
+///
+/// # Returns
+///
+/// `Ok(())` once the logical processor runs in its view.
+pub fn return_to_ept_view(vm: &mut Vm, _context: SingleStepContext) -> Result<(), HypervisorError> {
+    let manager = SHARED_EPT_VIEWS.lock();
+
+    let view = match manager.is_view(vm.ept_view.stepping_view) {
+        true => vm.ept_view.stepping_view,
+        false => vm.ept_view.assigned_view,
+    };
+    let (view, eptp) = manager.view_or_primary(vm, view);
+    drop(manager);
+
+    activate_view(vm, view, eptp);
+
+    Ok(())
+}
+
+/// Switches the current logical processor to a view.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `view` - The view.
+/// * `eptp` - The EPTP of the view.
+fn activate_view(vm: &mut Vm, view: EptViewId, eptp: u64) {
+    if vmread(vmcs::control::EPTP_FULL) != eptp {
+        vmwrite(vmcs::control::EPTP_FULL, eptp);
+        invept_eptp(eptp);
+    }
+
+    vm.ept_view.active_view = view;
+}
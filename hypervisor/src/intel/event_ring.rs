@@ -0,0 +1,288 @@
+//! Provides the event rings buffering the events recorded in VMX root operation until the client drains them, with an
+//! explicit backpressure policy, so high-rate tracing degrades predictably instead of losing an unknown subset of the
+//! events.
+//!
+//! While a ring is full, a new event is handled by the policy of the ring (see `BackpressurePolicy`):
+//! - `DropNewest` drops it, keeping the oldest events, which is the default,
+//! - `DropOldest` drops the oldest event to make room for it, keeping the most recent events,
+//! - `Stall` adds it to a reserve beyond the capacity, and the logical processor recording it waits for the client to
+//!   drain the ring below its capacity before resuming the guest, up to `STALL_TIMEOUT_MICROSECONDS`. The wait is
+//!   done at the end of the VM exit without any lock held, so the client can drain the ring from another logical
+//!   processor, and its time is hidden from the guest TSC with the rest of the VM exit. Once the reserve is full, new
+//!   events are dropped.
+//!
+//! Every lost event is counted, both since the last drain, as reported with the drained events, and since the
+//! hypervisor started, as reported by the statistics of the ring with the `ReadEventRingStats` command.
+
+use {
+    crate::intel::{
+        exception_telemetry::SHARED_EXCEPTION_TELEMETRY,
+        hooks::{allocation_monitor::SHARED_ALLOCATION_MONITOR, syscall_trace::SHARED_SYSCALL_TRACE},
+        profiler::SHARED_PROFILE,
+        support::rdtsc,
+        timing::tsc_frequency_hz,
+        vm::Vm,
+    },
+    alloc::{collections::VecDeque, vec::Vec},
+    core::sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    log::*,
+    shared::{BackpressurePolicy, EventRingId, EventRingStats},
+    x86::cpuid::cpuid,
+};
+
+/// The longest time a logical processor waits for the client to drain a ring under the `Stall` policy.
+pub const STALL_TIMEOUT_MICROSECONDS: u64 = 1000;
+
+/// The number of events a ring under the `Stall` policy holds beyond its capacity while the producers wait.
+pub const STALL_RESERVE: usize = 0x100;
+
+/// The number of event rings, the variants of `EventRingId`.
+const EVENT_RING_COUNT: usize = 4;
+
+/// The number of initial APIC IDs, which are 8-bit.
+const MAX_PROCESSORS: usize = 0x100;
+
+/// The rings each logical processor waits on before resuming the guest, as masks of `EventRingId` bits, by initial
+/// APIC ID.
+static STALL_REQUESTS: [AtomicU8; MAX_PROCESSORS] = [const { AtomicU8::new(0) }; MAX_PROCESSORS];
+
+/// The rings holding events beyond their capacity, as a mask of `EventRingId` bits.
+static OVER_CAPACITY_RINGS: AtomicU8 = AtomicU8::new(0);
+
+/// The number of waits that timed out, by ring.
+static STALL_TIMEOUTS: [AtomicU64; EVENT_RING_COUNT] = [const { AtomicU64::new(0) }; EVENT_RING_COUNT];
+
+/// The total time the logical processors waited, in TSC ticks, by ring.
+static STALL_TSC_TICKS: [AtomicU64; EVENT_RING_COUNT] = [const { AtomicU64::new(0) }; EVENT_RING_COUNT];
+
+/// Returns the bit of a ring in the masks of rings.
+///
+/// # Arguments
+///
+/// * `id` - The ring.
+fn ring_bit(id: EventRingId) -> u8 {
+    1 << id as u8
+}
+
+/// A ring of events recorded by all the logical processors, oldest first.
+#[derive(Debug)]
+pub struct EventRing<T> {
+    /// The ring, identifying it in the statistics and the wait of the producers.
+    id: EventRingId,
+
+    /// The number of events held before the policy applies.
+    capacity: usize,
+
+    /// What happens to new events while the ring is full.
+    policy: BackpressurePolicy,
+
+    /// The events not drained yet, oldest first.
+    events: VecDeque<T>,
+
+    /// The number of events lost since the last drain.
+    lost_since_drain: u64,
+
+    /// The statistics since the hypervisor started, without the waits counted by the producers.
+    stats: EventRingStats,
+}
+
+impl<T> EventRing<T> {
+    /// Creates an empty ring dropping the newest events while full, without allocating it.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ring.
+    /// * `capacity` - The number of events held before the policy applies.
+    pub fn new(id: EventRingId, capacity: usize) -> Self {
+        Self {
+            id,
+            capacity,
+            policy: BackpressurePolicy::DropNewest,
+            events: VecDeque::new(),
+            lost_since_drain: 0,
+            stats: EventRingStats {
+                capacity: capacity as u64,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Discards the events of a previous run and allocates the ring, keeping the policy and the statistics.
+    pub fn reset(&mut self) {
+        self.events.clear();
+        self.events.reserve_exact(self.capacity);
+        self.lost_since_drain = 0;
+        self.update_over_capacity();
+    }
+
+    /// Sets the policy applied to the next events while the ring is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy.
+    pub fn set_policy(&mut self, policy: BackpressurePolicy) {
+        debug!("Event ring {:?} backpressure policy set: {:?}", self.id, policy);
+        self.policy = policy;
+    }
+
+    /// Returns the number of events not drained yet.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns `true` if every event has been drained.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Adds an event to the ring, applying the policy if it's full.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event.
+    pub fn push(&mut self, event: T) {
+        if self.events.len() >= self.capacity {
+            match self.policy {
+                BackpressurePolicy::DropOldest => {
+                    self.events.pop_front();
+                    self.stats.dropped_oldest += 1;
+                    self.lost_since_drain += 1;
+                }
+                BackpressurePolicy::Stall if self.events.len() < self.capacity + STALL_RESERVE => {
+                    self.stats.stalled_events += 1;
+                    request_stall(self.id);
+                }
+                BackpressurePolicy::DropNewest | BackpressurePolicy::Stall => {
+                    self.stats.dropped_newest += 1;
+                    self.lost_since_drain += 1;
+                    return;
+                }
+            }
+        }
+
+        self.events.push_back(event);
+        self.stats.recorded_events += 1;
+        self.stats.high_watermark = self.stats.high_watermark.max(self.events.len() as u64);
+    }
+
+    /// Removes the oldest events from the ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_events` - The maximum number of events to remove.
+    ///
+    /// # Returns
+    ///
+    /// The events removed, oldest first, and the number of events lost since the last drain.
+    pub fn drain(&mut self, max_events: usize) -> (Vec<T>, u64) {
+        let count = self.events.len().min(max_events);
+        let events = self.events.drain(..count).collect();
+
+        self.update_over_capacity();
+
+        (events, core::mem::take(&mut self.lost_since_drain))
+    }
+
+    /// Returns the statistics of the ring, with the waits of the producers.
+    pub fn stats(&self) -> EventRingStats {
+        EventRingStats {
+            length: self.events.len() as u64,
+            stall_timeouts: STALL_TIMEOUTS[self.id as usize].load(Ordering::Relaxed),
+            stall_tsc_ticks: STALL_TSC_TICKS[self.id as usize].load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+
+    /// Releases the producers waiting on the ring once it's below its capacity.
+    fn update_over_capacity(&self) {
+        if self.events.len() < self.capacity {
+            OVER_CAPACITY_RINGS.fetch_and(!ring_bit(self.id), Ordering::AcqRel);
+        }
+    }
+}
+
+/// Makes the current logical processor wait on a ring before resuming the guest.
+///
+/// # Arguments
+///
+/// * `id` - The ring holding events beyond its capacity.
+fn request_stall(id: EventRingId) {
+    // The initial APIC ID as cached in the VM of the logical processor, which isn't available to the producers.
+    let processor_index = (cpuid!(0x1).ebx >> 24) as usize;
+
+    OVER_CAPACITY_RINGS.fetch_or(ring_bit(id), Ordering::AcqRel);
+    STALL_REQUESTS[processor_index].fetch_or(ring_bit(id), Ordering::AcqRel);
+}
+
+/// Waits for the client to drain the rings the current logical processor recorded events beyond the capacity of
+/// during this VM exit, up to `STALL_TIMEOUT_MICROSECONDS`.
+///
+/// This is called on every VM exit without any lock held, and only reads an atomic unless the processor must wait.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn wait_for_event_rings(vm: &Vm) {
+    let processor_index = vm.cpuid_feature_info.initial_local_apic_id() as usize;
+    let stalled_rings = STALL_REQUESTS[processor_index].swap(0, Ordering::AcqRel);
+
+    if stalled_rings == 0 {
+        return;
+    }
+
+    let start_tsc = rdtsc();
+    let timeout_tsc_ticks = tsc_frequency_hz() / 1_000_000 * STALL_TIMEOUT_MICROSECONDS;
+
+    let timed_out = loop {
+        if OVER_CAPACITY_RINGS.load(Ordering::Acquire) & stalled_rings == 0 {
+            break false;
+        }
+
+        if rdtsc().wrapping_sub(start_tsc) >= timeout_tsc_ticks {
+            break true;
+        }
+
+        core::hint::spin_loop();
+    };
+
+    let stall_tsc_ticks = rdtsc().wrapping_sub(start_tsc);
+
+    for ring in (0..EVENT_RING_COUNT).filter(|ring| stalled_rings & (1 << ring) != 0) {
+        STALL_TSC_TICKS[ring].fetch_add(stall_tsc_ticks, Ordering::Relaxed);
+
+        if timed_out {
+            STALL_TIMEOUTS[ring].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    trace!("Stalled on event rings {:#x} for {} ticks, timed out: {}", stalled_rings, stall_tsc_ticks, timed_out);
+}
+
+/// Sets the backpressure policy of an event ring.
+///
+/// # Arguments
+///
+/// * `id` - The ring.
+/// * `policy` - The policy applied to the next events while the ring is full.
+pub fn configure_event_ring(id: EventRingId, policy: BackpressurePolicy) {
+    match id {
+        EventRingId::SyscallTrace => SHARED_SYSCALL_TRACE.lock().event_ring().set_policy(policy),
+        EventRingId::ExceptionTelemetry => SHARED_EXCEPTION_TELEMETRY.lock().event_ring().set_policy(policy),
+        EventRingId::Profiler => SHARED_PROFILE.lock().event_ring().set_policy(policy),
+        EventRingId::AllocationAlerts => SHARED_ALLOCATION_MONITOR.lock().event_ring().set_policy(policy),
+    }
+}
+
+/// Returns the statistics of an event ring.
+///
+/// # Arguments
+///
+/// * `id` - The ring.
+pub fn event_ring_stats(id: EventRingId) -> EventRingStats {
+    match id {
+        EventRingId::SyscallTrace => SHARED_SYSCALL_TRACE.lock().event_ring().stats(),
+        EventRingId::ExceptionTelemetry => SHARED_EXCEPTION_TELEMETRY.lock().event_ring().stats(),
+        EventRingId::Profiler => SHARED_PROFILE.lock().event_ring().stats(),
+        EventRingId::AllocationAlerts => SHARED_ALLOCATION_MONITOR.lock().event_ring().stats(),
+    }
+}
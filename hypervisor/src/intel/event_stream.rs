@@ -0,0 +1,220 @@
+//! Provides the event stream, a ring of fixed-size records in a guest buffer registered by the guest agent with
+//! `Hypercall::RegisterEventStream`, so the agent consumes the events recorded in VMX root operation, e.g., the system
+//! calls traced, the hooks hit and the hooks tampered with, without a command per batch.
+//!
+//! The producers run on any logical processor and don't lock anything but the registration for reading: a record is
+//! reserved by a compare-and-exchange of the write index in the header of the buffer, written, and published by
+//! writing its sequence last (see `shared::EventStreamHeader`). The guest consumes the records and advances the read
+//! index concurrently, and a record is dropped and counted while the ring is full.
+//!
+//! The header and the records are written in the guest memory through the identity map of the host, so the agent must
+//! keep the buffer allocated and resident, e.g., as non-paged contiguous memory, until it unregisters it. As the guest
+//! can write the header at any time, the slot of a record is always the write index modulo the capacity fixed at the
+//! registration, and the reservation is retried a bounded number of times, so a hostile guest can't make the
+//! hypervisor write out of the buffer or spin forever.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{host_config::SHARED_HOST_CONFIG, support::rdtsc},
+    },
+    core::{
+        mem::{offset_of, size_of},
+        sync::atomic::{AtomicU64, Ordering},
+    },
+    lazy_static::lazy_static,
+    log::*,
+    shared::{EventStreamHeader, EventStreamKind, EventStreamRecord, EVENT_STREAM_DATA_SIZE},
+    spin::RwLock,
+    x86::cpuid::cpuid,
+};
+
+/// The largest event stream buffer, 16 MiB.
+pub const MAX_EVENT_STREAM_SIZE: u64 = 0x100_0000;
+
+/// The number of times the reservation of a record is retried when other logical processors, or the guest, change
+/// the write index meanwhile, before the record is dropped.
+const MAX_RESERVATION_ATTEMPTS: usize = 0x10;
+
+/// The size of a page, the alignment of the buffer.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The `EventStreamKind` bits of the events streamed, 0 while no buffer is registered, checked without locking by the
+/// producers.
+static EVENT_STREAM_KINDS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// A globally shared instance of the registered `EventStream`, if any, protected by a read-write lock, so the
+    /// producers of all the logical processors push records concurrently.
+    pub static ref SHARED_EVENT_STREAM: RwLock<Option<EventStream>> = RwLock::new(None);
+}
+
+/// An event stream buffer registered by the guest agent.
+#[derive(Debug, Clone, Copy)]
+pub struct EventStream {
+    /// The guest physical address of the buffer, its header.
+    buffer_pa: u64,
+
+    /// The number of records of the ring.
+    capacity: u64,
+}
+
+impl EventStream {
+    /// Returns a field of the header, shared with the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset of the field in `EventStreamHeader`.
+    fn header_field(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*((self.buffer_pa + offset as u64) as *const AtomicU64) }
+    }
+
+    /// Returns the record of a slot of the ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The position of the record since the registration.
+    fn record(&self, index: u64) -> *mut EventStreamRecord {
+        let slot = index % self.capacity;
+        (self.buffer_pa + size_of::<EventStreamHeader>() as u64 + slot * size_of::<EventStreamRecord>() as u64) as *mut EventStreamRecord
+    }
+
+    /// Reserves the next record, unless the ring is full.
+    ///
+    /// # Returns
+    ///
+    /// The position of the record since the registration, or `None` if it must be dropped.
+    fn reserve(&self) -> Option<u64> {
+        let write_index = self.header_field(offset_of!(EventStreamHeader, write_index));
+        let read_index = self.header_field(offset_of!(EventStreamHeader, read_index));
+
+        let mut index = write_index.load(Ordering::Acquire);
+
+        for _ in 0..MAX_RESERVATION_ATTEMPTS {
+            if index.wrapping_sub(read_index.load(Ordering::Acquire)) >= self.capacity {
+                return None;
+            }
+
+            match write_index.compare_exchange_weak(index, index.wrapping_add(1), Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(index),
+                Err(current) => index = current,
+            }
+        }
+
+        None
+    }
+
+    /// Pushes a record to the ring, or counts it as dropped if the ring is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The kind of the event.
+    /// * `data` - The values of the event.
+    fn push(&self, kind: EventStreamKind, data: [u64; EVENT_STREAM_DATA_SIZE]) {
+        let Some(index) = self.reserve() else {
+            self.header_field(offset_of!(EventStreamHeader, dropped_records))
+                .fetch_add(1, Ordering::AcqRel);
+            return;
+        };
+
+        let record = self.record(index);
+
+        unsafe {
+            core::ptr::addr_of_mut!((*record).kind).write_volatile(kind as u64);
+            core::ptr::addr_of_mut!((*record).processor_id).write_volatile((cpuid!(0x1).ebx >> 24) as u64);
+            core::ptr::addr_of_mut!((*record).tsc).write_volatile(rdtsc());
+            core::ptr::addr_of_mut!((*record).data).write_volatile(data);
+
+            (*(core::ptr::addr_of_mut!((*record).sequence) as *const AtomicU64)).store(index.wrapping_add(1), Ordering::Release);
+        }
+    }
+}
+
+/// Registers an event stream buffer, replacing the previous registration, and initializes its header and records.
+///
+/// # Arguments
+///
+/// * `buffer_pa` - The guest physical address of the buffer, page-aligned and physically contiguous.
+/// * `size` - The size of the buffer.
+/// * `kinds` - The `EventStreamKind` bits of the events streamed.
+///
+/// # Returns
+///
+/// * `Ok(u64)` - The number of records of the ring.
+/// * `Err(HypervisorError::InvalidEventStreamBuffer)` - If the buffer isn't page-aligned, is larger than
+///   `MAX_EVENT_STREAM_SIZE`, can't hold a record or overlaps the memory of the hypervisor.
+pub fn register_event_stream(buffer_pa: u64, size: u64, kinds: u64) -> Result<u64, HypervisorError> {
+    let header_size = size_of::<EventStreamHeader>() as u64;
+    let record_size = size_of::<EventStreamRecord>() as u64;
+
+    if buffer_pa == 0 || !buffer_pa.is_multiple_of(PAGE_SIZE) || size > MAX_EVENT_STREAM_SIZE || size < header_size + record_size {
+        return Err(HypervisorError::InvalidEventStreamBuffer);
+    }
+
+    let buffer_end = buffer_pa.checked_add(size).ok_or(HypervisorError::InvalidEventStreamBuffer)?;
+
+    // The hypervisor would otherwise write its own memory on behalf of the guest.
+    let overlaps_host_memory = SHARED_HOST_CONFIG
+        .read()
+        .allocated_memory_ranges
+        .iter()
+        .any(|&(start, range_size)| (start as u64) < buffer_end && buffer_pa < (start + range_size) as u64);
+
+    if overlaps_host_memory {
+        return Err(HypervisorError::InvalidEventStreamBuffer);
+    }
+
+    let capacity = (size - header_size) / record_size;
+
+    let mut event_stream = SHARED_EVENT_STREAM.write();
+    EVENT_STREAM_KINDS.store(0, Ordering::Release);
+
+    unsafe {
+        core::ptr::write_bytes(buffer_pa as *mut u8, 0, (header_size + capacity * record_size) as usize);
+        core::ptr::write_volatile(
+            buffer_pa as *mut EventStreamHeader,
+            EventStreamHeader {
+                write_index: 0,
+                read_index: 0,
+                capacity,
+                record_size,
+                dropped_records: 0,
+            },
+        );
+    }
+
+    *event_stream = Some(EventStream { buffer_pa, capacity });
+    EVENT_STREAM_KINDS.store(kinds, Ordering::Release);
+
+    debug!("Event stream registered at PA: {:#x}, capacity: {}, kinds: {:#x}", buffer_pa, capacity, kinds);
+
+    Ok(capacity)
+}
+
+/// Unregisters the event stream buffer, the hypervisor no longer writing it once this returns.
+pub fn unregister_event_stream() {
+    let mut event_stream = SHARED_EVENT_STREAM.write();
+    EVENT_STREAM_KINDS.store(0, Ordering::Release);
+
+    if event_stream.take().is_some() {
+        debug!("Event stream unregistered");
+    }
+}
+
+/// Pushes an event to the event stream buffer, if one is registered for its kind.
+///
+/// This is called in VMX root operation by the producers, and only reads an atomic unless the kind is streamed.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of the event.
+/// * `data` - The values of the event, as described by its kind.
+pub fn stream_event(kind: EventStreamKind, data: [u64; EVENT_STREAM_DATA_SIZE]) {
+    if EVENT_STREAM_KINDS.load(Ordering::Acquire) & (1 << kind as u64) == 0 {
+        return;
+    }
+
+    if let Some(event_stream) = SHARED_EVENT_STREAM.read().as_ref() {
+        event_stream.push(kind, data);
+    }
+}
@@ -1,15 +1,30 @@
 //! This module provides utilities and structures to manage event injection in VMX.
 //! It handles the representation, manipulation, and injection of various types of events.
+//!
+//! The NMIs and external interrupts injected by the hypervisor go through a pending-event queue of each logical
+//! processor, as the guest may not accept them at the VM exit requesting them: NMIs are blocked by an NMI being
+//! handled, external interrupts by RFLAGS.IF clear, both by the interrupt shadow of STI and MOV SS, and an event may
+//! already be injected on the next VM entry. A queued event is injected at the end of the first VM exit at which the
+//! guest accepts it, and the interrupt-window or NMI-window exiting is requested meanwhile, so a VM exit occurs as soon
+//! as the window opens. The NMIs received while the guest runs cause VM exits when the processor supports virtual
+//! NMIs, required by the NMI-window exiting, and are reflected to the guest through the queue.
 
 #![allow(dead_code)]
 
 use {
     crate::intel::{
-        support::vmwrite,
+        state::GuestActivityState,
+        support::{dr6_read, dr6_write, vmread, vmwrite},
+        vm::Vm,
         vmerror::{ExceptionInterrupt, InterruptionType},
     },
     bitfield::bitfield,
-    x86::vmx::vmcs,
+    log::*,
+    x86::vmx::vmcs::{
+        self,
+        control::{PinbasedControls, PrimaryControls},
+    },
+    x86_64::registers::rflags::RFlags,
 };
 
 bitfield! {
@@ -47,6 +62,54 @@ bitfield! {
 const VALID: u32 = 1;
 const INVALID: u32 = 0;
 
+/// The bit of the guest interruptibility state for blocking by STI.
+const BLOCKING_BY_STI: u64 = 1 << 0;
+
+/// The bit of the guest interruptibility state for blocking by MOV SS.
+const BLOCKING_BY_MOV_SS: u64 = 1 << 1;
+
+/// The bit of the guest interruptibility state for blocking by NMI, the virtual-NMI blocking with virtual NMIs.
+const BLOCKING_BY_NMI: u64 = 1 << 3;
+
+/// The lowest vector of the external interrupts, below which the vectors are reserved for the exceptions.
+const MIN_EXTERNAL_INTERRUPT_VECTOR: u8 = 32;
+
+/// The NMIs and external interrupts of a logical processor waiting for the guest to accept them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PendingEvents {
+    /// Whether an NMI is pending. As on the processor, the NMIs queued while one is pending are collapsed into it.
+    nmi: bool,
+
+    /// The pending external interrupts, one bit per vector, injected highest vector first as by the local APIC.
+    external_interrupts: [u64; 4],
+
+    /// Whether the NMI-window exiting is requested in the VMCS.
+    nmi_window_exiting: bool,
+
+    /// Whether the interrupt-window exiting is requested in the VMCS.
+    interrupt_window_exiting: bool,
+}
+
+impl PendingEvents {
+    /// Creates an empty queue, without window exiting.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if no event is pending.
+    pub fn is_empty(&self) -> bool {
+        !self.nmi && self.external_interrupts.iter().all(|&vectors| vectors == 0)
+    }
+
+    /// Returns the highest pending external interrupt vector, if any.
+    fn highest_external_interrupt(&self) -> Option<u8> {
+        (0..self.external_interrupts.len())
+            .rev()
+            .find(|&index| self.external_interrupts[index] != 0)
+            .map(|index| (index * 64 + 63 - self.external_interrupts[index].leading_zeros() as usize) as u8)
+    }
+}
+
 /// Provides methods for event injection in VMX.
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.6 EVENT INJECTION
@@ -63,12 +126,23 @@ impl EventInjection {
         event.0
     }
 
+    /// Inject Debug (#DB) to the guest (Event Injection).
+    fn debug() -> u32 {
+        let mut event = EventInjection(0);
+
+        event.set_vector(ExceptionInterrupt::Debug as u32);
+        event.set_type(InterruptionType::HardwareException as u32);
+        event.set_valid(VALID);
+
+        event.0
+    }
+
     /// Inject Breakpoint (#BP) to the guest (Event Injection).
     fn breakpoint() -> u32 {
         let mut event = EventInjection(0);
 
         event.set_vector(ExceptionInterrupt::Breakpoint as u32);
-        event.set_type(InterruptionType::HardwareException as u32);
+        event.set_type(InterruptionType::SoftwareException as u32);
         event.set_valid(VALID);
 
         event.0
@@ -96,6 +170,28 @@ impl EventInjection {
         event.0
     }
 
+    /// Inject External Interrupt to the guest (Event Injection).
+    fn external_interrupt(vector: u8) -> u32 {
+        let mut event = EventInjection(0);
+
+        event.set_vector(vector as u32);
+        event.set_type(InterruptionType::ExternalInterrupt as u32);
+        event.set_valid(VALID);
+
+        event.0
+    }
+
+    /// Inject Non-Maskable Interrupt (NMI) to the guest (Event Injection).
+    fn non_maskable_interrupt() -> u32 {
+        let mut event = EventInjection(0);
+
+        event.set_vector(ExceptionInterrupt::NonMaskableInterrupt as u32);
+        event.set_type(InterruptionType::NonMaskableInterrupt as u32);
+        event.set_valid(VALID);
+
+        event.0
+    }
+
     /// Injects a general protection fault into the guest.
     ///
     /// This function is used to signal to the guest that a protection violation
@@ -131,14 +227,130 @@ impl EventInjection {
     /// Injects a breakpoint exception into the guest.
     ///
     /// This function is used to signal to the guest that a breakpoint exception
-    /// has occurred, typically used for debugging purposes.
+    /// has occurred, typically used for debugging purposes. It's injected as a software exception
+    /// after an intercepted `int3`, so the guest sees the return address past the instruction.
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
     /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
     pub fn vmentry_inject_bp() {
+        vmwrite(vmcs::control::VMENTRY_INSTRUCTION_LEN, vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN));
         vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::breakpoint());
     }
 
+    /// Injects a debug exception into the guest.
+    ///
+    /// This function is used to deliver an intercepted debug exception to the guest. The processor doesn't update DR6
+    /// for debug exceptions causing VM exits, so the conditions reported by the exit qualification are set in DR6 first.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_qualification` - The exit qualification of the debug exception, in the format of DR6.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.1 Basic VM-Exit Information
+    /// and Table 28-1. Exit Qualification for Debug Exceptions.
+    pub fn vmentry_inject_db(exit_qualification: u64) {
+        dr6_write(dr6_read() | exit_qualification);
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::debug());
+    }
+
+    /// Re-injects the event whose delivery caused a VM exit into the guest, from the IDT-vectoring information.
+    ///
+    /// # Arguments
+    ///
+    /// * `idt_vectoring_info` - The IDT-vectoring information of the VM exit, which must be valid.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.4 Information for VM Exits During Event Delivery
+    /// and 29.8.3 Special Treatment of Events During VM Entry.
+    pub fn vmentry_reinject_idt_vectoring_event(idt_vectoring_info: u64) {
+        // Bits 30:12 are undefined in the IDT-vectoring information, but reserved in the VM-entry interruption-information field.
+        let mut event = EventInjection(idt_vectoring_info as u32 & !0x7FFF_F000);
+
+        if event.get_deliver_error_code() != 0 {
+            vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, vmread(vmcs::ro::IDT_VECTORING_ERR_CODE));
+        }
+
+        // Software interrupts and exceptions are delivered past the instruction that raised them.
+        if matches!(event.get_type(), 4..=6) {
+            vmwrite(vmcs::control::VMENTRY_INSTRUCTION_LEN, vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN));
+        }
+
+        event.set_valid(VALID);
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, event.0);
+    }
+
+    /// Re-injects the event whose delivery caused the VM exit, if any, unless the handler of the VM exit already
+    /// injected an event, which then takes precedence.
+    ///
+    /// A VM exit may occur while the processor delivers an event through the IDT of the guest, e.g., an EPT violation
+    /// on a hooked page touched by the delivery of a page fault, such as the page of the handler's stack or of the IDT
+    /// itself. The event hasn't been delivered then, and is lost unless it's injected on the next VM entry, as the
+    /// condition that raised it doesn't occur again once the guest resumes.
+    ///
+    /// This must be called once the VM exit has been handled, before anything else injects an event.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the event has been re-injected, `false` if there is none or another event has been injected.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.4 Information for VM Exits During Event Delivery
+    /// and 29.8.3 Special Treatment of Events During VM Entry.
+    pub fn vmentry_reinject_interrupted_event() -> bool {
+        let idt_vectoring_info = vmread(vmcs::ro::IDT_VECTORING_INFO);
+
+        if EventInjection(idt_vectoring_info as u32).get_valid() == INVALID || !is_accepting_events() {
+            return false;
+        }
+
+        // The processor clears the valid bit of the VM-entry interruption-information field on every VM exit.
+        let injected_event = vmread(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD);
+        if EventInjection(injected_event as u32).get_valid() == VALID {
+            if injected_event & 0x7FF != idt_vectoring_info & 0x7FF {
+                debug!("Event {:#x} interrupted by the VM exit superseded by event {:#x}", idt_vectoring_info, injected_event);
+            }
+
+            return false;
+        }
+
+        trace!("Re-injecting event interrupted by the VM exit: {:#x}", idt_vectoring_info);
+        Self::vmentry_reinject_idt_vectoring_event(idt_vectoring_info);
+
+        true
+    }
+
+    /// Injects an exception into the guest with the type and error code it was intercepted with, to reflect an
+    /// exception without a dedicated injection.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the exception.
+    /// * `interruption_type` - The type of the exception, from the VM-exit interruption information.
+    /// * `error_code` - The error code of the exception, if it delivers one.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
+    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
+    pub fn vmentry_inject_exception(vector: ExceptionInterrupt, interruption_type: InterruptionType, error_code: Option<u32>) {
+        let mut event = EventInjection(0);
+
+        event.set_vector(vector as u32);
+        event.set_type(interruption_type as u32);
+        event.set_valid(VALID);
+
+        if let Some(error_code) = error_code {
+            event.set_deliver_error_code(1);
+            vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
+        }
+
+        // Software interrupts and exceptions are delivered past the instruction that raised them.
+        if matches!(
+            interruption_type,
+            InterruptionType::SoftwareInterrupt | InterruptionType::PrivilegedSoftwareException | InterruptionType::SoftwareException
+        ) {
+            vmwrite(vmcs::control::VMENTRY_INSTRUCTION_LEN, vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN));
+        }
+
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, event.0);
+    }
+
     /// Injects an undefined opcode exception into the guest.
     ///
     /// This function is used to signal to the guest that an invalid or undefined opcode
@@ -149,4 +361,147 @@ impl EventInjection {
     pub fn vmentry_inject_ud() {
         vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::undefined_opcode());
     }
+
+    /// Injects a non-maskable interrupt into the guest.
+    ///
+    /// This function is used to make the guest handle an NMI, e.g., to trigger a crash dump of a hung guest.
+    /// The caller must check that the guest isn't blocking NMIs or in an interrupt shadow.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
+    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
+    pub fn vmentry_inject_nmi() {
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::non_maskable_interrupt());
+    }
+
+    /// Queues an NMI for the guest, injected once the guest doesn't block NMIs.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    pub fn queue_nmi(vm: &mut Vm) {
+        trace!("Queueing NMI");
+        vm.pending_events.nmi = true;
+    }
+
+    /// Queues an external interrupt for the guest, injected once the guest has interrupts enabled outside of an
+    /// interrupt shadow.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    /// * `vector` - The vector of the interrupt, 32 to 255.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the interrupt has been queued, `false` if the vector is reserved for the exceptions.
+    pub fn queue_external_interrupt(vm: &mut Vm, vector: u8) -> bool {
+        if vector < MIN_EXTERNAL_INTERRUPT_VECTOR {
+            warn!("Invalid external interrupt vector: {:#x}", vector);
+            return false;
+        }
+
+        trace!("Queueing external interrupt: {:#x}", vector);
+        vm.pending_events.external_interrupts[vector as usize / 64] |= 1 << (vector % 64);
+
+        true
+    }
+
+    /// Injects the pending event of the highest priority the guest accepts, if no event is injected on the next VM
+    /// entry yet, then requests the interrupt-window or NMI-window exiting for the events still pending.
+    ///
+    /// This must be called on every VM exit, after the interrupted event has been re-injected, and only reads the VMCS
+    /// while events are pending or a window exiting is requested.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.3 Changes to Instruction Behavior in VMX Non-Root Operation,
+    /// 26.7.5 Interrupt-Window Exiting and Virtual-Interrupt Delivery, 26.7.6 NMI-Window Exiting and 27.3.1.5 Checks on Guest Non-Register State.
+    pub fn vmentry_inject_pending_events(vm: &mut Vm) {
+        let is_single_stepping = vm.single_step.is_active();
+        let pending_events = &mut vm.pending_events;
+
+        if pending_events.is_empty() && !pending_events.nmi_window_exiting && !pending_events.interrupt_window_exiting {
+            return;
+        }
+
+        // The events are held while the guest is shut down or waits for a SIPI, from which only an INIT or a SIPI wakes it.
+        let is_injection_free =
+            EventInjection(vmread(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD) as u32).get_valid() == INVALID && is_accepting_events();
+        let interruptibility_state = vmread(vmcs::guest::INTERRUPTIBILITY_STATE);
+        let interrupts_enabled = RFlags::from_bits_retain(vmread(vmcs::guest::RFLAGS)).contains(RFlags::INTERRUPT_FLAG);
+
+        // Some processors require blocking by STI to be clear to inject an NMI as well. The NMIs are held while
+        // instructions are single-stepped, as the delivery would be stepped instead, and retried on the MTF VM exits.
+        let nmi_window_open = !is_single_stepping && interruptibility_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS | BLOCKING_BY_NMI) == 0;
+        let interrupt_window_open = interrupts_enabled && interruptibility_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0;
+
+        // NMIs take precedence over external interrupts.
+        if is_injection_free && pending_events.nmi && nmi_window_open {
+            trace!("Injecting pending NMI");
+            pending_events.nmi = false;
+            vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::non_maskable_interrupt());
+        } else if let Some(vector) = pending_events
+            .highest_external_interrupt()
+            .filter(|_| is_injection_free && interrupt_window_open)
+        {
+            trace!("Injecting pending external interrupt: {:#x}", vector);
+            pending_events.external_interrupts[vector as usize / 64] &= !(1 << (vector % 64));
+            vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::external_interrupt(vector));
+        }
+
+        // The NMI-window exiting requires virtual NMIs. Without it, the NMI is retried at the next VM exit, which the
+        // interrupt-window exiting makes happen as soon as the guest enables interrupts.
+        let virtual_nmis =
+            PinbasedControls::from_bits_truncate(vmread(vmcs::control::PINBASED_EXEC_CONTROLS) as u32).contains(PinbasedControls::VIRTUAL_NMIS);
+
+        let nmi_window_exiting = pending_events.nmi && virtual_nmis && !is_single_stepping;
+        let interrupt_window_exiting =
+            pending_events.highest_external_interrupt().is_some() || (pending_events.nmi && !virtual_nmis && !is_single_stepping);
+
+        if nmi_window_exiting == pending_events.nmi_window_exiting && interrupt_window_exiting == pending_events.interrupt_window_exiting {
+            return;
+        }
+
+        trace!("Window exiting requested: NMI: {}, interrupt: {}", nmi_window_exiting, interrupt_window_exiting);
+        pending_events.nmi_window_exiting = nmi_window_exiting;
+        pending_events.interrupt_window_exiting = interrupt_window_exiting;
+
+        let mut primary_controls = PrimaryControls::from_bits_truncate(vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+        primary_controls.set(PrimaryControls::NMI_WINDOW_EXITING, nmi_window_exiting);
+        primary_controls.set(PrimaryControls::INTERRUPT_WINDOW_EXITING, interrupt_window_exiting);
+        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
+    }
+
+    /// Discards the event being injected and the pending ones along with their window exiting, as an INIT signal or a
+    /// shutdown does, so the VM entry into the wait-for-SIPI or shutdown state is valid.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.5 Checks on Guest Non-Register State.
+    pub fn discard_pending_events(vm: &mut Vm) {
+        if !vm.pending_events.is_empty() {
+            debug!("Discarding the pending events: {:x?}", vm.pending_events);
+        }
+
+        vm.pending_events = PendingEvents::new();
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, INVALID);
+        vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, 0u64);
+        vmwrite(vmcs::guest::PENDING_DBG_EXCEPTIONS, 0u64);
+
+        let mut primary_controls = PrimaryControls::from_bits_truncate(vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+        primary_controls.remove(PrimaryControls::NMI_WINDOW_EXITING | PrimaryControls::INTERRUPT_WINDOW_EXITING);
+        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
+    }
+}
+
+/// Returns `true` if the guest is active or halted, the activity states in which events can be injected.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.5 Checks on Guest Non-Register State.
+fn is_accepting_events() -> bool {
+    let activity_state = vmread(vmcs::guest::ACTIVITY_STATE);
+    activity_state == GuestActivityState::Active as u64 || activity_state == GuestActivityState::Hlt as u64
 }
@@ -0,0 +1,278 @@
+//! Provides a telemetry stream of the faults of the whole guest, without instrumentation in the guest.
+//!
+//! While enabled, the selected exceptions among page faults (#PF), general protection faults (#GP) and invalid
+//! opcodes (#UD) are intercepted through the exception bitmap and immediately reflected to the guest, after recording
+//! their vector, error code, RIP, CR2, CR3 and process ID into a shared event buffer, which the client drains with the
+//! `ReadExceptionTelemetry` command. As the kernel handles page faults all the time, they can be restricted to
+//! user-mode accesses through the page-fault error-code mask and match, so the other page faults don't cause VM exits.
+//!
+//! The events are rate limited for the whole system: beyond the configured number of events per second, the
+//! exceptions are still reflected but only counted as suppressed. When the buffer is full, new events are handled by
+//! its backpressure policy (see the `event_ring` module), dropping them by default.
+//!
+//! An exception intercepted while an event is being delivered (e.g., a page fault on the stack of an interrupt
+//! handler) is recorded, then the original event is re-injected with the interception of the exception disabled
+//! until the next VM exit, so the processor resolves the nested exception itself (e.g., as a double fault).
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            event_ring::EventRing,
+            events::EventInjection,
+            seqlock::{Generation, Published},
+            support::{rdtsc, vmread, vmwrite},
+            timing::tsc_frequency_hz,
+            vm::Vm,
+            vmerror::ExceptionInterrupt,
+        },
+        windows::{eprocess::ProcessInformation, symbols::SymbolizedAddress},
+    },
+    alloc::vec::Vec,
+    lazy_static::lazy_static,
+    log::*,
+    shared::{EventRingId, ExceptionEvent},
+    spin::Mutex,
+    x86::vmx::vmcs,
+};
+
+/// The maximum number of events kept in the event buffer until they are drained.
+pub const EXCEPTION_EVENT_BUFFER_CAPACITY: usize = 0x1000;
+
+/// The exceptions that can be recorded, as a mask of vectors in the format of the exception bitmap.
+pub const TELEMETRY_EXCEPTION_VECTORS: u32 = (1 << ExceptionInterrupt::InvalidOpcode as u32)
+    | (1 << ExceptionInterrupt::GeneralProtectionFault as u32)
+    | (1 << ExceptionInterrupt::PageFault as u32);
+
+/// The page-fault error code: the access was a user-mode access (U/S).
+const PAGE_FAULT_ERROR_CODE_USER: u64 = 1 << 2;
+
+/// The IDT-vectoring information: the information is valid.
+const IDT_VECTORING_INFO_VALID: u64 = 1 << 31;
+
+/// The configuration of the telemetry applied to the exception bitmaps, disabled until it's configured.
+static EXCEPTION_TELEMETRY_CONFIG: Published<ExceptionTelemetryConfig> = Published::new(ExceptionTelemetryConfig {
+    vectors: 0,
+    user_mode_page_faults_only: false,
+    max_events_per_second: 0,
+});
+
+lazy_static! {
+    /// A globally shared instance of `ExceptionTelemetry`, protected by a mutex.
+    pub static ref SHARED_EXCEPTION_TELEMETRY: Mutex<ExceptionTelemetry> = Mutex::new(ExceptionTelemetry::new());
+}
+
+/// The configuration of the exception telemetry.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExceptionTelemetryConfig {
+    /// The recorded exceptions, a subset of `TELEMETRY_EXCEPTION_VECTORS`, or 0 while the telemetry is disabled.
+    pub vectors: u32,
+
+    /// Whether only the page faults of user-mode accesses are recorded.
+    pub user_mode_page_faults_only: bool,
+
+    /// The maximum number of events recorded per second for the whole system.
+    pub max_events_per_second: u64,
+}
+
+/// The configuration of the exception telemetry and the events recorded by all the logical processors.
+#[derive(Debug)]
+pub struct ExceptionTelemetry {
+    /// The current configuration.
+    config: ExceptionTelemetryConfig,
+
+    /// The events recorded and not drained yet, oldest first.
+    events: EventRing<ExceptionEvent>,
+
+    /// The number of events suppressed by the rate limit since the last drain.
+    suppressed_events: u64,
+
+    /// The TSC at which the current one-second window of the rate limit started.
+    window_start_tsc: u64,
+
+    /// The number of events recorded in the current window.
+    window_event_count: u64,
+}
+
+impl ExceptionTelemetry {
+    /// Creates a new disabled telemetry, without allocating the buffer.
+    fn new() -> Self {
+        Self {
+            config: ExceptionTelemetryConfig::default(),
+            events: EventRing::new(EventRingId::ExceptionTelemetry, EXCEPTION_EVENT_BUFFER_CAPACITY),
+            suppressed_events: 0,
+            window_start_tsc: 0,
+            window_event_count: 0,
+        }
+    }
+
+    /// Publishes a new configuration, which the logical processors pick up on their next VM exit.
+    ///
+    /// Enabling the telemetry discards the events of a previous run that haven't been drained.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new configuration.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the configuration was published, or `Err(HypervisorError::InvalidExceptionTelemetryConfig)` if an
+    /// exception can't be recorded or the rate limit would suppress every event.
+    pub fn configure(&mut self, config: ExceptionTelemetryConfig) -> Result<(), HypervisorError> {
+        if config.vectors & !TELEMETRY_EXCEPTION_VECTORS != 0 || (config.vectors != 0 && config.max_events_per_second == 0) {
+            return Err(HypervisorError::InvalidExceptionTelemetryConfig);
+        }
+
+        debug!("Exception telemetry configured: {:?}", config);
+
+        if config.vectors != 0 && self.config.vectors == 0 {
+            self.events.reset();
+            self.suppressed_events = 0;
+        }
+
+        self.config = config;
+        EXCEPTION_TELEMETRY_CONFIG.publish(config);
+
+        Ok(())
+    }
+
+    /// Returns the mask of the recorded exceptions, 0 while the telemetry is disabled.
+    pub fn recorded_vectors(&self) -> u32 {
+        self.config.vectors
+    }
+
+    /// Removes the oldest events from the buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_events` - The maximum number of events to remove.
+    ///
+    /// # Returns
+    ///
+    /// The events removed, oldest first, and the numbers of events dropped and suppressed since the last drain.
+    pub fn drain(&mut self, max_events: usize) -> (Vec<ExceptionEvent>, u64, u64) {
+        let (events, dropped_events) = self.events.drain(max_events);
+
+        (events, dropped_events, core::mem::take(&mut self.suppressed_events))
+    }
+
+    /// Returns the buffer of the events, to configure it or read its statistics.
+    pub fn event_ring(&mut self) -> &mut EventRing<ExceptionEvent> {
+        &mut self.events
+    }
+
+    /// Adds an event to the buffer, unless the rate limit is exceeded.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event.
+    fn push(&mut self, event: ExceptionEvent) {
+        if event.tsc.wrapping_sub(self.window_start_tsc) >= tsc_frequency_hz() {
+            self.window_start_tsc = event.tsc;
+            self.window_event_count = 0;
+        }
+
+        if self.window_event_count >= self.config.max_events_per_second {
+            self.suppressed_events += 1;
+            return;
+        }
+
+        self.window_event_count += 1;
+
+        self.events.push(event);
+    }
+}
+
+/// The exception telemetry state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorExceptionTelemetry {
+    /// The generation of the configuration applied to the exception bitmap of this logical processor, stale while the
+    /// interception is disabled to re-inject an event, so it's restored on the next VM exit.
+    generation: Generation,
+}
+
+impl ProcessorExceptionTelemetry {
+    /// Creates a new processor state, without interception.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Applies a new telemetry configuration to the exception bitmap of the current logical processor, or restores the
+/// interception disabled to re-inject an event.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_exception_telemetry(vm: &mut Vm) {
+    let Some(config) = EXCEPTION_TELEMETRY_CONFIG.sync(&mut vm.exception_telemetry.generation) else {
+        return;
+    };
+
+    // The vectors no longer recorded keep the interception of the exception hooks.
+    let exception_bitmap =
+        (vmread(vmcs::control::EXCEPTION_BITMAP) as u32 & !TELEMETRY_EXCEPTION_VECTORS) | config.vectors | vm.exception_hooks.vectors;
+    vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+
+    // With the page faults intercepted, a page fault causes a VM exit if its error code masked by the mask is the match.
+    let page_fault_error_code_filter = match config.user_mode_page_faults_only {
+        true => PAGE_FAULT_ERROR_CODE_USER,
+        false => 0,
+    };
+    vmwrite(vmcs::control::PAGE_FAULT_ERR_CODE_MASK, page_fault_error_code_filter);
+    vmwrite(vmcs::control::PAGE_FAULT_ERR_CODE_MATCH, page_fault_error_code_filter);
+}
+
+/// Records an intercepted exception if the telemetry records its vector, and re-injects the event during whose
+/// delivery it occurred, if any.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `vector` - The vector of the exception.
+/// * `error_code` - The error code of the exception, 0 if it has none.
+/// * `cr2` - The faulting linear address of a page fault, 0 for the other exceptions.
+///
+/// # Returns
+///
+/// * `true` - If the original event has been re-injected, in which case the exception must not be reflected.
+/// * `false` - If the exception must be reflected to the guest, or passed to the exception hooks if it isn't recorded.
+pub fn record_exception(vm: &mut Vm, vector: ExceptionInterrupt, error_code: u64, cr2: u64) -> bool {
+    // The vector may be intercepted by the exception hooks only.
+    if SHARED_EXCEPTION_TELEMETRY.lock().recorded_vectors() & (1 << vector as u32) == 0 {
+        return false;
+    }
+
+    let idt_vectoring_info = vmread(vmcs::ro::IDT_VECTORING_INFO);
+    let during_event_delivery = idt_vectoring_info & IDT_VECTORING_INFO_VALID != 0;
+
+    let event = ExceptionEvent {
+        processor_id: vm.cpuid_feature_info.initial_local_apic_id() as u64,
+        tsc: rdtsc(),
+        vector: vector as u64,
+        error_code,
+        rip: vm.guest_registers.rip,
+        cr2,
+        cr3: vmread(vmcs::guest::CR3),
+        process_id: ProcessInformation::get_current_process_id().unwrap_or_default(),
+        during_event_delivery: during_event_delivery as u64,
+    };
+
+    trace!("Exception telemetry event: {:x?} at {}", event, SymbolizedAddress::new(event.process_id, event.rip));
+    SHARED_EXCEPTION_TELEMETRY.lock().push(event);
+
+    if !during_event_delivery {
+        return false;
+    }
+
+    debug!("Exception {:?} during event delivery, re-injecting: {:#x}", vector, idt_vectoring_info);
+
+    let exception_bitmap = vmread(vmcs::control::EXCEPTION_BITMAP) & !(1u64 << vector as u32);
+    vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+    vm.exception_telemetry.generation = Generation::STALE;
+    vm.published_generation = Generation::STALE;
+
+    EventInjection::vmentry_reinject_idt_vectoring_event(idt_vectoring_info);
+
+    true
+}
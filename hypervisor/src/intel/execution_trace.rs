@@ -0,0 +1,437 @@
+//! Provides an instruction-level execution tracer, which records each instruction executed in a virtual address range
+//! of a target process, e.g., obfuscated or anti-debug code, from below the guest, where its debugger checks can't
+//! see it.
+//!
+//! While enabled, the guest pages backing the range are made non-executable in the EPT. An instruction fetch from a
+//! traced page makes it executable and starts single-stepping the guest (see `single_step`): each instruction is
+//! recorded, its RIP and optionally its general-purpose registers, while RIP is in the range in the address space of
+//! the target process, the kernel addresses being traced in any address space. The first instruction out of the range
+//! stops the stepping and makes the pages non-executable again, to catch the next entry into the range.
+//!
+//! The guest interrupts are disabled while stepping, so the stepping also stops after `MAX_EXECUTION_TRACE_BURST`
+//! instructions, letting the pending interrupts be delivered, and resumes on the next instruction fetch. The client
+//! drains the records with the `ReadExecutionTrace` command.
+//!
+//! As for the unpacker, the traced pages are the physical pages mapped when the trace starts, the pages of EPT hooks
+//! and of the unpacker aren't traced, and the EPT is modified on the logical processor handling the commands. The
+//! trace stops when the target process no longer exists.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            ept::AccessType,
+            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            paging::CR3_ADDRESS_MASK,
+            single_step::{single_step, SingleStepContext, SingleStepOwner},
+            support::{rdtsc, vmread},
+            unpacker::{is_unpacker_page, set_watched_page_permissions},
+            vm::Vm,
+            vmexit::ExitType,
+        },
+        windows::eprocess::ProcessInformation,
+    },
+    alloc::{
+        collections::{BTreeMap, VecDeque},
+        vec::Vec,
+    },
+    core::sync::atomic::{AtomicBool, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    shared::{ExecutionTraceRecord, EXECUTION_TRACE_REGISTER_COUNT},
+    spin::Mutex,
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        vmx::vmcs,
+    },
+};
+
+/// The maximum number of pages of the traced range, each requiring a page table once its large page is split.
+pub const MAX_EXECUTION_TRACE_PAGES: usize = 0x1000;
+
+/// The maximum number of records kept until they are drained.
+pub const EXECUTION_TRACE_CAPACITY: usize = 0x4000;
+
+/// The maximum number of instructions stepped before the guest resumes with its interrupts enabled.
+pub const MAX_EXECUTION_TRACE_BURST: u64 = 0x100;
+
+/// The first virtual address of the kernel half of the address space.
+const KERNEL_ADDRESS_BASE: u64 = 0xFFFF_8000_0000_0000;
+
+/// Whether the tracer is enabled, checked without locking on each EPT violation.
+static EXECUTION_TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// A globally shared instance of `ExecutionTracer`, protected by a mutex.
+    pub static ref SHARED_EXECUTION_TRACER: Mutex<ExecutionTracer> = Mutex::new(ExecutionTracer::new());
+}
+
+/// The state of a traced page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TracedPageState {
+    /// The page is readable and writable, the next instruction fetch is intercepted.
+    Protected,
+
+    /// The page is executable while the instructions are stepped.
+    Executable,
+}
+
+/// The traced range of the target process and the instructions recorded.
+#[derive(Debug)]
+pub struct ExecutionTracer {
+    /// The ID of the target process.
+    process_id: u64,
+
+    /// The directory table base of the target process, used to translate the traced range.
+    directory_table_base: u64,
+
+    /// The user directory table base of the target process with KVA shadowing, or 0.
+    user_directory_table_base: u64,
+
+    /// The first virtual address of the traced range.
+    base_va: u64,
+
+    /// The virtual address following the traced range.
+    end_va: u64,
+
+    /// Whether the general-purpose registers are recorded with each instruction.
+    capture_registers: bool,
+
+    /// The traced pages, by guest physical address.
+    pages: BTreeMap<u64, TracedPageState>,
+
+    /// The instructions stepped since the guest last resumed with its interrupts enabled.
+    burst_instructions: u64,
+
+    /// The records not drained yet, oldest first.
+    records: VecDeque<ExecutionTraceRecord>,
+
+    /// The number of records dropped since the last drain because the buffer was full.
+    dropped_records: u64,
+}
+
+impl ExecutionTracer {
+    /// Creates a new stopped tracer, without allocating the buffer.
+    fn new() -> Self {
+        Self {
+            process_id: 0,
+            directory_table_base: 0,
+            user_directory_table_base: 0,
+            base_va: 0,
+            end_va: 0,
+            capture_registers: false,
+            pages: BTreeMap::new(),
+            burst_instructions: 0,
+            records: VecDeque::new(),
+            dropped_records: 0,
+        }
+    }
+
+    /// Returns the number of traced pages.
+    pub fn traced_page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Removes the oldest records from the buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_records` - The maximum number of records to remove.
+    ///
+    /// # Returns
+    ///
+    /// The records removed, oldest first, and the number of records dropped since the last drain.
+    pub fn drain(&mut self, max_records: usize) -> (Vec<ExecutionTraceRecord>, u64) {
+        let count = self.records.len().min(max_records);
+        let records = self.records.drain(..count).collect();
+
+        (records, core::mem::take(&mut self.dropped_records))
+    }
+
+    /// Stops the trace if the target process no longer exists, as its physical pages may be reused by other processes.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the target process exists or the trace has been stopped, or `Err(HypervisorError)` if the EPT
+    /// couldn't be modified.
+    pub fn stop_if_target_exited(&mut self, vm: &mut Vm) -> Result<(), HypervisorError> {
+        if !EXECUTION_TRACE_ENABLED.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        if ProcessInformation::get_directory_table_base_by_process_id(self.process_id) != Some(self.directory_table_base) {
+            debug!("Execution trace target process {} exited, stopping", self.process_id);
+            return self.stop(vm);
+        }
+
+        Ok(())
+    }
+
+    /// Stops tracing the range, restoring the permissions of the traced pages and keeping the records so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the trace has been stopped, or `Err(HypervisorError)` if the EPT couldn't be modified.
+    pub fn stop(&mut self, vm: &mut Vm) -> Result<(), HypervisorError> {
+        EXECUTION_TRACE_ENABLED.store(false, Ordering::Release);
+
+        for guest_page_pa in core::mem::take(&mut self.pages).into_keys() {
+            set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+        }
+
+        debug!("Execution trace stopped, {} records pending", self.records.len());
+
+        vm.primary_ept.invalidate_ept_cache()
+    }
+
+    /// Returns `true` if an instruction is in the traced range, in the address space of the target process for the
+    /// user addresses.
+    ///
+    /// # Arguments
+    ///
+    /// * `rip` - The guest RIP of the instruction.
+    /// * `cr3` - The guest CR3.
+    fn is_traced(&self, rip: u64, cr3: u64) -> bool {
+        if !(self.base_va..self.end_va).contains(&rip) {
+            return false;
+        }
+
+        let directory_table_base = cr3 & CR3_ADDRESS_MASK;
+
+        rip >= KERNEL_ADDRESS_BASE
+            || directory_table_base == self.directory_table_base & CR3_ADDRESS_MASK
+            || (self.user_directory_table_base != 0 && directory_table_base == self.user_directory_table_base & CR3_ADDRESS_MASK)
+    }
+
+    /// Records the instruction at the guest RIP, or counts it as dropped if the buffer is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    /// * `cr3` - The guest CR3.
+    fn record(&mut self, vm: &Vm, cr3: u64) {
+        if self.records.len() >= EXECUTION_TRACE_CAPACITY {
+            self.dropped_records += 1;
+            return;
+        }
+
+        let registers = &vm.guest_registers;
+        let mut record = ExecutionTraceRecord {
+            tsc: rdtsc(),
+            cr3,
+            rip: registers.rip,
+            rflags: registers.rflags,
+            registers: [0; EXECUTION_TRACE_REGISTER_COUNT],
+        };
+
+        if self.capture_registers {
+            record.registers = [
+                registers.rax,
+                registers.rcx,
+                registers.rdx,
+                registers.rbx,
+                registers.rsp,
+                registers.rbp,
+                registers.rsi,
+                registers.rdi,
+                registers.r8,
+                registers.r9,
+                registers.r10,
+                registers.r11,
+                registers.r12,
+                registers.r13,
+                registers.r14,
+                registers.r15,
+            ];
+        }
+
+        self.records.push_back(record);
+    }
+
+    /// Makes the pages made executable while stepping non-executable again, to catch the next entry into the range.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    fn protect_executable_pages(&mut self, vm: &mut Vm) -> Result<(), HypervisorError> {
+        for (&guest_page_pa, state) in self.pages.iter_mut() {
+            if *state == TracedPageState::Executable {
+                *state = TracedPageState::Protected;
+                set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_WRITE)?;
+            }
+        }
+
+        vm.primary_ept.invalidate_ept_cache()
+    }
+}
+
+/// Starts tracing the instructions executed in a range of a process, discarding the records of a previous run.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `process_id` - The ID of the target process.
+/// * `base_va` - The first virtual address of the range in the target process.
+/// * `size` - The size of the range in bytes.
+/// * `capture_registers` - Whether the general-purpose registers are recorded with each instruction.
+///
+/// # Returns
+///
+/// `Ok(())` if the range is traced, `Err(HypervisorError::ProcessNotFound)` if the process doesn't exist,
+/// `Err(HypervisorError::InvalidExecutionTraceRange)` if the range is empty or too large, or another
+/// `HypervisorError` if the EPT couldn't be modified.
+pub fn start_execution_trace(vm: &mut Vm, process_id: u64, base_va: u64, size: u64, capture_registers: bool) -> Result<(), HypervisorError> {
+    let mut tracer = SHARED_EXECUTION_TRACER.lock();
+
+    if EXECUTION_TRACE_ENABLED.load(Ordering::Acquire) {
+        tracer.stop(vm)?;
+    }
+
+    let end_va = base_va.checked_add(size).ok_or(HypervisorError::InvalidExecutionTraceRange)?;
+    let base_page_va = base_va & !(BASE_PAGE_SIZE as u64 - 1);
+    let page_count = (end_va - base_page_va).div_ceil(BASE_PAGE_SIZE as u64) as usize;
+
+    if size == 0 || page_count > MAX_EXECUTION_TRACE_PAGES {
+        return Err(HypervisorError::InvalidExecutionTraceRange);
+    }
+
+    let directory_table_base = ProcessInformation::get_directory_table_base_by_process_id(process_id).ok_or(HypervisorError::ProcessNotFound)?;
+
+    tracer.process_id = process_id;
+    tracer.directory_table_base = directory_table_base;
+    tracer.user_directory_table_base = ProcessInformation::get_user_directory_table_base_by_process_id(process_id).unwrap_or(0);
+    tracer.base_va = base_va;
+    tracer.end_va = end_va;
+    tracer.capture_registers = capture_registers;
+    tracer.burst_instructions = 0;
+    tracer.records.clear();
+    tracer.records.reserve_exact(EXECUTION_TRACE_CAPACITY);
+    tracer.dropped_records = 0;
+
+    for index in 0..page_count {
+        let guest_va = base_page_va + (index * BASE_PAGE_SIZE) as u64;
+
+        let Ok(guest_pa) = PhysicalAddress::pa_from_va_with_explicit_cr3(guest_va, directory_table_base) else {
+            continue;
+        };
+        let guest_page_pa = PAddr::from(guest_pa).align_down_to_base_page().as_u64();
+
+        // The pages of EPT hooks and of the unpacker are already switched between permissions of their own.
+        if SHARED_HOOK_MANAGER.lock().memory_manager.is_guest_page_processed(guest_page_pa) || is_unpacker_page(guest_page_pa) {
+            continue;
+        }
+
+        set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_WRITE)?;
+        tracer.pages.insert(guest_page_pa, TracedPageState::Protected);
+    }
+
+    EXECUTION_TRACE_ENABLED.store(true, Ordering::Release);
+
+    debug!("Execution trace started for process {}: {:#x} ({} of {} pages)", process_id, base_va, tracer.pages.len(), page_count);
+
+    vm.primary_ept.invalidate_ept_cache()
+}
+
+/// Returns `true` if a guest page is traced by the execution tracer.
+///
+/// # Arguments
+///
+/// * `guest_page_pa` - The guest physical address of the page.
+pub fn is_execution_trace_page(guest_page_pa: u64) -> bool {
+    EXECUTION_TRACE_ENABLED.load(Ordering::Acquire) && SHARED_EXECUTION_TRACER.lock().pages.contains_key(&guest_page_pa)
+}
+
+/// Handles an EPT violation on a traced page.
+///
+/// An instruction fetch makes the page executable and starts stepping the guest, recording the instruction if it's in
+/// the traced range. A fetch while stepping, by an instruction crossing into another traced page, only makes the page
+/// executable.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `guest_page_pa` - The guest physical address of the traced page.
+/// * `instruction_fetch` - Whether the violation was caused by an instruction fetch.
+///
+/// # Returns
+///
+/// * `Result<ExitType, HypervisorError>` - `Ok(ExitType::Continue)` to retry the access.
+pub fn handle_execution_trace_access(vm: &mut Vm, guest_page_pa: u64, instruction_fetch: bool) -> Result<ExitType, HypervisorError> {
+    let mut tracer = SHARED_EXECUTION_TRACER.lock();
+
+    // The permissions have been changed meanwhile by another logical processor, retry the access.
+    let Some(state) = tracer.pages.get_mut(&guest_page_pa) else {
+        return Ok(ExitType::Continue);
+    };
+
+    if !instruction_fetch || *state == TracedPageState::Executable {
+        return Ok(ExitType::Continue);
+    }
+
+    *state = TracedPageState::Executable;
+    set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+    vm.primary_ept.invalidate_ept_cache()?;
+
+    if vm.single_step.is_stepping_for(SingleStepOwner::ExecutionTrace) {
+        return Ok(ExitType::Continue);
+    }
+
+    let cr3 = vmread(vmcs::guest::CR3);
+    if tracer.is_traced(vm.guest_registers.rip, cr3) {
+        trace!("Entering traced range at RIP: {:#x}", vm.guest_registers.rip);
+        tracer.record(vm, cr3);
+    }
+
+    tracer.burst_instructions = 0;
+    drop(tracer);
+
+    let context = SingleStepContext {
+        guest_page_pa,
+        guest_next_page_pa: None,
+    };
+    single_step(vm, SingleStepOwner::ExecutionTrace, 1, context, step_execution_trace)?;
+
+    Ok(ExitType::Continue)
+}
+
+/// Records the next instruction and keeps stepping while it's in the traced range, or makes the traced pages
+/// non-executable again once it leaves the range or the burst ends.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `context` - The guest physical address of the page the stepping started from.
+///
+/// # Returns
+///
+/// `Ok(())` if the instruction has been handled, or `Err(HypervisorError)` if the EPT couldn't be modified.
+pub fn step_execution_trace(vm: &mut Vm, context: SingleStepContext) -> Result<(), HypervisorError> {
+    let mut tracer = SHARED_EXECUTION_TRACER.lock();
+
+    // The trace may have been stopped meanwhile, restoring the permissions of the pages.
+    if !EXECUTION_TRACE_ENABLED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    let cr3 = vmread(vmcs::guest::CR3);
+    tracer.burst_instructions += 1;
+
+    if !tracer.is_traced(vm.guest_registers.rip, cr3) || tracer.burst_instructions >= MAX_EXECUTION_TRACE_BURST {
+        trace!("Leaving traced range at RIP: {:#x} after {} instructions", vm.guest_registers.rip, tracer.burst_instructions);
+        return tracer.protect_executable_pages(vm);
+    }
+
+    tracer.record(vm, cr3);
+    drop(tracer);
+
+    single_step(vm, SingleStepOwner::ExecutionTrace, 1, context, step_execution_trace)
+}
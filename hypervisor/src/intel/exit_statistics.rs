@@ -0,0 +1,263 @@
+//! Provides the VM exit statistics of each logical processor: its VM exits by basic exit reason, with the shortest,
+//! average and longest latency of their handler, to find the interceptions hurting the performance of the guest.
+//!
+//! The latency of a VM exit is measured with the TSC from the VM exit to the return of its handler, without the
+//! synchronizations done before the next VM entry, which the `benchmark` module includes. Each logical processor keeps
+//! its statistics in its `Vm`, and publishes them to a shared table when `PublishExitStatistics` is broadcast (see the
+//! `broadcast` module), resetting them if requested, so the `ReadExitStatistics` command reads the statistics of all the
+//! logical processors at once. The table only holds the statistics published for the last broadcast.
+
+use {
+    crate::intel::{
+        support::rdtsc,
+        timing::tsc_frequency_hz,
+        vm::Vm,
+        vmerror::{VmxBasicExitReason, EXIT_REASON_COUNT},
+    },
+    alloc::{format, string::String, vec::Vec},
+    lazy_static::lazy_static,
+    log::*,
+    shared::ExitStatisticsRecord,
+    spin::Mutex,
+};
+
+lazy_static! {
+    /// A globally shared instance of `ExitStatistics`, protected by a mutex.
+    pub static ref SHARED_EXIT_STATISTICS: Mutex<ExitStatistics> = Mutex::new(ExitStatistics::new());
+}
+
+/// The VM exits of a basic exit reason and the latency of their handler.
+#[derive(Debug, Clone, Copy, Default)]
+struct ExitLatency {
+    /// The number of VM exits.
+    count: u64,
+
+    /// The total latency, in TSC ticks.
+    total_ticks: u64,
+
+    /// The shortest latency, in TSC ticks, 0 without VM exits.
+    min_ticks: u64,
+
+    /// The longest latency, in TSC ticks.
+    max_ticks: u64,
+}
+
+impl ExitLatency {
+    /// Counts a VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticks` - The latency of the handler of the VM exit, in TSC ticks.
+    fn record(&mut self, ticks: u64) {
+        self.min_ticks = match self.count {
+            0 => ticks,
+            _ => self.min_ticks.min(ticks),
+        };
+        self.max_ticks = self.max_ticks.max(ticks);
+        self.total_ticks = self.total_ticks.saturating_add(ticks);
+        self.count += 1;
+    }
+
+    /// Adds the VM exits of the same reason on another logical processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The VM exits of the other logical processor.
+    fn merge(&mut self, other: &Self) {
+        if other.count == 0 {
+            return;
+        }
+
+        self.min_ticks = match self.count {
+            0 => other.min_ticks,
+            _ => self.min_ticks.min(other.min_ticks),
+        };
+        self.max_ticks = self.max_ticks.max(other.max_ticks);
+        self.total_ticks = self.total_ticks.saturating_add(other.total_ticks);
+        self.count += other.count;
+    }
+}
+
+/// The VM exit statistics of a logical processor since they were last reset.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorExitStatistics {
+    /// The VM exits of this logical processor, by basic exit reason.
+    exits: [ExitLatency; EXIT_REASON_COUNT],
+}
+
+impl ProcessorExitStatistics {
+    /// Creates new statistics without VM exits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for ProcessorExitStatistics {
+    /// Returns statistics without VM exits, the arrays of more than 32 elements not implementing `Default`.
+    fn default() -> Self {
+        Self {
+            exits: [ExitLatency::default(); EXIT_REASON_COUNT],
+        }
+    }
+}
+
+/// The statistics published by a logical processor.
+#[derive(Debug, Clone, Copy)]
+struct PublishedStatistics {
+    /// The initial APIC ID of the logical processor.
+    processor_id: u32,
+
+    /// The statistics of the logical processor when it executed the broadcast.
+    statistics: ProcessorExitStatistics,
+}
+
+/// The VM exit statistics published by the logical processors for the last broadcast.
+#[derive(Debug)]
+pub struct ExitStatistics {
+    /// The generation of the broadcast the statistics were published for.
+    generation: u64,
+
+    /// The statistics of each logical processor that executed the broadcast, in the order they executed it.
+    processors: Vec<PublishedStatistics>,
+}
+
+impl ExitStatistics {
+    /// Creates a new empty table.
+    fn new() -> Self {
+        Self {
+            generation: 0,
+            processors: Vec::new(),
+        }
+    }
+
+    /// Stores the statistics of a logical processor, discarding the ones of the previous broadcasts.
+    ///
+    /// # Arguments
+    ///
+    /// * `generation` - The generation of the broadcast executed by the logical processor.
+    /// * `processor_id` - The initial APIC ID of the logical processor.
+    /// * `statistics` - The statistics of the logical processor.
+    fn publish(&mut self, generation: u64, processor_id: u32, statistics: &ProcessorExitStatistics) {
+        // A logical processor executing an older broadcast late must not discard the statistics of the last one.
+        if generation < self.generation {
+            return;
+        }
+
+        if generation > self.generation {
+            self.generation = generation;
+            self.processors.clear();
+        }
+
+        let published = PublishedStatistics {
+            processor_id,
+            statistics: *statistics,
+        };
+
+        match self.processors.iter_mut().find(|published| published.processor_id == processor_id) {
+            Some(existing) => *existing = published,
+            None => self.processors.push(published),
+        }
+    }
+
+    /// Returns the number of logical processors whose statistics were published for the last broadcast.
+    pub fn reported_processors(&self) -> u64 {
+        self.processors.len() as u64
+    }
+
+    /// Returns the records read by the client: one for each logical processor and basic exit reason with VM exits.
+    pub fn records(&self) -> Vec<ExitStatisticsRecord> {
+        self.processors
+            .iter()
+            .flat_map(|published| {
+                published
+                    .statistics
+                    .exits
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, latency)| latency.count != 0)
+                    .map(|(reason, latency)| ExitStatisticsRecord {
+                        processor_id: published.processor_id as u64,
+                        reason: reason as u64,
+                        count: latency.count,
+                        total_ticks: latency.total_ticks,
+                        min_ticks: latency.min_ticks,
+                        max_ticks: latency.max_ticks,
+                    })
+            })
+            .collect()
+    }
+
+    /// Formats the statistics of all the logical processors as text, by basic exit reason, the most frequent first.
+    pub fn report(&self) -> String {
+        let tsc_frequency = tsc_frequency_hz().max(1) as u128;
+        let nanoseconds = |ticks: u128| ticks * 1_000_000_000 / tsc_frequency;
+
+        let mut totals = [ExitLatency::default(); EXIT_REASON_COUNT];
+        for published in &self.processors {
+            for (total, latency) in totals.iter_mut().zip(published.statistics.exits.iter()) {
+                total.merge(latency);
+            }
+        }
+
+        let mut reasons: Vec<(usize, ExitLatency)> = totals.into_iter().enumerate().filter(|(_, latency)| latency.count != 0).collect();
+        reasons.sort_unstable_by_key(|(_, latency)| core::cmp::Reverse(latency.count));
+
+        let total_exits: u64 = reasons.iter().map(|(_, latency)| latency.count).sum();
+        let mut report = format!("VM exit statistics: {} processors, {} VM exits\n", self.processors.len(), total_exits);
+
+        for (reason, latency) in reasons {
+            let name = match VmxBasicExitReason::from_u32(reason as u32) {
+                Some(basic_exit_reason) => format!("{:?}", basic_exit_reason),
+                None => format!("{}", reason),
+            };
+
+            report += &format!(
+                "  {}: {} VM exits, {} ns min, {} ns average, {} ns max\n",
+                name,
+                latency.count,
+                nanoseconds(latency.min_ticks as u128),
+                nanoseconds(latency.total_ticks as u128 / latency.count as u128),
+                nanoseconds(latency.max_ticks as u128)
+            );
+        }
+
+        report
+    }
+}
+
+/// Counts a VM exit on the current logical processor with the latency of its handler.
+///
+/// This is called right after the VM exit handler returns, so the latency doesn't include the synchronizations.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+/// * `exit_tsc` - The TSC at the VM exit.
+pub fn record_exit_latency(vm: &mut Vm, basic_exit_reason: VmxBasicExitReason, exit_tsc: u64) {
+    let ticks = rdtsc().saturating_sub(exit_tsc);
+
+    if let Some(latency) = vm.exit_statistics.exits.get_mut(basic_exit_reason as usize) {
+        latency.record(ticks);
+    }
+}
+
+/// Publishes the statistics of the current logical processor to the shared table, then resets them if requested.
+///
+/// This is called when the logical processor executes the `PublishExitStatistics` broadcast.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `generation` - The generation of the broadcast.
+/// * `reset` - Whether the statistics are reset once published.
+pub fn publish_exit_statistics(vm: &mut Vm, generation: u64, reset: bool) {
+    let processor_id = vm.cpuid_feature_info.initial_local_apic_id() as u32;
+
+    SHARED_EXIT_STATISTICS.lock().publish(generation, processor_id, &vm.exit_statistics);
+    trace!("VM exit statistics published, generation {}", generation);
+
+    if reset {
+        vm.exit_statistics = ProcessorExitStatistics::new();
+    }
+}
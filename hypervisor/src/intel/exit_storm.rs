@@ -0,0 +1,134 @@
+//! Detects VM exit storms and sheds the offending interception, preventing the guest from appearing frozen.
+//!
+//! The number of VM exits of each basic exit reason is counted per logical processor over a one-second window. When
+//! a reason exceeds `STORM_EXITS_PER_SECOND` within the window, e.g. because of a misconfigured MSR bitmap, a storm is
+//! reported and the interception that caused the current VM exit is relaxed when it can be: the MSR or the I/O ports
+//! are unhooked in the bitmaps of the logical processor. Other exit reasons can't be relaxed and are only reported.
+//!
+//! Shedding an interception may reveal what it was hiding (e.g., a hidden device or the RTC offset), trading stealth
+//! for a responsive guest.
+
+use {
+    crate::intel::{
+        bitmap::{is_msr_in_bitmap, IoOperation, MsrAccessType, MsrOperation},
+        support::vmread,
+        timing::tsc_frequency_hz,
+        vm::Vm,
+        vmerror::{VmxBasicExitReason, EXIT_REASON_COUNT},
+    },
+    log::*,
+    x86::vmx::vmcs,
+};
+
+/// The number of VM exits of a single reason per second above which a storm is reported.
+const STORM_EXITS_PER_SECOND: u32 = 1_000_000;
+
+/// The per-logical-processor VM exit counters.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitStormMonitor {
+    /// The TSC at the start of the current window, 0 until the first VM exit.
+    window_start_tsc: u64,
+
+    /// The length of a window in TSC ticks, one second.
+    window_tsc_ticks: u64,
+
+    /// The number of VM exits of each basic exit reason in the current window.
+    exit_counts: [u32; EXIT_REASON_COUNT],
+}
+
+impl ExitStormMonitor {
+    /// Creates a new monitor, with a window of one second measured with the TSC.
+    pub fn new() -> Self {
+        Self {
+            window_start_tsc: 0,
+            window_tsc_ticks: tsc_frequency_hz(),
+            exit_counts: [0; EXIT_REASON_COUNT],
+        }
+    }
+
+    /// Counts a VM exit, returning `true` if its reason exceeds the storm threshold in the current window.
+    ///
+    /// # Arguments
+    ///
+    /// * `basic_exit_reason` - The basic exit reason of the VM exit.
+    /// * `exit_tsc` - The TSC at the VM exit.
+    fn record(&mut self, basic_exit_reason: VmxBasicExitReason, exit_tsc: u64) -> bool {
+        if exit_tsc.wrapping_sub(self.window_start_tsc) >= self.window_tsc_ticks {
+            self.window_start_tsc = exit_tsc;
+            self.exit_counts.fill(0);
+        }
+
+        let Some(count) = self.exit_counts.get_mut(basic_exit_reason as usize) else {
+            return false;
+        };
+
+        *count += 1;
+
+        if *count < STORM_EXITS_PER_SECOND {
+            return false;
+        }
+
+        // Start counting again, so a storm that can't be relaxed is reported once per threshold crossing.
+        *count = 0;
+        true
+    }
+}
+
+impl Default for ExitStormMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts a VM exit on the current logical processor and sheds the interception that caused it if a storm is detected.
+///
+/// This must be called after the VM exit has been handled, while its exit qualification and guest registers are
+/// still those of the VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+/// * `exit_tsc` - The TSC at the VM exit.
+pub fn monitor_exit(vm: &mut Vm, basic_exit_reason: VmxBasicExitReason, exit_tsc: u64) {
+    if !vm.exit_storm_monitor.record(basic_exit_reason, exit_tsc) {
+        return;
+    }
+
+    error!("==================== VM EXIT STORM ====================");
+    error!("{:?} VM exits exceeded {} per second on this processor", basic_exit_reason, STORM_EXITS_PER_SECOND);
+
+    match basic_exit_reason {
+        VmxBasicExitReason::Rdmsr | VmxBasicExitReason::Wrmsr => {
+            let msr = vm.guest_registers.rcx as u32;
+
+            // MSRs outside of the ranges covered by the MSR bitmap always cause VM exits.
+            if !is_msr_in_bitmap(msr) {
+                error!("MSR {:#x} is outside of the MSR bitmap, the interception can't be relaxed", msr);
+                return;
+            }
+
+            let access = match basic_exit_reason {
+                VmxBasicExitReason::Rdmsr => MsrAccessType::Read,
+                _ => MsrAccessType::Write,
+            };
+
+            vm.msr_bitmap.modify_msr_interception(msr, access, MsrOperation::Unhook);
+            error!("Relaxed the interception of MSR {:#x}", msr);
+        }
+        VmxBasicExitReason::IoInstruction => {
+            let exit_qualification = vmread(vmcs::ro::EXIT_QUALIFICATION);
+
+            // Bits 2:0: size of access minus one. Bits 31:16: port number.
+            let size = (exit_qualification & 0x7) as u16 + 1;
+            let port = (exit_qualification >> 16) as u16;
+
+            for port in port..port.saturating_add(size) {
+                vm.io_bitmap.modify_io_interception(port, IoOperation::Unhook);
+            }
+
+            error!("Relaxed the interception of I/O ports {:#x}-{:#x}", port, port.saturating_add(size - 1));
+        }
+        _ => error!("{:?} VM exits can't be relaxed", basic_exit_reason),
+    }
+}
@@ -0,0 +1,638 @@
+//! Provides a map of the virtual memory allocations of target processes, maintained from their system calls
+//! allocating, protecting and freeing memory, and alerts for the memory both writable and executable, for
+//! exploit-detection research.
+//!
+//! While a process is monitored, `NtAllocateVirtualMemory`, `NtProtectVirtualMemory` and optionally
+//! `NtFreeVirtualMemory` are hooked (see the `syscall_hook` module) by their system call numbers, which the client
+//! resolves for the build of the guest. The handler requests the return of the system calls issued by a target
+//! process, where the base address and size rounded by the kernel are read from the output arguments, and the map of
+//! the process is updated if the system call succeeded.
+//!
+//! An alert is recorded when a target process allocates memory both writable and executable (RWX), gives this
+//! protection to memory, or makes memory executable that was writable, e.g., staged shellcode. The regions allocated
+//! before the monitoring started aren't in the map, but their protection changes are still alerted, the previous
+//! protection being read from the output of `NtProtectVirtualMemory`. The system calls on other processes through a
+//! handle are only alerted, marked as remote, as the handle isn't resolved.
+//!
+//! The ranges of the local alerts made executable are also copied by the `code_snapshot` module, if enabled.
+//!
+//! The map of a process is copied with the `ReadAllocationMap` command, and the alerts are drained with the
+//! `ReadAllocationAlerts` command. When the buffer of the alerts is full, new alerts are handled by its backpressure
+//! policy (see the `event_ring` module), dropping them by default.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            code_snapshot::snapshot_code_range,
+            event_ring::EventRing,
+            hooks::syscall_hook::{SyscallAction, SyscallContext, SyscallEntry, SHARED_SYSCALL_HOOK_MANAGER},
+            support::rdtsc,
+        },
+        windows::eprocess::ProcessInformation,
+    },
+    alloc::{collections::BTreeMap, vec::Vec},
+    lazy_static::lazy_static,
+    log::*,
+    shared::{AllocationAlert, AllocationAlertKind, AllocationRegion, CodeSnapshotSource, EventRingId},
+    spin::Mutex,
+};
+
+/// The maximum number of alerts kept in the alert buffer until they are drained.
+pub const ALLOCATION_ALERT_BUFFER_CAPACITY: usize = 0x400;
+
+/// The maximum number of regions in the map of a process, beyond which new regions are counted as dropped.
+pub const MAX_ALLOCATION_REGIONS: usize = 0x4000;
+
+/// The maximum number of monitored processes.
+const MAX_MONITORED_PROCESSES: usize = 0x40;
+
+/// The pseudo-handle of the current process, `NtCurrentProcess()`.
+const NT_CURRENT_PROCESS: u64 = u64::MAX;
+
+/// The state of committed pages.
+const MEM_COMMIT: u32 = 0x1000;
+
+/// The state of reserved pages.
+const MEM_RESERVE: u32 = 0x2000;
+
+/// The free type decommitting pages.
+const MEM_DECOMMIT: u32 = 0x4000;
+
+/// The free type releasing a whole allocation.
+const MEM_RELEASE: u32 = 0x8000;
+
+/// The protection constants giving write access, without the modifiers (e.g., `PAGE_GUARD`).
+const PAGE_WRITABLE_MASK: u32 = 0x04 | 0x08 | 0x40 | 0x80;
+
+/// The protection constants giving execute access, without the modifiers.
+const PAGE_EXECUTABLE_MASK: u32 = 0x10 | 0x20 | 0x40 | 0x80;
+
+/// The argument indexes of `NtAllocateVirtualMemory`.
+mod allocate_arguments {
+    pub const PROCESS_HANDLE: usize = 0;
+    pub const BASE_ADDRESS: usize = 1;
+    pub const REGION_SIZE: usize = 3;
+    pub const ALLOCATION_TYPE: usize = 4;
+    pub const PROTECT: usize = 5;
+}
+
+/// The argument indexes of `NtProtectVirtualMemory`.
+mod protect_arguments {
+    pub const PROCESS_HANDLE: usize = 0;
+    pub const BASE_ADDRESS: usize = 1;
+    pub const REGION_SIZE: usize = 2;
+    pub const NEW_PROTECT: usize = 3;
+    pub const OLD_PROTECT: usize = 4;
+}
+
+/// The argument indexes of `NtFreeVirtualMemory`.
+mod free_arguments {
+    pub const PROCESS_HANDLE: usize = 0;
+    pub const BASE_ADDRESS: usize = 1;
+    pub const REGION_SIZE: usize = 2;
+    pub const FREE_TYPE: usize = 3;
+}
+
+lazy_static! {
+    /// A globally shared instance of `AllocationMonitor`, protected by a mutex.
+    pub static ref SHARED_ALLOCATION_MONITOR: Mutex<AllocationMonitor> = Mutex::new(AllocationMonitor::new());
+}
+
+/// The system call numbers hooked by the allocation monitor, resolved by the client for the build of the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationSyscallNumbers {
+    /// The system call number of `NtAllocateVirtualMemory`.
+    pub allocate: u32,
+
+    /// The system call number of `NtProtectVirtualMemory`.
+    pub protect: u32,
+
+    /// The system call number of `NtFreeVirtualMemory`, or `None` if the freed regions stay in the map.
+    pub free: Option<u32>,
+}
+
+/// The regions allocated by a monitored process.
+#[derive(Debug, Default)]
+pub struct ProcessAllocations {
+    /// The regions, by base address, of the same state and protection.
+    regions: BTreeMap<u64, AllocationRegion>,
+
+    /// The number of regions dropped because the map was full.
+    dropped_regions: u64,
+}
+
+impl ProcessAllocations {
+    /// Splits the region containing an address, so a region starts at the address.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address.
+    fn split_at(&mut self, address: u64) {
+        let Some((_, region)) = self.regions.range_mut(..address).next_back() else {
+            return;
+        };
+
+        let region_end = region.base_address + region.size;
+        if region_end <= address {
+            return;
+        }
+
+        let tail = AllocationRegion {
+            base_address: address,
+            size: region_end - address,
+            ..*region
+        };
+        region.size = address - region.base_address;
+
+        self.insert(tail);
+    }
+
+    /// Returns `true` if a range is entirely covered by known regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_address` - The first address of the range.
+    /// * `size` - The size of the range in bytes.
+    fn covers(&self, base_address: u64, size: u64) -> bool {
+        if size == 0 {
+            return false;
+        }
+
+        let mut next_address = base_address;
+
+        if let Some((_, region)) = self.regions.range(..=base_address).next_back() {
+            next_address = next_address.max(region.base_address + region.size);
+        }
+
+        for (_, region) in self.regions.range(base_address + 1..base_address + size) {
+            if region.base_address > next_address {
+                return false;
+            }
+            next_address = next_address.max(region.base_address + region.size);
+        }
+
+        next_address >= base_address + size
+    }
+
+    /// Adds a region to the map, or counts it as dropped if the map is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `region` - The region.
+    fn insert(&mut self, region: AllocationRegion) {
+        if self.regions.len() < MAX_ALLOCATION_REGIONS || self.regions.contains_key(&region.base_address) {
+            self.regions.insert(region.base_address, region);
+        } else {
+            self.dropped_regions += 1;
+        }
+    }
+
+    /// Updates the regions of a range, splitting the regions crossing its bounds.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_address` - The first address of the range.
+    /// * `size` - The size of the range in bytes.
+    /// * `update` - The update applied to each region of the range.
+    fn update_range(&mut self, base_address: u64, size: u64, update: impl Fn(&mut AllocationRegion)) {
+        self.split_at(base_address);
+        self.split_at(base_address + size);
+
+        for (_, region) in self.regions.range_mut(base_address..base_address + size) {
+            update(region);
+        }
+    }
+
+    /// Records a successful `NtAllocateVirtualMemory`: pages committed into a known reservation, or a new allocation
+    /// replacing the stale regions it overlaps.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_address` - The base address of the allocated range.
+    /// * `size` - The size of the allocated range in bytes.
+    /// * `allocation_type` - The allocation type, e.g., `MEM_COMMIT | MEM_RESERVE`.
+    /// * `protect` - The protection of the allocated pages.
+    fn allocate(&mut self, base_address: u64, size: u64, allocation_type: u32, protect: u32) {
+        let state = match allocation_type & MEM_COMMIT {
+            0 => MEM_RESERVE,
+            _ => MEM_COMMIT,
+        };
+
+        if allocation_type & MEM_RESERVE == 0 && self.covers(base_address, size) {
+            self.update_range(base_address, size, |region| {
+                region.state = state as u64;
+                region.protect = protect as u64;
+            });
+            return;
+        }
+
+        self.release_range(base_address, size);
+
+        self.insert(AllocationRegion {
+            allocation_base: base_address,
+            base_address,
+            size,
+            state: state as u64,
+            protect: protect as u64,
+            allocation_protect: protect as u64,
+            tsc: rdtsc(),
+        });
+    }
+
+    /// Records a successful `NtProtectVirtualMemory` on the known regions of a range.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_address` - The base address of the protected range.
+    /// * `size` - The size of the protected range in bytes.
+    /// * `protect` - The new protection.
+    fn protect(&mut self, base_address: u64, size: u64, protect: u32) {
+        self.update_range(base_address, size, |region| region.protect = protect as u64);
+    }
+
+    /// Records a successful `NtFreeVirtualMemory`.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_address` - The base address of the freed range, the allocation base for a release.
+    /// * `size` - The size of the freed range in bytes.
+    /// * `free_type` - `MEM_RELEASE` or `MEM_DECOMMIT`.
+    fn free(&mut self, base_address: u64, size: u64, free_type: u32) {
+        if free_type & MEM_RELEASE != 0 {
+            self.regions.retain(|_, region| region.allocation_base != base_address);
+            self.release_range(base_address, size);
+        } else if free_type & MEM_DECOMMIT != 0 {
+            self.update_range(base_address, size, |region| {
+                region.state = MEM_RESERVE as u64;
+                region.protect = 0;
+            });
+        }
+    }
+
+    /// Removes the known regions of a range, splitting the regions crossing its bounds.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_address` - The first address of the range.
+    /// * `size` - The size of the range in bytes.
+    fn release_range(&mut self, base_address: u64, size: u64) {
+        self.split_at(base_address);
+        self.split_at(base_address + size);
+
+        let released_keys: Vec<_> = self.regions.range(base_address..base_address + size).map(|(&key, _)| key).collect();
+        for key in released_keys {
+            self.regions.remove(&key);
+        }
+    }
+}
+
+/// The monitored processes, their regions and the alerts recorded by all the logical processors.
+#[derive(Debug)]
+pub struct AllocationMonitor {
+    /// The hooked system call numbers, while processes are monitored.
+    syscall_numbers: Option<AllocationSyscallNumbers>,
+
+    /// The regions of the monitored processes, by process ID.
+    processes: BTreeMap<u64, ProcessAllocations>,
+
+    /// The alerts recorded and not drained yet, oldest first.
+    alerts: EventRing<AllocationAlert>,
+}
+
+impl AllocationMonitor {
+    /// Creates a new monitor without processes, without allocating the buffer.
+    fn new() -> Self {
+        Self {
+            syscall_numbers: None,
+            processes: BTreeMap::new(),
+            alerts: EventRing::new(EventRingId::AllocationAlerts, ALLOCATION_ALERT_BUFFER_CAPACITY),
+        }
+    }
+
+    /// Starts monitoring a process, hooking the system calls by their numbers, and discards its map if it was already
+    /// monitored.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The ID of the target process.
+    /// * `syscall_numbers` - The system call numbers of the build of the guest.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the process is monitored, `Err(HypervisorError::ProcessNotFound)` if it doesn't exist, or
+    /// `Err(HypervisorError::TooManyMonitoredProcesses)` if the maximum number of processes are already monitored.
+    pub fn start(&mut self, process_id: u64, syscall_numbers: AllocationSyscallNumbers) -> Result<(), HypervisorError> {
+        if process_id == 0 || ProcessInformation::get_directory_table_base_by_process_id(process_id).is_none() {
+            return Err(HypervisorError::ProcessNotFound);
+        }
+
+        if !self.processes.contains_key(&process_id) && self.processes.len() >= MAX_MONITORED_PROCESSES {
+            return Err(HypervisorError::TooManyMonitoredProcesses);
+        }
+
+        if self.syscall_numbers != Some(syscall_numbers) {
+            self.unhook_syscalls();
+
+            let mut syscall_hook_manager = SHARED_SYSCALL_HOOK_MANAGER.lock();
+            syscall_hook_manager.register(syscall_numbers.allocate, handle_allocation_syscall);
+            syscall_hook_manager.register(syscall_numbers.protect, handle_allocation_syscall);
+            if let Some(free) = syscall_numbers.free {
+                syscall_hook_manager.register(free, handle_allocation_syscall);
+            }
+
+            self.syscall_numbers = Some(syscall_numbers);
+        }
+
+        if self.processes.is_empty() {
+            self.alerts.reset();
+        }
+
+        self.processes.insert(process_id, ProcessAllocations::default());
+
+        debug!("Allocation monitor started for process {}: {:x?}", process_id, syscall_numbers);
+
+        Ok(())
+    }
+
+    /// Stops monitoring a process, or every process, discarding their maps, and unhooks the system calls once no
+    /// process is monitored. The alerts are kept until they are drained.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The ID of the target process, or 0 for every process.
+    pub fn stop(&mut self, process_id: u64) {
+        match process_id {
+            0 => self.processes.clear(),
+            _ => {
+                self.processes.remove(&process_id);
+            }
+        }
+
+        if self.processes.is_empty() {
+            self.unhook_syscalls();
+        }
+
+        debug!("Allocation monitor stopped for process {}", process_id);
+    }
+
+    /// Returns the regions of a monitored process, in the order of their addresses.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The ID of the monitored process.
+    /// * `max_regions` - The maximum number of regions returned.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(Vec<AllocationRegion>, u64, u64)>` - The first regions, the number of regions in the map and the number
+    ///   of regions dropped because the map was full, or `None` if the process isn't monitored.
+    pub fn regions(&self, process_id: u64, max_regions: usize) -> Option<(Vec<AllocationRegion>, u64, u64)> {
+        let process = self.processes.get(&process_id)?;
+        let regions = process.regions.values().take(max_regions).copied().collect();
+
+        Some((regions, process.regions.len() as u64, process.dropped_regions))
+    }
+
+    /// Removes the oldest alerts from the buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_alerts` - The maximum number of alerts to remove.
+    ///
+    /// # Returns
+    ///
+    /// The alerts removed, oldest first, and the number of alerts dropped since the last drain.
+    pub fn drain_alerts(&mut self, max_alerts: usize) -> (Vec<AllocationAlert>, u64) {
+        self.alerts.drain(max_alerts)
+    }
+
+    /// Returns the buffer of the alerts, to configure it or read its statistics.
+    pub fn event_ring(&mut self) -> &mut EventRing<AllocationAlert> {
+        &mut self.alerts
+    }
+
+    /// Unregisters the handlers of the hooked system calls.
+    fn unhook_syscalls(&mut self) {
+        let Some(syscall_numbers) = self.syscall_numbers.take() else {
+            return;
+        };
+
+        let mut syscall_hook_manager = SHARED_SYSCALL_HOOK_MANAGER.lock();
+        syscall_hook_manager.unregister(syscall_numbers.allocate);
+        syscall_hook_manager.unregister(syscall_numbers.protect);
+        if let Some(free) = syscall_numbers.free {
+            syscall_hook_manager.unregister(free);
+        }
+    }
+
+    /// Adds an alert to the buffer, applying its backpressure policy if it's full.
+    ///
+    /// # Arguments
+    ///
+    /// * `alert` - The alert.
+    fn push_alert(&mut self, alert: AllocationAlert) {
+        info!("Allocation alert: {:x?}", alert);
+        self.alerts.push(alert);
+    }
+}
+
+/// Returns `true` if a protection gives write access.
+fn is_writable(protect: u32) -> bool {
+    protect & PAGE_WRITABLE_MASK != 0
+}
+
+/// Returns `true` if a protection gives execute access.
+fn is_executable(protect: u32) -> bool {
+    protect & PAGE_EXECUTABLE_MASK != 0
+}
+
+/// Returns `true` if an NTSTATUS is a success or informational status.
+fn nt_success(status: u64) -> bool {
+    status as i32 >= 0
+}
+
+/// Reads the output base address and region size of a system call, from the pointers of its arguments.
+///
+/// # Arguments
+///
+/// * `entry` - The system call.
+/// * `base_address_index` - The index of the pointer argument receiving the base address.
+/// * `region_size_index` - The index of the pointer argument receiving the region size.
+///
+/// # Returns
+///
+/// * `Option<(u64, u64)>` - The base address and region size, or `None` if they can't be read.
+fn read_output_range(entry: &SyscallEntry, base_address_index: usize, region_size_index: usize) -> Option<(u64, u64)> {
+    let base_address = PhysicalAddress::read_guest_virt_with_current_cr3(entry.arguments[base_address_index] as *const u64)?;
+    let region_size = PhysicalAddress::read_guest_virt_with_current_cr3(entry.arguments[region_size_index] as *const u64)?;
+
+    Some((base_address, region_size))
+}
+
+/// Creates an alert for a system call of a monitored process.
+///
+/// # Arguments
+///
+/// * `entry` - The system call.
+/// * `kind` - The kind of the alert.
+/// * `remote` - Whether the system call was on another process through a handle.
+/// * `range` - The base address and size of the range.
+/// * `protect` - The protection given to the range.
+/// * `old_protect` - The previous protection of the range, 0 for an allocation.
+fn create_alert(entry: &SyscallEntry, kind: AllocationAlertKind, remote: bool, range: (u64, u64), protect: u32, old_protect: u32) -> AllocationAlert {
+    AllocationAlert {
+        tsc: rdtsc(),
+        process_id: entry.process_id,
+        thread_id: entry.thread_id,
+        kind: kind as u64,
+        remote: remote as u64,
+        base_address: range.0,
+        size: range.1,
+        protect: protect as u64,
+        old_protect: old_protect as u64,
+        return_address: entry.return_address,
+    }
+}
+
+/// The handler of the hooked system calls, requesting the return of those issued by a monitored process.
+///
+/// # Arguments
+///
+/// * `context` - The context of the system call.
+fn handle_allocation_syscall(context: &mut SyscallContext) -> SyscallAction {
+    let Some(process_id) = ProcessInformation::get_current_process_id() else {
+        return SyscallAction::Continue;
+    };
+
+    let allocation_monitor = SHARED_ALLOCATION_MONITOR.lock();
+
+    let Some(syscall_numbers) = allocation_monitor.syscall_numbers else {
+        return SyscallAction::Continue;
+    };
+
+    if !allocation_monitor.processes.contains_key(&process_id) {
+        return SyscallAction::Continue;
+    }
+
+    match context.syscall_number {
+        number if number == syscall_numbers.allocate => SyscallAction::ContinueWithReturn(complete_allocate),
+        number if number == syscall_numbers.protect => SyscallAction::ContinueWithReturn(complete_protect),
+        number if Some(number) == syscall_numbers.free => SyscallAction::ContinueWithReturn(complete_free),
+        _ => SyscallAction::Continue,
+    }
+}
+
+/// The return handler of `NtAllocateVirtualMemory`.
+///
+/// # Arguments
+///
+/// * `entry` - The system call.
+/// * `status` - The status of the system call.
+fn complete_allocate(entry: &SyscallEntry, status: u64) {
+    if !nt_success(status) {
+        return;
+    }
+
+    let Some(range) = read_output_range(entry, allocate_arguments::BASE_ADDRESS, allocate_arguments::REGION_SIZE) else {
+        warn!("Failed to read the allocated range of process {}", entry.process_id);
+        return;
+    };
+
+    let allocation_type = entry.arguments[allocate_arguments::ALLOCATION_TYPE] as u32;
+    let protect = entry.arguments[allocate_arguments::PROTECT] as u32;
+    let remote = entry.arguments[allocate_arguments::PROCESS_HANDLE] != NT_CURRENT_PROCESS;
+
+    trace!("Process {} allocated {:#x?} with type {:#x} and protection {:#x}", entry.process_id, range, allocation_type, protect);
+
+    let mut allocation_monitor = SHARED_ALLOCATION_MONITOR.lock();
+
+    if is_writable(protect) && is_executable(protect) {
+        allocation_monitor.push_alert(create_alert(entry, AllocationAlertKind::RwxAllocation, remote, range, protect, 0));
+    }
+
+    if remote {
+        return;
+    }
+
+    if let Some(process) = allocation_monitor.processes.get_mut(&entry.process_id) {
+        process.allocate(range.0, range.1, allocation_type, protect);
+    }
+}
+
+/// The return handler of `NtProtectVirtualMemory`.
+///
+/// # Arguments
+///
+/// * `entry` - The system call.
+/// * `status` - The status of the system call.
+fn complete_protect(entry: &SyscallEntry, status: u64) {
+    if !nt_success(status) {
+        return;
+    }
+
+    let Some(range) = read_output_range(entry, protect_arguments::BASE_ADDRESS, protect_arguments::REGION_SIZE) else {
+        warn!("Failed to read the protected range of process {}", entry.process_id);
+        return;
+    };
+
+    let protect = entry.arguments[protect_arguments::NEW_PROTECT] as u32;
+    let old_protect =
+        PhysicalAddress::read_guest_virt_with_current_cr3(entry.arguments[protect_arguments::OLD_PROTECT] as *const u32).unwrap_or_default();
+    let remote = entry.arguments[protect_arguments::PROCESS_HANDLE] != NT_CURRENT_PROCESS;
+
+    trace!("Process {} protected {:#x?} from {:#x} to {:#x}", entry.process_id, range, old_protect, protect);
+
+    let mut allocation_monitor = SHARED_ALLOCATION_MONITOR.lock();
+
+    let snapshot_source = if is_writable(protect) && is_executable(protect) {
+        allocation_monitor.push_alert(create_alert(entry, AllocationAlertKind::RwxProtection, remote, range, protect, old_protect));
+        Some(CodeSnapshotSource::RwxProtection)
+    } else if is_executable(protect) && is_writable(old_protect) {
+        allocation_monitor.push_alert(create_alert(entry, AllocationAlertKind::WritableToExecutable, remote, range, protect, old_protect));
+        Some(CodeSnapshotSource::WritableToExecutable)
+    } else {
+        None
+    };
+
+    if remote {
+        return;
+    }
+
+    if let Some(process) = allocation_monitor.processes.get_mut(&entry.process_id) {
+        process.protect(range.0, range.1, protect);
+    }
+
+    drop(allocation_monitor);
+
+    // The range is mapped in the current process, and already contains the code written while it was writable.
+    if let Some(source) = snapshot_source {
+        snapshot_code_range(source, range.0, range.1);
+    }
+}
+
+/// The return handler of `NtFreeVirtualMemory`.
+///
+/// # Arguments
+///
+/// * `entry` - The system call.
+/// * `status` - The status of the system call.
+fn complete_free(entry: &SyscallEntry, status: u64) {
+    if !nt_success(status) || entry.arguments[free_arguments::PROCESS_HANDLE] != NT_CURRENT_PROCESS {
+        return;
+    }
+
+    let Some((base_address, size)) = read_output_range(entry, free_arguments::BASE_ADDRESS, free_arguments::REGION_SIZE) else {
+        warn!("Failed to read the freed range of process {}", entry.process_id);
+        return;
+    };
+
+    let free_type = entry.arguments[free_arguments::FREE_TYPE] as u32;
+
+    trace!("Process {} freed {:#x} {:#x} with type {:#x}", entry.process_id, base_address, size, free_type);
+
+    if let Some(process) = SHARED_ALLOCATION_MONITOR.lock().processes.get_mut(&entry.process_id) {
+        process.free(base_address, size, free_type);
+    }
+}
@@ -0,0 +1,514 @@
+//! Provides the boot-time hook manifest, a list of kernel hooks read by the loader from the EFI System Partition or
+//! a UEFI variable, and installed automatically once the kernel is ready, so standard deployments need no guest
+//! client at all.
+//!
+//! The manifest is a text with one hook per line, empty lines and lines starting with `#` being ignored:
+//! - `export <name> [type]` hooks an export of ntoskrnl.exe by name, or of another loaded kernel module with
+//!   `<module>!<name>` (e.g., `hal!HalRequestSoftwareInterrupt`), following the forwarded exports,
+//! - `syscall <number> [type]` hooks the function of a system call number in the SSDT, in decimal or `0x` hexadecimal,
+//! - `signature <name> <pattern> [type]` hooks the first match of a byte pattern in ntoskrnl.exe, written as
+//!   hexadecimal bytes with `??` wildcards (e.g., `48 8B C4 ?? 89 58 08`), the name only identifying the hook.
+//!
+//! The type is the inline hook type, `int3`, `int3stub`, `cpuid` or `vmcall`, `vmcall` if omitted.
+//!
+//! The manifest is parsed by the loader before the processors are virtualized, so a malformed manifest is reported at
+//! boot. A hook failing to install is logged and skipped.
+//!
+//! The hooks are installed at a trigger point, selected by an optional `trigger` line, since the point at which the
+//! kernel is ready to be hooked depends on its version:
+//! - `trigger cache_information`, the default, fires on the first `CPUID` leaf 2 executed by the guest after the
+//!   kernel base has been captured, once the SSDT is initialized (see `HookManager::has_cpuid_cache_info_been_called`),
+//! - `trigger export <name>` fires on the first execution of an export of ntoskrnl.exe, hooked when the kernel base is
+//!   captured,
+//! - `trigger process_creation <number>` fires on the first process created from user mode, with the system call
+//!   number of `NtCreateUserProcess`, which changes between Windows versions,
+//! - `trigger hypercall` fires when a guest agent sends the `TriggerBootHooks` command.
+//!
+//! A trigger only marks the hooks as pending: they are installed at the next VM exit of a logical processor, where the
+//! hook manager isn't locked, and the callback or the system call handler of the trigger is then removed.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            hooks::{
+                callbacks::{HookCallbacks, HookContext},
+                hook_manager::{kernel_image, EptHookType, HookManager, SHARED_HOOK_MANAGER},
+                inline::InlineHookType,
+                syscall_hook::{SyscallAction, SyscallContext, SHARED_SYSCALL_HOOK_MANAGER},
+            },
+            signature_scan::{find_kernel_signature, Signature},
+            vm::Vm,
+        },
+        windows::nt::pe::djb2_hash,
+    },
+    alloc::{
+        string::{String, ToString},
+        vec::Vec,
+    },
+    core::sync::atomic::{AtomicU8, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+};
+
+/// The maximum number of hooks of a manifest.
+pub const MAX_BOOT_HOOKS: usize = 0x40;
+
+/// The state of the hooks of the manifest, a `BootHookState`.
+static BOOT_HOOK_STATE: AtomicU8 = AtomicU8::new(BootHookState::Idle as u8);
+
+lazy_static! {
+    /// A globally shared instance of `BootHookManifest`, protected by a mutex.
+    pub static ref SHARED_BOOT_HOOK_MANIFEST: Mutex<BootHookManifest> = Mutex::new(BootHookManifest::default());
+}
+
+/// The progress of the installation of the hooks of the manifest.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BootHookState {
+    /// The trigger isn't armed yet.
+    Idle = 0,
+
+    /// The hook or the system call handler of the trigger is installed, if it needs one.
+    Armed = 1,
+
+    /// The trigger has fired, and the hooks are installed at the next VM exit.
+    Pending = 2,
+
+    /// The hooks have been installed.
+    Installed = 3,
+}
+
+/// The point at which the hooks of the manifest are installed.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum BootHookTrigger {
+    /// The first `CPUID` leaf 2 executed after the kernel base has been captured, once the SSDT is initialized.
+    #[default]
+    CacheInformation,
+
+    /// The first execution of an export of ntoskrnl.exe, by name.
+    Export(String),
+
+    /// The first process created from user mode, with the system call number of `NtCreateUserProcess`.
+    ProcessCreation(u16),
+
+    /// The `TriggerBootHooks` command of a guest agent.
+    Hypercall,
+}
+
+/// The kernel function hooked by an entry of the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootHookTarget {
+    /// An export of ntoskrnl.exe, or of another kernel module as `module!name`, by name.
+    Export(String),
+
+    /// The function of a system call number in the SSDT.
+    Syscall(u16),
+
+    /// The first match of a byte pattern in ntoskrnl.exe, `None` bytes matching any byte.
+    Signature { name: String, pattern: Vec<Option<u8>> },
+}
+
+/// An entry of the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootHook {
+    /// The hooked function.
+    pub target: BootHookTarget,
+
+    /// The inline hook type.
+    pub hook_type: InlineHookType,
+}
+
+/// The hooks installed automatically once the kernel is ready.
+#[derive(Debug, Default)]
+pub struct BootHookManifest {
+    /// The entries of the manifest, in the order of the manifest.
+    hooks: Vec<BootHook>,
+
+    /// The point at which the hooks are installed.
+    trigger: BootHookTrigger,
+
+    /// The text the manifest has been parsed from, saved with the persistent configuration.
+    text: String,
+}
+
+impl BootHookManifest {
+    /// Parses a manifest and shares it with the hypervisor.
+    ///
+    /// This must be called by the loader before the processors are virtualized.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the manifest.
+    ///
+    /// # Returns
+    ///
+    /// The number of hooks of the manifest, or `Err(HypervisorError::InvalidHookManifest)` if a line is malformed, in
+    /// which case no hook is installed.
+    pub fn initialize_shared_boot_hook_manifest(text: &str) -> Result<usize, HypervisorError> {
+        let manifest = Self::parse(text)?;
+        let hook_count = manifest.hooks.len();
+
+        debug!("Boot hook manifest: {} hooks, trigger: {:?}", hook_count, manifest.trigger);
+
+        *SHARED_BOOT_HOOK_MANIFEST.lock() = manifest;
+
+        Ok(hook_count)
+    }
+
+    /// Parses the text of a manifest.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the manifest.
+    fn parse(text: &str) -> Result<Self, HypervisorError> {
+        let mut hooks = Vec::new();
+        let mut trigger = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(arguments) = line.strip_prefix("trigger ") {
+                // A manifest has a single trigger.
+                let Some(parsed) = parse_trigger(arguments).filter(|_| trigger.is_none()) else {
+                    error!("Invalid boot hook manifest trigger on line {}: {}", index + 1, line);
+                    return Err(HypervisorError::InvalidHookManifest);
+                };
+
+                trigger = Some(parsed);
+                continue;
+            }
+
+            let Some(hook) = parse_line(line) else {
+                error!("Invalid boot hook manifest line {}: {}", index + 1, line);
+                return Err(HypervisorError::InvalidHookManifest);
+            };
+
+            if hooks.len() >= MAX_BOOT_HOOKS {
+                error!("Too many boot hooks, at most {} are supported", MAX_BOOT_HOOKS);
+                return Err(HypervisorError::InvalidHookManifest);
+            }
+
+            hooks.push(hook);
+        }
+
+        Ok(Self {
+            hooks,
+            trigger: trigger.unwrap_or_default(),
+            text: String::from(text),
+        })
+    }
+
+    /// Returns the entries of the manifest.
+    pub fn hooks(&self) -> &[BootHook] {
+        &self.hooks
+    }
+
+    /// Returns the point at which the hooks are installed.
+    pub fn trigger(&self) -> &BootHookTrigger {
+        &self.trigger
+    }
+
+    /// Returns the text the manifest has been parsed from, empty without manifest.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Parses a line of the manifest, without comment.
+///
+/// # Arguments
+///
+/// * `line` - The trimmed line.
+fn parse_line(line: &str) -> Option<BootHook> {
+    let mut tokens = line.split_whitespace();
+
+    let target = match tokens.next()? {
+        "export" => BootHookTarget::Export(tokens.next()?.to_string()),
+        "syscall" => BootHookTarget::Syscall(parse_number(tokens.next()?)?),
+        "signature" => {
+            let name = tokens.next()?.to_string();
+            let mut pattern = Vec::new();
+            let mut hook_type = None;
+
+            // The pattern extends to the hook type, or to the end of the line.
+            for token in tokens {
+                match token {
+                    "??" | "?" => pattern.push(None),
+                    token if token.len() == 2 => pattern.push(Some(u8::from_str_radix(token, 16).ok()?)),
+                    token => {
+                        hook_type = Some(parse_hook_type(token)?);
+                        break;
+                    }
+                }
+            }
+
+            // A pattern starting with a wildcard would hook a byte that isn't part of the signature.
+            if pattern.first()?.is_none() {
+                return None;
+            }
+
+            return Some(BootHook {
+                target: BootHookTarget::Signature { name, pattern },
+                hook_type: hook_type.unwrap_or(InlineHookType::Vmcall),
+            });
+        }
+        _ => return None,
+    };
+
+    let hook_type = match tokens.next() {
+        Some(token) => parse_hook_type(token)?,
+        None => InlineHookType::Vmcall,
+    };
+
+    match tokens.next() {
+        Some(_) => None,
+        None => Some(BootHook { target, hook_type }),
+    }
+}
+
+/// Parses the arguments of a `trigger` line.
+///
+/// # Arguments
+///
+/// * `arguments` - The arguments following `trigger`.
+fn parse_trigger(arguments: &str) -> Option<BootHookTrigger> {
+    let mut tokens = arguments.split_whitespace();
+
+    let trigger = match tokens.next()? {
+        "cache_information" => BootHookTrigger::CacheInformation,
+        "export" => BootHookTrigger::Export(tokens.next()?.to_string()),
+        "process_creation" => BootHookTrigger::ProcessCreation(parse_number(tokens.next()?)?),
+        "hypercall" => BootHookTrigger::Hypercall,
+        _ => return None,
+    };
+
+    match tokens.next() {
+        Some(_) => None,
+        None => Some(trigger),
+    }
+}
+
+/// Parses a number in decimal or `0x` hexadecimal.
+///
+/// # Arguments
+///
+/// * `token` - The number.
+fn parse_number(token: &str) -> Option<u16> {
+    match token.strip_prefix("0x") {
+        Some(hexadecimal) => u16::from_str_radix(hexadecimal, 16).ok(),
+        None => token.parse().ok(),
+    }
+}
+
+/// Parses an inline hook type not requiring a handler address.
+///
+/// # Arguments
+///
+/// * `token` - The name of the type.
+fn parse_hook_type(token: &str) -> Option<InlineHookType> {
+    match token {
+        "int3" => Some(InlineHookType::Int3),
+        "int3stub" => Some(InlineHookType::Int3Stub),
+        "cpuid" => Some(InlineHookType::Cpuid),
+        "vmcall" => Some(InlineHookType::Vmcall),
+        _ => None,
+    }
+}
+
+/// Arms the trigger of the manifest once the kernel base has been captured, installing the hook of its export or its
+/// system call handler.
+///
+/// This is called each time the base address of ntoskrnl.exe is captured, i.e., on each logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `hook_manager` - The hook manager, with the kernel base captured.
+pub fn arm_boot_hook_trigger(vm: &mut Vm, hook_manager: &mut HookManager) {
+    if BOOT_HOOK_STATE
+        .compare_exchange(BootHookState::Idle as u8, BootHookState::Armed as u8, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+
+    let manifest = SHARED_BOOT_HOOK_MANIFEST.lock();
+
+    if manifest.hooks.is_empty() {
+        return;
+    }
+
+    debug!("Arming boot hook trigger: {:?}", manifest.trigger);
+
+    match &manifest.trigger {
+        BootHookTrigger::Export(name) => {
+            let function_hash = djb2_hash(name.as_bytes());
+            let result = hook_manager.resolve_kernel_function(function_hash, 0).and_then(|function_va| {
+                hook_manager.register_hook_callbacks(
+                    function_va - kernel_image().base_va,
+                    HookCallbacks {
+                        on_entry: Some(handle_trigger_export_entry),
+                        on_return: None,
+                    },
+                );
+                hook_manager.ept_hook_function(vm, function_va, function_hash, EptHookType::Function(InlineHookType::Vmcall))
+            });
+
+            if let Err(e) = result {
+                error!("Failed to hook the boot hook trigger export {}: {:?}", name, e);
+            }
+        }
+        BootHookTrigger::ProcessCreation(syscall_number) => {
+            SHARED_SYSCALL_HOOK_MANAGER
+                .lock()
+                .register(*syscall_number as u32, handle_trigger_process_creation);
+        }
+        BootHookTrigger::CacheInformation | BootHookTrigger::Hypercall => {}
+    }
+}
+
+/// Fires the trigger of the manifest, if it is the given one and the hooks aren't installed yet.
+///
+/// # Arguments
+///
+/// * `matches` - Whether the trigger point reached is the trigger of the manifest.
+///
+/// # Returns
+///
+/// `true` if the hooks are now pending, `false` if the trigger doesn't match or the hooks are already pending or
+/// installed.
+pub fn fire_boot_hook_trigger(matches: impl FnOnce(&BootHookTrigger) -> bool) -> bool {
+    if BOOT_HOOK_STATE.load(Ordering::Acquire) != BootHookState::Armed as u8 || !matches(&SHARED_BOOT_HOOK_MANIFEST.lock().trigger) {
+        return false;
+    }
+
+    let fired = BOOT_HOOK_STATE
+        .compare_exchange(BootHookState::Armed as u8, BootHookState::Pending as u8, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+
+    if fired {
+        info!("Boot hook trigger fired");
+    }
+
+    fired
+}
+
+/// Fires the trigger of the manifest, whatever it is, e.g., on the request of a guest agent.
+///
+/// # Returns
+///
+/// `true` if the hooks are now pending.
+pub fn force_boot_hook_trigger() -> bool {
+    fire_boot_hook_trigger(|_| true)
+}
+
+/// Marks the hooks as pending on the first execution of the trigger export.
+///
+/// # Arguments
+///
+/// * `_context` - The context of the hooked function.
+fn handle_trigger_export_entry(_context: &mut HookContext) {
+    fire_boot_hook_trigger(|trigger| matches!(trigger, BootHookTrigger::Export(_)));
+}
+
+/// Marks the hooks as pending on the first `NtCreateUserProcess` from user mode.
+///
+/// # Arguments
+///
+/// * `_context` - The context of the system call.
+fn handle_trigger_process_creation(_context: &mut SyscallContext) -> SyscallAction {
+    fire_boot_hook_trigger(|trigger| matches!(trigger, BootHookTrigger::ProcessCreation(_)));
+    SyscallAction::Continue
+}
+
+/// Installs the pending hooks of the manifest on the current logical processor, after removing the callback or the
+/// system call handler of the trigger.
+///
+/// This is called on every VM exit, and only locks the hook manager once the trigger has fired.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_boot_hooks(vm: &mut Vm) {
+    if BOOT_HOOK_STATE.load(Ordering::Acquire) != BootHookState::Pending as u8 {
+        return;
+    }
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    // Another logical processor may have installed them while the hook manager was locked.
+    if BOOT_HOOK_STATE
+        .compare_exchange(BootHookState::Pending as u8, BootHookState::Installed as u8, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+
+    let trigger = SHARED_BOOT_HOOK_MANIFEST.lock().trigger.clone();
+
+    match trigger {
+        // The hook itself is kept, since the execution of the export may still be single-stepped over it.
+        BootHookTrigger::Export(name) => {
+            if let Ok(function_va) = hook_manager.resolve_kernel_function(djb2_hash(name.as_bytes()), 0) {
+                let function_rva = function_va - kernel_image().base_va;
+                hook_manager.unregister_hook_callbacks(function_rva);
+            }
+        }
+        BootHookTrigger::ProcessCreation(syscall_number) => {
+            SHARED_SYSCALL_HOOK_MANAGER.lock().unregister(syscall_number as u32);
+        }
+        BootHookTrigger::CacheInformation | BootHookTrigger::Hypercall => {}
+    }
+
+    apply_boot_hook_manifest(vm, &mut hook_manager);
+}
+
+/// Installs the hooks of the manifest, logging and skipping the hooks failing to install.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `hook_manager` - The hook manager, with the kernel base captured.
+fn apply_boot_hook_manifest(vm: &mut Vm, hook_manager: &mut HookManager) {
+    let hooks = SHARED_BOOT_HOOK_MANIFEST.lock().hooks.clone();
+
+    if hooks.is_empty() {
+        return;
+    }
+
+    info!("Installing {} boot hooks", hooks.len());
+
+    // The TLBs are flushed once all the hooks are installed.
+    hook_manager.begin_deferred_flush();
+
+    for hook in hooks {
+        let ept_hook_type = EptHookType::Function(hook.hook_type);
+
+        let result = match &hook.target {
+            BootHookTarget::Export(name) => match name.split_once('!') {
+                Some((module, export)) => hook_manager
+                    .resolve_kernel_export(Some(module), export)
+                    .and_then(|function_va| hook_manager.ept_hook_function(vm, function_va, djb2_hash(export.as_bytes()), ept_hook_type)),
+                None => hook_manager.manage_kernel_ept_hook(vm, djb2_hash(name.as_bytes()), 0, ept_hook_type, true),
+            },
+            // No export name hashes to 0, so the function is resolved through the SSDT.
+            BootHookTarget::Syscall(syscall_number) => hook_manager.manage_kernel_ept_hook(vm, 0, *syscall_number, ept_hook_type, true),
+            BootHookTarget::Signature { name, pattern } => Signature::new(pattern)
+                .and_then(|signature| find_kernel_signature(&signature).ok_or(HypervisorError::PatternNotFound))
+                .and_then(|function_va| hook_manager.ept_hook_function(vm, function_va, djb2_hash(name.as_bytes()), ept_hook_type)),
+        };
+
+        match result {
+            Ok(()) => debug!("Boot hook installed: {:?}", hook),
+            Err(e) => error!("Failed to install boot hook {:?}: {:?}", hook, e),
+        }
+    }
+
+    hook_manager.end_deferred_flush(vm);
+}
@@ -0,0 +1,268 @@
+//! Provides typed Rust callbacks for kernel function hooks, dispatched on the entry of the hooked function and optionally
+//! on its return, so tools built on this crate can log arguments and modify return values without writing their own
+//! assembly stubs.
+//!
+//! The callbacks are registered in the `HookManager` by relative virtual address (RVA) from the base of ntoskrnl.exe,
+//! and are dispatched for the detours handled by the hypervisor: `Vmcall`, `Int3` and `Int3Stub`. An alternate hook
+//! view can carry its own callbacks for a function (see the `hook_view` module).
+//!
+//! To call the return callback, the return address on the guest stack is replaced on entry with the address of an
+//! `int3` (0xCC) byte found in the shadow page of the hooked function, outside of any hook. When the function returns
+//! to it, the breakpoint is matched to the entry by the stack pointer, the callback is called and the guest resumes at
+//! the original return address. This isn't compatible with kernel-mode hardware-enforced stack protection (CET shadow
+//! stacks), and a return that is skipped (e.g., by an exception unwinding the stack) leaves a stale pending return.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            capture::GuestRegisters,
+            event_stream::stream_event,
+            hooks::{hook_manager::SHARED_HOOK_MANAGER, memory_manager::HookInfo},
+            support::{vmread, vmwrite},
+            vm::Vm,
+        },
+    },
+    log::*,
+    shared::EventStreamKind,
+    x86::vmx::vmcs,
+};
+
+/// The number of arguments passed in registers by the Microsoft x64 calling convention (RCX, RDX, R8 and R9).
+const REGISTER_ARGUMENT_COUNT: usize = 4;
+
+/// The offset from the stack pointer on entry to the first stack argument: the return address and the 32-byte home space.
+const STACK_ARGUMENTS_OFFSET: u64 = 0x28;
+
+/// A callback called in VMX root operation on the entry or the return of a hooked function.
+///
+/// Modifications of the registers are written back to the guest. A callback on entry which changes the RIP redirects
+/// the execution instead of running the hooked function, in which case the return callback isn't called.
+pub type HookCallback = fn(context: &mut HookContext);
+
+/// The callbacks of a hooked function.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HookCallbacks {
+    /// The callback called on the entry of the function, before its first instruction is executed.
+    pub on_entry: Option<HookCallback>,
+
+    /// The callback called when the function returns, before the caller resumes.
+    pub on_return: Option<HookCallback>,
+}
+
+/// A return of a hooked function that has been redirected to the trampoline, waiting for the function to return.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingReturn {
+    /// The virtual address of the hooked function.
+    pub function_va: u64,
+
+    /// The original return address replaced on the guest stack.
+    pub return_address: u64,
+
+    /// The stack pointer on the entry of the function.
+    pub entry_rsp: u64,
+
+    /// The virtual address of the `int3` byte the function returns to.
+    pub trampoline_va: u64,
+
+    /// The callback to call when the function returns.
+    pub on_return: HookCallback,
+}
+
+/// The context passed to a `HookCallback`.
+pub struct HookContext<'a> {
+    /// The guest registers on the entry or the return of the function.
+    pub registers: &'a mut GuestRegisters,
+
+    /// The virtual address of the hooked function.
+    pub function_va: u64,
+
+    /// The address the function returns to.
+    pub return_address: u64,
+
+    /// The stack pointer on the entry of the function, pointing to the return address.
+    pub entry_rsp: u64,
+}
+
+impl HookContext<'_> {
+    /// Returns an argument of the function, following the Microsoft x64 calling convention.
+    ///
+    /// The first four arguments are read from RCX, RDX, R8 and R9, and the next ones from the stack of the caller.
+    /// On return, the registers may have been overwritten by the function, but the stack arguments are still available.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zero-based index of the argument.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The argument, or `None` if the stack of the caller can't be read.
+    pub fn argument(&self, index: usize) -> Option<u64> {
+        match index {
+            0 => Some(self.registers.rcx),
+            1 => Some(self.registers.rdx),
+            2 => Some(self.registers.r8),
+            3 => Some(self.registers.r9),
+            _ => PhysicalAddress::read_guest_virt_with_current_cr3(self.stack_argument_address(index) as *const u64),
+        }
+    }
+
+    /// Modifies an argument of the function, following the Microsoft x64 calling convention.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zero-based index of the argument.
+    /// * `value` - The new value of the argument.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<()>` - `Some(())` if the argument has been modified, or `None` if the stack of the caller can't be written.
+    pub fn set_argument(&mut self, index: usize, value: u64) -> Option<()> {
+        match index {
+            0 => self.registers.rcx = value,
+            1 => self.registers.rdx = value,
+            2 => self.registers.r8 = value,
+            3 => self.registers.r9 = value,
+            _ => PhysicalAddress::write_guest_virt_with_current_cr3(self.stack_argument_address(index) as *mut u64, value)?,
+        }
+
+        Some(())
+    }
+
+    /// Returns the return value of the function, only meaningful in a return callback.
+    pub fn return_value(&self) -> u64 {
+        self.registers.rax
+    }
+
+    /// Modifies the return value of the function, only meaningful in a return callback.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The new return value.
+    pub fn set_return_value(&mut self, value: u64) {
+        self.registers.rax = value;
+    }
+
+    /// Returns the virtual address of a stack argument of the function.
+    fn stack_argument_address(&self, index: usize) -> u64 {
+        self.entry_rsp + STACK_ARGUMENTS_OFFSET + ((index - REGISTER_ARGUMENT_COUNT) * core::mem::size_of::<u64>()) as u64
+    }
+}
+
+/// Dispatches the callbacks registered for a hooked function on its entry.
+///
+/// The entry callback is called without holding the hook manager lock, so it can use the hook manager itself. If a
+/// return callback is registered, the return address is redirected to the trampoline of the function. Every hit is
+/// pushed to the event stream of the guest agent, with or without callbacks.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `hook_info` - The information of the hook that has been hit.
+///
+/// # Returns
+///
+/// * `Ok(true)` - If the entry callback redirected the execution, so the hooked function must not be single-stepped.
+/// * `Ok(false)` - If the hooked function must be single-stepped, including when no callback is registered.
+pub fn dispatch_hook_entry(vm: &mut Vm, hook_info: &HookInfo) -> Result<bool, HypervisorError> {
+    stream_event(EventStreamKind::HookHit, [hook_info.guest_function_va, vmread(vmcs::guest::CR3), vm.guest_registers.rsp, 0]);
+
+    let callbacks = SHARED_HOOK_MANAGER
+        .lock()
+        .get_hook_callbacks(hook_info.guest_function_va, vm.hook_view.active_view);
+
+    let Some(callbacks) = callbacks else {
+        return Ok(false);
+    };
+
+    let guest_rip = vm.guest_registers.rip;
+    let entry_rsp = vm.guest_registers.rsp;
+    let return_address =
+        PhysicalAddress::read_guest_virt_with_current_cr3(entry_rsp as *const u64).ok_or(HypervisorError::VirtualToPhysicalAddressFailed)?;
+
+    if let Some(on_entry) = callbacks.on_entry {
+        trace!("Dispatching to entry callback for function at VA: {:#x}", hook_info.guest_function_va);
+
+        on_entry(&mut HookContext {
+            registers: &mut vm.guest_registers,
+            function_va: hook_info.guest_function_va,
+            return_address,
+            entry_rsp,
+        });
+
+        write_back_guest_registers(vm);
+
+        if vm.guest_registers.rip != guest_rip {
+            trace!("Entry callback redirected execution to: {:#x}", vm.guest_registers.rip);
+            return Ok(true);
+        }
+    }
+
+    if let Some(on_return) = callbacks.on_return {
+        let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+        let Some(trampoline_va) = hook_manager.find_return_trampoline(hook_info) else {
+            warn!("No return trampoline found for function at VA: {:#x}", hook_info.guest_function_va);
+            return Ok(false);
+        };
+
+        PhysicalAddress::write_guest_virt_with_current_cr3(entry_rsp as *mut u64, trampoline_va)
+            .ok_or(HypervisorError::VirtualToPhysicalAddressFailed)?;
+
+        trace!("Redirecting return of function at VA: {:#x} to trampoline: {:#x}", hook_info.guest_function_va, trampoline_va);
+
+        hook_manager.add_pending_return(PendingReturn {
+            function_va: hook_info.guest_function_va,
+            return_address,
+            entry_rsp,
+            trampoline_va,
+            on_return,
+        });
+    }
+
+    Ok(false)
+}
+
+/// Dispatches the return callback of a hooked function, if the breakpoint is a redirected return.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+///
+/// # Returns
+///
+/// * `true` - If the breakpoint was a redirected return, and the guest resumes at the original return address.
+/// * `false` - If the breakpoint isn't a redirected return.
+pub fn dispatch_hook_return(vm: &mut Vm) -> bool {
+    // The return address has been popped, so the stack pointer is one slot above the one on entry.
+    let entry_rsp = vm.guest_registers.rsp - core::mem::size_of::<u64>() as u64;
+
+    let pending_return = SHARED_HOOK_MANAGER.lock().take_pending_return(entry_rsp, vm.guest_registers.rip);
+
+    let Some(pending_return) = pending_return else {
+        return false;
+    };
+
+    trace!("Dispatching to return callback for function at VA: {:#x}", pending_return.function_va);
+
+    vm.guest_registers.rip = pending_return.return_address;
+
+    (pending_return.on_return)(&mut HookContext {
+        registers: &mut vm.guest_registers,
+        function_va: pending_return.function_va,
+        return_address: pending_return.return_address,
+        entry_rsp,
+    });
+
+    write_back_guest_registers(vm);
+
+    true
+}
+
+/// Writes the registers that aren't restored from `GuestRegisters` on VM entry back to the VMCS.
+pub fn write_back_guest_registers(vm: &Vm) {
+    vmwrite(vmcs::guest::RIP, vm.guest_registers.rip);
+    vmwrite(vmcs::guest::RSP, vm.guest_registers.rsp);
+    vmwrite(vmcs::guest::RFLAGS, vm.guest_registers.rflags);
+}
@@ -0,0 +1,431 @@
+//! Provides a registry of CPUID overrides, applied by the CPUID VM exit handler to the results of the host `CPUID`.
+//!
+//! An override is registered for a leaf and either a specific subleaf or every subleaf of the leaf, and either replaces
+//! the registers, masks and sets bits of them (e.g., to hide features or change the vendor string), or calls a
+//! callback deciding the result. An override for a specific subleaf takes precedence over the override of the whole leaf.
+//!
+//! The registry is initialized with the built-in override of the hypervisor-present bit, which can be replaced or
+//! removed at runtime like any other override.
+//!
+//! The hypervisor leaves (0x40000000-0x400000FF) follow the hypervisor presence, selected at build time with the
+//! `expose_hypervisor` feature and changed at runtime: while hidden, they return the results of the highest basic
+//! leaf, as processors do for leaves above their maximum, and the hypervisor-present bit is clear; while exposed, they
+//! report the "Illusion" vendor signature and version, and the hypervisor-present bit is set, for cooperative guests
+//! and tooling. The overrides apply on top of these results.
+//!
+//! Each change of the registry is published, and each logical processor copies the overrides and the presence on its
+//! next VM exit, so the CPUID VM exits read them without locking the registry.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            seqlock::{Generation, Published},
+            vm::Vm,
+            vmexit::cpuid::FeatureBits,
+        },
+    },
+    alloc::collections::BTreeMap,
+    core::ops::RangeInclusive,
+    lazy_static::lazy_static,
+    log::*,
+    shared::HypervisorPresence,
+    spin::Mutex,
+    x86::cpuid::{cpuid, CpuIdResult},
+};
+
+/// The CPUID leaves reserved for hypervisors.
+pub const HYPERVISOR_LEAF_RANGE: RangeInclusive<u32> = 0x40000000..=0x400000FF;
+
+/// The maximum number of CPUID overrides registered at the same time, copied to each logical processor.
+pub const MAX_CPUID_HOOKS: usize = 32;
+
+/// The changes of the overrides and the hypervisor presence, published while the registry is locked.
+static CPUID_HOOK_CHANGES: Published<()> = Published::new(());
+
+/// The highest hypervisor leaf reported while the hypervisor is exposed: the vendor, interface and version leaves.
+const HYPERVISOR_MAX_LEAF: u32 = 0x40000002;
+
+/// The vendor signature "Illusion" reported in EBX, ECX and EDX of leaf 0x40000000 while the hypervisor is exposed.
+const HYPERVISOR_VENDOR_SIGNATURE: [u32; 3] = [0x756c6c49, 0x6e6f6973, 0x00000000];
+
+/// The version reported in EAX of leaf 0x40000002 while the hypervisor is exposed, the major version in the high 16 bits.
+const HYPERVISOR_VERSION: u32 = (parse_version(env!("CARGO_PKG_VERSION_MAJOR")) << 16) | parse_version(env!("CARGO_PKG_VERSION_MINOR"));
+
+/// The hypervisor presence selected at build time.
+const DEFAULT_HYPERVISOR_PRESENCE: HypervisorPresence = match cfg!(feature = "expose_hypervisor") {
+    true => HypervisorPresence::Exposed,
+    false => HypervisorPresence::Hidden,
+};
+
+/// A callback called in VMX root operation when the guest executes `CPUID` for an overridden leaf.
+///
+/// `result` is the result of the host `CPUID` on entry, and is returned to the guest.
+///
+/// The callback is called without the registry locked, so it may register or unregister overrides, which apply from
+/// the next VM exit.
+pub type CpuidHookCallback = fn(vm: &mut Vm, leaf: u32, sub_leaf: u32, result: &mut CpuIdResult) -> Result<(), HypervisorError>;
+
+/// An override of a CPUID leaf, the registers being in the order EAX, EBX, ECX, EDX.
+#[derive(Debug, Clone, Copy)]
+pub enum CpuidHook {
+    /// The registers are replaced.
+    Replace([u32; 4]),
+
+    /// Each register is masked with `and_mask`, then combined with `or_mask`.
+    Modify { and_mask: [u32; 4], or_mask: [u32; 4] },
+
+    /// The callback decides the result.
+    Callback(CpuidHookCallback),
+}
+
+impl CpuidHook {
+    /// Creates the override clearing bits of the registers.
+    ///
+    /// # Arguments
+    ///
+    /// * `clear_mask` - The bits to clear in each register.
+    pub fn clear_bits(clear_mask: [u32; 4]) -> Self {
+        Self::Modify {
+            and_mask: clear_mask.map(|mask| !mask),
+            or_mask: [0; 4],
+        }
+    }
+
+    /// Combines an override applied after this one, composing the masks of two `Modify` overrides and keeping the new
+    /// override otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `next` - The override applied after this one.
+    pub fn then(self, next: CpuidHook) -> CpuidHook {
+        match (self, next) {
+            (
+                CpuidHook::Modify { and_mask, or_mask },
+                CpuidHook::Modify {
+                    and_mask: next_and_mask,
+                    or_mask: next_or_mask,
+                },
+            ) => CpuidHook::Modify {
+                and_mask: core::array::from_fn(|index| and_mask[index] & next_and_mask[index]),
+                or_mask: core::array::from_fn(|index| (or_mask[index] & next_and_mask[index]) | next_or_mask[index]),
+            },
+            (CpuidHook::Replace(registers), CpuidHook::Modify { and_mask, or_mask }) => {
+                CpuidHook::Replace(core::array::from_fn(|index| (registers[index] & and_mask[index]) | or_mask[index]))
+            }
+            (_, next) => next,
+        }
+    }
+}
+
+/// Manages the overrides of the CPUID leaves.
+#[derive(Debug)]
+pub struct CpuidHookManager {
+    /// The overrides, by leaf and subleaf, `None` for every subleaf of the leaf.
+    hooks: BTreeMap<(u32, Option<u32>), CpuidHook>,
+
+    /// Whether the hypervisor leaves and the hypervisor-present bit reveal the hypervisor.
+    presence: HypervisorPresence,
+}
+
+lazy_static! {
+    /// A globally shared instance of `CpuidHookManager`, protected by a mutex.
+    ///
+    /// The registry is initialized with the built-in override of the hypervisor-present bit of leaf 1, following the
+    /// hypervisor presence selected at build time.
+    pub static ref SHARED_CPUID_HOOK_MANAGER: Mutex<CpuidHookManager> = Mutex::new(CpuidHookManager::new());
+}
+
+impl CpuidHookManager {
+    /// Creates a new registry with the built-in overrides.
+    fn new() -> Self {
+        let mut cpuid_hook_manager = Self {
+            hooks: BTreeMap::new(),
+            presence: DEFAULT_HYPERVISOR_PRESENCE,
+        };
+
+        cpuid_hook_manager
+            .hooks
+            .insert((1, None), hypervisor_present_bit_hook(DEFAULT_HYPERVISOR_PRESENCE));
+
+        cpuid_hook_manager
+    }
+
+    /// Changes whether the hypervisor leaves and the hypervisor-present bit reveal the hypervisor.
+    ///
+    /// The hypervisor-present bit is composed with the current override of leaf 1, e.g., keeping VMX support hidden.
+    ///
+    /// # Arguments
+    ///
+    /// * `presence` - The hypervisor presence.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the presence has been changed, or `Err(HypervisorError::TooManyCpuidHooks)` if leaf 1 has no override
+    /// and `MAX_CPUID_HOOKS` overrides are registered.
+    pub fn set_presence(&mut self, presence: HypervisorPresence) -> Result<(), HypervisorError> {
+        debug!("Setting hypervisor presence: {:?}", presence);
+
+        let hook = match self.get(1, None) {
+            Some(current_hook) => current_hook.then(hypervisor_present_bit_hook(presence)),
+            None => hypervisor_present_bit_hook(presence),
+        };
+
+        self.register(1, None, hook)?;
+        self.presence = presence;
+        CPUID_HOOK_CHANGES.publish(());
+
+        Ok(())
+    }
+
+    /// Returns whether the hypervisor leaves and the hypervisor-present bit reveal the hypervisor.
+    pub fn presence(&self) -> HypervisorPresence {
+        self.presence
+    }
+
+    /// Registers an override for a CPUID leaf, replacing the previous override of the leaf and subleaf.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf to override.
+    /// * `sub_leaf` - The subleaf to override, or `None` for every subleaf of the leaf.
+    /// * `hook` - The override.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the override has been registered, or `Err(HypervisorError::TooManyCpuidHooks)` if the leaf and
+    /// subleaf have no override and `MAX_CPUID_HOOKS` overrides are registered.
+    pub fn register(&mut self, leaf: u32, sub_leaf: Option<u32>, hook: CpuidHook) -> Result<(), HypervisorError> {
+        debug!("Registering CPUID override: {:#x} {:x?}: {:x?}", leaf, sub_leaf, hook);
+
+        if !self.hooks.contains_key(&(leaf, sub_leaf)) && self.hooks.len() >= MAX_CPUID_HOOKS {
+            return Err(HypervisorError::TooManyCpuidHooks);
+        }
+
+        self.hooks.insert((leaf, sub_leaf), hook);
+        CPUID_HOOK_CHANGES.publish(());
+
+        Ok(())
+    }
+
+    /// Unregisters the override of a CPUID leaf.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The overridden leaf.
+    /// * `sub_leaf` - The overridden subleaf, or `None` for every subleaf of the leaf.
+    ///
+    /// # Returns
+    ///
+    /// The override that was registered, if any.
+    pub fn unregister(&mut self, leaf: u32, sub_leaf: Option<u32>) -> Option<CpuidHook> {
+        debug!("Unregistering CPUID override: {:#x} {:x?}", leaf, sub_leaf);
+
+        let hook = self.hooks.remove(&(leaf, sub_leaf));
+        CPUID_HOOK_CHANGES.publish(());
+
+        hook
+    }
+
+    /// Returns the override registered for a CPUID leaf and subleaf, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf.
+    /// * `sub_leaf` - The subleaf, or `None` for every subleaf of the leaf.
+    pub fn get(&self, leaf: u32, sub_leaf: Option<u32>) -> Option<CpuidHook> {
+        self.hooks.get(&(leaf, sub_leaf)).copied()
+    }
+
+    /// Returns the registered overrides, by leaf and subleaf, `None` for every subleaf of the leaf.
+    pub fn hooks(&self) -> impl Iterator<Item = (u32, Option<u32>, CpuidHook)> + '_ {
+        self.hooks.iter().map(|(&(leaf, sub_leaf), &hook)| (leaf, sub_leaf, hook))
+    }
+}
+
+/// The overrides and the hypervisor presence in use on a logical processor, copied from the registry.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorCpuidHooks {
+    /// The generation of the registry in use on this logical processor.
+    generation: Generation,
+
+    /// The overrides, by leaf and subleaf, `None` for every subleaf of the leaf.
+    hooks: [Option<(u32, Option<u32>, CpuidHook)>; MAX_CPUID_HOOKS],
+
+    /// The filter of the overridden leaves: the bit of each leaf, modulo 64, is set while one of the leaves sharing it
+    /// has an override.
+    leaf_filter: u64,
+
+    /// Whether the hypervisor leaves and the hypervisor-present bit reveal the hypervisor.
+    presence: HypervisorPresence,
+}
+
+impl ProcessorCpuidHooks {
+    /// Creates the state of a logical processor, copying the registry on its first VM exit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks if the results of a CPUID leaf may be changed by `apply_cpuid_hook`, without looking up its override.
+    ///
+    /// A leaf sharing its bit of the filter with an overridden leaf may be reported as overridden, but an overridden
+    /// leaf and the hypervisor leaves are always reported as overridden.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf requested by the guest in EAX.
+    pub fn may_be_overridden(&self, leaf: u32) -> bool {
+        self.leaf_filter & (1 << (leaf % 64)) != 0 || HYPERVISOR_LEAF_RANGE.contains(&leaf)
+    }
+
+    /// Returns the override in use for a CPUID leaf and subleaf, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf.
+    /// * `sub_leaf` - The subleaf, or `None` for every subleaf of the leaf.
+    fn get(&self, leaf: u32, sub_leaf: Option<u32>) -> Option<CpuidHook> {
+        self.hooks
+            .iter()
+            .flatten()
+            .find(|&&(hook_leaf, hook_sub_leaf, _)| hook_leaf == leaf && hook_sub_leaf == sub_leaf)
+            .map(|&(_, _, hook)| hook)
+    }
+}
+
+impl Default for ProcessorCpuidHooks {
+    /// Returns the state of a logical processor not having copied the registry yet.
+    fn default() -> Self {
+        Self {
+            generation: Generation::STALE,
+            hooks: [None; MAX_CPUID_HOOKS],
+            leaf_filter: 0,
+            presence: DEFAULT_HYPERVISOR_PRESENCE,
+        }
+    }
+}
+
+/// Copies the overrides and the hypervisor presence to the current logical processor, if the registry changed.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_cpuid_hooks(vm: &mut Vm) {
+    if CPUID_HOOK_CHANGES.sync(&mut vm.cpuid_hooks.generation).is_none() {
+        return;
+    }
+
+    let cpuid_hook_manager = SHARED_CPUID_HOOK_MANAGER.lock();
+
+    vm.cpuid_hooks.hooks = [None; MAX_CPUID_HOOKS];
+    vm.cpuid_hooks.leaf_filter = 0;
+    for (hook, registered_hook) in vm.cpuid_hooks.hooks.iter_mut().zip(cpuid_hook_manager.hooks()) {
+        *hook = Some(registered_hook);
+        vm.cpuid_hooks.leaf_filter |= 1 << (registered_hook.0 % 64);
+    }
+
+    vm.cpuid_hooks.presence = cpuid_hook_manager.presence;
+}
+
+/// Applies the override of a CPUID leaf, if any, to the result of the host `CPUID`.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `leaf` - The leaf requested by the guest in EAX.
+/// * `sub_leaf` - The subleaf requested by the guest in ECX.
+/// * `result` - The result of the host `CPUID`, returned to the guest.
+///
+/// # Returns
+///
+/// `Ok(())` if the override has been applied or there is none, or `Err(HypervisorError)` if the callback failed.
+pub fn apply_cpuid_hook(vm: &mut Vm, leaf: u32, sub_leaf: u32, result: &mut CpuIdResult) -> Result<(), HypervisorError> {
+    let hook = vm.cpuid_hooks.get(leaf, Some(sub_leaf)).or_else(|| vm.cpuid_hooks.get(leaf, None));
+
+    if HYPERVISOR_LEAF_RANGE.contains(&leaf) {
+        *result = hypervisor_leaf(vm.cpuid_hooks.presence, leaf, sub_leaf);
+    }
+
+    let Some(hook) = hook else {
+        return Ok(());
+    };
+
+    trace!("Applying CPUID override: {:#x} {:#x}: {:x?}", leaf, sub_leaf, hook);
+
+    let registers = [&mut result.eax, &mut result.ebx, &mut result.ecx, &mut result.edx];
+
+    match hook {
+        CpuidHook::Replace(values) => {
+            for (register, value) in registers.into_iter().zip(values) {
+                *register = value;
+            }
+        }
+        CpuidHook::Modify { and_mask, or_mask } => {
+            for ((register, and_mask), or_mask) in registers.into_iter().zip(and_mask).zip(or_mask) {
+                *register = (*register & and_mask) | or_mask;
+            }
+        }
+        CpuidHook::Callback(callback) => return callback(vm, leaf, sub_leaf, result),
+    }
+
+    Ok(())
+}
+
+/// Returns the override of leaf 1 setting or clearing the hypervisor-present bit.
+///
+/// # Arguments
+///
+/// * `presence` - The hypervisor presence.
+fn hypervisor_present_bit_hook(presence: HypervisorPresence) -> CpuidHook {
+    let hypervisor_present_bit = 1 << FeatureBits::HypervisorPresentBit as u32;
+
+    match presence {
+        HypervisorPresence::Hidden => CpuidHook::clear_bits([0, 0, hypervisor_present_bit, 0]),
+        HypervisorPresence::Exposed => CpuidHook::Modify {
+            and_mask: [u32::MAX; 4],
+            or_mask: [0, 0, hypervisor_present_bit, 0],
+        },
+    }
+}
+
+/// Returns the results of a hypervisor leaf, before the overrides.
+///
+/// # Arguments
+///
+/// * `presence` - The hypervisor presence.
+/// * `leaf` - The hypervisor leaf.
+/// * `sub_leaf` - The subleaf.
+fn hypervisor_leaf(presence: HypervisorPresence, leaf: u32, sub_leaf: u32) -> CpuIdResult {
+    if presence == HypervisorPresence::Hidden {
+        // Processors return the results of the highest basic leaf for the leaves above their maximum.
+        let max_basic_leaf = cpuid!(0).eax;
+        return cpuid!(max_basic_leaf, sub_leaf);
+    }
+
+    let [eax, ebx, ecx, edx] = match leaf {
+        0x40000000 => [
+            HYPERVISOR_MAX_LEAF,
+            HYPERVISOR_VENDOR_SIGNATURE[0],
+            HYPERVISOR_VENDOR_SIGNATURE[1],
+            HYPERVISOR_VENDOR_SIGNATURE[2],
+        ],
+        // The interface signature isn't "Hv#1", so the guest doesn't use the Hyper-V enlightenments.
+        0x40000001 => [0; 4],
+        0x40000002 => [HYPERVISOR_VERSION, 0, 0, 0],
+        _ => [0; 4],
+    };
+
+    CpuIdResult { eax, ebx, ecx, edx }
+}
+
+/// Parses a version component of the crate at compile time.
+///
+/// # Arguments
+///
+/// * `component` - The decimal version component.
+const fn parse_version(component: &str) -> u32 {
+    match u32::from_str_radix(component, 10) {
+        Ok(value) => value,
+        Err(_) => 0,
+    }
+}
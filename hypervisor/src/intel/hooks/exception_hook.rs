@@ -0,0 +1,406 @@
+//! Provides a registry of exception hooks, consulted by the exception VM exit handler before an intercepted exception
+//! is reflected to the guest.
+//!
+//! A hook is registered for an exception vector with a callback deciding whether the exception is handled in VMX root
+//! operation or reflected to the guest. The vectors of the hooks are intercepted in the exception bitmap of each
+//! logical processor at its first VM exit after the registry changed, and vectors can also be intercepted without a
+//! hook, in which case their exceptions are only reflected. The exceptions consumed by the built-in features, e.g., the
+//! breakpoints of the inline hooks or the hits of the hypervisor breakpoints, aren't passed to the hooks.
+//!
+//! The interception of #DB and #BP is never disabled by the registry, as the built-in features rely on it, and the
+//! interception of the vectors recorded by the exception telemetry is left to it. With #PF intercepted, only the page
+//! faults passing the error code filter of the exception telemetry cause VM exits.
+//!
+//! An exception reflected while it occurred during the delivery of another event, e.g., a page fault on the stack of
+//! an interrupt handler, isn't injected: the original event is re-injected with the interception of the vector disabled
+//! until the next VM exit, so the guest takes the exception from the processor with the correct event ordering.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            exception_telemetry::SHARED_EXCEPTION_TELEMETRY,
+            hooks::callbacks::write_back_guest_registers,
+            seqlock::{Generation, Published},
+            support::{vmread, vmwrite},
+            vm::Vm,
+            vmerror::{ExceptionInterrupt, InterruptionType, VmExitInterruptionInformation},
+        },
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::vmx::vmcs,
+};
+
+/// The number of vectors of the exception bitmap.
+const EXCEPTION_VECTOR_COUNT: usize = 32;
+
+/// The vectors that can be intercepted with the exception bitmap: the architectural exceptions, without the NMIs,
+/// which are intercepted with the NMI exiting control instead, and the reserved vectors.
+pub const HOOKABLE_EXCEPTION_VECTORS: u32 = 0x003F_FFFF & !(1 << ExceptionInterrupt::NonMaskableInterrupt as u32) & !(1 << 15);
+
+/// The vectors whose interception is never disabled by the registry, as the built-in features rely on it.
+const RESERVED_EXCEPTION_VECTORS: u32 = (1 << ExceptionInterrupt::Debug as u32) | (1 << ExceptionInterrupt::Breakpoint as u32);
+
+/// Bit 31 of the IDT-vectoring information, set if the VM exit occurred during the delivery of an event.
+const IDT_VECTORING_INFO_VALID: u64 = 1 << 31;
+
+/// The vectors intercepted by the registry, published each time a hook or an interception changes.
+static INTERCEPTED_EXCEPTION_VECTORS: Published<u32> = Published::new(0);
+
+/// A callback called in VMX root operation when the guest raises an exception of a hooked vector.
+///
+/// The guest registers may be modified, and are written back to the guest if the exception is handled, e.g., to skip
+/// the faulting instruction by advancing RIP.
+///
+/// The callback is called without the registry locked, so it may register or unregister hooks.
+pub type ExceptionHookCallback = fn(vm: &mut Vm, exception: &InterceptedException) -> Result<ExceptionHookResult, HypervisorError>;
+
+/// How an intercepted exception is completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionHookResult {
+    /// The exception has been handled, and the guest resumes without it.
+    Handled,
+
+    /// The exception is reflected to the guest, as if it hadn't been intercepted.
+    Reflect,
+}
+
+/// The exit qualification of an intercepted exception, decoded for the vectors that define it.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-1. Exit Qualification for Debug Exceptions
+/// and 28.2.1 Basic VM-Exit Information
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionQualification {
+    /// The exception has no exit qualification.
+    None,
+
+    /// A page fault, with its faulting linear address, which the processor doesn't write to CR2.
+    PageFault { linear_address: u64 },
+
+    /// A debug exception, with the conditions the processor doesn't write to DR6.
+    Debug {
+        /// The breakpoints whose conditions were met (B0 to B3), whether or not they are enabled in DR7.
+        breakpoints: u8,
+
+        /// A debug register access was detected while DR7.GD was set (BD).
+        debug_register_access: bool,
+
+        /// A single-step trap, or a branch trap with the BTF flag of IA32_DEBUGCTL set (BS).
+        single_step: bool,
+
+        /// The debug exception occurred in an RTM region (RTM), in which case the bit is set, unlike in DR6.
+        rtm: bool,
+    },
+}
+
+impl ExceptionQualification {
+    /// Decodes the exit qualification of an intercepted exception.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the exception.
+    /// * `exit_qualification` - The exit qualification of the VM exit.
+    pub fn decode(vector: ExceptionInterrupt, exit_qualification: u64) -> Self {
+        match vector {
+            ExceptionInterrupt::PageFault => Self::PageFault {
+                linear_address: exit_qualification,
+            },
+            ExceptionInterrupt::Debug => Self::Debug {
+                breakpoints: (exit_qualification & 0xF) as u8,
+                debug_register_access: exit_qualification & (1 << 13) != 0,
+                single_step: exit_qualification & (1 << 14) != 0,
+                rtm: exit_qualification & (1 << 16) != 0,
+            },
+            _ => Self::None,
+        }
+    }
+}
+
+/// An intercepted exception, decoded from the VM-exit information fields.
+#[derive(Debug, Clone, Copy)]
+pub struct InterceptedException {
+    /// The vector of the exception.
+    pub vector: ExceptionInterrupt,
+
+    /// The type of the exception, e.g., a software exception for `int3` and `into`.
+    pub interruption_type: InterruptionType,
+
+    /// The error code of the exception, if it delivers one.
+    pub error_code: Option<u32>,
+
+    /// The raw exit qualification, in the format of DR6 for debug exceptions.
+    pub exit_qualification: u64,
+
+    /// The decoded exit qualification.
+    pub qualification: ExceptionQualification,
+
+    /// The guest RIP, of the faulting instruction for faults, or of the next instruction for traps.
+    pub guest_rip: u64,
+
+    /// The length of the instruction raising a software exception, 0 for the other types.
+    pub instruction_length: u64,
+
+    /// The IDT-vectoring information of the event during whose delivery the exception occurred, if any.
+    pub idt_vectoring_info: Option<u64>,
+
+    /// Whether the exception unblocked NMIs with an IRET, which must be blocked again before it's reflected.
+    pub nmi_unblocking_due_to_iret: bool,
+}
+
+impl InterceptedException {
+    /// Decodes the exception of the current `ExceptionOrNmi` VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    ///
+    /// # Returns
+    ///
+    /// The exception, or `None` if the interruption information is invalid or the vector isn't an exception.
+    pub fn from_vmexit(vm: &Vm) -> Option<Self> {
+        let interruption_info = VmExitInterruptionInformation::from_u32(vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO) as u32)?;
+        let vector = ExceptionInterrupt::from_u32(interruption_info.vector.into())?;
+        let exit_qualification = vmread(vmcs::ro::EXIT_QUALIFICATION);
+        let idt_vectoring_info = vmread(vmcs::ro::IDT_VECTORING_INFO);
+
+        let instruction_length = match interruption_info.interruption_type {
+            InterruptionType::SoftwareInterrupt | InterruptionType::PrivilegedSoftwareException | InterruptionType::SoftwareException => {
+                vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN)
+            }
+            _ => 0,
+        };
+
+        Some(Self {
+            vector,
+            interruption_type: interruption_info.interruption_type,
+            error_code: interruption_info
+                .error_code_valid
+                .then(|| vmread(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE) as u32),
+            exit_qualification,
+            qualification: ExceptionQualification::decode(vector, exit_qualification),
+            guest_rip: vm.guest_registers.rip,
+            instruction_length,
+            idt_vectoring_info: (idt_vectoring_info & IDT_VECTORING_INFO_VALID != 0).then_some(idt_vectoring_info),
+            nmi_unblocking_due_to_iret: interruption_info.nmi_unblocking_due_to_iret,
+        })
+    }
+
+    /// Returns the same exception with another exit qualification, e.g., without the conditions consumed by the
+    /// built-in features.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_qualification` - The new exit qualification.
+    pub fn with_exit_qualification(&self, exit_qualification: u64) -> Self {
+        Self {
+            exit_qualification,
+            qualification: ExceptionQualification::decode(self.vector, exit_qualification),
+            ..*self
+        }
+    }
+}
+
+/// Manages the hooks and the interception of the exceptions.
+#[derive(Debug)]
+pub struct ExceptionHookManager {
+    /// The hooks, by vector.
+    hooks: [Option<ExceptionHookCallback>; EXCEPTION_VECTOR_COUNT],
+
+    /// The vectors intercepted without a hook, whose exceptions are reflected to the guest.
+    intercepted_vectors: u32,
+}
+
+lazy_static! {
+    /// A globally shared instance of `ExceptionHookManager`, protected by a mutex.
+    pub static ref SHARED_EXCEPTION_HOOK_MANAGER: Mutex<ExceptionHookManager> = Mutex::new(ExceptionHookManager::new());
+}
+
+impl ExceptionHookManager {
+    /// Creates a new registry, without hooks.
+    fn new() -> Self {
+        Self {
+            hooks: [None; EXCEPTION_VECTOR_COUNT],
+            intercepted_vectors: 0,
+        }
+    }
+
+    /// Registers a hook for an exception vector, replacing the previous hook of the vector, and intercepts it.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector to hook.
+    /// * `callback` - The callback deciding how the exceptions are handled.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the hook has been registered, or `Err(HypervisorError::InvalidExceptionVector)` if the vector isn't
+    /// in `HOOKABLE_EXCEPTION_VECTORS`.
+    pub fn register(&mut self, vector: ExceptionInterrupt, callback: ExceptionHookCallback) -> Result<(), HypervisorError> {
+        Self::validate_vectors(1 << vector as u32)?;
+
+        debug!("Registering exception hook: {:?}", vector);
+
+        self.hooks[vector as usize] = Some(callback);
+        self.publish();
+
+        Ok(())
+    }
+
+    /// Unregisters the hook of an exception vector, which stops being intercepted unless intercepted without a hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The hooked vector.
+    ///
+    /// # Returns
+    ///
+    /// The callback that was registered, if any.
+    pub fn unregister(&mut self, vector: ExceptionInterrupt) -> Option<ExceptionHookCallback> {
+        debug!("Unregistering exception hook: {:?}", vector);
+
+        let callback = self.hooks.get_mut(vector as usize)?.take();
+        self.publish();
+        callback
+    }
+
+    /// Sets the bits of the exception bitmap intercepting vectors without a hook, whose exceptions are reflected to the
+    /// guest, e.g., to count them with the benchmark.
+    ///
+    /// # Arguments
+    ///
+    /// * `vectors` - The mask of the intercepted vectors, replacing the previous one.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the interception has been changed, or `Err(HypervisorError::InvalidExceptionVector)` if a vector
+    /// isn't in `HOOKABLE_EXCEPTION_VECTORS`.
+    pub fn set_intercepted_vectors(&mut self, vectors: u32) -> Result<(), HypervisorError> {
+        Self::validate_vectors(vectors)?;
+
+        debug!("Intercepted exception vectors set: {:#x}", vectors);
+
+        self.intercepted_vectors = vectors;
+        self.publish();
+
+        Ok(())
+    }
+
+    /// Returns the mask of the vectors intercepted by the registry, with or without a hook.
+    pub fn intercepted_vectors(&self) -> u32 {
+        self.hooks
+            .iter()
+            .enumerate()
+            .filter(|(_, hook)| hook.is_some())
+            .fold(self.intercepted_vectors, |vectors, (vector, _)| vectors | (1 << vector))
+    }
+
+    /// Checks that vectors can be intercepted with the exception bitmap.
+    ///
+    /// # Arguments
+    ///
+    /// * `vectors` - The mask of the vectors.
+    fn validate_vectors(vectors: u32) -> Result<(), HypervisorError> {
+        if vectors & !HOOKABLE_EXCEPTION_VECTORS != 0 {
+            error!("Exception vectors can't be intercepted: {:#x}", vectors & !HOOKABLE_EXCEPTION_VECTORS);
+            return Err(HypervisorError::InvalidExceptionVector);
+        }
+
+        Ok(())
+    }
+
+    /// Publishes the intercepted vectors, which the logical processors pick up on their next VM exit.
+    fn publish(&self) {
+        INTERCEPTED_EXCEPTION_VECTORS.publish(self.intercepted_vectors());
+    }
+}
+
+/// The exception hook state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorExceptionHooks {
+    /// The generation of the vectors applied to the exception bitmap of this logical processor, stale while the
+    /// interception of a vector is disabled to re-inject an event, so it's restored on the next VM exit.
+    generation: Generation,
+
+    /// The vectors intercepted by the registry in the exception bitmap of this logical processor.
+    pub vectors: u32,
+}
+
+impl ProcessorExceptionHooks {
+    /// Creates a new processor state, without interception.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Applies the changes of the registry to the exception bitmap of the current logical processor, or restores the
+/// interception disabled to re-inject an event.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_exception_hooks(vm: &mut Vm) {
+    let mut generation = vm.exception_hooks.generation;
+    let Some(vectors) = INTERCEPTED_EXCEPTION_VECTORS.sync(&mut generation) else {
+        return;
+    };
+
+    // The vectors no longer intercepted by the registry keep the interception of the other features.
+    let telemetry_vectors = SHARED_EXCEPTION_TELEMETRY.lock().recorded_vectors();
+    let released_vectors = vm.exception_hooks.vectors & !vectors & !RESERVED_EXCEPTION_VECTORS & !telemetry_vectors;
+
+    let exception_bitmap = (vmread(vmcs::control::EXCEPTION_BITMAP) as u32 & !released_vectors) | vectors;
+    vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+
+    trace!("Exception bitmap on this processor: {:#x}", exception_bitmap);
+
+    vm.exception_hooks = ProcessorExceptionHooks { generation, vectors };
+}
+
+/// Disables the interception of a vector until the next VM exit, to re-inject the event during whose delivery its
+/// exception occurred without intercepting the exception again.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `vector` - The vector of the exception.
+pub fn disarm_exception_hook(vm: &mut Vm, vector: ExceptionInterrupt) {
+    let exception_bitmap = vmread(vmcs::control::EXCEPTION_BITMAP) & !(1u64 << vector as u32);
+    vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+    vm.exception_hooks.generation = Generation::STALE;
+    vm.published_generation = Generation::STALE;
+}
+
+/// Dispatches an intercepted exception to the hook of its vector, if any.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `exception` - The intercepted exception.
+///
+/// # Returns
+///
+/// `Ok(Some(ExceptionHookResult))` if the vector is hooked, `Ok(None)` otherwise, or `Err(HypervisorError)` if the
+/// callback failed.
+pub fn dispatch_exception_hook(vm: &mut Vm, exception: &InterceptedException) -> Result<Option<ExceptionHookResult>, HypervisorError> {
+    let Some(callback) = SHARED_EXCEPTION_HOOK_MANAGER
+        .lock()
+        .hooks
+        .get(exception.vector as usize)
+        .copied()
+        .flatten()
+    else {
+        return Ok(None);
+    };
+
+    trace!("Dispatching exception hook: {:x?}", exception);
+
+    let result = callback(vm, exception)?;
+
+    if result == ExceptionHookResult::Handled {
+        write_back_guest_registers(vm);
+    }
+
+    Ok(Some(result))
+}
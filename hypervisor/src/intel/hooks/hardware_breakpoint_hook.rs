@@ -0,0 +1,188 @@
+//! Provides hooks on guest functions implemented with the hardware breakpoints of the hypervisor (see the
+//! `debug_registers` module), as an alternative to the EPT and inline hooks modifying no byte of the guest: for hot
+//! functions whose page would often be single-stepped, small functions without room for a detour, or functions
+//! protected by PatchGuard.
+//!
+//! Up to four functions are hooked at a time, one per debug register, for every address space. The first instruction
+//! of a hooked function raises a debug exception, and its entry callback registered in the `HookManager` is dispatched
+//! to as for the `Vmcall` and `Int3` detours (see the `callbacks` module), then the function executes unless the
+//! callback redirected the execution. The return callbacks aren't supported, as they require a trampoline in a shadow
+//! page.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            debug_registers::{
+                BreakpointCondition, HardwareBreakpoint, HardwareBreakpointEvent, HARDWARE_BREAKPOINT_COUNT, SHARED_HARDWARE_BREAKPOINTS,
+            },
+            hooks::{
+                callbacks::{write_back_guest_registers, HookContext},
+                hook_manager::SHARED_HOOK_MANAGER,
+            },
+            vm::Vm,
+        },
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+};
+
+lazy_static! {
+    /// A globally shared instance of `HardwareBreakpointHooks`, protected by a mutex.
+    pub static ref SHARED_HARDWARE_BREAKPOINT_HOOKS: Mutex<HardwareBreakpointHooks> = Mutex::new(HardwareBreakpointHooks::new());
+}
+
+/// The functions hooked with hardware breakpoints.
+#[derive(Debug)]
+pub struct HardwareBreakpointHooks {
+    /// The virtual address of the function hooked with each debug register, or `None` for the registers used otherwise.
+    function_vas: [Option<u64>; HARDWARE_BREAKPOINT_COUNT],
+}
+
+impl HardwareBreakpointHooks {
+    /// Creates the registry, without hooks.
+    fn new() -> Self {
+        Self {
+            function_vas: [None; HARDWARE_BREAKPOINT_COUNT],
+        }
+    }
+
+    /// Hooks a function with an instruction breakpoint in a free debug register.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_va` - The virtual address of the function to hook.
+    ///
+    /// # Returns
+    ///
+    /// The debug register holding the hook, including when the function is already hooked, or
+    /// `Err(HypervisorError::NoFreeHardwareBreakpoint)` if the four debug registers are in use.
+    pub fn install(&mut self, function_va: u64) -> Result<usize, HypervisorError> {
+        if let Some(slot) = self.slot_of(function_va) {
+            return Ok(slot);
+        }
+
+        let mut hardware_breakpoints = SHARED_HARDWARE_BREAKPOINTS.lock();
+
+        let slot = (0..HARDWARE_BREAKPOINT_COUNT)
+            .find(|&slot| self.function_vas[slot].is_none() && hardware_breakpoints.breakpoint(slot).is_none())
+            .ok_or(HypervisorError::NoFreeHardwareBreakpoint)?;
+
+        let breakpoint = HardwareBreakpoint::new(function_va, BreakpointCondition::Execute, 1)?;
+        hardware_breakpoints.set_breakpoint(slot, Some(breakpoint))?;
+        self.function_vas[slot] = Some(function_va);
+
+        debug!("Function at VA: {:#x} hooked with hardware breakpoint {}", function_va, slot);
+
+        Ok(slot)
+    }
+
+    /// Removes the hook of a function, freeing its debug register.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_va` - The virtual address of the hooked function.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the hook has been removed, or `Err(HypervisorError::HookNotFound)` if the function isn't hooked
+    /// with a hardware breakpoint.
+    pub fn remove(&mut self, function_va: u64) -> Result<(), HypervisorError> {
+        let slot = self.slot_of(function_va).ok_or(HypervisorError::HookNotFound)?;
+
+        SHARED_HARDWARE_BREAKPOINTS.lock().set_breakpoint(slot, None)?;
+        self.function_vas[slot] = None;
+
+        debug!("Hardware breakpoint hook of function at VA: {:#x} removed", function_va);
+
+        Ok(())
+    }
+
+    /// Returns the debug register holding the hook of a function, if it's hooked with a hardware breakpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_va` - The virtual address of the function.
+    pub fn slot_of(&self, function_va: u64) -> Option<usize> {
+        self.function_vas.iter().position(|&va| va == Some(function_va))
+    }
+}
+
+/// Hooks a kernel function with a hardware breakpoint.
+///
+/// # Arguments
+///
+/// * `function_hash` - The hash of the function.
+/// * `syscall_number` - The syscall number to use if the function isn't exported.
+///
+/// # Returns
+///
+/// The debug register holding the hook, or an error if the function couldn't be found or hooked.
+pub fn install_kernel_hardware_breakpoint_hook(function_hash: u32, syscall_number: u16) -> Result<usize, HypervisorError> {
+    let function_va = SHARED_HOOK_MANAGER.lock().resolve_kernel_function(function_hash, syscall_number)?;
+    SHARED_HARDWARE_BREAKPOINT_HOOKS.lock().install(function_va)
+}
+
+/// Removes the hardware breakpoint hook of a kernel function.
+///
+/// # Arguments
+///
+/// * `function_hash` - The hash of the function.
+/// * `syscall_number` - The syscall number to use if the function isn't exported.
+///
+/// # Returns
+///
+/// `Ok(())` if the hook has been removed, or an error if the function couldn't be found or isn't hooked with a
+/// hardware breakpoint.
+pub fn remove_kernel_hardware_breakpoint_hook(function_hash: u32, syscall_number: u16) -> Result<(), HypervisorError> {
+    let function_va = SHARED_HOOK_MANAGER.lock().resolve_kernel_function(function_hash, syscall_number)?;
+    SHARED_HARDWARE_BREAKPOINT_HOOKS.lock().remove(function_va)
+}
+
+/// Dispatches the hit of a hypervisor breakpoint to the entry callback of the function it hooks, if any.
+///
+/// The callback is called without the registries locked, so it may install or remove hooks.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `event` - The hit of the hypervisor breakpoint.
+///
+/// # Returns
+///
+/// `true` if the breakpoint is a hook, `false` if it must be passed to the handler of the hardware breakpoints.
+pub fn dispatch_hardware_breakpoint_hook(vm: &mut Vm, event: &HardwareBreakpointEvent) -> bool {
+    let function_va = SHARED_HARDWARE_BREAKPOINT_HOOKS.lock().function_vas[event.slot];
+
+    let Some(function_va) = function_va.filter(|&va| va == event.breakpoint.address()) else {
+        return false;
+    };
+
+    let callbacks = SHARED_HOOK_MANAGER.lock().get_hook_callbacks(function_va, vm.hook_view.active_view);
+
+    let Some(on_entry) = callbacks.and_then(|callbacks| callbacks.on_entry) else {
+        trace!("Hardware breakpoint hook of function at VA: {:#x} hit without entry callback", function_va);
+        return true;
+    };
+
+    let entry_rsp = vm.guest_registers.rsp;
+    let Some(return_address) = PhysicalAddress::read_guest_virt_with_current_cr3(entry_rsp as *const u64) else {
+        warn!("Failed to read the return address of function at VA: {:#x}", function_va);
+        return true;
+    };
+
+    trace!("Dispatching to entry callback for function at VA: {:#x}", function_va);
+
+    on_entry(&mut HookContext {
+        registers: &mut vm.guest_registers,
+        function_va,
+        return_address,
+        entry_rsp,
+    });
+
+    write_back_guest_registers(vm);
+
+    true
+}
@@ -3,30 +3,35 @@ use {
         error::HypervisorError,
         intel::{
             addresses::PhysicalAddress,
-            bitmap::{MsrAccessType, MsrBitmap, MsrOperation},
             ept::AccessType,
             hooks::{
+                callbacks::{HookCallbacks, PendingReturn},
+                hook_view::{write_hook_bytes, HookViewId, HookViews, ProcessHookScope},
                 inline::{InlineHook, InlineHookType},
-                memory_manager::MemoryManager,
+                memory_manager::{HookInfo, MemoryManager},
+                syscall_hook::{SyscallHandler, SHARED_SYSCALL_HOOK_MANAGER},
+                tamper::HookTamperHandler,
             },
-            invept::invept_all_contexts,
-            invvpid::invvpid_all_contexts,
+            host_config::SHARED_HOST_CONFIG,
+            invept::invept_eptp,
+            invvpid::{invvpid_address_range, invvpid_single_context},
+            seqlock::SeqLock,
             vm::Vm,
         },
+        linux::kernel::SHARED_LINUX_KERNEL,
+        personality::is_linux_guest,
         windows::{
-            nt::pe::{get_export_by_hash, get_image_base_address, get_size_of_image},
-            ssdt::ssdt_hook::SsdtHook,
+            kernel::{resolve_kernel_export, ExportQuery},
+            nt::pe::{djb2_hash, get_image_base_address, get_size_of_image},
+            ssdt::ssdt_hook::{SsdtHook, WIN32K_SYSCALL_BASE},
         },
     },
-    alloc::vec::Vec,
-    core::intrinsics::copy_nonoverlapping,
+    alloc::{collections::BTreeMap, string::ToString, vec::Vec},
+    core::{intrinsics::copy_nonoverlapping, ops::Range},
     lazy_static::lazy_static,
     log::*,
     spin::Mutex,
-    x86::{
-        bits64::paging::{PAddr, BASE_PAGE_SIZE},
-        msr,
-    },
+    x86::bits64::paging::{PAddr, BASE_PAGE_SIZE},
 };
 
 /// Enum representing different types of hooks that can be applied.
@@ -41,35 +46,138 @@ pub enum EptHookType {
     Page,
 }
 
-/// Represents hook manager structures for hypervisor operations.
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct HookManager {
-    /// The memory manager instance for the pre-allocated shadow pages and page tables.
-    pub memory_manager: MemoryManager,
+/// A system call hooked by `HookManager::hook_syscall`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallTarget<'a> {
+    /// The system call number, from 0x1000 for the Win32k system calls.
+    Number(u16),
+
+    /// The name of the `Nt*` function of the system call, or of its `Zw*` alias, exported by ntoskrnl.exe.
+    Name(&'a str),
+}
+
+/// How a system call is hooked by `HookManager::hook_syscall`.
+#[derive(Debug, Clone, Copy)]
+pub enum SyscallHookMethod {
+    /// An EPT hook of the function of the system call in the SSDT, hit from any caller, including kernel mode.
+    Ept(InlineHookType),
 
-    /// A bitmap for handling MSRs.
-    pub msr_bitmap: MsrBitmap,
+    /// A handler called from the syscall entry by the IA32_LSTAR hook, for the system calls from user mode, before
+    /// the original `KiSystemCall64` runs.
+    Lstar(SyscallHandler),
+}
 
-    /// The physical address of the dummy page used for hiding hypervisor memory.
-    pub dummy_page_pa: u64,
+/// The policy applied when the guest writes to a hooked page, e.g., for Windows hot-patching or relocation fixups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowResyncPolicy {
+    /// Writes aren't tracked, so the shadow page keeps executing the code as it was when the page was hooked.
+    Disabled,
 
+    /// Writes are tracked by mapping the hooked guest pages read-only: after each write, the guest page is copied
+    /// to its shadow page again and the hooks of the page are reapplied.
+    Resync,
+}
+
+/// The views in which the hooked guest pages are mapped in the primary EPT, switched between on EPT violations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookViewPolicy {
+    /// The shadow page is mapped execute-only, and each read or write of the page restores the guest page with all
+    /// permissions for a single instruction before the shadow page is mapped again.
+    SingleStep,
+
+    /// The shadow page is mapped execute-only while the guest executes the page, and the guest page is mapped readable
+    /// (and writable unless writes are tracked) but non-executable while the guest reads or writes it, so integrity
+    /// reads and self-writes see the original bytes without single-stepping. A view is kept until an access it doesn't
+    /// permit. Instructions accessing the page they execute from are still single-stepped, since the two views would
+    /// otherwise fault on each other forever.
+    Split,
+}
+
+/// A handler called in VMX root operation when a breakpoint (`Int3` or `Int3Stub`) hook is hit.
+///
+/// The handler can inspect and modify the guest registers. If it leaves the guest RIP unchanged, the original
+/// instruction is single-stepped and the hook restored, otherwise the guest resumes at the new RIP.
+pub type BreakpointHandler = fn(vm: &mut Vm, hook_info: &HookInfo);
+
+/// The image of ntoskrnl.exe in the guest, captured on the first write to IA32_LSTAR.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelImage {
     /// The base virtual address of ntoskrnl.exe.
-    pub ntoskrnl_base_va: u64,
+    pub base_va: u64,
 
     /// The base physical address of ntoskrnl.exe.
-    pub ntoskrnl_base_pa: u64,
+    pub base_pa: u64,
 
     /// The size of ntoskrnl.exe.
-    pub ntoskrnl_size: u64,
+    pub size: u64,
+}
+
+impl KernelImage {
+    /// Returns `true` once the image has been captured, which it never is on a Linux guest.
+    pub fn is_captured(&self) -> bool {
+        self.base_va != 0
+    }
+}
+
+/// The image of ntoskrnl.exe, published outside of the hook manager as it's read on the VM exits of every logical
+/// processor, e.g., to resolve a kernel function, and only written once by `HookManager::set_kernel_base_and_size`.
+static KERNEL_IMAGE: SeqLock<KernelImage> = SeqLock::new(KernelImage {
+    base_va: 0,
+    base_pa: 0,
+    size: 0,
+});
+
+/// Returns the image of ntoskrnl.exe, without locking the hook manager.
+pub fn kernel_image() -> KernelImage {
+    KERNEL_IMAGE.read()
+}
+
+/// Represents hook manager structures for hypervisor operations.
+///
+/// This holds the mutable hook state shared between all logical processors. Read-only configuration
+/// set up at startup lives in `HostConfig`, and per-processor state lives in `Vm`. The image of the kernel is read
+/// through `kernel_image`, and whether a guest page may be hooked through `may_be_hooked_page`, without this lock.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct HookManager {
+    /// The memory manager instance for the shadow pages and page tables drawn from the page pool.
+    pub memory_manager: MemoryManager,
 
     /// A flag indicating whether the CPUID cache information has been called. This will be used to perform hooks at boot time when SSDT has been initialized.
     /// KiSetCacheInformation -> KiSetCacheInformationIntel -> KiSetStandardizedCacheInformation -> __cpuid(4, 0)
     pub has_cpuid_cache_info_been_called: bool,
 
-    /// A vector to keep track of allocated memory ranges for debugging and management purposes.
-    /// Each element is a tuple where the first value is the start address and the second value is the size of the allocation.
-    pub allocated_memory_ranges: Vec<(usize, usize)>,
+    /// A flag indicating whether TLB invalidations are deferred until `end_deferred_flush` is called.
+    /// This is used to flush only once after installing many hooks.
+    pub is_flush_deferred: bool,
+
+    /// A flag indicating whether a TLB invalidation has been deferred and is still pending.
+    pub has_pending_flush: bool,
+
+    /// The handlers of the breakpoint hooks, by guest virtual address of the hooked function.
+    pub breakpoint_handlers: BTreeMap<u64, BreakpointHandler>,
+
+    /// The callbacks of the hooked functions, by relative virtual address (RVA) from the base of ntoskrnl.exe.
+    pub hook_callbacks: BTreeMap<u64, HookCallbacks>,
+
+    /// The returns of hooked functions redirected to their trampoline, by stack pointer on the entry of the function.
+    pub pending_returns: BTreeMap<u64, PendingReturn>,
+
+    /// The policy applied when the guest writes to a hooked page, used for the pages hooked afterwards.
+    pub shadow_resync_policy: ShadowResyncPolicy,
+
+    /// A flag indicating whether the hooked guest pages are write-protected to report writes to the hooked bytes,
+    /// used for the pages hooked afterwards.
+    pub hook_tamper_detection: bool,
+
+    /// The handler called when the guest writes to the bytes overwritten by a hook.
+    pub hook_tamper_handler: Option<HookTamperHandler>,
+
+    /// The views the hooked guest pages are switched between on EPT violations.
+    pub hook_view_policy: HookViewPolicy,
+
+    /// The alternate hook views, in which the hooked functions carry different payloads, and their assignments.
+    pub hook_views: HookViews,
 }
 
 lazy_static! {
@@ -78,64 +186,238 @@ lazy_static! {
     ///
     /// The `HookManager` contains the following fields:
     /// - `memory_manager`: An instance of `MemoryManager` for managing shadow pages and page tables.
-    /// - `dummy_page_pa`: Physical address of the dummy page used for hiding hypervisor memory.
-    /// - `ntoskrnl_base_va`: Virtual address of the Windows kernel (ntoskrnl.exe).
-    /// - `ntoskrnl_base_pa`: Physical address of the Windows kernel (ntoskrnl.exe).
-    /// - `ntoskrnl_size`: Size of the Windows kernel (ntoskrnl.exe).
     /// - `has_cpuid_cache_info_been_called`: Flag indicating whether the CPUID cache information has been called.
+    /// - `is_flush_deferred`, `has_pending_flush`: Flags used by the "deferred flush" mode.
+    /// - `breakpoint_handlers`: The handlers dispatched to when a breakpoint hook is hit.
+    /// - `hook_callbacks`, `pending_returns`: The typed callbacks of the hooked functions and the returns waiting for them.
+    /// - `shadow_resync_policy`: The policy applied when the guest writes to a hooked page.
+    /// - `hook_tamper_detection`, `hook_tamper_handler`: The detection of writes to the hooked bytes and its handler.
+    /// - `hook_view_policy`: The views the hooked guest pages are switched between.
+    /// - `hook_views`: The alternate hook views and the processes and logical processors they are assigned to.
     pub static ref SHARED_HOOK_MANAGER: Mutex<HookManager> = Mutex::new(HookManager {
         memory_manager: MemoryManager::new(),
-        msr_bitmap: MsrBitmap::new(),
-        dummy_page_pa: 0,
-        ntoskrnl_base_va: 0,
-        ntoskrnl_base_pa: 0,
-        ntoskrnl_size: 0,
         has_cpuid_cache_info_been_called: false,
-        allocated_memory_ranges: Vec::with_capacity(128),
+        is_flush_deferred: false,
+        has_pending_flush: false,
+        breakpoint_handlers: BTreeMap::new(),
+        hook_callbacks: BTreeMap::new(),
+        pending_returns: BTreeMap::new(),
+        shadow_resync_policy: ShadowResyncPolicy::Resync,
+        hook_tamper_detection: false,
+        hook_tamper_handler: None,
+        hook_view_policy: HookViewPolicy::Split,
+        hook_views: HookViews::new(),
     });
 }
 
 impl HookManager {
-    /// Initializes the `SHARED_HOOK_MANAGER` with the provided dummy page physical address.
+    /// Starts a batched "deferred flush" mode.
     ///
-    /// This function should be called during the hypervisor setup process to set the `dummy_page_pa`
-    /// field of the `HookManager` instance. It ensures that the `dummy_page_pa` is correctly set before
-    /// any operations that depend on this field.
+    /// While in this mode, hook operations don't invalidate the TLB individually. Instead, a single
+    /// invalidation is performed when `end_deferred_flush` is called.
+    pub fn begin_deferred_flush(&mut self) {
+        trace!("Deferring TLB invalidations");
+        self.is_flush_deferred = true;
+    }
+
+    /// Ends the "deferred flush" mode and performs a single TLB invalidation if any was deferred.
     ///
     /// # Arguments
     ///
-    /// * `dummy_page_pa`: The physical address of the dummy page used for hiding hypervisor memory.
-    pub fn initialize_shared_hook_manager(dummy_page_pa: u64) {
-        let mut hook_manager = SHARED_HOOK_MANAGER.lock();
-        hook_manager.dummy_page_pa = dummy_page_pa;
-        trace!("Modifying MSR interception for LSTAR MSR write access");
-        hook_manager
-            .msr_bitmap
-            .modify_msr_interception(msr::IA32_LSTAR, MsrAccessType::Write, MsrOperation::Hook);
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    pub fn end_deferred_flush(&mut self, vm: &Vm) {
+        self.is_flush_deferred = false;
+
+        if self.has_pending_flush {
+            trace!("Performing deferred TLB invalidation");
+            invept_eptp(vm.primary_eptp);
+            invvpid_single_context(vm.vpid);
+            self.has_pending_flush = false;
+        }
+    }
 
-        hook_manager
-            .msr_bitmap
-            .modify_msr_interception(msr::IA32_FEATURE_CONTROL, MsrAccessType::Read, MsrOperation::Hook);
+    /// Registers the handler dispatched to when the breakpoint hook of a function is hit.
+    ///
+    /// The hook itself is installed separately with an `Int3` or `Int3Stub` inline hook type, e.g., through
+    /// `manage_kernel_ept_hook`. Without a handler, hitting the hook only single-steps the original instruction.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_function_va` - The virtual address of the hooked function.
+    /// * `handler` - The handler to dispatch to.
+    pub fn register_breakpoint_handler(&mut self, guest_function_va: u64, handler: BreakpointHandler) {
+        debug!("Registering breakpoint handler for function at VA: {:#x}", guest_function_va);
+        self.breakpoint_handlers.insert(guest_function_va, handler);
     }
 
-    /// Records a memory allocation for tracking purposes.
+    /// Unregisters the handler of the breakpoint hook of a function.
     ///
     /// # Arguments
     ///
-    /// * `start` - The start address of the memory allocation.
-    /// * `size` - The size of the memory allocation.
-    pub fn record_allocation(&mut self, start: usize, size: usize) {
-        self.allocated_memory_ranges.push((start, size));
+    /// * `guest_function_va` - The virtual address of the hooked function.
+    pub fn unregister_breakpoint_handler(&mut self, guest_function_va: u64) {
+        debug!("Unregistering breakpoint handler for function at VA: {:#x}", guest_function_va);
+        self.breakpoint_handlers.remove(&guest_function_va);
     }
 
-    /// Prints the allocated memory ranges for debugging purposes.
-    pub fn print_allocated_memory(&self) {
-        self.allocated_memory_ranges.iter().for_each(|(start, size)| {
-            debug!("Memory Range: Start = {:#x}, Size = {:#x}", start, size);
-        });
+    /// Enables the detection of guest writes to the hooked bytes, reported to a handler.
+    ///
+    /// The hooked guest pages are write-protected in the primary EPT, which only applies to the pages hooked afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The handler called for each write to the hooked bytes.
+    pub fn enable_hook_tamper_detection(&mut self, handler: HookTamperHandler) {
+        debug!("Enabling hook tamper detection");
+        self.hook_tamper_detection = true;
+        self.hook_tamper_handler = Some(handler);
     }
 
-    /// Sets the base address and size of the Windows kernel.
+    /// Disables the detection of guest writes to the hooked bytes.
+    ///
+    /// The hooked guest pages stay write-protected, so their writes are still tracked for the shadow resync policy.
+    pub fn disable_hook_tamper_detection(&mut self) {
+        debug!("Disabling hook tamper detection");
+        self.hook_tamper_detection = false;
+        self.hook_tamper_handler = None;
+    }
+
+    /// Registers the callbacks dispatched to on the entry and the return of a hooked function.
+    ///
+    /// The hook itself is installed separately with a `Vmcall`, `Int3` or `Int3Stub` inline hook type, e.g., through
+    /// `manage_kernel_ept_hook`. The callbacks replace any callbacks previously registered for the function.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_rva` - The relative virtual address of the hooked function from the base of ntoskrnl.exe.
+    /// * `callbacks` - The callbacks to dispatch to.
+    pub fn register_hook_callbacks(&mut self, function_rva: u64, callbacks: HookCallbacks) {
+        debug!("Registering hook callbacks for function at RVA: {:#x}", function_rva);
+        self.hook_callbacks.insert(function_rva, callbacks);
+    }
+
+    /// Unregisters the callbacks of a hooked function.
+    ///
+    /// Returns that are already redirected are still dispatched to their callback.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_rva` - The relative virtual address of the hooked function from the base of ntoskrnl.exe.
+    pub fn unregister_hook_callbacks(&mut self, function_rva: u64) {
+        debug!("Unregistering hook callbacks for function at RVA: {:#x}", function_rva);
+        self.hook_callbacks.remove(&function_rva);
+    }
+
+    /// Returns the callbacks registered for a hooked function in a view, if any, falling back to the callbacks of the
+    /// default view.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_function_va` - The virtual address of the hooked function.
+    /// * `view` - The hook view in use on the logical processor.
+    pub fn get_hook_callbacks(&self, guest_function_va: u64, view: HookViewId) -> Option<HookCallbacks> {
+        let function_rva = guest_function_va.checked_sub(kernel_image().base_va)?;
+        self.hook_views
+            .get_view_callbacks(view, function_rva)
+            .or_else(|| self.hook_callbacks.get(&function_rva).copied())
+    }
+
+    /// Returns the physical address of the page executed for a hooked guest page in a view while a process is
+    /// current: its shadow page, the original guest page or a variant shadow page (see the `hook_view` module).
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The hook view in use on the logical processor.
+    /// * `process_id` - The process with bound hooks in use on the logical processor, or `NO_HOOK_PROCESS`.
+    /// * `guest_page_pa` - The physical address of the hooked guest page.
+    pub fn get_execute_page_pa(&mut self, view: HookViewId, process_id: u64, guest_page_pa: u64) -> Result<u64, HypervisorError> {
+        self.hook_views
+            .execute_page_pa(&mut self.memory_manager, kernel_image().base_va, view, process_id, guest_page_pa)
+    }
+
+    /// Records a return of a hooked function redirected to its trampoline.
+    ///
+    /// # Arguments
+    ///
+    /// * `pending_return` - The redirected return.
+    pub fn add_pending_return(&mut self, pending_return: PendingReturn) {
+        self.pending_returns.insert(pending_return.entry_rsp, pending_return);
+    }
+
+    /// Removes and returns the redirected return matching a breakpoint, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry_rsp` - The stack pointer on the entry of the function, one slot below the stack pointer on return.
+    /// * `guest_rip` - The address of the breakpoint.
+    pub fn take_pending_return(&mut self, entry_rsp: u64, guest_rip: u64) -> Option<PendingReturn> {
+        match self.pending_returns.get(&entry_rsp) {
+            Some(pending_return) if pending_return.trampoline_va == guest_rip => self.pending_returns.remove(&entry_rsp),
+            _ => None,
+        }
+    }
+
+    /// Finds an `int3` (0xCC) byte in the shadow page of a hooked function that isn't overwritten by a hook, which the
+    /// function can return to in order to dispatch its return callback.
+    ///
+    /// Returning to the byte executes a breakpoint whatever instruction it belongs to, so any 0xCC byte of the page can
+    /// be used, such as the padding between functions.
+    ///
+    /// # Arguments
+    ///
+    /// * `hook_info` - The information of the hooked function.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The virtual address of the byte, or `None` if the page doesn't contain one.
+    pub fn find_return_trampoline(&self, hook_info: &HookInfo) -> Option<u64> {
+        const INT3: u8 = 0xCC;
+
+        let guest_function_pa = PAddr::from(hook_info.guest_function_pa);
+        let guest_page_pa = guest_function_pa.align_down_to_base_page();
+        let shadow_page_pa = self.memory_manager.get_shadow_page_as_ptr(guest_page_pa.as_u64())?;
+        let shadow_page = unsafe { core::slice::from_raw_parts(shadow_page_pa as *const u8, BASE_PAGE_SIZE) };
+
+        let hook_ranges: Vec<Range<usize>> = self
+            .memory_manager
+            .get_hook_info(guest_page_pa.as_u64())?
+            .iter()
+            .map(|hook| Self::hook_range_in_page(guest_page_pa, PAddr::from(hook.guest_function_pa), hook.ept_hook_type))
+            .collect();
+
+        let offset = shadow_page
+            .iter()
+            .enumerate()
+            .position(|(offset, &byte)| byte == INT3 && !hook_ranges.iter().any(|range| range.contains(&offset)))?;
+
+        Some((hook_info.guest_function_va & !(BASE_PAGE_SIZE as u64 - 1)) + offset as u64)
+    }
+
+    /// Invalidates the cached translations affected by a hook operation, or defers it if in "deferred flush" mode.
+    ///
+    /// A single-context INVEPT keyed on the primary EPTP and an address-range INVVPID are used instead of
+    /// invalidating every context, falling back to the broader types the processor supports (see `invept_eptp`
+    /// and `invvpid_address_range`).
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_va_range` - The range of guest virtual addresses affected by the hook operation, if any.
+    fn flush_tlb(&mut self, vm: &Vm, guest_va_range: Option<core::ops::Range<u64>>) {
+        if self.is_flush_deferred {
+            self.has_pending_flush = true;
+            return;
+        }
+
+        invept_eptp(vm.primary_eptp);
+
+        if let Some(guest_va_range) = guest_va_range {
+            invvpid_address_range(vm.vpid, guest_va_range);
+        }
+    }
+
+    /// Sets the base address and size of the Windows kernel, published to all the logical processors (see
+    /// `kernel_image`).
     ///
     /// # Arguments
     ///
@@ -146,13 +428,15 @@ impl HookManager {
     /// * `Ok(())` - The kernel base and size were set successfully.
     pub fn set_kernel_base_and_size(&mut self, guest_va: u64) -> Result<(), HypervisorError> {
         // Get the base address of ntoskrnl.exe.
-        self.ntoskrnl_base_va = unsafe { get_image_base_address(guest_va)? };
+        let base_va = unsafe { get_image_base_address(guest_va)? };
 
         // Get the physical address of ntoskrnl.exe using GUEST_CR3 and the virtual address.
-        self.ntoskrnl_base_pa = PhysicalAddress::pa_from_va_with_current_cr3(self.ntoskrnl_base_va)?;
+        let base_pa = PhysicalAddress::pa_from_kernel_va(base_va)?;
 
         // Get the size of ntoskrnl.exe.
-        self.ntoskrnl_size = unsafe { get_size_of_image(self.ntoskrnl_base_pa as _).ok_or(HypervisorError::FailedToGetKernelSize)? } as u64;
+        let size = unsafe { get_size_of_image(base_pa as _).ok_or(HypervisorError::FailedToGetKernelSize)? } as u64;
+
+        KERNEL_IMAGE.write(KernelImage { base_va, base_pa, size });
 
         Ok(())
     }
@@ -163,7 +447,7 @@ impl HookManager {
     ///
     /// * `vm` - The virtual machine to install/remove the hook on.
     /// * `function_hash` - The hash of the function to hook/unhook.
-    /// * `syscall_number` - The syscall number to use if `get_export_by_hash` fails.
+    /// * `syscall_number` - The syscall number to use if the function isn't exported.
     /// * `ept_hook_type` - The type of EPT hook to use.
     /// * `enable` - A boolean indicating whether to enable (true) or disable (false) the hook.
     ///
@@ -182,22 +466,9 @@ impl HookManager {
         let action = if enable { "Enabling" } else { "Disabling" };
         debug!("{} EPT hook for function: {:#x}", action, function_hash);
 
-        trace!("Ntoskrnl base VA: {:#x}", self.ntoskrnl_base_va);
-        trace!("Ntoskrnl base PA: {:#x}", self.ntoskrnl_base_pa);
-        trace!("Ntoskrnl size: {:#x}", self.ntoskrnl_size);
-
-        let function_va = unsafe {
-            if let Some(va) = get_export_by_hash(self.ntoskrnl_base_pa as _, self.ntoskrnl_base_va as _, function_hash) {
-                va
-            } else {
-                let ssdt_function_address =
-                    SsdtHook::find_ssdt_function_address(syscall_number as _, false, self.ntoskrnl_base_pa as _, self.ntoskrnl_size as _);
-                match ssdt_function_address {
-                    Ok(ssdt_hook) => ssdt_hook.guest_function_va as *mut u8,
-                    Err(_) => return Err(HypervisorError::FailedToGetExport),
-                }
-            }
-        };
+        trace!("Ntoskrnl: {:#x?}", kernel_image());
+
+        let function_va = self.resolve_kernel_function(function_hash, syscall_number)?;
 
         if enable {
             self.ept_hook_function(vm, function_va as _, function_hash, ept_hook_type)?;
@@ -208,11 +479,241 @@ impl HookManager {
         Ok(())
     }
 
-    /// Hides the hypervisor memory from the guest by installing EPT hooks on all allocated memory regions.
+    /// Resolves the virtual address of a kernel function by the hash of its export name, following the forwarded
+    /// exports, or by its syscall number through the SSDT if it isn't exported. On Linux guests, the function is
+    /// resolved by its syscall number through `sys_call_table`, the hash being ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_hash` - The hash of the function.
+    /// * `syscall_number` - The syscall number to use if the function isn't exported.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The virtual address of the function.
+    /// * `Err(HypervisorError::FailedToGetExport)` - If the function couldn't be found.
+    pub fn resolve_kernel_function(&self, function_hash: u32, syscall_number: u16) -> Result<u64, HypervisorError> {
+        if is_linux_guest() {
+            return SHARED_LINUX_KERNEL.lock().resolve_syscall(syscall_number);
+        }
+
+        let ntoskrnl = kernel_image();
+        if let Ok(function_va) = resolve_kernel_export(ntoskrnl.base_va, None, ExportQuery::Hash(function_hash)) {
+            return Ok(function_va);
+        }
+
+        match SsdtHook::find_ssdt_function_address(syscall_number as _, false, ntoskrnl.base_va, ntoskrnl.size) {
+            Ok(ssdt_hook) => Ok(ssdt_hook.guest_function_va as u64),
+            Err(_) => Err(HypervisorError::FailedToGetExport),
+        }
+    }
+
+    /// Resolves the virtual address of an export of ntoskrnl.exe or of another loaded kernel module by name, following
+    /// the forwarded exports.
+    ///
+    /// # Arguments
+    ///
+    /// * `module` - The base name of the exporting module, e.g., "hal", or `None` for ntoskrnl.exe.
+    /// * `name` - The name of the export.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The virtual address of the export.
+    /// * `Err(HypervisorError)` - If the module or the export couldn't be found.
+    pub fn resolve_kernel_export(&self, module: Option<&str>, name: &str) -> Result<u64, HypervisorError> {
+        resolve_kernel_export(kernel_image().base_va, module, ExportQuery::Name(name))
+    }
+
+    /// Returns `true` once the SSDT is initialized by the kernel, i.e., once the first `CPUID` leaf 2 has been
+    /// executed after the kernel base has been captured, so the system calls can be hooked by number or name.
+    pub fn is_ssdt_initialized(&self) -> bool {
+        self.has_cpuid_cache_info_been_called
+    }
+
+    /// Resolves the number of a system call and the virtual address of its function through the SSDT.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The number, or the name of the `Nt*` function, of the system call.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((u16, u64))` - The number of the system call and the virtual address of its function.
+    /// * `Err(HypervisorError::SsdtNotInitialized)` - If the kernel base hasn't been captured yet.
+    /// * `Err(HypervisorError::InvalidSyscallNumber)` - If the number isn't in the SSDT, or the function isn't one.
+    /// * `Err(HypervisorError)` - If the function can't be resolved, e.g., by name on Linux guests.
+    pub fn resolve_syscall(&self, target: SyscallTarget) -> Result<(u16, u64), HypervisorError> {
+        if is_linux_guest() {
+            return match target {
+                SyscallTarget::Number(syscall_number) => Ok((syscall_number, SHARED_LINUX_KERNEL.lock().resolve_syscall(syscall_number)?)),
+                SyscallTarget::Name(_) => Err(HypervisorError::FailedToGetExport),
+            };
+        }
+
+        let ntoskrnl = kernel_image();
+        if !ntoskrnl.is_captured() {
+            return Err(HypervisorError::SsdtNotInitialized);
+        }
+
+        let (syscall_number, function_va) = match target {
+            SyscallTarget::Number(syscall_number) => {
+                let get_from_win32k = syscall_number as i32 >= WIN32K_SYSCALL_BASE;
+                let ssdt_hook = SsdtHook::find_ssdt_function_address(syscall_number as _, get_from_win32k, ntoskrnl.base_va, ntoskrnl.size)?;
+
+                (syscall_number, ssdt_hook.guest_function_va as u64)
+            }
+            SyscallTarget::Name(name) => {
+                // The Zw* exports are the stubs entering the system call, whose SSDT function is the Nt* one.
+                let nt_name = match name.strip_prefix("Zw") {
+                    Some(suffix) => ["Nt", suffix].concat(),
+                    None => name.to_string(),
+                };
+
+                let function_va = self.resolve_kernel_export(None, &nt_name)?;
+                let syscall_number = SsdtHook::find_syscall_number(function_va, false, ntoskrnl.base_va, ntoskrnl.size)?;
+
+                (syscall_number as u16, function_va)
+            }
+        };
+
+        trace!("System call {:?} is {:#x} at {:#x}", target, syscall_number, function_va);
+
+        Ok((syscall_number, function_va))
+    }
+
+    /// Hooks a system call by number or by name, with an EPT hook of its function or a handler of the IA32_LSTAR hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine to install the hook on.
+    /// * `target` - The number, or the name of the `Nt*` function, of the system call.
+    /// * `method` - How the system call is hooked.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u16)` - The number of the hooked system call.
+    /// * `Err(HypervisorError)` - If the system call can't be resolved or hooked.
+    pub fn hook_syscall(&mut self, vm: &mut Vm, target: SyscallTarget, method: SyscallHookMethod) -> Result<u16, HypervisorError> {
+        let (syscall_number, function_va) = self.resolve_syscall(target)?;
+
+        debug!("Hooking system call {:#x} ({:?}) with {:?}", syscall_number, target, method);
+
+        match method {
+            SyscallHookMethod::Ept(inline_hook_type) => {
+                let function_hash = match target {
+                    SyscallTarget::Name(name) => djb2_hash(name.as_bytes()),
+                    SyscallTarget::Number(_) => 0,
+                };
+
+                self.ept_hook_function(vm, function_va, function_hash, EptHookType::Function(inline_hook_type))?;
+            }
+            SyscallHookMethod::Lstar(handler) => SHARED_SYSCALL_HOOK_MANAGER.lock().register(syscall_number as u32, handler),
+        }
+
+        Ok(syscall_number)
+    }
+
+    /// Removes a hook installed by `hook_syscall`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine to remove the hook from.
+    /// * `target` - The number, or the name of the `Nt*` function, of the system call.
+    /// * `method` - How the system call was hooked, the handler of an IA32_LSTAR hook being ignored.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The hook was removed.
+    /// * `Err(HypervisorError)` - If the system call can't be resolved or its hook removed.
+    pub fn unhook_syscall(&mut self, vm: &mut Vm, target: SyscallTarget, method: SyscallHookMethod) -> Result<(), HypervisorError> {
+        let (syscall_number, function_va) = self.resolve_syscall(target)?;
+
+        debug!("Unhooking system call {:#x} ({:?})", syscall_number, target);
+
+        match method {
+            SyscallHookMethod::Ept(inline_hook_type) => self.ept_unhook_function(vm, function_va, EptHookType::Function(inline_hook_type)),
+            SyscallHookMethod::Lstar(_) => SHARED_SYSCALL_HOOK_MANAGER
+                .lock()
+                .unregister(syscall_number as u32)
+                .map(|_| ())
+                .ok_or(HypervisorError::HookNotFound),
+        }
+    }
+
+    /// Enables or disables a hooked kernel function in an alternate hook view, and rebuilds the variant shadow pages
+    /// of its guest page so the logical processors in the view pick up the change.
+    ///
+    /// # Arguments
     ///
-    /// This function iterates through the recorded memory allocations and calls `ept_hide_hypervisor_memory`
-    /// for each page to split the 2MB pages into 4KB pages and fill the shadow page with a specified value.
-    /// It then swaps the guest page with the shadow page and sets the desired permissions.
+    /// * `view` - The alternate view.
+    /// * `function_hash` - The hash of the function.
+    /// * `syscall_number` - The syscall number to use if the function isn't exported.
+    /// * `enable` - Whether to enable or disable the function in the view.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the view was changed, `Err(HypervisorError)` otherwise.
+    pub fn set_hook_view_function(&mut self, view: HookViewId, function_hash: u32, syscall_number: u16, enable: bool) -> Result<(), HypervisorError> {
+        let guest_function_va = self.resolve_kernel_function(function_hash, syscall_number)?;
+        let function_rva = guest_function_va - kernel_image().base_va;
+
+        match enable {
+            true => self.hook_views.enable_hook(view, function_rva, None)?,
+            false => self.hook_views.disable_hook(view, function_rva)?,
+        }
+
+        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_kernel_va(guest_function_va)?);
+        let guest_page_pa = guest_function_pa.align_down_to_base_page().as_u64();
+
+        // The function may not be hooked yet, in which case the view applies once it is.
+        if self.memory_manager.is_guest_page_processed(guest_page_pa) {
+            self.hook_views
+                .refresh_variant_pages(&self.memory_manager, kernel_image().base_va, guest_page_pa)?;
+        }
+
+        Ok(())
+    }
+
+    /// Binds a hooked kernel function to a process, so the hook only fires while the current address space is the one
+    /// of the process, or removes its binding, and rebuilds the variant shadow pages of its guest page so the logical
+    /// processors pick up the change.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_hash` - The hash of the function.
+    /// * `syscall_number` - The syscall number to use if the function isn't exported.
+    /// * `scope` - The process and its address spaces, or `None` to enable the function in every process again.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the binding was changed, `Err(HypervisorError)` otherwise.
+    pub fn bind_hook_to_process(&mut self, function_hash: u32, syscall_number: u16, scope: Option<ProcessHookScope>) -> Result<(), HypervisorError> {
+        let guest_function_va = self.resolve_kernel_function(function_hash, syscall_number)?;
+        let function_rva = guest_function_va - kernel_image().base_va;
+
+        self.hook_views.bind_hook(function_rva, scope)?;
+
+        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_kernel_va(guest_function_va)?);
+        let guest_page_pa = guest_function_pa.align_down_to_base_page().as_u64();
+
+        // The function may not be hooked yet, in which case the binding applies once it is.
+        if self.memory_manager.is_guest_page_processed(guest_page_pa) {
+            self.hook_views
+                .refresh_variant_pages(&self.memory_manager, kernel_image().base_va, guest_page_pa)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hides the hypervisor memory from the guest by remapping every page of the recorded memory ranges to the dummy page.
+    ///
+    /// The ranges are recorded by the loader: the hypervisor image, which contains the heap the host paging structures,
+    /// the VMXON and VMCS regions and the EPTs are allocated from, the host stacks and the hook page pool the shadow
+    /// pages are drawn from. The pages are remapped to a decoy page rather than unmapped, so a guest physical memory
+    /// scanner reads 0xFF as for unpopulated memory instead of causing EPT violations.
+    ///
+    /// Only the ranges recorded since the last call on this logical processor are hidden, so this can be called
+    /// again to hide the ranges recorded later, such as the stacks of the processors virtualized afterwards.
     ///
     /// # Arguments
     ///
@@ -223,17 +724,26 @@ impl HookManager {
     ///
     /// Returns `Ok(())` if the hooks were successfully installed, `Err(HypervisorError)` otherwise.
     pub fn hide_hypervisor_memory(&mut self, vm: &mut Vm, page_permissions: AccessType) -> Result<(), HypervisorError> {
-        let pages: Vec<u64> = self
-            .allocated_memory_ranges
-            .iter()
-            .step_by(BASE_PAGE_SIZE)
-            .map(|(start, _size)| *start as u64)
-            .collect();
+        let (pages, range_count) = {
+            let host_config = SHARED_HOST_CONFIG.read();
+            (host_config.allocated_pages(vm.hidden_memory_range_count), host_config.allocated_memory_ranges.len())
+        };
+
+        debug!("Hiding {} hypervisor pages", pages.len());
+
+        // Flush only once after all the pages have been hidden.
+        self.begin_deferred_flush();
 
         for guest_page_pa in pages {
-            self.ept_hide_hypervisor_memory(vm, PAddr::from(guest_page_pa).align_down_to_base_page().as_u64(), page_permissions)?
+            if let Err(e) = self.ept_hide_hypervisor_memory(vm, guest_page_pa, page_permissions) {
+                self.end_deferred_flush(vm);
+                return Err(e);
+            }
         }
 
+        self.end_deferred_flush(vm);
+        vm.hidden_memory_range_count = range_count;
+
         Ok(())
     }
 
@@ -250,16 +760,43 @@ impl HookManager {
     ///
     /// * Returns `Ok(())` if the hook was successfully installed, `Err(HypervisorError)` otherwise.
     fn ept_hide_hypervisor_memory(&mut self, vm: &mut Vm, guest_page_pa: u64, page_permissions: AccessType) -> Result<(), HypervisorError> {
+        let dummy_page_pa = SHARED_HOST_CONFIG.read().dummy_page_pa;
+        trace!("Dummy page PA: {:#x}", dummy_page_pa);
+
+        self.ept_map_host_page(vm, guest_page_pa, dummy_page_pa, page_permissions)?;
+
+        trace!("EPT hide hypervisor memory completed successfully");
+
+        Ok(())
+    }
+
+    /// Maps a guest page to a host page in the EPT of the current logical processor.
+    /// This function will split the 2MB page to 4KB pages if required, swap the guest page with the host page
+    /// and set the permissions to the desired permissions.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_page_pa` - The physical address of the guest page to map.
+    /// * `host_page_pa` - The physical address of the host page to map the guest page to.
+    /// * `page_permissions` - The desired permissions for the mapped page.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the page was successfully mapped, `Err(HypervisorError)` otherwise.
+    pub fn ept_map_host_page(
+        &mut self,
+        vm: &mut Vm,
+        guest_page_pa: u64,
+        host_page_pa: u64,
+        page_permissions: AccessType,
+    ) -> Result<(), HypervisorError> {
         let guest_page_pa = PAddr::from(guest_page_pa).align_down_to_base_page();
         trace!("Guest page PA: {:#x}", guest_page_pa.as_u64());
 
         let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
         trace!("Guest large page PA: {:#x}", guest_large_page_pa.as_u64());
 
-        let dummy_page_pa = self.dummy_page_pa;
-
-        trace!("Dummy page PA: {:#x}", dummy_page_pa);
-
         trace!("Mapping large page");
         // Map the large page to the pre-allocated page table, if it hasn't been mapped already.
         self.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
@@ -275,18 +812,43 @@ impl HookManager {
             vm.primary_ept.split_2mb_to_4kb(guest_large_page_pa.as_u64(), pre_alloc_pt)?;
         }
 
-        trace!("Swapping guest page: {:#x} with dummy page: {:#x}", guest_page_pa.as_u64(), dummy_page_pa);
+        trace!("Swapping guest page: {:#x} with host page: {:#x}", guest_page_pa.as_u64(), host_page_pa);
         vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), dummy_page_pa, page_permissions, pre_alloc_pt)?;
+            .swap_page_deferred(guest_page_pa.as_u64(), host_page_pa, page_permissions, pre_alloc_pt)?;
 
-        invept_all_contexts();
-        invvpid_all_contexts();
-
-        trace!("EPT hide hypervisor memory completed successfully");
+        // Only the EPT has been modified, so there is no guest linear address range to invalidate.
+        self.flush_tlb(vm, None);
 
         Ok(())
     }
 
+    /// Retrieves the host page and the permissions a guest page is mapped with in the EPT of the current logical
+    /// processor, e.g., to restore them after `ept_map_host_page`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_page_pa` - The physical address of the guest page.
+    ///
+    /// # Returns
+    ///
+    /// * Returns the physical address of the host page and the permissions of the guest page, `Err(HypervisorError)` if
+    ///   the page table of a split page is not found.
+    pub fn ept_page_mapping(&mut self, vm: &Vm, guest_page_pa: u64) -> Result<(u64, AccessType), HypervisorError> {
+        let guest_page_pa = PAddr::from(guest_page_pa).align_down_to_base_page();
+        let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
+
+        // The page table is only read for split pages, which have been mapped to one.
+        self.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
+
+        let pre_alloc_pt = self
+            .memory_manager
+            .get_page_table_as_mut(guest_large_page_pa.as_u64())
+            .ok_or(HypervisorError::PageTableNotFound)?;
+
+        Ok(vm.primary_ept.page_mapping(guest_page_pa.as_u64(), pre_alloc_pt))
+    }
+
     /// Installs an EPT hook for a function.
     ///
     /// # Steps:
@@ -304,9 +866,13 @@ impl HookManager {
     ///
     /// 6. Change the permissions of the guest page to read-write only.
     ///
-    /// 7. Invalidate the EPT and VPID contexts to ensure the changes take effect.
+    /// 7. Invalidate the EPT and VPID contexts to ensure the changes take effect (deferred in "deferred flush" mode).
+    ///
+    /// Steps 1-4 and 6-7 are performed only once per guest page. Additional hooks on the same page share its shadow page,
+    /// so only step 5 is performed for them, as long as they don't overlap an existing hook.
     ///
-    /// These operations are performed only once per guest page to avoid overwriting existing hooks on the same page.
+    /// If the hook bytes cross the end of the function's page, steps 1-4 and 6-7 are also performed for the next guest
+    /// page, which may not be physically contiguous, and the hook bytes are split across both shadow pages.
     ///
     /// # Arguments
     ///
@@ -327,13 +893,140 @@ impl HookManager {
     ) -> Result<(), HypervisorError> {
         debug!("Creating EPT hook for function at VA: {:#x}", guest_function_va);
 
-        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(guest_function_va)?);
+        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_kernel_va(guest_function_va)?);
         debug!("Guest function PA: {:#x}", guest_function_pa.as_u64());
 
         let guest_page_pa = guest_function_pa.align_down_to_base_page();
         debug!("Guest page PA: {:#x}", guest_page_pa.as_u64());
 
-        let guest_large_page_pa = guest_function_pa.align_down_to_large_page();
+        let guest_page_va = guest_function_va & !(BASE_PAGE_SIZE as u64 - 1);
+
+        if self
+            .memory_manager
+            .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+            .is_some()
+        {
+            debug!("Hook already exists for function PA: {:#x}, skipping hook installation", guest_function_pa.as_u64());
+            return Ok(());
+        }
+
+        // The next guest page is translated through the guest page tables, since it may not be physically contiguous.
+        let guest_next_page_pa = if guest_function_pa.base_page_offset() as usize + Self::hook_size(ept_hook_type) > BASE_PAGE_SIZE {
+            let guest_next_page_pa = PAddr::from(PhysicalAddress::pa_from_kernel_va(guest_page_va + BASE_PAGE_SIZE as u64)?);
+            debug!("Hook crosses the page boundary, guest next page PA: {:#x}", guest_next_page_pa.as_u64());
+            Some(guest_next_page_pa)
+        } else {
+            None
+        };
+
+        let guest_pages = [Some(guest_page_pa), guest_next_page_pa];
+
+        if guest_pages
+            .iter()
+            .flatten()
+            .any(|&page_pa| self.is_overlapping_existing_hook(page_pa, guest_function_pa, ept_hook_type))
+        {
+            error!("Hook for function PA: {:#x} overlaps an existing hook on the same page", guest_function_pa.as_u64());
+            return Err(HypervisorError::OverlappingHooks);
+        }
+
+        // Encode the hook before any page is shadowed, so an unreachable handler doesn't leave a partially installed hook.
+        let shellcode = match ept_hook_type {
+            EptHookType::Function(inline_hook_type) => InlineHook::shellcode(inline_hook_type, guest_function_va)?,
+            EptHookType::Page => {
+                unimplemented!("Page hooks are not yet implemented");
+            }
+        };
+
+        let hook_info = HookInfo {
+            guest_function_va,
+            guest_function_pa: guest_function_pa.as_u64(),
+            ept_hook_type,
+            function_hash,
+            guest_next_page_pa: guest_next_page_pa.map(|pa| pa.as_u64()),
+        };
+
+        // 1-4. Split the large pages and map the guest pages to their shadow pages, copying the pages that are new.
+        let mut is_guest_page_processed = [true; 2];
+
+        for (index, page_pa) in guest_pages.iter().enumerate() {
+            if let Some(page_pa) = page_pa {
+                is_guest_page_processed[index] = self.shadow_guest_page(vm, *page_pa, &hook_info)?;
+            }
+        }
+
+        // 5. Install the inline hook at the shadow function address.
+        let shadow_page_pa = PAddr::from(
+            self.memory_manager
+                .get_shadow_page_as_ptr(guest_page_pa.as_u64())
+                .ok_or(HypervisorError::ShadowPageNotFound)?,
+        );
+
+        let shadow_function_pa = PAddr::from(Self::calculate_function_offset_in_host_shadow_page(shadow_page_pa, guest_function_pa));
+        debug!("Shadow Function PA: {:#x}", shadow_function_pa);
+
+        if let Some(guest_next_page_pa) = guest_next_page_pa {
+            let next_shadow_page_pa = self
+                .memory_manager
+                .get_shadow_page_as_ptr(guest_next_page_pa.as_u64())
+                .ok_or(HypervisorError::ShadowPageNotFound)?;
+
+            // Write the hook bytes that fit to the end of the first shadow page, and the rest to the start of the next one.
+            let first_page_length = BASE_PAGE_SIZE - guest_function_pa.base_page_offset() as usize;
+
+            debug!(
+                "Installing split inline hook at shadow function PA: {:#x} and next shadow page PA: {:#x}",
+                shadow_function_pa, next_shadow_page_pa
+            );
+            unsafe {
+                copy_nonoverlapping(shellcode.as_ptr(), shadow_function_pa.as_u64() as *mut u8, first_page_length);
+                copy_nonoverlapping(shellcode[first_page_length..].as_ptr(), next_shadow_page_pa as *mut u8, shellcode.len() - first_page_length);
+            }
+        } else {
+            debug!("Installing inline hook at shadow function PA: {:#x}", shadow_function_pa.as_u64());
+            unsafe { copy_nonoverlapping(shellcode.as_ptr(), shadow_function_pa.as_u64() as *mut u8, shellcode.len()) };
+        }
+
+        // The variant shadow pages of the alternate views must include the new hook if it is enabled in them.
+        for page_pa in guest_pages.iter().flatten() {
+            self.hook_views
+                .refresh_variant_pages(&self.memory_manager, kernel_image().base_va, page_pa.as_u64())?;
+        }
+
+        // 6-7. Make the new guest pages read-write only so execution is redirected to the shadow pages.
+        for (index, page_pa) in guest_pages.iter().enumerate() {
+            match page_pa {
+                Some(page_pa) if !is_guest_page_processed[index] => {
+                    self.protect_guest_page(vm, *page_pa, guest_page_va + (index * BASE_PAGE_SIZE) as u64)?;
+                }
+                Some(page_pa) => {
+                    // The guest page is already backed by the shadow page, so the new hook takes effect immediately.
+                    debug!("EPT hook added to already processed guest page: {:#x}", page_pa.as_u64());
+                }
+                None => {}
+            }
+        }
+
+        debug!("EPT hook created and enabled successfully");
+
+        Ok(())
+    }
+
+    /// Prepares a guest page for a hook: splits its large page, maps it to a shadow page and records the hook.
+    ///
+    /// The guest page is copied to the shadow page only if the page wasn't already processed by another hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_page_pa` - The physical address of the guest page.
+    /// * `hook_info` - The information of the hook to be installed.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(bool)` - `true` if the guest page was already processed, `false` if it has been newly shadowed.
+    fn shadow_guest_page(&mut self, vm: &mut Vm, guest_page_pa: PAddr, hook_info: &HookInfo) -> Result<bool, HypervisorError> {
+        let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
         debug!("Guest large page PA: {:#x}", guest_large_page_pa.as_u64());
 
         // 1. Map the large page to the pre-allocated page table, if it hasn't been mapped already.
@@ -356,66 +1049,167 @@ impl HookManager {
 
         // 3. Check if the guest page is already processed. If not, map the guest page to the shadow page.
         // Ensure the memory manager maintains a set of processed guest pages to track this mapping.
-        if !self.memory_manager.is_guest_page_processed(guest_page_pa.as_u64()) {
-            // We must map the guest page to the shadow page before accessing it.
-            debug!("Mapping guest page and shadow page");
-            self.memory_manager.map_guest_to_shadow_page(
-                guest_page_pa.as_u64(),
-                guest_function_va,
-                guest_function_pa.as_u64(),
-                ept_hook_type,
-                function_hash,
-            )?;
-
-            // We must map the guest page to the shadow page before accessing it.
+        // If the page is already processed, the hook is added to the existing shadow page shared by all hooks on this page.
+        let is_guest_page_processed = self.memory_manager.is_guest_page_processed(guest_page_pa.as_u64());
+
+        // We must map the guest page to the shadow page before accessing it.
+        debug!("Mapping guest page and shadow page");
+        self.memory_manager.map_guest_to_shadow_page(
+            guest_page_pa.as_u64(),
+            hook_info.guest_function_va,
+            hook_info.guest_function_pa,
+            hook_info.ept_hook_type,
+            hook_info.function_hash,
+            hook_info.guest_next_page_pa,
+        )?;
+
+        // 4. Copy the guest page to the shadow page if it hasn't been copied already, ensuring the shadow page contains the original function code.
+        if !is_guest_page_processed {
             let shadow_page_pa = PAddr::from(
                 self.memory_manager
                     .get_shadow_page_as_ptr(guest_page_pa.as_u64())
                     .ok_or(HypervisorError::ShadowPageNotFound)?,
             );
 
-            // 4. Copy the guest page to the shadow page if it hasn't been copied already, ensuring the shadow page contains the original function code.
             debug!("Copying guest page to shadow page: {:#x}", guest_page_pa.as_u64());
             Self::unsafe_copy_guest_to_shadow(guest_page_pa, shadow_page_pa);
+        }
 
-            // 5. Install the inline hook at the shadow function address if the hook type is `Function`.
-            match ept_hook_type {
-                EptHookType::Function(inline_hook_type) => {
-                    let shadow_function_pa = PAddr::from(Self::calculate_function_offset_in_host_shadow_page(shadow_page_pa, guest_function_pa));
-                    debug!("Shadow Function PA: {:#x}", shadow_function_pa);
+        Ok(is_guest_page_processed)
+    }
 
-                    debug!("Installing inline hook at shadow function PA: {:#x}", shadow_function_pa.as_u64());
-                    InlineHook::new(shadow_function_pa.as_u64() as *mut u8, inline_hook_type).detour64();
-                }
-                EptHookType::Page => {
-                    unimplemented!("Page hooks are not yet implemented");
-                }
-            }
+    /// Changes the permissions of a newly shadowed guest page to read-write only and invalidates its cached translations.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_page_pa` - The physical address of the guest page.
+    /// * `guest_page_va` - The virtual address of the guest page.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the permissions were changed, `Err(HypervisorError)` otherwise.
+    fn protect_guest_page(&mut self, vm: &mut Vm, guest_page_pa: PAddr, guest_page_va: u64) -> Result<(), HypervisorError> {
+        // 6. Change the permissions of the guest page to read-write only, or read-only to track or report the writes to the page.
+        let page_permissions = self.guest_page_access_type();
 
-            let pre_alloc_pt = self
-                .memory_manager
-                .get_page_table_as_mut(guest_large_page_pa.as_u64())
-                .ok_or(HypervisorError::PageTableNotFound)?;
+        let pre_alloc_pt = self
+            .memory_manager
+            .get_page_table_as_mut(guest_page_pa.align_down_to_large_page().as_u64())
+            .ok_or(HypervisorError::PageTableNotFound)?;
 
-            // 6. Change the permissions of the guest page to read-write only.
-            debug!("Changing Primary EPT permissions for page to Read-Write (RW) only: {:#x}", guest_page_pa);
-            vm.primary_ept
-                .modify_page_permissions(guest_page_pa.as_u64(), AccessType::READ_WRITE, pre_alloc_pt)?;
+        debug!("Changing Primary EPT permissions for page to {:?}: {:#x}", page_permissions, guest_page_pa);
+        vm.primary_ept
+            .modify_page_permissions(guest_page_pa.as_u64(), page_permissions, pre_alloc_pt)?;
+
+        // 7. Invalidate the EPT and VPID contexts to ensure the changes take effect.
+        self.flush_tlb(vm, Some(guest_page_va..guest_page_va + BASE_PAGE_SIZE as u64));
+
+        Ok(())
+    }
+
+    /// Returns `true` if the writes to the hooked guest pages are tracked, either to resynchronize their shadow pages
+    /// or to report writes to the hooked bytes.
+    pub fn is_write_tracked(&self) -> bool {
+        self.shadow_resync_policy == ShadowResyncPolicy::Resync || self.hook_tamper_detection
+    }
+
+    /// Returns the permissions of a hooked guest page while it is mapped for reads and writes: read-write, or
+    /// read-only while writes are tracked. The page is never executable, so executing it maps the shadow page.
+    pub fn guest_page_access_type(&self) -> AccessType {
+        match self.is_write_tracked() {
+            true => AccessType::READ,
+            false => AccessType::READ_WRITE,
+        }
+    }
+
+    /// Copies a hooked guest page written by the guest to its shadow page again and reapplies the hooks of the page,
+    /// so the shadow page doesn't keep executing stale code. The variant shadow pages of the alternate views are rebuilt as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_page_pa` - The physical address of the guest page.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the shadow page was resynchronized, `Err(HypervisorError)` otherwise.
+    pub fn resync_shadow_page(&mut self, guest_page_pa: PAddr) -> Result<(), HypervisorError> {
+        debug!("Resynchronizing shadow page of guest page: {:#x}", guest_page_pa.as_u64());
+
+        let shadow_page_pa = PAddr::from(
+            self.memory_manager
+                .get_shadow_page_as_ptr(guest_page_pa.as_u64())
+                .ok_or(HypervisorError::ShadowPageNotFound)?,
+        );
 
-            // 7. Invalidate the EPT and VPID contexts to ensure the changes take effect.
-            invept_all_contexts();
-            invvpid_all_contexts();
+        let hooks = self
+            .memory_manager
+            .get_hook_info(guest_page_pa.as_u64())
+            .cloned()
+            .ok_or(HypervisorError::HookInfoNotFound)?;
+
+        Self::unsafe_copy_guest_to_shadow(guest_page_pa, shadow_page_pa);
+        write_hook_bytes(guest_page_pa, shadow_page_pa, hooks.iter())?;
+
+        self.hook_views
+            .refresh_variant_pages(&self.memory_manager, kernel_image().base_va, guest_page_pa.as_u64())
+    }
+
+    /// Returns the range of offsets within a guest page overwritten by a hook.
+    ///
+    /// For a hook crossing the page boundary, this is the end of the function's page or the start of the next page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_page_pa` - The physical address of the guest page.
+    /// * `guest_function_pa` - The physical address of the hooked function.
+    /// * `ept_hook_type` - The type of EPT hook.
+    ///
+    /// # Returns
+    ///
+    /// * `Range<usize>` - The offsets of the overwritten bytes within the guest page.
+    pub fn hook_range_in_page(guest_page_pa: PAddr, guest_function_pa: PAddr, ept_hook_type: EptHookType) -> Range<usize> {
+        let start = guest_function_pa.base_page_offset() as usize;
+        let end = start + Self::hook_size(ept_hook_type);
 
-            debug!("EPT hook created and enabled successfully");
+        if guest_function_pa.align_down_to_base_page() == guest_page_pa {
+            start..end.min(BASE_PAGE_SIZE)
         } else {
-            debug!("Guest page already processed, skipping hook installation and permission modification.");
+            0..end.saturating_sub(BASE_PAGE_SIZE)
         }
+    }
 
-        Ok(())
+    /// Checks whether the bytes overwritten by a new hook overlap the bytes of an existing hook on the same guest page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_page_pa` - The physical address of the guest page.
+    /// * `guest_function_pa` - The physical address of the function to be hooked.
+    /// * `ept_hook_type` - The type of EPT hook to be installed.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the new hook overlaps an existing hook, `false` otherwise.
+    fn is_overlapping_existing_hook(&self, guest_page_pa: PAddr, guest_function_pa: PAddr, ept_hook_type: EptHookType) -> bool {
+        let range = Self::hook_range_in_page(guest_page_pa, guest_function_pa, ept_hook_type);
+
+        self.memory_manager
+            .get_hook_info(guest_page_pa.as_u64())
+            .map(|hooks| {
+                hooks.iter().any(|hook| {
+                    let hook_range = Self::hook_range_in_page(guest_page_pa, PAddr::from(hook.guest_function_pa), hook.ept_hook_type);
+                    range.start < hook_range.end && hook_range.start < range.end
+                })
+            })
+            .unwrap_or(false)
     }
 
     /// Removes an EPT hook for a function.
     ///
+    /// If other hooks remain on the same guest page, only the bytes overwritten by this hook are restored in the shared
+    /// shadow page. Otherwise, the guest page is swapped back and the shadow page is released. A hook crossing the page
+    /// boundary is removed from both guest pages in the same way.
+    ///
     /// # Arguments
     ///
     /// * `vm` - The virtual machine instance of the hypervisor.
@@ -428,27 +1222,84 @@ impl HookManager {
     pub fn ept_unhook_function(&mut self, vm: &mut Vm, guest_function_va: u64, _ept_hook_type: EptHookType) -> Result<(), HypervisorError> {
         debug!("Removing EPT hook for function at VA: {:#x}", guest_function_va);
 
-        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(guest_function_va)?);
+        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_kernel_va(guest_function_va)?);
         debug!("Guest function PA: {:#x}", guest_function_pa.as_u64());
 
         let guest_page_pa = guest_function_pa.align_down_to_base_page();
         debug!("Guest page PA: {:#x}", guest_page_pa.as_u64());
 
-        let guest_large_page_pa = guest_function_pa.align_down_to_large_page();
-        debug!("Guest large page PA: {:#x}", guest_large_page_pa.as_u64());
+        let guest_page_va = guest_function_va & !(BASE_PAGE_SIZE as u64 - 1);
+
+        let hook_info = self
+            .memory_manager
+            .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+            .cloned()
+            .ok_or(HypervisorError::HookInfoNotFound)?;
+
+        let guest_pages = [Some(guest_page_pa), hook_info.guest_next_page_pa.map(PAddr::from)];
+
+        for (index, page_pa) in guest_pages.iter().enumerate() {
+            if let Some(page_pa) = page_pa {
+                self.release_guest_page(vm, *page_pa, guest_page_va + (index * BASE_PAGE_SIZE) as u64, &hook_info)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes a hook from one of the guest pages it overwrites.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_page_pa` - The physical address of the guest page.
+    /// * `guest_page_va` - The virtual address of the guest page.
+    /// * `hook_info` - The information of the hook to be removed.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the hook was removed from the page, `Err(HypervisorError)` otherwise.
+    fn release_guest_page(&mut self, vm: &mut Vm, guest_page_pa: PAddr, guest_page_va: u64, hook_info: &HookInfo) -> Result<(), HypervisorError> {
+        let remaining_hooks = self.memory_manager.remove_hook(guest_page_pa.as_u64(), hook_info.guest_function_pa)?;
+
+        if remaining_hooks > 0 {
+            // Other hooks still share the shadow page, so only restore the original bytes overwritten by this hook.
+            debug!("{} hooks remain on guest page: {:#x}, restoring the original bytes only", remaining_hooks, guest_page_pa.as_u64());
+
+            let shadow_page_pa = self
+                .memory_manager
+                .get_shadow_page_as_ptr(guest_page_pa.as_u64())
+                .ok_or(HypervisorError::ShadowPageNotFound)?;
+            let range = Self::hook_range_in_page(guest_page_pa, PAddr::from(hook_info.guest_function_pa), hook_info.ept_hook_type);
+
+            unsafe {
+                copy_nonoverlapping(
+                    (guest_page_pa.as_u64() + range.start as u64) as *const u8,
+                    (shadow_page_pa + range.start as u64) as *mut u8,
+                    range.len(),
+                )
+            };
+
+            return self
+                .hook_views
+                .refresh_variant_pages(&self.memory_manager, kernel_image().base_va, guest_page_pa.as_u64());
+        }
 
         let pre_alloc_pt = self
             .memory_manager
-            .get_page_table_as_mut(guest_large_page_pa.as_u64())
+            .get_page_table_as_mut(guest_page_pa.align_down_to_large_page().as_u64())
             .ok_or(HypervisorError::PageTableNotFound)?;
 
         // Swap the page back and restore the original page permissions
         vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
+            .swap_page_deferred(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
+
+        self.flush_tlb(vm, Some(guest_page_va..guest_page_va + BASE_PAGE_SIZE as u64));
 
         // Update the memory manager to indicate that the guest page is no longer processed (unmapped/unhooked).
         // This will allow the page to be reprocessed/remapped/rehooked if needed.
         self.memory_manager.unmap_guest_from_shadow_page(guest_page_pa.as_u64())?;
+        self.hook_views.release_variant_pages(&mut self.memory_manager, guest_page_pa.as_u64());
 
         Ok(())
     }
@@ -512,29 +1363,50 @@ impl HookManager {
     /// Calculates the number of instructions that fit into the given number of bytes,
     /// adjusting for partial instruction overwrites by including the next full instruction.
     ///
+    /// If the bytes cross the end of the page and `guest_next_page_pa` is provided, the bytes past the
+    /// page boundary are read from the next guest page, which may not be physically contiguous.
+    ///
     /// # Safety
     ///
     /// This function is unsafe because it performs operations on raw pointers. The caller must
     /// ensure that the memory at `guest_pa` (converted properly to a virtual address if necessary)
     /// is valid and that reading beyond `hook_size` bytes does not cause memory violations.
-    pub unsafe fn calculate_instruction_count(guest_pa: u64, hook_size: usize) -> usize {
+    pub unsafe fn calculate_instruction_count(guest_pa: u64, guest_next_page_pa: Option<u64>, hook_size: usize) -> usize {
         // Define a buffer size, typical maximum x86-64 instruction length is 15 bytes.
-        let buffer_size = hook_size + 15; // Buffer size to read, slightly larger than hook_size to accommodate potential long instructions at the boundary.
-        let bytes = core::slice::from_raw_parts(guest_pa as *const u8, buffer_size);
-
-        let mut byte_count = 0;
-        let mut instruction_count = 0;
-        // Use a disassembler engine to iterate over the instructions within the bytes read.
-        for (opcode, pa) in lde::X64.iter(bytes, guest_pa) {
-            byte_count += opcode.len();
-            instruction_count += 1;
-
-            trace!("{:x}: {}", pa, opcode);
-            if byte_count >= hook_size {
-                break;
-            }
+        let mut buffer = [0u8; 32];
+        let buffer_size = (hook_size + 15).min(buffer.len()); // Buffer size to read, slightly larger than hook_size to accommodate potential long instructions at the boundary.
+
+        let first_page_length = match guest_next_page_pa {
+            Some(_) => (BASE_PAGE_SIZE - PAddr::from(guest_pa).base_page_offset() as usize).min(buffer_size),
+            None => buffer_size,
+        };
+
+        copy_nonoverlapping(guest_pa as *const u8, buffer.as_mut_ptr(), first_page_length);
+
+        if let Some(guest_next_page_pa) = guest_next_page_pa {
+            copy_nonoverlapping(guest_next_page_pa as *const u8, buffer.as_mut_ptr().add(first_page_length), buffer_size - first_page_length);
         }
 
+        let bytes = &buffer[..buffer_size];
+
+        // Round the hook up to a whole number of instructions, falling back to the generic length disassembler
+        // for instructions the inline hook decoder doesn't support (e.g., VEX-encoded instructions).
+        let (byte_count, instruction_count) = InlineHook::stolen_bytes_length(bytes, hook_size).unwrap_or_else(|| {
+            let mut byte_count = 0;
+            let mut instruction_count = 0;
+            // Use a disassembler engine to iterate over the instructions within the bytes read.
+            for (opcode, pa) in lde::X64.iter(bytes, guest_pa) {
+                byte_count += opcode.len();
+                instruction_count += 1;
+
+                trace!("{:x}: {}", pa, opcode);
+                if byte_count >= hook_size {
+                    break;
+                }
+            }
+            (byte_count, instruction_count)
+        });
+
         trace!("Calculated byte count: {}", byte_count);
         trace!("Calculated instruction count: {}", instruction_count);
 
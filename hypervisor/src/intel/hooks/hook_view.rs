@@ -0,0 +1,689 @@
+//! Provides alternate hook views, in which the hooked functions carry different payloads, or none, so researchers
+//! can compare instrumented and clean behavior on a live system without reinstalling the hooks.
+//!
+//! The default view (0) executes the shadow pages of the hook manager, with every hook installed and the callbacks
+//! registered with `register_hook_callbacks`. An alternate view enables a subset of the hooked functions, each with
+//! the default callbacks or its own. In an alternate view, a hooked guest page is executed from its shadow page if
+//! all its hooks are enabled, from the original guest page if none is, and otherwise from a variant shadow page built
+//! on demand with only the enabled hooks. A view in which no hook is enabled is therefore a clean view.
+//!
+//! Each logical processor uses the view assigned to the current process, if any, then the view assigned to the
+//! processor, then the default processor view. While views are assigned to processes, MOV to CR3 causes VM exits so
+//! the view follows the context switches of the guest. The user address space of a process (KVA shadow) keeps the
+//! view of its kernel address space, as the hooked kernel functions are only executed in the latter.
+//!
+//! A hooked function can also be bound to a process, in which case it is only enabled, in every view, while the
+//! current address space is the one of that process, and the other processes execute the function without the hook.
+//! The pages are selected for the view and the current process in the same way, with the variant shadow pages built
+//! per view and bound process, and MOV to CR3 causes VM exits while hooks are bound to processes.
+//!
+//! When the view of a logical processor changes, its hooked guest pages are made non-executable, so the next
+//! instruction fetch from each of them maps the execute page of the new view. The assignments are kept by the hook
+//! manager, and each logical processor copies them at its first VM exit after they changed.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            hooks::{
+                callbacks::HookCallbacks,
+                hook_manager::{EptHookType, HookManager, SHARED_HOOK_MANAGER},
+                inline::InlineHook,
+                memory_manager::{HookInfo, MemoryManager},
+            },
+            paging::CR3_ADDRESS_MASK,
+            seqlock::{Generation, Published},
+            single_step::SingleStepOwner,
+            support::vmread,
+            vm::Vm,
+            vmexit::cr::update_cr3_load_exiting,
+        },
+    },
+    alloc::collections::BTreeMap,
+    core::intrinsics::copy_nonoverlapping,
+    log::*,
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        vmx::vmcs,
+    },
+};
+
+/// The identifier of a hook view.
+pub type HookViewId = u8;
+
+/// The default view, with every hook installed.
+pub const DEFAULT_HOOK_VIEW: HookViewId = 0;
+
+/// The maximum number of processes with an assigned view, copied to each logical processor.
+pub const MAX_PROCESS_HOOK_VIEWS: usize = 8;
+
+/// The maximum number of processes with bound hooks, copied to each logical processor.
+pub const MAX_HOOK_PROCESSES: usize = 8;
+
+/// The process ID used while the current address space isn't the one of a process with bound hooks.
+pub const NO_HOOK_PROCESS: u64 = 0;
+
+/// The changes of the views and their assignments, published while the hook manager is locked.
+static HOOK_VIEW_CHANGES: Published<()> = Published::new(());
+
+/// A view assigned to a process, identified by its address spaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessHookView {
+    /// The directory table base of the kernel address space of the process.
+    pub directory_table_base: u64,
+
+    /// The directory table base of the user address space of the process with KVA shadow, or 0.
+    pub user_directory_table_base: u64,
+
+    /// The view of the process.
+    pub view: HookViewId,
+}
+
+/// A process hooked functions are bound to, identified by its address spaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessHookScope {
+    /// The process ID.
+    pub process_id: u64,
+
+    /// The directory table base of the kernel address space of the process.
+    pub directory_table_base: u64,
+
+    /// The directory table base of the user address space of the process with KVA shadow, or 0.
+    pub user_directory_table_base: u64,
+}
+
+/// The alternate hook views and their assignments, shared by all the logical processors.
+#[derive(Debug, Clone, Default)]
+pub struct HookViews {
+    /// The hooked functions enabled in each alternate view, by view and relative virtual address (RVA) from the base
+    /// of ntoskrnl.exe, with their callbacks if they differ from the callbacks of the default view.
+    views: BTreeMap<HookViewId, BTreeMap<u64, Option<HookCallbacks>>>,
+
+    /// The variant shadow pages, by view, bound process (or `NO_HOOK_PROCESS`) and guest page physical address.
+    variant_pages: BTreeMap<(HookViewId, u64, u64), u64>,
+
+    /// The processes the hooked functions are bound to, by relative virtual address (RVA) from the base of
+    /// ntoskrnl.exe.
+    hook_scopes: BTreeMap<u64, ProcessHookScope>,
+
+    /// The views assigned to processes, by process ID.
+    process_views: BTreeMap<u64, ProcessHookView>,
+
+    /// The views assigned to logical processors, by initial APIC ID.
+    processor_views: BTreeMap<u32, HookViewId>,
+
+    /// The view of the logical processors without an assigned view.
+    default_processor_view: HookViewId,
+}
+
+impl HookViews {
+    /// Creates the hook views, with every logical processor in the default view.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables a hooked function in an alternate view, replacing its callbacks in the view if it is already enabled.
+    ///
+    /// The hook itself is installed separately in the default view, e.g., through `manage_kernel_ept_hook`.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The alternate view.
+    /// * `function_rva` - The relative virtual address of the hooked function from the base of ntoskrnl.exe.
+    /// * `callbacks` - The callbacks of the function in the view, or `None` to use the callbacks of the default view.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The function was enabled in the view.
+    /// * `Err(HypervisorError::InvalidHookView)` - If the view is the default view, in which every hook is enabled.
+    pub fn enable_hook(&mut self, view: HookViewId, function_rva: u64, callbacks: Option<HookCallbacks>) -> Result<(), HypervisorError> {
+        if view == DEFAULT_HOOK_VIEW {
+            return Err(HypervisorError::InvalidHookView);
+        }
+
+        debug!("Enabling hook for function at RVA: {:#x} in view {}", function_rva, view);
+        self.views.entry(view).or_default().insert(function_rva, callbacks);
+        Self::publish();
+
+        Ok(())
+    }
+
+    /// Disables a hooked function in an alternate view, so the view executes its original code.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The alternate view.
+    /// * `function_rva` - The relative virtual address of the hooked function from the base of ntoskrnl.exe.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The function was disabled in the view.
+    /// * `Err(HypervisorError::InvalidHookView)` - If the view is the default view, in which every hook is enabled.
+    pub fn disable_hook(&mut self, view: HookViewId, function_rva: u64) -> Result<(), HypervisorError> {
+        if view == DEFAULT_HOOK_VIEW {
+            return Err(HypervisorError::InvalidHookView);
+        }
+
+        debug!("Disabling hook for function at RVA: {:#x} in view {}", function_rva, view);
+        if let Some(functions) = self.views.get_mut(&view) {
+            functions.remove(&function_rva);
+        }
+        Self::publish();
+
+        Ok(())
+    }
+
+    /// Assigns a view to a process, or removes its assignment.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The process ID.
+    /// * `process_view` - The address spaces and the view of the process, or `None` to remove the assignment.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The assignment was changed.
+    /// * `Err(HypervisorError::TooManyProcessHookViews)` - If `MAX_PROCESS_HOOK_VIEWS` processes already have a view.
+    pub fn assign_process_view(&mut self, process_id: u64, process_view: Option<ProcessHookView>) -> Result<(), HypervisorError> {
+        debug!("Assigning view of process {}: {:x?}", process_id, process_view);
+
+        match process_view {
+            Some(process_view) => {
+                if !self.process_views.contains_key(&process_id) && self.process_views.len() >= MAX_PROCESS_HOOK_VIEWS {
+                    return Err(HypervisorError::TooManyProcessHookViews);
+                }
+                self.process_views.insert(process_id, process_view);
+            }
+            None => {
+                self.process_views.remove(&process_id);
+            }
+        }
+        Self::publish();
+
+        Ok(())
+    }
+
+    /// Binds a hooked function to a process, so it is only enabled while the current address space is the one of the
+    /// process, or removes its binding.
+    ///
+    /// The hook itself is installed separately in the default view, e.g., through `manage_kernel_ept_hook`.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_rva` - The relative virtual address of the hooked function from the base of ntoskrnl.exe.
+    /// * `scope` - The process and its address spaces, or `None` to enable the function in every process again.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The binding was changed.
+    /// * `Err(HypervisorError::TooManyHookProcesses)` - If hooks are already bound to `MAX_HOOK_PROCESSES` other processes.
+    pub fn bind_hook(&mut self, function_rva: u64, scope: Option<ProcessHookScope>) -> Result<(), HypervisorError> {
+        debug!("Binding hook for function at RVA: {:#x} to process: {:x?}", function_rva, scope);
+
+        match scope {
+            Some(scope) => {
+                let is_new_process = !self.hook_scopes.values().any(|bound| bound.process_id == scope.process_id);
+                if is_new_process && self.hook_processes().count() >= MAX_HOOK_PROCESSES {
+                    return Err(HypervisorError::TooManyHookProcesses);
+                }
+
+                // The address spaces of a process are the same for all its hooks.
+                for bound in self.hook_scopes.values_mut().filter(|bound| bound.process_id == scope.process_id) {
+                    *bound = scope;
+                }
+                self.hook_scopes.insert(function_rva, scope);
+            }
+            None => {
+                self.hook_scopes.remove(&function_rva);
+            }
+        }
+        Self::publish();
+
+        Ok(())
+    }
+
+    /// Returns the processes with bound hooks, once each.
+    fn hook_processes(&self) -> impl Iterator<Item = &ProcessHookScope> {
+        self.hook_scopes
+            .values()
+            .enumerate()
+            .filter(|(index, scope)| !self.hook_scopes.values().take(*index).any(|bound| bound.process_id == scope.process_id))
+            .map(|(_, scope)| scope)
+    }
+
+    /// Assigns a view to a logical processor, or to the logical processors without an assigned view.
+    ///
+    /// # Arguments
+    ///
+    /// * `processor_id` - The initial APIC ID of the logical processor, or `None` for the default processor view.
+    /// * `view` - The view, or `None` to remove the assignment of the logical processor.
+    pub fn assign_processor_view(&mut self, processor_id: Option<u32>, view: Option<HookViewId>) {
+        debug!("Assigning view of processor {:?}: {:?}", processor_id, view);
+
+        match (processor_id, view) {
+            (Some(processor_id), Some(view)) => {
+                self.processor_views.insert(processor_id, view);
+            }
+            (Some(processor_id), None) => {
+                self.processor_views.remove(&processor_id);
+            }
+            (None, view) => self.default_processor_view = view.unwrap_or(DEFAULT_HOOK_VIEW),
+        }
+        Self::publish();
+    }
+
+    /// Returns the callbacks of a hooked function specific to a view, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The view.
+    /// * `function_rva` - The relative virtual address of the hooked function from the base of ntoskrnl.exe.
+    pub fn get_view_callbacks(&self, view: HookViewId, function_rva: u64) -> Option<HookCallbacks> {
+        *self.views.get(&view)?.get(&function_rva)?
+    }
+
+    /// Returns `true` if a hooked function is enabled in a view while a process is current.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The view.
+    /// * `process_id` - The current process with bound hooks, or `NO_HOOK_PROCESS`.
+    /// * `function_rva` - The relative virtual address of the hooked function from the base of ntoskrnl.exe.
+    fn is_hook_enabled(&self, view: HookViewId, process_id: u64, function_rva: u64) -> bool {
+        let is_in_scope = self.hook_scopes.get(&function_rva).is_none_or(|scope| scope.process_id == process_id);
+
+        is_in_scope && (view == DEFAULT_HOOK_VIEW || self.views.get(&view).is_some_and(|functions| functions.contains_key(&function_rva)))
+    }
+
+    /// Returns the physical address of the page executed for a hooked guest page in a view while a process is current,
+    /// building its variant shadow page if it is needed and doesn't exist yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_manager` - The memory manager of the hook manager.
+    /// * `ntoskrnl_base_va` - The base virtual address of ntoskrnl.exe.
+    /// * `view` - The view.
+    /// * `process_id` - The current process with bound hooks, or `NO_HOOK_PROCESS`.
+    /// * `guest_page_pa` - The physical address of the hooked guest page.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The shadow page, the original guest page or the variant shadow page.
+    /// * `Err(HypervisorError)` - If the guest page isn't hooked, or no page is available for the variant shadow page.
+    pub fn execute_page_pa(
+        &mut self,
+        memory_manager: &mut MemoryManager,
+        ntoskrnl_base_va: u64,
+        view: HookViewId,
+        process_id: u64,
+        guest_page_pa: u64,
+    ) -> Result<u64, HypervisorError> {
+        let shadow_page_pa = memory_manager
+            .get_shadow_page_as_ptr(guest_page_pa)
+            .ok_or(HypervisorError::ShadowPageNotFound)?;
+
+        if view == DEFAULT_HOOK_VIEW && self.hook_scopes.is_empty() {
+            return Ok(shadow_page_pa);
+        }
+
+        let hooks = memory_manager.get_hook_info(guest_page_pa).ok_or(HypervisorError::HookInfoNotFound)?;
+
+        // The pages of the processes without hooks bound on this page are the same, so they share their variant.
+        let process_id = match hooks.iter().any(|hook| {
+            self.hook_scopes
+                .get(&hook.guest_function_va.wrapping_sub(ntoskrnl_base_va))
+                .is_some_and(|scope| scope.process_id == process_id)
+        }) {
+            true => process_id,
+            false => NO_HOOK_PROCESS,
+        };
+
+        let enabled_count = hooks
+            .iter()
+            .filter(|hook| self.is_hook_enabled(view, process_id, hook.guest_function_va.wrapping_sub(ntoskrnl_base_va)))
+            .count();
+
+        if enabled_count == 0 {
+            return Ok(guest_page_pa);
+        }
+
+        if enabled_count == hooks.len() {
+            return Ok(shadow_page_pa);
+        }
+
+        if let Some(&variant_page_pa) = self.variant_pages.get(&(view, process_id, guest_page_pa)) {
+            return Ok(variant_page_pa);
+        }
+
+        let variant_page_pa = memory_manager.allocate_page().ok_or(HypervisorError::ShadowPagesUnavailable)?;
+        debug!(
+            "Building variant shadow page: {:#x} of guest page: {:#x} for view {} and process {}",
+            variant_page_pa, guest_page_pa, view, process_id
+        );
+
+        self.build_variant_page(memory_manager, ntoskrnl_base_va, view, process_id, guest_page_pa, variant_page_pa)?;
+        self.variant_pages.insert((view, process_id, guest_page_pa), variant_page_pa);
+
+        Ok(variant_page_pa)
+    }
+
+    /// Rebuilds the variant shadow pages of a hooked guest page after its hooks changed or it has been resynchronized,
+    /// and makes the logical processors in an alternate view or with bound hooks pick up the change.
+    ///
+    /// The variant shadow pages are rebuilt in place, as they may still be mapped by other logical processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_manager` - The memory manager of the hook manager.
+    /// * `ntoskrnl_base_va` - The base virtual address of ntoskrnl.exe.
+    /// * `guest_page_pa` - The physical address of the hooked guest page.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the variant shadow pages were rebuilt, `Err(HypervisorError)` otherwise.
+    pub fn refresh_variant_pages(&self, memory_manager: &MemoryManager, ntoskrnl_base_va: u64, guest_page_pa: u64) -> Result<(), HypervisorError> {
+        for (&(view, process_id, _), &variant_page_pa) in self.variant_pages.iter().filter(|(&(_, _, page_pa), _)| page_pa == guest_page_pa) {
+            self.build_variant_page(memory_manager, ntoskrnl_base_va, view, process_id, guest_page_pa, variant_page_pa)?;
+        }
+
+        if !self.views.is_empty() || !self.hook_scopes.is_empty() {
+            Self::publish();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the variant shadow pages of a guest page that is no longer hooked to the page pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_manager` - The memory manager of the hook manager.
+    /// * `guest_page_pa` - The physical address of the guest page.
+    pub fn release_variant_pages(&mut self, memory_manager: &mut MemoryManager, guest_page_pa: u64) {
+        self.variant_pages.retain(|&(_, _, page_pa), &mut variant_page_pa| {
+            if page_pa == guest_page_pa {
+                memory_manager.free_page(variant_page_pa);
+            }
+            page_pa != guest_page_pa
+        });
+    }
+
+    /// Copies a hooked guest page to a variant shadow page and writes the bytes of the hooks enabled in a view while a
+    /// process is current.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_manager` - The memory manager of the hook manager.
+    /// * `ntoskrnl_base_va` - The base virtual address of ntoskrnl.exe.
+    /// * `view` - The view.
+    /// * `process_id` - The current process with bound hooks, or `NO_HOOK_PROCESS`.
+    /// * `guest_page_pa` - The physical address of the hooked guest page.
+    /// * `variant_page_pa` - The physical address of the variant shadow page.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the variant shadow page was built, `Err(HypervisorError)` otherwise.
+    fn build_variant_page(
+        &self,
+        memory_manager: &MemoryManager,
+        ntoskrnl_base_va: u64,
+        view: HookViewId,
+        process_id: u64,
+        guest_page_pa: u64,
+        variant_page_pa: u64,
+    ) -> Result<(), HypervisorError> {
+        let hooks = memory_manager.get_hook_info(guest_page_pa).ok_or(HypervisorError::HookInfoNotFound)?;
+
+        HookManager::unsafe_copy_guest_to_shadow(PAddr::from(guest_page_pa), PAddr::from(variant_page_pa));
+
+        let enabled_hooks = hooks
+            .iter()
+            .filter(|hook| self.is_hook_enabled(view, process_id, hook.guest_function_va.wrapping_sub(ntoskrnl_base_va)));
+
+        write_hook_bytes(PAddr::from(guest_page_pa), PAddr::from(variant_page_pa), enabled_hooks)
+    }
+
+    /// Publishes a change, which the logical processors pick up on their next VM exit.
+    fn publish() {
+        HOOK_VIEW_CHANGES.publish(());
+    }
+}
+
+/// Writes the bytes of hooks to the shadow page of a hooked guest page, splitting the hooks crossing the page boundary.
+///
+/// # Arguments
+///
+/// * `guest_page_pa` - The physical address of the hooked guest page.
+/// * `shadow_page_pa` - The physical address of the shadow page.
+/// * `hooks` - The hooks to write, recorded on the guest page.
+///
+/// # Returns
+///
+/// * Returns `Ok(())` if the hooks were written, `Err(HypervisorError)` if a hook couldn't be encoded.
+pub fn write_hook_bytes<'a>(guest_page_pa: PAddr, shadow_page_pa: PAddr, hooks: impl Iterator<Item = &'a HookInfo>) -> Result<(), HypervisorError> {
+    for hook in hooks {
+        let EptHookType::Function(inline_hook_type) = hook.ept_hook_type else {
+            continue;
+        };
+
+        let shellcode = InlineHook::shellcode(inline_hook_type, hook.guest_function_va)?;
+        let guest_function_pa = PAddr::from(hook.guest_function_pa);
+        let function_offset = guest_function_pa.base_page_offset() as usize;
+
+        // The hook bytes that don't fit to the end of the function's page are at the start of the next page.
+        let (bytes, page_offset) = if guest_function_pa.align_down_to_base_page() == guest_page_pa {
+            (&shellcode[..shellcode.len().min(BASE_PAGE_SIZE - function_offset)], function_offset)
+        } else {
+            (&shellcode[BASE_PAGE_SIZE - function_offset..], 0)
+        };
+
+        trace!("Writing hook for function at VA: {:#x}", hook.guest_function_va);
+        unsafe { copy_nonoverlapping(bytes.as_ptr(), (shadow_page_pa.as_u64() + page_offset as u64) as *mut u8, bytes.len()) };
+    }
+
+    Ok(())
+}
+
+/// The hook view state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorHookView {
+    /// The generation of the assignments in use on this logical processor.
+    generation: Generation,
+
+    /// The view assigned to this logical processor.
+    processor_view: HookViewId,
+
+    /// The views assigned to processes.
+    process_views: [Option<ProcessHookView>; MAX_PROCESS_HOOK_VIEWS],
+
+    /// The processes with bound hooks.
+    hook_processes: [Option<ProcessHookScope>; MAX_HOOK_PROCESSES],
+
+    /// The view in use on this logical processor.
+    pub active_view: HookViewId,
+
+    /// The process with bound hooks in use on this logical processor, or `NO_HOOK_PROCESS`.
+    pub active_process_id: u64,
+}
+
+impl ProcessorHookView {
+    /// Creates a new state in the default view.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if views are assigned to processes, so the view depends on the address space.
+    pub fn has_process_views(&self) -> bool {
+        self.process_views.iter().any(Option::is_some)
+    }
+
+    /// Returns `true` if hooks are bound to processes, so the enabled hooks depend on the address space.
+    pub fn has_hook_processes(&self) -> bool {
+        self.hook_processes.iter().any(Option::is_some)
+    }
+
+    /// Returns the view of an address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The guest CR3.
+    ///
+    /// # Returns
+    ///
+    /// The view of the process of the address space, the view of this logical processor for the other address spaces,
+    /// or `None` to keep the view in use for the user address space of a process with a view.
+    fn view_of_address_space(&self, cr3: u64) -> Option<HookViewId> {
+        let directory_table_base = cr3 & CR3_ADDRESS_MASK;
+
+        for process_view in self.process_views.iter().flatten() {
+            if process_view.directory_table_base & CR3_ADDRESS_MASK == directory_table_base {
+                return Some(process_view.view);
+            }
+
+            if process_view.user_directory_table_base & CR3_ADDRESS_MASK == directory_table_base {
+                return None;
+            }
+        }
+
+        Some(self.processor_view)
+    }
+
+    /// Returns the process with bound hooks of an address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The guest CR3.
+    ///
+    /// # Returns
+    ///
+    /// The process of the address space, `NO_HOOK_PROCESS` for the address spaces of the other processes, or `None`
+    /// to keep the process in use for the user address space of a process with bound hooks.
+    fn process_of_address_space(&self, cr3: u64) -> Option<u64> {
+        let directory_table_base = cr3 & CR3_ADDRESS_MASK;
+
+        for scope in self.hook_processes.iter().flatten() {
+            if scope.directory_table_base & CR3_ADDRESS_MASK == directory_table_base {
+                return Some(scope.process_id);
+            }
+
+            if scope.user_directory_table_base & CR3_ADDRESS_MASK == directory_table_base {
+                return None;
+            }
+        }
+
+        Some(NO_HOOK_PROCESS)
+    }
+}
+
+/// Picks up new view assignments and hook bindings on the current logical processor and switches it to the view of the
+/// current process or of the processor, and to the hooks bound to the current process, when they changed.
+///
+/// This is called on every VM exit, and only locks the hook manager when the assignments or the view changed.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_hook_views(vm: &mut Vm) {
+    // The hooked pages can't be remapped while the instructions overwritten by a hook are being single-stepped.
+    if vm.single_step.is_stepping_for(SingleStepOwner::HookRestoration) {
+        return;
+    }
+
+    let had_hook_processes = vm.hook_view.has_hook_processes();
+
+    // A change published after the generation is picked up is picked up again at the next VM exit.
+    let is_changed = HOOK_VIEW_CHANGES.sync(&mut vm.hook_view.generation).is_some();
+
+    if is_changed {
+        let hook_manager = SHARED_HOOK_MANAGER.lock();
+        let hook_views = &hook_manager.hook_views;
+        let processor_id = vm.cpuid_feature_info.initial_local_apic_id() as u32;
+
+        vm.hook_view.processor_view = *hook_views
+            .processor_views
+            .get(&processor_id)
+            .unwrap_or(&hook_views.default_processor_view);
+        vm.hook_view.process_views = [None; MAX_PROCESS_HOOK_VIEWS];
+        for (slot, process_view) in vm.hook_view.process_views.iter_mut().zip(hook_views.process_views.values()) {
+            *slot = Some(*process_view);
+        }
+        vm.hook_view.hook_processes = [None; MAX_HOOK_PROCESSES];
+        for (slot, scope) in vm.hook_view.hook_processes.iter_mut().zip(hook_views.hook_processes()) {
+            *slot = Some(*scope);
+        }
+        drop(hook_manager);
+
+        update_cr3_load_exiting(vm);
+    }
+
+    let cr3 = match vm.hook_view.has_process_views() || vm.hook_view.has_hook_processes() {
+        true => Some(vmread(vmcs::guest::CR3)),
+        false => None,
+    };
+
+    let view = match vm.hook_view.has_process_views() {
+        true => cr3
+            .and_then(|cr3| vm.hook_view.view_of_address_space(cr3))
+            .unwrap_or(vm.hook_view.active_view),
+        false => vm.hook_view.processor_view,
+    };
+
+    let process_id = match vm.hook_view.has_hook_processes() {
+        true => cr3
+            .and_then(|cr3| vm.hook_view.process_of_address_space(cr3))
+            .unwrap_or(vm.hook_view.active_process_id),
+        false => NO_HOOK_PROCESS,
+    };
+
+    // The pages of an alternate view, or of any view while hooks are bound to processes, may have changed along with
+    // the assignments, the pages of the default view otherwise can't.
+    let are_pages_changed = is_changed && (view != DEFAULT_HOOK_VIEW || had_hook_processes || vm.hook_view.has_hook_processes());
+    if view == vm.hook_view.active_view && process_id == vm.hook_view.active_process_id && !are_pages_changed {
+        return;
+    }
+
+    if let Err(e) = switch_hook_view(vm, view, process_id) {
+        error!("Failed to switch to hook view {} of process {}: {:?}", view, process_id, e);
+    }
+}
+
+/// Switches the current logical processor to a view and a process with bound hooks, by making its hooked guest pages
+/// non-executable so the next instruction fetch from each of them maps the execute page of the view and the process.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `view` - The new view.
+/// * `process_id` - The new process with bound hooks, or `NO_HOOK_PROCESS`.
+///
+/// # Returns
+///
+/// * Returns `Ok(())` if the view is in use, `Err(HypervisorError)` otherwise.
+fn switch_hook_view(vm: &mut Vm, view: HookViewId, process_id: u64) -> Result<(), HypervisorError> {
+    trace!(
+        "Switching from hook view {} of process {} to {} of process {}",
+        vm.hook_view.active_view,
+        vm.hook_view.active_process_id,
+        view,
+        process_id
+    );
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    let guest_page_access_type = hook_manager.guest_page_access_type();
+
+    for guest_page_pa in hook_manager.memory_manager.hooked_guest_pages() {
+        let pre_alloc_pt = hook_manager
+            .memory_manager
+            .get_page_table_as_mut(PAddr::from(guest_page_pa).align_down_to_large_page().as_u64())
+            .ok_or(HypervisorError::PageTableNotFound)?;
+
+        vm.primary_ept
+            .swap_page_deferred(guest_page_pa, guest_page_pa, guest_page_access_type, pre_alloc_pt)?;
+    }
+
+    vm.primary_ept.invalidate_ept_cache()?;
+    vm.hook_view.active_view = view;
+    vm.hook_view.active_process_id = process_id;
+
+    Ok(())
+}
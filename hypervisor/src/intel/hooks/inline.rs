@@ -1,11 +1,33 @@
-use {core::ptr::copy_nonoverlapping, log::*};
+use {crate::error::HypervisorError, alloc::vec::Vec, core::ptr::copy_nonoverlapping, log::*};
 
 /// Enum to define the types of inline hooks we support.
+///
+/// The exit-based hooks (`Int3`, `Cpuid`, `Vmcall`, `Int3Stub`) cause a VM exit or an exception handled by the hypervisor,
+/// while the jump-based hooks redirect the execution to a handler at the given guest virtual address without leaving
+/// the guest. The handler can return to the original function through a trampoline (see `InlineHook::build_trampoline`).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InlineHookType {
+    /// `int3` (1 byte).
     Int3,
+
+    /// `cpuid` (2 bytes).
     Cpuid,
+
+    /// `vmcall` (3 bytes).
     Vmcall,
+
+    /// `int3` padded with `int3` to the size of `JmpRel32` (5 bytes), so a hook can switch between both encodings
+    /// without changing the stolen bytes.
+    Int3Stub,
+
+    /// `jmp rel32` to the handler (5 bytes), which must be within ±2 GiB of the function.
+    JmpRel32(u64),
+
+    /// `push imm32; mov dword ptr [rsp+4], imm32; ret` to the handler (14 bytes), without clobbering any register.
+    PushRet(u64),
+
+    /// `mov rax, imm64; jmp rax` to the handler (12 bytes), clobbering RAX.
+    MovRaxJmp(u64),
 }
 
 /// Structure representing our hook configuration.
@@ -14,6 +36,9 @@ pub struct InlineHook {
     /// The physical address of the shadow function.
     pub shadow_function_pa: *mut u8,
 
+    /// The guest virtual address of the function, which relative jumps are based on.
+    pub guest_function_va: u64,
+
     /// The type of hook we are using.
     pub hook_type: InlineHookType,
 }
@@ -24,41 +49,97 @@ impl InlineHook {
     /// # Arguments
     ///
     /// * `shadow_function_pa` - The physical address of the shadow function.
+    /// * `guest_function_va` - The guest virtual address of the function.
     /// * `hook_type` - The type of hook we are using.
     ///
     /// # Returns
     ///
     /// * `Self` - The new hook configuration.
-    pub fn new(shadow_function_pa: *mut u8, hook_type: InlineHookType) -> Self {
+    pub fn new(shadow_function_pa: *mut u8, guest_function_va: u64, hook_type: InlineHookType) -> Self {
         trace!("Creating a new hook configuration");
 
         Self {
             shadow_function_pa,
+            guest_function_va,
             hook_type,
         }
     }
 
-    /// Performs a detour or hook, from the source to the destination function, by overwriting it with either int3, cpuid, or vmcall instructions.
-    pub fn detour64(&mut self) {
+    /// Performs a detour or hook, from the source to the destination function, by overwriting it with the hook code.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The hook was installed successfully.
+    /// * `Err(HypervisorError::RelativeInstruction)` - If the handler of a `JmpRel32` hook is out of range.
+    pub fn detour64(&mut self) -> Result<(), HypervisorError> {
         trace!("Hook Type: {:?}", self.hook_type);
 
-        let shellcode: &mut [u8] = match self.hook_type {
+        let shellcode = Self::shellcode(self.hook_type, self.guest_function_va)?;
+
+        unsafe {
+            // Then, overwrite the target location with the hook
+            copy_nonoverlapping(shellcode.as_ptr(), self.shadow_function_pa, shellcode.len());
+        }
+
+        trace!("The hook has been installed successfully");
+
+        Ok(())
+    }
+
+    /// Returns the hook code based on the hook type.
+    ///
+    /// This is also used to install a hook whose bytes are split across two shadow pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `hook_type` - The type of hook.
+    /// * `guest_function_va` - The guest virtual address of the function, which relative jumps are based on.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<u8>)` - The instruction bytes of the hook.
+    /// * `Err(HypervisorError::RelativeInstruction)` - If the handler of a `JmpRel32` hook is out of range.
+    pub fn shellcode(hook_type: InlineHookType, guest_function_va: u64) -> Result<Vec<u8>, HypervisorError> {
+        let mut shellcode = Vec::with_capacity(Self::hook_size(hook_type));
+
+        match hook_type {
             // int3 instruction
-            InlineHookType::Int3 => &mut [0xCC],
+            InlineHookType::Int3 => shellcode.push(0xCC),
 
             // cpuid instruction
-            InlineHookType::Cpuid => &mut [0x0F, 0xA2],
+            InlineHookType::Cpuid => shellcode.extend_from_slice(&[0x0F, 0xA2]),
 
             // vmcall instruction
-            InlineHookType::Vmcall => &mut [0x0F, 0x01, 0xC1],
-        };
+            InlineHookType::Vmcall => shellcode.extend_from_slice(&[0x0F, 0x01, 0xC1]),
 
-        unsafe {
-            // Then, overwrite the target location with the hook
-            copy_nonoverlapping(shellcode.as_ptr(), self.shadow_function_pa, shellcode.len());
+            // int3 instructions
+            InlineHookType::Int3Stub => shellcode.extend_from_slice(&[0xCC; 5]),
+
+            // jmp rel32
+            InlineHookType::JmpRel32(handler) => {
+                let displacement = Self::relative_displacement(handler, guest_function_va + 5)?;
+                shellcode.push(0xE9);
+                shellcode.extend_from_slice(&displacement.to_le_bytes());
+            }
+
+            // push imm32 (sign-extended to 64 bits); mov dword ptr [rsp+4], imm32; ret
+            InlineHookType::PushRet(handler) => {
+                shellcode.push(0x68);
+                shellcode.extend_from_slice(&(handler as u32).to_le_bytes());
+                shellcode.extend_from_slice(&[0xC7, 0x44, 0x24, 0x04]);
+                shellcode.extend_from_slice(&((handler >> 32) as u32).to_le_bytes());
+                shellcode.push(0xC3);
+            }
+
+            // mov rax, imm64; jmp rax
+            InlineHookType::MovRaxJmp(handler) => {
+                shellcode.extend_from_slice(&[0x48, 0xB8]);
+                shellcode.extend_from_slice(&handler.to_le_bytes());
+                shellcode.extend_from_slice(&[0xFF, 0xE0]);
+            }
         }
 
-        trace!("The hook has been installed successfully");
+        Ok(shellcode)
     }
 
     /// Returns the size of the hook code in bytes based on the hook type.
@@ -68,9 +149,362 @@ impl InlineHook {
     /// * `usize` - The size of the hook code in bytes.
     pub fn hook_size(hook_type: InlineHookType) -> usize {
         match hook_type {
-            InlineHookType::Int3 => 1,   // int3 is 1 byte
-            InlineHookType::Cpuid => 2,  // cpuid is 2 bytes
-            InlineHookType::Vmcall => 3, // vmcall is 3 bytes
+            InlineHookType::Int3 => 1,          // int3 is 1 byte
+            InlineHookType::Cpuid => 2,         // cpuid is 2 bytes
+            InlineHookType::Vmcall => 3,        // vmcall is 3 bytes
+            InlineHookType::Int3Stub => 5,      // int3 padded to 5 bytes
+            InlineHookType::JmpRel32(_) => 5,   // jmp rel32 is 5 bytes
+            InlineHookType::PushRet(_) => 14,   // push imm32 (5) + mov [rsp+4], imm32 (8) + ret (1)
+            InlineHookType::MovRaxJmp(_) => 12, // mov rax, imm64 (10) + jmp rax (2)
+        }
+    }
+}
+
+/// The maximum length of an x86-64 instruction.
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// The size of the absolute jump back to the original function at the end of a trampoline.
+/// `jmp qword ptr [rip+0]` (FF 25 00 00 00 00) followed by the 8-byte target address.
+pub const TRAMPOLINE_JMP_SIZE: usize = 14;
+
+/// A relative operand of an instruction that must be re-encoded when the instruction is moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeOperand {
+    /// No relative operand, the instruction can be copied as-is.
+    None,
+
+    /// A 32-bit RIP-relative memory operand (ModRM with Mod = 00b and R/M = 101b), at the given offset.
+    RipDisplacement(usize),
+
+    /// A 32-bit branch displacement (`call/jmp rel32`, `jcc rel32`), at the given offset.
+    Branch32(usize),
+
+    /// An 8-bit branch displacement (`jmp rel8`, `jcc rel8`), at the given offset.
+    Branch8(usize),
+
+    /// An 8-bit branch displacement of `loop`, `loope`, `loopne` or `jrcxz`, which have no 32-bit form.
+    Loop8,
+}
+
+/// A decoded x86-64 instruction, with the information needed to relocate it.
+#[derive(Debug, Clone, Copy)]
+pub struct Instruction {
+    /// The length of the instruction in bytes.
+    pub length: usize,
+
+    /// The offset of the (first) opcode byte, past the legacy and REX prefixes.
+    pub opcode_offset: usize,
+
+    /// The relative operand of the instruction, if any.
+    pub relative: RelativeOperand,
+}
+
+impl Instruction {
+    /// Decodes the length and the relative operand of the instruction at the start of `code`.
+    ///
+    /// This is a minimal length disassembler for the general-purpose, x87 and legacy-encoded SSE instructions
+    /// found in function prologues. VEX and EVEX encoded instructions are not supported.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The instruction bytes, at least as long as the instruction.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Instruction>` - The decoded instruction, or `None` if it is invalid or not supported.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Appendix A Opcode Map
+    pub fn decode(code: &[u8]) -> Option<Self> {
+        let byte = |index: usize| code.get(index).copied();
+
+        let mut index = 0;
+        let mut operand_size_override = false;
+
+        // Legacy prefixes.
+        while let Some(prefix @ (0x66 | 0x67 | 0xF0 | 0xF2 | 0xF3 | 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65)) = byte(index) {
+            operand_size_override |= prefix == 0x66;
+            index += 1;
+
+            if index >= MAX_INSTRUCTION_LENGTH {
+                return None;
+            }
         }
+
+        // REX prefix.
+        let rex_w = match byte(index)? {
+            rex @ 0x40..=0x4F => {
+                index += 1;
+                rex & 0x8 != 0
+            }
+            _ => false,
+        };
+
+        let opcode_offset = index;
+        let opcode = byte(index)?;
+        index += 1;
+
+        // The size of a 16/32-bit immediate (Iz): 64-bit operands still use a 32-bit immediate.
+        let immediate_z = if operand_size_override && !rex_w { 2 } else { 4 };
+
+        let mut relative = RelativeOperand::None;
+
+        let (has_modrm, immediate_size) = match opcode {
+            0x0F => {
+                let opcode = byte(index)?;
+                index += 1;
+
+                match opcode {
+                    0x38 => {
+                        index += 1;
+                        (true, 0)
+                    }
+                    0x3A => {
+                        index += 1;
+                        (true, 1)
+                    }
+                    0x80..=0x8F => {
+                        relative = RelativeOperand::Branch32(index);
+                        (false, 4)
+                    }
+                    0x05..=0x09 | 0x0B | 0x30..=0x37 | 0x77 | 0xA0..=0xA2 | 0xA8..=0xAA | 0xC8..=0xCF => (false, 0),
+                    0x70..=0x73 | 0xA4 | 0xAC | 0xBA | 0xC2 | 0xC4..=0xC6 => (true, 1),
+                    0x04 | 0x0A | 0x0C | 0x0E | 0x0F | 0x24..=0x27 | 0x39..=0x3F | 0x7A | 0x7B | 0xA6 | 0xA7 | 0xFF => return None,
+                    _ => (true, 0),
+                }
+            }
+
+            // VEX and EVEX prefixes, and the opcodes that are invalid in 64-bit mode.
+            0xC4 | 0xC5 | 0x62 => return None,
+            0x06 | 0x07 | 0x0E | 0x16 | 0x17 | 0x1E | 0x1F | 0x27 | 0x2F | 0x37 | 0x3F | 0x60 | 0x61 | 0x82 | 0x9A | 0xD4..=0xD6 | 0xEA => {
+                return None;
+            }
+
+            // ALU operations: Eb,Gb / Ev,Gv / Gb,Eb / Gv,Ev, and AL,Ib / rAX,Iz.
+            0x00..=0x3F => match opcode & 0x7 {
+                0..=3 => (true, 0),
+                4 => (false, 1),
+                _ => (false, immediate_z),
+            },
+
+            0x50..=0x5F | 0x6C..=0x6F | 0x90..=0x99 | 0x9B..=0x9F | 0xA4..=0xA7 | 0xAA..=0xAF => (false, 0),
+            0xC3 | 0xC9 | 0xCB | 0xCC | 0xCF | 0xD7 | 0xEC..=0xEF | 0xF1 | 0xF4 | 0xF5 | 0xF8..=0xFD => (false, 0),
+
+            0x63 | 0x84..=0x8F | 0xC0..=0xC1 | 0xD0..=0xD3 | 0xD8..=0xDF | 0xFE | 0xFF => {
+                let immediate_size = if matches!(opcode, 0xC0 | 0xC1) { 1 } else { 0 };
+                (true, immediate_size)
+            }
+
+            0x69 | 0x81 | 0xC7 => (true, immediate_z),
+            0x6B | 0x80 | 0x83 | 0xC6 => (true, 1),
+
+            // TEST Eb,Ib / Ev,Iz are the only forms of groups 3 with an immediate.
+            0xF6 | 0xF7 => {
+                let has_immediate = (byte(index)? >> 3) & 0x7 < 2;
+                let immediate_size = match (has_immediate, opcode) {
+                    (false, _) => 0,
+                    (true, 0xF6) => 1,
+                    (true, _) => immediate_z,
+                };
+                (true, immediate_size)
+            }
+
+            0x68 => (false, immediate_z),
+            0x6A | 0xA8 | 0xB0..=0xB7 | 0xCD | 0xE4..=0xE7 => (false, 1),
+            0xA9 => (false, immediate_z),
+            0xB8..=0xBF => (false, if rex_w { 8 } else { immediate_z }),
+            0xA0..=0xA3 => (false, 8),
+            0xC2 | 0xCA => (false, 2),
+            0xC8 => (false, 3),
+
+            0x70..=0x7F | 0xEB => {
+                relative = RelativeOperand::Branch8(index);
+                (false, 1)
+            }
+            0xE0..=0xE3 => {
+                relative = RelativeOperand::Loop8;
+                (false, 1)
+            }
+            0xE8 | 0xE9 => {
+                relative = RelativeOperand::Branch32(index);
+                (false, 4)
+            }
+
+            // The remaining opcodes (0x40-0x4F) are REX prefixes, which can't follow another REX prefix.
+            _ => return None,
+        };
+
+        if has_modrm {
+            let modrm = byte(index)?;
+            index += 1;
+
+            let mode = modrm >> 6;
+            let rm = modrm & 0x7;
+
+            if mode != 0b11 {
+                // A SIB byte follows when R/M = 100b, with a 32-bit displacement when its base is 101b and Mod = 00b.
+                let has_sib_displacement = rm == 0b100 && {
+                    let sib = byte(index)?;
+                    index += 1;
+                    mode == 0b00 && sib & 0x7 == 0b101
+                };
+
+                match mode {
+                    0b00 if rm == 0b101 => {
+                        relative = RelativeOperand::RipDisplacement(index);
+                        index += 4;
+                    }
+                    0b00 if has_sib_displacement => index += 4,
+                    0b01 => index += 1,
+                    0b10 => index += 4,
+                    _ => {}
+                }
+            }
+        }
+
+        let length = index + immediate_size;
+
+        if length > MAX_INSTRUCTION_LENGTH || length > code.len() {
+            return None;
+        }
+
+        Some(Self {
+            length,
+            opcode_offset,
+            relative,
+        })
+    }
+}
+
+impl InlineHook {
+    /// Calculates the number of bytes stolen by a hook, rounded up to a whole number of instructions.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The original bytes of the function.
+    /// * `hook_size` - The size of the hook code in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(usize, usize)>` - The number of stolen bytes and instructions, or `None` if an instruction can't be decoded.
+    pub fn stolen_bytes_length(code: &[u8], hook_size: usize) -> Option<(usize, usize)> {
+        let mut length = 0;
+        let mut instruction_count = 0;
+
+        while length < hook_size {
+            length += Instruction::decode(&code[length..])?.length;
+            instruction_count += 1;
+        }
+
+        Some((length, instruction_count))
+    }
+
+    /// Builds a trampoline executing the instructions stolen by a hook, and jumping back to the rest of the function.
+    ///
+    /// The stolen bytes are a whole number of instructions. RIP-relative memory operands and branches are
+    /// re-encoded for the address of the trampoline, and short branches are widened to their 32-bit forms.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The original bytes of the function, from its start.
+    /// * `function_address` - The address of the function, in the address space the trampoline runs in.
+    /// * `hook_size` - The size of the hook code in bytes.
+    /// * `trampoline` - The buffer receiving the trampoline.
+    /// * `trampoline_address` - The address of the trampoline, in the address space it runs in.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(usize)` - The length of the trampoline in bytes.
+    /// * `Err(HypervisorError::InvalidBytes)` - If a stolen instruction can't be decoded.
+    /// * `Err(HypervisorError::UnsupportedInstruction)` - If a stolen instruction can't be relocated (`loop`, `jrcxz`).
+    /// * `Err(HypervisorError::RelativeInstruction)` - If a relative operand can't reach its target from the trampoline.
+    /// * `Err(HypervisorError::NotEnoughBytes)` - If the trampoline buffer is too small.
+    pub fn build_trampoline(
+        code: &[u8],
+        function_address: u64,
+        hook_size: usize,
+        trampoline: &mut [u8],
+        trampoline_address: u64,
+    ) -> Result<usize, HypervisorError> {
+        let mut source_offset = 0;
+        let mut trampoline_offset = 0;
+
+        while source_offset < hook_size {
+            let instruction = Instruction::decode(&code[source_offset..]).ok_or(HypervisorError::InvalidBytes)?;
+            let bytes = &code[source_offset..source_offset + instruction.length];
+
+            // The address of the next instruction, which relative operands are based on.
+            let source_next = function_address + (source_offset + instruction.length) as u64;
+
+            let relocated_length = match instruction.relative {
+                RelativeOperand::None => instruction.length,
+                RelativeOperand::RipDisplacement(_) | RelativeOperand::Branch32(_) => instruction.length,
+                // jmp rel8 (EB) becomes jmp rel32 (E9), jcc rel8 (7x) becomes jcc rel32 (0F 8x), without prefixes.
+                RelativeOperand::Branch8(_) if bytes[instruction.opcode_offset] == 0xEB => 5,
+                RelativeOperand::Branch8(_) => 6,
+                RelativeOperand::Loop8 => return Err(HypervisorError::UnsupportedInstruction),
+            };
+
+            let output = trampoline
+                .get_mut(trampoline_offset..trampoline_offset + relocated_length)
+                .ok_or(HypervisorError::NotEnoughBytes)?;
+            let trampoline_next = trampoline_address + (trampoline_offset + relocated_length) as u64;
+
+            match instruction.relative {
+                RelativeOperand::None | RelativeOperand::Loop8 => output.copy_from_slice(bytes),
+                RelativeOperand::RipDisplacement(offset) | RelativeOperand::Branch32(offset) => {
+                    output.copy_from_slice(bytes);
+
+                    let displacement = i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+                    let target = source_next.wrapping_add(displacement as i64 as u64);
+                    output[offset..offset + 4].copy_from_slice(&Self::relative_displacement(target, trampoline_next)?.to_le_bytes());
+                }
+                RelativeOperand::Branch8(offset) => {
+                    let opcode = bytes[instruction.opcode_offset];
+                    let target = source_next.wrapping_add(bytes[offset] as i8 as i64 as u64);
+
+                    let displacement_offset = match opcode {
+                        0xEB => {
+                            output[0] = 0xE9;
+                            1
+                        }
+                        _ => {
+                            output[0] = 0x0F;
+                            output[1] = 0x80 | (opcode & 0xF);
+                            2
+                        }
+                    };
+
+                    output[displacement_offset..].copy_from_slice(&Self::relative_displacement(target, trampoline_next)?.to_le_bytes());
+                }
+            }
+
+            trace!("Relocated instruction at {:#x}: {:02x?}", function_address + source_offset as u64, output);
+
+            source_offset += instruction.length;
+            trampoline_offset += relocated_length;
+        }
+
+        // jmp qword ptr [rip+0], back to the first instruction that wasn't stolen.
+        let output = trampoline
+            .get_mut(trampoline_offset..trampoline_offset + TRAMPOLINE_JMP_SIZE)
+            .ok_or(HypervisorError::NotEnoughBytes)?;
+        output[..6].copy_from_slice(&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00]);
+        output[6..].copy_from_slice(&(function_address + source_offset as u64).to_le_bytes());
+
+        Ok(trampoline_offset + TRAMPOLINE_JMP_SIZE)
+    }
+
+    /// Calculates the 32-bit displacement from the next instruction to a target address.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The target address.
+    /// * `next_instruction` - The address of the instruction following the relative operand.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(i32)` - The displacement, or `Err(HypervisorError::RelativeInstruction)` if it doesn't fit in 32 bits.
+    fn relative_displacement(target: u64, next_instruction: u64) -> Result<i32, HypervisorError> {
+        i32::try_from(target.wrapping_sub(next_instruction) as i64).map_err(|_| HypervisorError::RelativeInstruction)
     }
 }
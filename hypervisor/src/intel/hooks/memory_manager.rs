@@ -1,17 +1,61 @@
 //! Module for managing memory allocations related to Extended Page Tables (EPT)
 //! for a hypervisor. Provides memory resources for EPT hooks and management functionalities
-//! to maintain and access these resources effectively.
+//! to maintain and access these resources effectively. The shadow pages and page tables are
+//! drawn from a refillable `PagePool`, so the number of hooks can grow at runtime.
+//!
+//! The hooked guest pages are also recorded in a filter read without locking the hook manager, so the VM exits of the
+//! pages that aren't hooked, e.g., a hypercall or a breakpoint of a debugger, don't contend on its lock. The pages
+//! returned to the page pool are published, so a logical processor holding on to one it looked up, e.g., to restore a
+//! hook after single-stepping, looks it up again.
 
 use {
     crate::{
-        allocator::box_zeroed,
         error::HypervisorError,
-        intel::{ept::Pt, hooks::hook_manager::EptHookType, page::Page},
+        intel::{
+            ept::Pt,
+            hooks::{hook_manager::EptHookType, page_pool::PagePool},
+            seqlock::Published,
+        },
     },
-    alloc::{boxed::Box, collections::BTreeMap, vec::Vec},
+    alloc::{collections::BTreeMap, vec::Vec},
+    core::sync::atomic::{AtomicU64, Ordering},
     log::trace,
 };
 
+/// The number of bits of the hooked page filter.
+const HOOKED_PAGE_FILTER_BITS: usize = 0x1000;
+
+/// The filter of the hooked guest pages: the bit of each guest page frame number, modulo the size of the filter, is set
+/// while one of the pages sharing it is hooked.
+static HOOKED_PAGE_FILTER: [AtomicU64; HOOKED_PAGE_FILTER_BITS / 64] = [const { AtomicU64::new(0) }; HOOKED_PAGE_FILTER_BITS / 64];
+
+/// The releases of the shadow pages and page tables to the page pool, published while the hook manager is locked.
+static RELEASED_HOOK_PAGES: Published<()> = Published::new(());
+
+/// Returns the word and the mask of the bit of a guest page in the hooked page filter.
+///
+/// # Arguments
+/// * `guest_page_pa` - The guest physical address of the page.
+fn hooked_page_filter_bit(guest_page_pa: u64) -> (usize, u64) {
+    let bit = (guest_page_pa >> 12) as usize % HOOKED_PAGE_FILTER_BITS;
+    (bit / 64, 1 << (bit % 64))
+}
+
+/// Checks if a guest page may be hooked, without locking the hook manager.
+///
+/// A page sharing its bit of the filter with a hooked page may be reported as hooked, the hook manager then telling
+/// them apart, but a hooked page is always reported as hooked.
+///
+/// # Arguments
+/// * `guest_page_pa` - The guest physical address of the page.
+///
+/// # Returns
+/// `false` if the guest page isn't hooked, otherwise `true`.
+pub fn may_be_hooked_page(guest_page_pa: u64) -> bool {
+    let (word, mask) = hooked_page_filter_bit(guest_page_pa);
+    HOOKED_PAGE_FILTER[word].load(Ordering::Acquire) & mask != 0
+}
+
 /// Represents the hook information for a specific guest virtual address and EPT hook type.
 #[derive(Debug, Clone)]
 pub struct HookInfo {
@@ -23,25 +67,30 @@ pub struct HookInfo {
     pub ept_hook_type: EptHookType,
     /// Hash of the function to be hooked.
     pub function_hash: u32,
+    /// Guest physical address of the next page, if the hook bytes cross the end of the function's page.
+    /// The hook is then recorded on both guest pages, each with its own shadow page.
+    pub guest_next_page_pa: Option<u64>,
 }
 
 /// Represents the mapping information for a guest page.
 #[derive(Debug, Clone)]
 pub struct HookMapping {
-    /// The shadow page.
-    pub shadow_page: Box<Page>,
+    /// The physical address of the shadow page, drawn from the page pool.
+    pub shadow_page_pa: u64,
     /// The list of hooks associated with this page.
     pub hooks: Vec<HookInfo>,
 }
 
 /// Represents a memory management system that manages page tables and shadow pages
-/// for a hypervisor, allocating memory as needed at runtime.
+/// for a hypervisor, drawing memory from a page pool as needed at runtime.
 #[derive(Debug, Clone)]
 pub struct MemoryManager {
     /// Mappings of guest physical addresses to their respective hook mappings.
     guest_page_mappings: BTreeMap<u64, HookMapping>,
-    /// Mappings of large guest physical addresses to their respective page tables.
-    large_page_table_mappings: BTreeMap<u64, Box<Pt>>,
+    /// Mappings of large guest physical addresses to the physical addresses of their respective page tables.
+    large_page_table_mappings: BTreeMap<u64, u64>,
+    /// The pool of free pages used for the shadow pages and page tables.
+    page_pool: PagePool,
 }
 
 impl MemoryManager {
@@ -55,9 +104,70 @@ impl MemoryManager {
         Self {
             guest_page_mappings: BTreeMap::new(),
             large_page_table_mappings: BTreeMap::new(),
+            page_pool: PagePool::new(),
         }
     }
 
+    /// Adds a physically contiguous range of pages to the page pool.
+    ///
+    /// This allows the loader to configure the number of hooks at load time, the pool still grows
+    /// from the host heap once these pages are used up.
+    ///
+    /// # Arguments
+    /// * `base_pa` - The page-aligned physical address of the first page.
+    /// * `page_count` - The number of pages to add.
+    pub fn refill_page_pool(&mut self, base_pa: u64, page_count: usize) {
+        self.page_pool.refill(base_pa, page_count);
+    }
+
+    /// Adds a physically contiguous range of pages in the memory of a node to the page pool.
+    ///
+    /// The shadow pages and page tables of the hooks installed by the logical processors of the node are drawn from
+    /// these pages first.
+    ///
+    /// # Arguments
+    /// * `proximity_domain` - The proximity domain of the node the memory belongs to.
+    /// * `base_pa` - The page-aligned physical address of the first page.
+    /// * `page_count` - The number of pages to add.
+    pub fn refill_node_page_pool(&mut self, proximity_domain: u32, base_pa: u64, page_count: usize) {
+        self.page_pool.refill_node(proximity_domain, base_pa, page_count);
+    }
+
+    /// Returns a reference to the page pool, e.g., to query the number of free pages.
+    pub fn page_pool(&self) -> &PagePool {
+        &self.page_pool
+    }
+
+    /// Takes a page from the page pool, e.g., for a variant shadow page of an alternate hook view.
+    ///
+    /// # Returns
+    /// The physical address of the page, or `None` if no free pages are available.
+    pub fn allocate_page(&mut self) -> Option<u64> {
+        self.page_pool.allocate()
+    }
+
+    /// Returns a page taken with `allocate_page` to the page pool.
+    ///
+    /// # Arguments
+    /// * `page_pa` - The physical address of the page.
+    pub fn free_page(&mut self, page_pa: u64) {
+        self.release_page(page_pa);
+    }
+
+    /// Returns a page to the page pool, publishing the release.
+    ///
+    /// # Arguments
+    /// * `page_pa` - The physical address of the page.
+    fn release_page(&mut self, page_pa: u64) {
+        self.page_pool.free(page_pa);
+        RELEASED_HOOK_PAGES.publish(());
+    }
+
+    /// Returns the physical addresses of the hooked guest pages.
+    pub fn hooked_guest_pages(&self) -> Vec<u64> {
+        self.guest_page_mappings.keys().copied().collect()
+    }
+
     /// Checks if a guest page is already processed (split and copied).
     ///
     /// # Arguments
@@ -77,9 +187,10 @@ impl MemoryManager {
     /// * `guest_function_pa` - The guest physical address of the function.
     /// * `ept_hook_type` - The type of EPT hook.
     /// * `function_hash` - The hash of the function.
+    /// * `guest_next_page_pa` - The guest physical address of the next page, if the hook crosses the end of the function's page.
     ///
     /// # Returns
-    /// `Ok(())` if successful, or an error if no free pages are available or if already mapped.
+    /// `Ok(())` if successful, or an error if no free pages are available.
     pub fn map_guest_to_shadow_page(
         &mut self,
         guest_page_pa: u64,
@@ -87,6 +198,7 @@ impl MemoryManager {
         guest_function_pa: u64,
         ept_hook_type: EptHookType,
         function_hash: u32,
+        guest_next_page_pa: Option<u64>,
     ) -> Result<(), HypervisorError> {
         trace!("Mapping guest page and shadow page for PA: {:#x}", guest_page_pa);
 
@@ -95,6 +207,7 @@ impl MemoryManager {
             guest_function_pa,
             ept_hook_type,
             function_hash,
+            guest_next_page_pa,
         };
 
         // Check if the guest page is already mapped
@@ -109,19 +222,38 @@ impl MemoryManager {
             }
         } else {
             trace!("Mapping does not exist, creating new mapping");
-            // Allocate a new shadow page
-            let shadow_page = unsafe { box_zeroed::<Page>() };
+            // Take a new shadow page from the pool
+            let shadow_page_pa = self.page_pool.allocate().ok_or(HypervisorError::ShadowPagesUnavailable)?;
             let mut hooks = Vec::new();
             hooks.push(hook_info);
 
             // Insert new mapping into guest_page_mappings
-            self.guest_page_mappings.insert(guest_page_pa, HookMapping { shadow_page, hooks });
+            self.guest_page_mappings.insert(guest_page_pa, HookMapping { shadow_page_pa, hooks });
+
+            // The page is reported as hooked before the hook is applied to the EPT.
+            let (word, mask) = hooked_page_filter_bit(guest_page_pa);
+            HOOKED_PAGE_FILTER[word].fetch_or(mask, Ordering::Release);
             trace!("Guest page mapped to shadow page successfully");
         }
 
         Ok(())
     }
 
+    /// Rebuilds the hooked page filter from the hooked guest pages, once a page is no longer hooked.
+    ///
+    /// Each word is replaced at once, so the bit of a page still hooked is never seen clear by the readers.
+    fn rebuild_hooked_page_filter(&self) {
+        let mut words = [0u64; HOOKED_PAGE_FILTER_BITS / 64];
+        for &guest_page_pa in self.guest_page_mappings.keys() {
+            let (word, mask) = hooked_page_filter_bit(guest_page_pa);
+            words[word] |= mask;
+        }
+
+        for (filter_word, word) in HOOKED_PAGE_FILTER.iter().zip(words) {
+            filter_word.store(word, Ordering::Release);
+        }
+    }
+
     /// Maps a free page table to a large guest physical address, allocating memory as needed.
     ///
     /// # Arguments
@@ -133,9 +265,9 @@ impl MemoryManager {
         // Check if the large page is already mapped
         if !self.large_page_table_mappings.contains_key(&guest_large_page_pa) {
             trace!("Large page not mapped to page table, mapping now");
-            // Allocate a new page table
-            let pt = unsafe { box_zeroed::<Pt>() };
-            self.large_page_table_mappings.insert(guest_large_page_pa, pt);
+            // Take a new page table from the pool
+            let pt_pa = self.page_pool.allocate().ok_or(HypervisorError::PageTablesUnavailable)?;
+            self.large_page_table_mappings.insert(guest_large_page_pa, pt_pa);
             trace!("Large page mapped to page table successfully");
         } else {
             trace!("Large page PA: {:#x} is already mapped to a page table", guest_large_page_pa);
@@ -144,7 +276,42 @@ impl MemoryManager {
         Ok(())
     }
 
-    /// Unmaps a shadow page from a guest physical address, removing the associated hooks.
+    /// Removes the hook information of a single function from a hooked guest page.
+    ///
+    /// The shadow page stays mapped, so the other hooks on the same page are not affected.
+    /// Use `unmap_guest_from_shadow_page` to release the shadow page once no hooks remain.
+    ///
+    /// # Arguments
+    /// * `guest_page_pa` - The guest physical address of the page.
+    /// * `guest_function_pa` - The guest physical address of the hooked function.
+    ///
+    /// # Returns
+    /// `Ok(usize)` with the number of hooks remaining on the page, or an error if the hook was not found.
+    pub fn remove_hook(&mut self, guest_page_pa: u64, guest_function_pa: u64) -> Result<usize, HypervisorError> {
+        trace!("Removing hook for function PA: {:#x} from page PA: {:#x}", guest_function_pa, guest_page_pa);
+
+        let mapping = self
+            .guest_page_mappings
+            .get_mut(&guest_page_pa)
+            .ok_or(HypervisorError::ShadowPageNotFound)?;
+
+        let index = mapping
+            .hooks
+            .iter()
+            .position(|hook| hook.guest_function_pa == guest_function_pa)
+            .ok_or(HypervisorError::HookInfoNotFound)?;
+
+        mapping.hooks.remove(index);
+
+        Ok(mapping.hooks.len())
+    }
+
+    /// Returns the total number of hooks installed, across all guest pages.
+    pub fn hook_count(&self) -> usize {
+        self.guest_page_mappings.values().map(|mapping| mapping.hooks.len()).sum()
+    }
+
+    /// Unmaps a shadow page from a guest physical address, removing the associated hooks and returning the shadow page to the pool.
     ///
     /// # Arguments
     /// * `guest_page_pa` - The guest physical address to unmap.
@@ -155,7 +322,9 @@ impl MemoryManager {
         trace!("Unmapping guest page and shadow page for PA: {:#x}", guest_page_pa);
 
         // Remove the mapping if it exists
-        if self.guest_page_mappings.remove(&guest_page_pa).is_some() {
+        if let Some(mapping) = self.guest_page_mappings.remove(&guest_page_pa) {
+            self.release_page(mapping.shadow_page_pa);
+            self.rebuild_hooked_page_filter();
             trace!("Guest page unmapped from shadow page successfully");
             Ok(())
         } else {
@@ -164,7 +333,7 @@ impl MemoryManager {
         }
     }
 
-    /// Unmaps a page table from a large guest physical address.
+    /// Unmaps a page table from a large guest physical address, returning the page table to the pool.
     ///
     /// # Arguments
     /// * `guest_large_page_pa` - The large guest physical address to unmap.
@@ -175,7 +344,8 @@ impl MemoryManager {
         trace!("Unmapping large page and page table for PA: {:#x}", guest_large_page_pa);
 
         // Remove the mapping if it exists
-        if self.large_page_table_mappings.remove(&guest_large_page_pa).is_some() {
+        if let Some(pt_pa) = self.large_page_table_mappings.remove(&guest_large_page_pa) {
+            self.release_page(pt_pa);
             trace!("Large page unmapped from page table successfully");
             Ok(())
         } else {
@@ -192,7 +362,10 @@ impl MemoryManager {
     /// # Returns
     /// An `Option` containing a mutable reference to the `Pt` if found.
     pub fn get_page_table_as_mut(&mut self, guest_large_page_pa: u64) -> Option<&mut Pt> {
-        self.large_page_table_mappings.get_mut(&guest_large_page_pa).map(|pt| &mut **pt)
+        // The page table is owned by this memory manager and the host identity maps physical memory.
+        self.large_page_table_mappings
+            .get(&guest_large_page_pa)
+            .map(|&pt_pa| unsafe { &mut *(pt_pa as *mut Pt) })
     }
 
     /// Retrieves a pointer to the shadow page associated with a guest physical address.
@@ -203,9 +376,7 @@ impl MemoryManager {
     /// # Returns
     /// An `Option` containing the memory address of the `Page` as a `u64` if found.
     pub fn get_shadow_page_as_ptr(&self, guest_page_pa: u64) -> Option<u64> {
-        self.guest_page_mappings
-            .get(&guest_page_pa)
-            .map(|mapping| &*mapping.shadow_page as *const Page as u64)
+        self.guest_page_mappings.get(&guest_page_pa).map(|mapping| mapping.shadow_page_pa)
     }
 
     /// Retrieves a reference to the `HookInfo` associated with a guest physical address.
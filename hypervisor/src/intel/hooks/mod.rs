@@ -1,4 +1,17 @@
+pub mod allocation_monitor;
+pub mod boot_manifest;
+pub mod callbacks;
+pub mod cpuid_hook;
 pub mod descriptor_manager;
+pub mod exception_hook;
+pub mod hardware_breakpoint_hook;
 pub mod hook_manager;
+pub mod hook_view;
 pub mod inline;
 pub mod memory_manager;
+pub mod msr_hook;
+pub mod os_events;
+pub mod page_pool;
+pub mod syscall_hook;
+pub mod syscall_trace;
+pub mod tamper;
@@ -0,0 +1,387 @@
+//! Provides a registry of MSR hooks, consulted by the RDMSR and WRMSR VM exit handler before the MSR is accessed.
+//!
+//! A hook is registered for an MSR and an access type, either with a shadow value, returned by reads and replaced by
+//! writes without accessing the hardware, or with a callback deciding how the access is handled. The interception of
+//! the hooked MSRs is enabled in the MSR bitmap of each logical processor at its first VM exit after the registry
+//! changed. MSRs outside of the ranges covered by the MSR bitmap always cause VM exits, so they can be hooked as well,
+//! e.g., to emulate synthetic MSRs.
+//!
+//! The hooks are applied on top of an MSR bitmap profile, defining the interception of the MSR accesses that aren't
+//! hooked: by default the "quiet" profile intercepts none of them, but a whole bitmap can be swapped in at runtime,
+//! e.g., to intercept every access, and is copied to the MSR bitmap of each logical processor at its next VM exit.
+//!
+//! The shadow values are shared by all the logical processors.
+//!
+//! The handling of an MSR access may also depend on the guest context it's made from, with context rules matching the
+//! CPL, the address space and the range of the accessing instruction (see `MsrAccessContext`), e.g., to pass the reads
+//! of IA32_LSTAR made by the kernel's own early-boot code through to the hardware while serving the shadow value to
+//! any later reader. The rules of an access are evaluated in order before its hook, the first matching rule deciding
+//! whether the hook applies, and the accesses with rules are intercepted like the hooked ones.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            bitmap::{is_msr_in_bitmap, MsrAccessType, MsrBitmap, MsrOperation},
+            seqlock::Published,
+            support::vmread,
+            tsc_compensation::{configure_counter_msr_hooks, TscCompensationConfig},
+            vm::Vm,
+            vmexit::msr::{handle_feature_control_read, handle_lstar_read, handle_lstar_write, handle_sysenter_read, handle_sysenter_write},
+        },
+    },
+    alloc::{
+        boxed::Box,
+        collections::{BTreeMap, BTreeSet},
+        vec::Vec,
+    },
+    lazy_static::lazy_static,
+    log::*,
+    shared::{MsrAccessContext, MsrContextAction},
+    spin::Mutex,
+    x86::{msr, vmx::vmcs},
+};
+
+/// The maximum number of context rules of an MSR access.
+pub const MAX_MSR_CONTEXT_RULES: usize = 16;
+
+/// The changes of the registry, published each time a hook or a context rule is added or removed.
+static MSR_HOOK_CHANGES: Published<()> = Published::new(());
+
+/// The swaps of the MSR bitmap profile, published while the registry is locked.
+static MSR_BITMAP_PROFILE_CHANGES: Published<()> = Published::new(());
+
+/// A callback called in VMX root operation when the guest accesses a hooked MSR.
+///
+/// For reads, `value` is 0 on entry and is returned to the guest if the access is emulated. For writes, `value` is the
+/// value written by the guest, and may be changed before it is written to the hardware.
+///
+/// The callback is called without the registry locked, so it may register or unregister hooks.
+pub type MsrHookCallback = fn(vm: &mut Vm, msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError>;
+
+/// How an access to a hooked MSR is completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrHookResult {
+    /// The MSR is read from the hardware, or `value` is written to the hardware.
+    Passthrough,
+
+    /// `value` is returned to the guest for reads, and writes are discarded.
+    Emulated,
+
+    /// A general protection fault (#GP) is injected into the guest instead of completing the access.
+    InjectGp,
+}
+
+/// A hook of an MSR access.
+#[derive(Debug, Clone, Copy)]
+pub enum MsrHook {
+    /// Reads return the shadow value and writes replace it, without accessing the hardware.
+    Shadow(u64),
+
+    /// The callback decides how the access is handled.
+    Callback(MsrHookCallback),
+}
+
+/// A rule deciding how the MSR accesses made from a guest context are handled.
+#[derive(Debug, Clone, Copy)]
+pub struct MsrContextRule {
+    /// The guest context the rule applies to.
+    pub context: MsrAccessContext,
+
+    /// What the rule does with the matching accesses.
+    pub action: MsrContextAction,
+
+    /// The number of accesses the rule still applies to, or `None` if it never expires.
+    pub remaining_matches: Option<u64>,
+}
+
+impl MsrContextRule {
+    /// Returns whether an access made from a guest context matches the rule.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpl` - The current privilege level of the guest.
+    /// * `guest_cr3` - The page table base of the current address space of the guest, without the low 12 bits.
+    /// * `rip` - The address of the accessing instruction.
+    fn matches(&self, cpl: u8, guest_cr3: u64, rip: u64) -> bool {
+        self.context.cpl.is_none_or(|rule_cpl| rule_cpl == cpl)
+            && self.context.guest_cr3.is_none_or(|rule_cr3| rule_cr3 & !0xFFF == guest_cr3)
+            && self.context.code_range.is_none_or(|(start, size)| rip >= start && rip - start < size)
+    }
+}
+
+/// Manages the hooks of the MSR accesses.
+#[derive(Debug)]
+pub struct MsrHookManager {
+    /// The hooks, by MSR and access type.
+    hooks: BTreeMap<(u32, MsrAccessType), MsrHook>,
+
+    /// The context rules, by MSR and access type, in the order they are evaluated.
+    context_rules: BTreeMap<(u32, MsrAccessType), Vec<MsrContextRule>>,
+
+    /// The MSR accesses ever hooked or with rules, whose interception must be disabled once they are unregistered.
+    hooked_accesses: BTreeSet<(u32, MsrAccessType)>,
+
+    /// The interception of the MSR accesses that aren't hooked.
+    bitmap_profile: Box<MsrBitmap>,
+}
+
+lazy_static! {
+    /// A globally shared instance of `MsrHookManager`, protected by a mutex.
+    ///
+    /// The registry is initialized with the built-in hooks: the shadowed IA32_LSTAR, used to capture the base of
+    /// ntoskrnl.exe, the shadowed IA32_SYSENTER_EIP and IA32_SYSENTER_ESP, IA32_FEATURE_CONTROL reported as locked, and
+    /// the counter MSRs virtualized by the TSC compensation if enabled at build time.
+    pub static ref SHARED_MSR_HOOK_MANAGER: Mutex<MsrHookManager> = Mutex::new(MsrHookManager::new());
+}
+
+impl MsrHookManager {
+    /// Creates a new registry with the built-in hooks.
+    fn new() -> Self {
+        let mut msr_hook_manager = Self {
+            hooks: BTreeMap::new(),
+            context_rules: BTreeMap::new(),
+            hooked_accesses: BTreeSet::new(),
+            bitmap_profile: Box::new(MsrBitmap::new()),
+        };
+
+        msr_hook_manager.register(msr::IA32_LSTAR, MsrAccessType::Read, MsrHook::Callback(handle_lstar_read));
+        msr_hook_manager.register(msr::IA32_LSTAR, MsrAccessType::Write, MsrHook::Callback(handle_lstar_write));
+        for sysenter_msr in [msr::IA32_SYSENTER_EIP, msr::IA32_SYSENTER_ESP] {
+            msr_hook_manager.register(sysenter_msr, MsrAccessType::Read, MsrHook::Callback(handle_sysenter_read));
+            msr_hook_manager.register(sysenter_msr, MsrAccessType::Write, MsrHook::Callback(handle_sysenter_write));
+        }
+        msr_hook_manager.register(msr::IA32_FEATURE_CONTROL, MsrAccessType::Read, MsrHook::Callback(handle_feature_control_read));
+        configure_counter_msr_hooks(&mut msr_hook_manager, TscCompensationConfig::default().enabled);
+
+        msr_hook_manager
+    }
+
+    /// Registers a hook for an MSR access, replacing the previous hook of the access.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR to hook.
+    /// * `access_type` - The access type to hook.
+    /// * `hook` - The hook.
+    pub fn register(&mut self, msr: u32, access_type: MsrAccessType, hook: MsrHook) {
+        debug!("Registering MSR hook: {:#x} {:?}: {:?}", msr, access_type, hook);
+
+        self.hooks.insert((msr, access_type), hook);
+        self.hooked_accesses.insert((msr, access_type));
+        MSR_HOOK_CHANGES.publish(());
+    }
+
+    /// Unregisters the hook of an MSR access.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The hooked MSR.
+    /// * `access_type` - The hooked access type.
+    ///
+    /// # Returns
+    ///
+    /// The hook that was registered, if any.
+    pub fn unregister(&mut self, msr: u32, access_type: MsrAccessType) -> Option<MsrHook> {
+        debug!("Unregistering MSR hook: {:#x} {:?}", msr, access_type);
+
+        let hook = self.hooks.remove(&(msr, access_type));
+        MSR_HOOK_CHANGES.publish(());
+        hook
+    }
+
+    /// Returns the hook of an MSR access, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `access_type` - The access type.
+    pub fn get(&self, msr: u32, access_type: MsrAccessType) -> Option<MsrHook> {
+        self.hooks.get(&(msr, access_type)).copied()
+    }
+
+    /// Adds a context rule for an MSR access, evaluated after the existing rules of the access.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `access_type` - The access type.
+    /// * `rule` - The rule.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the rule has been added, or `Err(HypervisorError::TooManyMsrContextRules)` if the access already
+    /// has `MAX_MSR_CONTEXT_RULES` rules.
+    pub fn add_context_rule(&mut self, msr: u32, access_type: MsrAccessType, rule: MsrContextRule) -> Result<(), HypervisorError> {
+        let rules = self.context_rules.entry((msr, access_type)).or_default();
+
+        if rules.len() >= MAX_MSR_CONTEXT_RULES {
+            return Err(HypervisorError::TooManyMsrContextRules);
+        }
+
+        debug!("Adding MSR context rule: {:#x} {:?}: {:x?}", msr, access_type, rule);
+
+        rules.push(rule);
+        self.hooked_accesses.insert((msr, access_type));
+        MSR_HOOK_CHANGES.publish(());
+
+        Ok(())
+    }
+
+    /// Removes the context rules of an MSR access.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR.
+    /// * `access_type` - The access type.
+    ///
+    /// # Returns
+    ///
+    /// The number of rules removed.
+    pub fn clear_context_rules(&mut self, msr: u32, access_type: MsrAccessType) -> usize {
+        debug!("Clearing MSR context rules: {:#x} {:?}", msr, access_type);
+
+        let rule_count = self.context_rules.remove(&(msr, access_type)).map_or(0, |rules| rules.len());
+        MSR_HOOK_CHANGES.publish(());
+        rule_count
+    }
+
+    /// Returns the action of the first context rule of an MSR access matching the guest context, if any, and counts the
+    /// match, removing the rule once it expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    /// * `msr` - The accessed MSR.
+    /// * `access_type` - The access type.
+    fn match_context_rule(&mut self, vm: &Vm, msr: u32, access_type: MsrAccessType) -> Option<MsrContextAction> {
+        let rules = self.context_rules.get_mut(&(msr, access_type))?;
+
+        // The CPL is the DPL of SS, bits 6:5 of its access rights.
+        let cpl = ((vmread(vmcs::guest::SS_ACCESS_RIGHTS) >> 5) & 0x3) as u8;
+        let guest_cr3 = vmread(vmcs::guest::CR3) & !0xFFF;
+        let rip = vm.guest_registers.rip;
+
+        let index = rules.iter().position(|rule| rule.matches(cpl, guest_cr3, rip))?;
+        let rule = &mut rules[index];
+        let action = rule.action;
+
+        trace!("MSR context rule matched: {:#x} {:?} at {:#x}, CPL {}: {:?}", msr, access_type, rip, cpl, action);
+
+        if let Some(remaining_matches) = rule.remaining_matches.as_mut() {
+            *remaining_matches = remaining_matches.saturating_sub(1);
+
+            if *remaining_matches == 0 {
+                debug!("MSR context rule expired: {:#x} {:?}: {:x?}", msr, access_type, rules.remove(index));
+                MSR_HOOK_CHANGES.publish(());
+            }
+        }
+
+        Some(action)
+    }
+
+    /// Swaps the MSR bitmap profile, which the logical processors copy to their MSR bitmap on their next VM exit
+    /// before applying the hooks again.
+    ///
+    /// The interceptions modified on a logical processor since the previous profile was copied, e.g., relaxed by the
+    /// exit-storm detector, are discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `bitmap_profile` - The interception of the MSR accesses that aren't hooked.
+    pub fn set_bitmap_profile(&mut self, bitmap_profile: MsrBitmap) {
+        debug!("Swapping MSR bitmap profile");
+
+        *self.bitmap_profile = bitmap_profile;
+        MSR_BITMAP_PROFILE_CHANGES.publish(());
+    }
+
+    /// Returns the current MSR bitmap profile, e.g., to query the interception of an MSR access that isn't hooked.
+    pub fn bitmap_profile(&self) -> &MsrBitmap {
+        &self.bitmap_profile
+    }
+
+    /// Enables the interception of the hooked MSR accesses in an MSR bitmap, and restores the interception of the MSR
+    /// bitmap profile for the unregistered ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr_bitmap` - The MSR bitmap of a logical processor.
+    pub fn apply_interceptions(&self, msr_bitmap: &mut MsrBitmap) {
+        for &(msr, access_type) in &self.hooked_accesses {
+            // MSRs outside of the ranges covered by the MSR bitmap always cause VM exits.
+            if !is_msr_in_bitmap(msr) {
+                continue;
+            }
+
+            match self.hooks.contains_key(&(msr, access_type)) || self.context_rules.get(&(msr, access_type)).is_some_and(|rules| !rules.is_empty()) {
+                true => msr_bitmap.modify_msr_interception(msr, access_type, MsrOperation::Hook),
+                false => msr_bitmap.copy_msr_interception(msr, access_type, &self.bitmap_profile),
+            }
+        }
+    }
+}
+
+/// Applies the changes of the registry and of the MSR bitmap profile to the MSR bitmap of the current logical processor.
+///
+/// This is called on every VM exit, and only locks the registry when it changed.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_msr_hooks(vm: &mut Vm) {
+    let profile_changed = MSR_BITMAP_PROFILE_CHANGES.sync(&mut vm.msr_bitmap_profile_generation).is_some();
+    let hooks_changed = MSR_HOOK_CHANGES.sync(&mut vm.msr_hook_generation).is_some();
+
+    if !profile_changed && !hooks_changed {
+        return;
+    }
+
+    let msr_hook_manager = SHARED_MSR_HOOK_MANAGER.lock();
+
+    if profile_changed {
+        // The bitmap is copied in place, its physical address is referenced by the VMCS.
+        vm.msr_bitmap = *msr_hook_manager.bitmap_profile;
+    }
+
+    msr_hook_manager.apply_interceptions(&mut vm.msr_bitmap);
+}
+
+/// Dispatches an MSR access to its hook, if any, unless a context rule of the access decides otherwise.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `msr` - The accessed MSR.
+/// * `access_type` - The access type.
+/// * `value` - The value written for writes, or the value to return to the guest for emulated reads.
+///
+/// # Returns
+///
+/// `Ok(Some(MsrHookResult))` if the access is hooked or a context rule bypassed the hook, `Ok(None)` otherwise, or
+/// `Err(HypervisorError)` if the callback failed.
+pub fn dispatch_msr_hook(vm: &mut Vm, msr: u32, access_type: MsrAccessType, value: &mut u64) -> Result<Option<MsrHookResult>, HypervisorError> {
+    let mut msr_hook_manager = SHARED_MSR_HOOK_MANAGER.lock();
+
+    match msr_hook_manager.match_context_rule(vm, msr, access_type) {
+        Some(MsrContextAction::Passthrough) => return Ok(Some(MsrHookResult::Passthrough)),
+        Some(MsrContextAction::InjectGp) => return Ok(Some(MsrHookResult::InjectGp)),
+        Some(MsrContextAction::Hook) | None => {}
+    }
+
+    let callback = match msr_hook_manager.hooks.get_mut(&(msr, access_type)) {
+        None => return Ok(None),
+        Some(MsrHook::Shadow(shadow_value)) => {
+            match access_type {
+                MsrAccessType::Read => *value = *shadow_value,
+                MsrAccessType::Write => *shadow_value = *value,
+            }
+            return Ok(Some(MsrHookResult::Emulated));
+        }
+        Some(MsrHook::Callback(callback)) => *callback,
+    };
+
+    drop(msr_hook_manager);
+
+    callback(vm, msr, value).map(Some)
+}
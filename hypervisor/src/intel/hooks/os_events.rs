@@ -0,0 +1,202 @@
+//! Provides OS-level telemetry of Windows guests sourced from below the kernel: the process creations and the image
+//! loads, recorded by EPT hooks on the ntoskrnl.exe functions doing them and pushed to the event stream of the guest
+//! agent (see the `event_stream` module) as `ProcessCreated` and `ImageLoaded` events, so consumers get them without
+//! registering a kernel callback that a driver could find, remove or race.
+//!
+//! Neither function is exported, so they are hooked at the RVAs in ntoskrnl.exe passed by the client, e.g., resolved
+//! from the symbols of the running build:
+//! - `PspInsertProcess(NewProcess, ParentProcess, ...)`, which inserts a created process, its ID already allocated,
+//!   in the list of processes,
+//! - `PsCallImageNotifyRoutines(FullImageName, ProcessId, ImageInfo)`, which `MiMapViewOfImageSection` calls for each
+//!   image mapped while an image load notify routine is registered, as on any system with an antivirus.
+//!
+//! The events are recorded on the entry of the functions with `Vmcall` hooks and their `HookCallbacks`, before they
+//! run, and reconfiguring the hook points removes the hooks of the previous ones.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            event_stream::stream_event,
+            hooks::{
+                callbacks::{HookCallback, HookCallbacks, HookContext},
+                hook_manager::{kernel_image, EptHookType, HookManager, SHARED_HOOK_MANAGER},
+                inline::InlineHookType,
+            },
+            vm::Vm,
+        },
+        windows::{nt::pe::djb2_hash, offsets::windows_offsets},
+    },
+    core::sync::atomic::{AtomicU64, Ordering},
+    log::*,
+    shared::EventStreamKind,
+};
+
+/// Constants for offsets in `IMAGE_INFO`, the information of an image passed to the image load notify routines.
+const IMAGE_INFO_PROPERTIES_OFFSET: u64 = 0x0;
+const IMAGE_INFO_IMAGE_BASE_OFFSET: u64 = 0x8;
+const IMAGE_INFO_IMAGE_SIZE_OFFSET: u64 = 0x18;
+
+/// The RVA of the hooked `PspInsertProcess`, 0 while the process creations aren't recorded, changed with the hook
+/// manager locked.
+static PROCESS_INSERT_RVA: AtomicU64 = AtomicU64::new(0);
+
+/// The RVA of the hooked `PsCallImageNotifyRoutines`, 0 while the image loads aren't recorded, changed with the hook
+/// manager locked.
+static IMAGE_NOTIFY_RVA: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the functions hooked to record the process creations and the image loads, removing the hooks of the previous
+/// ones.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `process_insert_rva` - The RVA of `PspInsertProcess` in ntoskrnl.exe, or 0 not to record the process creations.
+/// * `image_notify_rva` - The RVA of `PsCallImageNotifyRoutines` in ntoskrnl.exe, or 0 not to record the image loads.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the functions are hooked.
+/// * `Err(HypervisorError::GetKernelBaseFailed)` - If the base of ntoskrnl.exe isn't captured yet.
+/// * `Err(HypervisorError::InvalidOsEventsConfig)` - If an RVA is out of the image of ntoskrnl.exe.
+/// * `Err(HypervisorError)` - If a function can't be hooked or unhooked.
+pub fn configure_os_events(vm: &mut Vm, process_insert_rva: u64, image_notify_rva: u64) -> Result<(), HypervisorError> {
+    let ntoskrnl = kernel_image();
+
+    if !ntoskrnl.is_captured() {
+        return Err(HypervisorError::GetKernelBaseFailed);
+    }
+
+    if process_insert_rva >= ntoskrnl.size || image_notify_rva >= ntoskrnl.size {
+        return Err(HypervisorError::InvalidOsEventsConfig);
+    }
+
+    debug!("OS events configured, PspInsertProcess RVA: {:#x}, PsCallImageNotifyRoutines RVA: {:#x}", process_insert_rva, image_notify_rva);
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    // The TLBs are flushed once all the hooks are changed.
+    hook_manager.begin_deferred_flush();
+
+    let result = set_hook_point(vm, &mut hook_manager, &PROCESS_INSERT_RVA, process_insert_rva, "PspInsertProcess", handle_process_insert)
+        .and_then(|_| set_hook_point(vm, &mut hook_manager, &IMAGE_NOTIFY_RVA, image_notify_rva, "PsCallImageNotifyRoutines", handle_image_notify));
+
+    hook_manager.end_deferred_flush(vm);
+
+    result
+}
+
+/// Moves the hook of a recorded function to a new RVA.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `hook_manager` - The hook manager, with the kernel base captured.
+/// * `hooked_rva` - The RVA of the function currently hooked, 0 if none.
+/// * `rva` - The RVA of the function to hook, 0 to only remove the current hook.
+/// * `name` - The name of the function, identifying the hook.
+/// * `on_entry` - The callback recording the event on the entry of the function.
+fn set_hook_point(
+    vm: &mut Vm,
+    hook_manager: &mut HookManager,
+    hooked_rva: &AtomicU64,
+    rva: u64,
+    name: &str,
+    on_entry: HookCallback,
+) -> Result<(), HypervisorError> {
+    let current_rva = hooked_rva.load(Ordering::Acquire);
+    let ept_hook_type = EptHookType::Function(InlineHookType::Vmcall);
+
+    if current_rva == rva {
+        return Ok(());
+    }
+
+    if current_rva != 0 {
+        hook_manager.unregister_hook_callbacks(current_rva);
+        hook_manager.ept_unhook_function(vm, kernel_image().base_va + current_rva, ept_hook_type)?;
+        hooked_rva.store(0, Ordering::Release);
+
+        debug!("{} at RVA {:#x} unhooked", name, current_rva);
+    }
+
+    if rva != 0 {
+        hook_manager.register_hook_callbacks(
+            rva,
+            HookCallbacks {
+                on_entry: Some(on_entry),
+                on_return: None,
+            },
+        );
+
+        if let Err(e) = hook_manager.ept_hook_function(vm, kernel_image().base_va + rva, djb2_hash(name.as_bytes()), ept_hook_type) {
+            hook_manager.unregister_hook_callbacks(rva);
+            return Err(e);
+        }
+
+        hooked_rva.store(rva, Ordering::Release);
+
+        debug!("{} at RVA {:#x} hooked", name, rva);
+    }
+
+    Ok(())
+}
+
+/// Records a `ProcessCreated` event on the entry of `PspInsertProcess`.
+///
+/// # Arguments
+///
+/// * `context` - The context of the hooked function.
+fn handle_process_insert(context: &mut HookContext) {
+    let Some(offsets) = windows_offsets() else {
+        return;
+    };
+
+    let Some(eprocess) = context.argument(0).filter(|&eprocess| eprocess != 0) else {
+        return;
+    };
+
+    let process_id = read_u64(eprocess + offsets.eprocess_unique_process_id);
+    let directory_table_base = read_u64(eprocess + offsets.kprocess_directory_table_base);
+
+    // The parent is NULL for the processes created by the kernel, e.g., the Registry and the Memory Compression ones.
+    let parent_process_id = match context.argument(1) {
+        Some(parent) if parent != 0 => read_u64(parent + offsets.eprocess_unique_process_id),
+        _ => 0,
+    };
+
+    trace!("Process created: {:#x}, parent: {:#x}, EPROCESS: {:#x}", process_id, parent_process_id, eprocess);
+
+    stream_event(EventStreamKind::ProcessCreated, [process_id, parent_process_id, eprocess, directory_table_base]);
+}
+
+/// Records an `ImageLoaded` event on the entry of `PsCallImageNotifyRoutines`.
+///
+/// # Arguments
+///
+/// * `context` - The context of the hooked function.
+fn handle_image_notify(context: &mut HookContext) {
+    // The process ID is NULL for the kernel images, e.g., the drivers.
+    let process_id = context.argument(1).unwrap_or_default();
+
+    let Some(image_info) = context.argument(2).filter(|&image_info| image_info != 0) else {
+        return;
+    };
+
+    let image_base = read_u64(image_info + IMAGE_INFO_IMAGE_BASE_OFFSET);
+    let image_size = read_u64(image_info + IMAGE_INFO_IMAGE_SIZE_OFFSET);
+    let properties = PhysicalAddress::read_guest_kernel_virt((image_info + IMAGE_INFO_PROPERTIES_OFFSET) as *const u32).unwrap_or_default();
+
+    trace!("Image loaded in process {:#x}: {:#x}, size: {:#x}", process_id, image_base, image_size);
+
+    stream_event(EventStreamKind::ImageLoaded, [process_id, image_base, image_size, properties as u64]);
+}
+
+/// Reads a `u64` of the kernel address space, 0 if it isn't mapped.
+///
+/// # Arguments
+///
+/// * `va` - The virtual address of the value.
+fn read_u64(va: u64) -> u64 {
+    PhysicalAddress::read_guest_kernel_virt(va as *const u64).unwrap_or_default()
+}
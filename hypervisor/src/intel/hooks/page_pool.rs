@@ -0,0 +1,190 @@
+//! Module providing a refillable pool of physical pages for the memory manager.
+//! The pool is used to draw the shadow pages and page tables of EPT hooks at runtime,
+//! so the number of hooks is only limited by the memory given to the pool.
+//! On a multi-socket system, the pool also holds pages in the memory of each node (see the `numa` module),
+//! and a page is taken from the node of the logical processor first.
+
+use {
+    crate::intel::numa::current_domain,
+    alloc::{alloc::alloc_zeroed, collections::BTreeMap, vec::Vec},
+    core::{alloc::Layout, ops::Range, ptr::write_bytes},
+    log::{debug, trace},
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The number of pages the pool grows by when it's empty and has to fall back to the heap.
+const HEAP_GROWTH_PAGES: usize = 16;
+
+/// Represents a pool of free 4KB physical pages.
+///
+/// The host uses an identity map, so the physical address of a page is also its host virtual address.
+/// Pages can be added to the pool at load time by the loader (`refill`), and the pool grows from the
+/// host heap on demand when it runs out of pages (`refill_from_heap`). Freed pages are returned to the pool.
+/// The pages added for a node (`refill_node`) are kept apart, and returned to their node when freed.
+#[derive(Debug, Clone)]
+pub struct PagePool {
+    /// The physical addresses of the free pages outside of any node.
+    free_pages: Vec<u64>,
+
+    /// The physical addresses of the free pages of each node, by proximity domain.
+    node_pages: BTreeMap<u32, Vec<u64>>,
+
+    /// The ranges of pages added for each node, with their proximity domain.
+    node_ranges: Vec<(Range<u64>, u32)>,
+
+    /// The total number of pages given to the pool.
+    total_pages: usize,
+}
+
+impl PagePool {
+    /// Constructs a new, empty `PagePool` instance.
+    ///
+    /// # Returns
+    /// A new instance of `PagePool`.
+    pub fn new() -> Self {
+        Self {
+            free_pages: Vec::new(),
+            node_pages: BTreeMap::new(),
+            node_ranges: Vec::new(),
+            total_pages: 0,
+        }
+    }
+
+    /// Adds a physically contiguous range of pages to the pool.
+    ///
+    /// The caller must make sure the memory is owned by the hypervisor for its whole lifetime
+    /// and is recorded in `HostConfig` so it is hidden from the guest.
+    ///
+    /// # Arguments
+    /// * `base_pa` - The page-aligned physical address of the first page.
+    /// * `page_count` - The number of pages to add.
+    pub fn refill(&mut self, base_pa: u64, page_count: usize) {
+        debug!("Adding {} pages at {:#x} to the page pool", page_count, base_pa);
+
+        prewarm(base_pa, page_count);
+
+        self.free_pages.reserve(page_count);
+        self.free_pages.extend((0..page_count).map(|i| base_pa + (i * BASE_PAGE_SIZE) as u64));
+        self.total_pages += page_count;
+    }
+
+    /// Adds a physically contiguous range of pages in the memory of a node to the pool.
+    ///
+    /// The same requirements as `refill` apply.
+    ///
+    /// # Arguments
+    /// * `proximity_domain` - The proximity domain of the node the memory belongs to.
+    /// * `base_pa` - The page-aligned physical address of the first page.
+    /// * `page_count` - The number of pages to add.
+    pub fn refill_node(&mut self, proximity_domain: u32, base_pa: u64, page_count: usize) {
+        debug!("Adding {} pages at {:#x} of the node {} to the page pool", page_count, base_pa, proximity_domain);
+
+        prewarm(base_pa, page_count);
+
+        let free_pages = self.node_pages.entry(proximity_domain).or_default();
+        free_pages.reserve(page_count);
+        free_pages.extend((0..page_count).map(|i| base_pa + (i * BASE_PAGE_SIZE) as u64));
+
+        self.node_ranges
+            .push((base_pa..base_pa + (page_count * BASE_PAGE_SIZE) as u64, proximity_domain));
+        self.total_pages += page_count;
+    }
+
+    /// Grows the pool with pages allocated from the host heap.
+    ///
+    /// # Arguments
+    /// * `page_count` - The number of pages to add.
+    ///
+    /// # Returns
+    /// `true` if all pages were allocated, otherwise `false` if the heap ran out of memory.
+    pub fn refill_from_heap(&mut self, page_count: usize) -> bool {
+        trace!("Growing the page pool by {} pages from the heap", page_count);
+
+        // The pages are never returned to the heap, they are recycled through the pool instead.
+        let layout = Layout::from_size_align(BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap();
+
+        for _ in 0..page_count {
+            let page = unsafe { alloc_zeroed(layout) };
+
+            if page.is_null() {
+                return false;
+            }
+
+            self.free_pages.push(page as u64);
+            self.total_pages += 1;
+        }
+
+        true
+    }
+
+    /// Takes a zeroed page from the pool, growing the pool from the heap if it's empty.
+    ///
+    /// The page is taken from the node of the current logical processor first, then outside of any node, then from
+    /// the other nodes, as remote memory is still closer than the heap running out.
+    ///
+    /// # Returns
+    /// An `Option` containing the physical address of the page, or `None` if no memory is left.
+    pub fn allocate(&mut self) -> Option<u64> {
+        let local_page_pa = current_domain().and_then(|proximity_domain| self.node_pages.get_mut(&proximity_domain)?.pop());
+
+        let page_pa = match local_page_pa
+            .or_else(|| self.free_pages.pop())
+            .or_else(|| self.node_pages.values_mut().find_map(|free_pages| free_pages.pop()))
+        {
+            Some(page_pa) => page_pa,
+            None => {
+                if !self.refill_from_heap(HEAP_GROWTH_PAGES) && self.free_pages.is_empty() {
+                    return None;
+                }
+                self.free_pages.pop()?
+            }
+        };
+
+        unsafe { write_bytes(page_pa as *mut u8, 0, BASE_PAGE_SIZE) };
+
+        Some(page_pa)
+    }
+
+    /// Returns a page to the pool, to its node if it was added for one.
+    ///
+    /// # Arguments
+    /// * `page_pa` - The physical address of the page previously returned by `allocate`.
+    pub fn free(&mut self, page_pa: u64) {
+        let proximity_domain = self
+            .node_ranges
+            .iter()
+            .find(|(range, _)| range.contains(&page_pa))
+            .map(|&(_, proximity_domain)| proximity_domain);
+
+        match proximity_domain.and_then(|proximity_domain| self.node_pages.get_mut(&proximity_domain)) {
+            Some(free_pages) => free_pages.push(page_pa),
+            None => self.free_pages.push(page_pa),
+        }
+    }
+
+    /// Returns the number of free pages in the pool.
+    pub fn free_page_count(&self) -> usize {
+        self.free_pages.len() + self.node_pages.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// Returns the total number of pages given to the pool.
+    pub fn total_page_count(&self) -> usize {
+        self.total_pages
+    }
+}
+
+impl Default for PagePool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pre-touches the pages added to the pool, so they are written for the first time at load time rather than on the
+/// hook path drawing them.
+///
+/// # Arguments
+/// * `base_pa` - The page-aligned physical address of the first page.
+/// * `page_count` - The number of pages.
+fn prewarm(base_pa: u64, page_count: usize) {
+    unsafe { write_bytes(base_pa as *mut u8, 0, page_count * BASE_PAGE_SIZE) };
+}
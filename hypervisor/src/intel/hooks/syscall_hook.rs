@@ -0,0 +1,582 @@
+//! Provides hooks of the system calls, dispatched from the syscall entry before the original `KiSystemCall64` runs.
+//!
+//! While handlers are registered, the effective IA32_LSTAR of each logical processor points to the syscall trampoline
+//! instead of the original syscall entry, while the guest keeps reading the original value (see `handle_lstar_read`).
+//! The trampoline is an `int3` (0xCC) byte of the section of ntoskrnl.exe holding the original entry, such as the
+//! padding between functions, so it is executable and mapped in every address space the original entry is, including
+//! the user address spaces of the kernel virtual address shadowing (KVA shadow). Breakpoints cause VM exits, so
+//! executing the trampoline exits to the hypervisor before the exception is delivered on the user stack.
+//!
+//! The handler registered for the system call number in EAX is called, then the guest either continues at the
+//! original entry, with the arguments possibly modified, or returns to user mode with a status, emulating `SYSRET`.
+//! The registers are still the ones of user mode: the stack pointer is the user stack pointer and `SWAPGS` hasn't
+//! been executed yet.
+//!
+//! A handler may also request the status of the system call when it returns, e.g., to read its output arguments. The
+//! return is captured like the return values of the traced system calls, by setting the trap flag in R11: the
+//! single-step trap after the `ret` of the system call stub calls the return handler with the arguments on entry.
+//!
+//! The trampoline is also used while the system calls are traced (see the `syscall_trace` module).
+//!
+//! The effective IA32_LSTAR is updated at the first VM exit of each logical processor after the registry changed.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: SYSCALL—Fast System Call and SYSRET—Return From Fast System Call
+
+use {
+    crate::{
+        intel::{
+            addresses::PhysicalAddress,
+            capture::GuestRegisters,
+            hooks::syscall_trace::{begin_syscall_trace, complete_syscall_trace_return, end_syscall_trace},
+            segmentation::VmxSegmentAccessRights,
+            seqlock::Published,
+            support::{rdmsr, vmread, vmwrite, wrmsr},
+            vm::Vm,
+            vmerror::ExceptionInterrupt,
+        },
+        windows::{eprocess::ProcessInformation, nt::pe::get_section_headers},
+    },
+    alloc::{collections::BTreeMap, vec::Vec},
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        msr,
+        segmentation::{CodeSegmentType, DataSegmentType},
+        vmx::vmcs,
+    },
+};
+
+/// The number of arguments passed in registers by the system call stubs (R10, RDX, R8 and R9).
+const REGISTER_ARGUMENT_COUNT: usize = 4;
+
+/// The offset from the user stack pointer on entry to the first stack argument: the return address of the stub and the 32-byte home space.
+const STACK_ARGUMENTS_OFFSET: u64 = 0x28;
+
+/// The RFLAGS bits restored from R11 by `SYSRET`.
+const SYSRET_RFLAGS_MASK: u64 = 0x3C_7FD7;
+
+/// The reserved bit 1 of RFLAGS, always set.
+const RFLAGS_RESERVED_BIT: u64 = 1 << 1;
+
+/// The number of arguments passed to a `SyscallReturnHandler`.
+pub const RETURN_HANDLER_ARGUMENT_COUNT: usize = 8;
+
+/// The maximum number of system calls waiting for their return handler, beyond which the next returns aren't captured.
+const MAX_PENDING_RETURN_HANDLERS: usize = 0x400;
+
+/// The trap flag of RFLAGS.
+pub const RFLAGS_TRAP_FLAG: u64 = 1 << 8;
+
+/// The exit qualification of debug exceptions: a single-step trap (BS).
+const DEBUG_EXIT_QUALIFICATION_SINGLE_STEP: u64 = 1 << 14;
+
+/// The changes of the registry, published each time a handler is registered or unregistered.
+static SYSCALL_HOOK_CHANGES: Published<()> = Published::new(());
+
+lazy_static! {
+    /// A globally shared instance of `SyscallHookManager`, protected by a mutex.
+    pub static ref SHARED_SYSCALL_HOOK_MANAGER: Mutex<SyscallHookManager> = Mutex::new(SyscallHookManager::new());
+}
+
+/// A handler called in VMX root operation when the guest executes a hooked system call.
+///
+/// Modifications of the registers are written back to the guest when it continues at the original syscall entry.
+pub type SyscallHandler = fn(context: &mut SyscallContext) -> SyscallAction;
+
+/// A handler called in VMX root operation when a system call whose handler returned `SyscallAction::ContinueWithReturn`
+/// returns to its stub, with the status of the system call from RAX.
+///
+/// The handler is called in the address space of the calling process, so the output arguments can be read with the
+/// current CR3, and without the registry locked.
+pub type SyscallReturnHandler = fn(entry: &SyscallEntry, status: u64);
+
+/// How a hooked system call continues after its handler.
+#[derive(Debug, Clone, Copy)]
+pub enum SyscallAction {
+    /// The original syscall entry handles the system call, with the arguments possibly modified by the handler.
+    Continue,
+
+    /// The original syscall entry handles the system call, then the return handler is called when the system call
+    /// returns to its stub. The return isn't captured if the guest has set the trap flag itself, or for the system
+    /// calls which don't return to their stub (e.g., `NtContinue`).
+    ContinueWithReturn(SyscallReturnHandler),
+
+    /// The system call returns to user mode with a status in RAX, without being handled by the kernel.
+    Complete(u64),
+}
+
+/// The context passed to a `SyscallHandler`.
+pub struct SyscallContext<'a> {
+    /// The guest registers on the syscall entry.
+    pub registers: &'a mut GuestRegisters,
+
+    /// The system call number, from EAX.
+    pub syscall_number: u32,
+}
+
+impl SyscallContext<'_> {
+    /// Returns an argument of the system call.
+    ///
+    /// The first four arguments are read from R10, RDX, R8 and R9, where the stubs of ntdll.dll and win32u.dll pass
+    /// them, and the next ones from the user stack.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zero-based index of the argument.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The argument, or `None` if the user stack can't be read.
+    pub fn argument(&self, index: usize) -> Option<u64> {
+        match index {
+            0 => Some(self.registers.r10),
+            1 => Some(self.registers.rdx),
+            2 => Some(self.registers.r8),
+            3 => Some(self.registers.r9),
+            _ => PhysicalAddress::read_guest_virt_with_current_cr3(self.stack_argument_address(index) as *const u64),
+        }
+    }
+
+    /// Modifies an argument of the system call.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zero-based index of the argument.
+    /// * `value` - The new value of the argument.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<()>` - `Some(())` if the argument has been modified, or `None` if the user stack can't be written.
+    pub fn set_argument(&mut self, index: usize, value: u64) -> Option<()> {
+        match index {
+            0 => self.registers.r10 = value,
+            1 => self.registers.rdx = value,
+            2 => self.registers.r8 = value,
+            3 => self.registers.r9 = value,
+            _ => PhysicalAddress::write_guest_virt_with_current_cr3(self.stack_argument_address(index) as *mut u64, value)?,
+        }
+
+        Some(())
+    }
+
+    /// Returns the user-mode address the system call returns to, from RCX.
+    pub fn return_address(&self) -> u64 {
+        self.registers.rcx
+    }
+
+    /// Returns the virtual address of a stack argument of the system call.
+    fn stack_argument_address(&self, index: usize) -> u64 {
+        self.registers.rsp + STACK_ARGUMENTS_OFFSET + ((index - REGISTER_ARGUMENT_COUNT) * core::mem::size_of::<u64>()) as u64
+    }
+}
+
+/// A system call waiting for its return handler, as it entered the original syscall entry.
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallEntry {
+    /// The system call number.
+    pub syscall_number: u32,
+
+    /// The first arguments of the system call after its handler, 0 for the stack arguments that couldn't be read.
+    pub arguments: [u64; RETURN_HANDLER_ARGUMENT_COUNT],
+
+    /// The ID of the process that issued the system call.
+    pub process_id: u64,
+
+    /// The ID of the thread that issued the system call.
+    pub thread_id: u64,
+
+    /// The user-mode address the system call returns to.
+    pub return_address: u64,
+}
+
+/// Manages the handlers of the hooked system calls and the syscall trampoline.
+#[derive(Debug)]
+pub struct SyscallHookManager {
+    /// The handlers, by system call number.
+    handlers: BTreeMap<u32, SyscallHandler>,
+
+    /// The virtual address of the syscall trampoline, once it has been found.
+    trampoline_va: Option<u64>,
+
+    /// Whether the system calls are traced, which also requires the trampoline.
+    tracing: bool,
+
+    /// The system calls waiting for their return handler, by thread ID and user stack pointer after the `ret` of the
+    /// system call stub.
+    pending_returns: BTreeMap<(u64, u64), (SyscallReturnHandler, SyscallEntry)>,
+}
+
+impl SyscallHookManager {
+    /// Creates a new registry without handlers.
+    fn new() -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+            trampoline_va: None,
+            tracing: false,
+            pending_returns: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the handler of a system call, replacing the previous handler of the system call.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_number` - The system call number.
+    /// * `handler` - The handler.
+    pub fn register(&mut self, syscall_number: u32, handler: SyscallHandler) {
+        debug!("Registering syscall hook: {:#x}", syscall_number);
+
+        self.handlers.insert(syscall_number, handler);
+        SYSCALL_HOOK_CHANGES.publish(());
+    }
+
+    /// Unregisters the handler of a system call.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_number` - The system call number.
+    ///
+    /// # Returns
+    ///
+    /// The handler that was registered, if any.
+    pub fn unregister(&mut self, syscall_number: u32) -> Option<SyscallHandler> {
+        debug!("Unregistering syscall hook: {:#x}", syscall_number);
+
+        let handler = self.handlers.remove(&syscall_number);
+        SYSCALL_HOOK_CHANGES.publish(());
+        handler
+    }
+
+    /// Sets whether the system calls are traced.
+    ///
+    /// # Arguments
+    ///
+    /// * `tracing` - Whether the system calls are traced.
+    pub fn set_tracing(&mut self, tracing: bool) {
+        self.tracing = tracing;
+        SYSCALL_HOOK_CHANGES.publish(());
+    }
+
+    /// Finds the syscall trampoline the first time the original syscall entry is captured.
+    ///
+    /// # Arguments
+    ///
+    /// * `ntoskrnl_base_va` - The virtual address of ntoskrnl.exe.
+    /// * `ntoskrnl_base_pa` - The physical address of ntoskrnl.exe.
+    /// * `original_lstar` - The original syscall entry, `KiSystemCall64` or `KiSystemCall64Shadow`.
+    pub fn initialize_trampoline(&mut self, ntoskrnl_base_va: u64, ntoskrnl_base_pa: u64, original_lstar: u64) {
+        if self.trampoline_va.is_some() {
+            return;
+        }
+
+        self.trampoline_va = find_syscall_trampoline(ntoskrnl_base_va, ntoskrnl_base_pa, original_lstar);
+
+        match self.trampoline_va {
+            Some(trampoline_va) => debug!("Syscall trampoline found at VA: {:#x}", trampoline_va),
+            None => warn!("No syscall trampoline found, the system calls can't be hooked"),
+        }
+
+        SYSCALL_HOOK_CHANGES.publish(());
+    }
+
+    /// Returns the effective IA32_LSTAR: the trampoline while handlers are registered or the system calls are traced,
+    /// otherwise the original syscall entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_lstar` - The original syscall entry.
+    pub fn effective_lstar(&self, original_lstar: u64) -> u64 {
+        match self.trampoline_va {
+            Some(trampoline_va) if !self.handlers.is_empty() || self.tracing => trampoline_va,
+            _ => original_lstar,
+        }
+    }
+}
+
+/// Finds an `int3` byte in the section of ntoskrnl.exe holding the original syscall entry, starting with its page.
+///
+/// # Arguments
+///
+/// * `ntoskrnl_base_va` - The virtual address of ntoskrnl.exe.
+/// * `ntoskrnl_base_pa` - The physical address of ntoskrnl.exe.
+/// * `original_lstar` - The original syscall entry.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The virtual address of the byte, or `None` if the section doesn't contain one.
+fn find_syscall_trampoline(ntoskrnl_base_va: u64, ntoskrnl_base_pa: u64, original_lstar: u64) -> Option<u64> {
+    const INT3: u8 = 0xCC;
+
+    let lstar_rva = original_lstar.checked_sub(ntoskrnl_base_va)?;
+
+    let section = unsafe { get_section_headers(ntoskrnl_base_pa as _)? }
+        .iter()
+        .find(|section| (section.VirtualAddress as u64..section.VirtualAddress as u64 + section.VirtualSize as u64).contains(&lstar_rva))?;
+
+    let section_start_va = ntoskrnl_base_va + section.VirtualAddress as u64;
+    let section_end_va = section_start_va + section.VirtualSize as u64;
+    let lstar_page_va = original_lstar & !(BASE_PAGE_SIZE as u64 - 1);
+
+    // The pages are translated one at a time, since the section may not be physically contiguous.
+    let page_vas = core::iter::once(lstar_page_va).chain(
+        (section_start_va & !(BASE_PAGE_SIZE as u64 - 1)..section_end_va)
+            .step_by(BASE_PAGE_SIZE)
+            .filter(|&page_va| page_va != lstar_page_va),
+    );
+
+    for page_va in page_vas {
+        let Some(page) = PhysicalAddress::read_guest_virt_slice_with_current_cr3(page_va as *const u8, BASE_PAGE_SIZE) else {
+            continue;
+        };
+
+        let trampoline_va = page
+            .iter()
+            .enumerate()
+            .map(|(offset, &byte)| (page_va + offset as u64, byte))
+            .find(|&(va, byte)| byte == INT3 && (section_start_va..section_end_va).contains(&va));
+
+        if let Some((trampoline_va, _)) = trampoline_va {
+            return Some(trampoline_va);
+        }
+    }
+
+    None
+}
+
+/// Applies the changes of the registry to the effective IA32_LSTAR of the current logical processor.
+///
+/// This is called on every VM exit, and only locks the registry when it changed. Until the guest has written its
+/// syscall entry on this logical processor, only the value substituted to this write is updated.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_syscall_hooks(vm: &mut Vm) {
+    if SYSCALL_HOOK_CHANGES.sync(&mut vm.syscall_hook_generation).is_none() {
+        return;
+    }
+
+    let syscall_hook_manager = SHARED_SYSCALL_HOOK_MANAGER.lock();
+
+    if vm.guest_registers.original_lstar != 0 {
+        vm.guest_registers.hook_lstar = syscall_hook_manager.effective_lstar(vm.guest_registers.original_lstar);
+        wrmsr(msr::IA32_LSTAR, vm.guest_registers.hook_lstar);
+        trace!("Effective IA32_LSTAR set to: {:#x}", vm.guest_registers.hook_lstar);
+    }
+
+    // The returns of the traced system calls and of the system calls waiting for their return handler are single-step
+    // traps. Their interception is enabled before any handler may request a return, as the thread may return on
+    // another logical processor, and stays enabled once tracing stopped or the handlers were unregistered, as system
+    // calls may still be waiting for their return.
+    if syscall_hook_manager.tracing || !syscall_hook_manager.handlers.is_empty() {
+        let exception_bitmap = vmread(vmcs::control::EXCEPTION_BITMAP) | (1u64 << (ExceptionInterrupt::Debug as u32));
+        vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+    }
+}
+
+/// Dispatches a system call to its handler, if the breakpoint is the syscall trampoline.
+///
+/// The handler is called without the registry locked, so it may register or unregister handlers. The system call is
+/// recorded if it is traced, and its return is captured if the handler requested it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// * `true` - If the breakpoint was the syscall trampoline, and the guest resumes at the original syscall entry or in user mode.
+/// * `false` - If the breakpoint isn't the syscall trampoline.
+pub fn dispatch_syscall_hook(vm: &mut Vm) -> bool {
+    let handler = {
+        let syscall_hook_manager = SHARED_SYSCALL_HOOK_MANAGER.lock();
+
+        if syscall_hook_manager.trampoline_va != Some(vm.guest_registers.rip) || vm.guest_registers.original_lstar == 0 {
+            return false;
+        }
+
+        syscall_hook_manager.handlers.get(&(vm.guest_registers.rax as u32)).copied()
+    };
+
+    let trace_record = begin_syscall_trace(vm);
+
+    // The trap flag set by the guest itself, before the capture of the returns sets it.
+    let guest_trap_flag = vm.guest_registers.r11 & RFLAGS_TRAP_FLAG != 0;
+
+    let action = match handler {
+        Some(handler) => {
+            let syscall_number = vm.guest_registers.rax as u32;
+            trace!("Dispatching to syscall handler: {:#x}", syscall_number);
+
+            handler(&mut SyscallContext {
+                registers: &mut vm.guest_registers,
+                syscall_number,
+            })
+        }
+        // The handler has been unregistered, or the system call isn't hooked.
+        None => SyscallAction::Continue,
+    };
+
+    if let Some(trace_record) = trace_record {
+        end_syscall_trace(vm, trace_record, action);
+    }
+
+    match action {
+        SyscallAction::Continue => vm.guest_registers.rip = vm.guest_registers.original_lstar,
+        SyscallAction::ContinueWithReturn(return_handler) => {
+            if !guest_trap_flag {
+                watch_syscall_return(vm, return_handler);
+            }
+            vm.guest_registers.rip = vm.guest_registers.original_lstar;
+        }
+        SyscallAction::Complete(status) => {
+            trace!("Syscall completed by its handler with status: {:#x}", status);
+            vm.guest_registers.rax = status;
+            emulate_sysret(vm);
+        }
+    }
+
+    vmwrite(vmcs::guest::RIP, vm.guest_registers.rip);
+    vmwrite(vmcs::guest::RSP, vm.guest_registers.rsp);
+    vmwrite(vmcs::guest::RFLAGS, vm.guest_registers.rflags);
+
+    true
+}
+
+/// Arms the capture of the return of a system call continuing at the original syscall entry, to call its return handler.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `return_handler` - The return handler.
+fn watch_syscall_return(vm: &mut Vm, return_handler: SyscallReturnHandler) {
+    let Some((process_id, thread_id)) = ProcessInformation::get_current_client_id() else {
+        return;
+    };
+
+    let syscall_number = vm.guest_registers.rax as u32;
+
+    let context = SyscallContext {
+        registers: &mut vm.guest_registers,
+        syscall_number,
+    };
+
+    let mut arguments = [0; RETURN_HANDLER_ARGUMENT_COUNT];
+    for (index, argument) in arguments.iter_mut().enumerate() {
+        *argument = context.argument(index).unwrap_or_default();
+    }
+
+    let entry = SyscallEntry {
+        syscall_number,
+        arguments,
+        process_id,
+        thread_id,
+        return_address: context.return_address(),
+    };
+
+    // The `ret` of the system call stub pops its return address.
+    let return_key = (thread_id, vm.guest_registers.rsp + 8);
+
+    let mut syscall_hook_manager = SHARED_SYSCALL_HOOK_MANAGER.lock();
+
+    // The system calls of this thread pending at or below this stack pointer can no longer return, their frames have
+    // been unwound (e.g., by `NtContinue`).
+    let unwound_keys: Vec<_> = syscall_hook_manager
+        .pending_returns
+        .range((thread_id, 0)..=return_key)
+        .map(|(&key, _)| key)
+        .collect();
+
+    for key in unwound_keys {
+        syscall_hook_manager.pending_returns.remove(&key);
+    }
+
+    if syscall_hook_manager.pending_returns.len() >= MAX_PENDING_RETURN_HANDLERS {
+        warn!("Too many syscalls waiting for their return handler, not capturing the return of {:#x}", entry.syscall_number);
+        return;
+    }
+
+    // R11 is restored from the guest registers on VM entry.
+    vm.guest_registers.r11 |= RFLAGS_TRAP_FLAG;
+    syscall_hook_manager.pending_returns.insert(return_key, (return_handler, entry));
+}
+
+/// Completes the system calls returning to their stub, if a debug exception is the single-step trap after their
+/// return: the record of a traced system call with its return value, and the return handler requested by the handler
+/// of a hooked system call.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// * `true` - If the debug exception was the return of a traced or hooked system call, and the guest resumes without it.
+/// * `false` - If the debug exception must be delivered to the guest.
+pub fn complete_syscall_return(vm: &mut Vm) -> bool {
+    if vmread(vmcs::ro::EXIT_QUALIFICATION) != DEBUG_EXIT_QUALIFICATION_SINGLE_STEP || vm.guest_registers.rflags & RFLAGS_TRAP_FLAG == 0 {
+        return false;
+    }
+
+    let Some((_, thread_id)) = ProcessInformation::get_current_client_id() else {
+        return false;
+    };
+
+    let return_key = (thread_id, vm.guest_registers.rsp);
+    let status = vm.guest_registers.rax;
+
+    let traced = complete_syscall_trace_return(return_key, status);
+
+    let pending_return = SHARED_SYSCALL_HOOK_MANAGER.lock().pending_returns.remove(&return_key);
+    if let Some((return_handler, entry)) = pending_return {
+        trace!("Syscall {:#x} of thread {} returned to its return handler: {:#x}", entry.syscall_number, thread_id, status);
+        return_handler(&entry, status);
+    }
+
+    if !traced && pending_return.is_none() {
+        return false;
+    }
+
+    vm.guest_registers.rflags &= !RFLAGS_TRAP_FLAG;
+    vmwrite(vmcs::guest::RFLAGS, vm.guest_registers.rflags);
+
+    true
+}
+
+/// Returns to 64-bit user mode like `SYSRET`: RIP from RCX, RFLAGS from R11, and the user CS and SS from IA32_STAR.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+fn emulate_sysret(vm: &mut Vm) {
+    // IA32_STAR[63:48] + 16 is the 64-bit user code selector and IA32_STAR[63:48] + 8 the user stack selector, both with RPL 3.
+    let sysret_selector = (rdmsr(msr::IA32_STAR) >> 48) & 0xFFFF;
+
+    vm.guest_registers.rip = vm.guest_registers.rcx;
+    vm.guest_registers.rflags = (vm.guest_registers.r11 & SYSRET_RFLAGS_MASK) | RFLAGS_RESERVED_BIT;
+
+    let mut access_rights = VmxSegmentAccessRights(0);
+    access_rights.set_segment_type(CodeSegmentType::ExecuteReadAccessed as u32);
+    access_rights.set_descriptor_type(true);
+    access_rights.set_descriptor_privilege_level(3);
+    access_rights.set_present(true);
+    access_rights.set_long_mode(true);
+    access_rights.set_granularity(true);
+
+    vmwrite(vmcs::guest::CS_SELECTOR, (sysret_selector + 16) | 3);
+    vmwrite(vmcs::guest::CS_BASE, 0u64);
+    vmwrite(vmcs::guest::CS_LIMIT, u32::MAX as u64);
+    vmwrite(vmcs::guest::CS_ACCESS_RIGHTS, access_rights.0);
+
+    // The DPL of SS is the current privilege level.
+    access_rights.set_segment_type(DataSegmentType::ReadWriteAccessed as u32);
+    access_rights.set_long_mode(false);
+    access_rights.set_default_big(true);
+
+    vmwrite(vmcs::guest::SS_SELECTOR, (sysret_selector + 8) | 3);
+    vmwrite(vmcs::guest::SS_BASE, 0u64);
+    vmwrite(vmcs::guest::SS_LIMIT, u32::MAX as u64);
+    vmwrite(vmcs::guest::SS_ACCESS_RIGHTS, access_rights.0);
+}
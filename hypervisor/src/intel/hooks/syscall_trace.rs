@@ -0,0 +1,272 @@
+//! Provides a trace of the system calls, recording the number, the arguments, the requesting address space, process
+//! and thread, and the return value of each traced system call into a trace buffer, which the client drains with the
+//! `ReadSyscallTrace` command.
+//!
+//! While tracing, every system call enters through the syscall trampoline (see the `syscall_hook` module), where the
+//! system calls passing the filter are recorded. The return value is captured by setting the trap flag in R11, which
+//! `SYSRET` restores to RFLAGS: the single-step trap after the first user-mode instruction, the `ret` of the system
+//! call stub, is intercepted (see `complete_syscall_return`), and the pending record of the thread with this stack
+//! pointer is completed with RAX. The
+//! trap flag isn't set if the guest has set it itself, e.g., while being debugged, in which case the record has no
+//! return value, as for the system calls which don't return to their stub (e.g., `NtContinue`).
+//!
+//! The records are added to the buffer when the system calls return, so a system call blocking for a long time (e.g.,
+//! a wait) appears after the system calls issued meanwhile. When the buffer is full, new records are handled by its
+//! backpressure policy (see the `event_ring` module), dropping them by default.
+
+use {
+    crate::{
+        intel::{
+            event_ring::EventRing,
+            event_stream::stream_event,
+            hooks::syscall_hook::{SyscallAction, SyscallContext, RFLAGS_TRAP_FLAG, SHARED_SYSCALL_HOOK_MANAGER},
+            support::{rdtsc, vmread},
+            vm::Vm,
+        },
+        windows::eprocess::ProcessInformation,
+    },
+    alloc::{
+        collections::{BTreeMap, BTreeSet},
+        vec::Vec,
+    },
+    core::sync::atomic::{AtomicBool, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    shared::{EventRingId, EventStreamKind, SyscallTraceFilterMode, SyscallTraceRecord, MAX_SYSCALL_TRACE_ARGUMENTS},
+    spin::Mutex,
+    x86::vmx::vmcs,
+};
+
+/// The maximum number of records kept in the trace buffer until they are drained.
+pub const SYSCALL_TRACE_BUFFER_CAPACITY: usize = 0x2000;
+
+/// The maximum number of system calls waiting for their return value, beyond which the next ones are recorded without it.
+const MAX_PENDING_SYSCALL_RETURNS: usize = 0x400;
+
+/// Whether the system calls are traced, checked without locking on each system call.
+static SYSCALL_TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// A globally shared instance of `SyscallTrace`, protected by a mutex.
+    pub static ref SHARED_SYSCALL_TRACE: Mutex<SyscallTrace> = Mutex::new(SyscallTrace::new());
+}
+
+/// The filter of the traced system calls and the records of all the logical processors.
+#[derive(Debug)]
+pub struct SyscallTrace {
+    /// How `syscall_numbers` selects the traced system calls.
+    filter_mode: SyscallTraceFilterMode,
+
+    /// The system call numbers of the filter.
+    syscall_numbers: BTreeSet<u32>,
+
+    /// The records of the system calls that returned and haven't been drained yet, oldest first.
+    records: EventRing<SyscallTraceRecord>,
+
+    /// The records of the system calls waiting for their return value, by thread ID and user stack pointer after the
+    /// `ret` of the system call stub.
+    pending_returns: BTreeMap<(u64, u64), SyscallTraceRecord>,
+}
+
+impl SyscallTrace {
+    /// Creates a new stopped trace, without allocating the buffer.
+    fn new() -> Self {
+        Self {
+            filter_mode: SyscallTraceFilterMode::All,
+            syscall_numbers: BTreeSet::new(),
+            records: EventRing::new(EventRingId::SyscallTrace, SYSCALL_TRACE_BUFFER_CAPACITY),
+            pending_returns: BTreeMap::new(),
+        }
+    }
+
+    /// Returns `true` if a system call passes the filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_number` - The system call number.
+    fn is_traced(&self, syscall_number: u32) -> bool {
+        match self.filter_mode {
+            SyscallTraceFilterMode::All => true,
+            SyscallTraceFilterMode::Allow => self.syscall_numbers.contains(&syscall_number),
+            SyscallTraceFilterMode::Deny => !self.syscall_numbers.contains(&syscall_number),
+        }
+    }
+
+    /// Adds a record to the buffer and pushes it to the event stream of the guest agent, if registered.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record of a system call that returned.
+    fn push(&mut self, record: SyscallTraceRecord) {
+        stream_event(EventStreamKind::SyscallTrace, [record.syscall_number, record.process_id, record.thread_id, record.return_value]);
+
+        self.records.push(record);
+    }
+
+    /// Removes the oldest records from the buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_records` - The maximum number of records to remove.
+    ///
+    /// # Returns
+    ///
+    /// The records removed, oldest first, and the number of records dropped since the last drain.
+    pub fn drain(&mut self, max_records: usize) -> (Vec<SyscallTraceRecord>, u64) {
+        self.records.drain(max_records)
+    }
+
+    /// Returns the buffer of the records, to configure it or read its statistics.
+    pub fn event_ring(&mut self) -> &mut EventRing<SyscallTraceRecord> {
+        &mut self.records
+    }
+}
+
+/// Starts tracing the system calls passing a filter on all the logical processors, discarding the records of a previous run.
+///
+/// # Arguments
+///
+/// * `filter_mode` - How `syscall_numbers` selects the traced system calls.
+/// * `syscall_numbers` - The system call numbers of the filter.
+pub fn start_syscall_trace(filter_mode: SyscallTraceFilterMode, syscall_numbers: &[u32]) {
+    let mut syscall_trace = SHARED_SYSCALL_TRACE.lock();
+
+    syscall_trace.filter_mode = filter_mode;
+    syscall_trace.syscall_numbers = syscall_numbers.iter().copied().collect();
+    syscall_trace.records.reset();
+
+    SYSCALL_TRACE_ENABLED.store(true, Ordering::Release);
+    SHARED_SYSCALL_HOOK_MANAGER.lock().set_tracing(true);
+
+    debug!("Syscall trace started: {:?} {:x?}", filter_mode, syscall_numbers);
+}
+
+/// Stops tracing the system calls on all the logical processors, keeping the records so far.
+///
+/// The system calls still waiting for their return value are completed when they return.
+pub fn stop_syscall_trace() {
+    SYSCALL_TRACE_ENABLED.store(false, Ordering::Release);
+    SHARED_SYSCALL_HOOK_MANAGER.lock().set_tracing(false);
+
+    debug!("Syscall trace stopped");
+}
+
+/// Creates the record of a system call entering through the syscall trampoline, if it is traced.
+///
+/// This is called before the handler of the system call, so the arguments are the ones passed by the guest.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// * `Option<SyscallTraceRecord>` - The record, or `None` if the system call isn't traced.
+pub fn begin_syscall_trace(vm: &mut Vm) -> Option<SyscallTraceRecord> {
+    if !SYSCALL_TRACE_ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let syscall_number = vm.guest_registers.rax as u32;
+
+    if !SHARED_SYSCALL_TRACE.lock().is_traced(syscall_number) {
+        return None;
+    }
+
+    let (process_id, thread_id) = ProcessInformation::get_current_client_id().unwrap_or_default();
+
+    let context = SyscallContext {
+        registers: &mut vm.guest_registers,
+        syscall_number,
+    };
+
+    let mut arguments = [0; MAX_SYSCALL_TRACE_ARGUMENTS];
+    for (index, argument) in arguments.iter_mut().enumerate() {
+        *argument = context.argument(index).unwrap_or_default();
+    }
+
+    Some(SyscallTraceRecord {
+        processor_id: vm.cpuid_feature_info.initial_local_apic_id() as u64,
+        tsc: rdtsc(),
+        syscall_number: syscall_number as u64,
+        arguments,
+        cr3: vmread(vmcs::guest::CR3),
+        process_id,
+        thread_id,
+        return_address: context.return_address(),
+        return_value: 0,
+        has_return_value: 0,
+    })
+}
+
+/// Completes the record of a traced system call after its handler, or arms the capture of its return value if the
+/// original syscall entry handles it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `record` - The record created by `begin_syscall_trace`.
+/// * `action` - How the system call continues after its handler.
+pub fn end_syscall_trace(vm: &mut Vm, mut record: SyscallTraceRecord, action: SyscallAction) {
+    let mut syscall_trace = SHARED_SYSCALL_TRACE.lock();
+
+    if let SyscallAction::Complete(status) = action {
+        record.return_value = status;
+        record.has_return_value = 1;
+        syscall_trace.push(record);
+        return;
+    }
+
+    // The `ret` of the system call stub pops its return address.
+    let return_key = (record.thread_id, vm.guest_registers.rsp + 8);
+
+    // The system calls of this thread pending at or below this stack pointer can no longer return, their frames have
+    // been unwound (e.g., by `NtContinue`).
+    let unwound_keys: Vec<_> = syscall_trace
+        .pending_returns
+        .range((record.thread_id, 0)..=return_key)
+        .map(|(&key, _)| key)
+        .collect();
+
+    for key in unwound_keys {
+        if let Some(unwound_record) = syscall_trace.pending_returns.remove(&key) {
+            syscall_trace.push(unwound_record);
+        }
+    }
+
+    if record.thread_id == 0 || vm.guest_registers.r11 & RFLAGS_TRAP_FLAG != 0 || syscall_trace.pending_returns.len() >= MAX_PENDING_SYSCALL_RETURNS {
+        syscall_trace.push(record);
+        return;
+    }
+
+    // R11 is restored from the guest registers on VM entry.
+    vm.guest_registers.r11 |= RFLAGS_TRAP_FLAG;
+    syscall_trace.pending_returns.insert(return_key, record);
+}
+
+/// Completes the record of a traced system call with its return value, if it is waiting for it.
+///
+/// # Arguments
+///
+/// * `return_key` - The thread ID and user stack pointer after the `ret` of the system call stub.
+/// * `return_value` - The return value, from RAX.
+///
+/// # Returns
+///
+/// * `true` - If a traced system call was waiting for its return value.
+/// * `false` - If no traced system call was waiting for it.
+pub fn complete_syscall_trace_return(return_key: (u64, u64), return_value: u64) -> bool {
+    let mut syscall_trace = SHARED_SYSCALL_TRACE.lock();
+
+    let Some(mut record) = syscall_trace.pending_returns.remove(&return_key) else {
+        return false;
+    };
+
+    trace!("Syscall {:#x} of thread {} returned: {:#x}", record.syscall_number, record.thread_id, return_value);
+
+    record.return_value = return_value;
+    record.has_return_value = 1;
+    syscall_trace.push(record);
+
+    true
+}
@@ -0,0 +1,107 @@
+//! Detects guest writes to the bytes overwritten by a hook, e.g., by anti-cheat software or PatchGuard restoring the
+//! original bytes of a hooked function.
+//!
+//! When enabled, the hooked guest pages are write-protected in the primary EPT. A write to a hooked page is let through
+//! for a single instruction, and once it has completed, a `HookTamperEvent` is reported to the registered handler for
+//! each hook whose bytes contain the faulting address. Writes starting before the hooked bytes aren't reported.
+
+use {
+    crate::intel::{event_stream::stream_event, hooks::hook_manager::HookManager},
+    log::*,
+    shared::EventStreamKind,
+    x86::bits64::paging::PAddr,
+};
+
+/// The maximum number of hooked bytes reported in a `HookTamperEvent`, at least the size of the largest hook.
+pub const MAX_TAMPER_BYTES: usize = 16;
+
+/// A handler called in VMX root operation when the guest writes to the bytes overwritten by a hook.
+///
+/// The handler is called while the hook manager is locked, so it must not lock it again.
+pub type HookTamperHandler = fn(event: &HookTamperEvent);
+
+/// A write to a hooked page recorded on the EPT violation, reported once the writing instruction has been single-stepped.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingHookWrite {
+    /// The guest physical address written to.
+    pub guest_pa: u64,
+
+    /// The guest RIP of the writing instruction.
+    pub guest_rip: u64,
+
+    /// The guest CR3 of the writing instruction, identifying the address space of the writer.
+    pub guest_cr3: u64,
+}
+
+/// A guest write to the bytes overwritten by a hook.
+#[derive(Debug, Clone, Copy)]
+pub struct HookTamperEvent {
+    /// The virtual address of the hooked function.
+    pub guest_function_va: u64,
+
+    /// The guest physical address written to.
+    pub guest_pa: u64,
+
+    /// The guest RIP of the writing instruction.
+    pub guest_rip: u64,
+
+    /// The guest CR3 of the writing instruction, identifying the address space of the writer.
+    pub guest_cr3: u64,
+
+    /// The hooked bytes of the guest page after the write, only the first `length` bytes are valid.
+    pub bytes: [u8; MAX_TAMPER_BYTES],
+
+    /// The number of hooked bytes on the guest page.
+    pub length: usize,
+}
+
+/// Reports a completed write to a hooked page to the hook tamper handler, for each hook whose bytes were written to.
+///
+/// # Arguments
+///
+/// * `hook_manager` - The locked hook manager.
+/// * `write` - The write to the hooked page.
+pub fn report_hook_tamper(hook_manager: &HookManager, write: &PendingHookWrite) {
+    let guest_pa = PAddr::from(write.guest_pa);
+    let guest_page_pa = guest_pa.align_down_to_base_page();
+    let write_offset = guest_pa.base_page_offset() as usize;
+
+    let Some(hooks) = hook_manager.memory_manager.get_hook_info(guest_page_pa.as_u64()) else {
+        return;
+    };
+
+    for hook in hooks {
+        let range = HookManager::hook_range_in_page(guest_page_pa, PAddr::from(hook.guest_function_pa), hook.ept_hook_type);
+        if !range.contains(&write_offset) {
+            continue;
+        }
+
+        let length = range.len().min(MAX_TAMPER_BYTES);
+
+        let mut bytes = [0u8; MAX_TAMPER_BYTES];
+        bytes[..length].copy_from_slice(unsafe { core::slice::from_raw_parts((guest_page_pa.as_u64() + range.start as u64) as *const u8, length) });
+
+        let event = HookTamperEvent {
+            guest_function_va: hook.guest_function_va,
+            guest_pa: write.guest_pa,
+            guest_rip: write.guest_rip,
+            guest_cr3: write.guest_cr3,
+            bytes,
+            length,
+        };
+
+        warn!(
+            "Hooked bytes of function at VA: {:#x} written from RIP: {:#x} (CR3: {:#x}): {:02x?}",
+            event.guest_function_va,
+            event.guest_rip,
+            event.guest_cr3,
+            &event.bytes[..event.length]
+        );
+
+        stream_event(EventStreamKind::HookTamper, [event.guest_function_va, event.guest_pa, event.guest_rip, event.guest_cr3]);
+
+        if let Some(handler) = hook_manager.hook_tamper_handler {
+            handler(&event);
+        }
+    }
+}
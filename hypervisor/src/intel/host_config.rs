@@ -0,0 +1,166 @@
+use {
+    crate::intel::support::rdmsr,
+    alloc::{collections::BTreeSet, vec::Vec},
+    lazy_static::lazy_static,
+    log::*,
+    spin::RwLock,
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        msr::{IA32_VMX_EPT_VPID_CAP, IA32_VMX_PROCBASED_CTLS, IA32_VMX_PROCBASED_CTLS2},
+        vmx::vmcs::control::{PrimaryControls, SecondaryControls},
+    },
+};
+
+/// Host configuration that is set up once by the loader before any processor is virtualized.
+///
+/// The `HostConfig` struct holds resources that are shared by all logical processors and are only
+/// read after startup, such as the dummy page used for hiding hypervisor memory and the memory ranges
+/// allocated for the hypervisor. Mutable state owned by a single logical processor (EPTs, MSR bitmap, etc.)
+/// lives in `Vm`, and mutable state shared between processors for hooks lives in `HookManager`.
+#[derive(Debug, Clone)]
+pub struct HostConfig {
+    /// The physical address of the dummy page used for hiding hypervisor memory.
+    pub dummy_page_pa: u64,
+
+    /// The physical address of the communication page shared with the guest client (see `shared::SharedPage`).
+    pub shared_page_pa: u64,
+
+    /// A vector to keep track of allocated memory ranges for debugging and management purposes.
+    /// Each element is a tuple where the first value is the start address and the second value is the size of the allocation.
+    pub allocated_memory_ranges: Vec<(usize, usize)>,
+
+    /// The value of the IA32_VMX_EPT_VPID_CAP MSR, read once so the INVEPT and INVVPID types can be checked on every flush.
+    pub ept_vpid_capabilities: u64,
+}
+
+lazy_static! {
+    /// A globally shared instance of `HostConfig`, protected by a read-write lock.
+    ///
+    /// The configuration is only written during startup (by the loader and while allocating the host stacks),
+    /// so a `spin::RwLock` allows all logical processors to read it concurrently afterwards.
+    pub static ref SHARED_HOST_CONFIG: RwLock<HostConfig> = RwLock::new(HostConfig {
+        dummy_page_pa: 0,
+        shared_page_pa: 0,
+        allocated_memory_ranges: Vec::with_capacity(128),
+        ept_vpid_capabilities: 0,
+    });
+}
+
+impl HostConfig {
+    /// Initializes the `SHARED_HOST_CONFIG` with the provided dummy page and shared page physical addresses,
+    /// and reads the EPT and VPID capabilities of the processor.
+    ///
+    /// This function should be called during the hypervisor setup process, before any operations
+    /// that depend on the `dummy_page_pa` or `shared_page_pa` fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `dummy_page_pa`: The physical address of the dummy page used for hiding hypervisor memory.
+    /// * `shared_page_pa`: The physical address of the communication page shared with the guest client.
+    pub fn initialize_shared_host_config(dummy_page_pa: u64, shared_page_pa: u64) {
+        let mut host_config = SHARED_HOST_CONFIG.write();
+        host_config.dummy_page_pa = dummy_page_pa;
+        host_config.shared_page_pa = shared_page_pa;
+        host_config.ept_vpid_capabilities = read_ept_vpid_capabilities();
+    }
+
+    /// Checks whether the processor supports the single-context INVEPT type.
+    ///
+    /// # Returns
+    ///
+    /// `true` if bit 25 of the IA32_VMX_EPT_VPID_CAP MSR is set, otherwise `false`.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.10 VPID AND EPT CAPABILITIES
+    pub fn is_invept_single_context_supported(&self) -> bool {
+        /// [Bit 25] When set to 1, the single-context INVEPT type is supported.
+        const INVEPT_SINGLE_CONTEXT: u64 = 1 << 25;
+
+        self.ept_vpid_capabilities & INVEPT_SINGLE_CONTEXT != 0
+    }
+
+    /// Checks whether the processor supports the individual-address INVVPID type.
+    ///
+    /// # Returns
+    ///
+    /// `true` if bit 40 of the IA32_VMX_EPT_VPID_CAP MSR is set, otherwise `false`.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.10 VPID AND EPT CAPABILITIES
+    pub fn is_invvpid_individual_address_supported(&self) -> bool {
+        /// [Bit 40] When set to 1, the individual-address INVVPID type is supported.
+        const INVVPID_INDIVIDUAL_ADDRESS: u64 = 1 << 40;
+
+        self.ept_vpid_capabilities & INVVPID_INDIVIDUAL_ADDRESS != 0
+    }
+
+    /// Records a memory allocation for tracking purposes.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The start address of the memory allocation.
+    /// * `size` - The size of the memory allocation.
+    pub fn record_allocation(&mut self, start: usize, size: usize) {
+        self.allocated_memory_ranges.push((start, size));
+    }
+
+    /// Returns the physical addresses of the pages covering the allocated memory ranges, without duplicates.
+    ///
+    /// The ranges don't have to be page-aligned, e.g., for pool allocations, so the pages at their ends may also
+    /// contain memory that isn't owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `first_range` - The index of the first allocated memory range to include.
+    ///
+    /// # Returns
+    ///
+    /// The page-aligned physical addresses, in ascending order.
+    pub fn allocated_pages(&self, first_range: usize) -> BTreeSet<u64> {
+        let mut pages = BTreeSet::new();
+
+        for &(start, size) in self.allocated_memory_ranges.iter().skip(first_range) {
+            let first_page = start & !(BASE_PAGE_SIZE - 1);
+            let end = (start + size).next_multiple_of(BASE_PAGE_SIZE);
+
+            pages.extend((first_page..end).step_by(BASE_PAGE_SIZE).map(|page| page as u64));
+        }
+
+        pages
+    }
+
+    /// Prints the allocated memory ranges for debugging purposes.
+    pub fn print_allocated_memory(&self) {
+        self.allocated_memory_ranges.iter().for_each(|(start, size)| {
+            debug!("Memory Range: Start = {:#x}, Size = {:#x}", start, size);
+        });
+    }
+}
+
+/// Reads the IA32_VMX_EPT_VPID_CAP MSR.
+///
+/// The MSR only exists if the processor supports VMX, the secondary processor-based controls, and either EPT or VPIDs,
+/// otherwise `rdmsr` raises #GP. Setup runs before the processor checks of `start_hypervisor`, so `0` is returned in that case.
+///
+/// # Returns
+///
+/// The value of the MSR, or `0` if it is not available.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.10 VPID AND EPT CAPABILITIES
+fn read_ept_vpid_capabilities() -> u64 {
+    let has_vmx = x86::cpuid::CpuId::new().get_feature_info().is_some_and(|fi| fi.has_vmx());
+    if !has_vmx {
+        return 0;
+    }
+
+    // Bits 63:32 of the control MSRs indicate the allowed 1-settings.
+    let primary_controls = PrimaryControls::from_bits_truncate((rdmsr(IA32_VMX_PROCBASED_CTLS) >> 32) as u32);
+    if !primary_controls.contains(PrimaryControls::SECONDARY_CONTROLS) {
+        return 0;
+    }
+
+    let secondary_controls = SecondaryControls::from_bits_truncate((rdmsr(IA32_VMX_PROCBASED_CTLS2) >> 32) as u32);
+    if !secondary_controls.intersects(SecondaryControls::ENABLE_EPT | SecondaryControls::ENABLE_VPID) {
+        return 0;
+    }
+
+    rdmsr(IA32_VMX_EPT_VPID_CAP)
+}
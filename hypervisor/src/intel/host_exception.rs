@@ -0,0 +1,422 @@
+//! Provides the exception handlers of the host, so a fault in VMX root operation, e.g., a page fault in a VM exit
+//! handler, is reported over the logger instead of hanging the logical processor.
+//!
+//! The host IDT, shared by all the logical processors, routes each exception to a stub saving the general-purpose
+//! registers, which calls `handle_host_exception`. The exception, the registers and the last VM exits of the logical
+//! processor are logged, then the logical processor halts with interrupts disabled. The double fault, the NMI and the
+//! machine check run on their own IST stacks, so an overflow of the host stack is still reported.
+//!
+//! An NMI received in VMX root operation is not an error: it's counted by its stub and dispatched at the end of the VM
+//! exit, or at the end of the next one if it's received after the pending NMIs were taken (see the `nmi` module).
+//!
+//! Each logical processor owns its TSS holding the IST stacks, loaded through the host TR base of its VMCS, and the
+//! host GS base points to its `ProcessorHostExceptions`, so the stubs and the handler find it without the VMCS.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 6.14 Exception and Interrupt Handling in
+//! 64-bit Mode and 8.7 Task Management in 64-bit Mode
+
+use {
+    crate::{
+        intel::{
+            host_stack::SHARED_HOST_STACKS,
+            nmi::dispatch_nmis,
+            support::{cr2, cr3, cr4, rdmsr, rdtsc},
+            timing::tsc_frequency_hz,
+            vm::Vm,
+            vmerror::{ExceptionInterrupt, VmxBasicExitReason},
+        },
+        logger::{force_unlock_serial, is_serial_locked},
+    },
+    alloc::vec::Vec,
+    core::{
+        arch::{asm, global_asm},
+        hint::spin_loop,
+        mem,
+        ptr::write_unaligned,
+        sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    log::*,
+    x86::{msr, segmentation::SegmentSelector},
+    x86_64::registers::control::Cr0,
+};
+
+/// The number of VM exits kept in the history of each logical processor.
+const EXIT_HISTORY_LENGTH: usize = 16;
+
+/// The size in bytes of each IST stack.
+const IST_STACK_SIZE: usize = 0x4000;
+
+/// The number of IST stacks of each logical processor.
+const IST_STACK_COUNT: usize = 3;
+
+/// The IST stack of the double fault.
+const DOUBLE_FAULT_IST: u8 = 1;
+
+/// The IST stack of the NMI.
+const NMI_IST: u8 = 2;
+
+/// The IST stack of the machine check.
+const MACHINE_CHECK_IST: u8 = 3;
+
+/// The number of exception vectors, routed to the stubs.
+const EXCEPTION_VECTOR_COUNT: usize = 32;
+
+/// The number of gates of the host IDT, each of them 16 bytes large.
+const IDT_GATE_COUNT: usize = 256;
+
+/// The exceptions pushing an error code: #DF, #TS, #NP, #SS, #GP, #PF, #AC, #CP, #VC and #SX.
+const ERROR_CODE_VECTORS: u32 = (1 << 8) | (1 << 10) | (1 << 11) | (1 << 12) | (1 << 13) | (1 << 14) | (1 << 17) | (1 << 21) | (1 << 29) | (1 << 30);
+
+/// The size in bytes of each exception stub, the stubs being laid out contiguously.
+const STUB_SIZE: usize = 16;
+
+/// The size in bytes of the 64-bit TSS.
+const TSS_SIZE: usize = 104;
+
+/// The offset of IST1 in the 64-bit TSS, the other IST pointers following it.
+const TSS_IST_OFFSET: usize = 36;
+
+/// The offset of the I/O map base address in the 64-bit TSS.
+const TSS_IO_MAP_BASE_OFFSET: usize = 102;
+
+/// The type and attributes of a present 64-bit interrupt gate of DPL 0.
+const INTERRUPT_GATE_ATTRIBUTES: u64 = 0x8E;
+
+/// The time in milliseconds the handler waits for the serial port to be released by another logical processor.
+const SERIAL_LOCK_TIMEOUT_MS: u64 = 100;
+
+/// A VM exit recorded in the history of a logical processor.
+#[derive(Debug, Clone, Copy)]
+struct ExitRecord {
+    /// The basic exit reason, `None` for an unused record.
+    reason: Option<VmxBasicExitReason>,
+
+    /// The RIP of the guest at the VM exit.
+    guest_rip: u64,
+
+    /// The TSC at the VM exit.
+    tsc: u64,
+}
+
+impl ExitRecord {
+    /// An unused record.
+    const EMPTY: Self = Self {
+        reason: None,
+        guest_rip: 0,
+        tsc: 0,
+    };
+}
+
+/// An IST stack of a logical processor.
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+/// The host exception state of a logical processor: its TSS, IST stacks, pending NMIs and VM exit history.
+#[repr(C, align(16))]
+pub struct ProcessorHostExceptions {
+    /// The 64-bit TSS, holding the IST pointers.
+    tss: [u8; TSS_SIZE],
+
+    /// The NMIs received in VMX root operation, not yet reflected to the guest.
+    pending_nmi_count: AtomicU64,
+
+    /// Whether a fatal exception is being reported, so a fault in the handler halts without recursing.
+    is_handling: AtomicBool,
+
+    /// The last VM exits, `exit_history_index` being the next record written.
+    exit_history: [ExitRecord; EXIT_HISTORY_LENGTH],
+
+    /// The index of the next record written in `exit_history`.
+    exit_history_index: usize,
+
+    /// The IST stacks of the double fault, the NMI and the machine check.
+    ist_stacks: [IstStack; IST_STACK_COUNT],
+}
+
+impl ProcessorHostExceptions {
+    /// Initializes the TSS with the IST stacks, and clears the pending NMIs and the VM exit history.
+    ///
+    /// The state is initialized in place, as the `Vm` containing it never moves once initialized.
+    pub fn init(&mut self) {
+        self.tss = [0; TSS_SIZE];
+
+        for (i, stack) in self.ist_stacks.iter().enumerate() {
+            let stack_top = stack.0.as_ptr_range().end as u64;
+            unsafe { write_unaligned(self.tss.as_mut_ptr().add(TSS_IST_OFFSET + 8 * i).cast::<u64>(), stack_top) };
+        }
+
+        // No I/O permission bitmap, the base being the size of the TSS.
+        unsafe { write_unaligned(self.tss.as_mut_ptr().add(TSS_IO_MAP_BASE_OFFSET).cast::<u16>(), TSS_SIZE as u16) };
+
+        self.pending_nmi_count = AtomicU64::new(0);
+        self.is_handling = AtomicBool::new(false);
+        self.exit_history = [ExitRecord::EMPTY; EXIT_HISTORY_LENGTH];
+        self.exit_history_index = 0;
+    }
+
+    /// Returns the base address of the TSS, written as the host TR base.
+    pub fn tss_base(&self) -> u64 {
+        self.tss.as_ptr() as u64
+    }
+
+    /// Returns the address of this state, written as the host GS base.
+    pub fn gs_base(&self) -> u64 {
+        self as *const Self as u64
+    }
+
+    /// Takes the NMIs received in VMX root operation since the last call.
+    ///
+    /// # Returns
+    ///
+    /// The number of NMIs received.
+    fn take_pending_nmis(&self) -> u64 {
+        self.pending_nmi_count.swap(0, Ordering::AcqRel)
+    }
+
+    /// Returns `true` if NMIs were received in VMX root operation since they were last taken.
+    pub fn has_pending_nmis(&self) -> bool {
+        self.pending_nmi_count.load(Ordering::Acquire) != 0
+    }
+
+    /// Returns the recorded VM exits, the most recent first.
+    fn recent_exits(&self) -> impl Iterator<Item = &ExitRecord> {
+        (1..=EXIT_HISTORY_LENGTH)
+            .map(move |age| &self.exit_history[(self.exit_history_index + EXIT_HISTORY_LENGTH - age) % EXIT_HISTORY_LENGTH])
+            .filter(|record| record.reason.is_some())
+    }
+}
+
+/// The registers saved by the exception stubs, followed by the frame pushed by the processor.
+#[repr(C)]
+#[derive(Debug)]
+struct HostExceptionFrame {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    vector: u64,
+    error_code: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+/// Records a VM exit in the history of the current logical processor, logged if the host faults.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `reason` - The basic exit reason of the VM exit.
+/// * `exit_tsc` - The TSC at the VM exit.
+pub fn record_exit(vm: &mut Vm, reason: VmxBasicExitReason, exit_tsc: u64) {
+    let state = &mut vm.host_exceptions;
+
+    state.exit_history[state.exit_history_index] = ExitRecord {
+        reason: Some(reason),
+        guest_rip: vm.guest_registers.rip,
+        tsc: exit_tsc,
+    };
+    state.exit_history_index = (state.exit_history_index + 1) % EXIT_HISTORY_LENGTH;
+}
+
+/// Dispatches the NMIs received in VMX root operation on the current logical processor (see the `nmi` module).
+///
+/// This is called at the end of every VM exit, before the pending events are injected.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn dispatch_root_nmis(vm: &mut Vm) {
+    let count = vm.host_exceptions.take_pending_nmis();
+    if count != 0 {
+        trace!("{} NMIs received in VMX root operation", count);
+    }
+
+    dispatch_nmis(vm, count);
+}
+
+/// Builds the host IDT, routing the exceptions to the stubs and leaving the other vectors not present.
+///
+/// # Arguments
+///
+/// * `cs` - The code segment selector of the host.
+///
+/// # Returns
+///
+/// The gates of the IDT, two `u64` per gate.
+pub fn build_host_idt(cs: SegmentSelector) -> Vec<u64> {
+    let stubs = host_exception_stubs as *const () as u64;
+
+    (0..IDT_GATE_COUNT)
+        .flat_map(|vector| {
+            let stub = stubs + (vector * STUB_SIZE) as u64;
+
+            match vector as u32 {
+                v if v as usize >= EXCEPTION_VECTOR_COUNT => [0, 0],
+                v if v == ExceptionInterrupt::NonMaskableInterrupt as u32 => interrupt_gate(host_nmi_stub as *const () as u64, cs, NMI_IST),
+                v if v == ExceptionInterrupt::DoubleFault as u32 => interrupt_gate(stub, cs, DOUBLE_FAULT_IST),
+                v if v == ExceptionInterrupt::MachineCheck as u32 => interrupt_gate(stub, cs, MACHINE_CHECK_IST),
+                _ => interrupt_gate(stub, cs, 0),
+            }
+        })
+        .collect()
+}
+
+/// Encodes a 64-bit interrupt gate.
+///
+/// # Arguments
+///
+/// * `handler` - The address of the handler.
+/// * `cs` - The code segment selector of the handler.
+/// * `ist` - The IST stack the handler runs on, 0 for the current stack.
+///
+/// # Returns
+///
+/// The low and high 8 bytes of the gate.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Figure 6-8. 64-Bit IDT Gate Descriptors
+fn interrupt_gate(handler: u64, cs: SegmentSelector, ist: u8) -> [u64; 2] {
+    let low = (handler & 0xFFFF)
+        | (u64::from(cs.bits()) << 16)
+        | (u64::from(ist) << 32)
+        | (INTERRUPT_GATE_ATTRIBUTES << 40)
+        | (((handler >> 16) & 0xFFFF) << 48);
+
+    [low, handler >> 32]
+}
+
+/// Reports an exception of the host, then halts the logical processor.
+///
+/// # Arguments
+///
+/// * `frame` - The registers saved by the stub and the frame pushed by the processor.
+extern "efiapi" fn handle_host_exception(frame: &HostExceptionFrame) -> ! {
+    let state = unsafe { &*(rdmsr(msr::IA32_GS_BASE) as *const ProcessorHostExceptions) };
+
+    // A fault while reporting one would recurse without end.
+    if state.is_handling.swap(true, Ordering::AcqRel) {
+        halt();
+    }
+
+    // The serial port may be held by another logical processor writing a message, or by the faulting code itself.
+    let deadline_tsc = rdtsc().wrapping_add(tsc_frequency_hz() / 1000 * SERIAL_LOCK_TIMEOUT_MS);
+    while is_serial_locked() && rdtsc() < deadline_tsc {
+        spin_loop();
+    }
+    if is_serial_locked() {
+        unsafe { force_unlock_serial() };
+    }
+
+    let vector = ExceptionInterrupt::from_u32(frame.vector as u32);
+
+    error!("==================== HOST EXCEPTION ====================");
+    error!("Exception: {:?} (vector {}), error code: {:#x}", vector, frame.vector, frame.error_code);
+    error!("RIP: {:#x}, CS: {:#x}, RFLAGS: {:#x}, RSP: {:#x}, SS: {:#x}", frame.rip, frame.cs, frame.rflags, frame.rsp, frame.ss);
+    error!("CR0: {:#x}, CR2: {:#x}, CR3: {:#x}, CR4: {:#x}", Cr0::read_raw(), cr2(), cr3(), cr4());
+    error!("RAX: {:#x}, RBX: {:#x}, RCX: {:#x}, RDX: {:#x}", frame.rax, frame.rbx, frame.rcx, frame.rdx);
+    error!("RSI: {:#x}, RDI: {:#x}, RBP: {:#x}", frame.rsi, frame.rdi, frame.rbp);
+    error!("R8: {:#x}, R9: {:#x}, R10: {:#x}, R11: {:#x}", frame.r8, frame.r9, frame.r10, frame.r11);
+    error!("R12: {:#x}, R13: {:#x}, R14: {:#x}, R15: {:#x}", frame.r12, frame.r13, frame.r14, frame.r15);
+
+    // The registry isn't waited for, the faulting code may hold its lock.
+    let fault_address = cr2();
+    if let Some(stack) = SHARED_HOST_STACKS.try_lock().and_then(|stacks| stacks.find(fault_address)) {
+        if stack.is_guard_page(fault_address) {
+            error!("Host stack overflow: {:#x} in the guard page of the stack {:#x?}", fault_address, stack.bottom()..stack.top());
+        }
+    }
+
+    error!("Last VM exits, most recent first:");
+    for record in state.recent_exits() {
+        error!("  {:?} at guest RIP {:#x}, TSC {:#x}", record.reason, record.guest_rip, record.tsc);
+    }
+
+    error!("Halting the logical processor");
+    log::logger().flush();
+
+    halt()
+}
+
+/// Halts the logical processor with interrupts disabled, an NMI resuming the halt once handled.
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli", "hlt") };
+    }
+}
+
+extern "C" {
+    /// The exception stubs, `STUB_SIZE` bytes apart.
+    fn host_exception_stubs();
+
+    /// The stub of the NMI, counting it without leaving the interrupted code.
+    fn host_nmi_stub();
+}
+
+global_asm!(
+    r#"
+// The stub of each exception pushes a dummy error code for the exceptions without one, then the vector, so the frame
+// is the same for all of them.
+.global host_exception_stubs
+.balign 16
+host_exception_stubs:
+.set host_exception_vector, 0
+.rept {vector_count}
+    .balign {stub_size}
+    .if (({error_code_vectors} >> host_exception_vector) & 1) == 0
+    push    0
+    .endif
+    push    host_exception_vector
+    jmp     host_exception_common
+    .set host_exception_vector, host_exception_vector + 1
+.endr
+
+// The processor aligns the stack to 16 bytes before pushing its frame of 5 qwords, the error code, the vector and the
+// 15 registers saved here keeping it aligned for the call.
+host_exception_common:
+    push    r15
+    push    r14
+    push    r13
+    push    r12
+    push    r11
+    push    r10
+    push    r9
+    push    r8
+    push    rbp
+    push    rdi
+    push    rsi
+    push    rdx
+    push    rcx
+    push    rbx
+    push    rax
+
+    mov     rcx, rsp
+    sub     rsp, 0x20
+    call    {handler}
+    ud2
+
+// The host GS base points to the `ProcessorHostExceptions` of the logical processor.
+.global host_nmi_stub
+.balign 16
+host_nmi_stub:
+    lock inc qword ptr gs:[{pending_nmi_count}]
+    iretq
+"#,
+    vector_count = const EXCEPTION_VECTOR_COUNT,
+    stub_size = const STUB_SIZE,
+    error_code_vectors = const ERROR_CODE_VECTORS,
+    handler = sym handle_host_exception,
+    pending_nmi_count = const mem::offset_of!(ProcessorHostExceptions, pending_nmi_count),
+);
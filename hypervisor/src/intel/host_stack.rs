@@ -0,0 +1,125 @@
+//! Tracks the host stacks of the logical processors, each of them preceded by a guard page unmapped from the host
+//! paging of its logical processor, so a stack overflow in VMX root operation faults instead of silently corrupting the
+//! memory below the stack.
+//!
+//! The loader allocates a stack of `STACK_PAGES_PER_PROCESSOR` pages plus `HOST_STACK_GUARD_PAGES` for each logical
+//! processor and registers it before switching to it. The `Vm` is initialized on that stack, so the stack VMX root
+//! operation runs on, and `HOST_RSP` written by `launch_vm`, are always within a registered stack. The page fault of an
+//! overflow can't be delivered on the overflowed stack, so it escalates to a double fault on its IST stack, reported by
+//! the `host_exception` module.
+
+use {
+    crate::{error::HypervisorError, global_const::HOST_STACK_GUARD_PAGES},
+    alloc::vec::Vec,
+    core::arch::asm,
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+lazy_static! {
+    /// A globally shared instance of `HostStacks`, protected by a mutex.
+    pub static ref SHARED_HOST_STACKS: Mutex<HostStacks> = Mutex::new(HostStacks::new());
+}
+
+/// A host stack and its guard pages.
+#[derive(Debug, Clone, Copy)]
+pub struct HostStack {
+    /// The address of the lowest guard page, page-aligned.
+    pub base: u64,
+
+    /// The size in bytes of the stack, including the guard pages.
+    pub size: u64,
+}
+
+impl HostStack {
+    /// Returns the address of the lowest guard page.
+    pub fn guard_page(&self) -> u64 {
+        self.base
+    }
+
+    /// Returns the address of the lowest usable byte of the stack, above the guard pages.
+    pub fn bottom(&self) -> u64 {
+        self.base + (HOST_STACK_GUARD_PAGES * BASE_PAGE_SIZE) as u64
+    }
+
+    /// Returns the top of the stack, the address following its highest byte.
+    pub fn top(&self) -> u64 {
+        self.base + self.size
+    }
+
+    /// Returns `true` if an address is in the guard pages of this stack.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address to check.
+    pub fn is_guard_page(&self, address: u64) -> bool {
+        (self.base..self.bottom()).contains(&address)
+    }
+}
+
+/// The host stacks registered by the loader.
+#[derive(Debug, Clone)]
+pub struct HostStacks {
+    /// The registered stacks.
+    stacks: Vec<HostStack>,
+}
+
+impl HostStacks {
+    /// Creates a new registry without any stack.
+    fn new() -> Self {
+        Self { stacks: Vec::new() }
+    }
+
+    /// Registers a host stack allocated by the loader, its lowest `HOST_STACK_GUARD_PAGES` pages being the guard.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - The address of the allocation, page-aligned.
+    /// * `size` - The size in bytes of the allocation, including the guard pages.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the stack is registered.
+    /// * `Err(HypervisorError::InvalidHostStack)` - If the allocation isn't page-aligned or is too small for its guard.
+    pub fn register_host_stack(&mut self, base: u64, size: u64) -> Result<(), HypervisorError> {
+        let guard_size = (HOST_STACK_GUARD_PAGES * BASE_PAGE_SIZE) as u64;
+
+        if !base.is_multiple_of(BASE_PAGE_SIZE as u64) || size <= guard_size {
+            return Err(HypervisorError::InvalidHostStack);
+        }
+
+        let stack = HostStack { base, size };
+        trace!("Host stack: {:#x?}, guard page: {:#x}", stack.bottom()..stack.top(), stack.guard_page());
+        self.stacks.push(stack);
+
+        Ok(())
+    }
+
+    /// Returns the registered stack an address is in, guard pages included.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address to look up, e.g., a stack pointer.
+    pub fn find(&self, address: u64) -> Option<HostStack> {
+        self.stacks.iter().find(|stack| (stack.base..stack.top()).contains(&address)).copied()
+    }
+}
+
+/// Returns the registered stack the current logical processor runs on.
+///
+/// # Returns
+///
+/// * `Ok(HostStack)` - The stack containing the current stack pointer, outside of its guard pages.
+/// * `Err(HypervisorError::HostStackNotFound)` - If the current stack isn't a registered host stack.
+pub fn current_host_stack() -> Result<HostStack, HypervisorError> {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+
+    SHARED_HOST_STACKS
+        .lock()
+        .find(rsp)
+        .filter(|stack| !stack.is_guard_page(rsp))
+        .ok_or(HypervisorError::HostStackNotFound)
+}
@@ -0,0 +1,242 @@
+//! Provides the authentication of the hypercalls, so guest code other than the guest agent can't drive the
+//! privileged hypercalls, e.g., to install hooks or write the memory of other processes.
+//!
+//! The agent opens a session with the secret shared with the hypervisor at build time, and the hypervisor returns a
+//! random session key, which the agent passes with each hypercall. A single session is open at a time, so the first
+//! agent loaded holds it until it closes it, and the session can be restricted to the address space of the agent and
+//! to a range of caller RIPs, e.g., its image, so a leaked key can't be used from elsewhere.
+//!
+//! The hypercalls failing the authentication raise #UD as VMCALL does without a hypervisor, so they don't reveal it.
+//! The failures are counted by address space, as any process can make hypercalls: an address space failing
+//! `MAX_FAILED_AUTHENTICATIONS` times is locked out for `LOCKOUT_DURATION_S`, bounding the guessing of the secret or
+//! the key without letting another process lock the agent out.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            paging::CR3_ADDRESS_MASK,
+            support::{rdrand, rdtsc},
+            timing::tsc_frequency_hz,
+        },
+    },
+    core::ops::Range,
+    lazy_static::lazy_static,
+    log::*,
+    shared::HYPERCALL_SECRET,
+    spin::Mutex,
+};
+
+/// The number of failed authentications after which an address space is locked out.
+pub const MAX_FAILED_AUTHENTICATIONS: u32 = 16;
+
+/// The time in seconds an address space is locked out for, after which its failures are forgotten.
+const LOCKOUT_DURATION_S: u64 = 60;
+
+/// The number of address spaces whose failed authentications are counted at the same time.
+const MAX_TRACKED_ADDRESS_SPACES: usize = 64;
+
+lazy_static! {
+    /// A globally shared instance of `HypercallAuth`, protected by a mutex.
+    pub static ref SHARED_HYPERCALL_AUTH: Mutex<HypercallAuth> = Mutex::new(HypercallAuth::new());
+}
+
+/// The open session of the guest agent.
+#[derive(Debug, Clone)]
+struct HypercallSession {
+    /// The key passed with each hypercall.
+    key: u64,
+
+    /// The address space the hypercalls are restricted to, without the PCID, if any.
+    directory_table_base: Option<u64>,
+
+    /// The range of RIPs the hypercalls are restricted to, if any.
+    rip_range: Option<Range<u64>>,
+}
+
+/// The failed authentications of an address space.
+#[derive(Debug, Clone, Copy)]
+struct FailedAuthentications {
+    /// The address space of the callers, without the PCID.
+    directory_table_base: u64,
+
+    /// The number of failed authentications since the first one, or since the end of the last lockout.
+    count: u32,
+
+    /// The TSC at which the failures are forgotten, the end of the lockout once `count` reaches
+    /// `MAX_FAILED_AUTHENTICATIONS`.
+    expiry_tsc: u64,
+}
+
+/// The session of the guest agent and the failed authentications.
+#[derive(Debug)]
+pub struct HypercallAuth {
+    /// The open session, if any.
+    session: Option<HypercallSession>,
+
+    /// The failed authentications of the address spaces.
+    failed_authentications: [Option<FailedAuthentications>; MAX_TRACKED_ADDRESS_SPACES],
+}
+
+impl HypercallAuth {
+    /// Creates the authentication state without a session.
+    fn new() -> Self {
+        Self {
+            session: None,
+            failed_authentications: [None; MAX_TRACKED_ADDRESS_SPACES],
+        }
+    }
+
+    /// Returns `true` if an address space is locked out after too many failed authentications.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The guest CR3 of the address space.
+    pub fn is_locked(&self, cr3: u64) -> bool {
+        let directory_table_base = cr3 & CR3_ADDRESS_MASK;
+        let tsc = rdtsc();
+
+        self.failed_authentications.iter().flatten().any(|failures| {
+            failures.directory_table_base == directory_table_base && failures.count >= MAX_FAILED_AUTHENTICATIONS && tsc < failures.expiry_tsc
+        })
+    }
+
+    /// Authenticates the opening of a session with the shared secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The secret passed by the caller.
+    /// * `cr3` - The guest CR3 of the caller.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the secret is the shared secret and the address space of the caller isn't locked out.
+    pub fn authenticate_secret(&mut self, secret: u64, cr3: u64) -> bool {
+        self.check(secret == HYPERCALL_SECRET, cr3)
+    }
+
+    /// Authenticates a hypercall with the key and the restrictions of the session.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The session key passed by the caller.
+    /// * `cr3` - The guest CR3 of the caller.
+    /// * `rip` - The guest RIP of the caller.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a session is open with this key, the caller passes its restrictions and its address space isn't locked
+    /// out.
+    pub fn authenticate_hypercall(&mut self, key: u64, cr3: u64, rip: u64) -> bool {
+        let authorized = self.session.as_ref().is_some_and(|session| {
+            session.key == key
+                && session
+                    .directory_table_base
+                    .is_none_or(|directory_table_base| directory_table_base == cr3 & CR3_ADDRESS_MASK)
+                && session.rip_range.as_ref().is_none_or(|rip_range| rip_range.contains(&rip))
+        });
+
+        self.check(authorized, cr3)
+    }
+
+    /// Counts a failed authentication of an address space, locking it out for `LOCKOUT_DURATION_S` after
+    /// `MAX_FAILED_AUTHENTICATIONS` failures.
+    ///
+    /// # Arguments
+    ///
+    /// * `authorized` - Whether the credentials of the caller are valid.
+    /// * `cr3` - The guest CR3 of the caller.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the caller is authorized and its address space isn't locked out.
+    fn check(&mut self, authorized: bool, cr3: u64) -> bool {
+        if self.is_locked(cr3) {
+            return false;
+        }
+
+        if authorized {
+            return true;
+        }
+
+        let directory_table_base = cr3 & CR3_ADDRESS_MASK;
+        let tsc = rdtsc();
+        let lockout_ticks = tsc_frequency_hz() * LOCKOUT_DURATION_S;
+
+        // The failures of an address space are forgotten once they expire, so its entry can be reused by another one.
+        let failures = self
+            .failed_authentications
+            .iter_mut()
+            .find(|failures| failures.is_none_or(|failures| failures.directory_table_base == directory_table_base || tsc >= failures.expiry_tsc));
+
+        let Some(failures) = failures else {
+            warn!("Hypercall authentication failed, too many address spaces failing to count CR3: {:#x}", directory_table_base);
+            return false;
+        };
+
+        let count = match failures {
+            Some(failures) if failures.directory_table_base == directory_table_base && tsc < failures.expiry_tsc => failures.count + 1,
+            _ => 1,
+        };
+
+        *failures = Some(FailedAuthentications {
+            directory_table_base,
+            count,
+            expiry_tsc: tsc.wrapping_add(lockout_ticks),
+        });
+
+        warn!("Hypercall authentication failed for CR3 {:#x} ({} of {})", directory_table_base, count, MAX_FAILED_AUTHENTICATIONS);
+
+        if count >= MAX_FAILED_AUTHENTICATIONS {
+            error!("Hypercalls of CR3 {:#x} locked out for {} s", directory_table_base, LOCKOUT_DURATION_S);
+        }
+
+        false
+    }
+
+    /// Opens the session of the guest agent, once its secret has been authenticated.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The random session key.
+    /// * `Err(HypervisorError::HypercallSessionAlreadyOpen)` - If another agent holds the session.
+    /// * `Err(HypervisorError::HypercallSessionKeyUnavailable)` - If the hardware random number generator is exhausted.
+    pub fn open_session(&mut self) -> Result<u64, HypervisorError> {
+        if self.session.is_some() {
+            return Err(HypervisorError::HypercallSessionAlreadyOpen);
+        }
+
+        let key = rdrand().ok_or(HypervisorError::HypercallSessionKeyUnavailable)?;
+
+        self.session = Some(HypercallSession {
+            key,
+            directory_table_base: None,
+            rip_range: None,
+        });
+
+        debug!("Hypercall session opened");
+
+        Ok(key)
+    }
+
+    /// Closes the session, so another agent can open one.
+    pub fn close_session(&mut self) {
+        self.session = None;
+        debug!("Hypercall session closed");
+    }
+
+    /// Restricts the hypercalls of the session to an address space and a range of caller RIPs, replacing the previous
+    /// restrictions.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The guest CR3 of the address space, or `None` for any address space.
+    /// * `rip_range` - The range of caller RIPs, or `None` for any caller.
+    pub fn restrict_session(&mut self, cr3: Option<u64>, rip_range: Option<Range<u64>>) {
+        if let Some(session) = &mut self.session {
+            debug!("Hypercall session restricted to CR3: {:x?}, RIPs: {:x?}", cr3, rip_range);
+            session.directory_table_base = cr3.map(|cr3| cr3 & CR3_ADDRESS_MASK);
+            session.rip_range = rip_range;
+        }
+    }
+}
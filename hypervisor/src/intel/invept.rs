@@ -4,6 +4,8 @@
 //! that cache translations derived from EPT. It's used to ensure that modifications to EPT entries don't cause
 //! inconsistencies due to stale cached translations.
 
+use crate::intel::host_config::SHARED_HOST_CONFIG;
+
 /// Represents the types of INVEPT operations.
 #[repr(u64)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -69,3 +71,18 @@ pub fn invept_all_contexts() {
     // The EPT pointer is irrelevant for this type of operation and is thus set to 0.
     invept(InveptType::AllContexts, 0);
 }
+
+/// Invalidates the cached translations derived from an EPTP.
+///
+/// This performs a single-context INVEPT if the processor supports that type, otherwise it falls back to
+/// invalidating all contexts, which is always supported by the processors the hypervisor runs on.
+///
+/// # Arguments
+/// * `eptp` - The Extended Page Table Pointer whose cached translations are to be invalidated.
+pub fn invept_eptp(eptp: u64) {
+    if SHARED_HOST_CONFIG.read().is_invept_single_context_supported() {
+        invept_single_context(eptp);
+    } else {
+        invept_all_contexts();
+    }
+}
@@ -4,7 +4,35 @@
 //! that are associated with a specific Virtual Processor Identifier (VPID). This is essential in virtualization
 //! environments to maintain consistency of memory translations across different virtual processors.
 
-pub const VPID_TAG: u16 = 0x1;
+use {
+    crate::{error::HypervisorError, intel::host_config::SHARED_HOST_CONFIG},
+    core::sync::atomic::{AtomicU16, Ordering},
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The next Virtual Processor Identifier (VPID) to assign to a logical processor.
+///
+/// VPID 0 is reserved for VMX root operation, so allocation starts at 1.
+static NEXT_VPID: AtomicU16 = AtomicU16::new(1);
+
+/// Allocates a unique, non-zero Virtual Processor Identifier (VPID) for a logical processor.
+///
+/// Giving each logical processor its own VPID allows TLB invalidations to target a single processor's
+/// cached translations instead of invalidating every context.
+///
+/// # Returns
+///
+/// * `Result<u16, HypervisorError>` - The allocated VPID, or `HypervisorError::VpidExhausted` if no VPID is left.
+pub fn allocate_vpid() -> Result<u16, HypervisorError> {
+    let vpid = NEXT_VPID.fetch_add(1, Ordering::Relaxed);
+
+    // The counter has wrapped around to the reserved VPID 0.
+    if vpid == 0 {
+        return Err(HypervisorError::VpidExhausted);
+    }
+
+    Ok(vpid)
+}
 
 /// Represents the types of INVVPID operations.
 #[repr(u64)]
@@ -19,13 +47,13 @@ pub enum InvvpidType {
     /// This type invalidates all mappings—except global translations—associated with the specified VPID.
     SingleContext = 1,
 
-    /// Invalidate mappings—including global translations—associated with all VPIDs.
+    /// Invalidate mappings—including global translations—associated with all VPIDs except VPID 0.
     /// This type invalidates all mappings for all VPIDs.
-    AllContextsIncludingGlobals = 2,
+    AllContexts = 2,
 
-    /// Invalidate mappings associated with all VPIDs except global translations.
-    /// This type invalidates all mappings except for global translations for all VPIDs.
-    AllContexts = 3,
+    /// Invalidate mappings associated with a specific VPID, except global translations.
+    /// This type invalidates all mappings—except global translations—associated with the specified VPID.
+    SingleContextRetainingGlobals = 3,
 }
 
 /// Represents an INVVPID descriptor.
@@ -72,6 +100,28 @@ pub fn invvpid_individual_address(vpid: u16, linear_address: u64) {
     invvpid(InvvpidType::IndividualAddress, &descriptor);
 }
 
+/// Invalidates TLB and paging-structure cache entries associated with a range of linear addresses and a VPID.
+///
+/// This performs an individual-address INVVPID for every 4KB page in the range, which is considerably cheaper
+/// than invalidating every context when only a few pages are affected. If the processor does not support the
+/// individual-address type, the whole VPID is invalidated instead.
+///
+/// # Arguments
+/// * `vpid` - Virtual Processor Identifier.
+/// * `linear_address_range` - The range of linear addresses whose mappings are to be invalidated.
+pub fn invvpid_address_range(vpid: u16, linear_address_range: core::ops::Range<u64>) {
+    if !SHARED_HOST_CONFIG.read().is_invvpid_individual_address_supported() {
+        invvpid_single_context(vpid);
+        return;
+    }
+
+    let start = linear_address_range.start & !(BASE_PAGE_SIZE as u64 - 1);
+
+    for linear_address in (start..linear_address_range.end).step_by(BASE_PAGE_SIZE) {
+        invvpid_individual_address(vpid, linear_address);
+    }
+}
+
 /// Invalidates TLB and paging-structure cache entries associated with a specific VPID.
 ///
 /// # Arguments
@@ -86,6 +136,20 @@ pub fn invvpid_single_context(vpid: u16) {
     invvpid(InvvpidType::SingleContext, &descriptor);
 }
 
+/// Invalidates TLB and paging-structure cache entries associated with a specific VPID, except global translations.
+///
+/// # Arguments
+/// * `vpid` - Virtual Processor Identifier.
+pub fn invvpid_single_context_retaining_globals(vpid: u16) {
+    let descriptor = InvvpidDescriptor {
+        vpid,              // VPID of the target context
+        reserved: [0; 3],  // Reserved fields, must be zero
+        linear_address: 0, // Irrelevant for SingleContextRetainingGlobals
+    };
+    // Perform the INVVPID operation for a single context, retaining global translations.
+    invvpid(InvvpidType::SingleContextRetainingGlobals, &descriptor);
+}
+
 /// Invalidates TLB and paging-structure cache entries for all VPIDs.
 ///
 /// This operation ignores the descriptor fields as they are irrelevant for the AllContexts type.
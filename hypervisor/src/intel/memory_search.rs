@@ -0,0 +1,521 @@
+//! Provides bounded string searches over guest memory regions, so agents don't have to stream large ranges to
+//! look for strings.
+//!
+//! A search matches a literal string or a regex-lite pattern, encoded as ASCII or UTF-16LE, at every byte offset
+//! of a range of a process. The pattern syntax is:
+//! - literal characters, and `\` followed by a special character to match it literally,
+//! - `.` matching any character, `\d` a digit, `\w` a word character, `\s` a white space and `\xHH` a character code,
+//! - `[...]` matching a set of characters or ranges, or their complement with `[^...]`,
+//! - the greedy quantifiers `?`, `*`, `+`, `{n}`, `{n,}` and `{n,m}` after any of the above.
+//!
+//! There are no alternations, groups or anchors, and only the character codes up to 0xFF can be matched, in UTF-16LE
+//! as code units with a zero high byte. A match is at most `MAX_SEARCH_MATCH_SIZE` bytes long, and the backtracking of
+//! each match attempt is bounded, so the pathological patterns fail to match rather than stall the VM exit.
+//!
+//! The pages that aren't mapped are skipped, and a match never spans one of them. A search scans at most
+//! `MAX_SEARCH_SCAN_SIZE` bytes and reports where it stopped, so the client pages through the range and the matches
+//! with further searches resuming at that address.
+
+use {
+    crate::{error::HypervisorError, intel::addresses::PhysicalAddress},
+    alloc::vec::Vec,
+    shared::{MemorySearchMatch, SearchEncoding, MAX_SEARCH_PATTERN_SIZE, MAX_SEARCH_SCAN_SIZE, SEARCH_MATCH_DATA_SIZE},
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The maximum size of a match in bytes, which is also the overlap between two scanned chunks.
+pub const MAX_SEARCH_MATCH_SIZE: usize = 0x200;
+
+/// The size in bytes of the chunks of the range scanned at a time.
+const SEARCH_CHUNK_SIZE: usize = 0x10000;
+
+/// The maximum number of characters examined by a match attempt, including the backtracking.
+const MAX_MATCH_STEPS: u32 = 0x1000;
+
+/// A set of character codes, one bit per code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct CharacterSet([u64; 4]);
+
+impl CharacterSet {
+    /// Returns the set of every character.
+    fn any() -> Self {
+        Self([u64::MAX; 4])
+    }
+
+    /// Returns the set of a single character.
+    fn single(character: u8) -> Self {
+        let mut set = Self::default();
+        set.insert(character);
+        set
+    }
+
+    /// Adds a character to the set.
+    fn insert(&mut self, character: u8) {
+        self.0[character as usize / 64] |= 1 << (character % 64);
+    }
+
+    /// Adds a range of characters to the set.
+    fn insert_range(&mut self, first: u8, last: u8) {
+        (first..=last).for_each(|character| self.insert(character));
+    }
+
+    /// Adds the characters of another set.
+    fn union(&mut self, other: &Self) {
+        self.0.iter_mut().zip(other.0).for_each(|(bits, other_bits)| *bits |= other_bits);
+    }
+
+    /// Returns the characters not in the set.
+    fn complement(&self) -> Self {
+        Self(self.0.map(|bits| !bits))
+    }
+
+    /// Adds the other case of the ASCII letters of the set.
+    fn fold_case(&mut self) {
+        for character in b'A'..=b'Z' {
+            if self.contains(character) || self.contains(character.to_ascii_lowercase()) {
+                self.insert(character);
+                self.insert(character.to_ascii_lowercase());
+            }
+        }
+    }
+
+    /// Returns `true` if a character is in the set.
+    fn contains(&self, character: u8) -> bool {
+        self.0[character as usize / 64] & (1 << (character % 64)) != 0
+    }
+}
+
+/// A set of characters repeated a bounded number of times.
+#[derive(Debug, Clone, Copy)]
+struct PatternElement {
+    /// The characters matched.
+    set: CharacterSet,
+
+    /// The minimum number of repetitions.
+    min: usize,
+
+    /// The maximum number of repetitions.
+    max: usize,
+}
+
+/// A compiled search pattern.
+#[derive(Debug, Clone)]
+pub struct SearchPattern {
+    /// The elements matched one after another.
+    elements: Vec<PatternElement>,
+
+    /// The encoding of the searched characters.
+    encoding: SearchEncoding,
+}
+
+impl SearchPattern {
+    /// Compiles a search pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The ASCII pattern, at most `MAX_SEARCH_PATTERN_SIZE` bytes long.
+    /// * `is_regex` - Whether the pattern uses the regex-lite syntax, or is a literal string.
+    /// * `case_insensitive` - Whether the ASCII letters match both cases.
+    /// * `encoding` - The encoding of the searched characters.
+    ///
+    /// # Returns
+    ///
+    /// The compiled pattern, or `Err(HypervisorError::InvalidSearchPattern)` if the pattern is empty, too long,
+    /// malformed, or matches empty strings.
+    pub fn new(pattern: &[u8], is_regex: bool, case_insensitive: bool, encoding: SearchEncoding) -> Result<Self, HypervisorError> {
+        if pattern.is_empty() || pattern.len() > MAX_SEARCH_PATTERN_SIZE {
+            return Err(HypervisorError::InvalidSearchPattern);
+        }
+
+        let elements = match is_regex {
+            true => parse_regex(pattern, case_insensitive)?,
+            false => pattern
+                .iter()
+                .map(|&character| {
+                    let mut set = CharacterSet::single(character);
+                    if case_insensitive {
+                        set.fold_case();
+                    }
+
+                    PatternElement { set, min: 1, max: 1 }
+                })
+                .collect(),
+        };
+
+        if elements.iter().all(|element| element.min == 0) {
+            return Err(HypervisorError::InvalidSearchPattern);
+        }
+
+        Ok(Self { elements, encoding })
+    }
+
+    /// Returns the size in bytes of a character in the searched encoding.
+    fn character_size(&self) -> usize {
+        match self.encoding {
+            SearchEncoding::Ascii => 1,
+            SearchEncoding::Utf16 => 2,
+        }
+    }
+
+    /// Returns the character at an offset of the data, if there is a complete one.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The searched data.
+    /// * `offset` - The offset of the character.
+    fn character_at(&self, data: &[u8], offset: usize) -> Option<u8> {
+        match self.encoding {
+            SearchEncoding::Ascii => data.get(offset).copied(),
+            SearchEncoding::Utf16 => match data.get(offset..offset + 2)? {
+                [character, 0] => Some(*character),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns the size in bytes of the match starting at the beginning of the data, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The searched data, truncated to `MAX_SEARCH_MATCH_SIZE` bytes.
+    pub fn match_at(&self, data: &[u8]) -> Option<usize> {
+        let mut steps = 0;
+        self.match_elements(data, 0, 0, &mut steps)
+    }
+
+    /// Matches the elements from an index at an offset of the data, backtracking over the repetitions.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The searched data.
+    /// * `index` - The index of the first element to match.
+    /// * `offset` - The offset of the data where it must match.
+    /// * `steps` - The number of characters examined so far by the match attempt.
+    ///
+    /// # Returns
+    ///
+    /// The offset of the end of the match, or `None` if the elements don't match.
+    fn match_elements(&self, data: &[u8], index: usize, offset: usize, steps: &mut u32) -> Option<usize> {
+        let Some(element) = self.elements.get(index) else {
+            return Some(offset);
+        };
+
+        let character_size = self.character_size();
+        let mut count = 0;
+
+        while count < element.max {
+            *steps += 1;
+            match self.character_at(data, offset + count * character_size) {
+                Some(character) if element.set.contains(character) => count += 1,
+                _ => break,
+            }
+        }
+
+        loop {
+            if count < element.min {
+                return None;
+            }
+
+            *steps += 1;
+            if *steps > MAX_MATCH_STEPS {
+                return None;
+            }
+
+            if let Some(end) = self.match_elements(data, index + 1, offset + count * character_size, steps) {
+                return Some(end);
+            }
+
+            count = count.checked_sub(1)?;
+        }
+    }
+}
+
+/// Parses a regex-lite pattern into its elements.
+///
+/// # Arguments
+///
+/// * `pattern` - The ASCII pattern.
+/// * `case_insensitive` - Whether the ASCII letters match both cases.
+///
+/// # Returns
+///
+/// The elements of the pattern, or `Err(HypervisorError::InvalidSearchPattern)` if it is malformed.
+fn parse_regex(pattern: &[u8], case_insensitive: bool) -> Result<Vec<PatternElement>, HypervisorError> {
+    let mut elements: Vec<PatternElement> = Vec::new();
+    let mut position = 0;
+
+    while position < pattern.len() {
+        let (min, max) = match pattern[position] {
+            b'?' => (0, 1),
+            b'*' => (0, MAX_SEARCH_MATCH_SIZE),
+            b'+' => (1, MAX_SEARCH_MATCH_SIZE),
+            b'{' => {
+                let end = pattern[position..]
+                    .iter()
+                    .position(|&c| c == b'}')
+                    .ok_or(HypervisorError::InvalidSearchPattern)?
+                    + position;
+                let bounds = core::str::from_utf8(&pattern[position + 1..end]).map_err(|_| HypervisorError::InvalidSearchPattern)?;
+                let parse = |bound: &str| bound.parse::<usize>().map_err(|_| HypervisorError::InvalidSearchPattern);
+
+                let (min, max) = match bounds.split_once(',') {
+                    None => (parse(bounds)?, parse(bounds)?),
+                    Some((min, "")) => (parse(min)?, MAX_SEARCH_MATCH_SIZE),
+                    Some((min, max)) => (parse(min)?, parse(max)?),
+                };
+
+                position = end;
+                (min, max)
+            }
+            _ => {
+                let (set, next) = parse_set(pattern, position, case_insensitive)?;
+                elements.push(PatternElement { set, min: 1, max: 1 });
+                position = next;
+                continue;
+            }
+        };
+
+        // A quantifier applies to the previous element, which must not be quantified already.
+        let element = elements
+            .last_mut()
+            .filter(|element| element.min == 1 && element.max == 1)
+            .ok_or(HypervisorError::InvalidSearchPattern)?;
+
+        if min > max || min > MAX_SEARCH_MATCH_SIZE {
+            return Err(HypervisorError::InvalidSearchPattern);
+        }
+
+        element.min = min;
+        element.max = max.min(MAX_SEARCH_MATCH_SIZE);
+        position += 1;
+    }
+
+    Ok(elements)
+}
+
+/// Parses the set of characters matched by the element at a position of a pattern.
+///
+/// # Arguments
+///
+/// * `pattern` - The ASCII pattern.
+/// * `position` - The position of the element.
+/// * `case_insensitive` - Whether the ASCII letters match both cases, which a complemented set excludes both of.
+///
+/// # Returns
+///
+/// The set and the position following the element, or `Err(HypervisorError::InvalidSearchPattern)` if it is
+/// malformed.
+fn parse_set(pattern: &[u8], position: usize, case_insensitive: bool) -> Result<(CharacterSet, usize), HypervisorError> {
+    let (mut set, next, negated) = match pattern[position] {
+        b'.' => (CharacterSet::any(), position + 1, false),
+        b'\\' => parse_escape(pattern, position).map(|(set, next)| (set, next, false))?,
+        b'[' => {
+            let negated = pattern.get(position + 1) == Some(&b'^');
+            let mut position = position + 1 + negated as usize;
+            let mut set = CharacterSet::default();
+
+            loop {
+                match pattern.get(position) {
+                    None => return Err(HypervisorError::InvalidSearchPattern),
+                    Some(b']') => break,
+                    _ => {}
+                }
+
+                let (first, next) = parse_class_character(pattern, position)?;
+
+                match (pattern.get(next), pattern.get(next + 1)) {
+                    (Some(b'-'), Some(&last)) if last != b']' => {
+                        let (last, next) = parse_class_character(pattern, next + 1)?;
+                        let (Some(first), Some(last)) = (single_character(&first), single_character(&last)) else {
+                            return Err(HypervisorError::InvalidSearchPattern);
+                        };
+
+                        if first > last {
+                            return Err(HypervisorError::InvalidSearchPattern);
+                        }
+
+                        set.insert_range(first, last);
+                        position = next;
+                    }
+                    _ => {
+                        set.union(&first);
+                        position = next;
+                    }
+                }
+            }
+
+            (set, position + 1, negated)
+        }
+        b'?' | b'*' | b'+' | b'{' | b'}' | b']' => return Err(HypervisorError::InvalidSearchPattern),
+        character => (CharacterSet::single(character), position + 1, false),
+    };
+
+    if case_insensitive {
+        set.fold_case();
+    }
+
+    Ok((if negated { set.complement() } else { set }, next))
+}
+
+/// Parses a character of a `[...]` set, which may be an escape.
+///
+/// # Arguments
+///
+/// * `pattern` - The ASCII pattern.
+/// * `position` - The position of the character.
+fn parse_class_character(pattern: &[u8], position: usize) -> Result<(CharacterSet, usize), HypervisorError> {
+    match pattern[position] {
+        b'\\' => parse_escape(pattern, position),
+        character => Ok((CharacterSet::single(character), position + 1)),
+    }
+}
+
+/// Parses an escape starting with `\` at a position of a pattern.
+///
+/// # Arguments
+///
+/// * `pattern` - The ASCII pattern.
+/// * `position` - The position of the `\`.
+fn parse_escape(pattern: &[u8], position: usize) -> Result<(CharacterSet, usize), HypervisorError> {
+    let mut set = CharacterSet::default();
+
+    match *pattern.get(position + 1).ok_or(HypervisorError::InvalidSearchPattern)? {
+        b'd' => set.insert_range(b'0', b'9'),
+        b'w' => {
+            set.insert_range(b'0', b'9');
+            set.insert_range(b'A', b'Z');
+            set.insert_range(b'a', b'z');
+            set.insert(b'_');
+        }
+        b's' => b" \t\r\n\x0b\x0c".iter().for_each(|&character| set.insert(character)),
+        b'x' => {
+            let digits = pattern.get(position + 2..position + 4).ok_or(HypervisorError::InvalidSearchPattern)?;
+            let digits = core::str::from_utf8(digits).map_err(|_| HypervisorError::InvalidSearchPattern)?;
+            let character = u8::from_str_radix(digits, 16).map_err(|_| HypervisorError::InvalidSearchPattern)?;
+
+            return Ok((CharacterSet::single(character), position + 4));
+        }
+        character => set.insert(character),
+    }
+
+    Ok((set, position + 2))
+}
+
+/// Returns the only character of a set, if it has exactly one.
+fn single_character(set: &CharacterSet) -> Option<u8> {
+    let mut characters = (0..=u8::MAX).filter(|&character| set.contains(character));
+    let character = characters.next()?;
+    characters.next().is_none().then_some(character)
+}
+
+/// The outcome of a search.
+#[derive(Debug)]
+pub struct SearchResult {
+    /// The matches, in address order.
+    pub matches: Vec<MemorySearchMatch>,
+
+    /// The address where a further search resumes, or the end of the range if the search is complete.
+    pub next_address: u64,
+
+    /// The number of bytes scanned, including the pages skipped as not mapped.
+    pub scanned_bytes: u64,
+}
+
+/// Searches a range of a guest address space for a pattern.
+///
+/// The search stops at the end of the range, after `MAX_SEARCH_SCAN_SIZE` bytes, or once `max_matches` matches are
+/// found, and a further search resumes at `next_address` without reporting the same matches again.
+///
+/// # Arguments
+///
+/// * `pattern` - The compiled pattern.
+/// * `start_address` - The virtual address where the search starts.
+/// * `end_address` - The virtual address where the range ends, exclusive.
+/// * `directory_table_base` - The directory table base translating the range.
+/// * `max_matches` - The maximum number of matches returned.
+pub fn search_guest_memory(
+    pattern: &SearchPattern,
+    start_address: u64,
+    end_address: u64,
+    directory_table_base: u64,
+    max_matches: usize,
+) -> SearchResult {
+    let scan_end = end_address.min(start_address.saturating_add(MAX_SEARCH_SCAN_SIZE));
+    let mut matches = Vec::new();
+    let mut address = start_address;
+
+    while address < scan_end && matches.len() < max_matches {
+        let chunk_end = scan_end.min(address.saturating_add(SEARCH_CHUNK_SIZE as u64));
+        let window_end = end_address.min(chunk_end.saturating_add(MAX_SEARCH_MATCH_SIZE as u64));
+        let window = read_mapped_bytes(address, (window_end - address) as usize, directory_table_base);
+
+        if window.is_empty() {
+            // The page isn't mapped, so the search continues at the next one.
+            address = scan_end.min((address | (BASE_PAGE_SIZE as u64 - 1)).saturating_add(1));
+            continue;
+        }
+
+        // The matches starting in the overlap with the next chunk are found when scanning that chunk, unless the
+        // window ends at a page that isn't mapped.
+        let scanned_size = window.len().min((chunk_end - address) as usize);
+        let mut offset = 0;
+
+        while offset < scanned_size && matches.len() < max_matches {
+            let candidate = &window[offset..window.len().min(offset + MAX_SEARCH_MATCH_SIZE)];
+
+            match pattern.match_at(candidate) {
+                Some(size) if size != 0 => {
+                    let mut data = [0; SEARCH_MATCH_DATA_SIZE];
+                    let data_size = size.min(SEARCH_MATCH_DATA_SIZE);
+                    data[..data_size].copy_from_slice(&candidate[..data_size]);
+
+                    matches.push(MemorySearchMatch {
+                        address: address + offset as u64,
+                        size: size as u64,
+                        data,
+                    });
+                    offset += size;
+                }
+                _ => offset += 1,
+            }
+        }
+
+        address += match matches.len() < max_matches {
+            true => offset.max(scanned_size),
+            false => offset,
+        } as u64;
+    }
+
+    SearchResult {
+        matches,
+        next_address: address.min(end_address),
+        scanned_bytes: address.min(end_address) - start_address,
+    }
+}
+
+/// Copies the mapped prefix of a range of guest memory, page by page.
+///
+/// # Arguments
+///
+/// * `va` - The guest virtual address of the range.
+/// * `length` - The size of the range in bytes.
+/// * `directory_table_base` - The directory table base translating the range.
+///
+/// # Returns
+///
+/// The content of the range up to its first page that isn't mapped.
+pub fn read_mapped_bytes(va: u64, length: usize, directory_table_base: u64) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(length);
+
+    while bytes.len() < length {
+        let page_va = va + bytes.len() as u64;
+        let chunk_length = (BASE_PAGE_SIZE - (page_va as usize & (BASE_PAGE_SIZE - 1))).min(length - bytes.len());
+
+        match PhysicalAddress::read_guest_virt_slice_with_explicit_cr3(page_va as *const u8, chunk_length, directory_table_base) {
+            Some(chunk) => bytes.extend_from_slice(chunk),
+            None => break,
+        }
+    }
+
+    bytes
+}
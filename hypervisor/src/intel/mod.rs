@@ -1,22 +1,63 @@
 pub mod addresses;
+pub mod benchmark;
 pub mod bitmap;
+pub mod broadcast;
 pub mod capture;
+pub mod code_snapshot;
 pub mod controls;
+pub mod debug_registers;
 pub mod descriptor;
+pub mod detection_corpus;
+pub mod determinism;
+pub mod devirtualize;
+pub mod device_hiding;
 pub mod ept;
+pub mod ept_view;
+pub mod event_ring;
+pub mod event_stream;
 pub mod events;
+pub mod exception_telemetry;
+pub mod execution_trace;
+pub mod exit_statistics;
+pub mod exit_storm;
 pub mod hooks;
+pub mod host_config;
+pub mod host_exception;
+pub mod host_stack;
+pub mod hypercall_auth;
 pub mod invept;
 pub mod invvpid;
+pub mod memory_search;
 pub mod mtrr;
+pub mod nmi;
+pub mod numa;
 pub mod page;
 pub mod paging;
+pub mod process_tracker;
+pub mod profiler;
+pub mod protected_memory;
+pub mod reset;
+pub mod rtc;
+pub mod scheduler;
 pub mod segmentation;
+pub mod seqlock;
+pub mod shared_page;
+pub mod signature_scan;
+pub mod single_step;
+pub mod sleep;
 pub mod state;
 pub mod support;
+pub mod timing;
+pub mod transfer;
+pub mod tsc_compensation;
+pub mod unpacker;
 pub mod vm;
 pub mod vmcs;
+pub mod vmcs_field;
 pub mod vmerror;
 pub mod vmexit;
 pub mod vmlaunch;
 pub mod vmxon;
+pub mod vtd;
+pub mod watchdog;
+pub mod xsave_policy;
@@ -0,0 +1,221 @@
+//! Provides the NMIs sent by the hypervisor to the other logical processors, e.g., to make them execute a broadcast
+//! command without waiting for their next VM exit, and tells them apart from the NMIs of the guest.
+//!
+//! An NMI received while the guest runs causes a VM exit with the NMI exiting control, and an NMI received in VMX root
+//! operation is counted by the NMI stub of the host IDT (see the `host_exception` module). Either way, the NMI is
+//! dispatched by `dispatch_nmis` before the next VM entry. Before sending an NMI, the hypervisor raises the request flag
+//! of the target, which the first NMI dispatched claims: the NMIs without a request belong to the guest, and are queued
+//! for it, injected once the guest doesn't block NMIs through the NMI-window exiting (see the `events` module). NMIs carry
+//! no information, so a guest NMI received between the request and the NMI of the hypervisor is claimed in its place
+//! without changing the count received by the guest. An NMI isn't sent while one is requested, the NMI dispatched
+//! claiming the request executing the commands published before it as well.
+//!
+//! The processor collapses an NMI received while another one is pending, so a guest NMI collapsed with an NMI of the
+//! hypervisor is lost: injecting one the guest never received instead would make Windows bugcheck. A logical processor
+//! accepts the NMIs of the hypervisor from when it joins the broadcast commands, and refuses them before leaving VMX
+//! operation, waiting for the NMIs in flight, as an NMI received natively would be delivered to the guest.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 11.6.1 Interrupt Command Register (ICR),
+//! 26.2 Other Causes of VM Exits and 26.7.6 NMI-Window Exiting
+
+use {
+    crate::intel::{
+        events::EventInjection,
+        support::{rdmsr, rdtsc, wrmsr},
+        timing::tsc_frequency_hz,
+        vm::Vm,
+    },
+    core::{
+        hint::spin_loop,
+        sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    log::*,
+    x86::msr,
+};
+
+/// The number of initial APIC IDs, which are 8-bit.
+const MAX_PROCESSORS: usize = 0x100;
+
+/// The bit of IA32_APIC_BASE enabling the x2APIC mode.
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+
+/// The bits of IA32_APIC_BASE holding the physical address of the xAPIC registers.
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The offset of the low 32 bits of the ICR in the xAPIC registers.
+const XAPIC_ICR_LOW_OFFSET: u64 = 0x300;
+
+/// The offset of the high 32 bits of the ICR in the xAPIC registers, holding the destination.
+const XAPIC_ICR_HIGH_OFFSET: u64 = 0x310;
+
+/// The shift of the destination in the high 32 bits of the ICR in xAPIC mode.
+const XAPIC_DESTINATION_SHIFT: u32 = 24;
+
+/// The ICR value of an NMI to a single processor with a physical destination: NMI delivery mode, level assert.
+const ICR_NMI: u32 = (0b100 << 8) | (1 << 14);
+
+/// The bit of the ICR set while the IPI hasn't been accepted by its target, in xAPIC mode.
+const ICR_DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+
+/// The time in microseconds a logical processor refusing the NMIs of the hypervisor waits for the ones sent before.
+const DRAIN_TIMEOUT_MICROSECONDS: u64 = 10;
+
+/// Whether each logical processor accepts the NMIs of the hypervisor, by initial APIC ID.
+static ACCEPTING: [AtomicBool; MAX_PROCESSORS] = [const { AtomicBool::new(false) }; MAX_PROCESSORS];
+
+/// Whether an NMI of the hypervisor was sent to each logical processor without being dispatched yet, by initial APIC ID.
+static REQUESTS: [AtomicBool; MAX_PROCESSORS] = [const { AtomicBool::new(false) }; MAX_PROCESSORS];
+
+/// The number of NMIs being sent to each logical processor, by initial APIC ID.
+static IN_FLIGHT: [AtomicU32; MAX_PROCESSORS] = [const { AtomicU32::new(0) }; MAX_PROCESSORS];
+
+/// Accepts the NMIs of the hypervisor on the current logical processor, once it's in VMX operation.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn accept_host_nmis(vm: &Vm) {
+    let processor_index = vm.cpuid_feature_info.initial_local_apic_id() as usize;
+
+    REQUESTS[processor_index].store(false, Ordering::SeqCst);
+    ACCEPTING[processor_index].store(true, Ordering::SeqCst);
+}
+
+/// Refuses the NMIs of the hypervisor on the current logical processor, then waits for the ones already sent to it to
+/// be received, so it can leave VMX operation.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn refuse_host_nmis(vm: &Vm) {
+    let processor_index = vm.cpuid_feature_info.initial_local_apic_id() as usize;
+
+    if !ACCEPTING[processor_index].swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    while IN_FLIGHT[processor_index].load(Ordering::SeqCst) != 0 {
+        spin_loop();
+    }
+
+    // An NMI accepted by the local APIC is received as soon as the processor doesn't block NMIs, which it doesn't in
+    // VMX root operation, but the x2APIC doesn't report when an IPI was accepted.
+    let deadline_tsc = rdtsc().wrapping_add(tsc_frequency_hz() / 1_000_000 * DRAIN_TIMEOUT_MICROSECONDS);
+    while REQUESTS[processor_index].load(Ordering::SeqCst) && !vm.host_exceptions.has_pending_nmis() && rdtsc() < deadline_tsc {
+        spin_loop();
+    }
+}
+
+/// Refuses the NMIs of the hypervisor on all the logical processors, on the resume from S3, as only the logical
+/// processor that entered the sleep state is virtualized again.
+pub fn reset_host_nmis() {
+    for (accepting, request) in ACCEPTING.iter().zip(REQUESTS.iter()) {
+        accepting.store(false, Ordering::SeqCst);
+        request.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Sends an NMI of the hypervisor to a logical processor, if it accepts them and none is requested yet, so it causes a
+/// VM exit, or interrupts the one it's handling, and executes the broadcast commands before its next VM entry.
+///
+/// # Arguments
+///
+/// * `processor_id` - The initial APIC ID of the target logical processor.
+///
+/// # Returns
+///
+/// `true` if an NMI is sent or already requested, `false` if the logical processor doesn't accept the NMIs.
+pub fn send_host_nmi(processor_id: u32) -> bool {
+    let processor_index = processor_id as usize;
+    if processor_index >= MAX_PROCESSORS {
+        return false;
+    }
+
+    // Paired with `refuse_host_nmis`: either the target refuses the NMI, or it waits for it to be sent.
+    IN_FLIGHT[processor_index].fetch_add(1, Ordering::SeqCst);
+
+    let is_accepted = ACCEPTING[processor_index].load(Ordering::SeqCst);
+    if is_accepted && !REQUESTS[processor_index].swap(true, Ordering::SeqCst) {
+        trace!("Sending an NMI to the logical processor {}", processor_id);
+        send_nmi_ipi(processor_id);
+    }
+
+    IN_FLIGHT[processor_index].fetch_sub(1, Ordering::SeqCst);
+
+    is_accepted
+}
+
+/// Sends an NMI of the hypervisor to all the logical processors accepting them except the current one.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// The number of logical processors the NMI is sent to.
+pub fn send_host_nmis_to_others(vm: &Vm) -> u32 {
+    let current_id = vm.cpuid_feature_info.initial_local_apic_id() as u32;
+
+    (0..MAX_PROCESSORS as u32)
+        .filter(|&processor_id| processor_id != current_id && ACCEPTING[processor_id as usize].load(Ordering::Acquire))
+        .filter(|&processor_id| send_host_nmi(processor_id))
+        .count() as u32
+}
+
+/// Dispatches the NMIs received by the current logical processor: the first one claims the NMI requested by the
+/// hypervisor, if any, and the others are queued for the guest.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `count` - The number of NMIs received.
+pub fn dispatch_nmis(vm: &mut Vm, count: u64) {
+    if count == 0 {
+        return;
+    }
+
+    let processor_index = vm.cpuid_feature_info.initial_local_apic_id() as usize;
+    let host_count = u64::from(REQUESTS[processor_index].swap(false, Ordering::SeqCst));
+
+    if host_count != 0 {
+        trace!("NMI of the hypervisor received");
+    }
+
+    // NMIs aren't queued, so the ones received together are delivered once, as they would be natively.
+    if count > host_count {
+        EventInjection::queue_nmi(vm);
+    }
+}
+
+/// Sends an NMI to a logical processor through the local APIC.
+///
+/// The destination of an IPI the guest is preparing in xAPIC mode is restored, the guest writing the high 32 bits of
+/// the ICR before the low ones.
+///
+/// # Arguments
+///
+/// * `processor_id` - The APIC ID of the target logical processor.
+fn send_nmi_ipi(processor_id: u32) {
+    let apic_base = rdmsr(msr::IA32_APIC_BASE);
+
+    if apic_base & APIC_BASE_X2APIC_ENABLE != 0 {
+        wrmsr(msr::IA32_X2APIC_ICR, (u64::from(processor_id) << 32) | u64::from(ICR_NMI));
+        return;
+    }
+
+    let icr_low = ((apic_base & APIC_BASE_ADDRESS_MASK) + XAPIC_ICR_LOW_OFFSET) as *mut u32;
+    let icr_high = ((apic_base & APIC_BASE_ADDRESS_MASK) + XAPIC_ICR_HIGH_OFFSET) as *mut u32;
+
+    unsafe {
+        let guest_icr_high = icr_high.read_volatile();
+        icr_high.write_volatile(processor_id << XAPIC_DESTINATION_SHIFT);
+        icr_low.write_volatile(ICR_NMI);
+
+        while icr_low.read_volatile() & ICR_DELIVERY_STATUS_PENDING != 0 {
+            spin_loop();
+        }
+
+        icr_high.write_volatile(guest_icr_high);
+    }
+}
@@ -0,0 +1,141 @@
+//! Provides the NUMA topology of the system from the System Resource Affinity Table (SRAT), so the memory used on the
+//! hot paths of a logical processor is placed in the proximity domain (node) of the processor.
+//!
+//! On a multi-socket system, the memory attached to another socket is reached through the interconnect, with a higher
+//! latency than the local memory. The loader gives the page pool pages in the memory of each node, from which the shadow
+//! pages and page tables of the hooks installed by a logical processor are drawn first (see the `page_pool` module), and
+//! allocates the host stack of each logical processor in its node. Without an SRAT, or with a single node, the memory
+//! isn't placed.
+//!
+//! Reference: Advanced Configuration and Power Interface (ACPI) Specification: 5.2.16 System Resource Affinity Table (SRAT)
+
+use {
+    crate::acpi::{MemoryAffinity, ProcessorAffinity, SHARED_ACPI_TABLES},
+    alloc::vec::Vec,
+    lazy_static::lazy_static,
+    log::*,
+    spin::RwLock,
+    x86::cpuid::cpuid,
+};
+
+/// The CPUID leaf of the extended topology enumeration, returning the x2APIC ID in EDX.
+const CPUID_EXTENDED_TOPOLOGY: u32 = 0xB;
+
+lazy_static! {
+    /// A globally shared instance of `NumaTopology`, protected by a read-write lock.
+    ///
+    /// The topology is read once at boot by `NumaTopology::initialize_shared_numa_topology` and is only read afterwards.
+    pub static ref SHARED_NUMA_TOPOLOGY: RwLock<NumaTopology> = RwLock::new(NumaTopology::default());
+}
+
+/// The proximity domains of the logical processors and of the physical memory.
+#[derive(Debug, Clone, Default)]
+pub struct NumaTopology {
+    /// The proximity domain of each enabled logical processor.
+    processors: Vec<ProcessorAffinity>,
+
+    /// The proximity domain of each enabled range of physical memory.
+    memory_ranges: Vec<MemoryAffinity>,
+}
+
+impl NumaTopology {
+    /// Reads the NUMA topology from the SRAT and stores it in `SHARED_NUMA_TOPOLOGY`.
+    ///
+    /// This must be called after the ACPI tables have been parsed and before the processors are virtualized.
+    pub fn initialize_shared_numa_topology() {
+        let acpi_tables = SHARED_ACPI_TABLES.read();
+        let topology = NumaTopology {
+            processors: acpi_tables.processor_affinities(),
+            memory_ranges: acpi_tables.memory_affinities(),
+        };
+
+        debug!(
+            "NUMA topology: {} nodes, {} processors, {} memory ranges",
+            topology.domains().len(),
+            topology.processors.len(),
+            topology.memory_ranges.len()
+        );
+
+        *SHARED_NUMA_TOPOLOGY.write() = topology;
+    }
+
+    /// Returns whether the physical memory is split between more than one node, the only case where it's placed.
+    pub fn is_multi_node(&self) -> bool {
+        self.memory_ranges
+            .first()
+            .is_some_and(|first| self.memory_ranges.iter().any(|range| range.proximity_domain != first.proximity_domain))
+    }
+
+    /// Returns the proximity domains with logical processors, in ascending order.
+    pub fn domains(&self) -> Vec<u32> {
+        let mut domains: Vec<u32> = self.processors.iter().map(|processor| processor.proximity_domain).collect();
+        domains.sort_unstable();
+        domains.dedup();
+        domains
+    }
+
+    /// Returns the APIC IDs of the logical processors of a node.
+    ///
+    /// # Arguments
+    ///
+    /// * `proximity_domain` - The proximity domain of the node.
+    pub fn processors(&self, proximity_domain: u32) -> Vec<u32> {
+        self.processors
+            .iter()
+            .filter(|processor| processor.proximity_domain == proximity_domain)
+            .map(|processor| processor.apic_id)
+            .collect()
+    }
+
+    /// Returns the ranges of physical memory of a node.
+    ///
+    /// # Arguments
+    ///
+    /// * `proximity_domain` - The proximity domain of the node.
+    pub fn memory_ranges(&self, proximity_domain: u32) -> Vec<MemoryAffinity> {
+        self.memory_ranges
+            .iter()
+            .filter(|range| range.proximity_domain == proximity_domain)
+            .copied()
+            .collect()
+    }
+
+    /// Returns the proximity domain of a logical processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `apic_id` - The APIC ID of the logical processor.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the proximity domain, `None` if the logical processor isn't described by the SRAT.
+    pub fn processor_domain(&self, apic_id: u32) -> Option<u32> {
+        self.processors
+            .iter()
+            .find(|processor| processor.apic_id == apic_id)
+            .map(|processor| processor.proximity_domain)
+    }
+}
+
+/// Returns the APIC ID of the current logical processor, its x2APIC ID if the extended topology enumeration is supported,
+/// as the SRAT describes the processors with APIC IDs above 254 by their x2APIC ID.
+pub fn current_apic_id() -> u32 {
+    match cpuid!(0).eax >= CPUID_EXTENDED_TOPOLOGY {
+        true => cpuid!(CPUID_EXTENDED_TOPOLOGY, 0).edx,
+        false => cpuid!(1).ebx >> 24,
+    }
+}
+
+/// Returns the proximity domain of the current logical processor.
+///
+/// # Returns
+///
+/// An `Option` containing the proximity domain, `None` if the memory isn't placed in nodes.
+pub fn current_domain() -> Option<u32> {
+    let topology = SHARED_NUMA_TOPOLOGY.read();
+
+    match topology.is_multi_node() {
+        true => topology.processor_domain(current_apic_id()),
+        false => None,
+    }
+}
@@ -9,10 +9,23 @@ use {
     crate::error::HypervisorError,
     bitfield::bitfield,
     core::ptr::addr_of,
-    log::error,
+    log::trace,
     x86::bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
 };
 
+/// The bits of CR3 holding the physical address of the top-level page table, without the PCID and the no-flush bit.
+pub const CR3_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The shift of the PML5 index in a virtual address with 5-level paging.
+const PML5_SHIFT: u64 = 48;
+
+/// The mask of the index of an entry in a paging structure.
+const PAGE_TABLE_INDEX_MASK: u64 = 0x1FF;
+
+/// The size of the identity map built by `PageTables::build_identity`, 512 GB, beyond which the guest paging structures
+/// can't be read.
+pub const IDENTITY_MAP_SIZE: u64 = 512 * 512 * LARGE_PAGE_SIZE as u64;
+
 /// Represents the entire Page Tables structure for the hypervisor.
 ///
 /// The Page Tables mechanism is crucial for virtual memory management in x86-64 architecture.
@@ -35,6 +48,8 @@ pub struct PageTables {
     pdpt: Pdpt,
     /// Array of Page Directory Table (PDT).
     pd: [Pd; 512],
+    /// Page Table (PT) splitting the large page containing the guard page of the host stack.
+    guard_pt: Pt,
 }
 
 impl PageTables {
@@ -43,6 +58,7 @@ impl PageTables {
         self.pml4 = Pml4(Table { entries: [Entry(0); 512] });
         self.pdpt = Pdpt(Table { entries: [Entry(0); 512] });
         self.pd = [Pd(Table { entries: [Entry(0); 512] }); 512];
+        self.guard_pt = Pt(Table { entries: [Entry(0); 512] });
     }
 
     /// Builds a basic identity map for the page tables.
@@ -86,13 +102,59 @@ impl PageTables {
         log::debug!("Identity map built successfully");
     }
 
+    /// Unmaps a guard page from the identity map, splitting the large page containing it into `guard_pt`.
+    ///
+    /// This must be called before the page tables are in use, as the TLB isn't flushed.
+    ///
+    /// # Arguments
+    /// * `va` - The address of the guard page, page-aligned.
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the guard page is unmapped.
+    /// * `Err(HypervisorError::PagingStructureOutOfRange)` - If the guard page is beyond the identity map.
+    /// * `Err(HypervisorError::GuardPageUnavailable)` - If another large page was already split for a guard page.
+    pub fn unmap_guard_page(&mut self, va: u64) -> Result<(), HypervisorError> {
+        if va >= IDENTITY_MAP_SIZE {
+            return Err(HypervisorError::PagingStructureOutOfRange);
+        }
+
+        let guard_pt_pfn = addr_of!(self.guard_pt) as u64 >> BASE_PAGE_SHIFT;
+        let pde = &mut self.pd[pdpt_index(VAddr::from(va))].0.entries[pd_index(VAddr::from(va))];
+
+        if pde.large() {
+            // Map the large page with 4KB pages, the guard page being unmapped below.
+            let large_page_pa = pde.pfn() << BASE_PAGE_SHIFT;
+            for (i, pte) in self.guard_pt.0.entries.iter_mut().enumerate() {
+                pte.set_present(true);
+                pte.set_writable(true);
+                pte.set_pfn((large_page_pa + (i * BASE_PAGE_SIZE) as u64) >> BASE_PAGE_SHIFT);
+            }
+
+            pde.set_large(false);
+            pde.set_pfn(guard_pt_pfn);
+        } else if pde.pfn() != guard_pt_pfn {
+            return Err(HypervisorError::GuardPageUnavailable);
+        }
+
+        self.guard_pt.0.entries[pt_index(VAddr::from(va))].set_present(false);
+
+        log::debug!("Guard page unmapped: {:#x}", va);
+
+        Ok(())
+    }
+
     /// Translates a guest virtual address to a guest physical address using the guest's CR3.
-    /// This function traverses the guest's page tables, assuming an identity-mapped
+    /// This function traverses the guest's page tables, 4-level or 5-level, assuming an identity-mapped
     /// host address space for simplicity.
     ///
+    /// The paging structures are read through the identity map of the host, so a translation referencing a paging
+    /// structure beyond the identity map fails instead of faulting, and a non-present entry, e.g., of a paged-out
+    /// page, fails with the error of its level without being logged as an error.
+    ///
     /// # Arguments
     /// * `guest_cr3` - The guest CR3 register value, which contains the base address of the guest's page table hierarchy.
     /// * `guest_va` - The guest virtual address to translate.
+    /// * `five_level` - Whether the guest uses 5-level paging (CR4.LA57), the PML5 table being the top level.
     ///
     /// # Safety
     /// This function is unsafe because it involves raw memory access based on potentially
@@ -105,11 +167,27 @@ impl PageTables {
     ///
     /// # Credits
     /// Credits to Jessie (jessiep_) for the help.
-    pub unsafe fn translate_guest_virtual_to_guest_physical(guest_cr3: u64, guest_va: u64) -> Result<u64, HypervisorError> {
+    pub unsafe fn translate_guest_virtual_to_guest_physical(guest_cr3: u64, guest_va: u64, five_level: bool) -> Result<u64, HypervisorError> {
         let guest_va = VAddr::from(guest_va);
 
-        // Cast guest CR3 to the PML4 table structure
-        let pml4_table = guest_cr3 as *const Pml4;
+        // The top-level table, without the PCID and the no-flush bit of CR3.
+        let top_level_table = guest_cr3 & CR3_ADDRESS_MASK;
+
+        // With 5-level paging, the PML5 entry references the PML4 table.
+        let pml4_table = if five_level {
+            let pml5_index = ((guest_va.as_u64() >> PML5_SHIFT) & PAGE_TABLE_INDEX_MASK) as usize;
+            let pml5_entry = &(*paging_structure::<Table>(top_level_table)?).entries[pml5_index];
+
+            // Check if the PML5 entry is present (readable).
+            if !pml5_entry.present() {
+                trace!("PML5 entry is not present: {:#x}", guest_va);
+                return Err(HypervisorError::InvalidPml5Entry);
+            }
+
+            paging_structure::<Pml4>(pml5_entry.pfn() << BASE_PAGE_SHIFT)?
+        } else {
+            paging_structure::<Pml4>(top_level_table)?
+        };
 
         // Calculate the PML4 index and access the corresponding entry.
         let pml4_index = pml4_index(guest_va);
@@ -117,12 +195,12 @@ impl PageTables {
 
         // Check if the PML4 entry is present (readable).
         if !pml4_entry.present() {
-            error!("PML4 entry is not present: {:#x}", guest_va);
+            trace!("PML4 entry is not present: {:#x}", guest_va);
             return Err(HypervisorError::InvalidPml4Entry);
         }
 
         // Cast the entry to the PDPT table structure.
-        let pdpt_table = (pml4_entry.pfn() << BASE_PAGE_SHIFT) as *const Pdpt;
+        let pdpt_table = paging_structure::<Pdpt>(pml4_entry.pfn() << BASE_PAGE_SHIFT)?;
 
         // Calculate the PDPT index and access the corresponding entry.
         let pdpt_index = pdpt_index(guest_va);
@@ -130,18 +208,20 @@ impl PageTables {
 
         // Check if the PDPT entry is present (readable).
         if !pdpt_entry.present() {
-            error!("PDPT entry is not present: {:#x}", guest_va);
+            trace!("PDPT entry is not present: {:#x}", guest_va);
             return Err(HypervisorError::InvalidPdptEntry);
         }
 
         // Check if the PDPT entry is a huge page (1 GB), if so, calculate the guest physical address.
+        // The PAT bit of a huge page entry is bit 12, the lowest bit of the PFN, so the frame is aligned down.
         if pdpt_entry.large() {
-            let guest_pa = (pdpt_entry.pfn() << BASE_PAGE_SHIFT) + (guest_va.as_u64() % HUGE_PAGE_SIZE as u64);
+            let frame = (pdpt_entry.pfn() << BASE_PAGE_SHIFT) & !(HUGE_PAGE_SIZE as u64 - 1);
+            let guest_pa = frame + (guest_va.as_u64() % HUGE_PAGE_SIZE as u64);
             return Ok(guest_pa);
         }
 
         // Cast the entry to the PD table structure.
-        let pd_table = (pdpt_entry.pfn() << BASE_PAGE_SHIFT) as *const Pd;
+        let pd_table = paging_structure::<Pd>(pdpt_entry.pfn() << BASE_PAGE_SHIFT)?;
 
         // Calculate the PD index and access the corresponding entry.
         let pd_index = pd_index(guest_va);
@@ -149,18 +229,20 @@ impl PageTables {
 
         // Check if the PD entry is present (readable).
         if !pd_entry.present() {
-            error!("PD entry is not present: {:#x}", guest_va);
+            trace!("PD entry is not present: {:#x}", guest_va);
             return Err(HypervisorError::InvalidPdEntry);
         }
 
         // Check if the PD entry is a large page (2 MB), if so, calculate the guest physical address.
+        // The PAT bit of a large page entry is bit 12 as well.
         if pd_entry.large() {
-            let guest_pa = (pd_entry.pfn() << BASE_PAGE_SHIFT) + (guest_va.as_u64() % LARGE_PAGE_SIZE as u64);
+            let frame = (pd_entry.pfn() << BASE_PAGE_SHIFT) & !(LARGE_PAGE_SIZE as u64 - 1);
+            let guest_pa = frame + (guest_va.as_u64() % LARGE_PAGE_SIZE as u64);
             return Ok(guest_pa);
         }
 
         // Cast the entry to the PT table structure.
-        let pt_table = (pd_entry.pfn() << BASE_PAGE_SHIFT) as *const Pt;
+        let pt_table = paging_structure::<Pt>(pd_entry.pfn() << BASE_PAGE_SHIFT)?;
 
         // Calculate the PT index and access the corresponding entry.
         let pt_index = pt_index(guest_va);
@@ -168,7 +250,7 @@ impl PageTables {
 
         // Check if the PT entry is present (readable).
         if !pt_entry.present() {
-            error!("PT entry is not present: {:#x}", guest_va);
+            trace!("PT entry is not present: {:#x}", guest_va);
             return Err(HypervisorError::InvalidPtEntry);
         }
 
@@ -279,3 +361,22 @@ bitfield! {
     large, set_large: 7;
     pfn, set_pfn: 51, 12;
 }
+
+/// Returns a guest paging structure read through the identity map of the host.
+///
+/// # Arguments
+///
+/// * `pa` - The guest physical address of the paging structure.
+///
+/// # Returns
+///
+/// * `Ok(*const T)` - The paging structure.
+/// * `Err(HypervisorError::PagingStructureOutOfRange)` - If the paging structure is beyond the identity map.
+fn paging_structure<T>(pa: u64) -> Result<*const T, HypervisorError> {
+    if pa >= IDENTITY_MAP_SIZE {
+        trace!("Paging structure beyond the identity map: {:#x}", pa);
+        return Err(HypervisorError::PagingStructureOutOfRange);
+    }
+
+    Ok(pa as *const T)
+}
@@ -0,0 +1,226 @@
+//! Provides the tracking of the process owning the current address space of each logical processor, the foundation
+//! of the per-process hooks and memory policies.
+//!
+//! While enabled, MOV to CR3 causes VM exits, and each new CR3 is mapped to the process using it as its kernel or user
+//! (KVA shadow) address space. The owners are looked up in the process list from the System process found through the
+//! kernel exports known to the hook manager, and cached per logical processor. A cached owner is checked against its
+//! `_EPROCESS` structure on each hit, so an address space reused by a new process is looked up again.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            paging::CR3_ADDRESS_MASK,
+            seqlock::{Generation, Published},
+            support::vmread,
+            vm::Vm,
+            vmexit::cr::update_cr3_load_exiting,
+        },
+        windows::eprocess::{AddressSpaceOwner, ProcessInformation},
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::vmx::vmcs,
+};
+
+/// The number of address space owners cached per logical processor.
+pub const PROCESS_CACHE_SIZE: usize = 16;
+
+/// The process ID of the System process.
+const SYSTEM_PROCESS_ID: u64 = 4;
+
+/// The configuration of the process tracker, disabled until it's configured.
+static PROCESS_TRACKER_CONFIG: Published<ProcessTrackerConfig> = Published::new(ProcessTrackerConfig {
+    enabled: false,
+    system_process: 0,
+    kernel_directory_table_base: 0,
+});
+
+lazy_static! {
+    /// A globally shared instance of `ProcessTracker`, protected by a mutex.
+    pub static ref SHARED_PROCESS_TRACKER: Mutex<ProcessTracker> = Mutex::new(ProcessTracker::new());
+}
+
+/// The configuration of the process tracker.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessTrackerConfig {
+    /// Whether the process of each address space is tracked.
+    pub enabled: bool,
+
+    /// The address of the `_EPROCESS` structure of the System process, the head of the process list.
+    system_process: u64,
+
+    /// The directory table base of the System process, which maps the kernel in every context.
+    kernel_directory_table_base: u64,
+}
+
+/// The process tracker, locked to serialize its configuration changes.
+#[derive(Debug)]
+pub struct ProcessTracker;
+
+impl ProcessTracker {
+    /// Creates a new disabled tracker.
+    fn new() -> Self {
+        Self
+    }
+
+    /// Enables or disables the tracking, which the logical processors pick up on their next VM exit.
+    ///
+    /// This must be called in the context of the guest kernel, as the System process is looked up through the current
+    /// address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether the process of each address space is tracked.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the tracker has been configured, or `Err(HypervisorError::ProcessNotFound)` if the System process
+    /// can't be found, e.g., before the kernel is initialized.
+    pub fn configure(&mut self, enabled: bool) -> Result<(), HypervisorError> {
+        let config = match enabled {
+            true => ProcessTrackerConfig {
+                enabled,
+                system_process: ProcessInformation::get_initial_system_process().ok_or(HypervisorError::ProcessNotFound)?,
+                kernel_directory_table_base: ProcessInformation::get_directory_table_base_by_process_id(SYSTEM_PROCESS_ID)
+                    .ok_or(HypervisorError::ProcessNotFound)?,
+            },
+            false => ProcessTrackerConfig::default(),
+        };
+
+        debug!("Process tracker configured: {:?}", config);
+
+        PROCESS_TRACKER_CONFIG.publish(config);
+
+        Ok(())
+    }
+}
+
+/// The process owning an address space, as tracked on a logical processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackedProcess {
+    /// The directory table base of the address space, without the PCID or the flags.
+    pub directory_table_base: u64,
+
+    /// The process using the address space.
+    pub owner: AddressSpaceOwner,
+}
+
+/// The process tracker state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessContext {
+    /// The generation of the configuration in use on this logical processor.
+    generation: Generation,
+
+    /// The configuration in use on this logical processor.
+    config: ProcessTrackerConfig,
+
+    /// The process owning the current address space, if known.
+    current: Option<TrackedProcess>,
+
+    /// The owners of the address spaces seen recently, replaced in round-robin order.
+    cache: [Option<TrackedProcess>; PROCESS_CACHE_SIZE],
+
+    /// The index of the next cache entry replaced.
+    next_cache_index: usize,
+}
+
+impl ProcessContext {
+    /// Creates a new disabled tracker state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if the process of each address space is tracked on this logical processor.
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Returns the process owning the current address space, or `None` if the tracking is disabled or the owner is
+    /// unknown, e.g., for the address spaces of processes being created or torn down.
+    pub fn current_process(&self) -> Option<TrackedProcess> {
+        self.current
+    }
+
+    /// Looks up the owner of an address space, from the cache if it is still valid or from the process list.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The guest CR3 of the address space.
+    fn lookup(&mut self, cr3: u64) -> Option<TrackedProcess> {
+        let directory_table_base = cr3 & CR3_ADDRESS_MASK;
+        let kernel_directory_table_base = self.config.kernel_directory_table_base;
+
+        let cache_index = self
+            .cache
+            .iter()
+            .position(|entry| entry.is_some_and(|entry| entry.directory_table_base == directory_table_base));
+
+        if let Some(cache_index) = cache_index {
+            let cached = self.cache[cache_index]?;
+
+            if ProcessInformation::get_address_space_owner(cached.owner.process, directory_table_base, kernel_directory_table_base)
+                .is_some_and(|owner| owner == cached.owner)
+            {
+                return Some(cached);
+            }
+
+            trace!("Cached owner of {:#x} is stale", directory_table_base);
+            self.cache[cache_index] = None;
+        }
+
+        let owner = ProcessInformation::find_address_space_owner(directory_table_base, self.config.system_process, kernel_directory_table_base)?;
+        let tracked = TrackedProcess { directory_table_base, owner };
+
+        trace!("Address space {:#x} owned by process {}", directory_table_base, owner.process_id);
+
+        let slot = match self.cache.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                let index = self.next_cache_index;
+                self.next_cache_index = (index + 1) % PROCESS_CACHE_SIZE;
+                index
+            }
+        };
+        self.cache[slot] = Some(tracked);
+
+        Some(tracked)
+    }
+}
+
+/// Records the process owning the new address space of the current logical processor, on a MOV to CR3.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `cr3` - The new guest CR3.
+pub fn track_address_space_switch(vm: &mut Vm, cr3: u64) {
+    if !vm.process_context.is_enabled() {
+        return;
+    }
+
+    vm.process_context.current = vm.process_context.lookup(cr3);
+}
+
+/// Picks up a new process tracker configuration on the current logical processor, enabling or disabling CR3-load
+/// exiting for it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_process_tracker(vm: &mut Vm) {
+    let mut generation = vm.process_context.generation;
+    let Some(config) = PROCESS_TRACKER_CONFIG.sync(&mut generation) else {
+        return;
+    };
+
+    vm.process_context = ProcessContext {
+        generation,
+        config,
+        ..ProcessContext::new()
+    };
+
+    update_cr3_load_exiting(vm);
+    track_address_space_switch(vm, vmread(vmcs::guest::CR3));
+}
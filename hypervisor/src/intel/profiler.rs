@@ -0,0 +1,220 @@
+//! Provides a sampling profiler of the whole system, independent of the guest operating system.
+//!
+//! While profiling, the VMX-preemption timer of each logical processor is armed to expire at the configured frequency
+//! of guest time. Each time it expires, the guest RIP, RSP and CR3 are recorded with the first slots of the guest stack
+//! into a shared profile buffer, which the client drains with the `ReadProfile` command. When the buffer is full, new
+//! samples are handled by its backpressure policy (see the `event_ring` module), dropping them by default.
+//!
+//! A logical processor starts or stops sampling at its first VM exit after the configuration is published.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            event_ring::EventRing,
+            seqlock::{Generation, Published},
+            support::{rdtsc, vmread},
+            vm::Vm,
+            vmexit::preemption_timer::{is_preemption_timer_supported, update_preemption_timer},
+        },
+    },
+    alloc::vec::Vec,
+    lazy_static::lazy_static,
+    log::*,
+    shared::{EventRingId, ProfileSample, MAX_PROFILE_STACK_DEPTH},
+    spin::Mutex,
+    x86::vmx::vmcs,
+};
+
+/// The maximum number of samples kept in the profile buffer until they are drained.
+pub const PROFILE_BUFFER_CAPACITY: usize = 0x2000;
+
+/// The configuration of the profiler, stopped until `start_profiling` is called.
+static PROFILER_CONFIG: Published<ProfilerConfig> = Published::new(ProfilerConfig {
+    interval_tsc_ticks: 0,
+    stack_depth: 0,
+});
+
+lazy_static! {
+    /// A globally shared instance of `Profile`, protected by a mutex.
+    pub static ref SHARED_PROFILE: Mutex<Profile> = Mutex::new(Profile::new());
+}
+
+/// The configuration of the profiler.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProfilerConfig {
+    /// The number of TSC ticks between two samples on a logical processor, 0 while the profiler is stopped.
+    pub interval_tsc_ticks: u64,
+
+    /// The number of stack slots recorded with each sample, at most `MAX_PROFILE_STACK_DEPTH`.
+    pub stack_depth: usize,
+}
+
+/// The samples recorded by all the logical processors.
+#[derive(Debug)]
+pub struct Profile {
+    /// The samples recorded and not drained yet, oldest first.
+    samples: EventRing<ProfileSample>,
+}
+
+impl Profile {
+    /// Creates a new stopped profile, without allocating the buffer.
+    fn new() -> Self {
+        Self {
+            samples: EventRing::new(EventRingId::Profiler, PROFILE_BUFFER_CAPACITY),
+        }
+    }
+
+    /// Removes the oldest samples from the buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_samples` - The maximum number of samples to remove.
+    ///
+    /// # Returns
+    ///
+    /// The samples removed, oldest first, and the number of samples dropped since the last drain.
+    pub fn drain(&mut self, max_samples: usize) -> (Vec<ProfileSample>, u64) {
+        self.samples.drain(max_samples)
+    }
+
+    /// Returns the buffer of the samples, to configure it or read its statistics.
+    pub fn event_ring(&mut self) -> &mut EventRing<ProfileSample> {
+        &mut self.samples
+    }
+}
+
+/// The profiler state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorProfiler {
+    /// The generation of the configuration in use on this logical processor.
+    generation: Generation,
+
+    /// The configuration in use on this logical processor.
+    config: ProfilerConfig,
+
+    /// The TSC at which the next sample is due.
+    next_sample_tsc: u64,
+}
+
+impl ProcessorProfiler {
+    /// Creates a new stopped profiler.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if the profiler is running and a sample is due.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The current TSC.
+    pub fn is_sample_due(&self, tsc: u64) -> bool {
+        self.config.interval_tsc_ticks != 0 && tsc >= self.next_sample_tsc
+    }
+
+    /// Returns the number of TSC ticks until the next sample is due, or `None` if the profiler is stopped.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The current TSC.
+    pub fn ticks_until_next_sample(&self, tsc: u64) -> Option<u64> {
+        match self.config.interval_tsc_ticks {
+            0 => None,
+            _ => Some(self.next_sample_tsc.saturating_sub(tsc).max(1)),
+        }
+    }
+}
+
+/// Starts profiling on all the logical processors, discarding the samples of a previous run.
+///
+/// # Arguments
+///
+/// * `config` - The configuration of the profiler.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the profiler has been started, otherwise `Err(HypervisorError)`.
+pub fn start_profiling(config: ProfilerConfig) -> Result<(), HypervisorError> {
+    if config.interval_tsc_ticks == 0 || config.stack_depth > MAX_PROFILE_STACK_DEPTH {
+        return Err(HypervisorError::InvalidProfilerConfig);
+    }
+
+    if !is_preemption_timer_supported() {
+        return Err(HypervisorError::PreemptionTimerUnsupported);
+    }
+
+    SHARED_PROFILE.lock().samples.reset();
+    PROFILER_CONFIG.publish(config);
+
+    debug!("Profiler started: {:?}", config);
+
+    Ok(())
+}
+
+/// Stops profiling on all the logical processors, keeping the samples recorded so far.
+pub fn stop_profiling() {
+    PROFILER_CONFIG.publish(ProfilerConfig::default());
+
+    debug!("Profiler stopped");
+}
+
+/// Picks up a new profiler configuration on the current logical processor and re-arms the VMX-preemption timer for it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_profiler(vm: &mut Vm) {
+    let mut generation = vm.profiler.generation;
+    let Some(config) = PROFILER_CONFIG.sync(&mut generation) else {
+        return;
+    };
+
+    vm.profiler = ProcessorProfiler {
+        generation,
+        config,
+        next_sample_tsc: rdtsc().wrapping_add(config.interval_tsc_ticks),
+    };
+
+    update_preemption_timer(vm);
+}
+
+/// Records a sample of the guest state of the current logical processor, and schedules the next sample.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sample_guest(vm: &mut Vm) {
+    let tsc = rdtsc();
+    let rsp = vm.guest_registers.rsp;
+
+    let mut sample = ProfileSample {
+        processor_id: vm.cpuid_feature_info.initial_local_apic_id() as u64,
+        tsc,
+        rip: vm.guest_registers.rip,
+        rsp,
+        cr3: vmread(vmcs::guest::CR3),
+        stack_depth: 0,
+        stack: [0; MAX_PROFILE_STACK_DEPTH],
+    };
+
+    // The stack is read one aligned slot at a time, so no slot crosses a page boundary, until a slot isn't mapped.
+    if rsp & 0x7 == 0 {
+        for (index, slot) in sample.stack.iter_mut().take(vm.profiler.config.stack_depth).enumerate() {
+            match PhysicalAddress::read_guest_virt_with_current_cr3((rsp + index as u64 * 8) as *const u64) {
+                Some(value) => *slot = value,
+                None => break,
+            }
+            sample.stack_depth += 1;
+        }
+    }
+
+    SHARED_PROFILE.lock().samples.push(sample);
+
+    // Skip the samples missed while the guest wasn't running on this processor, instead of taking them in a burst.
+    let interval_tsc_ticks = vm.profiler.config.interval_tsc_ticks;
+    vm.profiler.next_sample_tsc = vm.profiler.next_sample_tsc.wrapping_add(interval_tsc_ticks);
+    if vm.profiler.next_sample_tsc <= tsc {
+        vm.profiler.next_sample_tsc = tsc.wrapping_add(interval_tsc_ticks);
+    }
+}
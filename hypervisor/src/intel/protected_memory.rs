@@ -0,0 +1,555 @@
+//! Provides protected ranges of guest processes, whose memory the other processes can't read and/or write, e.g., to
+//! protect an in-guest agent or the credentials it holds from tampering.
+//!
+//! The guest pages backing a protected range are made execute-only in the EPT if the reads are denied, or read and
+//! execute if only the writes are. As the EPT only sees physical pages, the accessing context is identified on each
+//! EPT violation by the guest CR3 and CPL: the accesses of the owning process in user mode, and of the kernel in its
+//! address space if allowed, are executed for a single instruction with the page accessible, while the denied accesses
+//! are reported to the event stream as `ProtectedMemoryAccess` events and either get a page fault at the accessed
+//! address, or are redirected to a zeroed decoy page of the range for a single instruction, the protected memory being
+//! left unchanged. A guest page-fault handler finding the page mapped retries the access, so a denied access never
+//! completes with the page-fault action.
+//!
+//! As for the unpacker, the protected pages are the physical pages mapped when the range is protected, a shared page,
+//! e.g., of an image, being protected from every other process mapping it, and the pages of EPT hooks, of the unpacker
+//! and of the execution tracer aren't protected. The EPT is modified on the logical processor handling the commands,
+//! and a page being accessed for a single instruction on a logical processor is briefly accessible to the others, as
+//! for the hooked pages. The ranges of the processes that no longer exist are removed on the next command.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            ept::AccessType,
+            event_stream::stream_event,
+            events::EventInjection,
+            execution_trace::is_execution_trace_page,
+            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            paging::CR3_ADDRESS_MASK,
+            single_step::{single_step, SingleStepContext, SingleStepOwner},
+            support::{cr2_write, vmread},
+            unpacker::is_unpacker_page,
+            vm::Vm,
+            vmerror::EptViolationExitQualification,
+            vmexit::ExitType,
+        },
+        windows::eprocess::ProcessInformation,
+    },
+    alloc::{collections::BTreeMap, vec::Vec},
+    core::sync::atomic::{AtomicBool, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    shared::{EventStreamKind, ProtectedMemoryAction},
+    spin::Mutex,
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        vmx::vmcs,
+    },
+};
+
+/// The maximum number of protected ranges.
+pub const MAX_PROTECTED_REGIONS: usize = 0x10;
+
+/// The maximum number of protected pages of all the ranges, each requiring a page table once its large page is split.
+pub const MAX_PROTECTED_PAGES: usize = 0x1000;
+
+/// The bits of the error code of a page fault: the page is present, the access is a write, the access is made in
+/// user mode.
+const PAGE_FAULT_PRESENT: u32 = 1 << 0;
+const PAGE_FAULT_WRITE: u32 = 1 << 1;
+const PAGE_FAULT_USER: u32 = 1 << 2;
+
+/// Whether ranges are protected, checked without locking on each EPT violation.
+static PROTECTED_MEMORY_ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// A globally shared instance of `ProtectedMemory`, protected by a mutex.
+    pub static ref SHARED_PROTECTED_MEMORY: Mutex<ProtectedMemory> = Mutex::new(ProtectedMemory::new());
+}
+
+/// The accesses denied to the other processes, and what they get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionPolicy {
+    /// Whether the reads are denied, which also denies the writes.
+    pub deny_reads: bool,
+
+    /// Whether the writes are denied.
+    pub deny_writes: bool,
+
+    /// Whether the kernel is allowed to access the range in the address space of the owning process.
+    pub allow_kernel_access: bool,
+
+    /// What a denied access gets.
+    pub action: ProtectedMemoryAction,
+}
+
+/// A protected range of a process.
+#[derive(Debug, Clone, Copy)]
+struct ProtectedRegion {
+    /// The directory table base of the owning process.
+    directory_table_base: u64,
+
+    /// The user directory table base of the owning process with KVA shadowing, or 0.
+    user_directory_table_base: u64,
+
+    /// The virtual address following the range.
+    end_va: u64,
+
+    /// The accesses denied to the other processes.
+    policy: ProtectionPolicy,
+
+    /// The physical address of the decoy page, or 0 with the page-fault action.
+    decoy_page_pa: u64,
+}
+
+impl ProtectedRegion {
+    /// Returns the EPT permissions of the protected pages of the range.
+    fn access_type(&self) -> AccessType {
+        match self.policy.deny_reads {
+            true => AccessType::EXECUTE,
+            false => AccessType::READ_EXECUTE,
+        }
+    }
+
+    /// Returns `true` if an access is made by the owning process, or by the kernel in its address space if allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The guest CR3.
+    /// * `cpl` - The current privilege level of the guest.
+    fn is_owner_context(&self, cr3: u64, cpl: u8) -> bool {
+        let directory_table_base = cr3 & CR3_ADDRESS_MASK;
+        let is_owner_address_space = directory_table_base == self.directory_table_base & CR3_ADDRESS_MASK
+            || (self.user_directory_table_base != 0 && directory_table_base == self.user_directory_table_base & CR3_ADDRESS_MASK);
+
+        is_owner_address_space && (cpl == 3 || self.policy.allow_kernel_access)
+    }
+
+    /// Returns `true` if an access is denied to the other processes.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_qualification` - The exit qualification of the EPT violation.
+    fn denies(&self, exit_qualification: &EptViolationExitQualification) -> bool {
+        (exit_qualification.data_read && self.policy.deny_reads)
+            || (exit_qualification.data_write && (self.policy.deny_writes || self.policy.deny_reads))
+    }
+}
+
+/// A guest page of a protected range.
+#[derive(Debug, Clone, Copy)]
+struct ProtectedPage {
+    /// The process ID and the first virtual address of the range.
+    region: (u64, u64),
+
+    /// The virtual address of the page in the owning process.
+    guest_va: u64,
+}
+
+/// The protected ranges and their pages.
+#[derive(Debug)]
+pub struct ProtectedMemory {
+    /// The protected ranges, by process ID and first virtual address.
+    regions: BTreeMap<(u64, u64), ProtectedRegion>,
+
+    /// The protected pages, by guest physical address.
+    pages: BTreeMap<u64, ProtectedPage>,
+
+    /// The number of denied accesses since the first range was protected.
+    denied_accesses: u64,
+}
+
+impl ProtectedMemory {
+    /// Creates the protected memory, without ranges.
+    fn new() -> Self {
+        Self {
+            regions: BTreeMap::new(),
+            pages: BTreeMap::new(),
+            denied_accesses: 0,
+        }
+    }
+
+    /// Returns the number of protected ranges.
+    pub fn region_count(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Returns the number of protected pages.
+    pub fn protected_page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Returns the number of denied accesses since the first range was protected.
+    pub fn denied_access_count(&self) -> u64 {
+        self.denied_accesses
+    }
+
+    /// Removes a range, restoring the mapping and the permissions of its pages and releasing its decoy page.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    /// * `key` - The process ID and the first virtual address of the range.
+    fn remove_region(&mut self, vm: &mut Vm, key: (u64, u64)) -> Result<(), HypervisorError> {
+        let Some(region) = self.regions.remove(&key) else {
+            return Ok(());
+        };
+
+        let guest_page_pas: Vec<u64> = self
+            .pages
+            .iter()
+            .filter(|(_, page)| page.region == key)
+            .map(|(&guest_page_pa, _)| guest_page_pa)
+            .collect();
+
+        for guest_page_pa in guest_page_pas {
+            self.pages.remove(&guest_page_pa);
+            map_protected_page(vm, guest_page_pa, guest_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+        }
+
+        if region.decoy_page_pa != 0 {
+            SHARED_HOOK_MANAGER.lock().memory_manager.free_page(region.decoy_page_pa);
+        }
+
+        debug!("Removed protected range {:#x}-{:#x} of process {}", key.1, region.end_va, key.0);
+
+        Ok(())
+    }
+
+    /// Removes the ranges of the processes that no longer exist, as their physical pages may be reused.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    fn remove_exited_regions(&mut self, vm: &mut Vm) -> Result<(), HypervisorError> {
+        let exited_regions: Vec<(u64, u64)> = self
+            .regions
+            .iter()
+            .filter(|(&(process_id, _), region)| {
+                ProcessInformation::get_directory_table_base_by_process_id(process_id) != Some(region.directory_table_base)
+            })
+            .map(|(&key, _)| key)
+            .collect();
+
+        for key in exited_regions {
+            debug!("Owner of protected range {:#x} exited: {}", key.1, key.0);
+            self.remove_region(vm, key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the EPT violations of the protected pages routed to `handle_protected_memory_access` while ranges exist.
+    fn update_enabled(&self) {
+        PROTECTED_MEMORY_ENABLED.store(!self.regions.is_empty(), Ordering::Release);
+    }
+}
+
+/// Protects a range of a process from the accesses of the other processes, replacing the range of the process at the
+/// same address, if any.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `process_id` - The ID of the owning process.
+/// * `base_va` - The first virtual address of the range in the owning process.
+/// * `size` - The size of the range in bytes.
+/// * `policy` - The accesses denied to the other processes, and what they get.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of protected pages of the range, the pages not mapped being skipped.
+/// * `Err(HypervisorError::ProcessNotFound)` - If the process doesn't exist.
+/// * `Err(HypervisorError::InvalidProtectedMemoryRange)` - If the range is empty, no access is denied, or the pages of
+///   all the ranges would exceed `MAX_PROTECTED_PAGES`.
+/// * `Err(HypervisorError::TooManyProtectedRegions)` - If `MAX_PROTECTED_REGIONS` ranges are already protected.
+/// * `Err(HypervisorError)` - If no decoy page is available or the EPT couldn't be modified.
+pub fn protect_memory(vm: &mut Vm, process_id: u64, base_va: u64, size: u64, policy: ProtectionPolicy) -> Result<usize, HypervisorError> {
+    let mut protected_memory = SHARED_PROTECTED_MEMORY.lock();
+
+    protected_memory.remove_exited_regions(vm)?;
+    protected_memory.remove_region(vm, (process_id, base_va))?;
+    protected_memory.update_enabled();
+
+    let end_va = base_va.checked_add(size).ok_or(HypervisorError::InvalidProtectedMemoryRange)?;
+    let base_page_va = base_va & !(BASE_PAGE_SIZE as u64 - 1);
+    let page_count = (end_va - base_page_va).div_ceil(BASE_PAGE_SIZE as u64) as usize;
+
+    if size == 0 || !(policy.deny_reads || policy.deny_writes) || protected_memory.pages.len() + page_count > MAX_PROTECTED_PAGES {
+        return Err(HypervisorError::InvalidProtectedMemoryRange);
+    }
+
+    if protected_memory.regions.len() >= MAX_PROTECTED_REGIONS {
+        return Err(HypervisorError::TooManyProtectedRegions);
+    }
+
+    let directory_table_base = ProcessInformation::get_directory_table_base_by_process_id(process_id).ok_or(HypervisorError::ProcessNotFound)?;
+
+    let decoy_page_pa = match policy.action {
+        ProtectedMemoryAction::PageFault => 0,
+        ProtectedMemoryAction::Decoy => {
+            let decoy_page_pa = SHARED_HOOK_MANAGER
+                .lock()
+                .memory_manager
+                .allocate_page()
+                .ok_or(HypervisorError::ShadowPagesUnavailable)?;
+
+            // The host physical memory is identity mapped.
+            unsafe { core::ptr::write_bytes(decoy_page_pa as *mut u8, 0, BASE_PAGE_SIZE) };
+            decoy_page_pa
+        }
+    };
+
+    let region = ProtectedRegion {
+        directory_table_base,
+        user_directory_table_base: ProcessInformation::get_user_directory_table_base_by_process_id(process_id).unwrap_or(0),
+        end_va,
+        policy,
+        decoy_page_pa,
+    };
+
+    protected_memory.regions.insert((process_id, base_va), region);
+    protected_memory.update_enabled();
+
+    for index in 0..page_count {
+        let guest_va = base_page_va + (index * BASE_PAGE_SIZE) as u64;
+
+        let Ok(guest_pa) = PhysicalAddress::pa_from_va_with_explicit_cr3(guest_va, directory_table_base) else {
+            continue;
+        };
+        let guest_page_pa = PAddr::from(guest_pa).align_down_to_base_page().as_u64();
+
+        // The pages of EPT hooks, of the unpacker and of the execution tracer are already switched between permissions
+        // of their own, and a page of another range keeps its protection.
+        if protected_memory.pages.contains_key(&guest_page_pa)
+            || SHARED_HOOK_MANAGER.lock().memory_manager.is_guest_page_processed(guest_page_pa)
+            || is_unpacker_page(guest_page_pa)
+            || is_execution_trace_page(guest_page_pa)
+        {
+            continue;
+        }
+
+        map_protected_page(vm, guest_page_pa, guest_page_pa, region.access_type())?;
+        protected_memory.pages.insert(
+            guest_page_pa,
+            ProtectedPage {
+                region: (process_id, base_va),
+                guest_va,
+            },
+        );
+    }
+
+    let protected_page_count = protected_memory
+        .pages
+        .values()
+        .filter(|page| page.region == (process_id, base_va))
+        .count();
+
+    debug!("Protected range {:#x}-{:#x} of process {} ({} of {} pages): {:?}", base_va, end_va, process_id, protected_page_count, page_count, policy);
+
+    vm.primary_ept.invalidate_ept_cache()?;
+
+    Ok(protected_page_count)
+}
+
+/// Removes a protected range of a process, or all of them.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `process_id` - The ID of the owning process.
+/// * `base_va` - The first virtual address of the range, or 0 to remove all the ranges of the process.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of ranges removed.
+/// * `Err(HypervisorError::ProtectedRegionNotFound)` - If the process has no range at this address.
+/// * `Err(HypervisorError)` - If the EPT couldn't be modified.
+pub fn unprotect_memory(vm: &mut Vm, process_id: u64, base_va: u64) -> Result<usize, HypervisorError> {
+    let mut protected_memory = SHARED_PROTECTED_MEMORY.lock();
+
+    protected_memory.remove_exited_regions(vm)?;
+
+    let keys: Vec<(u64, u64)> = protected_memory
+        .regions
+        .keys()
+        .filter(|&&(region_process_id, region_base_va)| region_process_id == process_id && (base_va == 0 || region_base_va == base_va))
+        .copied()
+        .collect();
+
+    if base_va != 0 && keys.is_empty() {
+        protected_memory.update_enabled();
+        return Err(HypervisorError::ProtectedRegionNotFound);
+    }
+
+    for &key in &keys {
+        protected_memory.remove_region(vm, key)?;
+    }
+
+    protected_memory.update_enabled();
+    vm.primary_ept.invalidate_ept_cache()?;
+
+    Ok(keys.len())
+}
+
+/// Returns `true` if a guest page is protected.
+///
+/// # Arguments
+///
+/// * `guest_page_pa` - The guest physical address of the page.
+pub fn is_protected_memory_page(guest_page_pa: u64) -> bool {
+    PROTECTED_MEMORY_ENABLED.load(Ordering::Acquire) && SHARED_PROTECTED_MEMORY.lock().pages.contains_key(&guest_page_pa)
+}
+
+/// Handles an EPT violation on a protected page.
+///
+/// An access of the owning process, or not denied, is executed for a single instruction with the page accessible. A
+/// denied access gets a page fault, or is executed for a single instruction on the decoy page.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `guest_pa` - The guest physical address accessed.
+/// * `exit_qualification` - The exit qualification of the EPT violation.
+///
+/// # Returns
+///
+/// * `Result<ExitType, HypervisorError>` - `Ok(ExitType::Continue)` to retry the access or deliver the page fault.
+pub fn handle_protected_memory_access(
+    vm: &mut Vm,
+    guest_pa: u64,
+    exit_qualification: &EptViolationExitQualification,
+) -> Result<ExitType, HypervisorError> {
+    let guest_page_pa = PAddr::from(guest_pa).align_down_to_base_page().as_u64();
+    let mut protected_memory = SHARED_PROTECTED_MEMORY.lock();
+
+    // The range has been removed meanwhile by another logical processor, retry the access.
+    let Some((page, region)) = protected_memory
+        .pages
+        .get(&guest_page_pa)
+        .and_then(|page| Some((*page, *protected_memory.regions.get(&page.region)?)))
+    else {
+        return Ok(ExitType::Continue);
+    };
+
+    let cr3 = vmread(vmcs::guest::CR3);
+
+    // The CPL is the DPL of SS, bits 6:5 of its access rights.
+    let cpl = ((vmread(vmcs::guest::SS_ACCESS_RIGHTS) >> 5) & 0x3) as u8;
+
+    let host_page_pa = if region.is_owner_context(cr3, cpl) || !region.denies(exit_qualification) {
+        guest_page_pa
+    } else {
+        protected_memory.denied_accesses += 1;
+
+        let guest_va = match exit_qualification.guest_linear_address_valid {
+            true => vmread(vmcs::ro::GUEST_LINEAR_ADDR),
+            false => page.guest_va + PAddr::from(guest_pa).base_page_offset(),
+        };
+
+        warn!(
+            "Denied {} of protected range of process {} at {:#x} (PA: {:#x}) from RIP: {:#x} (CR3: {:#x})",
+            if exit_qualification.data_write { "write" } else { "read" },
+            page.region.0,
+            guest_va,
+            guest_pa,
+            vm.guest_registers.rip,
+            cr3
+        );
+
+        stream_event(EventStreamKind::ProtectedMemoryAccess, [guest_va, guest_pa, vm.guest_registers.rip, cr3]);
+
+        match region.policy.action {
+            ProtectedMemoryAction::PageFault => {
+                let mut error_code = PAGE_FAULT_PRESENT;
+
+                if exit_qualification.data_write {
+                    error_code |= PAGE_FAULT_WRITE;
+                }
+
+                if cpl == 3 {
+                    error_code |= PAGE_FAULT_USER;
+                }
+
+                // Do not increment RIP, the page fault is delivered for the faulting instruction.
+                cr2_write(guest_va);
+                EventInjection::vmentry_inject_pf(error_code);
+
+                return Ok(ExitType::Continue);
+            }
+            ProtectedMemoryAction::Decoy => region.decoy_page_pa,
+        }
+    };
+
+    drop(protected_memory);
+
+    map_protected_page(vm, guest_page_pa, host_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+    vm.primary_ept.invalidate_ept_cache()?;
+
+    let context = SingleStepContext {
+        guest_page_pa,
+        guest_next_page_pa: None,
+    };
+    single_step(vm, SingleStepOwner::ProtectedMemoryAccess, 1, context, restore_protected_page)?;
+
+    Ok(ExitType::Continue)
+}
+
+/// Protects a page again after the access has been single-stepped.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `context` - The guest physical address of the accessed page.
+///
+/// # Returns
+///
+/// `Ok(())` if the page has been protected again, or `Err(HypervisorError)` if the EPT couldn't be modified.
+pub fn restore_protected_page(vm: &mut Vm, context: SingleStepContext) -> Result<(), HypervisorError> {
+    let guest_page_pa = context.guest_page_pa;
+
+    let protected_memory = SHARED_PROTECTED_MEMORY.lock();
+
+    // The range may have been removed meanwhile, restoring the page.
+    let Some(access_type) = protected_memory
+        .pages
+        .get(&guest_page_pa)
+        .and_then(|page| protected_memory.regions.get(&page.region))
+        .map(ProtectedRegion::access_type)
+    else {
+        return Ok(());
+    };
+
+    drop(protected_memory);
+
+    map_protected_page(vm, guest_page_pa, guest_page_pa, access_type)?;
+    vm.primary_ept.invalidate_ept_cache()
+}
+
+/// Maps a protected page to a host page with permissions on the current logical processor, splitting its large page
+/// if needed. The caller invalidates the EPT cache.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `guest_page_pa` - The guest physical address of the page.
+/// * `host_page_pa` - The host physical address of the page, the guest page itself or a decoy page.
+/// * `access_type` - The permissions of the page.
+fn map_protected_page(vm: &mut Vm, guest_page_pa: u64, host_page_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
+    let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page();
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    hook_manager.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
+
+    let pre_alloc_pt = hook_manager
+        .memory_manager
+        .get_page_table_as_mut(guest_large_page_pa.as_u64())
+        .ok_or(HypervisorError::PageTableNotFound)?;
+
+    if vm.primary_ept.is_large_page(guest_page_pa) {
+        vm.primary_ept.split_2mb_to_4kb(guest_large_page_pa.as_u64(), pre_alloc_pt)?;
+    }
+
+    vm.primary_ept.swap_page(guest_page_pa, host_page_pa, access_type, pre_alloc_pt)
+}
@@ -0,0 +1,392 @@
+//! Provides control over the guest's attempts to reset or shut down the system through the standard I/O ports.
+//!
+//! The following writes are intercepted through the I/O bitmap:
+//! - The reset control register (`0xCF9`) with the reset CPU bit set.
+//! - A pulse of the reset line through the keyboard controller command port (`0x64`).
+//! - The ACPI reset register from the FADT, if it is in system I/O space.
+//! - The ACPI PM1a/PM1b control registers from the FADT with the sleep enable bit set, which enters a sleep state,
+//!   including the soft-off state (S5) used to shut down the system.
+//!
+//! Each request is logged with the guest RIP, CR3 and process that initiated it, and passed to the registered handler,
+//! e.g., to flush data to the disk. Depending on the `ResetPolicy`, the request is then passed through to the hardware,
+//! optionally after a delay to let the logs drain, or discarded. Requests are always discarded while a critical
+//! hypervisor operation is in progress (see `enter_critical_operation`), as completing them would lose its state.
+//! An allowed transition to S3 is completed by the `sleep` module, so the hypervisor is virtualized again on the wake.
+//!
+//! The resets that never reach the hardware are handled in the same way: a triple fault of the guest, and an INIT
+//! signal received by the bootstrap processor, e.g., from a soft reset of the chipset, which would leave it waiting for
+//! a SIPI that never comes. The hypervisor resets the platform itself for them, rather than restarting the firmware in
+//! the guest, as the firmware doesn't know about the memory of the hypervisor. A vetoed triple fault shuts the logical
+//! processor down, as the processor would without the reset, and a vetoed INIT is emulated as for the other processors.
+
+use {
+    crate::{
+        acpi::SHARED_ACPI_TABLES,
+        intel::{
+            bitmap::IoOperation,
+            support::{outb, rdtsc, vmread},
+            timing::tsc_frequency_hz,
+            vm::Vm,
+        },
+        windows::eprocess::ProcessInformation,
+    },
+    core::sync::atomic::{AtomicU64, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    shared::ResetPolicy,
+    spin::RwLock,
+    x86::vmx::vmcs,
+};
+
+/// The reset control register of the chipset.
+pub const RESET_CONTROL_PORT: u16 = 0xCF9;
+
+/// The reset control register: requests a reset when set, hard or soft depending on the system reset bit.
+const RESET_CONTROL_RESET_CPU: u8 = 1 << 2;
+
+/// The reset control register: selects a hard reset of the platform rather than a soft reset, an INIT of the processors.
+const RESET_CONTROL_SYSTEM_RESET: u8 = 1 << 1;
+
+/// The keyboard controller command port.
+pub const KEYBOARD_CONTROLLER_COMMAND_PORT: u16 = 0x64;
+
+/// The keyboard controller commands 0xF0-0xFF pulse the output lines whose bits 3:0 are clear, bit 0 being the reset line.
+const KEYBOARD_CONTROLLER_PULSE_OUTPUT: u8 = 0xF0;
+
+/// The keyboard controller command pulsing the reset line only.
+const KEYBOARD_CONTROLLER_PULSE_RESET: u8 = KEYBOARD_CONTROLLER_PULSE_OUTPUT | 0xE;
+
+/// The PM1 control register: the sleep type, bits 12:10.
+pub const PM1_CONTROL_SLEEP_TYPE_SHIFT: u64 = 10;
+
+/// The PM1 control register: enters the sleep state selected by the sleep type when set.
+pub const PM1_CONTROL_SLEEP_ENABLE: u64 = 1 << 13;
+
+/// The number of critical hypervisor operations in progress, during which resets are discarded.
+static CRITICAL_OPERATIONS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// A globally shared instance of `ResetControl`, protected by a read-write lock.
+    ///
+    /// The ports are discovered at boot by `ResetControl::initialize_shared_reset_control`, before the processors are virtualized.
+    pub static ref SHARED_RESET_CONTROL: RwLock<ResetControl> = RwLock::new(ResetControl::new());
+}
+
+/// A handler called in VMX root operation when the guest attempts to reset or shut down the system, before the policy is applied.
+pub type ResetHandler = fn(event: &ResetEvent);
+
+/// The reset and sleep ports of the platform, and how the guest's requests are handled.
+#[derive(Debug, Clone, Copy)]
+pub struct ResetControl {
+    /// The I/O ports of the ACPI PM1a and PM1b control registers, if present.
+    pm1_control_ports: [Option<u16>; 2],
+
+    /// The I/O port of the ACPI reset register and its reset value, if present.
+    reset_register: Option<(u16, u8)>,
+
+    /// How the requests are handled.
+    policy: ResetPolicy,
+
+    /// The handler called for each request.
+    handler: Option<ResetHandler>,
+}
+
+impl ResetControl {
+    /// Creates a new reset control allowing all the requests, without the ACPI ports.
+    fn new() -> Self {
+        Self {
+            pm1_control_ports: [None; 2],
+            reset_register: None,
+            policy: ResetPolicy::Allow,
+            handler: None,
+        }
+    }
+
+    /// Discovers the ACPI reset and sleep ports through the FADT and stores them in `SHARED_RESET_CONTROL`.
+    ///
+    /// This must be called after the ACPI tables have been parsed and before the processors are virtualized.
+    pub fn initialize_shared_reset_control() {
+        let acpi_tables = SHARED_ACPI_TABLES.read();
+        let mut reset_control = SHARED_RESET_CONTROL.write();
+
+        reset_control.pm1_control_ports = acpi_tables.pm1_control_ports();
+        reset_control.reset_register = acpi_tables.reset_register();
+
+        debug!("Reset control: PM1 control ports: {:x?}, reset register: {:x?}", reset_control.pm1_control_ports, reset_control.reset_register);
+    }
+
+    /// Sets how the guest's requests are handled.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The new policy.
+    pub fn set_policy(&mut self, policy: ResetPolicy) {
+        debug!("Reset policy set to: {:?}", policy);
+        self.policy = policy;
+    }
+
+    /// Registers the handler called for each request, replacing the previous one.
+    ///
+    /// The handler is called while the reset control is locked for reading, so it must not change it.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The handler, or `None` to only log the requests.
+    pub fn set_handler(&mut self, handler: Option<ResetHandler>) {
+        self.handler = handler;
+    }
+
+    /// Identifies a write to an I/O port as a reset or sleep request.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port.
+    /// * `size` - The size of the access in bytes.
+    /// * `value` - The value written by the guest.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the source of the request, if the write is one.
+    fn reset_source(&self, port: u16, size: u64, value: u64) -> Option<ResetSource> {
+        // The ACPI reset register is usually the reset control register, so it is reported as such.
+        if port == RESET_CONTROL_PORT && size == 1 && value as u8 & RESET_CONTROL_RESET_CPU != 0 {
+            return Some(ResetSource::ResetControlRegister);
+        }
+
+        if let Some((reset_register_port, reset_value)) = self.reset_register {
+            if port == reset_register_port && size == 1 && value as u8 == reset_value {
+                return Some(ResetSource::AcpiResetRegister);
+            }
+        }
+
+        if port == KEYBOARD_CONTROLLER_COMMAND_PORT
+            && size == 1
+            && value as u8 & KEYBOARD_CONTROLLER_PULSE_OUTPUT == KEYBOARD_CONTROLLER_PULSE_OUTPUT
+            && value & 1 == 0
+        {
+            return Some(ResetSource::KeyboardController);
+        }
+
+        // Accesses narrower than 2 bytes don't reach the sleep enable bit.
+        if self.pm1_control_ports.contains(&Some(port)) && size >= 2 && value & PM1_CONTROL_SLEEP_ENABLE != 0 {
+            return Some(ResetSource::AcpiSleep {
+                sleep_type: ((value >> PM1_CONTROL_SLEEP_TYPE_SHIFT) & 0x7) as u8,
+            });
+        }
+
+        None
+    }
+}
+
+/// The source of a reset or sleep request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetSource {
+    /// A write to the reset control register (`0xCF9`).
+    ResetControlRegister,
+
+    /// A pulse of the reset line through the keyboard controller.
+    KeyboardController,
+
+    /// A write of the reset value to the ACPI reset register.
+    AcpiResetRegister,
+
+    /// A transition to a sleep state through the ACPI PM1 control registers.
+    AcpiSleep {
+        /// The sleep type (SLP_TYP), whose meaning (e.g., S3 or S5) is defined by the `_Sx` objects of the DSDT.
+        sleep_type: u8,
+    },
+
+    /// A triple fault of the guest, which shuts the logical processor down.
+    TripleFault,
+
+    /// An INIT signal received by the bootstrap processor.
+    BootstrapProcessorInit,
+}
+
+/// A reset or sleep request of the guest.
+#[derive(Debug, Clone, Copy)]
+pub struct ResetEvent {
+    /// The initial APIC ID of the logical processor.
+    pub processor_id: u32,
+
+    /// The source of the request.
+    pub source: ResetSource,
+
+    /// The I/O port written by the guest, 0 for a triple fault or an INIT.
+    pub port: u16,
+
+    /// The value written by the guest, 0 for a triple fault or an INIT.
+    pub value: u64,
+
+    /// The guest RIP of the I/O instruction, or when the logical processor was shut down or got the INIT.
+    pub guest_rip: u64,
+
+    /// The guest CR3 of the I/O instruction, or when the logical processor was shut down or got the INIT.
+    pub guest_cr3: u64,
+
+    /// How the request is handled.
+    pub policy: ResetPolicy,
+}
+
+/// Marks the start of a critical hypervisor operation (e.g., a snapshot restore), during which the guest's requests are discarded.
+///
+/// Each call must be paired with a call to `leave_critical_operation`.
+pub fn enter_critical_operation() {
+    CRITICAL_OPERATIONS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Marks the end of a critical hypervisor operation started with `enter_critical_operation`.
+pub fn leave_critical_operation() {
+    CRITICAL_OPERATIONS.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Intercepts the guest's writes to the reset and sleep ports on the current logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn intercept_reset_ports(vm: &mut Vm) {
+    let reset_control = *SHARED_RESET_CONTROL.read();
+
+    let ports = [
+        Some(RESET_CONTROL_PORT),
+        Some(KEYBOARD_CONTROLLER_COMMAND_PORT),
+        reset_control.reset_register.map(|(port, _)| port),
+    ]
+    .into_iter()
+    .chain(reset_control.pm1_control_ports)
+    .flatten();
+
+    for port in ports {
+        debug!("Intercepting reset port: {:#x}", port);
+        vm.io_bitmap.modify_io_interception(port, IoOperation::Hook);
+    }
+}
+
+/// Handles a write of the guest to an I/O port if it is a reset or sleep request, applying the reset policy.
+///
+/// The request is logged and passed to the handler, then delayed if the policy says so.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `port` - The I/O port.
+/// * `size` - The size of the access in bytes.
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// `true` if the write must be discarded, `false` if it must be passed through to the hardware.
+pub fn handle_reset_request(vm: &Vm, port: u16, size: u64, value: u64) -> bool {
+    let reset_control = SHARED_RESET_CONTROL.read();
+
+    let Some(source) = reset_control.reset_source(port, size, value) else {
+        return false;
+    };
+
+    drop(reset_control);
+
+    apply_reset_policy(vm, source, port, value)
+}
+
+/// Handles a reset that doesn't reach the hardware, a triple fault or an INIT of the bootstrap processor, applying the
+/// reset policy, and resets the platform unless the policy vetoes it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `source` - The source of the reset.
+///
+/// # Returns
+///
+/// Only if the reset is vetoed.
+pub fn handle_processor_reset(vm: &Vm, source: ResetSource) {
+    if !apply_reset_policy(vm, source, 0, 0) {
+        reset_platform();
+    }
+}
+
+/// Resets the platform through the ACPI reset register if it is in system I/O space, then through the reset control
+/// register and the keyboard controller in case the previous one had no effect.
+pub fn reset_platform() -> ! {
+    let reset_register = SHARED_RESET_CONTROL.read().reset_register;
+
+    warn!("Resetting the platform");
+    log::logger().flush();
+
+    if let Some((port, value)) = reset_register {
+        outb(port, value);
+    }
+
+    outb(RESET_CONTROL_PORT, RESET_CONTROL_SYSTEM_RESET | RESET_CONTROL_RESET_CPU);
+    outb(KEYBOARD_CONTROLLER_COMMAND_PORT, KEYBOARD_CONTROLLER_PULSE_RESET);
+
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Logs a reset or sleep request and passes it to the handler, then applies the reset policy, delaying it if the policy
+/// says so.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `source` - The source of the request.
+/// * `port` - The I/O port written by the guest, 0 if none.
+/// * `value` - The value written by the guest, 0 if none.
+///
+/// # Returns
+///
+/// `true` if the request must be discarded, `false` if it must be completed.
+fn apply_reset_policy(vm: &Vm, source: ResetSource, port: u16, value: u64) -> bool {
+    let reset_control = SHARED_RESET_CONTROL.read();
+
+    let policy = match CRITICAL_OPERATIONS.load(Ordering::Acquire) {
+        0 => reset_control.policy,
+        _ => ResetPolicy::Veto,
+    };
+
+    let event = ResetEvent {
+        processor_id: vm.cpuid_feature_info.initial_local_apic_id() as u32,
+        source,
+        port,
+        value,
+        guest_rip: vm.guest_registers.rip,
+        guest_cr3: vmread(vmcs::guest::CR3),
+        policy,
+    };
+
+    warn!("==================== GUEST RESET ====================");
+    warn!("Guest requested a reset or sleep: {:#x?}", event);
+
+    if let Some(p) = ProcessInformation::get_current_process_info() {
+        warn!("Reset requested by ImageFileName: {}, UniqueProcessId: {}", p.file_name, p.unique_process_id);
+    }
+
+    if let Some(handler) = reset_control.handler {
+        handler(&event);
+    }
+
+    drop(reset_control);
+
+    match policy {
+        ResetPolicy::Allow => false,
+        ResetPolicy::Delay(delay_ms) => {
+            log::logger().flush();
+
+            let delay_tsc_ticks = (tsc_frequency_hz() as u128 * delay_ms as u128 / 1000) as u64;
+            let start_tsc = rdtsc();
+            while rdtsc().wrapping_sub(start_tsc) < delay_tsc_ticks {
+                core::hint::spin_loop();
+            }
+
+            debug!("Reset delayed by {} ms, completing it", delay_ms);
+            false
+        }
+        ResetPolicy::Veto => {
+            warn!("Reset vetoed, the request is discarded");
+            true
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Provides interception of the Real-Time Clock (RTC) through the CMOS index and data ports (0x70/0x71).
+//!
+//! Accesses to the CMOS are passed through to the hardware, while the guest's reads and writes of the RTC time
+//! and date registers are shifted by a configurable offset. This allows the wall-clock time observed by the guest
+//! to be moved forward or backward ("time travel") without modifying the operating system or the hardware clock.
+
+use {
+    crate::intel::{bitmap::IoOperation, vm::Vm},
+    core::sync::atomic::{AtomicI64, AtomicU8, Ordering},
+    log::*,
+    x86::io::{inb, outb},
+};
+
+/// The CMOS index port, which selects the register accessed through the data port.
+pub const CMOS_INDEX_PORT: u16 = 0x70;
+
+/// The CMOS data port.
+pub const CMOS_DATA_PORT: u16 = 0x71;
+
+/// Bit 7 of the CMOS index port disables NMIs, and is not part of the register index.
+const CMOS_NMI_DISABLE: u8 = 1 << 7;
+
+/// The RTC time and date registers.
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY_OF_WEEK: u8 = 0x06;
+const RTC_DAY_OF_MONTH: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_CENTURY: u8 = 0x32;
+
+/// The RTC status registers.
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+/// Status register A: update in progress.
+const RTC_STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+
+/// Status register B: updates of the time and date registers are inhibited while they are set.
+const RTC_STATUS_B_SET: u8 = 1 << 7;
+
+/// Status register B: the hours are in 24-hour format, otherwise in 12-hour format with bit 7 set for PM.
+const RTC_STATUS_B_24_HOUR: u8 = 1 << 1;
+
+/// Status register B: the time and date are in binary, otherwise in BCD.
+const RTC_STATUS_B_BINARY: u8 = 1 << 2;
+
+/// The number of seconds per day.
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// The last value written by the guest to the CMOS index port.
+///
+/// The index register is a single hardware register shared by all logical processors.
+static CMOS_INDEX: AtomicU8 = AtomicU8::new(0);
+
+/// The offset in seconds added to the RTC time observed by the guest.
+static RTC_OFFSET_SECONDS: AtomicI64 = AtomicI64::new(0);
+
+/// Sets the offset in seconds added to the RTC time observed by the guest.
+///
+/// # Arguments
+///
+/// * `offset_seconds` - The offset in seconds, negative to move the guest's clock backward.
+pub fn set_rtc_offset(offset_seconds: i64) {
+    debug!("Setting RTC offset to {} seconds", offset_seconds);
+    RTC_OFFSET_SECONDS.store(offset_seconds, Ordering::Relaxed);
+}
+
+/// Returns the offset in seconds added to the RTC time observed by the guest.
+pub fn rtc_offset() -> i64 {
+    RTC_OFFSET_SECONDS.load(Ordering::Relaxed)
+}
+
+/// Intercepts the guest's accesses to the CMOS index and data ports on the current logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn intercept_rtc(vm: &mut Vm) {
+    debug!("Intercepting CMOS ports: {:#x}, {:#x}", CMOS_INDEX_PORT, CMOS_DATA_PORT);
+    vm.io_bitmap.modify_io_interception(CMOS_INDEX_PORT, IoOperation::Hook);
+    vm.io_bitmap.modify_io_interception(CMOS_DATA_PORT, IoOperation::Hook);
+}
+
+/// Checks whether an I/O port is one of the CMOS ports.
+///
+/// # Arguments
+///
+/// * `port` - The I/O port.
+pub fn is_cmos_port(port: u16) -> bool {
+    port == CMOS_INDEX_PORT || port == CMOS_DATA_PORT
+}
+
+/// Emulates a byte read by the guest from one of the CMOS ports.
+///
+/// # Arguments
+///
+/// * `port` - The CMOS port.
+///
+/// # Returns
+///
+/// The value presented to the guest.
+pub fn read_cmos_port(port: u16) -> u8 {
+    let guest_index = CMOS_INDEX.load(Ordering::Relaxed);
+
+    // The index port is write-only on most chipsets, return the last index selected by the guest.
+    if port == CMOS_INDEX_PORT {
+        return guest_index;
+    }
+
+    let register = guest_index & !CMOS_NMI_DISABLE;
+    let offset_seconds = rtc_offset();
+
+    if offset_seconds == 0 || !is_rtc_time_register(register) {
+        return read_cmos_register(guest_index, register);
+    }
+
+    let status_b = read_cmos_register(guest_index, RTC_STATUS_B);
+    let guest_time = read_rtc_time(guest_index, status_b).shifted(offset_seconds);
+    let value = guest_time.encode_register(register, status_b);
+
+    // Leave the index register selected by the guest.
+    unsafe { outb(CMOS_INDEX_PORT, guest_index) };
+
+    trace!("Shifted RTC register {:#x} read: {:#x}", register, value);
+    value
+}
+
+/// Emulates a byte written by the guest to one of the CMOS ports.
+///
+/// # Arguments
+///
+/// * `port` - The CMOS port.
+/// * `value` - The value written by the guest.
+pub fn write_cmos_port(port: u16, value: u8) {
+    if port == CMOS_INDEX_PORT {
+        CMOS_INDEX.store(value, Ordering::Relaxed);
+        unsafe { outb(CMOS_INDEX_PORT, value) };
+        return;
+    }
+
+    let guest_index = CMOS_INDEX.load(Ordering::Relaxed);
+    let register = guest_index & !CMOS_NMI_DISABLE;
+    let offset_seconds = rtc_offset();
+
+    if offset_seconds == 0 || !is_rtc_time_register(register) || register == RTC_DAY_OF_WEEK {
+        unsafe {
+            outb(CMOS_INDEX_PORT, guest_index);
+            outb(CMOS_DATA_PORT, value);
+        }
+        return;
+    }
+
+    // The guest sets its (shifted) clock, so store the new time without the offset in the hardware clock.
+    let status_b = read_cmos_register(guest_index, RTC_STATUS_B);
+    let mut guest_time = read_rtc_time(guest_index, status_b).shifted(offset_seconds);
+    guest_time.decode_register(register, value, status_b);
+
+    trace!("Shifted RTC register {:#x} write: {:#x}", register, value);
+    write_rtc_time(guest_index, status_b, guest_time.shifted(-offset_seconds));
+}
+
+/// Checks whether a CMOS register is one of the RTC time and date registers shifted by the offset.
+fn is_rtc_time_register(register: u8) -> bool {
+    matches!(register, RTC_SECONDS | RTC_MINUTES | RTC_HOURS | RTC_DAY_OF_WEEK | RTC_DAY_OF_MONTH | RTC_MONTH | RTC_YEAR | RTC_CENTURY)
+}
+
+/// Reads a CMOS register, preserving the NMI disable bit selected by the guest.
+fn read_cmos_register(guest_index: u8, register: u8) -> u8 {
+    unsafe {
+        outb(CMOS_INDEX_PORT, (guest_index & CMOS_NMI_DISABLE) | register);
+        inb(CMOS_DATA_PORT)
+    }
+}
+
+/// Writes a CMOS register, preserving the NMI disable bit selected by the guest.
+fn write_cmos_register(guest_index: u8, register: u8, value: u8) {
+    unsafe {
+        outb(CMOS_INDEX_PORT, (guest_index & CMOS_NMI_DISABLE) | register);
+        outb(CMOS_DATA_PORT, value);
+    }
+}
+
+/// Reads the hardware RTC time, waiting for an update in progress to complete.
+fn read_rtc_time(guest_index: u8, status_b: u8) -> RtcTime {
+    // An update takes at most 2 ms, the guest has no expectation of a bounded access time anyway.
+    while read_cmos_register(guest_index, RTC_STATUS_A) & RTC_STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    let read = |register| decode_value(read_cmos_register(guest_index, register), status_b);
+
+    let hours = read_cmos_register(guest_index, RTC_HOURS);
+    let century = match read(RTC_CENTURY) {
+        century @ 19..=99 => century as i64,
+        _ => 20,
+    };
+
+    RtcTime {
+        year: century * 100 + read(RTC_YEAR) as i64,
+        month: read(RTC_MONTH) as i64,
+        day: read(RTC_DAY_OF_MONTH) as i64,
+        hours: decode_hours(hours, status_b) as i64,
+        minutes: read(RTC_MINUTES) as i64,
+        seconds: read(RTC_SECONDS) as i64,
+    }
+}
+
+/// Writes the hardware RTC time, inhibiting updates while the registers are written.
+fn write_rtc_time(guest_index: u8, status_b: u8, time: RtcTime) {
+    write_cmos_register(guest_index, RTC_STATUS_B, status_b | RTC_STATUS_B_SET);
+
+    for register in [
+        RTC_SECONDS,
+        RTC_MINUTES,
+        RTC_HOURS,
+        RTC_DAY_OF_WEEK,
+        RTC_DAY_OF_MONTH,
+        RTC_MONTH,
+        RTC_YEAR,
+        RTC_CENTURY,
+    ] {
+        write_cmos_register(guest_index, register, time.encode_register(register, status_b));
+    }
+
+    write_cmos_register(guest_index, RTC_STATUS_B, status_b & !RTC_STATUS_B_SET);
+
+    // Leave the index register selected by the guest.
+    unsafe { outb(CMOS_INDEX_PORT, guest_index) };
+}
+
+/// Decodes a BCD or binary RTC value.
+fn decode_value(value: u8, status_b: u8) -> u8 {
+    match status_b & RTC_STATUS_B_BINARY {
+        0 => (value >> 4) * 10 + (value & 0xF),
+        _ => value,
+    }
+}
+
+/// Encodes a BCD or binary RTC value.
+fn encode_value(value: u8, status_b: u8) -> u8 {
+    match status_b & RTC_STATUS_B_BINARY {
+        0 => ((value / 10) << 4) | (value % 10),
+        _ => value,
+    }
+}
+
+/// Decodes the RTC hours register to 0-23.
+fn decode_hours(value: u8, status_b: u8) -> u8 {
+    if status_b & RTC_STATUS_B_24_HOUR != 0 {
+        return decode_value(value, status_b);
+    }
+
+    // 12-hour format: 12 AM is midnight and 12 PM is noon.
+    let hours = decode_value(value & 0x7F, status_b) % 12;
+    if value & 0x80 != 0 {
+        hours + 12
+    } else {
+        hours
+    }
+}
+
+/// Encodes 0-23 hours to the RTC hours register.
+fn encode_hours(hours: u8, status_b: u8) -> u8 {
+    if status_b & RTC_STATUS_B_24_HOUR != 0 {
+        return encode_value(hours, status_b);
+    }
+
+    let pm = if hours >= 12 { 0x80 } else { 0 };
+    let hours = match hours % 12 {
+        0 => 12,
+        hours => hours,
+    };
+
+    encode_value(hours, status_b) | pm
+}
+
+/// A calendar date and time of the RTC.
+#[derive(Debug, Clone, Copy)]
+struct RtcTime {
+    year: i64,
+    month: i64,
+    day: i64,
+    hours: i64,
+    minutes: i64,
+    seconds: i64,
+}
+
+impl RtcTime {
+    /// Returns the time shifted by a number of seconds.
+    fn shifted(&self, offset_seconds: i64) -> Self {
+        let timestamp = self.to_unix_time() + offset_seconds;
+        Self::from_unix_time(timestamp)
+    }
+
+    /// Returns the number of days since 1970-01-01 of a civil date.
+    ///
+    /// Reference: Howard Hinnant, chrono-Compatible Low-Level Date Algorithms: days_from_civil
+    fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    /// Returns the number of seconds since 1970-01-01 00:00:00.
+    fn to_unix_time(self) -> i64 {
+        Self::days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY + self.hours * 3600 + self.minutes * 60 + self.seconds
+    }
+
+    /// Converts a number of seconds since 1970-01-01 00:00:00 to a calendar date and time.
+    ///
+    /// Reference: Howard Hinnant, chrono-Compatible Low-Level Date Algorithms: civil_from_days
+    fn from_unix_time(timestamp: i64) -> Self {
+        let days = timestamp.div_euclid(SECONDS_PER_DAY) + 719_468;
+        let seconds_of_day = timestamp.rem_euclid(SECONDS_PER_DAY);
+
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year,
+            month,
+            day,
+            hours: seconds_of_day / 3600,
+            minutes: seconds_of_day % 3600 / 60,
+            seconds: seconds_of_day % 60,
+        }
+    }
+
+    /// Returns the day of the week, from 1 (Sunday) to 7 (Saturday).
+    fn day_of_week(&self) -> i64 {
+        // 1970-01-01 was a Thursday.
+        (Self::days_from_civil(self.year, self.month, self.day) + 4).rem_euclid(7) + 1
+    }
+
+    /// Encodes the value of an RTC time and date register.
+    fn encode_register(&self, register: u8, status_b: u8) -> u8 {
+        let value = match register {
+            RTC_SECONDS => self.seconds,
+            RTC_MINUTES => self.minutes,
+            RTC_HOURS => return encode_hours(self.hours as u8, status_b),
+            RTC_DAY_OF_WEEK => self.day_of_week(),
+            RTC_DAY_OF_MONTH => self.day,
+            RTC_MONTH => self.month,
+            RTC_YEAR => self.year.rem_euclid(100),
+            _ => self.year.div_euclid(100),
+        };
+
+        encode_value(value as u8, status_b)
+    }
+
+    /// Decodes a value written to an RTC time and date register.
+    fn decode_register(&mut self, register: u8, value: u8, status_b: u8) {
+        match register {
+            RTC_SECONDS => self.seconds = decode_value(value, status_b) as i64,
+            RTC_MINUTES => self.minutes = decode_value(value, status_b) as i64,
+            RTC_HOURS => self.hours = decode_hours(value, status_b) as i64,
+            RTC_DAY_OF_MONTH => self.day = decode_value(value, status_b) as i64,
+            RTC_MONTH => self.month = decode_value(value, status_b) as i64,
+            RTC_YEAR => self.year = self.year.div_euclid(100) * 100 + decode_value(value, status_b) as i64,
+            RTC_CENTURY => self.year = decode_value(value, status_b) as i64 * 100 + self.year.rem_euclid(100),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Provides periodic work run in VMX root operation, driven by the VMX-preemption timer rather than by the VM exits
+//! of the guest, e.g., integrity scans, statistics flushes or deferred hook installations.
+//!
+//! A subsystem registers a callback with a period in milliseconds, and each logical processor calls it once per
+//! period from the VMX-preemption timer VM exit, so the callback runs even while the guest is idle or spinning. A
+//! callback due on several logical processors runs on each of them, so callbacks working on shared state should lock
+//! it and return early when another logical processor already did the work for the period. A period missed, e.g.,
+//! while the logical processor was in a long VM exit, is skipped rather than caught up.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            seqlock::{Generation, Published},
+            support::rdtsc,
+            timing::tsc_frequency_hz,
+            vm::Vm,
+            vmexit::preemption_timer::{is_preemption_timer_supported, update_preemption_timer},
+        },
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+};
+
+/// The maximum number of periodic tasks registered at the same time.
+pub const MAX_PERIODIC_TASKS: usize = 8;
+
+/// The registered tasks scheduled on the logical processors.
+static SCHEDULED_TASKS: Published<[Option<PeriodicTask>; MAX_PERIODIC_TASKS]> = Published::new([None; MAX_PERIODIC_TASKS]);
+
+lazy_static! {
+    /// A globally shared instance of `Scheduler`, protected by a mutex.
+    pub static ref SHARED_SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+}
+
+/// A callback run in VMX root operation once per period on each logical processor.
+///
+/// The callback is called without the scheduler locked, so it may register or unregister tasks, which the logical
+/// processors pick up on their next VM exit.
+pub type PeriodicCallback = fn(vm: &mut Vm);
+
+/// A periodic task registered with the scheduler.
+#[derive(Debug, Clone, Copy)]
+pub struct PeriodicTask {
+    /// The identifier returned on registration.
+    id: u64,
+
+    /// The name of the task, for the logs.
+    name: &'static str,
+
+    /// The callback of the task.
+    callback: PeriodicCallback,
+
+    /// The number of TSC ticks between two runs.
+    period_tsc_ticks: u64,
+}
+
+/// The periodic tasks, shared by all the logical processors.
+#[derive(Debug)]
+pub struct Scheduler {
+    /// The registered tasks.
+    tasks: [Option<PeriodicTask>; MAX_PERIODIC_TASKS],
+
+    /// The identifier of the next task registered.
+    next_id: u64,
+}
+
+impl Scheduler {
+    /// Creates a new scheduler without tasks.
+    fn new() -> Self {
+        Self {
+            tasks: [None; MAX_PERIODIC_TASKS],
+            next_id: 1,
+        }
+    }
+
+    /// Registers a task run on each logical processor once per period, starting one period after it is picked up.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the task, for the logs.
+    /// * `callback` - The callback of the task.
+    /// * `period_ms` - The period in milliseconds.
+    ///
+    /// # Returns
+    ///
+    /// The identifier of the task, `Err(HypervisorError::PreemptionTimerUnsupported)` if the VMX-preemption timer isn't
+    /// supported, `Err(HypervisorError::InvalidPeriodicTask)` if the period is zero, or
+    /// `Err(HypervisorError::TooManyPeriodicTasks)` if `MAX_PERIODIC_TASKS` are already registered.
+    pub fn register(&mut self, name: &'static str, callback: PeriodicCallback, period_ms: u64) -> Result<u64, HypervisorError> {
+        if !is_preemption_timer_supported() {
+            return Err(HypervisorError::PreemptionTimerUnsupported);
+        }
+
+        let period_tsc_ticks = (tsc_frequency_hz() as u128 * period_ms as u128 / 1000) as u64;
+        if period_tsc_ticks == 0 {
+            return Err(HypervisorError::InvalidPeriodicTask);
+        }
+
+        let slot = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.is_none())
+            .ok_or(HypervisorError::TooManyPeriodicTasks)?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        *slot = Some(PeriodicTask {
+            id,
+            name,
+            callback,
+            period_tsc_ticks,
+        });
+
+        debug!("Periodic task {} registered: {} every {} ms", id, name, period_ms);
+
+        self.publish();
+
+        Ok(id)
+    }
+
+    /// Unregisters a task, which isn't run again once the logical processors picked up the change.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier returned on registration.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the task was registered.
+    pub fn unregister(&mut self, id: u64) -> bool {
+        let Some(slot) = self.tasks.iter_mut().find(|task| task.is_some_and(|task| task.id == id)) else {
+            return false;
+        };
+
+        if let Some(task) = slot.take() {
+            debug!("Periodic task {} unregistered: {}", id, task.name);
+        }
+
+        self.publish();
+
+        true
+    }
+
+    /// Returns the number of registered tasks.
+    pub fn task_count(&self) -> usize {
+        self.tasks.iter().flatten().count()
+    }
+
+    /// Publishes the registered tasks, which the logical processors pick up on their next VM exit.
+    fn publish(&self) {
+        SCHEDULED_TASKS.publish(self.tasks);
+    }
+}
+
+/// A periodic task scheduled on a logical processor.
+#[derive(Debug, Clone, Copy)]
+struct ScheduledTask {
+    /// The identifier of the task.
+    id: u64,
+
+    /// The callback of the task.
+    callback: PeriodicCallback,
+
+    /// The number of TSC ticks between two runs.
+    period_tsc_ticks: u64,
+
+    /// The TSC at which the next run is due.
+    next_run_tsc: u64,
+}
+
+/// The scheduler state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorScheduler {
+    /// The generation of the tasks scheduled on this logical processor.
+    generation: Generation,
+
+    /// The tasks scheduled on this logical processor.
+    tasks: [Option<ScheduledTask>; MAX_PERIODIC_TASKS],
+}
+
+impl ProcessorScheduler {
+    /// Creates a new scheduler state without tasks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of TSC ticks until the next task is due, or `None` without tasks.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The current TSC.
+    pub fn ticks_until_next_task(&self, tsc: u64) -> Option<u64> {
+        self.tasks.iter().flatten().map(|task| task.next_run_tsc.saturating_sub(tsc).max(1)).min()
+    }
+}
+
+/// Picks up the registered tasks on the current logical processor and re-arms the VMX-preemption timer for them.
+///
+/// The tasks scheduled already keep their next run, and the new ones are first run one period after they are picked
+/// up.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_scheduler(vm: &mut Vm) {
+    let Some(tasks) = SCHEDULED_TASKS.sync(&mut vm.scheduler.generation) else {
+        return;
+    };
+
+    let tsc = rdtsc();
+    let previous = vm.scheduler;
+
+    vm.scheduler.tasks = tasks.map(|task| {
+        task.map(|task| {
+            let next_run_tsc = previous
+                .tasks
+                .iter()
+                .flatten()
+                .find(|scheduled| scheduled.id == task.id)
+                .map_or(tsc.wrapping_add(task.period_tsc_ticks), |scheduled| scheduled.next_run_tsc);
+
+            ScheduledTask {
+                id: task.id,
+                callback: task.callback,
+                period_tsc_ticks: task.period_tsc_ticks,
+                next_run_tsc,
+            }
+        })
+    });
+
+    update_preemption_timer(vm);
+}
+
+/// Runs the tasks due on the current logical processor and schedules their next run.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn run_due_tasks(vm: &mut Vm) {
+    for index in 0..MAX_PERIODIC_TASKS {
+        let tsc = rdtsc();
+
+        let Some(task) = vm.scheduler.tasks[index].filter(|task| tsc >= task.next_run_tsc) else {
+            continue;
+        };
+
+        // The periods missed are skipped, so a late run is followed by a full period.
+        if let Some(scheduled) = vm.scheduler.tasks[index].as_mut() {
+            scheduled.next_run_tsc = tsc.wrapping_add(task.period_tsc_ticks);
+        }
+
+        trace!("Running periodic task {}", task.id);
+        (task.callback)(vm);
+    }
+}
@@ -0,0 +1,189 @@
+//! Provides a sequence lock, publishing a small value written rarely to the logical processors reading it on their VM
+//! exits without making them wait for each other.
+//!
+//! The writer makes the sequence odd while the value is written and even again once it's written, and a reader copies
+//! the value between two reads of the sequence, retrying while it's written or if the sequence changed. The readers
+//! never write to the lock, so they don't contend on its cache line as they would with a spin lock, and a reader can't
+//! be blocked by another reader interrupted, e.g., by an NMI.
+//!
+//! A `Published` value is a sequence lock whose sequence doubles as its generation: each logical processor keeps the
+//! `Generation` of the value it picked up and compares it on its VM exits, copying the value only when it changed. Every
+//! publication also increments a global generation, so a VM exit with nothing new to pick up costs a single load for
+//! all the published values (see `is_any_published`).
+//!
+//! Reference: https://en.wikipedia.org/wiki/Seqlock
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+/// A value of a plain `Copy` type, read without locking and written by one writer at a time.
+pub struct SeqLock<T: Copy> {
+    /// The sequence of the value, odd while it's written.
+    sequence: AtomicU64,
+
+    /// The value, read with volatile copies as it can be written while it's read.
+    value: UnsafeCell<T>,
+}
+
+// The value is only accessed through copies validated by the sequence, so it's shared like the `Copy` value itself.
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    /// Creates a new sequence lock holding a value.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The initial value.
+    pub const fn new(value: T) -> Self {
+        Self {
+            sequence: AtomicU64::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns a copy of the value, retrying while it's written.
+    pub fn read(&self) -> T {
+        self.read_with_sequence().0
+    }
+
+    /// Returns a copy of the value and the sequence it was written with, retrying while it's written.
+    fn read_with_sequence(&self) -> (T, u64) {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence & 1 != 0 {
+                spin_loop();
+                continue;
+            }
+
+            // A copy torn by a concurrent write is discarded below, `T` being `Copy` it can't own anything.
+            let value = unsafe { self.value.get().read_volatile() };
+
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == sequence {
+                return (value, sequence);
+            }
+        }
+    }
+
+    /// Replaces the value, waiting for a concurrent writer to finish.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The new value.
+    pub fn write(&self, value: T) {
+        let sequence = loop {
+            let sequence = self.sequence.load(Ordering::Relaxed);
+            let is_acquired = sequence & 1 == 0
+                && self
+                    .sequence
+                    .compare_exchange_weak(sequence, sequence + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok();
+
+            if is_acquired {
+                break sequence;
+            }
+
+            spin_loop();
+        };
+
+        // The odd sequence must be visible before any byte of the new value.
+        fence(Ordering::Release);
+        unsafe { self.value.get().write_volatile(value) };
+
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+}
+
+/// The number of values published so far, incremented after each publication.
+static PUBLISHED_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The generation of a published value picked up by a logical processor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Generation(u64);
+
+impl Generation {
+    /// A generation never published, so the value is picked up at the next comparison.
+    pub const STALE: Self = Self(u64::MAX);
+}
+
+/// A value published to all the logical processors, which pick it up on their VM exits when it changed.
+pub struct Published<T: Copy> {
+    /// The value, its sequence being its generation.
+    value: SeqLock<T>,
+}
+
+impl<T: Copy> Published<T> {
+    /// Creates a new published value, considered picked up by a logical processor starting at `Generation::default()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The initial value.
+    pub const fn new(value: T) -> Self {
+        Self { value: SeqLock::new(value) }
+    }
+
+    /// Returns a copy of the current value.
+    pub fn read(&self) -> T {
+        self.value.read()
+    }
+
+    /// Returns the generation of the current value.
+    pub fn generation(&self) -> Generation {
+        Generation(self.value.read_with_sequence().1)
+    }
+
+    /// Returns `true` if the value changed since a generation, without copying it.
+    ///
+    /// # Arguments
+    ///
+    /// * `generation` - The generation picked up by the current logical processor.
+    pub fn is_changed(&self, generation: Generation) -> bool {
+        self.value.sequence.load(Ordering::Acquire) != generation.0
+    }
+
+    /// Publishes a new value, which the logical processors pick up on their next VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The new value.
+    pub fn publish(&self, value: T) {
+        self.value.write(value);
+        PUBLISHED_GENERATION.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns a copy of the value if it changed since the generation picked up by the current logical processor, and
+    /// updates that generation.
+    ///
+    /// # Arguments
+    ///
+    /// * `generation` - The generation picked up by the current logical processor.
+    pub fn sync(&self, generation: &mut Generation) -> Option<T> {
+        if !self.is_changed(*generation) {
+            return None;
+        }
+
+        let (value, sequence) = self.value.read_with_sequence();
+        *generation = Generation(sequence);
+
+        Some(value)
+    }
+}
+
+/// Returns `true` if any value was published since the global generation picked up by the current logical processor,
+/// and updates that generation. The values must be synchronized after this returns `true`, since the next call won't.
+///
+/// # Arguments
+///
+/// * `generation` - The global generation picked up by the current logical processor.
+pub fn is_any_published(generation: &mut Generation) -> bool {
+    let published_generation = PUBLISHED_GENERATION.load(Ordering::Acquire);
+    if published_generation == generation.0 {
+        return false;
+    }
+
+    *generation = Generation(published_generation);
+    true
+}
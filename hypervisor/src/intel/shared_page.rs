@@ -0,0 +1,159 @@
+//! Maps the shared communication page (see `shared::SharedPage`) over the guest page provided by the user mode client,
+//! in the primary EPT of every logical processor.
+//!
+//! The guest page may already be remapped, e.g., by a hook, so the host page and the permissions it is mapped with are
+//! saved when the shared page is mapped over it, and restored when it is unmapped. The page tables of split large pages
+//! are shared by the logical processors, so the mapping is saved once by the logical processor handling the command,
+//! then applied to each of them by a broadcast (see the `broadcast` module).
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            broadcast::{broadcast_host_command, HostCommand},
+            ept::AccessType,
+            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            host_config::SHARED_HOST_CONFIG,
+            transfer::cancel_async_transfer,
+            vm::Vm,
+        },
+    },
+    log::*,
+    shared::SharedPage,
+};
+
+/// A guest page mapped to a host page with some permissions in the primary EPT.
+#[derive(Debug, Clone, Copy)]
+pub struct PageMapping {
+    /// The physical address of the guest page.
+    pub guest_page_pa: u64,
+
+    /// The physical address of the host page backing the guest page.
+    pub host_page_pa: u64,
+
+    /// The permissions of the guest page.
+    pub access_type: AccessType,
+}
+
+/// Maps the shared page over a guest page on all the logical processors, initializing its layout first.
+///
+/// Only one guest page is backed by the shared page at a time, so the previous one is restored first.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `guest_page_pa` - The page-aligned physical address of the guest page.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the shared page was mapped on the current logical processor, the ones that didn't acknowledge the
+///   broadcast in time mapping it on a later VM exit.
+/// * `Err(HypervisorError)` - If the shared page isn't allocated, or the guest page or the broadcast failed.
+pub fn map_shared_page(vm: &mut Vm, guest_page_pa: u64) -> Result<(), HypervisorError> {
+    let shared_page_pa = SHARED_HOST_CONFIG.read().shared_page_pa;
+
+    if shared_page_pa == 0 {
+        return Err(HypervisorError::SharedPageNotAllocated);
+    }
+
+    // The mapping saved must be the one of the guest, not the one of the shared page.
+    if vm.shared_page_mapping.is_some() {
+        unmap_shared_page(vm)?;
+    }
+
+    let (host_page_pa, access_type) = SHARED_HOOK_MANAGER.lock().ept_page_mapping(vm, guest_page_pa)?;
+    debug!("Guest page {:#x} mapped to host page {:#x} ({:?})", guest_page_pa, host_page_pa, access_type);
+
+    // Reset the layout before the guest can observe the page.
+    unsafe { (*(shared_page_pa as *mut SharedPage)).initialize() };
+
+    broadcast_mapping(
+        vm,
+        HostCommand::MapSharedPage(PageMapping {
+            guest_page_pa,
+            host_page_pa,
+            access_type,
+        }),
+    )
+}
+
+/// Restores the guest page the shared page is mapped over on all the logical processors.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the guest page was restored on the current logical processor, the ones that didn't acknowledge the
+///   broadcast in time restoring it on a later VM exit.
+/// * `Err(HypervisorError)` - If the shared page isn't mapped, or the broadcast failed.
+pub fn unmap_shared_page(vm: &mut Vm) -> Result<(), HypervisorError> {
+    if vm.shared_page_mapping.is_none() {
+        return Err(HypervisorError::SharedPageNotMapped);
+    }
+
+    broadcast_mapping(vm, HostCommand::UnmapSharedPage)
+}
+
+/// Broadcasts a `MapSharedPage` or `UnmapSharedPage` command, tolerating the logical processors that acknowledge it late.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `command` - The command to broadcast.
+fn broadcast_mapping(vm: &mut Vm, command: HostCommand) -> Result<(), HypervisorError> {
+    match broadcast_host_command(vm, command) {
+        Err(HypervisorError::BroadcastTimeout) => {
+            warn!("{:?} not acknowledged by some logical processors yet", command);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Maps the shared page over a guest page in the primary EPT of the current logical processor, executing a broadcast
+/// `MapSharedPage` command. The guest page previously backed by the shared page is restored first, in case its
+/// `UnmapSharedPage` command was superseded before this logical processor executed it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `saved_mapping` - The mapping of the guest page to restore when the shared page is unmapped.
+pub fn apply_map_shared_page(vm: &mut Vm, saved_mapping: PageMapping) {
+    apply_unmap_shared_page(vm);
+
+    let shared_page_pa = SHARED_HOST_CONFIG.read().shared_page_pa;
+
+    let result = SHARED_HOOK_MANAGER
+        .lock()
+        .ept_map_host_page(vm, saved_mapping.guest_page_pa, shared_page_pa, AccessType::READ_WRITE);
+
+    match result {
+        Ok(()) => vm.shared_page_mapping = Some(saved_mapping),
+        Err(e) => error!("Failed to map the shared page over guest page {:#x}: {:?}", saved_mapping.guest_page_pa, e),
+    }
+}
+
+/// Restores the guest page the shared page is mapped over in the primary EPT of the current logical processor, if any,
+/// executing a broadcast `UnmapSharedPage` command.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn apply_unmap_shared_page(vm: &mut Vm) {
+    let Some(saved_mapping) = vm.shared_page_mapping.take() else {
+        return;
+    };
+
+    let result = SHARED_HOOK_MANAGER
+        .lock()
+        .ept_map_host_page(vm, saved_mapping.guest_page_pa, saved_mapping.host_page_pa, saved_mapping.access_type);
+
+    if let Err(e) = result {
+        error!("Failed to restore guest page {:#x}: {:?}", saved_mapping.guest_page_pa, e);
+    }
+
+    // The progress of an asynchronous transfer can't be reported without the shared page.
+    cancel_async_transfer(vm);
+}
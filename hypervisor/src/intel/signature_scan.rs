@@ -0,0 +1,302 @@
+//! Provides bounded signature scans over guest memory, finding byte patterns with wildcards, e.g., the prologue of an
+//! unexported ntoskrnl.exe function to hook, in the guest physical memory or in the address space of a process.
+//!
+//! A signature is a sequence of bytes, each matching a single value or any value, written in the IDA style for the
+//! internal users (e.g., `48 8B C4 ?? 89 58 08`) or as bytes and a mask by the clients.
+//!
+//! The virtual ranges are scanned page by page through the page tables of the process, skipping the pages that aren't
+//! mapped. The physical ranges are scanned through the identity map of the host, skipping the memory of the
+//! hypervisor and the pages that aren't write-back, e.g., MMIO, whose reads may have side effects. A match never spans
+//! a skipped page.
+//!
+//! A scan examines at most a given number of bytes, `MAX_SIGNATURE_SCAN_SIZE` for the clients, and reports where it
+//! stopped, so a large range is scanned by further scans resuming at that address rather than stalling the logical
+//! processor for seconds.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            hooks::hook_manager::kernel_image,
+            host_config::SHARED_HOST_CONFIG,
+            memory_search::read_mapped_bytes,
+            mtrr::{MemoryType, Mtrr},
+            paging::IDENTITY_MAP_SIZE,
+            support::vmread,
+        },
+    },
+    alloc::vec::Vec,
+    shared::{MAX_SIGNATURE_SCAN_SIZE, MAX_SIGNATURE_SIZE},
+    x86::{bits64::paging::BASE_PAGE_SIZE, vmx::vmcs},
+};
+
+/// The size in bytes of the chunks of the range scanned at a time.
+const SCAN_CHUNK_SIZE: usize = 0x10000;
+
+/// A byte pattern with wildcards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// The bytes of the pattern, `None` matching any byte.
+    bytes: Vec<Option<u8>>,
+}
+
+impl Signature {
+    /// Creates a signature from its bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes of the pattern, `None` matching any byte.
+    ///
+    /// # Returns
+    ///
+    /// The signature, or `Err(HypervisorError::InvalidSignature)` if it is empty, longer than `MAX_SIGNATURE_SIZE` or
+    /// only made of wildcards.
+    pub fn new(bytes: &[Option<u8>]) -> Result<Self, HypervisorError> {
+        if bytes.is_empty() || bytes.len() > MAX_SIGNATURE_SIZE || bytes.iter().all(Option::is_none) {
+            return Err(HypervisorError::InvalidSignature);
+        }
+
+        Ok(Self { bytes: bytes.to_vec() })
+    }
+
+    /// Creates a signature from the bytes and the mask sent by a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The bytes of the pattern.
+    /// * `mask` - The mask of the pattern, the bytes whose mask is 0 matching any byte.
+    pub fn from_masked(pattern: &[u8], mask: &[u8]) -> Result<Self, HypervisorError> {
+        if pattern.len() != mask.len() {
+            return Err(HypervisorError::InvalidSignature);
+        }
+
+        let bytes: Vec<Option<u8>> = pattern.iter().zip(mask).map(|(&byte, &mask)| (mask != 0).then_some(byte)).collect();
+
+        Self::new(&bytes)
+    }
+
+    /// Parses a signature written as hexadecimal bytes separated by white spaces, with `??` or `?` wildcards, e.g.,
+    /// `48 8B C4 ?? 89 58 08`.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the signature.
+    pub fn parse(text: &str) -> Result<Self, HypervisorError> {
+        let bytes = text
+            .split_whitespace()
+            .map(|token| match token {
+                "??" | "?" => Ok(None),
+                token if token.len() == 2 => u8::from_str_radix(token, 16).map(Some).map_err(|_| HypervisorError::InvalidSignature),
+                _ => Err(HypervisorError::InvalidSignature),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::new(&bytes)
+    }
+
+    /// Returns the size of the signature in bytes.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns `true` if the signature matches at the beginning of the data.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The scanned data, at least as long as the signature.
+    fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.bytes.len()
+            && data
+                .iter()
+                .zip(&self.bytes)
+                .all(|(byte, expected)| expected.is_none_or(|expected| *byte == expected))
+    }
+}
+
+/// The address space of a scanned range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanSpace {
+    /// The guest physical memory.
+    Physical,
+
+    /// The virtual addresses translated by a directory table base.
+    Virtual(u64),
+}
+
+/// The outcome of a scan.
+#[derive(Debug)]
+pub struct ScanResult {
+    /// The addresses of the matches, in address order.
+    pub matches: Vec<u64>,
+
+    /// The address where a further scan resumes, or the end of the range if the scan is complete.
+    pub next_address: u64,
+
+    /// The number of bytes scanned, including the pages skipped.
+    pub scanned_bytes: u64,
+}
+
+/// Scans a range of guest memory for a signature.
+///
+/// The scan stops at the end of the range, after `max_scan_size` bytes, or once `max_matches` matches are found, and
+/// a further scan resumes at `next_address` without reporting the same matches again.
+///
+/// # Arguments
+///
+/// * `signature` - The signature.
+/// * `space` - The address space of the range.
+/// * `start_address` - The address where the scan starts.
+/// * `end_address` - The address where the range ends, exclusive.
+/// * `max_matches` - The maximum number of matches returned.
+/// * `max_scan_size` - The maximum number of bytes scanned.
+pub fn scan_guest_memory(
+    signature: &Signature,
+    space: ScanSpace,
+    start_address: u64,
+    end_address: u64,
+    max_matches: usize,
+    max_scan_size: u64,
+) -> ScanResult {
+    let scan_end = end_address.min(start_address.saturating_add(max_scan_size));
+    let mut mtrr = Mtrr::new();
+    let mut matches = Vec::new();
+    let mut address = start_address;
+
+    while address < scan_end && matches.len() < max_matches {
+        let chunk_end = scan_end.min(address.saturating_add(SCAN_CHUNK_SIZE as u64));
+
+        // The window overlaps the next chunk by the size of the signature, so a match starting in this chunk is
+        // found even if it ends in the next one.
+        let window_end = end_address.min(chunk_end.saturating_add(signature.size() as u64 - 1));
+        let window_size = (window_end - address) as usize;
+
+        let window = match space {
+            ScanSpace::Physical => read_scannable_physical_bytes(address, window_size, &mut mtrr),
+            ScanSpace::Virtual(directory_table_base) => read_mapped_bytes(address, window_size, directory_table_base),
+        };
+
+        if window.is_empty() {
+            // The page can't be scanned, so the scan continues at the next one.
+            address = scan_end.min((address | (BASE_PAGE_SIZE as u64 - 1)).saturating_add(1));
+            continue;
+        }
+
+        let scanned_size = window.len().min((chunk_end - address) as usize);
+        let mut offset = 0;
+
+        while offset < scanned_size && matches.len() < max_matches {
+            if signature.matches(&window[offset..]) {
+                matches.push(address + offset as u64);
+            }
+
+            offset += 1;
+        }
+
+        address += offset as u64;
+    }
+
+    ScanResult {
+        matches,
+        next_address: address.min(end_address),
+        scanned_bytes: address.min(end_address) - start_address,
+    }
+}
+
+/// Finds the first match of a signature in the image of ntoskrnl.exe, e.g., an unexported function to hook.
+///
+/// The image is scanned entirely through the directory table base mapping the kernel, or the current guest CR3 if it
+/// hasn't been captured yet, so the pages of the image don't need to be physically contiguous.
+///
+/// # Arguments
+///
+/// * `signature` - The signature.
+///
+/// # Returns
+///
+/// The virtual address of the match, or `None` if the signature isn't found.
+pub fn find_kernel_signature(signature: &Signature) -> Option<u64> {
+    let ntoskrnl = kernel_image();
+    find_image_signature(ntoskrnl.base_va, ntoskrnl.size, signature)
+}
+
+/// Finds the first match of a signature in the image of a kernel module.
+///
+/// # Arguments
+///
+/// * `image_base_va` - The base virtual address of the image.
+/// * `image_size` - The size of the image in memory.
+/// * `signature` - The signature.
+///
+/// # Returns
+///
+/// The virtual address of the match, or `None` if the signature isn't found.
+pub fn find_image_signature(image_base_va: u64, image_size: u64, signature: &Signature) -> Option<u64> {
+    let directory_table_base = PhysicalAddress::kernel_directory_table_base().unwrap_or_else(|| vmread(vmcs::guest::CR3));
+    let image_end = image_base_va.checked_add(image_size)?;
+    let mut address = image_base_va;
+
+    while address < image_end {
+        let result = scan_guest_memory(signature, ScanSpace::Virtual(directory_table_base), address, image_end, 1, MAX_SIGNATURE_SCAN_SIZE);
+
+        if let Some(&function_va) = result.matches.first() {
+            return Some(function_va);
+        }
+
+        address = result.next_address;
+    }
+
+    None
+}
+
+/// Copies the scannable prefix of a range of guest physical memory, page by page.
+///
+/// # Arguments
+///
+/// * `pa` - The guest physical address of the range.
+/// * `length` - The size of the range in bytes.
+/// * `mtrr` - The memory types of the physical memory.
+///
+/// # Returns
+///
+/// The content of the range up to its first page that can't be scanned.
+fn read_scannable_physical_bytes(pa: u64, length: usize, mtrr: &mut Mtrr) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(length);
+
+    while bytes.len() < length {
+        let page_pa = pa + bytes.len() as u64;
+        let chunk_length = (BASE_PAGE_SIZE - (page_pa as usize & (BASE_PAGE_SIZE - 1))).min(length - bytes.len());
+
+        if !is_scannable_physical_page(page_pa & !(BASE_PAGE_SIZE as u64 - 1), mtrr) {
+            break;
+        }
+
+        // The guest physical memory is identity mapped in the host.
+        bytes.extend_from_slice(unsafe { core::slice::from_raw_parts(page_pa as *const u8, chunk_length) });
+    }
+
+    bytes
+}
+
+/// Returns `true` if a guest physical page can be read by a scan: it is identity mapped, write-back, and not memory of
+/// the hypervisor.
+///
+/// # Arguments
+///
+/// * `page_pa` - The guest physical address of the page.
+/// * `mtrr` - The memory types of the physical memory.
+fn is_scannable_physical_page(page_pa: u64, mtrr: &mut Mtrr) -> bool {
+    let page_end = page_pa + BASE_PAGE_SIZE as u64;
+
+    if page_end > IDENTITY_MAP_SIZE {
+        return false;
+    }
+
+    let is_host_memory = SHARED_HOST_CONFIG
+        .read()
+        .allocated_memory_ranges
+        .iter()
+        .any(|&(start, size)| (start as u64) < page_end && page_pa < (start + size) as u64);
+
+    !is_host_memory && mtrr.find(page_pa..page_end) == Some(MemoryType::WriteBack)
+}
@@ -0,0 +1,259 @@
+//! Provides the single-step engine of each logical processor, built on the Monitor Trap Flag (MTF), so the features
+//! executing guest instructions one at a time, e.g., the restoration of the instructions overwritten by a hook, the
+//! MMIO accesses that can't be emulated or the writes watched by the unpacker, share the MTF and the guest RFLAGS.IF
+//! instead of each managing them.
+//!
+//! A feature requests a number of instructions to be stepped from the guest RIP with a completion callback, called
+//! once the instructions have been executed. The guest interrupts are disabled while any request is active, so the
+//! stepped instructions aren't interrupted, and RFLAGS.IF is restored once the last request completes, leaving the
+//! other flags as the stepped instructions left them. Requests nest: a request made while another one is active, e.g.,
+//! by the EPT violation of a stepped instruction, counts the same MTF VM exits, each one being an executed instruction,
+//! and the requests whose instructions have all been executed complete innermost first.
+//!
+//! The completion callbacks are called once the completed requests have been removed, and the MTF and RFLAGS.IF
+//! restored if no request is left, so a callback can request more instructions, e.g., to keep tracing the guest.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::{vmread, vmwrite},
+            vm::Vm,
+        },
+    },
+    log::*,
+    x86::vmx::vmcs::{self, control::PrimaryControls},
+    x86_64::registers::rflags::RFlags,
+};
+
+/// The maximum number of nested single-step requests of a logical processor.
+pub const MAX_SINGLE_STEP_DEPTH: usize = 4;
+
+/// The callback called once the instructions of a single-step request have been executed and the request removed.
+pub type SingleStepCompletion = fn(vm: &mut Vm, context: SingleStepContext) -> Result<(), HypervisorError>;
+
+/// The feature that made a single-step request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SingleStepOwner {
+    /// The instructions overwritten by a hook, or an access to a hooked page, executed from the original guest page.
+    HookRestoration,
+
+    /// An access to an intercepted MMIO page which can't be emulated, e.g., the HPET.
+    MmioAccess,
+
+    /// A write to a page watched by the unpacker.
+    UnpackerWrite,
+
+    /// An instruction of a range traced by the execution tracer.
+    ExecutionTrace,
+
+    /// An access to a protected range, allowed or redirected to its decoy page.
+    ProtectedMemoryAccess,
+
+    /// An access denied by an alternate EPT view, executed in the primary EPT.
+    EptViewAccess,
+}
+
+/// The values a feature hands to the completion callback of its request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SingleStepContext {
+    /// The guest physical address of the page the stepped instructions access.
+    pub guest_page_pa: u64,
+
+    /// The guest physical address of a second page, e.g., the next page of a hook crossing the page boundary.
+    pub guest_next_page_pa: Option<u64>,
+}
+
+/// A single-step request.
+#[derive(Debug, Clone, Copy)]
+struct SingleStepRequest {
+    /// The feature that made the request.
+    owner: SingleStepOwner,
+
+    /// The guest RIP the instructions are stepped from.
+    guest_rip: u64,
+
+    /// The number of instructions still to be executed.
+    remaining_instructions: u64,
+
+    /// The values handed to the completion callback.
+    context: SingleStepContext,
+
+    /// The callback called once the instructions have been executed.
+    completion: SingleStepCompletion,
+}
+
+/// The single-step state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SingleStepEngine {
+    /// The active requests, outermost first.
+    requests: [Option<SingleStepRequest>; MAX_SINGLE_STEP_DEPTH],
+
+    /// The number of active requests.
+    depth: usize,
+
+    /// Whether RFLAGS.IF was set before the outermost request disabled the interrupts.
+    interrupts_enabled: bool,
+}
+
+impl SingleStepEngine {
+    /// Creates an engine without active requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if instructions are being stepped.
+    pub fn is_active(&self) -> bool {
+        self.depth != 0
+    }
+
+    /// Returns `true` if instructions are being stepped for a feature.
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The feature.
+    pub fn is_stepping_for(&self, owner: SingleStepOwner) -> bool {
+        self.requests[..self.depth].iter().flatten().any(|request| request.owner == owner)
+    }
+}
+
+/// Starts stepping instructions from the guest RIP, the completion callback being called once they have been
+/// executed.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `owner` - The feature making the request.
+/// * `instruction_count` - The number of instructions to step, at least 1.
+/// * `context` - The values handed to the completion callback.
+/// * `completion` - The callback called once the instructions have been executed.
+///
+/// # Returns
+///
+/// * `Ok(())` - The instructions are stepped once the guest resumes.
+/// * `Err(HypervisorError::SingleStepTooDeep)` - If `MAX_SINGLE_STEP_DEPTH` requests are active.
+pub fn single_step(
+    vm: &mut Vm,
+    owner: SingleStepOwner,
+    instruction_count: u64,
+    context: SingleStepContext,
+    completion: SingleStepCompletion,
+) -> Result<(), HypervisorError> {
+    let engine = &mut vm.single_step;
+
+    if engine.depth >= MAX_SINGLE_STEP_DEPTH {
+        error!("Single-step request of {:?} nested too deep at RIP: {:#x}", owner, vm.guest_registers.rip);
+        return Err(HypervisorError::SingleStepTooDeep);
+    }
+
+    trace!("Single-stepping {} instructions for {:?} from RIP: {:#x}, depth: {}", instruction_count, owner, vm.guest_registers.rip, engine.depth);
+
+    engine.requests[engine.depth] = Some(SingleStepRequest {
+        owner,
+        guest_rip: vm.guest_registers.rip,
+        remaining_instructions: instruction_count.max(1),
+        context,
+        completion,
+    });
+    engine.depth += 1;
+
+    if engine.depth == 1 {
+        let interrupts_enabled = set_guest_interrupt_flag(vm, false);
+        vm.single_step.interrupts_enabled = interrupts_enabled;
+        set_monitor_trap_flag(true);
+    }
+
+    Ok(())
+}
+
+/// Counts an executed instruction for the active requests on a Monitor Trap Flag (MTF) VM exit, and completes the
+/// requests whose instructions have all been executed, innermost first.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// * `Ok(())` - The instruction has been counted.
+/// * `Err(HypervisorError::MtfCounterNotSet)` - If no request is active.
+/// * `Err(HypervisorError)` - The error of a completion callback, the request being completed anyway.
+pub fn complete_single_step(vm: &mut Vm) -> Result<(), HypervisorError> {
+    let engine = &mut vm.single_step;
+
+    if engine.depth == 0 {
+        error!("No active single-step request found, possibly an error in state management.");
+        return Err(HypervisorError::MtfCounterNotSet);
+    }
+
+    for request in engine.requests[..engine.depth].iter_mut().flatten() {
+        request.remaining_instructions = request.remaining_instructions.saturating_sub(1);
+    }
+
+    let mut completed_requests = [None; MAX_SINGLE_STEP_DEPTH];
+
+    while engine.depth != 0 {
+        let depth = engine.depth - 1;
+
+        let Some(request) = engine.requests[depth].filter(|request| request.remaining_instructions == 0) else {
+            break;
+        };
+
+        trace!("Single-step of {:?} from RIP: {:#x} completed at RIP: {:#x}", request.owner, request.guest_rip, vm.guest_registers.rip);
+
+        engine.requests[depth] = None;
+        engine.depth = depth;
+        completed_requests[depth] = Some(request);
+    }
+
+    if engine.depth == 0 {
+        let interrupts_enabled = engine.interrupts_enabled;
+        set_monitor_trap_flag(false);
+        set_guest_interrupt_flag(vm, interrupts_enabled);
+    }
+
+    let mut result = Ok(());
+
+    // The outer requests still complete if a callback fails.
+    for request in completed_requests.iter().rev().flatten() {
+        result = result.and((request.completion)(vm, request.context));
+    }
+
+    result
+}
+
+/// Sets the monitor trap flag
+///
+/// # Arguments
+///
+/// * `set` - A flag indicating whether to set the monitor trap flag.
+fn set_monitor_trap_flag(set: bool) {
+    let mut primary_controls = PrimaryControls::from_bits_truncate(vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+    primary_controls.set(PrimaryControls::MONITOR_TRAP_FLAG, set);
+
+    vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
+    trace!("Monitor Trap Flag set to: {}", set);
+}
+
+/// Sets or clears the Interrupt Flag (IF) in the guest's RFLAGS register, leaving the other flags unchanged.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `enable` - Whether the guest interrupts are enabled.
+///
+/// # Returns
+///
+/// Whether the Interrupt Flag was set before.
+fn set_guest_interrupt_flag(vm: &mut Vm, enable: bool) -> bool {
+    let mut rflags = RFlags::from_bits_retain(vmread(vmcs::guest::RFLAGS));
+    let was_enabled = rflags.contains(RFlags::INTERRUPT_FLAG);
+
+    rflags.set(RFlags::INTERRUPT_FLAG, enable);
+
+    vmwrite(vmcs::guest::RFLAGS, rflags.bits());
+    vm.guest_registers.rflags = rflags.bits();
+    trace!("Guest RFLAGS.IF set to: {}", enable);
+
+    was_enabled
+}
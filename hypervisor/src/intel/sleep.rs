@@ -0,0 +1,530 @@
+//! Provides the survival of the hypervisor across the S3 sleep state (suspend to RAM), which powers the processors off
+//! and loses the VMX state, so the hypervisor would otherwise be gone on the wake.
+//!
+//! The write of the guest to a PM1 control register entering S3 is intercepted with the other sleep requests (see the
+//! `reset` module). The firmware waking vector of the FACS, where the firmware jumps in real mode on the wake, is
+//! replaced by a trampoline below 1MB, then the logical processor leaves VMX operation and completes the write itself.
+//! On the wake, the trampoline enters long mode with its own identity map and a stack reserved at boot, restores the
+//! control registers of the host and virtualizes the logical processor again, its guest starting at the original
+//! waking vector as after a SIPI. If the platform doesn't enter the sleep state, e.g., because of a pending wake event,
+//! the logical processor enters VMX operation again and the guest continues after the write.
+//!
+//! Only the logical processor entering the sleep state is virtualized again, the others being restarted natively by the
+//! guest after the wake, and its per-processor state, e.g., its EPT, starts over as on the first virtualization. A
+//! 64-bit waking vector (X_FIRMWARE_WAKING_VECTOR) isn't supported, the hypervisor being lost on the wake. The
+//! hibernation (S4) needs nothing here, as the hypervisor is loaded again on the boot preceding the resume.
+//!
+//! Reference: Advanced Configuration and Power Interface (ACPI) Specification: 16.3 Initialization
+
+use {
+    crate::{
+        acpi::SHARED_ACPI_TABLES,
+        error::HypervisorError,
+        intel::{
+            broadcast::reset_broadcasts,
+            capture::GuestRegisters,
+            reset::{PM1_CONTROL_SLEEP_ENABLE, PM1_CONTROL_SLEEP_TYPE_SHIFT},
+            state::GuestActivityState,
+            support::{cr0, cr0_write, cr4, cr4_write, rdmsr, rdtsc, vmclear, vmptrld, vmwrite, vmxoff, vmxon, wbinvd, wrmsr},
+            timing::tsc_frequency_hz,
+            vm::Vm,
+            vmexit::init::{adjust_guest_cr0, reset_guest_state},
+        },
+        vmm::start_hypervisor,
+    },
+    core::{
+        arch::global_asm,
+        hint::spin_loop,
+        mem,
+        ptr::{copy_nonoverlapping, read_unaligned, write_bytes, write_unaligned},
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        controlregs::Cr0,
+        cpuid::CpuId,
+        io::{outl, outw},
+        msr,
+        vmx::vmcs,
+    },
+    x86_64::registers::control::Cr4Flags,
+};
+
+/// The number of pages of the trampoline: its code and data, then the PML4 and the PDPT of its identity map.
+pub const TRAMPOLINE_PAGES: usize = 3;
+
+/// The highest physical address of the trampoline, entered in real mode.
+pub const TRAMPOLINE_MAX_PA: u64 = 0xFFFFF;
+
+/// The S3 sleep state.
+const S3_SLEEP_STATE: u8 = 3;
+
+/// The time in milliseconds the platform is given to enter the sleep state, before the guest is resumed.
+const SLEEP_ENTRY_TIMEOUT_MS: u64 = 1000;
+
+/// The offset of the length of the FACS.
+const FACS_LENGTH_OFFSET: u64 = 4;
+
+/// The offset of the firmware waking vector in the FACS, a real mode address.
+const FACS_FIRMWARE_WAKING_VECTOR_OFFSET: u64 = 12;
+
+/// The offset of the 64-bit firmware waking vector in the FACS, taking precedence over the firmware waking vector.
+const FACS_X_FIRMWARE_WAKING_VECTOR_OFFSET: u64 = 24;
+
+/// The offset of the `TrampolineData` in the trampoline, after the jump to its code.
+const TRAMPOLINE_DATA_OFFSET: usize = 8;
+
+/// The limit of the GDT of the trampoline: the null, 64-bit code and data descriptors.
+const TRAMPOLINE_GDT_LIMIT: u16 = 3 * 8 - 1;
+
+/// The selector of the 64-bit code segment of the GDT of the trampoline.
+const TRAMPOLINE_CODE_SELECTOR: u16 = 0x08;
+
+/// The present and writable bits of a paging-structure entry.
+const PAGE_PRESENT_WRITABLE: u64 = 0b11;
+
+/// The page size bit of a PDPT entry, mapping a 1GB page.
+const PAGE_SIZE_1GB: u64 = 1 << 7;
+
+/// The value of CR0 the firmware enters the waking vector with: real mode with the caches enabled.
+const WAKING_CR0: Cr0 = Cr0::CR0_EXTENSION_TYPE;
+
+lazy_static! {
+    /// A globally shared instance of `SleepResume`, protected by a mutex.
+    ///
+    /// The trampoline is installed at boot by `SleepResume::initialize_shared_sleep_resume`, before the processors are virtualized.
+    pub static ref SHARED_SLEEP_RESUME: Mutex<SleepResume> = Mutex::new(SleepResume::new());
+}
+
+extern "efiapi" {
+    /// The real mode entry of the trampoline, copied below 1MB and given to the firmware as the waking vector. It's
+    /// never called in place.
+    fn sleep_trampoline();
+
+    /// The 64-bit code of the trampoline, reached through a far jump from real mode.
+    fn sleep_trampoline_long_mode();
+
+    /// The GDT of the trampoline.
+    fn sleep_trampoline_gdt();
+
+    /// The end of the trampoline.
+    fn sleep_trampoline_end();
+}
+
+/// The data of the trampoline, read in real mode then in long mode.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct TrampolineData {
+    /// The limit of the GDT, loaded in real mode with the base.
+    gdtr_limit: u16,
+
+    /// The physical address of the GDT.
+    gdtr_base: u32,
+
+    /// The physical address of the PML4 of the identity map.
+    cr3: u32,
+
+    /// The physical address of the 64-bit code, loaded with the code selector by the far jump.
+    long_mode_entry: u32,
+
+    /// The selector of the 64-bit code segment.
+    code_selector: u16,
+
+    /// The top of the stack reserved at boot.
+    stack_top: u64,
+
+    /// The address of `resume_from_sleep`.
+    entry: u64,
+}
+
+/// The trampoline re-virtualizing the logical processor on the wake from S3, and the state it's entered with.
+#[derive(Debug, Clone, Copy)]
+pub struct SleepResume {
+    /// The physical address of the trampoline, 0 if it isn't installed.
+    trampoline_pa: u64,
+
+    /// The physical address of the FACS.
+    facs_pa: u64,
+
+    /// The I/O ports of the ACPI PM1a and PM1b control registers, if present.
+    pm1_control_ports: [Option<u16>; 2],
+
+    /// The values of the sleep type fields of the PM1a and PM1b control registers entering S3.
+    s3_sleep_types: [u8; 2],
+
+    /// The waking vector of the guest, replaced by the trampoline while entering S3.
+    guest_waking_vector: u32,
+
+    /// The CR0 of the host while entering S3.
+    host_cr0: u64,
+
+    /// The CR4 of the host while entering S3.
+    host_cr4: u64,
+
+    /// The IA32_EFER of the host while entering S3.
+    host_efer: u64,
+
+    /// Whether the logical processor woke from S3, its guest entering the waking vector.
+    is_resuming: bool,
+}
+
+impl SleepResume {
+    /// Creates a new sleep resume state without a trampoline.
+    fn new() -> Self {
+        Self {
+            trampoline_pa: 0,
+            facs_pa: 0,
+            pm1_control_ports: [None; 2],
+            s3_sleep_types: [0; 2],
+            guest_waking_vector: 0,
+            host_cr0: 0,
+            host_cr4: 0,
+            host_efer: 0,
+            is_resuming: false,
+        }
+    }
+
+    /// Discovers the FACS and the sleep types of S3 through the ACPI tables, installs the trampoline and stores them in
+    /// `SHARED_SLEEP_RESUME`.
+    ///
+    /// This must be called after the ACPI tables have been parsed and before the processors are virtualized.
+    ///
+    /// # Arguments
+    ///
+    /// * `trampoline_pa` - The physical address of `TRAMPOLINE_PAGES` pages below `TRAMPOLINE_MAX_PA`.
+    /// * `stack_top` - The top of the stack of the logical processor on the wake, `STACK_PAGES_PER_PROCESSOR` pages large.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the trampoline has been installed, otherwise `Err(HypervisorError)`.
+    pub fn initialize_shared_sleep_resume(trampoline_pa: u64, stack_top: u64) -> Result<(), HypervisorError> {
+        // The identity map of the trampoline maps the first 512GB, as the host paging does, with 1GB pages.
+        let has_1gib_pages = CpuId::new()
+            .get_extended_processor_and_feature_identifiers()
+            .is_some_and(|features| features.has_1gib_pages());
+
+        if !has_1gib_pages || trampoline_pa + (TRAMPOLINE_PAGES * BASE_PAGE_SIZE) as u64 > TRAMPOLINE_MAX_PA + 1 {
+            return Err(HypervisorError::SleepResumeUnsupported);
+        }
+
+        let acpi_tables = SHARED_ACPI_TABLES.read();
+        let facs_pa = acpi_tables.facs_pa().ok_or(HypervisorError::AcpiTableNotFound)?;
+        let (sleep_type_a, sleep_type_b) = acpi_tables.sleep_types(S3_SLEEP_STATE).ok_or(HypervisorError::SleepResumeUnsupported)?;
+        let pm1_control_ports = acpi_tables.pm1_control_ports();
+        drop(acpi_tables);
+
+        unsafe { install_trampoline(trampoline_pa, stack_top) };
+
+        *SHARED_SLEEP_RESUME.lock() = Self {
+            trampoline_pa,
+            facs_pa,
+            pm1_control_ports,
+            s3_sleep_types: [sleep_type_a, sleep_type_b],
+            ..Self::new()
+        };
+
+        debug!("Sleep resume: trampoline: {:#x}, FACS: {:#x}, PM1 control ports: {:x?}", trampoline_pa, facs_pa, pm1_control_ports);
+
+        Ok(())
+    }
+
+    /// Identifies a write to an I/O port as a transition to S3.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port.
+    /// * `size` - The size of the access in bytes.
+    /// * `value` - The value written by the guest.
+    fn is_s3_request(&self, port: u16, size: u64, value: u64) -> bool {
+        // Accesses narrower than 2 bytes don't reach the sleep enable bit.
+        if self.trampoline_pa == 0 || size < 2 || value & PM1_CONTROL_SLEEP_ENABLE == 0 {
+            return false;
+        }
+
+        let sleep_type = ((value >> PM1_CONTROL_SLEEP_TYPE_SHIFT) & 0x7) as u8;
+
+        self.pm1_control_ports
+            .iter()
+            .zip(self.s3_sleep_types)
+            .any(|(&control_port, s3_sleep_type)| control_port == Some(port) && sleep_type == s3_sleep_type)
+    }
+
+    /// Replaces the waking vector of the guest by the trampoline and saves the control registers of the host.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the waking vector has been replaced, otherwise `Err(HypervisorError::SleepResumeUnsupported)`
+    /// if the guest set no waking vector or a 64-bit one.
+    fn hook_waking_vector(&mut self) -> Result<(), HypervisorError> {
+        let facs_length = unsafe { read_unaligned((self.facs_pa + FACS_LENGTH_OFFSET) as *const u32) };
+        let x_waking_vector = match facs_length as u64 >= FACS_X_FIRMWARE_WAKING_VECTOR_OFFSET + 8 {
+            true => unsafe { read_unaligned((self.facs_pa + FACS_X_FIRMWARE_WAKING_VECTOR_OFFSET) as *const u64) },
+            false => 0,
+        };
+
+        let waking_vector_pa = (self.facs_pa + FACS_FIRMWARE_WAKING_VECTOR_OFFSET) as *mut u32;
+        let waking_vector = unsafe { read_unaligned(waking_vector_pa) };
+
+        if x_waking_vector != 0 || waking_vector == 0 {
+            return Err(HypervisorError::SleepResumeUnsupported);
+        }
+
+        // The write may be retried after the platform didn't enter the sleep state, the waking vector being hooked already.
+        if waking_vector as u64 != self.trampoline_pa {
+            self.guest_waking_vector = waking_vector;
+        }
+
+        unsafe { write_unaligned(waking_vector_pa, self.trampoline_pa as u32) };
+
+        self.host_cr0 = cr0().bits() as u64;
+        self.host_cr4 = cr4();
+        self.host_efer = rdmsr(msr::IA32_EFER);
+
+        Ok(())
+    }
+
+    /// Restores the waking vector of the guest in the FACS.
+    fn restore_waking_vector(&self) {
+        let waking_vector_pa = (self.facs_pa + FACS_FIRMWARE_WAKING_VECTOR_OFFSET) as *mut u32;
+        unsafe { write_unaligned(waking_vector_pa, self.guest_waking_vector) };
+    }
+}
+
+/// Handles a write of the guest to an I/O port if it enters S3, replacing the waking vector by the trampoline, then
+/// leaving VMX operation and completing the write.
+///
+/// This is called after the reset policy allowed the request, and never returns if the platform enters the sleep state.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `port` - The I/O port.
+/// * `size` - The size of the access in bytes.
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// `true` if the write has been completed without entering the sleep state, `false` if it must be passed through to
+/// the hardware, e.g., if it doesn't enter S3.
+pub fn enter_sleep_state(vm: &mut Vm, port: u16, size: u64, value: u64) -> bool {
+    {
+        let mut sleep_resume = SHARED_SLEEP_RESUME.lock();
+
+        if !sleep_resume.is_s3_request(port, size, value) {
+            return false;
+        }
+
+        if let Err(e) = sleep_resume.hook_waking_vector() {
+            warn!("Failed to hook the waking vector, the hypervisor is lost on the wake from S3: {:?}", e);
+            return false;
+        }
+    }
+
+    info!("Entering S3, leaving VMX operation until the wake");
+    log::logger().flush();
+
+    // The guest flushed the caches before the write, but not since the waking vector was replaced.
+    wbinvd();
+    vmclear(&vm.vmcs_region as *const _ as _);
+
+    if let Err(e) = vmxoff() {
+        error!("Failed to leave VMX operation: {:?}", e);
+        vmptrld(&vm.vmcs_region as *const _ as _);
+        vm.has_launched = false;
+        return false;
+    }
+
+    unsafe {
+        match size {
+            2 => outw(port, value as u16),
+            _ => outl(port, value as u32),
+        }
+    }
+
+    let deadline_tsc = rdtsc().wrapping_add(tsc_frequency_hz() / 1000 * SLEEP_ENTRY_TIMEOUT_MS);
+    while rdtsc() < deadline_tsc {
+        spin_loop();
+    }
+
+    // The VMCS has been cleared, so the next VM entry launches it again.
+    vmxon(&vm.vmxon_region as *const _ as _);
+    vmptrld(&vm.vmcs_region as *const _ as _);
+    vm.has_launched = false;
+
+    SHARED_SLEEP_RESUME.lock().restore_waking_vector();
+
+    warn!("S3 not entered after {} ms, resuming the guest", SLEEP_ENTRY_TIMEOUT_MS);
+
+    true
+}
+
+/// Makes the guest of the current logical processor enter its waking vector, if the logical processor woke from S3.
+///
+/// This is called once the VMCS is active, before the first VM entry. The firmware enters the waking vector in real
+/// mode with CS:IP set to (vector >> 4):(vector & 0xF), otherwise in the state following an INIT.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn enter_waking_vector(vm: &mut Vm) {
+    let waking_vector = {
+        let mut sleep_resume = SHARED_SLEEP_RESUME.lock();
+
+        if !mem::take(&mut sleep_resume.is_resuming) {
+            return;
+        }
+
+        sleep_resume.guest_waking_vector as u64
+    };
+
+    debug!("Entering the waking vector of the guest: {:#x}", waking_vector);
+
+    reset_guest_state(vm);
+
+    vmwrite(vmcs::control::CR0_READ_SHADOW, WAKING_CR0.bits() as u64);
+    vmwrite(vmcs::guest::CR0, adjust_guest_cr0(WAKING_CR0));
+
+    vmwrite(vmcs::guest::CS_SELECTOR, waking_vector >> 4);
+    vmwrite(vmcs::guest::CS_BASE, (waking_vector >> 4) << 4);
+    vm.guest_registers.rip = waking_vector & 0xF;
+    vmwrite(vmcs::guest::RIP, vm.guest_registers.rip);
+
+    vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::Active as u32);
+}
+
+/// Copies the trampoline to its pages and builds its identity map of the first 512GB.
+///
+/// # Arguments
+///
+/// * `trampoline_pa` - The physical address of the pages of the trampoline.
+/// * `stack_top` - The top of the stack of the logical processor on the wake.
+unsafe fn install_trampoline(trampoline_pa: u64, stack_top: u64) {
+    let start = sleep_trampoline as *const () as usize;
+    let end = sleep_trampoline_end as *const () as usize;
+    let pa_of = |symbol: usize| trampoline_pa + (symbol - start) as u64;
+
+    copy_nonoverlapping(start as *const u8, trampoline_pa as *mut u8, end - start);
+
+    let pml4_pa = trampoline_pa + BASE_PAGE_SIZE as u64;
+    let pdpt_pa = pml4_pa + BASE_PAGE_SIZE as u64;
+
+    let data = TrampolineData {
+        gdtr_limit: TRAMPOLINE_GDT_LIMIT,
+        gdtr_base: pa_of(sleep_trampoline_gdt as *const () as usize) as u32,
+        cr3: pml4_pa as u32,
+        long_mode_entry: pa_of(sleep_trampoline_long_mode as *const () as usize) as u32,
+        code_selector: TRAMPOLINE_CODE_SELECTOR,
+        // The stack is 16-byte aligned before the call of the entry.
+        stack_top: stack_top & !0xF,
+        entry: resume_from_sleep as *const () as u64,
+    };
+    write_unaligned((trampoline_pa + TRAMPOLINE_DATA_OFFSET as u64) as *mut TrampolineData, data);
+
+    let pml4 = pml4_pa as *mut u64;
+    let pdpt = pdpt_pa as *mut u64;
+
+    write_bytes(pml4, 0, 512);
+    pml4.write(pdpt_pa | PAGE_PRESENT_WRITABLE);
+
+    for i in 0..512u64 {
+        pdpt.add(i as usize).write((i << 30) | PAGE_SIZE_1GB | PAGE_PRESENT_WRITABLE);
+    }
+}
+
+/// The entry of the trampoline in long mode on the wake from S3, on the stack reserved at boot.
+///
+/// The control registers of the host are restored, then the logical processor is virtualized again as at boot, its
+/// guest entering the waking vector (see `enter_waking_vector`).
+extern "efiapi" fn resume_from_sleep() -> ! {
+    let (host_cr0, host_cr4, host_efer) = {
+        let mut sleep_resume = SHARED_SLEEP_RESUME.lock();
+        sleep_resume.restore_waking_vector();
+        sleep_resume.is_resuming = true;
+        (sleep_resume.host_cr0, sleep_resume.host_cr4, sleep_resume.host_efer)
+    };
+
+    wrmsr(msr::IA32_EFER, host_efer);
+    cr4_write(host_cr4);
+    cr0_write(host_cr0);
+
+    info!("Woke from S3, virtualizing the logical processor again");
+
+    reset_broadcasts();
+    start_hypervisor(&GuestRegisters::default())
+}
+
+global_asm!(
+    r#"
+// The trampoline entered by the firmware in real mode on the wake from S3, with CS set to its physical address >> 4 and
+// IP to 0, so its data is addressed by its offset. Long mode is entered directly from real mode by enabling paging and
+// protection together.
+.global sleep_trampoline
+.global sleep_trampoline_long_mode
+.global sleep_trampoline_gdt
+.global sleep_trampoline_end
+.code16
+.balign 8
+sleep_trampoline:
+    jmp     sleep_trampoline_real_mode
+
+.balign 8
+sleep_trampoline_data:
+    .space  {data_size}
+
+sleep_trampoline_real_mode:
+    cli
+    mov     ax, cs
+    mov     ds, ax
+    mov     ss, ax
+    lgdt    [{data_offset} + {data_gdtr}]
+
+    mov     eax, {cr4_pae}
+    mov     cr4, eax
+    mov     eax, [{data_offset} + {data_cr3}]
+    mov     cr3, eax
+
+    mov     ecx, {ia32_efer}
+    rdmsr
+    or      eax, {efer_lme}
+    wrmsr
+
+    mov     eax, {cr0_pg_pe}
+    mov     cr0, eax
+
+    // jmp fword ptr [long_mode_entry], the operand-size prefix selecting the 32-bit offset.
+    .byte   0x66, 0xFF, 0x2E
+    .word   {data_offset} + {data_long_mode_entry}
+
+.code64
+sleep_trampoline_long_mode:
+    mov     ax, {data_selector}
+    mov     ds, ax
+    mov     es, ax
+    mov     ss, ax
+    mov     rsp, [rip + sleep_trampoline_data + {data_stack_top}]
+    sub     rsp, 0x20
+    call    [rip + sleep_trampoline_data + {data_entry}]
+    ud2
+
+.balign 8
+sleep_trampoline_gdt:
+    .quad   0
+    .quad   0x00209A0000000000
+    .quad   0x0000920000000000
+sleep_trampoline_end:
+"#,
+    data_offset = const TRAMPOLINE_DATA_OFFSET,
+    data_gdtr = const mem::offset_of!(TrampolineData, gdtr_limit),
+    data_cr3 = const mem::offset_of!(TrampolineData, cr3),
+    data_long_mode_entry = const mem::offset_of!(TrampolineData, long_mode_entry),
+    data_stack_top = const mem::offset_of!(TrampolineData, stack_top),
+    data_entry = const mem::offset_of!(TrampolineData, entry),
+    data_size = const mem::size_of::<TrampolineData>(),
+    data_selector = const TRAMPOLINE_CODE_SELECTOR + 8,
+    cr4_pae = const Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits(),
+    ia32_efer = const msr::IA32_EFER,
+    efer_lme = const 1 << 8,
+    cr0_pg_pe = const Cr0::CR0_ENABLE_PAGING.union(Cr0::CR0_EXTENSION_TYPE).union(Cr0::CR0_PROTECTED_MODE).bits(),
+);
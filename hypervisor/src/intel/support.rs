@@ -1,7 +1,13 @@
 #![allow(dead_code)]
 
 use {
-    crate::{error::HypervisorError, intel::vmcs::Vmcs},
+    crate::{
+        error::HypervisorError,
+        intel::{
+            invvpid::{invvpid_address_range, invvpid_single_context},
+            vmcs::Vmcs,
+        },
+    },
     core::arch::asm,
 };
 
@@ -38,6 +44,25 @@ pub fn vmread(field: u32) -> u64 {
     unsafe { x86::bits64::vmx::vmread(field) }.unwrap_or(0)
 }
 
+/// Returns the Virtual Processor Identifier (VPID) of the current VMCS.
+pub fn current_vpid() -> u16 {
+    vmread(x86::vmx::vmcs::control::VPID) as u16
+}
+
+/// Invalidates the cached translations of the current VPID.
+pub fn invvpid_current_context() {
+    invvpid_single_context(current_vpid());
+}
+
+/// Invalidates the cached translations of a single linear address for the current VPID.
+///
+/// # Arguments
+///
+/// * `linear_address` - The linear address whose cached translations are to be invalidated.
+pub fn invvpid_current_address(linear_address: u64) {
+    invvpid_address_range(current_vpid(), linear_address..linear_address + 1);
+}
+
 /// Write to a specified field in a VMCS.
 pub fn vmwrite<T: Into<u64>>(field: u32, val: T)
 where
@@ -46,6 +71,11 @@ where
     unsafe { x86::bits64::vmx::vmwrite(field, u64::from(val)) }.unwrap();
 }
 
+/// Read the Extended Control Register XCR0. Only supported if CR4_ENABLE_OS_XSAVE is set.
+pub fn xgetbv() -> u64 {
+    x86_64::registers::xcontrol::XCr0::read_raw()
+}
+
 /// Write to Extended Control Register XCR0. Only supported if CR4_ENABLE_OS_XSAVE is set.
 pub fn xsetbv(val: u64) {
     unsafe {
@@ -66,6 +96,25 @@ pub fn rdtsc() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
 }
 
+/// Returns a random value from the hardware random number generator, or `None` if it's still exhausted after the
+/// retries recommended by Intel.
+pub fn rdrand() -> Option<u64> {
+    const RDRAND_RETRIES: usize = 10;
+
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let success: u8;
+
+        unsafe { asm!("rdrand {0}", "setc {1}", out(reg) value, out(reg_byte) success, options(nomem, nostack)) };
+
+        if success != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
 /// Reads an MSR.
 pub fn rdmsr(msr: u32) -> u64 {
     unsafe { x86::msr::rdmsr(msr) }
@@ -91,6 +140,11 @@ pub fn cr3() -> u64 {
     unsafe { x86::controlregs::cr3() }
 }
 
+/// Writes a value to the CR3 register.
+pub fn cr3_write(val: u64) {
+    unsafe { x86::controlregs::cr3_write(val) };
+}
+
 /// Reads the CR4 register.
 pub fn cr4() -> u64 {
     x86_64::registers::control::Cr4::read_raw()
@@ -113,6 +167,11 @@ pub fn read_effective_guest_cr4() -> u64 {
     vmread(x86::vmx::vmcs::control::CR4_READ_SHADOW) & mask | vmread(x86::vmx::vmcs::guest::CR4) & !mask
 }
 
+/// Reads the CR2 register.
+pub fn cr2() -> u64 {
+    unsafe { x86::controlregs::cr2() as u64 }
+}
+
 /// Writes a value to the Cr2 register.
 pub fn cr2_write(val: u64) {
     unsafe { x86::controlregs::cr2_write(val) };
@@ -144,6 +203,11 @@ pub fn dr6_write(val: u64) {
     unsafe { x86::debugregs::dr6_write(dr6) };
 }
 
+/// Writes a value to the DR7 register.
+pub fn dr7_write(val: u64) {
+    unsafe { x86::debugregs::dr7_write(x86::debugregs::Dr7(val as _)) };
+}
+
 /// Reads the DR0 register.
 pub fn dr0_read() -> u64 {
     unsafe { x86::debugregs::dr0() as u64 }
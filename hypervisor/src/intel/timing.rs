@@ -0,0 +1,309 @@
+//! Provides timing normalization of the secondary clock sources: the High Precision Event Timer (HPET)
+//! and the ACPI Power Management (PM) timer.
+//!
+//! Comparing the time an instruction takes on different clocks is a common hypervisor detection technique,
+//! e.g., timing CPUID with the HPET instead of the TSC. Each logical processor accounts the time it spends
+//! handling VM exits, and reads of the PM timer port (I/O bitmap) and of the HPET main counter (EPT) are
+//! intercepted and adjusted so this time is hidden from the guest.
+//!
+//! The hidden time is the largest amount of time spent in VMX root operation by any single logical processor,
+//! so the adjusted clocks stay monotonic across processors.
+//!
+//! The CMOS ports of the RTC are intercepted as well, see the `rtc` module.
+//!
+//! While the deterministic mode is enabled, the PM timer and the HPET main counter are derived from its virtual TSC
+//! instead, see the `determinism` module.
+
+use {
+    crate::{
+        acpi::SHARED_ACPI_TABLES,
+        error::HypervisorError,
+        intel::{
+            bitmap::IoOperation, determinism::read_virtual_clock, ept::AccessType, hooks::hook_manager::SHARED_HOOK_MANAGER, rtc::intercept_rtc,
+            support::rdtsc, vm::Vm,
+        },
+    },
+    core::{
+        ptr::read_volatile,
+        sync::atomic::{AtomicU64, Ordering},
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::RwLock,
+    x86::{bits64::paging::PAddr, cpuid::CpuId, io::inl},
+};
+
+/// The frequency of the ACPI PM timer, in Hz.
+pub const PM_TIMER_FREQUENCY_HZ: u64 = 3_579_545;
+
+/// The offset of the General Capabilities and ID Register of the HPET.
+const HPET_GENERAL_CAPABILITIES: u64 = 0x00;
+
+/// The offset of the Main Counter Value Register of the HPET.
+pub const HPET_MAIN_COUNTER: u64 = 0xF0;
+
+/// The frequency of the TSC assumed when it can't be determined, in Hz.
+const DEFAULT_TSC_FREQUENCY_HZ: u64 = 3_000_000_000;
+
+/// The number of femtoseconds per second, used to convert the HPET period to a frequency.
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// The calibration period of the TSC against the PM timer, in fractions of a second (10 ms).
+const CALIBRATION_PERIODS_PER_SECOND: u64 = 100;
+
+/// The largest amount of time spent handling VM exits by a single logical processor, in TSC ticks.
+static HIDDEN_TSC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// A globally shared instance of `ClockSources`, protected by a read-write lock.
+    ///
+    /// The clock sources are discovered once at boot by `ClockSources::initialize_shared_clock_sources`.
+    pub static ref SHARED_CLOCK_SOURCES: RwLock<ClockSources> = RwLock::new(ClockSources::default());
+}
+
+/// Describes the secondary clock sources of the platform.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClockSources {
+    /// The I/O port of the ACPI PM timer, if present.
+    pub pm_timer_port: Option<u16>,
+
+    /// The mask of the valid bits of the PM timer (24 or 32 bits).
+    pub pm_timer_mask: u32,
+
+    /// The physical address of the HPET registers, if present.
+    pub hpet_base_pa: Option<u64>,
+
+    /// The frequency of the HPET main counter, in Hz.
+    pub hpet_frequency_hz: u64,
+
+    /// The frequency of the TSC in Hz, calibrated against the PM timer.
+    pub tsc_frequency_hz: u64,
+}
+
+impl ClockSources {
+    /// Discovers the clock sources through the ACPI tables, calibrates the TSC and stores them in `SHARED_CLOCK_SOURCES`.
+    ///
+    /// This must be called after the ACPI tables have been parsed and before the processors are virtualized.
+    pub fn initialize_shared_clock_sources() {
+        let acpi_tables = SHARED_ACPI_TABLES.read();
+        let mut clock_sources = ClockSources {
+            pm_timer_port: acpi_tables.pm_timer_port(),
+            pm_timer_mask: match acpi_tables.is_pm_timer_32bit() {
+                true => u32::MAX,
+                false => 0xFF_FFFF,
+            },
+            ..Default::default()
+        };
+
+        if let Some(hpet_base_pa) = acpi_tables.hpet_base_address() {
+            // COUNTER_CLK_PERIOD (bits 63:32) is the period of the main counter in femtoseconds.
+            let period_fs = unsafe { read_volatile((hpet_base_pa + HPET_GENERAL_CAPABILITIES) as *const u64) } >> 32;
+
+            if let Some(hpet_frequency_hz) = FEMTOSECONDS_PER_SECOND.checked_div(period_fs) {
+                clock_sources.hpet_base_pa = Some(hpet_base_pa);
+                clock_sources.hpet_frequency_hz = hpet_frequency_hz;
+            }
+        }
+
+        if let Some(pm_timer_port) = clock_sources.pm_timer_port {
+            clock_sources.tsc_frequency_hz = calibrate_tsc_frequency(pm_timer_port, clock_sources.pm_timer_mask);
+        }
+
+        debug!("Clock sources: {:#x?}", clock_sources);
+
+        *SHARED_CLOCK_SOURCES.write() = clock_sources;
+    }
+}
+
+/// Calibrates the frequency of the TSC by measuring it against the PM timer for 10 ms.
+///
+/// # Arguments
+///
+/// * `pm_timer_port` - The I/O port of the PM timer.
+/// * `pm_timer_mask` - The mask of the valid bits of the PM timer.
+///
+/// # Returns
+///
+/// The frequency of the TSC in Hz.
+fn calibrate_tsc_frequency(pm_timer_port: u16, pm_timer_mask: u32) -> u64 {
+    let read_pm_timer = || unsafe { inl(pm_timer_port) } & pm_timer_mask;
+    let calibration_ticks = (PM_TIMER_FREQUENCY_HZ / CALIBRATION_PERIODS_PER_SECOND) as u32;
+
+    let start = read_pm_timer();
+    let start_tsc = rdtsc();
+
+    while read_pm_timer().wrapping_sub(start) & pm_timer_mask < calibration_ticks {
+        core::hint::spin_loop();
+    }
+
+    (rdtsc() - start_tsc) * CALIBRATION_PERIODS_PER_SECOND
+}
+
+/// Returns the frequency of the TSC in Hz.
+///
+/// The frequency calibrated against the PM timer is used if available, then the frequency reported by CPUID,
+/// otherwise `DEFAULT_TSC_FREQUENCY_HZ`.
+pub fn tsc_frequency_hz() -> u64 {
+    let calibrated_frequency_hz = SHARED_CLOCK_SOURCES.read().tsc_frequency_hz;
+    if calibrated_frequency_hz != 0 {
+        return calibrated_frequency_hz;
+    }
+
+    let cpuid = CpuId::new();
+
+    if let Some(frequency_hz) = cpuid.get_tsc_info().and_then(|tsc_info| tsc_info.tsc_frequency()) {
+        return frequency_hz;
+    }
+
+    // The processor base frequency is reported in MHz, and matches the TSC frequency on processors with an invariant TSC.
+    match cpuid.get_processor_frequency_info().map(|info| info.processor_base_frequency()) {
+        Some(frequency_mhz) if frequency_mhz != 0 => frequency_mhz as u64 * 1_000_000,
+        _ => DEFAULT_TSC_FREQUENCY_HZ,
+    }
+}
+
+/// Accounts the time spent handling a VM exit on the current logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `exit_tsc` - The TSC value read when the VM exit started being handled.
+pub fn account_exit_time(vm: &mut Vm, exit_tsc: u64) {
+    vm.hidden_tsc_ticks += rdtsc().saturating_sub(exit_tsc);
+    HIDDEN_TSC_TICKS.fetch_max(vm.hidden_tsc_ticks, Ordering::Relaxed);
+}
+
+/// Converts the hidden time to ticks of a clock with the given frequency.
+///
+/// # Arguments
+///
+/// * `clock_frequency_hz` - The frequency of the clock, in Hz.
+/// * `tsc_frequency_hz` - The frequency of the TSC, in Hz.
+///
+/// # Returns
+///
+/// The hidden time in ticks of the clock, or 0 if the TSC hasn't been calibrated.
+fn hidden_clock_ticks(clock_frequency_hz: u64, tsc_frequency_hz: u64) -> u64 {
+    if tsc_frequency_hz == 0 {
+        return 0;
+    }
+
+    (HIDDEN_TSC_TICKS.load(Ordering::Relaxed) as u128 * clock_frequency_hz as u128 / tsc_frequency_hz as u128) as u64
+}
+
+/// Adjusts a PM timer value read by the guest to hide the time spent in VMX root operation, or derives it from the
+/// virtual TSC while the deterministic mode is enabled.
+///
+/// # Arguments
+///
+/// * `value` - The actual value of the PM timer.
+///
+/// # Returns
+///
+/// The value of the PM timer presented to the guest.
+pub fn normalize_pm_timer(value: u32) -> u32 {
+    let clock_sources = SHARED_CLOCK_SOURCES.read();
+
+    if let Some(virtual_ticks) = read_virtual_clock(PM_TIMER_FREQUENCY_HZ) {
+        return virtual_ticks as u32 & clock_sources.pm_timer_mask;
+    }
+
+    let hidden_ticks = hidden_clock_ticks(PM_TIMER_FREQUENCY_HZ, clock_sources.tsc_frequency_hz);
+
+    value.wrapping_sub(hidden_ticks as u32) & clock_sources.pm_timer_mask
+}
+
+/// Adjusts an HPET main counter value read by the guest to hide the time spent in VMX root operation, or derives it
+/// from the virtual TSC while the deterministic mode is enabled.
+///
+/// # Arguments
+///
+/// * `value` - The actual value of the HPET main counter.
+///
+/// # Returns
+///
+/// The value of the HPET main counter presented to the guest.
+pub fn normalize_hpet_counter(value: u64) -> u64 {
+    let clock_sources = SHARED_CLOCK_SOURCES.read();
+
+    if let Some(virtual_ticks) = read_virtual_clock(clock_sources.hpet_frequency_hz) {
+        return virtual_ticks;
+    }
+
+    let hidden_ticks = hidden_clock_ticks(clock_sources.hpet_frequency_hz, clock_sources.tsc_frequency_hz);
+
+    value.wrapping_sub(hidden_ticks)
+}
+
+/// Checks whether a guest page contains the HPET registers.
+///
+/// # Arguments
+///
+/// * `guest_page_pa` - The physical address of the guest page.
+pub fn is_hpet_page(guest_page_pa: u64) -> bool {
+    SHARED_CLOCK_SOURCES
+        .read()
+        .hpet_base_pa
+        .is_some_and(|hpet_base_pa| PAddr::from(hpet_base_pa).align_down_to_base_page().as_u64() == guest_page_pa)
+}
+
+/// Intercepts the guest's reads of the PM timer port, of the HPET registers and of the RTC on the current logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the clock sources are intercepted, otherwise `Err(HypervisorError)`.
+pub fn intercept_clock_sources(vm: &mut Vm) -> Result<(), HypervisorError> {
+    let clock_sources = *SHARED_CLOCK_SOURCES.read();
+
+    if let Some(pm_timer_port) = clock_sources.pm_timer_port {
+        debug!("Intercepting PM timer port: {:#x}", pm_timer_port);
+        vm.io_bitmap.modify_io_interception(pm_timer_port, IoOperation::Hook);
+    }
+
+    if let Some(hpet_base_pa) = clock_sources.hpet_base_pa {
+        debug!("Intercepting HPET registers: {:#x}", hpet_base_pa);
+        set_hpet_page_permissions(vm, AccessType::empty())?;
+    }
+
+    intercept_rtc(vm);
+
+    Ok(())
+}
+
+/// Changes the primary EPT permissions of the page containing the HPET registers.
+///
+/// The page has no permissions while the HPET is intercepted, so every access causes an EPT violation.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `access_type` - The permissions of the page.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the permissions have been changed, otherwise `Err(HypervisorError)`.
+pub fn set_hpet_page_permissions(vm: &mut Vm, access_type: AccessType) -> Result<(), HypervisorError> {
+    let hpet_base_pa = SHARED_CLOCK_SOURCES.read().hpet_base_pa.ok_or(HypervisorError::AcpiTableNotFound)?;
+    let guest_page_pa = PAddr::from(hpet_base_pa).align_down_to_base_page();
+    let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    hook_manager.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
+
+    let pre_alloc_pt = hook_manager
+        .memory_manager
+        .get_page_table_as_mut(guest_large_page_pa.as_u64())
+        .ok_or(HypervisorError::PageTableNotFound)?;
+
+    if vm.primary_ept.is_large_page(guest_page_pa.as_u64()) {
+        vm.primary_ept.split_2mb_to_4kb(guest_large_page_pa.as_u64(), pre_alloc_pt)?;
+    }
+
+    vm.primary_ept
+        .modify_page_permissions(guest_page_pa.as_u64(), access_type, pre_alloc_pt)?;
+    vm.primary_ept.invalidate_ept_cache()
+}
@@ -0,0 +1,229 @@
+//! Provides an asynchronous copy engine for large reads of the memory of a process, such as process dumps or coverage maps.
+//!
+//! Copying several megabytes in a single VM exit would stall the logical processor for tens of milliseconds. Instead, the
+//! copy is split into chunks driven by the VMX-preemption timer: each time the timer expires, one chunk is copied to the
+//! destination buffer of the client, and the progress is published to the shared page so the client can poll it without
+//! causing VM exits. The timer is only armed for the copy while it is in progress on the logical processor.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.1 VMX-Preemption Timer
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            host_config::SHARED_HOST_CONFIG,
+            vm::Vm,
+            vmexit::preemption_timer::{is_preemption_timer_supported, update_preemption_timer},
+        },
+    },
+    core::sync::atomic::{fence, Ordering},
+    log::*,
+    shared::{SharedPage, TransferProgress, TransferStatus},
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The maximum number of bytes copied each time the VMX-preemption timer expires.
+const CHUNK_SIZE: u64 = 0x10000;
+
+/// The number of TSC ticks the guest runs between two chunks.
+pub const TIMER_INTERVAL_TSC_TICKS: u64 = 0x40000;
+
+/// A page of zeroes, copied to the destination buffer in place of the bytes that can't be read from the process.
+static ZERO_PAGE: [u8; BASE_PAGE_SIZE] = [0; BASE_PAGE_SIZE];
+
+/// A background copy from the memory of a process to the buffer of the client.
+#[derive(Debug, Clone, Copy)]
+pub struct AsyncTransfer {
+    /// The CR3 of the process the memory is read from.
+    source_cr3: u64,
+
+    /// The virtual address of the memory read from the process.
+    source_va: u64,
+
+    /// The CR3 of the client, captured when the copy is started as another process may run when the timer expires.
+    destination_cr3: u64,
+
+    /// The virtual address of the destination buffer of the client, which must be locked in memory.
+    destination_va: u64,
+
+    /// The total number of bytes to copy.
+    total_bytes: u64,
+
+    /// The number of bytes copied so far.
+    bytes_copied: u64,
+
+    /// The number of bytes that couldn't be read from the process and have been zero-filled.
+    unreadable_bytes: u64,
+}
+
+impl AsyncTransfer {
+    /// Creates a new background copy.
+    ///
+    /// # Arguments
+    ///
+    /// * `source_cr3` - The CR3 of the process the memory is read from.
+    /// * `source_va` - The virtual address of the memory read from the process.
+    /// * `destination_cr3` - The CR3 of the client.
+    /// * `destination_va` - The virtual address of the destination buffer of the client.
+    /// * `total_bytes` - The number of bytes to copy.
+    pub fn new(source_cr3: u64, source_va: u64, destination_cr3: u64, destination_va: u64, total_bytes: u64) -> Self {
+        Self {
+            source_cr3,
+            source_va,
+            destination_cr3,
+            destination_va,
+            total_bytes,
+            bytes_copied: 0,
+            unreadable_bytes: 0,
+        }
+    }
+
+    /// Returns `true` if all the bytes have been copied.
+    pub fn is_complete(&self) -> bool {
+        self.bytes_copied >= self.total_bytes
+    }
+
+    /// Copies the next chunk to the destination buffer, without crossing a page boundary of either buffer per copy.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Some(())` if the chunk has been copied, or `None` if the destination buffer couldn't be written.
+    fn copy_chunk(&mut self) -> Option<()> {
+        let chunk_end = self.total_bytes.min(self.bytes_copied + CHUNK_SIZE);
+
+        while self.bytes_copied < chunk_end {
+            let source_va = self.source_va + self.bytes_copied;
+            let destination_va = self.destination_va + self.bytes_copied;
+
+            let length = page_remaining(source_va)
+                .min(page_remaining(destination_va))
+                .min(chunk_end - self.bytes_copied) as usize;
+
+            let data = match PhysicalAddress::read_guest_virt_slice_with_explicit_cr3(source_va as *const u8, length, self.source_cr3) {
+                Some(data) => data,
+                None => {
+                    self.unreadable_bytes += length as u64;
+                    &ZERO_PAGE[..length]
+                }
+            };
+
+            PhysicalAddress::write_guest_virt_slice_with_explicit_cr3(destination_va as *mut u8, data, self.destination_cr3)?;
+
+            self.bytes_copied += length as u64;
+        }
+
+        Some(())
+    }
+
+    /// Returns the progress of the copy, to be published to the shared page.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The status of the copy.
+    fn progress(&self, status: TransferStatus) -> TransferProgress {
+        TransferProgress {
+            bytes_copied: self.bytes_copied,
+            total_bytes: self.total_bytes,
+            unreadable_bytes: self.unreadable_bytes,
+            status: status.to_u64(),
+        }
+    }
+}
+
+/// Starts a background copy on the current logical processor and arms the VMX-preemption timer.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `transfer` - The copy to start.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the copy has been started, otherwise `Err(HypervisorError)`.
+pub fn start_async_transfer(vm: &mut Vm, transfer: AsyncTransfer) -> Result<(), HypervisorError> {
+    if vm.async_transfer.is_some() {
+        return Err(HypervisorError::TransferInProgress);
+    }
+
+    // The progress is only observable by the client through the shared page.
+    if vm.shared_page_mapping.is_none() {
+        return Err(HypervisorError::SharedPageNotMapped);
+    }
+
+    if !is_preemption_timer_supported() {
+        return Err(HypervisorError::PreemptionTimerUnsupported);
+    }
+
+    debug!("Starting asynchronous transfer of {:#x} bytes", transfer.total_bytes);
+
+    publish_progress(transfer.progress(TransferStatus::InProgress));
+    vm.async_transfer = Some(transfer);
+    update_preemption_timer(vm);
+
+    Ok(())
+}
+
+/// Cancels the background copy of the current logical processor, if any, and stops arming the VMX-preemption timer for it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn cancel_async_transfer(vm: &mut Vm) {
+    if let Some(transfer) = vm.async_transfer.take() {
+        debug!("Cancelling asynchronous transfer after {:#x} bytes", transfer.bytes_copied);
+        publish_progress(transfer.progress(TransferStatus::Cancelled));
+    }
+
+    update_preemption_timer(vm);
+}
+
+/// Copies the next chunk of the background copy of the current logical processor, called when the VMX-preemption
+/// timer expires. The copy is kept until it completes or fails, and the caller re-arms the timer accordingly.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn advance_async_transfer(vm: &mut Vm) {
+    let Some(mut transfer) = vm.async_transfer.take() else {
+        return;
+    };
+
+    if transfer.copy_chunk().is_none() {
+        error!("Failed to write the destination buffer at offset {:#x}", transfer.bytes_copied);
+        publish_progress(transfer.progress(TransferStatus::Failed));
+        return;
+    }
+
+    if transfer.is_complete() {
+        debug!("Asynchronous transfer completed, {:#x} bytes unreadable", transfer.unreadable_bytes);
+        publish_progress(transfer.progress(TransferStatus::Completed));
+        return;
+    }
+
+    trace!("Asynchronous transfer: {:#x} of {:#x} bytes", transfer.bytes_copied, transfer.total_bytes);
+    publish_progress(transfer.progress(TransferStatus::InProgress));
+    vm.async_transfer = Some(transfer);
+}
+
+/// Writes the progress of the copy as a message to the host-to-guest buffer of the shared page.
+///
+/// # Arguments
+///
+/// * `progress` - The progress to publish.
+fn publish_progress(progress: TransferProgress) {
+    let shared_page = unsafe { &mut *(SHARED_HOST_CONFIG.read().shared_page_pa as *mut SharedPage) };
+    let message = unsafe { core::slice::from_raw_parts(&progress as *const TransferProgress as *const u8, core::mem::size_of::<TransferProgress>()) };
+
+    shared_page.host_to_guest_buffer[..message.len()].copy_from_slice(message);
+    shared_page.host_to_guest_length = message.len() as u64;
+
+    // The message must be visible before the client observes the new sequence counter.
+    fence(Ordering::Release);
+    shared_page.host_to_guest_sequence = shared_page.host_to_guest_sequence.wrapping_add(1);
+}
+
+/// Returns the number of bytes from a virtual address to the end of its page.
+fn page_remaining(va: u64) -> u64 {
+    BASE_PAGE_SIZE as u64 - (va & (BASE_PAGE_SIZE as u64 - 1))
+}
@@ -0,0 +1,534 @@
+//! Provides the compensation of the time-stamp counter (TSC) observed by the guest for the time spent in VMX root
+//! operation, so timing-based hypervisor detection (e.g., the delta between two `RDTSC` around `CPUID`) is defeated.
+//!
+//! While enabled, the guest TSC is the host TSC, scaled by the TSC multiplier on processors supporting TSC scaling,
+//! plus the TSC offset of the VMCS. On each VM exit, the offset of the logical processor is decreased by the time
+//! spent handling the exit plus the latency of the VM exit and VM entry transitions, so the guest TSC doesn't advance
+//! while the guest isn't running. When the multiplier changes, the offset is adjusted so the guest TSC stays
+//! continuous. Each logical processor only hides its own exits, so the guest TSCs of the processors drift apart by
+//! the difference of the time they spend in VMX root operation.
+//!
+//! The latency of the transitions can't be measured from VMX root operation, so it's calibrated at startup with the
+//! `tsc_compensation` feature: the loader measures the average duration of `CPUID` before the processors are
+//! virtualized, then again as the guest while only the handling time is compensated, the difference being the
+//! average exit latency the compensation misses.
+//!
+//! Optionally, `RDTSC` and `RDTSCP` cause VM exits and return the guest TSC computed by the hypervisor, the time of
+//! these exits being compensated as well. While the deterministic mode is enabled, they return its virtual TSC
+//! instead (see the `determinism` module).
+//!
+//! The other time sources are virtualized consistently while the compensation is enabled, with MSR hooks:
+//! - the reads of IA32_TIME_STAMP_COUNTER return the guest TSC, and its writes change the TSC offset instead of the
+//!   host TSC,
+//! - IA32_MPERF, counting at the TSC frequency, hides the same ticks as the TSC, and IA32_APERF, counting at the
+//!   actual frequency, hides them in the APERF/MPERF ratio observed since the previous read, so the effective
+//!   frequency computed by the guest is unchanged. Both are scaled by the TSC multiplier, and their writes only change
+//!   the values observed by the guest.
+//!
+//! The counters observed by the guest jump to the host counters when the compensation is disabled.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            bitmap::MsrAccessType,
+            controls::{adjust_vmx_controls, VmxControl},
+            determinism::is_deterministic_mode_enabled,
+            hooks::msr_hook::{MsrHook, MsrHookCallback, MsrHookManager, MsrHookResult, SHARED_MSR_HOOK_MANAGER},
+            seqlock::{Generation, Published},
+            support::{rdmsr, rdtsc, vmread, vmwrite},
+            vm::Vm,
+        },
+    },
+    core::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::{
+        cpuid::cpuid,
+        msr,
+        vmx::vmcs::{
+            self,
+            control::{PrimaryControls, SecondaryControls},
+        },
+    },
+};
+
+/// The TSC multiplier leaving the guest TSC unscaled, 1.0 in 16.48 fixed point.
+pub const TSC_MULTIPLIER_UNSCALED: u64 = 1 << 48;
+
+/// The number of `CPUID` executed to measure its average duration.
+const CALIBRATION_ITERATIONS: u64 = 0x1000;
+
+/// Whether `RDTSC` and `RDTSCP` cause VM exits for the compensation, checked without locking by the other features
+/// controlling RDTSC exiting.
+static RDTSC_EXITING_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The average exit latency measured at startup, in TSC ticks.
+static CALIBRATED_EXIT_LATENCY_TICKS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// A globally shared instance of `TscCompensation`, protected by a mutex.
+    pub static ref SHARED_TSC_COMPENSATION: Mutex<TscCompensation> = Mutex::new(TscCompensation::new());
+
+    /// The configuration applied to the controls of the logical processors, the one selected at build time until it's
+    /// configured.
+    static ref TSC_COMPENSATION_CONFIG: Published<TscCompensationConfig> = Published::new(TscCompensationConfig::default());
+}
+
+/// The configuration of the TSC compensation.
+#[derive(Debug, Clone, Copy)]
+pub struct TscCompensationConfig {
+    /// Whether the time spent in VMX root operation is hidden from the guest TSC.
+    pub enabled: bool,
+
+    /// Whether `RDTSC` and `RDTSCP` cause VM exits and return the guest TSC computed by the hypervisor.
+    pub rdtsc_exiting: bool,
+
+    /// The TSC multiplier in 16.48 fixed point, `TSC_MULTIPLIER_UNSCALED` to leave the guest TSC unscaled.
+    pub tsc_multiplier: u64,
+
+    /// The exit latency hidden on each VM exit in addition to the handling time, in TSC ticks, or `None` for the
+    /// latency calibrated at startup.
+    pub exit_latency_ticks: Option<u64>,
+}
+
+impl Default for TscCompensationConfig {
+    /// Returns the configuration selected at build time: enabled with the `tsc_compensation` feature, without RDTSC
+    /// exiting or scaling.
+    fn default() -> Self {
+        Self {
+            enabled: cfg!(feature = "tsc_compensation"),
+            rdtsc_exiting: false,
+            tsc_multiplier: TSC_MULTIPLIER_UNSCALED,
+            exit_latency_ticks: None,
+        }
+    }
+}
+
+/// The configuration of the TSC compensation, shared by all the logical processors.
+#[derive(Debug)]
+pub struct TscCompensation {
+    /// The current configuration.
+    config: TscCompensationConfig,
+}
+
+impl TscCompensation {
+    /// Creates the configuration selected at build time.
+    fn new() -> Self {
+        Self {
+            config: TscCompensationConfig::default(),
+        }
+    }
+
+    /// Publishes a new configuration, which the logical processors pick up on their next VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new configuration.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the configuration was published, or `Err(HypervisorError::InvalidTscCompensationConfig)` if the
+    /// multiplier is 0, or scales the guest TSC on a processor not supporting TSC scaling.
+    pub fn configure(&mut self, config: TscCompensationConfig) -> Result<(), HypervisorError> {
+        if config.tsc_multiplier == 0 || (config.tsc_multiplier != TSC_MULTIPLIER_UNSCALED && !is_tsc_scaling_supported()) {
+            return Err(HypervisorError::InvalidTscCompensationConfig);
+        }
+
+        debug!("TSC compensation configured: {:?}", config);
+
+        RDTSC_EXITING_ENABLED.store(config.enabled && config.rdtsc_exiting, Ordering::Release);
+        configure_counter_msr_hooks(&mut SHARED_MSR_HOOK_MANAGER.lock(), config.enabled);
+
+        self.config = config;
+        self.publish();
+
+        Ok(())
+    }
+
+    /// Returns the current configuration.
+    pub fn config(&self) -> TscCompensationConfig {
+        self.config
+    }
+
+    /// Publishes the configuration, so the logical processors apply it again.
+    fn publish(&self) {
+        TSC_COMPENSATION_CONFIG.publish(self.config);
+    }
+}
+
+/// The TSC compensation state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorTscCompensation {
+    /// The generation of the configuration applied to the controls of this logical processor.
+    generation: Generation,
+
+    /// Whether the TSC offsetting is enabled on this logical processor.
+    enabled: bool,
+
+    /// The TSC multiplier in use, `TSC_MULTIPLIER_UNSCALED` without scaling.
+    tsc_multiplier: u64,
+
+    /// The exit latency hidden on each VM exit in addition to the handling time, in TSC ticks.
+    exit_latency_ticks: u64,
+
+    /// The host TSC ticks hidden from the guest on this logical processor, kept when the configuration changes.
+    hidden_ticks: u64,
+
+    /// The state of the IA32_APERF and IA32_MPERF virtualization, kept when the configuration changes.
+    performance_counters: PerformanceCounterCompensation,
+}
+
+/// The IA32_APERF and IA32_MPERF virtualization state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+struct PerformanceCounterCompensation {
+    /// The value added to the compensated IA32_MPERF, so it returns the value last written by the guest.
+    mperf_offset: u64,
+
+    /// The value added to the compensated IA32_APERF, so it returns the value last written by the guest.
+    aperf_offset: u64,
+
+    /// The host IA32_APERF ticks hidden from the guest.
+    hidden_aperf: u64,
+
+    /// The hidden TSC ticks already accounted in `hidden_aperf`.
+    accounted_hidden_ticks: u64,
+
+    /// The host IA32_APERF at the previous access.
+    last_host_aperf: u64,
+
+    /// The host IA32_MPERF at the previous access.
+    last_host_mperf: u64,
+}
+
+impl PerformanceCounterCompensation {
+    /// Accounts the TSC ticks hidden since the previous access in the hidden IA32_APERF ticks, with the APERF/MPERF
+    /// ratio observed since then.
+    ///
+    /// # Arguments
+    ///
+    /// * `hidden_ticks` - The host TSC ticks hidden from the guest so far.
+    /// * `host_aperf` - The current host IA32_APERF.
+    /// * `host_mperf` - The current host IA32_MPERF.
+    fn account(&mut self, hidden_ticks: u64, host_aperf: u64, host_mperf: u64) {
+        let new_hidden_ticks = hidden_ticks.wrapping_sub(self.accounted_hidden_ticks);
+        let aperf_delta = host_aperf.wrapping_sub(self.last_host_aperf);
+        let mperf_delta = host_mperf.wrapping_sub(self.last_host_mperf);
+
+        let new_hidden_aperf = match mperf_delta {
+            0 => new_hidden_ticks,
+            _ => (new_hidden_ticks as u128 * aperf_delta as u128 / mperf_delta as u128) as u64,
+        };
+
+        // The ticks hidden can't exceed the ticks counted, which would make the counter observed go backwards.
+        self.hidden_aperf = self.hidden_aperf.wrapping_add(new_hidden_aperf.min(aperf_delta));
+        self.accounted_hidden_ticks = hidden_ticks;
+        self.last_host_aperf = host_aperf;
+        self.last_host_mperf = host_mperf;
+    }
+}
+
+impl ProcessorTscCompensation {
+    /// Creates a new processor state, without compensation until the configuration is applied on the first VM exit.
+    pub fn new() -> Self {
+        Self {
+            generation: Generation::STALE,
+            tsc_multiplier: TSC_MULTIPLIER_UNSCALED,
+            ..Self::default()
+        }
+    }
+}
+
+/// Returns `true` if `RDTSC` and `RDTSCP` cause VM exits for the compensation.
+pub fn is_rdtsc_exiting_enabled() -> bool {
+    RDTSC_EXITING_ENABLED.load(Ordering::Acquire)
+}
+
+/// Returns `true` if the processor supports TSC scaling.
+fn is_tsc_scaling_supported() -> bool {
+    adjust_vmx_controls(VmxControl::ProcessorBased2, SecondaryControls::USE_TSC_SCALING.bits() as u64)
+        & SecondaryControls::USE_TSC_SCALING.bits() as u64
+        != 0
+}
+
+/// Scales a number of TSC ticks with a TSC multiplier.
+///
+/// # Arguments
+///
+/// * `ticks` - The number of TSC ticks.
+/// * `tsc_multiplier` - The TSC multiplier in 16.48 fixed point.
+fn scale_tsc(ticks: u64, tsc_multiplier: u64) -> u64 {
+    ((ticks as u128 * tsc_multiplier as u128) >> 48) as u64
+}
+
+/// Applies a new compensation configuration to the controls of the current logical processor, keeping the guest TSC
+/// continuous.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_tsc_compensation(vm: &mut Vm) {
+    let mut generation = vm.tsc_compensation.generation;
+    let Some(config) = TSC_COMPENSATION_CONFIG.sync(&mut generation) else {
+        return;
+    };
+
+    let previous = vm.tsc_compensation;
+    let tsc_multiplier = match config.enabled {
+        true => config.tsc_multiplier,
+        false => TSC_MULTIPLIER_UNSCALED,
+    };
+
+    if config.enabled {
+        // The guest TSC keeps its current value with the new multiplier.
+        let tsc = rdtsc();
+        let tsc_offset = match previous.enabled {
+            true => vmread(vmcs::control::TSC_OFFSET_FULL),
+            false => 0,
+        };
+        let tsc_offset = tsc_offset
+            .wrapping_add(scale_tsc(tsc, previous.tsc_multiplier))
+            .wrapping_sub(scale_tsc(tsc, tsc_multiplier));
+
+        vmwrite(vmcs::control::TSC_OFFSET_FULL, tsc_offset);
+    }
+
+    let tsc_scaling = tsc_multiplier != TSC_MULTIPLIER_UNSCALED;
+    if tsc_scaling {
+        vmwrite(vmcs::control::TSC_MULTIPLIER_FULL, tsc_multiplier);
+    }
+
+    let mut primary_controls = PrimaryControls::from_bits_truncate(vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+    primary_controls.set(PrimaryControls::USE_TSC_OFFSETTING, config.enabled);
+    primary_controls.set(PrimaryControls::RDTSC_EXITING, is_rdtsc_exiting_enabled() || is_deterministic_mode_enabled());
+    vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
+
+    // The multiplier has been validated against the support of TSC scaling when configured.
+    let mut secondary_controls = SecondaryControls::from_bits_truncate(vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) as u32);
+    secondary_controls.set(SecondaryControls::USE_TSC_SCALING, tsc_scaling);
+    vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, secondary_controls.bits());
+
+    vm.tsc_compensation = ProcessorTscCompensation {
+        generation,
+        enabled: config.enabled,
+        tsc_multiplier,
+        exit_latency_ticks: config
+            .exit_latency_ticks
+            .unwrap_or_else(|| CALIBRATED_EXIT_LATENCY_TICKS.load(Ordering::Acquire)),
+        hidden_ticks: previous.hidden_ticks,
+        performance_counters: previous.performance_counters,
+    };
+
+    trace!("TSC compensation on this processor: {:?}", vm.tsc_compensation);
+}
+
+/// Hides the time spent handling a VM exit and the exit latency from the guest TSC of the current logical processor.
+///
+/// This must be called last before resuming the guest.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `exit_tsc` - The TSC value read when the VM exit started being handled.
+pub fn compensate_exit_time(vm: &mut Vm, exit_tsc: u64) {
+    if !vm.tsc_compensation.enabled {
+        return;
+    }
+
+    let hidden_ticks = rdtsc().saturating_sub(exit_tsc) + vm.tsc_compensation.exit_latency_ticks;
+    vm.tsc_compensation.hidden_ticks = vm.tsc_compensation.hidden_ticks.wrapping_add(hidden_ticks);
+
+    let tsc_offset = vmread(vmcs::control::TSC_OFFSET_FULL).wrapping_sub(scale_tsc(hidden_ticks, vm.tsc_compensation.tsc_multiplier));
+
+    vmwrite(vmcs::control::TSC_OFFSET_FULL, tsc_offset);
+}
+
+/// Converts a host TSC value to the TSC observed by the guest on the current logical processor, according to its
+/// TSC offsetting and scaling controls.
+///
+/// # Arguments
+///
+/// * `tsc` - The host TSC value.
+///
+/// # Returns
+///
+/// The guest TSC value.
+pub fn guest_tsc(tsc: u64) -> u64 {
+    let primary_controls = PrimaryControls::from_bits_truncate(vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+    if !primary_controls.contains(PrimaryControls::USE_TSC_OFFSETTING) {
+        return tsc;
+    }
+
+    let secondary_controls = SecondaryControls::from_bits_truncate(vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) as u32);
+    let tsc = match secondary_controls.contains(SecondaryControls::USE_TSC_SCALING) {
+        true => scale_tsc(tsc, vmread(vmcs::control::TSC_MULTIPLIER_FULL)),
+        false => tsc,
+    };
+
+    tsc.wrapping_add(vmread(vmcs::control::TSC_OFFSET_FULL))
+}
+
+/// Registers the hooks virtualizing IA32_TIME_STAMP_COUNTER, IA32_APERF and IA32_MPERF, or unregisters them.
+///
+/// This is called when the registry is created, with the configuration selected at build time, then each time the
+/// compensation is configured.
+///
+/// # Arguments
+///
+/// * `msr_hook_manager` - The MSR hook registry.
+/// * `enabled` - Whether the compensation is enabled.
+pub fn configure_counter_msr_hooks(msr_hook_manager: &mut MsrHookManager, enabled: bool) {
+    for counter_msr in [msr::IA32_TIME_STAMP_COUNTER, msr::IA32_APERF, msr::IA32_MPERF] {
+        for (access_type, callback) in [
+            (MsrAccessType::Read, handle_counter_msr_read as MsrHookCallback),
+            (MsrAccessType::Write, handle_counter_msr_write),
+        ] {
+            match enabled {
+                true => msr_hook_manager.register(counter_msr, access_type, MsrHook::Callback(callback)),
+                false => {
+                    msr_hook_manager.unregister(counter_msr, access_type);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the compensated value of IA32_TIME_STAMP_COUNTER, IA32_APERF or IA32_MPERF.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `msr` - The counter MSR.
+/// * `value` - Receives the value returned to the guest.
+///
+/// # Returns
+///
+/// `Ok(MsrHookResult::Emulated)`, or `Ok(MsrHookResult::Passthrough)` if the compensation isn't enabled on this
+/// logical processor yet.
+fn handle_counter_msr_read(vm: &mut Vm, msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError> {
+    if !vm.tsc_compensation.enabled {
+        return Ok(MsrHookResult::Passthrough);
+    }
+
+    *value = match msr {
+        msr::IA32_TIME_STAMP_COUNTER => guest_tsc(rdmsr(msr)),
+        _ => {
+            let (guest_aperf, guest_mperf) = guest_performance_counters(vm);
+            match msr {
+                msr::IA32_APERF => guest_aperf,
+                _ => guest_mperf,
+            }
+        }
+    };
+
+    Ok(MsrHookResult::Emulated)
+}
+
+/// Makes the guest observe the value written to IA32_TIME_STAMP_COUNTER, IA32_APERF or IA32_MPERF, without writing
+/// the host counter.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `msr` - The counter MSR.
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// `Ok(MsrHookResult::Emulated)`, or `Ok(MsrHookResult::Passthrough)` if the compensation isn't enabled on this
+/// logical processor yet.
+fn handle_counter_msr_write(vm: &mut Vm, msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError> {
+    if !vm.tsc_compensation.enabled {
+        return Ok(MsrHookResult::Passthrough);
+    }
+
+    trace!("Counter MSR {:#x} written by the guest: {:#x}", msr, *value);
+
+    match msr {
+        msr::IA32_TIME_STAMP_COUNTER => {
+            let tsc_offset = vmread(vmcs::control::TSC_OFFSET_FULL);
+            vmwrite(vmcs::control::TSC_OFFSET_FULL, tsc_offset.wrapping_add(value.wrapping_sub(guest_tsc(rdtsc()))));
+        }
+        _ => {
+            let (guest_aperf, guest_mperf) = guest_performance_counters(vm);
+            let performance_counters = &mut vm.tsc_compensation.performance_counters;
+
+            match msr {
+                msr::IA32_APERF => {
+                    performance_counters.aperf_offset = performance_counters.aperf_offset.wrapping_add(value.wrapping_sub(guest_aperf))
+                }
+                _ => performance_counters.mperf_offset = performance_counters.mperf_offset.wrapping_add(value.wrapping_sub(guest_mperf)),
+            }
+        }
+    }
+
+    Ok(MsrHookResult::Emulated)
+}
+
+/// Returns the IA32_APERF and IA32_MPERF observed by the guest on the current logical processor, without the ticks
+/// counted while in VMX root operation and scaled like the TSC.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+fn guest_performance_counters(vm: &mut Vm) -> (u64, u64) {
+    let host_aperf = rdmsr(msr::IA32_APERF);
+    let host_mperf = rdmsr(msr::IA32_MPERF);
+
+    let tsc_compensation = &mut vm.tsc_compensation;
+    let performance_counters = &mut tsc_compensation.performance_counters;
+    performance_counters.account(tsc_compensation.hidden_ticks, host_aperf, host_mperf);
+
+    let guest_aperf = scale_tsc(host_aperf.wrapping_sub(performance_counters.hidden_aperf), tsc_compensation.tsc_multiplier)
+        .wrapping_add(performance_counters.aperf_offset);
+    let guest_mperf = scale_tsc(host_mperf.wrapping_sub(tsc_compensation.hidden_ticks), tsc_compensation.tsc_multiplier)
+        .wrapping_add(performance_counters.mperf_offset);
+
+    (guest_aperf, guest_mperf)
+}
+
+/// Measures the average duration of `CPUID` observed with the TSC, in TSC ticks.
+///
+/// This is called by the loader before the processors are virtualized, then by `calibrate_exit_latency`.
+///
+/// # Returns
+///
+/// The average number of TSC ticks between the start and the end of `CPUID`.
+pub fn measure_cpuid_ticks() -> u64 {
+    let start_tsc = rdtsc();
+
+    for _ in 0..CALIBRATION_ITERATIONS {
+        cpuid!(0);
+    }
+
+    rdtsc().wrapping_sub(start_tsc) / CALIBRATION_ITERATIONS
+}
+
+/// Calibrates the exit latency as the difference between the average duration of `CPUID` observed by the guest,
+/// with only the handling time compensated, and its duration before the processors were virtualized, then publishes
+/// it to the logical processors.
+///
+/// This must be called by the guest right after the processors have been virtualized with the compensation enabled.
+///
+/// # Arguments
+///
+/// * `native_cpuid_ticks` - The average duration of `CPUID` measured by `measure_cpuid_ticks` before virtualization.
+///
+/// # Returns
+///
+/// The calibrated exit latency, in TSC ticks.
+pub fn calibrate_exit_latency(native_cpuid_ticks: u64) -> u64 {
+    let virtualized_cpuid_ticks = measure_cpuid_ticks();
+    let exit_latency_ticks = virtualized_cpuid_ticks.saturating_sub(native_cpuid_ticks);
+
+    info!(
+        "Calibrated exit latency: {} TSC ticks (CPUID: {} ticks native, {} ticks virtualized)",
+        exit_latency_ticks, native_cpuid_ticks, virtualized_cpuid_ticks
+    );
+
+    CALIBRATED_EXIT_LATENCY_TICKS.store(exit_latency_ticks, Ordering::Release);
+    SHARED_TSC_COMPENSATION.lock().publish();
+
+    exit_latency_ticks
+}
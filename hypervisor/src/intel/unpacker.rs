@@ -0,0 +1,445 @@
+//! Provides an unpacker assistance mode, which dumps the code written and then executed by a target process, as done
+//! by packed executables when they decompress or decrypt their original code at runtime.
+//!
+//! While enabled, the guest pages backing a virtual address range of the target process (e.g., its main image) are
+//! write-protected in the EPT. The first write to a protected page makes it writable but non-executable, and the next
+//! instruction fetch from a written page is a write→execute transition: every page written since the previous
+//! transition, i.e., the newly written code region, is dumped with the address of the executed instruction (e.g., the
+//! original entry point), then write-protected again to catch the next layer. The client drains the dumps with the
+//! `ReadUnpackerDumps` command.
+//!
+//! The writing instruction is single-stepped with the page writable and executable, so code patching its own page
+//! makes progress, and the page is made non-executable on the following MTF VM exit.
+//!
+//! The watched pages are physical pages, so a page remapped by the guest after the range has been scanned (e.g., a
+//! copy-on-write page of the image made private by `VirtualProtect`) isn't watched until the range is scanned again,
+//! which happens each time the dumps are read. The watch stops when the target process no longer exists.
+//!
+//! The dumped pages are also copied by the `code_snapshot` module, if enabled, which keeps them apart from the dumps.
+//!
+//! As for the EPT hooks, the EPT is modified on the logical processor handling the commands.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            code_snapshot::snapshot_code_page,
+            ept::AccessType,
+            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            single_step::{single_step, SingleStepContext, SingleStepOwner},
+            support::{rdtsc, vmread},
+            vm::Vm,
+            vmexit::ExitType,
+        },
+        windows::{eprocess::ProcessInformation, symbols::SymbolizedAddress},
+    },
+    alloc::{
+        collections::{BTreeMap, VecDeque},
+        vec::Vec,
+    },
+    core::sync::atomic::{AtomicBool, Ordering},
+    lazy_static::lazy_static,
+    log::*,
+    shared::{CodeSnapshotSource, UnpackedPage, UNPACKED_PAGE_SIZE},
+    spin::Mutex,
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        vmx::vmcs,
+    },
+};
+
+/// The maximum number of pages of the watched range, each requiring a page table once its large page is split.
+pub const MAX_UNPACKER_WATCHED_PAGES: usize = 0x1000;
+
+/// The maximum number of dumped pages kept until they are drained.
+pub const UNPACKER_DUMP_CAPACITY: usize = 0x100;
+
+/// Whether the unpacker is enabled, checked without locking on each EPT violation.
+static UNPACKER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// A globally shared instance of `Unpacker`, protected by a mutex.
+    pub static ref SHARED_UNPACKER: Mutex<Unpacker> = Mutex::new(Unpacker::new());
+}
+
+/// The state of a watched page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchedPageState {
+    /// The page is readable and executable, the next write is intercepted.
+    WriteProtected,
+
+    /// The page is written by the instruction being single-stepped, with the page readable, writable and executable.
+    Writing { write_rip: u64 },
+
+    /// The page has been written and is readable and writable, the next instruction fetch is intercepted.
+    Written { write_rip: u64 },
+}
+
+/// A guest page of the watched range.
+#[derive(Debug, Clone, Copy)]
+struct WatchedPage {
+    /// The virtual address of the page in the target process.
+    guest_va: u64,
+
+    /// The state of the page.
+    state: WatchedPageState,
+}
+
+/// The watched range of the target process and the pages dumped on write→execute transitions.
+#[derive(Debug)]
+pub struct Unpacker {
+    /// The ID of the target process.
+    process_id: u64,
+
+    /// The directory table base of the target process, used to translate the watched range.
+    directory_table_base: u64,
+
+    /// The first virtual address of the watched range, page aligned.
+    base_va: u64,
+
+    /// The number of pages of the watched range.
+    page_count: usize,
+
+    /// The watched pages, by guest physical address.
+    pages: BTreeMap<u64, WatchedPage>,
+
+    /// The pages dumped and not drained yet, oldest first.
+    dumps: VecDeque<UnpackedPage>,
+
+    /// The number of dumps dropped since the last drain because the buffer was full.
+    dropped_dumps: u64,
+}
+
+impl Unpacker {
+    /// Creates a new stopped unpacker, without allocating the buffer.
+    fn new() -> Self {
+        Self {
+            process_id: 0,
+            directory_table_base: 0,
+            base_va: 0,
+            page_count: 0,
+            pages: BTreeMap::new(),
+            dumps: VecDeque::new(),
+            dropped_dumps: 0,
+        }
+    }
+
+    /// Returns the number of watched pages.
+    pub fn watched_page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Removes the oldest dumps from the buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_dumps` - The maximum number of dumps to remove.
+    ///
+    /// # Returns
+    ///
+    /// The dumps removed, oldest first, and the number of dumps dropped since the last drain.
+    pub fn drain(&mut self, max_dumps: usize) -> (Vec<UnpackedPage>, u64) {
+        let count = self.dumps.len().min(max_dumps);
+        let dumps = self.dumps.drain(..count).collect();
+
+        (dumps, core::mem::take(&mut self.dropped_dumps))
+    }
+
+    /// Translates the watched range again with the directory table base of the target process, watching the pages
+    /// mapped since the previous scan and releasing the pages no longer mapped.
+    ///
+    /// The watch stops if the target process no longer exists, as its physical pages may be reused by other processes.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the range has been scanned, or `Err(HypervisorError)` if the EPT couldn't be modified.
+    pub fn rescan(&mut self, vm: &mut Vm) -> Result<(), HypervisorError> {
+        if !UNPACKER_ENABLED.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        if ProcessInformation::get_directory_table_base_by_process_id(self.process_id) != Some(self.directory_table_base) {
+            debug!("Unpacker target process {} exited, stopping", self.process_id);
+            return self.stop(vm);
+        }
+
+        let mut mapped_pages = BTreeMap::new();
+        for index in 0..self.page_count {
+            let guest_va = self.base_va + (index * BASE_PAGE_SIZE) as u64;
+
+            if let Ok(guest_pa) = PhysicalAddress::pa_from_va_with_explicit_cr3(guest_va, self.directory_table_base) {
+                mapped_pages.insert(PAddr::from(guest_pa).align_down_to_base_page().as_u64(), guest_va);
+            }
+        }
+
+        let unmapped_pages: Vec<u64> = self
+            .pages
+            .keys()
+            .filter(|guest_page_pa| !mapped_pages.contains_key(guest_page_pa))
+            .copied()
+            .collect();
+        for guest_page_pa in unmapped_pages {
+            self.pages.remove(&guest_page_pa);
+            set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+        }
+
+        for (guest_page_pa, guest_va) in mapped_pages {
+            if let Some(page) = self.pages.get_mut(&guest_page_pa) {
+                page.guest_va = guest_va;
+                continue;
+            }
+
+            // The pages of EPT hooks are already switched between views of their own.
+            if SHARED_HOOK_MANAGER.lock().memory_manager.is_guest_page_processed(guest_page_pa) {
+                continue;
+            }
+
+            set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_EXECUTE)?;
+            self.pages.insert(
+                guest_page_pa,
+                WatchedPage {
+                    guest_va,
+                    state: WatchedPageState::WriteProtected,
+                },
+            );
+        }
+
+        trace!("Unpacker watching {} pages of process {}", self.pages.len(), self.process_id);
+
+        vm.primary_ept.invalidate_ept_cache()
+    }
+
+    /// Stops watching the range, restoring the permissions of the watched pages and keeping the dumps so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the watch has been stopped, or `Err(HypervisorError)` if the EPT couldn't be modified.
+    pub fn stop(&mut self, vm: &mut Vm) -> Result<(), HypervisorError> {
+        UNPACKER_ENABLED.store(false, Ordering::Release);
+
+        for guest_page_pa in core::mem::take(&mut self.pages).into_keys() {
+            set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+        }
+
+        debug!("Unpacker stopped, {} dumps pending", self.dumps.len());
+
+        vm.primary_ept.invalidate_ept_cache()
+    }
+
+    /// Dumps every page written since the previous write→execute transition, and write-protects them again.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current logical processor.
+    /// * `execute_rip` - The guest RIP of the instruction fetched from a written page.
+    fn dump_written_pages(&mut self, vm: &mut Vm, execute_rip: u64) -> Result<(), HypervisorError> {
+        let tsc = rdtsc();
+        let cr3 = vmread(vmcs::guest::CR3);
+
+        for (&guest_page_pa, page) in self.pages.iter_mut() {
+            let WatchedPageState::Written { write_rip } = page.state else {
+                continue;
+            };
+
+            let mut dump = UnpackedPage {
+                tsc,
+                process_id: self.process_id,
+                cr3,
+                guest_va: page.guest_va,
+                guest_pa: guest_page_pa,
+                write_rip,
+                execute_rip,
+                data: [0; UNPACKED_PAGE_SIZE],
+            };
+
+            // The guest physical memory is identity mapped in the host.
+            unsafe { core::ptr::copy_nonoverlapping(guest_page_pa as *const u8, dump.data.as_mut_ptr(), UNPACKED_PAGE_SIZE) };
+
+            debug!(
+                "Unpacked page: {:#x} (PA: {:#x}), written at RIP: {:#x}, executed at RIP: {:#x}",
+                page.guest_va, guest_page_pa, write_rip, execute_rip
+            );
+
+            snapshot_code_page(CodeSnapshotSource::UnpackerTransition, self.process_id, cr3, page.guest_va, guest_page_pa);
+
+            if self.dumps.len() < UNPACKER_DUMP_CAPACITY {
+                self.dumps.push_back(dump);
+            } else {
+                self.dropped_dumps += 1;
+            }
+
+            page.state = WatchedPageState::WriteProtected;
+            set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_EXECUTE)?;
+        }
+
+        vm.primary_ept.invalidate_ept_cache()
+    }
+}
+
+/// Starts watching a range of a process for write→execute transitions, discarding the dumps of a previous run.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `process_id` - The ID of the target process.
+/// * `base_va` - The first virtual address of the range in the target process.
+/// * `size` - The size of the range in bytes.
+///
+/// # Returns
+///
+/// `Ok(())` if the range is watched, `Err(HypervisorError::ProcessNotFound)` if the process doesn't exist,
+/// `Err(HypervisorError::InvalidUnpackerRange)` if the range is empty or too large, or another `HypervisorError` if
+/// the EPT couldn't be modified.
+pub fn start_unpacker(vm: &mut Vm, process_id: u64, base_va: u64, size: u64) -> Result<(), HypervisorError> {
+    let mut unpacker = SHARED_UNPACKER.lock();
+
+    if UNPACKER_ENABLED.load(Ordering::Acquire) {
+        unpacker.stop(vm)?;
+    }
+
+    let end_va = base_va.checked_add(size).ok_or(HypervisorError::InvalidUnpackerRange)?;
+    let base_va = base_va & !(BASE_PAGE_SIZE as u64 - 1);
+    let page_count = (end_va - base_va).div_ceil(BASE_PAGE_SIZE as u64) as usize;
+
+    if size == 0 || page_count > MAX_UNPACKER_WATCHED_PAGES {
+        return Err(HypervisorError::InvalidUnpackerRange);
+    }
+
+    let directory_table_base = ProcessInformation::get_directory_table_base_by_process_id(process_id).ok_or(HypervisorError::ProcessNotFound)?;
+
+    unpacker.process_id = process_id;
+    unpacker.directory_table_base = directory_table_base;
+    unpacker.base_va = base_va;
+    unpacker.page_count = page_count;
+    unpacker.dumps.clear();
+    unpacker.dumps.reserve_exact(UNPACKER_DUMP_CAPACITY);
+    unpacker.dropped_dumps = 0;
+
+    UNPACKER_ENABLED.store(true, Ordering::Release);
+
+    debug!("Unpacker started for process {}: {:#x} ({} pages)", process_id, base_va, page_count);
+
+    unpacker.rescan(vm)
+}
+
+/// Returns `true` if a guest page is watched by the unpacker.
+///
+/// # Arguments
+///
+/// * `guest_page_pa` - The guest physical address of the page.
+pub fn is_unpacker_page(guest_page_pa: u64) -> bool {
+    UNPACKER_ENABLED.load(Ordering::Acquire) && SHARED_UNPACKER.lock().pages.contains_key(&guest_page_pa)
+}
+
+/// Handles an EPT violation on a watched page.
+///
+/// A write to a write-protected page is single-stepped with the page writable and executable. An instruction fetch
+/// from a written page dumps the written pages.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `guest_page_pa` - The guest physical address of the watched page.
+/// * `instruction_fetch` - Whether the violation was caused by an instruction fetch.
+///
+/// # Returns
+///
+/// * `Result<ExitType, HypervisorError>` - `Ok(ExitType::Continue)` to retry the access.
+pub fn handle_unpacker_access(vm: &mut Vm, guest_page_pa: u64, instruction_fetch: bool) -> Result<ExitType, HypervisorError> {
+    let mut unpacker = SHARED_UNPACKER.lock();
+
+    let Some(page) = unpacker.pages.get_mut(&guest_page_pa) else {
+        return Ok(ExitType::Continue);
+    };
+
+    match (page.state, instruction_fetch) {
+        (WatchedPageState::Written { .. }, true) => {
+            debug!("Write→execute transition at RIP: {}", SymbolizedAddress::new(unpacker.process_id, vm.guest_registers.rip));
+            let execute_rip = vm.guest_registers.rip;
+            unpacker.dump_written_pages(vm, execute_rip)?;
+        }
+        (WatchedPageState::WriteProtected, false) => {
+            trace!("First write to watched page: {:#x} at RIP: {:#x}", guest_page_pa, vm.guest_registers.rip);
+            page.state = WatchedPageState::Writing {
+                write_rip: vm.guest_registers.rip,
+            };
+
+            set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_WRITE_EXECUTE)?;
+            vm.primary_ept.invalidate_ept_cache()?;
+
+            let context = SingleStepContext {
+                guest_page_pa,
+                guest_next_page_pa: None,
+            };
+            single_step(vm, SingleStepOwner::UnpackerWrite, 1, context, complete_unpacker_write)?;
+        }
+        // The permissions have been changed meanwhile by another logical processor, retry the access.
+        _ => {}
+    }
+
+    Ok(ExitType::Continue)
+}
+
+/// Makes a watched page non-executable after the instruction writing it has been single-stepped.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `context` - The guest physical address of the written page.
+///
+/// # Returns
+///
+/// `Ok(())` if the page has been made non-executable, or `Err(HypervisorError)` if the EPT couldn't be modified.
+pub fn complete_unpacker_write(vm: &mut Vm, context: SingleStepContext) -> Result<(), HypervisorError> {
+    let guest_page_pa = context.guest_page_pa;
+
+    let mut unpacker = SHARED_UNPACKER.lock();
+
+    // The watch may have been stopped or the page released meanwhile.
+    let Some(page) = unpacker.pages.get_mut(&guest_page_pa) else {
+        return Ok(());
+    };
+
+    if let WatchedPageState::Writing { write_rip } = page.state {
+        page.state = WatchedPageState::Written { write_rip };
+        set_watched_page_permissions(vm, guest_page_pa, AccessType::READ_WRITE)?;
+        vm.primary_ept.invalidate_ept_cache()?;
+    }
+
+    Ok(())
+}
+
+/// Changes the EPT permissions of a watched page on the current logical processor, splitting its large page if needed.
+/// The caller invalidates the EPT cache. Also used by the execution tracer for the pages it traces.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `guest_page_pa` - The guest physical address of the page.
+/// * `access_type` - The permissions of the page.
+pub fn set_watched_page_permissions(vm: &mut Vm, guest_page_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
+    let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page();
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    hook_manager.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
+
+    let pre_alloc_pt = hook_manager
+        .memory_manager
+        .get_page_table_as_mut(guest_large_page_pa.as_u64())
+        .ok_or(HypervisorError::PageTableNotFound)?;
+
+    if vm.primary_ept.is_large_page(guest_page_pa) {
+        vm.primary_ept.split_2mb_to_4kb(guest_large_page_pa.as_u64(), pre_alloc_pt)?;
+    }
+
+    vm.primary_ept.modify_page_permissions(guest_page_pa, access_type, pre_alloc_pt)
+}
@@ -9,15 +9,41 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            benchmark::ProcessorBenchmark,
+            bitmap::{IoBitmap, MsrBitmap},
+            broadcast::ProcessorBroadcast,
             capture::GuestRegisters,
+            debug_registers::ProcessorDebugRegisters,
             ept::Ept,
-            hooks::{descriptor_manager::SHARED_DESCRIPTOR_MANAGER, hook_manager::SHARED_HOOK_MANAGER},
+            ept_view::ProcessorEptView,
+            events::PendingEvents,
+            exception_telemetry::ProcessorExceptionTelemetry,
+            exit_statistics::ProcessorExitStatistics,
+            exit_storm::ExitStormMonitor,
+            hooks::{
+                cpuid_hook::ProcessorCpuidHooks, descriptor_manager::SHARED_DESCRIPTOR_MANAGER, exception_hook::ProcessorExceptionHooks,
+                hook_view::ProcessorHookView, msr_hook::sync_msr_hooks, tamper::PendingHookWrite,
+            },
+            host_exception::ProcessorHostExceptions,
+            host_stack::current_host_stack,
+            invvpid::allocate_vpid,
             paging::PageTables,
-            support::{vmclear, vmptrld, vmread, vmxon},
+            process_tracker::ProcessContext,
+            profiler::ProcessorProfiler,
+            scheduler::ProcessorScheduler,
+            seqlock::Generation,
+            shared_page::PageMapping,
+            single_step::SingleStepEngine,
+            support::{vmclear, vmptrld, vmxon},
+            transfer::AsyncTransfer,
+            tsc_compensation::ProcessorTscCompensation,
             vmcs::Vmcs,
-            vmerror::{VmInstructionError, VmxBasicExitReason},
+            vmcs_field::{vm_instruction_error, VmcsField},
+            vmerror::VmxBasicExitReason,
+            vmexit::mtf::HookRestoration,
             vmlaunch::launch_vm,
             vmxon::Vmxon,
+            watchdog::ProcessorWatchdog,
         },
     },
     core::mem::MaybeUninit,
@@ -25,7 +51,6 @@ use {
     x86::{
         bits64::rflags::RFlags,
         cpuid::{cpuid, CpuId, FeatureInfo},
-        vmx::vmcs,
     },
 };
 
@@ -36,8 +61,8 @@ use {
 /// and the state of guest registers. Additionally, it tracks whether the VM has been launched.
 ///
 /// # Size
-/// - Total size in bytes: 4,204,969 bytes (0x4010B9)
-/// - Total size in pages: 1027 pages (0x403)
+/// - Total size in bytes: 4,217,331 bytes (0x4059F3)
+/// - Total size in pages: 1030 pages (0x406)
 pub struct Vm {
     /// The VMXON (Virtual Machine Extensions On) region for the VM.
     /// - Aligned to 4096 bytes (0x1000)
@@ -51,7 +76,8 @@ pub struct Vm {
     /// - Pml4: 4096 bytes (0x1000)
     /// - Pdpt: 4096 bytes (0x1000)
     /// - Pd: 512 * 4096 bytes (since each Pd is 4096 bytes) (0x200000)
-    /// - Total: 4096 + 4096 + (512 * 4096) = 2,096,128 bytes (0x200800)
+    /// - Pt: 4096 bytes (0x1000), splitting the large page of the guard page of the host stack
+    /// - Total: 4096 + 4096 + (512 * 4096) + 4096 = 2,100,224 bytes (0x201000)
     pub host_paging: PageTables,
 
     /// The primary EPT (Extended Page Tables) for the VM.
@@ -66,6 +92,36 @@ pub struct Vm {
     /// - Size: 8 bytes (0x8)
     pub primary_eptp: u64,
 
+    /// The Virtual Processor Identifier (VPID) of the VM, unique and non-zero for each logical processor.
+    /// - Size: 2 bytes (0x2)
+    pub vpid: u16,
+
+    /// The MSR bitmap for the VM. Each logical processor owns its bitmap, so interceptions
+    /// can be modified on one processor without affecting the others.
+    /// - Size: 4096 bytes (0x1000)
+    pub msr_bitmap: MsrBitmap,
+
+    /// The generation of the MSR hook registry applied to the MSR bitmap.
+    /// - Size: 8 bytes (0x8)
+    pub msr_hook_generation: Generation,
+
+    /// The generation of the MSR bitmap profile copied to the MSR bitmap.
+    /// - Size: 8 bytes (0x8)
+    pub msr_bitmap_profile_generation: Generation,
+
+    /// The generation of the syscall hook registry applied to the effective IA32_LSTAR.
+    /// - Size: 8 bytes (0x8)
+    pub syscall_hook_generation: Generation,
+
+    /// The global generation of the published values picked up by this logical processor, stale to pick them up again
+    /// at the next VM exit.
+    /// - Size: 8 bytes (0x8)
+    pub published_generation: Generation,
+
+    /// The I/O bitmaps for the VM, owned by each logical processor like the MSR bitmap.
+    /// - Size: 8192 bytes (0x2000)
+    pub io_bitmap: IoBitmap,
+
     /// State of guest general-purpose registers.
     /// - Size: 400 bytes (0x190)
     pub guest_registers: GuestRegisters,
@@ -74,14 +130,112 @@ pub struct Vm {
     /// - Size: 1 byte (0x1)
     pub has_launched: bool,
 
-    /// The old RFLAGS value before turning off the interrupt flag.
-    /// Used for restoring the RFLAGS register after handling the Monitor Trap Flag (MTF) VM exit.
-    /// - Size: 8 bytes (Option<u64>) (0x8)
-    pub old_rflags: Option<u64>,
+    /// The single-step requests of the features stepping guest instructions with the Monitor Trap Flag (MTF), and
+    /// the guest interrupt flag saved while stepping.
+    /// - Size: 240 bytes (0xF0)
+    pub single_step: SingleStepEngine,
+
+    /// The guest physical address of a hooked page written by the guest while single-stepping, whose shadow page
+    /// must be resynchronized with the guest page by the MTF VM exit.
+    /// - Size: 16 bytes (Option<u64>) (0x10)
+    pub mtf_resync_page: Option<u64>,
+
+    /// A write to a hooked page recorded while single-stepping, reported to the hook tamper handler by the MTF VM exit.
+    /// - Size: 32 bytes (Option<PendingHookWrite>) (0x20)
+    pub mtf_hook_write: Option<PendingHookWrite>,
+
+    /// The hooked pages looked up on the last hook hit, restored by the MTF VM exit once the overwritten instructions
+    /// have been single-stepped.
+    /// - Size: 88 bytes (Option<HookRestoration>) (0x58)
+    pub mtf_hook_restoration: Option<HookRestoration>,
+
+    /// The total time this logical processor has spent handling VM exits, hidden from the secondary clock sources.
+    /// - Size: 8 bytes (0x8)
+    pub hidden_tsc_ticks: u64,
+
+    /// The number of allocated memory ranges of `HostConfig` already hidden from the guest in the primary EPT.
+    /// - Size: 8 bytes (0x8)
+    pub hidden_memory_range_count: usize,
+
+    /// The saved mapping of the guest page that the shared communication page is mapped over, if any.
+    /// - Size: 32 bytes (Option<PageMapping>) (0x20)
+    pub shared_page_mapping: Option<PageMapping>,
+
+    /// The background copy driven by the VMX-preemption timer on this logical processor, if any.
+    /// - Size: 64 bytes (Option<AsyncTransfer>) (0x40)
+    pub async_transfer: Option<AsyncTransfer>,
+
+    /// The VM exit counters used to detect VM exit storms on this logical processor.
+    /// - Size: 320 bytes (0x140)
+    pub exit_storm_monitor: ExitStormMonitor,
+
+    /// The VM exits of this logical processor by basic exit reason and the latency of their handler.
+    /// - Size: 2432 bytes (0x980)
+    pub exit_statistics: ProcessorExitStatistics,
+
+    /// The state of the sampling profiler on this logical processor.
+    /// - Size: 32 bytes (0x20)
+    pub profiler: ProcessorProfiler,
+
+    /// The state of the watchdog of the guest progress on this logical processor.
+    /// - Size: 64 bytes (0x40)
+    pub watchdog: ProcessorWatchdog,
+
+    /// The periodic tasks scheduled on this logical processor.
+    /// - Size: 264 bytes (0x108)
+    pub scheduler: ProcessorScheduler,
+
+    /// The generation of the deterministic mode configuration in use on this logical processor.
+    /// - Size: 8 bytes
+    pub deterministic_mode_generation: Generation,
+
+    /// The hook view in use on this logical processor and the view assignments it follows.
+    /// - Size: 272 bytes (0x110)
+    pub hook_view: ProcessorHookView,
+
+    /// The EPT view this logical processor runs in and the view assigned to it.
+    /// - Size: 16 bytes (0x10)
+    pub ept_view: ProcessorEptView,
+
+    /// The process owning the current address space of this logical processor and the owners seen recently.
+    /// - Size: 584 bytes (0x248)
+    pub process_context: ProcessContext,
+
+    /// The state of the exception telemetry on this logical processor.
+    /// - Size: 8 bytes (0x8)
+    pub exception_telemetry: ProcessorExceptionTelemetry,
+
+    /// The state of the TSC compensation on this logical processor.
+    /// - Size: 96 bytes (0x60)
+    pub tsc_compensation: ProcessorTscCompensation,
+
+    /// The VM exits of this logical processor during the measurement window of the benchmark run.
+    /// - Size: 1832 bytes (0x728)
+    pub benchmark: ProcessorBenchmark,
+
+    /// The debug registers of this logical processor as observed by the guest, and the hypervisor breakpoints in them.
+    /// - Size: 128 bytes (0x80)
+    pub debug_registers: ProcessorDebugRegisters,
 
-    /// The number of times the MTF (Monitor Trap Flag) should be triggered before disabling it for restoring overwritten instructions.
-    /// - Size: 8 bytes (Option<u64>) (0x8)
-    pub mtf_counter: Option<u64>,
+    /// The vectors intercepted by the exception hooks in the exception bitmap of this logical processor.
+    /// - Size: 16 bytes (0x10)
+    pub exception_hooks: ProcessorExceptionHooks,
+
+    /// The CPUID overrides and the hypervisor presence in use on this logical processor.
+    /// - Size: 1,816 bytes (0x718)
+    pub cpuid_hooks: ProcessorCpuidHooks,
+
+    /// The NMIs and external interrupts waiting for the guest of this logical processor to accept them.
+    /// - Size: 40 bytes (0x28)
+    pub pending_events: PendingEvents,
+
+    /// The generation of the last broadcast command executed on this logical processor and its polling interval.
+    /// - Size: 24 bytes (0x18)
+    pub broadcast: ProcessorBroadcast,
+
+    /// The TSS and IST stacks of the host exception handlers on this logical processor, and its last VM exits.
+    /// - Size: 49,664 bytes (0xC200)
+    pub host_exceptions: ProcessorHostExceptions,
 
     /// The CPUID feature information for the VM.
     pub cpuid_feature_info: FeatureInfo,
@@ -124,6 +278,10 @@ impl Vm {
         trace!("Building Identity Paging for Host");
         self.host_paging.build_identity();
 
+        trace!("Unmapping the Guard Page of the Host Stack");
+        let host_stack = current_host_stack()?;
+        self.host_paging.unmap_guard_page(host_stack.guard_page())?;
+
         trace!("Initializing Primary EPT");
         self.primary_ept.init();
 
@@ -133,15 +291,104 @@ impl Vm {
         trace!("Creating primary EPTP with WB and 4-level walk");
         self.primary_eptp = self.primary_ept.create_eptp_with_wb_and_4lvl_walk()?;
 
+        trace!("Allocating VPID");
+        self.vpid = allocate_vpid()?;
+        trace!("VPID: {:#x}", self.vpid);
+
+        trace!("Initializing MSR Bitmap");
+        self.msr_bitmap = MsrBitmap::new();
+
+        trace!("Modifying MSR interception for the hooked MSRs");
+        self.msr_hook_generation = Generation::STALE;
+        self.msr_bitmap_profile_generation = Generation::STALE;
+        sync_msr_hooks(self);
+
+        trace!("Initializing I/O Bitmap");
+        self.io_bitmap = IoBitmap::new();
+
         trace!("Initializing Guest Registers");
         self.guest_registers = guest_registers.clone();
 
+        trace!("Initializing Syscall Hook Generation");
+        self.syscall_hook_generation = Generation::STALE;
+
+        trace!("Initializing Published Generation");
+        self.published_generation = Generation::STALE;
+
+        trace!("Initializing Shared Page State");
+        self.shared_page_mapping = None;
+
+        trace!("Initializing Asynchronous Transfer State");
+        self.async_transfer = None;
+
+        trace!("Initializing Exit Storm Monitor");
+        self.exit_storm_monitor = ExitStormMonitor::new();
+
+        trace!("Initializing Exit Statistics");
+        self.exit_statistics = ProcessorExitStatistics::new();
+
+        trace!("Initializing Sampling Profiler");
+        self.profiler = ProcessorProfiler::new();
+
+        trace!("Initializing Watchdog");
+        self.watchdog = ProcessorWatchdog::new();
+
+        trace!("Initializing Scheduler");
+        self.scheduler = ProcessorScheduler::new();
+
+        trace!("Initializing Deterministic Mode Generation");
+        self.deterministic_mode_generation = Generation::default();
+
+        trace!("Initializing Hook View");
+        self.hook_view = ProcessorHookView::new();
+
+        trace!("Initializing EPT View");
+        self.ept_view = ProcessorEptView::new();
+
+        trace!("Initializing Process Context");
+        self.process_context = ProcessContext::new();
+
+        trace!("Initializing Exception Telemetry");
+        self.exception_telemetry = ProcessorExceptionTelemetry::new();
+
+        trace!("Initializing TSC Compensation");
+        self.tsc_compensation = ProcessorTscCompensation::new();
+
+        trace!("Initializing Benchmark");
+        self.benchmark = ProcessorBenchmark::new();
+
+        trace!("Initializing Debug Registers");
+        self.debug_registers = ProcessorDebugRegisters::new();
+
+        trace!("Initializing Exception Hooks");
+        self.exception_hooks = ProcessorExceptionHooks::new();
+
+        trace!("Initializing CPUID Hooks");
+        self.cpuid_hooks = ProcessorCpuidHooks::new();
+
+        trace!("Initializing Pending Events");
+        self.pending_events = PendingEvents::new();
+
+        trace!("Initializing Broadcast State");
+        self.broadcast = ProcessorBroadcast::new();
+
+        trace!("Initializing Host Exceptions");
+        self.host_exceptions.init();
+
         trace!("Initializing Launch State");
         self.has_launched = false;
 
-        trace!("Initializing Old RFLAGS and MTF Counter");
-        self.old_rflags = None;
-        self.mtf_counter = None;
+        trace!("Initializing Single-Step Engine");
+        self.single_step = SingleStepEngine::new();
+        self.mtf_resync_page = None;
+        self.mtf_hook_write = None;
+        self.mtf_hook_restoration = None;
+
+        trace!("Initializing Hidden Time");
+        self.hidden_tsc_ticks = 0;
+
+        trace!("Initializing Hidden Memory Range Count");
+        self.hidden_memory_range_count = 0;
 
         trace!("Getting and Setting CPUID Feature Information and XCR0 Unsupported Mask");
         let cpuid_ext_state_info = cpuid!(0x0d, 0x00);
@@ -238,10 +485,8 @@ impl Vm {
 
         let primary_eptp = self.primary_eptp;
 
-        // Lock the shared hook manager
-        let hook_manager = SHARED_HOOK_MANAGER.lock();
-
-        let msr_bitmap = &hook_manager.msr_bitmap as *const _ as u64;
+        let msr_bitmap = &self.msr_bitmap as *const _ as u64;
+        let io_bitmap = &self.io_bitmap as *const _ as u64;
 
         // Lock the descriptor manager
         let descriptor_manager = SHARED_DESCRIPTOR_MANAGER.lock();
@@ -251,9 +496,9 @@ impl Vm {
 
         let pml4_pa = self.host_paging.get_pml4_pa()?;
 
-        Vmcs::setup_guest_registers_state(guest_descriptors, &self.guest_registers);
-        Vmcs::setup_host_registers_state(&host_descriptors, pml4_pa)?;
-        Vmcs::setup_vmcs_control_fields(primary_eptp, msr_bitmap)?;
+        Vmcs::setup_guest_registers_state(guest_descriptors, &self.guest_registers)?;
+        Vmcs::setup_host_registers_state(host_descriptors, &self.host_exceptions, pml4_pa)?;
+        Vmcs::setup_vmcs_control_fields(primary_eptp, msr_bitmap, io_bitmap, self.vpid)?;
 
         trace!("VMCS setup successfully!");
 
@@ -278,11 +523,11 @@ impl Vm {
 
         // VM-exit occurred. Copy the guest register values from VMCS so that
         // `self.registers` is complete and up to date.
-        self.guest_registers.rip = vmread(vmcs::guest::RIP);
-        self.guest_registers.rsp = vmread(vmcs::guest::RSP);
-        self.guest_registers.rflags = vmread(vmcs::guest::RFLAGS);
+        self.guest_registers.rip = VmcsField::GuestRip.read()?;
+        self.guest_registers.rsp = VmcsField::GuestRsp.read()?;
+        self.guest_registers.rflags = VmcsField::GuestRflags.read()?;
 
-        let exit_reason = vmread(vmcs::ro::EXIT_REASON) as u32;
+        let exit_reason = VmcsField::ExitReason.read()? as u32;
 
         let Some(basic_exit_reason) = VmxBasicExitReason::from_u32(exit_reason) else {
             error!("Unknown exit reason: {:#x}", exit_reason);
@@ -306,17 +551,7 @@ impl Vm {
     /// - 31.4 VM INSTRUCTION ERROR NUMBERS
     fn vm_succeed(flags: RFlags) -> Result<(), HypervisorError> {
         if flags.contains(RFlags::FLAGS_ZF) {
-            let instruction_error = vmread(vmcs::ro::VM_INSTRUCTION_ERROR) as u32;
-            return match VmInstructionError::from_u32(instruction_error) {
-                Some(error) => {
-                    error!("VM instruction error: {:?}", error);
-                    Err(HypervisorError::VmInstructionError)
-                }
-                None => {
-                    error!("Unknown VM instruction error: {:#x}", instruction_error);
-                    Err(HypervisorError::UnknownVMInstructionError)
-                }
-            };
+            return Err(vm_instruction_error());
         } else if flags.contains(RFlags::FLAGS_CF) {
             error!("VM instruction failed due to carry flag being set");
             return Err(HypervisorError::VMFailToLaunch);
@@ -3,6 +3,8 @@
 //! This crate provides functionality to set up the VMCS region in memory, which
 //! is vital for VMX operations on the CPU. It also offers utility functions for
 //! adjusting VMCS entries and displaying VMCS state for debugging purposes.
+//! The fields are accessed through the checked `VmcsField`, so a mistake in the setup fails with its
+//! VM-instruction error instead of the VM entry.
 
 use {
     crate::{
@@ -11,10 +13,13 @@ use {
             capture::GuestRegisters,
             controls::{adjust_vmx_controls, VmxControl},
             descriptor::Descriptors,
-            invept::invept_single_context,
-            invvpid::{invvpid_single_context, VPID_TAG},
+            host_exception::ProcessorHostExceptions,
+            invept::invept_eptp,
+            invvpid::invvpid_single_context,
             segmentation::{access_rights_from_native, lar, lsl},
-            support::{cr3, rdmsr, sidt, vmread, vmwrite},
+            support::{cr3, rdmsr, sidt},
+            vmcs_field::VmcsField,
+            vmerror::ExceptionInterrupt,
         },
     },
     bit_field::BitField,
@@ -29,6 +34,14 @@ use {
     x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
 };
 
+/// The CR0 bits owned by the host besides the ones fixed in VMX operation: CR0.CD and CR0.NW change the memory
+/// types cached with the EPT-derived mappings, and clearing CR0.WP is checked against CR4.CET.
+const CR0_HOST_OWNED_BITS: u64 = Cr0Flags::CACHE_DISABLE.bits() | Cr0Flags::NOT_WRITE_THROUGH.bits() | Cr0Flags::WRITE_PROTECT.bits();
+
+/// The CR4 bits owned by the host besides the ones fixed in VMX operation: CR4.VMXE, fixed to 1 but hidden from the
+/// guest, VMX not being exposed to it.
+const CR4_HOST_OWNED_BITS: u64 = Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits();
+
 /// Represents the VMCS region in memory.
 ///
 /// The VMCS region is essential for VMX operations on the CPU.
@@ -58,61 +71,69 @@ impl Vmcs {
     /// # Arguments
     /// * `guest_descriptor` - Descriptor tables for the guest.
     /// * `guest_registers` - Guest registers for the guest.
-    pub fn setup_guest_registers_state(guest_descriptor: &Descriptors, guest_registers: &GuestRegisters) {
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - A result indicating the success or failure of the operation.
+    pub fn setup_guest_registers_state(guest_descriptor: &Descriptors, guest_registers: &GuestRegisters) -> Result<(), HypervisorError> {
         log::debug!("Setting up Guest Registers State");
 
         let idtr = sidt();
 
-        vmwrite(vmcs::guest::CR0, Cr0::read_raw());
-        vmwrite(vmcs::guest::CR3, cr3());
-        vmwrite(vmcs::guest::CR4, Cr4::read_raw());
+        VmcsField::GuestCr0.write(Cr0::read_raw())?;
+        VmcsField::GuestCr3.write(cr3())?;
+        VmcsField::GuestCr4.write(Cr4::read_raw())?;
 
-        vmwrite(vmcs::guest::DR7, unsafe { dr7().0 as u64 });
+        VmcsField::GuestDr7.write(unsafe { dr7().0 as u64 })?;
 
-        vmwrite(vmcs::guest::RSP, guest_registers.rsp);
-        vmwrite(vmcs::guest::RIP, guest_registers.rip);
-        vmwrite(vmcs::guest::RFLAGS, rflags::read().bits());
+        VmcsField::GuestRsp.write(guest_registers.rsp)?;
+        VmcsField::GuestRip.write(guest_registers.rip)?;
+        VmcsField::GuestRflags.write(rflags::read().bits())?;
 
-        vmwrite(vmcs::guest::CS_SELECTOR, cs().bits());
-        vmwrite(vmcs::guest::SS_SELECTOR, ss().bits());
-        vmwrite(vmcs::guest::DS_SELECTOR, ds().bits());
-        vmwrite(vmcs::guest::ES_SELECTOR, es().bits());
-        vmwrite(vmcs::guest::FS_SELECTOR, fs().bits());
-        vmwrite(vmcs::guest::GS_SELECTOR, gs().bits());
+        VmcsField::GuestCsSelector.write(cs().bits())?;
+        VmcsField::GuestSsSelector.write(ss().bits())?;
+        VmcsField::GuestDsSelector.write(ds().bits())?;
+        VmcsField::GuestEsSelector.write(es().bits())?;
+        VmcsField::GuestFsSelector.write(fs().bits())?;
+        VmcsField::GuestGsSelector.write(gs().bits())?;
 
-        vmwrite(vmcs::guest::LDTR_SELECTOR, 0u16);
-        vmwrite(vmcs::guest::TR_SELECTOR, guest_descriptor.tr.bits());
+        VmcsField::GuestLdtrSelector.write(0u16)?;
+        VmcsField::GuestTrSelector.write(guest_descriptor.tr.bits())?;
 
         // All segment base registers are assumed to be zero, except that of TR.
-        vmwrite(vmcs::guest::TR_BASE, guest_descriptor.tss.base);
-
-        vmwrite(vmcs::guest::CS_LIMIT, lsl(ss()));
-        vmwrite(vmcs::guest::SS_LIMIT, lsl(ss()));
-        vmwrite(vmcs::guest::DS_LIMIT, lsl(ds()));
-        vmwrite(vmcs::guest::ES_LIMIT, lsl(es()));
-        vmwrite(vmcs::guest::FS_LIMIT, lsl(fs()));
-        vmwrite(vmcs::guest::GS_LIMIT, lsl(gs()));
-        vmwrite(vmcs::guest::LDTR_LIMIT, 0u32);
-        vmwrite(vmcs::guest::TR_LIMIT, guest_descriptor.tr.bits());
-
-        vmwrite(vmcs::guest::CS_ACCESS_RIGHTS, access_rights_from_native(lar(cs())) as u64);
-        vmwrite(vmcs::guest::SS_ACCESS_RIGHTS, access_rights_from_native(lar(ss())) as u64);
-        vmwrite(vmcs::guest::DS_ACCESS_RIGHTS, access_rights_from_native(lar(ds())) as u64);
-        vmwrite(vmcs::guest::ES_ACCESS_RIGHTS, access_rights_from_native(lar(es())) as u64);
-        vmwrite(vmcs::guest::FS_ACCESS_RIGHTS, access_rights_from_native(lar(fs())) as u64);
-        vmwrite(vmcs::guest::GS_ACCESS_RIGHTS, access_rights_from_native(lar(gs())) as u64);
-        vmwrite(vmcs::guest::LDTR_ACCESS_RIGHTS, access_rights_from_native(0u32));
-        vmwrite(vmcs::guest::TR_ACCESS_RIGHTS, access_rights_from_native(guest_descriptor.tss.ar));
-
-        vmwrite(vmcs::guest::GDTR_BASE, guest_descriptor.gdtr.base as u64);
-        vmwrite(vmcs::guest::IDTR_BASE, idtr.base as u64);
-
-        vmwrite(vmcs::guest::GDTR_LIMIT, guest_descriptor.gdtr.limit as u64);
-        vmwrite(vmcs::guest::IDTR_LIMIT, idtr.limit as u64);
-
-        vmwrite(vmcs::guest::LINK_PTR_FULL, u64::MAX);
+        VmcsField::GuestTrBase.write(guest_descriptor.tss.base)?;
+
+        VmcsField::GuestCsLimit.write(lsl(ss()))?;
+        VmcsField::GuestSsLimit.write(lsl(ss()))?;
+        VmcsField::GuestDsLimit.write(lsl(ds()))?;
+        VmcsField::GuestEsLimit.write(lsl(es()))?;
+        VmcsField::GuestFsLimit.write(lsl(fs()))?;
+        VmcsField::GuestGsLimit.write(lsl(gs()))?;
+        VmcsField::GuestLdtrLimit.write(0u32)?;
+        VmcsField::GuestTrLimit.write(guest_descriptor.tr.bits())?;
+
+        VmcsField::GuestCsAccessRights.write(access_rights_from_native(lar(cs())) as u64)?;
+        VmcsField::GuestSsAccessRights.write(access_rights_from_native(lar(ss())) as u64)?;
+        VmcsField::GuestDsAccessRights.write(access_rights_from_native(lar(ds())) as u64)?;
+        VmcsField::GuestEsAccessRights.write(access_rights_from_native(lar(es())) as u64)?;
+        VmcsField::GuestFsAccessRights.write(access_rights_from_native(lar(fs())) as u64)?;
+        VmcsField::GuestGsAccessRights.write(access_rights_from_native(lar(gs())) as u64)?;
+        VmcsField::GuestLdtrAccessRights.write(access_rights_from_native(0u32))?;
+        VmcsField::GuestTrAccessRights.write(access_rights_from_native(guest_descriptor.tss.ar))?;
+
+        VmcsField::GuestGdtrBase.write(guest_descriptor.gdtr.base as u64)?;
+        VmcsField::GuestIdtrBase.write(idtr.base as u64)?;
+
+        VmcsField::GuestGdtrLimit.write(guest_descriptor.gdtr.limit as u64)?;
+        VmcsField::GuestIdtrLimit.write(idtr.limit as u64)?;
+
+        VmcsField::GuestIa32Efer.write(rdmsr(msr::IA32_EFER))?;
+
+        VmcsField::GuestLinkPointer.write(u64::MAX)?;
 
         log::debug!("Guest Registers State setup successfully!");
+
+        Ok(())
     }
 
     /// Initialize the host state for the currently loaded VMCS.
@@ -122,20 +143,29 @@ impl Vmcs {
     ///
     /// # Arguments
     /// * `host_descriptor` - Descriptor tables for the host.
+    /// * `host_exceptions` - The TSS of the host exception handlers of the logical processor.
     /// * `host_paging` - Paging tables for the host.
-    pub fn setup_host_registers_state(host_descriptor: &Descriptors, pml4_pa: u64) -> Result<(), HypervisorError> {
+    pub fn setup_host_registers_state(
+        host_descriptor: &Descriptors,
+        host_exceptions: &ProcessorHostExceptions,
+        pml4_pa: u64,
+    ) -> Result<(), HypervisorError> {
         log::debug!("Setting up Host Registers State");
 
-        vmwrite(vmcs::host::CR0, Cr0::read_raw());
-        vmwrite(vmcs::host::CR3, pml4_pa);
-        vmwrite(vmcs::host::CR4, Cr4::read_raw());
+        VmcsField::HostCr0.write(Cr0::read_raw())?;
+        VmcsField::HostCr3.write(pml4_pa)?;
+        VmcsField::HostCr4.write(Cr4::read_raw())?;
 
-        vmwrite(vmcs::host::CS_SELECTOR, host_descriptor.cs.bits());
-        vmwrite(vmcs::host::TR_SELECTOR, host_descriptor.tr.bits());
+        VmcsField::HostCsSelector.write(host_descriptor.cs.bits())?;
+        VmcsField::HostTrSelector.write(host_descriptor.tr.bits())?;
 
-        vmwrite(vmcs::host::TR_BASE, host_descriptor.tss.base);
-        vmwrite(vmcs::host::GDTR_BASE, host_descriptor.gdtr.base as u64);
-        vmwrite(vmcs::host::IDTR_BASE, u64::MAX); // Bogus. No proper exception handling.
+        // Each logical processor has its own TSS for its IST stacks, and its GS base points to its state.
+        VmcsField::HostTrBase.write(host_exceptions.tss_base())?;
+        VmcsField::HostGsBase.write(host_exceptions.gs_base())?;
+        VmcsField::HostGdtrBase.write(host_descriptor.gdtr.base as u64)?;
+        VmcsField::HostIdtrBase.write(host_descriptor.idtr.base as u64)?;
+
+        VmcsField::HostIa32Efer.write(rdmsr(msr::IA32_EFER))?;
 
         log::debug!("Host Registers State setup successfully!");
 
@@ -154,15 +184,18 @@ impl Vmcs {
     ///
     /// * `primary_eptp` - The EPTP value for the primary EPT.
     /// * `msr_bitmap` - The physical address of the MSR bitmap.
+    /// * `io_bitmap` - The physical address of the I/O bitmaps A and B, which are contiguous.
+    /// * `vpid` - The Virtual Processor Identifier (VPID) of the logical processor.
     ///
     /// # Returns
     ///
     /// * `Result<(), HypervisorError>` - A result indicating the success or failure of the operation.
-    pub fn setup_vmcs_control_fields(primary_eptp: u64, msr_bitmap: u64) -> Result<(), HypervisorError> {
+    pub fn setup_vmcs_control_fields(primary_eptp: u64, msr_bitmap: u64, io_bitmap: u64, vpid: u16) -> Result<(), HypervisorError> {
         log::debug!("Setting up VMCS Control Fields");
 
-        const PRIMARY_CTL: u64 =
-            (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits() | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits()) as u64;
+        const PRIMARY_CTL: u64 = (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits()
+            | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits()
+            | vmcs::control::PrimaryControls::USE_IO_BITMAPS.bits()) as u64;
         const SECONDARY_CTL: u64 = (vmcs::control::SecondaryControls::ENABLE_RDTSCP.bits()
             | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS.bits()
             | vmcs::control::SecondaryControls::ENABLE_INVPCID.bits()
@@ -170,19 +203,26 @@ impl Vmcs {
             | vmcs::control::SecondaryControls::ENABLE_EPT.bits()
             | vmcs::control::SecondaryControls::CONCEAL_VMX_FROM_PT.bits()
             | vmcs::control::SecondaryControls::UNRESTRICTED_GUEST.bits()) as u64;
+        // The guest EFER is switched on VM entries and exits, as the application processors started by INIT-SIPI-SIPI
+        // leave long mode (see the `init` module).
         const ENTRY_CTL: u64 = (vmcs::control::EntryControls::IA32E_MODE_GUEST.bits()
             | vmcs::control::EntryControls::LOAD_DEBUG_CONTROLS.bits()
+            | vmcs::control::EntryControls::LOAD_IA32_EFER.bits()
             | vmcs::control::EntryControls::CONCEAL_VMX_FROM_PT.bits()) as u64;
         const EXIT_CTL: u64 = (vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits()
             | vmcs::control::ExitControls::SAVE_DEBUG_CONTROLS.bits()
+            | vmcs::control::ExitControls::SAVE_IA32_EFER.bits()
+            | vmcs::control::ExitControls::LOAD_IA32_EFER.bits()
             | vmcs::control::ExitControls::CONCEAL_VMX_FROM_PT.bits()) as u64;
-        const PINBASED_CTL: u64 = 0;
+        // The NMIs received while the guest runs are reflected through the pending-event queue, so the NMI-window
+        // exiting can be requested with virtual NMIs (see the `events` module).
+        const PINBASED_CTL: u64 = (vmcs::control::PinbasedControls::NMI_EXITING.bits() | vmcs::control::PinbasedControls::VIRTUAL_NMIS.bits()) as u64;
 
-        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased, PRIMARY_CTL));
-        vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL));
-        vmwrite(vmcs::control::VMENTRY_CONTROLS, adjust_vmx_controls(VmxControl::VmEntry, ENTRY_CTL));
-        vmwrite(vmcs::control::VMEXIT_CONTROLS, adjust_vmx_controls(VmxControl::VmExit, EXIT_CTL));
-        vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::PinBased, PINBASED_CTL));
+        VmcsField::PrimaryProcbasedExecControls.write(adjust_vmx_controls(VmxControl::ProcessorBased, PRIMARY_CTL))?;
+        VmcsField::SecondaryProcbasedExecControls.write(adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL))?;
+        VmcsField::VmentryControls.write(adjust_vmx_controls(VmxControl::VmEntry, ENTRY_CTL))?;
+        VmcsField::VmexitControls.write(adjust_vmx_controls(VmxControl::VmExit, EXIT_CTL))?;
+        VmcsField::PinbasedExecControls.write(adjust_vmx_controls(VmxControl::PinBased, PINBASED_CTL))?;
 
         let vmx_cr0_fixed0 = unsafe { msr::rdmsr(msr::IA32_VMX_CR0_FIXED0) };
         let vmx_cr0_fixed1 = unsafe { msr::rdmsr(msr::IA32_VMX_CR0_FIXED1) };
@@ -191,23 +231,25 @@ impl Vmcs {
         let vmx_cr4_fixed1 = unsafe { msr::rdmsr(msr::IA32_VMX_CR4_FIXED1) };
 
         // Credits to @vmctx
-        vmwrite(
-            vmcs::control::CR0_GUEST_HOST_MASK,
-            vmx_cr0_fixed0 | !vmx_cr0_fixed1 | Cr0Flags::CACHE_DISABLE.bits() | Cr0Flags::WRITE_PROTECT.bits(),
-        );
-        vmwrite(vmcs::control::CR4_GUEST_HOST_MASK, vmx_cr4_fixed0 | !vmx_cr4_fixed1);
+        // The bits fixed in VMX operation and the host-owned bits are read from the read shadows, and writing them
+        // causes a VM exit (see `vmexit::cr`), so CR4.VMXE is never seen set by the guest.
+        VmcsField::Cr0GuestHostMask.write(vmx_cr0_fixed0 | !vmx_cr0_fixed1 | CR0_HOST_OWNED_BITS)?;
+        VmcsField::Cr4GuestHostMask.write(vmx_cr4_fixed0 | !vmx_cr4_fixed1 | CR4_HOST_OWNED_BITS)?;
 
-        vmwrite(vmcs::control::CR0_READ_SHADOW, Cr0::read_raw());
-        vmwrite(vmcs::control::CR4_READ_SHADOW, Cr4::read_raw() & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits());
+        VmcsField::Cr0ReadShadow.write(Cr0::read_raw())?;
+        VmcsField::Cr4ReadShadow.write(Cr4::read_raw() & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits())?;
 
-        vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, msr_bitmap);
-        //vmwrite(vmcs::control::EXCEPTION_BITMAP, 1u64 << (ExceptionInterrupt::Breakpoint as u32));
+        VmcsField::MsrBitmapsAddress.write(msr_bitmap)?;
+        VmcsField::IoBitmapAAddress.write(io_bitmap)?;
+        VmcsField::IoBitmapBAddress.write(io_bitmap + 0x1000)?;
+        // Intercept breakpoints for the breakpoint hooks, other breakpoints are injected back into the guest.
+        VmcsField::ExceptionBitmap.write(1u64 << (ExceptionInterrupt::Breakpoint as u32))?;
 
-        vmwrite(vmcs::control::EPTP_FULL, primary_eptp);
-        vmwrite(vmcs::control::VPID, VPID_TAG);
+        VmcsField::Eptp.write(primary_eptp)?;
+        VmcsField::Vpid.write(vpid)?;
 
-        invept_single_context(primary_eptp);
-        invvpid_single_context(VPID_TAG);
+        invept_eptp(primary_eptp);
+        invvpid_single_context(vpid);
 
         log::debug!("VMCS Control Fields setup successfully!");
 
@@ -225,93 +267,96 @@ impl fmt::Debug for Vmcs {
     /// # Returns
     /// Formatting result.
     fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A field failing to be read is logged and shown as 0.
+        let read = |field: VmcsField| field.read().unwrap_or(0);
+
         format
             .debug_struct("Vmcs")
             .field("Current VMCS: ", &(self as *const _))
             .field("Revision ID: ", &self.revision_id)
             /* VMCS Guest state fields */
-            .field("Guest CR0: ", &vmread(vmcs::guest::CR0))
-            .field("Guest CR3: ", &vmread(vmcs::guest::CR3))
-            .field("Guest CR4: ", &vmread(vmcs::guest::CR4))
-            .field("Guest DR7: ", &vmread(vmcs::guest::DR7))
-            .field("Guest RSP: ", &vmread(vmcs::guest::RSP))
-            .field("Guest RIP: ", &vmread(vmcs::guest::RIP))
-            .field("Guest RFLAGS: ", &vmread(vmcs::guest::RFLAGS))
-            .field("Guest CS Selector: ", &vmread(vmcs::guest::CS_SELECTOR))
-            .field("Guest SS Selector: ", &vmread(vmcs::guest::SS_SELECTOR))
-            .field("Guest DS Selector: ", &vmread(vmcs::guest::DS_SELECTOR))
-            .field("Guest ES Selector: ", &vmread(vmcs::guest::ES_SELECTOR))
-            .field("Guest FS Selector: ", &vmread(vmcs::guest::FS_SELECTOR))
-            .field("Guest GS Selector: ", &vmread(vmcs::guest::GS_SELECTOR))
-            .field("Guest LDTR Selector: ", &vmread(vmcs::guest::LDTR_SELECTOR))
-            .field("Guest TR Selector: ", &vmread(vmcs::guest::TR_SELECTOR))
-            .field("Guest CS Base: ", &vmread(vmcs::guest::CS_BASE))
-            .field("Guest SS Base: ", &vmread(vmcs::guest::SS_BASE))
-            .field("Guest DS Base: ", &vmread(vmcs::guest::DS_BASE))
-            .field("Guest ES Base: ", &vmread(vmcs::guest::ES_BASE))
-            .field("Guest FS Base: ", &vmread(vmcs::guest::FS_BASE))
-            .field("Guest GS Base: ", &vmread(vmcs::guest::GS_BASE))
-            .field("Guest LDTR Base: ", &vmread(vmcs::guest::LDTR_BASE))
-            .field("Guest TR Base: ", &vmread(vmcs::guest::TR_BASE))
-            .field("Guest CS Limit: ", &vmread(vmcs::guest::CS_LIMIT))
-            .field("Guest SS Limit: ", &vmread(vmcs::guest::SS_LIMIT))
-            .field("Guest DS Limit: ", &vmread(vmcs::guest::DS_LIMIT))
-            .field("Guest ES Limit: ", &vmread(vmcs::guest::ES_LIMIT))
-            .field("Guest FS Limit: ", &vmread(vmcs::guest::FS_LIMIT))
-            .field("Guest GS Limit: ", &vmread(vmcs::guest::GS_LIMIT))
-            .field("Guest LDTR Limit: ", &vmread(vmcs::guest::LDTR_LIMIT))
-            .field("Guest TR Limit: ", &vmread(vmcs::guest::TR_LIMIT))
-            .field("Guest CS Access Rights: ", &vmread(vmcs::guest::CS_ACCESS_RIGHTS))
-            .field("Guest SS Access Rights: ", &vmread(vmcs::guest::SS_ACCESS_RIGHTS))
-            .field("Guest DS Access Rights: ", &vmread(vmcs::guest::DS_ACCESS_RIGHTS))
-            .field("Guest ES Access Rights: ", &vmread(vmcs::guest::ES_ACCESS_RIGHTS))
-            .field("Guest FS Access Rights: ", &vmread(vmcs::guest::FS_ACCESS_RIGHTS))
-            .field("Guest GS Access Rights: ", &vmread(vmcs::guest::GS_ACCESS_RIGHTS))
-            .field("Guest LDTR Access Rights: ", &vmread(vmcs::guest::LDTR_ACCESS_RIGHTS))
-            .field("Guest TR Access Rights: ", &vmread(vmcs::guest::TR_ACCESS_RIGHTS))
-            .field("Guest GDTR Base: ", &vmread(vmcs::guest::GDTR_BASE))
-            .field("Guest IDTR Base: ", &vmread(vmcs::guest::IDTR_BASE))
-            .field("Guest GDTR Limit: ", &vmread(vmcs::guest::GDTR_LIMIT))
-            .field("Guest IDTR Limit: ", &vmread(vmcs::guest::IDTR_LIMIT))
-            .field("Guest IA32_DEBUGCTL_FULL: ", &vmread(vmcs::guest::IA32_DEBUGCTL_FULL))
-            .field("Guest IA32_SYSENTER_CS: ", &vmread(vmcs::guest::IA32_SYSENTER_CS))
-            .field("Guest IA32_SYSENTER_ESP: ", &vmread(vmcs::guest::IA32_SYSENTER_ESP))
-            .field("Guest IA32_SYSENTER_EIP: ", &vmread(vmcs::guest::IA32_SYSENTER_EIP))
-            .field("Guest IA32_EFER_FULL: ", &vmread(vmcs::guest::IA32_EFER_FULL))
-            .field("Guest VMCS Link Pointer: ", &vmread(vmcs::guest::LINK_PTR_FULL))
-            .field("Guest Activity State: ", &vmread(vmcs::guest::ACTIVITY_STATE))
+            .field("Guest CR0: ", &read(VmcsField::GuestCr0))
+            .field("Guest CR3: ", &read(VmcsField::GuestCr3))
+            .field("Guest CR4: ", &read(VmcsField::GuestCr4))
+            .field("Guest DR7: ", &read(VmcsField::GuestDr7))
+            .field("Guest RSP: ", &read(VmcsField::GuestRsp))
+            .field("Guest RIP: ", &read(VmcsField::GuestRip))
+            .field("Guest RFLAGS: ", &read(VmcsField::GuestRflags))
+            .field("Guest CS Selector: ", &read(VmcsField::GuestCsSelector))
+            .field("Guest SS Selector: ", &read(VmcsField::GuestSsSelector))
+            .field("Guest DS Selector: ", &read(VmcsField::GuestDsSelector))
+            .field("Guest ES Selector: ", &read(VmcsField::GuestEsSelector))
+            .field("Guest FS Selector: ", &read(VmcsField::GuestFsSelector))
+            .field("Guest GS Selector: ", &read(VmcsField::GuestGsSelector))
+            .field("Guest LDTR Selector: ", &read(VmcsField::GuestLdtrSelector))
+            .field("Guest TR Selector: ", &read(VmcsField::GuestTrSelector))
+            .field("Guest CS Base: ", &read(VmcsField::GuestCsBase))
+            .field("Guest SS Base: ", &read(VmcsField::GuestSsBase))
+            .field("Guest DS Base: ", &read(VmcsField::GuestDsBase))
+            .field("Guest ES Base: ", &read(VmcsField::GuestEsBase))
+            .field("Guest FS Base: ", &read(VmcsField::GuestFsBase))
+            .field("Guest GS Base: ", &read(VmcsField::GuestGsBase))
+            .field("Guest LDTR Base: ", &read(VmcsField::GuestLdtrBase))
+            .field("Guest TR Base: ", &read(VmcsField::GuestTrBase))
+            .field("Guest CS Limit: ", &read(VmcsField::GuestCsLimit))
+            .field("Guest SS Limit: ", &read(VmcsField::GuestSsLimit))
+            .field("Guest DS Limit: ", &read(VmcsField::GuestDsLimit))
+            .field("Guest ES Limit: ", &read(VmcsField::GuestEsLimit))
+            .field("Guest FS Limit: ", &read(VmcsField::GuestFsLimit))
+            .field("Guest GS Limit: ", &read(VmcsField::GuestGsLimit))
+            .field("Guest LDTR Limit: ", &read(VmcsField::GuestLdtrLimit))
+            .field("Guest TR Limit: ", &read(VmcsField::GuestTrLimit))
+            .field("Guest CS Access Rights: ", &read(VmcsField::GuestCsAccessRights))
+            .field("Guest SS Access Rights: ", &read(VmcsField::GuestSsAccessRights))
+            .field("Guest DS Access Rights: ", &read(VmcsField::GuestDsAccessRights))
+            .field("Guest ES Access Rights: ", &read(VmcsField::GuestEsAccessRights))
+            .field("Guest FS Access Rights: ", &read(VmcsField::GuestFsAccessRights))
+            .field("Guest GS Access Rights: ", &read(VmcsField::GuestGsAccessRights))
+            .field("Guest LDTR Access Rights: ", &read(VmcsField::GuestLdtrAccessRights))
+            .field("Guest TR Access Rights: ", &read(VmcsField::GuestTrAccessRights))
+            .field("Guest GDTR Base: ", &read(VmcsField::GuestGdtrBase))
+            .field("Guest IDTR Base: ", &read(VmcsField::GuestIdtrBase))
+            .field("Guest GDTR Limit: ", &read(VmcsField::GuestGdtrLimit))
+            .field("Guest IDTR Limit: ", &read(VmcsField::GuestIdtrLimit))
+            .field("Guest IA32_DEBUGCTL_FULL: ", &read(VmcsField::GuestIa32Debugctl))
+            .field("Guest IA32_SYSENTER_CS: ", &read(VmcsField::GuestIa32SysenterCs))
+            .field("Guest IA32_SYSENTER_ESP: ", &read(VmcsField::GuestIa32SysenterEsp))
+            .field("Guest IA32_SYSENTER_EIP: ", &read(VmcsField::GuestIa32SysenterEip))
+            .field("Guest IA32_EFER_FULL: ", &read(VmcsField::GuestIa32Efer))
+            .field("Guest VMCS Link Pointer: ", &read(VmcsField::GuestLinkPointer))
+            .field("Guest Activity State: ", &read(VmcsField::GuestActivityState))
             /* VMCS Host state fields */
-            .field("Host CR0: ", &vmread(vmcs::host::CR0))
-            .field("Host CR3: ", &vmread(vmcs::host::CR3))
-            .field("Host CR4: ", &vmread(vmcs::host::CR4))
-            .field("Host RSP: ", &vmread(vmcs::host::RSP))
-            .field("Host RIP: ", &vmread(vmcs::host::RIP))
-            .field("Host CS Selector: ", &vmread(vmcs::host::CS_SELECTOR))
-            .field("Host SS Selector: ", &vmread(vmcs::host::SS_SELECTOR))
-            .field("Host DS Selector: ", &vmread(vmcs::host::DS_SELECTOR))
-            .field("Host ES Selector: ", &vmread(vmcs::host::ES_SELECTOR))
-            .field("Host FS Selector: ", &vmread(vmcs::host::FS_SELECTOR))
-            .field("Host GS Selector: ", &vmread(vmcs::host::GS_SELECTOR))
-            .field("Host TR Selector: ", &vmread(vmcs::host::TR_SELECTOR))
-            .field("Host FS Base: ", &vmread(vmcs::host::FS_BASE))
-            .field("Host GS Base: ", &vmread(vmcs::host::GS_BASE))
-            .field("Host TR Base: ", &vmread(vmcs::host::TR_BASE))
-            .field("Host GDTR Base: ", &vmread(vmcs::host::GDTR_BASE))
-            .field("Host IDTR Base: ", &vmread(vmcs::host::IDTR_BASE))
-            .field("Host IA32_SYSENTER_CS: ", &vmread(vmcs::host::IA32_SYSENTER_CS))
-            .field("Host IA32_SYSENTER_ESP: ", &vmread(vmcs::host::IA32_SYSENTER_ESP))
-            .field("Host IA32_SYSENTER_EIP: ", &vmread(vmcs::host::IA32_SYSENTER_EIP))
+            .field("Host CR0: ", &read(VmcsField::HostCr0))
+            .field("Host CR3: ", &read(VmcsField::HostCr3))
+            .field("Host CR4: ", &read(VmcsField::HostCr4))
+            .field("Host RSP: ", &read(VmcsField::HostRsp))
+            .field("Host RIP: ", &read(VmcsField::HostRip))
+            .field("Host CS Selector: ", &read(VmcsField::HostCsSelector))
+            .field("Host SS Selector: ", &read(VmcsField::HostSsSelector))
+            .field("Host DS Selector: ", &read(VmcsField::HostDsSelector))
+            .field("Host ES Selector: ", &read(VmcsField::HostEsSelector))
+            .field("Host FS Selector: ", &read(VmcsField::HostFsSelector))
+            .field("Host GS Selector: ", &read(VmcsField::HostGsSelector))
+            .field("Host TR Selector: ", &read(VmcsField::HostTrSelector))
+            .field("Host FS Base: ", &read(VmcsField::HostFsBase))
+            .field("Host GS Base: ", &read(VmcsField::HostGsBase))
+            .field("Host TR Base: ", &read(VmcsField::HostTrBase))
+            .field("Host GDTR Base: ", &read(VmcsField::HostGdtrBase))
+            .field("Host IDTR Base: ", &read(VmcsField::HostIdtrBase))
+            .field("Host IA32_SYSENTER_CS: ", &read(VmcsField::HostIa32SysenterCs))
+            .field("Host IA32_SYSENTER_ESP: ", &read(VmcsField::HostIa32SysenterEsp))
+            .field("Host IA32_SYSENTER_EIP: ", &read(VmcsField::HostIa32SysenterEip))
             /* VMCS Control fields */
-            .field("Primary Proc Based Execution Controls: ", &vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS))
-            .field("Secondary Proc Based Execution Controls: ", &vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS))
-            .field("VM Entry Controls: ", &vmread(vmcs::control::VMENTRY_CONTROLS))
-            .field("VM Exit Controls: ", &vmread(vmcs::control::VMEXIT_CONTROLS))
-            .field("Pin Based Execution Controls: ", &vmread(vmcs::control::PINBASED_EXEC_CONTROLS))
-            .field("CR0 Read Shadow: ", &vmread(vmcs::control::CR0_READ_SHADOW))
-            .field("CR4 Read Shadow: ", &vmread(vmcs::control::CR4_READ_SHADOW))
-            .field("MSR Bitmaps Address: ", &vmread(vmcs::control::MSR_BITMAPS_ADDR_FULL))
-            .field("EPT Pointer: ", &vmread(vmcs::control::EPTP_FULL))
-            .field("VPID: ", &vmread(vmcs::control::VPID))
+            .field("Primary Proc Based Execution Controls: ", &read(VmcsField::PrimaryProcbasedExecControls))
+            .field("Secondary Proc Based Execution Controls: ", &read(VmcsField::SecondaryProcbasedExecControls))
+            .field("VM Entry Controls: ", &read(VmcsField::VmentryControls))
+            .field("VM Exit Controls: ", &read(VmcsField::VmexitControls))
+            .field("Pin Based Execution Controls: ", &read(VmcsField::PinbasedExecControls))
+            .field("CR0 Read Shadow: ", &read(VmcsField::Cr0ReadShadow))
+            .field("CR4 Read Shadow: ", &read(VmcsField::Cr4ReadShadow))
+            .field("MSR Bitmaps Address: ", &read(VmcsField::MsrBitmapsAddress))
+            .field("EPT Pointer: ", &read(VmcsField::Eptp))
+            .field("VPID: ", &read(VmcsField::Vpid))
             .finish_non_exhaustive()
     }
 }
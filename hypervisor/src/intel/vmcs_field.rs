@@ -0,0 +1,236 @@
+//! Provides checked accesses to the fields of the current VMCS, through the `VmcsField` encodings.
+//!
+//! `support::vmread` and `support::vmwrite` don't report the failures of VMREAD and VMWRITE, a read returning 0 and a
+//! write panicking without the reason, so a mistyped encoding or a value too wide for its field is only noticed once
+//! the VM entry fails. The setup of the VMCS goes through `VmcsField::read` and `VmcsField::write` instead, which
+//! check the value against the width of the field, decoded from its encoding, and return the VM-instruction error of
+//! a failed access.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.11.2 VMREAD, VMWRITE, and Encodings of
+//! VMCS Fields, 31.4 VM INSTRUCTION ERROR NUMBERS and Appendix B Field Encoding in VMCS
+
+use {
+    crate::{error::HypervisorError, intel::vmerror::VmInstructionError},
+    log::*,
+    x86::vmx::{vmcs, VmFail},
+};
+
+/// The width of a VMCS field, in bits 14:13 of its encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmcsFieldWidth {
+    /// A 16-bit field.
+    Word,
+
+    /// A 64-bit field, or the high 32 bits of one with the high access type.
+    Qword,
+
+    /// A 32-bit field.
+    Dword,
+
+    /// A natural-width field, 64-bit on processors supporting the Intel 64 architecture.
+    Natural,
+}
+
+impl VmcsFieldWidth {
+    /// Returns the bits a value written to a field of this width can have set.
+    pub const fn mask(self) -> u64 {
+        match self {
+            Self::Word => u16::MAX as u64,
+            Self::Dword => u32::MAX as u64,
+            Self::Qword | Self::Natural => u64::MAX,
+        }
+    }
+}
+
+/// The VMCS fields set up by the hypervisor, by encoding.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmcsField {
+    // Guest-state fields.
+    GuestActivityState = vmcs::guest::ACTIVITY_STATE,
+    GuestCr0 = vmcs::guest::CR0,
+    GuestCr3 = vmcs::guest::CR3,
+    GuestCr4 = vmcs::guest::CR4,
+    GuestCsAccessRights = vmcs::guest::CS_ACCESS_RIGHTS,
+    GuestCsBase = vmcs::guest::CS_BASE,
+    GuestCsLimit = vmcs::guest::CS_LIMIT,
+    GuestCsSelector = vmcs::guest::CS_SELECTOR,
+    GuestDr7 = vmcs::guest::DR7,
+    GuestDsAccessRights = vmcs::guest::DS_ACCESS_RIGHTS,
+    GuestDsBase = vmcs::guest::DS_BASE,
+    GuestDsLimit = vmcs::guest::DS_LIMIT,
+    GuestDsSelector = vmcs::guest::DS_SELECTOR,
+    GuestEsAccessRights = vmcs::guest::ES_ACCESS_RIGHTS,
+    GuestEsBase = vmcs::guest::ES_BASE,
+    GuestEsLimit = vmcs::guest::ES_LIMIT,
+    GuestEsSelector = vmcs::guest::ES_SELECTOR,
+    GuestFsAccessRights = vmcs::guest::FS_ACCESS_RIGHTS,
+    GuestFsBase = vmcs::guest::FS_BASE,
+    GuestFsLimit = vmcs::guest::FS_LIMIT,
+    GuestFsSelector = vmcs::guest::FS_SELECTOR,
+    GuestGdtrBase = vmcs::guest::GDTR_BASE,
+    GuestGdtrLimit = vmcs::guest::GDTR_LIMIT,
+    GuestGsAccessRights = vmcs::guest::GS_ACCESS_RIGHTS,
+    GuestGsBase = vmcs::guest::GS_BASE,
+    GuestGsLimit = vmcs::guest::GS_LIMIT,
+    GuestGsSelector = vmcs::guest::GS_SELECTOR,
+    GuestIa32Debugctl = vmcs::guest::IA32_DEBUGCTL_FULL,
+    GuestIa32Efer = vmcs::guest::IA32_EFER_FULL,
+    GuestIa32SysenterCs = vmcs::guest::IA32_SYSENTER_CS,
+    GuestIa32SysenterEip = vmcs::guest::IA32_SYSENTER_EIP,
+    GuestIa32SysenterEsp = vmcs::guest::IA32_SYSENTER_ESP,
+    GuestIdtrBase = vmcs::guest::IDTR_BASE,
+    GuestIdtrLimit = vmcs::guest::IDTR_LIMIT,
+    GuestLdtrAccessRights = vmcs::guest::LDTR_ACCESS_RIGHTS,
+    GuestLdtrBase = vmcs::guest::LDTR_BASE,
+    GuestLdtrLimit = vmcs::guest::LDTR_LIMIT,
+    GuestLdtrSelector = vmcs::guest::LDTR_SELECTOR,
+    GuestLinkPointer = vmcs::guest::LINK_PTR_FULL,
+    GuestRflags = vmcs::guest::RFLAGS,
+    GuestRip = vmcs::guest::RIP,
+    GuestRsp = vmcs::guest::RSP,
+    GuestSsAccessRights = vmcs::guest::SS_ACCESS_RIGHTS,
+    GuestSsBase = vmcs::guest::SS_BASE,
+    GuestSsLimit = vmcs::guest::SS_LIMIT,
+    GuestSsSelector = vmcs::guest::SS_SELECTOR,
+    GuestTrAccessRights = vmcs::guest::TR_ACCESS_RIGHTS,
+    GuestTrBase = vmcs::guest::TR_BASE,
+    GuestTrLimit = vmcs::guest::TR_LIMIT,
+    GuestTrSelector = vmcs::guest::TR_SELECTOR,
+
+    // Host-state fields.
+    HostCr0 = vmcs::host::CR0,
+    HostCr3 = vmcs::host::CR3,
+    HostCr4 = vmcs::host::CR4,
+    HostCsSelector = vmcs::host::CS_SELECTOR,
+    HostDsSelector = vmcs::host::DS_SELECTOR,
+    HostEsSelector = vmcs::host::ES_SELECTOR,
+    HostFsBase = vmcs::host::FS_BASE,
+    HostFsSelector = vmcs::host::FS_SELECTOR,
+    HostGdtrBase = vmcs::host::GDTR_BASE,
+    HostGsBase = vmcs::host::GS_BASE,
+    HostGsSelector = vmcs::host::GS_SELECTOR,
+    HostIa32Efer = vmcs::host::IA32_EFER_FULL,
+    HostIa32SysenterCs = vmcs::host::IA32_SYSENTER_CS,
+    HostIa32SysenterEip = vmcs::host::IA32_SYSENTER_EIP,
+    HostIa32SysenterEsp = vmcs::host::IA32_SYSENTER_ESP,
+    HostIdtrBase = vmcs::host::IDTR_BASE,
+    HostRip = vmcs::host::RIP,
+    HostRsp = vmcs::host::RSP,
+    HostSsSelector = vmcs::host::SS_SELECTOR,
+    HostTrBase = vmcs::host::TR_BASE,
+    HostTrSelector = vmcs::host::TR_SELECTOR,
+
+    // Control fields.
+    Cr0GuestHostMask = vmcs::control::CR0_GUEST_HOST_MASK,
+    Cr0ReadShadow = vmcs::control::CR0_READ_SHADOW,
+    Cr4GuestHostMask = vmcs::control::CR4_GUEST_HOST_MASK,
+    Cr4ReadShadow = vmcs::control::CR4_READ_SHADOW,
+    Eptp = vmcs::control::EPTP_FULL,
+    ExceptionBitmap = vmcs::control::EXCEPTION_BITMAP,
+    IoBitmapAAddress = vmcs::control::IO_BITMAP_A_ADDR_FULL,
+    IoBitmapBAddress = vmcs::control::IO_BITMAP_B_ADDR_FULL,
+    MsrBitmapsAddress = vmcs::control::MSR_BITMAPS_ADDR_FULL,
+    PinbasedExecControls = vmcs::control::PINBASED_EXEC_CONTROLS,
+    PrimaryProcbasedExecControls = vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+    SecondaryProcbasedExecControls = vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+    VmentryControls = vmcs::control::VMENTRY_CONTROLS,
+    VmexitControls = vmcs::control::VMEXIT_CONTROLS,
+    Vpid = vmcs::control::VPID,
+
+    // Read-only data fields.
+    ExitReason = vmcs::ro::EXIT_REASON,
+    VmInstructionError = vmcs::ro::VM_INSTRUCTION_ERROR,
+}
+
+impl VmcsField {
+    /// Returns the encoding of the field, the operand of VMREAD and VMWRITE.
+    pub const fn encoding(self) -> u32 {
+        self as u32
+    }
+
+    /// Returns the width of the field, decoded from its encoding.
+    pub const fn width(self) -> VmcsFieldWidth {
+        let encoding = self.encoding();
+
+        match (encoding >> 13) & 0b11 {
+            0 => VmcsFieldWidth::Word,
+            // The high access type of a 64-bit field reads and writes its high 32 bits.
+            1 if encoding & 1 != 0 => VmcsFieldWidth::Dword,
+            1 => VmcsFieldWidth::Qword,
+            2 => VmcsFieldWidth::Dword,
+            _ => VmcsFieldWidth::Natural,
+        }
+    }
+
+    /// Reads the field from the current VMCS.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The value of the field.
+    /// * `Err(HypervisorError)` - If VMREAD fails, e.g., if the field isn't supported by the processor (see
+    ///   `vm_instruction_error`).
+    pub fn read(self) -> Result<u64, HypervisorError> {
+        unsafe { x86::bits64::vmx::vmread(self.encoding()) }.map_err(|fail| self.access_error(fail))
+    }
+
+    /// Writes the field of the current VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value of the field, which must fit its width.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the field is written.
+    /// * `Err(HypervisorError::VmcsFieldValueTooWide)` - If the value doesn't fit the width of the field.
+    /// * `Err(HypervisorError)` - If VMWRITE fails, e.g., if the field is read-only (see `vm_instruction_error`).
+    pub fn write<T: Into<u64>>(self, value: T) -> Result<(), HypervisorError> {
+        let value = value.into();
+
+        if value & !self.width().mask() != 0 {
+            error!("Value {:#x} too wide for the {:?} VMCS field {:?}", value, self.width(), self);
+            return Err(HypervisorError::VmcsFieldValueTooWide);
+        }
+
+        unsafe { x86::bits64::vmx::vmwrite(self.encoding(), value) }.map_err(|fail| self.access_error(fail))
+    }
+
+    /// Returns the error of a failed access to the field.
+    ///
+    /// # Arguments
+    ///
+    /// * `fail` - How VMREAD or VMWRITE failed.
+    fn access_error(self, fail: VmFail) -> HypervisorError {
+        error!("Failed to access the VMCS field {:?} ({:#x})", self, self.encoding());
+
+        match fail {
+            VmFail::VmFailValid => vm_instruction_error(),
+            VmFail::VmFailInvalid => {
+                error!("No current VMCS");
+                HypervisorError::InvalidVmcsPointer
+            }
+        }
+    }
+}
+
+/// Decodes the VM-instruction error of the current VMCS, after a VMX instruction failed with a valid VMCS pointer.
+///
+/// # Returns
+///
+/// * `HypervisorError::VmInstructionError` - If the error number is known, the error being logged.
+/// * `HypervisorError::UnknownVMInstructionError` - Otherwise.
+pub fn vm_instruction_error() -> HypervisorError {
+    let instruction_error = unsafe { x86::bits64::vmx::vmread(VmcsField::VmInstructionError.encoding()) }.unwrap_or(0) as u32;
+
+    match VmInstructionError::from_u32(instruction_error) {
+        Some(error) => {
+            error!("VM instruction error: {:?}", error);
+            HypervisorError::VmInstructionError
+        }
+        None => {
+            error!("Unknown VM instruction error: {:#x}", instruction_error);
+            HypervisorError::UnknownVMInstructionError
+        }
+    }
+}
@@ -85,6 +85,10 @@ pub enum VmxBasicExitReason {
     InstructionTimeout = 75,
 }
 
+/// The number of basic exit reasons, one more than the highest basic exit reason, to size the tables indexed by basic
+/// exit reason.
+pub const EXIT_REASON_COUNT: usize = VmxBasicExitReason::InstructionTimeout as usize + 1;
+
 impl VmxBasicExitReason {
     /// Converts a 32-bit VM exit reason from the VMCS to the corresponding `VmxBasicExitReason` variant.
     ///
@@ -380,7 +384,8 @@ pub struct ControlRegAccessExitQualification {
     pub access_type: CrAccessType,
     pub lmsw_op_type: LmswOperandType,
     pub gpr_mov_cr: u64,
-    // 31:16 not implemented for now
+    /// The source operand of LMSW, of which only the low 4 bits are loaded into CR0.
+    pub lmsw_source_data: u16,
 }
 
 impl ControlRegAccessExitQualification {
@@ -391,6 +396,7 @@ impl ControlRegAccessExitQualification {
             access_type: CrAccessType::from_u64(value.get_bits(4..6)).unwrap(),
             lmsw_op_type: LmswOperandType::from_u64(value.get_bit(6) as u64).unwrap(),
             gpr_mov_cr: value.get_bits(8..12),
+            lmsw_source_data: value.get_bits(16..32) as u16,
         }
     }
 }
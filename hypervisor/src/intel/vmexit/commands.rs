@@ -1,17 +1,89 @@
 use {
     crate::{
+        error::HypervisorError,
+        exfil::append_to_exfil_file,
         intel::{
             addresses::PhysicalAddress,
+            benchmark::SHARED_BENCHMARK,
+            bitmap::{MsrAccessType, MsrBitmap, MsrOperation},
+            broadcast::broadcast,
+            code_snapshot::SHARED_CODE_SNAPSHOTS,
+            debug_registers::{BreakpointCondition, HardwareBreakpoint, SHARED_HARDWARE_BREAKPOINTS},
+            detection_corpus::run_detection_corpus,
+            determinism::{DeterministicConfig, SHARED_DETERMINISM},
+            devirtualize::{cancel_devirtualization, request_devirtualization},
+            ept::AccessType,
+            ept_view::{DivergentPage, SHARED_EPT_VIEWS},
+            event_ring::{configure_event_ring, event_ring_stats},
+            exception_telemetry::{ExceptionTelemetryConfig, SHARED_EXCEPTION_TELEMETRY},
+            execution_trace::{start_execution_trace, SHARED_EXECUTION_TRACER},
+            exit_statistics::SHARED_EXIT_STATISTICS,
             hooks::{
+                allocation_monitor::{AllocationSyscallNumbers, SHARED_ALLOCATION_MONITOR},
+                boot_manifest::{fire_boot_hook_trigger, force_boot_hook_trigger, BootHookTrigger},
+                cpuid_hook::{CpuidHook, SHARED_CPUID_HOOK_MANAGER},
+                hardware_breakpoint_hook::{install_kernel_hardware_breakpoint_hook, remove_kernel_hardware_breakpoint_hook},
                 hook_manager::{EptHookType, SHARED_HOOK_MANAGER},
+                hook_view::{ProcessHookScope, ProcessHookView},
                 inline::InlineHookType,
+                msr_hook::{MsrContextRule, SHARED_MSR_HOOK_MANAGER},
+                os_events::configure_os_events,
+                syscall_trace::{start_syscall_trace, stop_syscall_trace, SHARED_SYSCALL_TRACE},
             },
+            memory_search::{search_guest_memory, SearchPattern},
+            process_tracker::SHARED_PROCESS_TRACKER,
+            profiler::{start_profiling, stop_profiling, ProfilerConfig, SHARED_PROFILE},
+            protected_memory::{protect_memory, unprotect_memory, ProtectionPolicy},
+            reset::SHARED_RESET_CONTROL,
+            rtc::set_rtc_offset,
+            shared_page::{map_shared_page, unmap_shared_page},
+            signature_scan::{scan_guest_memory, ScanSpace, Signature},
+            support::vmread,
+            timing::tsc_frequency_hz,
+            transfer::{cancel_async_transfer, start_async_transfer, AsyncTransfer},
+            tsc_compensation::{TscCompensationConfig, SHARED_TSC_COMPENSATION},
+            unpacker::{start_unpacker, SHARED_UNPACKER},
             vm::Vm,
+            vmerror::ExceptionInterrupt,
+            vmexit::preemption_timer::is_preemption_timer_supported,
+            watchdog::{WatchdogConfig, SHARED_WATCHDOG},
+            xsave_policy::SHARED_XSAVE_POLICY,
         },
-        windows::eprocess::ProcessInformation,
+        linux::{kernel::SHARED_LINUX_KERNEL, task::enumerate_linux_tasks},
+        logger::write_serial,
+        persistence::{discard_saved_configuration, save_configuration},
+        windows::{
+            code_integrity::configure_code_integrity,
+            eprocess::ProcessInformation,
+            introspection::{enumerate_processes, enumerate_threads, enumerate_vads},
+            kernel_callbacks::{enumerate_kernel_callbacks, MAX_KERNEL_CALLBACKS},
+            process_dump::{dump_process_memory, DumpFilter},
+            symbols::SHARED_SYMBOL_TABLE,
+        },
+    },
+    alloc::{string::String, vec::Vec},
+    log::{debug, error, info, warn},
+    shared::{
+        AllocationAlert, AllocationAlertHeader, AllocationMapHeader, AllocationMonitorOperation, AllocationRegion, BenchmarkExitReason,
+        BenchmarkHeader, BenchmarkOperation, BootHookOperation, BroadcastCommand, ClientCommand, ClientDataPayload, CodeIntegrityOperation,
+        CodeSnapshot, CodeSnapshotHeader, CodeSnapshotOperation, Command, CpuidOverrideAction, CpuidOverrideOperation, DetectionCheckResult,
+        DetectionCorpusHeader, DetectionCorpusOperation, DeterminismOperation, DetourType, DevirtualizeOperation, EptViewOperation,
+        EventRingOperation, EventRingStats, EventRingStatsOperation, ExceptionEvent, ExceptionTelemetryHeader, ExceptionTelemetryOperation,
+        ExecutionTraceHeader, ExecutionTraceOperation, ExecutionTraceRecord, ExfilOperation, ExitStatisticsHeader, ExitStatisticsOperation,
+        ExitStatisticsRecord, HardwareBreakpointOperation, HookData, HookViewOperation, HypervisorPresence, KernelCallback, KernelCallbackHeader,
+        LinuxKernelOperation, LinuxTask, LinuxTaskHeader, LinuxTasksOperation, MemorySearchHeader, MemorySearchMatch, MemorySearchOperation,
+        MsrBitmapOperation, MsrContextRuleOperation, OsEventsOperation, ProcessDumpChunk, ProcessDumpHeader, ProcessDumpOperation,
+        ProcessMemoryOperation, ProcessTrackingOperation, ProfileHeader, ProfileSample, ProfilerOperation, ProtectedMemoryOperation, ResetPolicy,
+        ResolvedSymbol, RtcOffsetOperation, SaveConfigurationOperation, SharedPageOperation, SignatureScanHeader, SignatureScanOperation,
+        SymbolHeader, SymbolOperation, SyscallTraceHeader, SyscallTraceOperation, SyscallTraceRecord, TscCompensationOperation, UnpackedPage,
+        UnpackerDumpHeader, UnpackerOperation, WatchdogOperation, WindowsIntrospectionOperation, WindowsProcess, WindowsProcessHeader, WindowsThread,
+        WindowsThreadHeader, WindowsVad, WindowsVadHeader, XsavePolicyOperation, MAX_PROCESS_DUMP_SIZE, MAX_SERIAL_PROCESS_DUMP_SIZE,
+        MAX_SIGNATURE_SCAN_SIZE, MAX_SYMBOL_ADDRESSES, MAX_SYSCALL_TRACE_FILTER, SYMBOL_EXPORT_NAME_SIZE, SYMBOL_MODULE_NAME_SIZE,
+    },
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        vmx::vmcs,
     },
-    log::{debug, error},
-    shared::{ClientCommand, ClientDataPayload, Command, HookData, ProcessMemoryOperation},
 };
 
 /// Handles guest commands sent to the hypervisor.
@@ -67,6 +139,477 @@ pub fn handle_guest_commands(vm: &mut Vm) -> Option<()> {
                 None
             }
         }
+        Command::MapSharedPage => {
+            if let ClientDataPayload::SharedPage(shared_page) = client_command.payload {
+                handle_map_shared_page(vm, shared_page)
+            } else {
+                error!("Expected SharedPage for MapSharedPage command.");
+                None
+            }
+        }
+        Command::UnmapSharedPage => handle_unmap_shared_page(vm),
+        Command::SetRtcOffset => {
+            if let ClientDataPayload::RtcOffset(rtc_offset) = client_command.payload {
+                handle_set_rtc_offset(rtc_offset)
+            } else {
+                error!("Expected RtcOffset for SetRtcOffset command.");
+                None
+            }
+        }
+        Command::AppendExfilData => {
+            if let ClientDataPayload::Exfil(exfil) = client_command.payload {
+                handle_append_exfil_data(exfil)
+            } else {
+                error!("Expected Exfil for AppendExfilData command.");
+                None
+            }
+        }
+        Command::StartAsyncRead => {
+            if let ClientDataPayload::Memory(memory) = client_command.payload {
+                handle_start_async_read(vm, memory)
+            } else {
+                error!("Expected Memory for StartAsyncRead command.");
+                None
+            }
+        }
+        Command::CancelAsyncRead => {
+            cancel_async_transfer(vm);
+            Some(())
+        }
+        Command::StartProfiling => {
+            if let ClientDataPayload::Profiler(profiler) = client_command.payload {
+                handle_start_profiling(profiler)
+            } else {
+                error!("Expected Profiler for StartProfiling command.");
+                None
+            }
+        }
+        Command::StopProfiling => {
+            stop_profiling();
+            Some(())
+        }
+        Command::ReadProfile => {
+            if let ClientDataPayload::Profiler(profiler) = client_command.payload {
+                handle_read_profile(profiler)
+            } else {
+                error!("Expected Profiler for ReadProfile command.");
+                None
+            }
+        }
+        Command::ConfigureWatchdog => {
+            if let ClientDataPayload::Watchdog(watchdog) = client_command.payload {
+                handle_configure_watchdog(watchdog)
+            } else {
+                error!("Expected Watchdog for ConfigureWatchdog command.");
+                None
+            }
+        }
+        Command::SetResetPolicy => {
+            if let ClientDataPayload::ResetPolicy(policy) = client_command.payload {
+                handle_set_reset_policy(policy)
+            } else {
+                error!("Expected ResetPolicy for SetResetPolicy command.");
+                None
+            }
+        }
+        Command::ConfigureDeterministicMode => {
+            if let ClientDataPayload::Determinism(determinism) = client_command.payload {
+                handle_configure_deterministic_mode(determinism)
+            } else {
+                error!("Expected Determinism for ConfigureDeterministicMode command.");
+                None
+            }
+        }
+        Command::ConfigureHookView => {
+            if let ClientDataPayload::HookView(hook_view) = client_command.payload {
+                handle_configure_hook_view(hook_view)
+            } else {
+                error!("Expected HookView for ConfigureHookView command.");
+                None
+            }
+        }
+        Command::StartSyscallTrace => {
+            if let ClientDataPayload::SyscallTrace(syscall_trace) = client_command.payload {
+                handle_start_syscall_trace(syscall_trace)
+            } else {
+                error!("Expected SyscallTrace for StartSyscallTrace command.");
+                None
+            }
+        }
+        Command::StopSyscallTrace => {
+            stop_syscall_trace();
+            Some(())
+        }
+        Command::ReadSyscallTrace => {
+            if let ClientDataPayload::SyscallTrace(syscall_trace) = client_command.payload {
+                handle_read_syscall_trace(syscall_trace)
+            } else {
+                error!("Expected SyscallTrace for ReadSyscallTrace command.");
+                None
+            }
+        }
+        Command::ConfigureExceptionTelemetry => {
+            if let ClientDataPayload::ExceptionTelemetry(exception_telemetry) = client_command.payload {
+                handle_configure_exception_telemetry(exception_telemetry)
+            } else {
+                error!("Expected ExceptionTelemetry for ConfigureExceptionTelemetry command.");
+                None
+            }
+        }
+        Command::ReadExceptionTelemetry => {
+            if let ClientDataPayload::ExceptionTelemetry(exception_telemetry) = client_command.payload {
+                handle_read_exception_telemetry(exception_telemetry)
+            } else {
+                error!("Expected ExceptionTelemetry for ReadExceptionTelemetry command.");
+                None
+            }
+        }
+        Command::ConfigureMsrBitmap => {
+            if let ClientDataPayload::MsrBitmap(msr_bitmap) = client_command.payload {
+                handle_configure_msr_bitmap(msr_bitmap)
+            } else {
+                error!("Expected MsrBitmap for ConfigureMsrBitmap command.");
+                None
+            }
+        }
+        Command::StartUnpacker => {
+            if let ClientDataPayload::Unpacker(unpacker) = client_command.payload {
+                handle_start_unpacker(vm, unpacker)
+            } else {
+                error!("Expected Unpacker for StartUnpacker command.");
+                None
+            }
+        }
+        Command::StopUnpacker => handle_stop_unpacker(vm),
+        Command::ReadUnpackerDumps => {
+            if let ClientDataPayload::Unpacker(unpacker) = client_command.payload {
+                handle_read_unpacker_dumps(vm, unpacker)
+            } else {
+                error!("Expected Unpacker for ReadUnpackerDumps command.");
+                None
+            }
+        }
+        Command::ConfigureCpuidOverride => {
+            if let ClientDataPayload::CpuidOverride(cpuid_override) = client_command.payload {
+                handle_configure_cpuid_override(cpuid_override)
+            } else {
+                error!("Expected CpuidOverride for ConfigureCpuidOverride command.");
+                None
+            }
+        }
+        Command::StartAllocationMonitor => {
+            if let ClientDataPayload::AllocationMonitor(allocation_monitor) = client_command.payload {
+                handle_start_allocation_monitor(allocation_monitor)
+            } else {
+                error!("Expected AllocationMonitor for StartAllocationMonitor command.");
+                None
+            }
+        }
+        Command::StopAllocationMonitor => {
+            if let ClientDataPayload::AllocationMonitor(allocation_monitor) = client_command.payload {
+                handle_stop_allocation_monitor(allocation_monitor)
+            } else {
+                error!("Expected AllocationMonitor for StopAllocationMonitor command.");
+                None
+            }
+        }
+        Command::ReadAllocationMap => {
+            if let ClientDataPayload::AllocationMonitor(allocation_monitor) = client_command.payload {
+                handle_read_allocation_map(allocation_monitor)
+            } else {
+                error!("Expected AllocationMonitor for ReadAllocationMap command.");
+                None
+            }
+        }
+        Command::ReadAllocationAlerts => {
+            if let ClientDataPayload::AllocationMonitor(allocation_monitor) = client_command.payload {
+                handle_read_allocation_alerts(allocation_monitor)
+            } else {
+                error!("Expected AllocationMonitor for ReadAllocationAlerts command.");
+                None
+            }
+        }
+        Command::ConfigureHypervisorPresence => {
+            if let ClientDataPayload::HypervisorPresence(presence) = client_command.payload {
+                handle_configure_hypervisor_presence(presence)
+            } else {
+                error!("Expected HypervisorPresence for ConfigureHypervisorPresence command.");
+                None
+            }
+        }
+        Command::ConfigureCodeSnapshots => {
+            if let ClientDataPayload::CodeSnapshot(code_snapshot) = client_command.payload {
+                handle_configure_code_snapshots(code_snapshot)
+            } else {
+                error!("Expected CodeSnapshot for ConfigureCodeSnapshots command.");
+                None
+            }
+        }
+        Command::ReadCodeSnapshots => {
+            if let ClientDataPayload::CodeSnapshot(code_snapshot) = client_command.payload {
+                handle_read_code_snapshots(code_snapshot)
+            } else {
+                error!("Expected CodeSnapshot for ReadCodeSnapshots command.");
+                None
+            }
+        }
+        Command::ConfigureTscCompensation => {
+            if let ClientDataPayload::TscCompensation(tsc_compensation) = client_command.payload {
+                handle_configure_tsc_compensation(tsc_compensation)
+            } else {
+                error!("Expected TscCompensation for ConfigureTscCompensation command.");
+                None
+            }
+        }
+        Command::LoadModuleSymbols => {
+            if let ClientDataPayload::Symbol(symbol) = client_command.payload {
+                handle_load_module_symbols(symbol)
+            } else {
+                error!("Expected Symbol for LoadModuleSymbols command.");
+                None
+            }
+        }
+        Command::ResolveSymbols => {
+            if let ClientDataPayload::Symbol(symbol) = client_command.payload {
+                handle_resolve_symbols(symbol)
+            } else {
+                error!("Expected Symbol for ResolveSymbols command.");
+                None
+            }
+        }
+        Command::TriggerBootHooks => {
+            if let ClientDataPayload::BootHook(boot_hook) = client_command.payload {
+                handle_trigger_boot_hooks(boot_hook)
+            } else {
+                error!("Expected BootHook for TriggerBootHooks command.");
+                None
+            }
+        }
+        Command::SearchMemory => {
+            if let ClientDataPayload::MemorySearch(memory_search) = client_command.payload {
+                handle_search_memory(memory_search)
+            } else {
+                error!("Expected MemorySearch for SearchMemory command.");
+                None
+            }
+        }
+        Command::ConfigureProcessTracking => {
+            if let ClientDataPayload::ProcessTracking(process_tracking) = client_command.payload {
+                handle_configure_process_tracking(process_tracking)
+            } else {
+                error!("Expected ProcessTracking for ConfigureProcessTracking command.");
+                None
+            }
+        }
+        Command::ConfigureXsavePolicy => {
+            if let ClientDataPayload::XsavePolicy(xsave_policy) = client_command.payload {
+                handle_configure_xsave_policy(xsave_policy)
+            } else {
+                error!("Expected XsavePolicy for ConfigureXsavePolicy command.");
+                None
+            }
+        }
+        Command::ReadBenchmark => {
+            if let ClientDataPayload::Benchmark(benchmark) = client_command.payload {
+                handle_read_benchmark(benchmark)
+            } else {
+                error!("Expected Benchmark for ReadBenchmark command.");
+                None
+            }
+        }
+        Command::SetHardwareBreakpoint => {
+            if let ClientDataPayload::HardwareBreakpoint(hardware_breakpoint) = client_command.payload {
+                handle_set_hardware_breakpoint(hardware_breakpoint)
+            } else {
+                error!("Expected HardwareBreakpoint for SetHardwareBreakpoint command.");
+                None
+            }
+        }
+        Command::ConfigureEventRing => {
+            if let ClientDataPayload::EventRing(event_ring) = client_command.payload {
+                handle_configure_event_ring(event_ring)
+            } else {
+                error!("Expected EventRing for ConfigureEventRing command.");
+                None
+            }
+        }
+        Command::ReadEventRingStats => {
+            if let ClientDataPayload::EventRingStats(event_ring) = client_command.payload {
+                handle_read_event_ring_stats(event_ring)
+            } else {
+                error!("Expected EventRingStats for ReadEventRingStats command.");
+                None
+            }
+        }
+        Command::SaveConfiguration => {
+            if let ClientDataPayload::SaveConfiguration(configuration) = client_command.payload {
+                handle_save_configuration(configuration)
+            } else {
+                error!("Expected SaveConfiguration for SaveConfiguration command.");
+                None
+            }
+        }
+        Command::ConfigureMsrContextRule => {
+            if let ClientDataPayload::MsrContextRule(msr_context_rule) = client_command.payload {
+                handle_configure_msr_context_rule(msr_context_rule)
+            } else {
+                error!("Expected MsrContextRule for ConfigureMsrContextRule command.");
+                None
+            }
+        }
+        Command::RunDetectionCorpus => {
+            if let ClientDataPayload::DetectionCorpus(detection_corpus) = client_command.payload {
+                handle_run_detection_corpus(vm, detection_corpus)
+            } else {
+                error!("Expected DetectionCorpus for RunDetectionCorpus command.");
+                None
+            }
+        }
+        Command::ConfigureLinuxKernel => {
+            if let ClientDataPayload::LinuxKernel(linux_kernel) = client_command.payload {
+                handle_configure_linux_kernel(linux_kernel)
+            } else {
+                error!("Expected LinuxKernel for ConfigureLinuxKernel command.");
+                None
+            }
+        }
+        Command::ReadLinuxTasks => {
+            if let ClientDataPayload::LinuxTasks(linux_tasks) = client_command.payload {
+                handle_read_linux_tasks(linux_tasks)
+            } else {
+                error!("Expected LinuxTasks for ReadLinuxTasks command.");
+                None
+            }
+        }
+        Command::StartExecutionTrace => {
+            if let ClientDataPayload::ExecutionTrace(execution_trace) = client_command.payload {
+                handle_start_execution_trace(vm, execution_trace)
+            } else {
+                error!("Expected ExecutionTrace for StartExecutionTrace command.");
+                None
+            }
+        }
+        Command::StopExecutionTrace => handle_stop_execution_trace(vm),
+        Command::ReadExecutionTrace => {
+            if let ClientDataPayload::ExecutionTrace(execution_trace) = client_command.payload {
+                handle_read_execution_trace(vm, execution_trace)
+            } else {
+                error!("Expected ExecutionTrace for ReadExecutionTrace command.");
+                None
+            }
+        }
+        Command::ScanSignature => {
+            if let ClientDataPayload::SignatureScan(signature_scan) = client_command.payload {
+                handle_scan_signature(signature_scan)
+            } else {
+                error!("Expected SignatureScan for ScanSignature command.");
+                None
+            }
+        }
+        Command::EnumerateProcesses => {
+            if let ClientDataPayload::WindowsIntrospection(introspection) = client_command.payload {
+                handle_enumerate_processes(introspection)
+            } else {
+                error!("Expected WindowsIntrospection for EnumerateProcesses command.");
+                None
+            }
+        }
+        Command::EnumerateThreads => {
+            if let ClientDataPayload::WindowsIntrospection(introspection) = client_command.payload {
+                handle_enumerate_threads(introspection)
+            } else {
+                error!("Expected WindowsIntrospection for EnumerateThreads command.");
+                None
+            }
+        }
+        Command::EnumerateVads => {
+            if let ClientDataPayload::WindowsIntrospection(introspection) = client_command.payload {
+                handle_enumerate_vads(introspection)
+            } else {
+                error!("Expected WindowsIntrospection for EnumerateVads command.");
+                None
+            }
+        }
+        Command::DumpProcessMemory => {
+            if let ClientDataPayload::ProcessDump(process_dump) = client_command.payload {
+                handle_dump_process_memory(process_dump)
+            } else {
+                error!("Expected ProcessDump for DumpProcessMemory command.");
+                None
+            }
+        }
+        Command::EnumerateKernelCallbacks => {
+            if let ClientDataPayload::WindowsIntrospection(introspection) = client_command.payload {
+                handle_enumerate_kernel_callbacks(introspection)
+            } else {
+                error!("Expected WindowsIntrospection for EnumerateKernelCallbacks command.");
+                None
+            }
+        }
+        Command::ConfigureOsEvents => {
+            if let ClientDataPayload::OsEvents(os_events) = client_command.payload {
+                handle_configure_os_events(vm, os_events)
+            } else {
+                error!("Expected OsEvents for ConfigureOsEvents command.");
+                None
+            }
+        }
+        Command::ConfigureCodeIntegrity => {
+            if let ClientDataPayload::CodeIntegrity(code_integrity) = client_command.payload {
+                handle_configure_code_integrity(code_integrity)
+            } else {
+                error!("Expected CodeIntegrity for ConfigureCodeIntegrity command.");
+                None
+            }
+        }
+        Command::ProtectMemory => {
+            if let ClientDataPayload::ProtectedMemory(protected_memory) = client_command.payload {
+                handle_protect_memory(vm, protected_memory)
+            } else {
+                error!("Expected ProtectedMemory for ProtectMemory command.");
+                None
+            }
+        }
+        Command::UnprotectMemory => {
+            if let ClientDataPayload::ProtectedMemory(protected_memory) = client_command.payload {
+                handle_unprotect_memory(vm, protected_memory)
+            } else {
+                error!("Expected ProtectedMemory for UnprotectMemory command.");
+                None
+            }
+        }
+        Command::ConfigureEptView => {
+            if let ClientDataPayload::EptView(ept_view) = client_command.payload {
+                handle_configure_ept_view(ept_view)
+            } else {
+                error!("Expected EptView for ConfigureEptView command.");
+                None
+            }
+        }
+        Command::Devirtualize => {
+            if let ClientDataPayload::Devirtualize(devirtualize) = client_command.payload {
+                handle_devirtualize(devirtualize)
+            } else {
+                error!("Expected Devirtualize for Devirtualize command.");
+                None
+            }
+        }
+        Command::Broadcast => {
+            if let ClientDataPayload::Broadcast(command) = client_command.payload {
+                handle_broadcast(vm, command)
+            } else {
+                error!("Expected Broadcast for Broadcast command.");
+                None
+            }
+        }
+        Command::ReadExitStatistics => {
+            if let ClientDataPayload::ExitStatistics(exit_statistics) = client_command.payload {
+                handle_read_exit_statistics(vm, exit_statistics)
+            } else {
+                error!("Expected ExitStatistics for ReadExitStatistics command.");
+                None
+            }
+        }
         Command::Invalid => {
             error!("Invalid command received");
             None
@@ -100,10 +643,27 @@ fn handle_open_process(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<(
     Some(())
 }
 
+/// The size of the chunks the memory of a process is copied by, through a buffer on the host stack.
+const PROCESS_MEMORY_CHUNK_SIZE: usize = 0x200;
+
+/// Returns the guest CR3 of the target process of a memory operation: the guest CR3 if provided, or the directory
+/// table base of the process ID otherwise.
+///
+/// # Arguments
+///
+/// * `memory` - The `ProcessMemoryOperation` identifying the target process.
+fn target_process_cr3(memory: &ProcessMemoryOperation) -> Option<u64> {
+    match memory.guest_cr3 {
+        Some(guest_cr3) => Some(guest_cr3),
+        None => ProcessInformation::get_directory_table_base_by_process_id(memory.process_id?),
+    }
+}
+
 /// Handles the `ReadProcessMemory` command.
 ///
-/// This function reads a block of memory from the guest target process identified by the stored CR3
-/// and writes the read data to the buffer provided by the user mode client.
+/// This function reads a block of memory from the guest target process identified by the stored CR3, or by its
+/// process ID if no CR3 is provided, and writes the read data to the buffer provided by the user mode client. The
+/// memory is copied page by page, and the command fails if a page of either range isn't present.
 ///
 /// # Arguments
 ///
@@ -114,22 +674,35 @@ fn handle_open_process(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<(
 ///
 /// * `Option<()>` - Returns `Some(())` if the memory was read successfully, or `None` if an error occurred.
 fn handle_read_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()> {
-    debug!("Reading memory from process, address: {:#x} with CR3: {:#x}", memory.address?, memory.guest_cr3?);
+    let target_cr3 = target_process_cr3(&memory)?;
+    debug!("Reading memory from process, address: {:#x} with CR3: {:#x}", memory.address?, target_cr3);
+
+    let client_cr3 = vmread(vmcs::guest::CR3);
+    let mut chunk = [0u8; PROCESS_MEMORY_CHUNK_SIZE];
+
+    for offset in (0..memory.buffer_size).step_by(PROCESS_MEMORY_CHUNK_SIZE) {
+        let length = (memory.buffer_size - offset).min(PROCESS_MEMORY_CHUNK_SIZE as u64) as usize;
 
-    // Read the memory from the specified address in the target process
-    let data =
-        PhysicalAddress::read_guest_virt_slice_with_explicit_cr3(memory.address? as *const u8, memory.buffer_size as usize, memory.guest_cr3?)?;
+        // Read the memory from the specified address in the target process
+        if PhysicalAddress::read_guest_virt_bytes_with_explicit_cr3(memory.address? + offset, &mut chunk[..length], target_cr3) != length {
+            debug!("Memory of process at {:#x} isn't present", memory.address? + offset);
+            return None;
+        }
 
-    // Write the read data to the buffer provided by the user mode client
-    PhysicalAddress::write_guest_virt_slice_with_current_cr3(memory.buffer as *mut u8, data)?;
+        // Write the read data to the buffer provided by the user mode client
+        if PhysicalAddress::write_guest_virt_bytes_with_explicit_cr3(memory.buffer + offset, &chunk[..length], client_cr3) != length {
+            return None;
+        }
+    }
 
     Some(())
 }
 
 /// Handles the `WriteProcessMemory` command.
 ///
-/// This function writes a block of memory to the guest target process identified by the stored CR3
-/// using the data provided in the user mode client's buffer.
+/// This function writes a block of memory to the guest target process identified by the stored CR3, or by its
+/// process ID if no CR3 is provided, using the data provided in the user mode client's buffer. The memory is copied
+/// page by page, and the command fails if a page of either range isn't present, the pages before it being written.
 ///
 /// # Arguments
 ///
@@ -140,11 +713,26 @@ fn handle_read_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()
 ///
 /// * `Option<()>` - Returns `Some(())` if the memory was written successfully, or `None` if an error occurred.
 fn handle_write_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()> {
-    debug!("Writing memory to process, address: {:#x} with CR3: {:#x}", memory.address?, memory.guest_cr3?);
+    let target_cr3 = target_process_cr3(&memory)?;
+    debug!("Writing memory to process, address: {:#x} with CR3: {:#x}", memory.address?, target_cr3);
+
+    let client_cr3 = vmread(vmcs::guest::CR3);
+    let mut chunk = [0u8; PROCESS_MEMORY_CHUNK_SIZE];
 
-    // Write the data from the buffer provided by the user mode client to the specified address in the target process
-    let data = PhysicalAddress::read_guest_virt_slice_with_current_cr3(memory.buffer as *const u8, memory.buffer_size as usize)?;
-    PhysicalAddress::write_guest_virt_slice_with_explicit_cr3(memory.address? as *mut u8, data, memory.guest_cr3?)?;
+    for offset in (0..memory.buffer_size).step_by(PROCESS_MEMORY_CHUNK_SIZE) {
+        let length = (memory.buffer_size - offset).min(PROCESS_MEMORY_CHUNK_SIZE as u64) as usize;
+
+        // Read the data from the buffer provided by the user mode client
+        if PhysicalAddress::read_guest_virt_bytes_with_explicit_cr3(memory.buffer + offset, &mut chunk[..length], client_cr3) != length {
+            return None;
+        }
+
+        // Write the data to the specified address in the target process
+        if PhysicalAddress::write_guest_virt_bytes_with_explicit_cr3(memory.address? + offset, &chunk[..length], target_cr3) != length {
+            debug!("Memory of process at {:#x} isn't present", memory.address? + offset);
+            return None;
+        }
+    }
 
     Some(())
 }
@@ -152,7 +740,9 @@ fn handle_write_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<(
 /// Handles commands related to enabling or disabling kernel EPT hooks.
 ///
 /// This function manages the setup or removal of kernel EPT hooks based on the provided command.
-/// It enables or disables the hooks for specific functions based on the function hash and syscall number provided.
+/// It enables or disables the hooks for specific functions based on the function hash and syscall number provided,
+/// using the detour selected by the client. The hardware breakpoint detour hooks the function with a debug register
+/// instead of an EPT hook, and a hook disabled with either is removed.
 ///
 /// # Arguments
 ///
@@ -163,12 +753,2177 @@ fn handle_write_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<(
 /// # Returns
 ///
 /// * `Option<()>` - Returns `Some(())` if the hook command was handled successfully, or `None` if an error occurred.
-fn handle_hook_command(vm: &mut Vm, command: Command, hook: HookData) -> Option<()> {
+pub fn handle_hook_command(vm: &mut Vm, command: Command, hook: HookData) -> Option<()> {
     let enable = command == Command::EnableKernelEptHook;
-    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
 
-    hook_manager
-        .manage_kernel_ept_hook(vm, hook.function_hash, hook.syscall_number, EptHookType::Function(InlineHookType::Vmcall), enable)
-        .ok()?;
+    if enable && hook.detour_type == DetourType::HardwareBreakpoint {
+        return install_kernel_hardware_breakpoint_hook(hook.function_hash, hook.syscall_number)
+            .ok()
+            .map(|_| ());
+    }
+
+    if !enable && remove_kernel_hardware_breakpoint_hook(hook.function_hash, hook.syscall_number).is_ok() {
+        return Some(());
+    }
+
+    let inline_hook_type = match hook.detour_type {
+        DetourType::Vmcall => InlineHookType::Vmcall,
+        DetourType::Int3 => InlineHookType::Int3,
+        DetourType::Cpuid => InlineHookType::Cpuid,
+        DetourType::Int3Stub => InlineHookType::Int3Stub,
+        DetourType::JmpRel32(handler) => InlineHookType::JmpRel32(handler),
+        DetourType::PushRet(handler) => InlineHookType::PushRet(handler),
+        DetourType::MovRaxJmp(handler) => InlineHookType::MovRaxJmp(handler),
+        // Only reached when disabling a hook, for which the detour is ignored.
+        DetourType::HardwareBreakpoint => InlineHookType::Vmcall,
+    };
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    hook_manager
+        .manage_kernel_ept_hook(vm, hook.function_hash, hook.syscall_number, EptHookType::Function(inline_hook_type), enable)
+        .ok()?;
+    Some(())
+}
+
+/// Handles the `MapSharedPage` command.
+///
+/// This function maps the host shared communication page over the guest page provided by the user mode client
+/// in the EPT of every logical processor, initializes its layout and writes the guest physical address of the
+/// page to the buffer provided by the client. The client must keep the guest page locked in memory until it is unmapped.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `shared_page` - The `SharedPageOperation` containing the guest page and the buffer to store its guest physical address.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the shared page was mapped successfully, or `None` if an error occurred.
+fn handle_map_shared_page(vm: &mut Vm, shared_page: SharedPageOperation) -> Option<()> {
+    let guest_page_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(shared_page.guest_page_va).ok()?).align_down_to_base_page();
+    debug!("Mapping shared page over guest page {:#x}", guest_page_pa.as_u64());
+
+    if let Err(e) = map_shared_page(vm, guest_page_pa.as_u64()) {
+        error!("Failed to map the shared page: {:?}", e);
+        return None;
+    }
+
+    // Advertise the guest physical address of the shared page to the user mode client
+    PhysicalAddress::write_guest_virt_with_current_cr3(shared_page.buffer as *mut u64, guest_page_pa.as_u64())?;
+
+    Some(())
+}
+
+/// Handles the `UnmapSharedPage` command.
+///
+/// This function restores the original mapping of the guest page that the shared communication page was mapped over,
+/// on every logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the shared page was unmapped successfully, or `None` if an error occurred.
+fn handle_unmap_shared_page(vm: &mut Vm) -> Option<()> {
+    debug!("Unmapping shared page");
+
+    if let Err(e) = unmap_shared_page(vm) {
+        error!("Failed to unmap the shared page: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `SetRtcOffset` command.
+///
+/// This function sets the offset added to the RTC time observed by the guest on all logical processors.
+/// The CMOS ports are only intercepted with the `timing_normalization` feature, so the offset has no effect otherwise.
+///
+/// # Arguments
+///
+/// * `rtc_offset` - The `RtcOffsetOperation` containing the offset in seconds.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the offset was set successfully.
+fn handle_set_rtc_offset(rtc_offset: RtcOffsetOperation) -> Option<()> {
+    set_rtc_offset(rtc_offset.offset_seconds);
+
+    Some(())
+}
+
+/// Handles the `AppendExfilData` command.
+///
+/// This function reads the buffer provided by the user mode client, one page at a time as it may not be physically
+/// contiguous, and appends it to the exfiltration file on the EFI System Partition.
+///
+/// # Arguments
+///
+/// * `exfil` - The `ExfilOperation` containing the buffer to append.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the buffer was written to the disk, or `None` if an error occurred.
+fn handle_append_exfil_data(exfil: ExfilOperation) -> Option<()> {
+    debug!("Appending {:#x} bytes to the exfiltration file", exfil.buffer_size);
+
+    let buffer_end = exfil.buffer.checked_add(exfil.buffer_size)?;
+    let mut data = Vec::with_capacity(exfil.buffer_size as usize);
+    let mut guest_va = exfil.buffer;
+
+    while guest_va < buffer_end {
+        let chunk_size = (BASE_PAGE_SIZE as u64 - (guest_va & (BASE_PAGE_SIZE as u64 - 1))).min(buffer_end - guest_va);
+        data.extend_from_slice(PhysicalAddress::read_guest_virt_slice_with_current_cr3(guest_va as *const u8, chunk_size as usize)?);
+        guest_va += chunk_size;
+    }
+
+    if let Err(e) = append_to_exfil_file(&data) {
+        error!("Failed to append to the exfiltration file: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `StartAsyncRead` command.
+///
+/// This function starts copying a block of memory from the guest target process identified by the stored CR3
+/// to the buffer provided by the user mode client in the background, one chunk each time the VMX-preemption timer
+/// expires, instead of stalling the logical processor in a single VM exit. The progress is reported through the
+/// shared page, which must be mapped on the current logical processor, and the buffer must stay locked in memory
+/// until the transfer completes or is cancelled.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `memory` - The `ProcessMemoryOperation` containing details about the memory read operation, including the target address and the buffer to store the read data.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the transfer was started successfully, or `None` if an error occurred.
+fn handle_start_async_read(vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()> {
+    debug!("Starting asynchronous read from process, address: {:#x} with CR3: {:#x}", memory.address?, memory.guest_cr3?);
+
+    // The current CR3 is the one of the client, which the buffer is written through when the timer expires.
+    let transfer = AsyncTransfer::new(memory.guest_cr3?, memory.address?, vmread(vmcs::guest::CR3), memory.buffer, memory.buffer_size);
+
+    if let Err(e) = start_async_transfer(vm, transfer) {
+        error!("Failed to start asynchronous read: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// The highest sampling frequency accepted by the `StartProfiling` command, in samples per second per logical processor.
+const MAX_PROFILING_FREQUENCY_HZ: u64 = 10_000;
+
+/// Handles the `StartProfiling` command.
+///
+/// This function starts sampling the guest RIP, RSP, CR3 and the first slots of the stack on all the logical
+/// processors, each time their VMX-preemption timer expires at the requested frequency. The samples of a previous run
+/// that haven't been read are discarded.
+///
+/// # Arguments
+///
+/// * `profiler` - The `ProfilerOperation` containing the sampling frequency and the stack depth.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the profiler was started successfully, or `None` if an error occurred.
+fn handle_start_profiling(profiler: ProfilerOperation) -> Option<()> {
+    debug!("Starting profiler at {} Hz with a stack depth of {}", profiler.frequency_hz, profiler.stack_depth);
+
+    if profiler.frequency_hz == 0 || profiler.frequency_hz > MAX_PROFILING_FREQUENCY_HZ {
+        error!("Invalid sampling frequency: {} Hz", profiler.frequency_hz);
+        return None;
+    }
+
+    let config = ProfilerConfig {
+        interval_tsc_ticks: tsc_frequency_hz() / profiler.frequency_hz,
+        stack_depth: profiler.stack_depth as usize,
+    };
+
+    if let Err(e) = start_profiling(config) {
+        error!("Failed to start profiler: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ReadProfile` command.
+///
+/// This function moves as many of the oldest samples as fit to the buffer provided by the user mode client, after a
+/// `ProfileHeader` giving their number. The buffer is written one page at a time as it may not be physically
+/// contiguous, and the samples moved are lost if it can't be written.
+///
+/// # Arguments
+///
+/// * `profiler` - The `ProfilerOperation` containing the buffer to write the samples to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the samples were written to the buffer, or `None` if an error occurred.
+fn handle_read_profile(profiler: ProfilerOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<ProfileHeader>();
+    let sample_size = core::mem::size_of::<ProfileSample>();
+
+    let max_samples = (profiler.buffer_size as usize).checked_sub(header_size)? / sample_size;
+    let (samples, dropped_samples) = SHARED_PROFILE.lock().drain(max_samples);
+
+    debug!("Reading {} profile samples, {} dropped", samples.len(), dropped_samples);
+
+    let header = ProfileHeader {
+        sample_count: samples.len() as u64,
+        dropped_samples,
+    };
+
+    let mut data = Vec::with_capacity(header_size + samples.len() * sample_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const ProfileHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * sample_size) });
+
+    write_guest_buffer(profiler.buffer, &data)
+}
+
+/// Writes data to a buffer of the user mode client one page at a time, as it may not be physically contiguous.
+///
+/// # Arguments
+///
+/// * `buffer` - The virtual address of the buffer.
+/// * `data` - The data to write.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the data was written, or `None` if a page of the buffer couldn't be written.
+fn write_guest_buffer(buffer: u64, data: &[u8]) -> Option<()> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let guest_va = buffer + offset as u64;
+        let chunk_size = (BASE_PAGE_SIZE - (guest_va as usize & (BASE_PAGE_SIZE - 1))).min(data.len() - offset);
+        PhysicalAddress::write_guest_virt_slice_with_current_cr3(guest_va as *mut u8, &data[offset..offset + chunk_size])?;
+        offset += chunk_size;
+    }
+
+    Some(())
+}
+
+/// Handles the `ConfigureWatchdog` command.
+///
+/// This function enables the watchdog on all the logical processors with the requested period, reporting those whose
+/// guest makes no progress for a whole period and optionally injecting an NMI into them, or disables it with a period of 0.
+///
+/// # Arguments
+///
+/// * `watchdog` - The `WatchdogOperation` containing the period and whether to inject an NMI.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the watchdog was configured successfully, or `None` if an error occurred.
+fn handle_configure_watchdog(watchdog: WatchdogOperation) -> Option<()> {
+    debug!("Configuring watchdog with a period of {} ms, inject NMI: {}", watchdog.period_ms, watchdog.inject_nmi);
+
+    if watchdog.period_ms != 0 && !is_preemption_timer_supported() {
+        error!("Failed to configure watchdog: {:?}", HypervisorError::PreemptionTimerUnsupported);
+        return None;
+    }
+
+    let config = WatchdogConfig {
+        period_tsc_ticks: (tsc_frequency_hz() as u128 * watchdog.period_ms as u128 / 1000) as u64,
+        inject_nmi: watchdog.inject_nmi,
+    };
+
+    SHARED_WATCHDOG.lock().configure(config);
+
+    Some(())
+}
+
+/// The longest delay accepted by the `SetResetPolicy` command, in milliseconds, as the guest is stalled in VMX root operation meanwhile.
+const MAX_RESET_DELAY_MS: u64 = 10_000;
+
+/// Handles the `SetResetPolicy` command.
+///
+/// This function sets how the guest's attempts to reset or shut down the system are handled.
+/// The requests are only intercepted when the `reset_control` feature is enabled.
+///
+/// # Arguments
+///
+/// * `policy` - The new `ResetPolicy`.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the policy was set, or `None` if the delay is too long.
+fn handle_set_reset_policy(policy: ResetPolicy) -> Option<()> {
+    debug!("Setting reset policy to: {:?}", policy);
+
+    if let ResetPolicy::Delay(delay_ms) = policy {
+        if delay_ms > MAX_RESET_DELAY_MS {
+            error!("Invalid reset delay: {} ms", delay_ms);
+            return None;
+        }
+    }
+
+    SHARED_RESET_CONTROL.write().set_policy(policy);
+
+    Some(())
+}
+
+/// Handles the `ConfigureDeterministicMode` command.
+///
+/// This function enables the deterministic mode on all the logical processors, in which `RDTSC`, `RDTSCP`, `RDRAND`
+/// and `RDSEED` return values advancing only by the requested policy, or disables it.
+///
+/// # Arguments
+///
+/// * `determinism` - The `DeterminismOperation` containing the seed and the virtual TSC policy.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the deterministic mode was configured successfully, or `None` if an error occurred.
+fn handle_configure_deterministic_mode(determinism: DeterminismOperation) -> Option<()> {
+    debug!("Configuring deterministic mode: {:?}", determinism);
+
+    let config = DeterministicConfig {
+        enabled: determinism.enabled,
+        seed: determinism.seed,
+        start_tsc: determinism.start_tsc,
+        tsc_ticks_per_read: determinism.tsc_ticks_per_read,
+    };
+
+    if let Err(e) = SHARED_DETERMINISM.lock().configure(config) {
+        error!("Failed to configure deterministic mode: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ConfigureHookView` command.
+///
+/// This function enables or disables a hooked kernel function in an alternate hook view, binds a hooked kernel function
+/// to a process, or assigns a view to a process or a logical processor. The hooks themselves are installed in the default view through the `EnableKernelEptHook` command.
+///
+/// # Arguments
+///
+/// * `hook_view` - The `HookViewOperation` to perform.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the operation was performed successfully, or `None` if an error occurred.
+fn handle_configure_hook_view(hook_view: HookViewOperation) -> Option<()> {
+    debug!("Configuring hook view: {:?}", hook_view);
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    let result = match hook_view {
+        HookViewOperation::EnableHook {
+            view,
+            function_hash,
+            syscall_number,
+        } => hook_manager.set_hook_view_function(view, function_hash, syscall_number, true),
+        HookViewOperation::DisableHook {
+            view,
+            function_hash,
+            syscall_number,
+        } => hook_manager.set_hook_view_function(view, function_hash, syscall_number, false),
+        HookViewOperation::AssignProcess { process_id, view } => {
+            let process_view = match view {
+                Some(view) => Some(ProcessHookView {
+                    directory_table_base: ProcessInformation::get_directory_table_base_by_process_id(process_id)?,
+                    user_directory_table_base: ProcessInformation::get_user_directory_table_base_by_process_id(process_id)?,
+                    view,
+                }),
+                None => None,
+            };
+            hook_manager.hook_views.assign_process_view(process_id, process_view)
+        }
+        HookViewOperation::BindHookToProcess {
+            function_hash,
+            syscall_number,
+            process_id,
+        } => {
+            let scope = match process_id {
+                Some(process_id) => Some(ProcessHookScope {
+                    process_id,
+                    directory_table_base: ProcessInformation::get_directory_table_base_by_process_id(process_id)?,
+                    user_directory_table_base: ProcessInformation::get_user_directory_table_base_by_process_id(process_id)?,
+                }),
+                None => None,
+            };
+            hook_manager.bind_hook_to_process(function_hash, syscall_number, scope)
+        }
+        HookViewOperation::AssignProcessor { processor_id, view } => {
+            hook_manager.hook_views.assign_processor_view(processor_id, view);
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        error!("Failed to configure hook view: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `StartSyscallTrace` command.
+///
+/// This function starts recording the system calls passing the filter on all the logical processors, with their
+/// arguments, the requesting CR3, process and thread, and their return values. The records of a previous run that
+/// haven't been read are discarded.
+///
+/// # Arguments
+///
+/// * `syscall_trace` - The `SyscallTraceOperation` containing the filter.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the trace was started successfully, or `None` if the filter is invalid.
+fn handle_start_syscall_trace(syscall_trace: SyscallTraceOperation) -> Option<()> {
+    debug!("Starting syscall trace: {:?}", syscall_trace.filter_mode);
+
+    if syscall_trace.syscall_count as usize > MAX_SYSCALL_TRACE_FILTER {
+        error!("Invalid syscall trace filter size: {}", syscall_trace.syscall_count);
+        return None;
+    }
+
+    start_syscall_trace(syscall_trace.filter_mode, &syscall_trace.syscall_numbers[..syscall_trace.syscall_count as usize]);
+
+    Some(())
+}
+
+/// Handles the `ReadSyscallTrace` command.
+///
+/// This function moves as many of the oldest records as fit to the buffer provided by the user mode client, after a
+/// `SyscallTraceHeader` giving their number. The records moved are lost if the buffer can't be written.
+///
+/// # Arguments
+///
+/// * `syscall_trace` - The `SyscallTraceOperation` containing the buffer to write the records to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the records were written to the buffer, or `None` if an error occurred.
+fn handle_read_syscall_trace(syscall_trace: SyscallTraceOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<SyscallTraceHeader>();
+    let record_size = core::mem::size_of::<SyscallTraceRecord>();
+
+    let max_records = (syscall_trace.buffer_size as usize).checked_sub(header_size)? / record_size;
+    let (records, dropped_records) = SHARED_SYSCALL_TRACE.lock().drain(max_records);
+
+    debug!("Reading {} syscall trace records, {} dropped", records.len(), dropped_records);
+
+    let header = SyscallTraceHeader {
+        record_count: records.len() as u64,
+        dropped_records,
+    };
+
+    let mut data = Vec::with_capacity(header_size + records.len() * record_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const SyscallTraceHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(records.as_ptr() as *const u8, records.len() * record_size) });
+
+    write_guest_buffer(syscall_trace.buffer, &data)
+}
+
+/// Handles the `ConfigureExceptionTelemetry` command.
+///
+/// This function enables the interception and recording of the selected exceptions on all the logical processors,
+/// which are reflected to the guest, or disables it if no exception is selected. Enabling it discards the events of a
+/// previous run that haven't been read.
+///
+/// # Arguments
+///
+/// * `exception_telemetry` - The `ExceptionTelemetryOperation` containing the selected exceptions and the rate limit.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the telemetry was configured successfully, or `None` if an error occurred.
+fn handle_configure_exception_telemetry(exception_telemetry: ExceptionTelemetryOperation) -> Option<()> {
+    debug!("Configuring exception telemetry: {:?}", exception_telemetry);
+
+    let vectors = [
+        (exception_telemetry.page_faults, ExceptionInterrupt::PageFault),
+        (exception_telemetry.general_protection_faults, ExceptionInterrupt::GeneralProtectionFault),
+        (exception_telemetry.invalid_opcodes, ExceptionInterrupt::InvalidOpcode),
+    ]
+    .into_iter()
+    .filter(|&(enabled, _)| enabled)
+    .fold(0, |vectors, (_, vector)| vectors | (1 << vector as u32));
+
+    let config = ExceptionTelemetryConfig {
+        vectors,
+        user_mode_page_faults_only: exception_telemetry.user_mode_page_faults_only,
+        max_events_per_second: exception_telemetry.max_events_per_second,
+    };
+
+    if let Err(e) = SHARED_EXCEPTION_TELEMETRY.lock().configure(config) {
+        error!("Failed to configure exception telemetry: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ReadExceptionTelemetry` command.
+///
+/// This function moves as many of the oldest events as fit to the buffer provided by the user mode client, after an
+/// `ExceptionTelemetryHeader` giving their number. The events moved are lost if the buffer can't be written.
+///
+/// # Arguments
+///
+/// * `exception_telemetry` - The `ExceptionTelemetryOperation` containing the buffer to write the events to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the events were written to the buffer, or `None` if an error occurred.
+fn handle_read_exception_telemetry(exception_telemetry: ExceptionTelemetryOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<ExceptionTelemetryHeader>();
+    let event_size = core::mem::size_of::<ExceptionEvent>();
+
+    let max_events = (exception_telemetry.buffer_size as usize).checked_sub(header_size)? / event_size;
+    let (events, dropped_events, suppressed_events) = SHARED_EXCEPTION_TELEMETRY.lock().drain(max_events);
+
+    debug!("Reading {} exception events, {} dropped, {} suppressed", events.len(), dropped_events, suppressed_events);
+
+    let header = ExceptionTelemetryHeader {
+        event_count: events.len() as u64,
+        dropped_events,
+        suppressed_events,
+    };
+
+    let mut data = Vec::with_capacity(header_size + events.len() * event_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const ExceptionTelemetryHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(events.as_ptr() as *const u8, events.len() * event_size) });
+
+    write_guest_buffer(exception_telemetry.buffer, &data)
+}
+
+/// Handles the `ConfigureMsrBitmap` command.
+///
+/// This function swaps the MSR bitmap profile as a whole, which every logical processor copies to its MSR bitmap on
+/// its next VM exit before applying the MSR hooks again, so the hooked MSR accesses stay intercepted.
+///
+/// # Arguments
+///
+/// * `msr_bitmap` - The `MsrBitmapOperation` selecting the new profile.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the profile was swapped successfully, or `None` if the range is invalid.
+fn handle_configure_msr_bitmap(msr_bitmap: MsrBitmapOperation) -> Option<()> {
+    debug!("Configuring MSR bitmap: {:x?}", msr_bitmap);
+
+    let mut msr_hook_manager = SHARED_MSR_HOOK_MANAGER.lock();
+
+    let bitmap_profile = match msr_bitmap {
+        MsrBitmapOperation::Quiet => MsrBitmap::new(),
+        MsrBitmapOperation::InterceptAll => MsrBitmap::intercept_all(),
+        MsrBitmapOperation::ModifyRange {
+            first_msr,
+            last_msr,
+            reads,
+            writes,
+            intercept,
+        } => {
+            if first_msr > last_msr {
+                error!("Invalid MSR range: {:#x}..={:#x}", first_msr, last_msr);
+                return None;
+            }
+
+            let operation = match intercept {
+                true => MsrOperation::Hook,
+                false => MsrOperation::Unhook,
+            };
+
+            let mut bitmap_profile = *msr_hook_manager.bitmap_profile();
+            if reads {
+                bitmap_profile.modify_msr_range_interception(first_msr..=last_msr, MsrAccessType::Read, operation);
+            }
+            if writes {
+                bitmap_profile.modify_msr_range_interception(first_msr..=last_msr, MsrAccessType::Write, operation);
+            }
+            bitmap_profile
+        }
+    };
+
+    msr_hook_manager.set_bitmap_profile(bitmap_profile);
+
+    Some(())
+}
+
+/// Handles the `StartUnpacker` command.
+///
+/// This function write-protects the pages backing a range of the target process, so the pages it writes and then
+/// executes are dumped, replacing the range watched by a previous run.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `unpacker` - The `UnpackerOperation` containing the target process and range.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the range is watched, or `None` if an error occurred.
+fn handle_start_unpacker(vm: &mut Vm, unpacker: UnpackerOperation) -> Option<()> {
+    debug!("Starting unpacker: {:x?}", unpacker);
+
+    if let Err(e) = start_unpacker(vm, unpacker.process_id, unpacker.base_address, unpacker.size) {
+        error!("Failed to start unpacker: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `StopUnpacker` command.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the watch has been stopped, or `None` if an error occurred.
+fn handle_stop_unpacker(vm: &mut Vm) -> Option<()> {
+    if let Err(e) = SHARED_UNPACKER.lock().stop(vm) {
+        error!("Failed to stop unpacker: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ReadUnpackerDumps` command.
+///
+/// This function scans the watched range again, to watch the pages remapped by the guest meanwhile, then moves as many
+/// of the oldest dumped pages as fit to the buffer provided by the user mode client, after an `UnpackerDumpHeader`
+/// giving their number. The pages moved are lost if the buffer can't be written.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `unpacker` - The `UnpackerOperation` containing the buffer to write the pages to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the pages were written to the buffer, or `None` if an error occurred.
+fn handle_read_unpacker_dumps(vm: &mut Vm, unpacker: UnpackerOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<UnpackerDumpHeader>();
+    let dump_size = core::mem::size_of::<UnpackedPage>();
+
+    let max_dumps = (unpacker.buffer_size as usize).checked_sub(header_size)? / dump_size;
+
+    let mut shared_unpacker = SHARED_UNPACKER.lock();
+
+    if let Err(e) = shared_unpacker.rescan(vm) {
+        error!("Failed to rescan unpacker range: {:?}", e);
+    }
+
+    let (dumps, dropped_dumps) = shared_unpacker.drain(max_dumps);
+    let watched_pages = shared_unpacker.watched_page_count() as u64;
+    drop(shared_unpacker);
+
+    debug!("Reading {} unpacked pages, {} dropped", dumps.len(), dropped_dumps);
+
+    let header = UnpackerDumpHeader {
+        dump_count: dumps.len() as u64,
+        dropped_dumps,
+        watched_pages,
+    };
+
+    let mut data = Vec::with_capacity(header_size + dumps.len() * dump_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const UnpackerDumpHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(dumps.as_ptr() as *const u8, dumps.len() * dump_size) });
+
+    write_guest_buffer(unpacker.buffer, &data)
+}
+
+/// Handles the `ConfigureCpuidOverride` command.
+///
+/// This function registers the override of a CPUID leaf, composing the masks of a `Modify` action with the current
+/// override of the leaf, or removes it.
+///
+/// # Arguments
+///
+/// * `cpuid_override` - The `CpuidOverrideOperation` containing the leaf and the action.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the override was configured successfully, or `None` if there was none to remove
+///   or too many overrides are registered.
+fn handle_configure_cpuid_override(cpuid_override: CpuidOverrideOperation) -> Option<()> {
+    debug!("Configuring CPUID override: {:x?}", cpuid_override);
+
+    let mut cpuid_hook_manager = SHARED_CPUID_HOOK_MANAGER.lock();
+
+    let hook = match cpuid_override.action {
+        CpuidOverrideAction::Replace(registers) => CpuidHook::Replace(registers),
+        CpuidOverrideAction::Modify { and_mask, or_mask } => CpuidHook::Modify { and_mask, or_mask },
+        CpuidOverrideAction::Remove => {
+            return cpuid_hook_manager.unregister(cpuid_override.leaf, cpuid_override.sub_leaf).map(|_| ());
+        }
+    };
+
+    let hook = match cpuid_hook_manager.get(cpuid_override.leaf, cpuid_override.sub_leaf) {
+        Some(current_hook) => current_hook.then(hook),
+        None => hook,
+    };
+
+    cpuid_hook_manager.register(cpuid_override.leaf, cpuid_override.sub_leaf, hook).ok()
+}
+
+/// Handles the `StartAllocationMonitor` command.
+///
+/// This function starts maintaining the allocation map of a process, hooking the system calls by the numbers resolved
+/// by the user mode client.
+///
+/// # Arguments
+///
+/// * `allocation_monitor` - The `AllocationMonitorOperation` containing the target process and the system call numbers.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the process is monitored, or `None` if an error occurred.
+fn handle_start_allocation_monitor(allocation_monitor: AllocationMonitorOperation) -> Option<()> {
+    debug!("Starting allocation monitor: {:x?}", allocation_monitor);
+
+    let syscall_numbers = AllocationSyscallNumbers {
+        allocate: allocation_monitor.allocate_syscall_number,
+        protect: allocation_monitor.protect_syscall_number,
+        free: allocation_monitor.free_syscall_number,
+    };
+
+    if let Err(e) = SHARED_ALLOCATION_MONITOR.lock().start(allocation_monitor.process_id, syscall_numbers) {
+        error!("Failed to start allocation monitor: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `StopAllocationMonitor` command.
+///
+/// # Arguments
+///
+/// * `allocation_monitor` - The `AllocationMonitorOperation` containing the target process, or 0 for every process.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` once the monitoring has been stopped.
+fn handle_stop_allocation_monitor(allocation_monitor: AllocationMonitorOperation) -> Option<()> {
+    SHARED_ALLOCATION_MONITOR.lock().stop(allocation_monitor.process_id);
+
+    Some(())
+}
+
+/// Handles the `ReadAllocationMap` command.
+///
+/// This function copies as many regions of the allocation map of a monitored process as fit to the buffer provided by
+/// the user mode client, in the order of their addresses, after an `AllocationMapHeader` giving their number.
+///
+/// # Arguments
+///
+/// * `allocation_monitor` - The `AllocationMonitorOperation` containing the target process and the buffer to write the regions to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the regions were written to the buffer, or `None` if the process isn't monitored or an error occurred.
+fn handle_read_allocation_map(allocation_monitor: AllocationMonitorOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<AllocationMapHeader>();
+    let region_size = core::mem::size_of::<AllocationRegion>();
+
+    let max_regions = (allocation_monitor.buffer_size as usize).checked_sub(header_size)? / region_size;
+
+    let Some((regions, total_regions, dropped_regions)) = SHARED_ALLOCATION_MONITOR.lock().regions(allocation_monitor.process_id, max_regions) else {
+        error!("Process {} isn't monitored", allocation_monitor.process_id);
+        return None;
+    };
+
+    debug!("Reading {} of {} allocation regions of process {}", regions.len(), total_regions, allocation_monitor.process_id);
+
+    let header = AllocationMapHeader {
+        region_count: regions.len() as u64,
+        total_regions,
+        dropped_regions,
+    };
+
+    let mut data = Vec::with_capacity(header_size + regions.len() * region_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const AllocationMapHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(regions.as_ptr() as *const u8, regions.len() * region_size) });
+
+    write_guest_buffer(allocation_monitor.buffer, &data)
+}
+
+/// Handles the `ReadAllocationAlerts` command.
+///
+/// This function moves as many of the oldest allocation alerts as fit to the buffer provided by the user mode client,
+/// after an `AllocationAlertHeader` giving their number. The alerts moved are lost if the buffer can't be written.
+///
+/// # Arguments
+///
+/// * `allocation_monitor` - The `AllocationMonitorOperation` containing the buffer to write the alerts to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the alerts were written to the buffer, or `None` if an error occurred.
+fn handle_read_allocation_alerts(allocation_monitor: AllocationMonitorOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<AllocationAlertHeader>();
+    let alert_size = core::mem::size_of::<AllocationAlert>();
+
+    let max_alerts = (allocation_monitor.buffer_size as usize).checked_sub(header_size)? / alert_size;
+
+    let (alerts, dropped_alerts) = SHARED_ALLOCATION_MONITOR.lock().drain_alerts(max_alerts);
+
+    debug!("Reading {} allocation alerts, {} dropped", alerts.len(), dropped_alerts);
+
+    let header = AllocationAlertHeader {
+        alert_count: alerts.len() as u64,
+        dropped_alerts,
+    };
+
+    let mut data = Vec::with_capacity(header_size + alerts.len() * alert_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const AllocationAlertHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(alerts.as_ptr() as *const u8, alerts.len() * alert_size) });
+
+    write_guest_buffer(allocation_monitor.buffer, &data)
+}
+
+/// Handles the `ConfigureHypervisorPresence` command.
+///
+/// This function hides or exposes the CPUID hypervisor leaves and the hypervisor-present bit on all the logical
+/// processors, keeping the other overrides of leaf 1.
+///
+/// # Arguments
+///
+/// * `presence` - The `HypervisorPresence` to apply.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` once the hypervisor presence has been changed, or `None` if the override of
+///   leaf 1 can't be registered.
+fn handle_configure_hypervisor_presence(presence: HypervisorPresence) -> Option<()> {
+    SHARED_CPUID_HOOK_MANAGER.lock().set_presence(presence).ok()
+}
+
+/// Handles the `ConfigureCodeSnapshots` command.
+///
+/// This function enables or disables the automatic copies of the code pages seen to become executable after being
+/// written, keeping the copies held so far.
+///
+/// # Arguments
+///
+/// * `code_snapshot` - The `CodeSnapshotOperation` containing whether the pages are copied and the rate limit.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the copies were configured successfully, or `None` if an error occurred.
+fn handle_configure_code_snapshots(code_snapshot: CodeSnapshotOperation) -> Option<()> {
+    if let Err(e) = SHARED_CODE_SNAPSHOTS
+        .lock()
+        .configure(code_snapshot.enabled, code_snapshot.max_snapshots_per_second)
+    {
+        error!("Failed to configure code snapshots: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ReadCodeSnapshots` command.
+///
+/// This function moves as many of the oldest copies as fit to the buffer provided by the user mode client, after a
+/// `CodeSnapshotHeader` giving their number. The copies moved are lost if the buffer can't be written.
+///
+/// # Arguments
+///
+/// * `code_snapshot` - The `CodeSnapshotOperation` containing the buffer to write the copies to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the copies were written to the buffer, or `None` if an error occurred.
+fn handle_read_code_snapshots(code_snapshot: CodeSnapshotOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<CodeSnapshotHeader>();
+    let snapshot_size = core::mem::size_of::<CodeSnapshot>();
+
+    let max_snapshots = (code_snapshot.buffer_size as usize).checked_sub(header_size)? / snapshot_size;
+
+    let (snapshots, dropped_snapshots, suppressed_snapshots, held_snapshots) = {
+        let mut code_snapshots = SHARED_CODE_SNAPSHOTS.lock();
+        let (snapshots, dropped_snapshots, suppressed_snapshots) = code_snapshots.drain(max_snapshots);
+        (snapshots, dropped_snapshots, suppressed_snapshots, code_snapshots.held_snapshot_count() as u64)
+    };
+
+    debug!("Reading {} code snapshots, {} dropped, {} suppressed, {} held", snapshots.len(), dropped_snapshots, suppressed_snapshots, held_snapshots);
+
+    let header = CodeSnapshotHeader {
+        snapshot_count: snapshots.len() as u64,
+        dropped_snapshots,
+        suppressed_snapshots,
+        held_snapshots,
+    };
+
+    let mut data = Vec::with_capacity(header_size + snapshots.len() * snapshot_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const CodeSnapshotHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(snapshots.as_ptr() as *const u8, snapshots.len() * snapshot_size) });
+
+    write_guest_buffer(code_snapshot.buffer, &data)
+}
+
+/// Handles the `ConfigureTscCompensation` command.
+///
+/// This function enables the compensation of the guest TSC for the time spent in VMX root operation on all the
+/// logical processors, with optional `RDTSC` exiting and TSC scaling, or disables it.
+///
+/// # Arguments
+///
+/// * `tsc_compensation` - The `TscCompensationOperation` containing the configuration.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the compensation was configured successfully, or `None` if an error occurred.
+fn handle_configure_tsc_compensation(tsc_compensation: TscCompensationOperation) -> Option<()> {
+    debug!("Configuring TSC compensation: {:?}", tsc_compensation);
+
+    let config = TscCompensationConfig {
+        enabled: tsc_compensation.enabled,
+        rdtsc_exiting: tsc_compensation.rdtsc_exiting,
+        tsc_multiplier: tsc_compensation.tsc_multiplier,
+        exit_latency_ticks: tsc_compensation.exit_latency_ticks,
+    };
+
+    if let Err(e) = SHARED_TSC_COMPENSATION.lock().configure(config) {
+        error!("Failed to configure TSC compensation: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `LoadModuleSymbols` command.
+///
+/// This function loads the exports of a module mapped in the guest, so the addresses of the module resolve to them,
+/// replacing the exports loaded before for the same module.
+///
+/// # Arguments
+///
+/// * `symbol` - The `SymbolOperation` containing the process and the base address of the module.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the exports were loaded successfully, or `None` if an error occurred.
+fn handle_load_module_symbols(symbol: SymbolOperation) -> Option<()> {
+    match SHARED_SYMBOL_TABLE.lock().load(symbol.process_id, symbol.base_address) {
+        Ok(_) => Some(()),
+        Err(e) => {
+            error!("Failed to load the symbols of {:#x}: {:?}", symbol.base_address, e);
+            None
+        }
+    }
+}
+
+/// Handles the `ResolveSymbols` command.
+///
+/// This function resolves the addresses to the loaded modules and writes them to the buffer provided by the user
+/// mode client, after a `SymbolHeader` giving their number. The names are truncated to the size of the fields.
+///
+/// # Arguments
+///
+/// * `symbol` - The `SymbolOperation` containing the process, the addresses and the buffer to write the symbols to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the symbols were written to the buffer, or `None` if an error occurred.
+fn handle_resolve_symbols(symbol: SymbolOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<SymbolHeader>();
+    let resolved_symbol_size = core::mem::size_of::<ResolvedSymbol>();
+
+    let max_symbols = (symbol.buffer_size as usize).checked_sub(header_size)? / resolved_symbol_size;
+    let address_count = (symbol.address_count as usize).min(MAX_SYMBOL_ADDRESSES).min(max_symbols);
+
+    let (symbols, module_count) = {
+        let symbol_table = SHARED_SYMBOL_TABLE.lock();
+
+        let symbols: Vec<ResolvedSymbol> = symbol.addresses[..address_count]
+            .iter()
+            .map(|&address| {
+                let mut resolved = ResolvedSymbol {
+                    address,
+                    module_base: 0,
+                    offset: 0,
+                    module_name: [0; SYMBOL_MODULE_NAME_SIZE],
+                    export_name: [0; SYMBOL_EXPORT_NAME_SIZE],
+                };
+
+                if let Some((module, export)) = symbol_table.resolve(symbol.process_id, address) {
+                    resolved.module_base = module.base_va;
+                    copy_symbol_name(&mut resolved.module_name, &module.name);
+
+                    match export {
+                        Some(export) => {
+                            resolved.offset = address - module.base_va - export.rva as u64;
+                            copy_symbol_name(&mut resolved.export_name, &export.name);
+                        }
+                        None => resolved.offset = address - module.base_va,
+                    }
+                }
+
+                resolved
+            })
+            .collect();
+
+        (symbols, symbol_table.module_count() as u64)
+    };
+
+    debug!("Resolving {} addresses with {} modules", symbols.len(), module_count);
+
+    let header = SymbolHeader {
+        symbol_count: symbols.len() as u64,
+        module_count,
+    };
+
+    let mut data = Vec::with_capacity(header_size + symbols.len() * resolved_symbol_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const SymbolHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(symbols.as_ptr() as *const u8, symbols.len() * resolved_symbol_size) });
+
+    write_guest_buffer(symbol.buffer, &data)
+}
+
+/// Copies a name into a NUL-padded field, truncated to keep a terminating NUL.
+///
+/// # Arguments
+///
+/// * `field` - The NUL-initialized field.
+/// * `name` - The name to copy.
+fn copy_symbol_name(field: &mut [u8], name: &str) {
+    let length = name.len().min(field.len() - 1);
+    field[..length].copy_from_slice(&name.as_bytes()[..length]);
+}
+
+/// Handles the `TriggerBootHooks` command.
+///
+/// This function fires the agent hypercall trigger of the boot-time hook manifest, or any trigger if forced, so the
+/// hooks of the manifest are installed at the next VM exit.
+///
+/// # Arguments
+///
+/// * `boot_hook` - The `BootHookOperation` containing whether the trigger of the manifest is overridden.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the hooks are now pending, or `None` if the trigger doesn't match or the
+///   hooks are already pending or installed.
+fn handle_trigger_boot_hooks(boot_hook: BootHookOperation) -> Option<()> {
+    debug!("Triggering boot hooks: {:?}", boot_hook);
+
+    let fired = match boot_hook.force {
+        true => force_boot_hook_trigger(),
+        false => fire_boot_hook_trigger(|trigger| *trigger == BootHookTrigger::Hypercall),
+    };
+
+    if !fired {
+        error!("Boot hooks not triggered: the trigger doesn't match, or they are not armed or already installed");
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `SearchMemory` command.
+///
+/// This function searches a range of a process for a string or a regex-lite pattern, and writes the matches found
+/// and the address where a further search resumes to the client buffer.
+///
+/// # Arguments
+///
+/// * `memory_search` - The `MemorySearchOperation` containing the process, the range, the pattern and the buffer.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the range has been searched, or `None` if the pattern is invalid, the
+///   process isn't found or the buffer can't hold the header.
+fn handle_search_memory(memory_search: MemorySearchOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<MemorySearchHeader>();
+    let match_size = core::mem::size_of::<MemorySearchMatch>();
+
+    let max_matches = (memory_search.buffer_size as usize).checked_sub(header_size)? / match_size;
+    let pattern = memory_search.pattern.get(..memory_search.pattern_size as usize)?;
+
+    let pattern = match SearchPattern::new(pattern, memory_search.is_regex, memory_search.case_insensitive, memory_search.encoding) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            error!("Failed to compile the search pattern: {:?}", e);
+            return None;
+        }
+    };
+
+    let directory_table_base = ProcessInformation::get_directory_table_base_by_process_id(memory_search.process_id)?;
+
+    debug!(
+        "Searching {:#x}-{:#x} of process {} for {} matches",
+        memory_search.start_address, memory_search.end_address, memory_search.process_id, max_matches
+    );
+
+    let result = search_guest_memory(&pattern, memory_search.start_address, memory_search.end_address, directory_table_base, max_matches);
+
+    let header = MemorySearchHeader {
+        match_count: result.matches.len() as u64,
+        next_address: result.next_address,
+        scanned_bytes: result.scanned_bytes,
+    };
+
+    let mut data = Vec::with_capacity(header_size + result.matches.len() * match_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const MemorySearchHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(result.matches.as_ptr() as *const u8, result.matches.len() * match_size) });
+
+    write_guest_buffer(memory_search.buffer, &data)
+}
+
+/// Handles the `ConfigureProcessTracking` command.
+///
+/// This function enables or disables the tracking of the process owning the current address space on all the logical
+/// processors, which pick up the change on their next VM exit.
+///
+/// # Arguments
+///
+/// * `process_tracking` - The `ProcessTrackingOperation` containing whether the processes are tracked.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the tracker has been configured, or `None` if the System process can't be found.
+fn handle_configure_process_tracking(process_tracking: ProcessTrackingOperation) -> Option<()> {
+    debug!("Configuring process tracking: {:?}", process_tracking);
+
+    if let Err(e) = SHARED_PROCESS_TRACKER.lock().configure(process_tracking.enabled) {
+        error!("Failed to configure process tracking: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ConfigureXsavePolicy` command.
+///
+/// This function sets the XSAVE state components that the guest may not enable, with the components depending on
+/// them, applied to the next `XSETBV` of each logical processor and to the CPUID results.
+///
+/// # Arguments
+///
+/// * `xsave_policy` - The `XsavePolicyOperation` containing the denied components.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the policy has been set, or `None` if it denies the x87 state.
+fn handle_configure_xsave_policy(xsave_policy: XsavePolicyOperation) -> Option<()> {
+    debug!("Configuring XSAVE policy: {:x?}", xsave_policy);
+
+    if let Err(e) = SHARED_XSAVE_POLICY.lock().set_denied_components(xsave_policy.denied_components) {
+        error!("Failed to configure the XSAVE policy: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ReadBenchmark` command.
+///
+/// This function writes the summary of the benchmark run to the buffer provided by the user mode client: a
+/// `BenchmarkHeader` followed by as many of the exit reasons with VM exits as fit.
+///
+/// # Arguments
+///
+/// * `benchmark` - The `BenchmarkOperation` containing the buffer to write the summary to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the summary was written, or `None` if no run is armed or the buffer couldn't be written.
+fn handle_read_benchmark(benchmark: BenchmarkOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<BenchmarkHeader>();
+    let exit_reason_size = core::mem::size_of::<BenchmarkExitReason>();
+
+    let max_exit_reasons = (benchmark.buffer_size as usize).checked_sub(header_size)? / exit_reason_size;
+    let (mut header, mut exit_reasons) = SHARED_BENCHMARK.lock().summary()?;
+
+    exit_reasons.truncate(max_exit_reasons);
+    header.exit_reason_count = exit_reasons.len() as u64;
+
+    debug!("Reading benchmark summary of run {}: {} exit reasons", header.run_index, exit_reasons.len());
+
+    let mut data = Vec::with_capacity(header_size + exit_reasons.len() * exit_reason_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const BenchmarkHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(exit_reasons.as_ptr() as *const u8, exit_reasons.len() * exit_reason_size) });
+
+    write_guest_buffer(benchmark.buffer, &data)
+}
+
+/// Handles the `SetHardwareBreakpoint` command.
+///
+/// This function sets or removes a hardware breakpoint of the hypervisor, which the logical processors program in
+/// their debug registers on their next VM exit while hiding it from the guest.
+///
+/// # Arguments
+///
+/// * `hardware_breakpoint` - The `HardwareBreakpointOperation` containing the slot and the breakpoint.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the slot has been changed, or `None` if the slot or the breakpoint is invalid.
+fn handle_set_hardware_breakpoint(hardware_breakpoint: HardwareBreakpointOperation) -> Option<()> {
+    debug!("Setting hardware breakpoint: {:x?}", hardware_breakpoint);
+
+    let breakpoint = match hardware_breakpoint.address {
+        0 => None,
+        address => {
+            let condition = match hardware_breakpoint.condition {
+                0 => BreakpointCondition::Execute,
+                1 => BreakpointCondition::Write,
+                3 => BreakpointCondition::ReadWrite,
+                _ => {
+                    error!("Invalid hardware breakpoint condition: {}", hardware_breakpoint.condition);
+                    return None;
+                }
+            };
+
+            match HardwareBreakpoint::new(address, condition, hardware_breakpoint.length as u8) {
+                Ok(breakpoint) => Some(breakpoint),
+                Err(e) => {
+                    error!("Invalid hardware breakpoint: {:?}", e);
+                    return None;
+                }
+            }
+        }
+    };
+
+    if let Err(e) = SHARED_HARDWARE_BREAKPOINTS
+        .lock()
+        .set_breakpoint(hardware_breakpoint.slot as usize, breakpoint)
+    {
+        error!("Failed to set the hardware breakpoint: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ConfigureEventRing` command.
+///
+/// This function sets the backpressure policy of an event ring, applied to the next events recorded while it's full.
+///
+/// # Arguments
+///
+/// * `event_ring` - The `EventRingOperation` containing the ring and its policy.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` once the policy has been set.
+fn handle_configure_event_ring(event_ring: EventRingOperation) -> Option<()> {
+    debug!("Configuring event ring: {:?}", event_ring);
+
+    configure_event_ring(event_ring.ring, event_ring.policy);
+
+    Some(())
+}
+
+/// Handles the `ReadEventRingStats` command.
+///
+/// This function copies the statistics of an event ring, including the numbers of events it lost by policy, to the
+/// buffer of the client.
+///
+/// # Arguments
+///
+/// * `event_ring` - The `EventRingStatsOperation` containing the ring and the buffer.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the statistics were written, or `None` if the buffer is too small or couldn't be written.
+fn handle_read_event_ring_stats(event_ring: EventRingStatsOperation) -> Option<()> {
+    let stats_size = core::mem::size_of::<EventRingStats>();
+
+    if (event_ring.buffer_size as usize) < stats_size {
+        error!("Event ring statistics buffer too small: {:#x}", event_ring.buffer_size);
+        return None;
+    }
+
+    let stats = event_ring_stats(event_ring.ring);
+
+    debug!("Reading event ring {:?} statistics: {:?}", event_ring.ring, stats);
+
+    let data = unsafe { core::slice::from_raw_parts(&stats as *const EventRingStats as *const u8, stats_size) };
+
+    write_guest_buffer(event_ring.buffer, data)
+}
+
+/// Handles the `SaveConfiguration` command.
+///
+/// This function saves the hypervisor presence, the TSC compensation, the CPUID overrides and the boot-time hook
+/// manifest to the configuration file on the EFI System Partition, re-applied by the loader on the next boot, or
+/// discards the saved configuration.
+///
+/// # Arguments
+///
+/// * `configuration` - The `SaveConfigurationOperation` selecting whether the configuration is saved or discarded.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the configuration file was written, or `None` if it isn't set up or the write failed.
+fn handle_save_configuration(configuration: SaveConfigurationOperation) -> Option<()> {
+    if configuration.discard {
+        debug!("Discarding the saved configuration");
+
+        if let Err(e) = discard_saved_configuration() {
+            error!("Failed to discard the saved configuration: {:?}", e);
+            return None;
+        }
+
+        return Some(());
+    }
+
+    match save_configuration() {
+        Ok(length) => {
+            debug!("Saved the configuration: {:#x} bytes", length);
+            Some(())
+        }
+        Err(e) => {
+            error!("Failed to save the configuration: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Handles the `ConfigureMsrContextRule` command.
+///
+/// This function adds a rule deciding how the reads or the writes of an MSR made from a guest context are handled, or
+/// removes the rules of the access. The accesses with rules are intercepted on every logical processor from its next
+/// VM exit.
+///
+/// # Arguments
+///
+/// * `msr_context_rule` - The `MsrContextRuleOperation` containing the rule to add or the access to clear.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the rules were updated, or `None` if the rule is invalid or the access has too many rules.
+fn handle_configure_msr_context_rule(msr_context_rule: MsrContextRuleOperation) -> Option<()> {
+    debug!("Configuring MSR context rule: {:x?}", msr_context_rule);
+
+    let access_type = |writes: bool| match writes {
+        true => MsrAccessType::Write,
+        false => MsrAccessType::Read,
+    };
+
+    let mut msr_hook_manager = SHARED_MSR_HOOK_MANAGER.lock();
+
+    match msr_context_rule {
+        MsrContextRuleOperation::Add {
+            msr,
+            writes,
+            context,
+            action,
+            max_matches,
+        } => {
+            if context.cpl.is_some_and(|cpl| cpl > 3) || max_matches == Some(0) || context.code_range.is_some_and(|(_, size)| size == 0) {
+                error!("Invalid MSR context rule: {:x?}", msr_context_rule);
+                return None;
+            }
+
+            let rule = MsrContextRule {
+                context,
+                action,
+                remaining_matches: max_matches,
+            };
+
+            if let Err(e) = msr_hook_manager.add_context_rule(msr, access_type(writes), rule) {
+                error!("Failed to add the MSR context rule: {:?}", e);
+                return None;
+            }
+        }
+        MsrContextRuleOperation::Clear { msr, writes } => {
+            let rule_count = msr_hook_manager.clear_context_rules(msr, access_type(writes));
+            debug!("Removed {} MSR context rules", rule_count);
+        }
+    }
+
+    Some(())
+}
+
+/// Handles the `RunDetectionCorpus` command.
+///
+/// This function evaluates the hypervisor against the corpus of public hypervisor-detection techniques on the current
+/// logical processor, from the observations of the guest made by the client, and writes the pass/fail matrix.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `detection_corpus` - The `DetectionCorpusOperation` containing the observations and the buffer receiving the results.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the results were written, or `None` if the buffer is too small or couldn't be written.
+fn handle_run_detection_corpus(vm: &mut Vm, detection_corpus: DetectionCorpusOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<DetectionCorpusHeader>();
+    let result_size = core::mem::size_of::<DetectionCheckResult>();
+
+    let (header, results) = run_detection_corpus(vm, &detection_corpus.probes);
+
+    if (detection_corpus.buffer_size as usize) < header_size + results.len() * result_size {
+        error!("Detection corpus buffer too small: {:#x}", detection_corpus.buffer_size);
+        return None;
+    }
+
+    debug!("Detection corpus: {} passed, {} failed, {} skipped", header.passed, header.failed, header.skipped);
+
+    let mut data = Vec::with_capacity(header_size + results.len() * result_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const DetectionCorpusHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(results.as_ptr() as *const u8, results.len() * result_size) });
+
+    write_guest_buffer(detection_corpus.buffer, &data)
+}
+
+/// Handles the `ConfigureLinuxKernel` command.
+///
+/// This function registers the offsets of a Linux kernel release, replacing its previous offsets, or removes them,
+/// the running kernel being matched again against the releases on its next use.
+///
+/// # Arguments
+///
+/// * `linux_kernel` - The `LinuxKernelOperation` containing the release and its offsets.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the offsets were registered or removed, or `None` if an error occurred.
+fn handle_configure_linux_kernel(linux_kernel: LinuxKernelOperation) -> Option<()> {
+    let release_size = linux_kernel.release.iter().position(|&b| b == 0).unwrap_or(linux_kernel.release.len());
+    let release = core::str::from_utf8(&linux_kernel.release[..release_size]).ok()?;
+
+    let mut kernel = SHARED_LINUX_KERNEL.lock();
+
+    if linux_kernel.remove {
+        debug!("Removing the Linux kernel offsets of {}", release);
+        return kernel.remove_offsets(release).then_some(());
+    }
+
+    match kernel.set_offsets(release, linux_kernel.offsets) {
+        Ok(_) => Some(()),
+        Err(e) => {
+            error!("Failed to register the Linux kernel offsets of {}: {:?}", release, e);
+            None
+        }
+    }
+}
+
+/// Handles the `ReadLinuxTasks` command.
+///
+/// This function enumerates the tasks of the running Linux kernel and writes them to the buffer provided by the user
+/// mode client, after a `LinuxTaskHeader` giving their number.
+///
+/// # Arguments
+///
+/// * `linux_tasks` - The `LinuxTasksOperation` containing the buffer to write the tasks to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the tasks were written to the buffer, or `None` if an error occurred.
+fn handle_read_linux_tasks(linux_tasks: LinuxTasksOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<LinuxTaskHeader>();
+    let task_size = core::mem::size_of::<LinuxTask>();
+
+    let max_tasks = (linux_tasks.buffer_size as usize).checked_sub(header_size)? / task_size;
+
+    let (tasks, total_tasks) = match enumerate_linux_tasks(max_tasks) {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            error!("Failed to enumerate the Linux tasks: {:?}", e);
+            return None;
+        }
+    };
+
+    debug!("Reading {} of {} Linux tasks", tasks.len(), total_tasks);
+
+    let header = LinuxTaskHeader {
+        task_count: tasks.len() as u64,
+        total_tasks: total_tasks as u64,
+    };
+
+    let mut data = Vec::with_capacity(header_size + tasks.len() * task_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const LinuxTaskHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(tasks.as_ptr() as *const u8, tasks.len() * task_size) });
+
+    write_guest_buffer(linux_tasks.buffer, &data)
+}
+
+/// Handles the `StartExecutionTrace` command.
+///
+/// This function makes the pages backing a range of the target process non-executable, so the instructions executed
+/// in the range are single-stepped and recorded, replacing the range traced by a previous run.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `execution_trace` - The `ExecutionTraceOperation` containing the target process and range.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the range is traced, or `None` if an error occurred.
+fn handle_start_execution_trace(vm: &mut Vm, execution_trace: ExecutionTraceOperation) -> Option<()> {
+    debug!("Starting execution trace: {:x?}", execution_trace);
+
+    if let Err(e) =
+        start_execution_trace(vm, execution_trace.process_id, execution_trace.base_address, execution_trace.size, execution_trace.capture_registers)
+    {
+        error!("Failed to start execution trace: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `StopExecutionTrace` command.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the trace has been stopped, or `None` if an error occurred.
+fn handle_stop_execution_trace(vm: &mut Vm) -> Option<()> {
+    if let Err(e) = SHARED_EXECUTION_TRACER.lock().stop(vm) {
+        error!("Failed to stop execution trace: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ReadExecutionTrace` command.
+///
+/// This function stops the trace if the target process has exited, then moves as many of the oldest records as fit to
+/// the buffer provided by the user mode client, after an `ExecutionTraceHeader` giving their number. The records moved
+/// are lost if the buffer can't be written.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `execution_trace` - The `ExecutionTraceOperation` containing the buffer to write the records to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the records were written to the buffer, or `None` if an error occurred.
+fn handle_read_execution_trace(vm: &mut Vm, execution_trace: ExecutionTraceOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<ExecutionTraceHeader>();
+    let record_size = core::mem::size_of::<ExecutionTraceRecord>();
+
+    let max_records = (execution_trace.buffer_size as usize).checked_sub(header_size)? / record_size;
+
+    let mut tracer = SHARED_EXECUTION_TRACER.lock();
+
+    if let Err(e) = tracer.stop_if_target_exited(vm) {
+        error!("Failed to stop execution trace: {:?}", e);
+    }
+
+    let (records, dropped_records) = tracer.drain(max_records);
+    let traced_pages = tracer.traced_page_count() as u64;
+    drop(tracer);
+
+    debug!("Reading {} execution trace records, {} dropped", records.len(), dropped_records);
+
+    let header = ExecutionTraceHeader {
+        record_count: records.len() as u64,
+        dropped_records,
+        traced_pages,
+    };
+
+    let mut data = Vec::with_capacity(header_size + records.len() * record_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const ExecutionTraceHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(records.as_ptr() as *const u8, records.len() * record_size) });
+
+    write_guest_buffer(execution_trace.buffer, &data)
+}
+
+/// Handles the `ScanSignature` command.
+///
+/// This function scans a range of the guest physical memory, or of a process, for a byte pattern with wildcards, and
+/// writes the addresses of the matches found and the address where a further scan resumes to the client buffer.
+///
+/// # Arguments
+///
+/// * `signature_scan` - The `SignatureScanOperation` containing the range, the pattern and the buffer.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the range has been scanned, or `None` if the pattern is invalid, the
+///   process isn't found or the buffer can't hold the header.
+fn handle_scan_signature(signature_scan: SignatureScanOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<SignatureScanHeader>();
+    let match_size = core::mem::size_of::<u64>();
+
+    let max_matches = (signature_scan.buffer_size as usize).checked_sub(header_size)? / match_size;
+    let pattern_size = signature_scan.pattern_size as usize;
+
+    let signature = match Signature::from_masked(signature_scan.pattern.get(..pattern_size)?, signature_scan.mask.get(..pattern_size)?) {
+        Ok(signature) => signature,
+        Err(e) => {
+            error!("Failed to parse the signature: {:?}", e);
+            return None;
+        }
+    };
+
+    let space = match signature_scan.physical {
+        true => ScanSpace::Physical,
+        false => ScanSpace::Virtual(ProcessInformation::get_directory_table_base_by_process_id(signature_scan.process_id)?),
+    };
+
+    debug!("Scanning {:#x}-{:#x} of {:x?} for {} matches", signature_scan.start_address, signature_scan.end_address, space, max_matches);
+
+    let result = scan_guest_memory(&signature, space, signature_scan.start_address, signature_scan.end_address, max_matches, MAX_SIGNATURE_SCAN_SIZE);
+
+    let header = SignatureScanHeader {
+        match_count: result.matches.len() as u64,
+        next_address: result.next_address,
+        scanned_bytes: result.scanned_bytes,
+    };
+
+    let mut data = Vec::with_capacity(header_size + result.matches.len() * match_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const SignatureScanHeader as *const u8, header_size) });
+    result.matches.iter().for_each(|address| data.extend_from_slice(&address.to_le_bytes()));
+
+    write_guest_buffer(signature_scan.buffer, &data)
+}
+
+/// Handles the `EnumerateProcesses` command.
+///
+/// This function enumerates the processes of the Windows guest and writes them to the buffer provided by the user
+/// mode client, after a `WindowsProcessHeader` giving their number.
+///
+/// # Arguments
+///
+/// * `introspection` - The `WindowsIntrospectionOperation` containing the buffer to write the processes to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the processes were written to the buffer, or `None` if an error occurred.
+fn handle_enumerate_processes(introspection: WindowsIntrospectionOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<WindowsProcessHeader>();
+    let process_size = core::mem::size_of::<WindowsProcess>();
+
+    let max_processes = (introspection.buffer_size as usize).checked_sub(header_size)? / process_size;
+
+    let (processes, total_processes) = match enumerate_processes(max_processes) {
+        Ok(processes) => processes,
+        Err(e) => {
+            error!("Failed to enumerate the Windows processes: {:?}", e);
+            return None;
+        }
+    };
+
+    debug!("Reading {} of {} Windows processes", processes.len(), total_processes);
+
+    let header = WindowsProcessHeader {
+        process_count: processes.len() as u64,
+        total_processes: total_processes as u64,
+    };
+
+    let mut data = Vec::with_capacity(header_size + processes.len() * process_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const WindowsProcessHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(processes.as_ptr() as *const u8, processes.len() * process_size) });
+
+    write_guest_buffer(introspection.buffer, &data)
+}
+
+/// Handles the `EnumerateThreads` command.
+///
+/// This function enumerates the threads of a process of the Windows guest and writes them to the buffer provided by
+/// the user mode client, after a `WindowsThreadHeader` giving their number.
+///
+/// # Arguments
+///
+/// * `introspection` - The `WindowsIntrospectionOperation` containing the ID of the process and the buffer to write
+///   the threads to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the threads were written to the buffer, or `None` if an error occurred.
+fn handle_enumerate_threads(introspection: WindowsIntrospectionOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<WindowsThreadHeader>();
+    let thread_size = core::mem::size_of::<WindowsThread>();
+
+    let max_threads = (introspection.buffer_size as usize).checked_sub(header_size)? / thread_size;
+
+    let (threads, total_threads) = match enumerate_threads(introspection.process_id, max_threads) {
+        Ok(threads) => threads,
+        Err(e) => {
+            error!("Failed to enumerate the threads of process {:#x}: {:?}", introspection.process_id, e);
+            return None;
+        }
+    };
+
+    debug!("Reading {} of {} threads of process {:#x}", threads.len(), total_threads, introspection.process_id);
+
+    let header = WindowsThreadHeader {
+        thread_count: threads.len() as u64,
+        total_threads: total_threads as u64,
+    };
+
+    let mut data = Vec::with_capacity(header_size + threads.len() * thread_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const WindowsThreadHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(threads.as_ptr() as *const u8, threads.len() * thread_size) });
+
+    write_guest_buffer(introspection.buffer, &data)
+}
+
+/// Handles the `EnumerateVads` command.
+///
+/// This function enumerates the VADs of a process of the Windows guest and writes them to the buffer provided by the
+/// user mode client, after a `WindowsVadHeader` giving their number.
+///
+/// # Arguments
+///
+/// * `introspection` - The `WindowsIntrospectionOperation` containing the ID of the process and the buffer to write
+///   the VADs to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the VADs were written to the buffer, or `None` if an error occurred.
+fn handle_enumerate_vads(introspection: WindowsIntrospectionOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<WindowsVadHeader>();
+    let vad_size = core::mem::size_of::<WindowsVad>();
+
+    let max_vads = (introspection.buffer_size as usize).checked_sub(header_size)? / vad_size;
+
+    let (vads, total_vads) = match enumerate_vads(introspection.process_id, max_vads) {
+        Ok(vads) => vads,
+        Err(e) => {
+            error!("Failed to enumerate the VADs of process {:#x}: {:?}", introspection.process_id, e);
+            return None;
+        }
+    };
+
+    debug!("Reading {} of {} VADs of process {:#x}", vads.len(), total_vads, introspection.process_id);
+
+    let header = WindowsVadHeader {
+        vad_count: vads.len() as u64,
+        total_vads: total_vads as u64,
+    };
+
+    let mut data = Vec::with_capacity(header_size + vads.len() * vad_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const WindowsVadHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(vads.as_ptr() as *const u8, vads.len() * vad_size) });
+
+    write_guest_buffer(introspection.buffer, &data)
+}
+
+/// Handles the `DumpProcessMemory` command.
+///
+/// This function dumps a bounded part of the memory of a process of the Windows guest and writes it to the buffer
+/// provided by the user mode client, after a `ProcessDumpHeader` giving where a further dump resumes, or to the serial
+/// port as `dump <process ID> <address> <bytes>` lines of hexadecimal bytes.
+///
+/// # Arguments
+///
+/// * `process_dump` - The `ProcessDumpOperation` containing the process, the range, the filter and the buffer.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the dump was written, or `None` if an error occurred.
+fn handle_dump_process_memory(process_dump: ProcessDumpOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<ProcessDumpHeader>();
+    let chunk_size = core::mem::size_of::<ProcessDumpChunk>();
+
+    let max_output_size = match process_dump.serial {
+        // The chunks written to the serial port have no header, but the output of the dump counts one per chunk.
+        true => MAX_SERIAL_PROCESS_DUMP_SIZE as usize + chunk_size,
+        false => (process_dump.buffer_size as usize)
+            .checked_sub(header_size)?
+            .min(MAX_PROCESS_DUMP_SIZE as usize),
+    };
+
+    let filter = DumpFilter {
+        private_only: process_dump.private_only,
+        executable_only: process_dump.executable_only,
+    };
+
+    debug!("Dumping {:#x}-{:#x} of process {:#x} with {:?}", process_dump.start_address, process_dump.end_address, process_dump.process_id, filter);
+
+    let dump = match dump_process_memory(process_dump.process_id, process_dump.start_address, process_dump.end_address, filter, max_output_size) {
+        Ok(dump) => dump,
+        Err(e) => {
+            error!("Failed to dump the memory of process {:#x}: {:?}", process_dump.process_id, e);
+            return None;
+        }
+    };
+
+    let mut data = Vec::new();
+
+    for chunk in &dump.chunks {
+        if process_dump.serial {
+            // 32 bytes per line, so a line is far shorter than the buffers of the serial consoles.
+            for (index, line) in chunk.bytes.chunks(0x20).enumerate() {
+                let address = chunk.address + index as u64 * 0x20;
+                write_serial(format_args!("dump {:#x} {:#018x} ", process_dump.process_id, address))?;
+                line.iter().try_for_each(|byte| write_serial(format_args!("{:02x}", byte)))?;
+                write_serial(format_args!("\n"))?;
+            }
+        } else {
+            let header = ProcessDumpChunk {
+                address: chunk.address,
+                size: chunk.bytes.len() as u64,
+            };
+
+            data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const ProcessDumpChunk as *const u8, chunk_size) });
+            data.extend_from_slice(&chunk.bytes);
+        }
+    }
+
+    let header = ProcessDumpHeader {
+        chunk_count: dump.chunks.len() as u64,
+        data_size: data.len() as u64,
+        next_address: dump.next_address,
+        dumped_bytes: dump.dumped_bytes,
+    };
+
+    let mut output = Vec::with_capacity(header_size + data.len());
+    output.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const ProcessDumpHeader as *const u8, header_size) });
+    output.extend_from_slice(&data);
+
+    write_guest_buffer(process_dump.buffer, &output)
+}
+
+/// Handles the `EnumerateKernelCallbacks` command.
+///
+/// This function enumerates the notification callbacks registered in the kernel of the Windows guest and writes them
+/// to the buffer provided by the user mode client, after a `KernelCallbackHeader` giving their number.
+///
+/// # Arguments
+///
+/// * `introspection` - The `WindowsIntrospectionOperation` containing the buffer to write the callbacks to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the callbacks were written to the buffer, or `None` if an error occurred.
+fn handle_enumerate_kernel_callbacks(introspection: WindowsIntrospectionOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<KernelCallbackHeader>();
+    let callback_size = core::mem::size_of::<KernelCallback>();
+
+    let max_callbacks = ((introspection.buffer_size as usize).checked_sub(header_size)? / callback_size).min(MAX_KERNEL_CALLBACKS);
+
+    let (callbacks, total_callbacks) = match enumerate_kernel_callbacks(max_callbacks) {
+        Ok(callbacks) => callbacks,
+        Err(e) => {
+            error!("Failed to enumerate the kernel callbacks: {:?}", e);
+            return None;
+        }
+    };
+
+    debug!("Reading {} of {} kernel callbacks", callbacks.len(), total_callbacks);
+
+    let header = KernelCallbackHeader {
+        callback_count: callbacks.len() as u64,
+        total_callbacks: total_callbacks as u64,
+    };
+
+    let mut data = Vec::with_capacity(header_size + callbacks.len() * callback_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const KernelCallbackHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(callbacks.as_ptr() as *const u8, callbacks.len() * callback_size) });
+
+    write_guest_buffer(introspection.buffer, &data)
+}
+
+/// Handles the `ConfigureOsEvents` command.
+///
+/// This function hooks the ntoskrnl.exe functions at the RVAs provided by the user mode client to push the process
+/// creations and the image loads of the guest to the event stream, removing the hooks of the previous functions.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `os_events` - The `OsEventsOperation` containing the RVAs of the hooked functions.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the functions are hooked, or `None` if an error occurred.
+fn handle_configure_os_events(vm: &mut Vm, os_events: OsEventsOperation) -> Option<()> {
+    debug!("Configuring OS events: {:x?}", os_events);
+
+    if let Err(e) = configure_os_events(vm, os_events.process_insert_rva, os_events.image_notify_rva) {
+        error!("Failed to configure OS events: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ConfigureCodeIntegrity` command.
+///
+/// This function hashes the code pages of ntoskrnl.exe and of the drivers named by the user mode client and starts
+/// their periodic verification, replacing the previous configuration, or stops it if the period is 0.
+///
+/// # Arguments
+///
+/// * `code_integrity` - The `CodeIntegrityOperation` containing the period and the names of the drivers.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the monitor is configured, or `None` if an error occurred.
+fn handle_configure_code_integrity(code_integrity: CodeIntegrityOperation) -> Option<()> {
+    let mut drivers = Vec::new();
+
+    for name in &code_integrity.drivers {
+        let size = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+
+        if size == 0 {
+            continue;
+        }
+
+        let Ok(name) = core::str::from_utf8(&name[..size]) else {
+            error!("Invalid driver name: {:x?}", &name[..size]);
+            return None;
+        };
+
+        drivers.push(String::from(name));
+    }
+
+    debug!("Configuring code integrity: every {} ms, drivers: {:?}", code_integrity.period_ms, drivers);
+
+    if let Err(e) = configure_code_integrity(code_integrity.period_ms, &drivers) {
+        error!("Failed to configure code integrity: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ProtectMemory` command.
+///
+/// This function protects a range of a process from the reads and/or writes of the other processes, replacing the
+/// range of the process at the same address, if any.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `protected_memory` - The `ProtectedMemoryOperation` containing the range and the accesses denied.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the range is protected, or `None` if an error occurred.
+fn handle_protect_memory(vm: &mut Vm, protected_memory: ProtectedMemoryOperation) -> Option<()> {
+    debug!("Protecting memory: {:x?}", protected_memory);
+
+    let policy = ProtectionPolicy {
+        deny_reads: protected_memory.deny_reads,
+        deny_writes: protected_memory.deny_writes,
+        allow_kernel_access: protected_memory.allow_kernel_access,
+        action: protected_memory.action,
+    };
+
+    if let Err(e) = protect_memory(vm, protected_memory.process_id, protected_memory.address, protected_memory.size, policy) {
+        error!("Failed to protect memory: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `UnprotectMemory` command.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `protected_memory` - The `ProtectedMemoryOperation` containing the process and the address of the range, or 0 for
+///   all the ranges of the process.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the ranges have been removed, or `None` if an error occurred.
+fn handle_unprotect_memory(vm: &mut Vm, protected_memory: ProtectedMemoryOperation) -> Option<()> {
+    debug!("Unprotecting memory of process {}: {:#x}", protected_memory.process_id, protected_memory.address);
+
+    if let Err(e) = unprotect_memory(vm, protected_memory.process_id, protected_memory.address) {
+        error!("Failed to unprotect memory: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `ConfigureEptView` command.
+///
+/// This function duplicates, collapses or switches an EPT view, or sets or resets a divergent page of an alternate
+/// view. The logical processors apply the changes on their next VM exit.
+///
+/// # Arguments
+///
+/// * `ept_view` - The `EptViewOperation` to perform.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the operation was performed successfully, or `None` if an error occurred.
+fn handle_configure_ept_view(ept_view: EptViewOperation) -> Option<()> {
+    debug!("Configuring EPT view: {:x?}", ept_view);
+
+    let mut manager = SHARED_EPT_VIEWS.lock();
+
+    let result = match ept_view {
+        EptViewOperation::Duplicate { view, source, name } => {
+            let size = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+
+            let Ok(name) = core::str::from_utf8(&name[..size]) else {
+                error!("Invalid EPT view name: {:x?}", &name[..size]);
+                return None;
+            };
+
+            manager.duplicate(view, source, name)
+        }
+        EptViewOperation::Collapse { view } => manager.collapse(view),
+        EptViewOperation::Switch { processor_id, view } => manager.switch(processor_id, view),
+        EptViewOperation::SetPage {
+            view,
+            guest_pa,
+            host_pa,
+            readable,
+            writable,
+            executable,
+            switch_view,
+        } => {
+            let mut access_type = AccessType::empty();
+            access_type.set(AccessType::READ, readable);
+            access_type.set(AccessType::WRITE, writable);
+            access_type.set(AccessType::EXECUTE, executable);
+
+            let page = DivergentPage {
+                host_page_pa: host_pa,
+                access_type,
+                switch_view,
+            };
+
+            manager.set_page(view, guest_pa, page)
+        }
+        EptViewOperation::ResetPage { view, guest_pa } => manager.reset_page(view, guest_pa),
+    };
+
+    if let Err(e) = result {
+        error!("Failed to configure EPT view: {:?}", e);
+        return None;
+    }
+
+    Some(())
+}
+
+/// Handles the `Devirtualize` command.
+///
+/// This function requests the logical processors to leave VMX operation, each one at the end of its next VM exit after
+/// which the guest can be resumed natively, or cancels the request.
+///
+/// # Arguments
+///
+/// * `devirtualize` - The `DevirtualizeOperation` to perform.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the operation was performed successfully, or `None` if an error occurred.
+fn handle_devirtualize(devirtualize: DevirtualizeOperation) -> Option<()> {
+    debug!("Devirtualize: {:?}", devirtualize);
+
+    match devirtualize {
+        DevirtualizeOperation::Start => {
+            if let Err(e) = request_devirtualization() {
+                error!("Failed to request devirtualization: {:?}", e);
+                return None;
+            }
+        }
+        DevirtualizeOperation::Cancel => cancel_devirtualization(),
+    }
+
+    Some(())
+}
+
+/// Handles the `Broadcast` command.
+///
+/// This function executes the command on the current logical processor, then waits for the other ones to execute it on
+/// their next VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `command` - The `BroadcastCommand` to broadcast to all the logical processors.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if each logical processor executed the command, or `None` if an error occurred.
+fn handle_broadcast(vm: &mut Vm, command: BroadcastCommand) -> Option<()> {
+    debug!("Broadcast: {:?}", command);
+
+    if let Err(e) = broadcast(vm, command) {
+        error!("Failed to broadcast {:?}: {:?}", command, e);
+        return None;
+    }
+
     Some(())
 }
+
+/// Handles the `ReadExitStatistics` command.
+///
+/// This function broadcasts `PublishExitStatistics` so each logical processor publishes its VM exit statistics, then
+/// writes them to the buffer provided by the user mode client: an `ExitStatisticsHeader` followed by as many of the
+/// records as fit. The statistics of the logical processors that didn't acknowledge the broadcast in time are missing.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `exit_statistics` - The `ExitStatisticsOperation` containing the buffer to write the statistics to.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the statistics were written, or `None` if an error occurred.
+fn handle_read_exit_statistics(vm: &mut Vm, exit_statistics: ExitStatisticsOperation) -> Option<()> {
+    let header_size = core::mem::size_of::<ExitStatisticsHeader>();
+    let record_size = core::mem::size_of::<ExitStatisticsRecord>();
+
+    let max_records = (exit_statistics.buffer_size as usize).checked_sub(header_size)? / record_size;
+
+    match broadcast(
+        vm,
+        BroadcastCommand::PublishExitStatistics {
+            reset: exit_statistics.reset,
+        },
+    ) {
+        Ok(()) => {}
+        Err(HypervisorError::BroadcastTimeout) => warn!("VM exit statistics of some logical processors missing"),
+        Err(e) => {
+            error!("Failed to broadcast PublishExitStatistics: {:?}", e);
+            return None;
+        }
+    }
+
+    let (reported_processors, mut records) = {
+        let statistics = SHARED_EXIT_STATISTICS.lock();
+
+        if exit_statistics.log {
+            for line in statistics.report().lines() {
+                info!("{}", line);
+            }
+        }
+
+        (statistics.reported_processors(), statistics.records())
+    };
+
+    let total_record_count = records.len() as u64;
+    records.truncate(max_records);
+
+    let header = ExitStatisticsHeader {
+        tsc_frequency_hz: tsc_frequency_hz(),
+        reported_processors,
+        record_count: records.len() as u64,
+        total_record_count,
+    };
+
+    debug!("Reading VM exit statistics of {} processors: {} of {} records", reported_processors, records.len(), total_record_count);
+
+    let mut data = Vec::with_capacity(header_size + records.len() * record_size);
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(&header as *const ExitStatisticsHeader as *const u8, header_size) });
+    data.extend_from_slice(unsafe { core::slice::from_raw_parts(records.as_ptr() as *const u8, records.len() * record_size) });
+
+    write_guest_buffer(exit_statistics.buffer, &data)
+}
@@ -5,13 +5,21 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            hooks::{
+                boot_manifest::{fire_boot_hook_trigger, BootHookTrigger},
+                cpuid_hook::apply_cpuid_hook,
+                hook_manager::{kernel_image, SHARED_HOOK_MANAGER},
+            },
             vm::Vm,
-            vmexit::{commands::handle_guest_commands, ExitType},
+            vmexit::{commands::handle_guest_commands, vmcall::dispatch_hypercall, ExitType},
+            xsave_policy::apply_xsave_policy,
         },
+        personality::is_linux_guest,
+        windows::{offsets::detect_windows_build, ssdt::ssdt_find::SsdtFind},
     },
-    bitfield::BitMut,
+    core::sync::atomic::{AtomicBool, Ordering},
     log::*,
-    shared::CommandStatus,
+    shared::{CommandStatus, HYPERCALL_MAGIC},
     x86::cpuid::cpuid,
 };
 
@@ -63,7 +71,7 @@ pub enum CpuidLeaf {
 /// Enumerates specific feature bits in the ECX register for CPUID instruction results.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[allow(dead_code)]
-enum FeatureBits {
+pub enum FeatureBits {
     /// Bit 5 of ECX for CPUID with EAX=1, indicating VMX support.
     HypervisorVmxSupportBit = 5,
     /// Bit 31 of ECX for CPUID with EAX=1, indicating hypervisor presence.
@@ -73,12 +81,19 @@ enum FeatureBits {
 /// The password used for authentication with the hypervisor.
 const PASSWORD: u64 = 0xDEADBEEF;
 
+/// Set once the cache information leaf fired its boot-time work, so its later executions don't lock the hook manager.
+static CACHE_INFORMATION_HANDLED: AtomicBool = AtomicBool::new(false);
+
 /// Handles the `CPUID` VM-exit.
 ///
 /// This function is invoked when the guest executes the `CPUID` instruction.
 /// The handler retrieves the results of the `CPUID` instruction executed on
 /// the host and then modifies or masks certain bits, if necessary, before
-/// returning the results to the guest.
+/// returning the results to the guest, as decided by the XSAVE policy and the
+/// override of the leaf in the CPUID hook registry, if any.
+///
+/// A `CPUID` with `HYPERCALL_MAGIC` in RAX is a hypercall, dispatched as with `VMCALL` if it's authenticated, and a
+/// `CPUID` with the password in RAX is a command of the client.
 ///
 /// # Arguments
 ///
@@ -97,6 +112,13 @@ pub fn handle_cpuid(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     let leaf = vm.guest_registers.rax as u32;
     let sub_leaf = vm.guest_registers.rcx as u32;
 
+    // A hypercall made with CPUID, covert as the leaf of its magic is executed as on bare metal unless the hypercall
+    // is authenticated.
+    if vm.guest_registers.rax == HYPERCALL_MAGIC && dispatch_hypercall(vm) {
+        vm.guest_registers.rbx = HYPERCALL_MAGIC;
+        return Ok(ExitType::IncrementRIP);
+    }
+
     if vm.guest_registers.rax == PASSWORD {
         // Handle the guest command and update the CPUID result accordingly
         vm.guest_registers.rax = match handle_guest_commands(vm) {
@@ -117,78 +139,42 @@ pub fn handle_cpuid(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
             leaf if leaf == CpuidLeaf::FeatureInformation as u32 => {
                 trace!("CPUID leaf 1 detected (Standard Feature Information).");
 
-                // Hide hypervisor presence by setting the appropriate bit in ECX.
-                cpuid_result.ecx.set_bit(FeatureBits::HypervisorPresentBit as usize, false);
-
-                // Hide VMX support by setting the appropriate bit in ECX.
-                // cpuid_result.ecx.set_bit(FeatureBits::HypervisorVmxSupportBit as usize, false);
+                // The hypervisor-present bit of ECX follows the hypervisor presence through a built-in override of
+                // the CPUID hook registry, which may also hide VMX support at runtime.
             }
             leaf if leaf == CpuidLeaf::CacheInformation as u32 => {
                 trace!("CPUID leaf 0x2 detected (Cache Information).");
 
-                /*
-                // Lock the shared hook manager
-                let mut hook_manager = crate::intel::hooks::hook_manager::SHARED_HOOK_MANAGER.lock();
-
-                if !hook_manager.has_cpuid_cache_info_been_called {
-                    // Test UEFI boot-time hooks
-                    hook_manager.manage_kernel_ept_hook(
-                        vm,
-                        crate::windows::nt::pe::djb2_hash("NtQuerySystemInformation".as_bytes()),
-                        0x0036,
-                        crate::intel::hooks::hook_manager::EptHookType::Function(crate::intel::hooks::inline::InlineHookType::Vmcall),
-                        true,
-                    )?;
-                    hook_manager.manage_kernel_ept_hook(
-                        vm,
-                        crate::windows::nt::pe::djb2_hash("NtCreateFile".as_bytes()),
-                        0x0055,
-                        crate::intel::hooks::hook_manager::EptHookType::Function(crate::intel::hooks::inline::InlineHookType::Vmcall),
-                        true,
-                    )?;
-                    hook_manager.manage_kernel_ept_hook(
-                        vm,
-                        crate::windows::nt::pe::djb2_hash("NtAllocateVirtualMemory".as_bytes()),
-                        0x18,
-                        crate::intel::hooks::hook_manager::EptHookType::Function(crate::intel::hooks::inline::InlineHookType::Vmcall),
-                        true,
-                    )?;
-                    hook_manager.manage_kernel_ept_hook(
-                        vm,
-                        crate::windows::nt::pe::djb2_hash("NtQueryInformationProcess".as_bytes()),
-                        0x19,
-                        crate::intel::hooks::hook_manager::EptHookType::Function(crate::intel::hooks::inline::InlineHookType::Vmcall),
-                        true,
-                    )?;
-                    // Set the flag
-                    hook_manager.has_cpuid_cache_info_been_called = true;
+                // The leaf is executed often, e.g., at each process start, so the hook manager is only locked until
+                // its boot-time work is done.
+                if !CACHE_INFORMATION_HANDLED.load(Ordering::Acquire) {
+                    handle_cache_information();
                 }
-                 */
             }
             leaf if leaf == CpuidLeaf::ExtendedFeatureInformation as u32 => {
                 trace!("CPUID leaf 0x7 detected (Extended Feature Information).");
             }
             leaf if leaf == CpuidLeaf::HypervisorVendor as u32 => {
                 trace!("CPUID leaf 0x40000000 detected (Hypervisor Vendor Information).");
-                // Set the CPUID response to provide the hypervisor's vendor ID signature.
-                // We use the signature "Illusion" encoded in a little-endian format.
-                // cpuid_result.eax = CpuidLeaf::HypervisorInterface as u32; // Maximum supported CPUID leaf range.
-                // cpuid_result.ebx = 0x756c6c49; // "ullI", part of "Illusion" (in reverse order due to little-endian storage).
-                // cpuid_result.ecx = 0x6e6f6973; // "nois", part of "Illusion" (in reverse order due to little-endian storage).
-                // cpuid_result.edx = 0x00000000; // Filled with null bytes as there are no more characters to encode.
+                // The hypervisor's vendor ID signature "Illusion" is reported by the CPUID hook registry while the
+                // hypervisor is exposed, and the leaf is hidden otherwise (see `cpuid_hook::hypervisor_leaf`).
             }
             leaf if leaf == CpuidLeaf::HypervisorInterface as u32 => {
                 trace!("CPUID leaf 0x40000001 detected (Hypervisor Interface Identification).");
-                // Return information indicating the hypervisor's interface.
-                // Here, we specify that our hypervisor does not conform to the Microsoft hypervisor interface ("Hv#1").
-                // cpuid_result.eax = 0x00000000; // Interface signature indicating non-conformance to Microsoft interface.
-                // cpuid_result.ebx = 0x00000000; // Reserved field set to zero.
-                // cpuid_result.ecx = 0x00000000; // Reserved field set to zero.
-                // cpuid_result.edx = 0x00000000; // Reserved field set to zero.
+                // While the hypervisor is exposed, the CPUID hook registry reports that it doesn't conform to the
+                // Microsoft hypervisor interface ("Hv#1").
             }
             _ => trace!("CPUID leaf 0x{leaf:X}."),
         }
 
+        // The feature bits and the XSAVE leaf follow the state components the guest may enable.
+        apply_xsave_policy(leaf, sub_leaf, &mut cpuid_result);
+
+        // Most leaves have no override, so their results are returned without looking one up.
+        if vm.cpuid_hooks.may_be_overridden(leaf) {
+            apply_cpuid_hook(vm, leaf, sub_leaf, &mut cpuid_result)?;
+        }
+
         // Update the guest registers with the results
         vm.guest_registers.rax = cpuid_result.eax as u64;
         vm.guest_registers.rbx = cpuid_result.ebx as u64;
@@ -200,3 +186,37 @@ pub fn handle_cpuid(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
 
     Ok(ExitType::IncrementRIP)
 }
+
+/// Fires the boot-time work of the cache information leaf once the kernel base has been captured from IA32_LSTAR, by
+/// which time the SSDT is initialized.
+fn handle_cache_information() {
+    let ntoskrnl = kernel_image();
+    if !ntoskrnl.is_captured() {
+        return;
+    }
+
+    // Lock the shared hook manager
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    if hook_manager.has_cpuid_cache_info_been_called {
+        return;
+    }
+
+    // Install the hooks of the boot-time hook manifest at the next VM exit, if it selects this trigger
+    fire_boot_hook_trigger(|trigger| *trigger == BootHookTrigger::CacheInformation);
+
+    // Set the flag
+    hook_manager.has_cpuid_cache_info_been_called = true;
+
+    // Locate the SSDT once, so the system calls are hooked by number or name without scanning the kernel,
+    // and detect the build, whose offsets are used by the introspection.
+    if !is_linux_guest() {
+        detect_windows_build(ntoskrnl.base_va);
+
+        if let Err(e) = SsdtFind::find_ssdt(ntoskrnl.base_va, ntoskrnl.size) {
+            warn!("Failed to locate the SSDT: {:?}", e);
+        }
+    }
+
+    CACHE_INFORMATION_HANDLED.store(true, Ordering::Release);
+}
@@ -3,24 +3,39 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            benchmark::is_cr3_load_exiting_forced,
             events::EventInjection,
-            invvpid::{invvpid_single_context, VPID_TAG},
+            invept::invept_all_contexts,
+            invvpid::{invvpid_single_context, invvpid_single_context_retaining_globals},
+            process_tracker::track_address_space_switch,
             support::{rdmsr, read_effective_guest_cr0, read_effective_guest_cr4, vmread, vmwrite},
             vm::Vm,
             vmerror::{ControlRegAccessExitQualification, CrAccessReg, CrAccessType},
-            vmexit::ExitType,
+            vmexit::{init::adjust_guest_cr0, ExitType},
         },
     },
     bit_field::BitField,
     core::{ops::Range, ptr::addr_of},
     log::trace,
-    x86::vmx::{
-        vmcs,
-        vmcs::{control, guest},
+    x86::{
+        controlregs::Cr0,
+        vmx::{
+            vmcs,
+            vmcs::{
+                control::{self, EntryControls, PrimaryControls},
+                guest,
+            },
+        },
+    },
+    x86_64::registers::{
+        control::{Cr0Flags, Cr4Flags},
+        model_specific::EferFlags,
     },
-    x86_64::registers::control::{Cr0Flags, Cr4Flags},
 };
 
+/// The L bit of the CS access rights, set for a 64-bit code segment.
+const CS_LONG_MODE: u64 = 1 << 13;
+
 /// Handles the `ControlRegisterAccess` VM-exit.
 ///
 /// This function is invoked when the guest executes certain instructions
@@ -42,12 +57,96 @@ pub fn handle_cr_reg_access(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     let cr = ControlRegAccessExitQualification::from_exit_qualification(qual);
     match cr.access_type {
         CrAccessType::MovToCr => match cr.control_reg {
-            CrAccessReg::Cr2 | CrAccessReg::Cr3 | CrAccessReg::Cr8 => Err(HypervisorError::UnhandledVmExit),
+            CrAccessReg::Cr2 | CrAccessReg::Cr8 => Err(HypervisorError::UnhandledVmExit),
             CrAccessReg::Cr0 => Ok(handle_mov_to_cr0(vm, cr.gpr_mov_cr)),
+            CrAccessReg::Cr3 => Ok(handle_mov_to_cr3(vm, cr.gpr_mov_cr)),
             CrAccessReg::Cr4 => Ok(handle_mov_to_cr4(vm, cr.gpr_mov_cr)?),
         },
-        CrAccessType::MovFromCr | CrAccessType::Clts | CrAccessType::Lmsw => Err(HypervisorError::UnhandledVmExit),
+        CrAccessType::Clts => Ok(handle_clts()),
+        CrAccessType::Lmsw => Ok(handle_lmsw(cr.lmsw_source_data)),
+        // MOV from CR3 and CR8 only cause VM exits with the CR3-store and CR8-store exiting, which aren't enabled.
+        CrAccessType::MovFromCr => Err(HypervisorError::UnhandledVmExit),
+    }
+}
+
+/// CLTS causes a VM exit if CR0.TS is set in both the CR0 guest/host mask and the CR0 read shadow, which only happens
+/// if CR0.TS is fixed in VMX operation. CR0.TS is cleared as by MOV to CR0.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+fn handle_clts() -> ExitType {
+    trace!("Handling CLTS VM exit...");
+
+    let new_cr0 = Cr0Flags::from_bits_retain(read_effective_guest_cr0()) - Cr0Flags::TASK_SWITCHED;
+
+    write_guest_cr0(new_cr0)
+}
+
+/// LMSW causes a VM exit if it would change CR0.MP, CR0.EM or CR0.TS while they are set in the CR0 guest/host mask, or
+/// set CR0.PE while it's set in the mask and clear in the CR0 read shadow, e.g., for an application processor started
+/// in real mode. LMSW loads the low 4 bits of its source operand into CR0, except that it can't clear CR0.PE, and the
+/// new value is checked as by MOV to CR0.
+///
+/// # Arguments
+///
+/// * `source_data`: The source operand of LMSW.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+fn handle_lmsw(source_data: u16) -> ExitType {
+    trace!("Handling LMSW VM exit...");
+
+    const LMSW_BITS: u64 = 0xF;
+
+    let curr_cr0 = read_effective_guest_cr0();
+    let new_cr0 = (curr_cr0 & !LMSW_BITS) | (u64::from(source_data) & LMSW_BITS) | (curr_cr0 & Cr0Flags::PROTECTED_MODE_ENABLE.bits());
+
+    write_guest_cr0(Cr0Flags::from_bits_retain(new_cr0))
+}
+
+/// The MOV to CR3 instruction causes a VM exit while CR3-load exiting is enabled, which is only the case while hook
+/// views or hooks are assigned to processes (see the `hook_view` module) or the processes are tracked (see the
+/// `process_tracker` module). The owner of the new address space is recorded, and its hook view and bound hooks are
+/// switched to on the way back to the guest.
+///
+/// The write is completed as the processor would: the cached translations of the guest are invalidated except for
+/// the global pages, unless PCIDs are enabled and bit 63 of the source operand requests them to be preserved.
+///
+/// # Arguments
+///
+/// * `vm`: A mutable reference to the VM.
+/// * `gpr`: The general-purpose register index.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.10.4.1 Operations that Invalidate TLBs and Paging-Structure Caches
+fn handle_mov_to_cr3(vm: &mut Vm, gpr: u64) -> ExitType {
+    trace!("Handling MOV to CR3 VM exit...");
+
+    const CR3_PCID_NO_FLUSH: u64 = 1 << 63;
+
+    let new_cr3 = *vm.guest_registers.register_mut(gpr as usize);
+    let is_pcid_enabled = Cr4Flags::from_bits_retain(read_effective_guest_cr4()).contains(Cr4Flags::PCID);
+
+    // Invalidating all the non-global translations of the VPID is a superset of invalidating those of the PCID.
+    if !is_pcid_enabled || new_cr3 & CR3_PCID_NO_FLUSH == 0 {
+        invvpid_single_context_retaining_globals(vm.vpid);
     }
+
+    // Bit 63 isn't written to CR3.
+    vmwrite(guest::CR3, new_cr3 & !CR3_PCID_NO_FLUSH);
+
+    track_address_space_switch(vm, new_cr3);
+
+    ExitType::IncrementRIP
 }
 
 /// The MOV to CR0 instruction causes a VM exit unless the value of its source operand matches, for
@@ -68,8 +167,21 @@ pub fn handle_cr_reg_access(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
 fn handle_mov_to_cr0(vm: &mut Vm, gpr: u64) -> ExitType {
     trace!("Handling MOV to CR0 VM exit...");
 
-    let mut new_cr0 = unsafe { Cr0Flags::from_bits_retain(addr_of!(vm.guest_registers).cast::<u64>().add(gpr as usize).read_unaligned()) };
+    let new_cr0 = unsafe { Cr0Flags::from_bits_retain(addr_of!(vm.guest_registers).cast::<u64>().add(gpr as usize).read_unaligned()) };
 
+    write_guest_cr0(new_cr0)
+}
+
+/// Writes a new value of CR0 for the guest, as MOV to CR0, LMSW or CLTS would, injecting #GP(0) if the value is invalid.
+///
+/// # Arguments
+///
+/// * `new_cr0`: The value written by the guest.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+fn write_guest_cr0(mut new_cr0: Cr0Flags) -> ExitType {
     let curr_cr0 = Cr0Flags::from_bits_retain(read_effective_guest_cr0());
     let curr_cr4 = Cr4Flags::from_bits_retain(read_effective_guest_cr4());
 
@@ -107,8 +219,22 @@ fn handle_mov_to_cr0(vm: &mut Vm, gpr: u64) -> ExitType {
         return ExitType::Continue;
     }
 
-    // #GP(0) if an attempt is made to clear CR0.PG
-    if !new_cr0.contains(Cr0Flags::PAGING) {
+    let mut entry_controls = EntryControls::from_bits_truncate(vmread(control::VMENTRY_CONTROLS) as u32);
+    let mut efer = EferFlags::from_bits_retain(vmread(guest::IA32_EFER_FULL));
+
+    // #GP(0) if an attempt is made to clear CR0.PG in 64-bit mode
+    let is_64bit_mode = entry_controls.contains(EntryControls::IA32E_MODE_GUEST) && vmread(guest::CS_ACCESS_RIGHTS) & CS_LONG_MODE != 0;
+    if !new_cr0.contains(Cr0Flags::PAGING) && is_64bit_mode {
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
+    // #GP(0) if an attempt is made to set CR0.PG with EFER.LME set while CR4.PAE is clear
+    if new_cr0.contains(Cr0Flags::PAGING)
+        && !curr_cr0.contains(Cr0Flags::PAGING)
+        && efer.contains(EferFlags::LONG_MODE_ENABLE)
+        && !curr_cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+    {
         EventInjection::vmentry_inject_gp(0);
         return ExitType::Continue;
     }
@@ -119,25 +245,32 @@ fn handle_mov_to_cr0(vm: &mut Vm, gpr: u64) -> ExitType {
         return ExitType::Continue;
     }
 
-    if new_cr0.contains(Cr0Flags::CACHE_DISABLE) != new_cr0.contains(Cr0Flags::CACHE_DISABLE)
+    // The memory types cached with the EPT-derived mappings depend on CR0.CD, cleared by the application processors
+    // started by INIT-SIPI-SIPI.
+    if new_cr0.contains(Cr0Flags::CACHE_DISABLE) != curr_cr0.contains(Cr0Flags::CACHE_DISABLE)
         || new_cr0.contains(Cr0Flags::NOT_WRITE_THROUGH) != curr_cr0.contains(Cr0Flags::NOT_WRITE_THROUGH)
     {
         // https://github.com/jonomango/hv/blob/cd4d4022351b5d762045a02108973c697a79bb34/hv/exit-handlers.cpp#L284
-
-        unimplemented!()
-        //invept_all_contexts();
+        invept_all_contexts();
     }
 
-    vmwrite(control::CR0_READ_SHADOW, new_cr0.bits());
+    // Activate or deactivate IA-32e mode, as the processor does when paging is toggled with EFER.LME set, the guest
+    // EFER being loaded on VM entry.
+    if new_cr0.contains(Cr0Flags::PAGING) != curr_cr0.contains(Cr0Flags::PAGING) {
+        let long_mode_active = new_cr0.contains(Cr0Flags::PAGING) && efer.contains(EferFlags::LONG_MODE_ENABLE);
+        trace!("Paging toggled, IA-32e mode active: {}", long_mode_active);
 
-    let vmx_cr0_fixed0 = rdmsr(x86::msr::IA32_VMX_CR0_FIXED0);
-    let vmx_cr0_fixed1 = rdmsr(x86::msr::IA32_VMX_CR0_FIXED1);
+        efer.set(EferFlags::LONG_MODE_ACTIVE, long_mode_active);
+        entry_controls.set(EntryControls::IA32E_MODE_GUEST, long_mode_active);
+        vmwrite(guest::IA32_EFER_FULL, efer.bits());
+        vmwrite(control::VMENTRY_CONTROLS, entry_controls.bits());
+    }
 
-    // make sure to account for VMX reserved bits when setting the real CR0
-    new_cr0 |= Cr0Flags::from_bits_retain(vmx_cr0_fixed0);
-    new_cr0 &= Cr0Flags::from_bits_retain(vmx_cr0_fixed1);
+    vmwrite(control::CR0_READ_SHADOW, new_cr0.bits());
 
-    vmwrite(guest::CR0, new_cr0.bits());
+    // make sure to account for VMX reserved bits when setting the real CR0, except CR0.PE and CR0.PG for an
+    // unrestricted guest
+    vmwrite(guest::CR0, adjust_guest_cr0(Cr0::from_bits_truncate(new_cr0.bits() as usize)));
 
     trace!("Handled MOV to CR0 successfully!");
 
@@ -171,7 +304,7 @@ fn handle_mov_to_cr4(vm: &mut Vm, gpr: u64) -> Result<ExitType, HypervisorError>
     let curr_cr4 = Cr4Flags::from_bits_retain(read_effective_guest_cr4());
 
     // #GP(0) if an attempt is made to set CR4.SMXE when SMX is not supported
-    if vm.cpuid_feature_info.has_smx() && new_cr4.contains(Cr4Flags::SAFER_MODE_EXTENSIONS) {
+    if !vm.cpuid_feature_info.has_smx() && new_cr4.contains(Cr4Flags::SAFER_MODE_EXTENSIONS) {
         EventInjection::vmentry_inject_gp(0);
         return Ok(ExitType::Continue);
     }
@@ -182,6 +315,12 @@ fn handle_mov_to_cr4(vm: &mut Vm, gpr: u64) -> Result<ExitType, HypervisorError>
         return Ok(ExitType::Continue);
     }
 
+    // #GP(0) if an attempt is made to set CR4.VMXE, VMX not being exposed to the guest
+    if new_cr4.contains(Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS) {
+        EventInjection::vmentry_inject_gp(0);
+        return Ok(ExitType::Continue);
+    }
+
     // #GP(0) if an attempt is made to change CR4.PCIDE from 0 to 1 while CR3[11:0] != 000H
     if new_cr4.contains(Cr4Flags::PCID) && !curr_cr4.contains(Cr4Flags::PCID) && curr_cr3.get_bits(0..12) != 0 {
         EventInjection::vmentry_inject_gp(0);
@@ -211,7 +350,7 @@ fn handle_mov_to_cr4(vm: &mut Vm, gpr: u64) -> Result<ExitType, HypervisorError>
         || !new_cr4.contains(Cr4Flags::PCID) && curr_cr4.contains(Cr4Flags::PCID)
         || new_cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION) && !curr_cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION)
     {
-        invvpid_single_context(VPID_TAG);
+        invvpid_single_context(vm.vpid);
     }
 
     vmwrite(control::CR4_READ_SHADOW, new_cr4.bits());
@@ -229,3 +368,19 @@ fn handle_mov_to_cr4(vm: &mut Vm, gpr: u64) -> Result<ExitType, HypervisorError>
 
     Ok(ExitType::IncrementRIP)
 }
+
+/// Enables CR3-load exiting on the current logical processor while hook views are assigned to processes, hooks are
+/// bound to processes, the processes are tracked or the benchmark run requires it, and disables it otherwise.
+///
+/// # Arguments
+///
+/// * `vm`: A reference to the VM.
+pub fn update_cr3_load_exiting(vm: &Vm) {
+    let enabled =
+        vm.hook_view.has_process_views() || vm.hook_view.has_hook_processes() || vm.process_context.is_enabled() || is_cr3_load_exiting_forced();
+
+    let mut primary_controls = PrimaryControls::from_bits_truncate(vmread(control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32);
+    primary_controls.set(PrimaryControls::CR3_LOAD_EXITING, enabled);
+    vmwrite(control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
+    trace!("CR3-load exiting {}", if enabled { "enabled" } else { "disabled" });
+}
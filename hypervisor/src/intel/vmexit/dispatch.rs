@@ -0,0 +1,229 @@
+//! Dispatches the VM exits to their handlers through a table indexed by basic exit reason, built at compile time.
+//!
+//! `CPUID` and the MSR accesses, the most frequent VM exits of a Windows guest, are handled on a fast path before the
+//! table, with direct calls the compiler can inline. Every handler of the table has the same signature, the ones that
+//! don't use the whole `Vm` or return an error being wrapped, and a basic exit reason without handler panics as before.
+
+use crate::intel::{
+    bitmap::MsrAccessType,
+    vm::Vm,
+    vmerror::{VmxBasicExitReason, EXIT_REASON_COUNT},
+    vmexit::{
+        cpuid::handle_cpuid,
+        cr::handle_cr_reg_access,
+        dr::handle_mov_dr,
+        ept_misconfiguration::handle_ept_misconfiguration,
+        ept_violation::handle_ept_violation,
+        exception::{handle_exception, handle_undefined_opcode_exception},
+        halt::handle_halt,
+        init::handle_init_signal,
+        interrupt_window::{handle_interrupt_window, handle_nmi_window},
+        invd::handle_invd,
+        invept::handle_invept,
+        invvpid::handle_invvpid,
+        io::handle_io_instruction,
+        msr::handle_msr_access,
+        mtf::handle_monitor_trap_flag,
+        preemption_timer::handle_preemption_timer,
+        rdrand::handle_rdrand,
+        rdtsc::{handle_rdtsc, handle_rdtscp},
+        sipi::handle_sipi_signal,
+        triple_fault::handle_triple_fault,
+        vmcall::handle_vmcall,
+        vmxon::handle_vmxon,
+        xsetbv::handle_xsetbv,
+        ExitType,
+    },
+};
+
+/// A handler of the dispatch table, called with the virtual machine instance of the current logical processor.
+type ExitHandler = fn(vm: &mut Vm) -> ExitType;
+
+/// The handler of each basic exit reason, `None` for the ones that aren't handled.
+static EXIT_HANDLERS: [Option<ExitHandler>; EXIT_REASON_COUNT] = build_exit_handlers();
+
+/// Builds the dispatch table.
+const fn build_exit_handlers() -> [Option<ExitHandler>; EXIT_REASON_COUNT] {
+    let mut handlers: [Option<ExitHandler>; EXIT_REASON_COUNT] = [None; EXIT_REASON_COUNT];
+
+    // 0
+    handlers[VmxBasicExitReason::ExceptionOrNmi as usize] = Some(exit_exception);
+    // 2
+    handlers[VmxBasicExitReason::TripleFault as usize] = Some(handle_triple_fault);
+    // 3
+    handlers[VmxBasicExitReason::InitSignal as usize] = Some(handle_init_signal);
+    // 4
+    handlers[VmxBasicExitReason::StartupIpi as usize] = Some(handle_sipi_signal);
+    // 7
+    handlers[VmxBasicExitReason::InterruptWindow as usize] = Some(exit_interrupt_window);
+    // 8
+    handlers[VmxBasicExitReason::NmiWindow as usize] = Some(exit_nmi_window);
+    // 10
+    handlers[VmxBasicExitReason::Cpuid as usize] = Some(exit_cpuid);
+    // 11
+    handlers[VmxBasicExitReason::Getsec as usize] = Some(exit_undefined_opcode);
+    // 12
+    handlers[VmxBasicExitReason::Hlt as usize] = Some(exit_hlt);
+    // 13
+    handlers[VmxBasicExitReason::Invd as usize] = Some(exit_invd);
+    // 16
+    handlers[VmxBasicExitReason::Rdtsc as usize] = Some(exit_rdtsc);
+    // 18
+    handlers[VmxBasicExitReason::Vmcall as usize] = Some(exit_vmcall);
+    // 19 to 26, the VMX instructions other than VMXON, as VMX isn't exposed to the guest.
+    handlers[VmxBasicExitReason::Vmclear as usize] = Some(exit_undefined_opcode);
+    handlers[VmxBasicExitReason::Vmlaunch as usize] = Some(exit_undefined_opcode);
+    handlers[VmxBasicExitReason::Vmptrld as usize] = Some(exit_undefined_opcode);
+    handlers[VmxBasicExitReason::Vmptrst as usize] = Some(exit_undefined_opcode);
+    handlers[VmxBasicExitReason::Vmread as usize] = Some(exit_undefined_opcode);
+    handlers[VmxBasicExitReason::Vmresume as usize] = Some(exit_undefined_opcode);
+    handlers[VmxBasicExitReason::Vmwrite as usize] = Some(exit_undefined_opcode);
+    handlers[VmxBasicExitReason::Vmxoff as usize] = Some(exit_undefined_opcode);
+    // 27
+    handlers[VmxBasicExitReason::Vmxon as usize] = Some(exit_vmxon);
+    // 28
+    handlers[VmxBasicExitReason::ControlRegisterAccesses as usize] = Some(exit_cr_access);
+    // 29
+    handlers[VmxBasicExitReason::MovDr as usize] = Some(handle_mov_dr);
+    // 30
+    handlers[VmxBasicExitReason::IoInstruction as usize] = Some(exit_io_instruction);
+    // 31
+    handlers[VmxBasicExitReason::Rdmsr as usize] = Some(exit_rdmsr);
+    // 32
+    handlers[VmxBasicExitReason::Wrmsr as usize] = Some(exit_wrmsr);
+    // 37
+    handlers[VmxBasicExitReason::MonitorTrapFlag as usize] = Some(exit_monitor_trap_flag);
+    // 48
+    handlers[VmxBasicExitReason::EptViolation as usize] = Some(exit_ept_violation);
+    // 49
+    handlers[VmxBasicExitReason::EptMisconfiguration as usize] = Some(exit_ept_misconfiguration);
+    // 50
+    handlers[VmxBasicExitReason::Invept as usize] = Some(exit_invept);
+    // 51
+    handlers[VmxBasicExitReason::Rdtscp as usize] = Some(exit_rdtscp);
+    // 52
+    handlers[VmxBasicExitReason::VmxPreemptionTimerExpired as usize] = Some(handle_preemption_timer);
+    // 53
+    handlers[VmxBasicExitReason::Invvpid as usize] = Some(exit_invvpid);
+    // 55
+    handlers[VmxBasicExitReason::Xsetbv as usize] = Some(handle_xsetbv);
+    // 57
+    handlers[VmxBasicExitReason::Rdrand as usize] = Some(handle_rdrand);
+    // 61
+    handlers[VmxBasicExitReason::Rdseed as usize] = Some(handle_rdrand);
+
+    handlers
+}
+
+/// Dispatches a VM exit to its handler.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+///
+/// # Returns
+///
+/// The `ExitType` returned by the handler.
+///
+/// # Panics
+///
+/// Panics if the basic exit reason isn't handled, or if its handler fails.
+pub fn dispatch_vmexit(vm: &mut Vm, basic_exit_reason: VmxBasicExitReason) -> ExitType {
+    match basic_exit_reason {
+        VmxBasicExitReason::Cpuid => return exit_cpuid(vm),
+        VmxBasicExitReason::Rdmsr => return exit_rdmsr(vm),
+        VmxBasicExitReason::Wrmsr => return exit_wrmsr(vm),
+        _ => {}
+    }
+
+    match EXIT_HANDLERS.get(basic_exit_reason as usize).copied().flatten() {
+        Some(handler) => handler(vm),
+        None => panic!("Unhandled VM exit reason: {:?}", basic_exit_reason),
+    }
+}
+
+// The handlers wrapped to the signature of the table.
+
+fn exit_exception(vm: &mut Vm) -> ExitType {
+    handle_exception(vm).expect("Failed to handle exception")
+}
+
+fn exit_interrupt_window(_vm: &mut Vm) -> ExitType {
+    handle_interrupt_window()
+}
+
+fn exit_nmi_window(_vm: &mut Vm) -> ExitType {
+    handle_nmi_window()
+}
+
+#[inline(always)]
+fn exit_cpuid(vm: &mut Vm) -> ExitType {
+    handle_cpuid(vm).expect("Failed to handle CPUID")
+}
+
+fn exit_undefined_opcode(_vm: &mut Vm) -> ExitType {
+    handle_undefined_opcode_exception()
+}
+
+fn exit_hlt(_vm: &mut Vm) -> ExitType {
+    handle_halt()
+}
+
+fn exit_invd(vm: &mut Vm) -> ExitType {
+    handle_invd(&mut vm.guest_registers)
+}
+
+fn exit_vmcall(vm: &mut Vm) -> ExitType {
+    handle_vmcall(vm).expect("Failed to handle VMCALL")
+}
+
+fn exit_vmxon(_vm: &mut Vm) -> ExitType {
+    handle_vmxon()
+}
+
+fn exit_cr_access(vm: &mut Vm) -> ExitType {
+    handle_cr_reg_access(vm).expect("Failed to handle CR access")
+}
+
+fn exit_io_instruction(vm: &mut Vm) -> ExitType {
+    handle_io_instruction(vm).expect("Failed to handle I/O instruction")
+}
+
+#[inline(always)]
+fn exit_rdmsr(vm: &mut Vm) -> ExitType {
+    handle_msr_access(vm, MsrAccessType::Read).expect("Failed to handle RDMSR")
+}
+
+#[inline(always)]
+fn exit_wrmsr(vm: &mut Vm) -> ExitType {
+    handle_msr_access(vm, MsrAccessType::Write).expect("Failed to handle WRMSR")
+}
+
+fn exit_monitor_trap_flag(vm: &mut Vm) -> ExitType {
+    handle_monitor_trap_flag(vm).expect("Failed to handle Monitor Trap Flag")
+}
+
+fn exit_ept_violation(vm: &mut Vm) -> ExitType {
+    handle_ept_violation(vm).expect("Failed to handle EPT violation")
+}
+
+fn exit_ept_misconfiguration(vm: &mut Vm) -> ExitType {
+    handle_ept_misconfiguration(vm).expect("Failed to handle EPT misconfiguration")
+}
+
+fn exit_invept(_vm: &mut Vm) -> ExitType {
+    handle_invept()
+}
+
+fn exit_rdtsc(vm: &mut Vm) -> ExitType {
+    handle_rdtsc(&mut vm.guest_registers)
+}
+
+fn exit_invvpid(_vm: &mut Vm) -> ExitType {
+    handle_invvpid()
+}
+
+fn exit_rdtscp(vm: &mut Vm) -> ExitType {
+    handle_rdtscp(&mut vm.guest_registers)
+}
@@ -0,0 +1,70 @@
+//! Handles the `MOV DR` VM exits, which are only enabled while the hypervisor owns hardware breakpoints, by emulating
+//! the accesses of the guest to its shadow of the debug registers (see the `debug_registers` module).
+
+use {
+    crate::intel::{
+        debug_registers::{emulate_general_detect, read_guest_debug_register, write_guest_debug_register},
+        events::EventInjection,
+        support::{vmread, vmwrite},
+        vm::Vm,
+        vmexit::ExitType,
+    },
+    bit_field::BitField,
+    log::*,
+    x86::vmx::vmcs,
+};
+
+/// Handles the `MOV DR` VM exit.
+///
+/// The debug register, the direction and the general-purpose register are decoded from the exit qualification. DR4
+/// and DR5 are aliases of DR6 and DR7, as `MOV DR` raises #UD for them before the VM exit while CR4.DE is set. The
+/// instruction always has 64-bit operands in 64-bit mode.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the instruction in the VM.
+/// * `ExitType::Continue` - If a general-detect debug exception or a #GP has been injected instead.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-4. Exit Qualification for MOV DR
+pub fn handle_mov_dr(vm: &mut Vm) -> ExitType {
+    trace!("Handling MOV DR VM exit...");
+
+    if emulate_general_detect(vm) {
+        return ExitType::Continue;
+    }
+
+    let exit_qualification = vmread(vmcs::ro::EXIT_QUALIFICATION);
+    let debug_register = match exit_qualification.get_bits(0..3) as usize {
+        4 => 6,
+        5 => 7,
+        debug_register => debug_register,
+    };
+    let is_move_from_dr = exit_qualification.get_bit(4);
+    let register = exit_qualification.get_bits(8..12) as usize;
+
+    if is_move_from_dr {
+        *vm.guest_registers.register_mut(register) = read_guest_debug_register(vm, debug_register);
+
+        if register == 4 {
+            vmwrite(vmcs::guest::RSP, vm.guest_registers.rsp);
+        }
+
+        trace!("MOV from DR{}: {:#x}", debug_register, *vm.guest_registers.register_mut(register));
+    } else {
+        let value = *vm.guest_registers.register_mut(register);
+
+        if !write_guest_debug_register(vm, debug_register, value) {
+            debug!("Invalid MOV to DR{}: {:#x}", debug_register, value);
+            EventInjection::vmentry_inject_gp(0);
+            return ExitType::Continue;
+        }
+
+        trace!("MOV to DR{}: {:#x}", debug_register, value);
+    }
+
+    ExitType::IncrementRIP
+}
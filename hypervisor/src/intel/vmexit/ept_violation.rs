@@ -2,13 +2,25 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            addresses::PhysicalAddress,
+            device_hiding::is_hidden_device_page,
             ept::AccessType,
-            hooks::hook_manager::SHARED_HOOK_MANAGER,
-            support::vmread,
+            ept_view::{handle_ept_view_violation, PRIMARY_EPT_VIEW},
+            execution_trace::{handle_execution_trace_access, is_execution_trace_page},
+            hooks::{
+                hook_manager::{HookViewPolicy, ShadowResyncPolicy, SHARED_HOOK_MANAGER},
+                tamper::PendingHookWrite,
+            },
+            protected_memory::{handle_protected_memory_access, is_protected_memory_page},
+            single_step::{single_step, SingleStepContext, SingleStepOwner},
+            support::{vmread, vmwrite},
+            timing::is_hpet_page,
+            unpacker::{handle_unpacker_access, is_unpacker_page},
             vm::Vm,
             vmerror::EptViolationExitQualification,
             vmexit::{
-                mtf::{set_monitor_trap_flag, update_guest_interrupt_flag},
+                hpet::{handle_hpet_access, read_guest_instruction},
+                mtf::restore_hooked_pages,
                 ExitType,
             },
         },
@@ -20,9 +32,9 @@ use {
 /// Handles VM exits for EPT violations.
 /// EPT violations occur when an operation is performed on an EPT entry that does not provide permissions to access that page.
 ///
-/// This function addresses the EPT violation by either swapping the page to a shadow page
-/// or restoring the original page based on the exit qualification. It also sets up the monitor trap flag
-/// if necessary.
+/// This function addresses the EPT violation by switching between the views of the hooked page based on the exit
+/// qualification: the execute-only shadow page for instruction fetches, and the original page for reads and writes,
+/// which is either kept mapped non-executable or restored for a single instruction with the monitor trap flag.
 ///
 /// # Arguments
 ///
@@ -43,17 +55,52 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
     trace!("Faulting Guest Large Page PA: {:#x}", guest_large_page_pa);
 
+    // The alternate EPT views handle their own violations, the pages of the primary EPT being identity-mapped there.
+    if vm.ept_view.active_view != PRIMARY_EPT_VIEW {
+        let exit_qualification = EptViolationExitQualification::from_exit_qualification(vmread(vmcs::ro::EXIT_QUALIFICATION));
+        return handle_ept_view_violation(vm, guest_page_pa.as_u64(), &exit_qualification);
+    }
+
+    // Accesses to the intercepted HPET registers are emulated rather than handled as a hook.
+    if is_hpet_page(guest_page_pa.as_u64()) {
+        return handle_hpet_access(vm, guest_pa);
+    }
+
+    // Hidden device pages are mapped read-only to the dummy page, so writes to them are discarded.
+    if is_hidden_device_page(guest_page_pa.as_u64()) {
+        return skip_hidden_device_access(vm);
+    }
+
+    // Pages watched by the unpacker are switched between write-protected and non-executable.
+    if is_unpacker_page(guest_page_pa.as_u64()) {
+        let exit_qualification = EptViolationExitQualification::from_exit_qualification(vmread(vmcs::ro::EXIT_QUALIFICATION));
+        return handle_unpacker_access(vm, guest_page_pa.as_u64(), exit_qualification.instruction_fetch);
+    }
+
+    // Pages traced by the execution tracer are made executable while their instructions are single-stepped.
+    if is_execution_trace_page(guest_page_pa.as_u64()) {
+        let exit_qualification = EptViolationExitQualification::from_exit_qualification(vmread(vmcs::ro::EXIT_QUALIFICATION));
+        return handle_execution_trace_access(vm, guest_page_pa.as_u64(), exit_qualification.instruction_fetch);
+    }
+
+    // Accesses to protected pages are allowed or denied depending on the accessing context.
+    if is_protected_memory_page(guest_page_pa.as_u64()) {
+        let exit_qualification = EptViolationExitQualification::from_exit_qualification(vmread(vmcs::ro::EXIT_QUALIFICATION));
+        return handle_protected_memory_access(vm, guest_pa, &exit_qualification);
+    }
+
     // Lock the shared hook manager
     let mut hook_manager = SHARED_HOOK_MANAGER.lock();
 
-    let shadow_page_pa = PAddr::from(
-        hook_manager
-            .memory_manager
-            .get_shadow_page_as_ptr(guest_page_pa.as_u64())
-            .ok_or(HypervisorError::ShadowPageNotFound)?,
-    );
+    // The page executed in the hook view of this processor: the shadow page, or the guest page or a variant shadow page in an alternate view.
+    let shadow_page_pa =
+        PAddr::from(hook_manager.get_execute_page_pa(vm.hook_view.active_view, vm.hook_view.active_process_id, guest_page_pa.as_u64())?);
     trace!("Shadow Page PA: {:#x}", shadow_page_pa.as_u64());
 
+    let hook_view_policy = hook_manager.hook_view_policy;
+    let is_write_tracked = hook_manager.is_write_tracked();
+    let guest_page_access_type = hook_manager.guest_page_access_type();
+
     let pre_alloc_pt = hook_manager
         .memory_manager
         .get_page_table_as_mut(guest_large_page_pa.as_u64())
@@ -66,34 +113,67 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     trace!("Exit Qualification for EPT Violations: {:#?}", ept_violation_qualification);
     trace!("Faulting Guest RIP: {:#x}", vm.guest_registers.rip);
 
-    if ept_violation_qualification.readable && ept_violation_qualification.writable && !ept_violation_qualification.executable {
+    if ept_violation_qualification.instruction_fetch && !ept_violation_qualification.executable {
         // if the instruction fetch is true and the page is not executable, we need to swap the page to a shadow page.
         //   Instruction Fetch: true,
-        //   Page Permissions: R:true, W:true, X:false (readable, writable, but non-executable).
-        trace!("Page Permissions: R:true, W:true, X:false (readable, writable, but non-executable).");
+        //   Page Permissions: R:true, W:true or false, X:false (readable, writable unless writes are tracked, but non-executable).
+        trace!("Page Permissions: R:{}, W:{}, X:false (non-executable).", ept_violation_qualification.readable, ept_violation_qualification.writable);
         trace!("Execution attempt on non-executable page, switching to hooked shadow-copy page.");
         vm.primary_ept
             .swap_page(guest_page_pa.as_u64(), shadow_page_pa.as_u64(), AccessType::EXECUTE, pre_alloc_pt)?;
         trace!("Page swapped successfully!");
-    } else if ept_violation_qualification.executable && !ept_violation_qualification.readable && !ept_violation_qualification.writable {
-        // if the instruction fetch is false and the page is executable, we need to swap the page to a shadow page.
+    } else if !ept_violation_qualification.instruction_fetch
+        && hook_view_policy == HookViewPolicy::Split
+        && !(ept_violation_qualification.data_write && is_write_tracked)
+        && !is_executing_from_page(vm, guest_page_pa)
+    {
+        // if the instruction fetch is false and the access doesn't need to be single-stepped, the page is the execute-only
+        // shadow page, we need to switch to the read/write view with the original page until it is executed again.
         //   Instruction Fetch: false,
         //   Page Permissions: R:false, W:false, X:true (non-readable, non-writable, but executable).
-        trace!("Read/Write attempt on execute-only page, restoring original page.");
-        trace!("Page Permissions: R:false, W:false, X:true (non-readable, non-writable, but executable).");
+        trace!("Read/Write attempt on hooked page, switching to the read/write view of the original page.");
+        vm.primary_ept
+            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), guest_page_access_type, pre_alloc_pt)?;
+    } else if !ept_violation_qualification.instruction_fetch {
+        // if the instruction fetch is false, the page is either the execute-only shadow page or the read-only guest page
+        // when writes are tracked, we need to restore the original page for a single instruction.
+        //   Instruction Fetch: false,
+        //   Page Permissions: R:false, W:false, X:true (non-readable, non-writable, but executable), or R:true, W:false, X:false.
+        trace!("Read/Write attempt on hooked page, restoring original page.");
+        trace!(
+            "Page Permissions: R:{}, W:{}, X:{}.",
+            ept_violation_qualification.readable,
+            ept_violation_qualification.writable,
+            ept_violation_qualification.executable
+        );
         vm.primary_ept
             .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
 
-        // We make this read-write-execute to allow the instruction performing a read-write
-        // operation and then switch back to execute-only shadow page from handle_mtf vmexit
-        vm.mtf_counter = Some(1);
+        if ept_violation_qualification.data_write {
+            debug!("Write to hooked guest page: {:#x} at RIP: {:#x}", guest_page_pa.as_u64(), vm.guest_registers.rip);
 
-        // Set the monitor trap flag and initialize counter to the number of overwritten instructions
-        set_monitor_trap_flag(true);
+            // The guest is patching the hooked page, so the shadow page must be copied again once the write has completed.
+            if hook_manager.shadow_resync_policy == ShadowResyncPolicy::Resync {
+                vm.mtf_resync_page = Some(guest_page_pa.as_u64());
+            }
 
-        // Ensure all data mutations to vm are done before calling this.
-        // This function will update the guest interrupt flag to prevent interrupts while single-stepping
-        update_guest_interrupt_flag(vm, false)?;
+            // The written bytes are reported once the write has completed.
+            if hook_manager.hook_tamper_detection {
+                vm.mtf_hook_write = Some(PendingHookWrite {
+                    guest_pa,
+                    guest_rip: vm.guest_registers.rip,
+                    guest_cr3: vmread(vmcs::guest::CR3),
+                });
+            }
+        }
+
+        // We make this read-write-execute to allow the instruction performing a read-write
+        // operation and then switch back to execute-only shadow page once it has been single-stepped
+        let context = SingleStepContext {
+            guest_page_pa: guest_page_pa.as_u64(),
+            guest_next_page_pa: None,
+        };
+        single_step(vm, SingleStepOwner::HookRestoration, 1, context, restore_hooked_pages)?;
     }
 
     trace!("EPT Violation handled successfully!");
@@ -101,3 +181,52 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     // Do not increment RIP, since we want it to execute the same instruction again.
     Ok(ExitType::Continue)
 }
+
+/// The maximum length of an x86 instruction in bytes.
+const MAX_INSTRUCTION_LENGTH: u64 = 15;
+
+/// Returns `true` if the faulting instruction may be fetched from a hooked page, in which case switching the page
+/// to the read/write view would cause an instruction fetch violation before the access is retried, and back again.
+///
+/// # Arguments
+///
+/// * `vm` - A reference to the virtual machine (VM) instance.
+/// * `guest_page_pa` - The physical address of the hooked guest page accessed by the instruction.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the first or the last byte the instruction may span is on the page, or can't be translated.
+fn is_executing_from_page(vm: &Vm, guest_page_pa: PAddr) -> bool {
+    [vm.guest_registers.rip, vm.guest_registers.rip + MAX_INSTRUCTION_LENGTH - 1]
+        .into_iter()
+        .any(|guest_va| {
+            PhysicalAddress::pa_from_va_with_current_cr3(guest_va)
+                .map_or(true, |guest_pa| PAddr::from(guest_pa).align_down_to_base_page() == guest_page_pa)
+        })
+}
+
+/// Discards a write to the page of a hidden device by skipping the faulting instruction.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `Result<ExitType, HypervisorError>` - `Ok(ExitType::Continue)` if the instruction was skipped.
+fn skip_hidden_device_access(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
+    trace!("Discarding hidden device access at RIP: {:#x}", vm.guest_registers.rip);
+
+    let instruction = read_guest_instruction(vm.guest_registers.rip)?;
+    let length = lde::X64.ld(&instruction) as u64;
+
+    if length == 0 {
+        return Err(HypervisorError::InvalidBytes);
+    }
+
+    // The VM-exit instruction length is not valid for EPT violations, use the decoded length instead.
+    vm.guest_registers.rip += length;
+    vmwrite(vmcs::guest::RIP, vm.guest_registers.rip);
+
+    Ok(ExitType::Continue)
+}
@@ -1,120 +1,291 @@
 //! Module handling VM exits due to exceptions or non-maskable interrupts (NMIs).
 //! It includes handling for various types of exceptions such as page faults,
-//! general protection faults, breakpoints, and invalid opcodes.
+//! general protection faults, breakpoints, and invalid opcodes. Page faults, general protection faults and invalid
+//! opcodes are intercepted for the exception telemetry (see the `exception_telemetry` module), and any exception can
+//! be intercepted for the exception hooks (see the `exception_hook` module).
+//!
+//! An intercepted exception is first passed to the built-in features, then to the hook of its vector, and is
+//! reflected to the guest unless one of them handled it. NMIs are told apart from the ones of the hypervisor, then
+//! reflected through the pending-event queue (see the `nmi` and `events` modules).
 
 use {
-    crate::intel::{
-        events::EventInjection,
-        support::vmread,
-        vm::Vm,
-        vmerror::{EptViolationExitQualification, ExceptionInterrupt, VmExitInterruptionInformation},
-        vmexit::ExitType,
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            debug_registers::{dispatch_hardware_breakpoints, reflect_debug_exception},
+            events::EventInjection,
+            exception_telemetry::record_exception,
+            hooks::{
+                callbacks::{dispatch_hook_entry, dispatch_hook_return},
+                exception_hook::{disarm_exception_hook, dispatch_exception_hook, ExceptionHookResult, ExceptionQualification, InterceptedException},
+                hook_manager::{EptHookType, SHARED_HOOK_MANAGER},
+                inline::InlineHookType,
+                memory_manager::may_be_hooked_page,
+                syscall_hook::{complete_syscall_return, dispatch_syscall_hook},
+            },
+            nmi::dispatch_nmis,
+            support::{cr2_write, vmread, vmwrite},
+            vm::Vm,
+            vmerror::{ExceptionInterrupt, InterruptionType},
+            vmexit::{mtf::single_step_hook, ExitType},
+        },
     },
-    x86::vmx::vmcs,
+    x86::{bits64::paging::PAddr, vmx::vmcs},
 };
 
 /// Handles exceptions and NMIs that occur during VM execution.
 ///
 /// This function is called when the VM exits due to an exception or NMI.
-/// It determines the type of exception, handles it accordingly, and prepares
-/// the VM for resumption.
+/// It decodes the exception, passes it to the built-in features handling its vector, then to the exception hooks, and
+/// reflects it to the guest if none of them handled it.
 ///
 /// # Arguments
 ///
-/// * `guest_registers` - A mutable reference to the guest's register state.
-/// * `vmx` - A mutable reference to the Vmx structure representing the current VM.
+/// * `vm` - A mutable reference to the virtual machine instance.
 ///
 /// # Returns
 ///
-/// * `ExitType::Continue` - Indicating that VM execution should continue after handling the exception
-pub fn handle_exception(_vm: &mut Vm) -> ExitType {
+/// * `Ok(ExitType::Continue)` - Indicating that VM execution should continue after handling the exception
+pub fn handle_exception(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     log::debug!("Handling ExceptionOrNmi VM exit...");
 
-    let interruption_info_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
-    let interruption_error_code_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE);
-
-    if let Some(interruption_info) = VmExitInterruptionInformation::from_u32(interruption_info_value as u32) {
-        if let Some(exception_interrupt) = ExceptionInterrupt::from_u32(interruption_info.vector.into()) {
-            match exception_interrupt {
-                ExceptionInterrupt::PageFault => {
-                    let exit_qualification_value = vmread(vmcs::ro::EXIT_QUALIFICATION);
-                    let ept_violation_qualification = EptViolationExitQualification::from_exit_qualification(exit_qualification_value);
-                    log::trace!("Exit Qualification for EPT Violations: {:#?}", ept_violation_qualification);
-                    EventInjection::vmentry_inject_pf(interruption_error_code_value as u32);
-                }
-                ExceptionInterrupt::GeneralProtectionFault => {
-                    EventInjection::vmentry_inject_gp(interruption_error_code_value as u32);
-                }
-                ExceptionInterrupt::Breakpoint => {
-                    //handle_breakpoint_exception(guest_registers, vm);
-                    EventInjection::vmentry_inject_bp();
-                }
-                ExceptionInterrupt::InvalidOpcode => {
-                    EventInjection::vmentry_inject_ud();
-                }
-                _ => {
-                    panic!("Unhandled exception: {:?}", exception_interrupt);
-                }
+    let Some(exception) = InterceptedException::from_vmexit(vm) else {
+        panic!("Invalid VM Exit Interruption Information: {:#x}", vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO));
+    };
+
+    let error_code = exception.error_code.unwrap_or_default() as u64;
+
+    match exception.vector {
+        ExceptionInterrupt::NonMaskableInterrupt if exception.interruption_type == InterruptionType::NonMaskableInterrupt => {
+            // The NMIs received while the guest runs cause VM exits with virtual NMIs, and the ones of the guest are injected
+            // once the guest doesn't block NMIs.
+            dispatch_nmis(vm, 1);
+        }
+        ExceptionInterrupt::PageFault => {
+            if !record_exception(vm, exception.vector, error_code, exception.exit_qualification) {
+                dispatch_or_reflect_exception(vm, &exception)?;
+            }
+        }
+        ExceptionInterrupt::GeneralProtectionFault | ExceptionInterrupt::InvalidOpcode => {
+            if !record_exception(vm, exception.vector, error_code, 0) {
+                dispatch_or_reflect_exception(vm, &exception)?;
             }
-        } else {
-            panic!("Invalid Exception Interrupt Vector: {}", interruption_info.vector);
         }
-    } else {
-        panic!("Invalid VM Exit Interruption Information");
+        ExceptionInterrupt::Debug => {
+            handle_debug_exception(vm, &exception)?;
+        }
+        ExceptionInterrupt::Breakpoint => {
+            handle_breakpoint_exception(vm, &exception)?;
+        }
+        _ => {
+            dispatch_or_reflect_exception(vm, &exception)?;
+        }
     }
 
     log::debug!("Exception Handled successfully!");
 
-    ExitType::Continue
+    Ok(ExitType::Continue)
+}
+
+/// Dispatches an exception not handled by the built-in features to the hook of its vector, and reflects it to the
+/// guest unless the hook handled it.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `exception` - The intercepted exception.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the exception has been handled or reflected.
+fn dispatch_or_reflect_exception(vm: &mut Vm, exception: &InterceptedException) -> Result<(), HypervisorError> {
+    if dispatch_exception_hook(vm, exception)? == Some(ExceptionHookResult::Handled) {
+        log::debug!("Exception {:?} handled by its hook", exception.vector);
+        return Ok(());
+    }
+
+    reflect_exception(vm, exception);
+
+    Ok(())
+}
+
+/// Reflects an intercepted exception to the guest, as the processor would have delivered it.
+///
+/// An exception that occurred during the delivery of another event isn't injected: the original event is re-injected
+/// with the interception of the vector disabled until the next VM exit, so the processor raises the exception again
+/// and delivers it to the guest. The blocking of NMIs by an IRET that faulted is restored, as the interrupted IRET
+/// didn't complete.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `exception` - The intercepted exception.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.2 Information for VM Exits Due to Vectored Events
+/// and 29.8.3 Special Treatment of Events During VM Entry.
+fn reflect_exception(vm: &mut Vm, exception: &InterceptedException) {
+    const BLOCKING_BY_NMI: u64 = 1 << 3;
+
+    // The debug exceptions are reflected with their conditions, and the breakpoints are raised by an instruction.
+    match exception.vector {
+        ExceptionInterrupt::Debug => {
+            reflect_debug_exception(vm, exception.exit_qualification);
+            return;
+        }
+        ExceptionInterrupt::Breakpoint => {
+            EventInjection::vmentry_inject_bp();
+            return;
+        }
+        _ => {}
+    }
+
+    if let Some(idt_vectoring_info) = exception.idt_vectoring_info {
+        log::debug!("Exception {:?} during event delivery, re-injecting: {:#x}", exception.vector, idt_vectoring_info);
+        disarm_exception_hook(vm, exception.vector);
+        EventInjection::vmentry_reinject_idt_vectoring_event(idt_vectoring_info);
+        return;
+    }
+
+    if exception.nmi_unblocking_due_to_iret && exception.vector != ExceptionInterrupt::DoubleFault {
+        vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, vmread(vmcs::guest::INTERRUPTIBILITY_STATE) | BLOCKING_BY_NMI);
+    }
+
+    match exception.qualification {
+        ExceptionQualification::PageFault { linear_address } => {
+            // The processor doesn't update CR2 for page faults causing VM exits, the faulting address is the exit qualification.
+            cr2_write(linear_address);
+            EventInjection::vmentry_inject_pf(exception.error_code.unwrap_or_default());
+        }
+        _ => EventInjection::vmentry_inject_exception(exception.vector, exception.interruption_type, exception.error_code),
+    }
 }
 
-/*
 /// Handles breakpoint (`#BP`) exceptions specifically.
 ///
-/// When a breakpoint exception occurs, this function checks for a registered hook
-/// at the current instruction pointer (RIP). If a hook is found, it transfers control
-/// to the hook's handler. Otherwise, it injects a breakpoint exception into the VM.
+/// When a breakpoint exception occurs, this function first checks whether a system call entered through the syscall
+/// trampoline or a hooked function returned to its trampoline, to dispatch their handler or return callback. Otherwise, it checks for a breakpoint hook at the current instruction pointer (RIP).
+/// If a hook is found, it dispatches to the callbacks and the handler registered for it, then single-steps the
+/// original instruction with the monitor trap flag unless they redirected the execution.
+/// Otherwise, it passes the breakpoint exception to the exception hooks, and injects it into the VM unless handled.
 ///
 /// # Arguments
 ///
-/// * `guest_registers` - A mutable reference to the guest's current register state.
-/// * `vmx` - A mutable reference to the Vmx structure.
-fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vm: &mut Vm) {
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `exception` - The intercepted breakpoint exception.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the breakpoint has been handled or injected.
+fn handle_breakpoint_exception(vm: &mut Vm, exception: &InterceptedException) -> Result<(), HypervisorError> {
     log::debug!("Breakpoint Exception");
 
-    let hook_manager = vm.hook_manager.as_mut();
-
-    log::trace!("Finding hook for RIP: {:#x}", guest_registers.rip);
-
-    // Find the handler address for the current instruction pointer (RIP) and
-    // transfer the execution to it. If we couldn't find a hook, we inject the
-    // #BP exception.
-    //
-    if let Some(Some(handler)) =
-        hook_manager
-            .find_hook_by_address(guest_registers.rip)
-            .map(|hook| {
-                log::trace!("Found hook for RIP: {:#x}", guest_registers.rip);
-                if let HookType::Function { inline_hook } = &hook.hook_type {
-                    log::trace!("Getting handler address");
-                    Some(inline_hook.handler_address())
-                } else {
-                    None
-                }
-            })
-    {
-        // Call our hook handle function (it will automatically call trampoline).
-        log::trace!("Transferring execution to handler: {:#x}", handler);
-        guest_registers.rip = handler;
-        vmwrite(vmcs::guest::RIP, guest_registers.rip);
-
-        log::debug!("Breakpoint (int3) hook handled successfully!");
-    } else {
-        EventInjection::vmentry_inject_bp();
+    // A system call entering through the syscall trampoline.
+    if dispatch_syscall_hook(vm) {
+        log::debug!("Breakpoint (int3) syscall trampoline handled successfully!");
+        return Ok(());
+    }
+
+    // A hooked function with a return callback returning to its trampoline.
+    if dispatch_hook_return(vm) {
+        log::debug!("Breakpoint (int3) return trampoline handled successfully!");
+        return Ok(());
+    }
+
+    let guest_rip = vm.guest_registers.rip;
+    log::trace!("Finding hook for RIP: {:#x}", guest_rip);
+
+    let Ok(guest_function_pa) = PhysicalAddress::pa_from_va_with_current_cr3(guest_rip).map(PAddr::from) else {
+        return dispatch_or_reflect_exception(vm, exception);
+    };
+
+    // A breakpoint outside of the hooked pages, e.g., of a debugger, doesn't lock the hook manager.
+    if !may_be_hooked_page(guest_function_pa.align_down_to_base_page().as_u64()) {
+        return dispatch_or_reflect_exception(vm, exception);
+    }
+
+    let hook_info = SHARED_HOOK_MANAGER
+        .lock()
+        .memory_manager
+        .get_hook_info_by_function_pa(guest_function_pa.align_down_to_base_page().as_u64(), guest_function_pa.as_u64())
+        .filter(|hook_info| {
+            matches!(hook_info.ept_hook_type, EptHookType::Function(InlineHookType::Int3) | EptHookType::Function(InlineHookType::Int3Stub))
+        })
+        .cloned();
+
+    // The breakpoint doesn't belong to a hook (e.g., a debugger breakpoint), let the exception hooks or the guest handle it.
+    let Some(hook_info) = hook_info else {
+        dispatch_or_reflect_exception(vm, exception)?;
         log::debug!("Breakpoint exception handled successfully!");
+        return Ok(());
     };
+
+    log::trace!("Found breakpoint hook for RIP: {:#x}", guest_rip);
+
+    if dispatch_hook_entry(vm, &hook_info)? {
+        return Ok(());
+    }
+
+    // The lock is released while the handler runs, so it can use the hook manager itself.
+    let handler = SHARED_HOOK_MANAGER.lock().breakpoint_handlers.get(&hook_info.guest_function_va).copied();
+
+    if let Some(handler) = handler {
+        log::trace!("Dispatching to breakpoint handler for function at VA: {:#x}", hook_info.guest_function_va);
+        handler(vm, &hook_info);
+
+        vmwrite(vmcs::guest::RSP, vm.guest_registers.rsp);
+        vmwrite(vmcs::guest::RFLAGS, vm.guest_registers.rflags);
+
+        if vm.guest_registers.rip != guest_rip {
+            log::trace!("Breakpoint handler redirected execution to: {:#x}", vm.guest_registers.rip);
+            vmwrite(vmcs::guest::RIP, vm.guest_registers.rip);
+            return Ok(());
+        }
+    }
+
+    single_step_hook(vm, &mut SHARED_HOOK_MANAGER.lock(), guest_function_pa)?;
+
+    log::debug!("Breakpoint (int3) hook handled successfully!");
+
+    Ok(())
+}
+
+/// Handles debug (`#DB`) exceptions, which are intercepted while the system calls are traced or hooked, or the
+/// hypervisor owns hardware breakpoints.
+///
+/// The single-step trap after the return of a traced system call completes its record, and the one after the return of
+/// a hooked system call calls its return handler, and the guest resumes without the exception. The hits of the
+/// hypervisor breakpoints are dispatched to their handler (see the `debug_registers` module). Other debug exceptions, e.g., of a debugger, are passed to
+/// the exception hooks without the conditions of the hypervisor breakpoints, and injected into the VM unless handled.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `exception` - The intercepted debug exception.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the debug exception has been handled or injected.
+fn handle_debug_exception(vm: &mut Vm, exception: &InterceptedException) -> Result<(), HypervisorError> {
+    log::debug!("Debug Exception");
+
+    if complete_syscall_return(vm) {
+        log::debug!("Debug exception of a syscall return handled successfully!");
+        return Ok(());
+    }
+
+    let exit_qualification = dispatch_hardware_breakpoints(vm, exception.exit_qualification);
+    if exit_qualification == 0 && exception.exit_qualification != 0 {
+        log::debug!("Debug exception of a hardware breakpoint handled successfully!");
+        return Ok(());
+    }
+
+    dispatch_or_reflect_exception(vm, &exception.with_exit_qualification(exit_qualification))?;
+
+    log::debug!("Debug exception handled successfully!");
+
+    Ok(())
 }
-*/
 
 /// Handles undefined opcode (`#UD`) exceptions.
 ///
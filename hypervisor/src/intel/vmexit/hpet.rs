@@ -0,0 +1,210 @@
+//! Handles EPT violations on the page containing the High Precision Event Timer (HPET) registers.
+//!
+//! The page has no EPT permissions while the HPET is intercepted. `MOV` loads and stores to the registers
+//! are emulated, with reads of the main counter adjusted to hide the time spent in VMX root operation.
+//! Any other instruction is single-stepped with the page accessible, and the page is protected again on MTF.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            ept::AccessType,
+            single_step::{single_step, SingleStepContext, SingleStepOwner},
+            support::vmwrite,
+            timing::{normalize_hpet_counter, set_hpet_page_permissions, HPET_MAIN_COUNTER},
+            vm::Vm,
+            vmexit::ExitType,
+        },
+    },
+    core::ptr::{read_volatile, write_volatile},
+    log::*,
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        vmx::vmcs,
+    },
+};
+
+/// The maximum length of an x86-64 instruction.
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// A decoded `MOV` between a general-purpose register and memory.
+struct MmioMove {
+    /// `true` for a load (`MOV r, m`), `false` for a store (`MOV m, r`).
+    is_load: bool,
+    /// The index of the general-purpose register (0 = RAX ... 15 = R15).
+    register: usize,
+    /// The size of the access in bytes (4 or 8).
+    size: usize,
+    /// The length of the instruction in bytes.
+    length: usize,
+}
+
+/// Handles an EPT violation on the HPET page.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `guest_pa` - The faulting guest physical address.
+///
+/// # Returns
+///
+/// * `Result<ExitType, HypervisorError>` - `Ok(ExitType::Continue)` if the access was handled successfully.
+pub fn handle_hpet_access(vm: &mut Vm, guest_pa: u64) -> Result<ExitType, HypervisorError> {
+    trace!("Handling HPET access at PA: {:#x}, RIP: {:#x}", guest_pa, vm.guest_registers.rip);
+
+    let instruction = read_guest_instruction(vm.guest_registers.rip)?;
+
+    let Some(mmio_move) = decode_mmio_move(&instruction) else {
+        // The instruction can't be emulated, make the page accessible and single-step it instead.
+        debug!("Single-stepping HPET access at RIP: {:#x}", vm.guest_registers.rip);
+        set_hpet_page_permissions(vm, AccessType::READ_WRITE)?;
+
+        let context = SingleStepContext {
+            guest_page_pa: PAddr::from(guest_pa).align_down_to_base_page().as_u64(),
+            guest_next_page_pa: None,
+        };
+        single_step(vm, SingleStepOwner::MmioAccess, 1, context, reprotect_hpet_page)?;
+
+        return Ok(ExitType::Continue);
+    };
+
+    let register = vm.guest_registers.register_mut(mmio_move.register);
+    let value_mask = match mmio_move.size {
+        8 => u64::MAX,
+        _ => u32::MAX as u64,
+    };
+
+    if mmio_move.is_load {
+        let offset = guest_pa & (BASE_PAGE_SIZE as u64 - 1);
+
+        let value = if (HPET_MAIN_COUNTER..HPET_MAIN_COUNTER + 8).contains(&offset) {
+            // Read the whole main counter, even for a 32-bit access to one of its halves.
+            let counter_pa = guest_pa - (offset - HPET_MAIN_COUNTER);
+            let counter = normalize_hpet_counter(unsafe { read_volatile(counter_pa as *const u64) });
+            (counter >> ((offset - HPET_MAIN_COUNTER) * 8)) & value_mask
+        } else {
+            match mmio_move.size {
+                8 => unsafe { read_volatile(guest_pa as *const u64) },
+                _ => unsafe { read_volatile(guest_pa as *const u32) as u64 },
+            }
+        };
+
+        // 32-bit register writes zero-extend to 64 bits.
+        *register = value;
+    } else {
+        match mmio_move.size {
+            8 => unsafe { write_volatile(guest_pa as *mut u64, *register) },
+            _ => unsafe { write_volatile(guest_pa as *mut u32, *register as u32) },
+        }
+    }
+
+    if mmio_move.is_load && mmio_move.register == 4 {
+        vmwrite(vmcs::guest::RSP, vm.guest_registers.rsp);
+    }
+
+    // The VM-exit instruction length is not valid for EPT violations, use the decoded length instead.
+    vm.guest_registers.rip += mmio_move.length as u64;
+    vmwrite(vmcs::guest::RIP, vm.guest_registers.rip);
+
+    Ok(ExitType::Continue)
+}
+
+/// Protects the HPET page again after an instruction accessing it has been single-stepped.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `_context` - The HPET page, whose permissions are set with the others of the HPET.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the page has been protected again.
+pub fn reprotect_hpet_page(vm: &mut Vm, _context: SingleStepContext) -> Result<(), HypervisorError> {
+    trace!("Protecting HPET page again after single-step");
+    set_hpet_page_permissions(vm, AccessType::empty())
+}
+
+/// Reads the bytes of the instruction at the guest RIP, which may span two pages.
+///
+/// # Arguments
+///
+/// * `guest_rip` - The guest virtual address of the instruction.
+///
+/// # Returns
+///
+/// * `Result<[u8; MAX_INSTRUCTION_LENGTH], HypervisorError>` - The instruction bytes.
+pub fn read_guest_instruction(guest_rip: u64) -> Result<[u8; MAX_INSTRUCTION_LENGTH], HypervisorError> {
+    let mut instruction = [0u8; MAX_INSTRUCTION_LENGTH];
+
+    let first_page_length = (BASE_PAGE_SIZE - (guest_rip as usize & (BASE_PAGE_SIZE - 1))).min(MAX_INSTRUCTION_LENGTH);
+    let first_pa = PhysicalAddress::pa_from_va_with_current_cr3(guest_rip)?;
+    unsafe { core::ptr::copy_nonoverlapping(first_pa as *const u8, instruction.as_mut_ptr(), first_page_length) };
+
+    if first_page_length < MAX_INSTRUCTION_LENGTH {
+        // The next page may not be mapped if the instruction is shorter, in which case the bytes are not needed.
+        if let Ok(second_pa) = PhysicalAddress::pa_from_va_with_current_cr3(guest_rip + first_page_length as u64) {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    second_pa as *const u8,
+                    instruction.as_mut_ptr().add(first_page_length),
+                    MAX_INSTRUCTION_LENGTH - first_page_length,
+                )
+            };
+        }
+    }
+
+    Ok(instruction)
+}
+
+/// Decodes a `MOV r32/r64, m` (8B /r) or `MOV m, r32/r64` (89 /r) instruction with an optional REX prefix.
+///
+/// # Arguments
+///
+/// * `instruction` - The instruction bytes.
+///
+/// # Returns
+///
+/// * `Option<MmioMove>` - The decoded instruction, or `None` if it can't be emulated.
+fn decode_mmio_move(instruction: &[u8; MAX_INSTRUCTION_LENGTH]) -> Option<MmioMove> {
+    let mut index = 0;
+
+    // Skip segment override prefixes, other prefixes (e.g., operand-size) are not emulated.
+    while index < MAX_INSTRUCTION_LENGTH - 2 && matches!(instruction[index], 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65) {
+        index += 1;
+    }
+
+    let rex = match instruction[index] {
+        rex @ 0x40..=0x4F => {
+            index += 1;
+            rex
+        }
+        _ => 0,
+    };
+
+    let is_load = match instruction[index] {
+        0x8B => true,
+        0x89 => false,
+        _ => return None,
+    };
+
+    let modrm = instruction[index + 1];
+
+    // A register operand (Mod = 11b) can't cause an EPT violation.
+    if modrm >> 6 == 0b11 {
+        return None;
+    }
+
+    let length = lde::X64.ld(instruction) as usize;
+
+    if length == 0 {
+        return None;
+    }
+
+    Some(MmioMove {
+        is_load,
+        register: ((modrm >> 3) & 0x7) as usize | (((rex >> 2) & 0x1) as usize) << 3,
+        size: if rex & 0x8 != 0 { 8 } else { 4 },
+        length,
+    })
+}
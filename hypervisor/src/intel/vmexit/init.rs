@@ -3,41 +3,94 @@
 //! Includes functionality for responding to INIT signals in a virtualized environment and adjusting
 //! control registers (CR0, CR4) to meet VMX operation requirements. Essential for virtual machine initialization
 //! and maintaining correct processor states.
+//!
+//! When the processors are virtualized from UEFI, the INIT-SIPI-SIPI sequence Windows sends to start the application
+//! processors is received in VMX non-root operation: the INIT signal causes a VM exit emulated here, putting the guest
+//! in the wait-for-SIPI state, and the SIPI a VM exit emulated by the `sipi` module. The guest then goes through real
+//! mode and protected mode back to long mode, which the `cr` module follows on the writes to CR0.
 //! Credits to Satoshi Tanada: https://github.com/tandasat/MiniVisorPkg/blob/master/Sources/HostMain.c
 
 use {
     crate::intel::{
-        capture::GuestRegisters,
-        invvpid::invvpid_single_context,
+        broadcast::leave_broadcasts,
+        events::EventInjection,
+        reset::{handle_processor_reset, ResetSource},
         segmentation::VmxSegmentAccessRights,
         state::GuestActivityState,
-        support::{cr2_write, dr0_write, dr1_write, dr2_write, dr3_write, dr6_write, rdmsr, vmread, vmwrite},
+        support::{cr2_write, dr0_write, dr1_write, dr2_write, dr3_write, dr6_write, invvpid_current_context, rdmsr, vmread, vmwrite},
+        vm::Vm,
         vmexit::ExitType,
     },
     x86::{
         bits64::rflags,
         controlregs::Cr0,
-        msr::{IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1},
+        msr::{IA32_APIC_BASE, IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1},
         segmentation::{CodeSegmentType, DataSegmentType, SystemDescriptorTypes64},
         vmx::vmcs::{self, control::SecondaryControls},
     },
     x86_64::registers::control::Cr4Flags,
 };
 
+/// The value of CR0 after INIT: caching disabled, not write-through and the extension type set.
+///
+/// See: Table 9-1. IA-32 and Intel 64 Processor States Following Power-up, Reset, or INIT
+const INIT_CR0: Cr0 = Cr0::CR0_CACHE_DISABLE.union(Cr0::CR0_NOT_WRITE_THROUGH).union(Cr0::CR0_EXTENSION_TYPE);
+
+/// The bit of IA32_APIC_BASE set on the bootstrap processor.
+const APIC_BASE_BSP: u64 = 1 << 8;
+
 /// Handles the INIT signal by initializing processor state according to Intel SDM.
 ///
 /// Initializes the guest's processor state to mimic the state after receiving an INIT signal, including
 /// setting registers and segment selectors to their startup values. This ensures the guest VM is correctly
 /// initialized in line with the MP initialization protocol.
 ///
+/// An INIT of the bootstrap processor resets the platform instead, unless the reset policy vetoes it (see the `reset`
+/// module). The events pending for the guest are discarded, and the logical processor stops acknowledging the broadcast
+/// commands until the SIPI, as it causes no VM exit while waiting for it.
+///
 /// # Arguments
 ///
-/// - `guest_registers`: A mutable reference to the guest's general-purpose registers.
+/// - `vm`: A mutable reference to the virtual machine (VM) instance.
 ///
 /// # Returns
 ///
 /// Returns `ExitType::Continue` to indicate the VM should continue execution post-initialization.
-pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
+pub fn handle_init_signal(vm: &mut Vm) -> ExitType {
+    // The bootstrap processor only gets an INIT on a reset, e.g., a soft reset of the chipset, and would wait for a SIPI
+    // forever, so the platform is reset unless the policy vetoes it.
+    if rdmsr(IA32_APIC_BASE) & APIC_BASE_BSP != 0 {
+        handle_processor_reset(vm, ResetSource::BootstrapProcessorInit);
+    }
+
+    EventInjection::discard_pending_events(vm);
+
+    // The logical processor may get another INIT before its SIPI.
+    if vmread(vmcs::guest::ACTIVITY_STATE) != GuestActivityState::WaitForSipi as u64 {
+        leave_broadcasts(vm);
+    }
+
+    reset_guest_state(vm);
+
+    //
+    // Set the activity state to "Wait for SIPI".
+    //
+    vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::WaitForSipi as u32);
+
+    ExitType::Continue
+}
+
+/// Initializes the guest's processor state to the state after an INIT signal, leaving its activity state unchanged.
+///
+/// This is also used to enter the waking vector of the guest on the resume from S3, which starts from the same state
+/// as a SIPI (see the `sleep` module).
+///
+/// # Arguments
+///
+/// - `vm`: A mutable reference to the virtual machine (VM) instance.
+pub fn reset_guest_state(vm: &mut Vm) {
+    let guest_registers = &mut vm.guest_registers;
+
     //
     // Initializes the processor to the state after INIT as described in the Intel SDM.
     //
@@ -49,7 +102,7 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
     vmwrite(vmcs::guest::RFLAGS, guest_registers.rflags);
     guest_registers.rip = 0xfff0u64;
     vmwrite(vmcs::guest::RIP, guest_registers.rip);
-    vmwrite(vmcs::control::CR0_READ_SHADOW, 0u64);
+    vmwrite(vmcs::control::CR0_READ_SHADOW, INIT_CR0.bits() as u64);
     cr2_write(0);
     vmwrite(vmcs::guest::CR3, 0u64);
     vmwrite(vmcs::control::CR4_READ_SHADOW, 0u64);
@@ -57,7 +110,7 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
     //
     // Actual guest CR0 and CR4 must fulfill requirements for VMX. Apply those.
     //
-    vmwrite(vmcs::guest::CR0, adjust_guest_cr0(Cr0::CR0_EXTENSION_TYPE));
+    vmwrite(vmcs::guest::CR0, adjust_guest_cr0(INIT_CR0));
     vmwrite(vmcs::guest::CR4, adjust_cr4());
 
     //
@@ -187,7 +240,8 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
     //  - IA32_BNDCFGS
 
     //
-    // Set Guest EFER, FS_BASE and GS_BASE to 0.
+    // Set Guest EFER, FS_BASE and GS_BASE to 0. The guest EFER is loaded on VM entry, so long mode is only entered
+    // again when the guest enables paging with EFER.LME set.
     //
     vmwrite(vmcs::guest::IA32_EFER_FULL, 0u64);
     vmwrite(vmcs::guest::FS_BASE, 0u64);
@@ -203,14 +257,7 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
     //
     // Invalidate TLB for current VPID
     //
-    invvpid_single_context(vmread(vmcs::control::VPID) as _);
-
-    //
-    // Set the activity state to "Wait for SIPI".
-    //
-    vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::WaitForSipi as u32);
-
-    ExitType::Continue
+    invvpid_current_context();
 }
 
 /// Adjusts guest CR0 considering UnrestrictedGuest feature and fixed MSRs.
@@ -225,7 +272,7 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
 /// # Returns
 ///
 /// Returns the adjusted CR0 value as a `u64`.
-fn adjust_guest_cr0(cr0: Cr0) -> u64 {
+pub fn adjust_guest_cr0(cr0: Cr0) -> u64 {
     // Adjust the CR0 register according to the fixed0 and fixed1 MSR values.
     let mut new_cr0 = adjust_cr0(cr0);
 
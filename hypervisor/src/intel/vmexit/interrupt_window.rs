@@ -0,0 +1,32 @@
+//! Handles the interrupt-window and NMI-window VM exits, requested while NMIs or external interrupts wait in the
+//! pending-event queue of the logical processor for the guest to accept them (see the `events` module).
+//!
+//! The window is open at these VM exits, and the pending events are injected at the end of every VM exit, so the
+//! handlers only resume the guest.
+
+use {crate::intel::vmexit::ExitType, log::trace};
+
+/// Handles the VM exit caused by the interrupt-window exiting, once the guest has interrupts enabled outside of an
+/// interrupt shadow.
+///
+/// # Returns
+///
+/// Returns `ExitType::Continue`, as no instruction caused the VM exit.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 7.
+pub fn handle_interrupt_window() -> ExitType {
+    trace!("Handling interrupt window VM exit...");
+    ExitType::Continue
+}
+
+/// Handles the VM exit caused by the NMI-window exiting, once the guest doesn't block NMIs.
+///
+/// # Returns
+///
+/// Returns `ExitType::Continue`, as no instruction caused the VM exit.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 8.
+pub fn handle_nmi_window() -> ExitType {
+    trace!("Handling NMI window VM exit...");
+    ExitType::Continue
+}
@@ -0,0 +1,126 @@
+//! Handles I/O instruction VM exits for the ports intercepted through the I/O bitmaps.
+//!
+//! Reads of the ACPI PM timer port are adjusted to hide the time spent in VMX root operation,
+//! accesses to the CMOS ports are emulated by the `rtc` module to shift the guest's RTC time,
+//! configuration accesses to hidden PCI functions read as all ones and are discarded, writes requesting a reset or sleep are
+//! handled by the `reset` module, and all other intercepted accesses are passed through to the actual port.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            device_hiding::is_hidden_pci_config_access,
+            reset::handle_reset_request,
+            rtc::{is_cmos_port, read_cmos_port, write_cmos_port},
+            sleep::enter_sleep_state,
+            support::vmread,
+            timing::{normalize_pm_timer, SHARED_CLOCK_SOURCES},
+            vm::Vm,
+            vmexit::ExitType,
+        },
+    },
+    log::*,
+    x86::{
+        io::{inb, inl, inw, outb, outl, outw},
+        vmx::vmcs,
+    },
+};
+
+/// Handles an I/O instruction VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+///
+/// # Returns
+///
+/// * `Ok(ExitType::IncrementRIP)` - To move past the I/O instruction in the VM.
+/// * `Err(HypervisorError::UnsupportedIoInstruction)` - If the instruction is a string I/O instruction (INS/OUTS).
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-5. Exit Qualification for I/O Instructions
+pub fn handle_io_instruction(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
+    let exit_qualification = vmread(vmcs::ro::EXIT_QUALIFICATION);
+
+    // Bits 2:0: size of access (0 = 1-byte, 1 = 2-byte, 3 = 4-byte).
+    let size = (exit_qualification & 0x7) + 1;
+    // Bit 3: direction of the attempted access (0 = OUT, 1 = IN).
+    let is_in = exit_qualification & (1 << 3) != 0;
+    // Bit 4: string instruction.
+    let is_string = exit_qualification & (1 << 4) != 0;
+    // Bits 31:16: port number.
+    let port = (exit_qualification >> 16) as u16;
+
+    trace!("I/O instruction: port {:#x}, size {}, in {}, string {}", port, size, is_in, is_string);
+
+    if is_string {
+        error!("String I/O instructions are not supported: port {:#x}", port);
+        return Err(HypervisorError::UnsupportedIoInstruction);
+    }
+
+    if is_cmos_port(port) && size == 1 {
+        if is_in {
+            vm.guest_registers.rax = (vm.guest_registers.rax & !0xFF) | read_cmos_port(port) as u64;
+        } else {
+            write_cmos_port(port, vm.guest_registers.rax as u8);
+        }
+
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    if is_hidden_pci_config_access(port) {
+        // The hidden function appears absent, so reads return all ones and writes are discarded.
+        if is_in {
+            vm.guest_registers.rax = match size {
+                1 => vm.guest_registers.rax | 0xFF,
+                2 => vm.guest_registers.rax | 0xFFFF,
+                _ => u32::MAX as u64,
+            };
+        }
+
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    if !is_in && handle_reset_request(vm, port, size, vm.guest_registers.rax) {
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    // The write entering S3 is completed out of VMX operation, returning only if the sleep state isn't entered.
+    let value = vm.guest_registers.rax;
+    if !is_in && enter_sleep_state(vm, port, size, value) {
+        return Ok(ExitType::IncrementRIP);
+    }
+
+    if is_in {
+        let value = unsafe {
+            match size {
+                1 => inb(port) as u32,
+                2 => inw(port) as u32,
+                _ => inl(port),
+            }
+        };
+
+        let value = match SHARED_CLOCK_SOURCES.read().pm_timer_port {
+            Some(pm_timer_port) if pm_timer_port == port && size == 4 => normalize_pm_timer(value),
+            _ => value,
+        };
+
+        // IN AL/AX only replace the low bits of RAX, IN EAX zero-extends to RAX.
+        vm.guest_registers.rax = match size {
+            1 => (vm.guest_registers.rax & !0xFF) | value as u64,
+            2 => (vm.guest_registers.rax & !0xFFFF) | value as u64,
+            _ => value as u64,
+        };
+    } else {
+        let value = vm.guest_registers.rax;
+
+        unsafe {
+            match size {
+                1 => outb(port, value as u8),
+                2 => outw(port, value as u16),
+                _ => outl(port, value as u32),
+            }
+        }
+    }
+
+    Ok(ExitType::IncrementRIP)
+}
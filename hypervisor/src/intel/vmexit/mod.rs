@@ -1,18 +1,26 @@
 pub mod commands;
 pub mod cpuid;
 pub mod cr;
+pub mod dispatch;
+pub mod dr;
 pub mod ept_misconfiguration;
 pub mod ept_violation;
 pub mod exception;
 pub mod halt;
+pub mod hpet;
 pub mod init;
+pub mod interrupt_window;
 pub mod invd;
 pub mod invept;
 pub mod invvpid;
+pub mod io;
 pub mod msr;
 pub mod mtf;
+pub mod preemption_timer;
+pub mod rdrand;
 pub mod rdtsc;
 pub mod sipi;
+pub mod triple_fault;
 pub mod vmcall;
 pub mod vmxon;
 pub mod xsetbv;
@@ -11,24 +11,35 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            addresses::PhysicalAddress,
             bitmap::{MsrAccessType, MsrOperation},
             events::EventInjection,
-            hooks::hook_manager::SHARED_HOOK_MANAGER,
-            support::{rdmsr, wrmsr},
+            hooks::{
+                boot_manifest::arm_boot_hook_trigger,
+                hook_manager::{kernel_image, SHARED_HOOK_MANAGER},
+                msr_hook::{dispatch_msr_hook, MsrHookResult},
+                syscall_hook::SHARED_SYSCALL_HOOK_MANAGER,
+            },
+            support::{rdmsr, vmread, vmwrite, wrmsr},
+            tsc_compensation::guest_tsc,
             vm::Vm,
             vmexit::ExitType,
         },
+        linux::kernel::SHARED_LINUX_KERNEL,
+        personality::{detect_guest_personality, GuestPersonality},
+        windows::{measurement::record_kernel_measurement, symbols::record_kernel_symbols},
     },
     bit_field::BitField,
     core::ops::RangeInclusive,
     log::*,
-    x86::msr,
+    x86::{msr, vmx::vmcs},
 };
 
 /// Handles MSR access based on the provided access type.
 ///
-/// This function checks if the requested MSR address is within a valid
-/// range, a reserved range, or a synthetic MSR range used by Hyper-V.
+/// The access is first dispatched to its hook in the MSR hook registry, if any, which may emulate it, change the
+/// value written or inject a general protection fault. Otherwise, this function checks if the requested MSR address
+/// is within a valid range, a reserved range, or a synthetic MSR range used by Hyper-V.
 /// For valid MSRs, the function will either read or write to the MSR based
 /// on the access type. For reserved or synthetic MSRs, a general protection
 /// fault is injected.
@@ -47,140 +58,327 @@ use {
 pub fn handle_msr_access(vm: &mut Vm, access_type: MsrAccessType) -> Result<ExitType, HypervisorError> {
     debug!("Handling MSR VM exit...");
 
-    // Define the mask for the low 32-bits of the MSR value
-    const MSR_MASK_LOW: u64 = u32::MAX as u64;
+    let msr_id = vm.guest_registers.rcx as u32;
+    let mut msr_value = match access_type {
+        MsrAccessType::Read => 0,
+        MsrAccessType::Write => (vm.guest_registers.rdx << 32) | (vm.guest_registers.rax & MSR_MASK_LOW),
+    };
 
-    // Define the VMX lock bit for IA32_FEATURE_CONTROL MSR
-    const VMX_LOCK_BIT: u64 = 0;
-    const VMXON_OUTSIDE_SMX: u64 = 2;
+    trace!("MSR access attempted: {:#x}", msr_id);
 
-    let msr_id = vm.guest_registers.rcx as u32;
-    let msr_value = (vm.guest_registers.rdx << 32) | (vm.guest_registers.rax & MSR_MASK_LOW);
+    match dispatch_msr_hook(vm, msr_id, access_type, &mut msr_value)? {
+        Some(MsrHookResult::InjectGp) => {
+            trace!("Hooked MSR access rejected: {:#x}", msr_id);
+            EventInjection::vmentry_inject_gp(0);
+            return Ok(ExitType::Continue);
+        }
+        Some(MsrHookResult::Emulated) => {
+            trace!("Hooked MSR access emulated: {:#x}", msr_id);
+            if access_type == MsrAccessType::Read {
+                set_msr_read_result(vm, msr_value);
+            }
+            return Ok(ExitType::IncrementRIP);
+        }
+        Some(MsrHookResult::Passthrough) | None => {}
+    }
 
     // Determine if the MSR address is valid, reserved, or synthetic (EasyAntiCheat and Battleye invalid MSR checks)
-    // by checking if the MSR address is in the Hyper-V range or outside other valid ranges
+    if is_invalid_msr(msr_id) {
+        trace!("Invalid MSR access attempted: {:#x}", msr_id);
+        EventInjection::vmentry_inject_gp(0);
+        return Ok(ExitType::Continue);
+    }
+
+    trace!("Valid MSR access attempted: {:#x}", msr_id);
+
+    match access_type {
+        // The TSC read through the MSR is offset and scaled like RDTSC (see the `tsc_compensation` module).
+        MsrAccessType::Read if msr_id == msr::IA32_TIME_STAMP_COUNTER => set_msr_read_result(vm, guest_tsc(rdmsr(msr_id))),
+        MsrAccessType::Read => set_msr_read_result(vm, rdmsr(msr_id)),
+        // Credits: https://github.com/tandasat/MiniVisorPkg/issues/4#issuecomment-664030968
+        //
+        // A write to IA32_GS_BASE happens at KiSystemStartup for each processor, and can be hooked as a trigger point
+        // to initialize a guest agent on the BSP, making the guest retry the write after returning from the agent.
+        // This place was chosen so that none of PatchGuard context is initialized. Such a trigger only applies to
+        // Windows guests (see `personality::is_windows_guest`).
+        MsrAccessType::Write => wrmsr(msr_id, msr_value),
+    }
 
+    debug!("MSR VMEXIT handled successfully.");
+    Ok(ExitType::IncrementRIP)
+}
+
+/// Returns whether an access to an MSR that isn't hooked raises a general protection fault (#GP), by checking if the
+/// MSR address is in the Hyper-V range or outside other valid ranges.
+///
+/// # Arguments
+///
+/// * `msr_id` - The accessed MSR.
+pub fn is_invalid_msr(msr_id: u32) -> bool {
     // Define the range for valid MSR access and Hyper-V MSRs
     const MSR_VALID_RANGE_LOW: RangeInclusive<u32> = 0x00000000..=0x00001FFF;
     const MSR_VALID_RANGE_HIGH: RangeInclusive<u32> = 0xC0000000..=0xC0001FFF;
     const MSR_HYPERV_RANGE: RangeInclusive<u32> = 0x40000000..=0x400000FF;
 
-    trace!("MSR access attempted: {:#x}", msr_id);
-
-    #[cfg(feature = "vmware")]
-    if !MSR_VALID_RANGE_LOW.contains(&msr_id) && !MSR_VALID_RANGE_HIGH.contains(&msr_id) && MSR_HYPERV_RANGE.contains(&msr_id) {
+    match cfg!(feature = "vmware") {
         // In VMware, do not inject #GP for MSRs within the Hyper-V range
-        trace!("Invalid MSR access attempted: {:#x}", msr_id);
-        EventInjection::vmentry_inject_gp(0);
-        return Ok(ExitType::Continue);
+        true => !MSR_VALID_RANGE_LOW.contains(&msr_id) && !MSR_VALID_RANGE_HIGH.contains(&msr_id) && MSR_HYPERV_RANGE.contains(&msr_id),
+        // On real hardware, inject #GP if MSR is in the Hyper-V range or outside the valid ranges
+        false => !(MSR_VALID_RANGE_LOW.contains(&msr_id) || MSR_VALID_RANGE_HIGH.contains(&msr_id)) || MSR_HYPERV_RANGE.contains(&msr_id),
     }
+}
 
-    #[cfg(not(feature = "vmware"))]
-    if !(MSR_VALID_RANGE_LOW.contains(&msr_id) || MSR_VALID_RANGE_HIGH.contains(&msr_id)) || MSR_HYPERV_RANGE.contains(&msr_id) {
-        // On real hardware, inject #GP if MSR is in the Hyper-V range or outside the valid ranges
-        trace!("Invalid MSR access attempted: {:#x}", msr_id);
-        EventInjection::vmentry_inject_gp(0);
-        return Ok(ExitType::Continue);
+/// The mask for the low 32-bits of the MSR value.
+const MSR_MASK_LOW: u64 = u32::MAX as u64;
+
+/// Returns the value of an MSR read to the guest in EDX:EAX.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `value` - The value of the MSR.
+fn set_msr_read_result(vm: &mut Vm, value: u64) {
+    vm.guest_registers.rax = value & MSR_MASK_LOW;
+    vm.guest_registers.rdx = value >> 32;
+}
+
+/// Handles a read of IA32_LSTAR, built-in hook of the MSR hook registry.
+///
+/// When the guest reads the LSTAR MSR, the hypervisor returns the shadowed original value instead of the actual
+/// (modified) value. This way, the guest OS sees what it expects, assuming no tampering has occurred.
+/// Credits: jessiep_ and https://revers.engineering/patchguard-detection-of-hypervisor-based-instrospection-p2/
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `_msr` - The accessed MSR, IA32_LSTAR.
+/// * `value` - The value returned to the guest.
+///
+/// # Returns
+///
+/// * `MsrHookResult::Emulated` with the original value once it has been captured, `MsrHookResult::Passthrough` before.
+pub fn handle_lstar_read(vm: &mut Vm, _msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError> {
+    trace!("IA32_LSTAR read attempted");
+
+    // The original value is populated during the first write to IA32_LSTAR, which is set during the initial phase by ntoskrnl.exe.
+    if vm.guest_registers.original_lstar == 0 {
+        return Ok(MsrHookResult::Passthrough);
     }
 
-    trace!("Valid MSR access attempted: {:#x}", msr_id);
+    *value = vm.guest_registers.original_lstar;
+    Ok(MsrHookResult::Emulated)
+}
 
-    match access_type {
-        // Credits: jessiep_ and https://revers.engineering/patchguard-detection-of-hypervisor-based-instrospection-p2/
-        MsrAccessType::Read => {
-            let result_value = match msr_id {
-                // When the guest reads the LSTAR MSR, the hypervisor returns the shadowed original value instead of the actual (modified) value.
-                // This way, the guest OS sees what it expects, assuming no tampering has occurred.
-                msr::IA32_LSTAR => {
-                    trace!("IA32_LSTAR read attempted with MSR value: {:#x}", msr_value);
-                    // This won't be 0 here because we intercept and populate it during MsrAccessType::Write on IA32_LSTAR which is set during the initial phase when ntoskrnl.exe
-                    vm.guest_registers.original_lstar
-                }
-
-                // Simulate IA32_FEATURE_CONTROL as locked: VMX locked bit set, VMX outside SMX clear.
-                // Set lock bit, indicating that feature control is locked.
-                // Credits to @vmctx
-                msr::IA32_FEATURE_CONTROL => {
-                    trace!("IA32_FEATURE_CONTROL read attempted with MSR value: {:#x}", msr_value);
-                    let mut result_value = rdmsr(msr_id as _);
-                    result_value.set_bit(VMX_LOCK_BIT as usize, true);
-                    result_value.set_bit(VMXON_OUTSIDE_SMX as usize, false);
-                    result_value
-                }
-                _ => rdmsr(msr_id),
-            };
-
-            vm.guest_registers.rax = result_value & MSR_MASK_LOW;
-            vm.guest_registers.rdx = result_value >> 32;
+/// Handles a write to IA32_LSTAR, built-in hook of the MSR hook registry.
+///
+/// The first write on each logical processor captures the base address of ntoskrnl.exe and the original syscall entry,
+/// and the interception of the writes is then disabled on the logical processor. The first write also detects the
+/// personality of the guest, unless it was selected at boot: the writes of other guests than Windows are passed through,
+/// the first one of a Linux guest capturing its syscall entry (see `linux::kernel`). The guest's write of the original
+/// value is replaced with the effective value, the syscall trampoline while system calls are hooked (see `syscall_hook`).
+/// Credits: jessiep_ and https://revers.engineering/patchguard-detection-of-hypervisor-based-instrospection-p2/
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `msr` - The accessed MSR, IA32_LSTAR.
+/// * `value` - The value written by the guest, replaced by the hook value.
+///
+/// # Returns
+///
+/// * `MsrHookResult::Passthrough` if the guest writes the original value, `MsrHookResult::Emulated` to discard other values.
+pub fn handle_lstar_write(vm: &mut Vm, msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError> {
+    trace!("IA32_LSTAR write attempted with MSR value: {:#x}", *value);
+    // trace!("GuestRegisters Original LSTAR value: {:#x}", vm.guest_registers.original_lstar);
+    // trace!("GuestRegisters Hook LSTAR value: {:#x}", vm.guest_registers.hook_lstar);
+
+    vm.msr_bitmap.modify_msr_interception(msr, MsrAccessType::Write, MsrOperation::Unhook);
+    trace!("Unhooked MSR_IA32_LSTAR");
+
+    // The kernel base and the syscall trampoline are only found in ntoskrnl.exe: Linux only records its syscall entry,
+    // which the offsets of its symbols are relative to.
+    match detect_guest_personality(*value) {
+        GuestPersonality::Windows => {}
+        GuestPersonality::Linux => {
+            SHARED_LINUX_KERNEL.lock().capture(*value, vmread(vmcs::guest::CR3));
+            return Ok(MsrHookResult::Passthrough);
         }
-        // Credits: jessiep_ and https://revers.engineering/patchguard-detection-of-hypervisor-based-instrospection-p2/
-        MsrAccessType::Write => {
-            if msr_id == msr::IA32_LSTAR {
-                trace!("IA32_LSTAR write attempted with MSR value: {:#x}", msr_value);
-                // trace!("GuestRegisters Original LSTAR value: {:#x}", vm.guest_registers.original_lstar);
-                // trace!("GuestRegisters Hook LSTAR value: {:#x}", vm.guest_registers.hook_lstar);
-
-                // Lock the shared hook manager
-                let mut hook_manager = SHARED_HOOK_MANAGER.lock();
-
-                hook_manager
-                    .msr_bitmap
-                    .modify_msr_interception(msr::IA32_LSTAR, MsrAccessType::Write, MsrOperation::Unhook);
-                trace!("Unhooked MSR_IA32_LSTAR");
-
-                // Get and set the ntoskrnl.exe base address and size, to be used for hooking later in `CpuidLeaf::CacheInformation` or by the guest client.
-                hook_manager.set_kernel_base_and_size(msr_value)?;
-
-                // Check if it's the first time we're intercepting a write to LSTAR.
-                // If so, store the value being written as the original LSTAR value.
-                if vm.guest_registers.original_lstar == 0 {
-                    vm.guest_registers.original_lstar = msr_value;
-                    // Optionally set a hook LSTAR value here. For now, let's assume we simply store the original value.
-                    // This is a placeholder for where you would set your hook.
-                    vm.guest_registers.hook_lstar = vm.guest_registers.original_lstar;
-                    // This should eventually be replaced with an actual hook address.
-                }
-
-                // If the guest attempts to write back the original LSTAR value we provided,
-                // it could be part of an integrity check. In such a case, we allow the write to go through
-                // but actually write our hook again to maintain control.
-                if msr_value == vm.guest_registers.original_lstar {
-                    // Write the hook LSTAR value if it's set, otherwise write the original value.
-                    // This check is necessary in case the hook_lstar is not yet implemented or set to 0.
-                    let value_to_write = if vm.guest_registers.hook_lstar != 0 {
-                        vm.guest_registers.hook_lstar
-                    } else {
-                        vm.guest_registers.original_lstar
-                    };
-
-                    wrmsr(msr_id, value_to_write);
-                }
-            } else if msr_id == msr::IA32_GS_BASE {
-                // Credits: https://github.com/tandasat/MiniVisorPkg/issues/4#issuecomment-664030968
-                //
-                // Write to this MSR happens at KiSystemStartup for each processor.
-                // We intercept this only once that occurs on the BSP as a trigger
-                // point to initialize the guest agent. We do not emulate this
-                // operation and instead, make the guest retry after returning from
-                // the guest agent. At the 2nd try, VM-exit no longer occurs.
-                //
-                // Note that is place is too early to debug the guest agent. Move to
-                // later such as KeInitAmd64SpecificState for this. This place was
-                // chosen so that none of PatchGuard context is initialized.
-                //
-                // trace!("Unhooking MSR_IA32_GS_BASE.");
-                // vm.msr_bitmap.modify_msr_interception(msr::IA32_GS_BASE, MsrAccessType::Write, MsrOperation::Unhook);
-                // trace!("KiSystemStartup being executed...");
-                wrmsr(msr_id, msr_value);
-            } else {
-                // For MSRs other than msr::IA32_LSTAR or non-original LSTAR value writes, proceed with the write operation.
-                // If the guest writes any other value (which would typically only happen if the guest is attempting to modify the syscall mechanism itself),
-                // the write operation proceeds.
-                wrmsr(msr_id, msr_value);
-            }
+        GuestPersonality::Unknown => return Ok(MsrHookResult::Passthrough),
+    }
+
+    // Lock the shared hook manager
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    // The kernel writes IA32_LSTAR with its own address space, which maps the kernel in every process with KVA shadow.
+    PhysicalAddress::set_kernel_directory_table_base(vmread(vmcs::guest::CR3));
+
+    // Get and set the ntoskrnl.exe base address and size, to be used for hooking later in `CpuidLeaf::CacheInformation` or by the guest client.
+    hook_manager.set_kernel_base_and_size(*value)?;
+
+    // Measure the kernel image the first time its base address is captured.
+    let ntoskrnl = kernel_image();
+    record_kernel_measurement(ntoskrnl.base_va, ntoskrnl.base_pa);
+
+    // Load the exports of the kernel, to symbolize the guest addresses of the events.
+    record_kernel_symbols(ntoskrnl.base_va);
+
+    // Arm the trigger of the boot-time hook manifest, which may hook an export of the kernel.
+    arm_boot_hook_trigger(vm, &mut hook_manager);
+
+    // Check if it's the first time we're intercepting a write to LSTAR.
+    // If so, store the value being written as the original LSTAR value, and point the effective LSTAR to the
+    // syscall trampoline if system calls are hooked.
+    if vm.guest_registers.original_lstar == 0 {
+        vm.guest_registers.original_lstar = *value;
+
+        let mut syscall_hook_manager = SHARED_SYSCALL_HOOK_MANAGER.lock();
+        syscall_hook_manager.initialize_trampoline(ntoskrnl.base_va, ntoskrnl.base_pa, *value);
+        vm.guest_registers.hook_lstar = syscall_hook_manager.effective_lstar(*value);
+    }
+
+    // If the guest attempts to write back the original LSTAR value we provided,
+    // it could be part of an integrity check. In such a case, we allow the write to go through
+    // but actually write our hook again to maintain control.
+    if *value != vm.guest_registers.original_lstar {
+        return Ok(MsrHookResult::Emulated);
+    }
+
+    // Write the hook LSTAR value if it's set, otherwise write the original value.
+    // This check is necessary in case the hook_lstar is not yet implemented or set to 0.
+    *value = if vm.guest_registers.hook_lstar != 0 {
+        vm.guest_registers.hook_lstar
+    } else {
+        vm.guest_registers.original_lstar
+    };
+
+    Ok(MsrHookResult::Passthrough)
+}
+
+/// Handles a read of IA32_FEATURE_CONTROL, built-in hook of the MSR hook registry.
+///
+/// Simulates IA32_FEATURE_CONTROL as locked: VMX locked bit set, VMX outside SMX clear.
+/// Credits to @vmctx
+///
+/// # Arguments
+///
+/// * `_vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `msr` - The accessed MSR, IA32_FEATURE_CONTROL.
+/// * `value` - The value returned to the guest.
+///
+/// # Returns
+///
+/// * `MsrHookResult::Emulated` - The simulated value is returned to the guest.
+pub fn handle_feature_control_read(_vm: &mut Vm, msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError> {
+    // Define the VMX lock bit for IA32_FEATURE_CONTROL MSR
+    const VMX_LOCK_BIT: usize = 0;
+    const VMXON_OUTSIDE_SMX: usize = 2;
+
+    trace!("IA32_FEATURE_CONTROL read attempted");
+
+    *value = rdmsr(msr);
+    value.set_bit(VMX_LOCK_BIT, true);
+    value.set_bit(VMXON_OUTSIDE_SMX, false);
+
+    Ok(MsrHookResult::Emulated)
+}
+
+/// Returns the shadow of a SYSENTER MSR on the current logical processor: its original and hook values, and the VMCS
+/// guest-state field it is loaded from on VM entry.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `msr` - IA32_SYSENTER_EIP or IA32_SYSENTER_ESP.
+fn sysenter_shadow(vm: &mut Vm, msr: u32) -> (&mut u64, &mut u64, u32) {
+    match msr {
+        msr::IA32_SYSENTER_EIP => {
+            (&mut vm.guest_registers.original_sysenter_eip, &mut vm.guest_registers.hook_sysenter_eip, vmcs::guest::IA32_SYSENTER_EIP)
         }
+        _ => (&mut vm.guest_registers.original_sysenter_esp, &mut vm.guest_registers.hook_sysenter_esp, vmcs::guest::IA32_SYSENTER_ESP),
     }
+}
 
-    debug!("MSR VMEXIT handled successfully.");
-    Ok(ExitType::IncrementRIP)
+/// Handles a read of IA32_SYSENTER_EIP or IA32_SYSENTER_ESP, built-in hooks of the MSR hook registry.
+///
+/// Like IA32_LSTAR, the guest reads the shadowed original value instead of the effective value, which is the hook
+/// value when one is set (see `set_sysenter_hook`). Before the original value is captured, the guest value is read
+/// from the VMCS, as the MSR of the logical processor holds the host value in VMX root operation.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `msr` - The accessed MSR, IA32_SYSENTER_EIP or IA32_SYSENTER_ESP.
+/// * `value` - The value returned to the guest.
+///
+/// # Returns
+///
+/// * `MsrHookResult::Emulated` - The original value is returned to the guest.
+pub fn handle_sysenter_read(vm: &mut Vm, msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError> {
+    trace!("SYSENTER MSR {:#x} read attempted", msr);
+
+    let (original, _, guest_field) = sysenter_shadow(vm, msr);
+
+    *value = match *original {
+        0 => vmread(guest_field),
+        original => original,
+    };
+
+    Ok(MsrHookResult::Emulated)
+}
+
+/// Handles a write to IA32_SYSENTER_EIP or IA32_SYSENTER_ESP, built-in hooks of the MSR hook registry.
+///
+/// The first write on each logical processor captures the original value, the 32-bit fast system call entry or its
+/// stack. Unlike IA32_LSTAR, the interception of the writes stays enabled: the guest's writes of the original value,
+/// e.g., integrity checks, re-arm the hook value if one is set, and writes of other values are discarded. The value is
+/// written to the VMCS, which the MSR is loaded from on VM entry.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `msr` - The accessed MSR, IA32_SYSENTER_EIP or IA32_SYSENTER_ESP.
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// * `MsrHookResult::Emulated` - The write has been applied to the VMCS or discarded.
+pub fn handle_sysenter_write(vm: &mut Vm, msr: u32, value: &mut u64) -> Result<MsrHookResult, HypervisorError> {
+    trace!("SYSENTER MSR {:#x} write attempted with MSR value: {:#x}", msr, *value);
+
+    let (original, hook, guest_field) = sysenter_shadow(vm, msr);
+
+    if *original == 0 {
+        *original = *value;
+    }
+
+    if *value != *original {
+        return Ok(MsrHookResult::Emulated);
+    }
+
+    let effective = if *hook != 0 { *hook } else { *original };
+    vmwrite(guest_field, effective);
+
+    Ok(MsrHookResult::Emulated)
+}
+
+/// Sets the hook value of IA32_SYSENTER_EIP or IA32_SYSENTER_ESP on the current logical processor, effective while the
+/// guest keeps reading the original value.
+///
+/// The hook value is written immediately if the original value has been captured, otherwise on the first write of the
+/// guest.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `msr` - IA32_SYSENTER_EIP or IA32_SYSENTER_ESP.
+/// * `hook_value` - The hook value, or 0 to restore the original value.
+pub fn set_sysenter_hook(vm: &mut Vm, msr: u32, hook_value: u64) {
+    debug!("Setting SYSENTER MSR {:#x} hook value: {:#x}", msr, hook_value);
+
+    let (original, hook, guest_field) = sysenter_shadow(vm, msr);
+    *hook = hook_value;
+
+    if *original != 0 {
+        vmwrite(guest_field, if hook_value != 0 { hook_value } else { *original });
+    }
 }
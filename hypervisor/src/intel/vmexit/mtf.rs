@@ -2,158 +2,262 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            addresses::PhysicalAddress,
-            ept::AccessType,
-            hooks::hook_manager::SHARED_HOOK_MANAGER,
-            support::{vmread, vmwrite},
+            ept::{AccessType, Pt},
+            hooks::{
+                hook_manager::{HookManager, SHARED_HOOK_MANAGER},
+                hook_view::HookViewId,
+                memory_manager::HookInfo,
+                tamper::report_hook_tamper,
+            },
+            seqlock::Generation,
+            single_step::{complete_single_step, single_step, SingleStepContext, SingleStepOwner},
             vm::Vm,
             vmexit::ExitType,
         },
     },
     log::*,
-    x86::{current::paging::PAddr, vmx::vmcs},
-    x86_64::registers::rflags::RFlags,
+    x86::current::paging::PAddr,
 };
 
-/// Handles the Monitor Trap Flag (MTF) VM exit.
-///
-/// This function ensures single stepping through overwritten instructions on a hooked function
-/// and restores the original execution flow once all instructions have been executed.
-///
-/// # Parameters
-/// * `vm`: A mutable reference to the virtual machine instance.
-///
-/// # Returns
-/// * `Result<ExitType, HypervisorError>`: Ok with the appropriate exit type or an error.
-pub fn handle_monitor_trap_flag(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
-    trace!("Handling Monitor Trap Flag exit.");
+/// The pages of a hook to restore once its overwritten instructions have been single-stepped, looked up on the hook hit
+/// while the hook manager is locked, so the MTF VM exit restores them without locking it.
+#[derive(Debug, Clone, Copy)]
+pub struct HookRestoration {
+    /// The hooked pages, the second one for a hook crossing the page boundary.
+    pages: [Option<RestoredPage>; 2],
 
-    if let Some(counter) = vm.mtf_counter.as_mut() {
-        trace!("Guest RIP: {:#x}", vm.guest_registers.rip);
-        trace!("MTF counter before decrement: {}", *counter);
-        *counter = counter.saturating_sub(1); // Safely decrement the counter
-        trace!("MTF counter after decremented: {}", *counter);
+    /// The hook view the shadow pages were looked up in.
+    active_view: HookViewId,
 
-        // If the counter is zero, we have completed the single-stepping process, restore the hook.
-        if *counter == 0 {
-            set_monitor_trap_flag(false);
+    /// The process with bound hooks the shadow pages were looked up for.
+    active_process_id: u64,
 
-            let guest_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(vm.guest_registers.rip)?);
-            trace!("Guest PA: {:#x}", guest_pa.as_u64());
+    /// The global generation of the published state when the pages were looked up, a page released to the page pool
+    /// since then being looked up again.
+    published_generation: Generation,
+}
 
-            let guest_page_pa = guest_pa.align_down_to_base_page();
-            trace!("Guest Page PA: {:#x}", guest_page_pa.as_u64());
+/// A hooked page restored after single-stepping.
+#[derive(Debug, Clone, Copy)]
+struct RestoredPage {
+    /// The guest physical address of the page.
+    guest_page_pa: u64,
 
-            let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
-            trace!("Guest Large Page PA: {:#x}", guest_large_page_pa.as_u64());
+    /// The physical address of the shadow page executed in the active view.
+    shadow_page_pa: u64,
 
-            let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    /// The physical address of the page table of the large page containing the page.
+    pt_pa: u64,
+}
 
-            let shadow_page_pa = PAddr::from(
-                hook_manager
-                    .memory_manager
-                    .get_shadow_page_as_ptr(guest_page_pa.as_u64())
-                    .ok_or(HypervisorError::ShadowPageNotFound)?,
-            );
-            trace!("Shadow Page PA: {:#x}", shadow_page_pa);
+impl HookRestoration {
+    /// Looks up the pages of a hook to restore once its overwritten instructions have been single-stepped.
+    ///
+    /// # Parameters
+    /// * `vm`: A reference to the virtual machine instance.
+    /// * `hook_manager`: A mutable reference to the locked hook manager.
+    /// * `context`: The hooked pages restored for single-stepping.
+    ///
+    /// # Returns
+    /// * `Option<HookRestoration>`: The pages, or `None` if one of them isn't found, the MTF VM exit then looking them
+    ///   up again.
+    fn look_up(vm: &Vm, hook_manager: &mut HookManager, context: SingleStepContext) -> Option<Self> {
+        let mut pages = [None; 2];
 
-            let pre_alloc_pt = hook_manager
-                .memory_manager
-                .get_page_table_as_mut(guest_large_page_pa.as_u64())
-                .ok_or(HypervisorError::PageTableNotFound)?;
+        for (page, guest_page_pa) in pages.iter_mut().zip([Some(context.guest_page_pa), context.guest_next_page_pa]) {
+            let Some(guest_page_pa) = guest_page_pa else {
+                continue;
+            };
 
-            // Restore the hook to continue monitoring
-            vm.primary_ept
-                .swap_page(guest_pa.align_down_to_base_page().as_u64(), shadow_page_pa.as_u64(), AccessType::EXECUTE, pre_alloc_pt)?;
+            let shadow_page_pa = hook_manager
+                .get_execute_page_pa(vm.hook_view.active_view, vm.hook_view.active_process_id, guest_page_pa)
+                .ok()?;
+            let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page().as_u64();
+            let pt_pa = hook_manager.memory_manager.get_page_table_as_mut(guest_large_page_pa)? as *mut Pt as u64;
 
-            restore_guest_interrupt_flag(vm)?;
-        } else {
-            set_monitor_trap_flag(true); // Keep MTF enabled if there are more steps
+            *page = Some(RestoredPage {
+                guest_page_pa,
+                shadow_page_pa,
+                pt_pa,
+            });
         }
-    } else {
-        error!("No active MTF counter found, possibly an error in state management.");
-        return Err(HypervisorError::MtfCounterNotSet);
+
+        Some(Self {
+            pages,
+            active_view: vm.hook_view.active_view,
+            active_process_id: vm.hook_view.active_process_id,
+            published_generation: vm.published_generation,
+        })
     }
 
-    Ok(ExitType::Continue)
+    /// Checks if the pages can be restored as they were looked up: the guest didn't write to them, and neither the
+    /// view in use nor the hook pages changed while single-stepping.
+    ///
+    /// # Parameters
+    /// * `vm`: A reference to the virtual machine instance.
+    /// * `context`: The hooked pages restored for single-stepping.
+    fn is_current(&self, vm: &Vm, context: SingleStepContext) -> bool {
+        vm.mtf_hook_write.is_none()
+            && vm.mtf_resync_page.is_none()
+            && self.pages[0].map(|page| page.guest_page_pa) == Some(context.guest_page_pa)
+            && self.pages[1].map(|page| page.guest_page_pa) == context.guest_next_page_pa
+            && self.active_view == vm.hook_view.active_view
+            && self.active_process_id == vm.hook_view.active_process_id
+            && self.published_generation == vm.published_generation
+    }
+
+    /// Swaps the hooked pages back to their shadow pages.
+    ///
+    /// # Parameters
+    /// * `vm`: A mutable reference to the virtual machine instance.
+    fn restore(&self, vm: &mut Vm) -> Result<(), HypervisorError> {
+        for page in self.pages.iter().flatten() {
+            trace!("Restoring hooked page {:#x} to shadow page {:#x}", page.guest_page_pa, page.shadow_page_pa);
+
+            // The page table is owned by the memory manager, and wasn't released since it was looked up.
+            let pre_alloc_pt = unsafe { &mut *(page.pt_pa as *mut Pt) };
+
+            vm.primary_ept
+                .swap_page(page.guest_page_pa, page.shadow_page_pa, AccessType::EXECUTE, pre_alloc_pt)?;
+        }
+
+        Ok(())
+    }
 }
 
-/// Set the monitor trap flag
+/// Handles the Monitor Trap Flag (MTF) VM exit.
 ///
-/// # Arguments
+/// An instruction has been executed while single-stepping, which is counted for the active single-step requests, and
+/// the requests whose instructions have all been executed are completed (see `single_step`).
 ///
-/// * `set` - A flag indicating whether to set the monitor trap flag.
-pub fn set_monitor_trap_flag(set: bool) {
-    let controls = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
-    let mut primary_controls = unsafe { vmcs::control::PrimaryControls::from_bits_unchecked(controls as u32) };
-
-    if set {
-        // Enabling the monitor trap flag
-        primary_controls.insert(vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG);
-    } else {
-        // Disabling the monitor trap flag
-        primary_controls.remove(vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG);
-    }
+/// # Parameters
+/// * `vm`: A mutable reference to the virtual machine instance.
+///
+/// # Returns
+/// * `Result<ExitType, HypervisorError>`: Ok with the appropriate exit type or an error.
+pub fn handle_monitor_trap_flag(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
+    trace!("Handling Monitor Trap Flag exit.");
+    trace!("Guest RIP: {:#x}", vm.guest_registers.rip);
+
+    complete_single_step(vm)?;
 
-    vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
-    trace!("Monitor Trap Flag set to: {}", set);
+    Ok(ExitType::Continue)
 }
 
-/// Sets or clears the Interrupt Flag (IF) in the guest's RFLAGS register based on the specified state,
-/// and stores the old RFLAGS value for potential restoration.
+/// Restores the hooks of the hooked pages once the original instructions have been single-stepped, the completion of
+/// the `SingleStepOwner::HookRestoration` requests.
 ///
 /// # Parameters
 /// * `vm`: A mutable reference to the virtual machine instance.
-/// * `enable`: If `true`, sets the Interrupt Flag; if `false`, clears the Interrupt Flag and optionally restores from old RFLAGS.
+/// * `context`: The hooked pages restored for single-stepping, the second one for a hook crossing the page boundary.
 ///
 /// # Returns
-/// * `Result<(), HypervisorError>`: Ok if successful, Err if an error occurred during VMCS read/write operations.
-pub fn update_guest_interrupt_flag(vm: &mut Vm, enable: bool) -> Result<(), HypervisorError> {
-    trace!("Updating guest interrupt flag...");
-
-    // Retrieve the current RFLAGS from the VMCS guest state area
-    let current_rflags_bits = vmread(vmcs::guest::RFLAGS);
-    let mut current_rflags = RFlags::from_bits_retain(current_rflags_bits);
-    trace!("Current guest RFLAGS before update: {:#x}", current_rflags_bits);
-
-    // Optionally save the current RFLAGS to old_rflags before modification
-    vm.old_rflags = Some(current_rflags_bits);
-
-    // Set or clear the Interrupt Flag based on the 'enable' parameter
-    if enable {
-        current_rflags.insert(RFlags::INTERRUPT_FLAG);
-    } else {
-        current_rflags.remove(RFlags::INTERRUPT_FLAG);
+/// * `Result<(), HypervisorError>`: Ok if the hooks have been restored, or an error.
+pub fn restore_hooked_pages(vm: &mut Vm, context: SingleStepContext) -> Result<(), HypervisorError> {
+    // The hook manager is only locked when the pages looked up on the hook hit may have changed.
+    if let Some(restoration) = vm.mtf_hook_restoration.take() {
+        if restoration.is_current(vm, context) {
+            return restoration.restore(vm);
+        }
+    }
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    // Report the write before the shadow page is resynchronized, while the hooks of the page are unchanged.
+    if let Some(hook_write) = vm.mtf_hook_write.take() {
+        report_hook_tamper(&hook_manager, &hook_write);
+    }
+
+    // The guest has written to the hooked page, copy it to the shadow page again before it is executed.
+    if let Some(resync_page_pa) = vm.mtf_resync_page.take() {
+        debug!("Guest write to hooked page: {:#x}, resynchronizing shadow page", resync_page_pa);
+        hook_manager.resync_shadow_page(PAddr::from(resync_page_pa))?;
     }
 
-    // Update the guest's RFLAGS register in the VMCS
-    vmwrite(vmcs::guest::RFLAGS, current_rflags.bits());
-    trace!("Updated guest RFLAGS: {:#x}", current_rflags.bits());
+    for guest_page_pa in [Some(context.guest_page_pa), context.guest_next_page_pa].into_iter().flatten() {
+        let guest_page_pa = PAddr::from(guest_page_pa);
+        trace!("Guest Page PA: {:#x}", guest_page_pa.as_u64());
 
-    // Synchronize the local VM state if necessary
-    vm.guest_registers.rflags = current_rflags.bits();
+        let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
+        trace!("Guest Large Page PA: {:#x}", guest_large_page_pa.as_u64());
+
+        let shadow_page_pa =
+            PAddr::from(hook_manager.get_execute_page_pa(vm.hook_view.active_view, vm.hook_view.active_process_id, guest_page_pa.as_u64())?);
+        trace!("Shadow Page PA: {:#x}", shadow_page_pa);
+
+        let pre_alloc_pt = hook_manager
+            .memory_manager
+            .get_page_table_as_mut(guest_large_page_pa.as_u64())
+            .ok_or(HypervisorError::PageTableNotFound)?;
+
+        // Restore the hook to continue monitoring
+        vm.primary_ept
+            .swap_page(guest_page_pa.as_u64(), shadow_page_pa.as_u64(), AccessType::EXECUTE, pre_alloc_pt)?;
+    }
 
     Ok(())
 }
 
-/// Restores the guest's RFLAGS register from the saved old RFLAGS.
+/// Starts single-stepping the original instructions overwritten by a hook, after the hook has been hit.
+///
+/// The hooked pages are restored to the original guest pages, and the overwritten instructions are single-stepped so
+/// the hook is restored by `restore_hooked_pages` once they have been executed.
 ///
 /// # Parameters
 /// * `vm`: A mutable reference to the virtual machine instance.
+/// * `hook_manager`: A mutable reference to the locked hook manager.
+/// * `guest_function_pa`: The guest physical address of the hooked function.
 ///
 /// # Returns
-/// * `Result<(), HypervisorError>`: Ok if successful, Err if an error occurred during VMCS read/write operations.
-pub fn restore_guest_interrupt_flag(vm: &mut Vm) -> Result<(), HypervisorError> {
-    if let Some(old_rflags_bits) = vm.old_rflags {
-        trace!("Restoring guest RFLAGS to old value: {:#x}", old_rflags_bits);
+/// * `Result<HookInfo, HypervisorError>`: The information of the hook that has been hit.
+pub fn single_step_hook(vm: &mut Vm, hook_manager: &mut HookManager, guest_function_pa: PAddr) -> Result<HookInfo, HypervisorError> {
+    let guest_page_pa = guest_function_pa.align_down_to_base_page();
+    let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
 
-        // Update VM register state first
-        vm.guest_registers.rflags = old_rflags_bits;
+    let pre_alloc_pt = hook_manager
+        .memory_manager
+        .get_page_table_as_mut(guest_large_page_pa.as_u64())
+        .ok_or(HypervisorError::PageTableNotFound)?;
 
-        // Then write to VMCS
-        vmwrite(vmcs::guest::RFLAGS, old_rflags_bits);
-        Ok(())
-    } else {
-        Err(HypervisorError::OldRflagsNotSet)
+    // Perform swap_page before the mutable borrow for update_guest_interrupt_flag
+    vm.primary_ept
+        .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
+
+    let hook_info = hook_manager
+        .memory_manager
+        .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+        .cloned()
+        .ok_or(HypervisorError::HookInfoNotFound)?;
+
+    debug!("Hook info: {:#x?}", hook_info);
+
+    // A hook crossing the page boundary also overwrites the start of the next page, so restore it as well.
+    if let Some(guest_next_page_pa) = hook_info.guest_next_page_pa {
+        let next_pre_alloc_pt = hook_manager
+            .memory_manager
+            .get_page_table_as_mut(PAddr::from(guest_next_page_pa).align_down_to_large_page().as_u64())
+            .ok_or(HypervisorError::PageTableNotFound)?;
+
+        vm.primary_ept
+            .swap_page(guest_next_page_pa, guest_next_page_pa, AccessType::READ_WRITE_EXECUTE, next_pre_alloc_pt)?;
     }
+
+    // Calculate the number of instructions in the function to set the MTF counter for restoring overwritten instructions by single-stepping.
+    let instruction_count = unsafe {
+        HookManager::calculate_instruction_count(
+            guest_function_pa.as_u64(),
+            hook_info.guest_next_page_pa,
+            HookManager::hook_size(hook_info.ept_hook_type),
+        ) as u64
+    };
+
+    // The hooked pages are swapped back to their shadow pages once the overwritten instructions have been executed.
+    let context = SingleStepContext {
+        guest_page_pa: guest_page_pa.as_u64(),
+        guest_next_page_pa: hook_info.guest_next_page_pa,
+    };
+    vm.mtf_hook_restoration = HookRestoration::look_up(vm, hook_manager, context);
+    single_step(vm, SingleStepOwner::HookRestoration, instruction_count, context, restore_hooked_pages)?;
+
+    Ok(hook_info)
 }
@@ -0,0 +1,135 @@
+//! Handles VM exits caused by the expiry of the VMX-preemption timer, which is only armed while an asynchronous
+//! transfer is in progress, the sampling profiler is running, the watchdog is enabled, periodic tasks are scheduled on
+//! the logical processor or to poll the broadcast commands.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.1 VMX-Preemption Timer
+
+use {
+    crate::intel::{
+        controls::{adjust_vmx_controls, VmxControl},
+        profiler::sample_guest,
+        scheduler::run_due_tasks,
+        support::{rdmsr, rdtsc, vmread, vmwrite},
+        transfer::{advance_async_transfer, TIMER_INTERVAL_TSC_TICKS},
+        vm::Vm,
+        vmexit::ExitType,
+        watchdog::check_guest_progress,
+    },
+    log::*,
+    x86::{
+        msr,
+        vmx::vmcs::{
+            self,
+            control::{ExitControls, PinbasedControls},
+            guest,
+        },
+    },
+};
+
+/// Handles the VMX-preemption timer VM exit by copying the next chunk of the asynchronous transfer, sampling the guest,
+/// checking its progress, running the periodic tasks and polling the broadcast commands if they are due, then re-arming
+/// the timer for the next of them.
+///
+/// The guest is resumed at the same instruction, as the VM exit isn't caused by the guest.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The guest continues execution without advancing RIP.
+pub fn handle_preemption_timer(vm: &mut Vm) -> ExitType {
+    trace!("Handling VMX-preemption timer VM exit...");
+
+    if vm.async_transfer.is_some() {
+        advance_async_transfer(vm);
+    }
+
+    if vm.profiler.is_sample_due(rdtsc()) {
+        sample_guest(vm);
+    }
+
+    if vm.watchdog.is_check_due(rdtsc()) {
+        check_guest_progress(vm);
+    }
+
+    run_due_tasks(vm);
+
+    // The broadcast commands are executed at the end of every VM exit, this one included.
+    if vm.broadcast.is_poll_due(rdtsc()) {
+        vm.broadcast.schedule_next_poll(rdtsc());
+    }
+
+    update_preemption_timer(vm);
+
+    ExitType::Continue
+}
+
+/// Arms the VMX-preemption timer for the next chunk of the asynchronous transfer, the next sample of the profiler, the
+/// next check of the watchdog, the next periodic task or the next poll of the broadcast commands, whichever comes first,
+/// or disarms it if none is active on the current logical processor.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn update_preemption_timer(vm: &Vm) {
+    let tsc = rdtsc();
+
+    let ticks = [
+        vm.async_transfer.map(|_| TIMER_INTERVAL_TSC_TICKS),
+        vm.profiler.ticks_until_next_sample(tsc),
+        vm.watchdog.ticks_until_next_check(tsc),
+        vm.scheduler.ticks_until_next_task(tsc),
+        vm.broadcast.ticks_until_next_poll(tsc),
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+
+    set_preemption_timer(ticks);
+}
+
+/// Returns `true` if the VMX-preemption timer and saving its value on VM exits are supported.
+pub fn is_preemption_timer_supported() -> bool {
+    let pinbased_controls = adjust_vmx_controls(VmxControl::PinBased, PinbasedControls::VMX_PREEMPTION_TIMER.bits() as u64);
+    let exit_controls = adjust_vmx_controls(VmxControl::VmExit, ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits() as u64);
+
+    pinbased_controls & PinbasedControls::VMX_PREEMPTION_TIMER.bits() as u64 != 0
+        && exit_controls & ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits() as u64 != 0
+}
+
+/// Enables or disables the VMX-preemption timer, saving its value on VM exits so the countdown isn't restarted by the
+/// VM exits happening before it expires.
+///
+/// # Arguments
+///
+/// * `ticks` - The number of TSC ticks until the timer expires, or `None` to disarm it.
+fn set_preemption_timer(ticks: Option<u64>) {
+    let mut pinbased_controls = PinbasedControls::from_bits_truncate(vmread(vmcs::control::PINBASED_EXEC_CONTROLS) as u32);
+    let mut exit_controls = ExitControls::from_bits_truncate(vmread(vmcs::control::VMEXIT_CONTROLS) as u32);
+
+    pinbased_controls.set(PinbasedControls::VMX_PREEMPTION_TIMER, ticks.is_some());
+    exit_controls.set(ExitControls::SAVE_VMX_PREEMPTION_TIMER, ticks.is_some());
+
+    if let Some(ticks) = ticks {
+        vmwrite(guest::VMX_PREEMPTION_TIMER_VALUE, preemption_timer_value(ticks));
+    }
+
+    vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, pinbased_controls.bits());
+    vmwrite(vmcs::control::VMEXIT_CONTROLS, exit_controls.bits());
+    trace!("VMX-preemption timer set to: {:?} TSC ticks", ticks);
+}
+
+/// Returns the value of the VMX-preemption timer expiring after a number of TSC ticks.
+///
+/// Bits 4:0 of IA32_VMX_MISC report the rate of the timer: it counts down by 1 every time bit X of the TSC changes.
+/// The timer value is 32 bits wide, so longer intervals are clamped and the timer simply expires early.
+///
+/// # Arguments
+///
+/// * `ticks` - The number of TSC ticks until the timer expires.
+fn preemption_timer_value(ticks: u64) -> u64 {
+    let rate = rdmsr(msr::IA32_VMX_MISC) & 0x1F;
+    (ticks >> rate).clamp(1, u32::MAX as u64)
+}
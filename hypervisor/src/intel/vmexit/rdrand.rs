@@ -0,0 +1,65 @@
+//! Handles the `RDRAND` and `RDSEED` VM exits, which are only enabled while the deterministic mode is enabled, by
+//! returning values of its seeded pseudo-random generator instead of hardware entropy (see the `determinism` module).
+
+use {
+    crate::intel::{
+        determinism::next_entropy,
+        support::{vmread, vmwrite},
+        vm::Vm,
+        vmexit::ExitType,
+    },
+    log::*,
+    x86::vmx::vmcs,
+    x86_64::registers::rflags::RFlags,
+};
+
+/// Handles the `RDRAND` and `RDSEED` VM exits.
+///
+/// The destination register and the operand size are decoded from the VM-exit instruction information. The
+/// register is written with the next pseudo-random value, and CF is set to report success while the other
+/// arithmetic flags are cleared, as the instructions do.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the instruction in the VM.
+/// * `ExitType::Continue` - To re-execute the instruction, if the deterministic mode was disabled meanwhile, as the
+///   VM exits are disabled before the guest is resumed.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-9. Format of the VM-Exit
+/// Instruction-Information Field as Used for RDRAND, RDSEED, TPAUSE, and UMWAIT
+pub fn handle_rdrand(vm: &mut Vm) -> ExitType {
+    trace!("Handling RDRAND/RDSEED VM exit...");
+
+    let Some(entropy) = next_entropy() else {
+        return ExitType::Continue;
+    };
+
+    let instruction_info = vmread(vmcs::ro::VMEXIT_INSTRUCTION_INFO);
+    let register = ((instruction_info >> 3) & 0xF) as usize;
+    let operand_size = (instruction_info >> 11) & 0x3;
+
+    let destination = vm.guest_registers.register_mut(register);
+    *destination = match operand_size {
+        // 16-bit operands leave the upper bits of the register unchanged.
+        0 => (*destination & !0xFFFF) | (entropy & 0xFFFF),
+        // 32-bit operands zero the upper 32 bits of the register.
+        1 => entropy & 0xFFFF_FFFF,
+        _ => entropy,
+    };
+
+    if register == 4 {
+        vmwrite(vmcs::guest::RSP, vm.guest_registers.rsp);
+    }
+
+    let mut rflags = RFlags::from_bits_retain(vm.guest_registers.rflags);
+    rflags.remove(RFlags::OVERFLOW_FLAG | RFlags::SIGN_FLAG | RFlags::ZERO_FLAG | RFlags::AUXILIARY_CARRY_FLAG | RFlags::PARITY_FLAG);
+    rflags.insert(RFlags::CARRY_FLAG);
+    vm.guest_registers.rflags = rflags.bits();
+    vmwrite(vmcs::guest::RFLAGS, vm.guest_registers.rflags);
+
+    ExitType::IncrementRIP
+}
@@ -1,10 +1,21 @@
 //! Handles RDTSC virtualization tasks, specifically intercepting and managing
 //! the `RDTSC` (Read Time-Stamp Counter) instruction in a VM to ensure appropriate time
 //! information is provided to the guest while maintaining the integrity of the hypervisor.
+//!
+//! `RDTSC` and `RDTSCP` only cause VM exits while the deterministic mode is enabled, in which case the virtual TSC
+//! is returned instead of the actual one (see the `determinism` module), or while the TSC compensation intercepts
+//! them, in which case the guest TSC with the time spent in VMX root operation hidden is returned (see the
+//! `tsc_compensation` module).
 
 use {
-    crate::intel::{capture::GuestRegisters, vmexit::ExitType},
-    x86::time::rdtsc,
+    crate::intel::{
+        capture::GuestRegisters,
+        determinism::read_virtual_tsc,
+        support::{rdmsr, rdtsc},
+        tsc_compensation::guest_tsc,
+        vmexit::ExitType,
+    },
+    x86::msr,
 };
 
 /*
@@ -16,7 +27,7 @@ User can add the following later:
 /// Handles the `RDTSC` VM-exit.
 ///
 /// This function is invoked when the guest executes the `RDTSC` instruction.
-/// It reads the current value of the host's time-stamp counter and updates the guest's
+/// It reads the virtual TSC, or the guest's view of the host's time-stamp counter, and updates the guest's
 /// RAX and RDX registers with the low and high 32-bits of the counter, respectively.
 ///
 /// # Arguments
@@ -31,8 +42,8 @@ User can add the following later:
 pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
     log::debug!("Handling RDTSC VM exit...");
 
-    // Read the time stamp counter.
-    let rdtsc_value: u64 = unsafe { rdtsc() };
+    // Read the virtual TSC of the deterministic mode, or the time stamp counter with the TSC offset and multiplier.
+    let rdtsc_value: u64 = read_virtual_tsc().unwrap_or_else(|| guest_tsc(rdtsc()));
 
     // Update the guest's RAX and RDX registers.
     guest_registers.rax = rdtsc_value & 0xFFFFFFFF; // Low 32 bits
@@ -42,3 +53,31 @@ pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
 
     ExitType::IncrementRIP
 }
+
+/// Handles the `RDTSCP` VM-exit.
+///
+/// This function is invoked when the guest executes the `RDTSCP` instruction.
+/// It updates the guest's RAX and RDX registers in the same way as `RDTSC`, and its RCX register
+/// with the value of IA32_TSC_AUX, which isn't intercepted.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `RDTSCP` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 51.
+pub fn handle_rdtscp(guest_registers: &mut GuestRegisters) -> ExitType {
+    log::debug!("Handling RDTSCP VM exit...");
+
+    handle_rdtsc(guest_registers);
+
+    // Update the guest's RCX register with the low 32 bits of IA32_TSC_AUX.
+    guest_registers.rcx = rdmsr(msr::IA32_TSC_AUX) & 0xFFFFFFFF;
+
+    log::debug!("RDTSCP VMEXIT handled successfully!");
+
+    ExitType::IncrementRIP
+}
@@ -7,9 +7,10 @@
 
 use {
     crate::intel::{
-        capture::GuestRegisters,
+        broadcast::join_broadcasts,
         state::GuestActivityState,
         support::{vmread, vmwrite},
+        vm::Vm,
         vmexit::ExitType,
     },
     x86::vmx::vmcs,
@@ -22,22 +23,29 @@ use {
 /// It ensures that subsequent SIPI signals, if any, are ignored once the AP is out of
 /// the wait-for-SIPI state, following VMX and MP initialization protocols.
 ///
+/// The logical processor acknowledges the broadcast commands again from then on.
+///
 /// # Arguments
 ///
-/// - `guest_registers`: A mutable reference to the guest's general-purpose registers. Currently unused.
+/// - `vm`: A mutable reference to the virtual machine (VM) instance.
 ///
 /// # Returns
 ///
 /// Returns `ExitType::Continue` to indicate the VM should continue execution.
-pub fn handle_sipi_signal(guest_registers: &mut GuestRegisters) -> ExitType {
-    let vector = vmread(vmcs::ro::EXIT_QUALIFICATION);
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.1 Basic VM-Exit Information
+pub fn handle_sipi_signal(vm: &mut Vm) -> ExitType {
+    // Bits 7:0 of the exit qualification hold the vector of the SIPI, the page number of the startup code.
+    let vector = vmread(vmcs::ro::EXIT_QUALIFICATION) & 0xFF;
 
     vmwrite(vmcs::guest::CS_SELECTOR, vector << 8);
     vmwrite(vmcs::guest::CS_BASE, vector << 12);
-    guest_registers.rip = 0x0u64;
-    vmwrite(vmcs::guest::RIP, guest_registers.rip);
+    vm.guest_registers.rip = 0x0u64;
+    vmwrite(vmcs::guest::RIP, vm.guest_registers.rip);
 
     vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::Active as u32);
 
+    join_broadcasts(vm);
+
     ExitType::Continue
 }
@@ -0,0 +1,85 @@
+//! Handles VM exits caused by a triple fault of the guest, which would otherwise shut the processor down and make the
+//! chipset reset the platform.
+//!
+//! The state of the guest is logged, then the reset is handled by the `reset` module according to the reset policy.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.2 Other Causes of VM Exits
+
+use {
+    crate::{
+        intel::{
+            events::EventInjection,
+            reset::{handle_processor_reset, reset_platform, ResetSource},
+            state::GuestActivityState,
+            support::{rdmsr, read_effective_guest_cr0, read_effective_guest_cr4, vmread, vmwrite},
+            vm::Vm,
+            vmexit::ExitType,
+        },
+        windows::eprocess::ProcessInformation,
+    },
+    log::*,
+    x86::{msr, vmx::vmcs},
+    x86_64::registers::rflags::RFlags,
+};
+
+/// The bit of IA32_VMX_MISC reporting the support of the shutdown activity state.
+const SHUTDOWN_ACTIVITY_STATE_SUPPORTED: u64 = 1 << 7;
+
+/// The bits of the guest interruptibility state for blocking by STI and by MOV SS, which VM entry requires clear to
+/// enter the HLT activity state.
+const BLOCKING_BY_STI_OR_MOV_SS: u64 = 0b11;
+
+/// Handles the triple fault VM exit by resetting the platform, or by shutting the logical processor down if the reset
+/// policy vetoes it, so the hypervisor keeps running, e.g., to inspect the guest.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The logical processor enters the shutdown state, until an NMI or an INIT wakes it up. Where
+///   the shutdown activity state isn't supported, it halts with the interrupts disabled instead, or the platform is
+///   reset if the guest can't be halted at its privilege level.
+pub fn handle_triple_fault(vm: &mut Vm) -> ExitType {
+    error!("==================== GUEST TRIPLE FAULT ====================");
+    error!(
+        "RIP: {:#x}, RSP: {:#x}, CR0: {:#x}, CR3: {:#x}, CR4: {:#x}, IDTR: {:#x}:{:#x}",
+        vm.guest_registers.rip,
+        vm.guest_registers.rsp,
+        read_effective_guest_cr0(),
+        vmread(vmcs::guest::CR3),
+        read_effective_guest_cr4(),
+        vmread(vmcs::guest::IDTR_BASE),
+        vmread(vmcs::guest::IDTR_LIMIT)
+    );
+
+    if let Some(p) = ProcessInformation::get_current_process_info() {
+        error!("Triple fault in ImageFileName: {}, UniqueProcessId: {}", p.file_name, p.unique_process_id);
+    }
+
+    handle_processor_reset(vm, ResetSource::TripleFault);
+
+    EventInjection::discard_pending_events(vm);
+
+    if rdmsr(msr::IA32_VMX_MISC) & SHUTDOWN_ACTIVITY_STATE_SUPPORTED != 0 {
+        vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::Shutdown as u32);
+        return ExitType::Continue;
+    }
+
+    // VM entry fails with the shutdown activity state when it isn't supported, and with the HLT one outside of ring 0.
+    let cpl = (vmread(vmcs::guest::SS_ACCESS_RIGHTS) >> 5) & 0x3;
+    if cpl != 0 {
+        error!("Shutdown activity state unsupported and guest at CPL {}, resetting the platform", cpl);
+        reset_platform();
+    }
+
+    warn!("Shutdown activity state unsupported, halting the logical processor with the interrupts disabled");
+
+    vm.guest_registers.rflags &= !RFlags::INTERRUPT_FLAG.bits();
+    vmwrite(vmcs::guest::RFLAGS, vm.guest_registers.rflags);
+    vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, vmread(vmcs::guest::INTERRUPTIBILITY_STATE) & !BLOCKING_BY_STI_OR_MOV_SS);
+    vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::Hlt as u32);
+
+    ExitType::Continue
+}
@@ -1,25 +1,67 @@
 //! This crate includes functionalities to handle virtual machine (VM) exit events in a hypervisor environment, particularly focusing on VMCALL instructions
 //! which are used for hypercalls or VM-to-hypervisor communication.
+//!
+//! A VMCALL is either the detour of a hook or a hypercall of a guest agent, identified by `HYPERCALL_MAGIC` in RAX,
+//! which is dispatched to the handler of its number in the hypercall registry. The same hypercalls can be made with
+//! CPUID (see `cpuid::handle_cpuid`), where VMCALL from user mode is undesirable. The hypercalls take their arguments
+//! and return their results in registers (see `shared::Hypercall`), so a guest agent doesn't need a buffer shared
+//! with the hypervisor, unlike the commands of the CPUID interface.
+//!
+//! The hypercalls are authenticated by the session of the guest agent (see `hypercall_auth`) before being dispatched,
+//! the hypercalls failing the authentication raising #UD.
 
 use {
     crate::{
         error::HypervisorError,
         intel::{
             addresses::PhysicalAddress,
-            ept::AccessType,
+            code_snapshot::{code_snapshots_enabled, SHARED_CODE_SNAPSHOTS},
+            event_stream::{register_event_stream, unregister_event_stream},
             events::EventInjection,
-            hooks::hook_manager::{HookManager, SHARED_HOOK_MANAGER},
-            vm::Vm,
-            vmexit::{
-                mtf::{set_monitor_trap_flag, update_guest_interrupt_flag},
-                ExitType,
+            hooks::{
+                callbacks::dispatch_hook_entry, cpuid_hook::SHARED_CPUID_HOOK_MANAGER, hook_manager::SHARED_HOOK_MANAGER,
+                memory_manager::may_be_hooked_page,
             },
+            hypercall_auth::SHARED_HYPERCALL_AUTH,
+            process_tracker::SHARED_PROCESS_TRACKER,
+            support::vmread,
+            vm::Vm,
+            vmexit::{commands::handle_hook_command, mtf::single_step_hook, ExitType},
         },
+        windows::eprocess::ProcessInformation,
     },
+    core::mem::size_of,
     log::*,
-    x86::bits64::paging::PAddr,
+    shared::{
+        Command, DetourType, HookData, Hypercall, HypercallDetour, HypercallFeature, HypercallStatus, HypervisorPresence, HYPERCALL_ABI_VERSION,
+        HYPERCALL_MAGIC,
+    },
+    x86::{bits64::paging::PAddr, vmx::vmcs},
 };
 
+/// The results of a hypercall, returned in RDX, R8 and R9.
+type HypercallResults = [u64; 3];
+
+/// A handler of the hypercall registry, taking the arguments of the hypercall in RDX, R8 and R9.
+type HypercallHandler = fn(vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus>;
+
+/// The hypercall registry, the handler of each hypercall.
+const HYPERCALL_HANDLERS: [(Hypercall, HypercallHandler); 13] = [
+    (Hypercall::QueryStatus, hypercall_query_status),
+    (Hypercall::InstallHook, hypercall_install_hook),
+    (Hypercall::RemoveHook, hypercall_remove_hook),
+    (Hypercall::ReadGuestMemory, hypercall_read_guest_memory),
+    (Hypercall::WriteGuestMemory, hypercall_write_guest_memory),
+    (Hypercall::ToggleFeature, hypercall_toggle_feature),
+    (Hypercall::OpenSession, hypercall_open_session),
+    (Hypercall::CloseSession, hypercall_close_session),
+    (Hypercall::RestrictSession, hypercall_restrict_session),
+    (Hypercall::RegisterEventStream, hypercall_register_event_stream),
+    (Hypercall::UnregisterEventStream, hypercall_unregister_event_stream),
+    (Hypercall::ReadProcessMemory, hypercall_read_process_memory),
+    (Hypercall::WriteProcessMemory, hypercall_write_process_memory),
+];
+
 /// Handles a VMCALL VM exit by executing the corresponding action based on the VMCALL command.
 ///
 /// # Parameters
@@ -48,52 +90,344 @@ pub fn handle_vmcall(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     let guest_page_pa = guest_function_pa.align_down_to_base_page();
     trace!("Guest Page PA: {:#x}", guest_page_pa.as_u64());
 
-    let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
-    trace!("Guest Large Page PA: {:#x}", guest_large_page_pa.as_u64());
-
-    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
-
-    // Set the current hook to the EPT hook for handling MTF exit
-    let exit_type = if let Some(shadow_page_pa) = hook_manager.memory_manager.get_shadow_page_as_ptr(guest_page_pa.as_u64()) {
-        trace!("Shadow Page PA: {:#x}", shadow_page_pa);
-
-        trace!("Executing VMCALL hook on shadow page for EPT hook at PA: {:#x} with VA: {:#x}", guest_function_pa, vm.guest_registers.rip);
-
-        let pre_alloc_pt = hook_manager
+    // A VMCALL outside of the hooked pages, e.g., a hypercall, doesn't lock the hook manager.
+    if may_be_hooked_page(guest_page_pa.as_u64()) {
+        let hook_info = SHARED_HOOK_MANAGER
+            .lock()
             .memory_manager
-            .get_page_table_as_mut(guest_large_page_pa.as_u64())
-            .ok_or(HypervisorError::PageTableNotFound)?;
+            .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+            .cloned();
 
-        // Perform swap_page before the mutable borrow for update_guest_interrupt_flag
-        vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
+        // The lock is released while the callbacks run, so they can use the hook manager themselves.
+        if let Some(hook_info) = hook_info {
+            if dispatch_hook_entry(vm, &hook_info)? {
+                trace!("Hook callback redirected execution to: {:#x}", vm.guest_registers.rip);
+                return Ok(ExitType::Continue);
+            }
+        }
 
-        let hook_info = hook_manager
-            .memory_manager
-            .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
-            .ok_or(HypervisorError::HookInfoNotFound)?;
+        let mut hook_manager = SHARED_HOOK_MANAGER.lock();
 
-        debug!("Hook info: {:#x?}", hook_info);
+        // Set the current hook to the EPT hook for handling MTF exit
+        if let Some(shadow_page_pa) = hook_manager.memory_manager.get_shadow_page_as_ptr(guest_page_pa.as_u64()) {
+            trace!("Shadow Page PA: {:#x}", shadow_page_pa);
 
-        // Calculate the number of instructions in the function to set the MTF counter for restoring overwritten instructions by single-stepping.
-        let instruction_count =
-            unsafe { HookManager::calculate_instruction_count(guest_function_pa.as_u64(), HookManager::hook_size(hook_info.ept_hook_type)) as u64 };
-        vm.mtf_counter = Some(instruction_count);
+            trace!("Executing VMCALL hook on shadow page for EPT hook at PA: {:#x} with VA: {:#x}", guest_function_pa, vm.guest_registers.rip);
 
-        // Set the monitor trap flag and initialize counter to the number of overwritten instructions
-        set_monitor_trap_flag(true);
+            single_step_hook(vm, &mut hook_manager, guest_function_pa)?;
 
-        // Ensure all data mutations to vm are done before calling this.
-        // This function will update the guest interrupt flag to prevent interrupts while single-stepping
-        update_guest_interrupt_flag(vm, false)?;
+            return Ok(ExitType::Continue);
+        }
+    }
 
-        Ok(ExitType::Continue)
+    if vm.guest_registers.rax == HYPERCALL_MAGIC {
+        Ok(handle_hypercall(vm))
     } else {
         // https://www.felixcloutier.com/x86/vmcall
         // #UD: If executed outside VMX operation.
         EventInjection::vmentry_inject_ud();
         Ok(ExitType::Continue)
+    }
+}
+
+/// Handles a hypercall of a guest agent made with VMCALL.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the VMCALL, with the `HypercallStatus` in RAX and the results in RDX, R8
+///   and R9.
+/// * `ExitType::Continue` - With #UD injected, if the hypercall isn't authenticated.
+fn handle_hypercall(vm: &mut Vm) -> ExitType {
+    if dispatch_hypercall(vm) {
+        return ExitType::IncrementRIP;
+    }
+
+    // https://www.felixcloutier.com/x86/vmcall
+    // #UD: If executed outside VMX operation.
+    EventInjection::vmentry_inject_ud();
+    ExitType::Continue
+}
+
+/// Dispatches a hypercall of a guest agent, made with VMCALL or CPUID, to the handler of its number in the hypercall
+/// registry once it has been authenticated: `Hypercall::OpenSession` by the shared secret, and the others by the
+/// session key in R10.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+///
+/// # Returns
+///
+/// `true` if the hypercall has been dispatched, with the `HypercallStatus` in RAX and the results in RDX, R8 and R9,
+/// or `false` with the registers unchanged if it isn't authenticated.
+pub fn dispatch_hypercall(vm: &mut Vm) -> bool {
+    let version = (vm.guest_registers.rcx >> 32) as u32;
+    let number = vm.guest_registers.rcx as u32;
+    let arguments = [vm.guest_registers.rdx, vm.guest_registers.r8, vm.guest_registers.r9];
+    let hypercall = Hypercall::from_u32(number);
+
+    let authenticated = {
+        let mut hypercall_auth = SHARED_HYPERCALL_AUTH.lock();
+        let cr3 = vmread(vmcs::guest::CR3);
+
+        match hypercall {
+            Some(Hypercall::OpenSession) => hypercall_auth.authenticate_secret(vm.guest_registers.rdx, cr3),
+            _ => hypercall_auth.authenticate_hypercall(vm.guest_registers.r10, cr3, vm.guest_registers.rip),
+        }
+    };
+
+    if !authenticated {
+        return false;
+    }
+
+    let handler = hypercall.and_then(|hypercall| {
+        HYPERCALL_HANDLERS
+            .iter()
+            .find(|(registered, _)| *registered == hypercall)
+            .map(|(_, handler)| *handler)
+    });
+
+    let result = match handler {
+        _ if version != HYPERCALL_ABI_VERSION => {
+            debug!("Hypercall {:#x} with ABI version {}, expected {}", number, version, HYPERCALL_ABI_VERSION);
+            Err(HypercallStatus::VersionMismatch)
+        }
+        None => {
+            debug!("Unknown hypercall: {:#x}", number);
+            Err(HypercallStatus::UnknownHypercall)
+        }
+        Some(handler) => handler(vm, arguments),
+    };
+
+    let (status, results) = match result {
+        Ok(results) => (HypercallStatus::Success, results),
+        Err(HypercallStatus::VersionMismatch) => (HypercallStatus::VersionMismatch, [HYPERCALL_ABI_VERSION as u64, 0, 0]),
+        Err(status) => (status, [0; 3]),
+    };
+
+    trace!("Hypercall {:#x} returned {:?}: {:#x?}", number, status, results);
+
+    vm.guest_registers.rax = status as u64;
+    vm.guest_registers.rdx = results[0];
+    vm.guest_registers.r8 = results[1];
+    vm.guest_registers.r9 = results[2];
+
+    true
+}
+
+/// Returns the `HypercallFeature` bits of the enabled features.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+fn enabled_features(vm: &Vm) -> u64 {
+    let mut features = 0;
+
+    if SHARED_CPUID_HOOK_MANAGER.lock().presence() == HypervisorPresence::Exposed {
+        features |= 1 << HypercallFeature::HypervisorPresence as u64;
+    }
+
+    if vm.process_context.is_enabled() {
+        features |= 1 << HypercallFeature::ProcessTracking as u64;
+    }
+
+    if code_snapshots_enabled() {
+        features |= 1 << HypercallFeature::CodeSnapshots as u64;
+    }
+
+    features
+}
+
+/// Handles `Hypercall::QueryStatus`, returning the ABI version, the enabled features and the number of hypercalls.
+fn hypercall_query_status(vm: &mut Vm, _arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    Ok([HYPERCALL_ABI_VERSION as u64, enabled_features(vm), HYPERCALL_HANDLERS.len() as u64])
+}
+
+/// Handles `Hypercall::InstallHook`, installing a kernel hook of a function hash or a syscall number.
+fn hypercall_install_hook(vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let hook = HookData {
+        function_hash: u32::try_from(arguments[0]).map_err(|_| HypercallStatus::InvalidArgument)?,
+        syscall_number: u16::try_from(arguments[1]).map_err(|_| HypercallStatus::InvalidArgument)?,
+        detour_type: HypercallDetour::detour_type_from_u64(arguments[2]).ok_or(HypercallStatus::InvalidArgument)?,
+    };
+
+    handle_hook_command(vm, Command::EnableKernelEptHook, hook).ok_or(HypercallStatus::Failed)?;
+
+    Ok([0; 3])
+}
+
+/// Handles `Hypercall::RemoveHook`, removing the kernel hook of a function hash or a syscall number.
+fn hypercall_remove_hook(vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let hook = HookData {
+        function_hash: u32::try_from(arguments[0]).map_err(|_| HypercallStatus::InvalidArgument)?,
+        syscall_number: u16::try_from(arguments[1]).map_err(|_| HypercallStatus::InvalidArgument)?,
+        // The detour is ignored when disabling a hook.
+        detour_type: DetourType::Vmcall,
+    };
+
+    handle_hook_command(vm, Command::DisableKernelEptHook, hook).ok_or(HypercallStatus::Failed)?;
+
+    Ok([0; 3])
+}
+
+/// Handles `Hypercall::ReadGuestMemory`, reading a `u64` of an address space.
+fn hypercall_read_guest_memory(_vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let [guest_cr3, address, _] = arguments;
+
+    let guest_cr3 = match guest_cr3 {
+        0 => vmread(vmcs::guest::CR3),
+        guest_cr3 => guest_cr3,
     };
 
-    exit_type
+    Ok([read_guest_u64(address, guest_cr3)?, 0, 0])
+}
+
+/// Handles `Hypercall::WriteGuestMemory`, writing a `u64` to an address space.
+fn hypercall_write_guest_memory(_vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let [guest_cr3, address, value] = arguments;
+
+    let guest_cr3 = match guest_cr3 {
+        0 => vmread(vmcs::guest::CR3),
+        guest_cr3 => guest_cr3,
+    };
+
+    write_guest_u64(address, value, guest_cr3)?;
+
+    Ok([0; 3])
+}
+
+/// Handles `Hypercall::ReadProcessMemory`, reading a `u64` from the address space of a process.
+fn hypercall_read_process_memory(_vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let [process_id, address, _] = arguments;
+
+    let guest_cr3 = ProcessInformation::get_directory_table_base_by_process_id(process_id).ok_or(HypercallStatus::InvalidArgument)?;
+
+    Ok([read_guest_u64(address, guest_cr3)?, 0, 0])
+}
+
+/// Handles `Hypercall::WriteProcessMemory`, writing a `u64` to the address space of a process.
+fn hypercall_write_process_memory(_vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let [process_id, address, value] = arguments;
+
+    let guest_cr3 = ProcessInformation::get_directory_table_base_by_process_id(process_id).ok_or(HypercallStatus::InvalidArgument)?;
+
+    write_guest_u64(address, value, guest_cr3)?;
+
+    Ok([0; 3])
+}
+
+/// Reads a `u64` from an address space, which can cross a page boundary.
+///
+/// # Arguments
+///
+/// * `address` - The guest virtual address to read from.
+/// * `guest_cr3` - The guest CR3 of the address space.
+fn read_guest_u64(address: u64, guest_cr3: u64) -> Result<u64, HypercallStatus> {
+    let mut bytes = [0u8; size_of::<u64>()];
+
+    match PhysicalAddress::read_guest_virt_bytes_with_explicit_cr3(address, &mut bytes, guest_cr3) == bytes.len() {
+        true => Ok(u64::from_le_bytes(bytes)),
+        false => Err(HypercallStatus::Failed),
+    }
+}
+
+/// Writes a `u64` to an address space, which can cross a page boundary.
+///
+/// # Arguments
+///
+/// * `address` - The guest virtual address to write to.
+/// * `value` - The value to write.
+/// * `guest_cr3` - The guest CR3 of the address space.
+fn write_guest_u64(address: u64, value: u64, guest_cr3: u64) -> Result<(), HypercallStatus> {
+    let bytes = value.to_le_bytes();
+
+    match PhysicalAddress::write_guest_virt_bytes_with_explicit_cr3(address, &bytes, guest_cr3) == bytes.len() {
+        true => Ok(()),
+        false => Err(HypercallStatus::Failed),
+    }
+}
+
+/// Handles `Hypercall::ToggleFeature`, enabling or disabling a feature and returning whether it was enabled.
+fn hypercall_toggle_feature(vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let [feature, enable, parameter] = arguments;
+
+    let feature = HypercallFeature::from_u64(feature).ok_or(HypercallStatus::InvalidArgument)?;
+    let enable = enable != 0;
+    let was_enabled = enabled_features(vm) & (1 << feature as u64) != 0;
+
+    debug!("Hypercall toggling {:?}: {}", feature, enable);
+
+    match feature {
+        HypercallFeature::HypervisorPresence => SHARED_CPUID_HOOK_MANAGER
+            .lock()
+            .set_presence(match enable {
+                true => HypervisorPresence::Exposed,
+                false => HypervisorPresence::Hidden,
+            })
+            .map_err(|_| HypercallStatus::Failed)?,
+        HypercallFeature::ProcessTracking => SHARED_PROCESS_TRACKER.lock().configure(enable).map_err(|_| HypercallStatus::Failed)?,
+        HypercallFeature::CodeSnapshots => SHARED_CODE_SNAPSHOTS
+            .lock()
+            .configure(enable, parameter)
+            .map_err(|_| HypercallStatus::InvalidArgument)?,
+    }
+
+    Ok([was_enabled as u64, 0, 0])
+}
+
+/// Handles `Hypercall::OpenSession`, returning the key of the new session.
+fn hypercall_open_session(_vm: &mut Vm, _arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let key = SHARED_HYPERCALL_AUTH.lock().open_session().map_err(|e| {
+        error!("Failed to open hypercall session: {:?}", e);
+        HypercallStatus::Failed
+    })?;
+
+    Ok([key, 0, 0])
+}
+
+/// Handles `Hypercall::CloseSession`.
+fn hypercall_close_session(_vm: &mut Vm, _arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    SHARED_HYPERCALL_AUTH.lock().close_session();
+
+    Ok([0; 3])
+}
+
+/// Handles `Hypercall::RestrictSession`, restricting the session to the address space of the caller and a range of
+/// caller RIPs.
+fn hypercall_restrict_session(_vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let [restrict_cr3, rip_start, rip_end] = arguments;
+
+    if rip_end != 0 && rip_start >= rip_end {
+        return Err(HypercallStatus::InvalidArgument);
+    }
+
+    let cr3 = (restrict_cr3 != 0).then(|| vmread(vmcs::guest::CR3));
+    let rip_range = (rip_end != 0).then_some(rip_start..rip_end);
+
+    SHARED_HYPERCALL_AUTH.lock().restrict_session(cr3, rip_range);
+
+    Ok([0; 3])
+}
+
+/// Handles `Hypercall::RegisterEventStream`, registering the event stream buffer and returning the capacity of its ring.
+fn hypercall_register_event_stream(_vm: &mut Vm, arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    let [buffer_pa, size, kinds] = arguments;
+
+    let capacity = register_event_stream(buffer_pa, size, kinds).map_err(|e| {
+        error!("Failed to register event stream: {:?}", e);
+        HypercallStatus::InvalidArgument
+    })?;
+
+    Ok([capacity, 0, 0])
+}
+
+/// Handles `Hypercall::UnregisterEventStream`, unregistering the event stream buffer.
+fn hypercall_unregister_event_stream(_vm: &mut Vm, _arguments: [u64; 3]) -> Result<HypercallResults, HypercallStatus> {
+    unregister_event_stream();
+
+    Ok([0; 3])
 }
@@ -1,20 +1,22 @@
 //! Provides handlers for managing VM exits due to the XSETBV instruction, ensuring
-//! controlled manipulation of the XCR0 register by guest VMs.
+//! controlled manipulation of the XCR0 register by guest VMs, as allowed by the XSAVE policy
+//! (see the `xsave_policy` module).
 
 use {
     crate::intel::{
         events::EventInjection,
-        support::{cr4, cr4_write, xsetbv},
+        support::{cr4, cr4_write, xgetbv, xsetbv},
         vm::Vm,
         vmexit::ExitType,
+        xsave_policy::audit_xcr0_transition,
     },
     core::arch::x86_64::_XCR_XFEATURE_ENABLED_MASK,
     x86_64::registers::{control::Cr4Flags, xcontrol::XCr0Flags},
 };
 
 /// Manages the XSETBV instruction during a VM exit. It logs the event, updates
-/// CR4 to enable the necessary feature, sets the XCR0 value, and advances the
-/// guest's instruction pointer.
+/// CR4 to enable the necessary feature, audits the transition against the XSAVE
+/// policy, sets the XCR0 value, and advances the guest's instruction pointer.
 ///
 /// # Arguments
 ///
@@ -64,6 +66,12 @@ pub fn handle_xsetbv(vm: &mut Vm) -> ExitType {
     // Enable the OS XSAVE feature in CR4 before setting the extended control register value.
     cr4_write(cr4() | Cr4Flags::OSXSAVE.bits());
 
+    // Make sure the guest is not trying to enable a state component denied by the XSAVE policy.
+    if !audit_xcr0_transition(vm, xgetbv(), value_raw) {
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
     // Write the value to the specified XCR (extended control register).
     xsetbv(value_raw);
 
@@ -0,0 +1,655 @@
+//! Intel® Virtualization Technology for Directed I/O (VT-d): DMA Remapping
+//!
+//! Programs the DMA remapping hardware units (IOMMU) so that device DMA goes through second-level page tables
+//! that identity map all physical memory up to the highest address of the memory map, except the ranges owned by the
+//! hypervisor. Without this, a malicious or
+//! compromised device could read or patch host structures that EPT hiding cannot protect.
+//!
+//! Note that an operating system that enables its own DMA protection (e.g., Windows Kernel DMA Protection) will
+//! reprogram the remapping hardware units and replace these tables. Hiding the DMAR table from the guest prevents this
+//! (see `AcpiTables::hide_table`).
+//!
+//! Reference: Intel® Virtualization Technology for Directed I/O Architecture Specification
+
+use {
+    crate::{
+        acpi::{DMAR_SIGNATURE, SHARED_ACPI_TABLES},
+        allocator::box_zeroed,
+        error::HypervisorError,
+        intel::{host_config::SHARED_HOST_CONFIG, support::wbinvd},
+    },
+    alloc::{boxed::Box, collections::BTreeMap, vec::Vec},
+    bitfield::bitfield,
+    core::ptr::{addr_of, read_unaligned, read_volatile, write_volatile},
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::bits64::paging::{PAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+};
+
+/// Offset of the Capability Register.
+const CAP_REG: u64 = 0x08;
+
+/// Offset of the Extended Capability Register.
+const ECAP_REG: u64 = 0x10;
+
+/// Offset of the Global Command Register.
+const GCMD_REG: u64 = 0x18;
+
+/// Offset of the Global Status Register.
+const GSTS_REG: u64 = 0x1C;
+
+/// Offset of the Root Table Address Register.
+const RTADDR_REG: u64 = 0x20;
+
+/// Offset of the Context Command Register.
+const CCMD_REG: u64 = 0x28;
+
+/// [Bit 31] Translation Enable (TE) in the Global Command and Status Registers.
+const GCMD_TE: u32 = 1 << 31;
+
+/// [Bit 30] Set Root Table Pointer (SRTP) in the Global Command and Status Registers.
+const GCMD_SRTP: u32 = 1 << 30;
+
+/// [Bit 27] Write Buffer Flush (WBF) in the Global Command Register, and its status (WBFS) in the Global Status Register.
+const GCMD_WBF: u32 = 1 << 27;
+
+/// The bits of the Global Status Register that must be preserved when issuing a one-shot command.
+///
+/// Reference: Intel® Virtualization Technology for Directed I/O Architecture Specification: 11.4.4 Global Command Register
+const GSTS_PRESERVE_MASK: u32 = 0x96FF_FFFF;
+
+/// [Bit 63] Invalidate Context-Cache (ICC) and [Bits 62:61] Global Invalidation Request in the Context Command Register.
+const CCMD_GLOBAL_INVALIDATION: u64 = (1 << 63) | (1 << 61);
+
+/// [Bit 63] Invalidate IOTLB (IVT) and [Bits 61:60] Global Invalidation Request in the IOTLB Invalidate Register.
+const IOTLB_GLOBAL_INVALIDATION: u64 = (1 << 63) | (1 << 60);
+
+/// [Bit 4] Required Write-Buffer Flushing (RWBF) in the Capability Register.
+const CAP_RWBF: u64 = 1 << 4;
+
+/// [Bit 7] Caching Mode (CM) in the Capability Register, set if not-present entries may be cached.
+const CAP_CACHING_MODE: u64 = 1 << 7;
+
+/// [Bit 10] SAGAW bit indicating support for a 48-bit address width with a 4-level page table.
+const CAP_SAGAW_4_LEVEL: u64 = 1 << 10;
+
+/// [Bit 0] Page-walk Coherency (C) in the Extended Capability Register.
+const ECAP_COHERENCY: u64 = 1 << 0;
+
+/// The domain identifier used for all devices.
+const DOMAIN_ID: u64 = 1;
+
+/// The memory identity mapped at least, so the MMIO below it stays reachable by peer-to-peer DMA on small systems.
+const MIN_IDENTITY_MAP_SIZE: u64 = 512 * HUGE_PAGE_SIZE as u64;
+
+/// The memory a 4-level second-level page table can map (48-bit address width).
+const MAX_IDENTITY_MAP_SIZE: u64 = 1 << 48;
+
+/// The maximum number of polling iterations before a command is considered to have timed out.
+const MAX_POLL_ITERATIONS: usize = 0x100_0000;
+
+lazy_static! {
+    /// A globally shared instance of `DmaRemapping`, protected by a mutex.
+    ///
+    /// This is `None` until `enable_dma_protection` has programmed the remapping hardware units.
+    pub static ref SHARED_DMA_REMAPPING: Mutex<Option<DmaRemapping>> = Mutex::new(None);
+}
+
+/// Discovers the DMA remapping hardware units through the ACPI DMAR table (see `SHARED_ACPI_TABLES`), builds the second-level page tables
+/// excluding the memory ranges recorded in `SHARED_HOST_CONFIG` and enables DMA remapping on every unit.
+///
+/// # Arguments
+///
+/// * `highest_pa` - The highest physical address of the memory map, up to which memory is identity mapped.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if DMA remapping has been enabled on all units, otherwise `Err(HypervisorError)`.
+pub fn enable_dma_protection(highest_pa: u64) -> Result<(), HypervisorError> {
+    let register_bases = find_remapping_units()?;
+    debug!("Found {} DMA remapping hardware units", register_bases.len());
+
+    let mut dma_remapping = DmaRemapping::new(register_bases, highest_pa);
+
+    for (start, size) in SHARED_HOST_CONFIG.read().allocated_memory_ranges.iter() {
+        dma_remapping.protect_range(*start as u64, *size as u64);
+    }
+
+    dma_remapping.enable()?;
+
+    *SHARED_DMA_REMAPPING.lock() = Some(dma_remapping);
+
+    Ok(())
+}
+
+/// Excludes an additional hypervisor-owned memory range from device DMA, if DMA protection is enabled.
+///
+/// # Arguments
+///
+/// * `start` - The start physical address of the range.
+/// * `size` - The size of the range in bytes.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the range is protected or DMA protection is not enabled, otherwise `Err(HypervisorError)`.
+pub fn protect_hypervisor_range(start: u64, size: u64) -> Result<(), HypervisorError> {
+    match SHARED_DMA_REMAPPING.lock().as_mut() {
+        Some(dma_remapping) => {
+            dma_remapping.protect_range(start, size);
+            dma_remapping.invalidate()
+        }
+        None => Ok(()),
+    }
+}
+
+/// Checks whether devices can access a physical memory range by DMA, e.g., the pages a controller driven by the
+/// hypervisor reads from.
+///
+/// # Arguments
+///
+/// * `start` - The start physical address of the range.
+/// * `size` - The size of the range in bytes.
+///
+/// # Returns
+///
+/// Returns `true` if every page of the range is accessible or DMA protection is not enabled, otherwise `false`.
+pub fn is_dma_accessible(start: u64, size: u64) -> bool {
+    match SHARED_DMA_REMAPPING.lock().as_ref() {
+        Some(dma_remapping) => {
+            let start = PAddr::from(start).align_down_to_base_page().as_u64();
+            (start..start + size).step_by(BASE_PAGE_SIZE).all(|pa| dma_remapping.is_accessible(pa))
+        }
+        None => true,
+    }
+}
+
+/// Represents the DMA remapping structures shared by all remapping hardware units.
+///
+/// All devices on all buses are assigned to a single domain whose second-level page tables identity map physical memory
+/// up to the highest address of the memory map (at least 512GB), with the pages owned by the hypervisor marked as not present.
+pub struct DmaRemapping {
+    /// The root table, with one entry per bus, all pointing to the same context table.
+    root_table: Box<RootTable>,
+
+    /// The context table, with one entry per device and function.
+    context_table: Box<ContextTable>,
+
+    /// The second-level PML4 table.
+    pml4: Box<Table>,
+
+    /// The second-level Page Directory Pointer Tables (PDPT), one per 512GB.
+    pdpt: Vec<Box<Table>>,
+
+    /// The second-level Page Directory Tables (PDT), mapping 2MB pages, one per 1GB.
+    pd: Vec<Box<Table>>,
+
+    /// The second-level Page Tables (PT) of split 2MB pages, keyed by the physical address of the 2MB page.
+    pt: BTreeMap<u64, Box<Table>>,
+
+    /// The size of the identity-mapped memory, anything above is inaccessible to devices.
+    identity_map_size: u64,
+
+    /// Whether present entries have been modified or made not present since the last invalidation.
+    has_modified_entries: bool,
+
+    /// Whether not-present entries have been made present since the last invalidation, which only matters to the units
+    /// in caching mode.
+    has_new_entries: bool,
+
+    /// The DMA remapping hardware units.
+    units: Vec<RemappingUnit>,
+}
+
+/// A DMA remapping hardware unit.
+#[derive(Debug, Clone, Copy)]
+struct RemappingUnit {
+    /// The register base address of the unit.
+    register_base: u64,
+
+    /// The value of the Capability Register of the unit.
+    cap: u64,
+
+    /// The value of the Extended Capability Register of the unit.
+    ecap: u64,
+}
+
+unsafe impl Send for DmaRemapping {}
+
+impl DmaRemapping {
+    /// Creates the DMA remapping structures with an identity map of the physical memory up to an address.
+    ///
+    /// # Arguments
+    ///
+    /// * `register_bases` - The register base addresses of the DMA remapping hardware units.
+    /// * `highest_pa` - The highest physical address of the memory map, rounded up to 1GB and clamped between 512GB
+    ///   and the 48-bit address width.
+    pub fn new(register_bases: Vec<u64>, highest_pa: u64) -> Self {
+        let identity_map_size = highest_pa
+            .next_multiple_of(HUGE_PAGE_SIZE as u64)
+            .clamp(MIN_IDENTITY_MAP_SIZE, MAX_IDENTITY_MAP_SIZE);
+        debug!("Identity mapping {:#x} bytes for device DMA", identity_map_size);
+
+        let units = register_bases
+            .into_iter()
+            .map(|register_base| unsafe {
+                RemappingUnit {
+                    register_base,
+                    cap: read_register_u64(register_base, CAP_REG),
+                    ecap: read_register_u64(register_base, ECAP_REG),
+                }
+            })
+            .collect();
+
+        let pd_count = (identity_map_size / HUGE_PAGE_SIZE as u64) as usize;
+
+        let mut dma_remapping = unsafe {
+            DmaRemapping {
+                root_table: box_zeroed::<RootTable>(),
+                context_table: box_zeroed::<ContextTable>(),
+                pml4: box_zeroed::<Table>(),
+                pdpt: (0..pd_count.div_ceil(512)).map(|_| box_zeroed::<Table>()).collect(),
+                pd: (0..pd_count).map(|_| box_zeroed::<Table>()).collect(),
+                pt: BTreeMap::new(),
+                identity_map_size,
+                has_modified_entries: false,
+                has_new_entries: false,
+                units,
+            }
+        };
+
+        dma_remapping.build_identity();
+
+        dma_remapping
+    }
+
+    /// Builds the identity-mapped second-level page tables and points all root and context entries to them.
+    fn build_identity(&mut self) {
+        for (pml4e, pdpt) in self.pml4.entries.iter_mut().zip(self.pdpt.iter()) {
+            pml4e.set_readable(true);
+            pml4e.set_writable(true);
+            pml4e.set_pfn(addr_of!(**pdpt) as u64 >> BASE_PAGE_SHIFT);
+        }
+
+        let mut pa = 0u64;
+
+        for (i, pd) in self.pd.iter_mut().enumerate() {
+            let pdpte = &mut self.pdpt[i / 512].entries[i % 512];
+            pdpte.set_readable(true);
+            pdpte.set_writable(true);
+            pdpte.set_pfn(addr_of!(**pd) as u64 >> BASE_PAGE_SHIFT);
+
+            for pde in pd.entries.iter_mut() {
+                pde.set_readable(true);
+                pde.set_writable(true);
+                pde.set_large(true);
+                pde.set_pfn(pa >> BASE_PAGE_SHIFT);
+                pa += LARGE_PAGE_SIZE as u64;
+            }
+        }
+
+        let context_table_pa = addr_of!(*self.context_table) as u64;
+        let pml4_pa = addr_of!(*self.pml4) as u64;
+
+        // Present (bit 0) and the context table pointer (bits 63:12).
+        self.root_table.entries.iter_mut().for_each(|root_entry| {
+            root_entry.lo = context_table_pa | 1;
+            root_entry.hi = 0;
+        });
+
+        // Present (bit 0), translation type 00b (untranslated requests only) and the second-level page table pointer (bits 63:12).
+        // Address width 010b (48-bit, 4-level page table) and the domain identifier (bits 23:8).
+        self.context_table.entries.iter_mut().for_each(|context_entry| {
+            context_entry.lo = pml4_pa | 1;
+            context_entry.hi = 0b010 | (DOMAIN_ID << 8);
+        });
+    }
+
+    /// Marks every 4KB page of a physical memory range as not present for device DMA.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The start physical address of the range.
+    /// * `size` - The size of the range in bytes.
+    pub fn protect_range(&mut self, start: u64, size: u64) {
+        trace!("Protecting range from DMA: {:#x} - {:#x}", start, start + size);
+
+        let start = PAddr::from(start).align_down_to_base_page().as_u64();
+
+        for pa in (start..start + size).step_by(BASE_PAGE_SIZE) {
+            let pa = PAddr::from(pa);
+
+            // Anything above the identity map is already inaccessible.
+            if pa.as_u64() >= self.identity_map_size {
+                break;
+            }
+
+            let large_page_pa = pa.align_down_to_large_page().as_u64();
+            let pt = self.split_2mb_to_4kb(large_page_pa);
+            let pte = &mut pt.entries[(pa.as_u64() as usize >> BASE_PAGE_SHIFT) & 0x1FF];
+
+            if pte.readable() || pte.writable() {
+                pte.set_readable(false);
+                pte.set_writable(false);
+                self.has_modified_entries = true;
+            }
+        }
+    }
+
+    /// Checks whether a 4KB page is readable and writable by device DMA.
+    ///
+    /// # Arguments
+    ///
+    /// * `pa` - The physical address of the page.
+    fn is_accessible(&self, pa: u64) -> bool {
+        if pa >= self.identity_map_size {
+            return false;
+        }
+
+        let large_page_pa = PAddr::from(pa).align_down_to_large_page().as_u64();
+        let pde = &self.pd[large_page_pa as usize >> 30].entries[(large_page_pa as usize >> 21) & 0x1FF];
+
+        let entry = match self.pt.get(&large_page_pa) {
+            Some(pt) if !pde.large() => &pt.entries[(pa as usize >> BASE_PAGE_SHIFT) & 0x1FF],
+            _ => pde,
+        };
+
+        entry.readable() && entry.writable()
+    }
+
+    /// Splits a 2MB page into 512 4KB pages, if it hasn't been split already.
+    ///
+    /// # Arguments
+    ///
+    /// * `large_page_pa` - The physical address of the 2MB page.
+    ///
+    /// # Returns
+    ///
+    /// A mutable reference to the page table mapping the 2MB page.
+    fn split_2mb_to_4kb(&mut self, large_page_pa: u64) -> &mut Table {
+        // The page directories are indexed by 1GB across all the PDPTs.
+        let pd_table_index = large_page_pa as usize >> 30;
+        let pd_index = (large_page_pa as usize >> 21) & 0x1FF;
+        let pde = &mut self.pd[pd_table_index].entries[pd_index];
+
+        let pt = self.pt.entry(large_page_pa).or_insert_with(|| {
+            let mut pt = unsafe { box_zeroed::<Table>() };
+
+            for (i, pte) in pt.entries.iter_mut().enumerate() {
+                pte.set_readable(true);
+                pte.set_writable(true);
+                pte.set_pfn((large_page_pa + (i * BASE_PAGE_SIZE) as u64) >> BASE_PAGE_SHIFT);
+            }
+
+            pt
+        });
+
+        // The 2MB page is replaced by a page table of new present entries.
+        if pde.large() {
+            *pde = Entry(0);
+            pde.set_readable(true);
+            pde.set_writable(true);
+            pde.set_pfn(addr_of!(**pt) as u64 >> BASE_PAGE_SHIFT);
+            self.has_modified_entries = true;
+            self.has_new_entries = true;
+        }
+
+        pt
+    }
+
+    /// Enables DMA remapping on all remapping hardware units.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if DMA remapping has been enabled on all units, otherwise `Err(HypervisorError)`.
+    ///
+    /// Reference: Intel® Virtualization Technology for Directed I/O Architecture Specification: 11.4 Register Descriptions
+    pub fn enable(&mut self) -> Result<(), HypervisorError> {
+        // Make sure the tables are visible to units that don't snoop the processor caches.
+        wbinvd();
+
+        for unit in self.units.iter() {
+            trace!("Remapping unit {:#x} capabilities: {:#x}, {:#x}", unit.register_base, unit.cap, unit.ecap);
+
+            if unit.cap & CAP_SAGAW_4_LEVEL == 0 {
+                error!("Remapping unit {:#x} does not support 4-level page tables", unit.register_base);
+                return Err(HypervisorError::DmaRemappingUnsupported);
+            }
+
+            unsafe {
+                if unit.cap & CAP_RWBF != 0 {
+                    flush_write_buffer(unit.register_base)?;
+                }
+
+                write_register_u64(unit.register_base, RTADDR_REG, addr_of!(*self.root_table) as u64);
+                issue_global_command(unit.register_base, GCMD_SRTP)?;
+
+                // Also drops the not-present entries cached by a unit in caching mode before the tables were set.
+                invalidate_context_cache(unit.register_base)?;
+                invalidate_iotlb(unit.register_base)?;
+
+                issue_global_command(unit.register_base, GCMD_TE)?;
+            }
+
+            debug!("DMA remapping enabled on remapping unit {:#x}", unit.register_base);
+        }
+
+        self.has_modified_entries = false;
+        self.has_new_entries = false;
+
+        Ok(())
+    }
+
+    /// Makes the changes to the page tables visible to all remapping hardware units.
+    ///
+    /// The write buffer of the units requiring it is flushed. The IOTLB is invalidated after present entries have been
+    /// modified or made not present, and also after not-present entries have been made present on the units in caching
+    /// mode, which may cache not-present entries.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the invalidation completed on all units, otherwise `Err(HypervisorError)`.
+    ///
+    /// Reference: Intel® Virtualization Technology for Directed I/O Architecture Specification: 6.1 Caching Mode
+    pub fn invalidate(&mut self) -> Result<(), HypervisorError> {
+        for unit in self.units.iter() {
+            if unit.ecap & ECAP_COHERENCY == 0 {
+                wbinvd();
+            }
+
+            if unit.cap & CAP_RWBF != 0 {
+                unsafe { flush_write_buffer(unit.register_base)? };
+            }
+
+            let is_caching_mode = unit.cap & CAP_CACHING_MODE != 0;
+
+            if self.has_modified_entries || (self.has_new_entries && is_caching_mode) {
+                unsafe { invalidate_iotlb(unit.register_base)? };
+            }
+        }
+
+        self.has_modified_entries = false;
+        self.has_new_entries = false;
+
+        Ok(())
+    }
+}
+
+/// Issues a one-shot command through the Global Command Register and waits for it to complete.
+///
+/// # Arguments
+///
+/// * `register_base` - The register base address of the remapping hardware unit.
+/// * `command` - The command bit to set.
+unsafe fn issue_global_command(register_base: u64, command: u32) -> Result<(), HypervisorError> {
+    let status = read_register_u32(register_base, GSTS_REG) & GSTS_PRESERVE_MASK;
+    write_register_u32(register_base, GCMD_REG, status | command);
+
+    poll(|| read_register_u32(register_base, GSTS_REG) & command != 0)
+}
+
+/// Flushes the write buffer of a remapping hardware unit and waits for it to complete, so the updates of the tables
+/// reach the memory before the unit walks them.
+///
+/// # Arguments
+///
+/// * `register_base` - The register base address of the remapping hardware unit.
+unsafe fn flush_write_buffer(register_base: u64) -> Result<(), HypervisorError> {
+    let status = read_register_u32(register_base, GSTS_REG) & GSTS_PRESERVE_MASK;
+    write_register_u32(register_base, GCMD_REG, status | GCMD_WBF);
+
+    // The status is cleared once the flush is complete.
+    poll(|| read_register_u32(register_base, GSTS_REG) & GCMD_WBF == 0)
+}
+
+/// Performs a global invalidation of the context-cache.
+///
+/// # Arguments
+///
+/// * `register_base` - The register base address of the remapping hardware unit.
+unsafe fn invalidate_context_cache(register_base: u64) -> Result<(), HypervisorError> {
+    write_register_u64(register_base, CCMD_REG, CCMD_GLOBAL_INVALIDATION);
+    poll(|| read_register_u64(register_base, CCMD_REG) & (1 << 63) == 0)
+}
+
+/// Performs a global invalidation of the IOTLB.
+///
+/// # Arguments
+///
+/// * `register_base` - The register base address of the remapping hardware unit.
+unsafe fn invalidate_iotlb(register_base: u64) -> Result<(), HypervisorError> {
+    // The IOTLB registers are located at the offset reported by ECAP.IRO (bits 17:8) in 16-byte units.
+    // The IOTLB Invalidate Register follows the Invalidate Address Register.
+    let ecap = read_register_u64(register_base, ECAP_REG);
+    let iotlb_reg = ((ecap >> 8) & 0x3FF) * 16 + 0x08;
+
+    write_register_u64(register_base, iotlb_reg, IOTLB_GLOBAL_INVALIDATION);
+    poll(|| read_register_u64(register_base, iotlb_reg) & (1 << 63) == 0)
+}
+
+/// Polls until the condition is met or the maximum number of iterations has been reached.
+fn poll(condition: impl Fn() -> bool) -> Result<(), HypervisorError> {
+    for _ in 0..MAX_POLL_ITERATIONS {
+        if condition() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+
+    Err(HypervisorError::DmaRemappingTimeout)
+}
+
+unsafe fn read_register_u32(register_base: u64, offset: u64) -> u32 {
+    read_volatile((register_base + offset) as *const u32)
+}
+
+unsafe fn write_register_u32(register_base: u64, offset: u64, value: u32) {
+    write_volatile((register_base + offset) as *mut u32, value)
+}
+
+unsafe fn read_register_u64(register_base: u64, offset: u64) -> u64 {
+    read_volatile((register_base + offset) as *const u64)
+}
+
+unsafe fn write_register_u64(register_base: u64, offset: u64, value: u64) {
+    write_volatile((register_base + offset) as *mut u64, value)
+}
+
+/// Finds the register base addresses of all DMA remapping hardware units by parsing the ACPI DMAR table.
+///
+/// # Returns
+///
+/// A `Result<Vec<u64>, HypervisorError>` containing the register base address of each DMA Remapping Hardware Unit Definition (DRHD).
+///
+/// Reference: Intel® Virtualization Technology for Directed I/O Architecture Specification: 8.1 DMA Remapping Reporting Structure
+fn find_remapping_units() -> Result<Vec<u64>, HypervisorError> {
+    /// The offset of the remapping structures in the DMAR table.
+    const DMAR_REMAPPING_STRUCTURES_OFFSET: u64 = 48;
+
+    /// The type of the DMA Remapping Hardware Unit Definition (DRHD) structure.
+    const DRHD_TYPE: u16 = 0;
+
+    let dmar = SHARED_ACPI_TABLES
+        .read()
+        .find_table(DMAR_SIGNATURE)
+        .ok_or(HypervisorError::AcpiTableNotFound)?;
+
+    trace!("DMAR table found at {:#x}", dmar.pa);
+
+    let mut offset = DMAR_REMAPPING_STRUCTURES_OFFSET;
+    let mut register_bases = Vec::new();
+
+    while offset + 4 <= dmar.length as u64 {
+        let structure_type = unsafe { read_unaligned((dmar.pa + offset) as *const u16) };
+        let structure_length = unsafe { read_unaligned((dmar.pa + offset + 2) as *const u16) } as u64;
+
+        if structure_length == 0 {
+            break;
+        }
+
+        if structure_type == DRHD_TYPE {
+            // The register base address is located at offset 8 of the DRHD structure.
+            register_bases.push(unsafe { read_unaligned((dmar.pa + offset + 8) as *const u64) });
+        }
+
+        offset += structure_length;
+    }
+
+    if register_bases.is_empty() {
+        return Err(HypervisorError::DmaRemappingUnsupported);
+    }
+
+    Ok(register_bases)
+}
+
+/// A root entry, mapping a bus number to a context table.
+///
+/// Reference: Intel® Virtualization Technology for Directed I/O Architecture Specification: 9.1 Root Entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RootEntry {
+    lo: u64,
+    hi: u64,
+}
+
+/// The root table, containing 256 root entries (one per bus).
+#[repr(C, align(4096))]
+struct RootTable {
+    entries: [RootEntry; 256],
+}
+
+/// A context entry, mapping a device and function to a domain and its second-level page tables.
+///
+/// Reference: Intel® Virtualization Technology for Directed I/O Architecture Specification: 9.3 Context Entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ContextEntry {
+    lo: u64,
+    hi: u64,
+}
+
+/// The context table, containing 256 context entries (one per device and function).
+#[repr(C, align(4096))]
+struct ContextTable {
+    entries: [ContextEntry; 256],
+}
+
+/// A table in the second-level paging structure.
+#[repr(C, align(4096))]
+struct Table {
+    entries: [Entry; 512],
+}
+
+bitfield! {
+    /// Represents a second-level paging entry.
+    ///
+    /// Reference: Intel® Virtualization Technology for Directed I/O Architecture Specification: 9.8 Second-Level Paging Entries
+    #[derive(Clone, Copy)]
+    pub struct Entry(u64);
+    impl Debug;
+
+    pub readable, set_readable: 0;
+    pub writable, set_writable: 1;
+    pub large, set_large: 7;
+    pub pfn, set_pfn: 51, 12;
+}
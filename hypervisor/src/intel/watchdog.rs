@@ -0,0 +1,286 @@
+//! Provides a watchdog reporting the logical processors whose guest makes no forward progress, e.g., because of a
+//! deadlock induced by a hook.
+//!
+//! While enabled, the VMX-preemption timer of each logical processor is armed to check the guest several times per
+//! period. The guest makes progress when its RIP leaves a small window around the RIP of the last progress, when it
+//! switches to another address space, or when it is idle waiting for an interrupt (halted, or resuming from `MWAIT`
+//! with interrupts enabled), so that timer interrupts can be delivered. A logical processor making no progress for a
+//! whole period is reported once with a `WatchdogEvent` to the registered handler, and optionally an NMI is injected,
+//! which Windows handles by bugchecking and writing a crash dump.
+
+use {
+    crate::intel::{
+        addresses::PhysicalAddress,
+        events::EventInjection,
+        seqlock::{Generation, Published},
+        state::GuestActivityState,
+        support::{rdtsc, vmread},
+        vm::Vm,
+        vmexit::preemption_timer::update_preemption_timer,
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::vmx::vmcs,
+    x86_64::registers::rflags::RFlags,
+};
+
+/// The number of times the guest is checked per period.
+const CHECKS_PER_PERIOD: u64 = 8;
+
+/// The distance in bytes from the RIP of the last progress within which the guest is considered spinning.
+const RIP_WINDOW_SIZE: u64 = 0x100;
+
+/// The encoding of the `MWAIT` instruction.
+const MWAIT_OPCODE: [u8; 3] = [0x0F, 0x01, 0xC9];
+
+/// The configuration of the watchdog, disabled until it's configured.
+static WATCHDOG_CONFIG: Published<WatchdogConfig> = Published::new(WatchdogConfig {
+    period_tsc_ticks: 0,
+    inject_nmi: false,
+});
+
+lazy_static! {
+    /// A globally shared instance of `Watchdog`, protected by a mutex.
+    pub static ref SHARED_WATCHDOG: Mutex<Watchdog> = Mutex::new(Watchdog::new());
+}
+
+/// A handler called in VMX root operation when a logical processor made no progress for a whole period.
+pub type WatchdogHandler = fn(event: &WatchdogEvent);
+
+/// The configuration of the watchdog.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WatchdogConfig {
+    /// The number of TSC ticks a logical processor may make no progress before it is reported, 0 while disabled.
+    pub period_tsc_ticks: u64,
+
+    /// Whether to inject an NMI into a logical processor that made no progress.
+    pub inject_nmi: bool,
+}
+
+/// The handler of the watchdog, shared by all the logical processors.
+#[derive(Debug)]
+pub struct Watchdog {
+    /// The handler called when a logical processor made no progress.
+    handler: Option<WatchdogHandler>,
+}
+
+impl Watchdog {
+    /// Creates a new disabled watchdog.
+    fn new() -> Self {
+        Self { handler: None }
+    }
+
+    /// Publishes a new configuration, which the logical processors pick up on their next VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new configuration.
+    pub fn configure(&mut self, config: WatchdogConfig) {
+        debug!("Watchdog configured: {:?}", config);
+
+        WATCHDOG_CONFIG.publish(config);
+    }
+
+    /// Registers the handler called when a logical processor made no progress, replacing the previous one.
+    ///
+    /// The handler is called while the watchdog is locked, so it must not lock it again.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The handler, or `None` to only log the events.
+    pub fn set_handler(&mut self, handler: Option<WatchdogHandler>) {
+        self.handler = handler;
+    }
+}
+
+/// A logical processor whose guest made no progress for a whole period.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchdogEvent {
+    /// The initial APIC ID of the logical processor.
+    pub processor_id: u32,
+
+    /// The guest RIP when the stall was detected.
+    pub guest_rip: u64,
+
+    /// The guest CR3 when the stall was detected.
+    pub guest_cr3: u64,
+
+    /// The number of TSC ticks since the last progress.
+    pub stalled_tsc_ticks: u64,
+
+    /// Whether the guest had interrupts enabled, so timer interrupts could still be delivered.
+    pub interrupts_enabled: bool,
+
+    /// Whether an NMI has been queued for the guest, injected once it doesn't block NMIs.
+    pub nmi_injected: bool,
+}
+
+/// The watchdog state of a logical processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessorWatchdog {
+    /// The generation of the configuration in use on this logical processor.
+    generation: Generation,
+
+    /// The configuration in use on this logical processor.
+    config: WatchdogConfig,
+
+    /// The TSC at which the next check is due.
+    next_check_tsc: u64,
+
+    /// The guest RIP of the last progress.
+    progress_rip: u64,
+
+    /// The guest CR3 of the last progress.
+    progress_cr3: u64,
+
+    /// The TSC of the last progress.
+    progress_tsc: u64,
+
+    /// Whether the current stall has been reported.
+    is_stall_reported: bool,
+}
+
+impl ProcessorWatchdog {
+    /// Creates a new disabled watchdog.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of TSC ticks between two checks.
+    fn check_interval(&self) -> u64 {
+        (self.config.period_tsc_ticks / CHECKS_PER_PERIOD).max(1)
+    }
+
+    /// Returns `true` if the watchdog is enabled and a check is due.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The current TSC.
+    pub fn is_check_due(&self, tsc: u64) -> bool {
+        self.config.period_tsc_ticks != 0 && tsc >= self.next_check_tsc
+    }
+
+    /// Returns the number of TSC ticks until the next check is due, or `None` while disabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The current TSC.
+    pub fn ticks_until_next_check(&self, tsc: u64) -> Option<u64> {
+        match self.config.period_tsc_ticks {
+            0 => None,
+            _ => Some(self.next_check_tsc.saturating_sub(tsc).max(1)),
+        }
+    }
+
+    /// Records the progress of the guest, starting a new period.
+    ///
+    /// # Arguments
+    ///
+    /// * `rip` - The guest RIP.
+    /// * `cr3` - The guest CR3.
+    /// * `tsc` - The current TSC.
+    fn record_progress(&mut self, rip: u64, cr3: u64, tsc: u64) {
+        self.progress_rip = rip;
+        self.progress_cr3 = cr3;
+        self.progress_tsc = tsc;
+        self.is_stall_reported = false;
+    }
+}
+
+/// Picks up a new watchdog configuration on the current logical processor and re-arms the VMX-preemption timer for it.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn sync_watchdog(vm: &mut Vm) {
+    let mut generation = vm.watchdog.generation;
+    let Some(config) = WATCHDOG_CONFIG.sync(&mut generation) else {
+        return;
+    };
+
+    let tsc = rdtsc();
+
+    vm.watchdog = ProcessorWatchdog {
+        generation,
+        config,
+        ..ProcessorWatchdog::new()
+    };
+    vm.watchdog.record_progress(vm.guest_registers.rip, vmread(vmcs::guest::CR3), tsc);
+    vm.watchdog.next_check_tsc = tsc.wrapping_add(vm.watchdog.check_interval());
+
+    update_preemption_timer(vm);
+}
+
+/// Checks the progress of the guest of the current logical processor, reports it if it made no progress for a whole
+/// period, and schedules the next check.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+pub fn check_guest_progress(vm: &mut Vm) {
+    let tsc = rdtsc();
+    let rip = vm.guest_registers.rip;
+    let cr3 = vmread(vmcs::guest::CR3);
+    let interrupts_enabled = RFlags::from_bits_retain(vmread(vmcs::guest::RFLAGS)).contains(RFlags::INTERRUPT_FLAG);
+
+    vm.watchdog.next_check_tsc = tsc.wrapping_add(vm.watchdog.check_interval());
+
+    if rip.abs_diff(vm.watchdog.progress_rip) > RIP_WINDOW_SIZE || cr3 != vm.watchdog.progress_cr3 || is_guest_idle(rip, interrupts_enabled) {
+        if vm.watchdog.is_stall_reported {
+            info!("Guest made progress again on this processor at RIP: {:#x}", rip);
+        }
+
+        vm.watchdog.record_progress(rip, cr3, tsc);
+        return;
+    }
+
+    let stalled_tsc_ticks = tsc.wrapping_sub(vm.watchdog.progress_tsc);
+    if vm.watchdog.is_stall_reported || stalled_tsc_ticks < vm.watchdog.config.period_tsc_ticks {
+        return;
+    }
+
+    vm.watchdog.is_stall_reported = true;
+
+    // The NMI is injected once the guest doesn't block NMIs, possibly at a later VM exit.
+    let nmi_injected = vm.watchdog.config.inject_nmi;
+    if nmi_injected {
+        EventInjection::queue_nmi(vm);
+    }
+
+    let event = WatchdogEvent {
+        processor_id: vm.cpuid_feature_info.initial_local_apic_id() as u32,
+        guest_rip: rip,
+        guest_cr3: cr3,
+        stalled_tsc_ticks,
+        interrupts_enabled,
+        nmi_injected,
+    };
+
+    error!("==================== GUEST WATCHDOG ====================");
+    error!("Guest made no progress on this processor for {:#x} TSC ticks: {:#x?}", stalled_tsc_ticks, event);
+
+    if let Some(handler) = SHARED_WATCHDOG.lock().handler {
+        handler(&event);
+    }
+}
+
+/// Returns `true` if the guest is waiting for an interrupt: halted, or resuming from `MWAIT` with interrupts enabled.
+///
+/// # Arguments
+///
+/// * `rip` - The guest RIP.
+/// * `interrupts_enabled` - Whether the guest has interrupts enabled.
+fn is_guest_idle(rip: u64, interrupts_enabled: bool) -> bool {
+    if vmread(vmcs::guest::ACTIVITY_STATE) == GuestActivityState::Hlt as u64 {
+        return true;
+    }
+
+    // The instruction is read only if it doesn't cross a page boundary, as only its first byte is translated.
+    let mwait_va = rip.wrapping_sub(MWAIT_OPCODE.len() as u64);
+    if !interrupts_enabled || mwait_va & 0xFFF > 0x1000 - MWAIT_OPCODE.len() as u64 {
+        return false;
+    }
+
+    PhysicalAddress::read_guest_virt_with_current_cr3(mwait_va as *const [u8; 3]) == Some(MWAIT_OPCODE)
+}
@@ -0,0 +1,302 @@
+//! Provides the policy deciding which XSAVE state components the guest may enable in XCR0, e.g., denying AMX on
+//! setups that don't support it, and the audit of the XCR0 transitions.
+//!
+//! The denied components are closed under the architectural dependencies of XCR0 (e.g., denying AVX denies AVX-512),
+//! so every XCR0 value the guest can still set is valid. An `XSETBV` enabling a denied component fails with #GP, as on
+//! a processor not supporting it, and the CPUID results follow the policy, so the guest doesn't try to:
+//! - leaf 0xD subleaf 0 reports the supported components without the denied ones, and the maximum size of the XSAVE
+//!   area for the remaining ones,
+//! - leaf 0xD subleafs 2-63 of the denied components report them as unsupported,
+//! - the feature bits of leaves 1 and 7 requiring a denied component are cleared (e.g., AVX2 without AVX state).
+//!
+//! The policy is selected at build time, denying AMX with the `deny_amx` feature, and changed at runtime. It applies to
+//! the next `XSETBV` of each logical processor, which Windows executes once per processor at boot, so a policy set
+//! later doesn't clear the components already enabled.
+//!
+//! Each XCR0 change, and each attempt to enable a denied component, is logged with the guest RIP and passed to the
+//! registered handler.
+//!
+//! The denied components are published, so the CPUID VM exits read them without locking the policy.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{seqlock::Published, support::vmread, vm::Vm},
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::{
+        cpuid::{cpuid, CpuIdResult},
+        vmx::vmcs,
+    },
+    x86_64::registers::xcontrol::XCr0Flags,
+};
+
+/// The CPUID leaf enumerating the XSAVE state components.
+pub const XSAVE_LEAF: u32 = 0xD;
+
+/// The AMX state components: TILECFG and TILEDATA.
+pub const AMX_COMPONENTS: u64 = (1 << 17) | (1 << 18);
+
+/// The AVX-512 state components: opmask, ZMM_Hi256 and Hi16_ZMM.
+const AVX512_COMPONENTS: u64 = XCr0Flags::OPMASK.bits() | XCr0Flags::ZMM_HI256.bits() | XCr0Flags::HI16_ZMM.bits();
+
+/// The MPX state components: BNDREGS and BNDCSR.
+const MPX_COMPONENTS: u64 = XCr0Flags::BNDREG.bits() | XCr0Flags::BNDCSR.bits();
+
+/// The size of the legacy region and the header of the XSAVE area, which precede the extended components.
+const XSAVE_LEGACY_AND_HEADER_SIZE: u32 = 512 + 64;
+
+/// The components denied at build time.
+const DEFAULT_DENIED_COMPONENTS: u64 = match cfg!(feature = "deny_amx") {
+    true => AMX_COMPONENTS,
+    false => 0,
+};
+
+/// The CPUID feature bits requiring state components, as the components, the leaf, the subleaf, the index of the
+/// register in the order EAX, EBX, ECX, EDX, and the bits.
+const COMPONENT_FEATURE_BITS: [(u64, u32, u32, usize, u32); 8] = [
+    // FMA, AVX and F16C.
+    (XCr0Flags::AVX.bits(), 1, 0, 2, (1 << 12) | (1 << 28) | (1 << 29)),
+    // AVX2.
+    (XCr0Flags::AVX.bits(), 7, 0, 1, 1 << 5),
+    // VAES and VPCLMULQDQ.
+    (XCr0Flags::AVX.bits(), 7, 0, 2, (1 << 9) | (1 << 10)),
+    // AVX512F, AVX512DQ, AVX512_IFMA, AVX512PF, AVX512ER, AVX512CD, AVX512BW and AVX512VL.
+    (AVX512_COMPONENTS, 7, 0, 1, (1 << 16) | (1 << 17) | (1 << 21) | (1 << 26) | (1 << 27) | (1 << 28) | (1 << 30) | (1 << 31)),
+    // AVX512_VBMI, AVX512_VBMI2, AVX512_VNNI, AVX512_BITALG and AVX512_VPOPCNTDQ.
+    (AVX512_COMPONENTS, 7, 0, 2, (1 << 1) | (1 << 6) | (1 << 11) | (1 << 12) | (1 << 14)),
+    // AVX512_4VNNIW, AVX512_4FMAPS, AVX512_VP2INTERSECT and AVX512_FP16.
+    (AVX512_COMPONENTS, 7, 0, 3, (1 << 2) | (1 << 3) | (1 << 8) | (1 << 23)),
+    // MPX.
+    (MPX_COMPONENTS, 7, 0, 1, 1 << 14),
+    // AMX-BF16, AMX-TILE and AMX-INT8.
+    (AMX_COMPONENTS, 7, 0, 3, (1 << 22) | (1 << 24) | (1 << 25)),
+];
+
+lazy_static! {
+    /// A globally shared instance of `XsavePolicy`, protected by a mutex.
+    pub static ref SHARED_XSAVE_POLICY: Mutex<XsavePolicy> = Mutex::new(XsavePolicy::new());
+
+    /// The components the guest may not enable, with the components depending on them, published while the policy is
+    /// locked.
+    static ref DENIED_COMPONENTS: Published<u64> = Published::new(close_denied_components(DEFAULT_DENIED_COMPONENTS));
+}
+
+/// A handler called in VMX root operation on each XCR0 transition of the guest.
+pub type Xcr0TransitionHandler = fn(event: &Xcr0TransitionEvent);
+
+/// An XCR0 change, or an attempt to enable a denied component, on a logical processor.
+#[derive(Debug, Clone, Copy)]
+pub struct Xcr0TransitionEvent {
+    /// The initial APIC ID of the logical processor.
+    pub processor_id: u32,
+
+    /// The guest RIP of the `XSETBV`.
+    pub guest_rip: u64,
+
+    /// The XCR0 value before the `XSETBV`.
+    pub previous_xcr0: u64,
+
+    /// The XCR0 value requested by the guest.
+    pub requested_xcr0: u64,
+
+    /// The denied components in the requested value, which then fails with #GP, or 0.
+    pub denied_components: u64,
+}
+
+/// The state components the guest may enable, and the audit of the transitions.
+#[derive(Debug)]
+pub struct XsavePolicy {
+    /// The number of XCR0 changes since the hypervisor started.
+    transition_count: u64,
+
+    /// The number of attempts to enable a denied component since the hypervisor started.
+    denied_count: u64,
+
+    /// The handler called on each transition.
+    handler: Option<Xcr0TransitionHandler>,
+}
+
+impl XsavePolicy {
+    /// Creates the policy selected at build time.
+    fn new() -> Self {
+        Self {
+            transition_count: 0,
+            denied_count: 0,
+            handler: None,
+        }
+    }
+
+    /// Sets the components the guest may not enable, with the components depending on them.
+    ///
+    /// # Arguments
+    ///
+    /// * `denied_components` - The XCR0 bits of the denied components.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the policy has been changed, or `Err(HypervisorError::InvalidXsavePolicy)` if it denies the x87
+    /// state, which XCR0 can't disable.
+    pub fn set_denied_components(&mut self, denied_components: u64) -> Result<(), HypervisorError> {
+        if denied_components & XCr0Flags::X87.bits() != 0 {
+            return Err(HypervisorError::InvalidXsavePolicy);
+        }
+
+        let denied_components = close_denied_components(denied_components);
+        DENIED_COMPONENTS.publish(denied_components);
+
+        debug!("XSAVE policy set, denied components: {:#x}", denied_components);
+
+        Ok(())
+    }
+
+    /// Returns the components the guest may not enable, with the components depending on them.
+    pub fn denied_components(&self) -> u64 {
+        DENIED_COMPONENTS.read()
+    }
+
+    /// Returns the number of XCR0 changes and the number of attempts to enable a denied component.
+    pub fn transition_counts(&self) -> (u64, u64) {
+        (self.transition_count, self.denied_count)
+    }
+
+    /// Registers the handler called on each transition, replacing the previous one.
+    ///
+    /// The handler is called while the policy is locked, so it must not lock it again.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The handler, or `None` to only log the transitions.
+    pub fn set_handler(&mut self, handler: Option<Xcr0TransitionHandler>) {
+        self.handler = handler;
+    }
+}
+
+/// Adds to the denied components those that can't be enabled without them, and denies the components enabled together
+/// as a whole.
+///
+/// # Arguments
+///
+/// * `denied_components` - The XCR0 bits of the denied components.
+fn close_denied_components(mut denied_components: u64) -> u64 {
+    if denied_components & XCr0Flags::SSE.bits() != 0 {
+        denied_components |= XCr0Flags::AVX.bits();
+    }
+
+    if denied_components & XCr0Flags::AVX.bits() != 0 {
+        denied_components |= AVX512_COMPONENTS;
+    }
+
+    for components in [AVX512_COMPONENTS, MPX_COMPONENTS, AMX_COMPONENTS] {
+        if denied_components & components != 0 {
+            denied_components |= components;
+        }
+    }
+
+    denied_components
+}
+
+/// Audits an `XSETBV` of the guest, and decides whether it enables a denied component.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+/// * `previous_xcr0` - The XCR0 value before the `XSETBV`.
+/// * `requested_xcr0` - The XCR0 value requested by the guest.
+///
+/// # Returns
+///
+/// `true` if the value is allowed by the policy, `false` if the `XSETBV` must fail with #GP.
+pub fn audit_xcr0_transition(vm: &Vm, previous_xcr0: u64, requested_xcr0: u64) -> bool {
+    let mut policy = SHARED_XSAVE_POLICY.lock();
+    let denied_components = requested_xcr0 & policy.denied_components();
+
+    if denied_components == 0 && requested_xcr0 == previous_xcr0 {
+        return true;
+    }
+
+    let event = Xcr0TransitionEvent {
+        processor_id: vm.cpuid_feature_info.initial_local_apic_id() as u32,
+        guest_rip: vmread(vmcs::guest::RIP),
+        previous_xcr0,
+        requested_xcr0,
+        denied_components,
+    };
+
+    match denied_components {
+        0 => {
+            policy.transition_count += 1;
+            debug!("XCR0 changed from {:#x} to {:#x} at RIP: {:#x}", previous_xcr0, requested_xcr0, event.guest_rip);
+        }
+        _ => {
+            policy.denied_count += 1;
+            warn!("XCR0 value {:#x} denied at RIP: {:#x}, denied components: {:#x}", requested_xcr0, event.guest_rip, denied_components);
+        }
+    }
+
+    if let Some(handler) = policy.handler {
+        handler(&event);
+    }
+
+    denied_components == 0
+}
+
+/// Applies the policy to the result of the host `CPUID`, before the overrides of the CPUID hook registry.
+///
+/// # Arguments
+///
+/// * `leaf` - The leaf requested by the guest in EAX.
+/// * `sub_leaf` - The subleaf requested by the guest in ECX.
+/// * `result` - The result of the host `CPUID`, returned to the guest.
+pub fn apply_xsave_policy(leaf: u32, sub_leaf: u32, result: &mut CpuIdResult) {
+    if leaf != 1 && leaf != 7 && leaf != XSAVE_LEAF {
+        return;
+    }
+
+    let denied_components = DENIED_COMPONENTS.read();
+
+    if denied_components == 0 {
+        return;
+    }
+
+    match leaf {
+        XSAVE_LEAF if sub_leaf == 0 => {
+            result.eax &= !(denied_components as u32);
+            result.edx &= !((denied_components >> 32) as u32);
+
+            // The maximum size of the XSAVE area, in the standard format, for the components that remain supported.
+            let supported_components = (result.edx as u64) << 32 | result.eax as u64;
+            result.ecx = (2..64)
+                .filter(|component| supported_components & (1 << component) != 0)
+                .map(|component| {
+                    let component_info = cpuid!(XSAVE_LEAF, component);
+                    component_info.ebx + component_info.eax
+                })
+                .fold(XSAVE_LEGACY_AND_HEADER_SIZE, u32::max);
+        }
+        XSAVE_LEAF if (2..64).contains(&sub_leaf) && denied_components & (1 << sub_leaf) != 0 => {
+            *result = CpuIdResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            };
+        }
+        _ => {
+            let registers = [&mut result.eax, &mut result.ebx, &mut result.ecx, &mut result.edx];
+
+            for (index, register) in registers.into_iter().enumerate() {
+                for (components, _, _, _, bits) in COMPONENT_FEATURE_BITS
+                    .iter()
+                    .filter(|entry| entry.1 == leaf && entry.2 == sub_leaf && entry.3 == index)
+                {
+                    if denied_components & components != 0 {
+                        *register &= !bits;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -12,10 +12,18 @@
 extern crate alloc;
 extern crate static_assertions;
 
+pub mod acpi;
+pub mod ahci;
 pub mod allocator;
 pub mod error;
+pub mod exfil;
 pub mod global_const;
 pub mod intel;
+pub mod linux;
 pub mod logger;
+pub mod persistence;
+pub mod personality;
+pub mod sha256;
+pub mod tpm;
 pub mod vmm;
 pub mod windows;
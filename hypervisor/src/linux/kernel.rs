@@ -0,0 +1,242 @@
+//! Provides the kernel of Linux guests: its syscall entry, captured from the IA32_LSTAR write, and the offsets of the
+//! running release, selected from a table of offsets keyed by kernel release registered by the client, as Linux has
+//! no export directory to resolve its symbols and structures from, unlike ntoskrnl.exe.
+//!
+//! The offsets of the symbols are relative to `entry_SYSCALL_64`, the syscall entry, so they don't depend on the
+//! KASLR slide. The entry of the running kernel is the one whose release is in the banner at its `linux_banner`
+//! offset, e.g., `Linux version 6.8.0-45-generic (...)`, and it's matched again when the table changes.
+//!
+//! The kernel memory is read with the page tables the kernel writes IA32_LSTAR with at boot, as the page tables of
+//! the processes only map the entry code of the kernel with page-table isolation (PTI), so a Linux guest agent can
+//! resolve and hook the kernel functions from user mode. A syscall number resolves to the function of its entry of
+//! `sys_call_table` (see `HookManager::resolve_kernel_function`), so the EPT and hardware breakpoint hooks hook the
+//! syscalls of Linux guests as they hook the functions of ntoskrnl.exe.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{addresses::PhysicalAddress, paging::CR3_ADDRESS_MASK},
+        personality::{is_linux_guest, LINUX_KERNEL_TEXT_BASE},
+    },
+    alloc::{
+        collections::BTreeMap,
+        string::{String, ToString},
+    },
+    core::mem::size_of,
+    lazy_static::lazy_static,
+    log::*,
+    shared::{LinuxKernelOffsets, LINUX_RELEASE_SIZE},
+    spin::Mutex,
+};
+
+/// The maximum number of kernel releases with offsets.
+pub const MAX_LINUX_KERNEL_OFFSETS: usize = 0x40;
+
+/// The prefix of the banner of the kernel, followed by the release and a space.
+const LINUX_BANNER_PREFIX: &[u8] = b"Linux version ";
+
+/// The size of the start of the banner read to match a release: the prefix, the longest release and the space.
+const LINUX_BANNER_MATCH_SIZE: usize = LINUX_BANNER_PREFIX.len() + LINUX_RELEASE_SIZE + 1;
+
+lazy_static! {
+    /// A globally shared instance of `LinuxKernel`, protected by a mutex.
+    pub static ref SHARED_LINUX_KERNEL: Mutex<LinuxKernel> = Mutex::new(LinuxKernel::new());
+}
+
+/// The kernel of a Linux guest.
+#[derive(Debug, Clone)]
+pub struct LinuxKernel {
+    /// The syscall entry written to IA32_LSTAR, `entry_SYSCALL_64`, or 0 until it's captured.
+    syscall_entry: u64,
+
+    /// The CR3 the kernel writes IA32_LSTAR with, mapping the whole kernel.
+    kernel_cr3: u64,
+
+    /// The offsets registered by the client, by kernel release.
+    offsets_table: BTreeMap<String, LinuxKernelOffsets>,
+
+    /// The release and the offsets of the running kernel, once matched.
+    running_kernel: Option<(String, LinuxKernelOffsets)>,
+}
+
+impl LinuxKernel {
+    /// Creates a kernel not captured yet, without offsets.
+    fn new() -> Self {
+        Self {
+            syscall_entry: 0,
+            kernel_cr3: 0,
+            offsets_table: BTreeMap::new(),
+            running_kernel: None,
+        }
+    }
+
+    /// Captures the syscall entry and the kernel page tables from the first IA32_LSTAR write.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_entry` - The syscall entry written to IA32_LSTAR.
+    /// * `guest_cr3` - The CR3 of the guest writing IA32_LSTAR.
+    pub fn capture(&mut self, syscall_entry: u64, guest_cr3: u64) {
+        if self.syscall_entry != 0 {
+            return;
+        }
+
+        self.syscall_entry = syscall_entry;
+        self.kernel_cr3 = guest_cr3 & CR3_ADDRESS_MASK;
+
+        info!("Linux syscall entry captured: {:#x}, kernel CR3: {:#x}", self.syscall_entry, self.kernel_cr3);
+    }
+
+    /// Returns the syscall entry, `entry_SYSCALL_64`, or 0 until it's captured.
+    pub fn syscall_entry(&self) -> u64 {
+        self.syscall_entry
+    }
+
+    /// Returns the CR3 mapping the whole kernel, or 0 until it's captured.
+    pub fn kernel_cr3(&self) -> u64 {
+        self.kernel_cr3
+    }
+
+    /// Registers the offsets of a kernel release, replacing its previous offsets.
+    ///
+    /// # Arguments
+    ///
+    /// * `release` - The release, as reported by `uname -r`.
+    /// * `offsets` - The offsets of the release.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The offsets were registered.
+    /// * `Err(HypervisorError::InvalidLinuxKernelRelease)` - If the release is empty or contains a space.
+    /// * `Err(HypervisorError::TooManyLinuxKernelOffsets)` - If `MAX_LINUX_KERNEL_OFFSETS` releases have offsets.
+    pub fn set_offsets(&mut self, release: &str, offsets: LinuxKernelOffsets) -> Result<(), HypervisorError> {
+        if release.is_empty() || release.contains(' ') {
+            return Err(HypervisorError::InvalidLinuxKernelRelease);
+        }
+
+        if !self.offsets_table.contains_key(release) && self.offsets_table.len() >= MAX_LINUX_KERNEL_OFFSETS {
+            return Err(HypervisorError::TooManyLinuxKernelOffsets);
+        }
+
+        debug!("Linux kernel offsets registered for {}: {:#x?}", release, offsets);
+        self.offsets_table.insert(release.to_string(), offsets);
+        self.running_kernel = None;
+
+        Ok(())
+    }
+
+    /// Removes the offsets of a kernel release.
+    ///
+    /// # Arguments
+    ///
+    /// * `release` - The release, as reported by `uname -r`.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the release had offsets.
+    pub fn remove_offsets(&mut self, release: &str) -> bool {
+        self.running_kernel = None;
+        self.offsets_table.remove(release).is_some()
+    }
+
+    /// Returns the offsets of the running kernel, matching the registered releases against its banner if needed.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(LinuxKernelOffsets)` - The offsets of the running release.
+    /// * `Err(HypervisorError::LinuxKernelNotCaptured)` - If the syscall entry hasn't been captured.
+    /// * `Err(HypervisorError::LinuxKernelOffsetsNotFound)` - If no registered release is the running one.
+    pub fn offsets(&mut self) -> Result<LinuxKernelOffsets, HypervisorError> {
+        if let Some((_, offsets)) = &self.running_kernel {
+            return Ok(*offsets);
+        }
+
+        if !is_linux_guest() || self.syscall_entry == 0 {
+            return Err(HypervisorError::LinuxKernelNotCaptured);
+        }
+
+        let (release, offsets) = self
+            .offsets_table
+            .iter()
+            .find(|(release, offsets)| self.is_running_release(release, offsets))
+            .map(|(release, offsets)| (release.clone(), *offsets))
+            .ok_or(HypervisorError::LinuxKernelOffsetsNotFound)?;
+
+        info!("Running Linux kernel matched: {}", release);
+        self.running_kernel = Some((release, offsets));
+
+        Ok(offsets)
+    }
+
+    /// Returns whether the banner at the `linux_banner` offset of a release reports this release.
+    ///
+    /// # Arguments
+    ///
+    /// * `release` - The release.
+    /// * `offsets` - The offsets of the release.
+    fn is_running_release(&self, release: &str, offsets: &LinuxKernelOffsets) -> bool {
+        // The kernel image is physically contiguous, so the banner can be read across a page boundary.
+        let Some(banner) = self.read::<[u8; LINUX_BANNER_MATCH_SIZE]>(self.symbol_address(offsets.linux_banner)) else {
+            return false;
+        };
+
+        let release_start = LINUX_BANNER_PREFIX.len();
+        let release_end = release_start + release.len();
+
+        banner.starts_with(LINUX_BANNER_PREFIX)
+            && banner.get(release_start..release_end) == Some(release.as_bytes())
+            && banner.get(release_end) == Some(&b' ')
+    }
+
+    /// Returns the address of a symbol from its offset to the syscall entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset of the symbol.
+    pub fn symbol_address(&self, offset: i64) -> u64 {
+        self.syscall_entry.wrapping_add_signed(offset)
+    }
+
+    /// Reads a value from the kernel memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `va` - The kernel virtual address to read from.
+    ///
+    /// # Returns
+    ///
+    /// The value read, or `None` if the address isn't mapped.
+    pub fn read<T: Sized>(&self, va: u64) -> Option<T> {
+        PhysicalAddress::read_guest_virt_with_explicit_cr3(va as *const T, self.kernel_cr3)
+    }
+
+    /// Resolves a syscall number to the function of its entry of `sys_call_table`.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_number` - The syscall number.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The virtual address of the function.
+    /// * `Err(HypervisorError::InvalidLinuxSyscallNumber)` - If the number is out of the table, or its entry isn't a
+    ///   function of the kernel text.
+    pub fn resolve_syscall(&mut self, syscall_number: u16) -> Result<u64, HypervisorError> {
+        let offsets = self.offsets()?;
+
+        if syscall_number as u64 >= offsets.syscall_count {
+            return Err(HypervisorError::InvalidLinuxSyscallNumber);
+        }
+
+        let entry_va = self.symbol_address(offsets.sys_call_table) + syscall_number as u64 * size_of::<u64>() as u64;
+        let function_va = self.read::<u64>(entry_va).ok_or(HypervisorError::InvalidLinuxSyscallNumber)?;
+
+        if function_va < LINUX_KERNEL_TEXT_BASE {
+            return Err(HypervisorError::InvalidLinuxSyscallNumber);
+        }
+
+        trace!("Linux syscall {} resolved to {:#x}", syscall_number, function_va);
+
+        Ok(function_va)
+    }
+}
@@ -0,0 +1,95 @@
+//! Provides the enumeration of the tasks of Linux guests, the threads of the processes and of the kernel, by walking
+//! the list of tasks from `init_task` with the offsets of the running kernel (see `kernel`), as `ProcessInformation`
+//! walks the list of processes of Windows guests.
+//!
+//! Each task reports its PID, its TGID, the PID of its process, its command name and the CR3 of its address space,
+//! the physical address of the PGD of its memory descriptor, or 0 for the kernel threads, which have none. The CR3
+//! can be passed to the commands taking a guest CR3, e.g., `ReadProcessMemory`.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::addresses::PhysicalAddress,
+        linux::kernel::{LinuxKernel, SHARED_LINUX_KERNEL},
+    },
+    alloc::vec::Vec,
+    log::*,
+    shared::{LinuxKernelOffsets, LinuxTask, LINUX_TASK_COMM_SIZE},
+};
+
+/// The maximum number of tasks walked, bounding the walk of a corrupted list.
+pub const MAX_LINUX_TASKS: usize = 0x10000;
+
+/// Enumerates the tasks of the running kernel, starting with `init_task`.
+///
+/// # Arguments
+///
+/// * `max_tasks` - The maximum number of tasks returned.
+///
+/// # Returns
+///
+/// * `Ok((Vec<LinuxTask>, usize))` - The first `max_tasks` tasks in the order of the list, and the number of tasks.
+/// * `Err(HypervisorError::InvalidLinuxTaskList)` - If the list can't be read or doesn't loop back to `init_task`.
+pub fn enumerate_linux_tasks(max_tasks: usize) -> Result<(Vec<LinuxTask>, usize), HypervisorError> {
+    let mut kernel = SHARED_LINUX_KERNEL.lock();
+    let offsets = kernel.offsets()?;
+
+    let init_task = kernel.symbol_address(offsets.init_task);
+    let mut tasks = Vec::new();
+    let mut task_va = init_task;
+
+    for total_tasks in 0..MAX_LINUX_TASKS {
+        if task_va == init_task && total_tasks != 0 {
+            debug!("Enumerated {} Linux tasks", total_tasks);
+            return Ok((tasks, total_tasks));
+        }
+
+        if tasks.len() < max_tasks {
+            tasks.push(read_linux_task(&kernel, &offsets, task_va).ok_or(HypervisorError::InvalidLinuxTaskList)?);
+        }
+
+        // The `next` pointer of the `list_head` points to the `tasks` member of the next task.
+        let next = kernel
+            .read::<u64>(task_va + offsets.task_tasks)
+            .ok_or(HypervisorError::InvalidLinuxTaskList)?;
+        task_va = next.wrapping_sub(offsets.task_tasks);
+    }
+
+    error!("Linux task list doesn't loop back to init_task after {} tasks", MAX_LINUX_TASKS);
+    Err(HypervisorError::InvalidLinuxTaskList)
+}
+
+/// Reads a task of the running kernel.
+///
+/// # Arguments
+///
+/// * `kernel` - The kernel.
+/// * `offsets` - The offsets of the running kernel.
+/// * `task_va` - The virtual address of the `task_struct`.
+///
+/// # Returns
+///
+/// The task, or `None` if its `task_struct` can't be read.
+fn read_linux_task(kernel: &LinuxKernel, offsets: &LinuxKernelOffsets, task_va: u64) -> Option<LinuxTask> {
+    let pid = kernel.read::<i32>(task_va + offsets.task_pid)?;
+    let tgid = kernel.read::<i32>(task_va + offsets.task_tgid)?;
+    let comm = kernel.read::<[u8; LINUX_TASK_COMM_SIZE]>(task_va + offsets.task_comm)?;
+    let mm = kernel.read::<u64>(task_va + offsets.task_mm)?;
+
+    // The PGD is in the direct map of the physical memory, so its physical address is the CR3 of the address space.
+    let guest_cr3 = match mm {
+        0 => 0,
+        mm => {
+            let pgd = kernel.read::<u64>(mm + offsets.mm_pgd)?;
+            PhysicalAddress::pa_from_va_with_explicit_cr3(pgd, kernel.kernel_cr3()).ok()?
+        }
+    };
+
+    Some(LinuxTask {
+        task_va,
+        pid: pid as u64,
+        tgid: tgid as u64,
+        guest_cr3,
+        comm,
+    })
+}
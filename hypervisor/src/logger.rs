@@ -43,6 +43,38 @@ pub fn init(port: SerialPort, level: log::LevelFilter) {
     log::set_logger(serial_logger).map(|()| log::set_max_level(level)).unwrap();
 }
 
+/// Writes formatted output to the serial port of the logger, without the prefix of the log messages, e.g., to stream
+/// data to the host of the machine.
+///
+/// # Arguments
+///
+/// - `args`: The formatted output to write.
+///
+/// # Returns
+///
+/// Returns `Some(())` if the output is written, or `None` if the logger isn't initialized.
+pub fn write_serial(args: fmt::Arguments<'_>) -> Option<()> {
+    let serial_logger = unsafe { (*core::ptr::addr_of!(SERIAL_LOGGER)).as_ref()? };
+    serial_logger.lock().write_fmt(args).ok()
+}
+
+/// Returns `true` if the serial port of the logger is locked, e.g., by a message being written.
+pub fn is_serial_locked() -> bool {
+    unsafe { (*core::ptr::addr_of!(SERIAL_LOGGER)).as_ref() }.is_some_and(|serial_logger| serial_logger.port.is_locked())
+}
+
+/// Releases the lock of the serial port of the logger, left held by the code interrupted by a fatal exception, so the
+/// exception can still be reported.
+///
+/// # Safety
+///
+/// The code holding the lock must never run again, or its message must be allowed to interleave with the next ones.
+pub unsafe fn force_unlock_serial() {
+    if let Some(serial_logger) = (*core::ptr::addr_of!(SERIAL_LOGGER)).as_ref() {
+        serial_logger.port.force_unlock();
+    }
+}
+
 /// A logger that outputs messages to a serial port.
 ///
 /// Encapsulates the functionality for logging messages over a serial port. It holds a mutex-protected
@@ -0,0 +1,483 @@
+//! Provides the persistent configuration, saved on demand by the client to a file on the EFI System Partition (ESP)
+//! and re-applied by the loader on the next boot, so a tuned setup survives reboots without replaying the commands of
+//! the client.
+//!
+//! The configuration is a text with one setting per line, empty lines and lines starting with `#` being ignored:
+//! - `presence hidden|exposed` is the hypervisor presence,
+//! - `tsc_compensation on|off <rdtsc_exiting on|off> <tsc_multiplier> <exit_latency_ticks|calibrated>` is the
+//!   configuration of the TSC compensation,
+//! - `cpuid <leaf> <subleaf|all> replace <eax> <ebx> <ecx> <edx>` and
+//!   `cpuid <leaf> <subleaf|all> modify <and_mask x4> <or_mask x4>` are the CPUID overrides,
+//! - `manifest <line>` is a line of the boot-time hook manifest, replacing the manifest read from `HOOKS.TXT` or the
+//!   `IllusionHookManifest` variable.
+//!
+//! Numbers are in decimal or `0x` hexadecimal. The CPUID overrides calling a callback are not saved, as they are
+//! registered by the code of the hypervisor rather than the client.
+//!
+//! The file is created with a fixed size at boot by the UEFI application, and written through the `ahci` module like
+//! the exfiltration file (see the `exfil` module). Its first sector is a header holding `CONFIG_MAGIC` and the length
+//! of the text following it, 0 if no configuration is saved.
+
+use {
+    crate::{
+        ahci::{write_esp_sectors, SECTOR_SIZE},
+        error::HypervisorError,
+        exfil::SectorExtent,
+        intel::{
+            hooks::{
+                boot_manifest::{BootHookManifest, SHARED_BOOT_HOOK_MANIFEST},
+                cpuid_hook::{CpuidHook, SHARED_CPUID_HOOK_MANAGER},
+            },
+            tsc_compensation::{TscCompensationConfig, SHARED_TSC_COMPENSATION},
+        },
+    },
+    alloc::{format, string::String, vec::Vec},
+    lazy_static::lazy_static,
+    log::*,
+    shared::HypervisorPresence,
+    spin::Mutex,
+};
+
+/// The magic value at the start of the header sector of the file ("ILLUCONF").
+pub const CONFIG_MAGIC: [u8; 8] = *b"ILLUCONF";
+
+lazy_static! {
+    /// A globally shared instance of `ConfigFile`, protected by a mutex.
+    ///
+    /// The file is set up once at boot by `ConfigFile::initialize_shared_config_file`, and is `None` otherwise.
+    pub static ref SHARED_CONFIG_FILE: Mutex<Option<ConfigFile>> = Mutex::new(None);
+}
+
+/// The file on the ESP that the configuration is saved to.
+#[derive(Debug)]
+pub struct ConfigFile {
+    /// The sectors backing the file, in file order, starting with the header sector.
+    extents: Vec<SectorExtent>,
+}
+
+impl ConfigFile {
+    /// Stores the file in `SHARED_CONFIG_FILE`.
+    ///
+    /// This must be called at boot, after the file has been created and its sectors resolved, and the port of the disk
+    /// holding the ESP has been stored in `SHARED_AHCI_PORT`.
+    ///
+    /// # Arguments
+    ///
+    /// * `extents` - The sectors backing the file, in file order, starting with the header sector.
+    pub fn initialize_shared_config_file(extents: Vec<SectorExtent>) {
+        let config_file = ConfigFile { extents };
+
+        debug!("Configuration file: {:#x} bytes available", config_file.capacity());
+
+        *SHARED_CONFIG_FILE.lock() = Some(config_file);
+    }
+
+    /// Returns the number of bytes of text the file holds, excluding the header sector.
+    pub fn capacity(&self) -> u64 {
+        let sectors: u64 = self.extents.iter().map(|extent| extent.count).sum();
+        sectors.saturating_sub(1) * SECTOR_SIZE as u64
+    }
+
+    /// Replaces the text of the file, writing the header last so the previous text is kept if a write fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the configuration, empty to discard the saved configuration.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the text has been written to the disk, otherwise `Err(HypervisorError)`.
+    pub fn write(&mut self, text: &str) -> Result<(), HypervisorError> {
+        if text.len() as u64 > self.capacity() {
+            error!("Configuration file is full: {:#x} bytes", text.len());
+            return Err(HypervisorError::ConfigFileFull);
+        }
+
+        for (index, chunk) in text.as_bytes().chunks(SECTOR_SIZE).enumerate() {
+            let mut sector = [0u8; SECTOR_SIZE];
+            sector[..chunk.len()].copy_from_slice(chunk);
+
+            // The text starts at the sector following the header.
+            write_esp_sectors(self.lba_of(1 + index as u64)?, &sector)?;
+        }
+
+        let mut header = [0u8; SECTOR_SIZE];
+        header[..8].copy_from_slice(&CONFIG_MAGIC);
+        header[8..16].copy_from_slice(&(text.len() as u64).to_le_bytes());
+
+        write_esp_sectors(self.lba_of(0)?, &header)
+    }
+
+    /// Returns the logical block address on the disk of a sector of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `sector` - The index of the sector in the file.
+    fn lba_of(&self, sector: u64) -> Result<u64, HypervisorError> {
+        SectorExtent::lba_of(&self.extents, sector).ok_or(HypervisorError::ConfigFileFull)
+    }
+}
+
+/// A CPUID override of the configuration.
+#[derive(Debug, Clone, Copy)]
+struct SavedCpuidHook {
+    /// The overridden leaf.
+    leaf: u32,
+
+    /// The overridden subleaf, or `None` for every subleaf of the leaf.
+    sub_leaf: Option<u32>,
+
+    /// The override, either `Replace` or `Modify`.
+    hook: CpuidHook,
+}
+
+/// The runtime configuration saved to the file.
+#[derive(Debug, Default)]
+pub struct SavedConfiguration {
+    /// The hypervisor presence.
+    presence: Option<HypervisorPresence>,
+
+    /// The configuration of the TSC compensation.
+    tsc_compensation: Option<TscCompensationConfig>,
+
+    /// The CPUID overrides not calling a callback.
+    cpuid_hooks: Vec<SavedCpuidHook>,
+
+    /// The lines of the boot-time hook manifest.
+    manifest: Vec<String>,
+}
+
+impl SavedConfiguration {
+    /// Captures the current runtime configuration.
+    pub fn capture() -> Self {
+        let (presence, cpuid_hooks) = {
+            let cpuid_hook_manager = SHARED_CPUID_HOOK_MANAGER.lock();
+            let cpuid_hooks = cpuid_hook_manager
+                .hooks()
+                .filter(|(_, _, hook)| !matches!(hook, CpuidHook::Callback(_)))
+                .map(|(leaf, sub_leaf, hook)| SavedCpuidHook { leaf, sub_leaf, hook })
+                .collect();
+            (cpuid_hook_manager.presence(), cpuid_hooks)
+        };
+
+        let manifest = SHARED_BOOT_HOOK_MANIFEST
+            .lock()
+            .text()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+
+        Self {
+            presence: Some(presence),
+            tsc_compensation: Some(SHARED_TSC_COMPENSATION.lock().config()),
+            cpuid_hooks,
+            manifest,
+        }
+    }
+
+    /// Parses the text of a saved configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text of the configuration.
+    ///
+    /// # Returns
+    ///
+    /// The configuration, or `Err(HypervisorError::InvalidSavedConfiguration)` if a line is malformed.
+    pub fn parse(text: &str) -> Result<Self, HypervisorError> {
+        let mut configuration = Self::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(manifest_line) = line.strip_prefix("manifest ") {
+                configuration.manifest.push(String::from(manifest_line.trim()));
+                continue;
+            }
+
+            if configuration.parse_line(line).is_none() {
+                error!("Invalid saved configuration line {}: {}", index + 1, line);
+                return Err(HypervisorError::InvalidSavedConfiguration);
+            }
+        }
+
+        Ok(configuration)
+    }
+
+    /// Parses a setting of the configuration other than a manifest line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line, without comment.
+    fn parse_line(&mut self, line: &str) -> Option<()> {
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next()? {
+            "presence" => {
+                self.presence = Some(match tokens.next()? {
+                    "hidden" => HypervisorPresence::Hidden,
+                    "exposed" => HypervisorPresence::Exposed,
+                    _ => return None,
+                });
+            }
+            "tsc_compensation" => {
+                let enabled = parse_switch(tokens.next()?)?;
+                let rdtsc_exiting = parse_switch(tokens.next()?)?;
+                let tsc_multiplier = parse_number(tokens.next()?)?;
+                let exit_latency_ticks = match tokens.next()? {
+                    "calibrated" => None,
+                    token => Some(parse_number(token)?),
+                };
+
+                self.tsc_compensation = Some(TscCompensationConfig {
+                    enabled,
+                    rdtsc_exiting,
+                    tsc_multiplier,
+                    exit_latency_ticks,
+                });
+            }
+            "cpuid" => {
+                let leaf = u32::try_from(parse_number(tokens.next()?)?).ok()?;
+                let sub_leaf = match tokens.next()? {
+                    "all" => None,
+                    token => Some(u32::try_from(parse_number(token)?).ok()?),
+                };
+
+                let hook = match tokens.next()? {
+                    "replace" => CpuidHook::Replace(parse_registers(&mut tokens)?),
+                    "modify" => CpuidHook::Modify {
+                        and_mask: parse_registers(&mut tokens)?,
+                        or_mask: parse_registers(&mut tokens)?,
+                    },
+                    _ => return None,
+                };
+
+                self.cpuid_hooks.push(SavedCpuidHook { leaf, sub_leaf, hook });
+            }
+            _ => return None,
+        }
+
+        // A setting has no trailing token.
+        match tokens.next() {
+            Some(_) => None,
+            None => Some(()),
+        }
+    }
+
+    /// Returns the text of the configuration.
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# Illusion persistent configuration\n");
+
+        if let Some(presence) = self.presence {
+            let presence = match presence {
+                HypervisorPresence::Hidden => "hidden",
+                HypervisorPresence::Exposed => "exposed",
+            };
+            text += &format!("presence {}\n", presence);
+        }
+
+        if let Some(config) = self.tsc_compensation {
+            let exit_latency_ticks = match config.exit_latency_ticks {
+                Some(ticks) => format!("{:#x}", ticks),
+                None => String::from("calibrated"),
+            };
+            text += &format!(
+                "tsc_compensation {} {} {:#x} {}\n",
+                switch_name(config.enabled),
+                switch_name(config.rdtsc_exiting),
+                config.tsc_multiplier,
+                exit_latency_ticks
+            );
+        }
+
+        for cpuid_hook in &self.cpuid_hooks {
+            let sub_leaf = match cpuid_hook.sub_leaf {
+                Some(sub_leaf) => format!("{:#x}", sub_leaf),
+                None => String::from("all"),
+            };
+
+            let hook = match cpuid_hook.hook {
+                CpuidHook::Replace(registers) => format!("replace {}", registers_text(&registers)),
+                CpuidHook::Modify { and_mask, or_mask } => format!("modify {} {}", registers_text(&and_mask), registers_text(&or_mask)),
+                CpuidHook::Callback(_) => continue,
+            };
+
+            text += &format!("cpuid {:#x} {} {}\n", cpuid_hook.leaf, sub_leaf, hook);
+        }
+
+        for line in &self.manifest {
+            text += &format!("manifest {}\n", line);
+        }
+
+        text
+    }
+
+    /// Applies the configuration, replacing the current settings it holds.
+    ///
+    /// The CPUID overrides not calling a callback that aren't part of the configuration are unregistered, then the
+    /// hypervisor presence is composed with the saved override of leaf 1.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the configuration has been applied, or `Err(HypervisorError)` if too many CPUID overrides are
+    /// registered, the TSC compensation configuration isn't supported or the manifest is malformed, in which case the
+    /// settings before it remain applied.
+    pub fn apply(&self) -> Result<(), HypervisorError> {
+        {
+            let mut cpuid_hook_manager = SHARED_CPUID_HOOK_MANAGER.lock();
+
+            let stale_hooks: Vec<_> = cpuid_hook_manager
+                .hooks()
+                .filter(|(_, _, hook)| !matches!(hook, CpuidHook::Callback(_)))
+                .map(|(leaf, sub_leaf, _)| (leaf, sub_leaf))
+                .collect();
+
+            for (leaf, sub_leaf) in stale_hooks {
+                cpuid_hook_manager.unregister(leaf, sub_leaf);
+            }
+
+            for cpuid_hook in &self.cpuid_hooks {
+                cpuid_hook_manager.register(cpuid_hook.leaf, cpuid_hook.sub_leaf, cpuid_hook.hook)?;
+            }
+
+            if let Some(presence) = self.presence {
+                cpuid_hook_manager.set_presence(presence)?;
+            }
+        }
+
+        if let Some(config) = self.tsc_compensation {
+            SHARED_TSC_COMPENSATION.lock().configure(config)?;
+        }
+
+        if !self.manifest.is_empty() {
+            let mut manifest = String::new();
+            for line in &self.manifest {
+                manifest += line;
+                manifest += "\n";
+            }
+
+            BootHookManifest::initialize_shared_boot_hook_manifest(&manifest)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Saves the current runtime configuration to the configuration file, if it has been set up at boot.
+///
+/// # Returns
+///
+/// The length of the saved text in bytes, or `Err(HypervisorError)` if it hasn't been written to the disk.
+pub fn save_configuration() -> Result<usize, HypervisorError> {
+    let text = SavedConfiguration::capture().to_text();
+
+    SHARED_CONFIG_FILE
+        .lock()
+        .as_mut()
+        .ok_or(HypervisorError::ConfigFileNotInitialized)?
+        .write(&text)?;
+
+    Ok(text.len())
+}
+
+/// Discards the configuration saved to the configuration file, if it has been set up at boot, so the next boot uses
+/// the configuration selected at build time and the boot-time hook manifest.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the header has been written to the disk, otherwise `Err(HypervisorError)`.
+pub fn discard_saved_configuration() -> Result<(), HypervisorError> {
+    SHARED_CONFIG_FILE
+        .lock()
+        .as_mut()
+        .ok_or(HypervisorError::ConfigFileNotInitialized)?
+        .write("")
+}
+
+/// Parses and applies a configuration saved on a previous boot.
+///
+/// This must be called by the loader before the processors are virtualized, after the boot-time hook manifest is read.
+///
+/// # Arguments
+///
+/// * `text` - The text of the configuration.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the configuration has been applied, otherwise `Err(HypervisorError)`, in which case nothing is
+/// applied if the text is malformed.
+pub fn apply_saved_configuration(text: &str) -> Result<(), HypervisorError> {
+    let configuration = SavedConfiguration::parse(text)?;
+
+    debug!("Applying saved configuration: {:x?}", configuration);
+
+    configuration.apply()
+}
+
+/// Parses a number in decimal or `0x` hexadecimal.
+///
+/// # Arguments
+///
+/// * `token` - The number.
+fn parse_number(token: &str) -> Option<u64> {
+    match token.strip_prefix("0x") {
+        Some(hexadecimal) => u64::from_str_radix(hexadecimal, 16).ok(),
+        None => token.parse().ok(),
+    }
+}
+
+/// Parses an `on` or `off` switch.
+///
+/// # Arguments
+///
+/// * `token` - The switch.
+fn parse_switch(token: &str) -> Option<bool> {
+    match token {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Returns the name of a switch.
+///
+/// # Arguments
+///
+/// * `enabled` - The state of the switch.
+fn switch_name(enabled: bool) -> &'static str {
+    match enabled {
+        true => "on",
+        false => "off",
+    }
+}
+
+/// Parses the values of the registers EAX, EBX, ECX and EDX.
+///
+/// # Arguments
+///
+/// * `tokens` - The remaining tokens of the line.
+fn parse_registers<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Option<[u32; 4]> {
+    let mut registers = [0u32; 4];
+
+    for register in &mut registers {
+        *register = u32::try_from(parse_number(tokens.next()?)?).ok()?;
+    }
+
+    Some(registers)
+}
+
+/// Returns the values of the registers EAX, EBX, ECX and EDX, in hexadecimal.
+///
+/// # Arguments
+///
+/// * `registers` - The values of the registers.
+fn registers_text(registers: &[u32; 4]) -> String {
+    let [eax, ebx, ecx, edx] = registers;
+    format!("{:#x} {:#x} {:#x} {:#x}", eax, ebx, ecx, edx)
+}
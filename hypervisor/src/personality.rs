@@ -0,0 +1,93 @@
+//! Provides the personality of the guest operating system, which gates the logic specific to an operating system,
+//! such as the capture of the kernel base from the IA32_LSTAR write, the syscall trampoline in ntoskrnl.exe and the
+//! structure offsets of the `windows` module, so other guests don't run into Windows-specific code paths.
+//!
+//! The personality is selected at boot by the loader, or detected from the syscall entry written to IA32_LSTAR by the
+//! first logical processor: Linux maps its kernel text in the top 2 GB of the address space (`__START_KERNEL_map`),
+//! where Windows never loads ntoskrnl.exe.
+
+use {
+    core::sync::atomic::{AtomicU8, Ordering},
+    log::*,
+};
+
+/// The first virtual address of the kernel text mapping of Linux, `__START_KERNEL_map`.
+pub const LINUX_KERNEL_TEXT_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+
+/// The personality of the guest, a `GuestPersonality`.
+static GUEST_PERSONALITY: AtomicU8 = AtomicU8::new(GuestPersonality::Unknown as u8);
+
+/// The operating system of the guest.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestPersonality {
+    /// The guest hasn't been identified yet, or isn't a supported operating system.
+    Unknown = 0,
+
+    /// Windows 10 or Windows 11, x64.
+    Windows = 1,
+
+    /// Linux, x86-64.
+    Linux = 2,
+}
+
+impl GuestPersonality {
+    /// Converts a `u8` value to a `GuestPersonality`.
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => GuestPersonality::Windows,
+            2 => GuestPersonality::Linux,
+            _ => GuestPersonality::Unknown,
+        }
+    }
+}
+
+/// Returns the personality of the guest.
+pub fn guest_personality() -> GuestPersonality {
+    GuestPersonality::from_u8(GUEST_PERSONALITY.load(Ordering::Acquire))
+}
+
+/// Returns `true` if the guest is Windows, so the Windows-specific logic applies.
+pub fn is_windows_guest() -> bool {
+    guest_personality() == GuestPersonality::Windows
+}
+
+/// Returns `true` if the guest is Linux, so the Linux-specific logic applies.
+pub fn is_linux_guest() -> bool {
+    guest_personality() == GuestPersonality::Linux
+}
+
+/// Selects the personality of the guest at boot, before the processors are virtualized, instead of detecting it.
+///
+/// # Arguments
+///
+/// * `personality` - The personality of the guest.
+pub fn set_guest_personality(personality: GuestPersonality) {
+    debug!("Guest personality selected: {:?}", personality);
+    GUEST_PERSONALITY.store(personality as u8, Ordering::Release);
+}
+
+/// Detects the personality of the guest from the syscall entry written to IA32_LSTAR, unless it has already been
+/// selected or detected.
+///
+/// # Arguments
+///
+/// * `syscall_entry` - The syscall entry written to IA32_LSTAR.
+///
+/// # Returns
+///
+/// The personality of the guest.
+pub fn detect_guest_personality(syscall_entry: u64) -> GuestPersonality {
+    let detected = match syscall_entry >= LINUX_KERNEL_TEXT_BASE {
+        true => GuestPersonality::Linux,
+        false => GuestPersonality::Windows,
+    };
+
+    match GUEST_PERSONALITY.compare_exchange(GuestPersonality::Unknown as u8, detected as u8, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {
+            info!("Guest personality detected from syscall entry {:#x}: {:?}", syscall_entry, detected);
+            detected
+        }
+        Err(personality) => GuestPersonality::from_u8(personality),
+    }
+}
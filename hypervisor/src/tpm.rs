@@ -0,0 +1,291 @@
+//! Provides a minimal TPM 2.0 client extending a PCR, used by the hypervisor to record measurements in the TPM without
+//! going through the guest's TPM driver.
+//!
+//! Commands are sent at locality 0 of the TPM, through either the FIFO (TIS) or the Command Response Buffer (CRB)
+//! interface, as reported by the interface identifier register. This is best-effort, as the guest's driver isn't
+//! synchronized with beyond requesting and relinquishing the locality.
+//!
+//! Reference: TCG PC Client Platform TPM Profile (PTP) Specification, Family "2.0", Level 00, Revision 01.05
+//! Reference: Trusted Platform Module Library, Part 3: Commands, 22.2 TPM2_PCR_Extend
+
+use {
+    crate::error::HypervisorError,
+    core::ptr::{read_volatile, write_volatile},
+    log::*,
+};
+
+/// The physical address of the registers of locality 0.
+const TPM_LOCALITY_0: u64 = 0xFED4_0000;
+
+/// The offset of the Interface Identifier register (TPM_INTERFACE_ID), common to both interfaces.
+const TPM_INTERFACE_ID: u64 = 0x30;
+
+/// The interface type of the CRB interface, bits 3:0 of TPM_INTERFACE_ID.
+const INTERFACE_TYPE_CRB: u32 = 0x1;
+
+/// The offset of the Access register (TPM_ACCESS) of the FIFO interface.
+const FIFO_ACCESS: u64 = 0x00;
+
+/// The offset of the Status register (TPM_STS) of the FIFO interface.
+const FIFO_STS: u64 = 0x18;
+
+/// The offset of the Data FIFO register (TPM_DATA_FIFO) of the FIFO interface.
+const FIFO_DATA: u64 = 0x24;
+
+/// The requestUse bit of TPM_ACCESS.
+const ACCESS_REQUEST_USE: u8 = 1 << 1;
+
+/// The activeLocality bit of TPM_ACCESS, written to relinquish the locality.
+const ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+
+/// The tpmRegValidSts bit of TPM_ACCESS.
+const ACCESS_VALID: u8 = 1 << 7;
+
+/// The dataAvail bit of TPM_STS.
+const STS_DATA_AVAIL: u32 = 1 << 4;
+
+/// The tpmGo bit of TPM_STS.
+const STS_GO: u32 = 1 << 5;
+
+/// The commandReady bit of TPM_STS.
+const STS_COMMAND_READY: u32 = 1 << 6;
+
+/// The stsValid bit of TPM_STS.
+const STS_VALID: u32 = 1 << 7;
+
+/// The offset of the Locality State register (TPM_LOC_STATE) of the CRB interface.
+const CRB_LOC_STATE: u64 = 0x00;
+
+/// The offset of the Locality Control register (TPM_LOC_CTRL) of the CRB interface.
+const CRB_LOC_CTRL: u64 = 0x08;
+
+/// The offset of the Control Area Request register (TPM_CRB_CTRL_REQ) of the CRB interface.
+const CRB_CTRL_REQ: u64 = 0x40;
+
+/// The offset of the Control Area Status register (TPM_CRB_CTRL_STS) of the CRB interface.
+const CRB_CTRL_STS: u64 = 0x44;
+
+/// The offset of the Control Area Start register (TPM_CRB_CTRL_START) of the CRB interface.
+const CRB_CTRL_START: u64 = 0x4C;
+
+/// The offset of the command buffer address registers (TPM_CRB_CTRL_CMD_LADDR and _HADDR) of the CRB interface.
+const CRB_CTRL_CMD_ADDR: u64 = 0x5C;
+
+/// The offset of the response buffer address register (TPM_CRB_CTRL_RSP_ADDR) of the CRB interface.
+const CRB_CTRL_RSP_ADDR: u64 = 0x68;
+
+/// The locAssigned and tpmRegValidSts bits of TPM_LOC_STATE.
+const LOC_STATE_ASSIGNED_VALID: u32 = (1 << 1) | (1 << 7);
+
+/// The requestAccess bit of TPM_LOC_CTRL.
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+
+/// The relinquish bit of TPM_LOC_CTRL.
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+
+/// The cmdReady bit of TPM_CRB_CTRL_REQ.
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+
+/// The goIdle bit of TPM_CRB_CTRL_REQ.
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+
+/// The tpmSts (fatal error) bit of TPM_CRB_CTRL_STS.
+const CTRL_STS_ERROR: u32 = 1 << 0;
+
+/// The number of times the registers are polled before giving up on the TPM.
+const POLL_ITERATIONS: usize = 10_000_000;
+
+/// The size of a TPM2_PCR_Extend command with a password session and a single SHA-256 digest.
+const PCR_EXTEND_COMMAND_SIZE: usize = 65;
+
+/// The size of a response header: tag, size and response code.
+const RESPONSE_HEADER_SIZE: usize = 10;
+
+/// Extends a PCR of the SHA-256 bank with a digest.
+///
+/// # Arguments
+///
+/// * `pcr_index` - The index of the PCR to extend.
+/// * `digest` - The SHA-256 digest to extend the PCR with.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the PCR has been extended, otherwise `Err(HypervisorError)`.
+pub fn extend_pcr(pcr_index: u32, digest: &[u8; 32]) -> Result<(), HypervisorError> {
+    // An absent TPM reads as all ones.
+    let interface_id = read_register::<u32>(TPM_INTERFACE_ID);
+    if interface_id == u32::MAX {
+        return Err(HypervisorError::TpmNotFound);
+    }
+
+    let command = pcr_extend_command(pcr_index, digest);
+    let mut response = [0u8; RESPONSE_HEADER_SIZE];
+
+    if interface_id & 0xF == INTERFACE_TYPE_CRB {
+        trace!("Sending TPM2_PCR_Extend through the CRB interface");
+        submit_crb(&command, &mut response)?;
+    } else {
+        trace!("Sending TPM2_PCR_Extend through the FIFO interface");
+        submit_fifo(&command, &mut response)?;
+    }
+
+    let response_code = u32::from_be_bytes([response[6], response[7], response[8], response[9]]);
+    if response_code != 0 {
+        error!("TPM2_PCR_Extend of PCR {} failed with response code: {:#x}", pcr_index, response_code);
+        return Err(HypervisorError::TpmCommandFailed);
+    }
+
+    debug!("Extended PCR {} with digest: {:02x?}", pcr_index, digest);
+    Ok(())
+}
+
+/// Builds a TPM2_PCR_Extend command authorized with an empty password, as PCRs are authorized by default.
+///
+/// # Arguments
+///
+/// * `pcr_index` - The index of the PCR to extend.
+/// * `digest` - The SHA-256 digest to extend the PCR with.
+fn pcr_extend_command(pcr_index: u32, digest: &[u8; 32]) -> [u8; PCR_EXTEND_COMMAND_SIZE] {
+    let mut command = [0u8; PCR_EXTEND_COMMAND_SIZE];
+
+    // TPM_ST_SESSIONS, commandSize, TPM_CC_PCR_Extend and the PCR handle.
+    command[0..2].copy_from_slice(&0x8002u16.to_be_bytes());
+    command[2..6].copy_from_slice(&(PCR_EXTEND_COMMAND_SIZE as u32).to_be_bytes());
+    command[6..10].copy_from_slice(&0x0000_0182u32.to_be_bytes());
+    command[10..14].copy_from_slice(&pcr_index.to_be_bytes());
+
+    // authorizationSize, then TPM_RS_PW with an empty nonce, no attributes and an empty password.
+    command[14..18].copy_from_slice(&9u32.to_be_bytes());
+    command[18..22].copy_from_slice(&0x4000_0009u32.to_be_bytes());
+
+    // A list of one digest, TPM_ALG_SHA256.
+    command[27..31].copy_from_slice(&1u32.to_be_bytes());
+    command[31..33].copy_from_slice(&0x000Bu16.to_be_bytes());
+    command[33..].copy_from_slice(digest);
+
+    command
+}
+
+/// Sends a command through the FIFO interface and reads the response header.
+///
+/// # Arguments
+///
+/// * `command` - The command to send.
+/// * `response` - The buffer the response header is read to.
+fn submit_fifo(command: &[u8], response: &mut [u8]) -> Result<(), HypervisorError> {
+    write_register::<u8>(FIFO_ACCESS, ACCESS_REQUEST_USE);
+    if !poll(|| read_register::<u8>(FIFO_ACCESS) & (ACCESS_VALID | ACCESS_ACTIVE_LOCALITY) == ACCESS_VALID | ACCESS_ACTIVE_LOCALITY) {
+        error!("TPM locality 0 is not available");
+        return Err(HypervisorError::TpmCommandTimeout);
+    }
+
+    let result = (|| {
+        write_register::<u32>(FIFO_STS, STS_COMMAND_READY);
+        if !poll(|| read_register::<u32>(FIFO_STS) & STS_COMMAND_READY != 0) {
+            return Err(HypervisorError::TpmCommandTimeout);
+        }
+
+        for &byte in command {
+            // Bits 23:8 of TPM_STS are the number of bytes the FIFO can take without waiting.
+            if !poll(|| (read_register::<u32>(FIFO_STS) >> 8) & 0xFFFF != 0) {
+                return Err(HypervisorError::TpmCommandTimeout);
+            }
+
+            write_register::<u8>(FIFO_DATA, byte);
+        }
+
+        write_register::<u32>(FIFO_STS, STS_GO);
+        if !poll(|| read_register::<u32>(FIFO_STS) & (STS_VALID | STS_DATA_AVAIL) == STS_VALID | STS_DATA_AVAIL) {
+            return Err(HypervisorError::TpmCommandTimeout);
+        }
+
+        for byte in response.iter_mut() {
+            *byte = read_register::<u8>(FIFO_DATA);
+        }
+
+        // Abort the rest of the response, if any, and make the TPM ready for the guest's next command.
+        write_register::<u32>(FIFO_STS, STS_COMMAND_READY);
+        Ok(())
+    })();
+
+    write_register::<u8>(FIFO_ACCESS, ACCESS_ACTIVE_LOCALITY);
+    result
+}
+
+/// Sends a command through the CRB interface and reads the response header.
+///
+/// # Arguments
+///
+/// * `command` - The command to send.
+/// * `response` - The buffer the response header is read to.
+fn submit_crb(command: &[u8], response: &mut [u8]) -> Result<(), HypervisorError> {
+    write_register::<u32>(CRB_LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+    if !poll(|| read_register::<u32>(CRB_LOC_STATE) & LOC_STATE_ASSIGNED_VALID == LOC_STATE_ASSIGNED_VALID) {
+        error!("TPM locality 0 is not available");
+        return Err(HypervisorError::TpmCommandTimeout);
+    }
+
+    let result = (|| {
+        write_register::<u32>(CRB_CTRL_REQ, CTRL_REQ_CMD_READY);
+        if !poll(|| read_register::<u32>(CRB_CTRL_REQ) & CTRL_REQ_CMD_READY == 0) {
+            return Err(HypervisorError::TpmCommandTimeout);
+        }
+
+        let command_pa = read_register::<u32>(CRB_CTRL_CMD_ADDR) as u64 | (read_register::<u32>(CRB_CTRL_CMD_ADDR + 4) as u64) << 32;
+        let response_pa = read_register::<u64>(CRB_CTRL_RSP_ADDR);
+
+        for (index, &byte) in command.iter().enumerate() {
+            unsafe { write_volatile((command_pa + index as u64) as *mut u8, byte) };
+        }
+
+        write_register::<u32>(CRB_CTRL_START, 1);
+        if !poll(|| read_register::<u32>(CRB_CTRL_START) & 1 == 0) {
+            return Err(HypervisorError::TpmCommandTimeout);
+        }
+
+        if read_register::<u32>(CRB_CTRL_STS) & CTRL_STS_ERROR != 0 {
+            return Err(HypervisorError::TpmCommandFailed);
+        }
+
+        for (index, byte) in response.iter_mut().enumerate() {
+            *byte = unsafe { read_volatile((response_pa + index as u64) as *const u8) };
+        }
+
+        write_register::<u32>(CRB_CTRL_REQ, CTRL_REQ_GO_IDLE);
+        Ok(())
+    })();
+
+    write_register::<u32>(CRB_LOC_CTRL, LOC_CTRL_RELINQUISH);
+    result
+}
+
+/// Polls the TPM until a condition is met.
+///
+/// # Arguments
+///
+/// * `condition` - The condition to wait for.
+///
+/// # Returns
+///
+/// `true` if the condition has been met, `false` if the polling timed out.
+fn poll(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..POLL_ITERATIONS {
+        if condition() {
+            return true;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    false
+}
+
+/// Reads a register of locality 0.
+fn read_register<T>(offset: u64) -> T {
+    unsafe { read_volatile((TPM_LOCALITY_0 + offset) as *const T) }
+}
+
+/// Writes a register of locality 0.
+fn write_register<T>(offset: u64, value: T) {
+    unsafe { write_volatile((TPM_LOCALITY_0 + offset) as *mut T, value) }
+}
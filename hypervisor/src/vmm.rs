@@ -8,38 +8,39 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            bitmap::MsrAccessType,
+            broadcast::{join_broadcasts, sync_broadcast},
             capture::GuestRegisters,
-            support::{rdmsr, vmread, vmwrite},
+            debug_registers::sync_debug_registers,
+            determinism::sync_deterministic_mode,
+            devirtualize::{devirtualize_processor, is_devirtualization_requested},
+            ept_view::sync_ept_views,
+            event_ring::wait_for_event_rings,
+            events::EventInjection,
+            exception_telemetry::sync_exception_telemetry,
+            exit_statistics::record_exit_latency,
+            hooks::{
+                boot_manifest::sync_boot_hooks, cpuid_hook::sync_cpuid_hooks, exception_hook::sync_exception_hooks, hook_view::sync_hook_views,
+                msr_hook::sync_msr_hooks, syscall_hook::sync_syscall_hooks,
+            },
+            host_exception::{dispatch_root_nmis, record_exit},
+            process_tracker::sync_process_tracker,
+            profiler::sync_profiler,
+            scheduler::sync_scheduler,
+            seqlock::is_any_published,
+            sleep::enter_waking_vector,
+            support::{rdmsr, rdtsc, vmread, vmwrite},
+            tsc_compensation::{compensate_exit_time, sync_tsc_compensation},
             vm::Vm,
             vmerror::VmxBasicExitReason,
-            vmexit::{
-                cpuid::handle_cpuid,
-                cr::handle_cr_reg_access,
-                ept_misconfiguration::handle_ept_misconfiguration,
-                ept_violation::handle_ept_violation,
-                exception::{handle_exception, handle_undefined_opcode_exception},
-                halt::handle_halt,
-                init::handle_init_signal,
-                invd::handle_invd,
-                invept::handle_invept,
-                invvpid::handle_invvpid,
-                msr::handle_msr_access,
-                mtf::handle_monitor_trap_flag,
-                rdtsc::handle_rdtsc,
-                sipi::handle_sipi_signal,
-                vmcall::handle_vmcall,
-                vmxon::handle_vmxon,
-                xsetbv::handle_xsetbv,
-                ExitType,
-            },
+            vmexit::{dispatch::dispatch_vmexit, ExitType},
+            watchdog::sync_watchdog,
         },
         windows::eprocess::ProcessInformation,
     },
     log::*,
     x86::{
-        msr::IA32_VMX_EPT_VPID_CAP,
-        vmx::vmcs::{guest, ro},
+        msr::{IA32_VMX_EPT_VPID_CAP, IA32_VMX_PROCBASED_CTLS2},
+        vmx::vmcs::{control::SecondaryControls, guest, ro},
     },
 };
 
@@ -84,98 +85,162 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
 
     #[cfg(feature = "hide_hv_with_ept")]
     {
-        debug!("Hiding hypervisor memory... (NOTE: EPT HOOKS WON'T WORK IF THIS IS ENABLED UNLESS SHADOW PAGES ARE EXCLUDED)");
+        debug!("Hiding hypervisor memory...");
+        crate::intel::host_config::SHARED_HOST_CONFIG.read().print_allocated_memory();
         let mut hook_manager = crate::intel::hooks::hook_manager::SHARED_HOOK_MANAGER.lock();
-        hook_manager.print_allocated_memory();
         match hook_manager.hide_hypervisor_memory(&mut vm, crate::intel::ept::AccessType::READ_WRITE_EXECUTE) {
             Ok(_) => debug!("Hypervisor memory hidden"),
             Err(e) => panic!("Failed to hide hypervisor memory: {:?}", e),
         };
     }
 
+    #[cfg(feature = "timing_normalization")]
+    {
+        debug!("Intercepting the HPET and ACPI PM timer clock sources");
+        match crate::intel::timing::intercept_clock_sources(&mut vm) {
+            Ok(_) => debug!("Clock sources intercepted"),
+            Err(e) => panic!("Failed to intercept clock sources: {:?}", e),
+        };
+    }
+
+    #[cfg(feature = "device_hiding")]
+    {
+        debug!("Hiding devices from the guest");
+        match crate::intel::device_hiding::intercept_hidden_devices(&mut vm) {
+            Ok(_) => debug!("Devices hidden"),
+            Err(e) => panic!("Failed to hide devices: {:?}", e),
+        };
+    }
+
+    #[cfg(feature = "reset_control")]
+    {
+        debug!("Intercepting the reset and sleep ports");
+        crate::intel::reset::intercept_reset_ports(&mut vm);
+    }
+
+    // On the wake from S3, the guest starts at its waking vector instead of the state captured at boot.
+    enter_waking_vector(&mut vm);
+
+    debug!("Joining the broadcast commands");
+    join_broadcasts(&mut vm);
+
     info!("Launching the VM until a vmexit occurs...");
 
     loop {
         if let Ok(basic_exit_reason) = vm.run() {
-            // Log the VM exit reason along with the current process information, only if available
-            if let Some(p) = ProcessInformation::get_current_process_info() {
-                debug!(
-                    "VM exit reason: {:?}, ImageFileName: {}, UniqueProcessId: {}, DirectoryTableBase: {:#x}",
-                    basic_exit_reason, p.file_name, p.unique_process_id, p.directory_table_base
-                );
-            } else {
-                if basic_exit_reason != VmxBasicExitReason::Cpuid {
+            let exit_tsc = rdtsc();
+
+            // Keep the last VM exits of this processor for the report of a host exception.
+            record_exit(&mut vm, basic_exit_reason, exit_tsc);
+
+            // Log the VM exit reason along with the current process information, only if available. The process is
+            // read from the guest memory, so only while the VM exits are logged.
+            if log_enabled!(Level::Debug) {
+                if let Some(p) = ProcessInformation::get_current_process_info() {
+                    debug!(
+                        "VM exit reason: {:?}, ImageFileName: {}, UniqueProcessId: {}, DirectoryTableBase: {:#x}",
+                        basic_exit_reason, p.file_name, p.unique_process_id, p.directory_table_base
+                    );
+                } else if basic_exit_reason != VmxBasicExitReason::Cpuid {
                     debug!("VM exit reason: {:?}", basic_exit_reason);
                 }
             }
 
-            let exit_type = match basic_exit_reason {
-                // 0
-                VmxBasicExitReason::ExceptionOrNmi => handle_exception(&mut vm),
-                // 3
-                VmxBasicExitReason::InitSignal => handle_init_signal(&mut vm.guest_registers),
-                // 4
-                VmxBasicExitReason::StartupIpi => handle_sipi_signal(&mut vm.guest_registers),
-                // 10
-                VmxBasicExitReason::Cpuid => handle_cpuid(&mut vm).expect("Failed to handle CPUID"),
-                // 11
-                VmxBasicExitReason::Getsec => handle_undefined_opcode_exception(),
-                // 12
-                VmxBasicExitReason::Hlt => handle_halt(),
-                // 13
-                VmxBasicExitReason::Invd => handle_invd(&mut vm.guest_registers),
-                // 18
-                VmxBasicExitReason::Vmcall => handle_vmcall(&mut vm).expect("Failed to handle VMCALL"),
-                // 19
-                VmxBasicExitReason::Vmclear => handle_undefined_opcode_exception(),
-                // 20
-                VmxBasicExitReason::Vmlaunch => handle_undefined_opcode_exception(),
-                // 21
-                VmxBasicExitReason::Vmptrld => handle_undefined_opcode_exception(),
-                // 22
-                VmxBasicExitReason::Vmptrst => handle_undefined_opcode_exception(),
-                // 23
-                VmxBasicExitReason::Vmread => handle_undefined_opcode_exception(),
-                // 24
-                VmxBasicExitReason::Vmresume => handle_undefined_opcode_exception(),
-                // 25
-                VmxBasicExitReason::Vmwrite => handle_undefined_opcode_exception(),
-                // 26
-                VmxBasicExitReason::Vmxoff => handle_undefined_opcode_exception(),
-                // 27
-                VmxBasicExitReason::Vmxon => handle_vmxon(),
-                // 28
-                VmxBasicExitReason::ControlRegisterAccesses => handle_cr_reg_access(&mut vm).expect("Failed to handle CR access"),
-                // 31
-                VmxBasicExitReason::Rdmsr => handle_msr_access(&mut vm, MsrAccessType::Read).expect("Failed to handle RDMSR"),
-                // 32
-                VmxBasicExitReason::Wrmsr => handle_msr_access(&mut vm, MsrAccessType::Write).expect("Failed to handle WRMSR"),
-                // 37
-                VmxBasicExitReason::MonitorTrapFlag => handle_monitor_trap_flag(&mut vm).expect("Failed to handle Monitor Trap Flag"),
-                // 48
-                VmxBasicExitReason::EptViolation => handle_ept_violation(&mut vm).expect("Failed to handle EPT violation"),
-                // 49
-                VmxBasicExitReason::EptMisconfiguration => handle_ept_misconfiguration(&mut vm).expect("Failed to handle EPT misconfiguration"),
-                // 50
-                VmxBasicExitReason::Invept => handle_invept(),
-                // 51
-                VmxBasicExitReason::Rdtsc => handle_rdtsc(&mut vm.guest_registers),
-                // 53
-                VmxBasicExitReason::Invvpid => handle_invvpid(),
-                // 55
-                VmxBasicExitReason::Xsetbv => handle_xsetbv(&mut vm),
-                _ => panic!("Unhandled VM exit reason: {:?}", basic_exit_reason),
-            };
+            let exit_type = dispatch_vmexit(&mut vm, basic_exit_reason);
 
             if exit_type == ExitType::IncrementRIP {
                 advance_guest_rip(&mut vm.guest_registers);
             }
+
+            // Count the VM exit with the latency of its handler, before the synchronizations.
+            record_exit_latency(&mut vm, basic_exit_reason, exit_tsc);
+
+            // Re-inject the event whose delivery the VM exit interrupted, e.g., a page fault delivered through a hooked page.
+            EventInjection::vmentry_reinject_interrupted_event();
+
+            // Dispatch the NMIs received in VMX root operation, e.g., during this VM exit.
+            dispatch_root_nmis(&mut vm);
+
+            // Inject the queued NMI or external interrupt the guest accepts, and request the windows of the others.
+            EventInjection::vmentry_inject_pending_events(&mut vm);
+
+            // Hide the memory ranges recorded since this processor was virtualized, e.g., the stacks of the other processors.
+            #[cfg(feature = "hide_hv_with_ept")]
+            if vm.hidden_memory_range_count != crate::intel::host_config::SHARED_HOST_CONFIG.read().allocated_memory_ranges.len() {
+                let mut hook_manager = crate::intel::hooks::hook_manager::SHARED_HOOK_MANAGER.lock();
+                if let Err(e) = hook_manager.hide_hypervisor_memory(&mut vm, crate::intel::ept::AccessType::READ_WRITE_EXECUTE) {
+                    error!("Failed to hide hypervisor memory: {:?}", e);
+                }
+                drop(hook_manager);
+
+                if let Err(e) = crate::intel::ept_view::SHARED_EPT_VIEWS.lock().hide_hypervisor_memory_in_views() {
+                    error!("Failed to hide hypervisor memory in the EPT views: {:?}", e);
+                }
+            }
+
+            sync_boot_hooks(&mut vm);
+            sync_published_state(&mut vm);
+
+            // The hook view follows the address space of the guest, so it's checked on every VM exit.
+            sync_hook_views(&mut vm);
+
+            // Execute the commands broadcast to all the logical processors, spinning here while paused.
+            sync_broadcast(&mut vm);
+
+            // Wait for the client to drain the stalling event rings this exit recorded into, without any lock held.
+            wait_for_event_rings(&vm);
+
+            #[cfg(feature = "exit_storm_detection")]
+            crate::intel::exit_storm::monitor_exit(&mut vm, basic_exit_reason, exit_tsc);
+
+            #[cfg(feature = "timing_normalization")]
+            crate::intel::timing::account_exit_time(&mut vm, exit_tsc);
+
+            #[cfg(feature = "benchmark")]
+            crate::intel::benchmark::record_benchmark_exit(&mut vm, basic_exit_reason, exit_tsc);
+
+            // Hide the time spent in VMX root operation from the guest TSC, last before resuming the guest.
+            compensate_exit_time(&mut vm, exit_tsc);
+
+            // Leave VMX operation instead of resuming the guest while the hypervisor is being unloaded, once the guest
+            // can be resumed natively.
+            if is_devirtualization_requested() {
+                devirtualize_processor(&vm);
+            }
         } else {
             panic!("Failed to run the VM");
         }
     }
 }
 
+/// Picks up the values published since the last VM exit of the current logical processor, e.g., the configurations of
+/// the features or the changes of the hook registries.
+///
+/// This is called on every VM exit, and only loads the global generation when nothing was published.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current logical processor.
+fn sync_published_state(vm: &mut Vm) {
+    if !is_any_published(&mut vm.published_generation) {
+        return;
+    }
+
+    sync_msr_hooks(vm);
+    sync_syscall_hooks(vm);
+    sync_profiler(vm);
+    sync_watchdog(vm);
+    sync_scheduler(vm);
+    sync_deterministic_mode(vm);
+    sync_ept_views(vm);
+    sync_process_tracker(vm);
+    sync_exception_telemetry(vm);
+    sync_exception_hooks(vm);
+    sync_debug_registers(vm);
+    sync_tsc_compensation(vm);
+    sync_cpuid_hooks(vm);
+}
+
 /// Advances the guest's instruction pointer after handling a VM exit.
 ///
 /// Ensures the guest VM does not re-execute the instruction causing the VM exit
@@ -216,6 +281,80 @@ fn check_supported_cpu() -> Result<(), HypervisorError> {
     Ok(())
 }
 
+/// Runs all hardware capability checks and prints a compatibility report without virtualizing the processor.
+///
+/// The required checks are the same as the ones performed by `start_hypervisor`. The optional features are not
+/// needed by the hypervisor today, but are reported so a machine can be validated before committing to a hooked boot.
+///
+/// # Returns
+///
+/// Returns `true` if the processor meets all requirements to run the hypervisor, otherwise `false`.
+pub fn print_compatibility_report() -> bool {
+    /// [Bit 0 of IA32_VMX_EPT_VPID_CAP] When set to 1, the processor supports execute-only EPT translations.
+    const EXECUTE_ONLY: u64 = 1 << 0;
+
+    /// [Bit 21 of IA32_VMX_EPT_VPID_CAP] When set to 1, accessed and dirty flags for EPT are supported.
+    const ACCESSED_DIRTY_FLAGS: u64 = 1 << 21;
+
+    /// [Bit 25 of IA32_VMX_EPT_VPID_CAP] When set to 1, the single-context INVEPT type is supported.
+    const INVEPT_SINGLE_CONTEXT: u64 = 1 << 25;
+
+    /// [Bit 40 of IA32_VMX_EPT_VPID_CAP] When set to 1, the individual-address INVVPID type is supported.
+    const INVVPID_INDIVIDUAL_ADDRESS: u64 = 1 << 40;
+
+    /// Reports the result of a single check and returns whether it passed.
+    fn report(name: &str, result: Result<(), HypervisorError>) -> bool {
+        match &result {
+            Ok(()) => info!("[+] {}: supported", name),
+            Err(e) => error!("[-] {}: {}", name, e),
+        }
+        result.is_ok()
+    }
+
+    /// Reports whether an optional feature is available.
+    fn report_optional(name: &str, supported: bool) {
+        if supported {
+            info!("[+] {}: supported", name);
+        } else {
+            warn!("[!] {}: unsupported", name);
+        }
+    }
+
+    info!("Hardware compatibility report:");
+
+    let mut is_compatible = report("Intel CPU", has_intel_cpu());
+    is_compatible &= report("Memory Type Range Registers (MTRRs)", has_mtrr());
+
+    // The VMX capability MSRs can only be read if VMX is supported, otherwise `rdmsr` raises #GP.
+    if !report("Virtual Machine Extension (VMX)", has_vmx_support()) {
+        error!("Hardware is not compatible with the hypervisor");
+        return false;
+    }
+
+    is_compatible &= report("Extended Page Tables (EPT)", check_ept_support());
+
+    // Bits 63:32 of the secondary processor-based controls MSR indicate the allowed 1-settings.
+    let secondary_controls = SecondaryControls::from_bits_truncate((rdmsr(IA32_VMX_PROCBASED_CTLS2) >> 32) as u32);
+    let ept_vpid_cap = rdmsr(IA32_VMX_EPT_VPID_CAP);
+
+    is_compatible &= report("Unrestricted guest", has_unrestricted_guest_support(secondary_controls));
+
+    report_optional("Execute-only EPT translations", ept_vpid_cap & EXECUTE_ONLY != 0);
+    report_optional("EPT accessed and dirty flags", ept_vpid_cap & ACCESSED_DIRTY_FLAGS != 0);
+    report_optional("Single-context INVEPT", ept_vpid_cap & INVEPT_SINGLE_CONTEXT != 0);
+    report_optional("Individual-address INVVPID", ept_vpid_cap & INVVPID_INDIVIDUAL_ADDRESS != 0);
+    report_optional("Page-modification logging (PML)", secondary_controls.contains(SecondaryControls::ENABLE_PML));
+    report_optional("TSC scaling", secondary_controls.contains(SecondaryControls::USE_TSC_SCALING));
+
+    if is_compatible {
+        info!("Hardware is compatible with the hypervisor");
+    } else {
+        error!("Hardware is not compatible with the hypervisor");
+    }
+
+    is_compatible
+}
+
 /// Verifies the CPU is from Intel.
 ///
 /// # Returns
@@ -266,9 +405,6 @@ fn check_ept_support() -> Result<(), HypervisorError> {
     /// [Bit 20] If bit 20 is read as 1, the INVEPT instruction is supported.
     const INVEPT: u64 = 1 << 20;
 
-    /// [Bit 25] When set to 1, the single-context INVEPT type is supported.
-    const INVEPT_SINGLE_CONTEXT: u64 = 1 << 25;
-
     /// [Bit 26] When set to 1, the all-context INVEPT type is supported.
     const INVEPT_ALL_CONTEXTS: u64 = 1 << 26;
 
@@ -288,7 +424,6 @@ fn check_ept_support() -> Result<(), HypervisorError> {
         | MEMORY_TYPE_WRITE_BACK
         | PDE_2MB_PAGES
         | INVEPT
-        | INVEPT_SINGLE_CONTEXT
         | INVEPT_ALL_CONTEXTS
         | INVVPID
         | INVVPID_SINGLE_CONTEXT
@@ -301,6 +436,22 @@ fn check_ept_support() -> Result<(), HypervisorError> {
     Ok(())
 }
 
+/// Checks for unrestricted guest support, which is required to run the guest in real mode after INIT-SIPI-SIPI.
+///
+/// # Arguments
+///
+/// * `secondary_controls` - The allowed 1-settings of the secondary processor-based VM-execution controls.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if unrestricted guest is supported, otherwise `Err(HypervisorError::UnrestrictedGuestUnsupported)`.
+fn has_unrestricted_guest_support(secondary_controls: SecondaryControls) -> Result<(), HypervisorError> {
+    if secondary_controls.contains(SecondaryControls::UNRESTRICTED_GUEST) {
+        return Ok(());
+    }
+    Err(HypervisorError::UnrestrictedGuestUnsupported)
+}
+
 /// Checks for Memory Type Range Registers (MTRRs) support on the CPU.
 ///
 /// # Returns
@@ -0,0 +1,311 @@
+//! Provides a code integrity monitor of the kernel of Windows guests, a PatchGuard running in the hypervisor: the pages
+//! of the code sections of ntoskrnl.exe and of selected drivers are hashed at steady state, when the monitor is
+//! configured, and periodically re-verified, each modification being logged and pushed to the event stream of the
+//! guest agent as a `CodeIntegrityViolation` event.
+//!
+//! The monitored pages are those of the sections that are executable, and neither writable nor discardable, so the
+//! sections modified by design, e.g., the data and the `INIT` code freed after boot, aren't reported. A page that isn't
+//! mapped, e.g., a paged-out page of a `PAGE` section, is skipped, and hashed the first time it is mapped if it wasn't
+//! at configuration. The pages are read through the guest physical memory, so the shadow pages of the EPT hooks aren't
+//! seen and the hooks of the hypervisor itself aren't reported.
+//!
+//! The verification is a periodic task of the `scheduler`, run from the VMX-preemption timer VM exit, so it also runs
+//! while the guest is idle. Each run verifies `PAGES_PER_RUN` pages, continuing where the previous run stopped, so a
+//! run stays short whatever the size of the monitored code, and a modified page is reported once, the page then being
+//! monitored against its new content.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            event_stream::stream_event,
+            hooks::hook_manager::{kernel_image, KernelImage},
+            scheduler::SHARED_SCHEDULER,
+            support::{rdtsc, vmread},
+            timing::tsc_frequency_hz,
+            vm::Vm,
+        },
+        sha256::{Sha256, DIGEST_SIZE},
+        windows::{
+            kernel::{enumerate_kernel_modules, is_module_name, read_section_headers, KernelModule},
+            nt::types::{IMAGE_SCN_MEM_DISCARDABLE, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_WRITE},
+        },
+    },
+    alloc::{string::String, vec::Vec},
+    lazy_static::lazy_static,
+    log::*,
+    shared::EventStreamKind,
+    spin::Mutex,
+    x86::{bits64::paging::BASE_PAGE_SIZE, vmx::vmcs},
+};
+
+/// The maximum number of monitored pages, 64 MiB of code.
+pub const MAX_CODE_INTEGRITY_PAGES: usize = 0x4000;
+
+/// The number of pages verified by each run of the periodic task.
+const PAGES_PER_RUN: usize = 0x40;
+
+lazy_static! {
+    /// A globally shared instance of `CodeIntegrityMonitor`, protected by a mutex.
+    pub static ref SHARED_CODE_INTEGRITY: Mutex<CodeIntegrityMonitor> = Mutex::new(CodeIntegrityMonitor::new());
+}
+
+/// A monitored page of code, or the part of it in a code section.
+#[derive(Debug, Clone, Copy)]
+struct MonitoredPage {
+    /// The virtual address of the monitored range.
+    va: u64,
+
+    /// The size of the monitored range, at most a page.
+    size: usize,
+
+    /// The base virtual address of the module of the page.
+    module_base_va: u64,
+
+    /// The hash of the range, or `None` until it is first mapped.
+    digest: Option<[u8; DIGEST_SIZE]>,
+}
+
+/// The monitored pages and the progress of their verification, shared by all the logical processors.
+#[derive(Debug)]
+pub struct CodeIntegrityMonitor {
+    /// The monitored pages, by module and address.
+    pages: Vec<MonitoredPage>,
+
+    /// The index of the next page verified.
+    next_page: usize,
+
+    /// The identifier of the periodic task, or `None` while the monitor is stopped.
+    task_id: Option<u64>,
+
+    /// The minimum number of TSC ticks between two runs, half the period, as the task runs on each logical processor.
+    min_run_interval: u64,
+
+    /// The TSC of the last run.
+    last_run_tsc: u64,
+
+    /// The number of times all the pages have been verified.
+    completed_passes: u64,
+
+    /// The number of modifications found since the monitor was configured.
+    violation_count: u64,
+}
+
+impl CodeIntegrityMonitor {
+    /// Creates a new stopped monitor, without pages.
+    fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            next_page: 0,
+            task_id: None,
+            min_run_interval: 0,
+            last_run_tsc: 0,
+            completed_passes: 0,
+            violation_count: 0,
+        }
+    }
+
+    /// Returns the number of monitored pages.
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Returns the number of modifications found since the monitor was configured.
+    pub fn violation_count(&self) -> u64 {
+        self.violation_count
+    }
+
+    /// Verifies the next pages, reporting the modified ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_pages` - The maximum number of pages verified.
+    fn verify_next_pages(&mut self, max_pages: usize) {
+        for _ in 0..max_pages.min(self.pages.len()) {
+            let page = &mut self.pages[self.next_page];
+
+            if let Some(digest) = hash_range(page.va, page.size) {
+                match page.digest {
+                    Some(expected) if expected != digest => {
+                        let page_pa = PhysicalAddress::pa_from_kernel_va(page.va).unwrap_or_default();
+
+                        warn!("Kernel code modified at {:#x} (PA: {:#x}), module: {:#x}", page.va, page_pa, page.module_base_va);
+                        stream_event(EventStreamKind::CodeIntegrityViolation, [page.va, page.module_base_va, page_pa, 0]);
+
+                        page.digest = Some(digest);
+                        self.violation_count += 1;
+                    }
+                    Some(_) => {}
+                    None => page.digest = Some(digest),
+                }
+            }
+
+            self.next_page += 1;
+
+            if self.next_page == self.pages.len() {
+                self.next_page = 0;
+                self.completed_passes += 1;
+
+                trace!("Code integrity pass {} completed, {} violations", self.completed_passes, self.violation_count);
+            }
+        }
+    }
+}
+
+/// Starts the monitor with the code of ntoskrnl.exe and of a set of drivers, hashing it, or stops it, replacing the
+/// monitored pages of a previous configuration.
+///
+/// # Arguments
+///
+/// * `period_ms` - The period of the verifications in milliseconds, or 0 to stop the monitor.
+/// * `drivers` - The base names of the drivers monitored besides ntoskrnl.exe, e.g., "ci.dll".
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of monitored pages, 0 if the monitor is stopped.
+/// * `Err(HypervisorError::GetKernelBaseFailed)` - If the base of ntoskrnl.exe isn't captured yet.
+/// * `Err(HypervisorError::KernelModuleNotFound)` - If a driver isn't loaded.
+/// * `Err(HypervisorError::TooManyCodeIntegrityPages)` - If the code exceeds `MAX_CODE_INTEGRITY_PAGES` pages.
+/// * `Err(HypervisorError)` - If the headers of an image can't be read, or the periodic task can't be registered.
+pub fn configure_code_integrity(period_ms: u64, drivers: &[String]) -> Result<usize, HypervisorError> {
+    let KernelImage {
+        base_va: ntoskrnl_base_va,
+        size: ntoskrnl_size,
+        ..
+    } = kernel_image();
+
+    let mut monitor = SHARED_CODE_INTEGRITY.lock();
+
+    if let Some(task_id) = monitor.task_id.take() {
+        SHARED_SCHEDULER.lock().unregister(task_id);
+    }
+
+    *monitor = CodeIntegrityMonitor::new();
+
+    if period_ms == 0 {
+        debug!("Code integrity monitor stopped");
+        return Ok(0);
+    }
+
+    if ntoskrnl_base_va == 0 {
+        return Err(HypervisorError::GetKernelBaseFailed);
+    }
+
+    let mut modules = Vec::new();
+    modules.push(KernelModule {
+        base_va: ntoskrnl_base_va,
+        size_of_image: ntoskrnl_size,
+        name: "ntoskrnl.exe".into(),
+    });
+
+    if !drivers.is_empty() {
+        let loaded_modules = enumerate_kernel_modules(ntoskrnl_base_va)?;
+
+        for driver in drivers {
+            let module = loaded_modules
+                .iter()
+                .find(|module| is_module_name(&module.name, driver))
+                .ok_or(HypervisorError::KernelModuleNotFound)?;
+
+            modules.push(module.clone());
+        }
+    }
+
+    let mut pages = Vec::new();
+
+    for module in &modules {
+        add_module_pages(module, &mut pages)?;
+    }
+
+    let page_count = pages.len();
+    let unmapped_pages = pages.iter().filter(|page| page.digest.is_none()).count();
+
+    monitor.pages = pages;
+    monitor.min_run_interval = (tsc_frequency_hz() as u128 * period_ms as u128 / 2000) as u64;
+    monitor.task_id = Some(SHARED_SCHEDULER.lock().register("code integrity", verify_code_integrity, period_ms)?);
+
+    info!("Code integrity monitor started: {} modules, {} pages, {} unmapped, every {} ms", modules.len(), page_count, unmapped_pages, period_ms);
+
+    Ok(page_count)
+}
+
+/// Adds the pages of the code sections of a module, hashing the mapped ones.
+///
+/// # Arguments
+///
+/// * `module` - The module.
+/// * `pages` - The monitored pages, the pages of the module being appended to them.
+fn add_module_pages(module: &KernelModule, pages: &mut Vec<MonitoredPage>) -> Result<(), HypervisorError> {
+    let directory_table_base = PhysicalAddress::kernel_directory_table_base().unwrap_or_else(|| vmread(vmcs::guest::CR3));
+    let sections = read_section_headers(module.base_va, directory_table_base)?;
+
+    for section in &sections {
+        if section.Characteristics & IMAGE_SCN_MEM_EXECUTE == 0 || section.Characteristics & (IMAGE_SCN_MEM_WRITE | IMAGE_SCN_MEM_DISCARDABLE) != 0 {
+            continue;
+        }
+
+        let start = module.base_va + section.VirtualAddress as u64;
+        let end = start + (section.VirtualSize as u64).min(module.size_of_image.saturating_sub(section.VirtualAddress as u64));
+
+        trace!("Monitoring section {:?} of {}: {:#x}-{:#x}", core::str::from_utf8(&section.Name).unwrap_or("?"), module.name, start, end);
+
+        let mut va = start;
+
+        while va < end {
+            if pages.len() >= MAX_CODE_INTEGRITY_PAGES {
+                return Err(HypervisorError::TooManyCodeIntegrityPages);
+            }
+
+            let size = (end.min((va | (BASE_PAGE_SIZE as u64 - 1)) + 1) - va) as usize;
+
+            pages.push(MonitoredPage {
+                va,
+                size,
+                module_base_va: module.base_va,
+                digest: hash_range(va, size),
+            });
+
+            va += size as u64;
+        }
+    }
+
+    Ok(())
+}
+
+/// Verifies the next monitored pages, once per period for all the logical processors.
+///
+/// # Arguments
+///
+/// * `_vm` - The virtual machine instance of the current logical processor.
+fn verify_code_integrity(_vm: &mut Vm) {
+    let mut monitor = SHARED_CODE_INTEGRITY.lock();
+    let tsc = rdtsc();
+
+    // The task runs on each logical processor, and the first one of a period verifies the pages for all of them.
+    if tsc.wrapping_sub(monitor.last_run_tsc) < monitor.min_run_interval {
+        return;
+    }
+
+    monitor.last_run_tsc = tsc;
+    monitor.verify_next_pages(PAGES_PER_RUN);
+}
+
+/// Hashes a range of kernel code within a page.
+///
+/// # Arguments
+///
+/// * `va` - The virtual address of the range.
+/// * `size` - The size of the range, not crossing the end of its page.
+///
+/// # Returns
+///
+/// The SHA-256 digest of the range, or `None` if its page isn't mapped.
+fn hash_range(va: u64, size: usize) -> Option<[u8; DIGEST_SIZE]> {
+    let data = PhysicalAddress::read_guest_kernel_virt_slice(va as *const u8, size)?;
+
+    let mut hash = Sha256::new();
+    hash.update(data);
+
+    Some(hash.finalize())
+}
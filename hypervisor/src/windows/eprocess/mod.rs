@@ -1,27 +1,47 @@
 use {
     crate::{
-        intel::{addresses::PhysicalAddress, hooks::hook_manager::SHARED_HOOK_MANAGER},
-        windows::nt::{
-            pe::{djb2_hash, get_export_by_hash},
-            types::{UNICODE_STRING, _LIST_ENTRY},
+        intel::{addresses::PhysicalAddress, hooks::hook_manager::kernel_image, paging::CR3_ADDRESS_MASK},
+        personality::is_windows_guest,
+        windows::{
+            kernel::{resolve_kernel_export, ExportQuery},
+            nt::types::{UNICODE_STRING, _LIST_ENTRY},
+            offsets::windows_offsets,
         },
     },
     alloc::string::String,
+    core::sync::atomic::{AtomicU64, Ordering},
     log::*,
     widestring::U16CStr,
     x86::{bits64::vmx::vmread, vmx::vmcs},
 };
 
-/// Constants for offsets in the process structures
+/// Constants for offsets in the structures that don't change across the supported builds, the offsets of the process
+/// structures being the ones of the guest build (see `offsets`).
 const THREAD_OFFSET: u64 = 0x188;
-const THREAD_PROCESS_OFFSET: u64 = 0xB8;
-const UNIQUE_PROCESS_ID_OFFSET: u64 = 0x440;
-const IMAGE_FILE_POINTER_OFFSET: u64 = 0x5a0;
 const IMAGE_FILE_NAME_OFFSET: u64 = 0x58;
-const DIRECTORY_TABLE_BASE_OFFSET: u64 = 0x28;
-const ACTIVE_PROCESS_LINKS_OFFSET: u64 = 0x448;
+const TEB_UNIQUE_PROCESS_OFFSET: u64 = 0x40;
+const TEB_UNIQUE_THREAD_OFFSET: u64 = 0x48;
+
+/// The virtual address of the `PsInitialSystemProcess` export of ntoskrnl.exe, 0 until it is resolved.
+static PS_INITIAL_SYSTEM_PROCESS: AtomicU64 = AtomicU64::new(0);
+
+/// The process owning an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressSpaceOwner {
+    /// The address of the `_EPROCESS` structure of the process.
+    pub process: u64,
+
+    /// The unique process ID of the process.
+    pub process_id: u64,
+
+    /// Whether the address space is the user address space of the process (KVA shadow) rather than its kernel one.
+    pub is_user_address_space: bool,
+}
 
 /// Struct representing process information
+///
+/// The structures are read with the offsets of the guest build, so the lookups fail on other guests (see
+/// `personality`) and on the builds without offsets.
 #[derive(Debug)]
 pub struct ProcessInformation {
     /// The image file name of the process.
@@ -68,9 +88,10 @@ impl ProcessInformation {
     pub fn get_current_process_info() -> Option<Self> {
         // Retrieve the physical address of the current process (_EPROCESS structure).
         let process = Self::ps_get_current_process()?;
+        let offsets = windows_offsets()?;
 
         // Read the image file pointer from the _EPROCESS structure.
-        let image_file_pointer = PhysicalAddress::read_guest_virt_with_current_cr3((process + IMAGE_FILE_POINTER_OFFSET) as *const u64)?;
+        let image_file_pointer = PhysicalAddress::read_guest_kernel_virt((process + offsets.eprocess_image_file_pointer) as *const u64)?;
 
         if image_file_pointer == 0 {
             return None;
@@ -78,24 +99,24 @@ impl ProcessInformation {
 
         // Read the image file name from the _FILE_OBJECT structure.
         let image_file_name =
-            unsafe { &*(PhysicalAddress::pa_from_va_with_current_cr3(image_file_pointer + IMAGE_FILE_NAME_OFFSET).ok()? as *const UNICODE_STRING) };
+            unsafe { &*(PhysicalAddress::pa_from_kernel_va(image_file_pointer + IMAGE_FILE_NAME_OFFSET).ok()? as *const UNICODE_STRING) };
 
         // Read the image file name bytes from the UNICODE_STRING structure.
         let image_file_name_buffer =
-            PhysicalAddress::read_guest_virt_slice_with_current_cr3(image_file_name.Buffer, image_file_name.MaximumLength as usize / 2)?;
+            PhysicalAddress::read_guest_kernel_virt_slice(image_file_name.Buffer, image_file_name.MaximumLength as usize / 2)?;
 
         // Convert the image file name bytes to a string.
         let file_name = U16CStr::from_slice_truncate(image_file_name_buffer).ok()?.to_string().ok()?;
 
         // Read the directory table base (CR3) from the _KPROCESS structure within _EPROCESS.
-        let directory_table_base = PhysicalAddress::read_guest_virt_with_current_cr3((process + DIRECTORY_TABLE_BASE_OFFSET) as *const u64)?;
+        let directory_table_base = PhysicalAddress::read_guest_kernel_virt((process + offsets.kprocess_directory_table_base) as *const u64)?;
 
         if directory_table_base == 0 {
             return None;
         }
 
         // Read the unique process ID from the _EPROCESS structure.
-        let unique_process_id = PhysicalAddress::read_guest_virt_with_current_cr3((process + UNIQUE_PROCESS_ID_OFFSET) as *const u64)?;
+        let unique_process_id = PhysicalAddress::read_guest_kernel_virt((process + offsets.eprocess_unique_process_id) as *const u64)?;
 
         // Return the populated ProcessInformation struct.
         Some(Self {
@@ -132,6 +153,10 @@ impl ProcessInformation {
     ///
     /// https://www.vergiliusproject.com/kernels/x64/windows-11/23h2
     fn ps_get_current_process() -> Option<u64> {
+        if !is_windows_guest() {
+            return None;
+        }
+
         // Read the GS base address.
         let gs = unsafe { vmread(vmcs::guest::GS_BASE).ok()? };
         trace!("GS base address: {:#x}", gs);
@@ -141,7 +166,7 @@ impl ProcessInformation {
         }
 
         // Compute the address of the current thread.
-        let current_thread = PhysicalAddress::read_guest_virt_with_current_cr3((gs + THREAD_OFFSET) as *const u64)?;
+        let current_thread = PhysicalAddress::read_guest_kernel_virt((gs + THREAD_OFFSET) as *const u64)?;
         trace!("Current thread address: {:#x}", current_thread);
 
         if current_thread == 0 {
@@ -149,7 +174,7 @@ impl ProcessInformation {
         }
 
         // Compute the address of the _EPROCESS structure.
-        let current_process = PhysicalAddress::read_guest_virt_with_current_cr3((current_thread + THREAD_PROCESS_OFFSET) as *const u64)?;
+        let current_process = PhysicalAddress::read_guest_kernel_virt((current_thread + windows_offsets()?.kthread_process) as *const u64)?;
         trace!("Current process address: {:#x}", current_process);
 
         if current_process == 0 {
@@ -159,6 +184,53 @@ impl ProcessInformation {
         Some(current_process)
     }
 
+    /// Retrieves the process and thread IDs of the current thread from its `_TEB`.
+    ///
+    /// This is only valid while the guest GS base is the user GS base: in user mode, or on a system call entry before
+    /// `SWAPGS`.
+    ///
+    /// # Example
+    ///
+    /// struct _TEB
+    ///     struct _CLIENT_ID ClientId;                                             //0x40
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(u64, u64)>` - The process ID and the thread ID, or `None` if the `_TEB` can't be read.
+    pub fn get_current_client_id() -> Option<(u64, u64)> {
+        if !is_windows_guest() {
+            return None;
+        }
+
+        let teb = unsafe { vmread(vmcs::guest::GS_BASE).ok()? };
+
+        if teb == 0 {
+            return None;
+        }
+
+        let process_id = PhysicalAddress::read_guest_virt_with_current_cr3((teb + TEB_UNIQUE_PROCESS_OFFSET) as *const u64)?;
+        let thread_id = PhysicalAddress::read_guest_virt_with_current_cr3((teb + TEB_UNIQUE_THREAD_OFFSET) as *const u64)?;
+
+        Some((process_id, thread_id))
+    }
+
+    /// Retrieves the process ID of the current process, from the `_TEB` in user mode and from the `_EPROCESS` in kernel mode.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The process ID of the current process, or `None` if it can't be read.
+    pub fn get_current_process_id() -> Option<u64> {
+        let cs_selector = unsafe { vmread(vmcs::guest::CS_SELECTOR).ok()? };
+
+        if cs_selector & 0x3 == 3 {
+            return Self::get_current_client_id().map(|(process_id, _)| process_id);
+        }
+
+        let process = Self::ps_get_current_process()?;
+
+        PhysicalAddress::read_guest_kernel_virt((process + windows_offsets()?.eprocess_unique_process_id) as *const u64)
+    }
+
     /// Retrieves the process ID of a process by its process ID.
     ///
     /// # Arguments
@@ -177,28 +249,23 @@ impl ProcessInformation {
     ///
     /// * `Option<u64>` - The physical address of the `_EPROCESS` structure of the specified process, or `None` if not found.
     fn get_process_by_process_id(process_id: u64) -> Option<u64> {
-        trace!("Searching for process with ID: {:#x}", process_id);
-
-        // Lock the shared hook manager
-        let hook_manager = SHARED_HOOK_MANAGER.lock();
-        trace!("Hook manager locked");
-
-        // Get the base address of the NT kernel module.
-        let ps_initial_system_process = unsafe {
-            get_export_by_hash(hook_manager.ntoskrnl_base_pa as _, hook_manager.ntoskrnl_base_va as _, djb2_hash("PsInitialSystemProcess".as_bytes()))
-        }?;
+        if !is_windows_guest() {
+            return None;
+        }
 
-        trace!("PsInitialSystemProcess address: {:#p}", ps_initial_system_process);
+        trace!("Searching for process with ID: {:#x}", process_id);
 
         // Retrieve the physical address of the SYSTEM process (_EPROCESS structure).
-        let start_process = PhysicalAddress::read_guest_virt_with_current_cr3(ps_initial_system_process as *const u64)?;
+        let start_process = Self::get_initial_system_process()?;
         trace!("Current process address: {:#x}", start_process);
 
+        let offsets = windows_offsets()?;
+
         let mut current_process = start_process;
 
         loop {
             // Read the unique process ID from the _EPROCESS structure.
-            let unique_process_id = PhysicalAddress::read_guest_virt_with_current_cr3((current_process + UNIQUE_PROCESS_ID_OFFSET) as *const u64)?;
+            let unique_process_id = PhysicalAddress::read_guest_kernel_virt((current_process + offsets.eprocess_unique_process_id) as *const u64)?;
             trace!("Checking process with ID: {:#x}", unique_process_id);
 
             // Check if the current process ID matches the specified process ID
@@ -210,8 +277,8 @@ impl ProcessInformation {
             trace!("Moving to the next process");
             // Move to the next process in the list by following the Flink pointer.
             let next_process_links =
-                PhysicalAddress::read_guest_virt_with_current_cr3((current_process + ACTIVE_PROCESS_LINKS_OFFSET) as *const _LIST_ENTRY)?;
-            current_process = next_process_links.Flink as u64 - ACTIVE_PROCESS_LINKS_OFFSET;
+                PhysicalAddress::read_guest_kernel_virt((current_process + offsets.eprocess_active_process_links) as *const _LIST_ENTRY)?;
+            current_process = next_process_links.Flink as u64 - offsets.eprocess_active_process_links;
 
             trace!("Next process address: {:#x}", current_process);
 
@@ -241,6 +308,127 @@ impl ProcessInformation {
         trace!("Reading Guest Virtual Address");
 
         // Read the directory table base (CR3) from the _KPROCESS structure within _EPROCESS.
-        PhysicalAddress::read_guest_virt_with_current_cr3((process + DIRECTORY_TABLE_BASE_OFFSET) as *const u64)
+        PhysicalAddress::read_guest_kernel_virt((process + windows_offsets()?.kprocess_directory_table_base) as *const u64)
+    }
+
+    /// Retrieves the directory table base of the user address space of a process by its process ID, which differs from
+    /// the directory table base of the process when KVA shadow is enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The process ID of the process to retrieve the directory table base from.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The user directory table base of the process, 0 without KVA shadow, or `None` if not found.
+    ///
+    /// # Example
+    ///
+    /// struct _KPROCESS
+    ///     ULONGLONG UserDirectoryTableBase;                                       //0x388 (Windows 10 2004 and later)
+    pub fn get_user_directory_table_base_by_process_id(process_id: u64) -> Option<u64> {
+        let process = Self::get_process_by_process_id(process_id)?;
+
+        PhysicalAddress::read_guest_kernel_virt((process + windows_offsets()?.kprocess_user_directory_table_base) as *const u64)
+    }
+
+    /// Retrieves the address of the `_EPROCESS` structure of the System process, the head of the process list.
+    ///
+    /// # Example
+    ///
+    /// ntoskrnl export:
+    ///     PEPROCESS PsInitialSystemProcess
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The address of the `_EPROCESS` structure of the System process, or `None` if not found.
+    pub fn get_initial_system_process() -> Option<u64> {
+        // The export is resolved once, as its address doesn't change until the next boot.
+        let ps_initial_system_process = match PS_INITIAL_SYSTEM_PROCESS.load(Ordering::Acquire) {
+            0 => {
+                let va = resolve_kernel_export(kernel_image().base_va, None, ExportQuery::Name("PsInitialSystemProcess")).ok()?;
+                PS_INITIAL_SYSTEM_PROCESS.store(va, Ordering::Release);
+                va
+            }
+            va => va,
+        };
+
+        trace!("PsInitialSystemProcess address: {:#x}", ps_initial_system_process);
+
+        PhysicalAddress::read_guest_kernel_virt(ps_initial_system_process as *const u64)
+    }
+
+    /// Retrieves the owner of an address space by walking the process list from the System process.
+    ///
+    /// The process structures are read through an explicit directory table base, so the lookup works from any address
+    /// space, including the user address spaces that don't map the kernel.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory_table_base` - The directory table base (CR3) of the address space.
+    /// * `system_process` - The address of the `_EPROCESS` structure of the System process.
+    /// * `kernel_directory_table_base` - A directory table base mapping the kernel.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<AddressSpaceOwner>` - The process using the address space as its kernel or user address space, or
+    ///   `None` if not found.
+    pub fn find_address_space_owner(directory_table_base: u64, system_process: u64, kernel_directory_table_base: u64) -> Option<AddressSpaceOwner> {
+        if !is_windows_guest() {
+            return None;
+        }
+
+        let offsets = windows_offsets()?;
+        let mut current_process = system_process;
+
+        loop {
+            if let Some(owner) = Self::get_address_space_owner(current_process, directory_table_base, kernel_directory_table_base) {
+                return Some(owner);
+            }
+
+            let next_process_links = PhysicalAddress::read_guest_virt_with_explicit_cr3(
+                (current_process + offsets.eprocess_active_process_links) as *const _LIST_ENTRY,
+                kernel_directory_table_base,
+            )?;
+            current_process = next_process_links.Flink as u64 - offsets.eprocess_active_process_links;
+
+            if current_process == system_process {
+                trace!("No process found with directory table base: {:#x}", directory_table_base);
+                return None;
+            }
+        }
+    }
+
+    /// Returns the address space owner built from a process if the process uses an address space, e.g., to check that
+    /// an owner found earlier is still valid.
+    ///
+    /// # Arguments
+    ///
+    /// * `process` - The address of the `_EPROCESS` structure of the process.
+    /// * `directory_table_base` - The directory table base (CR3) of the address space.
+    /// * `kernel_directory_table_base` - A directory table base mapping the kernel.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<AddressSpaceOwner>` - The owner, or `None` if the process doesn't use the address space.
+    pub fn get_address_space_owner(process: u64, directory_table_base: u64, kernel_directory_table_base: u64) -> Option<AddressSpaceOwner> {
+        let offsets = windows_offsets()?;
+        let read = |offset: u64| PhysicalAddress::read_guest_virt_with_explicit_cr3((process + offset) as *const u64, kernel_directory_table_base);
+        let directory_table_base = directory_table_base & CR3_ADDRESS_MASK;
+
+        // The user directory table base is 0 without KVA shadow, which never matches.
+        let is_user_address_space = if read(offsets.kprocess_directory_table_base)? & CR3_ADDRESS_MASK == directory_table_base {
+            false
+        } else if read(offsets.kprocess_user_directory_table_base)? & CR3_ADDRESS_MASK == directory_table_base {
+            true
+        } else {
+            return None;
+        };
+
+        Some(AddressSpaceOwner {
+            process,
+            process_id: read(offsets.eprocess_unique_process_id)?,
+            is_user_address_space,
+        })
     }
 }
@@ -0,0 +1,367 @@
+//! Provides the enumeration of the processes and threads of Windows guests, by walking the list of processes from
+//! `PsActiveProcessHead` and the list of threads of a process from `_EPROCESS.ThreadListHead`, with the offsets of the
+//! guest build (see `offsets`), as `linux::task` enumerates the tasks of Linux guests, and the map of the address
+//! space of a process, by walking its VAD tree from `_EPROCESS.VadRoot`.
+//!
+//! Each process reports its `_EPROCESS`, its ID, its image name, its directory table base and its PEB, and each thread
+//! its `_ETHREAD`, the IDs of its process and of itself, and its TEB. The directory table base can be passed to the
+//! commands taking a guest CR3, e.g., `ReadProcessMemory`. Each VAD reports its range, its protection at allocation,
+//! whether it is private memory and the name of the file it maps, so e.g. the private executable memory of a process
+//! can be found and dumped without code in the guest.
+//!
+//! `PsActiveProcessHead` isn't exported, but it is the previous entry of the System process in the list of processes,
+//! so the walk starts from `PsInitialSystemProcess`. The lists are walked through the kernel directory table base, and
+//! the walks are bounded, so a corrupted or hostile list or tree fails to enumerate instead of looping forever.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::addresses::PhysicalAddress,
+        windows::{
+            eprocess::ProcessInformation,
+            nt::types::{_LIST_ENTRY, UNICODE_STRING},
+            offsets::{windows_offsets, WindowsOffsets},
+        },
+    },
+    alloc::{string::String, vec::Vec},
+    log::*,
+    shared::{WindowsProcess, WindowsThread, WindowsVad, WINDOWS_PROCESS_IMAGE_NAME_SIZE, WINDOWS_VAD_FILE_NAME_SIZE},
+};
+
+/// The maximum number of processes walked, bounding the walk of a corrupted list.
+pub const MAX_WINDOWS_PROCESSES: usize = 0x10000;
+
+/// The maximum number of threads of a process walked, bounding the walk of a corrupted list.
+pub const MAX_WINDOWS_THREADS: usize = 0x10000;
+
+/// The maximum number of VADs of a process walked, bounding the walk of a corrupted tree.
+pub const MAX_WINDOWS_VADS: usize = 0x10000;
+
+/// The size of `_EPROCESS.ImageFileName`, without a terminator if the name is truncated.
+const IMAGE_FILE_NAME_SIZE: usize = 15;
+
+/// The maximum depth of the VAD tree walked, an AVL tree of `MAX_WINDOWS_VADS` nodes being much shallower.
+const MAX_VAD_TREE_DEPTH: usize = 64;
+
+/// The offsets of the fields of `_MMVAD_SHORT` and `_MMVAD`, the same on the supported builds.
+const MMVAD_LEFT_OFFSET: u64 = 0x0;
+const MMVAD_RIGHT_OFFSET: u64 = 0x8;
+const MMVAD_STARTING_VPN_OFFSET: u64 = 0x18;
+const MMVAD_ENDING_VPN_OFFSET: u64 = 0x1C;
+const MMVAD_STARTING_VPN_HIGH_OFFSET: u64 = 0x20;
+const MMVAD_ENDING_VPN_HIGH_OFFSET: u64 = 0x21;
+const MMVAD_FLAGS_OFFSET: u64 = 0x30;
+const MMVAD_SUBSECTION_OFFSET: u64 = 0x48;
+
+/// The bits of `_MMVAD_FLAGS` on the supported builds.
+const MMVAD_FLAGS_VAD_TYPE_SHIFT: u32 = 4;
+const MMVAD_FLAGS_VAD_TYPE_MASK: u32 = 0x7;
+const MMVAD_FLAGS_PROTECTION_SHIFT: u32 = 7;
+const MMVAD_FLAGS_PROTECTION_MASK: u32 = 0x1F;
+const MMVAD_FLAGS_PRIVATE_MEMORY_BIT: u32 = 20;
+
+/// The offsets of `_SUBSECTION.ControlArea`, `_CONTROL_AREA.FilePointer` and `_FILE_OBJECT.FileName`.
+const SUBSECTION_CONTROL_AREA_OFFSET: u64 = 0x0;
+const CONTROL_AREA_FILE_POINTER_OFFSET: u64 = 0x40;
+const FILE_OBJECT_FILE_NAME_OFFSET: u64 = 0x58;
+
+/// The reference count bits of an `_EX_FAST_REF`, e.g., `_CONTROL_AREA.FilePointer`.
+const EX_FAST_REF_MASK: u64 = 0xF;
+
+/// Enumerates the processes of the guest, starting with the System process.
+///
+/// # Arguments
+///
+/// * `max_processes` - The maximum number of processes returned.
+///
+/// # Returns
+///
+/// * `Ok((Vec<WindowsProcess>, usize))` - The first `max_processes` processes in the order of the list, and the number
+///   of processes.
+/// * `Err(HypervisorError::UnsupportedWindowsBuild)` - If the guest build has no offsets.
+/// * `Err(HypervisorError::InvalidProcessList)` - If the list can't be read or doesn't loop back to its head.
+pub fn enumerate_processes(max_processes: usize) -> Result<(Vec<WindowsProcess>, usize), HypervisorError> {
+    let offsets = windows_offsets().ok_or(HypervisorError::UnsupportedWindowsBuild)?;
+    let head = process_list_head(offsets)?;
+    let mut processes = Vec::new();
+
+    let total_processes = walk_list(head, MAX_WINDOWS_PROCESSES, |entry| {
+        if processes.len() < max_processes {
+            processes.push(read_process(offsets, entry - offsets.eprocess_active_process_links)?);
+        }
+
+        Some(())
+    })
+    .ok_or(HypervisorError::InvalidProcessList)?;
+
+    debug!("Enumerated {} Windows processes", total_processes);
+
+    Ok((processes, total_processes))
+}
+
+/// Enumerates the threads of a process of the guest.
+///
+/// # Arguments
+///
+/// * `process_id` - The ID of the process.
+/// * `max_threads` - The maximum number of threads returned.
+///
+/// # Returns
+///
+/// * `Ok((Vec<WindowsThread>, usize))` - The first `max_threads` threads in the order of the list of threads of the
+///   process, and the number of threads.
+/// * `Err(HypervisorError::UnsupportedWindowsBuild)` - If the guest build has no offsets.
+/// * `Err(HypervisorError::ProcessNotFound)` - If no process has this ID.
+/// * `Err(HypervisorError::InvalidProcessList)` - If a list can't be read or doesn't loop back to its head.
+pub fn enumerate_threads(process_id: u64, max_threads: usize) -> Result<(Vec<WindowsThread>, usize), HypervisorError> {
+    let offsets = windows_offsets().ok_or(HypervisorError::UnsupportedWindowsBuild)?;
+    let process = find_process(offsets, process_id)?;
+    let mut threads = Vec::new();
+
+    let total_threads = walk_list(process + offsets.eprocess_thread_list_head, MAX_WINDOWS_THREADS, |entry| {
+        if threads.len() < max_threads {
+            threads.push(read_thread(offsets, entry - offsets.ethread_thread_list_entry)?);
+        }
+
+        Some(())
+    })
+    .ok_or(HypervisorError::InvalidProcessList)?;
+
+    debug!("Enumerated {} threads of process {:#x}", total_threads, process_id);
+
+    Ok((threads, total_threads))
+}
+
+/// Enumerates the VADs of a process of the guest, the ranges of its virtual address space, in address order.
+///
+/// # Arguments
+///
+/// * `process_id` - The ID of the process.
+/// * `max_vads` - The maximum number of VADs returned.
+///
+/// # Returns
+///
+/// * `Ok((Vec<WindowsVad>, usize))` - The first `max_vads` VADs in address order, and the number of VADs.
+/// * `Err(HypervisorError::UnsupportedWindowsBuild)` - If the guest build has no offsets.
+/// * `Err(HypervisorError::ProcessNotFound)` - If no process has this ID.
+/// * `Err(HypervisorError::InvalidProcessList)` - If the list of processes can't be read.
+/// * `Err(HypervisorError::InvalidVadTree)` - If the VAD tree can't be read, is deeper than `MAX_VAD_TREE_DEPTH` or
+///   has more than `MAX_WINDOWS_VADS` nodes.
+pub fn enumerate_vads(process_id: u64, max_vads: usize) -> Result<(Vec<WindowsVad>, usize), HypervisorError> {
+    let offsets = windows_offsets().ok_or(HypervisorError::UnsupportedWindowsBuild)?;
+    let process = find_process(offsets, process_id)?;
+
+    // _RTL_AVL_TREE is the address of the root node.
+    let mut node = read::<u64>(process + offsets.eprocess_vad_root).ok_or(HypervisorError::InvalidVadTree)?;
+    let mut stack = Vec::new();
+    let mut vads = Vec::new();
+    let mut total_vads = 0;
+
+    // In-order walk of the tree, the nodes being sorted by address.
+    while node != 0 || !stack.is_empty() {
+        while node != 0 {
+            if stack.len() >= MAX_VAD_TREE_DEPTH {
+                error!("VAD tree of process {:#x} is deeper than {}", process_id, MAX_VAD_TREE_DEPTH);
+                return Err(HypervisorError::InvalidVadTree);
+            }
+
+            stack.push(node);
+            node = read::<u64>(node + MMVAD_LEFT_OFFSET).ok_or(HypervisorError::InvalidVadTree)?;
+        }
+
+        let Some(vad) = stack.pop() else {
+            break;
+        };
+
+        if total_vads >= MAX_WINDOWS_VADS {
+            error!("VAD tree of process {:#x} has more than {} nodes", process_id, MAX_WINDOWS_VADS);
+            return Err(HypervisorError::InvalidVadTree);
+        }
+
+        if vads.len() < max_vads {
+            vads.push(read_vad(vad).ok_or(HypervisorError::InvalidVadTree)?);
+        }
+
+        total_vads += 1;
+        node = read::<u64>(vad + MMVAD_RIGHT_OFFSET).ok_or(HypervisorError::InvalidVadTree)?;
+    }
+
+    debug!("Enumerated {} VADs of process {:#x}", total_vads, process_id);
+
+    Ok((vads, total_vads))
+}
+
+/// Returns the address of `PsActiveProcessHead`, the head of the list of processes.
+///
+/// # Arguments
+///
+/// * `offsets` - The offsets of the guest build.
+fn process_list_head(offsets: &WindowsOffsets) -> Result<u64, HypervisorError> {
+    let system_process = ProcessInformation::get_initial_system_process().ok_or(HypervisorError::InvalidProcessList)?;
+
+    let links = read::<_LIST_ENTRY>(system_process + offsets.eprocess_active_process_links).ok_or(HypervisorError::InvalidProcessList)?;
+
+    Ok(links.Blink as u64)
+}
+
+/// Finds the `_EPROCESS` structure of a process by its ID.
+///
+/// # Arguments
+///
+/// * `offsets` - The offsets of the guest build.
+/// * `process_id` - The ID of the process.
+fn find_process(offsets: &WindowsOffsets, process_id: u64) -> Result<u64, HypervisorError> {
+    let head = process_list_head(offsets)?;
+    let mut found = None;
+
+    walk_list(head, MAX_WINDOWS_PROCESSES, |entry| {
+        let process = entry - offsets.eprocess_active_process_links;
+
+        if found.is_none() && read::<u64>(process + offsets.eprocess_unique_process_id)? == process_id {
+            found = Some(process);
+        }
+
+        Some(())
+    })
+    .ok_or(HypervisorError::InvalidProcessList)?;
+
+    found.ok_or(HypervisorError::ProcessNotFound)
+}
+
+/// Walks a circular doubly linked list of the kernel, calling `visit` with the address of each entry but the head.
+///
+/// # Arguments
+///
+/// * `head` - The address of the `_LIST_ENTRY` head of the list.
+/// * `max_entries` - The maximum number of entries walked.
+/// * `visit` - The function called with each entry, stopping the walk if it returns `None`.
+///
+/// # Returns
+///
+/// The number of entries, or `None` if the list can't be read, doesn't loop back to its head within `max_entries`
+/// entries, or `visit` fails.
+pub fn walk_list(head: u64, max_entries: usize, mut visit: impl FnMut(u64) -> Option<()>) -> Option<usize> {
+    let mut entry = read::<_LIST_ENTRY>(head)?.Flink as u64;
+
+    for count in 0..max_entries {
+        if entry == head {
+            return Some(count);
+        }
+
+        visit(entry)?;
+        entry = read::<_LIST_ENTRY>(entry)?.Flink as u64;
+    }
+
+    error!("List at {:#x} doesn't loop back to its head after {} entries", head, max_entries);
+    None
+}
+
+/// Reads a process of the guest.
+///
+/// # Arguments
+///
+/// * `offsets` - The offsets of the guest build.
+/// * `eprocess` - The virtual address of the `_EPROCESS` structure.
+fn read_process(offsets: &WindowsOffsets, eprocess: u64) -> Option<WindowsProcess> {
+    let mut image_name = [0; WINDOWS_PROCESS_IMAGE_NAME_SIZE];
+    image_name[..IMAGE_FILE_NAME_SIZE].copy_from_slice(&read::<[u8; IMAGE_FILE_NAME_SIZE]>(eprocess + offsets.eprocess_image_file_name)?);
+
+    Some(WindowsProcess {
+        eprocess,
+        process_id: read(eprocess + offsets.eprocess_unique_process_id)?,
+        directory_table_base: read(eprocess + offsets.kprocess_directory_table_base)?,
+        peb: read(eprocess + offsets.eprocess_peb)?,
+        image_name,
+    })
+}
+
+/// Reads a thread of the guest.
+///
+/// # Arguments
+///
+/// * `offsets` - The offsets of the guest build.
+/// * `ethread` - The virtual address of the `_ETHREAD` structure.
+fn read_thread(offsets: &WindowsOffsets, ethread: u64) -> Option<WindowsThread> {
+    // _CLIENT_ID is the ID of the process followed by the ID of the thread.
+    let [process_id, thread_id] = read::<[u64; 2]>(ethread + offsets.ethread_cid)?;
+
+    Some(WindowsThread {
+        ethread,
+        process_id,
+        thread_id,
+        teb: read(ethread + offsets.kthread_teb)?,
+    })
+}
+
+/// Reads a VAD of the guest.
+///
+/// # Arguments
+///
+/// * `vad` - The virtual address of the `_MMVAD_SHORT` structure.
+fn read_vad(vad: u64) -> Option<WindowsVad> {
+    let starting_vpn = read::<u32>(vad + MMVAD_STARTING_VPN_OFFSET)? as u64 | (read::<u8>(vad + MMVAD_STARTING_VPN_HIGH_OFFSET)? as u64) << 32;
+    let ending_vpn = read::<u32>(vad + MMVAD_ENDING_VPN_OFFSET)? as u64 | (read::<u8>(vad + MMVAD_ENDING_VPN_HIGH_OFFSET)? as u64) << 32;
+    let flags = read::<u32>(vad + MMVAD_FLAGS_OFFSET)?;
+
+    let private_memory = (flags >> MMVAD_FLAGS_PRIVATE_MEMORY_BIT) & 1;
+    let mut file_name = [0; WINDOWS_VAD_FILE_NAME_SIZE];
+
+    // Only the views of a section are _MMVAD structures, with a subsection.
+    if private_memory == 0 {
+        if let Some(name) = read_vad_file_name(vad) {
+            // The name is truncated on a character boundary, keeping the NUL terminator.
+            let mut length = name.len().min(WINDOWS_VAD_FILE_NAME_SIZE - 1);
+
+            while !name.is_char_boundary(length) {
+                length -= 1;
+            }
+
+            file_name[..length].copy_from_slice(&name.as_bytes()[..length]);
+        }
+    }
+
+    Some(WindowsVad {
+        vad,
+        start_address: starting_vpn << 12,
+        end_address: (ending_vpn + 1) << 12,
+        protection: (flags >> MMVAD_FLAGS_PROTECTION_SHIFT) & MMVAD_FLAGS_PROTECTION_MASK,
+        vad_type: (flags >> MMVAD_FLAGS_VAD_TYPE_SHIFT) & MMVAD_FLAGS_VAD_TYPE_MASK,
+        private_memory: private_memory as u64,
+        file_name,
+    })
+}
+
+/// Reads the name of the file mapped by a view of a section.
+///
+/// # Arguments
+///
+/// * `vad` - The virtual address of the `_MMVAD` structure.
+///
+/// # Returns
+///
+/// The name of the file, or `None` if the section is backed by the paging file or the name can't be read.
+fn read_vad_file_name(vad: u64) -> Option<String> {
+    let subsection = read::<u64>(vad + MMVAD_SUBSECTION_OFFSET).filter(|&subsection| subsection != 0)?;
+    let control_area = read::<u64>(subsection + SUBSECTION_CONTROL_AREA_OFFSET).filter(|&control_area| control_area != 0)?;
+
+    // The control areas of the sections backed by the paging file have no file.
+    let file_object = read::<u64>(control_area + CONTROL_AREA_FILE_POINTER_OFFSET)? & !EX_FAST_REF_MASK;
+
+    if file_object == 0 {
+        return None;
+    }
+
+    let file_name = read::<UNICODE_STRING>(file_object + FILE_OBJECT_FILE_NAME_OFFSET)?;
+    let name = PhysicalAddress::read_guest_kernel_virt_slice(file_name.Buffer, file_name.Length as usize / 2)?;
+
+    Some(String::from_utf16_lossy(name))
+}
+
+/// Reads a value of the kernel address space.
+///
+/// # Arguments
+///
+/// * `va` - The virtual address of the value.
+fn read<T: Sized>(va: u64) -> Option<T> {
+    PhysicalAddress::read_guest_kernel_virt(va as *const T)
+}
@@ -0,0 +1,569 @@
+//! Provides the parsing of the PE images mapped in the guest, i.e., their headers and export directory, and the
+//! resolution of the exports of the kernel modules, so the kernel hooks resolve any export of ntoskrnl.exe or of
+//! another loaded module (e.g., hal.dll) by name, ordinal or hash.
+//!
+//! The images are read through an explicit directory table base, page by page, so they don't need to be physically
+//! contiguous or mapped in the current address space. The export directory is copied once, with its arrays and the
+//! names of the exports, and every read of the copy is bounds-checked, so a malformed or hostile image fails to
+//! resolve instead of making the hypervisor read out of it.
+//!
+//! A forwarded export, e.g., `NTOSKRNL.ExAllocatePoolWithTag` or `HAL.#12`, isn't code of the module but names an
+//! export of another module. `resolve_kernel_export` follows the forwarders through the modules of
+//! `PsLoadedModuleList`, up to `MAX_FORWARDER_DEPTH` of them.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{addresses::PhysicalAddress, support::vmread},
+        windows::nt::{
+            pe::djb2_hash,
+            types::{
+                IMAGE_DIRECTORY_ENTRY_EXPORT, IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_EXPORT_DIRECTORY, IMAGE_FILE_HEADER, IMAGE_NT_HEADERS64,
+                IMAGE_NT_SIGNATURE, IMAGE_SECTION_HEADER,
+            },
+        },
+    },
+    alloc::{
+        string::{String, ToString},
+        vec::Vec,
+    },
+    core::mem::size_of,
+    log::*,
+    x86::{bits64::paging::BASE_PAGE_SIZE, vmx::vmcs},
+};
+
+/// The maximum number of forwarders followed to resolve an export.
+pub const MAX_FORWARDER_DEPTH: usize = 4;
+
+/// The maximum size of the export directory of a module, names included.
+const MAX_EXPORT_DIRECTORY_SIZE: u32 = 0x10_0000;
+
+/// The maximum number of entries of `PsLoadedModuleList` walked to find a module.
+const MAX_LOADED_MODULES: usize = 0x400;
+
+/// Constants for offsets in `_KLDR_DATA_TABLE_ENTRY`, the entries of `PsLoadedModuleList`.
+const LDR_DLL_BASE_OFFSET: u64 = 0x30;
+const LDR_SIZE_OF_IMAGE_OFFSET: u64 = 0x40;
+const LDR_BASE_DLL_NAME_OFFSET: u64 = 0x58;
+
+/// The maximum size of the base name of a loaded module in bytes.
+const MAX_MODULE_NAME_SIZE: usize = 0x200;
+
+/// An export looked up in a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportQuery<'a> {
+    /// The name of the export, case-sensitive.
+    Name(&'a str),
+
+    /// The `djb2_hash` of the name of the export.
+    Hash(u32),
+
+    /// The ordinal of the export, including the ordinal base of the module.
+    Ordinal(u16),
+}
+
+/// What an export of a module resolves to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportTarget {
+    /// The virtual address of the exported code or data.
+    Address(u64),
+
+    /// A forwarder to the export of another module, e.g., `NTOSKRNL.ExAllocatePoolWithTag` or `HAL.#12`.
+    Forwarder(String),
+}
+
+/// A module of `PsLoadedModuleList`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelModule {
+    /// The base virtual address of the image.
+    pub base_va: u64,
+
+    /// The size of the image in memory.
+    pub size_of_image: u64,
+
+    /// The base name of the module, e.g., "hal.dll".
+    pub name: String,
+}
+
+impl KernelModule {
+    /// Returns `true` if a virtual address is in the image of the module.
+    ///
+    /// # Arguments
+    ///
+    /// * `va` - The virtual address.
+    pub fn contains(&self, va: u64) -> bool {
+        va.wrapping_sub(self.base_va) < self.size_of_image
+    }
+}
+
+/// The headers and the export directory of a PE image mapped in the guest.
+#[derive(Debug, Clone)]
+pub struct GuestImage {
+    /// The base virtual address of the image.
+    base_va: u64,
+
+    /// The size of the image in memory.
+    size_of_image: u32,
+
+    /// The RVA of the export directory, 0 if the image has no exports.
+    export_rva: u32,
+
+    /// The copy of the export directory, with its arrays and the names of the exports.
+    export_data: Vec<u8>,
+}
+
+impl GuestImage {
+    /// Reads the headers and the export directory of an image mapped in the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_va` - The base virtual address of the image.
+    /// * `directory_table_base` - The directory table base mapping the image.
+    ///
+    /// # Returns
+    ///
+    /// The image, or `Err(HypervisorError::InvalidModuleImage)` if its headers or its export directory can't be read.
+    pub fn read(base_va: u64, directory_table_base: u64) -> Result<Self, HypervisorError> {
+        let dos_header_bytes =
+            read_guest_bytes(base_va, size_of::<IMAGE_DOS_HEADER>(), directory_table_base).ok_or(HypervisorError::InvalidModuleImage)?;
+        let dos_header: IMAGE_DOS_HEADER = read_at(&dos_header_bytes, 0).ok_or(HypervisorError::InvalidModuleImage)?;
+
+        if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
+            return Err(HypervisorError::InvalidModuleImage);
+        }
+
+        let nt_headers_bytes = read_guest_bytes(base_va + dos_header.e_lfanew as u64, size_of::<IMAGE_NT_HEADERS64>(), directory_table_base)
+            .ok_or(HypervisorError::InvalidModuleImage)?;
+        let nt_headers: IMAGE_NT_HEADERS64 = read_at(&nt_headers_bytes, 0).ok_or(HypervisorError::InvalidModuleImage)?;
+
+        if nt_headers.Signature != IMAGE_NT_SIGNATURE {
+            return Err(HypervisorError::InvalidModuleImage);
+        }
+
+        let size_of_image = nt_headers.OptionalHeader.SizeOfImage;
+        let export_data_directory = &nt_headers.OptionalHeader.DataDirectory[IMAGE_DIRECTORY_ENTRY_EXPORT as usize];
+        let (export_rva, export_size) = (export_data_directory.VirtualAddress, export_data_directory.Size);
+
+        // A module without exports is still an image.
+        if export_rva == 0 || export_size < size_of::<IMAGE_EXPORT_DIRECTORY>() as u32 {
+            return Ok(Self {
+                base_va,
+                size_of_image,
+                export_rva: 0,
+                export_data: Vec::new(),
+            });
+        }
+
+        if export_size > MAX_EXPORT_DIRECTORY_SIZE {
+            return Err(HypervisorError::InvalidModuleImage);
+        }
+
+        let export_data =
+            read_guest_bytes(base_va + export_rva as u64, export_size as usize, directory_table_base).ok_or(HypervisorError::InvalidModuleImage)?;
+
+        Ok(Self {
+            base_va,
+            size_of_image,
+            export_rva,
+            export_data,
+        })
+    }
+
+    /// Returns the base virtual address of the image.
+    pub fn base_va(&self) -> u64 {
+        self.base_va
+    }
+
+    /// Returns the size of the image in memory.
+    pub fn size_of_image(&self) -> u32 {
+        self.size_of_image
+    }
+
+    /// Returns the name of the module from its export directory, without its extension, e.g., "ntoskrnl".
+    pub fn name(&self) -> Option<String> {
+        let name = read_string_at(&self.export_data, self.offset_of(self.export_directory()?.Name)?)?;
+
+        Some(match name.rfind('.') {
+            Some(extension) => name[..extension].to_string(),
+            None => name,
+        })
+    }
+
+    /// Returns the named exports of the image, in the order of the names, sorted alphabetically, with the RVAs of their
+    /// functions, the forwarders included (see `is_forwarder`).
+    pub fn named_exports(&self) -> impl Iterator<Item = (String, u32)> + '_ {
+        let name_count = self
+            .export_directory()
+            .map_or(0, |export_directory| export_directory.NumberOfNames as usize);
+
+        (0..name_count).filter_map(|index| {
+            let name = read_string_at(&self.export_data, self.offset_of(self.name_rva(index)?)?)?;
+            let function_rva = self.function_rva(self.name_ordinal(index)? as usize)?;
+
+            Some((name, function_rva))
+        })
+    }
+
+    /// Returns `true` if the RVA of an exported function is in the export directory, i.e., is a forwarder string.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_rva` - The RVA of the exported function.
+    pub fn is_forwarder(&self, function_rva: u32) -> bool {
+        (self.export_rva..self.export_rva + self.export_data.len() as u32).contains(&function_rva)
+    }
+
+    /// Looks up an export of the image.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The name, the hash of the name or the ordinal of the export.
+    ///
+    /// # Returns
+    ///
+    /// The address or the forwarder of the export, or `None` if the image doesn't export it.
+    pub fn find_export(&self, query: ExportQuery) -> Option<ExportTarget> {
+        let export_directory = self.export_directory()?;
+
+        let function_index = match query {
+            ExportQuery::Ordinal(ordinal) => (ordinal as u32).checked_sub(export_directory.Base)? as usize,
+            ExportQuery::Name(_) | ExportQuery::Hash(_) => {
+                let index = (0..export_directory.NumberOfNames as usize).find(|&index| {
+                    let Some(name) = self
+                        .name_rva(index)
+                        .and_then(|rva| self.offset_of(rva))
+                        .and_then(|offset| self.export_data.get(offset..))
+                    else {
+                        return false;
+                    };
+                    let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(name.len())];
+
+                    match query {
+                        ExportQuery::Name(expected) => name == expected.as_bytes(),
+                        ExportQuery::Hash(hash) => djb2_hash(name) == hash,
+                        ExportQuery::Ordinal(_) => false,
+                    }
+                })?;
+
+                self.name_ordinal(index)? as usize
+            }
+        };
+
+        let function_rva = self.function_rva(function_index)?;
+
+        if function_rva == 0 || function_rva >= self.size_of_image {
+            return None;
+        }
+
+        match self.is_forwarder(function_rva) {
+            true => read_string_at(&self.export_data, self.offset_of(function_rva)?).map(ExportTarget::Forwarder),
+            false => Some(ExportTarget::Address(self.base_va + function_rva as u64)),
+        }
+    }
+
+    /// Returns the export directory, if the image has exports.
+    fn export_directory(&self) -> Option<IMAGE_EXPORT_DIRECTORY> {
+        read_at(&self.export_data, 0)
+    }
+
+    /// Returns the offset of an RVA in the copy of the export directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `rva` - The RVA, in the export directory.
+    fn offset_of(&self, rva: u32) -> Option<usize> {
+        rva.checked_sub(self.export_rva).map(|offset| offset as usize)
+    }
+
+    /// Returns the RVA of the name of an export.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the name in `AddressOfNames`.
+    fn name_rva(&self, index: usize) -> Option<u32> {
+        read_at(&self.export_data, self.offset_of(self.export_directory()?.AddressOfNames)? + index * size_of::<u32>())
+    }
+
+    /// Returns the index of the function of a named export in `AddressOfFunctions`.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the name in `AddressOfNames`.
+    fn name_ordinal(&self, index: usize) -> Option<u16> {
+        read_at(&self.export_data, self.offset_of(self.export_directory()?.AddressOfNameOrdinals)? + index * size_of::<u16>())
+    }
+
+    /// Returns the RVA of an exported function.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the function in `AddressOfFunctions`.
+    fn function_rva(&self, index: usize) -> Option<u32> {
+        let export_directory = self.export_directory()?;
+
+        if index >= export_directory.NumberOfFunctions as usize {
+            return None;
+        }
+
+        read_at(&self.export_data, self.offset_of(export_directory.AddressOfFunctions)? + index * size_of::<u32>())
+    }
+}
+
+/// Resolves an export of a kernel module to its address, following the forwarders.
+///
+/// # Arguments
+///
+/// * `ntoskrnl_base_va` - The base virtual address of ntoskrnl.exe.
+/// * `module` - The base name of the exporting module without its extension, e.g., "hal", or `None` for ntoskrnl.exe.
+/// * `query` - The name, the hash of the name or the ordinal of the export.
+///
+/// # Returns
+///
+/// * `Ok(u64)` - The virtual address of the export.
+/// * `Err(HypervisorError::KernelModuleNotFound)` - If the module, or the module of a forwarder, isn't loaded.
+/// * `Err(HypervisorError::FailedToGetExport)` - If the export isn't found, or has more than `MAX_FORWARDER_DEPTH`
+///   forwarders.
+/// * `Err(HypervisorError::InvalidModuleImage)` - If the image of a module can't be read.
+pub fn resolve_kernel_export(ntoskrnl_base_va: u64, module: Option<&str>, query: ExportQuery) -> Result<u64, HypervisorError> {
+    let directory_table_base = PhysicalAddress::kernel_directory_table_base().unwrap_or_else(|| vmread(vmcs::guest::CR3));
+    let ntoskrnl = GuestImage::read(ntoskrnl_base_va, directory_table_base)?;
+
+    let mut image = match module {
+        Some(module) => find_kernel_module(&ntoskrnl, module, directory_table_base)?,
+        None => ntoskrnl.clone(),
+    };
+
+    // The name of a forwarded export outlives the image it was read from.
+    let mut forwarded_name: String;
+    let mut query = query;
+
+    for _ in 0..=MAX_FORWARDER_DEPTH {
+        let forwarder = match image.find_export(query).ok_or(HypervisorError::FailedToGetExport)? {
+            ExportTarget::Address(address) => return Ok(address),
+            ExportTarget::Forwarder(forwarder) => forwarder,
+        };
+
+        trace!("Export {:?} of {:#x} forwarded to {}", query, image.base_va(), forwarder);
+
+        let (module, export) = forwarder.rsplit_once('.').ok_or(HypervisorError::FailedToGetExport)?;
+        image = find_kernel_module(&ntoskrnl, module, directory_table_base)?;
+
+        forwarded_name = export.to_string();
+        query = match forwarded_name.strip_prefix('#') {
+            Some(ordinal) => ExportQuery::Ordinal(ordinal.parse().map_err(|_| HypervisorError::FailedToGetExport)?),
+            None => ExportQuery::Name(&forwarded_name),
+        };
+    }
+
+    error!("Export {:?} has more than {} forwarders", query, MAX_FORWARDER_DEPTH);
+    Err(HypervisorError::FailedToGetExport)
+}
+
+/// Enumerates the loaded kernel modules from `PsLoadedModuleList`, in load order, ntoskrnl.exe first.
+///
+/// # Arguments
+///
+/// * `ntoskrnl_base_va` - The base virtual address of ntoskrnl.exe.
+///
+/// # Returns
+///
+/// * `Ok(Vec<KernelModule>)` - The modules, at most `MAX_LOADED_MODULES` of them.
+/// * `Err(HypervisorError::KernelModuleNotFound)` - If the list can't be read.
+/// * `Err(HypervisorError::InvalidModuleImage)` - If the image of ntoskrnl.exe can't be read.
+pub fn enumerate_kernel_modules(ntoskrnl_base_va: u64) -> Result<Vec<KernelModule>, HypervisorError> {
+    let directory_table_base = PhysicalAddress::kernel_directory_table_base().unwrap_or_else(|| vmread(vmcs::guest::CR3));
+    let ntoskrnl = GuestImage::read(ntoskrnl_base_va, directory_table_base)?;
+
+    let Some(ExportTarget::Address(list_head)) = ntoskrnl.find_export(ExportQuery::Name("PsLoadedModuleList")) else {
+        return Err(HypervisorError::KernelModuleNotFound);
+    };
+
+    let read_u64 = |va: u64| read_guest_bytes(va, size_of::<u64>(), directory_table_base).and_then(|bytes| read_at::<u64>(&bytes, 0));
+    let mut entry = read_u64(list_head).ok_or(HypervisorError::KernelModuleNotFound)?;
+    let mut modules = Vec::new();
+
+    for _ in 0..MAX_LOADED_MODULES {
+        if entry == list_head || entry == 0 {
+            break;
+        }
+
+        let size_of_image = read_guest_bytes(entry + LDR_SIZE_OF_IMAGE_OFFSET, size_of::<u32>(), directory_table_base)
+            .and_then(|bytes| read_at::<u32>(&bytes, 0))
+            .ok_or(HypervisorError::KernelModuleNotFound)?;
+
+        modules.push(KernelModule {
+            base_va: read_u64(entry + LDR_DLL_BASE_OFFSET).ok_or(HypervisorError::KernelModuleNotFound)?,
+            size_of_image: size_of_image as u64,
+            name: read_module_name(entry, directory_table_base).unwrap_or_default(),
+        });
+
+        entry = read_u64(entry).ok_or(HypervisorError::KernelModuleNotFound)?;
+    }
+
+    trace!("{} kernel modules loaded", modules.len());
+
+    Ok(modules)
+}
+
+/// Reads the section table of a PE image mapped in the guest.
+///
+/// # Arguments
+///
+/// * `base_va` - The base virtual address of the image.
+/// * `directory_table_base` - The directory table base mapping the image.
+///
+/// # Returns
+///
+/// The section headers, or `Err(HypervisorError::InvalidModuleImage)` if the headers of the image can't be read.
+pub fn read_section_headers(base_va: u64, directory_table_base: u64) -> Result<Vec<IMAGE_SECTION_HEADER>, HypervisorError> {
+    let dos_header_bytes =
+        read_guest_bytes(base_va, size_of::<IMAGE_DOS_HEADER>(), directory_table_base).ok_or(HypervisorError::InvalidModuleImage)?;
+    let dos_header: IMAGE_DOS_HEADER = read_at(&dos_header_bytes, 0).ok_or(HypervisorError::InvalidModuleImage)?;
+
+    if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
+        return Err(HypervisorError::InvalidModuleImage);
+    }
+
+    let nt_headers_va = base_va + dos_header.e_lfanew as u64;
+    let nt_headers_bytes =
+        read_guest_bytes(nt_headers_va, size_of::<IMAGE_NT_HEADERS64>(), directory_table_base).ok_or(HypervisorError::InvalidModuleImage)?;
+    let nt_headers: IMAGE_NT_HEADERS64 = read_at(&nt_headers_bytes, 0).ok_or(HypervisorError::InvalidModuleImage)?;
+
+    if nt_headers.Signature != IMAGE_NT_SIGNATURE {
+        return Err(HypervisorError::InvalidModuleImage);
+    }
+
+    // The section table follows the optional header, whose size is given by the file header.
+    let section_table_va =
+        nt_headers_va + (size_of::<u32>() + size_of::<IMAGE_FILE_HEADER>()) as u64 + nt_headers.FileHeader.SizeOfOptionalHeader as u64;
+    let section_count = nt_headers.FileHeader.NumberOfSections as usize;
+
+    let section_table = read_guest_bytes(section_table_va, section_count * size_of::<IMAGE_SECTION_HEADER>(), directory_table_base)
+        .ok_or(HypervisorError::InvalidModuleImage)?;
+
+    (0..section_count)
+        .map(|index| {
+            read_at::<IMAGE_SECTION_HEADER>(&section_table, index * size_of::<IMAGE_SECTION_HEADER>()).ok_or(HypervisorError::InvalidModuleImage)
+        })
+        .collect()
+}
+
+/// Finds a loaded kernel module by its base name in `PsLoadedModuleList`.
+///
+/// # Arguments
+///
+/// * `ntoskrnl` - The image of ntoskrnl.exe, exporting `PsLoadedModuleList`.
+/// * `module` - The base name of the module, case-insensitive, with or without its extension.
+/// * `directory_table_base` - The directory table base mapping the kernel.
+///
+/// # Returns
+///
+/// The image of the module, or `Err(HypervisorError::KernelModuleNotFound)` if it isn't loaded.
+fn find_kernel_module(ntoskrnl: &GuestImage, module: &str, directory_table_base: u64) -> Result<GuestImage, HypervisorError> {
+    let Some(ExportTarget::Address(list_head)) = ntoskrnl.find_export(ExportQuery::Name("PsLoadedModuleList")) else {
+        return Err(HypervisorError::KernelModuleNotFound);
+    };
+
+    let read_u64 = |va: u64| read_guest_bytes(va, size_of::<u64>(), directory_table_base).and_then(|bytes| read_at::<u64>(&bytes, 0));
+    let mut entry = read_u64(list_head).ok_or(HypervisorError::KernelModuleNotFound)?;
+
+    for _ in 0..MAX_LOADED_MODULES {
+        if entry == list_head || entry == 0 {
+            break;
+        }
+
+        let dll_base = read_u64(entry + LDR_DLL_BASE_OFFSET).ok_or(HypervisorError::KernelModuleNotFound)?;
+        let name = read_module_name(entry, directory_table_base);
+
+        if name.as_deref().is_some_and(|name| is_module_name(name, module)) {
+            trace!("Kernel module {} at {:#x}, entry: {:#x}", module, dll_base, entry);
+            return GuestImage::read(dll_base, directory_table_base);
+        }
+
+        entry = read_u64(entry).ok_or(HypervisorError::KernelModuleNotFound)?;
+    }
+
+    Err(HypervisorError::KernelModuleNotFound)
+}
+
+/// Reads the base name of a loaded module, e.g., "hal.dll", from its `_KLDR_DATA_TABLE_ENTRY`.
+///
+/// # Arguments
+///
+/// * `entry` - The virtual address of the entry.
+/// * `directory_table_base` - The directory table base mapping the kernel.
+fn read_module_name(entry: u64, directory_table_base: u64) -> Option<String> {
+    // BaseDllName is a UNICODE_STRING: the length in bytes, the maximum length, and the buffer at offset 8.
+    let unicode_string = read_guest_bytes(entry + LDR_BASE_DLL_NAME_OFFSET, 0x10, directory_table_base)?;
+    let length = (read_at::<u16>(&unicode_string, 0)? as usize).min(MAX_MODULE_NAME_SIZE);
+    let buffer = read_at::<u64>(&unicode_string, 8)?;
+
+    let name = read_guest_bytes(buffer, length, directory_table_base)?;
+    let name: Vec<u16> = name.as_chunks::<2>().0.iter().map(|&bytes| u16::from_le_bytes(bytes)).collect();
+
+    String::from_utf16(&name).ok()
+}
+
+/// Returns `true` if the base name of a loaded module, e.g., "hal.dll", is the name of a module, e.g., "HAL", the
+/// extension being optional and the case ignored.
+///
+/// # Arguments
+///
+/// * `base_name` - The base name of the loaded module.
+/// * `module` - The name of the module.
+pub fn is_module_name(base_name: &str, module: &str) -> bool {
+    let stem = |name: &str| match name.rfind('.') {
+        Some(extension) => name[..extension].to_string(),
+        None => name.to_string(),
+    };
+
+    base_name.eq_ignore_ascii_case(module) || stem(base_name).eq_ignore_ascii_case(&stem(module))
+}
+
+/// Copies a range of guest memory, page by page.
+///
+/// # Arguments
+///
+/// * `va` - The guest virtual address of the range.
+/// * `length` - The size of the range in bytes.
+/// * `directory_table_base` - The directory table base translating the range.
+///
+/// # Returns
+///
+/// The content of the range, or `None` if a page of the range isn't mapped.
+pub fn read_guest_bytes(va: u64, length: usize, directory_table_base: u64) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(length);
+
+    while bytes.len() < length {
+        let page_va = va + bytes.len() as u64;
+        let chunk_length = (BASE_PAGE_SIZE - (page_va as usize & (BASE_PAGE_SIZE - 1))).min(length - bytes.len());
+
+        bytes.extend_from_slice(PhysicalAddress::read_guest_virt_slice_with_explicit_cr3(page_va as *const u8, chunk_length, directory_table_base)?);
+    }
+
+    Some(bytes)
+}
+
+/// Reads a value at an offset of a copy of guest memory.
+///
+/// # Arguments
+///
+/// * `data` - The copy of guest memory.
+/// * `offset` - The offset of the value in the copy.
+pub fn read_at<T>(data: &[u8], offset: usize) -> Option<T> {
+    let bytes = data.get(offset..offset.checked_add(size_of::<T>())?)?;
+    Some(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Reads a NUL-terminated string at an offset of a copy of guest memory.
+///
+/// # Arguments
+///
+/// * `data` - The copy of guest memory.
+/// * `offset` - The offset of the string in the copy.
+pub fn read_string_at(data: &[u8], offset: usize) -> Option<String> {
+    let bytes = data.get(offset..)?;
+    let length = bytes.iter().position(|&byte| byte == 0)?;
+
+    core::str::from_utf8(&bytes[..length]).ok().map(ToString::to_string)
+}
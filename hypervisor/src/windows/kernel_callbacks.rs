@@ -0,0 +1,444 @@
+//! Provides the enumeration of the notification callbacks registered in the kernel of Windows guests, the process,
+//! thread and image load notify routines, the registry callbacks and the process and thread handle callbacks, so the
+//! callbacks of EDRs and rootkits are found from below the OS, whatever the drivers registering them hide.
+//!
+//! None of the arrays and lists holding the callbacks is exported, so they are located from the code of the exported
+//! functions using them, decoded with the length disassembler of the inline hooks:
+//!
+//! - `PspCreateProcessNotifyRoutine` and `PspCreateThreadNotifyRoutine` are referenced by the first RIP-relative `lea`
+//!   of the function called by `PsSetCreateProcessNotifyRoutine` and `PsSetCreateThreadNotifyRoutine`.
+//! - `PspLoadImageNotifyRoutine` is referenced by the first RIP-relative `lea` of `PsSetLoadImageNotifyRoutineEx`.
+//! - `CallbackListHead` is referenced by the first RIP-relative `lea` of `CmUnRegisterCallback` targeting a list head.
+//! - The handle callbacks are in the `CallbackList` of the `_OBJECT_TYPE` exported by `PsProcessType` and
+//!   `PsThreadType`.
+//!
+//! Each callback reports the loaded module containing its function, none for a function outside of the images of
+//! `PsLoadedModuleList`, e.g., the code of a manually mapped driver. The sources are enumerated independently, so a
+//! source that can't be located, e.g., on a build whose code differs, doesn't prevent reporting the others.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            hooks::{
+                hook_manager::{kernel_image, KernelImage},
+                inline::{Instruction, RelativeOperand},
+            },
+            support::vmread,
+        },
+        windows::{
+            introspection::walk_list,
+            kernel::{enumerate_kernel_modules, read_guest_bytes, resolve_kernel_export, ExportQuery, KernelModule},
+        },
+    },
+    alloc::vec::Vec,
+    log::*,
+    shared::{KernelCallback, KernelCallbackKind, KERNEL_CALLBACK_MODULE_NAME_SIZE},
+    x86::vmx::vmcs,
+};
+
+/// The maximum number of callbacks returned by an enumeration.
+pub const MAX_KERNEL_CALLBACKS: usize = 0x400;
+
+/// The number of slots of `PspCreateProcessNotifyRoutine`, `PspCreateThreadNotifyRoutine` and
+/// `PspLoadImageNotifyRoutine`.
+const NOTIFY_ROUTINE_SLOTS: u64 = 64;
+
+/// The bits of an `_EX_FAST_REF` holding the reference count, the rest being the pointer.
+const EX_FAST_REF_MASK: u64 = 0xF;
+
+/// Constants for offsets in `_EX_CALLBACK_ROUTINE_BLOCK`, the blocks referenced by the slots of the notify arrays.
+const EX_CALLBACK_ROUTINE_BLOCK_FUNCTION_OFFSET: u64 = 0x8;
+const EX_CALLBACK_ROUTINE_BLOCK_CONTEXT_OFFSET: u64 = 0x10;
+
+/// Constants for offsets in `_CM_CALLBACK_ENTRY`, the entries of `CallbackListHead`.
+const CM_CALLBACK_ENTRY_COOKIE_OFFSET: u64 = 0x18;
+const CM_CALLBACK_ENTRY_CONTEXT_OFFSET: u64 = 0x20;
+const CM_CALLBACK_ENTRY_FUNCTION_OFFSET: u64 = 0x28;
+
+/// The offset of `_OBJECT_TYPE.CallbackList`, the list of the handle callbacks of an object type.
+const OBJECT_TYPE_CALLBACK_LIST_OFFSET: u64 = 0xC8;
+
+/// Constants for offsets in `_OB_CALLBACK_ENTRY`, the entries of `_OBJECT_TYPE.CallbackList`.
+const OB_CALLBACK_ENTRY_OPERATIONS_OFFSET: u64 = 0x10;
+const OB_CALLBACK_ENTRY_ENABLED_OFFSET: u64 = 0x14;
+const OB_CALLBACK_ENTRY_PRE_OPERATION_OFFSET: u64 = 0x28;
+const OB_CALLBACK_ENTRY_POST_OPERATION_OFFSET: u64 = 0x30;
+
+/// The maximum number of entries of a callback list walked.
+const MAX_CALLBACK_LIST_ENTRIES: usize = 0x400;
+
+/// The maximum number of bytes of a function decoded to find the instructions referencing a callback array or list.
+const MAX_DECODED_FUNCTION_SIZE: usize = 0x200;
+
+/// The opcodes of the instructions followed or ending the decoding of a function.
+const CALL_REL32_OPCODE: u8 = 0xE8;
+const JMP_REL32_OPCODE: u8 = 0xE9;
+const LEA_OPCODE: u8 = 0x8D;
+const RET_OPCODE: u8 = 0xC3;
+const INT3_OPCODE: u8 = 0xCC;
+
+/// An address referenced by an instruction of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CodeReference {
+    /// The target of a `call rel32` or of a `jmp rel32` tail call.
+    Call(u64),
+
+    /// The address loaded by a RIP-relative `lea`.
+    Lea(u64),
+}
+
+/// Enumerates the notification callbacks registered in the kernel of the guest.
+///
+/// # Arguments
+///
+/// * `max_callbacks` - The maximum number of callbacks returned.
+///
+/// # Returns
+///
+/// * `Ok((Vec<KernelCallback>, usize))` - The callbacks, at most `max_callbacks` of them, and the number of callbacks
+///   registered.
+/// * `Err(HypervisorError::GetKernelBaseFailed)` - If the base of ntoskrnl.exe isn't captured yet.
+/// * `Err(HypervisorError::KernelCallbacksNotFound)` - If none of the callback arrays and lists can be located.
+pub fn enumerate_kernel_callbacks(max_callbacks: usize) -> Result<(Vec<KernelCallback>, usize), HypervisorError> {
+    let KernelImage {
+        base_va: ntoskrnl_base_va,
+        size: ntoskrnl_size,
+        ..
+    } = kernel_image();
+
+    if ntoskrnl_base_va == 0 {
+        return Err(HypervisorError::GetKernelBaseFailed);
+    }
+
+    let ntoskrnl = KernelModule {
+        base_va: ntoskrnl_base_va,
+        size_of_image: ntoskrnl_size,
+        name: "ntoskrnl.exe".into(),
+    };
+
+    let modules = enumerate_kernel_modules(ntoskrnl_base_va).unwrap_or_else(|e| {
+        warn!("Failed to enumerate the kernel modules, the callbacks are reported without them: {:?}", e);
+        Vec::new()
+    });
+
+    let mut callbacks = Vec::new();
+    let mut located_sources = 0;
+
+    let notify_routines = [
+        (KernelCallbackKind::ProcessNotify, "PsSetCreateProcessNotifyRoutine", true),
+        (KernelCallbackKind::ThreadNotify, "PsSetCreateThreadNotifyRoutine", true),
+        (KernelCallbackKind::LoadImageNotify, "PsSetLoadImageNotifyRoutineEx", false),
+    ];
+
+    for (kind, function, is_wrapper) in notify_routines {
+        match find_notify_array(&ntoskrnl, function, is_wrapper) {
+            Some(array) => {
+                trace!("{:?} array at {:#x}", kind, array);
+                read_notify_array(kind, array, &modules, &mut callbacks);
+                located_sources += 1;
+            }
+            None => warn!("Failed to locate the {:?} array from {}", kind, function),
+        }
+    }
+
+    match find_registry_callback_list(&ntoskrnl) {
+        Some(list_head) => {
+            trace!("CallbackListHead at {:#x}", list_head);
+            read_registry_callbacks(list_head, &modules, &mut callbacks);
+            located_sources += 1;
+        }
+        None => warn!("Failed to locate CallbackListHead from CmUnRegisterCallback"),
+    }
+
+    let object_types = [
+        ("PsProcessType", KernelCallbackKind::ProcessHandlePreOperation, KernelCallbackKind::ProcessHandlePostOperation),
+        ("PsThreadType", KernelCallbackKind::ThreadHandlePreOperation, KernelCallbackKind::ThreadHandlePostOperation),
+    ];
+
+    for (object_type, pre_operation_kind, post_operation_kind) in object_types {
+        let object_type_va = resolve_kernel_export(ntoskrnl_base_va, None, ExportQuery::Name(object_type))
+            .ok()
+            .and_then(read::<u64>)
+            .filter(|&object_type_va| object_type_va != 0);
+
+        match object_type_va {
+            Some(object_type_va) => {
+                let list_head = object_type_va + OBJECT_TYPE_CALLBACK_LIST_OFFSET;
+                read_handle_callbacks(list_head, pre_operation_kind, post_operation_kind, &modules, &mut callbacks);
+                located_sources += 1;
+            }
+            None => warn!("Failed to read the object type of {}", object_type),
+        }
+    }
+
+    if located_sources == 0 {
+        return Err(HypervisorError::KernelCallbacksNotFound);
+    }
+
+    let total_callbacks = callbacks.len();
+    callbacks.truncate(max_callbacks);
+
+    trace!("{} kernel callbacks registered", total_callbacks);
+
+    Ok((callbacks, total_callbacks))
+}
+
+/// Locates a notify array of ntoskrnl.exe from an exported function registering in it.
+///
+/// # Arguments
+///
+/// * `ntoskrnl` - The image of ntoskrnl.exe.
+/// * `function` - The name of the exported function.
+/// * `is_wrapper` - Whether the array is referenced by the function it calls first rather than by the function itself.
+///
+/// # Returns
+///
+/// The virtual address of the array, or `None` if it can't be located.
+fn find_notify_array(ntoskrnl: &KernelModule, function: &str, is_wrapper: bool) -> Option<u64> {
+    let mut function_va = resolve_kernel_export(ntoskrnl.base_va, None, ExportQuery::Name(function)).ok()?;
+
+    if is_wrapper {
+        function_va = decode_references(function_va).into_iter().find_map(|reference| match reference {
+            CodeReference::Call(target) if ntoskrnl.contains(target) => Some(target),
+            _ => None,
+        })?;
+    }
+
+    decode_references(function_va).into_iter().find_map(|reference| match reference {
+        CodeReference::Lea(target) if ntoskrnl.contains(target) => Some(target),
+        _ => None,
+    })
+}
+
+/// Locates `CallbackListHead`, the list of the registry callbacks, from `CmUnRegisterCallback`.
+///
+/// # Arguments
+///
+/// * `ntoskrnl` - The image of ntoskrnl.exe.
+///
+/// # Returns
+///
+/// The virtual address of the head of the list, or `None` if it can't be located.
+fn find_registry_callback_list(ntoskrnl: &KernelModule) -> Option<u64> {
+    let function_va = resolve_kernel_export(ntoskrnl.base_va, None, ExportQuery::Name("CmUnRegisterCallback")).ok()?;
+
+    // The function also loads the lock and the count of the callbacks, which don't look like a list head.
+    decode_references(function_va).into_iter().find_map(|reference| match reference {
+        CodeReference::Lea(target) if ntoskrnl.contains(target) && is_list_head(target) => Some(target),
+        _ => None,
+    })
+}
+
+/// Reads the notify routines registered in a notify array.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of the notify routines of the array.
+/// * `array` - The virtual address of the array of `_EX_FAST_REF`.
+/// * `modules` - The loaded kernel modules.
+/// * `callbacks` - The callbacks, the notify routines being appended to them.
+fn read_notify_array(kind: KernelCallbackKind, array: u64, modules: &[KernelModule], callbacks: &mut Vec<KernelCallback>) {
+    for slot in 0..NOTIFY_ROUTINE_SLOTS {
+        let Some(fast_ref) = read::<u64>(array + slot * 8) else {
+            warn!("Failed to read slot {} of the {:?} array", slot, kind);
+            return;
+        };
+
+        let routine_block = fast_ref & !EX_FAST_REF_MASK;
+
+        if routine_block == 0 {
+            continue;
+        }
+
+        let Some(function) = read::<u64>(routine_block + EX_CALLBACK_ROUTINE_BLOCK_FUNCTION_OFFSET) else {
+            continue;
+        };
+
+        let context = read::<u64>(routine_block + EX_CALLBACK_ROUTINE_BLOCK_CONTEXT_OFFSET).unwrap_or_default();
+
+        callbacks.push(new_callback(kind, function, routine_block, context, 0, true, modules));
+    }
+}
+
+/// Reads the registry callbacks of `CallbackListHead`.
+///
+/// # Arguments
+///
+/// * `list_head` - The virtual address of `CallbackListHead`.
+/// * `modules` - The loaded kernel modules.
+/// * `callbacks` - The callbacks, the registry callbacks being appended to them.
+fn read_registry_callbacks(list_head: u64, modules: &[KernelModule], callbacks: &mut Vec<KernelCallback>) {
+    let walked = walk_list(list_head, MAX_CALLBACK_LIST_ENTRIES, |entry| {
+        let function = read::<u64>(entry + CM_CALLBACK_ENTRY_FUNCTION_OFFSET)?;
+        let context = read::<u64>(entry + CM_CALLBACK_ENTRY_CONTEXT_OFFSET)?;
+        let cookie = read::<u64>(entry + CM_CALLBACK_ENTRY_COOKIE_OFFSET)?;
+
+        callbacks.push(new_callback(KernelCallbackKind::Registry, function, entry, context, cookie, true, modules));
+        Some(())
+    });
+
+    if walked.is_none() {
+        warn!("Failed to walk the registry callbacks at {:#x}", list_head);
+    }
+}
+
+/// Reads the handle callbacks of an object type, one callback for each of the pre-operation and the post-operation
+/// of an entry.
+///
+/// # Arguments
+///
+/// * `list_head` - The virtual address of `_OBJECT_TYPE.CallbackList`.
+/// * `pre_operation_kind` - The kind of the pre-operation callbacks of the object type.
+/// * `post_operation_kind` - The kind of the post-operation callbacks of the object type.
+/// * `modules` - The loaded kernel modules.
+/// * `callbacks` - The callbacks, the handle callbacks being appended to them.
+fn read_handle_callbacks(
+    list_head: u64,
+    pre_operation_kind: KernelCallbackKind,
+    post_operation_kind: KernelCallbackKind,
+    modules: &[KernelModule],
+    callbacks: &mut Vec<KernelCallback>,
+) {
+    let walked = walk_list(list_head, MAX_CALLBACK_LIST_ENTRIES, |entry| {
+        let operations = read::<u32>(entry + OB_CALLBACK_ENTRY_OPERATIONS_OFFSET)?;
+        let enabled = read::<u8>(entry + OB_CALLBACK_ENTRY_ENABLED_OFFSET)? != 0;
+
+        let operation_callbacks = [
+            (pre_operation_kind, OB_CALLBACK_ENTRY_PRE_OPERATION_OFFSET),
+            (post_operation_kind, OB_CALLBACK_ENTRY_POST_OPERATION_OFFSET),
+        ];
+
+        for (kind, offset) in operation_callbacks {
+            let function = read::<u64>(entry + offset)?;
+
+            if function != 0 {
+                callbacks.push(new_callback(kind, function, entry, 0, operations as u64, enabled, modules));
+            }
+        }
+
+        Some(())
+    });
+
+    if walked.is_none() {
+        warn!("Failed to walk the handle callbacks at {:#x}", list_head);
+    }
+}
+
+/// Creates a callback, with the loaded module containing its function.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of the callback.
+/// * `function` - The virtual address of the callback function.
+/// * `registration` - The virtual address of the registration of the callback.
+/// * `context` - The context of the callback.
+/// * `cookie` - The cookie or the operations of the callback.
+/// * `enabled` - Whether the callback is enabled.
+/// * `modules` - The loaded kernel modules.
+fn new_callback(
+    kind: KernelCallbackKind,
+    function: u64,
+    registration: u64,
+    context: u64,
+    cookie: u64,
+    enabled: bool,
+    modules: &[KernelModule],
+) -> KernelCallback {
+    let module = modules.iter().find(|module| module.contains(function));
+
+    let mut module_name = [0u8; KERNEL_CALLBACK_MODULE_NAME_SIZE];
+
+    if let Some(module) = module {
+        // The name is NUL-terminated if it fits.
+        let length = module.name.len().min(KERNEL_CALLBACK_MODULE_NAME_SIZE - 1);
+        module_name[..length].copy_from_slice(&module.name.as_bytes()[..length]);
+    }
+
+    KernelCallback {
+        kind: kind as u64,
+        function,
+        registration,
+        context,
+        cookie,
+        enabled: enabled as u64,
+        module_base: module.map_or(0, |module| module.base_va),
+        module_name,
+    }
+}
+
+/// Decodes a function of the kernel up to its first return, tail call or undecodable instruction, and returns the
+/// addresses its calls and RIP-relative `lea` reference, in code order.
+///
+/// # Arguments
+///
+/// * `function_va` - The virtual address of the function.
+fn decode_references(function_va: u64) -> Vec<CodeReference> {
+    let mut references = Vec::new();
+    let directory_table_base = PhysicalAddress::kernel_directory_table_base().unwrap_or_else(|| vmread(vmcs::guest::CR3));
+
+    let Some(code) = read_guest_bytes(function_va, MAX_DECODED_FUNCTION_SIZE, directory_table_base) else {
+        return references;
+    };
+
+    let mut offset = 0;
+
+    while let Some(instruction) = Instruction::decode(&code[offset..]) {
+        let opcode = code[offset + instruction.opcode_offset];
+        let next_instruction_va = function_va + (offset + instruction.length) as u64;
+
+        let displacement = |displacement_offset: usize| {
+            let bytes = code.get(offset + displacement_offset..offset + displacement_offset + 4)?;
+            Some(i32::from_le_bytes(bytes.try_into().ok()?) as i64)
+        };
+
+        match instruction.relative {
+            RelativeOperand::Branch32(displacement_offset) if opcode == CALL_REL32_OPCODE || opcode == JMP_REL32_OPCODE => {
+                if let Some(displacement) = displacement(displacement_offset) {
+                    references.push(CodeReference::Call(next_instruction_va.wrapping_add_signed(displacement)));
+                }
+
+                if opcode == JMP_REL32_OPCODE {
+                    break;
+                }
+            }
+            RelativeOperand::RipDisplacement(displacement_offset) if opcode == LEA_OPCODE => {
+                if let Some(displacement) = displacement(displacement_offset) {
+                    references.push(CodeReference::Lea(next_instruction_va.wrapping_add_signed(displacement)));
+                }
+            }
+            _ if opcode == RET_OPCODE || opcode == INT3_OPCODE => break,
+            _ => {}
+        }
+
+        offset += instruction.length;
+
+        if offset >= code.len() {
+            break;
+        }
+    }
+
+    references
+}
+
+/// Returns `true` if a `_LIST_ENTRY` of the kernel is linked, its next entry linking back to it.
+///
+/// # Arguments
+///
+/// * `va` - The virtual address of the `_LIST_ENTRY`.
+fn is_list_head(va: u64) -> bool {
+    read::<u64>(va)
+        .filter(|&flink| flink != 0)
+        .and_then(|flink| read::<u64>(flink + 8))
+        .is_some_and(|blink| blink == va)
+}
+
+/// Reads a value of the kernel address space.
+///
+/// # Arguments
+///
+/// * `va` - The virtual address of the value.
+fn read<T: Sized>(va: u64) -> Option<T> {
+    PhysicalAddress::read_guest_kernel_virt(va as *const T)
+}
@@ -0,0 +1,152 @@
+//! Measures the image of the guest kernel (ntoskrnl.exe) once its base address is captured, giving a hypervisor-rooted
+//! record of which kernel was booted.
+//!
+//! The SHA-256 digest covers the headers and the sections that are neither writable nor discardable, in the order of
+//! the section table, as they are mapped in memory. Relocations have already been applied by the loader, so the digest
+//! depends on the base address recorded alongside it. The measurement is logged, and with the `tpm_measurement`
+//! feature, also extended into `MEASUREMENT_PCR` of the SHA-256 bank of the TPM.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::addresses::PhysicalAddress,
+        sha256::{Sha256, DIGEST_SIZE},
+        windows::nt::{
+            pe::{get_nt_headers, get_section_headers},
+            types::{IMAGE_SCN_MEM_DISCARDABLE, IMAGE_SCN_MEM_WRITE},
+        },
+    },
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The PCR extended with the digest of the kernel image, the PCR reserved for application support.
+pub const MEASUREMENT_PCR: u32 = 23;
+
+/// A page of zeroes, hashed in place of the pages of the image that aren't mapped.
+static ZERO_PAGE: [u8; BASE_PAGE_SIZE] = [0; BASE_PAGE_SIZE];
+
+/// The measurement of the image of the guest kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelMeasurement {
+    /// The base virtual address of the image.
+    pub image_base_va: u64,
+
+    /// The size of the image in memory.
+    pub size_of_image: u32,
+
+    /// The link time of the image from the file header, which with `size_of_image` identifies the build of the image.
+    pub time_date_stamp: u32,
+
+    /// The SHA-256 digest of the headers and the measured sections.
+    pub digest: [u8; DIGEST_SIZE],
+
+    /// The number of bytes hashed.
+    pub measured_bytes: u64,
+
+    /// The number of pages of the measured sections that weren't mapped, hashed as zeroes.
+    pub unmapped_pages: u64,
+}
+
+lazy_static! {
+    /// The measurement of the image of the guest kernel, taken the first time its base address is captured.
+    pub static ref SHARED_KERNEL_MEASUREMENT: Mutex<Option<KernelMeasurement>> = Mutex::new(None);
+}
+
+/// Measures the image of the guest kernel and records the measurement, unless it has already been measured.
+///
+/// # Arguments
+///
+/// * `image_base_va` - The base virtual address of the image.
+/// * `image_base_pa` - The base physical address of the image.
+pub fn record_kernel_measurement(image_base_va: u64, image_base_pa: u64) {
+    let mut kernel_measurement = SHARED_KERNEL_MEASUREMENT.lock();
+
+    if kernel_measurement.is_some() {
+        return;
+    }
+
+    let measurement = match measure_kernel_image(image_base_va, image_base_pa) {
+        Ok(measurement) => measurement,
+        Err(error) => {
+            error!("Failed to measure the kernel image: {:?}", error);
+            return;
+        }
+    };
+
+    info!("==================== KERNEL MEASUREMENT ====================");
+    info!("Image base: {:#x}, size: {:#x}, timestamp: {:#x}", measurement.image_base_va, measurement.size_of_image, measurement.time_date_stamp);
+    info!("SHA-256: {:02x?}", measurement.digest);
+    info!("Measured {:#x} bytes, {} unmapped pages", measurement.measured_bytes, measurement.unmapped_pages);
+
+    #[cfg(feature = "tpm_measurement")]
+    if let Err(error) = crate::tpm::extend_pcr(MEASUREMENT_PCR, &measurement.digest) {
+        error!("Failed to extend PCR {} with the kernel measurement: {:?}", MEASUREMENT_PCR, error);
+    }
+
+    *kernel_measurement = Some(measurement);
+}
+
+/// Hashes the headers and the sections that are neither writable nor discardable of an image mapped in the guest.
+///
+/// # Arguments
+///
+/// * `image_base_va` - The base virtual address of the image.
+/// * `image_base_pa` - The base physical address of the image, the headers are read from.
+///
+/// # Returns
+///
+/// Returns `Ok(KernelMeasurement)` if the headers are valid, otherwise `Err(HypervisorError)`.
+fn measure_kernel_image(image_base_va: u64, image_base_pa: u64) -> Result<KernelMeasurement, HypervisorError> {
+    let nt_headers = unsafe { get_nt_headers(image_base_pa as _).ok_or(HypervisorError::FailedToGetKernelSize)? };
+    let section_headers = unsafe { get_section_headers(image_base_pa as _).ok_or(HypervisorError::FailedToGetKernelSize)? };
+
+    let (size_of_image, size_of_headers, time_date_stamp) =
+        unsafe { ((*nt_headers).OptionalHeader.SizeOfImage, (*nt_headers).OptionalHeader.SizeOfHeaders, (*nt_headers).FileHeader.TimeDateStamp) };
+
+    let mut hash = Sha256::new();
+    let mut measured_bytes = 0u64;
+    let mut unmapped_pages = 0u64;
+
+    let mut measure_range = |rva: u64, length: u64| {
+        let end = (rva + length).min(size_of_image as u64);
+        let mut rva = rva;
+
+        while rva < end {
+            let length = (BASE_PAGE_SIZE as u64 - (rva & (BASE_PAGE_SIZE as u64 - 1))).min(end - rva) as usize;
+
+            match PhysicalAddress::read_guest_virt_slice_with_current_cr3((image_base_va + rva) as *const u8, length) {
+                Some(data) => hash.update(data),
+                None => {
+                    unmapped_pages += 1;
+                    hash.update(&ZERO_PAGE[..length]);
+                }
+            }
+
+            measured_bytes += length as u64;
+            rva += length as u64;
+        }
+    };
+
+    measure_range(0, size_of_headers as u64);
+
+    for section in section_headers {
+        if section.Characteristics & (IMAGE_SCN_MEM_WRITE | IMAGE_SCN_MEM_DISCARDABLE) != 0 {
+            continue;
+        }
+
+        trace!("Measuring section {:?} at RVA: {:#x}", core::str::from_utf8(&section.Name).unwrap_or("?"), section.VirtualAddress);
+        measure_range(section.VirtualAddress as u64, section.VirtualSize as u64);
+    }
+
+    Ok(KernelMeasurement {
+        image_base_va,
+        size_of_image,
+        time_date_stamp,
+        digest: hash.finalize(),
+        measured_bytes,
+        unmapped_pages,
+    })
+}
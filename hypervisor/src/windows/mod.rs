@@ -1,4 +1,12 @@
+pub mod code_integrity;
 pub mod eprocess;
+pub mod introspection;
+pub mod kernel;
+pub mod kernel_callbacks;
 pub mod log;
+pub mod measurement;
 pub mod nt;
+pub mod offsets;
+pub mod process_dump;
 pub mod ssdt;
+pub mod symbols;
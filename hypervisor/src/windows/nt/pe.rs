@@ -6,7 +6,8 @@ use {
         error::HypervisorError,
         intel::addresses::PhysicalAddress,
         windows::nt::types::{
-            IMAGE_DIRECTORY_ENTRY_EXPORT, IMAGE_DOS_SIGNATURE, IMAGE_NT_SIGNATURE, PIMAGE_DOS_HEADER, PIMAGE_EXPORT_DIRECTORY, PIMAGE_NT_HEADERS64,
+            IMAGE_DIRECTORY_ENTRY_EXPORT, IMAGE_DOS_SIGNATURE, IMAGE_FILE_HEADER, IMAGE_NT_SIGNATURE, IMAGE_SECTION_HEADER, PIMAGE_DOS_HEADER,
+            PIMAGE_EXPORT_DIRECTORY, PIMAGE_NT_HEADERS64, PIMAGE_SECTION_HEADER,
         },
     },
     core::slice::from_raw_parts,
@@ -107,6 +108,30 @@ pub unsafe fn get_size_of_image(module_base: *mut u8) -> Option<u32> {
     Some((*nt_headers).OptionalHeader.SizeOfImage)
 }
 
+/// Get the section headers of an image
+///
+/// # Arguments
+///
+/// * `module_base` - The base address of the module.
+///
+/// # Returns
+///
+/// * `Option<&[IMAGE_SECTION_HEADER]>` - The section headers, which follow the optional header.
+///
+/// # Safety
+///
+/// The headers of the module must be mapped at `module_base`.
+pub unsafe fn get_section_headers<'a>(module_base: *mut u8) -> Option<&'a [IMAGE_SECTION_HEADER]> {
+    let nt_headers = get_nt_headers(module_base)?;
+
+    let section_headers = (nt_headers as usize
+        + core::mem::size_of::<u32>()
+        + core::mem::size_of::<IMAGE_FILE_HEADER>()
+        + (*nt_headers).FileHeader.SizeOfOptionalHeader as usize) as PIMAGE_SECTION_HEADER;
+
+    Some(from_raw_parts(section_headers, (*nt_headers).FileHeader.NumberOfSections as usize))
+}
+
 /// Get the length of a C String
 ///
 /// # Arguments
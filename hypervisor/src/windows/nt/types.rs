@@ -7,16 +7,21 @@ pub const IMAGE_DOS_SIGNATURE: u16 = 23117u16;
 pub const IMAGE_NT_SIGNATURE: u32 = 17744u32;
 pub const IMAGE_DIRECTORY_ENTRY_EXPORT: IMAGE_DIRECTORY_ENTRY = 0u16;
 pub const SYSTEM_MODULE_INFORMATION: SYSTEM_INFORMATION_CLASS = 11;
+pub const IMAGE_SCN_MEM_DISCARDABLE: IMAGE_SECTION_CHARACTERISTICS = 0x02000000u32;
+pub const IMAGE_SCN_MEM_EXECUTE: IMAGE_SECTION_CHARACTERISTICS = 0x20000000u32;
+pub const IMAGE_SCN_MEM_WRITE: IMAGE_SECTION_CHARACTERISTICS = 0x80000000u32;
 
 pub type PIMAGE_DOS_HEADER = *mut IMAGE_DOS_HEADER;
 pub type PIMAGE_NT_HEADERS64 = *mut IMAGE_NT_HEADERS64;
 pub type PIMAGE_EXPORT_DIRECTORY = *mut IMAGE_EXPORT_DIRECTORY;
+pub type PIMAGE_SECTION_HEADER = *mut IMAGE_SECTION_HEADER;
 pub type IMAGE_FILE_MACHINE = u16;
 pub type IMAGE_FILE_CHARACTERISTICS = u16;
 pub type IMAGE_OPTIONAL_HEADER_MAGIC = u16;
 pub type IMAGE_SUBSYSTEM = u16;
 pub type IMAGE_DLL_CHARACTERISTICS = u16;
 pub type IMAGE_DIRECTORY_ENTRY = u16;
+pub type IMAGE_SECTION_CHARACTERISTICS = u32;
 pub type PHYSICAL_ADDRESS = _LARGE_INTEGER;
 pub type SYSTEM_INFORMATION_CLASS = u32;
 pub type HANDLE = isize;
@@ -205,6 +210,20 @@ pub struct IMAGE_EXPORT_DIRECTORY {
     pub AddressOfNameOrdinals: u32,
 }
 
+#[repr(C)]
+pub struct IMAGE_SECTION_HEADER {
+    pub Name: [u8; 8],
+    pub VirtualSize: u32,
+    pub VirtualAddress: u32,
+    pub SizeOfRawData: u32,
+    pub PointerToRawData: u32,
+    pub PointerToRelocations: u32,
+    pub PointerToLinenumbers: u32,
+    pub NumberOfRelocations: u16,
+    pub NumberOfLinenumbers: u16,
+    pub Characteristics: IMAGE_SECTION_CHARACTERISTICS,
+}
+
 //0x10 bytes (sizeof)
 #[repr(C)]
 #[derive(Clone, Copy)]
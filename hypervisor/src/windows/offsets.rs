@@ -0,0 +1,263 @@
+//! Provides the offsets of the fields of the kernel structures of the supported Windows builds, e.g., `_EPROCESS`,
+//! `_KPROCESS` and `_KTHREAD`, so the introspection (see `eprocess`) follows the layout of the running build rather
+//! than the one of a single version.
+//!
+//! The build is detected once, from the `NtBuildNumber` export of ntoskrnl.exe when the kernel base is known, or from
+//! `KUSER_SHARED_DATA`, mapped at the same address in every address space. The lookups fail on the builds without
+//! offsets, instead of reading the structures with the layout of another build.
+//!
+//! # References
+//!
+//! https://www.vergiliusproject.com/kernels/x64
+
+use {
+    crate::{
+        intel::addresses::PhysicalAddress,
+        windows::kernel::{resolve_kernel_export, ExportQuery},
+    },
+    core::sync::atomic::{AtomicU32, Ordering},
+    log::*,
+};
+
+/// The virtual address of `KUSER_SHARED_DATA` in the kernel address space.
+const KUSER_SHARED_DATA: u64 = 0xFFFF_F780_0000_0000;
+
+/// The offsets of `KUSER_SHARED_DATA.NtBuildNumber` and `KUSER_SHARED_DATA.NtMajorVersion`.
+const KUSER_SHARED_DATA_NT_BUILD_NUMBER_OFFSET: u64 = 0x260;
+const KUSER_SHARED_DATA_NT_MAJOR_VERSION_OFFSET: u64 = 0x26C;
+
+/// The bits of the `NtBuildNumber` export holding the build number, the upper ones flagging a free or checked build.
+const NT_BUILD_NUMBER_MASK: u32 = 0xFFFF;
+
+/// The build number of the guest, 0 until it is detected, as it doesn't change until the next boot.
+static WINDOWS_BUILD_NUMBER: AtomicU32 = AtomicU32::new(0);
+
+/// The offsets of the fields of the kernel structures of a range of Windows builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsOffsets {
+    /// The first build number of the range.
+    pub first_build: u32,
+
+    /// The last build number of the range, inclusive.
+    pub last_build: u32,
+
+    /// The offset of `_KTHREAD.ApcState.Process`, the process of a thread.
+    pub kthread_process: u64,
+
+    /// The offset of `_KTHREAD.Teb`.
+    pub kthread_teb: u64,
+
+    /// The offset of `_ETHREAD.Cid`, the IDs of the process and of the thread.
+    pub ethread_cid: u64,
+
+    /// The offset of `_ETHREAD.ThreadListEntry`, the entry of a thread in the list of threads of its process.
+    pub ethread_thread_list_entry: u64,
+
+    /// The offset of `_KPROCESS.DirectoryTableBase`.
+    pub kprocess_directory_table_base: u64,
+
+    /// The offset of `_KPROCESS.UserDirectoryTableBase`, the user address space of a process under KVA shadow.
+    pub kprocess_user_directory_table_base: u64,
+
+    /// The offset of `_EPROCESS.UniqueProcessId`.
+    pub eprocess_unique_process_id: u64,
+
+    /// The offset of `_EPROCESS.ActiveProcessLinks`.
+    pub eprocess_active_process_links: u64,
+
+    /// The offset of `_EPROCESS.ImageFilePointer`.
+    pub eprocess_image_file_pointer: u64,
+
+    /// The offset of `_EPROCESS.Peb`.
+    pub eprocess_peb: u64,
+
+    /// The offset of `_EPROCESS.ImageFileName`.
+    pub eprocess_image_file_name: u64,
+
+    /// The offset of `_EPROCESS.ThreadListHead`, the head of the list of threads of a process.
+    pub eprocess_thread_list_head: u64,
+
+    /// The offset of `_EPROCESS.VadRoot`, the root of the VAD tree of a process.
+    pub eprocess_vad_root: u64,
+}
+
+/// The offsets of the supported builds, by increasing build number.
+const WINDOWS_OFFSETS: [WindowsOffsets; 4] = [
+    // Windows 10 1809 and Windows Server 2019.
+    WindowsOffsets {
+        first_build: 17763,
+        last_build: 17763,
+        kthread_process: 0xB8,
+        kthread_teb: 0xF0,
+        ethread_cid: 0x640,
+        ethread_thread_list_entry: 0x6A8,
+        kprocess_directory_table_base: 0x28,
+        kprocess_user_directory_table_base: 0x278,
+        eprocess_unique_process_id: 0x2E0,
+        eprocess_active_process_links: 0x2E8,
+        eprocess_image_file_pointer: 0x448,
+        eprocess_peb: 0x3F8,
+        eprocess_image_file_name: 0x450,
+        eprocess_thread_list_head: 0x488,
+        eprocess_vad_root: 0x628,
+    },
+    // Windows 10 1903 and 1909.
+    WindowsOffsets {
+        first_build: 18362,
+        last_build: 18363,
+        kthread_process: 0xB8,
+        kthread_teb: 0xF0,
+        ethread_cid: 0x648,
+        ethread_thread_list_entry: 0x6B8,
+        kprocess_directory_table_base: 0x28,
+        kprocess_user_directory_table_base: 0x280,
+        eprocess_unique_process_id: 0x2E8,
+        eprocess_active_process_links: 0x2F0,
+        eprocess_image_file_pointer: 0x448,
+        eprocess_peb: 0x3F8,
+        eprocess_image_file_name: 0x450,
+        eprocess_thread_list_head: 0x488,
+        eprocess_vad_root: 0x658,
+    },
+    // Windows 10 2004 to 22H2 and Windows Server 2022.
+    WindowsOffsets {
+        first_build: 19041,
+        last_build: 20348,
+        kthread_process: 0xB8,
+        kthread_teb: 0xF0,
+        ethread_cid: 0x478,
+        ethread_thread_list_entry: 0x4E8,
+        kprocess_directory_table_base: 0x28,
+        kprocess_user_directory_table_base: 0x388,
+        eprocess_unique_process_id: 0x440,
+        eprocess_active_process_links: 0x448,
+        eprocess_image_file_pointer: 0x5A0,
+        eprocess_peb: 0x550,
+        eprocess_image_file_name: 0x5A8,
+        eprocess_thread_list_head: 0x5E0,
+        eprocess_vad_root: 0x7D8,
+    },
+    // Windows 11 21H2 to 23H2, whose `_KTHREAD` is larger.
+    WindowsOffsets {
+        first_build: 22000,
+        last_build: 22631,
+        kthread_process: 0xB8,
+        kthread_teb: 0xF0,
+        ethread_cid: 0x4C8,
+        ethread_thread_list_entry: 0x538,
+        kprocess_directory_table_base: 0x28,
+        kprocess_user_directory_table_base: 0x388,
+        eprocess_unique_process_id: 0x440,
+        eprocess_active_process_links: 0x448,
+        eprocess_image_file_pointer: 0x5A0,
+        eprocess_peb: 0x550,
+        eprocess_image_file_name: 0x5A8,
+        eprocess_thread_list_head: 0x5E0,
+        eprocess_vad_root: 0x7D8,
+    },
+];
+
+/// Detects the build number of the guest from the `NtBuildNumber` export of ntoskrnl.exe, or from `KUSER_SHARED_DATA`
+/// if it can't be resolved, and caches it.
+///
+/// # Arguments
+///
+/// * `ntoskrnl_base_va` - The base virtual address of ntoskrnl.exe.
+///
+/// # Returns
+///
+/// The build number of the guest, or `None` if it can't be read.
+pub fn detect_windows_build(ntoskrnl_base_va: u64) -> Option<u32> {
+    if let Some(build_number) = cached_build_number() {
+        return Some(build_number);
+    }
+
+    let build_number = resolve_kernel_export(ntoskrnl_base_va, None, ExportQuery::Name("NtBuildNumber"))
+        .ok()
+        .and_then(|nt_build_number| PhysicalAddress::read_guest_kernel_virt(nt_build_number as *const u32))
+        .map(|nt_build_number| nt_build_number & NT_BUILD_NUMBER_MASK)
+        .filter(|&build_number| build_number != 0)
+        .or_else(read_shared_data_build_number)?;
+
+    cache_build_number(build_number);
+
+    Some(build_number)
+}
+
+/// Returns the build number of the guest, detecting it from `KUSER_SHARED_DATA` if it hasn't been detected yet.
+///
+/// # Returns
+///
+/// The build number of the guest, or `None` if it can't be read.
+pub fn windows_build_number() -> Option<u32> {
+    if let Some(build_number) = cached_build_number() {
+        return Some(build_number);
+    }
+
+    let build_number = read_shared_data_build_number()?;
+    cache_build_number(build_number);
+
+    Some(build_number)
+}
+
+/// Returns the offsets of the kernel structures of the guest build.
+///
+/// # Returns
+///
+/// The offsets, or `None` if the build can't be detected or isn't supported.
+pub fn windows_offsets() -> Option<&'static WindowsOffsets> {
+    find_offsets(windows_build_number()?)
+}
+
+/// Returns the offsets of the kernel structures of a build.
+///
+/// # Arguments
+///
+/// * `build_number` - The build number.
+fn find_offsets(build_number: u32) -> Option<&'static WindowsOffsets> {
+    WINDOWS_OFFSETS
+        .iter()
+        .find(|offsets| (offsets.first_build..=offsets.last_build).contains(&build_number))
+}
+
+/// Returns the cached build number, if it has been detected.
+fn cached_build_number() -> Option<u32> {
+    match WINDOWS_BUILD_NUMBER.load(Ordering::Acquire) {
+        0 => None,
+        build_number => Some(build_number),
+    }
+}
+
+/// Caches the build number, warning once if the build has no offsets.
+///
+/// # Arguments
+///
+/// * `build_number` - The build number of the guest.
+fn cache_build_number(build_number: u32) {
+    if WINDOWS_BUILD_NUMBER.swap(build_number, Ordering::AcqRel) == build_number {
+        return;
+    }
+
+    if find_offsets(build_number).is_some() {
+        debug!("Windows build {} detected", build_number);
+    } else {
+        warn!("Windows build {} detected without kernel structure offsets", build_number);
+    }
+}
+
+/// Reads the build number of the guest from `KUSER_SHARED_DATA`.
+///
+/// # Returns
+///
+/// The build number, or `None` if the page isn't mapped yet or isn't the one of Windows 10 or later.
+fn read_shared_data_build_number() -> Option<u32> {
+    let major_version = PhysicalAddress::read_guest_kernel_virt((KUSER_SHARED_DATA + KUSER_SHARED_DATA_NT_MAJOR_VERSION_OFFSET) as *const u32)?;
+
+    // NtBuildNumber is only in KUSER_SHARED_DATA since Windows 10.
+    if major_version < 10 {
+        return None;
+    }
+
+    PhysicalAddress::read_guest_kernel_virt((KUSER_SHARED_DATA + KUSER_SHARED_DATA_NT_BUILD_NUMBER_OFFSET) as *const u32)
+        .filter(|&build_number| build_number != 0)
+}
@@ -0,0 +1,165 @@
+//! Provides the dump of the memory of the processes of Windows guests, combining the VAD tree of a process (see
+//! `introspection`), to dump only its allocated ranges, and the reads of its address space through its directory
+//! table base, so the memory of a process is dumped without code in the guest.
+//!
+//! A dump covers the VADs intersecting a range of the process, optionally only the private or executable ones, and is
+//! made of chunks, runs of mapped pages of a VAD, the pages that aren't mapped, e.g., reserved or paged out, being
+//! skipped. A dump is bounded by the size of its output and by the size of the address space it examines, and reports
+//! where it stopped, so a large process is dumped by further dumps resuming at that address rather than stalling the
+//! logical processor.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::addresses::PhysicalAddress,
+        windows::{
+            eprocess::ProcessInformation,
+            introspection::{enumerate_vads, MAX_WINDOWS_VADS},
+        },
+    },
+    alloc::vec::Vec,
+    core::mem::size_of,
+    log::*,
+    shared::{ProcessDumpChunk, WindowsVad},
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The maximum size of the address space examined by a dump, the reserved ranges of the VADs, e.g., the bitmap of
+/// Control Flow Guard, being far larger than their mapped pages.
+pub const MAX_PROCESS_DUMP_EXAMINED_SIZE: u64 = 0x1000_0000;
+
+/// The VADs dumped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpFilter {
+    /// Whether only the private memory is dumped, rather than also the views of the sections.
+    pub private_only: bool,
+
+    /// Whether only the memory allocated as executable is dumped.
+    pub executable_only: bool,
+}
+
+impl DumpFilter {
+    /// Returns `true` if a VAD is dumped.
+    ///
+    /// # Arguments
+    ///
+    /// * `vad` - The VAD.
+    fn matches(&self, vad: &WindowsVad) -> bool {
+        (!self.private_only || vad.private_memory != 0) && (!self.executable_only || vad.is_executable())
+    }
+}
+
+/// A run of mapped pages of a VAD.
+#[derive(Debug)]
+pub struct DumpChunk {
+    /// The virtual address of the chunk.
+    pub address: u64,
+
+    /// The content of the chunk.
+    pub bytes: Vec<u8>,
+}
+
+/// The outcome of a dump.
+#[derive(Debug)]
+pub struct ProcessDump {
+    /// The chunks, in address order.
+    pub chunks: Vec<DumpChunk>,
+
+    /// The address where a further dump resumes, or the end of the range if the dump is complete.
+    pub next_address: u64,
+
+    /// The number of bytes of memory dumped.
+    pub dumped_bytes: u64,
+}
+
+/// Dumps the memory of a process of the guest.
+///
+/// The dump stops at the end of the range, once its output would exceed `max_output_size` bytes, each chunk taking a
+/// `ProcessDumpChunk` and its bytes, or after examining `MAX_PROCESS_DUMP_EXAMINED_SIZE` bytes of address space, and a
+/// further dump resumes at `next_address` without dumping the same memory again.
+///
+/// # Arguments
+///
+/// * `process_id` - The ID of the process.
+/// * `start_address` - The address where the dump starts.
+/// * `end_address` - The address where the range ends, exclusive.
+/// * `filter` - The VADs dumped.
+/// * `max_output_size` - The maximum size of the output of the dump.
+///
+/// # Returns
+///
+/// * `Ok(ProcessDump)` - The dump.
+/// * `Err(HypervisorError::ProcessNotFound)` - If no process has this ID.
+/// * `Err(HypervisorError)` - If the VADs of the process can't be enumerated.
+pub fn dump_process_memory(
+    process_id: u64,
+    start_address: u64,
+    end_address: u64,
+    filter: DumpFilter,
+    max_output_size: usize,
+) -> Result<ProcessDump, HypervisorError> {
+    let directory_table_base = ProcessInformation::get_directory_table_base_by_process_id(process_id).ok_or(HypervisorError::ProcessNotFound)?;
+    let (vads, _) = enumerate_vads(process_id, MAX_WINDOWS_VADS)?;
+
+    let mut dump = ProcessDump {
+        chunks: Vec::new(),
+        next_address: end_address,
+        dumped_bytes: 0,
+    };
+
+    let mut address = start_address;
+    let mut output_size = 0;
+    let mut examined_size = 0;
+
+    for vad in vads
+        .iter()
+        .filter(|vad| vad.end_address > start_address && vad.start_address < end_address && filter.matches(vad))
+    {
+        address = address.max(vad.start_address);
+        let region_end = vad.end_address.min(end_address);
+
+        // The chunks don't span VADs, so the chunk being extended, if any, is the one of the previous mapped page.
+        let mut is_chunk_open = false;
+
+        while address < region_end {
+            if examined_size >= MAX_PROCESS_DUMP_EXAMINED_SIZE {
+                dump.next_address = address;
+                return Ok(dump);
+            }
+
+            let page_end = region_end.min((address | (BASE_PAGE_SIZE as u64 - 1)) + 1);
+            let size = (page_end - address) as usize;
+            examined_size += size as u64;
+
+            let Some(bytes) = PhysicalAddress::read_guest_virt_slice_with_explicit_cr3(address as *const u8, size, directory_table_base) else {
+                is_chunk_open = false;
+                address = page_end;
+                continue;
+            };
+
+            let chunk_header_size = if is_chunk_open { 0 } else { size_of::<ProcessDumpChunk>() };
+
+            if output_size + chunk_header_size + size > max_output_size {
+                dump.next_address = address;
+                return Ok(dump);
+            }
+
+            match dump.chunks.last_mut() {
+                Some(chunk) if is_chunk_open => chunk.bytes.extend_from_slice(bytes),
+                _ => dump.chunks.push(DumpChunk {
+                    address,
+                    bytes: bytes.to_vec(),
+                }),
+            }
+
+            is_chunk_open = true;
+            output_size += chunk_header_size + size;
+            dump.dumped_bytes += size as u64;
+            address = page_end;
+        }
+    }
+
+    trace!("Dumped {:#x} bytes of process {:#x} in {} chunks", dump.dumped_bytes, process_id, dump.chunks.len());
+
+    Ok(dump)
+}
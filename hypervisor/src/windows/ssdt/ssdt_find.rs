@@ -6,37 +6,100 @@
 //! behavior at a granular level.
 //!
 
-use {crate::error::HypervisorError, core::cmp::Ordering, log::*};
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            signature_scan::{find_image_signature, Signature},
+        },
+    },
+    core::{
+        cmp::Ordering,
+        sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+    },
+    log::*,
+};
+
+/// The pattern of `KiSystemServiceStart`, followed by the `lea r10, [rel KeServiceDescriptorTable]` and
+/// `lea r11, [rel KeServiceDescriptorTableShadow]` instructions.
+const KI_SYSTEM_SERVICE_START_PATTERN: &str = "8B F8 C1 EF 07 83 E7 20 25 FF 0F 00 00 4C 8D 15 ?? ?? ?? ?? 4C 8D 1D ?? ?? ?? ??";
+
+/// The pattern of `KiSystemServiceRepeat` in `KiSystemCall64`, the same `lea r10` and `lea r11` instructions, used
+/// if the builds don't match `KI_SYSTEM_SERVICE_START_PATTERN`.
+const KI_SYSTEM_SERVICE_REPEAT_PATTERN: &str = "4C 8D 15 ?? ?? ?? ?? 4C 8D 1D ?? ?? ?? ?? F7";
+
+/// The offset of the `lea r11, [rel KeServiceDescriptorTableShadow]` instruction in each pattern.
+const KI_SYSTEM_SERVICE_START_LEA_R11_OFFSET: u64 = 20;
+const KI_SYSTEM_SERVICE_REPEAT_LEA_R11_OFFSET: u64 = 7;
+
+/// The size of a `lea r11, [rel disp32]` instruction, and the offset of its displacement.
+const LEA_R11_SIZE: u64 = 7;
+const LEA_R11_DISPLACEMENT_OFFSET: u64 = 3;
+
+/// The size of a service descriptor table entry, the offset of the Win32k table in `KeServiceDescriptorTableShadow`.
+const SERVICE_DESCRIPTOR_TABLE_SIZE: u64 = 0x20;
+
+/// The virtual address of `KeServiceDescriptorTableShadow`, 0 until it is found, as it doesn't move until the next
+/// boot.
+static KE_SERVICE_DESCRIPTOR_TABLE_SHADOW: AtomicU64 = AtomicU64::new(0);
 
 /// Represents the addresses of the SSDT tables for NT and Win32k system calls.
 #[derive(Debug, Clone, Copy)]
 pub struct SsdtFind {
-    /// The address of the NT table within the SSDT.
-    pub nt_table: *const u64,
+    /// The virtual address of the NT table within the SSDT.
+    pub nt_table: u64,
 
-    /// The address of the Win32k table within the SSDT.
-    pub win32k_table: *const u64,
+    /// The virtual address of the Win32k table within the SSDT.
+    pub win32k_table: u64,
 }
 
 impl SsdtFind {
     /// Locates the SSDT based on a given kernel base and size.
     ///
-    /// This function scans the kernel memory for specific patterns to find the SSDT addresses.
-    /// It leverages known structures within the Windows kernel to pinpoint the exact location
-    /// of the NT and Win32k service descriptor tables.
+    /// This function scans the image of the kernel through the guest page tables for the instructions of
+    /// `KiSystemCall64` loading the service descriptor tables, `KiSystemServiceStart` first and then
+    /// `KiSystemServiceRepeat`, and decodes the address of `KeServiceDescriptorTableShadow` from the RIP-relative
+    /// `lea r11`. The address is found once and cached.
     ///
     /// # Arguments
     ///
-    /// * `kernel_base` - The base address of the kernel in memory.
-    /// * `kernel_size` - The size of the kernel memory space.
+    /// * `kernel_base` - The base virtual address of the kernel.
+    /// * `kernel_size` - The size of the kernel image.
     ///
     /// # Returns
     ///
     /// * `Ok(SsdtFind)` - An `SsdtFind` struct containing the addresses of the NT and Win32k tables.
-    /// * `Err(HypervisorError)` - An error occurred during the scanning process.
-    pub fn find_ssdt(kernel_base: *const u8, kernel_size: usize) -> Result<Self, HypervisorError> {
-        debug!("Kernel base address: {:p}", kernel_base);
-        debug!("Kernel size: {}", kernel_size);
+    /// * `Err(HypervisorError::PatternNotFound)` - Neither pattern was found in the kernel image.
+    pub fn find_ssdt(kernel_base: u64, kernel_size: u64) -> Result<Self, HypervisorError> {
+        let shadow = match KE_SERVICE_DESCRIPTOR_TABLE_SHADOW.load(AtomicOrdering::Acquire) {
+            0 => {
+                let shadow = Self::find_service_descriptor_table_shadow(kernel_base, kernel_size)?;
+                KE_SERVICE_DESCRIPTOR_TABLE_SHADOW.store(shadow, AtomicOrdering::Release);
+                shadow
+            }
+            shadow => shadow,
+        };
+
+        // KeServiceDescriptorTableShadow holds the NT table, as KeServiceDescriptorTable, followed by the Win32k table.
+        let nt_table = shadow;
+        let win32k_table = shadow + SERVICE_DESCRIPTOR_TABLE_SIZE;
+
+        trace!("NtTable address: {:#x}", nt_table);
+        trace!("Win32kTable address: {:#x}", win32k_table);
+
+        Ok(Self { nt_table, win32k_table })
+    }
+
+    /// Scans the kernel image for `KeServiceDescriptorTableShadow`.
+    ///
+    /// # Arguments
+    ///
+    /// * `kernel_base` - The base virtual address of the kernel.
+    /// * `kernel_size` - The size of the kernel image.
+    fn find_service_descriptor_table_shadow(kernel_base: u64, kernel_size: u64) -> Result<u64, HypervisorError> {
+        debug!("Kernel base address: {:#x}", kernel_base);
+        debug!("Kernel size: {:#x}", kernel_size);
 
         /*
            14042ba50  uint64_t KiSystemServiceStart(int64_t arg1, int64_t arg2, uint64_t arg3, int64_t arg4, int32_t arg5 @ rax, uint64_t arg6 @ rbx, int128_t* arg7 @ rbp, uint64_t arg8 @ ssp)
@@ -51,48 +114,36 @@ impl SsdtFind {
            14042ba6b  4c8d1d8e368f00     lea     r11, [rel KeServiceDescriptorTableShadow]
         */
 
-        // Pattern to identify the KiServiceSystemStart in the kernel memory.
-        let ki_service_system_start_pattern = &[0x8B, 0xF8, 0xC1, 0xEF, 0x07, 0x83, 0xE7, 0x20, 0x25, 0xFF, 0x0F, 0x00, 0x00];
-        let signature_size = 13;
-
-        // Create a slice from the Windows kernel (ntoskrnl.exe) base address for the specified size.
-        let ntoskrnl_data = unsafe { core::slice::from_raw_parts(kernel_base, kernel_size) };
-
-        // Find the starting offset of the KiServiceSystemStart pattern within the kernel data.
-        let offset = Self::find_needle(ntoskrnl_data, ki_service_system_start_pattern).ok_or(HypervisorError::PatternNotFound)?;
-
-        // Calculate the starting address of KiServiceSystemStart based on the offset.
-        // That is: `14042ba57  8bf8               mov     edi, eax` in this case.
-        let ki_service_system_start = unsafe { kernel_base.add(offset) };
-        trace!("KiServiceSystemStart address: {:p}", ki_service_system_start);
+        let patterns = [
+            (KI_SYSTEM_SERVICE_START_PATTERN, KI_SYSTEM_SERVICE_START_LEA_R11_OFFSET),
+            (KI_SYSTEM_SERVICE_REPEAT_PATTERN, KI_SYSTEM_SERVICE_REPEAT_LEA_R11_OFFSET),
+        ];
 
-        // Address of the 'lea r10, [rel KeServiceDescriptorTable]' instruction
-        let lea_r10_address = unsafe { ki_service_system_start.add(signature_size) };
+        for (pattern, lea_r11_offset) in patterns {
+            let signature = Signature::parse(pattern)?;
 
-        // Address of the 'lea r11, [rel KeServiceDescriptorTableShadow]' instruction
-        let lea_r11_address = unsafe { lea_r10_address.add(7) }; // 7 bytes after lea r10
+            let Some(match_va) = find_image_signature(kernel_base, kernel_size, &signature) else {
+                trace!("SSDT pattern not found: {}", pattern);
+                continue;
+            };
 
-        // Reading the 4-byte relative offset for KeServiceDescriptorTableShadow
-        let relative_offset = unsafe { *(lea_r11_address.add(3) as *const i32) }; // 3 bytes after the opcode
+            // Address of the 'lea r11, [rel KeServiceDescriptorTableShadow]' instruction
+            let lea_r11_address = match_va + lea_r11_offset;
 
-        trace!("Relative offset: {:x}", relative_offset);
+            // Reading the 4-byte relative offset for KeServiceDescriptorTableShadow
+            let relative_offset = PhysicalAddress::read_guest_kernel_virt((lea_r11_address + LEA_R11_DISPLACEMENT_OFFSET) as *const i32)
+                .ok_or(HypervisorError::PatternNotFound)?;
 
-        // Compute the absolute address of KeServiceDescriptorTableShadow
-        let ke_service_descriptor_table_shadow = unsafe { lea_r11_address.add(7).offset(relative_offset as isize) };
+            trace!("Relative offset: {:x}", relative_offset);
 
-        // Extracting nt!KiServiceTable and win32k!W32pServiceTable addresses
-        let shadow = ke_service_descriptor_table_shadow;
+            // Compute the absolute address of KeServiceDescriptorTableShadow
+            let shadow = (lea_r11_address + LEA_R11_SIZE).wrapping_add_signed(relative_offset as i64);
+            debug!("KeServiceDescriptorTableShadow address: {:#x}", shadow);
 
-        // NtTable Address of Nt Syscall Table
-        let nt_table = shadow as *const u64;
-
-        // Win32kTable Address of Win32k Syscall Table
-        let win32k_table = unsafe { shadow.offset(0x20) as *const u64 };
-
-        trace!("NtTable address: {:p}", nt_table);
-        trace!("Win32kTable address: {:p}", win32k_table);
+            return Ok(shadow);
+        }
 
-        Ok(Self { nt_table, win32k_table })
+        Err(HypervisorError::PatternNotFound)
     }
 
     /// Scans a given data slice for a specific pattern.
@@ -6,24 +6,80 @@
 
 use {
     crate::{error::HypervisorError, intel::addresses::PhysicalAddress, windows::ssdt::ssdt_find::SsdtFind},
+    core::mem::size_of,
     log::*,
 };
 
+/// The first system call number of the Win32k table.
+pub const WIN32K_SYSCALL_BASE: i32 = 0x1000;
+
+/// The maximum number of services of a table, bounding the reads of a corrupted table.
+const MAX_NUMBER_OF_SERVICES: u64 = 0x1000;
+
 /// Represents the layout of the System Service Dispatch Table (SSDT).
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 struct SSDTStruct {
-    /// Pointer to the service table containing addresses of system call functions.
-    p_service_table: *const i32,
+    /// The virtual address of the service table containing the offsets of the system call functions.
+    p_service_table: u64,
 
-    /// Pointer to the counter table, which might be used for statistics or limits.
-    p_counter_table: *const u8,
+    /// The virtual address of the counter table, which might be used for statistics or limits.
+    p_counter_table: u64,
 
     /// The number of services or system calls available in this SSDT.
     number_of_services: u64,
 
-    /// Pointer to the argument table, detailing the arguments each system call expects.
-    p_argument_table: *const u8,
+    /// The virtual address of the argument table, detailing the arguments each system call expects.
+    p_argument_table: u64,
+}
+
+impl SSDTStruct {
+    /// Reads the service descriptor table of the NT or the Win32k system calls.
+    ///
+    /// # Arguments
+    ///
+    /// * `get_from_win32k` - Whether to read the Win32k table instead of the NT table.
+    /// * `kernel_base` - The base virtual address of the kernel.
+    /// * `kernel_size` - The size of the kernel image.
+    fn read(get_from_win32k: bool, kernel_base: u64, kernel_size: u64) -> Result<Self, HypervisorError> {
+        let ssdt = SsdtFind::find_ssdt(kernel_base, kernel_size)?;
+
+        trace!("NT SSDT address: {:x?}", ssdt);
+
+        // Determine the correct SSDT structure based on whether we are hooking an NT or Win32k function.
+        let table_va = match get_from_win32k {
+            true => ssdt.win32k_table,
+            false => ssdt.nt_table,
+        };
+
+        let table = PhysicalAddress::read_guest_kernel_virt(table_va as *const Self).ok_or(HypervisorError::SsdtNotFound)?;
+
+        trace!("SSDT structure: {:x?}", table);
+
+        if table.p_service_table == 0 || table.number_of_services > MAX_NUMBER_OF_SERVICES {
+            return Err(HypervisorError::SsdtNotFound);
+        }
+
+        Ok(table)
+    }
+
+    /// Returns the virtual address of the function of an index of the service table.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index in the service table.
+    fn function_va(&self, index: u64) -> Result<u64, HypervisorError> {
+        if index >= self.number_of_services {
+            return Err(HypervisorError::InvalidSyscallNumber);
+        }
+
+        // Each entry holds the offset of the function from the table in its upper 28 bits, and the number of
+        // arguments passed on the stack in its lower 4 bits.
+        let entry_va = self.p_service_table + index * size_of::<i32>() as u64;
+        let entry = PhysicalAddress::read_guest_kernel_virt(entry_va as *const i32).ok_or(HypervisorError::SsdtNotFound)?;
+
+        Ok(self.p_service_table.wrapping_add_signed((entry >> 4) as i64))
+    }
 }
 
 /// Describes a hook into the SSDT, allowing redirection of system calls.
@@ -42,54 +98,33 @@ impl SsdtHook {
     ///
     /// * `api_number` - The API number of the function to hook.
     /// * `get_from_win32k` - Whether to get the function from the Win32k table instead of the NT table.
-    /// * `kernel_base` - The base address of the kernel in memory.
-    /// * `kernel_size` - The size of the kernel memory space.
+    /// * `kernel_base` - The base virtual address of the kernel.
+    /// * `kernel_size` - The size of the kernel image.
     ///
     /// # Returns
     ///
     /// * `Ok(SsdtHook)` - A hook structure containing the address of the original function and its API number.
+    /// * `Err(HypervisorError::InvalidSyscallNumber)` - The API number is beyond the services of the table.
     /// * `Err(HypervisorError)` - An error occurred while finding the SSDT or the function within it.
     pub fn find_ssdt_function_address(
         mut api_number: i32,
         get_from_win32k: bool,
-        kernel_base: *const u8,
-        kernel_size: usize,
+        kernel_base: u64,
+        kernel_size: u64,
     ) -> Result<Self, HypervisorError> {
         trace!("Finding SSDT function address");
 
-        let ssdt = SsdtFind::find_ssdt(kernel_base, kernel_size)?;
-
-        trace!("NT SSDT address: {:?}", ssdt);
+        let ssdt = SSDTStruct::read(get_from_win32k, kernel_base, kernel_size)?;
 
-        // Determine the correct SSDT structure based on whether we are hooking an NT or Win32k function.
-        let ssdt = if !get_from_win32k {
-            unsafe { &*(ssdt.nt_table as *const SSDTStruct) }
-        } else {
+        if get_from_win32k {
             // Adjust the API number for Win32k syscalls, which start from 0x1000.
-            api_number = api_number - 0x1000;
-            unsafe { &*(ssdt.win32k_table as *const SSDTStruct) }
-        };
-
-        trace!("SSDT structure: {:?}", ssdt);
-
-        let ssdt_base_va = ssdt.p_service_table as *mut u8;
-
-        if ssdt_base_va.is_null() {
-            return Err(HypervisorError::SsdtNotFound);
+            api_number -= WIN32K_SYSCALL_BASE;
         }
 
-        trace!("SSDT base address: {:p}", ssdt_base_va);
-
-        // Get a pointer to the target offset within the SSDT.
-        // let offset = unsafe { ssdt.p_service_table.add(api_number as usize).read() as usize >> 4 }; // We can't do this because it's a guest VA.
-        //
-        let offset_ptr_va = unsafe { ssdt.p_service_table.add(api_number as usize) };
-        let offset_ptr_pa = PhysicalAddress::pa_from_va_with_current_cr3(offset_ptr_va as u64)? as *const i32;
-        let offset = unsafe { offset_ptr_pa.read() as usize >> 4 };
-        trace!("SSDT function offset: {:#x}", offset);
+        let index = u64::try_from(api_number).map_err(|_| HypervisorError::InvalidSyscallNumber)?;
 
         // Compute the function's address by adding its offset to the base address.
-        let guest_function_va = unsafe { ssdt_base_va.add(offset) as *const u8 };
+        let guest_function_va = ssdt.function_va(index)? as *const u8;
         trace!("SSDT function address: {:p}", guest_function_va);
 
         Ok(Self {
@@ -97,4 +132,34 @@ impl SsdtHook {
             api_number,
         })
     }
+
+    /// Finds the system call number of a function of the SSDT, e.g., an `Nt*` export, by looking it up in the service
+    /// table.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_function_va` - The virtual address of the function.
+    /// * `get_from_win32k` - Whether to look the function up in the Win32k table instead of the NT table.
+    /// * `kernel_base` - The base virtual address of the kernel.
+    /// * `kernel_size` - The size of the kernel image.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(i32)` - The system call number, from 0x1000 for the Win32k table.
+    /// * `Err(HypervisorError::InvalidSyscallNumber)` - The function isn't in the table.
+    /// * `Err(HypervisorError)` - An error occurred while finding the SSDT.
+    pub fn find_syscall_number(guest_function_va: u64, get_from_win32k: bool, kernel_base: u64, kernel_size: u64) -> Result<i32, HypervisorError> {
+        let ssdt = SSDTStruct::read(get_from_win32k, kernel_base, kernel_size)?;
+
+        let index = (0..ssdt.number_of_services)
+            .find(|&index| ssdt.function_va(index).is_ok_and(|function_va| function_va == guest_function_va))
+            .ok_or(HypervisorError::InvalidSyscallNumber)? as i32;
+
+        trace!("SSDT function {:#x} is system call {:#x}", guest_function_va, index);
+
+        Ok(match get_from_win32k {
+            true => index + WIN32K_SYSCALL_BASE,
+            false => index,
+        })
+    }
 }
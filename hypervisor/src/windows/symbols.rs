@@ -0,0 +1,264 @@
+//! Provides the symbolization of guest addresses with the exported functions of the loaded modules, so the addresses
+//! of the event records (e.g., the RIPs of the exception telemetry or the return addresses of the syscall trace) read
+//! as `ntoskrnl!NtCreateFile+0x23` instead of raw addresses.
+//!
+//! Each module has a map of its exports sorted by RVA, built from its export directory: an address of the image
+//! resolves to the export with the highest RVA not above it, or to the module itself before the first export. Only
+//! the exported functions are known, so an address in an internal function resolves to the preceding export with a
+//! larger offset. The forwarded exports aren't code of the module and are skipped.
+//!
+//! The exports of ntoskrnl.exe are loaded when its base address is captured. The other modules, e.g., hal.dll, a
+//! driver or a DLL of a process, are loaded by the client with the `LoadModuleSymbols` command, the user-mode modules
+//! being specific to a process. The client decoder resolves the addresses of the records with the `ResolveSymbols`
+//! command, and the hypervisor annotates the addresses of its own log messages with `SymbolizedAddress`.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::support::vmread,
+        windows::{eprocess::ProcessInformation, kernel::GuestImage},
+    },
+    alloc::{collections::BTreeMap, format, string::String, vec::Vec},
+    core::fmt,
+    lazy_static::lazy_static,
+    log::*,
+    spin::Mutex,
+    x86::vmx::vmcs,
+};
+
+/// The maximum number of modules with symbols, kernel and user-mode modules together.
+pub const MAX_SYMBOL_MODULES: usize = 0x80;
+
+/// The first address of the kernel address space, whose modules are shared by all the processes.
+const KERNEL_ADDRESS_SPACE_START: u64 = 0xFFFF_8000_0000_0000;
+
+/// The ID of the System process, whose directory table base maps the kernel modules.
+const SYSTEM_PROCESS_ID: u64 = 4;
+
+lazy_static! {
+    /// A globally shared instance of `SymbolTable`, protected by a mutex.
+    pub static ref SHARED_SYMBOL_TABLE: Mutex<SymbolTable> = Mutex::new(SymbolTable::new());
+}
+
+/// An exported function of a module.
+#[derive(Debug, Clone)]
+pub struct ExportSymbol {
+    /// The RVA of the function.
+    pub rva: u32,
+
+    /// The name of the export.
+    pub name: String,
+}
+
+/// The exports of a loaded module.
+#[derive(Debug, Clone)]
+pub struct ModuleSymbols {
+    /// The name of the module from its export directory, without its extension, e.g., "ntoskrnl".
+    pub name: String,
+
+    /// The base virtual address of the image.
+    pub base_va: u64,
+
+    /// The size of the image in memory.
+    pub size_of_image: u32,
+
+    /// The exported functions, sorted by RVA.
+    pub exports: Vec<ExportSymbol>,
+}
+
+impl ModuleSymbols {
+    /// Returns the export containing an RVA of the image, the export with the highest RVA not above it.
+    ///
+    /// # Arguments
+    ///
+    /// * `rva` - The RVA in the image.
+    pub fn export_at(&self, rva: u32) -> Option<&ExportSymbol> {
+        let index = self.exports.partition_point(|export| export.rva <= rva);
+        index.checked_sub(1).map(|index| &self.exports[index])
+    }
+}
+
+/// The modules with symbols, by process ID (0 for the kernel modules) and base address.
+#[derive(Debug)]
+pub struct SymbolTable {
+    /// The modules, by process ID and base virtual address.
+    modules: BTreeMap<(u64, u64), ModuleSymbols>,
+}
+
+impl SymbolTable {
+    /// Creates a new empty symbol table.
+    fn new() -> Self {
+        Self { modules: BTreeMap::new() }
+    }
+
+    /// Returns the number of modules with symbols.
+    pub fn module_count(&self) -> usize {
+        self.modules.len()
+    }
+
+    /// Returns `true` if the symbols of a module are loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The ID of the process of a user-mode module, or 0 for a kernel module.
+    /// * `base_va` - The base virtual address of the module.
+    pub fn is_loaded(&self, process_id: u64, base_va: u64) -> bool {
+        self.modules.contains_key(&(process_id, base_va))
+    }
+
+    /// Loads the exports of a module mapped in the guest, replacing them if already loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The ID of the process of a user-mode module, or 0 for a kernel module.
+    /// * `base_va` - The base virtual address of the module.
+    ///
+    /// # Returns
+    ///
+    /// The number of exported functions loaded, `Err(HypervisorError::ProcessNotFound)` if the process doesn't exist,
+    /// `Err(HypervisorError::TooManySymbolModules)` if the table is full, or `Err(HypervisorError::InvalidModuleImage)`
+    /// if the headers or the export directory can't be read.
+    pub fn load(&mut self, process_id: u64, base_va: u64) -> Result<usize, HypervisorError> {
+        let directory_table_base = match process_id {
+            0 => kernel_directory_table_base(),
+            process_id => ProcessInformation::get_directory_table_base_by_process_id(process_id).ok_or(HypervisorError::ProcessNotFound)?,
+        };
+
+        if self.modules.len() >= MAX_SYMBOL_MODULES && !self.is_loaded(process_id, base_va) {
+            return Err(HypervisorError::TooManySymbolModules);
+        }
+
+        let module = read_module_symbols(base_va, directory_table_base)?;
+        let export_count = module.exports.len();
+
+        debug!("Loaded {} exports of {} at {:#x} for process {}", export_count, module.name, base_va, process_id);
+
+        self.modules.insert((process_id, base_va), module);
+
+        Ok(export_count)
+    }
+
+    /// Resolves a guest address to the module containing it and the export preceding it.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The ID of the process the address was seen in, for the addresses of user-mode modules.
+    /// * `address` - The guest virtual address.
+    ///
+    /// # Returns
+    ///
+    /// The module and the export, if any, containing the address, or `None` if no loaded module contains it.
+    pub fn resolve(&self, process_id: u64, address: u64) -> Option<(&ModuleSymbols, Option<&ExportSymbol>)> {
+        let process_id = match address >= KERNEL_ADDRESS_SPACE_START {
+            true => 0,
+            false => process_id,
+        };
+
+        let (_, module) = self.modules.range((process_id, 0)..=(process_id, address)).next_back()?;
+        let rva = address - module.base_va;
+
+        if rva >= module.size_of_image as u64 {
+            return None;
+        }
+
+        Some((module, module.export_at(rva as u32)))
+    }
+}
+
+/// A guest address formatted as `module!export+0x23`, `module+0x1234` or the raw address, depending on the symbols
+/// resolving it.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolizedAddress {
+    /// The ID of the process the address was seen in.
+    pub process_id: u64,
+
+    /// The guest virtual address.
+    pub address: u64,
+}
+
+impl SymbolizedAddress {
+    /// Creates a guest address to format with its symbol.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The ID of the process the address was seen in.
+    /// * `address` - The guest virtual address.
+    pub fn new(process_id: u64, address: u64) -> Self {
+        Self { process_id, address }
+    }
+}
+
+impl fmt::Display for SymbolizedAddress {
+    /// Formats the address with the symbol table locked, so it must not be formatted while the table is locked.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol_table = SHARED_SYMBOL_TABLE.lock();
+
+        match symbol_table.resolve(self.process_id, self.address) {
+            Some((module, Some(export))) => write!(f, "{}!{}+{:#x}", module.name, export.name, self.address - module.base_va - export.rva as u64),
+            Some((module, None)) => write!(f, "{}+{:#x}", module.name, self.address - module.base_va),
+            None => write!(f, "{:#x}", self.address),
+        }
+    }
+}
+
+/// Loads the exports of the guest kernel, unless they have already been loaded.
+///
+/// This is called each time the base address of ntoskrnl.exe is captured, i.e., on each logical processor.
+///
+/// # Arguments
+///
+/// * `image_base_va` - The base virtual address of ntoskrnl.exe.
+pub fn record_kernel_symbols(image_base_va: u64) {
+    let mut symbol_table = SHARED_SYMBOL_TABLE.lock();
+
+    if symbol_table.is_loaded(0, image_base_va) {
+        return;
+    }
+
+    if let Err(error) = symbol_table.load(0, image_base_va) {
+        error!("Failed to load the kernel symbols: {:?}", error);
+    }
+}
+
+/// Returns the directory table base mapping the kernel modules: the one of the System process, or the current guest
+/// CR3 before the process list is available.
+fn kernel_directory_table_base() -> u64 {
+    ProcessInformation::get_directory_table_base_by_process_id(SYSTEM_PROCESS_ID).unwrap_or_else(|| vmread(vmcs::guest::CR3))
+}
+
+/// Reads the exports of a module mapped in the guest.
+///
+/// # Arguments
+///
+/// * `base_va` - The base virtual address of the module.
+/// * `directory_table_base` - The directory table base mapping the module.
+///
+/// # Returns
+///
+/// The exports of the module, or `Err(HypervisorError::InvalidModuleImage)` if they can't be read.
+fn read_module_symbols(base_va: u64, directory_table_base: u64) -> Result<ModuleSymbols, HypervisorError> {
+    let image = GuestImage::read(base_va, directory_table_base)?;
+    let size_of_image = image.size_of_image();
+
+    // A module without exports still resolves its addresses to module offsets.
+    let name = image.name().unwrap_or_else(|| format!("{:x}", base_va));
+
+    let mut exports: Vec<ExportSymbol> = image
+        .named_exports()
+        .filter(|&(_, function_rva)| function_rva != 0 && function_rva < size_of_image && !image.is_forwarder(function_rva))
+        .map(|(name, rva)| ExportSymbol { rva, name })
+        .collect();
+
+    // The aliases of a function keep the first name in the order of the names, which is sorted alphabetically.
+    exports.sort_by_key(|export| export.rva);
+    exports.dedup_by_key(|export| export.rva);
+
+    trace!("Module {} at {:#x}: {} exports", name, base_va, exports.len());
+
+    Ok(ModuleSymbols {
+        name,
+        base_va,
+        size_of_image,
+        exports,
+    })
+}
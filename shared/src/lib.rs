@@ -23,6 +23,202 @@ pub enum Command {
     /// Command to write the memory of a process.
     WriteProcessMemory = 4,
 
+    /// Command to map the shared communication page over a guest page and retrieve its guest physical address.
+    MapSharedPage = 5,
+
+    /// Command to restore the original guest page that the shared communication page was mapped over.
+    UnmapSharedPage = 6,
+
+    /// Command to shift the RTC time observed by the guest by an offset in seconds.
+    SetRtcOffset = 7,
+
+    /// Command to append a buffer to the exfiltration file on the EFI System Partition.
+    AppendExfilData = 8,
+
+    /// Command to start copying the memory of a process to a buffer in the background, reporting its progress through the shared page.
+    StartAsyncRead = 9,
+
+    /// Command to cancel the background copy started by `StartAsyncRead`.
+    CancelAsyncRead = 10,
+
+    /// Command to start sampling the guest RIP and stack on all logical processors at a fixed frequency.
+    StartProfiling = 11,
+
+    /// Command to stop the sampling started by `StartProfiling`.
+    StopProfiling = 12,
+
+    /// Command to move the samples recorded so far to a buffer.
+    ReadProfile = 13,
+
+    /// Command to configure the watchdog reporting logical processors whose guest makes no progress.
+    ConfigureWatchdog = 14,
+
+    /// Command to set how the guest's attempts to reset or shut down the system are handled.
+    SetResetPolicy = 15,
+
+    /// Command to enable or disable the deterministic mode, in which the time and entropy observed by the guest only advance by policy.
+    ConfigureDeterministicMode = 16,
+
+    /// Command to enable or disable hooked functions in an alternate hook view, or to assign views to processes and logical processors.
+    ConfigureHookView = 17,
+
+    /// Command to start recording the system calls passing a filter, with their arguments and return values.
+    StartSyscallTrace = 18,
+
+    /// Command to stop the recording started by `StartSyscallTrace`.
+    StopSyscallTrace = 19,
+
+    /// Command to move the system call records recorded so far to a buffer.
+    ReadSyscallTrace = 20,
+
+    /// Command to configure the interception and recording of the page faults, general protection faults and invalid opcodes of the guest.
+    ConfigureExceptionTelemetry = 21,
+
+    /// Command to move the exception events recorded so far to a buffer.
+    ReadExceptionTelemetry = 22,
+
+    /// Command to swap the MSR bitmap profile defining the interception of the MSR accesses that aren't hooked, or to modify a range of it.
+    ConfigureMsrBitmap = 23,
+
+    /// Command to write-protect a range of a process and dump its pages written and then executed, as done by unpackers.
+    StartUnpacker = 24,
+
+    /// Command to stop the watch started by `StartUnpacker`.
+    StopUnpacker = 25,
+
+    /// Command to move the pages dumped so far to a buffer.
+    ReadUnpackerDumps = 26,
+
+    /// Command to register, compose or remove the override of the results of a CPUID leaf.
+    ConfigureCpuidOverride = 27,
+
+    /// Command to start maintaining the map of the virtual memory allocations of a process and alerting its RWX memory.
+    StartAllocationMonitor = 28,
+
+    /// Command to stop the monitoring started by `StartAllocationMonitor`.
+    StopAllocationMonitor = 29,
+
+    /// Command to copy the allocation map of a monitored process to a buffer.
+    ReadAllocationMap = 30,
+
+    /// Command to move the allocation alerts recorded so far to a buffer.
+    ReadAllocationAlerts = 31,
+
+    /// Command to hide the hypervisor leaves and the hypervisor-present bit, or to expose them to cooperative guests.
+    ConfigureHypervisorPresence = 32,
+
+    /// Command to enable or disable the automatic copies of the code pages seen to become executable after being written.
+    ConfigureCodeSnapshots = 33,
+
+    /// Command to read the copies of the transient code pages.
+    ReadCodeSnapshots = 34,
+
+    /// Command to configure the compensation of the guest TSC for the time spent in the hypervisor.
+    ConfigureTscCompensation = 35,
+
+    /// Command to load the exports of a module mapped in the guest, to symbolize the addresses of the events.
+    LoadModuleSymbols = 36,
+
+    /// Command to resolve guest addresses to the exports of the loaded modules.
+    ResolveSymbols = 37,
+
+    /// Command to install the hooks of the boot-time hook manifest from a guest agent.
+    TriggerBootHooks = 38,
+
+    /// Command to search a range of a process for a string or a regex-lite pattern, returning the matches a page at a time.
+    SearchMemory = 39,
+
+    /// Command to enable or disable the tracking of the process owning the current address space of each logical processor.
+    ConfigureProcessTracking = 40,
+
+    /// Command to set the XSAVE state components that the guest may not enable in XCR0.
+    ConfigureXsavePolicy = 41,
+
+    /// Command to read the summary of the VM exits of the benchmark run.
+    ReadBenchmark = 42,
+
+    /// Command to set or remove a hardware breakpoint of the hypervisor, hidden from the guest.
+    SetHardwareBreakpoint = 43,
+
+    /// Command to set the backpressure policy of an event ring, deciding what happens to new events while it's full.
+    ConfigureEventRing = 44,
+
+    /// Command to read the statistics of an event ring, including the number of events lost to backpressure.
+    ReadEventRingStats = 45,
+
+    /// Command to save the runtime configuration to the ESP, re-applied on the next boot, or to discard it.
+    SaveConfiguration = 46,
+
+    /// Command to add or clear the rules deciding how the MSR accesses are handled depending on the guest context.
+    ConfigureMsrContextRule = 47,
+
+    /// Command to evaluate the hypervisor against the corpus of public hypervisor-detection techniques, from the
+    /// observations of the guest made by the client.
+    RunDetectionCorpus = 48,
+
+    /// Command to register or remove the offsets of a Linux kernel release, used to resolve the syscall table and the
+    /// tasks of Linux guests.
+    ConfigureLinuxKernel = 49,
+
+    /// Command to read the tasks of a Linux guest.
+    ReadLinuxTasks = 50,
+
+    /// Command to trace the instructions executed in a range of a process, single-stepping them while RIP is in the
+    /// range.
+    StartExecutionTrace = 51,
+
+    /// Command to stop the trace started by `StartExecutionTrace`.
+    StopExecutionTrace = 52,
+
+    /// Command to read the instructions traced so far.
+    ReadExecutionTrace = 53,
+
+    /// Command to scan a range of guest physical memory, or of a process, for a byte pattern with wildcards.
+    ScanSignature = 54,
+
+    /// Command to read the processes of a Windows guest.
+    EnumerateProcesses = 55,
+
+    /// Command to read the threads of a process of a Windows guest.
+    EnumerateThreads = 56,
+
+    /// Command to read the VAD tree of a process of a Windows guest, the map of its virtual address space.
+    EnumerateVads = 57,
+
+    /// Command to dump the memory of a process of a Windows guest, a bounded part per command, into the buffer of the
+    /// client or over the serial port.
+    DumpProcessMemory = 58,
+
+    /// Command to read the notification callbacks registered in the kernel of a Windows guest, e.g., by
+    /// `PsSetCreateProcessNotifyRoutine`, `CmRegisterCallback` or `ObRegisterCallbacks`.
+    EnumerateKernelCallbacks = 59,
+
+    /// Command to set the ntoskrnl.exe functions hooked to push the process creations and the image loads of a Windows
+    /// guest to the event stream.
+    ConfigureOsEvents = 60,
+
+    /// Command to start, reconfigure or stop the periodic verification of the code of ntoskrnl.exe and selected
+    /// drivers of a Windows guest against their hashes.
+    ConfigureCodeIntegrity = 61,
+
+    /// Command to protect a range of a guest process from the reads and/or writes of the other processes.
+    ProtectMemory = 62,
+
+    /// Command to remove a protected range of a guest process, or all of them.
+    UnprotectMemory = 63,
+
+    /// Command to duplicate, collapse or switch the EPT views, or to set and reset their divergent pages.
+    ConfigureEptView = 64,
+
+    /// Command to unload the hypervisor: each logical processor leaves VMX operation and resumes the guest natively.
+    Devirtualize = 65,
+
+    /// Command to broadcast an operation to all the logical processors, e.g., to flush the EPT or pause the guest.
+    Broadcast = 66,
+
+    /// Command to read the VM exits of each logical processor by basic exit reason, with their handling latency.
+    ReadExitStatistics = 67,
+
     /// Invalid command.
     Invalid,
 }
@@ -36,6 +232,69 @@ impl Command {
             2 => Command::OpenProcess,
             3 => Command::ReadProcessMemory,
             4 => Command::WriteProcessMemory,
+            5 => Command::MapSharedPage,
+            6 => Command::UnmapSharedPage,
+            7 => Command::SetRtcOffset,
+            8 => Command::AppendExfilData,
+            9 => Command::StartAsyncRead,
+            10 => Command::CancelAsyncRead,
+            11 => Command::StartProfiling,
+            12 => Command::StopProfiling,
+            13 => Command::ReadProfile,
+            14 => Command::ConfigureWatchdog,
+            15 => Command::SetResetPolicy,
+            16 => Command::ConfigureDeterministicMode,
+            17 => Command::ConfigureHookView,
+            18 => Command::StartSyscallTrace,
+            19 => Command::StopSyscallTrace,
+            20 => Command::ReadSyscallTrace,
+            21 => Command::ConfigureExceptionTelemetry,
+            22 => Command::ReadExceptionTelemetry,
+            23 => Command::ConfigureMsrBitmap,
+            24 => Command::StartUnpacker,
+            25 => Command::StopUnpacker,
+            26 => Command::ReadUnpackerDumps,
+            27 => Command::ConfigureCpuidOverride,
+            28 => Command::StartAllocationMonitor,
+            29 => Command::StopAllocationMonitor,
+            30 => Command::ReadAllocationMap,
+            31 => Command::ReadAllocationAlerts,
+            32 => Command::ConfigureHypervisorPresence,
+            33 => Command::ConfigureCodeSnapshots,
+            34 => Command::ReadCodeSnapshots,
+            35 => Command::ConfigureTscCompensation,
+            36 => Command::LoadModuleSymbols,
+            37 => Command::ResolveSymbols,
+            38 => Command::TriggerBootHooks,
+            39 => Command::SearchMemory,
+            40 => Command::ConfigureProcessTracking,
+            41 => Command::ConfigureXsavePolicy,
+            42 => Command::ReadBenchmark,
+            43 => Command::SetHardwareBreakpoint,
+            44 => Command::ConfigureEventRing,
+            45 => Command::ReadEventRingStats,
+            46 => Command::SaveConfiguration,
+            47 => Command::ConfigureMsrContextRule,
+            48 => Command::RunDetectionCorpus,
+            49 => Command::ConfigureLinuxKernel,
+            50 => Command::ReadLinuxTasks,
+            51 => Command::StartExecutionTrace,
+            52 => Command::StopExecutionTrace,
+            53 => Command::ReadExecutionTrace,
+            54 => Command::ScanSignature,
+            55 => Command::EnumerateProcesses,
+            56 => Command::EnumerateThreads,
+            57 => Command::EnumerateVads,
+            58 => Command::DumpProcessMemory,
+            59 => Command::EnumerateKernelCallbacks,
+            60 => Command::ConfigureOsEvents,
+            61 => Command::ConfigureCodeIntegrity,
+            62 => Command::ProtectMemory,
+            63 => Command::UnprotectMemory,
+            64 => Command::ConfigureEptView,
+            65 => Command::Devirtualize,
+            66 => Command::Broadcast,
+            67 => Command::ReadExitStatistics,
             _ => Command::Invalid,
         }
     }
@@ -67,11 +326,212 @@ impl CommandStatus {
     }
 }
 
+/// The magic in RAX identifying a VMCALL or a CPUID as a hypercall of a guest agent, "ILLUSION" in ASCII. A CPUID
+/// hypercall returns the magic in RBX, which a CPUID only sets to 32-bit values, and a CPUID hypercall that isn't
+/// authenticated returns the results of leaf 0x53494F4E, the low 32 bits of the magic, as on bare metal, instead of
+/// raising #UD.
+pub const HYPERCALL_MAGIC: u64 = 0x494C_4C55_5349_4F4E;
+
+/// The secret a guest agent passes in RDX to `Hypercall::OpenSession`, shared with the hypervisor at build time.
+pub const HYPERCALL_SECRET: u64 = 0x8F3A_61D2_C04B_97E5;
+
+/// The version of the hypercall ABI, in bits 63:32 of RCX, the hypercall number being in bits 31:0.
+///
+/// The arguments are passed in RDX, R8 and R9, and the session key returned by `Hypercall::OpenSession` in R10. The
+/// hypervisor returns a `HypercallStatus` in RAX and the results in RDX, R8 and R9. A hypercall with another version
+/// fails with `HypercallStatus::VersionMismatch` and the version of the hypervisor in RDX.
+///
+/// A hypercall that isn't authenticated, with a wrong secret or session key, or from outside the restrictions of the
+/// session, raises #UD as VMCALL does without a hypervisor.
+pub const HYPERCALL_ABI_VERSION: u32 = 2;
+
+/// Enumeration of the hypercalls a guest agent can make with VMCALL, each taking its arguments in registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Hypercall {
+    /// Returns the ABI version in RDX, the `HypercallFeature` bits of the enabled features in R8 and the number of
+    /// hypercalls in R9.
+    QueryStatus = 0,
+
+    /// Installs a kernel EPT hook: the function hash in RDX, the syscall number in R8 and the `HypercallDetour` in R9.
+    InstallHook = 1,
+
+    /// Removes a kernel hook: the function hash in RDX and the syscall number in R8.
+    RemoveHook = 2,
+
+    /// Reads the `u64` at the virtual address in R8 of the address space whose CR3 is in RDX, or of the caller if 0,
+    /// returning it in RDX.
+    ReadGuestMemory = 3,
+
+    /// Writes R9 as a `u64` to the virtual address in R8 of the address space whose CR3 is in RDX, or of the caller
+    /// if 0.
+    WriteGuestMemory = 4,
+
+    /// Enables the `HypercallFeature` in RDX if R8 is non-zero, with the parameter of the feature in R9, or disables it,
+    /// returning whether it was enabled in RDX.
+    ToggleFeature = 5,
+
+    /// Opens the session of the guest agent with the `HYPERCALL_SECRET` in RDX, returning the session key in RDX. A
+    /// single session can be open at a time.
+    OpenSession = 6,
+
+    /// Closes the session, so another agent can open one.
+    CloseSession = 7,
+
+    /// Restricts the session to the address space of the caller if RDX is non-zero, and to the callers whose RIP is in
+    /// the range from R8 to R9 (exclusive) if R9 is non-zero.
+    RestrictSession = 8,
+
+    /// Registers the event stream buffer at the page-aligned, physically contiguous guest physical address in RDX, of
+    /// the size in R8, streaming the events whose `EventStreamKind` bit is set in R9, and returns the capacity of the
+    /// ring in RDX. Replaces the previous registration.
+    RegisterEventStream = 9,
+
+    /// Unregisters the event stream buffer, which the hypervisor no longer writes once the hypercall returns.
+    UnregisterEventStream = 10,
+
+    /// Reads the `u64` at the virtual address in R8 of the process whose ID is in RDX, returning it in RDX.
+    ReadProcessMemory = 11,
+
+    /// Writes R9 as a `u64` to the virtual address in R8 of the process whose ID is in RDX.
+    WriteProcessMemory = 12,
+}
+
+impl Hypercall {
+    /// Converts a `u32` value to a `Hypercall` enum variant.
+    pub fn from_u32(value: u32) -> Option<Hypercall> {
+        match value {
+            0 => Some(Hypercall::QueryStatus),
+            1 => Some(Hypercall::InstallHook),
+            2 => Some(Hypercall::RemoveHook),
+            3 => Some(Hypercall::ReadGuestMemory),
+            4 => Some(Hypercall::WriteGuestMemory),
+            5 => Some(Hypercall::ToggleFeature),
+            6 => Some(Hypercall::OpenSession),
+            7 => Some(Hypercall::CloseSession),
+            8 => Some(Hypercall::RestrictSession),
+            9 => Some(Hypercall::RegisterEventStream),
+            10 => Some(Hypercall::UnregisterEventStream),
+            11 => Some(Hypercall::ReadProcessMemory),
+            12 => Some(Hypercall::WriteProcessMemory),
+            _ => None,
+        }
+    }
+}
+
+/// The status returned in RAX by a hypercall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum HypercallStatus {
+    /// The hypercall succeeded.
+    Success = 0,
+    /// The hypercall number isn't known to the hypervisor.
+    UnknownHypercall = 1,
+    /// The ABI version isn't the version of the hypervisor, returned in RDX.
+    VersionMismatch = 2,
+    /// An argument is invalid, e.g., an unknown feature or detour.
+    InvalidArgument = 3,
+    /// The hypercall failed, e.g., the hook couldn't be installed or the memory isn't mapped.
+    Failed = 4,
+}
+
+impl HypercallStatus {
+    /// Converts a `u64` value to a `HypercallStatus` enum variant.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(HypercallStatus::Success),
+            1 => Some(HypercallStatus::UnknownHypercall),
+            2 => Some(HypercallStatus::VersionMismatch),
+            3 => Some(HypercallStatus::InvalidArgument),
+            4 => Some(HypercallStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// The features a guest agent can toggle with `Hypercall::ToggleFeature`, each reported as the bit of its value by
+/// `Hypercall::QueryStatus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum HypercallFeature {
+    /// The hypervisor-present bit and the hypervisor leaves of CPUID are exposed to the guest.
+    HypervisorPresence = 0,
+    /// The process owning the current address space of each logical processor is tracked.
+    ProcessTracking = 1,
+    /// The code pages seen to become executable after being written are copied, at most R9 per second.
+    CodeSnapshots = 2,
+}
+
+impl HypercallFeature {
+    /// Converts a `u64` value to a `HypercallFeature` enum variant.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(HypercallFeature::HypervisorPresence),
+            1 => Some(HypercallFeature::ProcessTracking),
+            2 => Some(HypercallFeature::CodeSnapshots),
+            _ => None,
+        }
+    }
+}
+
+/// The detours a guest agent can install with `Hypercall::InstallHook`, those of `DetourType` without a guest handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum HypercallDetour {
+    /// `DetourType::Vmcall`.
+    Vmcall = 0,
+    /// `DetourType::Int3`.
+    Int3 = 1,
+    /// `DetourType::Cpuid`.
+    Cpuid = 2,
+    /// `DetourType::Int3Stub`.
+    Int3Stub = 3,
+    /// `DetourType::HardwareBreakpoint`.
+    HardwareBreakpoint = 4,
+}
+
+impl HypercallDetour {
+    /// Converts a `u64` value to the `DetourType` of a `HypercallDetour`.
+    pub fn detour_type_from_u64(value: u64) -> Option<DetourType> {
+        match value {
+            0 => Some(DetourType::Vmcall),
+            1 => Some(DetourType::Int3),
+            2 => Some(DetourType::Cpuid),
+            3 => Some(DetourType::Int3Stub),
+            4 => Some(DetourType::HardwareBreakpoint),
+            _ => None,
+        }
+    }
+}
+
+/// The detour written over the start of a function by a kernel EPT hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetourType {
+    /// `vmcall`, handled by the hypervisor (3 bytes).
+    Vmcall,
+    /// `int3` (1 byte).
+    Int3,
+    /// `cpuid` (2 bytes).
+    Cpuid,
+    /// `int3` padded to 5 bytes.
+    Int3Stub,
+    /// `jmp rel32` to a guest handler within ±2 GiB of the function (5 bytes).
+    JmpRel32(u64),
+    /// `push imm32; mov dword ptr [rsp+4], imm32; ret` to a guest handler (14 bytes).
+    PushRet(u64),
+    /// `mov rax, imm64; jmp rax` to a guest handler (12 bytes).
+    MovRaxJmp(u64),
+    /// A hardware breakpoint of the hypervisor on the first instruction, hidden from the guest (0 bytes).
+    HardwareBreakpoint,
+}
+
 /// Structure representing the hook data sent by the client to the hypervisor.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct HookData {
     pub function_hash: u32,
     pub syscall_number: u16,
+    /// The detour to install, ignored when disabling a hook.
+    pub detour_type: DetourType,
 }
 
 /// Structure representing the memory operation data sent by the client to the hypervisor.
@@ -84,28 +544,1769 @@ pub struct ProcessMemoryOperation {
     pub buffer_size: u64,
 }
 
-/// Enum representing the data that can be sent by the client to the hypervisor.
+/// Structure representing the shared page data sent by the client to the hypervisor.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ClientDataPayload {
-    Hook(HookData),
-    Memory(ProcessMemoryOperation),
+pub struct SharedPageOperation {
+    /// The virtual address of the page-aligned (and locked) guest page to map the shared page over.
+    pub guest_page_va: u64,
+    /// The virtual address of a `u64` buffer that receives the guest physical address of the shared page.
+    pub buffer: u64,
 }
 
-/// Structure representing the data sent by the client to the hypervisor.
+/// Structure representing the RTC offset data sent by the client to the hypervisor.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct ClientCommand {
-    pub command: Command,
-    pub payload: ClientDataPayload,
+pub struct RtcOffsetOperation {
+    /// The offset in seconds added to the RTC time observed by the guest, 0 to restore the hardware time.
+    pub offset_seconds: i64,
 }
 
-impl ClientCommand {
-    /// Converts `ClientCommand` to a pointer.
-    pub fn as_ptr(&self) -> u64 {
-        self as *const ClientCommand as u64
-    }
+/// Structure representing the exfiltration data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExfilOperation {
+    /// The virtual address of the buffer to append to the exfiltration file.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
 
-    /// Converts a pointer to `ClientCommand`.
-    pub fn from_ptr(ptr: u64) -> &'static ClientCommand {
-        unsafe { &*(ptr as *const ClientCommand) }
+/// Structure representing the profiler data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfilerOperation {
+    /// The number of samples per second taken on each logical processor, used by `StartProfiling`.
+    pub frequency_hz: u64,
+    /// The number of stack slots recorded with each sample, at most `MAX_PROFILE_STACK_DEPTH`, used by `StartProfiling`.
+    pub stack_depth: u64,
+    /// The virtual address of the buffer receiving a `ProfileHeader` followed by the samples, used by `ReadProfile`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the watchdog configuration sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogOperation {
+    /// The time in milliseconds a logical processor may make no progress before it is reported, or 0 to disable the watchdog.
+    pub period_ms: u64,
+    /// Whether to inject an NMI into a logical processor that made no progress, e.g., to trigger a crash dump of the guest.
+    pub inject_nmi: bool,
+}
+
+/// How the guest's attempts to reset or shut down the system are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetPolicy {
+    /// The reset or shutdown proceeds immediately.
+    Allow,
+    /// The reset or shutdown proceeds after a delay in milliseconds, e.g., to let the logs drain.
+    Delay(u64),
+    /// The reset or shutdown is discarded.
+    Veto,
+}
+
+/// Structure representing the deterministic mode configuration sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeterminismOperation {
+    /// Whether the deterministic mode is enabled.
+    pub enabled: bool,
+    /// The seed of the values returned by `RDRAND` and `RDSEED`.
+    pub seed: u64,
+    /// The TSC value returned by the first read, or 0 to start at the current TSC.
+    pub start_tsc: u64,
+    /// The number of ticks the TSC advances by on each read.
+    pub tsc_ticks_per_read: u64,
+}
+
+/// Enum representing a hook view operation sent by the client to the hypervisor.
+///
+/// View 0 is the default view, with every hook installed. The other views only execute the hooked functions enabled in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookViewOperation {
+    /// Enables a hooked kernel function, identified by its hash or syscall number, in an alternate view.
+    EnableHook { view: u8, function_hash: u32, syscall_number: u16 },
+    /// Disables a hooked kernel function, identified by its hash or syscall number, in an alternate view.
+    DisableHook { view: u8, function_hash: u32, syscall_number: u16 },
+    /// Assigns a view to a process, or removes its assignment.
+    AssignProcess { process_id: u64, view: Option<u8> },
+    /// Binds a hooked kernel function, identified by its hash or syscall number, to a process so it only fires in that process, or removes its binding if `None`.
+    BindHookToProcess { function_hash: u32, syscall_number: u16, process_id: Option<u64> },
+    /// Assigns a view to a logical processor by initial APIC ID, or to the logical processors without an assigned view if `None`.
+    AssignProcessor { processor_id: Option<u32>, view: Option<u8> },
+}
+
+/// How the system call numbers of a `SyscallTraceOperation` select the traced system calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallTraceFilterMode {
+    /// Every system call is traced, the system call numbers are ignored.
+    All,
+    /// Only the listed system calls are traced.
+    Allow,
+    /// Every system call except the listed ones is traced.
+    Deny,
+}
+
+/// The maximum number of system call numbers in the filter of a `SyscallTraceOperation`.
+pub const MAX_SYSCALL_TRACE_FILTER: usize = 32;
+
+/// Structure representing the syscall trace data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallTraceOperation {
+    /// How `syscall_numbers` selects the traced system calls, used by `StartSyscallTrace`.
+    pub filter_mode: SyscallTraceFilterMode,
+    /// The number of valid entries in `syscall_numbers`, at most `MAX_SYSCALL_TRACE_FILTER`, used by `StartSyscallTrace`.
+    pub syscall_count: u64,
+    /// The system call numbers of the filter, used by `StartSyscallTrace`.
+    pub syscall_numbers: [u32; MAX_SYSCALL_TRACE_FILTER],
+    /// The virtual address of the buffer receiving a `SyscallTraceHeader` followed by the records, used by `ReadSyscallTrace`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the exception telemetry data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionTelemetryOperation {
+    /// Whether page faults (#PF) are recorded, used by `ConfigureExceptionTelemetry`.
+    pub page_faults: bool,
+    /// Whether only the page faults of user-mode accesses are recorded, used by `ConfigureExceptionTelemetry`.
+    pub user_mode_page_faults_only: bool,
+    /// Whether general protection faults (#GP) are recorded, used by `ConfigureExceptionTelemetry`.
+    pub general_protection_faults: bool,
+    /// Whether invalid opcodes (#UD) are recorded, used by `ConfigureExceptionTelemetry`.
+    pub invalid_opcodes: bool,
+    /// The maximum number of events recorded per second for the whole system, used by `ConfigureExceptionTelemetry`.
+    pub max_events_per_second: u64,
+    /// The virtual address of the buffer receiving an `ExceptionTelemetryHeader` followed by the events, used by `ReadExceptionTelemetry`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Enum representing an MSR bitmap operation sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrBitmapOperation {
+    /// Swaps in the "quiet" profile, in which only the hooked MSR accesses cause VM exits.
+    Quiet,
+    /// Swaps in the profile intercepting every MSR access.
+    InterceptAll,
+    /// Swaps in a copy of the current profile with the interception of the reads and/or writes of a range of MSRs enabled or disabled.
+    ModifyRange { first_msr: u32, last_msr: u32, reads: bool, writes: bool, intercept: bool },
+}
+
+/// The guest context an MSR access is made from, every field set having to match for a rule to apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MsrAccessContext {
+    /// The current privilege level of the accessing code, 0 for the kernel.
+    pub cpl: Option<u8>,
+    /// The page table base of the address space of the accessing process, the low 12 bits being ignored.
+    pub guest_cr3: Option<u64>,
+    /// The first address and the size in bytes of the range holding the accessing instruction, e.g., a module or a section.
+    pub code_range: Option<(u64, u64)>,
+}
+
+/// What an MSR context rule does with the accesses made from a matching guest context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrContextAction {
+    /// The access bypasses the hook of the MSR and reaches the hardware, e.g., for the kernel's own early-boot code.
+    Passthrough,
+    /// The access is handled by the hook of the MSR, e.g., serving its shadow value, as without rule.
+    Hook,
+    /// A general protection fault (#GP) is injected instead of completing the access.
+    InjectGp,
+}
+
+/// Enum representing an MSR context rule operation sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrContextRuleOperation {
+    /// Adds a rule for the reads or the writes of an MSR, after the existing rules of the access, the first matching
+    /// rule applying. The rule is removed once it has matched `max_matches` accesses, if set.
+    Add { msr: u32, writes: bool, context: MsrAccessContext, action: MsrContextAction, max_matches: Option<u64> },
+    /// Removes the rules of the reads or the writes of an MSR.
+    Clear { msr: u32, writes: bool },
+}
+
+/// Structure representing the unpacker data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnpackerOperation {
+    /// The ID of the target process, used by `StartUnpacker`.
+    pub process_id: u64,
+    /// The first virtual address of the watched range in the target process, e.g., its image base, used by `StartUnpacker`.
+    pub base_address: u64,
+    /// The size of the watched range in bytes, used by `StartUnpacker`.
+    pub size: u64,
+    /// The virtual address of the buffer receiving an `UnpackerDumpHeader` followed by the dumped pages, used by `ReadUnpackerDumps`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// How the results of a CPUID leaf are overridden, the registers being in the order EAX, EBX, ECX, EDX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuidOverrideAction {
+    /// The registers are replaced, e.g., to emulate the hypervisor vendor leaves.
+    Replace([u32; 4]),
+    /// Each register is masked with `and_mask`, then combined with `or_mask`, e.g., to hide feature bits or change the
+    /// vendor string. The masks are composed with those of the current override of the leaf, if any.
+    Modify { and_mask: [u32; 4], or_mask: [u32; 4] },
+    /// The override of the leaf is removed, including a built-in one.
+    Remove,
+}
+
+/// Structure representing the CPUID override data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidOverrideOperation {
+    /// The overridden leaf.
+    pub leaf: u32,
+    /// The overridden subleaf, or `None` for every subleaf of the leaf.
+    pub sub_leaf: Option<u32>,
+    /// How the results are overridden.
+    pub action: CpuidOverrideAction,
+}
+
+/// Structure representing the allocation monitor data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationMonitorOperation {
+    /// The ID of the target process, used by `StartAllocationMonitor`, `StopAllocationMonitor` (0 for every process) and `ReadAllocationMap`.
+    pub process_id: u64,
+    /// The system call number of `NtAllocateVirtualMemory`, used by `StartAllocationMonitor`.
+    pub allocate_syscall_number: u32,
+    /// The system call number of `NtProtectVirtualMemory`, used by `StartAllocationMonitor`.
+    pub protect_syscall_number: u32,
+    /// The system call number of `NtFreeVirtualMemory`, or `None` if the freed regions stay in the map, used by `StartAllocationMonitor`.
+    pub free_syscall_number: Option<u32>,
+    /// The virtual address of the buffer receiving a header followed by the regions or alerts, used by `ReadAllocationMap` and `ReadAllocationAlerts`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Whether the CPUID hypervisor leaves (0x40000000-0x400000FF) and the hypervisor-present bit reveal the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HypervisorPresence {
+    /// The hypervisor-present bit is clear and the hypervisor leaves return the results of the highest basic leaf, as on bare metal.
+    Hidden,
+    /// The hypervisor-present bit is set and the hypervisor leaves report the "Illusion" vendor signature and version.
+    Exposed,
+}
+
+/// Structure representing the code snapshot data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeSnapshotOperation {
+    /// Whether the pages seen to become executable after being written are copied, used by `ConfigureCodeSnapshots`.
+    pub enabled: bool,
+    /// The maximum number of pages copied per second for the whole system, used by `ConfigureCodeSnapshots`.
+    pub max_snapshots_per_second: u64,
+    /// The virtual address of the buffer receiving a `CodeSnapshotHeader` followed by the copies, used by `ReadCodeSnapshots`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the TSC compensation configuration sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TscCompensationOperation {
+    /// Whether the time spent in the hypervisor is hidden from the guest TSC with the TSC offset.
+    pub enabled: bool,
+    /// Whether `RDTSC` and `RDTSCP` cause VM exits and return the TSC computed by the hypervisor.
+    pub rdtsc_exiting: bool,
+    /// The TSC multiplier in 16.48 fixed point, `1 << 48` to leave the guest TSC unscaled.
+    pub tsc_multiplier: u64,
+    /// The exit latency hidden on each VM exit in TSC ticks, or `None` for the latency calibrated at startup.
+    pub exit_latency_ticks: Option<u64>,
+}
+
+/// The maximum number of addresses resolved by a `ResolveSymbols` command.
+pub const MAX_SYMBOL_ADDRESSES: usize = 0x20;
+
+/// Structure representing a symbol operation sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SymbolOperation {
+    /// The ID of the process of a user-mode module, or 0 for a kernel module.
+    pub process_id: u64,
+    /// The base address of the module whose exports are loaded, used by `LoadModuleSymbols`.
+    pub base_address: u64,
+    /// The guest addresses to resolve, used by `ResolveSymbols`.
+    pub addresses: [u64; MAX_SYMBOL_ADDRESSES],
+    /// The number of addresses to resolve.
+    pub address_count: u64,
+    /// The virtual address of the buffer receiving a `SymbolHeader` followed by the symbols, used by `ResolveSymbols`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Structure representing a boot hook trigger sent by a guest agent to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootHookOperation {
+    /// Whether the hooks are installed even if the manifest selects another trigger than the agent hypercall.
+    pub force: bool,
+}
+
+/// The maximum size of the pattern of a `MemorySearchOperation` in bytes.
+pub const MAX_SEARCH_PATTERN_SIZE: usize = 0x80;
+
+/// The maximum number of bytes scanned by a `SearchMemory` command, larger ranges being searched by further commands.
+pub const MAX_SEARCH_SCAN_SIZE: u64 = 0x40_0000;
+
+/// The encoding of the characters searched by a `MemorySearchOperation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchEncoding {
+    /// One byte per character.
+    Ascii,
+    /// Two bytes per character, little-endian.
+    Utf16,
+}
+
+/// Structure representing a memory search sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySearchOperation {
+    /// The ID of the process whose address space is searched, 4 for the kernel address space.
+    pub process_id: u64,
+    /// The virtual address where the search starts, or the `next_address` of the previous search to resume it.
+    pub start_address: u64,
+    /// The virtual address where the searched range ends, exclusive.
+    pub end_address: u64,
+    /// The ASCII pattern.
+    pub pattern: [u8; MAX_SEARCH_PATTERN_SIZE],
+    /// The size of the pattern in bytes.
+    pub pattern_size: u64,
+    /// Whether the pattern uses the regex-lite syntax, or is a literal string.
+    pub is_regex: bool,
+    /// Whether the ASCII letters match both cases.
+    pub case_insensitive: bool,
+    /// The encoding of the searched characters.
+    pub encoding: SearchEncoding,
+    /// The virtual address of the buffer receiving a `MemorySearchHeader` followed by the matches.
+    pub buffer: u64,
+    /// The size of the buffer in bytes, bounding the number of matches returned.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the process tracking configuration sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessTrackingOperation {
+    /// Whether MOV to CR3 causes VM exits to map each new address space to the process owning it.
+    pub enabled: bool,
+}
+
+/// Structure representing the XSAVE policy sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XsavePolicyOperation {
+    /// The XCR0 bits of the state components the guest may not enable, e.g., `0x60000` for AMX, or 0 to allow all the supported ones.
+    pub denied_components: u64,
+}
+
+/// Structure representing the benchmark data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchmarkOperation {
+    /// The virtual address of the buffer receiving a `BenchmarkHeader` followed by the exit reasons.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the VM exit statistics data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatisticsOperation {
+    /// The virtual address of the buffer receiving an `ExitStatisticsHeader` followed by the records.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+    /// Whether each logical processor resets its statistics once published, so the next read covers the VM exits since this one.
+    pub reset: bool,
+    /// Whether the hypervisor also logs the statistics of all the logical processors by basic exit reason.
+    pub log: bool,
+}
+
+/// Structure representing the hardware breakpoint sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HardwareBreakpointOperation {
+    /// The index of the debug register holding the breakpoint, 0 to 3.
+    pub slot: u64,
+    /// The linear address of the breakpoint, or 0 to remove the breakpoint of the slot.
+    pub address: u64,
+    /// The accesses triggering the breakpoint: 0 for execution, 1 for writes, 3 for reads and writes.
+    pub condition: u64,
+    /// The size of the monitored range in bytes: 1, 2, 4 or 8, and 1 for execution.
+    pub length: u64,
+}
+
+/// The event rings of the hypervisor, drained by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventRingId {
+    /// The records of the traced system calls, drained by `ReadSyscallTrace`.
+    SyscallTrace,
+    /// The events of the exception telemetry, drained by `ReadExceptionTelemetry`.
+    ExceptionTelemetry,
+    /// The samples of the profiler, drained by `ReadProfile`.
+    Profiler,
+    /// The alerts of the allocation monitor, drained by `ReadAllocationAlerts`.
+    AllocationAlerts,
+}
+
+/// What an event ring does with a new event while it's full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// The new event is dropped, keeping the oldest ones.
+    DropNewest,
+    /// The oldest event is dropped to make room for the new one, keeping the most recent ones.
+    DropOldest,
+    /// The new event is kept in a reserve beyond the capacity, and the logical processor recording it waits for the
+    /// client to drain the ring, up to a timeout, before resuming the guest. Events are dropped once the reserve is full.
+    Stall,
+}
+
+/// Structure representing the event ring configuration sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventRingOperation {
+    /// The event ring.
+    pub ring: EventRingId,
+    /// The policy applied while the ring is full.
+    pub policy: BackpressurePolicy,
+}
+
+/// Structure representing the event ring statistics request sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventRingStatsOperation {
+    /// The event ring.
+    pub ring: EventRingId,
+    /// The virtual address of the buffer receiving an `EventRingStats`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the persistent configuration request sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveConfigurationOperation {
+    /// Whether the saved configuration is discarded, so the next boot uses the configuration selected at build time,
+    /// instead of saving the current configuration.
+    pub discard: bool,
+}
+
+/// The observations of the guest made by the client for `RunDetectionCorpus`, with the instructions a user-mode
+/// detector executes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DetectionProbes {
+    /// The results of CPUID leaf 1, in EAX, EBX, ECX and EDX order.
+    pub cpuid_feature_information: [u32; 4],
+    /// The results of CPUID leaf 0x40000000, the hypervisor vendor leaf.
+    pub cpuid_hypervisor_leaf: [u32; 4],
+    /// The results of the highest basic CPUID leaf, returned by processors for the leaves above their maximum.
+    pub cpuid_max_basic_leaf: [u32; 4],
+    /// The shortest duration of CPUID between two RDTSC, in TSC ticks, or 0 if it wasn't measured.
+    pub cpuid_tsc_ticks: u64,
+}
+
+/// Structure representing the detection corpus request sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectionCorpusOperation {
+    /// The observations of the guest made by the client.
+    pub probes: DetectionProbes,
+    /// The virtual address of the buffer receiving a `DetectionCorpusHeader` followed by the results.
+    pub buffer: u64,
+    /// The size of the buffer in bytes, at least room for `DETECTION_TECHNIQUE_COUNT` results.
+    pub buffer_size: u64,
+}
+
+/// The size of the NUL-padded release of a Linux kernel, as reported by `uname -r`.
+pub const LINUX_RELEASE_SIZE: usize = 64;
+
+/// The offsets of a Linux kernel release, found in `/proc/kallsyms` and the debug information of the kernel.
+///
+/// The offsets of the symbols are relative to `entry_SYSCALL_64`, the syscall entry written to IA32_LSTAR, so they
+/// don't depend on the KASLR slide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinuxKernelOffsets {
+    /// The offset of `linux_banner`, the `Linux version ...` string identifying the running release.
+    pub linux_banner: i64,
+    /// The offset of `sys_call_table`.
+    pub sys_call_table: i64,
+    /// The number of entries of `sys_call_table`, `NR_syscalls`.
+    pub syscall_count: u64,
+    /// The offset of `init_task`, the first task of the list of tasks.
+    pub init_task: i64,
+    /// The offset of `tasks`, the `list_head` linking the tasks, in `task_struct`.
+    pub task_tasks: u64,
+    /// The offset of `pid` in `task_struct`.
+    pub task_pid: u64,
+    /// The offset of `tgid` in `task_struct`.
+    pub task_tgid: u64,
+    /// The offset of `comm`, the command name, in `task_struct`.
+    pub task_comm: u64,
+    /// The offset of `mm`, the memory descriptor, in `task_struct`.
+    pub task_mm: u64,
+    /// The offset of `pgd`, the top-level page table, in `mm_struct`.
+    pub mm_pgd: u64,
+}
+
+/// Structure representing the offsets of a Linux kernel release sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinuxKernelOperation {
+    /// The NUL-padded release of the kernel, as reported by `uname -r`.
+    pub release: [u8; LINUX_RELEASE_SIZE],
+    /// The offsets of the release, ignored if `remove` is set.
+    pub offsets: LinuxKernelOffsets,
+    /// Whether the offsets of the release are removed instead of registered.
+    pub remove: bool,
+}
+
+/// Structure representing the Linux task request sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinuxTasksOperation {
+    /// The virtual address of the buffer receiving a `LinuxTaskHeader` followed by the tasks.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the execution trace data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionTraceOperation {
+    /// The ID of the target process, used by `StartExecutionTrace`.
+    pub process_id: u64,
+    /// The first virtual address of the traced range in the target process, used by `StartExecutionTrace`.
+    pub base_address: u64,
+    /// The size of the traced range in bytes, used by `StartExecutionTrace`.
+    pub size: u64,
+    /// Whether the general-purpose registers are recorded with each instruction, used by `StartExecutionTrace`.
+    pub capture_registers: bool,
+    /// The virtual address of the buffer receiving an `ExecutionTraceHeader` followed by the records, used by `ReadExecutionTrace`.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// The maximum size of the pattern of a `SignatureScanOperation` in bytes.
+pub const MAX_SIGNATURE_SIZE: usize = 0x80;
+
+/// The maximum number of bytes scanned by a `ScanSignature` command, larger ranges being scanned by further commands.
+pub const MAX_SIGNATURE_SCAN_SIZE: u64 = 0x40_0000;
+
+/// Structure representing a signature scan sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureScanOperation {
+    /// The ID of the process whose address space is scanned, 4 for the kernel address space, ignored if `physical`.
+    pub process_id: u64,
+    /// Whether the range is of guest physical addresses, or of virtual addresses of the process.
+    pub physical: bool,
+    /// The address where the scan starts, or the `next_address` of the previous scan to resume it.
+    pub start_address: u64,
+    /// The address where the scanned range ends, exclusive.
+    pub end_address: u64,
+    /// The bytes of the pattern.
+    pub pattern: [u8; MAX_SIGNATURE_SIZE],
+    /// The mask of the pattern, the bytes whose mask is 0 matching any byte.
+    pub mask: [u8; MAX_SIGNATURE_SIZE],
+    /// The size of the pattern in bytes.
+    pub pattern_size: u64,
+    /// The virtual address of the buffer receiving a `SignatureScanHeader` followed by the addresses of the matches.
+    pub buffer: u64,
+    /// The size of the buffer in bytes, bounding the number of matches returned.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the Windows process, thread or kernel callback enumeration request sent by the client to the
+/// hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsIntrospectionOperation {
+    /// The ID of the process whose threads or VADs are read by `EnumerateThreads` or `EnumerateVads`, ignored by
+    /// `EnumerateProcesses` and `EnumerateKernelCallbacks`.
+    pub process_id: u64,
+    /// The virtual address of the buffer receiving a `WindowsProcessHeader` followed by the processes, a
+    /// `WindowsThreadHeader` followed by the threads, a `WindowsVadHeader` followed by the VADs, or a
+    /// `KernelCallbackHeader` followed by the callbacks.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// The maximum number of bytes of memory dumped by a `DumpProcessMemory` command into the buffer of the client, larger
+/// dumps being resumed by further commands.
+pub const MAX_PROCESS_DUMP_SIZE: u64 = 0x10_0000;
+
+/// The maximum number of bytes of memory dumped by a `DumpProcessMemory` command over the serial port, far slower
+/// than a copy.
+pub const MAX_SERIAL_PROCESS_DUMP_SIZE: u64 = 0x1000;
+
+/// Structure representing the process memory dump request sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessDumpOperation {
+    /// The ID of the target process.
+    pub process_id: u64,
+    /// The address where the dump starts, or resumes.
+    pub start_address: u64,
+    /// The address where the dumped range ends, exclusive.
+    pub end_address: u64,
+    /// Whether only the private memory is dumped, rather than also the views of the sections, e.g., the images.
+    pub private_only: bool,
+    /// Whether only the memory allocated as executable is dumped.
+    pub executable_only: bool,
+    /// Whether the memory is written to the serial port as hexadecimal lines rather than to the buffer.
+    pub serial: bool,
+    /// The virtual address of the buffer receiving a `ProcessDumpHeader`, followed by the chunks unless the memory is
+    /// written to the serial port, each a `ProcessDumpChunk` followed by its bytes.
+    pub buffer: u64,
+    /// The size of the buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// Structure representing the OS event configuration sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OsEventsOperation {
+    /// The RVA of `PspInsertProcess` in ntoskrnl.exe, or 0 not to push `ProcessCreated` events.
+    pub process_insert_rva: u64,
+    /// The RVA of `PsCallImageNotifyRoutines` in ntoskrnl.exe, or 0 not to push `ImageLoaded` events.
+    pub image_notify_rva: u64,
+}
+
+/// The maximum number of drivers whose code is monitored besides ntoskrnl.exe.
+pub const MAX_CODE_INTEGRITY_DRIVERS: usize = 8;
+
+/// The size of the NUL-padded base name of a driver of a `CodeIntegrityOperation`, in bytes.
+pub const CODE_INTEGRITY_DRIVER_NAME_SIZE: usize = 32;
+
+/// Structure representing the code integrity monitor configuration sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeIntegrityOperation {
+    /// The period of the verifications in milliseconds, each verifying a bounded number of pages, or 0 to stop the
+    /// monitor.
+    pub period_ms: u64,
+    /// The base names of the drivers monitored besides ntoskrnl.exe, e.g., "ci.dll", NUL-padded, the empty ones being
+    /// ignored.
+    pub drivers: [[u8; CODE_INTEGRITY_DRIVER_NAME_SIZE]; MAX_CODE_INTEGRITY_DRIVERS],
+}
+
+/// What a denied access to a protected range gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectedMemoryAction {
+    /// A page fault is injected at the accessed address.
+    PageFault,
+    /// The access is redirected to a decoy page, reading zeros or what was previously written to the decoy, the
+    /// protected memory being left unchanged.
+    Decoy,
+}
+
+/// Structure representing the protected range of a process sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectedMemoryOperation {
+    /// The ID of the process owning the range.
+    pub process_id: u64,
+    /// The first virtual address of the range in the process, which identifies the range for `UnprotectMemory`, 0
+    /// removing all the ranges of the process.
+    pub address: u64,
+    /// The size of the range in bytes, used by `ProtectMemory`.
+    pub size: u64,
+    /// Whether the reads of the other processes are denied, which also denies their writes.
+    pub deny_reads: bool,
+    /// Whether the writes of the other processes are denied.
+    pub deny_writes: bool,
+    /// Whether the kernel is allowed to access the range in the address space of the process, e.g., in the system
+    /// calls of the process, which also allows the kernel attached to the process for another one.
+    pub allow_kernel_access: bool,
+    /// What a denied access gets, used by `ProtectMemory`.
+    pub action: ProtectedMemoryAction,
+}
+
+/// The size of the NUL-padded name of an EPT view, in bytes.
+pub const EPT_VIEW_NAME_SIZE: usize = 16;
+
+/// Enum representing an EPT view operation sent by the client to the hypervisor.
+///
+/// View 0 is the primary EPT, with the hooks. The other views map the guest pages identically except their divergent pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptViewOperation {
+    /// Creates an alternate view duplicated from another view, the primary EPT giving the clean identity map, and names it, e.g., "clean", NUL-padded.
+    Duplicate { view: u8, source: u8, name: [u8; EPT_VIEW_NAME_SIZE] },
+    /// Discards an alternate view, the logical processors running in it returning to the primary EPT.
+    Collapse { view: u8 },
+    /// Assigns a view to a logical processor by initial APIC ID, or to the logical processors without an assigned view if `None`.
+    Switch { processor_id: Option<u32>, view: u8 },
+    /// Maps a guest page of an alternate view to a host page with permissions, the accesses it denies switching to `switch_view`, or being executed in the primary EPT if `None`.
+    SetPage {
+        view: u8,
+        guest_pa: u64,
+        host_pa: u64,
+        readable: bool,
+        writable: bool,
+        executable: bool,
+        switch_view: Option<u8>,
+    },
+    /// Maps a divergent guest page of an alternate view back to itself.
+    ResetPage { view: u8, guest_pa: u64 },
+}
+
+/// Enum representing a devirtualization operation sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevirtualizeOperation {
+    /// Requests each logical processor to leave VMX operation on its next VM exit in a state it can be resumed from natively.
+    Start,
+    /// Cancels the request, the logical processors that already left staying devirtualized.
+    Cancel,
+}
+
+/// Enum representing a command broadcast by the hypervisor to all the logical processors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastCommand {
+    /// Invalidates the EPT-derived mappings of every EPTP on each logical processor.
+    FlushEpt,
+    /// Holds the logical processors, except the one receiving the command, in VMX root operation until `Resume`, for at
+    /// most `timeout_ms` milliseconds.
+    Pause { timeout_ms: u64 },
+    /// Releases the paused logical processors.
+    Resume,
+    /// Unloads the hypervisor from each logical processor, as `DevirtualizeOperation::Start`.
+    Unload,
+    /// Publishes the VM exit statistics of each logical processor for `ReadExitStatistics`, resetting them if `reset` is set.
+    PublishExitStatistics { reset: bool },
+}
+
+/// Enum representing the data that can be sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientDataPayload {
+    Hook(HookData),
+    Memory(ProcessMemoryOperation),
+    SharedPage(SharedPageOperation),
+    RtcOffset(RtcOffsetOperation),
+    Exfil(ExfilOperation),
+    Profiler(ProfilerOperation),
+    Watchdog(WatchdogOperation),
+    ResetPolicy(ResetPolicy),
+    Determinism(DeterminismOperation),
+    HookView(HookViewOperation),
+    SyscallTrace(SyscallTraceOperation),
+    ExceptionTelemetry(ExceptionTelemetryOperation),
+    MsrBitmap(MsrBitmapOperation),
+    Unpacker(UnpackerOperation),
+    CpuidOverride(CpuidOverrideOperation),
+    AllocationMonitor(AllocationMonitorOperation),
+    HypervisorPresence(HypervisorPresence),
+    CodeSnapshot(CodeSnapshotOperation),
+    TscCompensation(TscCompensationOperation),
+    Symbol(SymbolOperation),
+    BootHook(BootHookOperation),
+    MemorySearch(MemorySearchOperation),
+    ProcessTracking(ProcessTrackingOperation),
+    XsavePolicy(XsavePolicyOperation),
+    Benchmark(BenchmarkOperation),
+    HardwareBreakpoint(HardwareBreakpointOperation),
+    EventRing(EventRingOperation),
+    EventRingStats(EventRingStatsOperation),
+    SaveConfiguration(SaveConfigurationOperation),
+    MsrContextRule(MsrContextRuleOperation),
+    DetectionCorpus(DetectionCorpusOperation),
+    LinuxKernel(LinuxKernelOperation),
+    LinuxTasks(LinuxTasksOperation),
+    ExecutionTrace(ExecutionTraceOperation),
+    SignatureScan(SignatureScanOperation),
+    WindowsIntrospection(WindowsIntrospectionOperation),
+    ProcessDump(ProcessDumpOperation),
+    OsEvents(OsEventsOperation),
+    CodeIntegrity(CodeIntegrityOperation),
+    ProtectedMemory(ProtectedMemoryOperation),
+    EptView(EptViewOperation),
+    Devirtualize(DevirtualizeOperation),
+    Broadcast(BroadcastCommand),
+    ExitStatistics(ExitStatisticsOperation),
+}
+
+/// Structure representing the data sent by the client to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientCommand {
+    pub command: Command,
+    pub payload: ClientDataPayload,
+}
+
+impl ClientCommand {
+    /// Converts `ClientCommand` to a pointer.
+    pub fn as_ptr(&self) -> u64 {
+        self as *const ClientCommand as u64
+    }
+
+    /// Converts a pointer to `ClientCommand`.
+    pub fn from_ptr(ptr: u64) -> &'static ClientCommand {
+        unsafe { &*(ptr as *const ClientCommand) }
+    }
+}
+
+/// The magic value identifying an initialized `SharedPage` ("ILLU").
+pub const SHARED_PAGE_MAGIC: u32 = 0x554C4C49;
+
+/// The version of the `SharedPage` layout. Incremented whenever the layout changes.
+pub const SHARED_PAGE_VERSION: u32 = 1;
+
+/// The size of the header of the `SharedPage` in bytes.
+pub const SHARED_PAGE_HEADER_SIZE: usize = 0x28;
+
+/// The size of each per-direction buffer of the `SharedPage` in bytes.
+pub const SHARED_PAGE_BUFFER_SIZE: usize = (0x1000 - SHARED_PAGE_HEADER_SIZE) / 2;
+
+/// The layout of the 4KB communication page shared between the client and the hypervisor.
+///
+/// The page is used for zero-copy exchange of large buffers. Each direction has its own buffer and
+/// sequence counter: the producer writes the data and its length, then increments the sequence counter.
+/// The consumer processes the data once it observes a sequence counter different from the last one it has seen.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SharedPage {
+    /// Set to `SHARED_PAGE_MAGIC` by the hypervisor when the page is mapped.
+    pub magic: u32,
+    /// Set to `SHARED_PAGE_VERSION` by the hypervisor when the page is mapped.
+    pub version: u32,
+    /// Incremented by the client after writing a message to `guest_to_host_buffer`.
+    pub guest_to_host_sequence: u64,
+    /// The length in bytes of the message in `guest_to_host_buffer`.
+    pub guest_to_host_length: u64,
+    /// Incremented by the hypervisor after writing a message to `host_to_guest_buffer`.
+    pub host_to_guest_sequence: u64,
+    /// The length in bytes of the message in `host_to_guest_buffer`.
+    pub host_to_guest_length: u64,
+    /// The buffer written by the client and read by the hypervisor.
+    pub guest_to_host_buffer: [u8; SHARED_PAGE_BUFFER_SIZE],
+    /// The buffer written by the hypervisor and read by the client.
+    pub host_to_guest_buffer: [u8; SHARED_PAGE_BUFFER_SIZE],
+}
+
+impl SharedPage {
+    /// Resets the header of the shared page and sets the magic and version.
+    pub fn initialize(&mut self) {
+        self.magic = SHARED_PAGE_MAGIC;
+        self.version = SHARED_PAGE_VERSION;
+        self.guest_to_host_sequence = 0;
+        self.guest_to_host_length = 0;
+        self.host_to_guest_sequence = 0;
+        self.host_to_guest_length = 0;
+    }
+
+    /// Returns `true` if the page has been initialized with a layout version that matches this crate.
+    pub fn is_valid(&self) -> bool {
+        self.magic == SHARED_PAGE_MAGIC && self.version == SHARED_PAGE_VERSION
+    }
+}
+
+/// The state of a background copy started by `StartAsyncRead`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    /// The copy is still in progress.
+    InProgress,
+    /// All the bytes have been copied to the destination buffer.
+    Completed,
+    /// The destination buffer couldn't be written, e.g. because it isn't locked in memory.
+    Failed,
+    /// The copy has been cancelled by `CancelAsyncRead`.
+    Cancelled,
+}
+
+impl TransferStatus {
+    /// Converts `TransferStatus` to a u64 for storing in the shared page.
+    pub fn to_u64(self) -> u64 {
+        match self {
+            TransferStatus::InProgress => 0x0,
+            TransferStatus::Completed => 0x1,
+            TransferStatus::Failed => 0x2,
+            TransferStatus::Cancelled => 0x3,
+        }
+    }
+
+    /// Converts a `u64` value to a `TransferStatus` enum variant.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0x0 => Some(TransferStatus::InProgress),
+            0x1 => Some(TransferStatus::Completed),
+            0x2 => Some(TransferStatus::Failed),
+            0x3 => Some(TransferStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// The progress of a background copy, written by the hypervisor to `host_to_guest_buffer` of the `SharedPage`
+/// each time a chunk has been copied.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// The number of bytes copied to the destination buffer so far.
+    pub bytes_copied: u64,
+    /// The total number of bytes to copy.
+    pub total_bytes: u64,
+    /// The number of bytes that couldn't be read from the process (e.g., paged out or unmapped), zero-filled in the destination buffer.
+    pub unreadable_bytes: u64,
+    /// The `TransferStatus` of the copy, as a u64.
+    pub status: u64,
+}
+
+/// The maximum number of stack slots recorded with a `ProfileSample`.
+pub const MAX_PROFILE_STACK_DEPTH: usize = 16;
+
+/// The header written by the hypervisor at the start of the buffer of `ReadProfile`, followed by the samples.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileHeader {
+    /// The number of `ProfileSample` following the header.
+    pub sample_count: u64,
+    /// The number of samples dropped since the last `ReadProfile` because the profile buffer of the hypervisor was full.
+    pub dropped_samples: u64,
+}
+
+/// A sample of the guest state taken by the hypervisor when the VMX-preemption timer of a logical processor expires.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileSample {
+    /// The initial APIC ID of the logical processor.
+    pub processor_id: u64,
+    /// The TSC when the sample was taken.
+    pub tsc: u64,
+    /// The guest RIP.
+    pub rip: u64,
+    /// The guest RSP.
+    pub rsp: u64,
+    /// The guest CR3, identifying the address space of the sampled code.
+    pub cr3: u64,
+    /// The number of valid slots in `stack`, fewer than requested if the stack couldn't be read.
+    pub stack_depth: u64,
+    /// The raw slots of the guest stack starting at RSP, which contain the return addresses of the callers.
+    pub stack: [u64; MAX_PROFILE_STACK_DEPTH],
+}
+
+/// The maximum number of arguments recorded with a `SyscallTraceRecord`.
+pub const MAX_SYSCALL_TRACE_ARGUMENTS: usize = 8;
+
+/// The header written by the hypervisor at the start of the buffer of `ReadSyscallTrace`, followed by the records.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallTraceHeader {
+    /// The number of `SyscallTraceRecord` following the header.
+    pub record_count: u64,
+    /// The number of records dropped since the last `ReadSyscallTrace` because the trace buffer of the hypervisor was full.
+    pub dropped_records: u64,
+}
+
+/// A system call recorded by the hypervisor while the system calls are traced.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallTraceRecord {
+    /// The initial APIC ID of the logical processor the system call was issued on.
+    pub processor_id: u64,
+    /// The TSC when the system call was issued.
+    pub tsc: u64,
+    /// The system call number.
+    pub syscall_number: u64,
+    /// The first arguments of the system call, 0 for the stack arguments that couldn't be read.
+    pub arguments: [u64; MAX_SYSCALL_TRACE_ARGUMENTS],
+    /// The guest CR3 when the system call was issued, the user address space with KVA shadow.
+    pub cr3: u64,
+    /// The ID of the process that issued the system call.
+    pub process_id: u64,
+    /// The ID of the thread that issued the system call.
+    pub thread_id: u64,
+    /// The user-mode address the system call returns to.
+    pub return_address: u64,
+    /// The return value of the system call, valid if `has_return_value` is 1.
+    pub return_value: u64,
+    /// 1 if the return value was captured, 0 if the system call didn't return to its stub or its return couldn't be intercepted.
+    pub has_return_value: u64,
+}
+
+/// The header written by the hypervisor at the start of the buffer of `ReadExceptionTelemetry`, followed by the events.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionTelemetryHeader {
+    /// The number of `ExceptionEvent` following the header.
+    pub event_count: u64,
+    /// The number of events dropped since the last `ReadExceptionTelemetry` because the event buffer of the hypervisor was full.
+    pub dropped_events: u64,
+    /// The number of events suppressed by the rate limit since the last `ReadExceptionTelemetry`.
+    pub suppressed_events: u64,
+}
+
+/// An exception of the guest intercepted and reflected by the hypervisor while the exception telemetry is enabled.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionEvent {
+    /// The initial APIC ID of the logical processor.
+    pub processor_id: u64,
+    /// The TSC when the exception was intercepted.
+    pub tsc: u64,
+    /// The vector of the exception: 6 (#UD), 13 (#GP) or 14 (#PF).
+    pub vector: u64,
+    /// The error code of the exception, 0 for invalid opcodes.
+    pub error_code: u64,
+    /// The guest RIP of the faulting instruction.
+    pub rip: u64,
+    /// The faulting linear address of a page fault, 0 for the other exceptions.
+    pub cr2: u64,
+    /// The guest CR3, identifying the address space of the faulting code.
+    pub cr3: u64,
+    /// The ID of the current process, 0 if it couldn't be read.
+    pub process_id: u64,
+    /// 1 if the exception occurred while delivering another event, e.g., an interrupt.
+    pub during_event_delivery: u64,
+}
+
+/// The size of the pages dumped by the unpacker.
+pub const UNPACKED_PAGE_SIZE: usize = 0x1000;
+
+/// The header written by `ReadUnpackerDumps` before the dumped pages.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnpackerDumpHeader {
+    /// The number of `UnpackedPage` following the header.
+    pub dump_count: u64,
+    /// The number of pages dropped since the last `ReadUnpackerDumps` because the dump buffer of the hypervisor was full.
+    pub dropped_dumps: u64,
+    /// The number of pages of the range currently watched, 0 once the watch has stopped.
+    pub watched_pages: u64,
+}
+
+/// A page of the watched range dumped by the hypervisor when code was executed from it after it had been written.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnpackedPage {
+    /// The TSC when the page was dumped.
+    pub tsc: u64,
+    /// The ID of the target process.
+    pub process_id: u64,
+    /// The guest CR3 of the executing code.
+    pub cr3: u64,
+    /// The virtual address of the page in the target process.
+    pub guest_va: u64,
+    /// The guest physical address of the page.
+    pub guest_pa: u64,
+    /// The guest RIP of the first instruction that wrote the page since it was last dumped.
+    pub write_rip: u64,
+    /// The guest RIP of the instruction fetched from a written page, e.g., the original entry point of a packed executable.
+    pub execute_rip: u64,
+    /// The content of the page.
+    pub data: [u8; UNPACKED_PAGE_SIZE],
+}
+
+/// The header written by `ReadAllocationMap` before the regions.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationMapHeader {
+    /// The number of `AllocationRegion` following the header.
+    pub region_count: u64,
+    /// The number of regions in the map, more than `region_count` if the buffer was too small.
+    pub total_regions: u64,
+    /// The number of regions dropped because the map of the hypervisor was full.
+    pub dropped_regions: u64,
+}
+
+/// A region of the allocation map of a monitored process, whose pages have the same state and protection.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationRegion {
+    /// The base address of the allocation the region is part of.
+    pub allocation_base: u64,
+    /// The base address of the region.
+    pub base_address: u64,
+    /// The size of the region in bytes.
+    pub size: u64,
+    /// The state of the pages: `MEM_COMMIT` (0x1000) or `MEM_RESERVE` (0x2000).
+    pub state: u64,
+    /// The current protection of the pages, e.g., `PAGE_EXECUTE_READWRITE` (0x40), 0 for reserved pages.
+    pub protect: u64,
+    /// The protection requested when the allocation was made.
+    pub allocation_protect: u64,
+    /// The TSC when the allocation was made.
+    pub tsc: u64,
+}
+
+/// The header written by `ReadAllocationAlerts` before the alerts.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationAlertHeader {
+    /// The number of `AllocationAlert` following the header.
+    pub alert_count: u64,
+    /// The number of alerts dropped since the last `ReadAllocationAlerts` because the alert buffer of the hypervisor was full.
+    pub dropped_alerts: u64,
+}
+
+/// The kind of an `AllocationAlert`.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationAlertKind {
+    /// Memory was allocated both writable and executable.
+    RwxAllocation = 0,
+    /// Memory was made both writable and executable.
+    RwxProtection = 1,
+    /// Memory that was writable was made executable.
+    WritableToExecutable = 2,
+}
+
+/// A system call of a monitored process allocating or protecting memory both writable and executable, or making
+/// writable memory executable.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationAlert {
+    /// The TSC when the system call returned.
+    pub tsc: u64,
+    /// The ID of the monitored process.
+    pub process_id: u64,
+    /// The ID of the thread that issued the system call.
+    pub thread_id: u64,
+    /// The `AllocationAlertKind` of the alert.
+    pub kind: u64,
+    /// 1 if the system call was on another process through a handle, e.g., for code injection.
+    pub remote: u64,
+    /// The base address of the range.
+    pub base_address: u64,
+    /// The size of the range in bytes.
+    pub size: u64,
+    /// The protection given to the range.
+    pub protect: u64,
+    /// The previous protection of the range, 0 for an allocation.
+    pub old_protect: u64,
+    /// The user-mode address the system call returns to.
+    pub return_address: u64,
+}
+
+/// The size of the content of a `CodeSnapshot`, a 4-KiB page.
+pub const CODE_SNAPSHOT_PAGE_SIZE: usize = 0x1000;
+
+/// The header written by `ReadCodeSnapshots` before the copies.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeSnapshotHeader {
+    /// The number of `CodeSnapshot` following the header.
+    pub snapshot_count: u64,
+    /// The number of pages dropped since the last `ReadCodeSnapshots` because the store of the hypervisor was full.
+    pub dropped_snapshots: u64,
+    /// The number of pages suppressed by the rate limit since the last `ReadCodeSnapshots`.
+    pub suppressed_snapshots: u64,
+    /// The number of copies still held by the hypervisor, which didn't fit in the buffer.
+    pub held_snapshots: u64,
+}
+
+/// What observed a page becoming executable after being written.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeSnapshotSource {
+    /// The unpacker saw code executed from the page after it had been written.
+    UnpackerTransition = 0,
+    /// The allocation monitor saw the page made executable while it was writable.
+    WritableToExecutable = 1,
+    /// The allocation monitor saw the page given the RWX protection.
+    RwxProtection = 2,
+}
+
+/// A copy of a guest page made by the hypervisor when the page was seen to become executable after being written.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeSnapshot {
+    /// The TSC when the page was copied.
+    pub tsc: u64,
+    /// The ID of the process the page belongs to.
+    pub process_id: u64,
+    /// The guest CR3 of the process.
+    pub cr3: u64,
+    /// The virtual address of the page in the process.
+    pub guest_va: u64,
+    /// The guest physical address of the page.
+    pub guest_pa: u64,
+    /// The `CodeSnapshotSource` of the copy.
+    pub source: u64,
+    /// The content of the page.
+    pub data: [u8; CODE_SNAPSHOT_PAGE_SIZE],
+}
+
+/// The size of the NUL-padded module name of a `ResolvedSymbol`.
+pub const SYMBOL_MODULE_NAME_SIZE: usize = 32;
+
+/// The size of the NUL-padded export name of a `ResolvedSymbol`.
+pub const SYMBOL_EXPORT_NAME_SIZE: usize = 96;
+
+/// The header written by `ResolveSymbols` before the symbols.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SymbolHeader {
+    /// The number of `ResolvedSymbol` following the header, one per address in the order of the addresses.
+    pub symbol_count: u64,
+    /// The number of modules with symbols loaded in the hypervisor.
+    pub module_count: u64,
+}
+
+/// A guest address resolved to the module containing it and the export preceding it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedSymbol {
+    /// The guest address.
+    pub address: u64,
+    /// The base address of the module containing the address, or 0 if no loaded module contains it.
+    pub module_base: u64,
+    /// The offset of the address from the export, or from the module base if the export name is empty.
+    pub offset: u64,
+    /// The name of the module without its extension, NUL-padded and truncated if needed.
+    pub module_name: [u8; SYMBOL_MODULE_NAME_SIZE],
+    /// The name of the export preceding the address, NUL-padded and truncated if needed, empty if there is none.
+    pub export_name: [u8; SYMBOL_EXPORT_NAME_SIZE],
+}
+
+/// The number of bytes of a match copied into a `MemorySearchMatch`.
+pub const SEARCH_MATCH_DATA_SIZE: usize = 0x40;
+
+/// The header written by `SearchMemory` before the matches.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySearchHeader {
+    /// The number of `MemorySearchMatch` following the header, in address order.
+    pub match_count: u64,
+    /// The address where a further search resumes, equal to the end of the range once it has been searched entirely.
+    pub next_address: u64,
+    /// The number of bytes scanned by this search, including the pages skipped as not mapped.
+    pub scanned_bytes: u64,
+}
+
+/// A match of a memory search.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySearchMatch {
+    /// The virtual address of the match.
+    pub address: u64,
+    /// The size of the match in bytes.
+    pub size: u64,
+    /// The first bytes of the match, zero-padded.
+    pub data: [u8; SEARCH_MATCH_DATA_SIZE],
+}
+
+/// The maximum number of `BenchmarkExitReason` following a `BenchmarkHeader`, one per basic exit reason.
+pub const MAX_BENCHMARK_EXIT_REASONS: usize = 0x80;
+
+/// The header written by `ReadBenchmark` before the exit reasons.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchmarkHeader {
+    /// The index of the run in the benchmark plan.
+    pub run_index: u64,
+    /// The number of runs of the benchmark plan.
+    pub run_count: u64,
+    /// The interceptions of the run, as the bits of `BenchmarkInterceptions` of the hypervisor.
+    pub interceptions: u64,
+    /// The frequency of the TSC in Hz, to convert the ticks.
+    pub tsc_frequency_hz: u64,
+    /// The length of the measurement window in TSC ticks.
+    pub duration_tsc_ticks: u64,
+    /// The number of logical processors whose VM exits are included, each reporting after the measurement window.
+    pub reported_processors: u64,
+    /// 1 once the summary is complete, one second after the measurement window, 0 before.
+    pub is_complete: u64,
+    /// The number of `BenchmarkExitReason` following the header, in exit reason order.
+    pub exit_reason_count: u64,
+}
+
+/// The VM exits of a basic exit reason during the measurement window of a benchmark run, on all the logical processors.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchmarkExitReason {
+    /// The basic exit reason.
+    pub reason: u64,
+    /// The number of VM exits.
+    pub count: u64,
+    /// The total handling time in VMX root operation, in TSC ticks.
+    pub total_ticks: u64,
+    /// The longest handling time in VMX root operation, in TSC ticks.
+    pub max_ticks: u64,
+}
+
+/// The maximum number of `ExitStatisticsRecord` read by the client after an `ExitStatisticsHeader`.
+pub const MAX_EXIT_STATISTICS_RECORDS: usize = 0x2000;
+
+/// The header written by `ReadExitStatistics` before the records.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatisticsHeader {
+    /// The frequency of the TSC in Hz, to convert the ticks.
+    pub tsc_frequency_hz: u64,
+    /// The number of logical processors whose statistics are included.
+    pub reported_processors: u64,
+    /// The number of `ExitStatisticsRecord` following the header, by logical processor then exit reason.
+    pub record_count: u64,
+    /// The number of records available, more than `record_count` if the buffer was too small.
+    pub total_record_count: u64,
+}
+
+/// The VM exits of a basic exit reason on a logical processor since its statistics were last reset.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatisticsRecord {
+    /// The initial APIC ID of the logical processor.
+    pub processor_id: u64,
+    /// The basic exit reason.
+    pub reason: u64,
+    /// The number of VM exits.
+    pub count: u64,
+    /// The total handling time of the VM exit handler, in TSC ticks, for the average.
+    pub total_ticks: u64,
+    /// The shortest handling time of the VM exit handler, in TSC ticks.
+    pub min_ticks: u64,
+    /// The longest handling time of the VM exit handler, in TSC ticks.
+    pub max_ticks: u64,
+}
+
+/// The statistics of an event ring written by the hypervisor for `ReadEventRingStats`, counted since the hypervisor started.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EventRingStats {
+    /// The number of events the ring holds before its backpressure policy applies.
+    pub capacity: u64,
+    /// The number of events in the ring, waiting to be drained.
+    pub length: u64,
+    /// The largest number of events the ring held.
+    pub high_watermark: u64,
+    /// The number of events added to the ring.
+    pub recorded_events: u64,
+    /// The number of new events dropped while the ring was full.
+    pub dropped_newest: u64,
+    /// The number of old events dropped to make room for new ones.
+    pub dropped_oldest: u64,
+    /// The number of events added beyond the capacity, for which the logical processor waited for the client.
+    pub stalled_events: u64,
+    /// The number of waits that timed out before the client drained the ring.
+    pub stall_timeouts: u64,
+    /// The total time the logical processors waited for the client, in TSC ticks.
+    pub stall_tsc_ticks: u64,
+}
+
+/// The number of techniques of the detection corpus, the variants of `DetectionTechnique`.
+pub const DETECTION_TECHNIQUE_COUNT: usize = 8;
+
+/// A public hypervisor-detection technique of the corpus evaluated by `RunDetectionCorpus`.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectionTechnique {
+    /// The hypervisor-present bit of CPUID leaf 1 (ECX bit 31) is set.
+    CpuidHypervisorBit = 0,
+    /// CPUID leaf 0x40000000 returns a hypervisor vendor signature instead of the results of the highest basic leaf.
+    CpuidHypervisorLeaf = 1,
+    /// CPUID, which causes a VM exit, takes longer between two RDTSC than on bare metal.
+    CpuidTiming = 2,
+    /// Reading a synthetic Hyper-V MSR doesn't raise #GP.
+    SyntheticMsr = 3,
+    /// IA32_FEATURE_CONTROL is unlocked or enables VMXON outside SMX, as the hypervisor needs.
+    FeatureControl = 4,
+    /// IA32_LSTAR returns the syscall trampoline of the hypervisor instead of the syscall entry of the kernel.
+    LstarHook = 5,
+    /// The debug registers expose the hardware breakpoints of the hypervisor.
+    DebugRegisters = 6,
+    /// The memory of the hypervisor isn't hidden from the guest in the EPT.
+    HypervisorMemory = 7,
+}
+
+impl DetectionTechnique {
+    /// Converts a `u64` value to a `DetectionTechnique` enum variant.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(DetectionTechnique::CpuidHypervisorBit),
+            1 => Some(DetectionTechnique::CpuidHypervisorLeaf),
+            2 => Some(DetectionTechnique::CpuidTiming),
+            3 => Some(DetectionTechnique::SyntheticMsr),
+            4 => Some(DetectionTechnique::FeatureControl),
+            5 => Some(DetectionTechnique::LstarHook),
+            6 => Some(DetectionTechnique::DebugRegisters),
+            7 => Some(DetectionTechnique::HypervisorMemory),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of a technique of the detection corpus.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectionVerdict {
+    /// The guest observes what it does on bare metal: the technique doesn't detect the hypervisor.
+    Pass = 0,
+    /// The technique detects the hypervisor.
+    Fail = 1,
+    /// The technique doesn't apply to the configuration, e.g., the hypervisor presence is exposed on purpose.
+    Skipped = 2,
+}
+
+impl DetectionVerdict {
+    /// Converts a `u64` value to a `DetectionVerdict` enum variant.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(DetectionVerdict::Pass),
+            1 => Some(DetectionVerdict::Fail),
+            2 => Some(DetectionVerdict::Skipped),
+            _ => None,
+        }
+    }
+}
+
+/// The header written by `RunDetectionCorpus` before the results.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectionCorpusHeader {
+    /// The number of `DetectionCheckResult` following the header, one per technique in `DetectionTechnique` order.
+    pub check_count: u64,
+    /// The number of techniques that passed.
+    pub passed: u64,
+    /// The number of techniques that detected the hypervisor.
+    pub failed: u64,
+    /// The number of techniques skipped.
+    pub skipped: u64,
+}
+
+/// The result of a technique of the detection corpus.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectionCheckResult {
+    /// The `DetectionTechnique`, as a u64.
+    pub technique: u64,
+    /// The `DetectionVerdict`, as a u64.
+    pub verdict: u64,
+    /// The value observed by the guest, specific to the technique.
+    pub observed: u64,
+    /// The value the guest observes on bare metal, or the threshold of the technique.
+    pub expected: u64,
+}
+
+/// The size of the NUL-padded command name of a `LinuxTask`, `TASK_COMM_LEN`.
+pub const LINUX_TASK_COMM_SIZE: usize = 16;
+
+/// The header written by `ReadLinuxTasks` before the tasks.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinuxTaskHeader {
+    /// The number of `LinuxTask` following the header, in the order of the list of tasks.
+    pub task_count: u64,
+    /// The number of tasks in the list, including those that didn't fit in the buffer.
+    pub total_tasks: u64,
+}
+
+/// A task of a Linux guest, a thread of a process or of the kernel.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinuxTask {
+    /// The virtual address of the `task_struct`.
+    pub task_va: u64,
+    /// The ID of the task.
+    pub pid: u64,
+    /// The ID of the process of the task, the ID of its first thread.
+    pub tgid: u64,
+    /// The CR3 of the address space of the task, or 0 for the kernel threads.
+    pub guest_cr3: u64,
+    /// The command name of the task.
+    pub comm: [u8; LINUX_TASK_COMM_SIZE],
+}
+
+/// The number of general-purpose registers of an `ExecutionTraceRecord`, in the order RAX, RCX, RDX, RBX, RSP, RBP,
+/// RSI, RDI, R8 to R15.
+pub const EXECUTION_TRACE_REGISTER_COUNT: usize = 16;
+
+/// The header written by `ReadExecutionTrace` before the records.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionTraceHeader {
+    /// The number of `ExecutionTraceRecord` following the header, oldest first.
+    pub record_count: u64,
+    /// The number of records dropped since the last `ReadExecutionTrace` because the trace buffer of the hypervisor was full.
+    pub dropped_records: u64,
+    /// The number of pages of the range currently traced, 0 once the trace has stopped.
+    pub traced_pages: u64,
+}
+
+/// An instruction of the traced range executed by the guest, recorded before it executes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionTraceRecord {
+    /// The TSC when the instruction was recorded.
+    pub tsc: u64,
+    /// The guest CR3 of the executing code.
+    pub cr3: u64,
+    /// The guest RIP of the instruction.
+    pub rip: u64,
+    /// The guest RFLAGS before the instruction, with the interrupt flag as the guest set it.
+    pub rflags: u64,
+    /// The general-purpose registers before the instruction, zeroed unless the trace captures the registers.
+    pub registers: [u64; EXECUTION_TRACE_REGISTER_COUNT],
+}
+
+/// The number of values of an `EventStreamRecord`, whose meaning depends on its `EventStreamKind`.
+pub const EVENT_STREAM_DATA_SIZE: usize = 4;
+
+/// The kinds of the events pushed to the event stream buffer, each selected by the bit of its value in the mask passed
+/// to `Hypercall::RegisterEventStream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum EventStreamKind {
+    /// A traced system call returned: the system call number, the process ID, the thread ID and the return value.
+    SyscallTrace = 0,
+    /// A hooked function with callbacks was entered: the virtual address of the function, the guest CR3, the guest RSP
+    /// and 0.
+    HookHit = 1,
+    /// The hooked bytes of a function were written: the virtual address of the function, the guest physical address
+    /// written to, the guest RIP and the guest CR3 of the writer.
+    HookTamper = 2,
+    /// A process was created (see `ConfigureOsEvents`): the process ID, the parent process ID, the virtual address of
+    /// the `_EPROCESS` and the directory table base.
+    ProcessCreated = 3,
+    /// An image was mapped (see `ConfigureOsEvents`): the process ID, 0 for a kernel image, the base address, the size
+    /// and the `IMAGE_INFO.Properties` of the image.
+    ImageLoaded = 4,
+    /// A monitored page of kernel code was modified (see `ConfigureCodeIntegrity`): the virtual address of the page,
+    /// the base address of its module, the guest physical address of the page and 0.
+    CodeIntegrityViolation = 5,
+    /// A denied access to a protected range (see `ProtectMemory`): the guest virtual address accessed, the guest
+    /// physical address, the guest RIP and the guest CR3 of the accessing context.
+    ProtectedMemoryAccess = 6,
+}
+
+/// The header at the start of the buffer registered with `Hypercall::RegisterEventStream`, followed by a ring of
+/// `capacity` `EventStreamRecord`.
+///
+/// The hypervisor reserves the record at `write_index % capacity` and publishes it by writing its `sequence` last, so
+/// the guest consumes the record at `read_index % capacity` once its `sequence` is `read_index + 1`, then increments
+/// `read_index`. A record isn't written while the ring is full, `write_index - read_index` being `capacity`, and is
+/// counted in `dropped_records`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventStreamHeader {
+    /// The number of records reserved since the registration, written by the hypervisor.
+    pub write_index: u64,
+    /// The number of records consumed since the registration, written by the guest.
+    pub read_index: u64,
+    /// The number of records of the ring, written by the hypervisor at the registration.
+    pub capacity: u64,
+    /// The size of a record, written by the hypervisor at the registration.
+    pub record_size: u64,
+    /// The number of records dropped because the ring was full, written by the hypervisor.
+    pub dropped_records: u64,
+}
+
+/// An event pushed to the event stream buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventStreamRecord {
+    /// The position of the record since the registration plus 1, written last once the record is complete.
+    pub sequence: u64,
+    /// The `EventStreamKind` of the event.
+    pub kind: u64,
+    /// The initial APIC ID of the logical processor the event was recorded on.
+    pub processor_id: u64,
+    /// The TSC when the event was recorded.
+    pub tsc: u64,
+    /// The values of the event, as described by its kind.
+    pub data: [u64; EVENT_STREAM_DATA_SIZE],
+}
+
+/// The header written by `ScanSignature` before the addresses of the matches, each a `u64`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureScanHeader {
+    /// The number of addresses following the header, in address order.
+    pub match_count: u64,
+    /// The address where a further scan resumes, equal to the end of the range once it has been scanned entirely.
+    pub next_address: u64,
+    /// The number of bytes scanned by this scan, including the pages skipped as not mapped or not scannable.
+    pub scanned_bytes: u64,
+}
+
+/// The size of the NUL-padded image name of a `WindowsProcess`, `_EPROCESS.ImageFileName` and its terminator.
+pub const WINDOWS_PROCESS_IMAGE_NAME_SIZE: usize = 16;
+
+/// The header written by `EnumerateProcesses` before the processes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsProcessHeader {
+    /// The number of `WindowsProcess` following the header, in the order of the list of processes.
+    pub process_count: u64,
+    /// The number of processes in the list, including those that didn't fit in the buffer.
+    pub total_processes: u64,
+}
+
+/// A process of a Windows guest.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsProcess {
+    /// The virtual address of the `_EPROCESS` structure.
+    pub eprocess: u64,
+    /// The ID of the process.
+    pub process_id: u64,
+    /// The directory table base (CR3) of the process.
+    pub directory_table_base: u64,
+    /// The virtual address of the PEB of the process, or 0 for the processes without user address space.
+    pub peb: u64,
+    /// The image name of the process, truncated to 15 characters by the kernel.
+    pub image_name: [u8; WINDOWS_PROCESS_IMAGE_NAME_SIZE],
+}
+
+/// The header written by `EnumerateThreads` before the threads.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsThreadHeader {
+    /// The number of `WindowsThread` following the header, in the order of the list of threads of the process.
+    pub thread_count: u64,
+    /// The number of threads of the process, including those that didn't fit in the buffer.
+    pub total_threads: u64,
+}
+
+/// A thread of a process of a Windows guest.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsThread {
+    /// The virtual address of the `_ETHREAD` structure.
+    pub ethread: u64,
+    /// The ID of the process of the thread.
+    pub process_id: u64,
+    /// The ID of the thread.
+    pub thread_id: u64,
+    /// The virtual address of the TEB of the thread, or 0 for the system threads.
+    pub teb: u64,
+}
+
+/// The size of the NUL-padded name of the file mapped by a `WindowsVad`, truncated if longer.
+pub const WINDOWS_VAD_FILE_NAME_SIZE: usize = 256;
+
+/// The header written by `EnumerateVads` before the VADs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsVadHeader {
+    /// The number of `WindowsVad` following the header, in address order.
+    pub vad_count: u64,
+    /// The number of VADs of the process, including those that didn't fit in the buffer.
+    pub total_vads: u64,
+}
+
+/// A VAD of a process of a Windows guest, a range of its virtual address space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsVad {
+    /// The virtual address of the `_MMVAD_SHORT` structure.
+    pub vad: u64,
+    /// The virtual address of the first page of the range.
+    pub start_address: u64,
+    /// The virtual address of the end of the range, exclusive.
+    pub end_address: u64,
+    /// The protection of the range at its allocation, an `MM_*` protection, e.g., 6 for `MM_EXECUTE_READWRITE`.
+    pub protection: u32,
+    /// The type of the VAD, e.g., 2 for `VadImageMap`.
+    pub vad_type: u32,
+    /// 1 if the range is private memory, 0 if it is a view of a section.
+    pub private_memory: u64,
+    /// The name of the file mapped by the range, empty for private memory and for the sections backed by the paging
+    /// file.
+    pub file_name: [u8; WINDOWS_VAD_FILE_NAME_SIZE],
+}
+
+impl WindowsVad {
+    /// Returns the size of the range in bytes.
+    pub fn size(&self) -> u64 {
+        self.end_address - self.start_address
+    }
+
+    /// Returns `true` if the protection of the range at its allocation allows executing it.
+    pub fn is_executable(&self) -> bool {
+        // The low 3 bits are MM_EXECUTE, MM_EXECUTE_READ, MM_EXECUTE_READWRITE or MM_EXECUTE_WRITECOPY.
+        matches!(self.protection & 0x7, 2 | 3 | 6 | 7)
+    }
+}
+
+/// The header written by `DumpProcessMemory` before the chunks.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessDumpHeader {
+    /// The number of chunks following the header, in address order, or written to the serial port.
+    pub chunk_count: u64,
+    /// The number of bytes following the header, the chunks and their bytes.
+    pub data_size: u64,
+    /// The address where a further dump resumes, equal to the end of the range once it has been dumped entirely.
+    pub next_address: u64,
+    /// The number of bytes of memory dumped by this command.
+    pub dumped_bytes: u64,
+}
+
+/// A chunk of a process memory dump, a range of mapped memory followed by its bytes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessDumpChunk {
+    /// The virtual address of the chunk.
+    pub address: u64,
+    /// The size of the chunk in bytes.
+    pub size: u64,
+}
+
+/// The size of the module name of a `KernelCallback`, in bytes.
+pub const KERNEL_CALLBACK_MODULE_NAME_SIZE: usize = 32;
+
+/// The header written by `EnumerateKernelCallbacks` before the callbacks.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelCallbackHeader {
+    /// The number of `KernelCallback` following the header.
+    pub callback_count: u64,
+    /// The number of callbacks registered, larger than `callback_count` if the buffer is too small.
+    pub total_callbacks: u64,
+}
+
+/// The kind of a `KernelCallback`, the notification it is registered for.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelCallbackKind {
+    /// A process creation and exit callback, `PsSetCreateProcessNotifyRoutine(Ex/Ex2)`.
+    ProcessNotify = 0,
+    /// A thread creation and exit callback, `PsSetCreateThreadNotifyRoutine(Ex)`.
+    ThreadNotify = 1,
+    /// An image load callback, `PsSetLoadImageNotifyRoutine(Ex)`.
+    LoadImageNotify = 2,
+    /// A registry callback, `CmRegisterCallback(Ex)`.
+    Registry = 3,
+    /// A pre-operation callback of the process handles, `ObRegisterCallbacks` on `PsProcessType`.
+    ProcessHandlePreOperation = 4,
+    /// A post-operation callback of the process handles, `ObRegisterCallbacks` on `PsProcessType`.
+    ProcessHandlePostOperation = 5,
+    /// A pre-operation callback of the thread handles, `ObRegisterCallbacks` on `PsThreadType`.
+    ThreadHandlePreOperation = 6,
+    /// A post-operation callback of the thread handles, `ObRegisterCallbacks` on `PsThreadType`.
+    ThreadHandlePostOperation = 7,
+}
+
+impl KernelCallbackKind {
+    /// Converts a `u64` value to a `KernelCallbackKind` enum variant.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(KernelCallbackKind::ProcessNotify),
+            1 => Some(KernelCallbackKind::ThreadNotify),
+            2 => Some(KernelCallbackKind::LoadImageNotify),
+            3 => Some(KernelCallbackKind::Registry),
+            4 => Some(KernelCallbackKind::ProcessHandlePreOperation),
+            5 => Some(KernelCallbackKind::ProcessHandlePostOperation),
+            6 => Some(KernelCallbackKind::ThreadHandlePreOperation),
+            7 => Some(KernelCallbackKind::ThreadHandlePostOperation),
+            _ => None,
+        }
+    }
+}
+
+/// A notification callback registered in the kernel of a Windows guest.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelCallback {
+    /// The `KernelCallbackKind` of the callback.
+    pub kind: u64,
+    /// The virtual address of the callback function.
+    pub function: u64,
+    /// The virtual address of the registration, the `_EX_CALLBACK_ROUTINE_BLOCK` of the notify routines, the
+    /// `_CM_CALLBACK_ENTRY` of the registry callbacks or the `_OB_CALLBACK_ENTRY` of the handle callbacks.
+    pub registration: u64,
+    /// The context passed to the callback, or the flags of a notify routine, e.g., whether it was registered by
+    /// `PsSetCreateProcessNotifyRoutineEx`.
+    pub context: u64,
+    /// The cookie of a registry callback, or the `OB_OPERATION` flags of a handle callback, 0 for the notify
+    /// routines.
+    pub cookie: u64,
+    /// 1 if the handle callback is enabled, always 1 for the other callbacks.
+    pub enabled: u64,
+    /// The base virtual address of the loaded module containing the function, 0 if it isn't in a loaded module, e.g.,
+    /// in a pool allocation.
+    pub module_base: u64,
+    /// The base name of the loaded module containing the function, truncated and NUL-padded, empty if it isn't in a
+    /// loaded module.
+    pub module_name: [u8; KERNEL_CALLBACK_MODULE_NAME_SIZE],
+}
+
+impl KernelCallback {
+    /// Returns `true` if the function isn't in the image of a loaded module, which is how callbacks registered by
+    /// manually mapped drivers look.
+    pub fn is_unbacked(&self) -> bool {
+        self.module_base == 0
     }
 }
@@ -0,0 +1,83 @@
+//! Provides the selection of the benchmark run of this boot (see `hypervisor::intel::benchmark`).
+//!
+//! The plan is read from the `IllusionBenchmarkPlan` UEFI variable if it exists, or from the `BENCH.TXT` file in the
+//! root directory of the partition the hypervisor has been loaded from otherwise. The index of the next run is kept
+//! in the `IllusionBenchmarkRun` UEFI variable, a 32-bit little-endian integer, and advanced before the run starts,
+//! so a run hanging the system is not repeated on the next boot. Once all the runs are done, the system boots without
+//! benchmark until the variable is deleted, e.g., with `dmpstore -d` from the UEFI shell, to run the plan again.
+
+use {
+    crate::manifest::{read_esp_file, ILLUSION_VARIABLE_VENDOR},
+    hypervisor::intel::benchmark::BenchmarkPlan,
+    log::*,
+    uefi::{cstr16, prelude::*, table::runtime::VariableAttributes, CStr16},
+};
+
+/// The name of the UEFI variable holding the plan.
+const PLAN_VARIABLE_NAME: &CStr16 = cstr16!("IllusionBenchmarkPlan");
+
+/// The name of the UEFI variable holding the index of the next run.
+const RUN_VARIABLE_NAME: &CStr16 = cstr16!("IllusionBenchmarkRun");
+
+/// The name of the plan file in the root directory of the ESP.
+const PLAN_FILE_NAME: &CStr16 = cstr16!("BENCH.TXT");
+
+/// The maximum size of the plan in bytes.
+const MAX_PLAN_SIZE: u64 = 0x1000;
+
+/// Reads the benchmark plan, if there is one, and arms its next run.
+///
+/// This must be called before `ExitBootServices`, after the boot-time hook manifest is read.
+///
+/// # Arguments
+///
+/// * `system_table` - A reference to the UEFI System Table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating success or failure, `Status::INVALID_PARAMETER` if the plan is malformed.
+pub fn setup_benchmark(system_table: &SystemTable<Boot>) -> uefi::Result<()> {
+    let runtime_services = system_table.runtime_services();
+
+    let plan = match runtime_services.get_variable_boxed(PLAN_VARIABLE_NAME, &ILLUSION_VARIABLE_VENDOR) {
+        Ok((plan, _)) => {
+            debug!("Read the benchmark plan from the {} variable", PLAN_VARIABLE_NAME);
+            plan.into_vec()
+        }
+        Err(e) if e.status() == Status::NOT_FOUND => match read_esp_file(system_table.boot_services(), PLAN_FILE_NAME, MAX_PLAN_SIZE)? {
+            Some(plan) => plan,
+            None => {
+                debug!("No benchmark plan found");
+                return Ok(());
+            }
+        },
+        Err(e) => return Err(e),
+    };
+
+    let text = core::str::from_utf8(&plan).map_err(|_| Status::INVALID_PARAMETER)?;
+    let plan = BenchmarkPlan::parse(text).map_err(|_| Status::INVALID_PARAMETER)?;
+
+    let mut run_index = [0u8; 4];
+    match runtime_services.get_variable(RUN_VARIABLE_NAME, &ILLUSION_VARIABLE_VENDOR, &mut run_index) {
+        Ok(_) => {}
+        Err(e) if e.status() == Status::NOT_FOUND => {}
+        Err(e) => return Err(e.to_err_without_payload()),
+    }
+    let run_index = u32::from_le_bytes(run_index) as usize;
+
+    if run_index >= plan.run_count() {
+        info!("Benchmark complete: all {} runs are done", plan.run_count());
+        return Ok(());
+    }
+
+    runtime_services.set_variable(
+        RUN_VARIABLE_NAME,
+        &ILLUSION_VARIABLE_VENDOR,
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &(run_index as u32 + 1).to_le_bytes(),
+    )?;
+
+    plan.arm(run_index).map_err(|_| Status::INVALID_PARAMETER)?;
+
+    Ok(())
+}
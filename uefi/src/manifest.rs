@@ -0,0 +1,105 @@
+//! Provides the loading of the boot-time hook manifest, installed automatically by the hypervisor once the kernel is
+//! ready (see `hypervisor::intel::hooks::boot_manifest`).
+//!
+//! The manifest is read from the `IllusionHookManifest` UEFI variable if it exists, e.g., set with `dmpstore` or
+//! `setvar` from the UEFI shell, or from the `HOOKS.TXT` file in the root directory of the partition the hypervisor
+//! has been loaded from otherwise.
+
+use {
+    alloc::{vec, vec::Vec},
+    hypervisor::intel::hooks::boot_manifest::BootHookManifest,
+    log::*,
+    uefi::{
+        cstr16, guid,
+        prelude::*,
+        proto::media::file::{File, FileAttribute, FileInfo, FileMode},
+        table::runtime::VariableVendor,
+        CStr16,
+    },
+};
+
+/// The name of the UEFI variable holding the manifest.
+const MANIFEST_VARIABLE_NAME: &CStr16 = cstr16!("IllusionHookManifest");
+
+/// The vendor GUID of the UEFI variables of the hypervisor, holding the manifest and the benchmark state.
+pub const ILLUSION_VARIABLE_VENDOR: VariableVendor = VariableVendor(guid!("5c7b1d22-3f4e-4a8b-9c61-0e2d8a4f7b13"));
+
+/// The name of the manifest file in the root directory of the ESP.
+const MANIFEST_FILE_NAME: &CStr16 = cstr16!("HOOKS.TXT");
+
+/// The maximum size of the manifest in bytes.
+const MAX_MANIFEST_SIZE: u64 = 0x10000;
+
+/// Reads the boot-time hook manifest and hands it to the hypervisor, if there is one.
+///
+/// This must be called before `ExitBootServices`.
+///
+/// # Arguments
+///
+/// * `system_table` - A reference to the UEFI System Table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating success or failure, `Status::INVALID_PARAMETER` if the manifest is malformed.
+pub fn setup_hook_manifest(system_table: &SystemTable<Boot>) -> uefi::Result<()> {
+    let manifest = match system_table
+        .runtime_services()
+        .get_variable_boxed(MANIFEST_VARIABLE_NAME, &ILLUSION_VARIABLE_VENDOR)
+    {
+        Ok((manifest, _)) => {
+            debug!("Read the hook manifest from the {} variable", MANIFEST_VARIABLE_NAME);
+            manifest.into_vec()
+        }
+        Err(e) if e.status() == Status::NOT_FOUND => match read_esp_file(system_table.boot_services(), MANIFEST_FILE_NAME, MAX_MANIFEST_SIZE)? {
+            Some(manifest) => manifest,
+            None => {
+                debug!("No hook manifest found");
+                return Ok(());
+            }
+        },
+        Err(e) => return Err(e),
+    };
+
+    let text = core::str::from_utf8(&manifest).map_err(|_| Status::INVALID_PARAMETER)?;
+    let hook_count = BootHookManifest::initialize_shared_boot_hook_manifest(text).map_err(|_| Status::INVALID_PARAMETER)?;
+
+    info!("Hook manifest loaded: {} hooks", hook_count);
+
+    Ok(())
+}
+
+/// Reads a file from the root directory of the ESP, e.g., the manifest.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+/// * `file_name` - The name of the file.
+/// * `max_size` - The maximum size of the file in bytes.
+///
+/// # Returns
+///
+/// The contents of the file, or `None` if it doesn't exist.
+pub fn read_esp_file(boot_services: &BootServices, file_name: &CStr16, max_size: u64) -> uefi::Result<Option<Vec<u8>>> {
+    let mut file_system = boot_services.get_image_file_system(boot_services.image_handle())?;
+    let mut root = file_system.open_volume()?;
+
+    let mut file = match root.open(file_name, FileMode::Read, FileAttribute::empty()) {
+        Ok(file) => file.into_regular_file().ok_or(Status::UNSUPPORTED)?,
+        Err(e) if e.status() == Status::NOT_FOUND => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let file_size = file.get_boxed_info::<FileInfo>()?.file_size();
+    if file_size > max_size {
+        error!("{} is larger than {:#x} bytes", file_name, max_size);
+        return Err(Status::BAD_BUFFER_SIZE.into());
+    }
+
+    let mut contents = vec![0u8; file_size as usize];
+    let length = file.read(&mut contents)?;
+    contents.truncate(length);
+
+    debug!("Read {} from the ESP", file_name);
+
+    Ok(Some(contents))
+}
@@ -0,0 +1,118 @@
+//! Provides the setup of the configuration file on the EFI System Partition (ESP) at boot, re-applying the
+//! configuration saved by the client on a previous boot (see `hypervisor::persistence`).
+//!
+//! The file is created in the root directory of the partition the hypervisor has been loaded from, filled with zeros
+//! so all of its clusters are allocated, and its sectors are resolved (see the `esp` module), so the hypervisor can
+//! save the configuration to it after `ExitBootServices`. Deleting the file, or discarding the configuration with the
+//! client, boots with the configuration selected at build time and the boot-time hook manifest.
+
+use {
+    crate::esp::resolve_esp_file,
+    alloc::vec,
+    hypervisor::{
+        ahci::SECTOR_SIZE,
+        persistence::{apply_saved_configuration, ConfigFile, CONFIG_MAGIC},
+    },
+    log::*,
+    uefi::{
+        cstr16,
+        prelude::*,
+        proto::media::file::{File, FileAttribute, FileInfo, FileMode},
+        CStr16,
+    },
+};
+
+/// The name of the configuration file in the root directory of the ESP.
+const CONFIG_FILE_NAME: &CStr16 = cstr16!("CONFIG.BIN");
+
+/// The 8.3 short name of the configuration file, as stored in its directory entry.
+const CONFIG_FILE_SHORT_NAME: &[u8; 11] = b"CONFIG  BIN";
+
+/// The size of the configuration file in bytes, including the header sector (64KB).
+const CONFIG_FILE_SIZE: u64 = 0x1_0000;
+
+/// Re-applies the configuration saved in the configuration file, if any, then creates the file if needed, resolves
+/// the disk sectors backing it and hands it to the hypervisor.
+///
+/// This must be called before `ExitBootServices`, after the boot-time hook manifest is read, as a saved manifest
+/// replaces it.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating success or failure, `Status::INVALID_PARAMETER` if the saved configuration is
+/// malformed or can't be applied, in which case the file is still handed to the hypervisor so it can be saved again.
+pub fn setup_persistent_config(boot_services: &BootServices) -> uefi::Result<()> {
+    let applied = read_config_file(boot_services);
+
+    let extents = resolve_esp_file(boot_services, CONFIG_FILE_SHORT_NAME, CONFIG_FILE_SIZE)?;
+    debug!("Configuration file extents: {:#x?}", extents);
+
+    ConfigFile::initialize_shared_config_file(extents);
+
+    applied
+}
+
+/// Creates the configuration file with an empty header, or reads and applies the configuration it holds if it already
+/// exists.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating success or failure.
+fn read_config_file(boot_services: &BootServices) -> uefi::Result<()> {
+    let mut file_system = boot_services.get_image_file_system(boot_services.image_handle())?;
+    let mut root = file_system.open_volume()?;
+    let mut file = root
+        .open(CONFIG_FILE_NAME, FileMode::CreateReadWrite, FileAttribute::empty())?
+        .into_regular_file()
+        .ok_or(Status::UNSUPPORTED)?;
+
+    let mut header = [0u8; SECTOR_SIZE];
+
+    if file.get_boxed_info::<FileInfo>()?.file_size() == CONFIG_FILE_SIZE {
+        file.read(&mut header)?;
+    }
+
+    if header[..8] == CONFIG_MAGIC {
+        let capacity = CONFIG_FILE_SIZE - SECTOR_SIZE as u64;
+        let length = u64::from_le_bytes(header[8..16].try_into().unwrap()).min(capacity);
+
+        if length == 0 {
+            debug!("No saved configuration found");
+            return Ok(());
+        }
+
+        let mut text = vec![0u8; length as usize];
+        file.read(&mut text)?;
+
+        let text = core::str::from_utf8(&text).map_err(|_| Status::INVALID_PARAMETER)?;
+        apply_saved_configuration(text).map_err(|_| Status::INVALID_PARAMETER)?;
+
+        info!("Saved configuration applied: {:#x} bytes", length);
+        return Ok(());
+    }
+
+    // Write the whole file so all of its clusters are allocated, starting with an empty header.
+    debug!("Creating configuration file of {:#x} bytes", CONFIG_FILE_SIZE);
+    header.fill(0);
+    header[..8].copy_from_slice(&CONFIG_MAGIC);
+
+    file.set_position(0)?;
+    file.write(&header).map_err(|e| e.status())?;
+
+    let zeros = [0u8; SECTOR_SIZE];
+    for _ in 1..CONFIG_FILE_SIZE / SECTOR_SIZE as u64 {
+        file.write(&zeros).map_err(|e| e.status())?;
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
@@ -3,14 +3,31 @@
 
 use {
     crate::virtualize::virtualize_system,
-    core::ffi::c_void,
+    alloc::vec::Vec,
+    core::{ffi::c_void, time::Duration},
     hypervisor::intel::capture::{capture_registers, GuestRegisters},
     log::*,
+    spin::Mutex,
     uefi::{prelude::*, proto::pi::mp::MpServices},
 };
 
+/// The maximum amount of time a single Application Processor (AP) is given to virtualize itself.
+/// If the AP does not return from the startup procedure within this period, the MP Services
+/// protocol terminates the procedure on that AP and the boot continues with the remaining processors.
+const AP_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Records the processor numbers and statuses of the APs that failed to virtualize.
+///
+/// This is populated by `start_hypervisor_on_all_processors` and can be queried after startup
+/// to find out which logical processors are not running under the hypervisor.
+pub static FAILED_PROCESSORS: Mutex<Vec<(usize, Status)>> = Mutex::new(Vec::new());
+
 /// Starts the hypervisor on all processors.
 ///
+/// Each Application Processor (AP) is started individually with a timeout. If an AP fails or times out,
+/// the failure is recorded in `FAILED_PROCESSORS` and the remaining processors are still virtualized,
+/// instead of aborting the whole boot because of a single misbehaving core.
+///
 /// # Arguments
 ///
 /// * `boot_services` - A reference to the UEFI Boot Services.
@@ -26,6 +43,9 @@ pub fn start_hypervisor_on_all_processors(boot_services: &BootServices) -> uefi:
     info!("Total processors: {}", processor_count.total);
     info!("Enabled processors: {}", processor_count.enabled);
 
+    // The BSP is counted as virtualized, as a failure to virtualize it doesn't return.
+    let mut virtualized_processors = 1;
+
     if processor_count.enabled == 1 {
         info!("Found only one processor, virtualizing it");
         start_hypervisor();
@@ -36,14 +56,67 @@ pub fn start_hypervisor_on_all_processors(boot_services: &BootServices) -> uefi:
         start_hypervisor();
 
         // Virtualize all other threads...
-        mp_services.startup_all_aps(true, start_hypervisor_on_ap as _, core::ptr::null_mut(), None, None)?;
+        virtualized_processors += start_hypervisor_on_aps(&mp_services, processor_count.total);
     }
 
-    info!("The hypervisor has been installed successfully!");
+    if virtualized_processors >= processor_count.enabled {
+        info!("The hypervisor has been installed successfully!");
+    } else {
+        warn!(
+            "The hypervisor has been installed on {} out of {} enabled processors",
+            virtualized_processors,
+            processor_count.enabled
+        );
+    }
 
     Ok(())
 }
 
+/// Starts the hypervisor on every enabled Application Processor (AP), one at a time.
+///
+/// # Arguments
+///
+/// * `mp_services` - A reference to the MP Services protocol.
+/// * `total_processors` - The total number of logical processors in the system.
+///
+/// # Returns
+///
+/// The number of APs the hypervisor has been started on.
+fn start_hypervisor_on_aps(mp_services: &MpServices, total_processors: usize) -> usize {
+    let mut virtualized_aps = 0;
+
+    for processor_number in 0..total_processors {
+        match mp_services.get_processor_info(processor_number) {
+            // The BSP has already been virtualized and disabled processors can't be started.
+            Ok(info) if info.is_bsp() || !info.is_enabled() => continue,
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to get processor info for processor {}: {:?}", processor_number, e.status());
+                FAILED_PROCESSORS.lock().push((processor_number, e.status()));
+                continue;
+            }
+        }
+
+        debug!("Virtualizing processor {}", processor_number);
+
+        if let Err(e) = mp_services.startup_this_ap(
+            processor_number,
+            start_hypervisor_on_ap as _,
+            core::ptr::null_mut(),
+            None,
+            Some(AP_STARTUP_TIMEOUT),
+        ) {
+            // `Status::TIMEOUT` means the procedure was terminated on the AP before it returned.
+            error!("Failed to virtualize processor {}: {:?}", processor_number, e.status());
+            FAILED_PROCESSORS.lock().push((processor_number, e.status()));
+        } else {
+            virtualized_aps += 1;
+        }
+    }
+
+    virtualized_aps
+}
+
 /// Hypervisor initialization procedure for Application Processors (APs).
 ///
 /// # Arguments
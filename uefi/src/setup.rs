@@ -3,19 +3,42 @@
 //! physical to virtual addressing. This is useful for ensuring a stable memory layout in hypervisor development.
 
 use {
-    alloc::boxed::Box,
+    crate::stack::reserve_node_host_stack,
+    alloc::{boxed::Box, vec::Vec},
+    core::ptr::write_bytes,
     hypervisor::{
+        acpi::MemoryAffinity,
         allocator::box_zeroed,
+        error::HypervisorError,
+        global_const::{HOST_STACK_GUARD_PAGES, STACK_PAGES_PER_PROCESSOR},
         intel::{
-            hooks::hook_manager::{HookManager, SHARED_HOOK_MANAGER},
+            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            host_config::{HostConfig, SHARED_HOST_CONFIG},
+            host_stack::SHARED_HOST_STACKS,
+            numa::SHARED_NUMA_TOPOLOGY,
             page::Page,
+            paging::IDENTITY_MAP_SIZE,
+            sleep::{SleepResume, TRAMPOLINE_MAX_PA, TRAMPOLINE_PAGES},
         },
     },
-    log::debug,
-    uefi::{prelude::BootServices, proto::loaded_image::LoadedImage},
+    log::{debug, warn},
+    uefi::{
+        prelude::BootServices,
+        proto::loaded_image::LoadedImage,
+        table::boot::{AllocateType, MemoryType, PAGE_SIZE},
+        Status,
+    },
 };
 
-/// Sets up the hypervisor by recording the image base, creating a dummy page, initializing the shared hook manager, and nullifying relocations.
+/// The number of pages given to the hook page pool at load time (2MB, 512 shadow pages or page tables).
+/// The pool grows from the host heap once these are used up, this only avoids depleting the heap.
+const HOOK_PAGE_POOL_PAGES: usize = 0x200;
+
+/// The number of pages given to the hook page pool in the memory of each node on a multi-socket system (1MB).
+const NODE_HOOK_PAGE_POOL_PAGES: usize = 0x100;
+
+/// Sets up the hypervisor by recording the image base, creating the dummy and shared pages, initializing the shared host configuration,
+/// allocating the hook page pool, and nullifying relocations.
 ///
 /// # Arguments
 ///
@@ -29,7 +52,10 @@ pub fn setup(boot_services: &BootServices) -> uefi::Result<()> {
     record_image_base(&loaded_image);
 
     let dummpy_page_pa = create_dummy_page(0xFF);
-    HookManager::initialize_shared_hook_manager(dummpy_page_pa);
+    let shared_page_pa = allocate_shared_page(boot_services, &loaded_image)?;
+    HostConfig::initialize_shared_host_config(dummpy_page_pa, shared_page_pa);
+
+    allocate_hook_page_pool(boot_services, &loaded_image)?;
 
     let image_base = loaded_image.info().0 as u64;
     zap_relocations(image_base);
@@ -50,9 +76,247 @@ pub fn record_image_base(loaded_image: &LoadedImage) {
     let image_range = image_base as usize..(image_base as usize + image_size as usize);
     debug!("Loaded image base: {:#x?}", image_range);
 
-    // Lock the shared hook manager
-    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
-    hook_manager.record_allocation(image_base as usize, image_size as usize);
+    // Lock the shared host configuration for writing
+    let mut host_config = SHARED_HOST_CONFIG.write();
+    host_config.record_allocation(image_base as usize, image_size as usize);
+}
+
+/// Allocates the communication page shared with the guest client (see `shared::SharedPage`).
+///
+/// The page is allocated on its own with the same memory type as the loaded image, so it persists after
+/// `ExitBootServices` for runtime drivers, zeroed, and recorded so its identity mapping is hidden from the guest.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+/// * `loaded_image` - A reference to the loaded UEFI image.
+///
+/// # Returns
+///
+/// Returns the physical address of the page.
+pub fn allocate_shared_page(boot_services: &BootServices, loaded_image: &LoadedImage) -> uefi::Result<u64> {
+    let shared_page_pa = boot_services.allocate_pages(AllocateType::AnyPages, loaded_image.data_type(), 1)?;
+    debug!("Shared page: {:#x}", shared_page_pa);
+
+    unsafe { write_bytes(shared_page_pa as *mut u8, 0, PAGE_SIZE) };
+
+    SHARED_HOST_CONFIG.write().record_allocation(shared_page_pa as usize, PAGE_SIZE);
+
+    Ok(shared_page_pa)
+}
+
+/// Allocates the pages of the hook page pool and hands them to the memory manager.
+///
+/// The pages are allocated with the same memory type as the loaded image, so they persist after
+/// `ExitBootServices` for runtime drivers, and are recorded so they are hidden from the guest.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+/// * `loaded_image` - A reference to the loaded UEFI image.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating success or failure.
+pub fn allocate_hook_page_pool(boot_services: &BootServices, loaded_image: &LoadedImage) -> uefi::Result<()> {
+    let pool_pa = boot_services.allocate_pages(AllocateType::AnyPages, loaded_image.data_type(), HOOK_PAGE_POOL_PAGES)?;
+    debug!("Hook page pool: {:#x} ({} pages)", pool_pa, HOOK_PAGE_POOL_PAGES);
+
+    SHARED_HOST_CONFIG
+        .write()
+        .record_allocation(pool_pa as usize, HOOK_PAGE_POOL_PAGES * PAGE_SIZE);
+
+    SHARED_HOOK_MANAGER.lock().memory_manager.refill_page_pool(pool_pa, HOOK_PAGE_POOL_PAGES);
+
+    Ok(())
+}
+
+/// Places the memory used on the hot paths of the logical processors in their node, on a multi-socket system.
+///
+/// Pages of the memory of each node are given to the hook page pool, and the host stack of each logical processor is
+/// allocated in its node and reserved for it. All of them are pre-touched, allocated with the same memory type as the
+/// loaded image and recorded so they are hidden from the guest. The memory is allocated from the conventional memory
+/// of the UEFI memory map in the ranges of the node, the node falling back to the memory allocated by default otherwise.
+///
+/// This must be called after the NUMA topology has been read and before the processors are virtualized.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating success or failure.
+pub fn setup_numa_placement(boot_services: &BootServices) -> uefi::Result<()> {
+    let topology = SHARED_NUMA_TOPOLOGY.read();
+    if !topology.is_multi_node() {
+        debug!("Single NUMA node, the memory isn't placed");
+        return Ok(());
+    }
+
+    let loaded_image = boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())?;
+    let memory_type = loaded_image.data_type();
+    let stack_pages = STACK_PAGES_PER_PROCESSOR + HOST_STACK_GUARD_PAGES;
+
+    for proximity_domain in topology.domains() {
+        let memory_ranges = topology.memory_ranges(proximity_domain);
+
+        match allocate_node_pages(boot_services, memory_type, &memory_ranges, NODE_HOOK_PAGE_POOL_PAGES) {
+            Some(pool_pa) => {
+                SHARED_HOST_CONFIG
+                    .write()
+                    .record_allocation(pool_pa as usize, NODE_HOOK_PAGE_POOL_PAGES * PAGE_SIZE);
+
+                SHARED_HOOK_MANAGER
+                    .lock()
+                    .memory_manager
+                    .refill_node_page_pool(proximity_domain, pool_pa, NODE_HOOK_PAGE_POOL_PAGES);
+            }
+            None => warn!("Failed to allocate the hook page pool of the NUMA node {}", proximity_domain),
+        }
+
+        for apic_id in topology.processors(proximity_domain) {
+            let Some(stack_pa) = allocate_node_pages(boot_services, memory_type, &memory_ranges, stack_pages) else {
+                warn!("Failed to allocate the host stack of the processor {} in the NUMA node {}", apic_id, proximity_domain);
+                continue;
+            };
+
+            if !reserve_node_host_stack(apic_id, stack_pa) {
+                unsafe { boot_services.free_pages(stack_pa, stack_pages)? };
+                continue;
+            }
+
+            unsafe { write_bytes(stack_pa as *mut u8, 0, stack_pages * PAGE_SIZE) };
+            SHARED_HOST_CONFIG.write().record_allocation(stack_pa as usize, stack_pages * PAGE_SIZE);
+        }
+
+        debug!("NUMA node {}: {} memory ranges", proximity_domain, memory_ranges.len());
+    }
+
+    Ok(())
+}
+
+/// Allocates physically contiguous pages in the memory of a node, from the conventional memory of the UEFI memory map
+/// in the ranges of the node that are identity mapped by the host.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+/// * `memory_type` - The memory type of the allocation.
+/// * `memory_ranges` - The ranges of physical memory of the node.
+/// * `page_count` - The number of pages to allocate.
+///
+/// # Returns
+///
+/// An `Option` containing the physical address of the first page, `None` if the node has no large enough free range.
+fn allocate_node_pages(boot_services: &BootServices, memory_type: MemoryType, memory_ranges: &[MemoryAffinity], page_count: usize) -> Option<u64> {
+    let size = (page_count * PAGE_SIZE) as u64;
+
+    // The candidates are collected first, as the memory map is stale once a page is allocated.
+    let candidates: Vec<u64> = {
+        let memory_map = boot_services.memory_map(MemoryType::LOADER_DATA).ok()?;
+
+        memory_map
+            .entries()
+            .filter(|descriptor| descriptor.ty == MemoryType::CONVENTIONAL)
+            .flat_map(|descriptor| {
+                memory_ranges.iter().filter_map(move |range| {
+                    let start = descriptor.phys_start.max(range.base_pa).next_multiple_of(PAGE_SIZE as u64);
+                    let end = (descriptor.phys_start + descriptor.page_count * PAGE_SIZE as u64)
+                        .min(range.base_pa.saturating_add(range.length))
+                        .min(IDENTITY_MAP_SIZE);
+
+                    (end.saturating_sub(start) >= size).then_some(start)
+                })
+            })
+            .collect()
+    };
+
+    candidates
+        .into_iter()
+        .find_map(|pa| boot_services.allocate_pages(AllocateType::Address(pa), memory_type, page_count).ok())
+}
+
+/// Finds the highest physical address of the memory described by the UEFI memory map, excluding MMIO.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// Returns the address following the last byte of memory.
+pub fn highest_memory_address(boot_services: &BootServices) -> uefi::Result<u64> {
+    let memory_map = boot_services.memory_map(MemoryType::LOADER_DATA)?;
+
+    let highest_pa = memory_map
+        .entries()
+        .filter(|descriptor| descriptor.ty != MemoryType::MMIO && descriptor.ty != MemoryType::MMIO_PORT_SPACE)
+        .map(|descriptor| descriptor.phys_start + descriptor.page_count * PAGE_SIZE as u64)
+        .max()
+        .unwrap_or(0);
+
+    debug!("Highest memory address: {:#x}", highest_pa);
+
+    Ok(highest_pa)
+}
+
+/// Allocates the trampoline and the stack virtualizing the processor again on the wake from S3, and installs them.
+///
+/// The trampoline is entered in real mode, so its pages are allocated below 1MB. Both are allocated with the same
+/// memory type as the loaded image and recorded so they are hidden from the guest, unless the resume from sleep is
+/// unsupported, in which case they are freed.
+///
+/// This must be called after the ACPI tables have been parsed.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating success or failure.
+pub fn setup_sleep_resume(boot_services: &BootServices) -> uefi::Result<()> {
+    let loaded_image = boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())?;
+
+    let trampoline_pa = boot_services.allocate_pages(AllocateType::MaxAddress(TRAMPOLINE_MAX_PA), loaded_image.data_type(), TRAMPOLINE_PAGES)?;
+    // The stack is preceded by its guard pages, unmapped from the host paging once the processor is virtualized again.
+    let stack_pages = STACK_PAGES_PER_PROCESSOR + HOST_STACK_GUARD_PAGES;
+    let stack_pa = match boot_services.allocate_pages(AllocateType::AnyPages, loaded_image.data_type(), stack_pages) {
+        Ok(stack_pa) => stack_pa,
+        Err(e) => {
+            unsafe { boot_services.free_pages(trampoline_pa, TRAMPOLINE_PAGES)? };
+            return Err(e);
+        }
+    };
+    let stack_size = stack_pages * PAGE_SIZE;
+
+    if let Err(e) = SleepResume::initialize_shared_sleep_resume(trampoline_pa, stack_pa + stack_size as u64) {
+        debug!("Resume from sleep unavailable: {:?}", e);
+        unsafe {
+            boot_services.free_pages(stack_pa, stack_pages)?;
+            boot_services.free_pages(trampoline_pa, TRAMPOLINE_PAGES)?;
+        }
+
+        return Err(match e {
+            HypervisorError::AcpiTableNotFound => Status::NOT_FOUND,
+            _ => Status::UNSUPPORTED,
+        }
+        .into());
+    }
+
+    debug!("Sleep trampoline: {:#x}, resume stack: {:#x} ({} pages)", trampoline_pa, stack_pa, stack_pages);
+
+    SHARED_HOST_STACKS
+        .lock()
+        .register_host_stack(stack_pa, stack_size as u64)
+        .map_err(|_| Status::UNSUPPORTED)?;
+
+    let mut host_config = SHARED_HOST_CONFIG.write();
+    host_config.record_allocation(trampoline_pa as usize, TRAMPOLINE_PAGES * PAGE_SIZE);
+    host_config.record_allocation(stack_pa as usize, stack_size);
+
+    Ok(())
 }
 
 /// Creates a dummy page filled with a specific byte value.
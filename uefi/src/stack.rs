@@ -3,9 +3,9 @@ use {
         alloc::Layout,
         ffi::c_void,
         ptr,
-        sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+        sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering},
     },
-    hypervisor::intel::hooks::hook_manager::SHARED_HOOK_MANAGER,
+    hypervisor::intel::host_config::SHARED_HOST_CONFIG,
     uefi::{
         prelude::{Boot, BootServices, SystemTable},
         proto::loaded_image::LoadedImage,
@@ -23,6 +23,13 @@ static SYSTEM_TABLE: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
 /// The memory type used for pool memory allocations.
 static MEMORY_TYPE: AtomicU32 = AtomicU32::new(MemoryType::LOADER_DATA.0);
 
+/// The number of APIC IDs a host stack can be reserved for.
+const MAX_RESERVED_STACKS: usize = 0x100;
+
+/// The host stacks allocated in the node of each logical processor on a multi-socket system, by APIC ID, 0 for the
+/// logical processors allocating their host stack when they're virtualized.
+static NODE_HOST_STACKS: [AtomicU64; MAX_RESERVED_STACKS] = [const { AtomicU64::new(0) }; MAX_RESERVED_STACKS];
+
 /// Initializes the allocator.
 ///
 /// # Safety
@@ -89,13 +96,56 @@ pub unsafe fn allocate_host_stack(layout: Layout) -> *mut u8 {
             .unwrap_or(ptr::null_mut())
     };
 
-    // Lock the shared hook manager
-    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
-    hook_manager.record_allocation(stack as usize, layout.size());
+    // Lock the shared host configuration for writing
+    let mut host_config = SHARED_HOST_CONFIG.write();
+    host_config.record_allocation(stack as usize, layout.size());
+    drop(host_config);
+
+    // Stacks allocated after DMA protection has been enabled must be excluded from device DMA as well.
+    #[cfg(feature = "dma_protection")]
+    if hypervisor::intel::vtd::protect_hypervisor_range(stack as u64, layout.size() as u64).is_err() {
+        log::error!("Failed to protect the host stack from DMA");
+    }
 
     stack
 }
 
+/// Reserves a host stack allocated in the node of a logical processor, taken when the logical processor is virtualized.
+///
+/// # Arguments
+///
+/// * `apic_id` - The APIC ID of the logical processor.
+/// * `stack` - The address of the host stack, recorded in `HostConfig`.
+///
+/// # Returns
+///
+/// `true` if the host stack is reserved, `false` if the APIC ID is too large.
+pub fn reserve_node_host_stack(apic_id: u32, stack: u64) -> bool {
+    match NODE_HOST_STACKS.get(apic_id as usize) {
+        Some(reserved) => {
+            reserved.store(stack, Ordering::Release);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Takes the host stack reserved for a logical processor by `reserve_node_host_stack`.
+///
+/// # Arguments
+///
+/// * `apic_id` - The APIC ID of the logical processor.
+///
+/// # Returns
+///
+/// An `Option` containing the host stack, `None` if none is reserved.
+pub fn take_node_host_stack(apic_id: u32) -> Option<*mut u8> {
+    match NODE_HOST_STACKS.get(apic_id as usize)?.swap(0, Ordering::AcqRel) {
+        0 => None,
+        stack => Some(stack as *mut u8),
+    }
+}
+
 /// Access the boot services
 fn boot_services() -> *const BootServices {
     let ptr = SYSTEM_TABLE.load(Ordering::Acquire);
@@ -4,11 +4,11 @@
 //! Credits to Satoshi Tanda: https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/switch_stack.rs
 
 use {
-    crate::stack::allocate_host_stack,
-    core::{alloc::Layout, arch::global_asm, intrinsics::copy_nonoverlapping},
+    crate::stack::{allocate_host_stack, take_node_host_stack},
+    core::{alloc::Layout, arch::global_asm, ptr::write_bytes},
     hypervisor::{
-        global_const::STACK_PAGES_PER_PROCESSOR,
-        intel::{capture::GuestRegisters, page::Page},
+        global_const::{HOST_STACK_GUARD_PAGES, STACK_PAGES_PER_PROCESSOR},
+        intel::{capture::GuestRegisters, host_stack::SHARED_HOST_STACKS, numa::current_apic_id, page::Page},
         vmm::start_hypervisor,
     },
     log::debug,
@@ -22,15 +22,24 @@ use {
 pub fn virtualize_system(guest_registers: &GuestRegisters) -> ! {
     debug!("Allocating stack space for host");
 
-    let layout = Layout::array::<Page>(STACK_PAGES_PER_PROCESSOR).unwrap();
-    let stack = unsafe { allocate_host_stack(layout) };
+    // The stack is preceded by its guard pages, unmapped from the host paging of this processor. On a multi-socket
+    // system, the stack was allocated in the node of this processor at load time.
+    let layout = Layout::array::<Page>(STACK_PAGES_PER_PROCESSOR + HOST_STACK_GUARD_PAGES).unwrap();
+    let stack = match take_node_host_stack(current_apic_id()) {
+        Some(stack) => stack,
+        None => unsafe { allocate_host_stack(layout) },
+    };
     let size = layout.size();
 
+    if stack.is_null() {
+        panic!("Failed to allocate stack");
+    }
+
     debug!("Zeroing stack space for host");
-    unsafe { copy_nonoverlapping(0 as _, stack, size) }
+    unsafe { write_bytes(stack, 0, size) }
 
-    if stack == core::ptr::null_mut() {
-        panic!("Failed to allocate stack");
+    if let Err(e) = SHARED_HOST_STACKS.lock().register_host_stack(stack as u64, size as u64) {
+        panic!("Failed to register the host stack: {:?}", e);
     }
 
     let stack_base = stack as u64 + layout.size() as u64 - 0x10;